@@ -0,0 +1,130 @@
+//! Cold loads of NearVM contracts of about 4 MiB, prefetched with
+//! [`PrefetchingContractCache`]s of one thread and more.
+//!
+//! ```text
+//! $ cargo bench --bench cold_load [-- THREADS]
+//! ```
+//!
+//! Compiles [`CONTRACTS`] contracts into a [`FilesystemContractRuntimeCache`]
+//! in a temporary directory, then for one prefetching thread and `THREADS`
+//! (default: up to 4, as many as there are CPUs) prints the time the prefetch
+//! of all the contracts takes and the average latency of the first call of a
+//! contract once prefetched.  The artifacts are read from the page cache
+//! unless the caches of the kernel are dropped between the runs.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use unc_parameters::vm::VMKind;
+use unc_parameters::RuntimeConfigStore;
+use unc_primitives_core::version::PROTOCOL_VERSION;
+use unc_vm_runner::logic::mocks::mock_context::get_context;
+use unc_vm_runner::logic::mocks::mock_external::MockedExternal;
+use unc_vm_runner::logic::{CompiledContractCache, Config};
+use unc_vm_runner::{
+    precompile_contract, ContractCode, FilesystemContractRuntimeCache, PrefetchingContractCache,
+};
+use wasm_encoder::{
+    CodeSection, ExportKind, ExportSection, Function, FunctionSection, Instruction, MemorySection,
+    MemoryType, Module, TypeSection,
+};
+
+const CONTRACTS: u32 = 8;
+const FUNCTIONS: u32 = 1000;
+/// Bytes of code of a contract.
+const CONTRACT_SIZE: usize = 4_000_000;
+
+/// A contract of about [`CONTRACT_SIZE`] bytes exporting `main`, different
+/// for every `salt`.
+fn large_contract(salt: u32) -> Vec<u8> {
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.function([], []);
+    module.section(&types);
+    let mut functions = FunctionSection::new();
+    for _ in 0..FUNCTIONS {
+        functions.function(0);
+    }
+    module.section(&functions);
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType { minimum: 1, maximum: None, memory64: false, shared: false });
+    module.section(&memories);
+    let mut exports = ExportSection::new();
+    exports.export("main", ExportKind::Func, 0);
+    module.section(&exports);
+    let mut code = CodeSection::new();
+    // `i32.const` of a 5 bytes immediate and `drop` take 7 bytes.
+    let pairs = CONTRACT_SIZE / FUNCTIONS as usize / 7;
+    for index in 0..FUNCTIONS {
+        let mut function = Function::new([]);
+        for pair in 0..pairs {
+            let value = (salt << 28) | (index << 16) | pair as u32;
+            function.instruction(&Instruction::I32Const(value as i32 | i32::MIN));
+            function.instruction(&Instruction::Drop);
+        }
+        function.instruction(&Instruction::End);
+        code.function(&function);
+    }
+    module.section(&code);
+    module.finish()
+}
+
+/// Time of the prefetch of `codes` with `threads` threads, and average time
+/// of the first call of each of them afterwards.
+fn cold_load(
+    inner: &Arc<FilesystemContractRuntimeCache>,
+    config: &Config,
+    codes: &[ContractCode],
+    threads: usize,
+) -> (Duration, Duration) {
+    let fees = RuntimeConfigStore::new(None).get_config(PROTOCOL_VERSION).fees.clone();
+    let cache = PrefetchingContractCache::new(inner.clone(), config.clone(), usize::MAX)
+        .with_threads(threads);
+    let start = Instant::now();
+    let prefetched = cache.prefetch(codes.iter().map(|code| *code.hash())).join().unwrap();
+    let prefetch = start.elapsed();
+    assert_eq!(prefetched, codes.len());
+    let start = Instant::now();
+    for code in codes {
+        let mut ext = MockedExternal::new();
+        let outcome = unc_vm_runner::run(
+            code,
+            "main",
+            &mut ext,
+            get_context(),
+            config,
+            &fees,
+            &[],
+            Some(&cache),
+        )
+        .unwrap();
+        assert!(outcome.aborted.is_none(), "{:?}", outcome.aborted);
+    }
+    (prefetch, start.elapsed() / codes.len() as u32)
+}
+
+fn main() {
+    let threads = std::env::args()
+        .skip(1)
+        .find(|arg| arg != "--bench")
+        .map(|arg| arg.parse().expect("THREADS should be a number"))
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get().min(4)));
+    let runtime_config = RuntimeConfigStore::new(None).get_config(PROTOCOL_VERSION).clone();
+    let mut config = Config::from(runtime_config.wasm_config.clone());
+    config.vm_kind = VMKind::NearVm;
+    let dir = std::env::temp_dir().join(format!("unc-vm-runner-cold-load-{}", std::process::id()));
+    let inner = Arc::new(FilesystemContractRuntimeCache::new(&dir).unwrap());
+    let codes: Vec<ContractCode> =
+        (0..CONTRACTS).map(|salt| ContractCode::new(large_contract(salt), None)).collect();
+    for code in &codes {
+        precompile_contract(code, &config, Some(&*inner as &dyn CompiledContractCache))
+            .unwrap()
+            .unwrap();
+    }
+    println!("{CONTRACTS} contracts of {} bytes", codes[0].code().len());
+    println!("{:<8} {:>12} {:>12}", "threads", "prefetch", "first call");
+    for threads in [1, threads] {
+        let (prefetch, first_call) = cold_load(&inner, &config, &codes, threads);
+        println!("{:<8} {:>12?} {:>12?}", threads, prefetch, first_call);
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
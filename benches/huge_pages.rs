@@ -0,0 +1,92 @@
+//! NearVM calls going through their memory with and without huge pages.
+//!
+//! ```text
+//! $ perf stat -e dTLB-load-misses cargo bench --bench huge_pages -- off
+//! $ perf stat -e dTLB-load-misses cargo bench --bench huge_pages -- transparent
+//! $ perf stat -e dTLB-load-misses cargo bench --bench huge_pages -- explicit
+//! ```
+//!
+//! Calls a contract incrementing a word of every small page of its initial
+//! memory, round after round, and prints the time a call takes with the
+//! given `huge_pages` host setting, all of them without an argument.  Run under
+//! `perf stat`, the number of dTLB misses of each mode shows the misses
+//! huge pages save; `explicit` needs pages reserved in
+//! `/proc/sys/vm/nr_hugepages`.
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use unc_parameters::vm::VMKind;
+use unc_parameters::{RuntimeConfigStore, RuntimeFeesConfig};
+use unc_primitives_core::version::PROTOCOL_VERSION;
+use unc_vm_runner::logic::mocks::mock_context::get_view_context;
+use unc_vm_runner::logic::mocks::mock_external::MockedExternal;
+use unc_vm_runner::logic::Config;
+use unc_vm_runner::{ContractCode, HostSettings, HugePages, SharedContract};
+
+const CALLS: u32 = 20;
+const ROUNDS: u32 = 10;
+const SMALL_PAGE_SIZE: u64 = 4096;
+
+/// A contract going through its first `memory_len` bytes [`ROUNDS`] times.
+fn memory_walk(memory_len: u64) -> Vec<u8> {
+    wat::parse_str(format!(
+        r#"(module
+          (memory 1)
+          (func (export "main")
+            (local $offset i32) (local $round i32)
+            (loop $rounds
+              (local.set $offset (i32.const 0))
+              (loop $pages
+                (i32.store (local.get $offset)
+                  (i32.add (i32.load (local.get $offset)) (i32.const 1)))
+                (local.set $offset (i32.add (local.get $offset) (i32.const {SMALL_PAGE_SIZE})))
+                (br_if $pages (i32.lt_u (local.get $offset) (i32.const {memory_len}))))
+              (local.set $round (i32.add (local.get $round) (i32.const 1)))
+              (br_if $rounds (i32.lt_u (local.get $round) (i32.const {ROUNDS}))))))"#
+    ))
+    .unwrap()
+}
+
+/// Average time of a call of `code` with `config` on a host with `huge_pages`.
+fn time(
+    config: Config,
+    huge_pages: Option<HugePages>,
+    fees: &Arc<RuntimeFeesConfig>,
+    code: &[u8],
+) -> Duration {
+    let max_gas_burnt = config.limit_config.max_gas_burnt;
+    let code = ContractCode::new(code.to_vec(), None);
+    let host = HostSettings { huge_pages, ..HostSettings::default() };
+    let contract =
+        SharedContract::new(config, Arc::clone(fees), code).unwrap().with_host_settings(host);
+    let runner = contract.runner(get_view_context(max_gas_burnt)).unwrap();
+    let start = Instant::now();
+    for _ in 0..CALLS {
+        let outcome = runner.view("main", vec![], &mut MockedExternal::new()).unwrap();
+        assert!(outcome.aborted.is_none(), "{:?}", outcome.aborted);
+        black_box(outcome);
+    }
+    start.elapsed() / CALLS
+}
+
+fn main() {
+    let modes: Vec<(&str, Option<HugePages>)> = vec![
+        ("off", None),
+        ("transparent", Some(HugePages::Transparent)),
+        ("explicit", Some(HugePages::Explicit)),
+    ];
+    let selected: Vec<String> = std::env::args().skip(1).filter(|arg| arg != "--bench").collect();
+    let runtime_config = RuntimeConfigStore::new(None).get_config(PROTOCOL_VERSION).clone();
+    let fees = Arc::new(runtime_config.fees.clone());
+    let mut config = Config::from(runtime_config.wasm_config.clone());
+    config.vm_kind = VMKind::NearVm;
+    let memory_len = u64::from(config.limit_config.initial_memory_pages) * 65536;
+    let code = memory_walk(memory_len);
+    println!("{ROUNDS} rounds over {} MiB of memory", memory_len >> 20);
+    for (name, huge_pages) in modes {
+        if selected.is_empty() || selected.iter().any(|arg| arg == name) {
+            println!("{:<12} {:>12?}", name, time(config.clone(), huge_pages, &fees, &code));
+        }
+    }
+}
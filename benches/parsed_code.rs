@@ -0,0 +1,92 @@
+//! Inspecting large contracts with and without [`ParsedContractCode`].
+//!
+//! ```text
+//! $ cargo bench --bench parsed_code [-- FUNCTIONS]
+//! ```
+//!
+//! Builds a contract exporting `FUNCTIONS` functions (default 20000) with a
+//! custom section at its end, and prints the time each inspection takes on
+//! the bytes and on the parsed code.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use unc_parameters::RuntimeConfigStore;
+use unc_primitives_core::version::PROTOCOL_VERSION;
+use unc_vm_runner::{analyze_contract, exported_methods, ContractCode, ParsedContractCode};
+use wasm_encoder::{
+    CodeSection, CustomSection, ExportKind, ExportSection, Function, FunctionSection, Instruction,
+    MemorySection, MemoryType, Module, TypeSection,
+};
+
+const ITERATIONS: u32 = 20;
+
+fn large_contract(functions: u32) -> Vec<u8> {
+    let mut module = Module::new();
+    let mut types = TypeSection::new();
+    types.function([], []);
+    module.section(&types);
+    let mut function_section = FunctionSection::new();
+    for _ in 0..functions {
+        function_section.function(0);
+    }
+    module.section(&function_section);
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType { minimum: 1, maximum: None, memory64: false, shared: false });
+    module.section(&memories);
+    let mut exports = ExportSection::new();
+    for index in 0..functions {
+        exports.export(&format!("method_{index}"), ExportKind::Func, index);
+    }
+    module.section(&exports);
+    let mut code = CodeSection::new();
+    for index in 0..functions {
+        let mut function = Function::new([]);
+        for _ in 0..16 {
+            function.instruction(&Instruction::I32Const(index as i32));
+            function.instruction(&Instruction::Drop);
+        }
+        function.instruction(&Instruction::End);
+        code.function(&function);
+    }
+    module.section(&code);
+    module.section(&CustomSection { name: "unc_abi".into(), data: b"{}".as_slice().into() });
+    module.finish()
+}
+
+/// Average time of `f` over [`ITERATIONS`] runs.
+fn time<T>(mut f: impl FnMut() -> T) -> Duration {
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(f());
+    }
+    start.elapsed() / ITERATIONS
+}
+
+fn main() {
+    let functions = std::env::args().skip(1).find_map(|arg| arg.parse().ok()).unwrap_or(20_000);
+    let bytes = large_contract(functions);
+    let config = RuntimeConfigStore::new(None).get_config(PROTOCOL_VERSION).wasm_config.clone();
+    println!("contract of {functions} functions, {} bytes", bytes.len());
+
+    let parse = time(|| ParsedContractCode::new(ContractCode::new(bytes.clone(), None)).unwrap());
+    println!("{:<24} {:>12?}", "parse", parse);
+    let parsed = ParsedContractCode::new(ContractCode::new(bytes.clone(), None)).unwrap();
+
+    let bytes_time = time(|| exported_methods(&bytes, &config).unwrap());
+    let parsed_time = time(|| parsed.exported_methods(&config).unwrap());
+    println!("{:<24} {:>12?} {:>12?}", "exported_methods", bytes_time, parsed_time);
+
+    let bytes_time = time(|| {
+        wasmparser::Parser::new(0).parse_all(&bytes).find_map(|payload| match payload {
+            Ok(wasmparser::Payload::CustomSection { name: "unc_abi", data, .. }) => Some(data),
+            _ => None,
+        })
+    });
+    let parsed_time = time(|| parsed.custom_section("unc_abi"));
+    println!("{:<24} {:>12?} {:>12?}", "custom_section", bytes_time, parsed_time);
+
+    let bytes_time = time(|| analyze_contract(&bytes, &config));
+    parsed.analyze(&config);
+    let parsed_time = time(|| parsed.analyze(&config));
+    println!("{:<24} {:>12?} {:>12?}", "analyze (repeated)", bytes_time, parsed_time);
+}
@@ -0,0 +1,99 @@
+//! View calls per second of each backend through [`ThroughputRunner`]s.
+//!
+//! ```text
+//! $ cargo bench --bench throughput [-- THREADS]
+//! ```
+//!
+//! Calls a contract echoing its input from `THREADS` threads (default: the
+//! number of CPUs), each keeping a runner of the same [`SharedContract`], and
+//! prints the calls per second of every backend compiled in, calling the
+//! method alone and in batches of [`BATCH`] calls with
+//! [`ThroughputRunner::view_many`].  The state is an empty mocked one, so the
+//! numbers are those of the runtime overhead of a call.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use unc_parameters::vm::VMKind;
+use unc_parameters::RuntimeConfigStore;
+use unc_primitives_core::version::PROTOCOL_VERSION;
+use unc_vm_runner::logic::mocks::mock_context::get_view_context;
+use unc_vm_runner::logic::mocks::mock_external::MockedExternal;
+use unc_vm_runner::logic::{Config, VMContext};
+use unc_vm_runner::{ContractCode, SharedContract, ThroughputRunner};
+
+const CALLS: usize = 2000;
+const BATCH: usize = 100;
+
+const ECHO_CONTRACT: &str = r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (memory 1)
+  (func (export "echo")
+    (call $input (i64.const 0))
+    (call $read_register (i64.const 0) (i64.const 0))
+    (call $value_return (call $register_len (i64.const 0)) (i64.const 0))))
+"#;
+
+/// Calls per second of [`CALLS`] calls on each of `threads` threads, made by
+/// `serve` with a runner of the thread calling with `context`.
+fn calls_per_second(
+    contract: &SharedContract,
+    context: &VMContext,
+    threads: usize,
+    serve: impl Fn(&ThroughputRunner) + Sync,
+) -> f64 {
+    let start = Instant::now();
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            let (context, serve) = (context.clone(), &serve);
+            s.spawn(move || serve(&contract.runner(context).unwrap()));
+        }
+    });
+    let elapsed = start.elapsed().max(Duration::from_nanos(1));
+    (CALLS * threads) as f64 / elapsed.as_secs_f64()
+}
+
+fn single(runner: &ThroughputRunner) {
+    for call in 0..CALLS {
+        let input = call.to_le_bytes().to_vec();
+        let outcome = runner.view("echo", input, &mut MockedExternal::new()).unwrap();
+        assert!(outcome.aborted.is_none(), "{:?}", outcome.aborted);
+    }
+}
+
+fn batched(runner: &ThroughputRunner) {
+    for batch in 0..CALLS / BATCH {
+        let calls: Vec<(&str, Vec<u8>)> = (0..BATCH)
+            .map(|call| ("echo", (batch * BATCH + call).to_le_bytes().to_vec()))
+            .collect();
+        let outcomes = runner.view_many(&calls, &mut MockedExternal::new()).unwrap();
+        assert!(outcomes.iter().all(|outcome| outcome.aborted.is_none()));
+    }
+}
+
+fn main() {
+    let threads = std::env::args()
+        .skip(1)
+        .find(|arg| arg != "--bench")
+        .map(|arg| arg.parse().expect("THREADS should be a number"))
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+    let runtime_config = RuntimeConfigStore::new(None).get_config(PROTOCOL_VERSION).clone();
+    let fees = Arc::new(runtime_config.fees.clone());
+    let code = wat::parse_str(ECHO_CONTRACT).unwrap();
+    println!("{threads} threads, {CALLS} calls each");
+    println!("{:<10} {:>12} {:>12}", "vm", "calls/s", "batched/s");
+    for vm_kind in [VMKind::NearVm, VMKind::Wasmer2, VMKind::Wasmer0, VMKind::Wasmtime] {
+        let config = Config { vm_kind, ..Config::from(runtime_config.wasm_config.clone()) };
+        let context = get_view_context(config.limit_config.max_gas_burnt);
+        let code = ContractCode::new(code.clone(), None);
+        let Ok(contract) = SharedContract::new(config, Arc::clone(&fees), code) else {
+            continue;
+        };
+        let single = calls_per_second(&contract, &context, threads, single);
+        let batched = calls_per_second(&contract, &context, threads, batched);
+        println!("{:<10} {:>12.0} {:>12.0}", format!("{vm_kind:?}"), single, batched);
+    }
+}
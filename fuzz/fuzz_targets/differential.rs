@@ -0,0 +1,12 @@
+#![no_main]
+
+use unc_vm_runner::ContractCode;
+use unc_vm_runner_fuzz::{create_context, find_entry_point, ArbitraryModule};
+
+libfuzzer_sys::fuzz_target!(|module: ArbitraryModule| {
+    let code = ContractCode::new(module.0.module.to_bytes(), None);
+    let method_name = find_entry_point(&code).unwrap_or_else(|| "main".to_string());
+    let report =
+        unc_vm_runner::differential::run_all_kinds(&code, &method_name, &create_context(vec![]));
+    report.assert_agree();
+});
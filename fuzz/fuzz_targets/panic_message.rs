@@ -0,0 +1,49 @@
+#![no_main]
+
+use unc_vm_runner::logic::error_codes::ErrorParam;
+use unc_vm_runner::ContractCode;
+use unc_vm_runner_fuzz::create_context;
+
+/// Bytes the message of an error may have besides its string fields.
+const MAX_MESSAGE_OVERHEAD: usize = 1000;
+
+/// Panics with its input, as UTF-8 or as an AssemblyScript abort message.
+const CONTRACT: &str = r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "panic_utf8" (func $panic_utf8 (param i64 i64)))
+  (import "env" "abort" (func $abort (param i32 i32 i32 i32)))
+  (memory 16)
+  (func (export "panic_utf8")
+    (call $input (i64.const 0))
+    (call $read_register (i64.const 0) (i64.const 0))
+    (call $panic_utf8 (call $register_len (i64.const 0)) (i64.const 0)))
+  (func (export "abort")
+    (call $input (i64.const 0))
+    (i32.store (i32.const 0) (i32.wrap_i64 (call $register_len (i64.const 0))))
+    (call $read_register (i64.const 0) (i64.const 4))
+    (call $abort (i32.const 4) (i32.const 4) (i32.const 1) (i32.const 2)))
+)"#;
+
+libfuzzer_sys::fuzz_target!(|input: (bool, Vec<u8>)| {
+    let (abort, payload) = input;
+    let code = ContractCode::new(wat::parse_str(CONTRACT).unwrap(), None);
+    let method_name = if abort { "abort" } else { "panic_utf8" };
+    let report =
+        unc_vm_runner::differential::run_all_kinds(&code, method_name, &create_context(payload));
+    for run in &report.runs {
+        let Ok(outcome) = &run.result else { continue };
+        if let Some(err) = &outcome.aborted {
+            let params = err.code().params;
+            let fields_len: usize = params
+                .iter()
+                .map(|(_, param)| if let ErrorParam::Str(s) = param { s.len() } else { 0 })
+                .sum();
+            let len = err.to_string().len();
+            assert!(len <= fields_len + MAX_MESSAGE_OVERHEAD, "{:?}: {len} bytes", run.vm_kind);
+        }
+    }
+    report.assert_agree();
+});
@@ -0,0 +1,411 @@
+//! Contract ABI metadata embedded in the contract code.
+//!
+//! Contract SDKs can describe the methods of a contract in an `unc_abi`
+//! custom section holding a JSON document like
+//!
+//! ```json
+//! {"functions": [
+//!     {"name": "get", "kind": "view", "serialization": "json",
+//!      "args": [{"name": "key", "type": "string"}], "result": {"type": "string"}},
+//!     {"name": "set", "kind": "call", "serialization": "borsh",
+//!      "args": [{"name": "key", "type": "string"}, {"name": "value", "type": {"vec": "u8"}}]}
+//! ]}
+//! ```
+//!
+//! The custom section is ignored when running contracts.  It lets tools
+//! generate well-formed inputs for each method, see [`AbiFuzzer`], check
+//! that an upgrade of the contract keeps its methods, see
+//! [`ContractInterface`], and show what the methods return, see
+//! [`decode_return_value`].
+
+mod compat;
+mod decode;
+mod fuzz;
+
+pub use compat::{BreakingChange, Compatibility, ContractInterface};
+pub use decode::{decode_return_value, DecodedValue};
+pub use fuzz::{AbiFuzzOptions, AbiFuzzer, FuzzFailure, InvariantViolation, MethodFuzzReport};
+
+/// Name of the custom section holding the ABI.
+pub const ABI_SECTION: &str = "unc_abi";
+
+#[derive(Debug, thiserror::Error)]
+pub enum AbiError {
+    #[error("the contract is not valid wasm: {0}")]
+    Wasm(#[from] wasmparser::BinaryReaderError),
+    #[error("the contract has several {ABI_SECTION} sections")]
+    DuplicateSection,
+    #[error("malformed ABI: {0}")]
+    Json(#[from] serde_json::Error),
+    #[error("cannot read the exports of the contract: {0}")]
+    Exports(crate::logic::errors::PrepareError),
+}
+
+/// The methods of a contract described by its ABI.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct ContractAbi {
+    pub functions: Vec<AbiFunction>,
+}
+
+impl ContractAbi {
+    /// Reads the ABI from the [`ABI_SECTION`] of `code`, if it has one.
+    pub fn from_code(code: &[u8]) -> Result<Option<Self>, AbiError> {
+        let mut abi = None;
+        for payload in wasmparser::Parser::new(0).parse_all(code) {
+            if let wasmparser::Payload::CustomSection { name: ABI_SECTION, data, .. } = payload? {
+                if abi.is_some() {
+                    return Err(AbiError::DuplicateSection);
+                }
+                abi = Some(Self::from_json(data)?);
+            }
+        }
+        Ok(abi)
+    }
+
+    /// Parses an ABI stored outside of the contract.
+    pub fn from_json(json: &[u8]) -> Result<Self, AbiError> {
+        Ok(serde_json::from_slice(json)?)
+    }
+
+    pub fn function(&self, name: &str) -> Option<&AbiFunction> {
+        self.functions.iter().find(|function| function.name == name)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AbiFunction {
+    pub name: String,
+    pub kind: AbiFunctionKind,
+    /// Encoding of the arguments.
+    #[serde(default)]
+    pub serialization: AbiSerialization,
+    #[serde(default)]
+    pub args: Vec<AbiParameter>,
+    /// What the function returns, if the ABI says.
+    #[serde(default)]
+    pub result: Option<AbiResult>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AbiFunctionKind {
+    /// Only reads the state, called as a view call.
+    View,
+    /// May change the state, called by a transaction or a receipt.
+    Call,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AbiSerialization {
+    /// The arguments are the fields of a JSON object.
+    #[default]
+    Json,
+    /// The arguments are Borsh encoded one after the other, like the fields
+    /// of a struct.
+    Borsh,
+}
+
+/// The value returned by an [`AbiFunction`].
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AbiResult {
+    #[serde(default)]
+    pub serialization: AbiSerialization,
+    #[serde(rename = "type")]
+    pub ty: AbiType,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct AbiParameter {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: AbiType,
+}
+
+/// Type of an argument.
+///
+/// In JSON, 128 bit integers are strings like the `U128` wrappers of the
+/// contract SDKs, enums are the names of their variants and tuples are
+/// arrays.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AbiType {
+    Bool,
+    U8,
+    U16,
+    U32,
+    U64,
+    U128,
+    I8,
+    I16,
+    I32,
+    I64,
+    I128,
+    String,
+    Vec(Box<AbiType>),
+    Option(Box<AbiType>),
+    Tuple(Vec<AbiType>),
+    Struct(Vec<AbiParameter>),
+    /// Enum with unit variants only.
+    Enum(Vec<String>),
+}
+
+/// Values generated for the arguments tend towards the edge cases.
+const INTERESTING_INTEGERS: [i128; 5] = [0, 1, -1, i128::MIN, i128::MAX];
+
+/// Nesting depth past which collections are generated empty, so that
+/// recursive types generate inputs of bounded size.
+const MAX_DEPTH: usize = 4;
+
+/// Small deterministic generator, so that the inputs of a fuzzing session can
+/// be reproduced from its seed.
+#[derive(Clone, Debug)]
+pub(crate) struct FuzzRng(u64);
+
+impl FuzzRng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    /// SplitMix64.
+    pub(crate) fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next_u64() % n as u64) as usize
+    }
+
+    /// An integer of `bits` bits, as the bit pattern of its two's complement.
+    fn integer(&mut self, bits: u32) -> u128 {
+        let value = if self.below(2) == 0 {
+            INTERESTING_INTEGERS[self.below(INTERESTING_INTEGERS.len())] as u128
+        } else {
+            u128::from(self.next_u64()) << 64 | u128::from(self.next_u64())
+        };
+        value & (u128::MAX >> (128 - bits))
+    }
+
+    fn string(&mut self) -> String {
+        const CHARS: [char; 8] = ['a', 'Z', '0', ' ', '"', '\\', 'é', '😀'];
+        (0..self.below(33)).map(|_| CHARS[self.below(CHARS.len())]).collect()
+    }
+}
+
+impl AbiType {
+    fn bits(&self) -> Option<(u32, bool)> {
+        Some(match self {
+            Self::U8 => (8, false),
+            Self::U16 => (16, false),
+            Self::U32 => (32, false),
+            Self::U64 => (64, false),
+            Self::U128 => (128, false),
+            Self::I8 => (8, true),
+            Self::I16 => (16, true),
+            Self::I32 => (32, true),
+            Self::I64 => (64, true),
+            Self::I128 => (128, true),
+            _ => return None,
+        })
+    }
+
+    fn len(rng: &mut FuzzRng, depth: usize) -> usize {
+        if depth >= MAX_DEPTH {
+            0
+        } else {
+            rng.below(9)
+        }
+    }
+
+    fn generate_json(&self, rng: &mut FuzzRng, depth: usize) -> serde_json::Value {
+        use serde_json::Value;
+        if let Some((bits, signed)) = self.bits() {
+            let value = rng.integer(bits);
+            return match (bits, signed) {
+                (128, false) => Value::String(value.to_string()),
+                (128, true) => Value::String((value as i128).to_string()),
+                (_, false) => Value::from(value as u64),
+                // Sign extend.
+                (_, true) => Value::from(((value << (128 - bits)) as i128 >> (128 - bits)) as i64),
+            };
+        }
+        match self {
+            Self::Bool => Value::Bool(rng.below(2) == 1),
+            Self::String => Value::String(rng.string()),
+            Self::Vec(item) => (0..Self::len(rng, depth))
+                .map(|_| item.generate_json(rng, depth + 1))
+                .collect::<Vec<_>>()
+                .into(),
+            Self::Option(item) if depth < MAX_DEPTH && rng.below(2) == 1 => {
+                item.generate_json(rng, depth + 1)
+            }
+            Self::Option(_) => Value::Null,
+            Self::Tuple(items) => items
+                .iter()
+                .map(|item| item.generate_json(rng, depth + 1))
+                .collect::<Vec<_>>()
+                .into(),
+            Self::Struct(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|field| (field.name.clone(), field.ty.generate_json(rng, depth + 1)))
+                    .collect(),
+            ),
+            Self::Enum(variants) if variants.is_empty() => Value::Null,
+            Self::Enum(variants) => Value::String(variants[rng.below(variants.len())].clone()),
+            _ => unreachable!("integers are handled above"),
+        }
+    }
+
+    fn generate_borsh(&self, rng: &mut FuzzRng, depth: usize, out: &mut Vec<u8>) {
+        if let Some((bits, _)) = self.bits() {
+            let bytes = rng.integer(bits).to_le_bytes();
+            out.extend_from_slice(&bytes[..bits as usize / 8]);
+            return;
+        }
+        match self {
+            Self::Bool => out.push(rng.below(2) as u8),
+            Self::String => {
+                let string = rng.string();
+                out.extend_from_slice(&(string.len() as u32).to_le_bytes());
+                out.extend_from_slice(string.as_bytes());
+            }
+            Self::Vec(item) => {
+                let len = Self::len(rng, depth);
+                out.extend_from_slice(&(len as u32).to_le_bytes());
+                for _ in 0..len {
+                    item.generate_borsh(rng, depth + 1, out);
+                }
+            }
+            Self::Option(item) if depth < MAX_DEPTH && rng.below(2) == 1 => {
+                out.push(1);
+                item.generate_borsh(rng, depth + 1, out);
+            }
+            Self::Option(_) => out.push(0),
+            Self::Tuple(items) => {
+                for item in items {
+                    item.generate_borsh(rng, depth + 1, out);
+                }
+            }
+            Self::Struct(fields) => {
+                for field in fields {
+                    field.ty.generate_borsh(rng, depth + 1, out);
+                }
+            }
+            Self::Enum(variants) => out.push(rng.below(variants.len().max(1)) as u8),
+            _ => unreachable!("integers are handled above"),
+        }
+    }
+}
+
+impl AbiFunction {
+    /// Generates an input matching the arguments of the function.
+    pub(crate) fn generate_input(&self, rng: &mut FuzzRng) -> Vec<u8> {
+        match self.serialization {
+            AbiSerialization::Json => {
+                let args = self
+                    .args
+                    .iter()
+                    .map(|arg| (arg.name.clone(), arg.ty.generate_json(rng, 0)))
+                    .collect();
+                serde_json::to_vec(&serde_json::Value::Object(args)).unwrap()
+            }
+            AbiSerialization::Borsh => {
+                let mut input = Vec::new();
+                for arg in &self.args {
+                    arg.ty.generate_borsh(rng, 0, &mut input);
+                }
+                input
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use borsh::BorshDeserialize;
+
+    /// Appends a custom section to the wasm module `code`.
+    pub(crate) fn with_custom_section(mut code: Vec<u8>, name: &str, data: &[u8]) -> Vec<u8> {
+        let mut section = Vec::new();
+        leb128(&mut section, name.len());
+        section.extend_from_slice(name.as_bytes());
+        section.extend_from_slice(data);
+        code.push(0);
+        leb128(&mut code, section.len());
+        code.extend(section);
+        code
+    }
+
+    fn leb128(out: &mut Vec<u8>, mut value: usize) {
+        loop {
+            let byte = (value & 0x7f) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(byte);
+                return;
+            }
+            out.push(byte | 0x80);
+        }
+    }
+
+    const ABI: &str = r#"{"functions": [
+        {"name": "get", "kind": "view", "args": [{"name": "key", "type": "string"}]},
+        {"name": "set", "kind": "call", "serialization": "borsh", "args": [
+            {"name": "key", "type": "string"},
+            {"name": "values", "type": {"vec": {"option": "i32"}}},
+            {"name": "amount", "type": "u128"},
+            {"name": "mode", "type": {"enum": ["a", "b"]}}
+        ]}
+    ]}"#;
+
+    #[test]
+    fn test_abi_from_code() {
+        let code = wat::parse_str("(module)").unwrap();
+        assert_eq!(ContractAbi::from_code(&code).unwrap(), None);
+
+        let code = with_custom_section(code, ABI_SECTION, ABI.as_bytes());
+        let abi = ContractAbi::from_code(&code).unwrap().unwrap();
+        assert_eq!(abi.functions.len(), 2);
+        let get = abi.function("get").unwrap();
+        assert_eq!(get.kind, AbiFunctionKind::View);
+        assert_eq!(get.serialization, AbiSerialization::Json);
+        assert_eq!(get.args[0].ty, AbiType::String);
+
+        let code = with_custom_section(code, ABI_SECTION, ABI.as_bytes());
+        assert!(matches!(ContractAbi::from_code(&code), Err(AbiError::DuplicateSection)));
+    }
+
+    #[test]
+    fn test_generated_inputs_are_well_formed() {
+        let abi = ContractAbi::from_json(ABI.as_bytes()).unwrap();
+        let mut rng = FuzzRng::new(42);
+        for _ in 0..100 {
+            let input = abi.function("get").unwrap().generate_input(&mut rng);
+            let json: serde_json::Value = serde_json::from_slice(&input).unwrap();
+            assert!(json["key"].is_string());
+
+            let input = abi.function("set").unwrap().generate_input(&mut rng);
+            let (_, values, _, mode) =
+                <(String, Vec<Option<i32>>, u128, u8)>::try_from_slice(&input).unwrap();
+            assert!(values.len() <= 8);
+            assert!(mode < 2);
+        }
+    }
+
+    #[test]
+    fn test_generation_is_reproducible() {
+        let abi = ContractAbi::from_json(ABI.as_bytes()).unwrap();
+        let inputs = |seed| {
+            let mut rng = FuzzRng::new(seed);
+            (0..10).map(|_| abi.functions[1].generate_input(&mut rng)).collect::<Vec<_>>()
+        };
+        assert_eq!(inputs(1), inputs(1));
+        assert_ne!(inputs(1), inputs(2));
+    }
+}
@@ -0,0 +1,303 @@
+//! Checking that a new version of a contract can replace the old one.
+//!
+//! Upgrading a contract breaks its callers when a method they call is gone,
+//! cannot be called anymore or expects other arguments.  A
+//! [`ContractInterface`] holds the exported methods of a version of a
+//! contract and its ABI, if it has one, and
+//! [`ContractInterface::check_upgrade`] lists what the new version breaks, so
+//! that upgrade pipelines can refuse to deploy it.
+//!
+//! Without an ABI on both sides only the exported methods are compared.  The
+//! arguments of a method are compatible when the old callers' inputs still
+//! decode:
+//!
+//! * Borsh arguments are positional, so they must not change at all;
+//! * JSON arguments may be added if they are optional, and removed, the
+//!   contract SDKs ignore unknown fields; the type of a kept argument must be
+//!   the same, or become optional.
+
+use super::{
+    AbiError, AbiFunction, AbiFunctionKind, AbiParameter, AbiSerialization, AbiType, ContractAbi,
+};
+use crate::logic::errors::MethodResolveError;
+use crate::method_name::{exported_methods, ExportedMethod};
+use crate::logic::Config;
+
+/// The methods callers can call on a version of a contract.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContractInterface {
+    pub methods: Vec<ExportedMethod>,
+    pub abi: Option<ContractAbi>,
+}
+
+impl ContractInterface {
+    /// Reads the exported methods of `code` and the ABI of its
+    /// [`super::ABI_SECTION`].
+    pub fn from_code(code: &[u8], config: &Config) -> Result<Self, AbiError> {
+        let methods = exported_methods(code, config).map_err(AbiError::Exports)?;
+        Ok(Self { methods, abi: ContractAbi::from_code(code)? })
+    }
+
+    /// Uses `abi` instead of the ABI of the code, e.g. one stored apart from
+    /// the contract.
+    pub fn with_abi(self, abi: ContractAbi) -> Self {
+        Self { abi: Some(abi), ..self }
+    }
+
+    /// Compares this version with the `new` one which is to replace it.
+    pub fn check_upgrade(&self, new: &ContractInterface) -> Compatibility {
+        let mut breaking_changes = Vec::new();
+        for method in &self.methods {
+            if method.callable.is_err() {
+                continue;
+            }
+            let name = &method.name;
+            match new.methods.iter().find(|new_method| new_method.name == *name) {
+                None => {
+                    breaking_changes.push(BreakingChange::MethodRemoved { method: name.clone() })
+                }
+                Some(ExportedMethod { callable: Err(error), .. }) => {
+                    breaking_changes.push(BreakingChange::MethodNotCallable {
+                        method: name.clone(),
+                        error: error.clone(),
+                    })
+                }
+                Some(_) => {}
+            }
+        }
+        let compared_abis = match (&self.abi, &new.abi) {
+            (Some(old_abi), Some(new_abi)) => {
+                for old in &old_abi.functions {
+                    let removed = breaking_changes.iter().any(|change| change.method() == old.name);
+                    if let (false, Some(new)) = (removed, new_abi.function(&old.name)) {
+                        compare_functions(old, new, &mut breaking_changes);
+                    }
+                }
+                true
+            }
+            _ => false,
+        };
+        let added_methods = new
+            .methods
+            .iter()
+            .filter(|method| self.methods.iter().all(|old| old.name != method.name))
+            .map(|method| method.name.clone())
+            .collect();
+        Compatibility { added_methods, breaking_changes, compared_abis }
+    }
+}
+
+/// Outcome of [`ContractInterface::check_upgrade`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Compatibility {
+    /// Methods exported by the new version and not by the old one.
+    pub added_methods: Vec<String>,
+    /// What breaks the callers of the old version: the methods which are not
+    /// exported or callable anymore first, then the changes of the ABI.
+    pub breaking_changes: Vec<BreakingChange>,
+    /// Whether both versions have an ABI, without which the arguments of the
+    /// methods are not compared.
+    pub compared_abis: bool,
+}
+
+impl Compatibility {
+    pub fn is_compatible(&self) -> bool {
+        self.breaking_changes.is_empty()
+    }
+}
+
+/// A change of a method breaking its callers, see the module documentation.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum BreakingChange {
+    #[error("{method} is not exported anymore")]
+    MethodRemoved { method: String },
+    #[error("{method} cannot be called anymore: {error}")]
+    MethodNotCallable { method: String, error: MethodResolveError },
+    #[error("{method} is not a view anymore")]
+    ViewBecameCall { method: String },
+    #[error("the arguments of {method} are encoded in {new:?} instead of {old:?}")]
+    SerializationChanged { method: String, old: AbiSerialization, new: AbiSerialization },
+    #[error("the Borsh arguments of {method} changed")]
+    BorshArgumentsChanged { method: String, old: Vec<AbiParameter>, new: Vec<AbiParameter> },
+    #[error("{method} has the new required argument {argument}")]
+    RequiredArgumentAdded { method: String, argument: String },
+    #[error("the argument {argument} of {method} changed from {old:?} to {new:?}")]
+    ArgumentTypeChanged { method: String, argument: String, old: AbiType, new: AbiType },
+}
+
+impl BreakingChange {
+    /// The method which changed.
+    pub fn method(&self) -> &str {
+        match self {
+            Self::MethodRemoved { method }
+            | Self::MethodNotCallable { method, .. }
+            | Self::ViewBecameCall { method }
+            | Self::SerializationChanged { method, .. }
+            | Self::BorshArgumentsChanged { method, .. }
+            | Self::RequiredArgumentAdded { method, .. }
+            | Self::ArgumentTypeChanged { method, .. } => method,
+        }
+    }
+}
+
+fn compare_functions(old: &AbiFunction, new: &AbiFunction, changes: &mut Vec<BreakingChange>) {
+    let method = || old.name.clone();
+    // Calls can become views, transactions can call views.
+    if old.kind == AbiFunctionKind::View && new.kind == AbiFunctionKind::Call {
+        changes.push(BreakingChange::ViewBecameCall { method: method() });
+    }
+    if old.serialization != new.serialization {
+        changes.push(BreakingChange::SerializationChanged {
+            method: method(),
+            old: old.serialization,
+            new: new.serialization,
+        });
+        return;
+    }
+    match old.serialization {
+        AbiSerialization::Borsh if old.args != new.args => {
+            changes.push(BreakingChange::BorshArgumentsChanged {
+                method: method(),
+                old: old.args.clone(),
+                new: new.args.clone(),
+            })
+        }
+        AbiSerialization::Borsh => {}
+        AbiSerialization::Json => {
+            for arg in &new.args {
+                match old.args.iter().find(|old_arg| old_arg.name == arg.name) {
+                    None if !matches!(arg.ty, AbiType::Option(_)) => {
+                        changes.push(BreakingChange::RequiredArgumentAdded {
+                            method: method(),
+                            argument: arg.name.clone(),
+                        })
+                    }
+                    Some(old_arg) if !json_type_accepts(&arg.ty, &old_arg.ty) => {
+                        changes.push(BreakingChange::ArgumentTypeChanged {
+                            method: method(),
+                            argument: arg.name.clone(),
+                            old: old_arg.ty.clone(),
+                            new: arg.ty.clone(),
+                        })
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Whether the JSON values of type `old` decode as `new`.
+fn json_type_accepts(new: &AbiType, old: &AbiType) -> bool {
+    match new {
+        AbiType::Option(item) => new == old || **item == *old,
+        _ => new == old,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::tests::with_custom_section;
+    use crate::abi::ABI_SECTION;
+    use crate::tests::test_vm_config;
+
+    fn interface(wat: &str, abi: &str) -> ContractInterface {
+        let code = with_custom_section(wat::parse_str(wat).unwrap(), ABI_SECTION, abi.as_bytes());
+        ContractInterface::from_code(&code, &test_vm_config()).unwrap()
+    }
+
+    const OLD_WAT: &str = r#"(module
+        (func (export "get")) (func (export "set")) (func (export "burn")) (func (export "mint")))"#;
+    const OLD_ABI: &str = r#"{"functions": [
+        {"name": "get", "kind": "view", "args": [
+            {"name": "key", "type": "string"}, {"name": "limit", "type": "u32"}]},
+        {"name": "set", "kind": "call", "serialization": "borsh", "args": [
+            {"name": "key", "type": "string"}, {"name": "value", "type": "u64"}]},
+        {"name": "mint", "kind": "call", "args": []}
+    ]}"#;
+
+    #[test]
+    fn test_compatible_upgrade() {
+        let old = interface(OLD_WAT, OLD_ABI);
+        let new = interface(
+            r#"(module (func (export "get")) (func (export "set")) (func (export "burn"))
+                (func (export "mint")) (func (export "pause")))"#,
+            r#"{"functions": [
+                {"name": "get", "kind": "view", "args": [
+                    {"name": "key", "type": "string"}, {"name": "limit", "type": {"option": "u32"}},
+                    {"name": "from", "type": {"option": "string"}}]},
+                {"name": "set", "kind": "call", "serialization": "borsh", "args": [
+                    {"name": "key", "type": "string"}, {"name": "value", "type": "u64"}]},
+                {"name": "mint", "kind": "view", "args": []}
+            ]}"#,
+        );
+        let compatibility = old.check_upgrade(&new);
+        assert_eq!(compatibility.breaking_changes, []);
+        assert_eq!(compatibility.added_methods, ["pause"]);
+        assert!(compatibility.compared_abis);
+        assert!(compatibility.is_compatible());
+    }
+
+    #[test]
+    fn test_breaking_upgrade() {
+        let old = interface(OLD_WAT, OLD_ABI);
+        let new = interface(
+            r#"(module (func (export "get")) (func (export "set"))
+                (func (export "burn") (param i32)))"#,
+            r#"{"functions": [
+                {"name": "get", "kind": "call", "args": [
+                    {"name": "key", "type": "u64"}, {"name": "owner", "type": "string"}]},
+                {"name": "set", "kind": "call", "serialization": "borsh", "args": [
+                    {"name": "key", "type": "string"}]}
+            ]}"#,
+        );
+        let compatibility = old.check_upgrade(&new);
+        let method = |name: &str| name.to_string();
+        assert_eq!(
+            compatibility.breaking_changes,
+            [
+                BreakingChange::MethodNotCallable {
+                    method: method("burn"),
+                    error: MethodResolveError::MethodInvalidSignature,
+                },
+                BreakingChange::MethodRemoved { method: method("mint") },
+                BreakingChange::ViewBecameCall { method: method("get") },
+                BreakingChange::ArgumentTypeChanged {
+                    method: method("get"),
+                    argument: "key".to_string(),
+                    old: AbiType::String,
+                    new: AbiType::U64,
+                },
+                BreakingChange::RequiredArgumentAdded {
+                    method: method("get"),
+                    argument: "owner".to_string(),
+                },
+                BreakingChange::BorshArgumentsChanged {
+                    method: method("set"),
+                    old: old.abi.as_ref().unwrap().functions[1].args.clone(),
+                    new: new.abi.as_ref().unwrap().functions[1].args.clone(),
+                },
+            ]
+        );
+        assert!(!compatibility.is_compatible());
+    }
+
+    #[test]
+    fn test_upgrade_without_abi() {
+        let old = ContractInterface::from_code(
+            &wat::parse_str(r#"(module (func (export "get")) (func (export "set")))"#).unwrap(),
+            &test_vm_config(),
+        )
+        .unwrap();
+        let new = interface(r#"(module (func (export "get")))"#, OLD_ABI);
+        let compatibility = old.check_upgrade(&new);
+        assert!(!compatibility.compared_abis);
+        assert_eq!(
+            compatibility.breaking_changes,
+            [BreakingChange::MethodRemoved { method: "set".to_string() }]
+        );
+        let abi = ContractAbi::from_json(OLD_ABI.as_bytes()).unwrap();
+        assert!(old.with_abi(abi).check_upgrade(&new).compared_abis);
+    }
+}
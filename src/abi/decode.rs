@@ -0,0 +1,168 @@
+//! Decoding the values returned by methods, to show them to developers.
+//!
+//! An outcome only holds the bytes the method returned.  When the ABI of the
+//! contract gives the `result` of the method, [`decode_return_value`] decodes
+//! them with the encoding it declares, Borsh values being read with the type
+//! of the result and shown in the JSON conventions of [`AbiType`].  Without a
+//! result in the ABI, the value is shown as JSON if it is JSON, the encoding
+//! of the contract SDKs by default.  Values which decode neither way are shown
+//! in hex.
+
+use super::{AbiResult, AbiSerialization, AbiType, ContractAbi};
+use serde_json::Value;
+
+/// A value returned by a method, see [`decode_return_value`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DecodedValue {
+    Json(Value),
+    /// A Borsh value decoded with the type of the ABI.
+    Borsh(Value),
+    /// A value which did not decode.
+    Hex(Vec<u8>),
+}
+
+impl std::fmt::Display for DecodedValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Json(value) => write!(f, "json {value}"),
+            Self::Borsh(value) => write!(f, "borsh {value}"),
+            Self::Hex(bytes) => {
+                write!(f, "hex ")?;
+                bytes.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+            }
+        }
+    }
+}
+
+/// Decodes `value`, returned by `method_name` of the contract with `abi`, see
+/// the module documentation.
+pub fn decode_return_value(
+    abi: Option<&ContractAbi>,
+    method_name: &str,
+    value: &[u8],
+) -> DecodedValue {
+    let result = abi.and_then(|abi| abi.function(method_name)?.result.as_ref());
+    let decoded = match result {
+        Some(AbiResult { serialization: AbiSerialization::Borsh, ty }) => {
+            let mut input = value;
+            ty.decode_borsh(&mut input).filter(|_| input.is_empty()).map(DecodedValue::Borsh)
+        }
+        _ => serde_json::from_slice(value).ok().map(DecodedValue::Json),
+    };
+    decoded.unwrap_or_else(|| DecodedValue::Hex(value.to_vec()))
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if input.len() < len {
+        return None;
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Some(taken)
+}
+
+impl AbiType {
+    /// Reads a Borsh value of this type from the start of `input`.
+    fn decode_borsh(&self, input: &mut &[u8]) -> Option<Value> {
+        if let Some((bits, signed)) = self.bits() {
+            let bytes = take(input, bits as usize / 8)?;
+            let mut le_bytes = [0; 16];
+            le_bytes[..bytes.len()].copy_from_slice(bytes);
+            let value = u128::from_le_bytes(le_bytes);
+            // Sign extend.
+            let signed_value = (value << (128 - bits)) as i128 >> (128 - bits);
+            return Some(match (bits, signed) {
+                (128, false) => Value::String(value.to_string()),
+                (128, true) => Value::String(signed_value.to_string()),
+                (_, false) => Value::from(value as u64),
+                (_, true) => Value::from(signed_value as i64),
+            });
+        }
+        Some(match self {
+            Self::Bool => match take(input, 1)? {
+                [0] => Value::Bool(false),
+                [1] => Value::Bool(true),
+                _ => return None,
+            },
+            Self::String => {
+                let len = u32::from_le_bytes(take(input, 4)?.try_into().unwrap());
+                let bytes = take(input, len as usize)?;
+                Value::String(std::str::from_utf8(bytes).ok()?.to_string())
+            }
+            Self::Vec(item) => {
+                let len = u32::from_le_bytes(take(input, 4)?.try_into().unwrap()) as usize;
+                // Keeps a forged length from looping over items of no bytes.
+                if len > input.len() {
+                    return None;
+                }
+                (0..len).map(|_| item.decode_borsh(input)).collect::<Option<Vec<_>>>()?.into()
+            }
+            Self::Option(item) => match take(input, 1)? {
+                [0] => Value::Null,
+                [1] => item.decode_borsh(input)?,
+                _ => return None,
+            },
+            Self::Tuple(items) => items
+                .iter()
+                .map(|item| item.decode_borsh(input))
+                .collect::<Option<Vec<_>>>()?
+                .into(),
+            Self::Struct(fields) => Value::Object(
+                fields
+                    .iter()
+                    .map(|field| Some((field.name.clone(), field.ty.decode_borsh(input)?)))
+                    .collect::<Option<_>>()?,
+            ),
+            Self::Enum(variants) => {
+                Value::String(variants.get(take(input, 1)?[0] as usize)?.clone())
+            }
+            _ => unreachable!("integers are handled above"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ABI: &str = r#"{"functions": [
+        {"name": "get", "kind": "view", "result": {"type": "string"}},
+        {"name": "pair", "kind": "view", "result": {"serialization": "borsh", "type":
+            {"struct": [
+                {"name": "amount", "type": "u128"},
+                {"name": "delta", "type": "i8"},
+                {"name": "tags", "type": {"vec": {"option": "string"}}},
+                {"name": "mode", "type": {"enum": ["a", "b"]}}
+            ]}}},
+        {"name": "none", "kind": "call"}
+    ]}"#;
+
+    #[test]
+    fn test_decode_return_value() {
+        let abi = ContractAbi::from_json(ABI.as_bytes()).unwrap();
+        let decode = |method, value: &[u8]| decode_return_value(Some(&abi), method, value);
+        assert_eq!(decode("get", br#""hi""#), DecodedValue::Json(Value::from("hi")));
+        assert_eq!(decode("get", b"\xff").to_string(), "hex ff");
+
+        let value = borsh::to_vec(&(7u128, -2i8, vec![Some("x".to_string()), None], 1u8)).unwrap();
+        assert_eq!(
+            decode("pair", &value),
+            DecodedValue::Borsh(serde_json::json!({
+                "amount": "7",
+                "delta": -2,
+                "tags": ["x", null],
+                "mode": "b",
+            }))
+        );
+        // Trailing bytes, a truncated value and an unknown variant.
+        assert!(matches!(decode("pair", &[&value[..], &[0]].concat()), DecodedValue::Hex(_)));
+        assert!(matches!(decode("pair", &value[..value.len() - 1]), DecodedValue::Hex(_)));
+        let unknown = [&value[..value.len() - 1], &[2]].concat();
+        assert!(matches!(decode("pair", &unknown), DecodedValue::Hex(_)));
+
+        // Without a result, JSON values are still shown as JSON.
+        assert_eq!(decode("none", b"[1]").to_string(), "json [1]");
+        assert_eq!(decode_return_value(None, "pair", b"{}").to_string(), "json {}");
+        assert_eq!(decode_return_value(None, "pair", &value[..2]).to_string(), "hex 0700");
+    }
+}
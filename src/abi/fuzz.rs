@@ -0,0 +1,271 @@
+use super::{AbiFunction, AbiFunctionKind, ContractAbi, FuzzRng};
+use crate::logic::mocks::mock_context::{get_context, get_view_context};
+use crate::logic::mocks::mock_external::MockedExternal;
+use crate::logic::VMOutcome;
+use crate::runner::{BackendUnavailable, VMKindExt, VM};
+use crate::{ContractCode, MockCompiledContractCache};
+use std::panic::AssertUnwindSafe;
+use crate::logic::Config;
+use unc_parameters::RuntimeFeesConfig;
+use unc_primitives_core::types::Gas;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AbiFuzzOptions {
+    /// Number of inputs generated for each method.
+    pub runs: usize,
+    /// Number of times each input is run to check that the outcome is always
+    /// the same.
+    pub repeats: usize,
+    /// Seed of the generated inputs.
+    pub seed: u64,
+    /// Prepaid gas of the calls, or maximum gas burnt by views.
+    pub prepaid_gas: Gas,
+    /// Gas no call is expected to exceed, if lower than the prepaid gas.
+    pub gas_budget: Option<Gas>,
+}
+
+impl Default for AbiFuzzOptions {
+    fn default() -> Self {
+        Self { runs: 100, repeats: 2, seed: 0, prepaid_gas: 300 * 10u64.pow(12), gas_budget: None }
+    }
+}
+
+/// An invariant broken by a call.
+///
+/// Calls which abort, e.g. because the contract rejects the input, are not
+/// violations: contracts are free to reject inputs.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum InvariantViolation {
+    #[error("the host panicked: {0}")]
+    HostPanic(String),
+    #[error("the runner failed: {0}")]
+    RunnerError(String),
+    #[error("burnt {burnt} gas, more than {limit}")]
+    GasExceeded { burnt: Gas, limit: Gas },
+    #[error("burnt {burnt} gas, more than the {used} gas used")]
+    GasAccounting { burnt: Gas, used: Gas },
+    #[error("repeating the call changed its outcome from {first} to {repeat}")]
+    Nondeterministic { first: String, repeat: String },
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuzzFailure {
+    pub input: Vec<u8>,
+    pub violation: InvariantViolation,
+}
+
+/// Results of fuzzing one method.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MethodFuzzReport {
+    pub method: String,
+    pub runs: usize,
+    /// Calls aborted by the contract.
+    pub aborted: usize,
+    pub failures: Vec<FuzzFailure>,
+}
+
+/// Calls the methods of a contract with inputs generated from its
+/// [`ContractAbi`], checking that the runtime behaves for all of them.
+///
+/// This is meant for contract authors to run before deploying: every call
+/// runs against an empty mocked state, so contracts reading their state get
+/// the same results as on a fresh account.
+pub struct AbiFuzzer {
+    runtime: Box<dyn VM>,
+    fees: RuntimeFeesConfig,
+    cache: MockCompiledContractCache,
+    options: AbiFuzzOptions,
+}
+
+impl AbiFuzzer {
+    pub fn new(
+        config: Config,
+        fees: RuntimeFeesConfig,
+        options: AbiFuzzOptions,
+    ) -> Result<Self, BackendUnavailable> {
+        let vm_kind = config.vm_kind;
+        let runtime = vm_kind.runtime(config)?;
+        Ok(Self { runtime, fees, cache: MockCompiledContractCache::default(), options })
+    }
+
+    /// Fuzzes every function of `abi`.
+    pub fn fuzz(&self, code: &ContractCode, abi: &ContractAbi) -> Vec<MethodFuzzReport> {
+        abi.functions.iter().map(|function| self.fuzz_function(code, function)).collect()
+    }
+
+    pub fn fuzz_function(&self, code: &ContractCode, function: &AbiFunction) -> MethodFuzzReport {
+        let mut report = MethodFuzzReport {
+            method: function.name.clone(),
+            runs: 0,
+            aborted: 0,
+            failures: Vec::new(),
+        };
+        // Every method gets the same inputs whatever the methods before it.
+        let mut rng = FuzzRng::new(self.options.seed);
+        for _ in 0..self.options.runs {
+            let input = function.generate_input(&mut rng);
+            report.runs += 1;
+            match self.check(code, function, &input) {
+                Ok(aborted) => report.aborted += usize::from(aborted),
+                Err(violation) => report.failures.push(FuzzFailure { input, violation }),
+            }
+        }
+        report
+    }
+
+    /// Runs the function `repeats` times with `input`, returning whether it
+    /// aborted.
+    fn check(
+        &self,
+        code: &ContractCode,
+        function: &AbiFunction,
+        input: &[u8],
+    ) -> Result<bool, InvariantViolation> {
+        let mut first = None;
+        for _ in 0..self.options.repeats.max(1) {
+            let (outcome, ext) = self.call(code, function, input)?;
+            let limit = self
+                .options
+                .gas_budget
+                .map_or(self.options.prepaid_gas, |budget| budget.min(self.options.prepaid_gas));
+            if outcome.burnt_gas > limit {
+                return Err(InvariantViolation::GasExceeded { burnt: outcome.burnt_gas, limit });
+            }
+            if outcome.burnt_gas > outcome.used_gas {
+                return Err(InvariantViolation::GasAccounting {
+                    burnt: outcome.burnt_gas,
+                    used: outcome.used_gas,
+                });
+            }
+            let observed = observation(&outcome, &ext);
+            match &first {
+                None => first = Some((outcome.aborted.is_some(), observed)),
+                Some((_, first)) if *first != observed => {
+                    return Err(InvariantViolation::Nondeterministic {
+                        first: first.clone(),
+                        repeat: observed,
+                    });
+                }
+                Some(_) => {}
+            }
+        }
+        Ok(first.map_or(false, |(aborted, _)| aborted))
+    }
+
+    fn call(
+        &self,
+        code: &ContractCode,
+        function: &AbiFunction,
+        input: &[u8],
+    ) -> Result<(VMOutcome, MockedExternal), InvariantViolation> {
+        let mut context = match function.kind {
+            AbiFunctionKind::View => get_view_context(self.options.prepaid_gas),
+            AbiFunctionKind::Call => {
+                let mut context = get_context();
+                context.prepaid_gas = self.options.prepaid_gas;
+                context
+            }
+        };
+        context.input = input.to_vec();
+        let mut ext = MockedExternal::new();
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+            self.runtime.run(
+                code,
+                &function.name,
+                &mut ext,
+                context,
+                &self.fees,
+                &[],
+                Some(&self.cache),
+            )
+        }));
+        match result {
+            Ok(Ok(outcome)) => Ok((outcome, ext)),
+            Ok(Err(err)) => Err(InvariantViolation::RunnerError(err.to_string())),
+            Err(panic) => {
+                let message = panic
+                    .downcast_ref::<&str>()
+                    .map(|msg| msg.to_string())
+                    .or_else(|| panic.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "<non-string panic>".to_string());
+                Err(InvariantViolation::HostPanic(message))
+            }
+        }
+    }
+}
+
+/// Everything a call can affect, to compare repeated calls.
+fn observation(outcome: &VMOutcome, ext: &MockedExternal) -> String {
+    let mut storage: Vec<_> = ext.fake_trie.iter().collect();
+    storage.sort();
+    format!("{outcome:?} storage {storage:?} actions {:?}", ext.action_log)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abi::tests::with_custom_section;
+    use crate::abi::ABI_SECTION;
+    use crate::tests::{test_vm_config, with_vm_variants};
+
+    /// `echo` returns its input, `grow` loops as many times as the first byte
+    /// of its input.
+    const CONTRACT: &str = r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (memory 1)
+  (func (export "echo")
+    (call $input (i64.const 0))
+    (call $read_register (i64.const 0) (i64.const 0))
+    (call $value_return (call $register_len (i64.const 0)) (i64.const 0)))
+  (func (export "grow") (local $n i32)
+    (call $input (i64.const 0))
+    (call $read_register (i64.const 0) (i64.const 0))
+    (local.set $n (i32.load8_u (i32.const 0)))
+    (loop $l
+      (local.set $n (i32.sub (local.get $n) (i32.const 1)))
+      (br_if $l (i32.gt_s (local.get $n) (i32.const 0)))))
+)"#;
+
+    const ABI: &str = r#"{"functions": [
+        {"name": "echo", "kind": "view", "args": [{"name": "text", "type": "string"}]},
+        {"name": "grow", "kind": "call", "serialization": "borsh",
+         "args": [{"name": "n", "type": "u8"}]}
+    ]}"#;
+
+    #[test]
+    fn test_fuzz_abi() {
+        let code = wat::parse_str(CONTRACT).unwrap();
+        let code = with_custom_section(code, ABI_SECTION, ABI.as_bytes());
+        let abi = ContractAbi::from_code(&code).unwrap().unwrap();
+        let code = ContractCode::new(code, None);
+        let config = test_vm_config();
+        with_vm_variants(&config, |vm_kind| {
+            let mut config = config.clone();
+            config.vm_kind = vm_kind;
+            let options = AbiFuzzOptions { runs: 20, ..AbiFuzzOptions::default() };
+            let fuzzer =
+                AbiFuzzer::new(config.clone(), RuntimeFeesConfig::test(), options.clone()).unwrap();
+            let reports = fuzzer.fuzz(&code, &abi);
+            assert_eq!(reports.len(), 2);
+            for report in &reports {
+                assert_eq!(report.runs, 20);
+                assert_eq!(report.aborted, 0, "{vm_kind:?} {report:?}");
+                assert_eq!(report.failures, [], "{vm_kind:?}");
+            }
+
+            // Looping over large inputs exceeds a tiny budget.
+            let options = AbiFuzzOptions { gas_budget: Some(1), ..options };
+            let fuzzer = AbiFuzzer::new(config, RuntimeFeesConfig::test(), options).unwrap();
+            let report = fuzzer.fuzz_function(&code, abi.function("grow").unwrap());
+            assert!(!report.failures.is_empty());
+            assert!(report.failures.iter().all(|failure| matches!(
+                failure.violation,
+                InvariantViolation::GasExceeded { limit: 1, .. }
+            )));
+        });
+    }
+}
@@ -0,0 +1,375 @@
+//! Admission control of view calls based on their recent cost.
+//!
+//! A view call server serving a method which suddenly takes much longer, for
+//! example because the state it reads grew, spends its threads on that method
+//! and answers the others late, or only when their deadline passes.  An
+//! [`AdmissionController`] accumulates the wall time of the calls of each
+//! method of each contract in [`CallStats`], in the histograms of the
+//! metrics of the runner, and once the 95th percentile of the recent calls of
+//! a method exceeds the threshold of its [`AdmissionPolicy`], tells to reject
+//! or to deprioritize the next calls of the method with an [`Overloaded`]
+//! error saying why.
+//!
+//! The calls are forgotten once older than the window of the policy, a
+//! quarter of a window at a time, so a method whose calls are rejected is
+//! admitted again after a window without calls, and the calls then made tell
+//! whether it is still slow.  The windows slide on the [`Clock`] of the
+//! controller.  The controller keeps the stats of the `max_methods` methods
+//! called last, so that callers choosing the names of the methods cannot
+//! make it grow without bounds.
+//!
+//! Admission only decides which calls are made: the outcome of a call never
+//! depends on it.
+
+use crate::clock::Clock;
+use crate::metrics::Histogram;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use unc_primitives_core::hash::CryptoHash;
+
+/// What to do with the calls of a method over the threshold of its policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OverloadAction {
+    /// Fail the calls with [`Overloaded`].
+    Reject,
+    /// Make the calls, after the other ones if the server has a queue.
+    Deprioritize,
+}
+
+/// When the calls of a method are overloaded, see the module documentation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AdmissionPolicy {
+    /// Calls are overloaded when the 95th percentile of the wall time of the
+    /// recent calls of their method exceeds this, see [`CallStats::p95`].
+    pub p95_threshold: Duration,
+    /// How long the wall time of a call is remembered.
+    pub window: Duration,
+    /// Number of calls in the window needed to rate a method, the methods
+    /// with fewer calls are always admitted.
+    pub min_samples: usize,
+    /// Number of methods whose calls are remembered, those called longest
+    /// ago being forgotten first.
+    pub max_methods: usize,
+    pub action: OverloadAction,
+}
+
+impl Default for AdmissionPolicy {
+    fn default() -> Self {
+        Self {
+            p95_threshold: Duration::from_millis(100),
+            window: Duration::from_secs(60),
+            min_samples: 20,
+            max_methods: 10_000,
+            action: OverloadAction::Reject,
+        }
+    }
+}
+
+/// Calls to a method refused by an [`AdmissionController`].
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error(
+    "calls to {method_name} of contract {code_hash} are overloaded: the 95th percentile of their \
+     wall time is {p95:?}, over the threshold of {threshold:?}"
+)]
+pub struct Overloaded {
+    pub code_hash: CryptoHash,
+    pub method_name: String,
+    pub p95: Duration,
+    pub threshold: Duration,
+}
+
+/// Decision of an [`AdmissionController`] on a call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Admission {
+    Admit,
+    Deprioritize(Overloaded),
+    Reject(Overloaded),
+}
+
+/// The calls of a window are counted in this many histograms, each of the
+/// calls of a part of the window.
+const SLOTS: u32 = 4;
+
+/// Wall times of the recent calls of a method of a contract.
+#[derive(Clone, Debug, Default)]
+pub struct CallStats {
+    /// The calls of each part of the window, with when the first of them
+    /// ended, oldest first.
+    slots: VecDeque<(Instant, Histogram)>,
+}
+
+impl CallStats {
+    /// Accounts a call which ended at `now` after `wall_time`.
+    pub fn record(&mut self, now: Instant, wall_time: Duration, policy: &AdmissionPolicy) {
+        self.forget(now, policy);
+        let slot_len = policy.window / SLOTS;
+        match self.slots.back() {
+            Some((start, _)) if now.saturating_duration_since(*start) <= slot_len => {}
+            _ => self.slots.push_back((now, Histogram::new())),
+        }
+        self.slots.back().unwrap().1.record(wall_time);
+    }
+
+    /// Number of calls in the window of `policy`.
+    pub fn len(&self) -> usize {
+        self.slots.iter().map(|(_, histogram)| histogram.count() as usize).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// 95th percentile of the wall time of the calls in the window, using the
+    /// nearest-rank method, rounded up to the bound of its bucket in the
+    /// histograms unless the longest call is shorter.
+    pub fn p95(&self) -> Option<Duration> {
+        Histogram::percentile(self.slots.iter().map(|(_, histogram)| histogram), 95)
+    }
+
+    fn forget(&mut self, now: Instant, policy: &AdmissionPolicy) {
+        while let Some((start, _)) = self.slots.front() {
+            if now.saturating_duration_since(*start) <= policy.window {
+                break;
+            }
+            self.slots.pop_front();
+        }
+    }
+}
+
+type MethodKey = (CryptoHash, String);
+
+/// The stats of the methods called last, see [`AdmissionPolicy::max_methods`].
+#[derive(Debug, Default)]
+struct Methods {
+    stats: HashMap<MethodKey, (CallStats, u64)>,
+    by_use: BTreeMap<u64, MethodKey>,
+    clock: u64,
+}
+
+impl Methods {
+    fn remove(&mut self, key: &MethodKey) -> Option<CallStats> {
+        let (stats, last_use) = self.stats.remove(key)?;
+        self.by_use.remove(&last_use);
+        Some(stats)
+    }
+
+    /// Keeps `stats` as those of the method called last, forgetting those of
+    /// the methods called longest ago beyond `max_methods`.
+    fn insert(&mut self, key: MethodKey, stats: CallStats, max_methods: usize) {
+        while self.stats.len() >= max_methods {
+            let Some((_, oldest)) = self.by_use.pop_first() else { break };
+            self.stats.remove(&oldest);
+        }
+        self.clock += 1;
+        self.by_use.insert(self.clock, key.clone());
+        self.stats.insert(key, (stats, self.clock));
+    }
+}
+
+/// Decides which view calls to make from the recent cost of their method,
+/// see the module documentation.
+///
+/// Shared by all the threads of a server.
+#[derive(Debug)]
+pub struct AdmissionController {
+    policy: AdmissionPolicy,
+    methods: Mutex<Methods>,
+    /// The system clock if `None`.
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl AdmissionController {
+    pub fn new(policy: AdmissionPolicy) -> Self {
+        Self { policy, methods: Default::default(), clock: None }
+    }
+
+    /// Makes the windows of the calls slide on `clock` instead of the system
+    /// clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn policy(&self) -> &AdmissionPolicy {
+        &self.policy
+    }
+
+    /// Decides whether to call `method_name` of contract `code_hash` now.
+    pub fn admit(&self, code_hash: &CryptoHash, method_name: &str) -> Admission {
+        self.admit_at(self.now(), code_hash, method_name)
+    }
+
+    /// Accounts a call to `method_name` of contract `code_hash` which took
+    /// `wall_time`, and ended now.
+    pub fn record(&self, code_hash: &CryptoHash, method_name: &str, wall_time: Duration) {
+        self.record_at(self.now(), code_hash, method_name, wall_time)
+    }
+
+    /// Wall times of the recent calls to `method_name` of contract
+    /// `code_hash`, if it has been called.
+    pub fn stats(&self, code_hash: &CryptoHash, method_name: &str) -> Option<CallStats> {
+        let key = (*code_hash, method_name.to_string());
+        self.methods.lock().unwrap().stats.get(&key).map(|(stats, _)| stats.clone())
+    }
+
+    fn now(&self) -> Instant {
+        self.clock.as_ref().map_or_else(Instant::now, |clock| clock.now())
+    }
+
+    fn admit_at(&self, now: Instant, code_hash: &CryptoHash, method_name: &str) -> Admission {
+        let key = (*code_hash, method_name.to_string());
+        let mut methods = self.methods.lock().unwrap();
+        let Some((method_stats, _)) = methods.stats.get_mut(&key) else {
+            return Admission::Admit;
+        };
+        method_stats.forget(now, &self.policy);
+        let p95 = match method_stats.p95() {
+            Some(p95) if method_stats.len() >= self.policy.min_samples => p95,
+            _ => return Admission::Admit,
+        };
+        if p95 <= self.policy.p95_threshold {
+            return Admission::Admit;
+        }
+        let overloaded = Overloaded {
+            code_hash: *code_hash,
+            method_name: method_name.to_string(),
+            p95,
+            threshold: self.policy.p95_threshold,
+        };
+        match self.policy.action {
+            OverloadAction::Reject => Admission::Reject(overloaded),
+            OverloadAction::Deprioritize => Admission::Deprioritize(overloaded),
+        }
+    }
+
+    fn record_at(
+        &self,
+        now: Instant,
+        code_hash: &CryptoHash,
+        method_name: &str,
+        wall_time: Duration,
+    ) {
+        if self.policy.max_methods == 0 {
+            return;
+        }
+        let key = (*code_hash, method_name.to_string());
+        let mut methods = self.methods.lock().unwrap();
+        let mut stats = methods.remove(&key).unwrap_or_default();
+        stats.record(now, wall_time, &self.policy);
+        methods.insert(key, stats, self.policy.max_methods);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(action: OverloadAction) -> AdmissionPolicy {
+        AdmissionPolicy {
+            p95_threshold: Duration::from_millis(10),
+            window: Duration::from_secs(10),
+            min_samples: 20,
+            max_methods: 100,
+            action,
+        }
+    }
+
+    #[test]
+    fn test_p95() {
+        let policy = policy(OverloadAction::Reject);
+        let now = Instant::now();
+        let mut stats = CallStats::default();
+        assert_eq!(stats.p95(), None);
+        for ms in 1..=100 {
+            stats.record(now, Duration::from_millis(ms), &policy);
+        }
+        assert_eq!(stats.len(), 100);
+        // 95 ms is in the bucket of the calls of 50 ms to 100 ms.
+        assert_eq!(stats.p95(), Some(Duration::from_millis(100)));
+        let mut short = CallStats::default();
+        short.record(now, Duration::from_millis(60), &policy);
+        assert_eq!(short.p95(), Some(Duration::from_millis(60)));
+
+        // The calls are forgotten a quarter of a window at a time.
+        let later = now + Duration::from_secs(5);
+        stats.record(later, Duration::from_secs(10), &policy);
+        assert_eq!(stats.len(), 101);
+        stats.forget(now + Duration::from_secs(12), &policy);
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats.p95(), Some(Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn test_max_methods() {
+        let hash = CryptoHash::hash_bytes(b"contract");
+        let controller = AdmissionController::new(AdmissionPolicy {
+            max_methods: 2,
+            ..policy(OverloadAction::Reject)
+        });
+        let now = Instant::now();
+        let wall_time = Duration::from_millis(1);
+        controller.record_at(now, &hash, "a", wall_time);
+        controller.record_at(now, &hash, "b", wall_time);
+        controller.record_at(now, &hash, "a", wall_time);
+        controller.record_at(now, &hash, "c", wall_time);
+        assert_eq!(controller.methods.lock().unwrap().stats.len(), 2);
+        assert_eq!(controller.stats(&hash, "a").unwrap().len(), 2);
+        assert!(controller.stats(&hash, "b").is_none());
+        assert_eq!(controller.stats(&hash, "c").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_admission() {
+        let hash = CryptoHash::hash_bytes(b"contract");
+        let slow = Duration::from_millis(50);
+        let fast = Duration::from_millis(1);
+        for action in [OverloadAction::Reject, OverloadAction::Deprioritize] {
+            let controller = AdmissionController::new(policy(action));
+            let start = Instant::now();
+            assert_eq!(controller.admit_at(start, &hash, "slow"), Admission::Admit);
+            // Too few samples to rate the method.
+            for _ in 0..19 {
+                controller.record_at(start, &hash, "slow", slow);
+                controller.record_at(start, &hash, "fast", fast);
+            }
+            assert_eq!(controller.admit_at(start, &hash, "slow"), Admission::Admit);
+            controller.record_at(start, &hash, "slow", slow);
+            controller.record_at(start, &hash, "fast", fast);
+
+            let overloaded = Overloaded {
+                code_hash: hash,
+                method_name: "slow".to_string(),
+                p95: slow,
+                threshold: Duration::from_millis(10),
+            };
+            let want = match action {
+                OverloadAction::Reject => Admission::Reject(overloaded),
+                OverloadAction::Deprioritize => Admission::Deprioritize(overloaded),
+            };
+            assert_eq!(controller.admit_at(start, &hash, "slow"), want);
+            assert_eq!(controller.admit_at(start, &hash, "fast"), Admission::Admit);
+            let other = CryptoHash::hash_bytes(b"other");
+            assert_eq!(controller.admit_at(start, &other, "slow"), Admission::Admit);
+
+            // The method is admitted again once its calls are forgotten.
+            let later = start + Duration::from_secs(11);
+            assert_eq!(controller.admit_at(later, &hash, "slow"), Admission::Admit);
+        }
+    }
+    #[test]
+    fn test_admission_clock() {
+        let hash = CryptoHash::hash_bytes(b"contract");
+        let clock = Arc::new(crate::VirtualClock::new());
+        let controller =
+            AdmissionController::new(policy(OverloadAction::Reject)).with_clock(clock.clone());
+        for _ in 0..20 {
+            controller.record(&hash, "slow", Duration::from_millis(50));
+        }
+        assert!(matches!(controller.admit(&hash, "slow"), Admission::Reject(_)));
+        clock.advance(Duration::from_secs(9));
+        assert!(matches!(controller.admit(&hash, "slow"), Admission::Reject(_)));
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(controller.admit(&hash, "slow"), Admission::Admit);
+    }
+}
@@ -0,0 +1,268 @@
+//! Inspecting a contract without compiling it.
+//!
+//! Explorers and deploy tooling want to know whether a contract will run
+//! before broadcasting the deploy action.  [`analyze_contract`] runs the
+//! checks of the preparation of the config on the contract and reports what
+//! it exports and imports, the limits of its memory and tables, the wasm
+//! features it needs which the config does not accept, and an estimate of
+//! the size of its compiled artifact.  Nothing is compiled, so the analysis
+//! is much cheaper than [`crate::precompile_contract`], but a contract
+//! passing it can still fail to compile on the rare limits only the
+//! compilers check.
+
+use crate::logic::errors::PrepareError;
+use crate::logic::host_functions::HOST_FUNCTIONS;
+use crate::logic::Config;
+use crate::method_name::{exported_methods, ExportedMethod};
+use finite_wasm::wasmparser as wp;
+
+/// What [`analyze_contract`] found out about a contract.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ContractAnalysis {
+    /// Outcome of the checks of the preparation, the error a call of the
+    /// contract would fail with if it does not pass them.
+    pub validation: Result<(), PrepareError>,
+    /// The functions exported by the contract, empty if it fails to parse.
+    pub methods: Vec<ExportedMethod>,
+    /// The functions imported by the contract, in the order of its import
+    /// section.
+    pub imports: Vec<ImportedFunction>,
+    /// The memory the contract declares or imports.  Whatever it declares,
+    /// the contract runs with the standardized memory of the config.
+    pub memory: Option<Limits>,
+    /// The tables the contract declares, in elements.
+    pub tables: Vec<Limits>,
+    /// The wasm features the contract uses which the config does not accept.
+    pub disallowed_features: Vec<WasmFeature>,
+    /// Rough size of the compiled artifact in bytes, to budget the cache.
+    pub estimated_compiled_size: u64,
+}
+
+/// A function imported by a contract.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImportedFunction {
+    pub module: String,
+    pub name: String,
+    /// Whether the config provides a host function of that name.  Calls of
+    /// contracts importing unknown functions fail to link.
+    pub available: bool,
+}
+
+/// Initial and maximum sizes of a memory or table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Limits {
+    pub initial: u64,
+    pub maximum: Option<u64>,
+}
+
+/// A wasm proposal a contract may use.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum WasmFeature {
+    SaturatingFloatToInt,
+    SignExtension,
+    ReferenceTypes,
+    MultiValue,
+    BulkMemory,
+    Simd,
+    RelaxedSimd,
+    Threads,
+    TailCall,
+    MultiMemory,
+    Exceptions,
+    Memory64,
+    ExtendedConst,
+}
+
+impl WasmFeature {
+    const ALL: [WasmFeature; 13] = [
+        WasmFeature::SaturatingFloatToInt,
+        WasmFeature::SignExtension,
+        WasmFeature::ReferenceTypes,
+        WasmFeature::MultiValue,
+        WasmFeature::BulkMemory,
+        WasmFeature::Simd,
+        WasmFeature::RelaxedSimd,
+        WasmFeature::Threads,
+        WasmFeature::TailCall,
+        WasmFeature::MultiMemory,
+        WasmFeature::Exceptions,
+        WasmFeature::Memory64,
+        WasmFeature::ExtendedConst,
+    ];
+
+    fn flag(self, features: &mut wp::WasmFeatures) -> &mut bool {
+        match self {
+            WasmFeature::SaturatingFloatToInt => &mut features.saturating_float_to_int,
+            WasmFeature::SignExtension => &mut features.sign_extension,
+            WasmFeature::ReferenceTypes => &mut features.reference_types,
+            WasmFeature::MultiValue => &mut features.multi_value,
+            WasmFeature::BulkMemory => &mut features.bulk_memory,
+            WasmFeature::Simd => &mut features.simd,
+            WasmFeature::RelaxedSimd => &mut features.relaxed_simd,
+            WasmFeature::Threads => &mut features.threads,
+            WasmFeature::TailCall => &mut features.tail_call,
+            WasmFeature::MultiMemory => &mut features.multi_memory,
+            WasmFeature::Exceptions => &mut features.exceptions,
+            WasmFeature::Memory64 => &mut features.memory64,
+            WasmFeature::ExtendedConst => &mut features.extended_const,
+        }
+    }
+}
+
+/// Compiled bytes per byte of function bodies, and per function, of the
+/// single-pass compilers, with their usual instrumentation.  Their output is
+/// several times larger than the wasm but grows linearly with it.
+const COMPILED_BYTES_PER_CODE_BYTE: u64 = 8;
+const COMPILED_BYTES_PER_FUNCTION: u64 = 64;
+
+/// Analyzes `code` as a contract running with `config`, see the module
+/// documentation.
+pub fn analyze_contract(code: &[u8], config: &Config) -> ContractAnalysis {
+    let mut analysis = ContractAnalysis {
+        validation: crate::prepare::validate_contract(code, config),
+        methods: exported_methods(code, config).unwrap_or_default(),
+        imports: Vec::new(),
+        memory: None,
+        tables: Vec::new(),
+        disallowed_features: disallowed_features(code, config),
+        estimated_compiled_size: 0,
+    };
+    let mut code_bytes = 0;
+    let mut data_bytes = 0;
+    let mut functions = 0;
+    for payload in wp::Parser::new(0).parse_all(code) {
+        // The validation reports the malformed contracts, keep what was
+        // parsed until then.
+        let Ok(payload) = payload else { break };
+        match payload {
+            wp::Payload::ImportSection(reader) => {
+                for import in reader.into_iter().flatten() {
+                    match import.ty {
+                        wp::TypeRef::Func(_) => analysis.imports.push(ImportedFunction {
+                            module: import.module.to_string(),
+                            name: import.name.to_string(),
+                            available: import.module != "internal"
+                                && HOST_FUNCTIONS.is_available(config, import.module, import.name),
+                        }),
+                        wp::TypeRef::Memory(ty) => {
+                            analysis.memory =
+                                Some(Limits { initial: ty.initial, maximum: ty.maximum })
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            wp::Payload::MemorySection(reader) => {
+                for ty in reader.into_iter().flatten() {
+                    analysis.memory = Some(Limits { initial: ty.initial, maximum: ty.maximum });
+                }
+            }
+            wp::Payload::TableSection(reader) => {
+                for table in reader.into_iter().flatten() {
+                    analysis.tables.push(Limits {
+                        initial: u64::from(table.ty.initial),
+                        maximum: table.ty.maximum.map(u64::from),
+                    });
+                }
+            }
+            wp::Payload::CodeSectionStart { count, size, .. } => {
+                functions = u64::from(count);
+                code_bytes = u64::from(size);
+            }
+            wp::Payload::DataSection(reader) => data_bytes = reader.range().len() as u64,
+            _ => {}
+        }
+    }
+    // The artifact also holds the data segments to initialize the memory.
+    analysis.estimated_compiled_size = code_bytes
+        .saturating_mul(COMPILED_BYTES_PER_CODE_BYTE)
+        .saturating_add(functions.saturating_mul(COMPILED_BYTES_PER_FUNCTION))
+        .saturating_add(data_bytes);
+    analysis
+}
+
+/// The features `code` needs which the config does not accept.
+///
+/// A feature is needed when the contract, valid with all the features, is
+/// not without that one.  Malformed contracts need none.
+fn disallowed_features(code: &[u8], config: &Config) -> Vec<WasmFeature> {
+    let mut accepted: wp::WasmFeatures = crate::features::WasmFeatures::from(config).into();
+    let valid = |features: wp::WasmFeatures| {
+        wp::Validator::new_with_features(features).validate_all(code).is_ok()
+    };
+    if valid(accepted) {
+        return Vec::new();
+    }
+    let mut all = accepted;
+    for feature in WasmFeature::ALL {
+        *feature.flag(&mut all) = true;
+    }
+    if !valid(all) {
+        return Vec::new();
+    }
+    WasmFeature::ALL
+        .into_iter()
+        .filter(|feature| !*feature.flag(&mut accepted))
+        .filter(|feature| {
+            let mut without = all;
+            *feature.flag(&mut without) = false;
+            !valid(without)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_vm_config;
+
+    #[test]
+    fn test_analyze_contract() {
+        let code = wat::parse_str(
+            r#"(module
+  (import "env" "input" (func (param i64)))
+  (import "env" "no_such_function" (func))
+  (memory 1 2)
+  (table 3 funcref)
+  (data (i32.const 0) "abc")
+  (func (export "main"))
+  (func (export "with_params") (param i32)))"#,
+        )
+        .unwrap();
+        let config = test_vm_config();
+        let analysis = analyze_contract(&code, &config);
+        assert_eq!(analysis.validation, Ok(()));
+        let methods: Vec<_> = analysis.methods.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(methods, ["main", "with_params"]);
+        assert!(analysis.methods[1].callable.is_err());
+        let imports: Vec<_> =
+            analysis.imports.iter().map(|i| (i.name.as_str(), i.available)).collect();
+        assert_eq!(imports, [("input", true), ("no_such_function", false)]);
+        assert_eq!(analysis.memory, Some(Limits { initial: 1, maximum: Some(2) }));
+        assert_eq!(analysis.tables, [Limits { initial: 3, maximum: None }]);
+        assert_eq!(analysis.disallowed_features, []);
+        assert!(analysis.estimated_compiled_size > 3);
+    }
+
+    #[test]
+    fn test_disallowed_features() {
+        let code = wat::parse_str(
+            r#"(module
+  (func (export "main") (result i32 i32)
+    (i32.trunc_sat_f32_s (f32.const 1))
+    (i32.const 0)))"#,
+        )
+        .unwrap();
+        let analysis = analyze_contract(&code, &test_vm_config());
+        assert_eq!(analysis.validation, Err(PrepareError::Deserialization));
+        assert_eq!(
+            analysis.disallowed_features,
+            [WasmFeature::SaturatingFloatToInt, WasmFeature::MultiValue]
+        );
+
+        let analysis = analyze_contract(b"\0asm\x01\0\0\0\x01", &test_vm_config());
+        assert_eq!(analysis.validation, Err(PrepareError::Deserialization));
+        assert_eq!(analysis.methods, []);
+        assert_eq!(analysis.disallowed_features, []);
+    }
+}
@@ -0,0 +1,107 @@
+//! The stable API of the crate.
+//!
+//! Everything re-exported here follows semver: it is only changed in a
+//! breaking way, renamed or removed in a new major version.  The rest of the
+//! crate, the other modules and the re-exports at its root, is an
+//! implementation detail which can change in any release, and is hidden from
+//! the documentation.  Downstream projects should import from this module
+//! only.  The C ABI of the `ffi` feature is stable on its own terms, see
+//! `ffi/unc_vm.h`.
+//!
+//! Enums of errors and outcomes may gain variants and fields through the
+//! versioned serialization formats, which only ever append to them.
+
+pub use crate::cache::{
+    get_contract_cache_key, precompile_contract, FilesystemContractRuntimeCache,
+    MockCompiledContractCache,
+};
+pub use crate::code::ContractCode;
+pub use crate::errors::ContractPrecompilatonResult;
+pub use crate::logic::errors::{
+    CacheError, CompilationError, FunctionCallError, HostError, MethodResolveError, PrepareError,
+    VMRunnerError, WasmTrap,
+};
+pub use crate::logic::host_functions::{host_functions, HostFnInfo, HostValType};
+pub use crate::logic::types::{ActionReceipt, PromiseResult, ReceiptAction, ReturnData};
+pub use crate::logic::{
+    CompiledContract, CompiledContractCache, Config, External, ExtraExtCostsConfig,
+    ExtraLimitConfig, ValuePtr, VMContext, VMOutcome,
+};
+pub use crate::profile::ProfileDataV3;
+pub use crate::runner::{run, run_with_options, HostSettings, HugePages, RunOptions, VMResult, VM};
+pub use unc_parameters::vm::VMKind;
+pub use unc_parameters::RuntimeFeesConfig;
+
+/// Pins the signatures of the stable functions and the stable fields of the
+/// types, so that breaking one of them fails the build of this crate instead
+/// of the builds of downstream projects.
+#[allow(dead_code)]
+fn api_signatures(options: RunOptions, context: VMContext, outcome: VMOutcome) {
+    use unc_parameters::RuntimeConfigStore;
+    use unc_primitives_core::hash::CryptoHash;
+    use unc_primitives_core::types::ProtocolVersion;
+
+    let _: fn(
+        &ContractCode,
+        &str,
+        &mut dyn External,
+        VMContext,
+        &Config,
+        &RuntimeFeesConfig,
+        &[PromiseResult],
+        Option<&dyn CompiledContractCache>,
+    ) -> VMResult = run;
+    let _: fn(
+        &ContractCode,
+        &str,
+        &mut dyn External,
+        VMContext,
+        &Config,
+        &RuntimeFeesConfig,
+        &[PromiseResult],
+        Option<&dyn CompiledContractCache>,
+        &RunOptions,
+    ) -> VMResult = run_with_options;
+    let _: fn(
+        &ContractCode,
+        &Config,
+        Option<&dyn CompiledContractCache>,
+    ) -> Result<Result<ContractPrecompilatonResult, CompilationError>, CacheError> =
+        precompile_contract;
+    let _: fn(&ContractCode, &Config) -> CryptoHash = get_contract_cache_key;
+    let _: fn(Vec<u8>, Option<CryptoHash>) -> ContractCode = ContractCode::new;
+    let _: fn(ProtocolVersion, &RuntimeConfigStore) -> Vec<HostFnInfo> = host_functions;
+    let _: fn(unc_parameters::vm::Config) -> Config = Config::from;
+    let RunOptions { deadline: _, record_checkpoints: _, host: _, .. } = options;
+    let HostSettings { huge_pages: _, hardening: _, memory_pool_size: _ } = HostSettings::default();
+    let VMContext {
+        current_account_id: _,
+        signer_account_id: _,
+        signer_account_pk: _,
+        predecessor_account_id: _,
+        input: _,
+        block_height: _,
+        block_timestamp: _,
+        epoch_height: _,
+        account_balance: _,
+        account_locked_balance: _,
+        storage_usage: _,
+        attached_deposit: _,
+        prepaid_gas: _,
+        random_seed: _,
+        view_config: _,
+        output_data_receivers: _,
+    } = context;
+    let VMOutcome {
+        balance: _,
+        storage_usage: _,
+        return_data: _,
+        burnt_gas: _,
+        used_gas: _,
+        compute_usage: _,
+        logs: _,
+        profile: _,
+        aborted: _,
+        ..
+    } = outcome;
+}
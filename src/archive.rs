@@ -0,0 +1,396 @@
+//! Compact encoding of outcomes, for indexers archiving them.
+//!
+//! Most of the borsh serialization of an outcome is its profile, a `u64` for
+//! every cost even though a call only pays a few of them, and its logs, which
+//! often repeat the same prefix.  [`encode_archived_outcome`] encodes an
+//! outcome with only the costs which are not zero, and each log as the length
+//! of the prefix it shares with the log before it followed by the rest of it.
+//! [`decode_archived_outcome`] decodes it back into the same outcome.
+//!
+//! An archived outcome of version 1 is, in order:
+//!
+//! - the version of the format, a byte;
+//! - the borsh serialization of [`HeadV1`], the fields of the outcome before
+//!   its logs, prefixed by its length;
+//! - the number of logs, then for each log the length of the prefix it shares
+//!   with the log before it, the length of the rest and the rest;
+//! - the borsh serialization of the receipts, prefixed by its length;
+//! - the costs of the actions then those of the host functions, indexed as in
+//!   the borsh serialization of the profile: the number of costs, the number
+//!   of costs which are not zero, then for each of those the distance from the
+//!   index of the previous one and the gas, followed by the gas of the wasm
+//!   code;
+//! - the borsh serialization of [`TailV1`], the fields after the profile.
+//!
+//! Numbers outside of borsh serializations are LEB128 integers.
+//!
+//! The layouts of the fields of each version are structs of their own rather
+//! than that of [`VMOutcome`], so that adding a field to the outcome keeps
+//! the archives of earlier versions decodable: the field gets a new version
+//! of the format, whose decoder is added next to those of the earlier ones,
+//! which leave the field empty.  The fields themselves are in their borsh
+//! format, so changing that of their types, as adding a field to an action
+//! receipt, also takes a new version.
+
+use crate::logic::errors::FunctionCallError;
+use crate::logic::{
+    ContractBacktrace, GasProfile, HostCallCheckpoint, HostCallRecord, ReceiptCost, ReturnData,
+    StateWitness, StorageUsageDelta, UsedMemory, VMOutcome, WasmFrame,
+};
+use crate::ProfileDataV3;
+use borsh::{BorshDeserialize, BorshSerialize};
+use unc_primitives_core::hash::CryptoHash;
+use unc_primitives_core::types::{Balance, Compute, Gas, StorageUsage};
+
+/// Version of the format written by [`encode_archived_outcome`].
+pub const ARCHIVE_FORMAT_VERSION: u8 = 1;
+
+/// More costs than any profile has, bounding what forged archives allocate.
+const MAX_COSTS: u64 = 1 << 12;
+
+/// More than the logs a call can write, bounding what forged archives
+/// allocate by repeating long prefixes.
+const MAX_LOGS_LEN: u64 = 1 << 26;
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("not an archived outcome of this format version, or truncated")]
+    Malformed,
+    #[error("archived outcome of format version {0}, expected {ARCHIVE_FORMAT_VERSION}")]
+    UnsupportedVersion(u8),
+}
+
+/// The fields of an outcome before its logs, in version 1.
+#[derive(BorshDeserialize)]
+struct HeadV1 {
+    balance: Balance,
+    storage_usage: StorageUsage,
+    storage_delta: StorageUsageDelta,
+    return_data: ReturnData,
+    burnt_gas: Gas,
+    used_gas: Gas,
+    promises_gas: Gas,
+    refunded_gas: Gas,
+    compute_usage: Compute,
+}
+
+/// The fields of an outcome after its profile, in version 1.
+#[derive(BorshDeserialize)]
+struct TailV1 {
+    gas_profile: Option<GasProfile>,
+    aborted: Option<FunctionCallError>,
+    gas_exhaustion_trace: Vec<WasmFrame>,
+    checkpoints: Vec<HostCallCheckpoint>,
+    host_calls: Vec<HostCallRecord>,
+    used_memory: UsedMemory,
+    backtrace: Option<ContractBacktrace>,
+    receipt_costs: Vec<ReceiptCost>,
+    state_witness: Option<StateWitness>,
+    execution_fingerprint: Option<CryptoHash>,
+}
+
+/// The borsh serialization of a [`ProfileDataV3`], which indexes its costs
+/// independently of the versions of this crate.
+#[derive(BorshSerialize, BorshDeserialize)]
+struct ProfileCosts {
+    actions: Vec<Gas>,
+    ext: Vec<Gas>,
+    wasm: Gas,
+}
+
+/// Encodes `outcome` in the format of the module documentation.
+pub fn encode_archived_outcome(outcome: &VMOutcome) -> Vec<u8> {
+    // Destructured so that new fields fail to compile until they get a
+    // version of the format.
+    let VMOutcome {
+        balance,
+        storage_usage,
+        storage_delta,
+        return_data,
+        burnt_gas,
+        used_gas,
+        promises_gas,
+        refunded_gas,
+        compute_usage,
+        logs,
+        receipts,
+        profile,
+        gas_profile,
+        aborted,
+        gas_exhaustion_trace,
+        checkpoints,
+        host_calls,
+        used_memory,
+        backtrace,
+        receipt_costs,
+        state_witness,
+        execution_fingerprint,
+    } = outcome;
+    // Serialized as `HeadV1` and `TailV1`, without cloning the fields.
+    let head = borsh::to_vec(&(
+        balance,
+        storage_usage,
+        storage_delta,
+        return_data,
+        burnt_gas,
+        used_gas,
+        promises_gas,
+        refunded_gas,
+        compute_usage,
+    ))
+    .expect("failed serializing an outcome");
+    let tail = borsh::to_vec(&(
+        gas_profile,
+        aborted,
+        gas_exhaustion_trace,
+        checkpoints,
+        host_calls,
+        used_memory,
+        backtrace,
+        receipt_costs,
+        state_witness,
+        execution_fingerprint,
+    ))
+    .expect("failed serializing an outcome");
+
+    let mut out = vec![ARCHIVE_FORMAT_VERSION];
+    write_bytes(&mut out, &head);
+    write_u64(&mut out, logs.len() as u64);
+    let mut previous: &[u8] = &[];
+    for log in logs {
+        let log = log.as_bytes();
+        let shared = previous.iter().zip(log).take_while(|(a, b)| a == b).count();
+        write_u64(&mut out, shared as u64);
+        write_bytes(&mut out, &log[shared..]);
+        previous = log;
+    }
+    write_bytes(&mut out, &borsh::to_vec(receipts).expect("failed serializing receipts"));
+    let profile = borsh::to_vec(profile).expect("failed serializing a profile");
+    let ProfileCosts { actions, ext, wasm } = borsh::from_slice(&profile).unwrap();
+    for costs in [actions, ext] {
+        write_u64(&mut out, costs.len() as u64);
+        write_u64(&mut out, costs.iter().filter(|gas| **gas != 0).count() as u64);
+        let mut next = 0;
+        for (index, gas) in costs.iter().enumerate().filter(|(_, gas)| **gas != 0) {
+            write_u64(&mut out, (index - next) as u64);
+            write_u64(&mut out, *gas);
+            next = index + 1;
+        }
+    }
+    write_u64(&mut out, wasm);
+    out.extend_from_slice(&tail);
+    out
+}
+
+/// Decodes an outcome encoded by [`encode_archived_outcome`], of any version
+/// up to [`ARCHIVE_FORMAT_VERSION`].
+pub fn decode_archived_outcome(bytes: &[u8]) -> Result<VMOutcome, ArchiveError> {
+    let (&version, mut input) = bytes.split_first().ok_or(ArchiveError::Malformed)?;
+    match version {
+        1 => decode_v1(&mut input),
+        _ => Err(ArchiveError::UnsupportedVersion(version)),
+    }
+}
+
+fn decode_v1(input: &mut &[u8]) -> Result<VMOutcome, ArchiveError> {
+    let HeadV1 {
+        balance,
+        storage_usage,
+        storage_delta,
+        return_data,
+        burnt_gas,
+        used_gas,
+        promises_gas,
+        refunded_gas,
+        compute_usage,
+    } = from_borsh(read_bytes(input)?)?;
+
+    let count = read_u64(input)?;
+    // Each log takes at least two bytes.
+    if count > input.len() as u64 / 2 {
+        return Err(ArchiveError::Malformed);
+    }
+    let mut logs = Vec::with_capacity(count as usize);
+    let mut log = Vec::new();
+    let mut logs_len = 0;
+    for _ in 0..count {
+        let shared = read_u64(input)?;
+        if shared > log.len() as u64 {
+            return Err(ArchiveError::Malformed);
+        }
+        log.truncate(shared as usize);
+        log.extend_from_slice(read_bytes(input)?);
+        logs_len += log.len() as u64;
+        if logs_len > MAX_LOGS_LEN {
+            return Err(ArchiveError::Malformed);
+        }
+        logs.push(String::from_utf8(log.clone()).map_err(|_| ArchiveError::Malformed)?);
+    }
+
+    let receipts = from_borsh(read_bytes(input)?)?;
+    let mut costs = [Vec::new(), Vec::new()];
+    for costs in &mut costs {
+        let len = read_u64(input)?;
+        let non_zero = read_u64(input)?;
+        if len > MAX_COSTS || non_zero > len {
+            return Err(ArchiveError::Malformed);
+        }
+        *costs = vec![0; len as usize];
+        let mut next = 0;
+        for _ in 0..non_zero {
+            let index = next + read_u64(input)?.min(MAX_COSTS);
+            *costs.get_mut(index as usize).ok_or(ArchiveError::Malformed)? = read_u64(input)?;
+            next = index + 1;
+        }
+    }
+    let [actions, ext] = costs;
+    let profile = ProfileCosts { actions, ext, wasm: read_u64(input)? };
+    let profile: ProfileDataV3 = from_borsh(&borsh::to_vec(&profile).unwrap())?;
+
+    let TailV1 {
+        gas_profile,
+        aborted,
+        gas_exhaustion_trace,
+        checkpoints,
+        host_calls,
+        used_memory,
+        backtrace,
+        receipt_costs,
+        state_witness,
+        execution_fingerprint,
+    } = from_borsh(input)?;
+    Ok(VMOutcome {
+        balance,
+        storage_usage,
+        storage_delta,
+        return_data,
+        burnt_gas,
+        used_gas,
+        promises_gas,
+        refunded_gas,
+        compute_usage,
+        logs,
+        receipts,
+        profile,
+        gas_profile,
+        aborted,
+        gas_exhaustion_trace,
+        checkpoints,
+        host_calls,
+        used_memory,
+        backtrace,
+        receipt_costs,
+        state_witness,
+        execution_fingerprint,
+    })
+}
+
+/// Deserializes the whole of `bytes`.
+fn from_borsh<T: BorshDeserialize>(bytes: &[u8]) -> Result<T, ArchiveError> {
+    T::try_from_slice(bytes).map_err(|_| ArchiveError::Malformed)
+}
+
+fn write_u64(out: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            out.push(byte);
+            return;
+        }
+        out.push(byte | 0x80);
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_u64(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+fn read_u64(input: &mut &[u8]) -> Result<u64, ArchiveError> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = input.split_first().ok_or(ArchiveError::Malformed)?;
+        *input = rest;
+        let bits = u64::from(byte & 0x7f);
+        if bits << shift >> shift != bits {
+            return Err(ArchiveError::Malformed);
+        }
+        value |= bits << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err(ArchiveError::Malformed)
+}
+
+fn read_bytes<'a>(input: &mut &'a [u8]) -> Result<&'a [u8], ArchiveError> {
+    let len = read_u64(input)?;
+    if len > input.len() as u64 {
+        return Err(ArchiveError::Malformed);
+    }
+    let (bytes, rest) = input.split_at(len as usize);
+    *input = rest;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::errors::FunctionCallError;
+    use crate::logic::ReturnData;
+    use unc_parameters::{ActionCosts, ExtCosts};
+
+    fn outcome() -> VMOutcome {
+        let mut outcome =
+            VMOutcome::nop_outcome(FunctionCallError::LinkError { msg: "missing".to_string() });
+        outcome.balance = 10u128.pow(24);
+        outcome.return_data = ReturnData::Value(b"ok".to_vec());
+        outcome.burnt_gas = 3_000_000;
+        outcome.used_gas = 5_000_000;
+        outcome.promises_gas = 2_000_000;
+        outcome.logs = vec![
+            r#"EVENT_JSON:{"standard":"nep141","event":"ft_transfer"}"#.to_string(),
+            r#"EVENT_JSON:{"standard":"nep141","event":"ft_burn"}"#.to_string(),
+            "é".to_string(),
+            "è".to_string(),
+            String::new(),
+        ];
+        outcome.profile.add_ext_cost(ExtCosts::base, 1_000);
+        outcome.profile.add_ext_cost(ExtCosts::log_byte, 2_000);
+        outcome.profile.add_action_cost(ActionCosts::transfer, 500);
+        outcome.profile.compute_wasm_instruction_cost(3_000_000);
+        outcome
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let outcome = outcome();
+        let archived = encode_archived_outcome(&outcome);
+        // The logs share a prefix and the profile has three costs.
+        let serialized = borsh::to_vec(&outcome).unwrap();
+        assert!(archived.len() * 2 < serialized.len(), "{} {}", archived.len(), serialized.len());
+        assert_eq!(decode_archived_outcome(&archived), Ok(outcome));
+
+        let nop = VMOutcome::nop_outcome(FunctionCallError::Timeout);
+        assert_eq!(decode_archived_outcome(&encode_archived_outcome(&nop)), Ok(nop));
+    }
+
+    #[test]
+    fn test_malformed() {
+        let archived = encode_archived_outcome(&outcome());
+        for len in 0..archived.len() {
+            assert_eq!(decode_archived_outcome(&archived[..len]), Err(ArchiveError::Malformed));
+        }
+        let mut other_version = archived.clone();
+        other_version[0] = 2;
+        assert_eq!(
+            decode_archived_outcome(&other_version),
+            Err(ArchiveError::UnsupportedVersion(2))
+        );
+        assert_eq!(
+            decode_archived_outcome(&[&archived[..], &[0]].concat()),
+            Err(ArchiveError::Malformed)
+        );
+    }
+}
@@ -0,0 +1,258 @@
+//! Exporting compiled contracts, to load them on other nodes.
+//!
+//! Operators compile contracts offline with [`export_artifact`] and ship the
+//! artifacts to their nodes, which put them in their caches with
+//! [`import_artifact`] instead of compiling the contracts themselves.  Only
+//! NearVM and Wasmer2, which cache native code, have artifacts.
+//!
+//! An artifact is the magic `UNCVMART`, the version of the format as a
+//! little endian `u32`, then the borsh serialization of an [`ArtifactHeader`]
+//! followed by the compiled code.  The header tells which contract, config and
+//! compiler the code is for, and an artifact is refused unless all of them
+//! match those of the importing node.  The sha256 of the code in the header
+//! catches artifacts corrupted on the way, but does not authenticate them:
+//! nodes run the native code of the artifacts they import, so they must only
+//! import artifacts from trusted sources.
+
+use crate::cache::{contract_cache_key_for_hash, vm_hash};
+use crate::fingerprint::ConfigFingerprint;
+use crate::logic::errors::{CacheError, CompilationError};
+use crate::logic::{CompiledContract, CompiledContractCache, Config};
+use crate::runner::CodegenTarget;
+use crate::{ContractCode, MockCompiledContractCache};
+use borsh::{BorshDeserialize, BorshSerialize};
+use unc_parameters::vm::VMKind;
+use unc_primitives_core::hash::{hash as sha256, CryptoHash};
+
+const MAGIC: &[u8; 8] = b"UNCVMART";
+
+/// Version of the format written by [`export_artifact`].
+pub const ARTIFACT_FORMAT_VERSION: u32 = 1;
+
+/// What the code of an artifact is for, see the module documentation.
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct ArtifactHeader {
+    /// Hash of the contract, as [`ContractCode::hash`].
+    pub code_hash: CryptoHash,
+    /// Fingerprint of the config, see [`ConfigFingerprint`].
+    pub config_fingerprint: CryptoHash,
+    /// Name of the VM, as in [`VMKind`].
+    pub vm_kind: String,
+    /// Hash of the version of the compiler of the VM.
+    pub vm_hash: u64,
+    /// Whether the code is for any host, see [`CodegenTarget::Baseline`].
+    pub baseline: bool,
+    /// sha256 of the compiled code.
+    pub payload_hash: CryptoHash,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArtifactError {
+    #[error("{0:?} does not have artifacts")]
+    UnsupportedVm(VMKind),
+    #[error("not an artifact of this format version, or truncated")]
+    Malformed,
+    #[error("artifact of format version {0}, expected {ARTIFACT_FORMAT_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("artifact of contract {found}, expected {expected}")]
+    CodeHashMismatch { expected: CryptoHash, found: CryptoHash },
+    #[error("artifact of another VM config")]
+    ConfigMismatch,
+    #[error("artifact of VM {found}, expected {expected:?}")]
+    VmKindMismatch { expected: VMKind, found: String },
+    #[error("artifact of another version of the compiler")]
+    CompilerMismatch,
+    #[error("the compiled code of the artifact does not match its hash")]
+    Corrupted,
+    #[error(transparent)]
+    Cache(#[from] CacheError),
+}
+
+/// Compiles `code` for `config` and `codegen`, and returns the artifact.
+///
+/// Contracts failing to compile have no artifact: the error is returned in
+/// the inner result, and nodes compiling them get it as well.
+///
+/// Panics if the VM of the config is not enabled or does not support
+/// `codegen`, as [`crate::precompile_contract_for_codegen`].
+pub fn export_artifact(
+    code: &ContractCode,
+    config: &Config,
+    codegen: CodegenTarget,
+) -> Result<Result<Vec<u8>, CompilationError>, ArtifactError> {
+    check_vm_kind(config.vm_kind)?;
+    let cache = MockCompiledContractCache::default();
+    if let Err(err) = crate::precompile_contract_for_codegen(code, config, codegen, Some(&cache))? {
+        return Ok(Err(err));
+    }
+    let key = contract_cache_key_for_hash(code.hash(), config, codegen);
+    let payload = match cache.get(&key).map_err(CacheError::ReadError)? {
+        Some(CompiledContract::Code(payload)) => payload,
+        Some(CompiledContract::CompileModuleError(err)) => return Ok(Err(err)),
+        None => unreachable!("precompiling caches the artifact"),
+    };
+    let header = ArtifactHeader {
+        code_hash: *code.hash(),
+        config_fingerprint: config.fingerprint(),
+        vm_kind: format!("{:?}", config.vm_kind),
+        vm_hash: vm_hash(config),
+        baseline: codegen == CodegenTarget::Baseline,
+        payload_hash: sha256(&payload),
+    };
+    let mut artifact = MAGIC.to_vec();
+    artifact.extend_from_slice(&ARTIFACT_FORMAT_VERSION.to_le_bytes());
+    header.serialize(&mut artifact).expect("writing to a vector cannot fail");
+    artifact.extend_from_slice(&payload);
+    Ok(Ok(artifact))
+}
+
+/// Reads the header of `artifact`, without checking it.
+pub fn read_artifact_header(artifact: &[u8]) -> Result<ArtifactHeader, ArtifactError> {
+    split_artifact(artifact).map(|(header, _)| header)
+}
+
+/// Checks that `artifact` is one of contract `code_hash` for `config`, and
+/// puts its code in `cache` for the calls of the contract to use.
+///
+/// Returns the cache key of the code.  Nothing is put in the cache unless
+/// every check passes.
+pub fn import_artifact(
+    artifact: &[u8],
+    code_hash: &CryptoHash,
+    config: &Config,
+    cache: &dyn CompiledContractCache,
+) -> Result<CryptoHash, ArtifactError> {
+    check_vm_kind(config.vm_kind)?;
+    let (header, payload) = split_artifact(artifact)?;
+    if header.code_hash != *code_hash {
+        return Err(ArtifactError::CodeHashMismatch {
+            expected: *code_hash,
+            found: header.code_hash,
+        });
+    }
+    if header.vm_kind != format!("{:?}", config.vm_kind) {
+        return Err(ArtifactError::VmKindMismatch {
+            expected: config.vm_kind,
+            found: header.vm_kind,
+        });
+    }
+    if header.config_fingerprint != config.fingerprint() {
+        return Err(ArtifactError::ConfigMismatch);
+    }
+    if header.vm_hash != vm_hash(config) {
+        return Err(ArtifactError::CompilerMismatch);
+    }
+    if header.payload_hash != sha256(payload) {
+        return Err(ArtifactError::Corrupted);
+    }
+    let codegen = if header.baseline { CodegenTarget::Baseline } else { CodegenTarget::Host };
+    let key = contract_cache_key_for_hash(code_hash, config, codegen);
+    let value = CompiledContract::Code(payload.to_vec());
+    cache.put(&key, value).map_err(CacheError::WriteError)?;
+    Ok(key)
+}
+
+fn check_vm_kind(vm_kind: VMKind) -> Result<(), ArtifactError> {
+    match vm_kind {
+        VMKind::NearVm | VMKind::Wasmer2 => Ok(()),
+        VMKind::Wasmer0 | VMKind::Wasmtime => Err(ArtifactError::UnsupportedVm(vm_kind)),
+    }
+}
+
+fn split_artifact(artifact: &[u8]) -> Result<(ArtifactHeader, &[u8]), ArtifactError> {
+    let rest = artifact.strip_prefix(MAGIC).ok_or(ArtifactError::Malformed)?;
+    let (version, mut rest) = rest.split_first_chunk::<4>().ok_or(ArtifactError::Malformed)?;
+    let version = u32::from_le_bytes(*version);
+    if version != ARTIFACT_FORMAT_VERSION {
+        return Err(ArtifactError::UnsupportedVersion(version));
+    }
+    let header = ArtifactHeader::deserialize(&mut rest).map_err(|_| ArtifactError::Malformed)?;
+    Ok((header, rest))
+}
+
+#[cfg(all(test, feature = "unc_vm", target_arch = "x86_64"))]
+mod tests {
+    use super::*;
+    use crate::runner::{VMKindExt, VM};
+    use crate::tests::test_vm_config;
+
+    fn contract() -> ContractCode {
+        ContractCode::new(wat::parse_str(r#"(module (func (export "main")))"#).unwrap(), None)
+    }
+
+    #[test]
+    fn test_artifact_roundtrip() {
+        let mut config = test_vm_config();
+        config.vm_kind = VMKind::NearVm;
+        let code = contract();
+        let artifact = export_artifact(&code, &config, CodegenTarget::Host).unwrap().unwrap();
+        let header = read_artifact_header(&artifact).unwrap();
+        assert_eq!(header.code_hash, *code.hash());
+        assert_eq!(header.vm_kind, "NearVm");
+        assert!(!header.baseline);
+
+        let cache = MockCompiledContractCache::default();
+        let key = import_artifact(&artifact, code.hash(), &config, &cache).unwrap();
+        assert_eq!(key, crate::get_contract_cache_key(&code, &config));
+        assert!(cache.has(&key).unwrap());
+
+        let outcome = config
+            .vm_kind
+            .runtime(config.clone())
+            .unwrap()
+            .run(
+                &code,
+                "main",
+                &mut crate::logic::mocks::mock_external::MockedExternal::new(),
+                crate::tests::create_context(vec![]),
+                &unc_parameters::RuntimeFeesConfig::test(),
+                &[],
+                Some(&cache),
+            )
+            .unwrap();
+        assert_eq!(outcome.aborted, None);
+    }
+
+    #[test]
+    fn test_artifact_mismatches() {
+        let mut config = test_vm_config();
+        config.vm_kind = VMKind::NearVm;
+        let code = contract();
+        let artifact = export_artifact(&code, &config, CodegenTarget::Host).unwrap().unwrap();
+        let cache = MockCompiledContractCache::default();
+        let import = |artifact: &[u8], code_hash: &CryptoHash, config: &Config| {
+            import_artifact(artifact, code_hash, config, &cache).unwrap_err()
+        };
+
+        let other_hash = CryptoHash::hash_bytes(b"other");
+        assert!(matches!(
+            import(&artifact, &other_hash, &config),
+            ArtifactError::CodeHashMismatch { found, .. } if found == *code.hash()
+        ));
+        let mut other_config = config.clone();
+        other_config.regular_op_cost += 1;
+        assert!(matches!(
+            import(&artifact, code.hash(), &other_config),
+            ArtifactError::ConfigMismatch
+        ));
+        let mut wasmtime = config.clone();
+        wasmtime.vm_kind = VMKind::Wasmtime;
+        assert!(matches!(
+            import(&artifact, code.hash(), &wasmtime),
+            ArtifactError::UnsupportedVm(VMKind::Wasmtime)
+        ));
+
+        let mut corrupted = artifact.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert!(matches!(import(&corrupted, code.hash(), &config), ArtifactError::Corrupted));
+        let mut newer = artifact.clone();
+        newer[8] = 2;
+        assert!(matches!(
+            import(&newer, code.hash(), &config),
+            ArtifactError::UnsupportedVersion(2)
+        ));
+        assert!(matches!(import(&artifact[..20], code.hash(), &config), ArtifactError::Malformed));
+        assert!(matches!(import(b"", code.hash(), &config), ArtifactError::Malformed));
+        assert_eq!(cache.len(), 0);
+    }
+}
@@ -0,0 +1,372 @@
+//! Symbolication of the wasm call stack of aborted calls.
+//!
+//! The backends report the frames of the prepared contract they run, see
+//! [`WasmFrame`].  The preparation adds the imports of the instrumentation
+//! and inserts its calls into the function bodies, so the frames are mapped
+//! back to the contract as deployed before they are resolved with its debug
+//! information: a prepared function is the original function of the same
+//! body, and an instruction of a prepared body is the original instruction of
+//! the same rank once the instructions inserted by the finite-wasm
+//! instrumentation are left out.  An inserted instruction stands for the
+//! original one it precedes, e.g. the gas charge for the block it meters.
+//!
+//! The bodies from which the inserted instructions cannot be told apart, as
+//! those of the pwasm instrumentation of the older prepare versions, get no
+//! lines.
+
+use crate::debug_info::{self, LineTable};
+use crate::logic::{BacktraceFrame, ContractBacktrace, WasmFrame};
+use crate::ContractCode;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use crate::logic::Config;
+use unc_primitives_core::hash::CryptoHash;
+
+/// Import module of the finite-wasm instrumentation, see
+/// `prepare_v2::prepare_contract`.
+const INSTRUMENTATION_MODULE: &str = "internal";
+
+/// Number of contracts whose [`Symbolicator`] is kept, the contracts which
+/// aborted last.
+const SYMBOLICATOR_CACHE_SIZE: usize = 16;
+
+/// The symbolicators of the contracts which aborted last, with the hash of
+/// their code and of the config, the most recent last, so that a contract
+/// trapping on every call is only prepared again once evicted.
+static SYMBOLICATORS: Mutex<VecDeque<((CryptoHash, u64), Arc<Symbolicator>)>> =
+    Mutex::new(VecDeque::new());
+
+/// Resolves `trace`, reported by a backend running `code` with `config`,
+/// against the names and the line information of `code`.
+pub(crate) fn symbolicate(
+    code: &ContractCode,
+    config: &Config,
+    trace: &[WasmFrame],
+) -> ContractBacktrace {
+    let symbolicator = symbolicator(code, config);
+    let frames = trace
+        .iter()
+        .take(ContractBacktrace::MAX_FRAMES)
+        .map(|frame| symbolicator.frame(frame))
+        .collect();
+    let omitted = trace.len().saturating_sub(ContractBacktrace::MAX_FRAMES);
+    ContractBacktrace { frames, omitted_frames: u32::try_from(omitted).unwrap_or(u32::MAX) }
+}
+
+/// The symbolicator of `code` with `config`, from [`SYMBOLICATORS`] when it
+/// has it.
+fn symbolicator(code: &ContractCode, config: &Config) -> Arc<Symbolicator> {
+    let key = (*code.hash(), config.non_crypto_hash());
+    let cached = {
+        let mut cache = SYMBOLICATORS.lock().unwrap();
+        let position = cache.iter().position(|(cached, _)| *cached == key);
+        position.and_then(|position| cache.remove(position)).map(|entry| {
+            let symbolicator = Arc::clone(&entry.1);
+            cache.push_back(entry);
+            symbolicator
+        })
+    };
+    if let Some(symbolicator) = cached {
+        return symbolicator;
+    }
+    // Made without the lock, calls aborting at the same time may both make
+    // it, and keep either.
+    let symbolicator = Arc::new(Symbolicator::new(code.code(), config));
+    let mut cache = SYMBOLICATORS.lock().unwrap();
+    if !cache.iter().any(|(cached, _)| *cached == key) {
+        if cache.len() == SYMBOLICATOR_CACHE_SIZE {
+            cache.pop_front();
+        }
+        cache.push_back((key, Arc::clone(&symbolicator)));
+    }
+    symbolicator
+}
+
+struct Symbolicator {
+    original: ModuleCode,
+    prepared: Option<ModuleCode>,
+    names: HashMap<u32, String>,
+    lines: Option<LineTable>,
+}
+
+impl Symbolicator {
+    fn new(code: &[u8], config: &Config) -> Self {
+        let lines = LineTable::from_code(code);
+        // The preparation is deterministic, so this is the code the backend
+        // ran.  Its frames are taken as they are without it.
+        let prepared = crate::prepare::prepare_contract(code, config, config.vm_kind)
+            .ok()
+            .and_then(|prepared| ModuleCode::parse(&prepared).ok());
+        Self {
+            original: ModuleCode::parse(code).unwrap_or_default(),
+            prepared,
+            names: debug_info::function_names(code),
+            lines,
+        }
+    }
+
+    fn frame(&self, frame: &WasmFrame) -> BacktraceFrame {
+        let body = self
+            .prepared
+            .as_ref()
+            .and_then(|prepared| frame.func_index.checked_sub(prepared.imported_functions));
+        let func_index = match body {
+            Some(body) => self.original.imported_functions + body,
+            None => frame.func_index,
+        };
+        let location = body.and_then(|body| self.location(body as usize, frame.module_offset?));
+        let function_name = match self.names.get(&func_index) {
+            Some(name) => Some(name.clone()),
+            None => frame.function_name.clone(),
+        };
+        BacktraceFrame {
+            func_index,
+            function_name: function_name.map(truncate),
+            file: location.map(|(file, _)| truncate(file.to_string())),
+            line: location.map(|(_, line)| line),
+        }
+    }
+
+    /// File and line of the original instruction of the instruction at
+    /// `module_offset` of the prepared body `body`.
+    fn location(&self, body: usize, module_offset: usize) -> Option<(&str, u64)> {
+        let lines = self.lines.as_ref()?;
+        let instructions = self.prepared.as_ref()?.bodies.get(body)?;
+        let instruction =
+            instructions.partition_point(|(offset, _)| *offset <= module_offset).checked_sub(1)?;
+        let original = self.original.bodies.get(body)?;
+        let ranks = self.prepared.as_ref()?.original_ranks(body, original.len())?;
+        let rank = ranks[instruction..].iter().flatten().next().copied()?;
+        let offset = original.get(rank)?.0 - self.original.code_start;
+        lines.location(offset as u64)
+    }
+}
+
+/// Cuts `name` at [`ContractBacktrace::MAX_NAME_LEN`] bytes.
+fn truncate(mut name: String) -> String {
+    if name.len() > ContractBacktrace::MAX_NAME_LEN {
+        let mut len = ContractBacktrace::MAX_NAME_LEN;
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        name.truncate(len);
+    }
+    name
+}
+
+/// The instructions of the instrumentation are told apart by these.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Instruction {
+    Block,
+    Call(u32),
+    Other,
+}
+
+/// The function bodies of a module.
+#[derive(Debug, Default)]
+struct ModuleCode {
+    imported_functions: u32,
+    /// Functions imported from [`INSTRUMENTATION_MODULE`], with their number
+    /// of parameters.
+    instrumentation: HashMap<u32, usize>,
+    /// Offset of the content of the code section in the module.
+    code_start: usize,
+    /// Offset in the module and kind of the instructions of each body.
+    bodies: Vec<Vec<(usize, Instruction)>>,
+}
+
+impl ModuleCode {
+    fn parse(code: &[u8]) -> wasmparser::Result<Self> {
+        let mut module = Self::default();
+        let mut params = Vec::new();
+        for payload in wasmparser::Parser::new(0).parse_all(code) {
+            match payload? {
+                wasmparser::Payload::TypeSection(reader) => {
+                    for ty in reader {
+                        params.push(match ty? {
+                            wasmparser::TypeDef::Func(ty) => ty.params.len(),
+                            _ => 0,
+                        });
+                    }
+                }
+                wasmparser::Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import?;
+                        if let wasmparser::ImportSectionEntryType::Function(ty) = import.ty {
+                            if import.module == INSTRUMENTATION_MODULE {
+                                let arity = params.get(ty as usize).copied().unwrap_or_default();
+                                module.instrumentation.insert(module.imported_functions, arity);
+                            }
+                            module.imported_functions += 1;
+                        }
+                    }
+                }
+                wasmparser::Payload::CodeSectionStart { range, .. } => {
+                    module.code_start = range.start
+                }
+                wasmparser::Payload::CodeSectionEntry(body) => {
+                    let mut reader = body.get_operators_reader()?;
+                    let mut instructions = Vec::new();
+                    while !reader.eof() {
+                        let (op, offset) = reader.read_with_offset()?;
+                        let instruction = match op {
+                            wasmparser::Operator::Block { .. } => Instruction::Block,
+                            wasmparser::Operator::Call { function_index } => {
+                                Instruction::Call(function_index)
+                            }
+                            _ => Instruction::Other,
+                        };
+                        instructions.push((offset, instruction));
+                    }
+                    module.bodies.push(instructions);
+                }
+                _ => {}
+            }
+        }
+        Ok(module)
+    }
+
+    /// Rank of the original instruction of each instruction of `body`, or
+    /// `None` for the instructions inserted by the instrumentation, if the
+    /// remaining instructions are the `original_len` ones of the original
+    /// body.
+    fn original_ranks(&self, body: usize, original_len: usize) -> Option<Vec<Option<usize>>> {
+        let instructions = self.bodies.get(body)?;
+        let instrumentation_call = |index: usize| match instructions.get(index) {
+            Some((_, Instruction::Call(function))) => self.instrumentation.get(function).copied(),
+            _ => None,
+        };
+        // The instrumentation passes its arguments as constants right before
+        // its calls.
+        let mut inserted = vec![false; instructions.len()];
+        for index in 0..instructions.len() {
+            if let Some(arity) = instrumentation_call(index) {
+                inserted[index.checked_sub(arity)?..=index].fill(true);
+            }
+        }
+        // The bodies of which the stack is accounted are wrapped in a block
+        // starting with the reservation of the stack, and end with its release
+        // after the end of the block, which stands for the original end.
+        let wrapped = matches!(instructions.first(), Some((_, Instruction::Block)))
+            && instrumentation_call(3) == Some(2);
+        if wrapped {
+            inserted[0] = true;
+            *inserted.last_mut()? = true;
+        }
+        if inserted.iter().filter(|inserted| !**inserted).count() != original_len {
+            return None;
+        }
+        let mut rank = 0;
+        let ranks = inserted
+            .iter()
+            .map(|inserted| {
+                if *inserted {
+                    return None;
+                }
+                rank += 1;
+                Some(rank - 1)
+            })
+            .collect();
+        Some(ranks)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::logic::FunctionCallError;
+    use crate::runner::RunOptions;
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+    use crate::ContractCode;
+    use unc_parameters::vm::VMKind;
+    use unc_parameters::RuntimeFeesConfig;
+
+    const CONTRACT: &str = r#"
+(module
+  (func $inner (param i32) (result i32)
+    (if (i32.eqz (local.get 0)) (then (return (i32.const 1))))
+    (loop (br_if 0 (i32.eqz (i32.const 1))))
+    unreachable)
+  (func (export "main")
+    (drop (call $inner (i32.const 2))))
+)"#;
+
+    #[test]
+    fn test_backtrace() {
+        let code = ContractCode::new(wat::parse_str(CONTRACT).unwrap(), None);
+        let config = test_vm_config();
+        let fees = RuntimeFeesConfig::test();
+        with_vm_variants(&config, |vm_kind| {
+            let mut config = config.clone();
+            config.vm_kind = vm_kind;
+            let options = RunOptions { backtrace: true, ..RunOptions::default() };
+            let outcome = crate::run_with_options(
+                &code,
+                "main",
+                &mut MockedExternal::new(),
+                create_context(vec![]),
+                &config,
+                &fees,
+                &[],
+                None,
+                &options,
+            )
+            .unwrap();
+            assert!(matches!(outcome.aborted, Some(FunctionCallError::WasmTrap(_))));
+            if vm_kind == VMKind::Wasmer0 {
+                assert_eq!(outcome.backtrace, None);
+                return;
+            }
+            let frame = |func_index, name: &str| BacktraceFrame {
+                func_index,
+                function_name: Some(name.to_string()),
+                file: None,
+                line: None,
+            };
+            assert_eq!(
+                outcome.backtrace,
+                Some(ContractBacktrace {
+                    frames: vec![frame(0, "inner"), frame(1, "main")],
+                    omitted_frames: 0,
+                }),
+                "{vm_kind:?}"
+            );
+        });
+    }
+
+    #[test]
+    fn test_original_ranks() {
+        let code = wat::parse_str(CONTRACT).unwrap();
+        let config = test_vm_config();
+        let original = ModuleCode::parse(&code).unwrap();
+        for vm_kind in [VMKind::Wasmtime, VMKind::NearVm] {
+            let prepared_code = crate::prepare::prepare_contract(&code, &config, vm_kind).unwrap();
+            let prepared = ModuleCode::parse(&prepared_code).unwrap();
+            for (body, instructions) in prepared.bodies.iter().enumerate() {
+                let original_body = &original.bodies[body];
+                let ranks = prepared.original_ranks(body, original_body.len()).unwrap();
+                for ((offset, _), rank) in instructions.iter().zip(ranks) {
+                    // Instructions keep their opcode.
+                    if let Some(rank) = rank {
+                        let original_offset = original_body[rank].0;
+                        assert_eq!(prepared_code[*offset], code[original_offset], "{vm_kind:?}");
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_bounded_backtrace() {
+        let code = ContractCode::new(wat::parse_str(CONTRACT).unwrap(), None);
+        let frame = WasmFrame { func_index: 0, function_name: None, module_offset: None };
+        let backtrace = symbolicate(&code, &test_vm_config(), &vec![frame; 40]);
+        assert_eq!(backtrace.frames.len(), ContractBacktrace::MAX_FRAMES);
+        assert_eq!(backtrace.omitted_frames, 8);
+        // The contract is only prepared once.
+        let first = symbolicator(&code, &test_vm_config());
+        assert!(Arc::ptr_eq(&first, &symbolicator(&code, &test_vm_config())));
+        assert_eq!(truncate("é".repeat(200)).len(), ContractBacktrace::MAX_NAME_LEN);
+    }
+}
@@ -0,0 +1,235 @@
+//! Running independent contract calls in parallel.
+//!
+//! The receipts of a chunk which touch different accounts do not depend on
+//! each other, and a chunk producer can execute them at the same time.
+//! [`BatchRunner::run_batch`] runs such calls on the pool of worker threads
+//! of the runner, which each make their runtime once, when the runner is
+//! made, and keep it for all the batches.  Each call runs with its own
+//! [`External`] and `VMLogic`, on the runtime of the worker running it, so the
+//! calls share nothing but the compiled contracts of the cache of the runner,
+//! which only ever adds artifacts.
+//!
+//! The runner does not check that the calls are independent: calls whose
+//! externals share a state see the writes of each other in whatever order
+//! the workers make them.  The results come back in the order of the calls
+//! whatever order they ran in.
+//!
+//! Calls of Wasmer0 and Wasmtime do not overlap when both VMs are compiled
+//! in, see [`crate::execution_concurrency`], so batches of these VMs run one
+//! call at a time while calls of the other VM are running.
+
+use crate::logic::types::PromiseResult;
+use crate::logic::{CompiledContractCache, External, VMContext, VMOutcome};
+use crate::runner::{BackendUnavailable, RunOptions, VMKindExt, VMResult, VM};
+use crate::{ContractCode, MockCompiledContractCache};
+use std::cell::RefCell;
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+use crate::logic::Config;
+use unc_parameters::RuntimeFeesConfig;
+
+thread_local! {
+    /// Runtime of the [`BatchRunner`] whose pool this thread is a worker of.
+    static RUNTIME: RefCell<Option<Box<dyn VM>>> = const { RefCell::new(None) };
+}
+
+/// A call of [`BatchRunner::run_batch`] with everything it runs against.
+pub struct PreparedCall<'a> {
+    pub code: Arc<ContractCode>,
+    pub method_name: String,
+    /// The state of the call, which no other call of the batch should use.
+    pub ext: &'a mut (dyn External + Send),
+    pub context: VMContext,
+    pub promise_results: Vec<PromiseResult>,
+    pub options: RunOptions,
+}
+
+impl<'a> PreparedCall<'a> {
+    /// A call without promise results and with the default options.
+    pub fn new(
+        code: Arc<ContractCode>,
+        method_name: impl Into<String>,
+        ext: &'a mut (dyn External + Send),
+        context: VMContext,
+    ) -> Self {
+        Self {
+            code,
+            method_name: method_name.into(),
+            ext,
+            context,
+            promise_results: Vec::new(),
+            options: RunOptions::default(),
+        }
+    }
+
+    pub fn with_promise_results(mut self, promise_results: Vec<PromiseResult>) -> Self {
+        self.promise_results = promise_results;
+        self
+    }
+
+    pub fn with_options(mut self, options: RunOptions) -> Self {
+        self.options = options;
+        self
+    }
+}
+
+/// Runs batches of independent calls on threads, see the module
+/// documentation.
+pub struct BatchRunner {
+    config: Config,
+    fees: Arc<RuntimeFeesConfig>,
+    cache: Arc<dyn CompiledContractCache>,
+    pool: rayon::ThreadPool,
+}
+
+impl BatchRunner {
+    /// Runs the calls with the VM of `config`, compiling the contracts into
+    /// an in-memory cache and with as many threads as the host has cores.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the worker threads cannot be spawned.
+    pub fn new(config: Config, fees: Arc<RuntimeFeesConfig>) -> Result<Self, BackendUnavailable> {
+        config.vm_kind.runtime(config.clone())?;
+        let threads = std::thread::available_parallelism().map_or(1, NonZeroUsize::get);
+        let cache = Arc::new(MockCompiledContractCache::default());
+        let pool = spawn_workers(&config, threads);
+        Ok(Self { config, fees, cache, pool })
+    }
+
+    /// Loads and stores the compiled contracts in `cache`.
+    pub fn with_cache(mut self, cache: Arc<dyn CompiledContractCache>) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// Runs at most `threads` calls at the same time, on a pool of that many
+    /// workers replacing the one of the runner.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.pool = spawn_workers(&self.config, threads.max(1));
+        self
+    }
+
+    /// Runs `calls`, returning their outcomes in the same order.
+    ///
+    /// Calls failing in the contract have their outcome like the others.
+    /// Fails with the error of the first call, in the order of the calls,
+    /// which the runner could not run at all, once all the calls are over.
+    ///
+    /// # Panics
+    ///
+    /// Panics if one of the calls panics, once the other calls are over.
+    pub fn run_batch(&self, calls: Vec<PreparedCall<'_>>) -> VMResult<Vec<VMOutcome>> {
+        let _span = tracing::debug_span!(target: "vm", "run_batch", calls = calls.len()).entered();
+        let mut results: Vec<Option<VMResult>> = calls.iter().map(|_| None).collect();
+        self.pool.scope(|s| {
+            for (call, result) in calls.into_iter().zip(&mut results) {
+                s.spawn(move |_| *result = Some(self.run_one(call)));
+            }
+        });
+        results.into_iter().map(|result| result.expect("every call of the batch has run")).collect()
+    }
+
+    /// Runs `call` on the runtime of the worker thread running it.
+    fn run_one(&self, call: PreparedCall<'_>) -> VMResult {
+        RUNTIME.with(|runtime| {
+            let runtime = runtime.borrow();
+            let runtime = runtime.as_deref().expect("workers make their runtime when spawned");
+            runtime.run_with_options(
+                &call.code,
+                &call.method_name,
+                call.ext,
+                call.context,
+                &self.fees,
+                &call.promise_results,
+                Some(&*self.cache),
+                &call.options,
+            )
+        })
+    }
+}
+
+/// A pool of `threads` workers, each with a runtime of the VM of `config`.
+fn spawn_workers(config: &Config, threads: usize) -> rayon::ThreadPool {
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|index| format!("vm-batch-{index}"))
+        .build()
+        .expect("failed spawning the workers of a batch runner");
+    pool.broadcast(|_| {
+        let runtime = config.vm_kind.runtime(config.clone()).expect("the backend has been checked");
+        RUNTIME.with(|worker| *worker.borrow_mut() = Some(runtime));
+    });
+    pool
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::logic::FunctionCallError;
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+
+    /// Writes its input under itself.
+    const CONTRACT: &str = r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "storage_write"
+    (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (memory 1)
+  (func (export "main")
+    (call $input (i64.const 0))
+    (drop (call $storage_write
+      (i64.const -1) (i64.const 0) (i64.const -1) (i64.const 0) (i64.const 1)))))"#;
+
+    #[test]
+    fn test_run_batch() {
+        let code = Arc::new(ContractCode::new(wat::parse_str(CONTRACT).unwrap(), None));
+        let fees = Arc::new(RuntimeFeesConfig::test());
+        with_vm_variants(&test_vm_config(), |vm_kind| {
+            let mut config = test_vm_config();
+            config.vm_kind = vm_kind;
+            let runner = BatchRunner::new(config.clone(), fees.clone()).unwrap().with_threads(4);
+            let method = |index: u8| if index == 5 { "missing" } else { "main" };
+            let mut exts: Vec<_> = (0..16).map(|_| MockedExternal::new()).collect();
+            let calls = (0u8..)
+                .zip(&mut exts)
+                .map(|(index, ext)| {
+                    let context = create_context(vec![index]);
+                    PreparedCall::new(code.clone(), method(index), ext, context)
+                })
+                .collect();
+            let outcomes = runner.run_batch(calls).unwrap();
+
+            assert_eq!(outcomes.len(), exts.len());
+            for ((index, outcome), ext) in (0u8..).zip(outcomes).zip(&exts) {
+                let expected = crate::run_with_options(
+                    &code,
+                    method(index),
+                    &mut MockedExternal::new(),
+                    create_context(vec![index]),
+                    &config,
+                    &fees,
+                    &[],
+                    None,
+                    &RunOptions::default(),
+                )
+                .unwrap();
+                assert_eq!(outcome, expected, "{vm_kind:?} call {index}");
+                // The failed call leaves the others alone.
+                if index == 5 {
+                    assert!(
+                        matches!(outcome.aborted, Some(FunctionCallError::MethodResolveError(_))),
+                        "{vm_kind:?}"
+                    );
+                    assert!(ext.fake_trie.is_empty());
+                } else {
+                    assert_eq!(outcome.aborted, None, "{vm_kind:?} call {index}");
+                    let writes: Vec<_> = ext.fake_trie.iter().collect();
+                    assert_eq!(writes, [(&vec![index], &vec![index])], "{vm_kind:?}");
+                }
+            }
+            assert!(runner.run_batch(Vec::new()).unwrap().is_empty());
+        });
+    }
+}
@@ -0,0 +1,116 @@
+//! `call` subcommand: runs one method of a contract against a
+//! [`MockedExternal`] and prints the [`VMOutcome`] as JSON.
+//!
+//! The context and the config are read from JSON files, in the formats of
+//! [`VMContext`] and [`VMConfigView`], so that a call recorded elsewhere can
+//! be replayed locally.  The state starts empty.  With `--stream-logs` the
+//! logs are printed as the contract emits them, which shows the progress of
+//! long calls, and with `--fingerprint` the outcome has the
+//! `execution_fingerprint` of the call, to compare with the one of another
+//! node.  The value the method returns is also printed to stderr, decoded
+//! with the ABI of the contract, see [`decode_return_value`].
+//!
+//! [`VMOutcome`]: unc_vm_runner::logic::VMOutcome
+//! [`decode_return_value`]: unc_vm_runner::decode_return_value
+
+use crate::{default_config, parse_vm_kind, ContractFile};
+use serde::de::DeserializeOwned;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use unc_parameters::view::VMConfigView;
+use unc_vm_runner::logic::Config;
+use unc_vm_runner::logic::mocks::mock_context::get_context;
+use unc_vm_runner::logic::mocks::mock_external::MockedExternal;
+use unc_vm_runner::logic::{ReturnData, VMContext};
+use std::sync::Arc;
+use unc_vm_runner::{ContractAbi, ContractCode, LogCapture, LogSink, RunOptions};
+
+/// Prints the logs to stderr, so that stdout stays valid JSON.
+#[derive(Debug)]
+struct StderrLogSink;
+
+impl LogSink for StderrLogSink {
+    fn log(&self, message: &str) {
+        eprintln!("{message}");
+    }
+}
+
+pub(crate) fn call(args: &[String]) -> Result<ExitCode, String> {
+    let mut contract = None;
+    let mut method = None;
+    let mut context_path = None;
+    let mut config_path = None;
+    let mut input = None;
+    let mut vm_kind = None;
+    let mut stream_logs = false;
+    let mut fingerprint = false;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("missing value for {arg}"));
+        match arg.as_str() {
+            "--wasm" => contract = Some(ContractFile::Wasm(PathBuf::from(value()?))),
+            "--wat" => contract = Some(ContractFile::Wat(PathBuf::from(value()?))),
+            "--method" => method = Some(value()?.clone()),
+            "--context" => context_path = Some(PathBuf::from(value()?)),
+            "--config" => config_path = Some(PathBuf::from(value()?)),
+            "--input" => input = Some(value()?.as_bytes().to_vec()),
+            "--vm" => vm_kind = Some(parse_vm_kind(value()?)?),
+            "--stream-logs" => stream_logs = true,
+            "--fingerprint" => fingerprint = true,
+            _ => return Err(format!("unknown option: {arg}")),
+        }
+    }
+    let contract = contract.ok_or("--wasm or --wat is required")?;
+    let method = method.ok_or("--method is required")?;
+    let code = ContractCode::new(contract.read()?, None);
+
+    let runtime_config = default_config();
+    let mut config = match &config_path {
+        Some(path) => {
+            Config::from(unc_parameters::vm::Config::from(read_json::<VMConfigView>(path)?))
+        }
+        None => Config::from(runtime_config.wasm_config.clone()),
+    };
+    if let Some(vm_kind) = vm_kind {
+        config.vm_kind = vm_kind;
+    }
+    let mut context = match &context_path {
+        Some(path) => read_json::<VMContext>(path)?,
+        None => VMContext { input: Vec::new(), ..get_context() },
+    };
+    if let Some(input) = input {
+        context.input = input;
+    }
+
+    let mut options = RunOptions { execution_fingerprint: fingerprint, ..RunOptions::default() };
+    if stream_logs {
+        options.log_capture = LogCapture::Stream(Arc::new(StderrLogSink));
+    }
+    let mut ext = MockedExternal::new();
+    let outcome = unc_vm_runner::run_with_options(
+        &code,
+        &method,
+        &mut ext,
+        context,
+        &config,
+        &runtime_config.fees,
+        &[],
+        None,
+        &options,
+    )
+    .map_err(|err| format!("cannot run {method}: {err}"))?;
+    if let ReturnData::Value(value) = &outcome.return_data {
+        // A malformed ABI only loses the decoding.
+        let abi = ContractAbi::from_code(code.code()).ok().flatten();
+        eprintln!("returned {}", unc_vm_runner::decode_return_value(abi.as_ref(), &method, value));
+    }
+    let json = serde_json::to_string_pretty(&outcome).map_err(|err| err.to_string())?;
+    println!("{json}");
+    Ok(if outcome.aborted.is_some() { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
+
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<T, String> {
+    let json =
+        std::fs::read(path).map_err(|err| format!("cannot read {}: {err}", path.display()))?;
+    serde_json::from_slice(&json).map_err(|err| format!("{}: {err}", path.display()))
+}
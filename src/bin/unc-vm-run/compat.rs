@@ -0,0 +1,57 @@
+//! `compat` subcommand: compares the interface of two versions of a contract
+//! through [`ContractInterface::check_upgrade`] and reports what the new one
+//! breaks.
+
+use crate::{default_config, ContractFile};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use unc_vm_runner::logic::Config;
+use unc_vm_runner::{ContractAbi, ContractInterface};
+
+pub(crate) fn compat(args: &[String]) -> Result<ExitCode, String> {
+    let mut old = None;
+    let mut new = None;
+    let mut old_abi = None;
+    let mut new_abi = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("missing value for {arg}"));
+        match arg.as_str() {
+            "--old" => old = Some(ContractFile::Wasm(PathBuf::from(value()?))),
+            "--old-wat" => old = Some(ContractFile::Wat(PathBuf::from(value()?))),
+            "--new" => new = Some(ContractFile::Wasm(PathBuf::from(value()?))),
+            "--new-wat" => new = Some(ContractFile::Wat(PathBuf::from(value()?))),
+            "--old-abi" => old_abi = Some(PathBuf::from(value()?)),
+            "--new-abi" => new_abi = Some(PathBuf::from(value()?)),
+            _ => return Err(format!("unknown option: {arg}")),
+        }
+    }
+    let old = old.ok_or("--old or --old-wat is required")?;
+    let new = new.ok_or("--new or --new-wat is required")?;
+    let old = interface(&old, old_abi.as_deref())?;
+    let new = interface(&new, new_abi.as_deref())?;
+
+    let compatibility = old.check_upgrade(&new);
+    if !compatibility.compared_abis {
+        println!("the arguments are not compared: both versions need an ABI");
+    }
+    for method in &compatibility.added_methods {
+        println!("added {method}");
+    }
+    for change in &compatibility.breaking_changes {
+        println!("breaking: {change}");
+    }
+    Ok(if compatibility.is_compatible() { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
+
+fn interface(contract: &ContractFile, abi: Option<&Path>) -> Result<ContractInterface, String> {
+    let code = contract.read()?;
+    let config = Config::from(default_config().wasm_config.clone());
+    let interface = ContractInterface::from_code(&code, &config)
+        .map_err(|err| format!("{}: {err}", contract.path().display()))?;
+    let Some(path) = abi else { return Ok(interface) };
+    let json =
+        std::fs::read(path).map_err(|err| format!("cannot read {}: {err}", path.display()))?;
+    let abi = ContractAbi::from_json(&json).map_err(|err| format!("{}: {err}", path.display()))?;
+    Ok(interface.with_abi(abi))
+}
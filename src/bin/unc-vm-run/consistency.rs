@@ -0,0 +1,192 @@
+//! `consistency` subcommand: compares the outcomes of a contract corpus
+//! across all the VMs.
+//!
+//! This is the consistency check of the crate's `TestBuilder` applied to
+//! contracts that are not part of the test suite: each exported function is
+//! called with an empty input at several prepaid gas levels and the outcomes
+//! (balance, storage usage, return data, gas and abort reason) are compared
+//! between the VMs.
+
+use crate::{default_config, is_supported, wasm_files, ALL_VMS};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use unc_vm_runner::logic::Config;
+use unc_parameters::vm::VMKind;
+use unc_parameters::RuntimeConfig;
+use unc_primitives_core::types::Gas;
+use unc_primitives_core::version::PROTOCOL_VERSION;
+use unc_vm_runner::internal::VMKindExt;
+use unc_vm_runner::logic::mocks::mock_context::get_context;
+use unc_vm_runner::logic::mocks::mock_external::MockedExternal;
+use unc_vm_runner::logic::types::ReturnData;
+use unc_vm_runner::logic::VMOutcome;
+use unc_vm_runner::ContractCode;
+
+pub(crate) const DEFAULT_GAS: [Gas; 3] = [10u64.pow(12), 10u64.pow(13), 300 * 10u64.pow(12)];
+
+#[derive(serde::Serialize)]
+struct Report {
+    protocol_version: u32,
+    vms: Vec<String>,
+    total_calls: usize,
+    inconsistent_calls: usize,
+    contracts: Vec<ContractReport>,
+}
+
+#[derive(serde::Serialize)]
+struct ContractReport {
+    path: PathBuf,
+    /// Set when the exports of the contract could not be read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    calls: Vec<CallReport>,
+}
+
+#[derive(serde::Serialize)]
+struct CallReport {
+    method: String,
+    prepaid_gas: Gas,
+    consistent: bool,
+    /// Outcome of the call on each VM.
+    outcomes: BTreeMap<String, String>,
+}
+
+pub(crate) fn consistency(args: &[String]) -> Result<ExitCode, String> {
+    let mut dir = None;
+    let mut gas_levels = DEFAULT_GAS.to_vec();
+    let mut report_path = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("missing value for {arg}"));
+        match arg.as_str() {
+            "--dir" => dir = Some(PathBuf::from(value()?)),
+            "--gas" => {
+                gas_levels = value()?
+                    .split(',')
+                    .map(|gas| gas.trim().parse().map_err(|err| format!("invalid --gas: {err}")))
+                    .collect::<Result<_, _>>()?;
+            }
+            "--report" => report_path = Some(PathBuf::from(value()?)),
+            _ => return Err(format!("unknown option: {arg}")),
+        }
+    }
+    let dir = dir.ok_or("--dir is required")?;
+
+    let runtime_config = default_config();
+    let vms: Vec<VMKind> =
+        ALL_VMS.into_iter().filter(|vm_kind| is_supported(*vm_kind, &runtime_config)).collect();
+    let mut contracts = Vec::new();
+    for path in wasm_files(&dir)? {
+        contracts.push(check_contract(&path, &gas_levels, &vms, &runtime_config));
+    }
+
+    let calls = contracts.iter().flat_map(|c| &c.calls);
+    let report = Report {
+        protocol_version: PROTOCOL_VERSION,
+        vms: vms.iter().map(|vm_kind| format!("{vm_kind:?}")).collect(),
+        total_calls: calls.clone().count(),
+        inconsistent_calls: calls.filter(|call| !call.consistent).count(),
+        contracts,
+    };
+    let json = serde_json::to_string_pretty(&report).unwrap();
+    match report_path {
+        Some(path) => std::fs::write(&path, json + "\n")
+            .map_err(|err| format!("cannot write {}: {err}", path.display()))?,
+        None => println!("{json}"),
+    }
+    eprintln!(
+        "{} calls on {} VMs, {} inconsistent",
+        report.total_calls,
+        report.vms.len(),
+        report.inconsistent_calls
+    );
+    Ok(if report.inconsistent_calls == 0 { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
+
+fn check_contract(
+    path: &Path,
+    gas_levels: &[Gas],
+    vms: &[VMKind],
+    runtime_config: &RuntimeConfig,
+) -> ContractReport {
+    let mut report = ContractReport { path: path.to_path_buf(), error: None, calls: Vec::new() };
+    let methods = match std::fs::read(path) {
+        Ok(code) => exported_functions(&code, &Config::from(runtime_config.wasm_config.clone()))
+            .map(|methods| (code, methods)),
+        Err(err) => Err(format!("cannot read: {err}")),
+    };
+    let (code, methods) = match methods {
+        Ok(it) => it,
+        Err(err) => {
+            report.error = Some(err);
+            return report;
+        }
+    };
+    let code = ContractCode::new(code, None);
+    for method in &methods {
+        for &prepaid_gas in gas_levels {
+            let outcomes: BTreeMap<_, _> = vms
+                .iter()
+                .map(|&vm_kind| {
+                    let outcome = run(&code, method, prepaid_gas, vm_kind, runtime_config);
+                    (format!("{vm_kind:?}"), outcome)
+                })
+                .collect();
+            let mut distinct = outcomes.values();
+            let first = distinct.next();
+            let consistent = distinct.all(|outcome| Some(outcome) == first);
+            report.calls.push(CallReport {
+                method: method.clone(),
+                prepaid_gas,
+                consistent,
+                outcomes,
+            });
+        }
+    }
+    report
+}
+
+/// The names of the functions `code` exports, callable or not, so that the
+/// VMs are also compared on the calls failing to resolve.
+pub(crate) fn exported_functions(code: &[u8], config: &Config) -> Result<Vec<String>, String> {
+    let methods = unc_vm_runner::exported_methods(code, config)
+        .map_err(|err| format!("invalid wasm: {err}"))?;
+    Ok(methods.into_iter().map(|method| method.name).collect())
+}
+
+fn run(
+    code: &ContractCode,
+    method: &str,
+    prepaid_gas: Gas,
+    vm_kind: VMKind,
+    runtime_config: &RuntimeConfig,
+) -> String {
+    let mut config = Config::from(runtime_config.wasm_config.clone());
+    config.vm_kind = vm_kind;
+    let runtime = vm_kind.runtime(config).expect("runtime has not been compiled");
+    let mut context = get_context();
+    context.input = Vec::new();
+    context.prepaid_gas = prepaid_gas;
+    let mut ext = MockedExternal::new();
+    match runtime.run(code, method, &mut ext, context, &runtime_config.fees, &[], None) {
+        Ok(outcome) => fmt_outcome(&outcome),
+        Err(err) => format!("runner error: {err}"),
+    }
+}
+
+fn fmt_outcome(outcome: &VMOutcome) -> String {
+    let return_data = match &outcome.return_data {
+        ReturnData::None => "None".to_string(),
+        ReturnData::ReceiptIndex(_) => "Receipt".to_string(),
+        ReturnData::Value(v) => format!("Value [{} bytes]", v.len()),
+    };
+    let mut out = format!(
+        "balance {} storage_usage {} return data {} burnt gas {} used gas {}",
+        outcome.balance, outcome.storage_usage, return_data, outcome.burnt_gas, outcome.used_gas
+    );
+    if let Some(err) = &outcome.aborted {
+        out.push_str(&format!(" Err: {err}"));
+    }
+    out
+}
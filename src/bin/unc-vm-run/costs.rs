@@ -0,0 +1,71 @@
+//! `costs` subcommand: estimates the gas parameters of the current protocol
+//! version on this machine through a [`CostEstimator`].
+//!
+//! The table has the current value and the estimate of each parameter, with
+//! the estimates on each VM.  `--diff` writes the parameters moving by more
+//! than `--tolerance` in the format of the runtime config diff files, ready to
+//! be reviewed and added as the diff of a new protocol version.  Timings are
+//! only meaningful in a release build on an otherwise idle machine.
+
+use crate::{default_config, parse_vm_kind};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use unc_vm_runner::costs::CostEstimator;
+use unc_vm_runner::logic::Config;
+
+const DEFAULT_TOLERANCE: f64 = 0.1;
+
+pub(crate) fn costs(args: &[String]) -> Result<ExitCode, String> {
+    let mut vm_kinds = Vec::new();
+    let mut iterations = CostEstimator::DEFAULT_ITERATIONS;
+    let mut samples = CostEstimator::DEFAULT_SAMPLES;
+    let mut tolerance = DEFAULT_TOLERANCE;
+    let mut diff = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("missing value for {arg}"));
+        match arg.as_str() {
+            "--vm" => vm_kinds.push(parse_vm_kind(value()?)?),
+            "--iterations" => {
+                iterations =
+                    value()?.parse().map_err(|err| format!("invalid --iterations: {err}"))?;
+            }
+            "--samples" => {
+                samples = value()?.parse().map_err(|err| format!("invalid --samples: {err}"))?;
+            }
+            "--tolerance" => {
+                tolerance =
+                    value()?.parse().map_err(|err| format!("invalid --tolerance: {err}"))?;
+            }
+            "--diff" => diff = Some(PathBuf::from(value()?)),
+            _ => return Err(format!("unknown option: {arg}")),
+        }
+    }
+
+    let runtime_config = default_config();
+    let mut estimator = CostEstimator::new(Config::from(runtime_config.wasm_config.clone()))
+        .with_iterations(iterations)
+        .with_samples(samples);
+    if !vm_kinds.is_empty() {
+        estimator = estimator.with_vm_kinds(vm_kinds);
+    }
+    let estimate = estimator.estimate().map_err(|err| err.to_string())?;
+
+    println!("{:<40} {:>18} {:>18}  {}", "parameter", "current", "estimated", "by vm");
+    for parameter in &estimate.parameters {
+        let by_vm: Vec<String> =
+            parameter.by_vm.iter().map(|(vm_kind, gas)| format!("{vm_kind:?}={gas}")).collect();
+        println!(
+            "{:<40} {:>18} {:>18}  {}",
+            parameter.parameter.to_string(),
+            parameter.current,
+            parameter.estimated,
+            by_vm.join(" ")
+        );
+    }
+    if let Some(path) = diff {
+        std::fs::write(&path, estimate.diff(tolerance).to_string())
+            .map_err(|err| format!("cannot write {}: {err}", path.display()))?;
+    }
+    Ok(ExitCode::SUCCESS)
+}
@@ -0,0 +1,332 @@
+//! `determinism` subcommand: runs a contract corpus in several environments
+//! and checks that the outcomes do not depend on them.
+//!
+//! Every environment is a child process running `unc-vm-run
+//! determinism-run`, which calls each exported function of each contract on
+//! all the VMs from a Rayon pool, the calls sharing one cache.  The
+//! environments vary:
+//!
+//! * the number of threads of the pool, so that the calls and compilations
+//!   interleave differently;
+//! * the allocator: the system one, or a poisoning one shifting every
+//!   allocation and filling the memory it hands out and frees with junk,
+//!   which changes the heap addresses and the content of uninitialized
+//!   memory;
+//! * address space layout randomization, disabled on Linux through
+//!   `personality(ADDR_NO_RANDOMIZE)`.
+//!
+//! The outcomes compared are the full
+//! [`VMOutcome`](unc_vm_runner::logic::VMOutcome)s and the state left in
+//! the mocked external, so any difference is reported.
+
+use crate::consistency::{exported_functions, DEFAULT_GAS};
+use crate::{default_config, is_supported, wasm_files, ALL_VMS};
+use rayon::prelude::*;
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitCode, Stdio};
+use std::sync::atomic::{AtomicU8, Ordering};
+use unc_parameters::vm::VMKind;
+use unc_parameters::RuntimeConfig;
+use unc_primitives_core::types::Gas;
+use unc_vm_runner::internal::VMKindExt;
+use unc_vm_runner::logic::mocks::mock_context::get_context;
+use unc_vm_runner::logic::Config;
+use unc_vm_runner::logic::mocks::mock_external::MockedExternal;
+use unc_vm_runner::{ContractCode, MockCompiledContractCache};
+
+/// Environment variable selecting the allocator of the process, see
+/// [`EnvAllocator`].
+const ALLOCATOR_VAR: &str = "UNC_VM_RUN_ALLOCATOR";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AllocatorKind {
+    System,
+    Poison,
+}
+
+/// Allocator of the binary: the system allocator, or with `poison` in
+/// [`ALLOCATOR_VAR`] a poisoning wrapper around it.
+///
+/// The choice is made on the first allocation, by reading the environment
+/// without allocating, and never changes: blocks must be freed by the
+/// allocator which allocated them.
+pub(crate) struct EnvAllocator;
+
+static ALLOCATOR_KIND: AtomicU8 = AtomicU8::new(0);
+
+const UNKNOWN: u8 = 0;
+const SYSTEM: u8 = 1;
+const POISON: u8 = 2;
+
+impl EnvAllocator {
+    fn poisons(&self) -> bool {
+        let mut kind = ALLOCATOR_KIND.load(Ordering::Relaxed);
+        if kind == UNKNOWN {
+            // SAFETY: the name is nul terminated, and the environment is not
+            // modified while the binary runs.
+            let value = unsafe { libc::getenv(c"UNC_VM_RUN_ALLOCATOR".as_ptr()) };
+            // SAFETY: `getenv` returns null or a nul terminated string.
+            let poison = !value.is_null()
+                && unsafe { std::ffi::CStr::from_ptr(value) }.to_bytes() == b"poison";
+            kind = if poison { POISON } else { SYSTEM };
+            ALLOCATOR_KIND.store(kind, Ordering::Relaxed);
+        }
+        kind == POISON
+    }
+
+    /// Layout of the block backing an allocation of `layout` when poisoning,
+    /// and the offset of the allocation in it.
+    fn padded(layout: Layout) -> (Layout, usize) {
+        let offset = layout.align().max(16);
+        let padded = Layout::from_size_align(layout.size() + offset, layout.align())
+            .expect("allocation too large");
+        (padded, offset)
+    }
+}
+
+// SAFETY: allocations are forwarded to the system allocator, shifted by an
+// offset preserving their alignment.
+unsafe impl GlobalAlloc for EnvAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if !self.poisons() {
+            return System.alloc(layout);
+        }
+        let (padded, offset) = Self::padded(layout);
+        let block = System.alloc(padded);
+        if block.is_null() {
+            return block;
+        }
+        block.write_bytes(0xa5, padded.size());
+        block.add(offset)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        if !self.poisons() {
+            return System.dealloc(ptr, layout);
+        }
+        let (padded, offset) = Self::padded(layout);
+        ptr.write_bytes(0x5a, layout.size());
+        System.dealloc(ptr.sub(offset), padded);
+    }
+}
+
+struct Environment {
+    threads: usize,
+    allocator: AllocatorKind,
+    aslr: bool,
+}
+
+impl Environment {
+    fn describe(&self) -> String {
+        format!(
+            "{} threads, {:?} allocator, ASLR {}",
+            self.threads,
+            self.allocator,
+            if self.aslr { "on" } else { "off" }
+        )
+    }
+}
+
+pub(crate) fn determinism(args: &[String]) -> Result<ExitCode, String> {
+    let mut dir = None;
+    let mut threads = vec![1, std::thread::available_parallelism().map_or(4, |n| n.get().max(2))];
+    let mut allocators = vec![AllocatorKind::System, AllocatorKind::Poison];
+    let mut aslr = vec![true, false];
+    let mut gas_levels = DEFAULT_GAS.to_vec();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("missing value for {arg}"));
+        match arg.as_str() {
+            "--dir" => dir = Some(PathBuf::from(value()?)),
+            "--threads" => threads = parse_list(arg, value()?, |n| n.parse().ok())?,
+            "--allocators" => {
+                allocators = parse_list(arg, value()?, |name| match name {
+                    "system" => Some(AllocatorKind::System),
+                    "poison" => Some(AllocatorKind::Poison),
+                    _ => None,
+                })?
+            }
+            "--aslr" => {
+                aslr = parse_list(arg, value()?, |state| match state {
+                    "on" => Some(true),
+                    "off" => Some(false),
+                    _ => None,
+                })?
+            }
+            "--gas" => gas_levels = parse_list(arg, value()?, |gas| gas.parse().ok())?,
+            _ => return Err(format!("unknown option: {arg}")),
+        }
+    }
+    let dir = dir.ok_or("--dir is required")?;
+    if !cfg!(target_os = "linux") && aslr.contains(&false) {
+        return Err("ASLR can only be disabled on Linux, pass --aslr on".to_string());
+    }
+
+    let mut environments = Vec::new();
+    for &threads in &threads {
+        for &allocator in &allocators {
+            for &aslr in &aslr {
+                environments.push(Environment { threads: threads.max(1), allocator, aslr });
+            }
+        }
+    }
+    let exe = std::env::current_exe().map_err(|err| format!("cannot find unc-vm-run: {err}"))?;
+    let gas = gas_levels.iter().map(Gas::to_string).collect::<Vec<_>>().join(",");
+    let mut reference: Option<(&Environment, BTreeMap<String, String>)> = None;
+    let mut differences = 0;
+    for environment in &environments {
+        let outcomes = run_environment(&exe, &dir, &gas, environment)?;
+        println!("{}: {} calls", environment.describe(), outcomes.len());
+        let Some((reference_environment, expected)) = &reference else {
+            reference = Some((environment, outcomes));
+            continue;
+        };
+        let keys =
+            expected.keys().chain(outcomes.keys()).collect::<std::collections::BTreeSet<_>>();
+        for key in keys {
+            let (expected, actual) = (expected.get(key), outcomes.get(key));
+            if expected != actual {
+                differences += 1;
+                println!("    {key}");
+                println!("        {}: {expected:?}", reference_environment.describe());
+                println!("        {}: {actual:?}", environment.describe());
+            }
+        }
+    }
+    println!("{} environments, {differences} differences", environments.len());
+    Ok(if differences == 0 { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
+
+fn parse_list<T>(
+    arg: &str,
+    value: &str,
+    parse: impl Fn(&str) -> Option<T>,
+) -> Result<Vec<T>, String> {
+    value
+        .split(',')
+        .map(|item| parse(item.trim()).ok_or_else(|| format!("invalid {arg}: {item}")))
+        .collect()
+}
+
+fn run_environment(
+    exe: &Path,
+    dir: &Path,
+    gas: &str,
+    environment: &Environment,
+) -> Result<BTreeMap<String, String>, String> {
+    let mut command = Command::new(exe);
+    command
+        .arg("determinism-run")
+        .arg("--dir")
+        .arg(dir)
+        .args(["--threads", &environment.threads.to_string(), "--gas", gas])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit());
+    match environment.allocator {
+        AllocatorKind::System => command.env_remove(ALLOCATOR_VAR),
+        AllocatorKind::Poison => command.env(ALLOCATOR_VAR, "poison"),
+    };
+    #[cfg(target_os = "linux")]
+    if !environment.aslr {
+        use std::os::unix::process::CommandExt;
+        // SAFETY: `personality` is async-signal-safe and does not allocate.
+        unsafe {
+            command.pre_exec(|| {
+                if libc::personality(libc::ADDR_NO_RANDOMIZE as libc::c_ulong) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+                Ok(())
+            });
+        }
+    }
+    let output = command.output().map_err(|err| format!("cannot run unc-vm-run: {err}"))?;
+    if !output.status.success() {
+        return Err(format!("{} failed with {}", environment.describe(), output.status));
+    }
+    serde_json::from_slice(&output.stdout)
+        .map_err(|err| format!("invalid output of {}: {err}", environment.describe()))
+}
+
+/// `determinism-run`: the calls of one environment, printed as JSON.
+pub(crate) fn determinism_run(args: &[String]) -> Result<ExitCode, String> {
+    let mut dir = None;
+    let mut threads = 1;
+    let mut gas_levels = DEFAULT_GAS.to_vec();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("missing value for {arg}"));
+        match arg.as_str() {
+            "--dir" => dir = Some(PathBuf::from(value()?)),
+            "--threads" => {
+                threads = value()?.parse().map_err(|err| format!("invalid {arg}: {err}"))?
+            }
+            "--gas" => gas_levels = parse_list(arg, value()?, |gas| gas.parse().ok())?,
+            _ => return Err(format!("unknown option: {arg}")),
+        }
+    }
+    let dir = dir.ok_or("--dir is required")?;
+
+    let runtime_config = default_config();
+    let vms: Vec<VMKind> =
+        ALL_VMS.into_iter().filter(|vm_kind| is_supported(*vm_kind, &runtime_config)).collect();
+    let mut calls = Vec::new();
+    for path in wasm_files(&dir)? {
+        let code =
+            std::fs::read(&path).map_err(|err| format!("cannot read {}: {err}", path.display()))?;
+        let methods = exported_functions(&code, &Config::from(runtime_config.wasm_config.clone()))
+            .map_err(|err| format!("{}: {err}", path.display()))?;
+        let code = std::sync::Arc::new(ContractCode::new(code, None));
+        for method in methods {
+            for &prepaid_gas in &gas_levels {
+                for &vm_kind in &vms {
+                    calls.push((path.clone(), code.clone(), method.clone(), prepaid_gas, vm_kind));
+                }
+            }
+        }
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .map_err(|err| format!("cannot start the thread pool: {err}"))?;
+    let cache = MockCompiledContractCache::default();
+    let outcomes: BTreeMap<String, String> = pool.install(|| {
+        calls
+            .par_iter()
+            .map(|(path, code, method, prepaid_gas, vm_kind)| {
+                let key = format!("{} {method} gas {prepaid_gas} {vm_kind:?}", path.display());
+                let outcome = run(code, method, *prepaid_gas, *vm_kind, &runtime_config, &cache);
+                (key, outcome)
+            })
+            .collect()
+    });
+    println!("{}", serde_json::to_string(&outcomes).unwrap());
+    Ok(ExitCode::SUCCESS)
+}
+
+fn run(
+    code: &ContractCode,
+    method: &str,
+    prepaid_gas: Gas,
+    vm_kind: VMKind,
+    runtime_config: &RuntimeConfig,
+    cache: &MockCompiledContractCache,
+) -> String {
+    let mut config = Config::from(runtime_config.wasm_config.clone());
+    config.vm_kind = vm_kind;
+    let runtime = vm_kind.runtime(config).expect("runtime has not been compiled");
+    let mut context = get_context();
+    context.input = Vec::new();
+    context.prepaid_gas = prepaid_gas;
+    let mut ext = MockedExternal::new();
+    match runtime.run(code, method, &mut ext, context, &runtime_config.fees, &[], Some(cache)) {
+        Ok(outcome) => {
+            let mut storage: Vec<_> = ext.fake_trie.iter().collect();
+            storage.sort();
+            format!("{outcome:?} storage {storage:?} actions {:?}", ext.action_log)
+        }
+        Err(err) => format!("runner error: {err}"),
+    }
+}
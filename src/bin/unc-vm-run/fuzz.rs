@@ -0,0 +1,98 @@
+//! `fuzz` subcommand: calls every method described by the ABI of a contract
+//! with generated inputs through an [`AbiFuzzer`] and reports the calls
+//! breaking the invariants of the runtime.
+
+use crate::{default_config, parse_vm_kind, ContractFile};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use unc_vm_runner::logic::Config;
+use unc_vm_runner::{AbiFuzzOptions, AbiFuzzer, ContractAbi, ContractCode};
+
+/// Number of failures printed for each method.
+const SHOWN_FAILURES: usize = 5;
+
+pub(crate) fn fuzz(args: &[String]) -> Result<ExitCode, String> {
+    let mut contract = None;
+    let mut abi_path = None;
+    let mut methods = Vec::new();
+    let mut vm_kind = None;
+    let mut options = AbiFuzzOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("missing value for {arg}"));
+        let invalid = |err: std::num::ParseIntError| format!("invalid {arg}: {err}");
+        match arg.as_str() {
+            "--wasm" => contract = Some(ContractFile::Wasm(PathBuf::from(value()?))),
+            "--wat" => contract = Some(ContractFile::Wat(PathBuf::from(value()?))),
+            "--abi" => abi_path = Some(PathBuf::from(value()?)),
+            "--method" => methods.push(value()?.clone()),
+            "--vm" => vm_kind = Some(parse_vm_kind(value()?)?),
+            "--runs" => options.runs = value()?.parse().map_err(invalid)?,
+            "--repeats" => options.repeats = value()?.parse().map_err(invalid)?,
+            "--seed" => options.seed = value()?.parse().map_err(invalid)?,
+            "--gas" => options.prepaid_gas = value()?.parse().map_err(invalid)?,
+            "--gas-budget" => options.gas_budget = Some(value()?.parse().map_err(invalid)?),
+            _ => return Err(format!("unknown option: {arg}")),
+        }
+    }
+    let contract = contract.ok_or("--wasm or --wat is required")?;
+    let code = contract.read()?;
+    let wasm = contract.path();
+    let abi = match &abi_path {
+        Some(path) => {
+            let json = std::fs::read(path)
+                .map_err(|err| format!("cannot read {}: {err}", path.display()))?;
+            ContractAbi::from_json(&json).map_err(|err| format!("{}: {err}", path.display()))?
+        }
+        None => ContractAbi::from_code(&code)
+            .map_err(|err| format!("{}: {err}", wasm.display()))?
+            .ok_or_else(|| {
+                format!(
+                    "{} has no {} section, pass --abi",
+                    wasm.display(),
+                    unc_vm_runner::ABI_SECTION
+                )
+            })?,
+    };
+    let functions = if methods.is_empty() {
+        abi.functions.iter().collect()
+    } else {
+        methods
+            .iter()
+            .map(|method| abi.function(method).ok_or_else(|| format!("{method} is not in the ABI")))
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    let runtime_config = default_config();
+    let vm_kind = vm_kind.unwrap_or(runtime_config.wasm_config.vm_kind);
+    let mut config = Config::from(runtime_config.wasm_config.clone());
+    config.vm_kind = vm_kind;
+    let fuzzer = AbiFuzzer::new(config, runtime_config.fees.clone(), options)
+        .map_err(|err| err.to_string())?;
+    let code = ContractCode::new(code, None);
+
+    println!("{:<24} {:>8} {:>8} {:>8}", "method", "runs", "aborted", "failures");
+    let mut failed = false;
+    for function in functions {
+        let report = fuzzer.fuzz_function(&code, function);
+        println!(
+            "{:<24} {:>8} {:>8} {:>8}",
+            report.method,
+            report.runs,
+            report.aborted,
+            report.failures.len()
+        );
+        for failure in report.failures.iter().take(SHOWN_FAILURES) {
+            println!(
+                "    input {}: {}",
+                String::from_utf8_lossy(&failure.input),
+                failure.violation
+            );
+        }
+        if report.failures.len() > SHOWN_FAILURES {
+            println!("    and {} more", report.failures.len() - SHOWN_FAILURES);
+        }
+        failed |= !report.failures.is_empty();
+    }
+    Ok(if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
@@ -0,0 +1,323 @@
+//! Command line interface to the contract runtime.
+//!
+//! ```text
+//! unc-vm-run precompile --dir ./contracts [--vm near-vm] [--cache ./cache] [--baseline]
+//!     [--opt-level fast] [--json] [--isolated]
+//! unc-vm-run consistency --dir ./contracts [--gas G1,G2,...] [--report report.json]
+//! unc-vm-run throughput --wasm contract.wasm --method get [--calls N] [--threads N]
+//!     [--huge-pages transparent]
+//! unc-vm-run fuzz --wat contract.wat [--method get] [--runs N] [--gas-budget G]
+//! unc-vm-run determinism --dir ./contracts [--threads 1,8] [--allocators system,poison]
+//!     [--aslr on,off]
+//! unc-vm-run call --wasm contract.wasm --method get [--context context.json]
+//!     [--config config.json] [--input STRING] [--vm near-vm] [--stream-logs] [--fingerprint]
+//! unc-vm-run compat --old v1.wasm --new v2.wasm [--old-abi v1.json] [--new-abi v2.json]
+//! unc-vm-run costs [--vm near-vm] [--iterations N] [--samples N] [--diff costs.yaml]
+//! ```
+//!
+//! `precompile` compiles every `.wasm` file in the directory in parallel,
+//! storing the artifacts in the cache directory if one is given, and prints a
+//! summary with the size, compile time, compiler and outcome of each contract.  The
+//! exit code is non-zero if any of the contracts failed to compile, which
+//! makes it suitable for CI pipelines publishing contract releases.  With
+//! `--isolated`, the contracts are compiled in jailed `unc-vm-run
+//! compile-worker` processes through an [`unc_vm_runner::IsolatedCompiler`].
+//!
+//! `consistency` runs every exported function of every `.wasm` file in the
+//! directory on all the VMs compiled into the binary and reports the calls
+//! whose outcome differs between the VMs.
+//!
+//! `throughput` serves the same view call over and over through
+//! [`unc_vm_runner::ThroughputRunner`]s and reports the calls per second each
+//! VM achieves.
+//!
+//! `fuzz` calls the methods described by the ABI of the contract with
+//! generated inputs through an [`unc_vm_runner::AbiFuzzer`] and reports the
+//! inputs breaking the invariants of the runtime: host panics, gas over the
+//! budget and outcomes changing between repeated calls.
+//!
+//! `determinism` runs the corpus of the directory in child processes with
+//! different thread counts, allocators and ASLR states and reports the calls
+//! whose outcome depends on the environment.
+//!
+//! `call` runs one method of the contract against an empty mocked state and
+//! prints the outcome, with its logs and gas profile, as JSON, and the value
+//! the method returned to stderr, decoded as JSON or as the Borsh type of its
+//! ABI.
+//!
+//! `compat` compares the exported methods and the ABIs of two versions of a
+//! contract through [`unc_vm_runner::ContractInterface::check_upgrade`] and
+//! reports the changes breaking the callers of the old one.  The exit code is
+//! non-zero if there are any, so that upgrade pipelines can stop the deploy.
+//!
+//! `costs` times workloads of the host functions and the wasm instructions
+//! through an [`unc_vm_runner::costs::CostEstimator`] and prints the gas
+//! parameters they suggest, optionally writing them as a runtime config diff.
+
+mod call;
+mod compat;
+mod consistency;
+mod costs;
+mod determinism;
+mod fuzz;
+mod precompile;
+mod throughput;
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use unc_parameters::vm::VMKind;
+use unc_parameters::{RuntimeConfig, RuntimeConfigStore};
+use unc_primitives_core::version::PROTOCOL_VERSION;
+
+#[global_allocator]
+static ALLOCATOR: determinism::EnvAllocator = determinism::EnvAllocator;
+
+const USAGE: &str = "\
+usage: unc-vm-run <command> [options]
+
+commands:
+  precompile --dir <DIR> [--vm <VM>] [--cache <DIR>] [--jobs <N>] [--baseline]
+             [--opt-level <LEVEL>] [--json] [--isolated]
+      Compiles every .wasm file in DIR and prints a summary.
+
+      --vm        one of near-vm, wasmer2, wasmer0, wasmtime (default: the VM
+                  of the current protocol version)
+      --cache     directory to store the compiled artifacts in (default: the
+                  artifacts are discarded)
+      --jobs      number of contracts compiled in parallel (default: number
+                  of CPUs)
+      --baseline  generate code for the baseline CPU features only, so that
+                  the cache can be shared between different machines
+      --opt-level one of fast, optimized (default: optimized); only the
+                  Wasmtime compiler has optimization levels
+      --json      print the summary as JSON
+      --isolated  compile in jailed worker processes, one per job
+
+  consistency --dir <DIR> [--gas <GAS,...>] [--report <FILE>]
+      Calls every exported function of every .wasm file in DIR with an empty
+      input on all available VMs and compares the outcomes.
+
+      --gas     comma separated prepaid gas amounts to call each function with
+                (default: 1000000000000,10000000000000,300000000000000)
+      --report  file to write the JSON report to (default: standard output)
+
+  throughput (--wasm <FILE> | --wat <FILE>) --method <NAME> [--input <STRING>] [--vm <VM>]
+             [--calls <N>] [--threads <N>] [--huge-pages <MODE>]
+      Calls the view method of the contract repeatedly and prints the calls per
+      second achieved by each VM.
+
+      --wat      the contract in the text format, needs a build with the wat
+                 feature
+
+      --input    input of the call (default: empty)
+      --vm       only measure this VM (default: all available VMs)
+      --calls    number of calls per thread (default: 10000)
+      --threads  number of threads serving calls (default: number of CPUs)
+      --huge-pages
+                 one of off, transparent, explicit (default: off); puts the
+                 memories and the code of NearVM calls in huge pages
+
+  fuzz (--wasm <FILE> | --wat <FILE>) [--abi <FILE>] [--method <NAME>]... [--vm <VM>]
+       [--runs <N>] [--repeats <N>] [--seed <N>] [--gas <GAS>] [--gas-budget <GAS>]
+      Calls the methods described by the ABI of the contract with generated
+      inputs and reports the inputs breaking the invariants of the runtime.
+
+      --wat         the contract in the text format, needs a build with the wat
+                    feature
+
+      --abi         JSON file with the ABI (default: the unc_abi custom section
+                    of the contract)
+      --method      only fuzz this method, can be repeated (default: all methods)
+      --vm          VM to run the calls on (default: the VM of the current
+                    protocol version)
+      --runs        number of inputs per method (default: 100)
+      --repeats     number of times each input is called to check that the
+                    outcome does not change (default: 2)
+      --seed        seed of the generated inputs (default: 0)
+      --gas         prepaid gas of each call (default: 300000000000000)
+      --gas-budget  report the calls burning more gas than this
+
+  determinism --dir <DIR> [--threads <N,...>] [--allocators <ALLOCATOR,...>]
+              [--aslr <on|off,...>] [--gas <GAS,...>]
+      Calls every exported function of every .wasm file in DIR on all
+      available VMs in every combination of the environments below and
+      compares the outcomes.
+
+      --threads     comma separated sizes of the thread pool running the calls
+                    (default: 1 and the number of CPUs)
+      --allocators  comma separated allocators among system and poison, which
+                    shifts allocations and fills memory with junk (default:
+                    both)
+      --aslr        comma separated ASLR states, off only works on Linux
+                    (default: on,off)
+      --gas         comma separated prepaid gas amounts to call each function with
+                    (default: 1000000000000,10000000000000,300000000000000)
+
+  call (--wasm <FILE> | --wat <FILE>) --method <NAME> [--context <FILE>] [--config <FILE>]
+       [--input <STRING>] [--vm <VM>] [--stream-logs] [--fingerprint]
+      Calls the method of the contract against an empty mocked state and
+      prints the outcome as JSON.  The returned value is printed to stderr,
+      decoded with the ABI of the contract, as JSON otherwise, or in hex.
+      The exit code is non-zero if the call aborted.
+
+      --wat      the contract in the text format, needs a build with the wat
+                 feature
+
+      --context  JSON file with the VMContext of the call (default: a test
+                 context of alice.near calling its own contract)
+      --config   JSON file with the VM config, as in the RPC view of the
+                 runtime config (default: the config of the current protocol
+                 version)
+      --input    input of the call, replacing the one of the context
+      --vm       VM to run the call on, replacing the one of the config
+      --stream-logs
+                 print the logs to stderr as the contract emits them instead
+                 of in the outcome
+      --fingerprint
+                 add the hash of the host functions called with the gas
+                 burnt before each to the outcome, to compare executions
+
+  compat (--old <FILE> | --old-wat <FILE>) (--new <FILE> | --new-wat <FILE>)
+         [--old-abi <FILE>] [--new-abi <FILE>]
+      Compares the exported methods and the ABIs of two versions of a contract
+      and prints the changes breaking the callers of the old one.  The exit
+      code is non-zero if there are any.
+
+      --old-wat, --new-wat
+                 the contracts in the text format, need a build with the wat
+                 feature
+
+      --old-abi, --new-abi
+                 JSON files with the ABIs (default: the unc_abi custom
+                 sections of the contracts)
+
+  costs [--vm <VM>]... [--iterations <N>] [--samples <N>] [--tolerance <F>] [--diff <FILE>]
+      Times workloads of the host functions and the wasm instructions and
+      prints the gas parameters of the current protocol version they suggest.
+      Only meaningful in a release build on an idle machine.
+
+      --vm          estimate on this VM, can be repeated (default: all
+                    available VMs)
+      --iterations  iterations of the longer run of each workload (default:
+                    1000)
+      --samples     runs of each workload, the fastest counting (default: 5)
+      --tolerance   relative change below which a parameter is left out of the
+                    diff (default: 0.1)
+      --diff        file to write the changed parameters to, in the format of
+                    the runtime config diff files
+";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let result = match args.first().map(String::as_str) {
+        Some("precompile") => precompile::precompile(&args[1..]),
+        Some("consistency") => consistency::consistency(&args[1..]),
+        Some("throughput") => throughput::throughput(&args[1..]),
+        Some("fuzz") => fuzz::fuzz(&args[1..]),
+        Some("determinism") => determinism::determinism(&args[1..]),
+        Some("call") => call::call(&args[1..]),
+        Some("compat") => compat::compat(&args[1..]),
+        Some("costs") => costs::costs(&args[1..]),
+        // Not in the usage: started by `determinism`.
+        Some("determinism-run") => determinism::determinism_run(&args[1..]),
+        // Not in the usage: started by `precompile --isolated`.
+        Some("compile-worker") => {
+            return match unc_vm_runner::run_compile_worker() {
+                Ok(()) => ExitCode::SUCCESS,
+                Err(err) => {
+                    eprintln!("compile worker: {err}");
+                    ExitCode::FAILURE
+                }
+            };
+        }
+        Some("-h" | "--help") => {
+            print!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Some(command) => Err(format!("unknown command: {command}")),
+        None => Err("missing command".to_string()),
+    };
+    match result {
+        Ok(code) => code,
+        Err(msg) => {
+            eprintln!("error: {msg}\n\n{USAGE}");
+            ExitCode::from(2)
+        }
+    }
+}
+
+fn parse_vm_kind(name: &str) -> Result<VMKind, String> {
+    Ok(match name {
+        "near-vm" => VMKind::NearVm,
+        "wasmer2" => VMKind::Wasmer2,
+        "wasmer0" => VMKind::Wasmer0,
+        "wasmtime" => VMKind::Wasmtime,
+        _ => return Err(format!("unknown VM: {name}")),
+    })
+}
+
+const ALL_VMS: [VMKind; 4] = [VMKind::NearVm, VMKind::Wasmer2, VMKind::Wasmer0, VMKind::Wasmtime];
+
+/// Whether the VM is compiled in and can run contracts of this config.
+fn is_supported(vm_kind: VMKind, runtime_config: &RuntimeConfig) -> bool {
+    let config = unc_vm_runner::logic::Config::from(runtime_config.wasm_config.clone());
+    unc_vm_runner::check_backend(vm_kind, &config).is_ok()
+}
+
+/// Runtime configuration of the current protocol version.
+fn default_config() -> Arc<RuntimeConfig> {
+    let store = RuntimeConfigStore::new(None);
+    Arc::clone(store.get_config(PROTOCOL_VERSION))
+}
+
+/// Contract file given with `--wasm` or, in the text format, with `--wat`.
+enum ContractFile {
+    Wasm(PathBuf),
+    Wat(PathBuf),
+}
+
+impl ContractFile {
+    fn path(&self) -> &Path {
+        match self {
+            Self::Wasm(path) | Self::Wat(path) => path,
+        }
+    }
+
+    /// Reads the contract, parsing the text format within the default
+    /// [`unc_vm_runner::WatLimits`].
+    fn read(&self) -> Result<Vec<u8>, String> {
+        let path = self.path();
+        let cannot_read = |err| format!("cannot read {}: {err}", path.display());
+        match self {
+            Self::Wasm(_) => std::fs::read(path).map_err(cannot_read),
+            #[cfg(feature = "wat")]
+            Self::Wat(_) => {
+                let source = std::fs::read_to_string(path).map_err(cannot_read)?;
+                unc_vm_runner::parse_wat(&source, &unc_vm_runner::WatLimits::default())
+                    .map_err(|err| format!("{}: {err}", path.display()))
+            }
+            #[cfg(not(feature = "wat"))]
+            Self::Wat(_) => Err("--wat needs a build with the wat feature".to_string()),
+        }
+    }
+}
+
+fn wasm_files(dir: &Path) -> Result<Vec<PathBuf>, String> {
+    let read_dir =
+        std::fs::read_dir(dir).map_err(|err| format!("cannot read {}: {err}", dir.display()))?;
+    let mut files = Vec::new();
+    for entry in read_dir {
+        let path = entry.map_err(|err| format!("cannot read {}: {err}", dir.display()))?.path();
+        if path.is_file() && path.extension().map_or(false, |ext| ext == "wasm") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+fn duration_ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
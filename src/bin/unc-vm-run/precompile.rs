@@ -0,0 +1,232 @@
+//! `precompile` subcommand: compiles a directory of contracts in parallel.
+
+use crate::{default_config, duration_ms, parse_vm_kind, wasm_files};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Instant;
+use unc_vm_runner::logic::Config;
+use unc_vm_runner::internal::VMKindExt;
+use unc_vm_runner::logic::CompiledContractCache;
+use unc_vm_runner::{
+    get_contract_cache_key_with_options, precompile_contract_with_options, CodegenTarget,
+    CompilationInfo, CompileOptions, ConfigFingerprint, ContractCode, ContractPrecompilatonResult,
+    FilesystemContractRuntimeCache, IsolatedCompiler, IsolationLimits, MockCompiledContractCache,
+    OptLevel,
+};
+use unc_primitives_core::version::PROTOCOL_VERSION;
+
+#[derive(serde::Serialize)]
+struct PrecompileEntry {
+    path: PathBuf,
+    size: usize,
+    compile_time_ms: f64,
+    /// `compiled`, `cached` or `failed`.
+    status: &'static str,
+    /// The compiler and passes used, when known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compiler: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    opt_level: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    passes: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(serde::Serialize)]
+struct PrecompileSummary {
+    vm_kind: String,
+    config_fingerprint: String,
+    baseline_codegen: bool,
+    opt_level: String,
+    total: usize,
+    failed: usize,
+    total_compile_time_ms: f64,
+    contracts: Vec<PrecompileEntry>,
+}
+
+pub(crate) fn precompile(args: &[String]) -> Result<ExitCode, String> {
+    let mut dir = None;
+    let mut cache_dir = None;
+    let mut vm_kind = None;
+    let mut jobs = None;
+    let mut json = false;
+    let mut isolated = false;
+    let mut options = CompileOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("missing value for {arg}"));
+        match arg.as_str() {
+            "--dir" => dir = Some(PathBuf::from(value()?)),
+            "--cache" => cache_dir = Some(PathBuf::from(value()?)),
+            "--vm" => vm_kind = Some(parse_vm_kind(value()?)?),
+            "--jobs" => {
+                let n: usize = value()?.parse().map_err(|err| format!("invalid --jobs: {err}"))?;
+                jobs = Some(n.max(1));
+            }
+            "--json" => json = true,
+            "--isolated" => isolated = true,
+            "--baseline" => options.codegen = CodegenTarget::Baseline,
+            "--opt-level" => options.opt_level = parse_opt_level(value()?)?,
+            _ => return Err(format!("unknown option: {arg}")),
+        }
+    }
+    let dir = dir.ok_or("--dir is required")?;
+
+    let mut config = Config::from(default_config().wasm_config.clone());
+    if let Some(vm_kind) = vm_kind {
+        config.vm_kind = vm_kind;
+    }
+    // Fail early rather than in every worker if the VM cannot be used.
+    config.vm_kind.runtime_with_options(config.clone(), options).map_err(|err| err.to_string())?;
+    let cache: Box<dyn CompiledContractCache> = match cache_dir {
+        Some(cache_dir) => Box::new(
+            FilesystemContractRuntimeCache::new(&cache_dir)
+                .map_err(|err| format!("cannot open cache {}: {err}", cache_dir.display()))?,
+        ),
+        None => Box::new(MockCompiledContractCache::default()),
+    };
+    let files = wasm_files(&dir)?;
+    let jobs = jobs
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()))
+        .min(files.len().max(1));
+    let isolated = if isolated {
+        let exe = std::env::current_exe()
+            .map_err(|err| format!("cannot find the compile worker: {err}"))?;
+        Some(IsolatedCompiler::new(exe, ["compile-worker"], IsolationLimits::default(), jobs))
+    } else {
+        None
+    };
+
+    let next = AtomicUsize::new(0);
+    let entries = Mutex::new(Vec::with_capacity(files.len()));
+    std::thread::scope(|s| {
+        for _ in 0..jobs {
+            s.spawn(|| loop {
+                let Some(path) = files.get(next.fetch_add(1, Ordering::Relaxed)) else { break };
+                let entry = precompile_one(path, &config, options, &*cache, isolated.as_ref());
+                entries.lock().unwrap().push(entry);
+            });
+        }
+    });
+    let mut contracts = entries.into_inner().unwrap();
+    contracts.sort_by(|a, b| a.path.cmp(&b.path));
+
+    let summary = PrecompileSummary {
+        vm_kind: format!("{:?}", config.vm_kind),
+        config_fingerprint: config.fingerprint().to_string(),
+        baseline_codegen: options.codegen == CodegenTarget::Baseline,
+        opt_level: format!("{:?}", options.opt_level),
+        total: contracts.len(),
+        failed: contracts.iter().filter(|e| e.error.is_some()).count(),
+        total_compile_time_ms: contracts.iter().map(|e| e.compile_time_ms).sum(),
+        contracts,
+    };
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summary).unwrap());
+    } else {
+        print_summary(&summary);
+    }
+    Ok(if summary.failed == 0 { ExitCode::SUCCESS } else { ExitCode::FAILURE })
+}
+
+fn parse_opt_level(name: &str) -> Result<OptLevel, String> {
+    Ok(match name {
+        "fast" => OptLevel::Fast,
+        "optimized" => OptLevel::Optimized,
+        _ => return Err(format!("unknown optimization level: {name}")),
+    })
+}
+
+fn precompile_one(
+    path: &Path,
+    config: &Config,
+    options: CompileOptions,
+    cache: &dyn CompiledContractCache,
+    isolated: Option<&IsolatedCompiler>,
+) -> PrecompileEntry {
+    let code = match std::fs::read(path) {
+        Ok(code) => code,
+        Err(err) => {
+            return PrecompileEntry {
+                path: path.to_path_buf(),
+                size: 0,
+                compile_time_ms: 0.0,
+                status: "failed",
+                compiler: None,
+                opt_level: None,
+                passes: Vec::new(),
+                error: Some(format!("cannot read: {err}")),
+            }
+        }
+    };
+    let size = code.len();
+    let code = ContractCode::new(code, None);
+    let start = Instant::now();
+    let result = match isolated {
+        Some(compiler) => compiler
+            .precompile(&code, config, PROTOCOL_VERSION, options, cache)
+            .map_err(|err| format!("isolated compilation error: {err}")),
+        None => precompile_contract_with_options(&code, config, options, Some(cache))
+            .map_err(|err| format!("cache error: {err}")),
+    };
+    let compile_time_ms = duration_ms(start.elapsed());
+    let (status, info, error) = match result {
+        Ok(Ok(ContractPrecompilatonResult::ContractCompiled(info))) => {
+            ("compiled", Some(info), None)
+        }
+        Ok(Ok(ContractPrecompilatonResult::CacheNotAvailable)) => ("compiled", None, None),
+        Ok(Ok(ContractPrecompilatonResult::ContractAlreadyInCache)) => {
+            let key = get_contract_cache_key_with_options(&code, config, options);
+            let info = cache.get_compilation_info(&key).ok().flatten();
+            ("cached", info, None)
+        }
+        Ok(Err(err)) => ("failed", None, Some(format!("compilation error: {err}"))),
+        Err(err) => ("failed", None, Some(err)),
+    };
+    let (compiler, opt_level, passes) = match info {
+        Some(CompilationInfo { compiler, opt_level, passes, .. }) => {
+            let passes = passes.iter().map(ToString::to_string).collect();
+            (Some(compiler.to_string()), Some(format!("{opt_level:?}")), passes)
+        }
+        None => (None, None, Vec::new()),
+    };
+    PrecompileEntry {
+        path: path.to_path_buf(),
+        size,
+        compile_time_ms,
+        status,
+        compiler,
+        opt_level,
+        passes,
+        error,
+    }
+}
+
+fn print_summary(summary: &PrecompileSummary) {
+    println!(
+        "{:>10} {:>12}  {:<8}  {:<10}  {:<9}  {}",
+        "size", "compile ms", "status", "compiler", "opt level", "contract"
+    );
+    for entry in &summary.contracts {
+        println!(
+            "{:>10} {:>12.2}  {:<8}  {:<10}  {:<9}  {}",
+            entry.size,
+            entry.compile_time_ms,
+            entry.status,
+            entry.compiler.as_deref().unwrap_or("-"),
+            entry.opt_level.as_deref().unwrap_or("-"),
+            entry.path.display()
+        );
+        if let Some(error) = &entry.error {
+            println!("{:>57}{error}", "");
+        }
+    }
+    println!(
+        "{} contracts compiled with {} in {:.2} ms, {} failed",
+        summary.total, summary.vm_kind, summary.total_compile_time_ms, summary.failed
+    );
+    println!("config fingerprint {}", summary.config_fingerprint);
+}
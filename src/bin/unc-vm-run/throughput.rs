@@ -0,0 +1,141 @@
+//! `throughput` subcommand: measures how many view calls per second each VM
+//! serves through [`ThroughputRunner`]s.
+//!
+//! Every thread keeps its own runner of the same [`SharedContract`] and calls
+//! the method `--calls` times against an empty mocked state, so the numbers
+//! include the runtime overhead of a call but not the cost of storage access.
+//!
+//! With `--huge-pages`, NearVM puts the memories and the code of the calls in
+//! huge pages.  Comparing the runs with and without them under
+//! `perf stat -e dTLB-load-misses` on a contract going through its memory
+//! shows the TLB misses they save.
+
+use crate::{default_config, is_supported, parse_vm_kind, ContractFile, ALL_VMS};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Instant;
+use unc_vm_runner::logic::Config;
+use unc_parameters::vm::VMKind;
+use unc_vm_runner::logic::mocks::mock_context::get_view_context;
+use unc_vm_runner::logic::mocks::mock_external::MockedExternal;
+use unc_vm_runner::{ContractCode, HostSettings, HugePages, SharedContract};
+
+const DEFAULT_CALLS: usize = 10_000;
+
+pub(crate) fn throughput(args: &[String]) -> Result<ExitCode, String> {
+    let mut contract = None;
+    let mut method = None;
+    let mut input = Vec::new();
+    let mut vm_kind = None;
+    let mut calls = DEFAULT_CALLS;
+    let mut threads = None;
+    let mut huge_pages = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let mut value = || args.next().ok_or_else(|| format!("missing value for {arg}"));
+        match arg.as_str() {
+            "--wasm" => contract = Some(ContractFile::Wasm(PathBuf::from(value()?))),
+            "--wat" => contract = Some(ContractFile::Wat(PathBuf::from(value()?))),
+            "--method" => method = Some(value()?.clone()),
+            "--input" => input = value()?.as_bytes().to_vec(),
+            "--vm" => vm_kind = Some(parse_vm_kind(value()?)?),
+            "--calls" => {
+                calls = value()?.parse().map_err(|err| format!("invalid --calls: {err}"))?;
+            }
+            "--threads" => {
+                let n: usize =
+                    value()?.parse().map_err(|err| format!("invalid --threads: {err}"))?;
+                threads = Some(n.max(1));
+            }
+            "--huge-pages" => huge_pages = parse_huge_pages(value()?)?,
+            _ => return Err(format!("unknown option: {arg}")),
+        }
+    }
+    let contract = contract.ok_or("--wasm or --wat is required")?;
+    let method = method.ok_or("--method is required")?;
+    let code = contract.read()?;
+    let threads =
+        threads.unwrap_or_else(|| std::thread::available_parallelism().map_or(1, |n| n.get()));
+
+    let runtime_config = default_config();
+    let vm_kinds: Vec<VMKind> = match vm_kind {
+        Some(vm_kind) => vec![vm_kind],
+        None => ALL_VMS.into_iter().filter(|&vm| is_supported(vm, &runtime_config)).collect(),
+    };
+    let fees = Arc::new(runtime_config.fees.clone());
+    let context = get_view_context(runtime_config.wasm_config.limit_config.max_gas_burnt);
+
+    println!("{:<10} {:>12} {:>12}  {}", "vm", "calls", "calls/s", "error");
+    let mut failed = false;
+    for vm_kind in vm_kinds {
+        let mut config = Config::from(runtime_config.wasm_config.clone());
+        config.vm_kind = vm_kind;
+        let code = ContractCode::new(code.clone(), None);
+        let host = HostSettings { huge_pages, ..HostSettings::default() };
+        let contract = match SharedContract::new(config, Arc::clone(&fees), code) {
+            Ok(contract) => contract.with_host_settings(host),
+            Err(err) => {
+                failed = true;
+                println!("{:<10} {:>12} {:>12}  {err}", format!("{vm_kind:?}"), "-", "-");
+                continue;
+            }
+        };
+        let start = Instant::now();
+        let errors: Vec<String> = std::thread::scope(|s| {
+            let handles: Vec<_> = (0..threads)
+                .map(|_| {
+                    let (contract, context, input, method) = (&contract, &context, &input, &method);
+                    s.spawn(move || -> Result<(), String> {
+                        let runner =
+                            contract.runner(context.clone()).map_err(|err| err.to_string())?;
+                        for _ in 0..calls {
+                            let mut ext = MockedExternal::new();
+                            let outcome = runner
+                                .view(method, input.clone(), &mut ext)
+                                .map_err(|err| err.to_string())?;
+                            if let Some(err) = outcome.aborted {
+                                return Err(format!("call aborted: {err}"));
+                            }
+                        }
+                        Ok(())
+                    })
+                })
+                .collect();
+            handles.into_iter().filter_map(|h| h.join().unwrap().err()).collect()
+        });
+        let elapsed = start.elapsed();
+        let total = calls * threads;
+        match errors.first() {
+            Some(err) => {
+                failed = true;
+                println!("{:<10} {:>12} {:>12}  {err}", format!("{vm_kind:?}"), total, "-");
+            }
+            None => println!(
+                "{:<10} {:>12} {:>12.0}",
+                format!("{vm_kind:?}"),
+                total,
+                total as f64 / elapsed.as_secs_f64()
+            ),
+        }
+    }
+    Ok(if failed { ExitCode::FAILURE } else { ExitCode::SUCCESS })
+}
+
+/// Huge pages given to `--huge-pages`, `None` for `off`.
+fn parse_huge_pages(value: &str) -> Result<Option<HugePages>, String> {
+    let huge_pages = match value {
+        "off" => return Ok(None),
+        "transparent" => HugePages::Transparent,
+        "explicit" => HugePages::Explicit,
+        _ => {
+            return Err(format!(
+                "invalid --huge-pages: {value}, expected off, transparent or explicit"
+            ))
+        }
+    };
+    if !cfg!(all(feature = "unc_vm", target_arch = "x86_64", target_os = "linux")) {
+        return Err("--huge-pages needs a build with NearVM on Linux".to_string());
+    }
+    Ok(Some(huge_pages))
+}
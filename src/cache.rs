@@ -1,13 +1,18 @@
 use crate::errors::ContractPrecompilatonResult;
+use crate::fingerprint::ConfigFingerprint;
 use crate::logic::errors::{CacheError, CompilationError};
 use crate::logic::{CompiledContract, CompiledContractCache, Config};
-use crate::runner::VMKindExt;
+use crate::prepare::{FunctionSizeLimit, NanCanonicalization};
+use crate::runner::{CodegenTarget, CompilationInfo, CompileOptions, VMKindExt};
 use crate::ContractCode;
-use borsh::BorshSerialize;
+use borsh::{BorshDeserialize, BorshSerialize};
 use unc_parameters::vm::VMKind;
 use unc_primitives_core::hash::CryptoHash;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
 
 #[derive(Debug, Clone, BorshSerialize)]
@@ -21,10 +26,16 @@ enum ContractCacheKey {
         vm_kind: VMKind,
         vm_hash: u64,
     },
+    Version5 {
+        code_hash: CryptoHash,
+        vm_config_fingerprint: CryptoHash,
+        vm_kind: VMKind,
+        vm_hash: u64,
+    },
 }
 
-fn vm_hash(vm_kind: VMKind) -> u64 {
-    match vm_kind {
+pub(crate) fn vm_hash(config: &Config) -> u64 {
+    match config.vm_kind {
         #[cfg(all(feature = "wasmer0_vm", target_arch = "x86_64"))]
         VMKind::Wasmer0 => crate::wasmer_runner::wasmer0_vm_hash(),
         #[cfg(not(all(feature = "wasmer0_vm", target_arch = "x86_64")))]
@@ -34,7 +45,9 @@ fn vm_hash(vm_kind: VMKind) -> u64 {
         #[cfg(not(all(feature = "wasmer2_vm", target_arch = "x86_64")))]
         VMKind::Wasmer2 => panic!("Wasmer2 is not enabled"),
         #[cfg(feature = "wasmtime_vm")]
-        VMKind::Wasmtime => crate::wasmtime_runner::wasmtime_vm_hash(),
+        VMKind::Wasmtime => {
+            crate::wasmtime_runner::wasmtime_vm_hash(config, crate::OptLevel::default())
+        }
         #[cfg(not(feature = "wasmtime_vm"))]
         VMKind::Wasmtime => panic!("Wasmtime is not enabled"),
         #[cfg(all(feature = "unc_vm", target_arch = "x86_64"))]
@@ -45,12 +58,89 @@ fn vm_hash(vm_kind: VMKind) -> u64 {
 }
 
 pub fn get_contract_cache_key(code: &ContractCode, config: &Config) -> CryptoHash {
+    contract_cache_key(code, config, CodegenTarget::Host)
+}
+
+/// Cache key of the artifact generated for `codegen`.
+///
+/// The keys of [`CodegenTarget::Host`] artifacts are the same as before the
+/// codegen target was introduced, baseline artifacts get a distinct `vm_hash`.
+pub(crate) fn contract_cache_key(
+    code: &ContractCode,
+    config: &Config,
+    codegen: CodegenTarget,
+) -> CryptoHash {
+    contract_cache_key_for_hash(code.hash(), config, codegen)
+}
+
+pub(crate) fn contract_cache_key_for_hash(
+    code_hash: &CryptoHash,
+    config: &Config,
+    codegen: CodegenTarget,
+) -> CryptoHash {
     let _span = tracing::debug_span!(target: "vm", "get_key").entered();
-    let key = ContractCacheKey::Version4 {
-        code_hash: *code.hash(),
-        vm_config_non_crypto_hash: config.non_crypto_hash(),
-        vm_kind: config.vm_kind,
-        vm_hash: vm_hash(config.vm_kind),
+    let vm_hash = match codegen {
+        CodegenTarget::Host => vm_hash(config),
+        CodegenTarget::Baseline => {
+            crate::utils::stable_hash((vm_hash(config), crate::runner::BASELINE_CPU_FEATURES))
+        }
+    };
+    contract_cache_key_for_vm_hash(code_hash, config, vm_hash)
+}
+
+/// Cache key of the artifact compiled with `options`, as by
+/// [`precompile_contract_with_options`].
+///
+/// Same as [`get_contract_cache_key`] for the default options.  The artifacts
+/// of [`CodegenTarget::Baseline`] get their own keys, and so do those of each
+/// optimization level of Wasmtime.
+pub fn get_contract_cache_key_with_options(
+    code: &ContractCode,
+    config: &Config,
+    options: CompileOptions,
+) -> CryptoHash {
+    #[cfg(feature = "wasmtime_vm")]
+    if config.vm_kind == VMKind::Wasmtime {
+        let vm_hash = crate::wasmtime_runner::wasmtime_vm_hash(config, options.opt_level);
+        return contract_cache_key_for_vm_hash(code.hash(), config, vm_hash);
+    }
+    contract_cache_key(code, config, options.codegen)
+}
+
+/// Cache key of the artifact of `code_hash` generated by a compiler hashing
+/// to `vm_hash`.
+pub(crate) fn contract_cache_key_for_vm_hash(
+    code_hash: &CryptoHash,
+    config: &Config,
+    mut vm_hash: u64,
+) -> CryptoHash {
+    // The artifacts of the compilers canonicalizing the NaNs themselves get
+    // other keys.
+    if NanCanonicalization::for_config(config) == NanCanonicalization::Prepare {
+        vm_hash = crate::utils::stable_hash((vm_hash, "nan_canonicalization_pass"));
+    }
+    // And the artifacts compiled without the limit on the size of functions,
+    // which include the contracts it rejects.
+    if let Some(limit) = FunctionSizeLimit::for_config(config) {
+        vm_hash = crate::utils::stable_hash((vm_hash, limit.max_function_body_size));
+    }
+    // The configs of `unc-parameters` keep the keys their artifacts had
+    // before the fingerprint.  The others get keys which do not depend on the
+    // `Hash` implementations of std, see `crate::fingerprint`.
+    let key = if config.compiles_as_base() {
+        ContractCacheKey::Version4 {
+            code_hash: *code_hash,
+            vm_config_non_crypto_hash: config.base.non_crypto_hash(),
+            vm_kind: config.vm_kind,
+            vm_hash,
+        }
+    } else {
+        ContractCacheKey::Version5 {
+            code_hash: *code_hash,
+            vm_config_fingerprint: config.fingerprint(),
+            vm_kind: config.vm_kind,
+            vm_hash,
+        }
     };
     CryptoHash::hash_borsh(key)
 }
@@ -58,6 +148,7 @@ pub fn get_contract_cache_key(code: &ContractCode, config: &Config) -> CryptoHas
 #[derive(Default)]
 pub struct MockCompiledContractCache {
     store: Arc<Mutex<HashMap<CryptoHash, CompiledContract>>>,
+    info: Arc<Mutex<HashMap<CryptoHash, CompilationInfo>>>,
 }
 
 impl MockCompiledContractCache {
@@ -75,6 +166,15 @@ impl CompiledContractCache for MockCompiledContractCache {
     fn get(&self, key: &CryptoHash) -> std::io::Result<Option<CompiledContract>> {
         Ok(self.store.lock().unwrap().get(key).map(Clone::clone))
     }
+
+    fn put_compilation_info(&self, key: &CryptoHash, info: &CompilationInfo) -> io::Result<()> {
+        self.info.lock().unwrap().insert(*key, info.clone());
+        Ok(())
+    }
+
+    fn get_compilation_info(&self, key: &CryptoHash) -> io::Result<Option<CompilationInfo>> {
+        Ok(self.info.lock().unwrap().get(key).cloned())
+    }
 }
 
 impl fmt::Debug for MockCompiledContractCache {
@@ -85,6 +185,446 @@ impl fmt::Debug for MockCompiledContractCache {
     }
 }
 
+/// Compiled contract cache persisting the artifacts in a directory, one file
+/// per cache key.
+///
+/// The keys cover the code hash, the VM kind and version and the hash of the
+/// whole VM config, which includes the contract preparation version, so
+/// artifacts of other VMs or configs are never loaded, and a directory can be
+/// shared by nodes running different versions.
+///
+/// Entries are written to a temporary file first and then renamed into place,
+/// so concurrent readers, in this process or others, never observe a
+/// partially written artifact.  The [`CompilationInfo`] of an artifact is
+/// kept next to it, in a file with the `info` extension.
+///
+/// Caches opened with [`Self::with_max_size`] evict the least recently used
+/// artifacts when their total size exceeds the limit.  They only account for
+/// the artifacts found when opening and the ones read or written through
+/// them, so processes sharing the directory each enforce the limit on their
+/// own view of it.
+#[derive(Clone, Debug)]
+pub struct FilesystemContractRuntimeCache {
+    dir: PathBuf,
+    lru: Option<Arc<Mutex<LruIndex>>>,
+}
+
+/// Sizes and recency of the entries of a size limited filesystem cache.
+#[derive(Debug)]
+struct LruIndex {
+    max_size: u64,
+    size: u64,
+    /// Size and last use of each entry, artifact and info files together.
+    entries: HashMap<CryptoHash, (u64, u64)>,
+    by_use: BTreeMap<u64, CryptoHash>,
+    clock: u64,
+}
+
+impl LruIndex {
+    /// Marks an entry as just used, one of its files going from `old_size`
+    /// to `new_size` bytes.
+    fn touch(&mut self, key: &CryptoHash, old_size: u64, new_size: u64) {
+        self.clock += 1;
+        let (size, last_use) = self.entries.entry(*key).or_insert((0, 0));
+        self.by_use.remove(last_use);
+        let old_size = old_size.min(*size);
+        *size = *size - old_size + new_size;
+        *last_use = self.clock;
+        self.by_use.insert(self.clock, *key);
+        self.size = self.size - old_size + new_size;
+    }
+
+    /// Removes least recently used entries until the cache fits its limit,
+    /// returning them.
+    fn evict(&mut self) -> Vec<CryptoHash> {
+        let mut evicted = Vec::new();
+        while self.size > self.max_size {
+            let Some((_, key)) = self.by_use.pop_first() else { break };
+            let (size, _) = self.entries.remove(&key).unwrap();
+            self.size -= size;
+            evicted.push(key);
+        }
+        evicted
+    }
+}
+
+/// Distinguishes the temporary files written concurrently by one process.
+static TMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl FilesystemContractRuntimeCache {
+    /// Opens the cache in `dir`, creating the directory if it does not exist.
+    pub fn new(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        std::fs::create_dir_all(&dir)?;
+        Ok(Self { dir, lru: None })
+    }
+
+    /// Opens the cache in `dir`, creating the directory if it does not exist,
+    /// and keeps the artifacts under `max_size` bytes on disk.
+    ///
+    /// The recency of the artifacts already in the directory is their
+    /// modification time, which reads through the cache update.
+    pub fn with_max_size(dir: impl Into<PathBuf>, max_size: u64) -> io::Result<Self> {
+        let mut cache = Self::new(dir)?;
+        let mut found = HashMap::<CryptoHash, (u64, std::time::SystemTime)>::new();
+        for entry in std::fs::read_dir(&cache.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else { continue };
+            let (key, is_info) = match name.strip_suffix(".info") {
+                Some(key) => (key, true),
+                None => (name, false),
+            };
+            // Skips the temporary files and anything else not ours.
+            let Ok(key) = key.parse::<CryptoHash>() else { continue };
+            let metadata = entry.metadata()?;
+            let (size, modified) = found.entry(key).or_insert((0, std::time::UNIX_EPOCH));
+            *size += metadata.len();
+            if !is_info {
+                *modified = metadata.modified()?;
+            }
+        }
+        let mut found: Vec<_> = found.into_iter().collect();
+        found.sort_by_key(|(_, (_, modified))| *modified);
+        let mut lru = LruIndex {
+            max_size,
+            size: 0,
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+        };
+        for (key, (size, _)) in found {
+            lru.touch(&key, 0, size);
+        }
+        let evicted = lru.evict();
+        cache.lru = Some(Arc::new(Mutex::new(lru)));
+        cache.remove(&evicted)?;
+        Ok(cache)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Total size of the artifacts, if the size of the cache is limited.
+    pub fn size(&self) -> Option<u64> {
+        self.lru.as_ref().map(|lru| lru.lock().unwrap().size)
+    }
+
+    fn path(&self, key: &CryptoHash) -> PathBuf {
+        self.dir.join(key.to_string())
+    }
+
+    fn write(path: &Path, bytes: &[u8]) -> io::Result<()> {
+        let tmp = path.with_extension(format!(
+            "tmp.{}.{}",
+            std::process::id(),
+            TMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::write(&tmp, bytes)?;
+        std::fs::rename(&tmp, path)
+    }
+
+    fn read<T: BorshDeserialize>(path: &Path) -> io::Result<Option<T>> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        T::try_from_slice(&bytes)
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Writes the file of an entry and accounts for it, evicting other
+    /// entries if the cache is full.
+    fn write_entry(&self, key: &CryptoHash, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        let Some(lru) = &self.lru else { return Self::write(path, bytes) };
+        let old_size = std::fs::metadata(path).map_or(0, |metadata| metadata.len());
+        Self::write(path, bytes)?;
+        let evicted = {
+            let mut lru = lru.lock().unwrap();
+            lru.touch(key, old_size, bytes.len() as u64);
+            lru.evict()
+        };
+        self.remove(&evicted)
+    }
+
+    /// Marks an entry as used, so that it is evicted last.
+    fn touch(&self, key: &CryptoHash, path: &Path) {
+        let Some(lru) = &self.lru else { return };
+        lru.lock().unwrap().touch(key, 0, 0);
+        // Keeps the recency across restarts; failing to is harmless.
+        let _ = std::fs::File::options()
+            .write(true)
+            .open(path)
+            .and_then(|file| file.set_modified(std::time::SystemTime::now()));
+    }
+
+    fn remove(&self, keys: &[CryptoHash]) -> io::Result<()> {
+        for key in keys {
+            let path = self.path(key);
+            for path in [path.with_extension("info"), path] {
+                match std::fs::remove_file(&path) {
+                    Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CompiledContractCache for FilesystemContractRuntimeCache {
+    fn put(&self, key: &CryptoHash, value: CompiledContract) -> io::Result<()> {
+        self.write_entry(key, &self.path(key), &borsh::to_vec(&value)?)
+    }
+
+    fn get(&self, key: &CryptoHash) -> io::Result<Option<CompiledContract>> {
+        let path = self.path(key);
+        let value = Self::read(&path)?;
+        if value.is_some() {
+            self.touch(key, &path);
+        }
+        Ok(value)
+    }
+
+    fn has(&self, key: &CryptoHash) -> io::Result<bool> {
+        self.path(key).try_exists()
+    }
+
+    fn put_compilation_info(&self, key: &CryptoHash, info: &CompilationInfo) -> io::Result<()> {
+        self.write_entry(key, &self.path(key).with_extension("info"), &borsh::to_vec(info)?)
+    }
+
+    fn get_compilation_info(&self, key: &CryptoHash) -> io::Result<Option<CompilationInfo>> {
+        Self::read(&self.path(key).with_extension("info"))
+    }
+}
+
+/// Most threads a [`PrefetchingContractCache`] prefetches with by default.
+const DEFAULT_PREFETCH_THREADS: usize = 4;
+
+/// Compiled contract cache holding prefetched artifacts in memory in front of
+/// another cache.
+///
+/// Before applying a chunk, [`Self::prefetch`] reads the artifacts of the
+/// contracts its receipts call from the underlying cache on a background
+/// thread, so that loading them does not wait for the disk.  The prefetched
+/// artifacts are kept until the next prefetch and their total size is bounded
+/// by the memory budget; those not fitting are left on disk.  A prefetch
+/// started while another runs cancels the reads left of the other, and a
+/// `put` keeps the artifacts read before it from being held.
+///
+/// Reading and deserializing the artifacts is spread over a small pool of
+/// threads, one per CPU and at most four unless set with
+/// [`Self::with_threads`].  Loading a single artifact into the VM is
+/// done by the VM's engine and remains single-threaded; `cargo bench --bench
+/// cold_load` measures the latency prefetching saves for large contracts.
+#[derive(Clone)]
+pub struct PrefetchingContractCache {
+    inner: Arc<dyn CompiledContractCache>,
+    config: Config,
+    memory_budget: usize,
+    threads: usize,
+    prefetched: Arc<Mutex<Prefetched>>,
+}
+
+/// The artifacts held by a [`PrefetchingContractCache`].
+#[derive(Default)]
+struct Prefetched {
+    artifacts: HashMap<CryptoHash, CompiledContract>,
+    /// Total size of the artifacts.
+    bytes: usize,
+    /// Number of prefetches started, telling the reads of a prefetch that a
+    /// later one replaced it.
+    generation: u64,
+    /// Number of `put`s, telling the reads which started before one that the
+    /// artifact they read may be stale.
+    writes: u64,
+}
+
+impl Prefetched {
+    fn insert(&mut self, key: CryptoHash, artifact: CompiledContract) {
+        self.bytes += artifact_size(&artifact);
+        if let Some(previous) = self.artifacts.insert(key, artifact) {
+            self.bytes -= artifact_size(&previous);
+        }
+    }
+
+    fn remove(&mut self, key: &CryptoHash) {
+        if let Some(artifact) = self.artifacts.remove(key) {
+            self.bytes -= artifact_size(&artifact);
+        }
+    }
+}
+
+impl PrefetchingContractCache {
+    /// Makes a cache in front of `inner`, which holds the artifacts compiled
+    /// with `config`, prefetching at most `memory_budget` bytes of artifacts.
+    pub fn new(
+        inner: Arc<dyn CompiledContractCache>,
+        config: Config,
+        memory_budget: usize,
+    ) -> Self {
+        let threads = std::thread::available_parallelism()
+            .map_or(1, |cpus| cpus.get().min(DEFAULT_PREFETCH_THREADS));
+        Self { inner, config, memory_budget, threads, prefetched: Default::default() }
+    }
+
+    /// Prefetches with `threads` threads reading artifacts in parallel, one
+    /// reading them all in order.
+    pub fn with_threads(mut self, threads: usize) -> Self {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Starts loading the artifacts of the contracts with the given code
+    /// hashes in the background, replacing the previously prefetched ones.
+    ///
+    /// Contracts missing from the underlying cache are skipped, as are those
+    /// exceeding the remaining memory budget.  The returned handle yields the
+    /// number of artifacts held once the prefetch is done.
+    pub fn prefetch(
+        &self,
+        code_hashes: impl IntoIterator<Item = CryptoHash>,
+    ) -> std::thread::JoinHandle<usize> {
+        let mut wanted = HashSet::new();
+        let keys: Vec<CryptoHash> = code_hashes
+            .into_iter()
+            .map(|hash| contract_cache_key_for_hash(&hash, &self.config, CodegenTarget::Host))
+            .filter(|key| wanted.insert(*key))
+            .collect();
+        // Keep what is already prefetched for the new contracts.
+        let generation = {
+            let mut prefetched = self.prefetched.lock().unwrap();
+            let Prefetched { artifacts, bytes, generation, .. } = &mut *prefetched;
+            artifacts.retain(|key, _| wanted.contains(key));
+            *bytes = artifacts.values().map(artifact_size).sum();
+            *generation += 1;
+            *generation
+        };
+        let this = self.clone();
+        std::thread::spawn(move || {
+            let _span =
+                tracing::debug_span!(target: "vm", "prefetch", count = keys.len()).entered();
+            let next = AtomicUsize::new(0);
+            std::thread::scope(|s| {
+                for _ in 0..this.threads.min(keys.len()) {
+                    s.spawn(|| {
+                        while let Some(key) = keys.get(next.fetch_add(1, Ordering::Relaxed)) {
+                            if !this.prefetch_one(key, generation) {
+                                break;
+                            }
+                        }
+                    });
+                }
+            });
+            this.prefetched.lock().unwrap().artifacts.len()
+        })
+    }
+
+    /// Reads the artifact of `key` for the prefetch of `generation`, false
+    /// once a later prefetch replaced it.
+    fn prefetch_one(&self, key: &CryptoHash, generation: u64) -> bool {
+        let writes = {
+            let prefetched = self.prefetched.lock().unwrap();
+            if prefetched.generation != generation {
+                return false;
+            }
+            if prefetched.artifacts.contains_key(key) {
+                return true;
+            }
+            prefetched.writes
+        };
+        let artifact = match self.inner.get(key) {
+            Ok(Some(artifact)) => artifact,
+            Ok(None) => return true,
+            Err(err) => {
+                tracing::debug!(target: "vm", %err, "cannot prefetch contract");
+                return true;
+            }
+        };
+        let mut prefetched = self.prefetched.lock().unwrap();
+        if prefetched.generation != generation {
+            return false;
+        }
+        let fits = prefetched.bytes + artifact_size(&artifact) <= self.memory_budget;
+        if fits && prefetched.writes == writes {
+            prefetched.insert(*key, artifact);
+        }
+        true
+    }
+
+    /// Total size of the prefetched artifacts.
+    pub fn prefetched_bytes(&self) -> usize {
+        self.prefetched.lock().unwrap().bytes
+    }
+}
+
+fn artifact_size(artifact: &CompiledContract) -> usize {
+    match artifact {
+        CompiledContract::Code(code) => code.len(),
+        CompiledContract::CompileModuleError(_) => std::mem::size_of::<CompiledContract>(),
+    }
+}
+
+impl CompiledContractCache for PrefetchingContractCache {
+    fn put(&self, key: &CryptoHash, value: CompiledContract) -> io::Result<()> {
+        let result = self.inner.put(key, value);
+        // After the write, so that the reads of the old artifact racing it
+        // are not held either.
+        let mut prefetched = self.prefetched.lock().unwrap();
+        prefetched.remove(key);
+        prefetched.writes += 1;
+        result
+    }
+
+    fn get(&self, key: &CryptoHash) -> io::Result<Option<CompiledContract>> {
+        if let Some(artifact) = self.prefetched.lock().unwrap().artifacts.get(key) {
+            return Ok(Some(artifact.clone()));
+        }
+        self.inner.get(key)
+    }
+
+    fn has(&self, key: &CryptoHash) -> io::Result<bool> {
+        if self.prefetched.lock().unwrap().artifacts.contains_key(key) {
+            return Ok(true);
+        }
+        self.inner.has(key)
+    }
+
+    fn put_compilation_info(&self, key: &CryptoHash, info: &CompilationInfo) -> io::Result<()> {
+        self.inner.put_compilation_info(key, info)
+    }
+
+    fn get_compilation_info(&self, key: &CryptoHash) -> io::Result<Option<CompilationInfo>> {
+        self.inner.get_compilation_info(key)
+    }
+}
+
+impl fmt::Debug for PrefetchingContractCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PrefetchingContractCache")
+            .field("memory_budget", &self.memory_budget)
+            .field("prefetched_bytes", &self.prefetched_bytes())
+            .finish()
+    }
+}
+
+/// Reads the compiled contract of a call from `cache`, if any.
+pub(crate) fn lookup(
+    cache: Option<&dyn CompiledContractCache>,
+    key: &CryptoHash,
+    vm_kind: VMKind,
+) -> Result<Option<CompiledContract>, CacheError> {
+    let Some(cache) = cache else { return Ok(None) };
+    let _span = tracing::debug_span!(target: "vm", "cache_lookup", ?vm_kind).entered();
+    let record = cache.get(key).map_err(CacheError::ReadError)?;
+    crate::metrics::cache_lookup(vm_kind, record.is_some());
+    Ok(record)
+}
+
 /// Precompiles contract for the current default VM, and stores result to the cache.
 /// Returns `Ok(true)` if compiled code was added to the cache, and `Ok(false)` if element
 /// is already in the cache, or if cache is `None`.
@@ -92,17 +632,44 @@ pub fn precompile_contract(
     code: &ContractCode,
     config: &Config,
     cache: Option<&dyn CompiledContractCache>,
+) -> Result<Result<ContractPrecompilatonResult, CompilationError>, CacheError> {
+    precompile_contract_for_codegen(code, config, CodegenTarget::Host, cache)
+}
+
+/// Same as [`precompile_contract`] but generates code for `codegen`.
+///
+/// Panics if the VM does not support `codegen`, see
+/// [`VMKindExt::runtime_for_codegen`].
+pub fn precompile_contract_for_codegen(
+    code: &ContractCode,
+    config: &Config,
+    codegen: CodegenTarget,
+    cache: Option<&dyn CompiledContractCache>,
+) -> Result<Result<ContractPrecompilatonResult, CompilationError>, CacheError> {
+    let options = CompileOptions { codegen, ..CompileOptions::default() };
+    precompile_contract_with_options(code, config, options, cache)
+}
+
+/// Same as [`precompile_contract`] but compiles with `options`, e.g. with
+/// [`crate::OptLevel::Fast`] at deploy time.
+///
+/// Panics if the VM does not support `options.codegen`, see
+/// [`VMKindExt::runtime_for_codegen`].
+pub fn precompile_contract_with_options(
+    code: &ContractCode,
+    config: &Config,
+    options: CompileOptions,
+    cache: Option<&dyn CompiledContractCache>,
 ) -> Result<Result<ContractPrecompilatonResult, CompilationError>, CacheError> {
     let _span = tracing::debug_span!(target: "vm", "precompile_contract").entered();
     let vm_kind = config.vm_kind;
-    let runtime = vm_kind
-        .runtime(config.clone())
-        .unwrap_or_else(|| panic!("the {vm_kind:?} runtime has not been enabled at compile time"));
+    let runtime =
+        vm_kind.runtime_with_options(config.clone(), options).unwrap_or_else(|err| panic!("{err}"));
     let cache = match cache {
         Some(it) => it,
         None => return Ok(Ok(ContractPrecompilatonResult::CacheNotAvailable)),
     };
-    let key = get_contract_cache_key(code, config);
+    let key = get_contract_cache_key_with_options(code, config, options);
     // Check if we already cached with such a key.
     if cache.has(&key).map_err(CacheError::ReadError)? {
         return Ok(Ok(ContractPrecompilatonResult::ContractAlreadyInCache));
@@ -0,0 +1,575 @@
+//! Chaos testing of the environment errors of the runner.
+//!
+//! The database behind the [`External`] and the [`CompiledContractCache`] of
+//! a node can fail, be slow, or fail after doing a write.  Such failures are
+//! the node's, not the contract's: the runner must return them as a
+//! [`VMRunnerError`], so the node retries the call, and never as a
+//! [`crate::logic::errors::FunctionCallError`] in an outcome, which would be
+//! final and differ between the nodes which saw the failure and the others.
+//!
+//! [`ChaosExternal`] and [`ChaosCache`] wrap an [`External`] and a cache and
+//! inject failures, latencies and partial writes drawn from a seed, so a
+//! failing run is reproduced by running it again with the same seed.
+//! [`check_chaos_run`] then checks the result of a call against its outcome
+//! without chaos: a call must either fail with an environment error, only
+//! when a fault was injected, or have the same outcome.
+//!
+//! A partial write is a write which is done but reported as failed, as a
+//! database write which times out after landing.  The caches of nodes write
+//! records atomically, so nothing is ever written truncated.
+
+use crate::logic::errors::{AnyError, VMLogicError, VMRunnerError};
+use crate::logic::shuffle::{derive_seed, DeterministicRng};
+use crate::logic::types::ReceiptIndex;
+use crate::logic::{CompiledContract, CompiledContractCache, External, TrieNodesCount, ValuePtr};
+use crate::logic::{StorageGetMode, VMOutcome};
+use crate::runner::VMResult;
+use crate::CompilationInfo;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+use unc_crypto::PublicKey;
+use unc_primitives_core::hash::CryptoHash;
+use unc_primitives_core::types::{AccountId, Balance, Gas, GasWeight, Nonce, Power};
+
+type Result<T, E = VMLogicError> = std::result::Result<T, E>;
+
+/// What to inject, see the module documentation.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ChaosConfig {
+    pub seed: u64,
+    /// Probability of an operation which can fail to fail.
+    pub failure_rate: f64,
+    /// Probability of a write which did not fail to be done but reported as
+    /// failed.
+    pub partial_write_rate: f64,
+    /// Each operation is delayed by up to this.
+    pub max_latency: Duration,
+}
+
+impl ChaosConfig {
+    pub fn new(seed: u64) -> Self {
+        Self {
+            seed,
+            failure_rate: 0.05,
+            partial_write_rate: 0.05,
+            max_latency: Duration::from_micros(50),
+        }
+    }
+}
+
+/// The error of an injected failure.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("chaos: injected {0}")]
+pub struct ChaosError(&'static str);
+
+/// Draws the faults of a wrapper.
+struct Injector {
+    config: ChaosConfig,
+    rng: Mutex<DeterministicRng>,
+    faults: AtomicUsize,
+}
+
+impl Injector {
+    fn new(config: ChaosConfig, domain: &[u8]) -> Self {
+        let seed = derive_seed(&config.seed.to_le_bytes(), domain);
+        let rng = Mutex::new(DeterministicRng::new(&seed.0));
+        Self { config, rng, faults: AtomicUsize::new(0) }
+    }
+
+    fn chance(&self, rng: &mut DeterministicRng, rate: f64) -> bool {
+        const SCALE: u64 = 1 << 32;
+        rng.next_below(SCALE) < (rate.clamp(0.0, 1.0) * SCALE as f64) as u64
+    }
+
+    /// Delays the operation, and tells whether to fail it.
+    fn fail(&self) -> bool {
+        let mut rng = self.rng.lock().unwrap();
+        let max_latency = self.config.max_latency.as_nanos() as u64;
+        if max_latency > 0 {
+            std::thread::sleep(Duration::from_nanos(rng.next_below(max_latency + 1)));
+        }
+        let fail = self.chance(&mut rng, self.config.failure_rate);
+        if fail {
+            self.faults.fetch_add(1, Ordering::Relaxed);
+        }
+        fail
+    }
+
+    /// Tells whether to report a write which was done as failed.
+    fn partial_write(&self) -> bool {
+        let mut rng = self.rng.lock().unwrap();
+        let partial = self.chance(&mut rng, self.config.partial_write_rate);
+        if partial {
+            self.faults.fetch_add(1, Ordering::Relaxed);
+        }
+        partial
+    }
+
+    fn faults(&self) -> usize {
+        self.faults.load(Ordering::Relaxed)
+    }
+}
+
+fn external_error(what: &'static str) -> VMLogicError {
+    VMLogicError::ExternalError(AnyError::new(ChaosError(what)))
+}
+
+fn cache_error(what: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, ChaosError(what))
+}
+
+/// [`External`] injecting faults in the calls going through it.
+///
+/// Only the methods which can fail get failures; all of them get latencies.
+pub struct ChaosExternal<'a> {
+    inner: &'a mut dyn External,
+    injector: Injector,
+}
+
+impl<'a> ChaosExternal<'a> {
+    pub fn new(inner: &'a mut dyn External, config: ChaosConfig) -> Self {
+        Self { inner, injector: Injector::new(config, b"external") }
+    }
+
+    /// Number of faults injected so far.
+    pub fn faults(&self) -> usize {
+        self.injector.faults()
+    }
+
+    fn read<T>(
+        &self,
+        what: &'static str,
+        op: impl FnOnce(&dyn External) -> Result<T>,
+    ) -> Result<T> {
+        if self.injector.fail() {
+            return Err(external_error(what));
+        }
+        op(&*self.inner)
+    }
+
+    fn write<T>(
+        &mut self,
+        what: &'static str,
+        op: impl FnOnce(&mut dyn External) -> Result<T>,
+    ) -> Result<T> {
+        if self.injector.fail() {
+            return Err(external_error(what));
+        }
+        let value = op(&mut *self.inner)?;
+        if self.injector.partial_write() {
+            return Err(external_error(what));
+        }
+        Ok(value)
+    }
+
+    /// Delays an operation which cannot fail.
+    fn delay(&self) {
+        let max_latency = self.injector.config.max_latency.as_nanos() as u64;
+        if max_latency > 0 {
+            let mut rng = self.injector.rng.lock().unwrap();
+            std::thread::sleep(Duration::from_nanos(rng.next_below(max_latency + 1)));
+        }
+    }
+}
+
+/// Fails reading the bytes of a `storage_get` value.
+struct ChaosValuePtr<'a> {
+    inner: Box<dyn ValuePtr + 'a>,
+    injector: &'a Injector,
+}
+
+impl ValuePtr for ChaosValuePtr<'_> {
+    fn len(&self) -> u32 {
+        self.inner.len()
+    }
+
+    fn deref(&self) -> Result<Vec<u8>> {
+        if self.injector.fail() {
+            return Err(external_error("value read failure"));
+        }
+        self.inner.deref()
+    }
+}
+
+impl External for ChaosExternal<'_> {
+    fn storage_set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.write("storage_set failure", |ext| ext.storage_set(key, value))
+    }
+
+    fn storage_get<'b>(
+        &'b self,
+        key: &[u8],
+        mode: StorageGetMode,
+    ) -> Result<Option<Box<dyn ValuePtr + 'b>>> {
+        if self.injector.fail() {
+            return Err(external_error("storage_get failure"));
+        }
+        let ptr = self.inner.storage_get(key, mode)?;
+        Ok(ptr.map(|inner| {
+            Box::new(ChaosValuePtr { inner, injector: &self.injector }) as Box<dyn ValuePtr>
+        }))
+    }
+
+    fn storage_remove(&mut self, key: &[u8]) -> Result<()> {
+        self.write("storage_remove failure", |ext| ext.storage_remove(key))
+    }
+
+    fn storage_remove_subtree(&mut self, prefix: &[u8]) -> Result<()> {
+        self.write("storage_remove_subtree failure", |ext| ext.storage_remove_subtree(prefix))
+    }
+
+    fn storage_has_key(&mut self, key: &[u8], mode: StorageGetMode) -> Result<bool> {
+        // A read, though it takes `&mut self`.
+        if self.injector.fail() {
+            return Err(external_error("storage_has_key failure"));
+        }
+        self.inner.storage_has_key(key, mode)
+    }
+
+    fn storage_proof(&self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.read("storage_proof failure", |ext| ext.storage_proof(key))
+    }
+
+    fn state_changed(&self) -> bool {
+        self.inner.state_changed()
+    }
+
+    fn generate_data_id(&mut self) -> CryptoHash {
+        self.delay();
+        self.inner.generate_data_id()
+    }
+
+    fn get_trie_nodes_count(&self) -> TrieNodesCount {
+        self.inner.get_trie_nodes_count()
+    }
+
+    fn validator_frozen(&self, account_id: &AccountId) -> Result<Option<Balance>> {
+        self.read("validator_frozen failure", |ext| ext.validator_frozen(account_id))
+    }
+
+    fn validator_power(&self, account_id: &AccountId) -> Result<Option<Power>> {
+        self.read("validator_power failure", |ext| ext.validator_power(account_id))
+    }
+
+    fn validator_total_frozen(&self) -> Result<Balance> {
+        self.read("validator_total_frozen failure", |ext| ext.validator_total_frozen())
+    }
+
+    fn validator_total_power(&self) -> Result<Power> {
+        self.read("validator_total_power failure", |ext| ext.validator_total_power())
+    }
+
+    fn account_code_hash(&self, account_id: &AccountId) -> Result<Option<CryptoHash>> {
+        self.read("account_code_hash failure", |ext| ext.account_code_hash(account_id))
+    }
+
+    fn create_receipt(
+        &mut self,
+        receipt_indices: Vec<ReceiptIndex>,
+        receiver_id: AccountId,
+    ) -> Result<ReceiptIndex, VMLogicError> {
+        self.write("create_receipt failure", |ext| ext.create_receipt(receipt_indices, receiver_id))
+    }
+
+    fn append_action_create_account(
+        &mut self,
+        receipt_index: ReceiptIndex,
+    ) -> Result<(), VMLogicError> {
+        self.write("create_account failure", |ext| ext.append_action_create_account(receipt_index))
+    }
+
+    fn append_action_deploy_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code: Vec<u8>,
+    ) -> Result<(), VMLogicError> {
+        self.write("deploy_contract failure", |ext| {
+            ext.append_action_deploy_contract(receipt_index, code)
+        })
+    }
+
+    fn append_action_deploy_global_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code_hash: CryptoHash,
+    ) -> Result<(), VMLogicError> {
+        self.write("deploy_global_contract failure", |ext| {
+            ext.append_action_deploy_global_contract(receipt_index, code_hash)
+        })
+    }
+
+    fn append_action_function_call_weight(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        method_name: Vec<u8>,
+        args: Vec<u8>,
+        attached_deposit: Balance,
+        prepaid_gas: Gas,
+        gas_weight: GasWeight,
+    ) -> Result<(), VMLogicError> {
+        self.write("function_call failure", |ext| {
+            ext.append_action_function_call_weight(
+                receipt_index,
+                method_name,
+                args,
+                attached_deposit,
+                prepaid_gas,
+                gas_weight,
+            )
+        })
+    }
+
+    fn append_action_transfer(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        deposit: Balance,
+    ) -> Result<(), VMLogicError> {
+        self.write("transfer failure", |ext| ext.append_action_transfer(receipt_index, deposit))
+    }
+
+    fn append_action_stake(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        stake: Balance,
+        public_key: PublicKey,
+    ) {
+        self.delay();
+        self.inner.append_action_stake(receipt_index, stake, public_key)
+    }
+
+    fn append_action_add_key_with_full_access(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+    ) {
+        self.delay();
+        self.inner.append_action_add_key_with_full_access(receipt_index, public_key, nonce)
+    }
+
+    fn append_action_add_key_with_function_call(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+        allowance: Option<Balance>,
+        receiver_id: AccountId,
+        method_names: Vec<Vec<u8>>,
+    ) -> Result<(), VMLogicError> {
+        self.write("add_key failure", |ext| {
+            ext.append_action_add_key_with_function_call(
+                receipt_index,
+                public_key,
+                nonce,
+                allowance,
+                receiver_id,
+                method_names,
+            )
+        })
+    }
+
+    fn append_action_delete_key(&mut self, receipt_index: ReceiptIndex, public_key: PublicKey) {
+        self.delay();
+        self.inner.append_action_delete_key(receipt_index, public_key)
+    }
+
+    fn append_action_delete_account(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        beneficiary_id: AccountId,
+    ) -> Result<(), VMLogicError> {
+        self.write("delete_account failure", |ext| {
+            ext.append_action_delete_account(receipt_index, beneficiary_id)
+        })
+    }
+
+    fn scratch_get(&self) -> Result<Option<Vec<u8>>> {
+        self.read("scratch_get failure", |ext| ext.scratch_get())
+    }
+
+    fn append_scratch(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        data: Vec<u8>,
+    ) -> Result<(), VMLogicError> {
+        self.write("append_scratch failure", |ext| ext.append_scratch(receipt_index, data))
+    }
+
+    fn get_receipt_receiver(&self, receipt_index: ReceiptIndex) -> &AccountId {
+        self.inner.get_receipt_receiver(receipt_index)
+    }
+}
+
+/// [`CompiledContractCache`] injecting faults in the reads and writes going
+/// through it.
+pub struct ChaosCache<C> {
+    inner: C,
+    injector: Injector,
+}
+
+impl<C: CompiledContractCache> ChaosCache<C> {
+    pub fn new(inner: C, config: ChaosConfig) -> Self {
+        Self { inner, injector: Injector::new(config, b"cache") }
+    }
+
+    /// Number of faults injected so far.
+    pub fn faults(&self) -> usize {
+        self.injector.faults()
+    }
+
+    pub fn inner(&self) -> &C {
+        &self.inner
+    }
+}
+
+impl<C: CompiledContractCache> CompiledContractCache for ChaosCache<C> {
+    fn put(&self, key: &CryptoHash, value: CompiledContract) -> io::Result<()> {
+        if self.injector.fail() {
+            return Err(cache_error("cache write failure"));
+        }
+        self.inner.put(key, value)?;
+        if self.injector.partial_write() {
+            return Err(cache_error("cache partial write"));
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &CryptoHash) -> io::Result<Option<CompiledContract>> {
+        if self.injector.fail() {
+            return Err(cache_error("cache read failure"));
+        }
+        self.inner.get(key)
+    }
+
+    fn put_compilation_info(&self, key: &CryptoHash, info: &CompilationInfo) -> io::Result<()> {
+        if self.injector.fail() {
+            return Err(cache_error("cache write failure"));
+        }
+        self.inner.put_compilation_info(key, info)
+    }
+
+    fn get_compilation_info(&self, key: &CryptoHash) -> io::Result<Option<CompilationInfo>> {
+        if self.injector.fail() {
+            return Err(cache_error("cache read failure"));
+        }
+        self.inner.get_compilation_info(key)
+    }
+}
+
+/// A run under chaos which went wrong, see [`check_chaos_run`].
+#[derive(Debug, thiserror::Error)]
+pub enum ChaosViolation {
+    #[error("the call failed with `{0}` while no fault was injected")]
+    FailedWithoutFault(String),
+    #[error("the call failed with `{0}`, which is not an environment error")]
+    NotAnEnvironmentError(String),
+    #[error("{faults} faults were injected but the call did not fail")]
+    FaultSwallowed { faults: usize },
+    #[error("the outcome of the call differs from the one without chaos:\n{expected}\n{found}")]
+    Diverged { expected: String, found: String },
+}
+
+/// Checks the `result` of a call run with `faults` faults injected against
+/// its `reference` outcome without chaos.
+pub fn check_chaos_run(
+    reference: &VMOutcome,
+    result: &VMResult,
+    faults: usize,
+) -> Result<(), ChaosViolation> {
+    match result {
+        Err(err) if faults == 0 => Err(ChaosViolation::FailedWithoutFault(err.to_string())),
+        Err(VMRunnerError::ExternalError(_) | VMRunnerError::CacheError(_)) => Ok(()),
+        Err(err) => Err(ChaosViolation::NotAnEnvironmentError(err.to_string())),
+        Ok(_) if faults > 0 => Err(ChaosViolation::FaultSwallowed { faults }),
+        Ok(outcome) if outcome != reference => Err(ChaosViolation::Diverged {
+            expected: format!("{reference:?}"),
+            found: format!("{outcome:?}"),
+        }),
+        Ok(_) => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::runner::VMKindExt;
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+    use crate::{ContractCode, MockCompiledContractCache};
+    use crate::logic::Config;
+    use unc_parameters::RuntimeFeesConfig;
+
+    /// Reads a key, writes it back incremented by one and logs it.
+    const COUNTER_CONTRACT: &str = r#"
+(module
+  (import "env" "storage_read" (func $storage_read (param i64 i64 i64) (result i64)))
+  (import "env" "storage_write" (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "log_utf8" (func $log_utf8 (param i64 i64)))
+  (memory 1)
+  (data (i32.const 0) "counter")
+  (func (export "main")
+    (if (i64.eq (call $storage_read (i64.const 7) (i64.const 0) (i64.const 0)) (i64.const 1))
+      (then (call $read_register (i64.const 0) (i64.const 8))))
+    (i32.store8 (i32.const 8) (i32.add (i32.load8_u (i32.const 8)) (i32.const 1)))
+    (drop (call $storage_write (i64.const 7) (i64.const 0) (i64.const 1) (i64.const 8) (i64.const 1)))
+    (call $log_utf8 (i64.const 7) (i64.const 0)))
+)"#;
+
+    #[test]
+    fn test_environment_errors_stay_environment_errors() {
+        let code = ContractCode::new(wat::parse_str(COUNTER_CONTRACT).unwrap(), None);
+        let fees = RuntimeFeesConfig::test();
+        let config = test_vm_config();
+        with_vm_variants(&config, |vm_kind| {
+            let mut config = config.clone();
+            config.vm_kind = vm_kind;
+            let runtime = vm_kind.runtime(config).unwrap();
+            let mut state = MockedExternal::new();
+            state.fake_trie.insert(b"counter".to_vec(), vec![41]);
+            let run = |ext: &mut dyn External, cache: &dyn CompiledContractCache| {
+                let context = create_context(vec![]);
+                runtime.run(&code, "main", ext, context, &fees, &[], Some(cache))
+            };
+            let reference = run(&mut state.clone(), &MockCompiledContractCache::default()).unwrap();
+            assert_eq!(reference.aborted, None, "{vm_kind:?}");
+
+            let mut failures = 0;
+            for seed in 0..100 {
+                let config = ChaosConfig {
+                    failure_rate: 0.2,
+                    partial_write_rate: 0.2,
+                    ..ChaosConfig::new(seed)
+                };
+                // The cache is kept between the runs, as on a node.
+                let cache = ChaosCache::new(MockCompiledContractCache::default(), config);
+                for _ in 0..3 {
+                    let mut inner = state.clone();
+                    let mut ext = ChaosExternal::new(&mut inner, config);
+                    let faults_before = cache.faults();
+                    let result = run(&mut ext, &cache);
+                    let faults = ext.faults() + cache.faults() - faults_before;
+                    if let Err(violation) = check_chaos_run(&reference, &result, faults) {
+                        panic!("{vm_kind:?} with seed {seed}: {violation}");
+                    }
+                    failures += result.is_err() as usize;
+                }
+            }
+            assert!(failures > 0, "{vm_kind:?}: no fault injected");
+        });
+    }
+
+    #[test]
+    fn test_faults_are_reproducible() {
+        let config = ChaosConfig { failure_rate: 0.5, ..ChaosConfig::new(7) };
+        let draws = || {
+            let cache = ChaosCache::new(MockCompiledContractCache::default(), config);
+            (0..32).map(|_| cache.get(&CryptoHash::default()).is_err()).collect::<Vec<_>>()
+        };
+        let first = draws();
+        assert_eq!(first, draws());
+        assert!(first.contains(&true) && first.contains(&false));
+    }
+}
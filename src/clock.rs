@@ -0,0 +1,81 @@
+//! Where the runner reads the time.
+//!
+//! The deadlines of calls, the expiry of the [`crate::ViewCallCache`] and
+//! the windows of the [`crate::AdmissionController`] read the time from the
+//! [`Clock`] given to them, [`crate::RunOptions::clock`] or `with_clock`,
+//! and from the [`SystemClock`] without one.  Embedders replaying or
+//! simulating calls give them a [`VirtualClock`] instead, which only moves
+//! when told to, so that whether a call times out no longer depends on the
+//! speed of the machine.
+//!
+//! Metrics, and the tools timing the VMs themselves such as
+//! the `costs` estimator, always read the system clock.
+
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// A source of time, shared by the threads of the process.
+pub trait Clock: Send + Sync + std::fmt::Debug {
+    fn now(&self) -> Instant;
+}
+
+/// The monotonic clock of the system, [`Instant::now`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock which stands still until [`VirtualClock::advance`] moves it.
+#[derive(Debug)]
+pub struct VirtualClock {
+    start: Instant,
+    elapsed: Mutex<Duration>,
+}
+
+impl VirtualClock {
+    /// A clock starting at the current time of the system.
+    pub fn new() -> Self {
+        Self { start: Instant::now(), elapsed: Mutex::new(Duration::ZERO) }
+    }
+
+    /// Moves the clock `by` forward.
+    pub fn advance(&self, by: Duration) {
+        *self.elapsed.lock().unwrap() += by;
+    }
+
+    /// How far the clock moved since it was created.
+    pub fn elapsed(&self) -> Duration {
+        *self.elapsed.lock().unwrap()
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        self.start + self.elapsed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_clock() {
+        let clock = VirtualClock::new();
+        let start = clock.now();
+        assert_eq!(clock.now(), start);
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(clock.now(), start + Duration::from_secs(2));
+        assert_eq!(clock.elapsed(), Duration::from_secs(2));
+    }
+}
@@ -1,5 +1,13 @@
 use unc_primitives_core::hash::{hash as sha256, CryptoHash};
 
+/// A [`ContractCode`] was paired with a hash which is not the hash of its code.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("contract code hash mismatch: claimed {claimed}, actual {actual}")]
+pub struct CodeHashMismatch {
+    pub claimed: CryptoHash,
+    pub actual: CryptoHash,
+}
+
 pub struct ContractCode {
     code: Vec<u8>,
     hash: CryptoHash,
@@ -13,6 +21,32 @@ impl ContractCode {
         ContractCode { code, hash }
     }
 
+    /// Same as [`Self::new`] with a `claimed_hash`, but checks the hash in
+    /// release builds too.
+    ///
+    /// The hash is used as the cache key of the compiled contract, so pairing
+    /// it with the wrong code would make the runtime run some other contract.
+    pub fn new_verified(
+        code: Vec<u8>,
+        claimed_hash: CryptoHash,
+    ) -> Result<ContractCode, CodeHashMismatch> {
+        let actual = sha256(&code);
+        if actual != claimed_hash {
+            return Err(CodeHashMismatch { claimed: claimed_hash, actual });
+        }
+        Ok(ContractCode { code, hash: actual })
+    }
+
+    /// Checks that the hash this contract has been created with is the hash of
+    /// its code.
+    pub fn verify(&self) -> Result<(), CodeHashMismatch> {
+        let actual = sha256(&self.code);
+        if actual != self.hash {
+            return Err(CodeHashMismatch { claimed: self.hash, actual });
+        }
+        Ok(())
+    }
+
     pub fn code(&self) -> &[u8] {
         self.code.as_slice()
     }
@@ -25,3 +59,50 @@ impl ContractCode {
         &self.hash
     }
 }
+
+/// Verifies `codes` with [`ContractCode::verify`], returning the mismatches
+/// along with the index of the contract in `codes`.
+///
+/// Useful to check all the contracts of a chunk or of a snapshot at once.
+pub fn verify_contract_codes<'a>(
+    codes: impl IntoIterator<Item = &'a ContractCode>,
+) -> Result<(), Vec<(usize, CodeHashMismatch)>> {
+    let mismatches: Vec<_> = codes
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, code)| code.verify().err().map(|err| (index, err)))
+        .collect();
+    if mismatches.is_empty() {
+        Ok(())
+    } else {
+        Err(mismatches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_new_verified() {
+        let hash = sha256(b"code");
+        let code = ContractCode::new_verified(b"code".to_vec(), hash).unwrap();
+        assert_eq!(code.hash(), &hash);
+        assert_eq!(
+            ContractCode::new_verified(b"other code".to_vec(), hash).err(),
+            Some(CodeHashMismatch { claimed: hash, actual: sha256(b"other code") })
+        );
+    }
+
+    #[test]
+    fn test_verify_contract_codes() {
+        let good = ContractCode::new(b"good".to_vec(), None);
+        // `ContractCode::new` only checks the hash in debug builds.
+        let bad = ContractCode { code: b"bad".to_vec(), hash: sha256(b"good") };
+        assert_eq!(verify_contract_codes([&good, &good]), Ok(()));
+        assert_eq!(
+            verify_contract_codes([&good, &bad, &good]),
+            Err(vec![(1, CodeHashMismatch { claimed: sha256(b"good"), actual: sha256(b"bad") })])
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! How many contract calls the VMs can run at the same time.
+//!
+//! The VMs turn the traps of contracts into errors with process-wide signal
+//! handlers.  The handlers of Wasmer0 also catch the signals raised by the
+//! code Wasmtime generates and turn them into bogus failures, so calls of
+//! these two VMs must not overlap.  Their runners wait for the calls of the
+//! other VM to finish before starting, and embedders can ask
+//! [`execution_concurrency`] to size their thread pools accordingly.
+
+use std::num::NonZeroUsize;
+use std::sync::{Condvar, Mutex};
+use unc_parameters::vm::VMKind;
+
+/// How many calls of a VM can run at the same time in a process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExecutionConcurrency {
+    /// Maximum number of calls of the VM running at the same time, `None`
+    /// if any number of calls can.
+    pub max_concurrent_calls: Option<NonZeroUsize>,
+    /// VMs whose calls never run at the same time as the calls of this VM.
+    ///
+    /// The runners enforce this by making the calls wait, so a thread pool
+    /// shared by these VMs is only fully used by one of them at a time.
+    pub exclusive_with: &'static [VMKind],
+}
+
+/// Returns how many calls of `vm_kind` can safely run at the same time.
+pub fn execution_concurrency(vm_kind: VMKind) -> ExecutionConcurrency {
+    // Wasmer0 only installs its signal handlers when it is compiled in.
+    let wasmer0 = cfg!(all(feature = "wasmer0_vm", target_arch = "x86_64"));
+    let exclusive_with: &[VMKind] = match vm_kind {
+        VMKind::Wasmer0 if wasmer0 => &[VMKind::Wasmtime],
+        VMKind::Wasmtime if wasmer0 => &[VMKind::Wasmer0],
+        _ => &[],
+    };
+    ExecutionConcurrency { max_concurrent_calls: None, exclusive_with }
+}
+
+struct GateState {
+    /// VM of the calls running, if any.
+    owner: Option<VMKind>,
+    running: usize,
+    /// Calls waiting for the calls of another VM to finish, per VM.
+    waiting: [usize; 4],
+}
+
+pub(crate) fn index(vm_kind: VMKind) -> usize {
+    match vm_kind {
+        VMKind::Wasmer0 => 0,
+        VMKind::Wasmtime => 1,
+        VMKind::Wasmer2 => 2,
+        VMKind::NearVm => 3,
+    }
+}
+
+static GATE: Mutex<GateState> = Mutex::new(GateState { owner: None, running: 0, waiting: [0; 4] });
+static GATE_CHANGED: Condvar = Condvar::new();
+
+/// Marks a call as running until dropped, see [`enter`].
+#[must_use]
+pub(crate) struct ExecutionGuard {
+    gated: bool,
+}
+
+/// Waits until a call of `vm_kind` can run alongside the calls running.
+///
+/// Calls of a VM keep running concurrently, but once a call of an exclusive
+/// VM waits, new calls wait for it too so that neither VM starves.
+pub(crate) fn enter(vm_kind: VMKind) -> ExecutionGuard {
+    if execution_concurrency(vm_kind).exclusive_with.is_empty() {
+        return ExecutionGuard { gated: false };
+    }
+    let mut state = GATE.lock().unwrap();
+    let can_run = |state: &GateState| {
+        let others_waiting =
+            state.waiting.iter().enumerate().any(|(i, &n)| i != index(vm_kind) && n > 0);
+        state.running == 0 || (state.owner == Some(vm_kind) && !others_waiting)
+    };
+    if !can_run(&state) {
+        state.waiting[index(vm_kind)] += 1;
+        state = GATE_CHANGED.wait_while(state, |state| !can_run(state)).unwrap();
+        state.waiting[index(vm_kind)] -= 1;
+    }
+    state.owner = Some(vm_kind);
+    state.running += 1;
+    ExecutionGuard { gated: true }
+}
+
+impl Drop for ExecutionGuard {
+    fn drop(&mut self) {
+        if !self.gated {
+            return;
+        }
+        let mut state = GATE.lock().unwrap();
+        state.running -= 1;
+        if state.running == 0 {
+            state.owner = None;
+            GATE_CHANGED.notify_all();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_exclusive_calls_do_not_overlap() {
+        if execution_concurrency(VMKind::Wasmer0).exclusive_with.is_empty() {
+            return;
+        }
+        assert_eq!(execution_concurrency(VMKind::Wasmtime).exclusive_with, [VMKind::Wasmer0]);
+        assert!(execution_concurrency(VMKind::NearVm).exclusive_with.is_empty());
+
+        let running = [AtomicUsize::new(0), AtomicUsize::new(0)];
+        std::thread::scope(|s| {
+            for thread in 0..8 {
+                let running = &running;
+                s.spawn(move || {
+                    let (vm_kind, mine, other) = if thread % 2 == 0 {
+                        (VMKind::Wasmer0, &running[0], &running[1])
+                    } else {
+                        (VMKind::Wasmtime, &running[1], &running[0])
+                    };
+                    for _ in 0..100 {
+                        let _guard = enter(vm_kind);
+                        mine.fetch_add(1, Ordering::SeqCst);
+                        assert_eq!(other.load(Ordering::SeqCst), 0);
+                        std::thread::yield_now();
+                        mine.fetch_sub(1, Ordering::SeqCst);
+                    }
+                });
+            }
+        });
+    }
+}
@@ -0,0 +1,467 @@
+//! Estimating the gas parameters of the runtime on this host.
+//!
+//! Forks of the runtime run it on other hardware, with other VMs or host
+//! functions, and need fees matching them.  [`CostEstimator`] times generated
+//! workloads, one per parameter, on each VM compiled in, and
+//! [`CostEstimate::diff`] turns the estimates into a diff of the parameters
+//! in the format of the runtime config files of `unc-parameters`.
+//!
+//! A workload calls a host function, or runs wasm arithmetic, in a loop.  It
+//! runs with two numbers of iterations, keeping the fastest of a few samples
+//! of each, so that the difference is the time and the costs of the extra
+//! iterations: everything else the call does cancels out.  The workloads run
+//! with every parameter set to one gas, so that the gas profile counts the
+//! charges of each parameter.  The time of an iteration is then split among
+//! the parameters it charges: the ones estimated before take their estimate,
+//! the others their current value, and what remains goes to the parameter of
+//! the workload.  Each workload comes after the ones estimating the other
+//! parameters it charges, when there are such workloads.
+//!
+//! The estimate of a parameter is the largest among the VMs, since the fees
+//! must cover the slowest, converted at [`GAS_PER_NANOSECOND`] without safety
+//! margin.  The parameters of the storage depend on the database of the node
+//! and are not estimated, nor are the compute costs.
+//!
+//! The timings depend on the build and on the load of the host: the
+//! estimates are only reproducible in a release build on an idle machine,
+//! and the estimates of a few runs tell how noisy they are.
+
+#[cfg(feature = "bn128")]
+mod alt_bn128;
+mod workloads;
+
+use crate::logic::errors::VMRunnerError;
+use crate::logic::mocks::mock_external::MockedExternal;
+use crate::runner::{check_backend, standalone_context, BackendUnavailable, VMKindExt, VM};
+use crate::{ContractCode, MockCompiledContractCache, ProfileDataV3};
+use std::collections::HashMap;
+use std::fmt;
+use std::time::{Duration, Instant};
+use strum::IntoEnumIterator;
+use crate::logic::{Config, ExtraExtCostsConfig};
+use unc_parameters::vm::VMKind;
+use unc_parameters::{ExtCosts, ExtCostsConfig, Parameter, ParameterCost, RuntimeFeesConfig};
+use unc_primitives_core::types::Gas;
+use workloads::{Workload, WORKLOADS};
+
+#[cfg(feature = "bn128")]
+pub use alt_bn128::{calibrate_alt_bn128, Calibration, CostSuggestion};
+
+/// Gas charged for a nanosecond of execution, one teragas per millisecond.
+pub const GAS_PER_NANOSECOND: u64 = 1_000_000;
+
+fn gas(time: Duration) -> Gas {
+    u64::try_from(time.as_nanos()).unwrap_or(u64::MAX).saturating_mul(GAS_PER_NANOSECOND)
+}
+
+/// A gas parameter of the runtime the [`CostEstimator`] measures.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CostParameter {
+    /// [`Config::regular_op_cost`], charged for each wasm instruction.
+    RegularOp,
+    Ext(ExtCosts),
+}
+
+impl CostParameter {
+    /// The parameter in the runtime config files.
+    pub fn parameter(self) -> Parameter {
+        match self {
+            Self::RegularOp => Parameter::WasmRegularOpCost,
+            Self::Ext(cost) => cost.param(),
+        }
+    }
+
+    /// Value of the parameter in `config`.
+    pub fn gas(self, config: &Config) -> Gas {
+        match self {
+            Self::RegularOp => config.regular_op_cost.into(),
+            Self::Ext(cost) => config.ext_costs.gas_cost(cost),
+        }
+    }
+}
+
+impl fmt::Display for CostParameter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.parameter().fmt(f)
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CostEstimationError {
+    #[error("no VM to estimate the costs on")]
+    NoVm,
+    #[error(transparent)]
+    BackendUnavailable(#[from] BackendUnavailable),
+    #[error("the {parameter} workload failed on {vm_kind:?}: {error}")]
+    Workload { parameter: CostParameter, vm_kind: VMKind, error: String },
+    #[error("the {parameter} workload does not charge it on {vm_kind:?}")]
+    NotCharged { parameter: CostParameter, vm_kind: VMKind },
+}
+
+/// The estimate of a parameter.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParameterEstimate {
+    pub parameter: CostParameter,
+    /// The value in the config of the estimator.
+    pub current: Gas,
+    /// The largest of [`Self::by_vm`], or the estimate of the parameters
+    /// measured apart from the VMs.
+    pub estimated: Gas,
+    /// The estimate on each VM, empty for the alt_bn128 parameters, which
+    /// time the curve arithmetic of the host directly.
+    pub by_vm: Vec<(VMKind, Gas)>,
+}
+
+/// Outcome of [`CostEstimator::estimate`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CostEstimate {
+    /// The estimated parameters, in the order they are estimated.
+    pub parameters: Vec<ParameterEstimate>,
+}
+
+impl CostEstimate {
+    /// The parameters of which the estimate differs from the current value
+    /// by more than `tolerance` times the current value.
+    pub fn diff(&self, tolerance: f64) -> ParameterDiff {
+        let changes = self
+            .parameters
+            .iter()
+            .filter(|estimate| {
+                let change = estimate.estimated.abs_diff(estimate.current) as f64;
+                change > tolerance * estimate.current as f64 && change > 0.0
+            })
+            .map(|estimate| ParameterChange {
+                parameter: estimate.parameter.parameter(),
+                old: estimate.current,
+                new: estimate.estimated,
+            })
+            .collect();
+        ParameterDiff { changes }
+    }
+}
+
+/// A change of a parameter in a [`ParameterDiff`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParameterChange {
+    pub parameter: Parameter,
+    pub old: Gas,
+    pub new: Gas,
+}
+
+/// Changes of parameters, which display as a diff of the runtime config
+/// files: one `name: { old: 1_000, new: 2_000 }` line per parameter.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ParameterDiff {
+    pub changes: Vec<ParameterChange>,
+}
+
+impl fmt::Display for ParameterDiff {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for change in &self.changes {
+            writeln!(
+                f,
+                "{}: {{ old: {}, new: {} }}",
+                change.parameter,
+                grouped(change.old),
+                grouped(change.new)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// `value` with its digits grouped by three, as in the config files.
+fn grouped(value: Gas) -> String {
+    let digits = value.to_string();
+    let mut grouped = String::new();
+    for (i, digit) in digits.chars().enumerate() {
+        if i > 0 && (digits.len() - i) % 3 == 0 {
+            grouped.push('_');
+        }
+        grouped.push(digit);
+    }
+    grouped
+}
+
+/// Estimates the gas parameters of a [`Config`] on this host, see the module
+/// documentation.
+#[derive(Clone, Debug)]
+pub struct CostEstimator {
+    config: Config,
+    vm_kinds: Vec<VMKind>,
+    iterations: u64,
+    samples: usize,
+}
+
+impl CostEstimator {
+    /// Iterations of the longer run of each workload by default.
+    pub const DEFAULT_ITERATIONS: u64 = 1_000;
+    /// Runs of each workload timed by default, the fastest one counting.
+    pub const DEFAULT_SAMPLES: usize = 5;
+
+    /// Estimates the parameters of `config` on every VM able to run its
+    /// contracts on this host.
+    pub fn new(config: Config) -> Self {
+        let vm_kinds = [VMKind::NearVm, VMKind::Wasmer2, VMKind::Wasmer0, VMKind::Wasmtime]
+            .into_iter()
+            .filter(|&vm_kind| check_backend(vm_kind, &config).is_ok())
+            .collect();
+        Self {
+            config,
+            vm_kinds,
+            iterations: Self::DEFAULT_ITERATIONS,
+            samples: Self::DEFAULT_SAMPLES,
+        }
+    }
+
+    /// Only estimates on `vm_kinds`.
+    pub fn with_vm_kinds(self, vm_kinds: Vec<VMKind>) -> Self {
+        Self { vm_kinds, ..self }
+    }
+
+    /// Runs the workloads for `iterations` and a tenth of it, at least 10.
+    pub fn with_iterations(self, iterations: u64) -> Self {
+        Self { iterations: iterations.max(10), ..self }
+    }
+
+    /// Times `samples` runs of each workload, at least one.
+    pub fn with_samples(self, samples: usize) -> Self {
+        Self { samples: samples.max(1), ..self }
+    }
+
+    /// The parameters the estimator measures with its config, in the order
+    /// they are estimated.
+    pub fn parameters(&self) -> Vec<CostParameter> {
+        let mut parameters: Vec<_> = self.workloads().map(|workload| workload.parameter).collect();
+        #[cfg(feature = "bn128")]
+        if self.config.alt_bn128 {
+            parameters.extend(ALT_BN128_COSTS.iter().map(|cost| CostParameter::Ext(*cost)));
+        }
+        parameters
+    }
+
+    pub fn estimate(&self) -> Result<CostEstimate, CostEstimationError> {
+        if self.vm_kinds.is_empty() {
+            return Err(CostEstimationError::NoVm);
+        }
+        let workloads: Vec<&Workload> = self.workloads().collect();
+        let mut by_vm = vec![Vec::new(); workloads.len()];
+        for &vm_kind in &self.vm_kinds {
+            let estimates = self.estimate_on(vm_kind, &workloads)?;
+            for (by_vm, estimate) in by_vm.iter_mut().zip(estimates) {
+                by_vm.push((vm_kind, estimate));
+            }
+        }
+        #[allow(unused_mut)]
+        let mut parameters: Vec<_> = workloads
+            .iter()
+            .zip(by_vm)
+            .map(|(workload, by_vm)| ParameterEstimate {
+                parameter: workload.parameter,
+                current: workload.parameter.gas(&self.config),
+                estimated: by_vm.iter().map(|(_, estimate)| *estimate).max().unwrap_or_default(),
+                by_vm,
+            })
+            .collect();
+        #[cfg(feature = "bn128")]
+        if self.config.alt_bn128 {
+            let calibration = calibrate_alt_bn128(&self.config.ext_costs, self.samples);
+            parameters.extend(calibration.suggestions.into_iter().map(|suggestion| {
+                ParameterEstimate {
+                    parameter: CostParameter::Ext(suggestion.cost),
+                    current: suggestion.current,
+                    estimated: suggestion.suggested,
+                    by_vm: Vec::new(),
+                }
+            }));
+        }
+        Ok(CostEstimate { parameters })
+    }
+
+    fn workloads(&self) -> impl Iterator<Item = &'static Workload> + '_ {
+        WORKLOADS.iter().filter(|workload| workload.is_available(&self.config))
+    }
+
+    /// Estimates the parameter of each of `workloads` on `vm_kind`.
+    fn estimate_on(
+        &self,
+        vm_kind: VMKind,
+        workloads: &[&Workload],
+    ) -> Result<Vec<Gas>, CostEstimationError> {
+        let runtime = vm_kind.runtime(counting_config(&self.config, vm_kind))?;
+        let cache = MockCompiledContractCache::default();
+        // Nanoseconds per charge of the parameters estimated so far.
+        let mut times = HashMap::new();
+        let mut estimates = Vec::new();
+        for workload in workloads {
+            let parameter = workload.parameter;
+            let iteration = self
+                .measure(&*runtime, &cache, workload)
+                .map_err(|error| CostEstimationError::Workload { parameter, vm_kind, error })?;
+            let charges = iteration.charges(parameter);
+            if charges <= 0.0 {
+                return Err(CostEstimationError::NotCharged { parameter, vm_kind });
+            }
+            let others: f64 = iteration
+                .charges
+                .iter()
+                .filter(|(other, _)| *other != parameter)
+                .map(|(other, charges)| {
+                    let time = times.get(other).copied().unwrap_or_else(|| {
+                        other.gas(&self.config) as f64 / GAS_PER_NANOSECOND as f64
+                    });
+                    charges * time
+                })
+                .sum();
+            let time = ((iteration.nanos - others) / charges).max(0.0);
+            times.insert(parameter, time);
+            estimates.push((time * GAS_PER_NANOSECOND as f64) as Gas);
+        }
+        Ok(estimates)
+    }
+
+    /// The time and the charges of an iteration of `workload`.
+    fn measure(
+        &self,
+        runtime: &dyn VM,
+        cache: &MockCompiledContractCache,
+        workload: &Workload,
+    ) -> Result<Iteration, String> {
+        let code = ContractCode::new(workload.code(), None);
+        let fees = RuntimeFeesConfig::free();
+        let run = |iterations: u64| -> Result<(Duration, ProfileDataV3), String> {
+            let context = standalone_context(workload.input(iterations));
+            let start = Instant::now();
+            let outcome = runtime
+                .run(&code, "main", &mut MockedExternal::new(), context, &fees, &[], Some(cache))
+                .map_err(|err: VMRunnerError| err.to_string())?;
+            let elapsed = start.elapsed();
+            match outcome.aborted {
+                Some(err) => Err(err.to_string()),
+                None => Ok((elapsed, outcome.profile)),
+            }
+        };
+        let fastest = |iterations: u64| -> Result<(Duration, ProfileDataV3), String> {
+            let (mut fastest, profile) = run(iterations)?;
+            for _ in 1..self.samples {
+                fastest = fastest.min(run(iterations)?.0);
+            }
+            Ok((fastest, profile))
+        };
+        // Compiles the contract into the cache before the timed runs.
+        run(1)?;
+        let small = self.iterations / 10;
+        let (small_time, small_profile) = fastest(small)?;
+        let (large_time, large_profile) = fastest(self.iterations)?;
+        let extra = (self.iterations - small) as f64;
+        let delta = |large: Gas, small: Gas| (large as f64 - small as f64) / extra;
+        let mut charges = vec![(
+            CostParameter::RegularOp,
+            delta(large_profile.get_wasm_cost(), small_profile.get_wasm_cost()),
+        )];
+        for cost in ExtCosts::iter() {
+            let cost_charges =
+                delta(large_profile.get_ext_cost(cost), small_profile.get_ext_cost(cost));
+            if cost_charges != 0.0 {
+                charges.push((CostParameter::Ext(cost), cost_charges));
+            }
+        }
+        let nanos = large_time.as_nanos() as f64 - small_time.as_nanos() as f64;
+        Ok(Iteration { nanos: nanos / extra, charges })
+    }
+}
+
+/// The alt_bn128 parameters of [`calibrate_alt_bn128`].
+#[cfg(feature = "bn128")]
+const ALT_BN128_COSTS: [ExtCosts; 6] = [
+    ExtCosts::alt_bn128_g1_multiexp_base,
+    ExtCosts::alt_bn128_g1_sum_base,
+    ExtCosts::alt_bn128_pairing_check_base,
+    ExtCosts::alt_bn128_g1_multiexp_element,
+    ExtCosts::alt_bn128_g1_sum_element,
+    ExtCosts::alt_bn128_pairing_check_element,
+];
+
+/// Time and charges of the parameters of an iteration of a workload.
+struct Iteration {
+    nanos: f64,
+    charges: Vec<(CostParameter, f64)>,
+}
+
+impl Iteration {
+    fn charges(&self, parameter: CostParameter) -> f64 {
+        self.charges
+            .iter()
+            .find(|(charged, _)| *charged == parameter)
+            .map_or(0.0, |(_, charges)| *charges)
+    }
+}
+
+/// `config` on `vm_kind` with every parameter costing one gas, so that the
+/// gas profile of a call counts the charges of each parameter.
+fn counting_config(config: &Config, vm_kind: VMKind) -> Config {
+    let mut config = config.clone();
+    config.vm_kind = vm_kind;
+    config.ext_costs =
+        ExtCostsConfig { costs: enum_map::enum_map! { _ => ParameterCost { gas: 1, compute: 1 } } };
+    config.regular_op_cost = 1;
+    // These have no parameter in the profile to count their charges under.
+    config.extra_ext_costs = ExtraExtCostsConfig::free();
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_vm_config;
+
+    #[test]
+    fn test_estimate() {
+        let config = test_vm_config();
+        let estimator = CostEstimator::new(config.clone()).with_iterations(20).with_samples(1);
+        let estimate = estimator.estimate().unwrap();
+        let parameters: Vec<_> = estimate.parameters.iter().map(|p| p.parameter).collect();
+        assert_eq!(parameters, estimator.parameters());
+        assert_eq!(parameters[..2], [CostParameter::RegularOp, CostParameter::Ext(ExtCosts::base)]);
+        assert!(parameters.contains(&CostParameter::Ext(ExtCosts::sha256_byte)));
+        for parameter in &estimate.parameters {
+            assert_eq!(parameter.current, parameter.parameter.gas(&config));
+            if !parameter.by_vm.is_empty() {
+                assert_eq!(parameter.by_vm.len(), estimator.vm_kinds.len());
+                let largest = parameter.by_vm.iter().map(|(_, gas)| *gas).max().unwrap();
+                assert_eq!(parameter.estimated, largest);
+            }
+        }
+    }
+
+    #[test]
+    fn test_diff() {
+        let estimate = |parameter, current, estimated| ParameterEstimate {
+            parameter,
+            current,
+            estimated,
+            by_vm: Vec::new(),
+        };
+        let estimate = CostEstimate {
+            parameters: vec![
+                estimate(CostParameter::RegularOp, 822_756, 1_000_000),
+                estimate(CostParameter::Ext(ExtCosts::base), 264_768_111, 270_000_000),
+                estimate(CostParameter::Ext(ExtCosts::sha256_byte), 0, 24),
+            ],
+        };
+        assert_eq!(
+            estimate.diff(0.1).to_string(),
+            "wasm_regular_op_cost: { old: 822_756, new: 1_000_000 }\n\
+             wasm_sha256_byte: { old: 0, new: 24 }\n"
+        );
+        assert_eq!(estimate.diff(0.0).changes.len(), 3);
+        // A parameter growing from zero always changes.
+        assert_eq!(estimate.diff(1.0).changes.len(), 1);
+    }
+
+    #[test]
+    fn test_no_vm() {
+        let estimator = CostEstimator::new(test_vm_config()).with_vm_kinds(Vec::new());
+        assert!(matches!(estimator.estimate(), Err(CostEstimationError::NoVm)));
+    }
+}
@@ -0,0 +1,168 @@
+//! Measuring what the alt_bn128 host functions cost on this host.
+//!
+//! The costs of the alt_bn128 family are parameters of the protocol,
+//! estimated once on reference hardware.  [`calibrate_alt_bn128`] times the
+//! curve arithmetic of each function on the host at hand and suggests the
+//! values of its `base` and `element` parameters, which
+//! [`super::CostEstimator`] compares against the live config, those of the
+//! G2 multiexp being in the [`ExtraExtCostsConfig`].  It only
+//! measures the curve arithmetic: reading the input and calling the host
+//! function are covered by the other parameters.
+//!
+//! The suggestions use the conversion of [`super::GAS_PER_NANOSECOND`] and
+//! carry no safety margin.
+//!
+//! [`ExtraExtCostsConfig`]: crate::logic::ExtraExtCostsConfig
+
+use super::gas;
+use crate::logic::alt_bn128::{
+    encode_g1, encode_g2, g1_multiexp, g1_sum, g2_multiexp, pairing_check, split_elements,
+};
+use bn::Group;
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+use unc_parameters::{ExtCosts, ExtCostsConfig};
+use unc_primitives_core::types::Gas;
+
+/// Numbers of elements the functions are timed with, the costs being fitted
+/// on the difference.
+const SMALL: usize = 1;
+const LARGE: usize = 5;
+
+/// A parameter of the config and the value measured on this host.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CostSuggestion {
+    pub cost: ExtCosts,
+    /// The value in the config.
+    pub current: Gas,
+    /// The value measured on this host.
+    pub suggested: Gas,
+}
+
+/// Outcome of [`calibrate_alt_bn128`].
+#[derive(Clone, Debug)]
+pub struct Calibration {
+    /// The `base` and `element` parameter of each function, the bases first.
+    pub suggestions: Vec<CostSuggestion>,
+    /// The values measured on this host of the `alt_bn128_g2_multiexp_base`
+    /// and `alt_bn128_g2_multiexp_element` of the
+    /// [`crate::logic::ExtraExtCostsConfig`].
+    pub g2_multiexp: (Gas, Gas),
+}
+
+/// Times each alt_bn128 function `samples` times, keeping the fastest run,
+/// and suggests the values of its parameters in `ext_costs` and of the G2
+/// multiexp.
+pub fn calibrate_alt_bn128(ext_costs: &ExtCostsConfig, samples: usize) -> Calibration {
+    let samples = samples.max(1);
+    let g1_multiexp_cost = fit(samples, |n| {
+        let input: Vec<u8> = (0..n).flat_map(|i| [g1(i).as_slice(), &scalar(i)].concat()).collect();
+        move || {
+            black_box(g1_multiexp(split_elements(&input).unwrap()).unwrap());
+        }
+    });
+    let g1_sum_cost = fit(samples, |n| {
+        let input: Vec<u8> =
+            (0..n).flat_map(|i| [&[(i % 2) as u8], g1(i).as_slice()].concat()).collect();
+        move || {
+            black_box(g1_sum(split_elements(&input).unwrap()).unwrap());
+        }
+    });
+    let pairing_check_cost = fit(samples, |n| {
+        let input: Vec<u8> = (0..n).flat_map(|i| [g1(i).as_slice(), &g2(i)].concat()).collect();
+        move || {
+            black_box(pairing_check(split_elements(&input).unwrap()).unwrap());
+        }
+    });
+    let g2_multiexp_cost = fit(samples, |n| {
+        let input: Vec<u8> = (0..n).flat_map(|i| [g2(i).as_slice(), &scalar(i)].concat()).collect();
+        move || {
+            black_box(g2_multiexp(split_elements(&input).unwrap()).unwrap());
+        }
+    });
+
+    let costs = [
+        (ExtCosts::alt_bn128_g1_multiexp_base, g1_multiexp_cost.0),
+        (ExtCosts::alt_bn128_g1_sum_base, g1_sum_cost.0),
+        (ExtCosts::alt_bn128_pairing_check_base, pairing_check_cost.0),
+        (ExtCosts::alt_bn128_g1_multiexp_element, g1_multiexp_cost.1),
+        (ExtCosts::alt_bn128_g1_sum_element, g1_sum_cost.1),
+        (ExtCosts::alt_bn128_pairing_check_element, pairing_check_cost.1),
+    ];
+    let suggestions = costs
+        .into_iter()
+        .map(|(cost, time)| CostSuggestion {
+            cost,
+            current: ext_costs.gas_cost(cost),
+            suggested: gas(time),
+        })
+        .collect();
+    Calibration { suggestions, g2_multiexp: (gas(g2_multiexp_cost.0), gas(g2_multiexp_cost.1)) }
+}
+
+/// The base and per element times of the function which `setup` prepares
+/// to run on the given number of elements.
+fn fit<F: FnMut()>(samples: usize, mut setup: impl FnMut(usize) -> F) -> (Duration, Duration) {
+    let mut time = |n| {
+        let mut run = setup(n);
+        (0..samples)
+            .map(|_| {
+                let start = Instant::now();
+                run();
+                start.elapsed()
+            })
+            .min()
+            .unwrap()
+    };
+    let small = time(SMALL);
+    let large = time(LARGE);
+    let element = large.saturating_sub(small) / (LARGE - SMALL) as u32;
+    let base = small.saturating_sub(element * SMALL as u32);
+    (base, element)
+}
+
+/// A scalar distinct for each element, so that no work is skipped.
+fn scalar(i: usize) -> [u8; 32] {
+    let mut scalar = [0; 32];
+    scalar[..8].copy_from_slice(&(i as u64 + 2).to_le_bytes());
+    scalar
+}
+
+fn fr(i: usize) -> bn::Fr {
+    bn::Fr::from_str(&(i + 2).to_string()).unwrap()
+}
+
+fn g1(i: usize) -> [u8; 64] {
+    encode_g1(bn::G1::one() * fr(i))
+}
+
+fn g2(i: usize) -> [u8; 128] {
+    encode_g2(bn::G2::one() * fr(i))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_calibrate_alt_bn128() {
+        let ext_costs = ExtCostsConfig::test();
+        let calibration = calibrate_alt_bn128(&ext_costs, 1);
+        let costs: Vec<ExtCosts> =
+            calibration.suggestions.iter().map(|suggestion| suggestion.cost).collect();
+        assert_eq!(costs.len(), 6);
+        assert!(costs.contains(&ExtCosts::alt_bn128_pairing_check_element));
+        for suggestion in &calibration.suggestions {
+            assert_eq!(suggestion.current, ext_costs.gas_cost(suggestion.cost));
+        }
+        // Pairings are by far the most expensive elements.
+        let suggested =
+            |cost| calibration.suggestions.iter().find(|s| s.cost == cost).unwrap().suggested;
+        assert!(
+            suggested(ExtCosts::alt_bn128_pairing_check_element)
+                > suggested(ExtCosts::alt_bn128_g1_sum_element)
+        );
+        // G2 arithmetic takes longer than G1 arithmetic.
+        assert!(calibration.g2_multiexp.1 > suggested(ExtCosts::alt_bn128_g1_multiexp_element));
+    }
+}
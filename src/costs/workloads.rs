@@ -0,0 +1,215 @@
+//! The contracts [`super::CostEstimator`] times, one per estimated parameter.
+//!
+//! Each contract exports `main`, which reads a number of iterations from the
+//! first 8 bytes of its input and runs the body of its workload that many
+//! times.  The runs of a workload only differ by their input, so that what
+//! does not depend on the iterations, e.g. loading the contract, cancels out
+//! between two runs.
+
+use super::CostParameter;
+use crate::logic::host_functions::{HostValType, HOST_FUNCTIONS};
+use crate::logic::Config;
+use unc_parameters::ExtCosts;
+use wasm_encoder::{
+    BlockType, CodeSection, EntityType, ExportKind, ExportSection, Function, FunctionSection,
+    ImportSection, Instruction, MemArg, MemorySection, MemoryType, Module, TypeSection, ValType,
+};
+
+/// Bytes the per-byte workloads hash, copy or read per iteration.
+const BYTES: u64 = 4096;
+/// Offset in memory of the bytes the workloads read and write.
+const DATA_PTR: u64 = 1024;
+/// Register holding [`BYTES`] bytes, set before the loop.
+const DATA_REGISTER: u64 = 2;
+/// Register the host functions of the loop write to.
+const OUT_REGISTER: u64 = 1;
+/// Register holding the input, set before the loop.
+const INPUT_REGISTER: u64 = 0;
+/// Blocks of arithmetic in an iteration of the [`CostParameter::RegularOp`]
+/// workload.
+const OPS_BLOCKS: usize = 16;
+
+// Locals of `main`.
+const ITERATIONS: u32 = 0;
+const X: u32 = 1;
+const Y: u32 = 2;
+
+/// A loop charging one parameter, and maybe some estimated before it.
+pub(super) struct Workload {
+    pub(super) parameter: CostParameter,
+    /// Host function of `env` each iteration calls with `args`, or `None`
+    /// for wasm arithmetic.
+    host_function: Option<&'static str>,
+    args: &'static [u64],
+    /// Bytes appended to the input, which `input` copies.
+    input_padding: usize,
+}
+
+/// The workloads, in the order they are estimated, see the module
+/// documentation of [`super`].
+pub(super) const WORKLOADS: &[Workload] = &[
+    Workload::ops(),
+    Workload::call(ExtCosts::base, "block_index", &[]),
+    Workload::call(ExtCosts::write_register_base, "current_account_id", &[OUT_REGISTER]),
+    Workload {
+        input_padding: BYTES as usize,
+        ..Workload::call(ExtCosts::write_register_byte, "input", &[OUT_REGISTER])
+    },
+    Workload::call(ExtCosts::read_memory_base, "write_register", &[OUT_REGISTER, 0, DATA_PTR]),
+    Workload::call(ExtCosts::read_memory_byte, "write_register", &[OUT_REGISTER, BYTES, DATA_PTR]),
+    Workload::call(ExtCosts::write_memory_base, "account_balance", &[DATA_PTR]),
+    Workload::call(ExtCosts::read_register_base, "read_register", &[INPUT_REGISTER, DATA_PTR]),
+    Workload::call(ExtCosts::read_register_byte, "read_register", &[DATA_REGISTER, DATA_PTR]),
+    Workload::call(ExtCosts::sha256_base, "sha256", &[0, DATA_PTR, OUT_REGISTER]),
+    Workload::call(ExtCosts::sha256_byte, "sha256", &[BYTES, DATA_PTR, OUT_REGISTER]),
+    Workload::call(ExtCosts::keccak256_base, "keccak256", &[0, DATA_PTR, OUT_REGISTER]),
+    Workload::call(ExtCosts::keccak256_byte, "keccak256", &[BYTES, DATA_PTR, OUT_REGISTER]),
+    Workload::call(ExtCosts::keccak512_base, "keccak512", &[0, DATA_PTR, OUT_REGISTER]),
+    Workload::call(ExtCosts::keccak512_byte, "keccak512", &[BYTES, DATA_PTR, OUT_REGISTER]),
+    Workload::call(ExtCosts::ripemd160_base, "ripemd160", &[0, DATA_PTR, OUT_REGISTER]),
+    Workload::call(ExtCosts::ripemd160_block, "ripemd160", &[BYTES, DATA_PTR, OUT_REGISTER]),
+];
+
+impl Workload {
+    const fn ops() -> Self {
+        Self {
+            parameter: CostParameter::RegularOp,
+            host_function: None,
+            args: &[],
+            input_padding: 0,
+        }
+    }
+
+    const fn call(cost: ExtCosts, host_function: &'static str, args: &'static [u64]) -> Self {
+        Self {
+            parameter: CostParameter::Ext(cost),
+            host_function: Some(host_function),
+            args,
+            input_padding: 0,
+        }
+    }
+
+    /// Whether contracts running with `config` can call the host function
+    /// of the workload.
+    pub(super) fn is_available(&self, config: &Config) -> bool {
+        self.host_function.map_or(true, |name| HOST_FUNCTIONS.is_available(config, "env", name))
+    }
+
+    /// The input making `main` run `iterations` times.
+    pub(super) fn input(&self, iterations: u64) -> Vec<u8> {
+        let mut input = iterations.to_le_bytes().to_vec();
+        input.resize(8 + self.input_padding, 0);
+        input
+    }
+
+    /// The contract of the workload.
+    pub(super) fn code(&self) -> Vec<u8> {
+        let mut imports = vec!["input", "read_register", "write_register"];
+        if let Some(name) = self.host_function {
+            if !imports.contains(&name) {
+                imports.push(name);
+            }
+        }
+        let index = |name: &str| imports.iter().position(|import| *import == name).unwrap() as u32;
+
+        let mut types = TypeSection::new();
+        let mut import_section = ImportSection::new();
+        for (ty, name) in imports.iter().enumerate() {
+            let function = HOST_FUNCTIONS.get("env", name).expect("workloads call known functions");
+            types.function(
+                function.params.iter().map(|(_, ty)| val_type(*ty)),
+                function.results.iter().copied().map(val_type),
+            );
+            import_section.import("env", name, EntityType::Function(ty as u32));
+        }
+        types.function([], []);
+        let mut functions = FunctionSection::new();
+        functions.function(imports.len() as u32);
+        let mut memories = MemorySection::new();
+        memories.memory(MemoryType { minimum: 1, maximum: None, memory64: false, shared: false });
+        let mut exports = ExportSection::new();
+        exports.export("main", ExportKind::Func, imports.len() as u32);
+
+        let mut main = Function::new([(3, ValType::I64)]);
+        let mem = MemArg { offset: 0, align: 3, memory_index: 0 };
+        main.instruction(&Instruction::I64Const(INPUT_REGISTER as i64))
+            .instruction(&Instruction::Call(index("input")))
+            .instruction(&Instruction::I64Const(INPUT_REGISTER as i64))
+            .instruction(&Instruction::I64Const(0))
+            .instruction(&Instruction::Call(index("read_register")))
+            .instruction(&Instruction::I32Const(0))
+            .instruction(&Instruction::I64Load(mem))
+            .instruction(&Instruction::LocalSet(ITERATIONS));
+        for arg in [DATA_REGISTER, BYTES, DATA_PTR] {
+            main.instruction(&Instruction::I64Const(arg as i64));
+        }
+        main.instruction(&Instruction::Call(index("write_register")))
+            .instruction(&Instruction::I64Const(1))
+            .instruction(&Instruction::LocalSet(Y))
+            .instruction(&Instruction::Block(BlockType::Empty))
+            .instruction(&Instruction::Loop(BlockType::Empty))
+            .instruction(&Instruction::LocalGet(ITERATIONS))
+            .instruction(&Instruction::I64Eqz)
+            .instruction(&Instruction::BrIf(1));
+        match self.host_function {
+            Some(name) => {
+                for arg in self.args {
+                    main.instruction(&Instruction::I64Const(*arg as i64));
+                }
+                main.instruction(&Instruction::Call(index(name)));
+                let function = HOST_FUNCTIONS.get("env", name).unwrap();
+                if !function.results.is_empty() {
+                    main.instruction(&Instruction::LocalGet(X))
+                        .instruction(&Instruction::I64Add)
+                        .instruction(&Instruction::LocalSet(X));
+                }
+            }
+            // A chain of dependent operations which compilers cannot fold.
+            None => {
+                for _ in 0..OPS_BLOCKS {
+                    main.instruction(&Instruction::LocalGet(X))
+                        .instruction(&Instruction::LocalGet(Y))
+                        .instruction(&Instruction::I64Add)
+                        .instruction(&Instruction::LocalSet(X))
+                        .instruction(&Instruction::LocalGet(Y))
+                        .instruction(&Instruction::LocalGet(X))
+                        .instruction(&Instruction::I64Xor)
+                        .instruction(&Instruction::LocalSet(Y));
+                }
+            }
+        }
+        main.instruction(&Instruction::LocalGet(ITERATIONS))
+            .instruction(&Instruction::I64Const(1))
+            .instruction(&Instruction::I64Sub)
+            .instruction(&Instruction::LocalSet(ITERATIONS))
+            .instruction(&Instruction::Br(0))
+            .instruction(&Instruction::End)
+            .instruction(&Instruction::End)
+            // Keeps the results of the loop alive.
+            .instruction(&Instruction::I32Const(8))
+            .instruction(&Instruction::LocalGet(X))
+            .instruction(&Instruction::LocalGet(Y))
+            .instruction(&Instruction::I64Add)
+            .instruction(&Instruction::I64Store(mem))
+            .instruction(&Instruction::End);
+        let mut code = CodeSection::new();
+        code.function(&main);
+
+        let mut module = Module::new();
+        module
+            .section(&types)
+            .section(&import_section)
+            .section(&functions)
+            .section(&memories)
+            .section(&exports)
+            .section(&code);
+        module.finish()
+    }
+}
+
+fn val_type(ty: HostValType) -> ValType {
+    match ty {
+        HostValType::I32 => ValType::I32,
+        HostValType::I64 => ValType::I64,
+    }
+}
@@ -0,0 +1,397 @@
+//! Code coverage of contracts for their test frameworks.
+//!
+//! Calls run with [`CoverageCounters`] in
+//! [`RunOptions::coverage`](crate::RunOptions::coverage) count how many times
+//! each block of their contract is executed.  Their config gets `coverage`,
+//! so that the preparation instruments the contract with a pass adding a
+//! call of `coverage_hit` at the start of every block of instructions which
+//! are either all executed or not at all, the blocks of the gas metering.
+//! [`coverage_map`] tells where these blocks are in the contract, and
+//! [`CoverageMap::to_lcov`] reports the counts of all the calls in the lcov
+//! format read by coverage tools.  [`CoverageMap::to_heatmap`] sums them up
+//! by function in a [`Heatmap`].
+//!
+//! The report is keyed by the DWARF line information of the contract when it
+//! has one, and otherwise by function: the function of index `i` is the line
+//! `i + 1` of the source, and its blocks are the branches of that line.
+//!
+//! The pass imports `coverage_hit` from the module [`COVERAGE_MODULE`] once
+//! the contract is validated, and the VMs only link that module for calls
+//! with counters: contracts importing it themselves are rejected as any
+//! import outside of `env`, with the feature or without it.  The calls of
+//! the function are free, but instrumented contracts go through more
+//! instructions than their original and burn more gas; they are only meant
+//! for testing.  The pass only runs in the V2 preparation, and decodes the
+//! code with `parity-wasm`, which does not support the sign extension and
+//! SIMD proposals.
+
+use crate::debug_info::{self, LineTable};
+use crate::instrument::coverage::{inject_block_counters, InstrumentedBlock};
+use crate::logic::errors::PrepareError;
+use crate::Heatmap;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use unc_primitives_core::types::Gas;
+
+/// Import module of the function counting the executions of the blocks,
+/// which only the coverage pass imports.
+pub(crate) const COVERAGE_MODULE: &str = "unc_coverage";
+
+/// Where the blocks counted by the calls of `code` with coverage are, see
+/// the module documentation.
+pub fn coverage_map(code: &[u8]) -> Result<CoverageMap, PrepareError> {
+    let (_, blocks) = inject_coverage(code)?;
+    CoverageMap::new(code, &blocks).map_err(|_| PrepareError::Deserialization)
+}
+
+/// The coverage pass of the preparation, on validated `code`.
+pub(crate) fn instrument(code: &[u8]) -> Result<Vec<u8>, PrepareError> {
+    let (module, _) = inject_coverage(code)?;
+    parity_wasm::serialize(module).map_err(|_| PrepareError::Serialization)
+}
+
+fn inject_coverage(
+    code: &[u8],
+) -> Result<(parity_wasm::elements::Module, Vec<InstrumentedBlock>), PrepareError> {
+    let module = parity_wasm::deserialize_buffer(code).map_err(|e| {
+        tracing::debug!(err=?e, "parity_wasm failed decoding a contract");
+        PrepareError::Deserialization
+    })?;
+    inject_block_counters(module, COVERAGE_MODULE, "coverage_hit")
+        .map_err(|_| PrepareError::GasInstrumentation)
+}
+
+/// Execution counts of the blocks of a contract, accumulated over all the
+/// calls run with them.
+///
+/// Can be shared by calls on several threads.
+#[derive(Debug)]
+pub struct CoverageCounters {
+    hits: Box<[AtomicU64]>,
+}
+
+impl CoverageCounters {
+    /// Counters of the blocks of `map`, all zero.
+    pub fn new(map: &CoverageMap) -> Self {
+        Self { hits: map.blocks.iter().map(|_| AtomicU64::new(0)).collect() }
+    }
+
+    /// Counts an execution of `block`, ignoring the blocks unknown to the
+    /// map, which only the calls of another contract reach.
+    pub(crate) fn hit(&self, block: u32) {
+        if let Some(hits) = self.hits.get(block as usize) {
+            hits.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of executions of each block, in the order of
+    /// [`CoverageMap::blocks`].
+    pub fn hits(&self) -> Vec<u64> {
+        self.hits.iter().map(|hits| hits.load(Ordering::Relaxed)).collect()
+    }
+}
+
+/// A block of instructions of a contract instrumented for coverage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CoverageBlock {
+    /// Index of the function in the function index space of the original
+    /// module.
+    pub function_index: u32,
+    /// Offset of the first instruction of the block from the start of the
+    /// code section of the original module, as DWARF addresses are.
+    pub code_offset: u64,
+    /// Number of instructions of the block.
+    pub instructions: u32,
+}
+
+/// Where the blocks counted in a contract are in its code.
+#[derive(Clone, Debug, Default)]
+pub struct CoverageMap {
+    blocks: Vec<CoverageBlock>,
+    function_names: HashMap<u32, String>,
+    lines: Option<LineTable>,
+}
+
+impl CoverageMap {
+    fn new(code: &[u8], blocks: &[InstrumentedBlock]) -> wasmparser::Result<Self> {
+        let function_offsets = debug_info::instruction_offsets(code)?;
+        let first_function = blocks.first().map_or(0, |block| block.function_index);
+        let blocks = blocks
+            .iter()
+            .map(|block| {
+                let body = (block.function_index - first_function) as usize;
+                let code_offset = function_offsets
+                    .get(body)
+                    .and_then(|offsets| offsets.get(block.instruction))
+                    .copied()
+                    .unwrap_or_default();
+                CoverageBlock {
+                    function_index: block.function_index,
+                    code_offset,
+                    instructions: block.instructions,
+                }
+            })
+            .collect();
+        Ok(Self {
+            blocks,
+            function_names: debug_info::function_names(code),
+            lines: LineTable::from_code(code),
+        })
+    }
+
+    /// The blocks of the contract, in the order of the function index space.
+    pub fn blocks(&self) -> &[CoverageBlock] {
+        &self.blocks
+    }
+
+    /// Whether the report is keyed by the DWARF line information rather than
+    /// by function.
+    pub fn has_line_info(&self) -> bool {
+        self.lines.is_some()
+    }
+
+    /// File and line of the instruction at `address`.
+    fn location(&self, address: u64) -> Option<(&str, u64)> {
+        self.lines.as_ref()?.location(address)
+    }
+
+    /// The calls and gas of each function counted by `counters`.
+    ///
+    /// The calls of a function are the executions of its first block, and its
+    /// gas is that of the instructions of its blocks at `regular_op_cost`
+    /// each, without the host functions it calls.
+    pub fn to_heatmap(&self, counters: &CoverageCounters, regular_op_cost: u32) -> Heatmap {
+        let hits = counters.hits();
+        let mut heatmap = Heatmap::new();
+        let mut previous = None;
+        for (block, hits) in self.blocks.iter().zip(hits) {
+            let gas = Gas::from(block.instructions) * Gas::from(regular_op_cost);
+            let entered = previous != Some(block.function_index);
+            let calls = if entered { hits } else { 0 };
+            heatmap.record(block.function_index, calls, gas.saturating_mul(hits));
+            previous = Some(block.function_index);
+        }
+        for (&index, name) in &self.function_names {
+            heatmap.set_name(index, name);
+        }
+        heatmap
+    }
+
+    /// Renders `counters` as an lcov tracefile, see the module documentation.
+    ///
+    /// Without line information, and for the functions without one, the
+    /// source file is named `source_name`.
+    pub fn to_lcov(&self, counters: &CoverageCounters, source_name: &str) -> String {
+        let hits = counters.hits();
+        let block_hits = |block: usize| hits.get(block).copied().unwrap_or_default();
+        let mut records: BTreeMap<&str, LcovRecord> = BTreeMap::new();
+        let mut block = 0;
+        while block < self.blocks.len() {
+            let function_index = self.blocks[block].function_index;
+            let end = block
+                + self.blocks[block..]
+                    .iter()
+                    .take_while(|other| other.function_index == function_index)
+                    .count();
+            let name = match self.function_names.get(&function_index) {
+                Some(name) => name.clone(),
+                None => format!("func[{function_index}]"),
+            };
+            let entry_hits = block_hits(block);
+            match self.location(self.blocks[block].code_offset) {
+                Some((file, line)) => {
+                    records.entry(file).or_default().functions.push((line, name, entry_hits))
+                }
+                None => {
+                    let line = u64::from(function_index) + 1;
+                    let record = records.entry(source_name).or_default();
+                    record.functions.push((line, name, entry_hits));
+                    record.add_line(line, entry_hits);
+                    for (branch, block) in (block..end).enumerate() {
+                        record.branches.push((line, branch, block_hits(block)));
+                    }
+                }
+            }
+            block = end;
+        }
+        // Each row of the line information is executed as many times as the
+        // block it is in.
+        for (address, file, line) in self.lines.iter().flat_map(LineTable::rows) {
+            let block = self.blocks.partition_point(|block| block.code_offset <= address);
+            let Some(block) = block.checked_sub(1) else { continue };
+            records.entry(file).or_default().add_line(line, block_hits(block));
+        }
+
+        let mut lcov = String::new();
+        for (file, record) in records {
+            record.write(&mut lcov, file);
+        }
+        lcov
+    }
+}
+
+/// The lcov record of a source file.
+#[derive(Default)]
+struct LcovRecord {
+    /// Line, name and hits of the functions.
+    functions: Vec<(u64, String, u64)>,
+    /// Line, number and hits of the branches.
+    branches: Vec<(u64, usize, u64)>,
+    lines: BTreeMap<u64, u64>,
+}
+
+impl LcovRecord {
+    fn add_line(&mut self, line: u64, hits: u64) {
+        let line_hits = self.lines.entry(line).or_default();
+        *line_hits = (*line_hits).max(hits);
+    }
+
+    fn write(&self, lcov: &mut String, file: &str) {
+        let _ = writeln!(lcov, "TN:\nSF:{file}");
+        for (line, name, _) in &self.functions {
+            let _ = writeln!(lcov, "FN:{line},{name}");
+        }
+        for (_, name, hits) in &self.functions {
+            let _ = writeln!(lcov, "FNDA:{hits},{name}");
+        }
+        let hit = self.functions.iter().filter(|(_, _, hits)| *hits > 0).count();
+        let _ = writeln!(lcov, "FNF:{}\nFNH:{hit}", self.functions.len());
+        if !self.branches.is_empty() {
+            for (line, branch, hits) in &self.branches {
+                let _ = writeln!(lcov, "BRDA:{line},0,{branch},{hits}");
+            }
+            let hit = self.branches.iter().filter(|(_, _, hits)| *hits > 0).count();
+            let _ = writeln!(lcov, "BRF:{}\nBRH:{hit}", self.branches.len());
+        }
+        for (line, hits) in &self.lines {
+            let _ = writeln!(lcov, "DA:{line},{hits}");
+        }
+        let hit = self.lines.values().filter(|hits| **hits > 0).count();
+        let _ = writeln!(lcov, "LF:{}\nLH:{hit}\nend_of_record", self.lines.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::runner::RunOptions;
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+    use crate::ContractCode;
+    use std::sync::Arc;
+    use unc_parameters::RuntimeFeesConfig;
+
+    const CONTRACT: &str = r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (func $branch (param i64)
+    (if (i64.eqz (local.get 0))
+      (then (nop))
+      (else (nop))))
+  (func $unused)
+  (func (export "main")
+    (call $input (i64.const 0))
+    (call $branch (call $register_len (i64.const 0))))
+)"#;
+
+    #[test]
+    fn test_coverage() {
+        let code = wat::parse_str(CONTRACT).unwrap();
+        let map = coverage_map(&code).unwrap();
+        let functions: Vec<u32> = map.blocks().iter().map(|block| block.function_index).collect();
+        assert_eq!(functions, [2, 2, 2, 3, 4]);
+        assert!(!map.has_line_info());
+        let code = ContractCode::new(code, None);
+        let config = test_vm_config();
+        let fees = RuntimeFeesConfig::test();
+        with_vm_variants(&config, |vm_kind| {
+            let mut config = config.clone();
+            config.vm_kind = vm_kind;
+            let counters = Arc::new(CoverageCounters::new(&map));
+            let options = RunOptions { coverage: Some(counters.clone()), ..RunOptions::default() };
+            for input in [vec![], vec![1], vec![2]] {
+                let outcome = crate::run_with_options(
+                    &code,
+                    "main",
+                    &mut MockedExternal::new(),
+                    create_context(input),
+                    &config,
+                    &fees,
+                    &[],
+                    None,
+                    &options,
+                )
+                .unwrap();
+                assert_eq!(outcome.aborted, None, "{vm_kind:?}");
+            }
+            // The entry of `$branch` and its two branches, `$unused` and
+            // `main`.
+            assert_eq!(counters.hits(), [3, 1, 2, 0, 3], "{vm_kind:?}");
+            // The contract is not instrumented without counters.
+            let outcome = crate::run_with_options(
+                &code,
+                "main",
+                &mut MockedExternal::new(),
+                create_context(vec![]),
+                &config,
+                &fees,
+                &[],
+                None,
+                &RunOptions::default(),
+            )
+            .unwrap();
+            assert_eq!(outcome.aborted, None, "{vm_kind:?}");
+            assert_eq!(counters.hits(), [3, 1, 2, 0, 3], "{vm_kind:?}");
+            let heatmap = map.to_heatmap(&counters, config.regular_op_cost);
+            let calls: Vec<_> = heatmap.functions().map(|f| (f.index, f.calls)).collect();
+            assert_eq!(calls, [(2, 3), (3, 0), (4, 3)], "{vm_kind:?}");
+            assert!(heatmap.hottest().iter().all(|f| (f.gas > 0) == (f.calls > 0)));
+            let lcov = map.to_lcov(&counters, "contract.wasm");
+            assert_eq!(
+                lcov,
+                "TN:\nSF:contract.wasm\nFN:3,func[2]\nFN:4,func[3]\nFN:5,func[4]\n\
+                 FNDA:3,func[2]\nFNDA:0,func[3]\nFNDA:3,func[4]\nFNF:3\nFNH:2\n\
+                 BRDA:3,0,0,3\nBRDA:3,0,1,1\nBRDA:3,0,2,2\nBRDA:4,0,0,0\nBRDA:5,0,0,3\n\
+                 BRF:5\nBRH:4\nDA:3,3\nDA:4,0\nDA:5,3\nLF:3\nLH:2\n\
+                 end_of_record\n",
+                "{vm_kind:?}"
+            );
+        });
+    }
+
+    #[test]
+    fn test_lcov_with_line_info() {
+        let block = |function_index, code_offset| CoverageBlock {
+            function_index,
+            code_offset,
+            instructions: 1,
+        };
+        let map = CoverageMap {
+            blocks: vec![block(1, 10), block(1, 20), block(2, 30)],
+            function_names: HashMap::from([(1, "main".to_string())]),
+            lines: Some(LineTable {
+                files: vec!["src/lib.rs".to_string()],
+                rows: vec![(10, Some((0, 3))), (15, Some((0, 4))), (20, Some((0, 5))), (25, None)],
+            }),
+        };
+        let counters = CoverageCounters::new(&map);
+        counters.hit(0);
+        counters.hit(0);
+        counters.hit(1);
+        counters.hit(7);
+        assert_eq!(counters.hits(), [2, 1, 0]);
+        assert_eq!(
+            map.to_lcov(&counters, "contract.wasm"),
+            "TN:\nSF:contract.wasm\nFN:3,func[2]\nFNDA:0,func[2]\nFNF:1\nFNH:0\n\
+             BRDA:3,0,0,0\nBRF:1\nBRH:0\nDA:3,0\nLF:1\nLH:0\nend_of_record\n\
+             TN:\nSF:src/lib.rs\nFN:3,main\nFNDA:2,main\nFNF:1\nFNH:1\n\
+             DA:3,2\nDA:4,2\nDA:5,1\nLF:3\nLH:3\nend_of_record\n"
+        );
+        let heatmap = map.to_heatmap(&counters, 10);
+        let functions: Vec<_> =
+            heatmap.functions().map(|f| (f.display_name(), f.calls, f.gas)).collect();
+        assert_eq!(functions, [("main".to_string(), 2, 30), ("func[2]".to_string(), 0, 0)]);
+    }
+}
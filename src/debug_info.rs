@@ -0,0 +1,156 @@
+//! Debugging information of contracts, for the coverage reports and the
+//! backtraces.
+//!
+//! Contracts built for debugging carry the names of their functions in the
+//! `name` custom section and the source lines of their instructions in DWARF
+//! `.debug_*` custom sections.  DWARF addresses are offsets from the start of
+//! the code section, right after its size.  The debugging information only
+//! makes reports easier to read, so malformed sections are ignored.
+
+use gimli::{EndianSlice, LittleEndian};
+use std::collections::HashMap;
+
+/// Names of the functions of `code` by function index, from its `name`
+/// custom section.
+pub(crate) fn function_names(code: &[u8]) -> HashMap<u32, String> {
+    let mut names = HashMap::new();
+    for payload in wasmparser::Parser::new(0).parse_all(code) {
+        match payload {
+            Ok(wasmparser::Payload::CustomSection { name: "name", data, data_offset, .. }) => {
+                let _ = read_names(data, data_offset, &mut names);
+            }
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    names
+}
+
+fn read_names(
+    data: &[u8],
+    data_offset: usize,
+    names: &mut HashMap<u32, String>,
+) -> wasmparser::Result<()> {
+    let mut reader = wasmparser::NameSectionReader::new(data, data_offset)?;
+    while !reader.eof() {
+        if let wasmparser::Name::Function(function_names) = reader.read()? {
+            let mut map = function_names.get_map()?;
+            for _ in 0..map.get_count() {
+                let naming = map.read()?;
+                names.insert(naming.index, naming.name.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The DWARF line information of a contract.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct LineTable {
+    pub(crate) files: Vec<String>,
+    /// Rows ordered by address, with the index of the file and the line of the
+    /// instructions from the address on, or `None` after the end of a
+    /// sequence of instructions.
+    pub(crate) rows: Vec<(u64, Option<(usize, u64)>)>,
+}
+
+impl LineTable {
+    /// Reads the line information of `code`, if it has any.
+    pub(crate) fn from_code(code: &[u8]) -> Option<Self> {
+        let mut sections = HashMap::new();
+        for payload in wasmparser::Parser::new(0).parse_all(code) {
+            match payload {
+                Ok(wasmparser::Payload::CustomSection { name, data, .. })
+                    if name.starts_with(".debug_") =>
+                {
+                    sections.insert(name, data);
+                }
+                Ok(_) => {}
+                Err(_) => return None,
+            }
+        }
+        if sections.is_empty() {
+            return None;
+        }
+        Self::read(&sections).ok().filter(|table| !table.rows.is_empty())
+    }
+
+    fn read(sections: &HashMap<&str, &[u8]>) -> gimli::Result<Self> {
+        let load = |id: gimli::SectionId| -> gimli::Result<EndianSlice<LittleEndian>> {
+            let data = sections.get(id.name()).copied().unwrap_or_default();
+            Ok(EndianSlice::new(data, LittleEndian))
+        };
+        let dwarf = gimli::Dwarf::load(load)?;
+        let mut table = Self::default();
+        let mut files = HashMap::new();
+        let mut units = dwarf.units();
+        while let Some(header) = units.next()? {
+            let unit = dwarf.unit(header)?;
+            let Some(program) = unit.line_program.clone() else { continue };
+            let mut rows = program.rows();
+            while let Some((header, row)) = rows.next_row()? {
+                let location = match (row.end_sequence(), row.file(header), row.line()) {
+                    (false, Some(file), Some(line)) => {
+                        let mut path = String::new();
+                        if let Some(directory) = file.directory(header) {
+                            path = dwarf.attr_string(&unit, directory)?.to_string_lossy().into();
+                            path.push('/');
+                        }
+                        path.push_str(
+                            &dwarf.attr_string(&unit, file.path_name())?.to_string_lossy(),
+                        );
+                        let next = files.len();
+                        let file = *files.entry(path).or_insert(next);
+                        Some((file, line.get()))
+                    }
+                    _ => None,
+                };
+                table.rows.push((row.address(), location));
+            }
+        }
+        table.files = vec![String::new(); files.len()];
+        for (path, file) in files {
+            table.files[file] = path;
+        }
+        table.rows.sort_by_key(|(address, _)| *address);
+        Ok(table)
+    }
+
+    /// File and line of the instruction at `address`.
+    pub(crate) fn location(&self, address: u64) -> Option<(&str, u64)> {
+        let row = self.rows.partition_point(|(start, _)| *start <= address).checked_sub(1)?;
+        let (file, line) = self.rows[row].1?;
+        Some((&self.files[file], line))
+    }
+
+    /// Address, file and line of the rows with a location.
+    #[cfg(feature = "coverage")]
+    pub(crate) fn rows(&self) -> impl Iterator<Item = (u64, &str, u64)> {
+        self.rows.iter().filter_map(|(address, location)| {
+            location.map(|(file, line)| (*address, self.files[file].as_str(), line))
+        })
+    }
+}
+
+/// Offsets of the instructions of each function body of `code` from the
+/// start of its code section, as DWARF addresses are.
+#[cfg(feature = "coverage")]
+pub(crate) fn instruction_offsets(code: &[u8]) -> wasmparser::Result<Vec<Vec<u64>>> {
+    let mut code_start = 0;
+    let mut functions = Vec::new();
+    for payload in wasmparser::Parser::new(0).parse_all(code) {
+        match payload? {
+            wasmparser::Payload::CodeSectionStart { range, .. } => code_start = range.start,
+            wasmparser::Payload::CodeSectionEntry(body) => {
+                let mut reader = body.get_operators_reader()?;
+                let mut offsets = Vec::new();
+                while !reader.eof() {
+                    offsets.push((reader.read_with_offset()?.1 - code_start) as u64);
+                }
+                functions.push(offsets);
+            }
+            _ => {}
+        }
+    }
+    Ok(functions)
+}
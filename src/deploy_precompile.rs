@@ -0,0 +1,413 @@
+//! Precompiling deployed contracts in the background, under back-pressure.
+//!
+//! A contract deployed in a block is compiled at its first call unless the
+//! cache already has it, so embedders precompile contracts when they see
+//! their `DeployContract` action.  Compiling on the thread applying the chunk
+//! delays the block, and compiling every deploy on other threads lets a burst
+//! of deploys take the CPUs block production needs.  A [`DeployPrecompiler`]
+//! queues the deploys instead and compiles them on a few threads of its own:
+//!
+//! * the queue is bounded, and a deploy arriving at a full queue is dropped,
+//!   or first waits for room up to the delay of the [`QueueFullAction`];
+//! * [`DeployPriority::High`] deploys, e.g. of contracts already called by
+//!   pending receipts, are compiled first and take the place of the newest
+//!   normal one in a full queue;
+//! * [`DeployPrecompiler::pause`] keeps the threads from starting
+//!   compilations while the embedder produces a block.
+//!
+//! A dropped deploy is compiled at its first call, as without precompilation.
+//! The queue is only about when contracts are compiled: the artifacts and the
+//! outcomes of the calls are the same.  With the `metrics` feature, the depth
+//! of the queues and what became of the deploys are part of
+//! [`crate::prometheus_metrics`].
+
+use crate::cache::get_contract_cache_key_with_options;
+use crate::logic::{CompiledContractCache, Config};
+use crate::runner::{CompileOptions, OptLevel, VMKindExt};
+use crate::{ContractCode, ContractPrecompilatonResult};
+use std::collections::VecDeque;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Longest a delayed deploy waits before reading the clock again, for the
+/// clocks which do not move with the system clock.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// Order in which the queued deploys are compiled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum DeployPriority {
+    Normal,
+    /// Compiled before the normal deploys, and queued in their place when the
+    /// queue is full.
+    High,
+}
+
+/// What becomes of a deploy arriving at a full queue.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QueueFullAction {
+    /// The deploy is dropped at once.
+    Drop,
+    /// [`DeployPrecompiler::on_deploy`] waits up to the duration for room in
+    /// the queue, and then drops the deploy.
+    Delay(Duration),
+}
+
+/// How many deploys a [`DeployPrecompiler`] queues and compiles at once.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DeployPrecompilerPolicy {
+    /// Deploys waiting to be compiled at most.
+    pub max_queued: usize,
+    pub when_full: QueueFullAction,
+    /// Threads compiling the deploys.
+    pub threads: usize,
+}
+
+impl Default for DeployPrecompilerPolicy {
+    fn default() -> Self {
+        Self { max_queued: 64, when_full: QueueFullAction::Drop, threads: 1 }
+    }
+}
+
+/// What [`DeployPrecompiler::on_deploy`] did with a deploy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Enqueued {
+    Queued,
+    /// The same code is already queued, now with the higher of the two
+    /// priorities.
+    AlreadyQueued,
+    /// The queue is full, the contract is compiled at its first call.
+    Dropped,
+}
+
+/// Deploys of a [`DeployPrecompiler`], by state.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeployPrecompilerStats {
+    /// Deploys waiting in the queue.
+    pub queued: usize,
+    /// Deploys being compiled.
+    pub compiling: usize,
+    pub compiled: u64,
+    /// Deploys whose contract was in the cache already.
+    pub already_cached: u64,
+    /// Deploys whose contract does not compile or could not be stored.
+    pub failed: u64,
+    /// Deploys dropped because of a full queue, or queued when the
+    /// precompiler was dropped.
+    pub dropped: u64,
+}
+
+/// [`DeployPrecompiler::flush`] was called while the precompiler is paused,
+/// with deploys left that it does not compile until the pause is over.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("the precompiler is paused with {queued} deploys queued")]
+pub struct PrecompilerPaused {
+    pub queued: usize,
+}
+
+/// What became of a deploy, as counted by the metrics.
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum PrecompileResult {
+    Compiled,
+    AlreadyCached,
+    Failed,
+    Dropped,
+}
+
+/// Compiles deployed contracts in the background, see the module
+/// documentation.
+///
+/// Dropping the precompiler waits for the compilations in progress and drops
+/// the queued deploys.
+pub struct DeployPrecompiler {
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+struct Shared {
+    config: Config,
+    cache: Arc<dyn CompiledContractCache>,
+    policy: DeployPrecompilerPolicy,
+    state: Mutex<State>,
+    /// Notified on every change of the state.
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    /// Queued deploys by [`DeployPriority`], oldest first.
+    queues: [VecDeque<ContractCode>; 2],
+    pauses: usize,
+    stopping: bool,
+    stats: DeployPrecompilerStats,
+}
+
+impl State {
+    fn queued(&self) -> usize {
+        self.queues.iter().map(VecDeque::len).sum()
+    }
+
+    fn drop_deploy(&mut self) {
+        self.stats.dropped += 1;
+        crate::metrics::precompile_done(PrecompileResult::Dropped);
+    }
+}
+
+impl DeployPrecompiler {
+    /// Makes a precompiler storing the contracts compiled with `config` in
+    /// `cache`.
+    pub fn new(
+        config: Config,
+        cache: Arc<dyn CompiledContractCache>,
+        policy: DeployPrecompilerPolicy,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            config,
+            cache,
+            policy,
+            state: Mutex::default(),
+            changed: Condvar::new(),
+        });
+        let workers = (0..policy.threads.max(1))
+            .map(|_| {
+                let shared = Arc::clone(&shared);
+                std::thread::Builder::new()
+                    .name("deploy-precompile".to_string())
+                    .spawn(move || shared.work())
+                    .expect("failed to spawn a deploy precompile thread")
+            })
+            .collect();
+        Self { shared, workers }
+    }
+
+    pub fn policy(&self) -> &DeployPrecompilerPolicy {
+        &self.shared.policy
+    }
+
+    /// Queues the precompilation of `code`, deployed by a `DeployContract`
+    /// action, see the module documentation.
+    pub fn on_deploy(&self, code: ContractCode, priority: DeployPriority) -> Enqueued {
+        let shared = &*self.shared;
+        let mut state = shared.state.lock().unwrap();
+        let normal = &mut state.queues[DeployPriority::Normal as usize];
+        if let Some(position) = normal.iter().position(|queued| queued.hash() == code.hash()) {
+            if priority == DeployPriority::High {
+                let code = normal.remove(position).unwrap();
+                state.queues[DeployPriority::High as usize].push_back(code);
+            }
+            return Enqueued::AlreadyQueued;
+        }
+        let high = &state.queues[DeployPriority::High as usize];
+        if high.iter().any(|queued| queued.hash() == code.hash()) {
+            return Enqueued::AlreadyQueued;
+        }
+
+        let deadline = match shared.policy.when_full {
+            QueueFullAction::Drop => None,
+            QueueFullAction::Delay(delay) => Some(Instant::now() + delay),
+        };
+        while state.queued() >= shared.policy.max_queued {
+            if priority == DeployPriority::High {
+                if state.queues[DeployPriority::Normal as usize].pop_back().is_some() {
+                    state.drop_deploy();
+                    crate::metrics::precompile_queue(-1, 0);
+                    continue;
+                }
+            }
+            let now = Instant::now();
+            match deadline {
+                Some(deadline) if now < deadline && shared.policy.max_queued > 0 => {
+                    let wait = (deadline - now).min(POLL_INTERVAL);
+                    state = shared.changed.wait_timeout(state, wait).unwrap().0;
+                }
+                _ => {
+                    state.drop_deploy();
+                    return Enqueued::Dropped;
+                }
+            }
+        }
+        state.queues[priority as usize].push_back(code);
+        crate::metrics::precompile_queue(1, 0);
+        shared.changed.notify_all();
+        Enqueued::Queued
+    }
+
+    /// Keeps the threads from starting compilations until the returned guard
+    /// is dropped, e.g. while producing a block.
+    ///
+    /// The compilations in progress go on.  Deploys are still queued, up to
+    /// the bound of the queue.
+    pub fn pause(&self) -> PrecompilePause<'_> {
+        self.shared.state.lock().unwrap().pauses += 1;
+        PrecompilePause { shared: &self.shared }
+    }
+
+    pub fn stats(&self) -> DeployPrecompilerStats {
+        let state = self.shared.state.lock().unwrap();
+        DeployPrecompilerStats { queued: state.queued(), ..state.stats }
+    }
+
+    /// Waits until the deploys queued so far are compiled.
+    ///
+    /// While the precompiler is paused, only waits for the compilations in
+    /// progress, and fails if deploys are left in the queue.
+    pub fn flush(&self) -> Result<(), PrecompilerPaused> {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.queued() > 0 || state.stats.compiling > 0 {
+            if state.pauses > 0 && state.stats.compiling == 0 {
+                return Err(PrecompilerPaused { queued: state.queued() });
+            }
+            state = self.shared.changed.wait(state).unwrap();
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DeployPrecompiler {
+    fn drop(&mut self) {
+        {
+            let mut state = self.shared.state.lock().unwrap();
+            state.stopping = true;
+            let abandoned = std::mem::take(&mut state.queues);
+            for _ in abandoned.iter().flatten() {
+                state.drop_deploy();
+            }
+            crate::metrics::precompile_queue(-(abandoned.iter().flatten().count() as i64), 0);
+        }
+        self.shared.changed.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Pauses a [`DeployPrecompiler`] until dropped, see
+/// [`DeployPrecompiler::pause`].
+#[must_use]
+pub struct PrecompilePause<'a> {
+    shared: &'a Shared,
+}
+
+impl Drop for PrecompilePause<'_> {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().pauses -= 1;
+        self.shared.changed.notify_all();
+    }
+}
+
+impl Shared {
+    fn work(&self) {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            match self.next(&mut state) {
+                Some(code) => {
+                    drop(state);
+                    let result = precompile(&self.config, &code, &*self.cache);
+                    state = self.state.lock().unwrap();
+                    state.stats.compiling -= 1;
+                    match result {
+                        PrecompileResult::Compiled => state.stats.compiled += 1,
+                        PrecompileResult::AlreadyCached => state.stats.already_cached += 1,
+                        PrecompileResult::Failed => state.stats.failed += 1,
+                        PrecompileResult::Dropped => state.stats.dropped += 1,
+                    }
+                    crate::metrics::precompile_queue(0, -1);
+                    crate::metrics::precompile_done(result);
+                    self.changed.notify_all();
+                }
+                None if state.stopping => return,
+                None => state = self.changed.wait(state).unwrap(),
+            }
+        }
+    }
+
+    /// Takes the next deploy to compile, if the precompiler is not paused.
+    fn next(&self, state: &mut MutexGuard<'_, State>) -> Option<ContractCode> {
+        if state.pauses > 0 || state.stopping {
+            return None;
+        }
+        let code = state.queues.iter_mut().rev().find_map(VecDeque::pop_front)?;
+        state.stats.compiling += 1;
+        crate::metrics::precompile_queue(-1, 1);
+        // A deploy waiting for room can be queued now.
+        self.changed.notify_all();
+        Some(code)
+    }
+}
+
+fn precompile(
+    config: &Config,
+    code: &ContractCode,
+    cache: &dyn CompiledContractCache,
+) -> PrecompileResult {
+    let _span =
+        tracing::debug_span!(target: "vm", "precompile_deploy", code_hash = %code.hash()).entered();
+    let options = CompileOptions { opt_level: OptLevel::Fast, ..CompileOptions::default() };
+    let runtime = match config.vm_kind.runtime_with_options(config.clone(), options) {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            tracing::warn!(target: "vm", %err, "cannot precompile deployed contract");
+            return PrecompileResult::Failed;
+        }
+    };
+    match cache.has(&get_contract_cache_key_with_options(code, config, options)) {
+        Ok(true) => return PrecompileResult::AlreadyCached,
+        Ok(false) => {}
+        Err(err) => {
+            tracing::warn!(target: "vm", %err, "cannot read the cache of deployed contracts");
+            return PrecompileResult::Failed;
+        }
+    }
+    match runtime.precompile(code, cache) {
+        Ok(Ok(ContractPrecompilatonResult::ContractCompiled(_))) => PrecompileResult::Compiled,
+        Ok(Ok(_)) => PrecompileResult::AlreadyCached,
+        Ok(Err(err)) => {
+            tracing::debug!(target: "vm", %err, "deployed contract does not compile");
+            PrecompileResult::Failed
+        }
+        Err(err) => {
+            tracing::warn!(target: "vm", %err, "cannot store deployed contract");
+            PrecompileResult::Failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_vm_config;
+    use crate::MockCompiledContractCache;
+
+    #[test]
+    fn test_deploys_are_precompiled() {
+        let trivial = || ContractCode::new(unc_test_contracts::trivial_contract().to_vec(), None);
+        let empty = || ContractCode::new(wat::parse_str("(module)").unwrap(), None);
+        let cache = Arc::new(MockCompiledContractCache::default());
+        let policy = DeployPrecompilerPolicy { max_queued: 1, ..Default::default() };
+        let precompiler = DeployPrecompiler::new(test_vm_config(), cache.clone(), policy);
+
+        let pause = precompiler.pause();
+        assert_eq!(precompiler.on_deploy(trivial(), DeployPriority::Normal), Enqueued::Queued);
+        assert_eq!(precompiler.on_deploy(trivial(), DeployPriority::High), Enqueued::AlreadyQueued);
+        assert_eq!(precompiler.on_deploy(empty(), DeployPriority::Normal), Enqueued::Dropped);
+        // Only normal deploys make room for high priority ones.
+        assert_eq!(precompiler.on_deploy(empty(), DeployPriority::High), Enqueued::Dropped);
+        let stats = precompiler.stats();
+        assert_eq!((stats.queued, stats.compiling, stats.dropped), (1, 0, 2));
+        assert_eq!(precompiler.flush(), Err(PrecompilerPaused { queued: 1 }));
+        drop(pause);
+        precompiler.flush().unwrap();
+        let stats = precompiler.stats();
+        assert_eq!((stats.queued, stats.compiled, cache.len()), (0, 1, 1));
+
+        // A high priority deploy takes the place of a normal one.
+        let pause = precompiler.pause();
+        assert_eq!(precompiler.on_deploy(empty(), DeployPriority::Normal), Enqueued::Queued);
+        assert_eq!(precompiler.on_deploy(trivial(), DeployPriority::High), Enqueued::Queued);
+        assert_eq!(precompiler.stats().dropped, 3);
+        drop(pause);
+        precompiler.flush().unwrap();
+        let stats = precompiler.stats();
+        assert_eq!((stats.compiled, stats.already_cached), (1, 1));
+    }
+}
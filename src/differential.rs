@@ -0,0 +1,228 @@
+//! Differential testing: running a call on every VM and comparing the outcomes.
+//!
+//! Nodes running different VMs must agree on the outcome of every call, gas
+//! included, or they fork.  [`run_all_kinds`] runs a call on each VM compiled
+//! into the binary which can run the contracts of the config, every time
+//! against a fresh [`MockedExternal`], and [`DifferentialReport::divergence`]
+//! compares the outcomes and the states the calls leave behind.
+//!
+//! This is the check fuzzers run on generated modules, see the
+//! `differential` target of the `fuzz` crate, and tests can use it for
+//! contracts written by hand.
+
+use crate::logic::errors::FunctionCallError;
+use crate::logic::mocks::mock_external::MockedExternal;
+use crate::logic::types::ReturnData;
+use crate::logic::{Config, VMContext};
+use crate::runner::{check_backend, BackendRejection, VMKindExt, VMResult};
+use crate::{ContractCode, RunOptions};
+use std::collections::BTreeMap;
+use unc_parameters::vm::VMKind;
+use unc_parameters::{RuntimeConfigStore, RuntimeFeesConfig};
+use unc_primitives_core::version::PROTOCOL_VERSION;
+
+const ALL_VM_KINDS: [VMKind; 4] =
+    [VMKind::NearVm, VMKind::Wasmer2, VMKind::Wasmer0, VMKind::Wasmtime];
+
+/// A call run on one VM.
+pub struct VMRun {
+    pub vm_kind: VMKind,
+    pub result: VMResult,
+    /// The state after the call.
+    pub ext: MockedExternal,
+}
+
+/// The runs of a call on every VM, see [`run_all_kinds`].
+pub struct DifferentialReport {
+    /// The runs, in the order of preference of the VMs.
+    pub runs: Vec<VMRun>,
+    /// The VMs which cannot run the contracts of the config.
+    pub skipped: Vec<(VMKind, BackendRejection)>,
+}
+
+/// A part of the outcome on which a VM disagrees with the first one.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("{other:?} disagrees with {reference:?} on the {what}: {other_value} != {reference_value}")]
+pub struct Divergence {
+    pub reference: VMKind,
+    pub other: VMKind,
+    /// The part of the outcome, e.g. `burnt gas`.
+    pub what: &'static str,
+    pub reference_value: String,
+    pub other_value: String,
+}
+
+impl DifferentialReport {
+    /// The first divergence between the first run and the others, if any.
+    pub fn divergence(&self) -> Option<Divergence> {
+        let (reference, others) = self.runs.split_first()?;
+        let expected = facets(reference);
+        for other in others {
+            for ((what, reference_value), (_, other_value)) in expected.iter().zip(facets(other)) {
+                if *reference_value != other_value {
+                    return Some(Divergence {
+                        reference: reference.vm_kind,
+                        other: other.vm_kind,
+                        what,
+                        reference_value: reference_value.clone(),
+                        other_value,
+                    });
+                }
+            }
+        }
+        None
+    }
+
+    /// Panics if the VMs disagree.
+    #[track_caller]
+    pub fn assert_agree(&self) {
+        if let Some(divergence) = self.divergence() {
+            panic!("{divergence}");
+        }
+    }
+}
+
+/// The parts of the outcome of a run the VMs must agree on, in a fixed order.
+///
+/// The message of link errors comes from the VM and is not compared, nor is
+/// the stack collected when running out of gas, which not every VM can walk.
+fn facets(run: &VMRun) -> [(&'static str, String); 11] {
+    let state: BTreeMap<_, _> = run.ext.fake_trie.iter().collect();
+    let state = format!("{state:?}");
+    let outcome = match &run.result {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            let none = || "-".to_string();
+            return [
+                ("runner error", err.to_string()),
+                ("abort", none()),
+                ("return data", none()),
+                ("burnt gas", none()),
+                ("used gas", none()),
+                ("balance", none()),
+                ("storage usage", none()),
+                ("logs", none()),
+                ("receipts", none()),
+                ("profile", none()),
+                ("state", state),
+            ];
+        }
+    };
+    let abort = match &outcome.aborted {
+        Some(FunctionCallError::LinkError { .. }) => "link error".to_string(),
+        Some(err) => err.to_string(),
+        None => "none".to_string(),
+    };
+    let return_data = match &outcome.return_data {
+        ReturnData::Value(value) => format!("value {value:?}"),
+        ReturnData::ReceiptIndex(index) => format!("receipt {index}"),
+        ReturnData::None => "none".to_string(),
+    };
+    [
+        ("runner error", "none".to_string()),
+        ("abort", abort),
+        ("return data", return_data),
+        ("burnt gas", outcome.burnt_gas.to_string()),
+        ("used gas", outcome.used_gas.to_string()),
+        ("balance", outcome.balance.to_string()),
+        ("storage usage", outcome.storage_usage.to_string()),
+        ("logs", format!("{:?}", outcome.logs)),
+        ("receipts", format!("{:?}", outcome.receipts)),
+        ("profile", format!("{:?}", outcome.profile)),
+        ("state", state),
+    ]
+}
+
+/// Runs `method_name` of `code` on every VM with the config of the current
+/// protocol version.
+pub fn run_all_kinds(
+    code: &ContractCode,
+    method_name: &str,
+    context: &VMContext,
+) -> DifferentialReport {
+    let store = RuntimeConfigStore::new(None);
+    let runtime_config = store.get_config(PROTOCOL_VERSION);
+    run_all_kinds_with_config(
+        code,
+        method_name,
+        context,
+        &Config::from(runtime_config.wasm_config.clone()),
+        &runtime_config.fees,
+    )
+}
+
+/// Same as [`run_all_kinds`] with the given config, of which the VM is
+/// ignored.
+pub fn run_all_kinds_with_config(
+    code: &ContractCode,
+    method_name: &str,
+    context: &VMContext,
+    config: &Config,
+    fees_config: &RuntimeFeesConfig,
+) -> DifferentialReport {
+    let mut runs = Vec::new();
+    let mut skipped = Vec::new();
+    for vm_kind in ALL_VM_KINDS {
+        let mut config = config.clone();
+        config.vm_kind = vm_kind;
+        if let Err(rejection) = check_backend(vm_kind, &config) {
+            skipped.push((vm_kind, rejection));
+            continue;
+        }
+        let runtime = vm_kind.runtime(config).expect("the backend has been checked");
+        let mut ext = MockedExternal::new();
+        let options = RunOptions { record_receipts: true, ..RunOptions::default() };
+        let result = runtime.run_with_options(
+            code,
+            method_name,
+            &mut ext,
+            context.clone(),
+            fees_config,
+            &[],
+            None,
+            &options,
+        );
+        runs.push(VMRun { vm_kind, result, ext });
+    }
+    DifferentialReport { runs, skipped }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{create_context, test_vm_config};
+
+    #[test]
+    fn test_vms_agree() {
+        let code = wat::parse_str(
+            r#"
+(module
+  (import "env" "storage_write" (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "log_utf8" (func $log_utf8 (param i64 i64)))
+  (memory 1)
+  (data (i32.const 0) "key" "value" "hello")
+  (func (export "main")
+    (drop (call $storage_write (i64.const 3) (i64.const 0) (i64.const 5) (i64.const 3)
+      (i64.const 0)))
+    (call $log_utf8 (i64.const 5) (i64.const 8)))
+  (func (export "trap") unreachable)
+  (func (export "loop") (loop (br 0)))
+)"#,
+        )
+        .unwrap();
+        let code = ContractCode::new(code, None);
+        let config = test_vm_config();
+        let fees = RuntimeFeesConfig::test();
+        let context = VMContext { prepaid_gas: 10u64.pow(10), ..create_context(vec![]) };
+        let run = |method| run_all_kinds_with_config(&code, method, &context, &config, &fees);
+        for method in ["main", "trap", "loop", "missing"] {
+            let report = run(method);
+            assert!(!report.runs.is_empty());
+            report.assert_agree();
+        }
+
+        let mut report = run("trap");
+        report.runs.insert(0, run("main").runs.remove(0));
+        assert_eq!(report.divergence().unwrap().what, "abort");
+    }
+}
@@ -0,0 +1,377 @@
+//! Estimating the gas of a call by running it without side effects.
+//!
+//! Wallets and RPC nodes need to know how much gas to attach to a call.
+//! [`crate::VM::estimate_gas`] runs the call once with all the gas it could
+//! use and reports what it burnt and attached to receipts.
+//!
+//! The call runs on a [`DryRunExternal`], which reads the state through the
+//! real [`External`] but keeps the writes and receipts of the call to itself,
+//! so the state the estimate ran against stays the same.
+
+use crate::logic::errors::{FunctionCallError, VMLogicError};
+use crate::logic::mocks::mock_external::{MockedExternal, MockedValuePtr};
+use crate::logic::types::ReceiptIndex;
+use crate::logic::{ActionReceipt, External, ReceiptCost, StorageGetMode, TrieNodesCount, ValuePtr};
+use std::collections::HashMap;
+use unc_crypto::PublicKey;
+use unc_primitives_core::hash::CryptoHash;
+use unc_primitives_core::types::{AccountId, Balance, Gas, GasWeight, Nonce, Power};
+
+type Result<T, E = VMLogicError> = std::result::Result<T, E>;
+
+/// Gas needed by a call, see [`crate::VM::estimate_gas`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct GasEstimate {
+    /// Gas burnt by the execution of the call.
+    pub burnt_gas: Gas,
+    /// Gas to prepay for the call: the burnt gas plus the gas attached to
+    /// the receipts it creates.
+    ///
+    /// Function calls with a gas weight only get the gas they specify, not
+    /// the unused gas the runtime would distribute to them.
+    pub used_gas: Gas,
+    /// Receipts the call would create.
+    pub receipts: Vec<ActionReceipt>,
+    /// Gas and tokens the call would attach to each of the receipts.
+    pub receipt_costs: Vec<ReceiptCost>,
+    /// Why the call failed, in which case the gas is what it used until then.
+    ///
+    /// A call failing with [`crate::logic::HostError::GasLimitExceeded`]
+    /// burns more than any transaction can pay for.
+    pub aborted: Option<FunctionCallError>,
+}
+
+/// [`External`] reading the state of another one and keeping the changes of
+/// the call to itself.
+///
+/// Storage reads see the writes of the call first and go to the underlying
+/// external otherwise, so the trie nodes they touch are counted as usual.
+/// Receipts are created in a [`MockedExternal`], as are data ids, which
+/// therefore differ from the ones the runtime would generate.
+pub struct DryRunExternal<'a> {
+    ext: &'a mut dyn External,
+    /// Values written, or removed with `None`, by the call.
+    changes: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Prefixes of the subtrees removed by the call, before `changes`.
+    removed_prefixes: Vec<Vec<u8>>,
+    receipts: MockedExternal,
+}
+
+impl<'a> DryRunExternal<'a> {
+    pub fn new(ext: &'a mut dyn External) -> Self {
+        Self {
+            ext,
+            changes: HashMap::new(),
+            removed_prefixes: Vec::new(),
+            receipts: MockedExternal::new(),
+        }
+    }
+
+    /// The value of `key` written by the call, `Some(None)` if it has been
+    /// removed and `None` if the call left it alone.
+    fn changed(&self, key: &[u8]) -> Option<Option<&[u8]>> {
+        match self.changes.get(key) {
+            Some(value) => Some(value.as_deref()),
+            None if self.removed_prefixes.iter().any(|prefix| key.starts_with(prefix)) => {
+                Some(None)
+            }
+            None => None,
+        }
+    }
+}
+
+impl External for DryRunExternal<'_> {
+    fn storage_set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.changes.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn storage_get<'b>(
+        &'b self,
+        key: &[u8],
+        mode: StorageGetMode,
+    ) -> Result<Option<Box<dyn ValuePtr + 'b>>> {
+        match self.changed(key) {
+            Some(value) => Ok(value.map(|value| Box::new(MockedValuePtr::new(value)) as Box<_>)),
+            None => self.ext.storage_get(key, mode),
+        }
+    }
+
+    fn storage_remove(&mut self, key: &[u8]) -> Result<()> {
+        self.changes.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn storage_remove_subtree(&mut self, prefix: &[u8]) -> Result<()> {
+        self.changes.retain(|key, _| !key.starts_with(prefix));
+        self.removed_prefixes.push(prefix.to_vec());
+        Ok(())
+    }
+
+    fn storage_has_key(&mut self, key: &[u8], mode: StorageGetMode) -> Result<bool> {
+        match self.changed(key) {
+            Some(value) => Ok(value.is_some()),
+            None => self.ext.storage_has_key(key, mode),
+        }
+    }
+
+    fn storage_proof(&self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.ext.storage_proof(key)
+    }
+
+    fn state_changed(&self) -> bool {
+        !self.changes.is_empty() || !self.removed_prefixes.is_empty() || self.ext.state_changed()
+    }
+
+    fn generate_data_id(&mut self) -> CryptoHash {
+        self.receipts.generate_data_id()
+    }
+
+    fn get_trie_nodes_count(&self) -> TrieNodesCount {
+        self.ext.get_trie_nodes_count()
+    }
+
+    fn validator_frozen(&self, account_id: &AccountId) -> Result<Option<Balance>> {
+        self.ext.validator_frozen(account_id)
+    }
+
+    fn validator_power(&self, account_id: &AccountId) -> Result<Option<Power>> {
+        self.ext.validator_power(account_id)
+    }
+
+    fn validator_total_frozen(&self) -> Result<Balance> {
+        self.ext.validator_total_frozen()
+    }
+
+    fn validator_total_power(&self) -> Result<Power> {
+        self.ext.validator_total_power()
+    }
+
+    fn account_code_hash(&self, account_id: &AccountId) -> Result<Option<CryptoHash>> {
+        self.ext.account_code_hash(account_id)
+    }
+
+    fn create_receipt(
+        &mut self,
+        receipt_indices: Vec<ReceiptIndex>,
+        receiver_id: AccountId,
+    ) -> Result<ReceiptIndex> {
+        self.receipts.create_receipt(receipt_indices, receiver_id)
+    }
+
+    fn append_action_create_account(&mut self, receipt_index: ReceiptIndex) -> Result<()> {
+        self.receipts.append_action_create_account(receipt_index)
+    }
+
+    fn append_action_deploy_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code: Vec<u8>,
+    ) -> Result<()> {
+        self.receipts.append_action_deploy_contract(receipt_index, code)
+    }
+
+    fn append_action_deploy_global_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code_hash: CryptoHash,
+    ) -> Result<()> {
+        self.receipts.append_action_deploy_global_contract(receipt_index, code_hash)
+    }
+
+    fn append_action_function_call_weight(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        method_name: Vec<u8>,
+        args: Vec<u8>,
+        attached_deposit: Balance,
+        prepaid_gas: Gas,
+        gas_weight: GasWeight,
+    ) -> Result<()> {
+        self.receipts.append_action_function_call_weight(
+            receipt_index,
+            method_name,
+            args,
+            attached_deposit,
+            prepaid_gas,
+            gas_weight,
+        )
+    }
+
+    fn append_action_transfer(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        deposit: Balance,
+    ) -> Result<()> {
+        self.receipts.append_action_transfer(receipt_index, deposit)
+    }
+
+    fn append_action_stake(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        stake: Balance,
+        public_key: PublicKey,
+    ) {
+        self.receipts.append_action_stake(receipt_index, stake, public_key)
+    }
+
+    fn append_action_add_key_with_full_access(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+    ) {
+        self.receipts.append_action_add_key_with_full_access(receipt_index, public_key, nonce)
+    }
+
+    fn append_action_add_key_with_function_call(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+        allowance: Option<Balance>,
+        receiver_id: AccountId,
+        method_names: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        self.receipts.append_action_add_key_with_function_call(
+            receipt_index,
+            public_key,
+            nonce,
+            allowance,
+            receiver_id,
+            method_names,
+        )
+    }
+
+    fn append_action_delete_key(&mut self, receipt_index: ReceiptIndex, public_key: PublicKey) {
+        self.receipts.append_action_delete_key(receipt_index, public_key)
+    }
+
+    fn append_action_delete_account(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        beneficiary_id: AccountId,
+    ) -> Result<()> {
+        self.receipts.append_action_delete_account(receipt_index, beneficiary_id)
+    }
+
+    fn scratch_get(&self) -> Result<Option<Vec<u8>>> {
+        self.ext.scratch_get()
+    }
+
+    fn append_scratch(&mut self, receipt_index: ReceiptIndex, data: Vec<u8>) -> Result<()> {
+        self.receipts.append_scratch(receipt_index, data)
+    }
+
+    fn get_receipt_receiver(&self, receipt_index: ReceiptIndex) -> &AccountId {
+        self.receipts.get_receipt_receiver(receipt_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::types::{ReceiptAction, ReturnData};
+    use crate::runner::VMKindExt;
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+    use crate::{ContractCode, RunOptions};
+    use unc_parameters::vm::VMKind;
+    use unc_parameters::RuntimeFeesConfig;
+
+    #[test]
+    fn test_dry_run_external() {
+        let mut ext = MockedExternal::new();
+        ext.storage_set(b"a1", b"x").unwrap();
+        ext.storage_set(b"a2", b"y").unwrap();
+        ext.storage_set(b"b", b"z").unwrap();
+        let original = ext.fake_trie.clone();
+
+        let mut dry_run = DryRunExternal::new(&mut ext);
+        let get = |dry_run: &DryRunExternal, key: &[u8]| {
+            dry_run.storage_get(key, StorageGetMode::Trie).unwrap().map(|ptr| ptr.deref().unwrap())
+        };
+        dry_run.storage_set(b"b", b"w").unwrap();
+        assert_eq!(get(&dry_run, b"b"), Some(b"w".to_vec()));
+        dry_run.storage_remove(b"b").unwrap();
+        assert_eq!(get(&dry_run, b"b"), None);
+        assert!(!dry_run.storage_has_key(b"b", StorageGetMode::Trie).unwrap());
+
+        dry_run.storage_set(b"a3", b"v").unwrap();
+        dry_run.storage_remove_subtree(b"a").unwrap();
+        dry_run.storage_set(b"a2", b"u").unwrap();
+        assert_eq!(get(&dry_run, b"a1"), None);
+        assert_eq!(get(&dry_run, b"a2"), Some(b"u".to_vec()));
+        assert_eq!(get(&dry_run, b"a3"), None);
+
+        let receipt = dry_run.create_receipt(vec![], "bob.near".parse().unwrap()).unwrap();
+        dry_run.append_action_transfer(receipt, 1).unwrap();
+        assert_eq!(dry_run.get_receipt_receiver(receipt).as_str(), "bob.near");
+
+        assert_eq!(ext.fake_trie, original);
+        assert!(ext.action_log.is_empty());
+    }
+
+    #[test]
+    fn test_external_storage() {
+        let mut ext = MockedExternal::new();
+        crate::logic::test_utils::test_external_storage(&mut DryRunExternal::new(&mut ext));
+        assert!(ext.fake_trie.is_empty());
+    }
+
+    #[test]
+    fn test_estimate_gas() {
+        let code = wat::parse_str(
+            r#"
+(module
+  (import "env" "storage_write" (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "storage_read" (func $storage_read (param i64 i64 i64) (result i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (import "env" "promise_create"
+    (func $promise_create (param i64 i64 i64 i64 i64 i64 i64 i64) (result i64)))
+  (memory 1)
+  (data (i32.const 0) "keyvaluebob.nearmain")
+  (func (export "main")
+    (drop (call $storage_write (i64.const 3) (i64.const 0) (i64.const 5) (i64.const 3) (i64.const 0)))
+    (drop (call $storage_read (i64.const 3) (i64.const 0) (i64.const 0)))
+    (call $value_return (i64.const 5) (i64.const 3))
+    (drop (call $promise_create
+      (i64.const 8) (i64.const 8) (i64.const 4) (i64.const 16) (i64.const 0) (i64.const 0)
+      (i64.const 32) (i64.const 1000000000000))))
+)"#,
+        )
+        .unwrap();
+        let code = ContractCode::new(code, None);
+        let config = test_vm_config();
+        let fees = RuntimeFeesConfig::test();
+        with_vm_variants(&config, |vm_kind: VMKind| {
+            let runtime = vm_kind.runtime(config.clone()).unwrap();
+            let mut ext = MockedExternal::new();
+            let context = create_context(vec![]);
+            let estimate =
+                runtime.estimate_gas(&code, "main", &mut ext, context, &fees, &[], None).unwrap();
+            assert_eq!(estimate.aborted, None);
+            assert_eq!(estimate.used_gas, estimate.burnt_gas + 1_000_000_000_000);
+            assert_eq!(estimate.receipts.len(), 1);
+            assert_eq!(estimate.receipts[0].receiver_id.as_str(), "bob.near");
+            assert!(matches!(
+                estimate.receipts[0].actions[..],
+                [ReceiptAction::FunctionCall { prepaid_gas: 1_000_000_000_000, .. }]
+            ));
+            assert!(ext.fake_trie.is_empty());
+            assert!(ext.action_log.is_empty());
+
+            // The estimated gas is enough to run the call for real.
+            let mut context = create_context(vec![]);
+            context.prepaid_gas = estimate.used_gas;
+            let options = RunOptions { record_receipts: true, ..RunOptions::default() };
+            let outcome = runtime
+                .run_with_options(&code, "main", &mut ext, context, &fees, &[], None, &options)
+                .unwrap();
+            assert_eq!(outcome.aborted, None);
+            assert_eq!(outcome.return_data, ReturnData::Value(b"value".to_vec()));
+            assert_eq!(outcome.burnt_gas, estimate.burnt_gas);
+            assert_eq!(outcome.used_gas, estimate.used_gas);
+            assert_eq!(outcome.receipts, estimate.receipts);
+        });
+    }
+}
@@ -6,7 +6,7 @@ pub trait IntoVMError {
 
 #[derive(Debug, PartialEq)]
 pub enum ContractPrecompilatonResult {
-    ContractCompiled,
+    ContractCompiled(crate::CompilationInfo),
     ContractAlreadyInCache,
     CacheNotAvailable,
 }
@@ -1,7 +1,6 @@
-const REFERENCE_TYPES: bool = false;
+use crate::logic::Config;
+
 const MULTI_VALUE: bool = false;
-const BULK_MEMORY: bool = false;
-const SIMD: bool = false;
 const THREADS: bool = false;
 const TAIL_CALL: bool = false;
 const MULTI_MEMORY: bool = false;
@@ -18,16 +17,51 @@ const MEMORY_CONTROL: bool = false;
 #[derive(Clone, Copy, PartialEq, Eq)]
 pub(crate) struct WasmFeatures {
     sign_extension: bool,
+    /// The fixed-width SIMD proposal, only with the finite-wasm
+    /// instrumentation: the pwasm passes cannot parse it.
+    simd: bool,
+    /// The bulk memory and reference types proposals, only with the
+    /// finite-wasm instrumentation, which the length of their instructions is
+    /// charged with.
+    bulk_memory_reftypes: bool,
+}
+
+impl WasmFeatures {
+    /// Whether contracts may have instructions of which the work grows with
+    /// an operand, see `crate::prepare::aggregate_gas`.
+    pub(crate) fn has_aggregates(self) -> bool {
+        self.bulk_memory_reftypes
+    }
+
+    /// The features of the contracts prepared with `passes` under `config`.
+    pub(crate) fn for_passes(passes: &crate::prepare::PreparePasses, config: &Config) -> Self {
+        use crate::prepare::{GasInstrumentation, StackLimiter};
+        let pwasm = passes.gas == GasInstrumentation::Pwasm || passes.stack == StackLimiter::Pwasm;
+        WasmFeatures {
+            sign_extension: passes.sign_extension,
+            simd: config.simd && !pwasm,
+            bulk_memory_reftypes: config.bulk_memory_reftypes && !pwasm,
+        }
+    }
 }
 
-impl From<crate::logic::ContractPrepareVersion> for WasmFeatures {
-    fn from(version: crate::logic::ContractPrepareVersion) -> Self {
+impl From<&Config> for WasmFeatures {
+    fn from(config: &Config) -> Self {
+        let version = config.limit_config.contract_prepare_version;
         let sign_extension = match version {
             crate::logic::ContractPrepareVersion::V0 => false,
             crate::logic::ContractPrepareVersion::V1 => false,
             crate::logic::ContractPrepareVersion::V2 => true,
         };
-        WasmFeatures { sign_extension }
+        let v2 = version == crate::logic::ContractPrepareVersion::V2;
+        if let Some(passes) = config.prepare_passes.as_ref().filter(|_| v2) {
+            return Self::for_passes(passes, config);
+        }
+        WasmFeatures {
+            sign_extension,
+            simd: config.simd && v2,
+            bulk_memory_reftypes: config.bulk_memory_reftypes && v2,
+        }
     }
 }
 
@@ -38,11 +72,11 @@ impl From<WasmFeatures> for finite_wasm::wasmparser::WasmFeatures {
             mutable_global: true,
             sign_extension: f.sign_extension,
 
-            reference_types: REFERENCE_TYPES,
+            reference_types: f.bulk_memory_reftypes,
             // wasmer singlepass compiler requires multi_value return values to be disabled.
             multi_value: MULTI_VALUE,
-            bulk_memory: BULK_MEMORY,
-            simd: SIMD,
+            bulk_memory: f.bulk_memory_reftypes,
+            simd: f.simd,
             threads: THREADS,
             tail_call: TAIL_CALL,
             multi_memory: MULTI_MEMORY,
@@ -60,7 +94,7 @@ impl From<WasmFeatures> for finite_wasm::wasmparser::WasmFeatures {
 }
 
 impl From<WasmFeatures> for wasmparser::WasmFeatures {
-    fn from(_: WasmFeatures) -> Self {
+    fn from(f: WasmFeatures) -> Self {
         // /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\
         //
         // There are features that this version of wasmparser enables by default, but pwasm
@@ -78,10 +112,10 @@ impl From<WasmFeatures> for wasmparser::WasmFeatures {
             deterministic_only: false,
 
             module_linking: false, // old version of component model
-            reference_types: REFERENCE_TYPES,
+            reference_types: f.bulk_memory_reftypes,
             multi_value: MULTI_VALUE,
-            bulk_memory: BULK_MEMORY,
-            simd: SIMD,
+            bulk_memory: f.bulk_memory_reftypes,
+            simd: f.simd,
             threads: THREADS,
             tail_call: TAIL_CALL,
             multi_memory: MULTI_MEMORY,
@@ -99,9 +133,9 @@ impl From<WasmFeatures> for unc_vm_types::Features {
             sign_extension: f.sign_extension,
 
             threads: THREADS,
-            reference_types: REFERENCE_TYPES,
-            simd: SIMD,
-            bulk_memory: BULK_MEMORY,
+            reference_types: f.bulk_memory_reftypes,
+            simd: f.simd,
+            bulk_memory: f.bulk_memory_reftypes,
             multi_value: MULTI_VALUE,
             tail_call: TAIL_CALL,
             multi_memory: MULTI_MEMORY,
@@ -114,7 +148,7 @@ impl From<WasmFeatures> for unc_vm_types::Features {
 
 #[cfg(all(feature = "wasmer2_vm", target_arch = "x86_64"))]
 impl From<WasmFeatures> for wasmer_types::Features {
-    fn from(_: crate::features::WasmFeatures) -> Self {
+    fn from(f: crate::features::WasmFeatures) -> Self {
         // /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\ /!\
         //
         // There are features that this version of wasmparser enables by default, but pwasm
@@ -131,9 +165,9 @@ impl From<WasmFeatures> for wasmer_types::Features {
         Self {
             module_linking: false, // old version of component model
             threads: THREADS,
-            reference_types: REFERENCE_TYPES,
-            simd: SIMD,
-            bulk_memory: BULK_MEMORY,
+            reference_types: f.bulk_memory_reftypes,
+            simd: f.simd,
+            bulk_memory: f.bulk_memory_reftypes,
             multi_value: MULTI_VALUE,
             tail_call: TAIL_CALL,
             multi_memory: MULTI_MEMORY,
@@ -145,12 +179,12 @@ impl From<WasmFeatures> for wasmer_types::Features {
 
 #[cfg(feature = "wasmtime_vm")]
 impl From<WasmFeatures> for wasmtime::Config {
-    fn from(_: WasmFeatures) -> Self {
+    fn from(f: WasmFeatures) -> Self {
         let mut config = wasmtime::Config::default();
         config.wasm_threads(THREADS);
-        config.wasm_reference_types(REFERENCE_TYPES);
-        config.wasm_simd(SIMD);
-        config.wasm_bulk_memory(BULK_MEMORY);
+        config.wasm_reference_types(f.bulk_memory_reftypes);
+        config.wasm_simd(f.simd);
+        config.wasm_bulk_memory(f.bulk_memory_reftypes);
         config.wasm_multi_value(MULTI_VALUE);
         config.wasm_multi_memory(MULTI_MEMORY);
         config.wasm_memory64(MEMORY64);
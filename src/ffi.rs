@@ -0,0 +1,803 @@
+//! C bindings of the runner, for indexers and node implementations which are
+//! not written in Rust.
+//!
+//! The functions below make a stable C ABI, declared in `ffi/unc_vm.h`, with
+//! an example consumer in `ffi/example.c`, which is not built by the tests.
+//! Calls run either against a state given as JSON, with [`unc_vm_run`], or
+//! against the state, validators and receipts of the node, which it provides
+//! through the callbacks of an [`UncVmExternal`], with
+//! [`unc_vm_run_external`].  The context of a call and its
+//! outcome cross it as JSON in the serde formats of [`VMContext`] and
+//! [`VMOutcome`], which only ever gain fields, so that the ABI does not
+//! change when they do.  Build the crate as a shared library with
+//!
+//! ```console
+//! $ cargo rustc --release --features ffi --crate-type cdylib
+//! ```
+//!
+//! # Ownership
+//!
+//! Every pointer returned by a function is owned by the caller and freed
+//! with the matching `_free` function, exactly once: a cache with
+//! [`unc_vm_cache_free`], an outcome with [`unc_vm_outcome_free`] and a string
+//! with [`unc_vm_string_free`].  The accessors of an outcome return pointers
+//! into it, which are valid until the outcome is freed and must not be freed
+//! themselves.  The arguments are borrowed for the duration of the call only.
+//!
+//! A cache can be used by several threads at the same time, an outcome by
+//! one thread at a time.  Panics do not unwind into the caller: the call
+//! fails with the message of the panic instead.
+//!
+//! [`VMOutcome`]: crate::logic::VMOutcome
+
+mod external;
+
+pub use external::UncVmExternal;
+
+use crate::logic::errors::VMRunnerError;
+use external::{CallbackExternal, CallbackFailed};
+use crate::logic::mocks::mock_external::MockedExternal;
+use crate::logic::types::{PromiseResult, ReturnData};
+use crate::logic::{CompiledContractCache, VMContext, VMOutcome};
+use crate::runner::VMKindExt;
+use crate::{ContractCode, FilesystemContractRuntimeCache, MockCompiledContractCache};
+use serde_with::base64::Base64;
+use serde_with::serde_as;
+use std::ffi::{c_char, CStr, CString};
+use std::panic::AssertUnwindSafe;
+use std::sync::{Arc, OnceLock};
+use crate::logic::Config;
+use unc_parameters::vm::VMKind;
+use unc_parameters::{RuntimeConfigStore, RuntimeFeesConfig};
+use unc_primitives_core::types::ProtocolVersion;
+use unc_primitives_core::version::PROTOCOL_VERSION;
+
+/// Version of the ABI, bumped when a function changes in a breaking way.
+pub const UNC_VM_FFI_VERSION: u32 = 1;
+
+/// The config of a call, by default the one of the current protocol version.
+#[derive(Default, serde::Deserialize)]
+struct ConfigRequest {
+    protocol_version: Option<ProtocolVersion>,
+    vm_kind: Option<VMKind>,
+}
+
+impl ConfigRequest {
+    /// The config, or why its VM cannot run.
+    fn config(&self) -> Result<(Config, RuntimeFeesConfig), String> {
+        static STORE: OnceLock<RuntimeConfigStore> = OnceLock::new();
+        let store = STORE.get_or_init(|| RuntimeConfigStore::new(None));
+        let runtime_config = store.get_config(self.protocol_version.unwrap_or(PROTOCOL_VERSION));
+        let mut config = Config::from(runtime_config.wasm_config.clone());
+        if let Some(vm_kind) = self.vm_kind {
+            config.vm_kind = vm_kind;
+        }
+        config.vm_kind.runtime(config.clone()).map_err(|err| err.to_string())?;
+        Ok((config, runtime_config.fees.clone()))
+    }
+}
+
+/// The JSON request of [`unc_vm_run`].
+#[derive(serde::Deserialize)]
+struct RunRequest {
+    method_name: String,
+    context: VMContext,
+    #[serde(default)]
+    promise_results: Vec<PromiseResult>,
+    /// The state of the account before the call.
+    #[serde(default)]
+    state: Vec<StateEntry>,
+    #[serde(flatten)]
+    config: ConfigRequest,
+}
+
+#[serde_as]
+#[derive(serde::Serialize, serde::Deserialize)]
+struct StateEntry {
+    #[serde_as(as = "Base64")]
+    key: Vec<u8>,
+    #[serde_as(as = "Base64")]
+    value: Vec<u8>,
+}
+
+/// The JSON of an [`UncVmOutcome`].
+#[derive(serde::Serialize)]
+#[serde(untagged)]
+enum RunResponse<'a> {
+    /// The state of the account after the call, sorted by key, unless the
+    /// node keeps it.
+    Ran {
+        outcome: &'a VMOutcome,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        state: Option<Vec<StateEntry>>,
+    },
+    Failed {
+        error: &'a str,
+    },
+}
+
+/// A compiled contract cache shared by calls.
+pub struct UncVmCache(Arc<dyn CompiledContractCache>);
+
+/// The result of [`unc_vm_run`], read with the `unc_vm_outcome_` accessors.
+pub struct UncVmOutcome {
+    json: CString,
+    /// Why the call did not run, if it did not.
+    error: Option<CString>,
+    /// The code of the error the call aborted with, see
+    /// [`crate::logic::error_codes`].
+    aborted: Option<CString>,
+    return_value: Option<Vec<u8>>,
+    burnt_gas: u64,
+    used_gas: u64,
+}
+
+impl UncVmOutcome {
+    fn ran(outcome: &VMOutcome, state: Option<Vec<StateEntry>>) -> Self {
+        let json = serde_json::to_string(&RunResponse::Ran { outcome, state })
+            .expect("outcomes serialize to JSON");
+        Self {
+            json: CString::new(json).expect("JSON has no NUL bytes"),
+            error: None,
+            aborted: outcome.aborted.as_ref().map(|err| c_string(err.code().code)),
+            return_value: match &outcome.return_data {
+                ReturnData::Value(value) => Some(value.clone()),
+                _ => None,
+            },
+            burnt_gas: outcome.burnt_gas,
+            used_gas: outcome.used_gas,
+        }
+    }
+
+    fn failed(error: &str) -> Self {
+        let json = serde_json::to_string(&RunResponse::Failed { error })
+            .expect("strings serialize to JSON");
+        Self {
+            json: CString::new(json).expect("JSON has no NUL bytes"),
+            error: Some(c_string(error)),
+            aborted: None,
+            return_value: None,
+            burnt_gas: 0,
+            used_gas: 0,
+        }
+    }
+}
+
+/// `s` as a C string, cut at its first NUL byte.
+fn c_string(s: &str) -> CString {
+    let s = s.split('\0').next().unwrap_or_default();
+    CString::new(s).expect("the NUL bytes have been cut")
+}
+
+/// Runs `f`, turning a panic into an error with its message.
+fn catch_panic<T>(f: impl FnOnce() -> Result<T, String>) -> Result<T, String> {
+    std::panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown panic");
+        Err(format!("panicked: {message}"))
+    })
+}
+
+/// # Safety
+///
+/// `ptr` must be NULL or point to a NUL-terminated string.
+unsafe fn read_str<'a>(ptr: *const c_char, what: &str) -> Result<&'a str, String> {
+    if ptr.is_null() {
+        return Err(format!("{what} is NULL"));
+    }
+    // SAFETY: `ptr` points to a NUL-terminated string, as the caller ensures.
+    unsafe { CStr::from_ptr(ptr) }.to_str().map_err(|_| format!("{what} is not UTF-8"))
+}
+
+/// # Safety
+///
+/// `ptr` must be NULL or point to `len` bytes.
+unsafe fn read_code(ptr: *const u8, len: usize) -> Result<ContractCode, String> {
+    if ptr.is_null() {
+        return Err("the code is NULL".to_string());
+    }
+    // SAFETY: `ptr` points to `len` bytes, as the caller ensures.
+    let code = unsafe { std::slice::from_raw_parts(ptr, len) };
+    Ok(ContractCode::new(code.to_vec(), None))
+}
+
+/// # Safety
+///
+/// `cache` must be NULL or a live cache.
+unsafe fn read_cache<'a>(cache: *const UncVmCache) -> Option<&'a dyn CompiledContractCache> {
+    // SAFETY: `cache` is NULL or live, as the caller ensures.
+    unsafe { cache.as_ref() }.map(|cache| &*cache.0)
+}
+
+/// The version of the ABI, [`UNC_VM_FFI_VERSION`].
+#[no_mangle]
+pub extern "C" fn unc_vm_ffi_version() -> u32 {
+    UNC_VM_FFI_VERSION
+}
+
+/// A new empty cache in memory.
+#[no_mangle]
+pub extern "C" fn unc_vm_cache_new_in_memory() -> *mut UncVmCache {
+    Box::into_raw(Box::new(UncVmCache(Arc::new(MockCompiledContractCache::default()))))
+}
+
+/// A new cache storing the compiled contracts in the directory `dir`, or
+/// NULL if the directory cannot be created.
+///
+/// # Safety
+///
+/// `dir` must be NULL or point to a NUL-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn unc_vm_cache_new_filesystem(dir: *const c_char) -> *mut UncVmCache {
+    let cache = catch_panic(|| {
+        // SAFETY: as ensured by the caller.
+        let dir = unsafe { read_str(dir, "the directory") }?;
+        FilesystemContractRuntimeCache::new(dir).map_err(|err| err.to_string())
+    });
+    match cache {
+        Ok(cache) => Box::into_raw(Box::new(UncVmCache(Arc::new(cache)))),
+        Err(err) => {
+            tracing::debug!(target: "vm", %err, "cannot create the contract cache");
+            std::ptr::null_mut()
+        }
+    }
+}
+
+/// Frees `cache`.
+///
+/// # Safety
+///
+/// `cache` must be NULL or a cache which has not been freed, and which no
+/// running call uses.
+#[no_mangle]
+pub unsafe extern "C" fn unc_vm_cache_free(cache: *mut UncVmCache) {
+    if !cache.is_null() {
+        // SAFETY: `cache` was allocated by a `unc_vm_cache_new_` function.
+        drop(unsafe { Box::from_raw(cache) });
+    }
+}
+
+/// Compiles the `code_len` bytes of `code` into `cache` with the config of
+/// `config_json`, an object with the optional `protocol_version` and
+/// `vm_kind` of [`unc_vm_run`], or NULL for the defaults.
+///
+/// Returns NULL on success, and the reason of the failure otherwise, to be
+/// freed with [`unc_vm_string_free`].
+///
+/// # Safety
+///
+/// `code` must point to `code_len` bytes, `config_json` must be NULL or point
+/// to a NUL-terminated string and `cache` must be a live cache.
+#[no_mangle]
+pub unsafe extern "C" fn unc_vm_precompile(
+    code: *const u8,
+    code_len: usize,
+    config_json: *const c_char,
+    cache: *const UncVmCache,
+) -> *mut c_char {
+    let result = catch_panic(|| {
+        // SAFETY: as ensured by the caller.
+        let code = unsafe { read_code(code, code_len) }?;
+        // SAFETY: as ensured by the caller.
+        let cache = unsafe { read_cache(cache) }.ok_or("the cache is NULL")?;
+        let request = match config_json.is_null() {
+            true => ConfigRequest::default(),
+            // SAFETY: as ensured by the caller.
+            false => serde_json::from_str(unsafe { read_str(config_json, "the config") }?)
+                .map_err(|err| format!("invalid config: {err}"))?,
+        };
+        let (config, _) = request.config()?;
+        match crate::precompile_contract(&code, &config, Some(cache)) {
+            Ok(Ok(_)) => Ok(()),
+            Ok(Err(err)) => Err(err.to_string()),
+            Err(err) => Err(err.to_string()),
+        }
+    });
+    match result {
+        Ok(()) => std::ptr::null_mut(),
+        Err(err) => c_string(&err).into_raw(),
+    }
+}
+
+/// Runs a method of the contract made of the `code_len` bytes of `code`.
+///
+/// `request_json` is an object with
+///
+/// - `method_name`, the method to run;
+/// - `context`, the [`VMContext`] of the call;
+/// - `promise_results`, optionally, the results of the promises the call
+///   depends on;
+/// - `state`, optionally, the state of the account before the call, as a
+///   list of `{"key": …, "value": …}` objects in Base64;
+/// - `protocol_version` and `vm_kind`, optionally, the protocol version of
+///   the config and the VM, by default the current ones.
+///
+/// The outcome is never NULL: a call which cannot run has the reason in
+/// [`unc_vm_outcome_error`].  `cache` may be NULL to compile the contract for
+/// this call only.
+///
+/// # Safety
+///
+/// `code` must point to `code_len` bytes, `request_json` must point to a
+/// NUL-terminated string and `cache` must be NULL or a live cache.
+#[no_mangle]
+pub unsafe extern "C" fn unc_vm_run(
+    code: *const u8,
+    code_len: usize,
+    request_json: *const c_char,
+    cache: *const UncVmCache,
+) -> *mut UncVmOutcome {
+    let result = catch_panic(|| {
+        // SAFETY: as ensured by the caller.
+        let code = unsafe { read_code(code, code_len) }?;
+        // SAFETY: as ensured by the caller.
+        let request = unsafe { read_str(request_json, "the request") }?;
+        let request: RunRequest =
+            serde_json::from_str(request).map_err(|err| format!("invalid request: {err}"))?;
+        let (config, fees) = request.config.config()?;
+        let mut ext = MockedExternal::new();
+        ext.fake_trie = request.state.into_iter().map(|entry| (entry.key, entry.value)).collect();
+        let result = crate::run(
+            &code,
+            &request.method_name,
+            &mut ext,
+            request.context,
+            &config,
+            &fees,
+            &request.promise_results,
+            // SAFETY: as ensured by the caller.
+            unsafe { read_cache(cache) },
+        );
+        let mut state: Vec<StateEntry> =
+            ext.fake_trie.into_iter().map(|(key, value)| StateEntry { key, value }).collect();
+        state.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        result
+            .map(|outcome| UncVmOutcome::ran(&outcome, Some(state)))
+            .map_err(|err: VMRunnerError| err.to_string())
+    });
+    let outcome = result.unwrap_or_else(|err| UncVmOutcome::failed(&err));
+    Box::into_raw(Box::new(outcome))
+}
+
+/// Same as [`unc_vm_run`], against the state, validators and receipts the
+/// node provides through the functions of `ext`, see [`UncVmExternal`].
+///
+/// The request has no `state`, and the JSON of the outcome has none either.
+/// A call whose `ext` failed has the status it failed with in
+/// [`unc_vm_outcome_error`], as `the external failed with status …`, even
+/// if the function which failed could not fail the call right away: the
+/// node then discards whatever the call did through `ext`.
+///
+/// # Safety
+///
+/// As for [`unc_vm_run`], and `ext` must point to a table whose functions
+/// can be called with its `ctx` during the call.
+#[no_mangle]
+pub unsafe extern "C" fn unc_vm_run_external(
+    code: *const u8,
+    code_len: usize,
+    request_json: *const c_char,
+    ext: *const UncVmExternal,
+    cache: *const UncVmCache,
+) -> *mut UncVmOutcome {
+    let result = catch_panic(|| {
+        // SAFETY: as ensured by the caller.
+        let code = unsafe { read_code(code, code_len) }?;
+        // SAFETY: as ensured by the caller.
+        let request = unsafe { read_str(request_json, "the request") }?;
+        let request: RunRequest =
+            serde_json::from_str(request).map_err(|err| format!("invalid request: {err}"))?;
+        if !request.state.is_empty() {
+            return Err("the state of a call comes from the external".to_string());
+        }
+        // SAFETY: `ext` is NULL or valid, as the caller ensures.
+        let table = unsafe { ext.as_ref() }.ok_or("the external is NULL")?;
+        let (config, fees) = request.config.config()?;
+        let mut ext = CallbackExternal::new(table);
+        let result = crate::run(
+            &code,
+            &request.method_name,
+            &mut ext,
+            request.context,
+            &config,
+            &fees,
+            &request.promise_results,
+            // SAFETY: as ensured by the caller.
+            unsafe { read_cache(cache) },
+        );
+        let failed = |CallbackFailed(status)| format!("the external failed with status {status}");
+        if let Some(failure) = ext.failure {
+            return Err(failed(failure));
+        }
+        match result {
+            Ok(outcome) => Ok(UncVmOutcome::ran(&outcome, None)),
+            Err(VMRunnerError::ExternalError(err)) => {
+                Err(err.downcast().map_or_else(|_| "external error".to_string(), failed))
+            }
+            Err(err) => Err(err.to_string()),
+        }
+    });
+    let outcome = result.unwrap_or_else(|err| UncVmOutcome::failed(&err));
+    Box::into_raw(Box::new(outcome))
+}
+
+/// The outcome as JSON, an object with either the [`VMOutcome`] of the call
+/// and the `state` of the account after it, or the `error` of
+/// [`unc_vm_outcome_error`].
+///
+/// # Safety
+///
+/// `outcome` must be a live outcome.
+#[no_mangle]
+pub unsafe extern "C" fn unc_vm_outcome_json(outcome: *const UncVmOutcome) -> *const c_char {
+    // SAFETY: `outcome` is live, as the caller ensures.
+    unsafe { &*outcome }.json.as_ptr()
+}
+
+/// Why the call did not run, e.g. an invalid request or a corrupt cache, or
+/// NULL if it ran.
+///
+/// # Safety
+///
+/// `outcome` must be a live outcome.
+#[no_mangle]
+pub unsafe extern "C" fn unc_vm_outcome_error(outcome: *const UncVmOutcome) -> *const c_char {
+    // SAFETY: `outcome` is live, as the caller ensures.
+    unsafe { &*outcome }.error.as_ref().map_or(std::ptr::null(), |error| error.as_ptr())
+}
+
+/// The stable code of the error the call aborted with, such as
+/// `host.gas_exceeded`, or NULL if it did not abort.
+///
+/// # Safety
+///
+/// `outcome` must be a live outcome.
+#[no_mangle]
+pub unsafe extern "C" fn unc_vm_outcome_aborted(outcome: *const UncVmOutcome) -> *const c_char {
+    // SAFETY: `outcome` is live, as the caller ensures.
+    unsafe { &*outcome }.aborted.as_ref().map_or(std::ptr::null(), |code| code.as_ptr())
+}
+
+/// The value the call returned, with its length in `len`, or NULL if it
+/// returned none.
+///
+/// # Safety
+///
+/// `outcome` must be a live outcome and `len` must point to a `size_t`.
+#[no_mangle]
+pub unsafe extern "C" fn unc_vm_outcome_return_value(
+    outcome: *const UncVmOutcome,
+    len: *mut usize,
+) -> *const u8 {
+    // SAFETY: `outcome` is live and `len` is valid, as the caller ensures.
+    let (outcome, len) = unsafe { (&*outcome, &mut *len) };
+    match &outcome.return_value {
+        Some(value) => {
+            *len = value.len();
+            value.as_ptr()
+        }
+        None => {
+            *len = 0;
+            std::ptr::null()
+        }
+    }
+}
+
+/// The gas burnt by the call, 0 if it did not run.
+///
+/// # Safety
+///
+/// `outcome` must be a live outcome.
+#[no_mangle]
+pub unsafe extern "C" fn unc_vm_outcome_burnt_gas(outcome: *const UncVmOutcome) -> u64 {
+    // SAFETY: `outcome` is live, as the caller ensures.
+    unsafe { &*outcome }.burnt_gas
+}
+
+/// The gas used by the call, 0 if it did not run.
+///
+/// # Safety
+///
+/// `outcome` must be a live outcome.
+#[no_mangle]
+pub unsafe extern "C" fn unc_vm_outcome_used_gas(outcome: *const UncVmOutcome) -> u64 {
+    // SAFETY: `outcome` is live, as the caller ensures.
+    unsafe { &*outcome }.used_gas
+}
+
+/// Frees `outcome` and everything its accessors returned.
+///
+/// # Safety
+///
+/// `outcome` must be NULL or an outcome which has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn unc_vm_outcome_free(outcome: *mut UncVmOutcome) {
+    if !outcome.is_null() {
+        // SAFETY: `outcome` was allocated by `unc_vm_run`.
+        drop(unsafe { Box::from_raw(outcome) });
+    }
+}
+
+/// Frees a string returned by a function of the ABI.
+///
+/// # Safety
+///
+/// `s` must be NULL or a string returned by a function of the ABI which has
+/// not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn unc_vm_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        // SAFETY: `s` was allocated by `CString::into_raw`.
+        drop(unsafe { CString::from_raw(s) });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::create_context;
+
+    /// Writes its input under `"key"` and returns the previous value.
+    const CONTRACT: &str = r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "storage_write"
+    (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (memory 1)
+  (data (i32.const 0) "key")
+  (func (export "main")
+    (call $input (i64.const 0))
+    (if (i32.wrap_i64 (call $storage_write
+          (i64.const 3) (i64.const 0) (i64.const -1) (i64.const 0) (i64.const 1)))
+      (then
+        (call $read_register (i64.const 1) (i64.const 16))
+        (call $value_return (call $register_len (i64.const 1)) (i64.const 16))))))"#;
+
+    fn string(ptr: *const c_char) -> Option<String> {
+        (!ptr.is_null()).then(|| unsafe { CStr::from_ptr(ptr) }.to_str().unwrap().to_string())
+    }
+
+    /// The calls of `ffi/example.c`, from Rust.
+    #[test]
+    fn test_run() {
+        let code = wat::parse_str(CONTRACT).unwrap();
+        let request = serde_json::json!({
+            "method_name": "main",
+            "context": create_context(b"new".to_vec()),
+            "state": [{"key": "a2V5", "value": "b2xk"}],
+            "vm_kind": "Wasmtime",
+        });
+        let request = CString::new(request.to_string()).unwrap();
+        unsafe {
+            let cache = unc_vm_cache_new_in_memory();
+            let err = unc_vm_precompile(code.as_ptr(), code.len(), std::ptr::null(), cache);
+            assert_eq!(string(err), None);
+
+            let outcome = unc_vm_run(code.as_ptr(), code.len(), request.as_ptr(), cache);
+            assert_eq!(string(unc_vm_outcome_error(outcome)), None);
+            assert_eq!(string(unc_vm_outcome_aborted(outcome)), None);
+            let mut len = 0;
+            let value = unc_vm_outcome_return_value(outcome, &mut len);
+            assert_eq!(std::slice::from_raw_parts(value, len), b"old");
+            assert!(unc_vm_outcome_burnt_gas(outcome) > 0);
+            let json: serde_json::Value =
+                serde_json::from_str(&string(unc_vm_outcome_json(outcome)).unwrap()).unwrap();
+            assert_eq!(json["state"], serde_json::json!([{"key": "a2V5", "value": "bmV3"}]));
+            assert_eq!(json["outcome"]["return_data"], serde_json::json!({"Value": "b2xk"}));
+            unc_vm_outcome_free(outcome);
+            unc_vm_cache_free(cache);
+        }
+    }
+
+    #[test]
+    fn test_run_errors() {
+        let code = wat::parse_str(CONTRACT).unwrap();
+        let run = |request: &str| unsafe {
+            let request = CString::new(request).unwrap();
+            let outcome = unc_vm_run(code.as_ptr(), code.len(), request.as_ptr(), std::ptr::null());
+            let result =
+                (string(unc_vm_outcome_error(outcome)), string(unc_vm_outcome_aborted(outcome)));
+            unc_vm_outcome_free(outcome);
+            result
+        };
+        let (error, aborted) = run("{}");
+        assert!(error.unwrap().starts_with("invalid request: "));
+        assert_eq!(aborted, None);
+
+        let request = serde_json::json!({
+            "method_name": "missing",
+            "context": create_context(vec![]),
+            "vm_kind": "Wasmtime",
+        });
+        assert_eq!(run(&request.to_string()), (None, Some("method.not_found".to_string())));
+
+        unsafe {
+            let outcome = unc_vm_run(std::ptr::null(), 0, std::ptr::null(), std::ptr::null_mut());
+            assert_eq!(string(unc_vm_outcome_error(outcome)).unwrap(), "the code is NULL");
+            unc_vm_outcome_free(outcome);
+        }
+    }
+
+    /// The state and the actions of a node, behind an [`UncVmExternal`].
+    #[derive(Default)]
+    struct Node {
+        state: std::collections::HashMap<Vec<u8>, Vec<u8>>,
+        actions: Vec<serde_json::Value>,
+        /// The status `storage_set` fails with.
+        failing: i32,
+        /// The value `storage_get` returned last.
+        value: Vec<u8>,
+    }
+
+    unsafe fn node<'a>(ctx: *mut std::ffi::c_void) -> &'a mut Node {
+        unsafe { &mut *ctx.cast::<Node>() }
+    }
+
+    unsafe extern "C" fn storage_get(
+        ctx: *mut std::ffi::c_void,
+        key: *const u8,
+        key_len: usize,
+        value: *mut *const u8,
+        value_len: *mut usize,
+    ) -> i32 {
+        let node = unsafe { node(ctx) };
+        let key = unsafe { std::slice::from_raw_parts(key, key_len) };
+        match node.state.get(key) {
+            Some(found) => {
+                node.value = found.clone();
+                unsafe { (*value, *value_len) = (node.value.as_ptr(), node.value.len()) };
+            }
+            None => unsafe { *value = std::ptr::null() },
+        }
+        0
+    }
+
+    unsafe extern "C" fn storage_set(
+        ctx: *mut std::ffi::c_void,
+        key: *const u8,
+        key_len: usize,
+        value: *const u8,
+        value_len: usize,
+    ) -> i32 {
+        let node = unsafe { node(ctx) };
+        let key = unsafe { std::slice::from_raw_parts(key, key_len) };
+        let value = unsafe { std::slice::from_raw_parts(value, value_len) };
+        node.state.insert(key.to_vec(), value.to_vec());
+        node.failing
+    }
+
+    unsafe extern "C" fn storage_remove(_: *mut std::ffi::c_void, _: *const u8, _: usize) -> i32 {
+        1
+    }
+
+    unsafe extern "C" fn validator(
+        _: *mut std::ffi::c_void,
+        _: *const c_char,
+        found: *mut bool,
+        _: *mut [u8; 16],
+        _: *mut u64,
+    ) -> i32 {
+        unsafe { *found = false };
+        0
+    }
+
+    unsafe extern "C" fn validator_totals(
+        _: *mut std::ffi::c_void,
+        _: *mut [u8; 16],
+        _: *mut u64,
+    ) -> i32 {
+        0
+    }
+
+    unsafe extern "C" fn generate_data_id(_: *mut std::ffi::c_void, _: *mut [u8; 32]) -> i32 {
+        0
+    }
+
+    unsafe extern "C" fn action(
+        ctx: *mut std::ffi::c_void,
+        action_json: *const c_char,
+        receipt_index: *mut u64,
+    ) -> i32 {
+        let node = unsafe { node(ctx) };
+        let action = unsafe { CStr::from_ptr(action_json) }.to_str().unwrap();
+        unsafe { *receipt_index = node.actions.len() as u64 };
+        node.actions.push(serde_json::from_str(action).unwrap());
+        0
+    }
+
+    /// Sends a token to `bob.near` after the write of `CONTRACT`.
+    const SENDING_CONTRACT: &str = r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "storage_write"
+    (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "promise_batch_create"
+    (func $promise_batch_create (param i64 i64) (result i64)))
+  (import "env" "promise_batch_action_transfer"
+    (func $promise_batch_action_transfer (param i64 i64)))
+  (memory 1)
+  (data (i32.const 0) "key")
+  (data (i32.const 8) "bob.near")
+  (data (i32.const 16) "\01")
+  (func (export "main")
+    (call $input (i64.const 0))
+    (drop (call $storage_write
+      (i64.const 3) (i64.const 0) (i64.const -1) (i64.const 0) (i64.const 1)))
+    (call $promise_batch_action_transfer
+      (call $promise_batch_create (i64.const 8) (i64.const 8)) (i64.const 16))))"#;
+
+    #[test]
+    fn test_run_external() {
+        let code = wat::parse_str(SENDING_CONTRACT).unwrap();
+        let request = serde_json::json!({
+            "method_name": "main",
+            "context": create_context(b"new".to_vec()),
+            "vm_kind": "Wasmtime",
+        });
+        let request = CString::new(request.to_string()).unwrap();
+        let run = |node: &mut Node| unsafe {
+            let ext = UncVmExternal {
+                ctx: (node as *mut Node).cast(),
+                storage_get,
+                storage_set,
+                storage_remove,
+                storage_remove_subtree: storage_remove,
+                validator,
+                validator_totals,
+                generate_data_id,
+                action,
+                account_code_hash: None,
+                scratch_get: None,
+            };
+            let outcome = unc_vm_run_external(
+                code.as_ptr(),
+                code.len(),
+                request.as_ptr(),
+                &ext,
+                std::ptr::null(),
+            );
+            let result =
+                (string(unc_vm_outcome_error(outcome)), string(unc_vm_outcome_aborted(outcome)));
+            let json = string(unc_vm_outcome_json(outcome)).unwrap();
+            unc_vm_outcome_free(outcome);
+            (result, serde_json::from_str::<serde_json::Value>(&json).unwrap())
+        };
+
+        let mut node = Node::default();
+        node.state.insert(b"key".to_vec(), b"old".to_vec());
+        let (result, json) = run(&mut node);
+        assert_eq!(result, (None, None));
+        assert_eq!(json.get("state"), None);
+        assert_eq!(node.state[&b"key".to_vec()], b"new");
+        assert_eq!(node.actions.len(), 2);
+        assert_eq!(node.actions[0]["CreateReceipt"]["receiver_id"], "bob.near");
+        assert_eq!(node.actions[1]["Transfer"]["receipt_index"], 0);
+        assert_eq!(node.actions[1]["Transfer"]["deposit"], 1);
+
+        let mut node = Node { failing: 7, ..Node::default() };
+        let (result, _) = run(&mut node);
+        assert_eq!(result, (Some("the external failed with status 7".to_string()), None));
+        assert!(node.actions.is_empty());
+    }
+
+    /// The header declares every function of the ABI.
+    #[test]
+    fn test_header() {
+        let header = include_str!("ffi/unc_vm.h");
+        let functions = include_str!("ffi.rs")
+            .lines()
+            .filter_map(|line| line.split("extern \"C\" fn ").nth(1))
+            .map(|rest| rest.split('(').next().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(functions.len(), 15);
+        for function in functions {
+            assert!(header.contains(&format!(" {function}(")), "{function}");
+        }
+    }
+}
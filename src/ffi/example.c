@@ -0,0 +1,85 @@
+/*
+ * Runs a method of a contract through the C bindings, with the input and
+ * the state of `test_run` of the `ffi` module, and prints the outcome.
+ *
+ *   $ cargo rustc --release --features ffi --crate-type cdylib
+ *   $ cc src/ffi/example.c -Isrc/ffi -Ltarget/release -lunc_vm_runner -o example
+ *   $ ./example contract.wasm main
+ */
+
+#include <stdio.h>
+#include <stdlib.h>
+
+#include "unc_vm.h"
+
+static uint8_t *read_file(const char *path, size_t *len) {
+    FILE *file = fopen(path, "rb");
+    if (file == NULL) {
+        return NULL;
+    }
+    fseek(file, 0, SEEK_END);
+    *len = (size_t)ftell(file);
+    fseek(file, 0, SEEK_SET);
+    uint8_t *data = malloc(*len);
+    if (data != NULL && fread(data, 1, *len, file) != *len) {
+        free(data);
+        data = NULL;
+    }
+    fclose(file);
+    return data;
+}
+
+int main(int argc, char **argv) {
+    if (argc != 3) {
+        fprintf(stderr, "usage: %s CONTRACT.wasm METHOD\n", argv[0]);
+        return 2;
+    }
+    if (unc_vm_ffi_version() != UNC_VM_FFI_VERSION) {
+        fprintf(stderr, "the library does not match unc_vm.h\n");
+        return 2;
+    }
+    size_t code_len;
+    uint8_t *code = read_file(argv[1], &code_len);
+    if (code == NULL) {
+        fprintf(stderr, "cannot read %s\n", argv[1]);
+        return 2;
+    }
+
+    UncVmCache *cache = unc_vm_cache_new_in_memory();
+    char *err = unc_vm_precompile(code, code_len, NULL, cache);
+    if (err != NULL) {
+        fprintf(stderr, "cannot compile the contract: %s\n", err);
+        unc_vm_string_free(err);
+    }
+
+    char request[1024];
+    snprintf(request, sizeof request,
+             "{\"method_name\": \"%s\", \"state\": [{\"key\": \"a2V5\", \"value\": \"b2xk\"}],"
+             " \"context\": {\"current_account_id\": \"alice\", \"signer_account_id\": \"bob\","
+             " \"signer_account_pk\": \"AAEC\", \"predecessor_account_id\": \"carol\","
+             " \"input\": \"bmV3\", \"block_height\": 10, \"block_timestamp\": 42,"
+             " \"epoch_height\": 1, \"account_balance\": \"2\", \"account_locked_balance\": \"0\","
+             " \"storage_usage\": 12, \"attached_deposit\": \"2\","
+             " \"prepaid_gas\": 1000000000000000,"
+             " \"random_seed\": \"AAEC\", \"view_config\": null, \"output_data_receivers\": []}}",
+             argv[2]);
+    UncVmOutcome *outcome = unc_vm_run(code, code_len, request, cache);
+    int status = 0;
+    if (unc_vm_outcome_error(outcome) != NULL) {
+        fprintf(stderr, "the call did not run: %s\n", unc_vm_outcome_error(outcome));
+        status = 1;
+    } else {
+        const char *aborted = unc_vm_outcome_aborted(outcome);
+        size_t value_len;
+        const uint8_t *value = unc_vm_outcome_return_value(outcome, &value_len);
+        printf("aborted: %s\n", aborted != NULL ? aborted : "no");
+        printf("returned %zu bytes, burnt %llu gas\n", value != NULL ? value_len : 0,
+               (unsigned long long)unc_vm_outcome_burnt_gas(outcome));
+        printf("%s\n", unc_vm_outcome_json(outcome));
+    }
+
+    unc_vm_outcome_free(outcome);
+    unc_vm_cache_free(cache);
+    free(code);
+    return status;
+}
@@ -0,0 +1,415 @@
+//! The [`External`] of [`super::unc_vm_run_external`], calling back into the
+//! node through a table of C functions.
+
+use crate::logic::errors::{AnyError, VMLogicError};
+use crate::logic::mocks::mock_external::{MockAction, MockedValuePtr};
+use crate::logic::types::ReceiptIndex;
+use crate::logic::{External, StorageGetMode, TrieNodesCount, ValuePtr};
+use std::collections::HashMap;
+use std::ffi::{c_char, c_void, CString};
+use unc_crypto::PublicKey;
+use unc_primitives_core::hash::CryptoHash;
+use unc_primitives_core::types::{AccountId, Balance, Gas, GasWeight, Nonce, Power};
+
+type Result<T, E = VMLogicError> = std::result::Result<T, E>;
+
+/// The state, validators and receipts of a call, provided by the node.
+///
+/// Every function gets `ctx` first and returns 0 on success.  Any other
+/// status fails the call with the status in [`super::unc_vm_outcome_error`].
+/// Values the functions return through pointers stay owned by the node and
+/// need only be valid until the next function of the table is called.  The
+/// optional functions may be NULL.
+#[repr(C)]
+pub struct UncVmExternal {
+    pub ctx: *mut c_void,
+    /// Sets `*value` to the value of `key`, or to NULL if it has none.
+    pub storage_get: unsafe extern "C" fn(
+        ctx: *mut c_void,
+        key: *const u8,
+        key_len: usize,
+        value: *mut *const u8,
+        value_len: *mut usize,
+    ) -> i32,
+    pub storage_set: unsafe extern "C" fn(
+        ctx: *mut c_void,
+        key: *const u8,
+        key_len: usize,
+        value: *const u8,
+        value_len: usize,
+    ) -> i32,
+    pub storage_remove:
+        unsafe extern "C" fn(ctx: *mut c_void, key: *const u8, key_len: usize) -> i32,
+    pub storage_remove_subtree:
+        unsafe extern "C" fn(ctx: *mut c_void, prefix: *const u8, prefix_len: usize) -> i32,
+    /// Sets `*found` to whether `account_id` is a validator, with its frozen
+    /// balance, little-endian, and its power.
+    pub validator: unsafe extern "C" fn(
+        ctx: *mut c_void,
+        account_id: *const c_char,
+        found: *mut bool,
+        frozen: *mut [u8; 16],
+        power: *mut u64,
+    ) -> i32,
+    /// The frozen balance, little-endian, and the power of all validators.
+    pub validator_totals:
+        unsafe extern "C" fn(ctx: *mut c_void, frozen: *mut [u8; 16], power: *mut u64) -> i32,
+    /// A new id for the data of a promise.
+    pub generate_data_id: unsafe extern "C" fn(ctx: *mut c_void, data_id: *mut [u8; 32]) -> i32,
+    /// Records the action of `action_json`, the JSON of a [`MockAction`].
+    /// For a `CreateReceipt` action, sets `*receipt_index` to the index of
+    /// the new receipt, which the other actions of the receipt refer to.
+    pub action: unsafe extern "C" fn(
+        ctx: *mut c_void,
+        action_json: *const c_char,
+        receipt_index: *mut u64,
+    ) -> i32,
+    /// Sets `*found` to whether `account_id` has a contract, with its hash.
+    /// Calls reading the hashes fail without it.
+    pub account_code_hash: Option<
+        unsafe extern "C" fn(
+            ctx: *mut c_void,
+            account_id: *const c_char,
+            found: *mut bool,
+            hash: *mut [u8; 32],
+        ) -> i32,
+    >,
+    /// Sets `*value` to the scratch area of the receipt, or to NULL if it
+    /// has none.  Receipts have none without it.
+    pub scratch_get: Option<
+        unsafe extern "C" fn(ctx: *mut c_void, value: *mut *const u8, value_len: *mut usize) -> i32,
+    >,
+}
+
+/// The status a function of an [`UncVmExternal`] failed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct CallbackFailed(pub i32);
+
+/// An [`External`] calling the functions of an [`UncVmExternal`].
+pub(super) struct CallbackExternal<'a> {
+    table: &'a UncVmExternal,
+    /// Receivers of the receipts created by the call.
+    receivers: HashMap<ReceiptIndex, AccountId>,
+    state_changed: bool,
+    /// The status of the first failure, failing the call once it is over
+    /// even if the function which failed could not fail it right away.
+    pub(super) failure: Option<CallbackFailed>,
+}
+
+impl<'a> CallbackExternal<'a> {
+    pub(super) fn new(table: &'a UncVmExternal) -> Self {
+        Self { table, receivers: HashMap::new(), state_changed: false, failure: None }
+    }
+
+    fn check(&mut self, status: i32) -> Result<()> {
+        if status == 0 {
+            return Ok(());
+        }
+        self.failure.get_or_insert(CallbackFailed(status));
+        Err(VMLogicError::ExternalError(AnyError::new(CallbackFailed(status))))
+    }
+
+    /// Same as [`Self::check`], for `&self` methods, which leave the failure
+    /// to the error they return.
+    fn check_status(status: i32) -> Result<()> {
+        match status {
+            0 => Ok(()),
+            status => Err(VMLogicError::ExternalError(AnyError::new(CallbackFailed(status)))),
+        }
+    }
+
+    fn action(&mut self, action: MockAction) -> Result<ReceiptIndex> {
+        let json = serde_json::to_string(&action).expect("actions serialize to JSON");
+        let json = CString::new(json).expect("JSON has no NUL bytes");
+        let mut receipt_index = 0;
+        // SAFETY: the table is valid for the call, as the caller of
+        // `unc_vm_run_external` ensures.
+        let status =
+            unsafe { (self.table.action)(self.table.ctx, json.as_ptr(), &mut receipt_index) };
+        self.check(status).map(|()| receipt_index)
+    }
+
+    /// Same as [`Self::action`], for the actions which cannot fail the call
+    /// right away.
+    fn deferred_action(&mut self, action: MockAction) {
+        // The failure is kept in `self.failure`.
+        let _ = self.action(action);
+    }
+
+    fn validator(&self, account_id: &AccountId) -> Result<Option<(Balance, Power)>> {
+        let account_id = CString::new(account_id.as_str()).expect("account ids have no NUL bytes");
+        let (mut found, mut frozen, mut power) = (false, [0; 16], 0);
+        // SAFETY: as in `Self::action`.
+        let status = unsafe {
+            (self.table.validator)(
+                self.table.ctx,
+                account_id.as_ptr(),
+                &mut found,
+                &mut frozen,
+                &mut power,
+            )
+        };
+        Self::check_status(status)?;
+        Ok(found.then(|| (Balance::from_le_bytes(frozen), power)))
+    }
+
+    fn validator_totals(&self) -> Result<(Balance, Power)> {
+        let (mut frozen, mut power) = ([0; 16], 0);
+        // SAFETY: as in `Self::action`.
+        let status =
+            unsafe { (self.table.validator_totals)(self.table.ctx, &mut frozen, &mut power) };
+        Self::check_status(status).map(|()| (Balance::from_le_bytes(frozen), power))
+    }
+}
+
+/// Copies the `len` bytes of `ptr`, or none if it is NULL.
+///
+/// # Safety
+///
+/// `ptr` must be NULL or point to `len` bytes.
+unsafe fn read_value(ptr: *const u8, len: usize) -> Option<Vec<u8>> {
+    // SAFETY: `ptr` points to `len` bytes, as the caller ensures.
+    (!ptr.is_null()).then(|| unsafe { std::slice::from_raw_parts(ptr, len) }.to_vec())
+}
+
+impl External for CallbackExternal<'_> {
+    fn storage_set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.state_changed = true;
+        // SAFETY: as in `Self::action`.
+        let status = unsafe {
+            (self.table.storage_set)(
+                self.table.ctx,
+                key.as_ptr(),
+                key.len(),
+                value.as_ptr(),
+                value.len(),
+            )
+        };
+        self.check(status)
+    }
+
+    fn storage_get<'b>(
+        &'b self,
+        key: &[u8],
+        _mode: StorageGetMode,
+    ) -> Result<Option<Box<dyn ValuePtr + 'b>>> {
+        let (mut value, mut value_len) = (std::ptr::null(), 0);
+        // SAFETY: as in `Self::action`.
+        let status = unsafe {
+            (self.table.storage_get)(
+                self.table.ctx,
+                key.as_ptr(),
+                key.len(),
+                &mut value,
+                &mut value_len,
+            )
+        };
+        Self::check_status(status)?;
+        // SAFETY: the node returns a value of `value_len` bytes, or NULL.
+        let value = unsafe { read_value(value, value_len) };
+        Ok(value.map(|value| Box::new(MockedValuePtr::new(value)) as Box<dyn ValuePtr>))
+    }
+
+    fn storage_remove(&mut self, key: &[u8]) -> Result<()> {
+        self.state_changed = true;
+        // SAFETY: as in `Self::action`.
+        let status =
+            unsafe { (self.table.storage_remove)(self.table.ctx, key.as_ptr(), key.len()) };
+        self.check(status)
+    }
+
+    fn storage_remove_subtree(&mut self, prefix: &[u8]) -> Result<()> {
+        self.state_changed = true;
+        // SAFETY: as in `Self::action`.
+        let status = unsafe {
+            (self.table.storage_remove_subtree)(self.table.ctx, prefix.as_ptr(), prefix.len())
+        };
+        self.check(status)
+    }
+
+    fn storage_has_key(&mut self, key: &[u8], mode: StorageGetMode) -> Result<bool> {
+        self.storage_get(key, mode).map(|value| value.is_some())
+    }
+
+    fn state_changed(&self) -> bool {
+        self.state_changed
+    }
+
+    fn generate_data_id(&mut self) -> CryptoHash {
+        let mut data_id = [0; 32];
+        // SAFETY: as in `Self::action`.
+        let status = unsafe { (self.table.generate_data_id)(self.table.ctx, &mut data_id) };
+        // The failure is kept in `self.failure`.
+        let _ = self.check(status);
+        CryptoHash(data_id)
+    }
+
+    /// The node does not report the trie nodes it reads.
+    fn get_trie_nodes_count(&self) -> TrieNodesCount {
+        TrieNodesCount { db_reads: 0, mem_reads: 0 }
+    }
+
+    fn validator_frozen(&self, account_id: &AccountId) -> Result<Option<Balance>> {
+        self.validator(account_id).map(|validator| validator.map(|(frozen, _)| frozen))
+    }
+
+    fn validator_power(&self, account_id: &AccountId) -> Result<Option<Power>> {
+        self.validator(account_id).map(|validator| validator.map(|(_, power)| power))
+    }
+
+    fn validator_total_frozen(&self) -> Result<Balance> {
+        self.validator_totals().map(|(frozen, _)| frozen)
+    }
+
+    fn validator_total_power(&self) -> Result<Power> {
+        self.validator_totals().map(|(_, power)| power)
+    }
+
+    fn account_code_hash(&self, account_id: &AccountId) -> Result<Option<CryptoHash>> {
+        let Some(account_code_hash) = self.table.account_code_hash else {
+            return Err(
+                crate::logic::errors::InconsistentStateError::ExternalMethodNotImplemented {
+                    method: "account_code_hash".to_string(),
+                }
+                .into(),
+            );
+        };
+        let account_id = CString::new(account_id.as_str()).expect("account ids have no NUL bytes");
+        let (mut found, mut hash) = (false, [0; 32]);
+        // SAFETY: as in `Self::action`.
+        let status = unsafe {
+            account_code_hash(self.table.ctx, account_id.as_ptr(), &mut found, &mut hash)
+        };
+        Self::check_status(status).map(|()| found.then_some(CryptoHash(hash)))
+    }
+
+    fn create_receipt(
+        &mut self,
+        receipt_indices: Vec<ReceiptIndex>,
+        receiver_id: AccountId,
+    ) -> Result<ReceiptIndex> {
+        let receiver = receiver_id.clone();
+        let receipt_index =
+            self.action(MockAction::CreateReceipt { receipt_indices, receiver_id })?;
+        self.receivers.insert(receipt_index, receiver);
+        Ok(receipt_index)
+    }
+
+    fn append_action_create_account(&mut self, receipt_index: ReceiptIndex) -> Result<()> {
+        self.action(MockAction::CreateAccount { receipt_index }).map(drop)
+    }
+
+    fn append_action_deploy_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code: Vec<u8>,
+    ) -> Result<()> {
+        self.action(MockAction::DeployContract { receipt_index, code }).map(drop)
+    }
+
+    fn append_action_deploy_global_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code_hash: CryptoHash,
+    ) -> Result<()> {
+        self.action(MockAction::DeployGlobalContract { receipt_index, code_hash }).map(drop)
+    }
+
+    fn append_action_function_call_weight(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        method_name: Vec<u8>,
+        args: Vec<u8>,
+        attached_deposit: Balance,
+        prepaid_gas: Gas,
+        gas_weight: GasWeight,
+    ) -> Result<()> {
+        self.action(MockAction::FunctionCallWeight {
+            receipt_index,
+            method_name,
+            args,
+            attached_deposit,
+            prepaid_gas,
+            gas_weight,
+        })
+        .map(drop)
+    }
+
+    fn append_action_transfer(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        deposit: Balance,
+    ) -> Result<()> {
+        self.action(MockAction::Transfer { receipt_index, deposit }).map(drop)
+    }
+
+    fn append_action_stake(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        stake: Balance,
+        public_key: PublicKey,
+    ) {
+        self.deferred_action(MockAction::Stake { receipt_index, stake, public_key });
+    }
+
+    fn append_action_add_key_with_full_access(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+    ) {
+        self.deferred_action(MockAction::AddKeyWithFullAccess { receipt_index, public_key, nonce });
+    }
+
+    fn append_action_add_key_with_function_call(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+        allowance: Option<Balance>,
+        receiver_id: AccountId,
+        method_names: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        self.action(MockAction::AddKeyWithFunctionCall {
+            receipt_index,
+            public_key,
+            nonce,
+            allowance,
+            receiver_id,
+            method_names,
+        })
+        .map(drop)
+    }
+
+    fn append_action_delete_key(&mut self, receipt_index: ReceiptIndex, public_key: PublicKey) {
+        self.deferred_action(MockAction::DeleteKey { receipt_index, public_key });
+    }
+
+    fn append_action_delete_account(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        beneficiary_id: AccountId,
+    ) -> Result<()> {
+        self.action(MockAction::DeleteAccount { receipt_index, beneficiary_id }).map(drop)
+    }
+
+    fn scratch_get(&self) -> Result<Option<Vec<u8>>> {
+        let Some(scratch_get) = self.table.scratch_get else {
+            return Ok(None);
+        };
+        let (mut value, mut value_len) = (std::ptr::null(), 0);
+        // SAFETY: as in `Self::action`.
+        let status = unsafe { scratch_get(self.table.ctx, &mut value, &mut value_len) };
+        Self::check_status(status)?;
+        // SAFETY: the node returns a value of `value_len` bytes, or NULL.
+        Ok(unsafe { read_value(value, value_len) })
+    }
+
+    fn append_scratch(&mut self, receipt_index: ReceiptIndex, data: Vec<u8>) -> Result<()> {
+        self.action(MockAction::AttachScratch { receipt_index, data }).map(drop)
+    }
+
+    fn get_receipt_receiver(&self, receipt_index: ReceiptIndex) -> &AccountId {
+        self.receivers.get(&receipt_index).expect("not a receipt of the call")
+    }
+}
@@ -0,0 +1,127 @@
+/*
+ * C bindings of unc-vm-runner, built with the `ffi` feature.
+ *
+ * Every pointer returned by a function is owned by the caller and freed
+ * exactly once with the matching `_free` function.  The pointers returned by
+ * the `unc_vm_outcome_` accessors point into the outcome: they stay valid
+ * until the outcome is freed and are not freed themselves.  Arguments are
+ * only borrowed for the duration of the call.
+ *
+ * A cache can be shared by threads, an outcome is used by one thread at a
+ * time.  See the documentation of the `ffi` module for the JSON formats.
+ */
+
+#ifndef UNC_VM_H
+#define UNC_VM_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define UNC_VM_FFI_VERSION 1
+
+typedef struct UncVmCache UncVmCache;
+typedef struct UncVmOutcome UncVmOutcome;
+
+/* The state, validators and receipts of a call, provided by the node to
+ * unc_vm_run_external.  Every function gets `ctx` first and returns 0 on
+ * success; any other status fails the call.  Values returned through
+ * pointers stay owned by the node and need only be valid until the next
+ * function of the table is called.  Balances are 16 bytes, little-endian. */
+typedef struct UncVmExternal {
+    void *ctx;
+    /* Sets `*value` to the value of `key`, or to NULL if it has none. */
+    int32_t (*storage_get)(void *ctx, const uint8_t *key, size_t key_len, const uint8_t **value,
+                           size_t *value_len);
+    int32_t (*storage_set)(void *ctx, const uint8_t *key, size_t key_len, const uint8_t *value,
+                           size_t value_len);
+    int32_t (*storage_remove)(void *ctx, const uint8_t *key, size_t key_len);
+    int32_t (*storage_remove_subtree)(void *ctx, const uint8_t *prefix, size_t prefix_len);
+    /* Sets `*found` to whether `account_id` is a validator, with its frozen
+     * balance and its power. */
+    int32_t (*validator)(void *ctx, const char *account_id, bool *found, uint8_t (*frozen)[16],
+                         uint64_t *power);
+    /* The frozen balance and the power of all validators. */
+    int32_t (*validator_totals)(void *ctx, uint8_t (*frozen)[16], uint64_t *power);
+    /* A new id for the data of a promise. */
+    int32_t (*generate_data_id)(void *ctx, uint8_t (*data_id)[32]);
+    /* Records an action, as JSON such as `{"Transfer": {"receipt_index": 0,
+     * "deposit": 1}}`.  For `CreateReceipt` actions, sets `*receipt_index` to
+     * the index of the new receipt, which its other actions refer to. */
+    int32_t (*action)(void *ctx, const char *action_json, uint64_t *receipt_index);
+    /* Optional: sets `*found` to whether `account_id` has a contract, with
+     * its hash.  Calls reading the hashes fail when NULL. */
+    int32_t (*account_code_hash)(void *ctx, const char *account_id, bool *found,
+                                 uint8_t (*hash)[32]);
+    /* Optional: sets `*value` to the scratch area of the receipt, or to NULL
+     * if it has none.  Receipts have none when NULL. */
+    int32_t (*scratch_get)(void *ctx, const uint8_t **value, size_t *value_len);
+} UncVmExternal;
+
+/* The version of the ABI of the library, UNC_VM_FFI_VERSION when the header
+ * matches it. */
+uint32_t unc_vm_ffi_version(void);
+
+/* A new empty cache in memory. */
+UncVmCache *unc_vm_cache_new_in_memory(void);
+
+/* A new cache in the directory `dir`, or NULL if it cannot be created. */
+UncVmCache *unc_vm_cache_new_filesystem(const char *dir);
+
+/* Frees `cache`, which no running call may use.  NULL is ignored. */
+void unc_vm_cache_free(UncVmCache *cache);
+
+/* Compiles `code` into `cache` with the config of `config_json`, an object
+ * with the optional `protocol_version` and `vm_kind`, or NULL for the
+ * defaults.  Returns NULL on success and the reason of the failure
+ * otherwise, to be freed with unc_vm_string_free. */
+char *unc_vm_precompile(const uint8_t *code, size_t code_len, const char *config_json,
+                        const UncVmCache *cache);
+
+/* Runs the method of the contract `code` described by `request_json`: an
+ * object with `method_name`, `context` and the optional `promise_results`,
+ * `state`, `protocol_version` and `vm_kind`.  `cache` may be NULL.  Never
+ * returns NULL. */
+UncVmOutcome *unc_vm_run(const uint8_t *code, size_t code_len, const char *request_json,
+                         const UncVmCache *cache);
+
+/* Same as unc_vm_run, against the state, validators and receipts of `ext`.
+ * The request has no `state`, nor the JSON of the outcome.  A call whose
+ * `ext` returned a failure has "the external failed with status N" in
+ * unc_vm_outcome_error. */
+UncVmOutcome *unc_vm_run_external(const uint8_t *code, size_t code_len, const char *request_json,
+                                  const UncVmExternal *ext, const UncVmCache *cache);
+
+/* The outcome as JSON: `{"outcome": ..., "state": [...]}` if the call ran,
+ * `{"error": ...}` otherwise. */
+const char *unc_vm_outcome_json(const UncVmOutcome *outcome);
+
+/* Why the call did not run, or NULL if it ran. */
+const char *unc_vm_outcome_error(const UncVmOutcome *outcome);
+
+/* The stable code of the error the call aborted with, such as
+ * "host.gas_exceeded", or NULL if it did not abort. */
+const char *unc_vm_outcome_aborted(const UncVmOutcome *outcome);
+
+/* The value the call returned, with its length in `len`, or NULL. */
+const uint8_t *unc_vm_outcome_return_value(const UncVmOutcome *outcome, size_t *len);
+
+/* The gas burnt and used by the call, 0 if it did not run. */
+uint64_t unc_vm_outcome_burnt_gas(const UncVmOutcome *outcome);
+uint64_t unc_vm_outcome_used_gas(const UncVmOutcome *outcome);
+
+/* Frees `outcome` and what its accessors returned.  NULL is ignored. */
+void unc_vm_outcome_free(UncVmOutcome *outcome);
+
+/* Frees a string returned by the library.  NULL is ignored. */
+void unc_vm_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
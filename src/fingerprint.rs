@@ -0,0 +1,408 @@
+//! Deterministic hashes of a [`Config`] and of a [`RuntimeFeesConfig`].
+//!
+//! `Config::non_crypto_hash` goes through the `Hash` implementations of std,
+//! which may change between compiler versions and platforms, so nodes built
+//! differently may disagree on it for the same config.
+//! [`ConfigFingerprint::fingerprint`] hashes a serialization of the config
+//! which is defined here instead, so the question whether two nodes run the
+//! same VM config is answered by comparing one value.
+//!
+//! The serialization, returned by [`ConfigFingerprint::fingerprint_text`], is
+//! UTF-8 text: the line `unc-vm-runner config v1`, then one `name=value` line
+//! per parameter, each line ending with `\n`.  The parameters come in the
+//! order of the fields of the config of `unc-parameters` and of its
+//! `limit_config`, then of the other fields of [`Config`], named after these
+//! fields, the fields of `limit_config` prefixed with `limit_config.` and
+//! those of `extra_limits` and `extra_ext_costs` with `extra_limits.` and
+//! `extra_ext_costs.`.  The `opcode_blocklist` is its opcodes separated by
+//! `,`, each followed by `@` and the name of every VM it is blocked on unless
+//! it is blocked on all, and `prepare_passes` is `gas:<gas> stack:<stack>
+//! sign_extension:<sign_extension>` when there are some.  The ext costs come
+//! first, in the order of [`ExtCosts`], as `ext_costs.<cost>.gas` and
+//! `ext_costs.<cost>.compute`.  Integers are written in decimal, booleans as
+//! `true` or `false`, enums by the name of their variant and missing optional
+//! values as `none`.  The fingerprint is the sha256 of the text.
+//!
+//! Adding a parameter to the config changes the fingerprints of all the
+//! configs, as it should, since they then describe a different VM.
+//!
+//! The text of a [`RuntimeFeesConfig`] is written the same way, starting
+//! with the line `unc-vm-runner fees v1`: the fees of the actions in the
+//! order of [`ActionCosts`], as `action_fees.<cost>.send_sir`,
+//! `action_fees.<cost>.send_not_sir` and `action_fees.<cost>.execution`,
+//! then the fields of `storage_usage_config` prefixed with
+//! `storage_usage_config.`, and the ratios `burnt_gas_reward` and
+//! `pessimistic_gas_price_inflation_ratio` as `<numerator>/<denominator>`.
+//!
+//! [`ExtCosts`]: unc_parameters::ExtCosts
+//! [`ActionCosts`]: unc_parameters::ActionCosts
+
+use crate::logic::{Config, ExtraExtCostsConfig, ExtraLimitConfig};
+use crate::prepare::{OpcodeBlocklist, PreparePasses};
+use std::fmt::{Display, Write};
+use unc_parameters::vm::LimitConfig;
+use unc_parameters::{Fee, RuntimeFeesConfig, StorageUsageConfig};
+use unc_primitives_core::hash::CryptoHash;
+
+const HEADER: &str = "unc-vm-runner config v1";
+const FEES_HEADER: &str = "unc-vm-runner fees v1";
+
+/// Deterministic hash of a [`Config`] or of a [`RuntimeFeesConfig`], see the
+/// module documentation.
+pub trait ConfigFingerprint {
+    /// The sha256 of [`Self::fingerprint_text`].
+    fn fingerprint(&self) -> CryptoHash;
+
+    /// The serialization of the config hashed by [`Self::fingerprint`].
+    ///
+    /// Diffing the texts of two configs shows the parameters on which they
+    /// differ.
+    fn fingerprint_text(&self) -> String;
+}
+
+impl ConfigFingerprint for Config {
+    fn fingerprint(&self) -> CryptoHash {
+        CryptoHash::hash_bytes(self.fingerprint_text().as_bytes())
+    }
+
+    fn fingerprint_text(&self) -> String {
+        // Destructured so that new parameters fail to compile until they are
+        // added to the serialization.
+        let Config {
+            base,
+            host_imported_memory,
+            log_decoding_cost,
+            simd,
+            bulk_memory_reftypes,
+            nan_canonicalization_pass,
+            buffer_storage_writes,
+            prepare_passes,
+            limit_method_name_length,
+            deterministic_stack_limit,
+            min_refund_gas,
+            opcode_blocklist,
+            extra_limits,
+            extra_ext_costs,
+            experimental_host_fns,
+            consensus_hooks,
+            coverage,
+        } = self;
+        let unc_parameters::vm::Config {
+            ext_costs,
+            grow_mem_cost,
+            regular_op_cost,
+            vm_kind,
+            disable_9393_fix,
+            storage_get_mode,
+            fix_contract_loading_cost,
+            implicit_account_creation,
+            math_extension,
+            ed25519_verify,
+            alt_bn128,
+            function_call_weight,
+            eth_implicit_accounts,
+            limit_config,
+        } = base;
+        let mut text = Text(format!("{HEADER}\n"));
+        for (cost, value) in ext_costs.costs.iter() {
+            text.param(&format!("ext_costs.{cost}.gas"), value.gas);
+            text.param(&format!("ext_costs.{cost}.compute"), value.compute);
+        }
+        text.param("grow_mem_cost", grow_mem_cost);
+        text.param("regular_op_cost", regular_op_cost);
+        text.param("vm_kind", format_args!("{vm_kind:?}"));
+        text.param("disable_9393_fix", disable_9393_fix);
+        text.param("storage_get_mode", format_args!("{storage_get_mode:?}"));
+        text.param("fix_contract_loading_cost", fix_contract_loading_cost);
+        text.param("implicit_account_creation", implicit_account_creation);
+        text.param("math_extension", math_extension);
+        text.param("ed25519_verify", ed25519_verify);
+        text.param("alt_bn128", alt_bn128);
+        text.param("function_call_weight", function_call_weight);
+        text.param("eth_implicit_accounts", eth_implicit_accounts);
+        text.limit_config(limit_config);
+        text.param("host_imported_memory", host_imported_memory);
+        text.param("log_decoding_cost", log_decoding_cost);
+        text.param("simd", simd);
+        text.param("bulk_memory_reftypes", bulk_memory_reftypes);
+        text.param("nan_canonicalization_pass", nan_canonicalization_pass);
+        text.param("buffer_storage_writes", buffer_storage_writes);
+        text.prepare_passes(prepare_passes);
+        text.param("limit_method_name_length", limit_method_name_length);
+        text.param("deterministic_stack_limit", deterministic_stack_limit);
+        text.param("min_refund_gas", min_refund_gas);
+        text.opcode_blocklist(opcode_blocklist);
+        text.extra_limits(extra_limits);
+        text.extra_ext_costs(extra_ext_costs);
+        text.param("experimental_host_fns", experimental_host_fns);
+        text.param("consensus_hooks", consensus_hooks);
+        text.param("coverage", coverage);
+        text.0
+    }
+}
+
+impl ConfigFingerprint for RuntimeFeesConfig {
+    fn fingerprint(&self) -> CryptoHash {
+        CryptoHash::hash_bytes(self.fingerprint_text().as_bytes())
+    }
+
+    fn fingerprint_text(&self) -> String {
+        let RuntimeFeesConfig {
+            action_fees,
+            storage_usage_config,
+            burnt_gas_reward,
+            pessimistic_gas_price_inflation_ratio,
+        } = self;
+        let StorageUsageConfig {
+            storage_amount_per_byte,
+            num_bytes_account,
+            num_extra_bytes_record,
+        } = storage_usage_config;
+        let mut text = Text(format!("{FEES_HEADER}\n"));
+        for (cost, fee) in action_fees.iter() {
+            let Fee { send_sir, send_not_sir, execution } = fee;
+            text.param(&format!("action_fees.{cost}.send_sir"), send_sir);
+            text.param(&format!("action_fees.{cost}.send_not_sir"), send_not_sir);
+            text.param(&format!("action_fees.{cost}.execution"), execution);
+        }
+        text.param("storage_usage_config.storage_amount_per_byte", storage_amount_per_byte);
+        text.param("storage_usage_config.num_bytes_account", num_bytes_account);
+        text.param("storage_usage_config.num_extra_bytes_record", num_extra_bytes_record);
+        let ratio =
+            |ratio: &num_rational::Rational32| format!("{}/{}", ratio.numer(), ratio.denom());
+        text.param("burnt_gas_reward", ratio(burnt_gas_reward));
+        text.param(
+            "pessimistic_gas_price_inflation_ratio",
+            ratio(pessimistic_gas_price_inflation_ratio),
+        );
+        text.0
+    }
+}
+
+struct Text(String);
+
+impl Text {
+    fn param(&mut self, name: &str, value: impl Display) {
+        writeln!(self.0, "{name}={value}").expect("writing to a string cannot fail");
+    }
+
+    fn optional(&mut self, name: &str, value: Option<u64>) {
+        match value {
+            Some(value) => self.param(name, value),
+            None => self.param(name, "none"),
+        }
+    }
+
+    fn prepare_passes(&mut self, passes: &Option<PreparePasses>) {
+        match passes {
+            Some(PreparePasses { gas, stack, sign_extension }) => self.param(
+                "prepare_passes",
+                format!("gas:{gas:?} stack:{stack:?} sign_extension:{sign_extension}"),
+            ),
+            None => self.param("prepare_passes", "none"),
+        }
+    }
+
+    /// The blocked opcodes separated by `,`, each followed by the VMs it is
+    /// blocked on, if not all of them, prefixed with `@`.
+    fn opcode_blocklist(&mut self, blocklist: &OpcodeBlocklist) {
+        let mut value = String::new();
+        for (i, blocked) in blocklist.opcodes().iter().enumerate() {
+            if i > 0 {
+                value.push(',');
+            }
+            value.push_str(&blocked.opcode);
+            for vm_kind in &blocked.vm_kinds {
+                write!(value, "@{vm_kind:?}").expect("writing to a string cannot fail");
+            }
+        }
+        self.param("opcode_blocklist", value);
+    }
+
+    fn extra_limits(&mut self, extra_limits: &ExtraLimitConfig) {
+        let ExtraLimitConfig {
+            max_function_body_size,
+            max_br_table_targets,
+            max_nesting_depth,
+            max_prepare_operations_per_contract_byte,
+            memory_cap,
+        } = extra_limits;
+        self.optional("extra_limits.max_function_body_size", *max_function_body_size);
+        self.optional("extra_limits.max_br_table_targets", max_br_table_targets.map(u64::from));
+        self.optional("extra_limits.max_nesting_depth", max_nesting_depth.map(u64::from));
+        self.optional(
+            "extra_limits.max_prepare_operations_per_contract_byte",
+            *max_prepare_operations_per_contract_byte,
+        );
+        self.optional("extra_limits.memory_cap", *memory_cap);
+    }
+
+    fn extra_ext_costs(&mut self, extra_ext_costs: &ExtraExtCostsConfig) {
+        let ExtraExtCostsConfig {
+            alt_bn128_g2_multiexp_base,
+            alt_bn128_g2_multiexp_element,
+            alt_bn128_pairing_check_batch_base,
+            ed25519_verify_batch_base,
+            ed25519_verify_batch_element,
+            u256_muldiv_base,
+            u256_pow_base,
+            u256_pow_bit,
+            log_decoding_byte,
+            log_emit_byte,
+            bls12381_g1_sum_base,
+            bls12381_g1_sum_element,
+            bls12381_g2_sum_base,
+            bls12381_g2_sum_element,
+            bls12381_pairing_check_base,
+            bls12381_pairing_check_element,
+            bls12381_map_to_curve_base,
+            bls12381_map_to_curve_element,
+        } = extra_ext_costs;
+        let mut param = |name: &str, value: &dyn Display| {
+            self.param(&format!("extra_ext_costs.{name}"), value);
+        };
+        param("alt_bn128_g2_multiexp_base", alt_bn128_g2_multiexp_base);
+        param("alt_bn128_g2_multiexp_element", alt_bn128_g2_multiexp_element);
+        param("alt_bn128_pairing_check_batch_base", alt_bn128_pairing_check_batch_base);
+        param("ed25519_verify_batch_base", ed25519_verify_batch_base);
+        param("ed25519_verify_batch_element", ed25519_verify_batch_element);
+        param("u256_muldiv_base", u256_muldiv_base);
+        param("u256_pow_base", u256_pow_base);
+        param("u256_pow_bit", u256_pow_bit);
+        param("log_decoding_byte", log_decoding_byte);
+        param("log_emit_byte", log_emit_byte);
+        param("bls12381_g1_sum_base", bls12381_g1_sum_base);
+        param("bls12381_g1_sum_element", bls12381_g1_sum_element);
+        param("bls12381_g2_sum_base", bls12381_g2_sum_base);
+        param("bls12381_g2_sum_element", bls12381_g2_sum_element);
+        param("bls12381_pairing_check_base", bls12381_pairing_check_base);
+        param("bls12381_pairing_check_element", bls12381_pairing_check_element);
+        param("bls12381_map_to_curve_base", bls12381_map_to_curve_base);
+        param("bls12381_map_to_curve_element", bls12381_map_to_curve_element);
+    }
+
+    fn limit_config(&mut self, limit_config: &LimitConfig) {
+        let LimitConfig {
+            max_gas_burnt,
+            max_stack_height,
+            contract_prepare_version,
+            initial_memory_pages,
+            max_memory_pages,
+            registers_memory_limit,
+            max_register_size,
+            max_number_registers,
+            max_number_logs,
+            max_total_log_length,
+            max_total_prepaid_gas,
+            max_actions_per_receipt,
+            max_number_bytes_method_names,
+            max_length_method_name,
+            max_arguments_length,
+            max_length_returned_data,
+            max_contract_size,
+            max_transaction_size,
+            max_length_storage_key,
+            max_length_storage_value,
+            max_promises_per_function_call_action,
+            max_number_input_data_dependencies,
+            max_functions_number_per_contract,
+            wasmer2_stack_limit,
+            max_locals_per_contract,
+            account_id_validity_rules_version,
+        } = limit_config;
+        let mut param = |name: &str, value: &dyn Display| {
+            self.param(&format!("limit_config.{name}"), value);
+        };
+        param("max_gas_burnt", max_gas_burnt);
+        param("max_stack_height", max_stack_height);
+        param("contract_prepare_version", &format_args!("{contract_prepare_version:?}"));
+        param("initial_memory_pages", initial_memory_pages);
+        param("max_memory_pages", max_memory_pages);
+        param("registers_memory_limit", registers_memory_limit);
+        param("max_register_size", max_register_size);
+        param("max_number_registers", max_number_registers);
+        param("max_number_logs", max_number_logs);
+        param("max_total_log_length", max_total_log_length);
+        param("max_total_prepaid_gas", max_total_prepaid_gas);
+        param("max_actions_per_receipt", max_actions_per_receipt);
+        param("max_number_bytes_method_names", max_number_bytes_method_names);
+        param("max_length_method_name", max_length_method_name);
+        param("max_arguments_length", max_arguments_length);
+        param("max_length_returned_data", max_length_returned_data);
+        param("max_contract_size", max_contract_size);
+        param("max_transaction_size", max_transaction_size);
+        param("max_length_storage_key", max_length_storage_key);
+        param("max_length_storage_value", max_length_storage_value);
+        param("max_promises_per_function_call_action", max_promises_per_function_call_action);
+        param("max_number_input_data_dependencies", max_number_input_data_dependencies);
+        self.optional(
+            "limit_config.max_functions_number_per_contract",
+            *max_functions_number_per_contract,
+        );
+        self.param("limit_config.wasmer2_stack_limit", wasmer2_stack_limit);
+        self.optional("limit_config.max_locals_per_contract", *max_locals_per_contract);
+        self.param(
+            "limit_config.account_id_validity_rules_version",
+            format_args!("{account_id_validity_rules_version:?}"),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prepare::BlockedOpcode;
+    use crate::tests::test_vm_config;
+    use unc_parameters::vm::VMKind;
+
+    #[test]
+    fn test_fingerprint() {
+        let config = test_vm_config();
+        let text = config.fingerprint_text();
+        assert!(text.starts_with("unc-vm-runner config v1\next_costs.base.gas="), "{text}");
+        assert!(text.contains("\nlimit_config.max_locals_per_contract="), "{text}");
+        assert!(text.ends_with('\n'));
+        assert_eq!(config.fingerprint(), CryptoHash::hash_bytes(text.as_bytes()));
+        assert_eq!(config.fingerprint(), config.clone().fingerprint());
+
+        let mut other = config.clone();
+        other.vm_kind = VMKind::Wasmtime;
+        assert_ne!(other.fingerprint(), config.fingerprint());
+
+        let mut other = config.clone();
+        other.limit_config.max_locals_per_contract = None;
+        let text = other.fingerprint_text();
+        assert!(text.contains("\nlimit_config.max_locals_per_contract=none\n"), "{text}");
+        other.regular_op_cost += 1;
+        assert_ne!(other.fingerprint(), config.fingerprint());
+
+        let mut other = config.clone();
+        assert!(other.fingerprint_text().contains("\nopcode_blocklist=\n"));
+        other.opcode_blocklist = OpcodeBlocklist::new(vec![
+            BlockedOpcode { opcode: "i64.div_s".to_string(), vm_kinds: vec![] },
+            BlockedOpcode {
+                opcode: "f64.nearest".to_string(),
+                vm_kinds: vec![VMKind::NearVm, VMKind::Wasmtime],
+            },
+        ])
+        .unwrap();
+        let text = other.fingerprint_text();
+        assert!(text.contains("\nopcode_blocklist=i64.div_s,f64.nearest@NearVm@Wasmtime\n"));
+        assert_ne!(other.fingerprint(), config.fingerprint());
+    }
+
+    #[test]
+    fn test_fees_fingerprint() {
+        let fees = RuntimeFeesConfig::test();
+        let text = fees.fingerprint_text();
+        assert!(text.starts_with("unc-vm-runner fees v1\naction_fees.create_account.send_sir="));
+        assert!(text.ends_with("\npessimistic_gas_price_inflation_ratio=103/100\n"), "{text}");
+        assert!(text.contains("\nburnt_gas_reward=3/10\n"), "{text}");
+        assert_eq!(fees.fingerprint(), CryptoHash::hash_bytes(text.as_bytes()));
+
+        let mut other = fees.clone();
+        other.storage_usage_config.num_bytes_account += 1;
+        assert_ne!(other.fingerprint(), fees.fingerprint());
+        assert_ne!(RuntimeFeesConfig::free().fingerprint(), fees.fingerprint());
+    }
+}
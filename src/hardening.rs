@@ -0,0 +1,370 @@
+//! Hardening of the memory of the VMs, for operators trading some speed for
+//! defenses against exploits of the compiled contracts.
+//!
+//! The VMs already map the code they compile writable while they write it and
+//! executable once written, and put guard regions after the linear memories
+//! of calls.  The `hardening` of the [`crate::HostSettings`] goes further:
+//!
+//! * [`MemoryHardening::write_xor_execute`] checks, after the VM loads a
+//!   contract, that none of the mappings holding its code is writable and
+//!   executable at once, and fails the call with
+//!   [`VMRunnerError::LoadingError`] otherwise.  The mappings are read from
+//!   `/proc/self/maps` on each load; those of the rest of the process are not
+//!   checked.
+//! * [`MemoryHardening::guard_size`] makes the guard regions after the linear
+//!   memories at least this long, so that accesses far past a memory trap
+//!   instead of reaching the mappings after it.  Larger guards only reserve
+//!   more address space.
+//! * [`MemoryHardening::code_protection`] protects the code memories further.
+//!   With [`CodeProtection::ProtectionKeys`], the code memories of NearVM get
+//!   a memory protection key leaving them writable only by a thread loading a
+//!   contract, even while they are free or being filled.
+//!
+//! The rights of protection keys are per thread.  The key is allocated read
+//! only for the thread allocating it, the threads it then spawns inherit its
+//! rights and the kernel gives the others no access at all.  Each NearVM
+//! call makes the code memories read only for its thread, whichever thread
+//! runs it, and a load only makes them writable for its thread until the
+//! load ends.
+//!
+//! What each VM supports on the host is reported by
+//! [`hardening_capabilities`], and what the host offers by
+//! [`host_capabilities`]; [`VMKindExt::runtime_on_host`](crate::VMKindExt::runtime_on_host)
+//! rejects hardening its VM does not support.  `MAP_JIT` code memories
+//! are reported for macOS, but none of the VMs map their code memories
+//! themselves with it yet.
+
+use crate::logic::errors::VMRunnerError;
+use std::ops::Range;
+use unc_parameters::vm::VMKind;
+
+/// Largest guard region of the VMs which map their memories with the one of
+/// [`MemoryHardening::guard_size`].
+const MAX_GUARD_SIZE: u64 = 4 << 30;
+
+/// Guard region of the memories of Wasmer0 and Wasmtime, which they always
+/// reserve on 64-bit hosts.
+const FIXED_GUARD_SIZE: u64 = if cfg!(target_pointer_width = "64") { 2 << 30 } else { 0 };
+
+/// How the code memories of a VM are protected, besides being executable only
+/// once written.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub enum CodeProtection {
+    #[default]
+    None,
+    /// Code memories mapped with `MAP_JIT`, which macOS needs to toggle their
+    /// protection per thread.
+    MapJit,
+    /// Code memories tagged with a memory protection key which threads only
+    /// unlock for writing while they load a contract.
+    ///
+    /// Needs Linux on an x86_64 CPU with protection keys.
+    ProtectionKeys,
+}
+
+/// Hardening options of a VM, see the module documentation.
+#[derive(Clone, Copy, Debug, Default, Hash, PartialEq, Eq)]
+pub struct MemoryHardening {
+    pub write_xor_execute: bool,
+    /// Minimum bytes of guard region after the maximum size of a linear
+    /// memory, rounded up to wasm pages; 0 keeps the default of the VM.
+    pub guard_size: u64,
+    pub code_protection: CodeProtection,
+}
+
+/// What the host offers to harden the memory of the VMs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HostCapabilities {
+    /// The mappings of the process can be listed.
+    pub process_mappings: bool,
+    /// Code memories can be mapped with `MAP_JIT`.
+    pub map_jit: bool,
+    /// The CPU has memory protection keys and the OS enabled them.
+    pub protection_keys: bool,
+}
+
+/// What a VM supports of [`MemoryHardening`] on the host.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HardeningCapabilities {
+    pub write_xor_execute: bool,
+    /// Largest [`MemoryHardening::guard_size`] the VM gives.
+    pub max_guard_size: u64,
+    pub map_jit: bool,
+    pub protection_keys: bool,
+}
+
+impl HardeningCapabilities {
+    pub fn supports(&self, protection: CodeProtection) -> bool {
+        match protection {
+            CodeProtection::None => true,
+            CodeProtection::MapJit => self.map_jit,
+            CodeProtection::ProtectionKeys => self.protection_keys,
+        }
+    }
+}
+
+/// Hardening a VM does not support on the host, see
+/// [`hardening_capabilities`].
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum HardeningError {
+    #[error("the mappings of the process cannot be checked on this host")]
+    WriteXorExecute,
+    #[error("{vm_kind:?} gives guard regions of at most {max} bytes, not {requested}")]
+    GuardSize { vm_kind: VMKind, requested: u64, max: u64 },
+    #[error("{vm_kind:?} does not support {protection:?} code protection on this host")]
+    CodeProtection { vm_kind: VMKind, protection: CodeProtection },
+}
+
+/// Reports what the host offers, see [`HostCapabilities`].
+pub fn host_capabilities() -> HostCapabilities {
+    HostCapabilities {
+        process_mappings: cfg!(target_os = "linux") && std::fs::metadata("/proc/self/maps").is_ok(),
+        map_jit: cfg!(target_os = "macos"),
+        protection_keys: host_protection_keys(),
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+fn host_protection_keys() -> bool {
+    if !cfg!(target_os = "linux") {
+        return false;
+    }
+    let features = std::arch::x86_64::__cpuid_count(7, 0);
+    // OSPKE, set once the OS enabled the protection keys of the CPU.
+    features.ecx & (1 << 4) != 0
+}
+
+#[cfg(not(target_arch = "x86_64"))]
+fn host_protection_keys() -> bool {
+    false
+}
+
+/// Reports what `vm_kind` supports on the host, see
+/// [`HardeningCapabilities`].
+pub fn hardening_capabilities(vm_kind: VMKind) -> HardeningCapabilities {
+    let host = host_capabilities();
+    let max_guard_size = match vm_kind {
+        VMKind::NearVm | VMKind::Wasmer2 => MAX_GUARD_SIZE,
+        VMKind::Wasmer0 | VMKind::Wasmtime => FIXED_GUARD_SIZE,
+    };
+    HardeningCapabilities {
+        // Wasmer0 does not tell where it put the code of a contract.
+        write_xor_execute: vm_kind != VMKind::Wasmer0 && host.process_mappings,
+        max_guard_size,
+        map_jit: false,
+        protection_keys: vm_kind == VMKind::NearVm
+            && cfg!(feature = "unc_vm")
+            && host.protection_keys,
+    }
+}
+
+/// Fails if `vm_kind` does not support `hardening` on the host.
+pub(crate) fn check_memory_hardening(
+    vm_kind: VMKind,
+    hardening: &MemoryHardening,
+) -> Result<(), HardeningError> {
+    let capabilities = hardening_capabilities(vm_kind);
+    if hardening.write_xor_execute && !capabilities.write_xor_execute {
+        return Err(HardeningError::WriteXorExecute);
+    }
+    if hardening.guard_size > capabilities.max_guard_size {
+        return Err(HardeningError::GuardSize {
+            vm_kind,
+            requested: hardening.guard_size,
+            max: capabilities.max_guard_size,
+        });
+    }
+    let protection = hardening.code_protection;
+    if !capabilities.supports(protection) {
+        return Err(HardeningError::CodeProtection { vm_kind, protection });
+    }
+    Ok(())
+}
+
+/// Bytes of guard region after the memories of a VM whose own guard is
+/// `default` bytes.
+#[cfg_attr(not(any(feature = "unc_vm", feature = "wasmer2_vm")), allow(dead_code))]
+pub(crate) fn guard_size(hardening: &MemoryHardening, default: u64) -> u64 {
+    const WASM_PAGE_SIZE: u64 = 65536;
+    default.max(hardening.guard_size.div_ceil(WASM_PAGE_SIZE) * WASM_PAGE_SIZE)
+}
+
+/// Checks the mappings holding the `code` a VM loaded, if `hardening` asks
+/// to.
+pub(crate) fn check_loaded(
+    hardening: &MemoryHardening,
+    code: impl IntoIterator<Item = Range<usize>>,
+) -> Result<(), VMRunnerError> {
+    if !hardening.write_xor_execute {
+        return Ok(());
+    }
+    let code: Vec<_> = code.into_iter().filter(|range| !range.is_empty()).collect();
+    if code.is_empty() {
+        return Ok(());
+    }
+    let maps = std::fs::read_to_string("/proc/self/maps")
+        .map_err(|err| VMRunnerError::LoadingError(format!("cannot read the mappings: {err}")))?;
+    match writable_executable(&maps, &code) {
+        Some(mapping) => Err(VMRunnerError::LoadingError(format!(
+            "mapping {mapping} is writable and executable"
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// The first mapping of `maps`, in the format of `/proc/self/maps`, which
+/// overlaps one of the `code` ranges and is writable and executable.
+fn writable_executable<'a>(maps: &'a str, code: &[Range<usize>]) -> Option<&'a str> {
+    maps.lines().map(str::trim_end).find(|line| {
+        let mut fields = line.split_whitespace();
+        let range =
+            fields.next().and_then(|range| range.split_once('-')).and_then(|(start, end)| {
+                let start = usize::from_str_radix(start, 16).ok()?;
+                Some(start..usize::from_str_radix(end, 16).ok()?)
+            });
+        let Some(range) = range else { return false };
+        let perms = fields.next().unwrap_or_default().as_bytes();
+        perms.get(1) == Some(&b'w')
+            && perms.get(2) == Some(&b'x')
+            && code.iter().any(|code| code.start < range.end && range.start < code.end)
+    })
+}
+
+/// The protection key of the code memories of NearVM.
+#[cfg(all(feature = "unc_vm", target_arch = "x86_64"))]
+pub(crate) mod protection_keys {
+    use std::sync::OnceLock;
+
+    /// Rights of a key denying writes, which `libc` does not define.
+    #[cfg(target_os = "linux")]
+    const PKEY_DISABLE_WRITE: libc::c_long = 0x2;
+
+    /// The key of the code memories, once [`allocate_code_key`] ran.
+    static CODE_KEY: OnceLock<Option<u32>> = OnceLock::new();
+
+    /// Allocates the key to tag the code memories with, read only for the
+    /// current thread, or returns the key already allocated.
+    pub(crate) fn allocate_code_key() -> Option<u32> {
+        *CODE_KEY.get_or_init(|| {
+            #[cfg(target_os = "linux")]
+            // SAFETY: allocating a key has no effect on the memory.
+            let key = unsafe { libc::syscall(libc::SYS_pkey_alloc, 0, PKEY_DISABLE_WRITE) };
+            #[cfg(not(target_os = "linux"))]
+            let key = -1;
+            u32::try_from(key).ok()
+        })
+    }
+
+    /// The key of the code memories, if they were tagged with one.
+    fn code_key() -> Option<u32> {
+        CODE_KEY.get().copied().flatten()
+    }
+
+    /// Tags the `len` readable and writable bytes at `ptr` with `key`.
+    ///
+    /// Safety: the bytes must be a mapping of code memory which nothing
+    /// references.
+    #[cfg(target_os = "linux")]
+    pub(crate) unsafe fn tag(ptr: *mut u8, len: usize, key: u32) -> bool {
+        let prot = libc::PROT_READ | libc::PROT_WRITE;
+        unsafe { libc::syscall(libc::SYS_pkey_mprotect, ptr, len, prot, key) == 0 }
+    }
+
+    /// Lets the current thread read the memories of `key`, and write them
+    /// only if `writable`.
+    fn set_rights(key: u32, writable: bool) {
+        let mut pkru: u32;
+        // SAFETY: the OS enabled the protection keys, or no key could have
+        // been allocated, and RDPKRU needs ECX zeroed.
+        unsafe { std::arch::asm!("rdpkru", in("ecx") 0, out("eax") pkru, out("edx") _) };
+        // Bit 2 * key disables accesses, the next one writes.
+        pkru &= !(0b11 << (2 * key));
+        if !writable {
+            pkru |= 0b10 << (2 * key);
+        }
+        // SAFETY: only the rights of the code memories change, and WRPKRU
+        // needs ECX and EDX zeroed.
+        unsafe { std::arch::asm!("wrpkru", in("eax") pkru, in("ecx") 0, in("edx") 0) };
+    }
+
+    /// Makes the code memories read only for the current thread, which then
+    /// runs contracts.
+    pub(crate) fn forbid_code_writes() {
+        if let Some(key) = code_key() {
+            set_rights(key, false);
+        }
+    }
+
+    /// Lets the current thread write the code memories until the guard is
+    /// dropped, to load a contract.
+    pub(crate) fn allow_code_writes() -> CodeWrites {
+        let key = code_key();
+        if let Some(key) = key {
+            set_rights(key, true);
+        }
+        CodeWrites(key)
+    }
+
+    pub(crate) struct CodeWrites(Option<u32>);
+
+    impl Drop for CodeWrites {
+        fn drop(&mut self) {
+            if let Some(key) = self.0 {
+                set_rights(key, false);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_writable_executable() {
+        let maps = "\
+55d0c0a00000-55d0c0a21000 r-xp 00000000 08:01 42 /usr/bin/node
+7f3c2a000000-7f3c2a021000 rw-p 00000000 00:00 0
+7f3c2b000000-7f3c2b001000 rwxp 00000000 00:00 0
+";
+        let code = [0x7f3c2b000800..0x7f3c2b000900];
+        assert_eq!(
+            writable_executable(maps, &code),
+            Some("7f3c2b000000-7f3c2b001000 rwxp 00000000 00:00 0")
+        );
+        // Mappings not holding the code are not checked.
+        assert_eq!(writable_executable(maps, &[0x55d0c0a00000..0x55d0c0a00100]), None);
+        assert_eq!(writable_executable(maps, &[0x7f3c2b001000..0x7f3c2b002000]), None);
+        assert_eq!(writable_executable(maps.lines().next().unwrap(), &code), None);
+        assert_eq!(writable_executable("", &code), None);
+    }
+
+    #[test]
+    fn test_check_memory_hardening() {
+        let capabilities = hardening_capabilities(VMKind::Wasmtime);
+        assert!(!capabilities.protection_keys);
+        assert!(!hardening_capabilities(VMKind::Wasmer0).write_xor_execute);
+        let hardening = MemoryHardening { guard_size: 8 << 30, ..MemoryHardening::default() };
+        assert_eq!(
+            check_memory_hardening(VMKind::Wasmer2, &hardening),
+            Err(HardeningError::GuardSize {
+                vm_kind: VMKind::Wasmer2,
+                requested: 8 << 30,
+                max: MAX_GUARD_SIZE
+            })
+        );
+        let hardening = MemoryHardening {
+            code_protection: CodeProtection::MapJit,
+            ..MemoryHardening::default()
+        };
+        assert!(matches!(
+            check_memory_hardening(VMKind::NearVm, &hardening),
+            Err(HardeningError::CodeProtection { .. })
+        ));
+
+        let hardening = MemoryHardening { guard_size: 100_000, ..MemoryHardening::default() };
+        check_memory_hardening(VMKind::Wasmer2, &hardening).unwrap();
+        assert_eq!(guard_size(&hardening, 65536), 131072);
+        assert_eq!(guard_size(&MemoryHardening::default(), 65536), 65536);
+    }
+}
@@ -0,0 +1,295 @@
+//! Per-function execution heatmap of a contract.
+//!
+//! [`Heatmap`] accumulates how many times each wasm function was entered and
+//! how much gas was burnt while executing it, and exports these numbers so
+//! that contract developers can see which functions dominate the cost of a
+//! call.  With the `coverage` feature, `CoverageMap::to_heatmap`
+//! builds one from the block counts of the calls run with coverage.
+//!
+//! Two report formats are supported: JSON via the `serde::Serialize`
+//! implementation (e.g. with `serde_json::to_string_pretty`) and an SVG
+//! treemap via [`Heatmap::to_svg`] where the area of each function is
+//! proportional to the gas it burnt.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use unc_primitives_core::types::Gas;
+
+/// Execution statistics of a single wasm function.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct FunctionHeat {
+    /// Index of the function in the function index space of the module.
+    pub index: u32,
+    /// Name of the function from the `name` custom section, if present.
+    pub name: Option<String>,
+    /// Number of times the function was entered.
+    pub calls: u64,
+    /// Gas burnt executing the body of the function, excluding its callees.
+    pub gas: Gas,
+}
+
+impl FunctionHeat {
+    /// Returns the name of the function or a `func[<index>]` placeholder.
+    pub fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("func[{}]", self.index),
+        }
+    }
+}
+
+/// Per-function execution counts and gas of a contract.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Heatmap {
+    functions: BTreeMap<u32, FunctionHeat>,
+}
+
+impl serde::Serialize for Heatmap {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeStruct;
+        let mut s = serializer.serialize_struct("Heatmap", 2)?;
+        s.serialize_field("total_gas", &self.total_gas())?;
+        s.serialize_field("functions", &self.hottest())?;
+        s.end()
+    }
+}
+
+impl Heatmap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `calls` entries and `gas` burnt to the function at `index`.
+    pub fn record(&mut self, index: u32, calls: u64, gas: Gas) {
+        let entry = self.functions.entry(index).or_insert_with(|| FunctionHeat {
+            index,
+            name: None,
+            calls: 0,
+            gas: 0,
+        });
+        entry.calls = entry.calls.saturating_add(calls);
+        entry.gas = entry.gas.saturating_add(gas);
+    }
+
+    /// Names the function at `index`, if it is recorded.
+    pub(crate) fn set_name(&mut self, index: u32, name: &str) {
+        if let Some(function) = self.functions.get_mut(&index) {
+            function.name = Some(name.to_string());
+        }
+    }
+
+    /// Fills in the function names from the `name` custom section of `code`.
+    ///
+    /// Malformed name sections are ignored: the names are a debugging aid and
+    /// the report is still useful without them.
+    pub fn resolve_names(&mut self, code: &[u8]) {
+        for payload in wasmparser::Parser::new(0).parse_all(code) {
+            let (data, data_offset) = match payload {
+                Ok(wasmparser::Payload::CustomSection {
+                    name: "name", data, data_offset, ..
+                }) => (data, data_offset),
+                Ok(_) => continue,
+                Err(_) => return,
+            };
+            let _ = self.resolve_names_from_section(data, data_offset);
+        }
+    }
+
+    fn resolve_names_from_section(
+        &mut self,
+        data: &[u8],
+        data_offset: usize,
+    ) -> wasmparser::Result<()> {
+        let mut reader = wasmparser::NameSectionReader::new(data, data_offset)?;
+        while !reader.eof() {
+            if let wasmparser::Name::Function(names) = reader.read()? {
+                let mut map = names.get_map()?;
+                for _ in 0..map.get_count() {
+                    let naming = map.read()?;
+                    self.set_name(naming.index, naming.name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Functions ordered by their index.
+    pub fn functions(&self) -> impl Iterator<Item = &FunctionHeat> {
+        self.functions.values()
+    }
+
+    /// Functions ordered from the one which burnt the most gas.
+    pub fn hottest(&self) -> Vec<&FunctionHeat> {
+        let mut functions: Vec<_> = self.functions.values().collect();
+        functions.sort_by(|a, b| b.gas.cmp(&a.gas).then(a.index.cmp(&b.index)));
+        functions
+    }
+
+    /// Total gas burnt by all functions.
+    pub fn total_gas(&self) -> Gas {
+        self.functions.values().fold(0, |acc, f| acc.saturating_add(f.gas))
+    }
+
+    /// Renders the heatmap as an SVG treemap of the given size.
+    ///
+    /// The area of each rectangle is proportional to the gas burnt by the
+    /// function and its colour goes from yellow to red with its share of the
+    /// total gas.  Hovering over a rectangle shows the function name, number
+    /// of calls and gas.  Functions which burnt no gas are omitted.
+    pub fn to_svg(&self, width: u32, height: u32) -> String {
+        let functions: Vec<_> = self.hottest().into_iter().filter(|f| f.gas > 0).collect();
+        let total = self.total_gas();
+        let mut svg = String::new();
+        let _ = writeln!(
+            svg,
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="{width}" height="{height}" viewBox="0 0 {width} {height}">"#
+        );
+        let area = Rect { x: 0.0, y: 0.0, w: f64::from(width), h: f64::from(height) };
+        layout(&functions, area, &mut |function, rect| {
+            let share = function.gas as f64 / total as f64;
+            let hue = 60.0 * (1.0 - share);
+            let _ = writeln!(
+                svg,
+                r#"<g><title>{name}: {calls} calls, {gas} gas</title><rect x="{x:.2}" y="{y:.2}" width="{w:.2}" height="{h:.2}" fill="hsl({hue:.0},100%,50%)" stroke="black" stroke-width="0.5"/></g>"#,
+                name = escape_xml(&function.display_name()),
+                calls = function.calls,
+                gas = function.gas,
+                x = rect.x,
+                y = rect.y,
+                w = rect.w,
+                h = rect.h,
+            );
+        });
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Rect {
+    x: f64,
+    y: f64,
+    w: f64,
+    h: f64,
+}
+
+/// Slice-and-dice layout: splits the functions (sorted by decreasing gas)
+/// into two groups of roughly equal gas and cuts the longer side of the
+/// rectangle accordingly.
+fn layout(functions: &[&FunctionHeat], rect: Rect, emit: &mut impl FnMut(&FunctionHeat, Rect)) {
+    match functions {
+        [] => {}
+        [function] => emit(function, rect),
+        _ => {
+            let total: f64 = functions.iter().map(|f| f.gas as f64).sum();
+            let mut acc = 0.0;
+            let mut split = 1;
+            for (i, function) in functions.iter().enumerate().take(functions.len() - 1) {
+                acc += function.gas as f64;
+                split = i + 1;
+                if acc * 2.0 >= total {
+                    break;
+                }
+            }
+            let ratio = acc / total;
+            let (first, second) = if rect.w >= rect.h {
+                let w = rect.w * ratio;
+                (Rect { w, ..rect }, Rect { x: rect.x + w, w: rect.w - w, ..rect })
+            } else {
+                let h = rect.h * ratio;
+                (Rect { h, ..rect }, Rect { y: rect.y + h, h: rect.h - h, ..rect })
+            };
+            layout(&functions[..split], first, emit);
+            layout(&functions[split..], second, emit);
+        }
+    }
+}
+
+fn escape_xml(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '&' => out.push_str("&amp;"),
+            '"' => out.push_str("&quot;"),
+            _ => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_accumulates() {
+        let mut heatmap = Heatmap::new();
+        heatmap.record(2, 1, 100);
+        heatmap.record(0, 3, 50);
+        heatmap.record(2, 1, 20);
+        assert_eq!(heatmap.total_gas(), 170);
+        let hottest: Vec<_> = heatmap.hottest().iter().map(|f| (f.index, f.calls, f.gas)).collect();
+        assert_eq!(hottest, [(2, 2, 120), (0, 3, 50)]);
+    }
+
+    #[test]
+    fn test_resolve_names() {
+        let code = wat::parse_str(
+            r#"(module
+                (func $cheap)
+                (func $expensive)
+                (func (export "main")))"#,
+        )
+        .unwrap();
+        let mut heatmap = Heatmap::new();
+        heatmap.record(0, 1, 1);
+        heatmap.record(1, 1, 10);
+        heatmap.record(2, 1, 5);
+        heatmap.resolve_names(&code);
+        let names: Vec<_> = heatmap.hottest().iter().map(|f| f.display_name()).collect();
+        assert_eq!(names, ["expensive", "func[2]", "cheap"]);
+    }
+
+    #[test]
+    fn test_json() {
+        let mut heatmap = Heatmap::new();
+        heatmap.record(1, 2, 30);
+        heatmap.record(0, 1, 10);
+        expect_test::expect![[r#"
+            {
+              "total_gas": 40,
+              "functions": [
+                {
+                  "index": 1,
+                  "name": null,
+                  "calls": 2,
+                  "gas": 30
+                },
+                {
+                  "index": 0,
+                  "name": null,
+                  "calls": 1,
+                  "gas": 10
+                }
+              ]
+            }"#]]
+        .assert_eq(&serde_json::to_string_pretty(&heatmap).unwrap());
+    }
+
+    #[test]
+    fn test_svg() {
+        let mut heatmap = Heatmap::new();
+        heatmap.record(0, 1, 300);
+        heatmap.record(1, 1, 100);
+        heatmap.record(2, 1, 0);
+        expect_test::expect![[r#"
+            <svg xmlns="http://www.w3.org/2000/svg" width="200" height="100" viewBox="0 0 200 100">
+            <g><title>func[0]: 1 calls, 300 gas</title><rect x="0.00" y="0.00" width="150.00" height="100.00" fill="hsl(15,100%,50%)" stroke="black" stroke-width="0.5"/></g>
+            <g><title>func[1]: 1 calls, 100 gas</title><rect x="150.00" y="0.00" width="50.00" height="100.00" fill="hsl(45,100%,50%)" stroke="black" stroke-width="0.5"/></g>
+            </svg>
+        "#]]
+        .assert_eq(&heatmap.to_svg(200, 100));
+    }
+}
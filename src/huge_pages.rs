@@ -0,0 +1,168 @@
+//! Huge pages for the linear memories and the code of NearVM.
+//!
+//! Contracts going through a lot of memory miss the TLB often when their
+//! memory is in pages of 4KiB: a 64MiB memory needs 16384 entries, but only
+//! 32 in pages of 2MiB.  [`HugePageAllocator`] is a [`GuestMemoryAllocator`]
+//! putting the memories of calls in huge pages, which NearVM uses for the
+//! calls with the `huge_pages` host setting, and asks for huge pages for the
+//! compiled code of the contracts then.
+//!
+//! The runner changes the protection of the memory of a call past its initial
+//! size with the granularity of wasm pages, so only the pages which stay
+//! accessible during the whole call can be huge.  The kernel splits the huge
+//! pages of the rest where their protection changes.
+//!
+//! Huge pages cost more to fault in and to zero than small ones, so they pay
+//! off for contracts using a lot of their memory, and slow down the calls
+//! touching a few pages.  The `huge_pages` benchmark compares both on a
+//! contract going through its memory; running it under
+//! `perf stat -e dTLB-load-misses` shows the misses saved.
+
+use crate::{GuestMemoryAllocator, HugePages};
+use std::ptr::NonNull;
+
+/// Size of the huge pages used, the default of x86_64.
+const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// Allocator of the linear memories of NearVM in huge pages.
+///
+/// NearVM uses one for the calls with the `huge_pages` host setting, unless
+/// [`crate::RunOptions::memory_allocator`] is set.
+#[derive(Clone, Copy, Debug)]
+pub struct HugePageAllocator {
+    huge_pages: HugePages,
+}
+
+impl HugePageAllocator {
+    pub fn new(huge_pages: HugePages) -> Self {
+        Self { huge_pages }
+    }
+
+    pub fn huge_pages(&self) -> HugePages {
+        self.huge_pages
+    }
+}
+
+// SAFETY: the regions are fresh anonymous mappings, aligned to huge pages,
+// readable, writable and zeroed.
+unsafe impl GuestMemoryAllocator for HugePageAllocator {
+    /// Without knowing which pages stay accessible, only transparent huge
+    /// pages are asked for, which the kernel splits where needed.
+    fn allocate(&self, len: usize) -> Option<NonNull<u8>> {
+        let accessible = match self.huge_pages {
+            HugePages::Transparent => len,
+            HugePages::Explicit => 0,
+        };
+        self.allocate_accessible(len, accessible)
+    }
+
+    fn allocate_accessible(&self, len: usize, accessible: usize) -> Option<NonNull<u8>> {
+        let map_len = round_up(len, HUGE_PAGE_SIZE)?;
+        let region = map_aligned(map_len)?;
+        // The pages after `accessible` change protection and get split anyway.
+        let huge_len = accessible / HUGE_PAGE_SIZE * HUGE_PAGE_SIZE;
+        // SAFETY: the region is `map_len` bytes long and not used yet.
+        unsafe {
+            let base = region.as_ptr().cast::<libc::c_void>();
+            match self.huge_pages {
+                HugePages::Transparent => {
+                    libc::madvise(base, huge_len, libc::MADV_HUGEPAGE);
+                }
+                HugePages::Explicit => {
+                    if huge_len > 0 && !remap(base, huge_len, libc::MAP_HUGETLB) {
+                        // No reserved pages are left, the kernel may have
+                        // unmapped the part already.
+                        if !remap(base, huge_len, 0) {
+                            libc::munmap(base, map_len);
+                            return None;
+                        }
+                    }
+                }
+            }
+        }
+        Some(region)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, len: usize) {
+        let map_len = round_up(len, HUGE_PAGE_SIZE).expect("allocated regions fit in memory");
+        // SAFETY: the region was mapped by `allocate` with this length.
+        unsafe { libc::munmap(ptr.as_ptr().cast(), map_len) };
+    }
+}
+
+/// Advises the kernel to back the `len` bytes at `ptr` with transparent huge
+/// pages.
+///
+/// Safety: `ptr` must be the start of a mapping of at least `len` bytes.
+pub(crate) unsafe fn advise_huge_pages(ptr: *mut u8, len: usize) {
+    unsafe { libc::madvise(ptr.cast(), len, libc::MADV_HUGEPAGE) };
+}
+
+fn round_up(len: usize, multiple: usize) -> Option<usize> {
+    Some(len.checked_add(multiple - 1)? / multiple * multiple)
+}
+
+/// Maps `len` zeroed bytes aligned to a huge page, by mapping a huge page
+/// more and unmapping what sticks out.
+fn map_aligned(len: usize) -> Option<NonNull<u8>> {
+    let reserve_len = len.checked_add(HUGE_PAGE_SIZE)?;
+    // SAFETY: a new anonymous mapping does not alias anything.
+    unsafe {
+        let reserve = libc::mmap(
+            std::ptr::null_mut(),
+            reserve_len,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+            -1,
+            0,
+        );
+        if reserve == libc::MAP_FAILED {
+            return None;
+        }
+        let start = reserve as usize;
+        let aligned = round_up(start, HUGE_PAGE_SIZE)?;
+        let head = aligned - start;
+        let tail = reserve_len - head - len;
+        if head > 0 {
+            libc::munmap(reserve, head);
+        }
+        if tail > 0 {
+            libc::munmap((aligned + len) as *mut libc::c_void, tail);
+        }
+        NonNull::new(aligned as *mut u8)
+    }
+}
+
+/// Maps new zeroed pages over the `len` bytes at `base`, aligned to huge
+/// pages, with the `MAP_*` flags `huge`.
+///
+/// Safety: the bytes must be an unused part of a mapping of this module.
+unsafe fn remap(base: *mut libc::c_void, len: usize, huge: libc::c_int) -> bool {
+    let flags = libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_FIXED | huge;
+    let prot = libc::PROT_READ | libc::PROT_WRITE;
+    unsafe { libc::mmap(base, len, prot, flags, -1, 0) != libc::MAP_FAILED }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_huge_page_allocator() {
+        for huge_pages in [HugePages::Transparent, HugePages::Explicit] {
+            let allocator = HugePageAllocator::new(huge_pages);
+            let len = 3 * HUGE_PAGE_SIZE + 65536;
+            let region = allocator.allocate_accessible(len, 2 * HUGE_PAGE_SIZE + 65536).unwrap();
+            assert_eq!(region.as_ptr() as usize % HUGE_PAGE_SIZE, 0, "{huge_pages:?}");
+            // SAFETY: the region is `len` bytes long and owned by the test.
+            let bytes = unsafe { std::slice::from_raw_parts_mut(region.as_ptr(), len) };
+            assert!(bytes.iter().all(|&b| b == 0), "{huge_pages:?}");
+            bytes[0] = 1;
+            bytes[len - 1] = 1;
+            unsafe { allocator.deallocate(region, len) };
+            let region = allocator.allocate(len).unwrap();
+            assert_eq!(region.as_ptr() as usize % HUGE_PAGE_SIZE, 0, "{huge_pages:?}");
+            unsafe { allocator.deallocate(region, len) };
+        }
+    }
+}
@@ -7,260 +7,119 @@
 //! support. The actual definitions of host functions live in the `vm-logic`
 //! crate.
 //!
-//! Basically, what the following code does is (in pseudo-code):
+//! The host functions and their signatures are listed once, in
+//! [`crate::logic::host_functions`], which generates the
+//! `for_each_available_import!` macro used here: each supported WASM runtime
+//! (see submodules of this module) calls it with its own import definition
+//! logic, and it calls that logic for every host function available with the
+//! config of the call.
 //!
-//! ```ignore
-//! for host_fn in all_host_functions {
-//!    wasm_imports.define("env", host_fn.name, |args| host_fn(args))
-//! }
-//! ```
+//! The backends then link the host functions of the embedder, see
+//! [`crate::logic::custom_host_functions`].  Their signatures are only known
+//! at run time, so they go through [`VMLogic::call_custom_host_function`]
+//! with their arguments in a slice.
 //!
-//! The actual implementation is a bit more complicated, for two reasons. First,
-//! host functions have different signatures, so there isn't a trivial single
-//! type one can use to hold a host function. Second, we want to use direct
-//! calls in the compiled WASM, so we need to avoid dynamic dispatch and hand
-//! functions as ZSTs to the WASM runtimes. This basically means that we need to
-//! code the above for-loop as a macro.
+//! The experimental host functions are linked from their own module, as
+//! `env` and `internal`, only with `experimental_host_fns` in the config.
 //!
-//! So, the `imports!` macro invocation is the main "public" API -- it just list
-//! all host functions with their signatures. `imports! { foo, bar, baz }`
-//! expands to roughly
+//! The calls with coverage counters also get `coverage_hit`, in its own
+//! module, see [`crate::coverage_map`].
 //!
-//! ```ignore
-//! macro_rules! for_each_available_import {
-//!    $($M:ident) => {
-//!        $M!(foo);
-//!        $M!(bar);
-//!        $M!(baz);
-//!    }
-//! }
-//! ```
-//!
-//! That is, `for_each_available_import` is a high-order macro which takes macro
-//! `M` as a parameter, and calls `M!` with each import. Each supported WASM
-//! runtime (see submodules of this module) then calls
-//! `for_each_available_import` with its own import definition logic.
-//!
-//! The real `for_each_available_import` takes one more argument --
-//! `VMConfig`. We can add new imports, but we must make sure that they
-//! are only available to contracts at a specific protocol version -- we can't
-//! make imports retroactively available to old transactions. So
-//! `for_each_available_import` takes care to invoke `M!` only for currently
-//! available imports.
-
-macro_rules! call_with_name {
-    ( $M:ident => @in $mod:ident : $func:ident < [ $( $arg_name:ident : $arg_type:ident ),* ] -> [ $( $returns:ident ),* ] > ) => {
-        $M!($mod / $func : $func < [ $( $arg_name : $arg_type ),* ] -> [ $( $returns ),* ] >)
-    };
-    ( $M:ident => @as $name:ident : $func:ident < [ $( $arg_name:ident : $arg_type:ident ),* ] -> [ $( $returns:ident ),* ] > ) => {
-        $M!(env / $name : $func < [ $( $arg_name : $arg_type ),* ] -> [ $( $returns ),* ] >)
+//! [`VMLogic::call_custom_host_function`]: crate::logic::VMLogic::call_custom_host_function
+
+/// Import module of the experimental host functions, see
+/// [`crate::logic::host_functions`].
+pub(crate) const EXPERIMENTAL_MODULE: &str = "env_experimental";
+
+/// Defines the trampolines of the custom host functions in the module of a
+/// backend linking host functions by address, `$vm` being its VM crate.
+///
+/// Each custom function gets a `CustomImport` as its host environment,
+/// which tells the trampoline the function of the registry to call.  There
+/// is one trampoline per signature, taking the arguments in registers.
+#[cfg(all(any(feature = "wasmer2_vm", feature = "unc_vm"), target_arch = "x86_64"))]
+macro_rules! custom_imports {
+    ($vm:ident) => {
+        custom_imports!(@define $vm;
+            0 => [],
+            1 => [a0],
+            2 => [a0, a1],
+            3 => [a0, a1, a2],
+            4 => [a0, a1, a2, a3],
+            5 => [a0, a1, a2, a3, a4],
+            6 => [a0, a1, a2, a3, a4, a5],
+            7 => [a0, a1, a2, a3, a4, a5, a6],
+            8 => [a0, a1, a2, a3, a4, a5, a6, a7]
+        );
     };
-    ( $M:ident => $func:ident < [ $( $arg_name:ident : $arg_type:ident ),* ] -> [ $( $returns:ident ),* ] > ) => {
-        $M!(env / $func : $func < [ $( $arg_name : $arg_type ),* ] -> [ $( $returns ),* ] >)
-    };
-}
+    (@define $vm:ident; $( $params:literal => [ $( $arg:ident ),* ] ),*) => {
+        pub(crate) struct CustomImport {
+            logic: *mut std::ffi::c_void,
+            index: usize,
+        }
 
-macro_rules! imports {
-    (
-      $($(#[$config_field:ident])? $(##[$feature_name:literal])?
-        $( @in $mod:ident : )?
-        $( @as $name:ident : )?
-        $func:ident < [ $( $arg_name:ident : $arg_type:ident ),* ] -> [ $( $returns:ident ),* ] >,)*
-    ) => {
-        macro_rules! for_each_available_import {
-            ($config:expr, $M:ident) => {$(
-                $(#[cfg(feature = $feature_name)])?
-                if true $(&& ($config).$config_field)? {
-                    call_with_name!($M => $( @in $mod : )? $( @as $name : )? $func < [ $( $arg_name : $arg_type ),* ] -> [ $( $returns ),* ] >);
+        fn custom_imports(logic: &mut VMLogic<'_>) -> Vec<CustomImport> {
+            let count =
+                logic.custom_host_functions().map_or(0, |registry| registry.functions().count());
+            let logic = logic as *mut _ as *mut std::ffi::c_void;
+            (0..count).map(|index| CustomImport { logic, index }).collect()
+        }
+
+        fn call_custom(import: *const CustomImport, args: &[u64]) -> Option<u64> {
+            let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                let _span = tracing::trace_span!(target: "host-function", "custom").entered();
+                // SAFETY: the `CustomImport`s live in the imports, which borrow the `VMLogic`
+                // for as long as the instance can call its functions.
+                unsafe {
+                    let import = &*import;
+                    let logic = &mut *(import.logic as *mut VMLogic<'_>);
+                    logic.call_custom_host_function(import.index, args)
                 }
-            )*}
+            }));
+            match result {
+                Ok(Ok(value)) => value,
+                Ok(Err(trap)) => unsafe {
+                    // SAFETY: this can only be called by a WASM contract, so all the
+                    // necessary hooks are known to be in place.
+                    $vm::raise_user_trap(Box::new(trap))
+                },
+                Err(e) => unsafe {
+                    // SAFETY: this can only be called by a WASM contract, so all the
+                    // necessary hooks are known to be in place.
+                    $vm::resume_panic(e)
+                },
+            }
         }
-    }
-}
 
-imports! {
-    // #########################
-    // # Finite-wasm internals #
-    // #########################
-    @in internal: finite_wasm_gas<[gas: u64] -> []>,
-    @in internal: finite_wasm_stack<[operand_size: u64, frame_size: u64] -> []>,
-    @in internal: finite_wasm_unstack<[operand_size: u64, frame_size: u64] -> []>,
-    // #############
-    // # Registers #
-    // #############
-    read_register<[register_id: u64, ptr: u64] -> []>,
-    register_len<[register_id: u64] -> [u64]>,
-    write_register<[register_id: u64, data_len: u64, data_ptr: u64] -> []>,
-    // ###############
-    // # Context API #
-    // ###############
-    current_account_id<[register_id: u64] -> []>,
-    signer_account_id<[register_id: u64] -> []>,
-    signer_account_pk<[register_id: u64] -> []>,
-    predecessor_account_id<[register_id: u64] -> []>,
-    input<[register_id: u64] -> []>,
-    block_index<[] -> [u64]>,
-    block_timestamp<[] -> [u64]>,
-    epoch_height<[] -> [u64]>,
-    storage_usage<[] -> [u64]>,
-    // #################
-    // # Economics API #
-    // #################
-    account_balance<[balance_ptr: u64] -> []>,
-    account_locked_balance<[balance_ptr: u64] -> []>,
-    attached_deposit<[balance_ptr: u64] -> []>,
-    prepaid_gas<[] -> [u64]>,
-    used_gas<[] -> [u64]>,
-    // ############
-    // # Math API #
-    // ############
-    random_seed<[register_id: u64] -> []>,
-    sha256<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
-    keccak256<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
-    keccak512<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
-    #[ed25519_verify] ed25519_verify<[sig_len: u64,
-        sig_ptr: u64,
-        msg_len: u64,
-        msg_ptr: u64,
-        pub_key_len: u64,
-        pub_key_ptr: u64
-    ] -> [u64]>,
-    #[math_extension] ripemd160<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
-    #[math_extension] ecrecover<[hash_len: u64, hash_ptr: u64, sign_len: u64, sig_ptr: u64, v: u64, malleability_flag: u64, register_id: u64] -> [u64]>,
-    // #####################
-    // # Miscellaneous API #
-    // #####################
-    value_return<[value_len: u64, value_ptr: u64] -> []>,
-    panic<[] -> []>,
-    panic_utf8<[len: u64, ptr: u64] -> []>,
-    log_utf8<[len: u64, ptr: u64] -> []>,
-    log_utf16<[len: u64, ptr: u64] -> []>,
-    abort<[msg_ptr: u32, filename_ptr: u32, line: u32, col: u32] -> []>,
-    // ################
-    // # Promises API #
-    // ################
-    promise_create<[
-        account_id_len: u64,
-        account_id_ptr: u64,
-        method_name_len: u64,
-        method_name_ptr: u64,
-        arguments_len: u64,
-        arguments_ptr: u64,
-        amount_ptr: u64,
-        gas: u64
-    ] -> [u64]>,
-    promise_then<[
-        promise_index: u64,
-        account_id_len: u64,
-        account_id_ptr: u64,
-        method_name_len: u64,
-        method_name_ptr: u64,
-        arguments_len: u64,
-        arguments_ptr: u64,
-        amount_ptr: u64,
-        gas: u64
-    ] -> [u64]>,
-    promise_and<[promise_idx_ptr: u64, promise_idx_count: u64] -> [u64]>,
-    promise_batch_create<[account_id_len: u64, account_id_ptr: u64] -> [u64]>,
-    promise_batch_then<[promise_index: u64, account_id_len: u64, account_id_ptr: u64] -> [u64]>,
-    // #######################
-    // # Promise API actions #
-    // #######################
-    promise_batch_action_create_account<[promise_index: u64] -> []>,
-    promise_batch_action_deploy_contract<[promise_index: u64, code_len: u64, code_ptr: u64] -> []>,
-    promise_batch_action_function_call<[
-        promise_index: u64,
-        method_name_len: u64,
-        method_name_ptr: u64,
-        arguments_len: u64,
-        arguments_ptr: u64,
-        amount_ptr: u64,
-        gas: u64
-    ] -> []>,
-    #[function_call_weight] promise_batch_action_function_call_weight<[
-        promise_index: u64,
-        method_name_len: u64,
-        method_name_ptr: u64,
-        arguments_len: u64,
-        arguments_ptr: u64,
-        amount_ptr: u64,
-        gas: u64,
-        gas_weight: u64
-    ] -> []>,
-    promise_batch_action_transfer<[promise_index: u64, amount_ptr: u64] -> []>,
-    promise_batch_action_stake<[
-        promise_index: u64,
-        amount_ptr: u64,
-        public_key_len: u64,
-        public_key_ptr: u64
-    ] -> []>,
-    promise_batch_action_add_key_with_full_access<[
-        promise_index: u64,
-        public_key_len: u64,
-        public_key_ptr: u64,
-        nonce: u64
-    ] -> []>,
-    promise_batch_action_add_key_with_function_call<[
-        promise_index: u64,
-        public_key_len: u64,
-        public_key_ptr: u64,
-        nonce: u64,
-        allowance_ptr: u64,
-        receiver_id_len: u64,
-        receiver_id_ptr: u64,
-        method_names_len: u64,
-        method_names_ptr: u64
-    ] -> []>,
-    promise_batch_action_delete_key<[
-        promise_index: u64,
-        public_key_len: u64,
-        public_key_ptr: u64
-    ] -> []>,
-    promise_batch_action_delete_account<[
-        promise_index: u64,
-        beneficiary_id_len: u64,
-        beneficiary_id_ptr: u64
-    ] -> []>,
-    // #######################
-    // # Promise API results #
-    // #######################
-    promise_results_count<[] -> [u64]>,
-    promise_result<[result_idx: u64, register_id: u64] -> [u64]>,
-    promise_return<[promise_idx: u64] -> []>,
-    // ###############
-    // # Storage API #
-    // ###############
-    storage_write<[key_len: u64, key_ptr: u64, value_len: u64, value_ptr: u64, register_id: u64] -> [u64]>,
-    storage_read<[key_len: u64, key_ptr: u64, register_id: u64] -> [u64]>,
-    storage_remove<[key_len: u64, key_ptr: u64, register_id: u64] -> [u64]>,
-    storage_has_key<[key_len: u64, key_ptr: u64] -> [u64]>,
-    storage_iter_prefix<[prefix_len: u64, prefix_ptr: u64] -> [u64]>,
-    storage_iter_range<[start_len: u64, start_ptr: u64, end_len: u64, end_ptr: u64] -> [u64]>,
-    storage_iter_next<[iterator_id: u64, key_register_id: u64, value_register_id: u64] -> [u64]>,
-    // Function for the injected gas counter. Automatically called by the gas meter.
-    @as gas: gas_seen_from_wasm<[gas_amount: u32] -> []>,
-    // ###############
-    // # Validator API #
-    // ###############
-    validator_frozen<[account_id_len: u64, account_id_ptr: u64, frozen_ptr: u64] -> []>,
-    validator_total_frozen<[frozen_ptr: u64] -> []>,
-    validator_power<[account_id_len: u64, account_id_ptr: u64, power_ptr: u64] -> []>,
-    validator_total_power<[power_ptr: u64] -> []>,
-    // #############
-    // # Alt BN128 #
-    // #############
-    #[alt_bn128] alt_bn128_g1_multiexp<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
-    #[alt_bn128] alt_bn128_g1_sum<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
-    #[alt_bn128] alt_bn128_pairing_check<[value_len: u64, value_ptr: u64] -> [u64]>,
-    // #############
-    // #  Sandbox  #
-    // #############
-    ##["sandbox"] sandbox_debug_log<[len: u64, ptr: u64] -> []>,
+        /// Trampoline of the custom functions taking `params` arguments.
+        fn custom_trampoline(params: usize, returns: bool) -> *const $vm::VMFunctionBody {
+            match (params, returns) {
+                $(
+                    ($params, true) => {
+                        extern "C" fn trampoline(
+                            import: *const CustomImport $( , $arg: u64 )*
+                        ) -> u64 {
+                            call_custom(import, &[$( $arg ),*]).unwrap_or_default()
+                        }
+                        trampoline as *const _
+                    }
+                    ($params, false) => {
+                        extern "C" fn trampoline(import: *const CustomImport $( , $arg: u64 )*) {
+                            call_custom(import, &[$( $arg ),*]);
+                        }
+                        trampoline as *const _
+                    }
+                )*
+                _ => unreachable!("custom host functions take at most 8 parameters"),
+            }
+        }
+    };
 }
 
 #[cfg(all(feature = "wasmer0_vm", target_arch = "x86_64"))]
 pub(crate) mod wasmer {
     use super::str_eq;
+    use crate::logic::host_functions::for_each_available_import;
     use crate::logic::{VMLogic, VMLogicError};
     use std::ffi::c_void;
 
@@ -283,6 +142,10 @@ pub(crate) mod wasmer {
         let mut ns_internal = wasmer_runtime_core::import::Namespace::new();
         let mut ns_env = wasmer_runtime_core::import::Namespace::new();
         ns_env.insert("memory", memory);
+        #[allow(unused_mut)] // there may be no experimental functions at the moment.
+        let mut ns_env_experimental = wasmer_runtime_core::import::Namespace::new();
+        #[cfg(feature = "coverage")]
+        let mut ns_coverage = wasmer_runtime_core::import::Namespace::new();
 
         macro_rules! add_import {
             (
@@ -297,20 +160,39 @@ pub(crate) mod wasmer {
                         Some(tracing::trace_span!(target: "host-function", stringify!($name)).entered())
                     };
                     let logic: &mut VMLogic<'_> = unsafe { &mut *(ctx.data as *mut VMLogic<'_>) };
-                    logic.$func( $( $arg_name, )* )
+                    const IS_HOST_CALL: bool = !IS_GAS && !str_eq(stringify!($mod), "internal");
+                    if IS_HOST_CALL {
+                        logic.enter_host_function(stringify!($name), &[$( $arg_name as u64 ),*]);
+                    }
+                    let mut result = logic.$func( $( $arg_name, )* );
+                    if IS_HOST_CALL {
+                        result = logic.exit_host_function(result);
+                    }
+                    result
                 }
 
                 match stringify!($mod) {
                     "env" => ns_env.insert(stringify!($name), wasmer_runtime::func!($name)),
                     "internal" => ns_internal.insert(stringify!($name), wasmer_runtime::func!($name)),
+                    "env_experimental" => ns_env_experimental.insert(stringify!($name), wasmer_runtime::func!($name)),
+                    #[cfg(feature = "coverage")]
+                    "unc_coverage" => ns_coverage.insert(stringify!($name), wasmer_runtime::func!($name)),
                     _ => unimplemented!(),
                 }
             };
         }
         for_each_available_import!(logic.config, add_import);
+        #[cfg(feature = "coverage")]
+        if logic.has_coverage() {
+            add_import!(unc_coverage / coverage_hit : coverage_hit < [block: u32] -> [] >);
+            import_object.register(crate::coverage::COVERAGE_MODULE, ns_coverage);
+        }
 
         import_object.register("env", ns_env);
         import_object.register("internal", ns_internal);
+        if logic.config.experimental_host_fns {
+            import_object.register(super::EXPERIMENTAL_MODULE, ns_env_experimental);
+        }
         import_object
     }
 }
@@ -320,6 +202,7 @@ pub(crate) mod wasmer2 {
     use std::sync::Arc;
 
     use super::str_eq;
+    use crate::logic::host_functions::for_each_available_import;
     use crate::logic::VMLogic;
     use wasmer_engine::Engine;
     use wasmer_engine_universal::UniversalEngine;
@@ -333,8 +216,13 @@ pub(crate) mod wasmer2 {
         pub(crate) vmlogic: &'vmlogic mut VMLogic<'vmlogic_refs>,
         pub(crate) metadata: Arc<ExportFunctionMetadata>,
         pub(crate) engine: &'engine UniversalEngine,
+        /// Host environments of the custom host functions, by index in the
+        /// registry.  Not modified once built, the functions point into it.
+        custom: Vec<CustomImport>,
     }
 
+    custom_imports!(wasmer_vm);
+
     trait Wasmer2Type {
         type Wasmer;
         fn to_wasmer(self) -> Self::Wasmer;
@@ -402,7 +290,17 @@ pub(crate) mod wasmer2 {
                             // lifetime and so it is safe to dereference the `env` pointer which is
                             // known to be derived from a valid `&'vmlogic mut VMLogic<'_>` in the
                             // first place.
-                            unsafe { (*env).$func( $( $arg_name, )* ) }
+                            unsafe {
+                                const IS_HOST_CALL: bool = !IS_GAS && !str_eq(stringify!($mod), "internal");
+                                if IS_HOST_CALL {
+                                    (*env).enter_host_function(stringify!($name), &[$( $arg_name as u64 ),*]);
+                                }
+                                let mut result = (*env).$func( $( $arg_name, )* );
+                                if IS_HOST_CALL {
+                                    result = (*env).exit_host_function(result);
+                                }
+                                result
+                            }
                         }));
                         // We want to ensure that the only kind of error that host function calls
                         // return are VMLogicError. This is important because we later attempt to
@@ -450,7 +348,31 @@ pub(crate) mod wasmer2 {
                 };
             }
             for_each_available_import!(self.vmlogic.config, add_import);
-            return None;
+            #[cfg(feature = "coverage")]
+            if self.vmlogic.has_coverage() {
+                add_import!(unc_coverage / coverage_hit : coverage_hit < [block: u32] -> [] >);
+            }
+            let registry = self.vmlogic.custom_host_functions()?;
+            let (index, function) = registry.get(module, field)?;
+            let args = vec![wasmer_types::Type::I64; function.params];
+            let rets: &[_] = if function.returns { &[wasmer_types::Type::I64] } else { &[] };
+            let signature = wasmer_types::FunctionTypeRef::new(&args[..], rets);
+            let signature = self.engine.register_signature(signature);
+            return Some(wasmer_vm::Export::Function(ExportFunction {
+                vm_function: VMFunction {
+                    address: custom_trampoline(function.params, function.returns),
+                    // SAFETY: `custom` is not modified while the imports live, see
+                    // `add_import` for the lifetime of the `vmlogic`.
+                    vmctx: wasmer_vm::VMFunctionEnvironment {
+                        host_env: &self.custom[index] as *const _ as *mut _,
+                    },
+                    signature,
+                    kind: VMFunctionKind::Static,
+                    call_trampoline: None,
+                    instance_ref: None,
+                },
+                metadata: Some(Arc::clone(&self.metadata)),
+            }));
         }
     }
 
@@ -465,7 +387,8 @@ pub(crate) mod wasmer2 {
             // contains this metadata.
             ExportFunctionMetadata::new(logic as *mut _ as *mut _, None, |ptr| ptr, |_| {})
         };
-        Wasmer2Imports { memory, vmlogic: logic, metadata: Arc::new(metadata), engine }
+        let custom = custom_imports(logic);
+        Wasmer2Imports { memory, vmlogic: logic, metadata: Arc::new(metadata), engine, custom }
     }
 }
 
@@ -474,6 +397,7 @@ pub(crate) mod unc_vm {
     use std::sync::Arc;
 
     use super::str_eq;
+    use crate::logic::host_functions::for_each_available_import;
     use crate::logic::VMLogic;
     use unc_vm_engine::universal::UniversalEngine;
     use unc_vm_vm::{
@@ -486,8 +410,13 @@ pub(crate) mod unc_vm {
         pub(crate) vmlogic: &'vmlogic mut VMLogic<'vmlogic_refs>,
         pub(crate) metadata: Arc<ExportFunctionMetadata>,
         pub(crate) engine: &'engine UniversalEngine,
+        /// Host environments of the custom host functions, by index in the
+        /// registry.  Not modified once built, the functions point into it.
+        custom: Vec<CustomImport>,
     }
 
+    custom_imports!(unc_vm_vm);
+
     trait NearVmType {
         type NearVm;
         fn to_unc_vm(self) -> Self::NearVm;
@@ -555,7 +484,17 @@ pub(crate) mod unc_vm {
                             // lifetime and so it is safe to dereference the `env` pointer which is
                             // known to be derived from a valid `&'vmlogic mut VMLogic<'_>` in the
                             // first place.
-                            unsafe { (*env).$func( $( $arg_name, )* ) }
+                            unsafe {
+                                const IS_HOST_CALL: bool = !IS_GAS && !str_eq(stringify!($mod), "internal");
+                                if IS_HOST_CALL {
+                                    (*env).enter_host_function(stringify!($name), &[$( $arg_name as u64 ),*]);
+                                }
+                                let mut result = (*env).$func( $( $arg_name, )* );
+                                if IS_HOST_CALL {
+                                    result = (*env).exit_host_function(result);
+                                }
+                                result
+                            }
                         }));
                         // We want to ensure that the only kind of error that host function calls
                         // return are VMLogicError. This is important because we later attempt to
@@ -603,7 +542,58 @@ pub(crate) mod unc_vm {
                 };
             }
             for_each_available_import!(self.vmlogic.config, add_import);
-            return None;
+            #[cfg(feature = "coverage")]
+            if self.vmlogic.has_coverage() {
+                add_import!(unc_coverage / coverage_hit : coverage_hit < [block: u32] -> [] >);
+            }
+            let registry = self.vmlogic.custom_host_functions()?;
+            let (index, function) = registry.get(module, field)?;
+            let args = vec![unc_vm_types::Type::I64; function.params];
+            let rets: &[_] = if function.returns { &[unc_vm_types::Type::I64] } else { &[] };
+            let signature = unc_vm_types::FunctionType::new(&args[..], rets);
+            let signature = self.engine.register_signature(signature);
+            return Some(unc_vm_vm::Export::Function(ExportFunction {
+                vm_function: VMFunction {
+                    address: custom_trampoline(function.params, function.returns),
+                    // SAFETY: `custom` is not modified while the imports live, see
+                    // `add_import` for the lifetime of the `vmlogic`.
+                    vmctx: unc_vm_vm::VMFunctionEnvironment {
+                        host_env: &self.custom[index] as *const _ as *mut _,
+                    },
+                    signature,
+                    kind: VMFunctionKind::Static,
+                    call_trampoline: None,
+                    instance_ref: None,
+                },
+                metadata: Some(Arc::clone(&self.metadata)),
+            }));
+        }
+    }
+
+    /// The environment of a host function of [`NearVmImports`].
+    #[derive(Clone, Copy, Debug)]
+    pub(crate) enum HostEnv {
+        /// The logic, for the host functions of the logic.
+        Logic,
+        /// A custom host function, by index in the registry.
+        Custom(usize),
+    }
+
+    impl NearVmImports<'_, '_, '_> {
+        /// The pointer the host functions with `env` are called with.
+        pub(crate) fn host_env(&self, env: HostEnv) -> *mut std::ffi::c_void {
+            match env {
+                HostEnv::Logic => &*self.vmlogic as *const VMLogic<'_> as *mut _,
+                HostEnv::Custom(index) => &self.custom[index] as *const _ as *mut _,
+            }
+        }
+
+        /// Which environment of these imports `host_env` points to, if any.
+        pub(crate) fn find_host_env(&self, host_env: *mut std::ffi::c_void) -> Option<HostEnv> {
+            if host_env == self.host_env(HostEnv::Logic) {
+                return Some(HostEnv::Logic);
+            }
+            (0..self.custom.len()).map(HostEnv::Custom).find(|&env| host_env == self.host_env(env))
         }
     }
 
@@ -618,13 +608,15 @@ pub(crate) mod unc_vm {
             // contains this metadata.
             ExportFunctionMetadata::new(logic as *mut _ as *mut _, None, |ptr| ptr, |_| {})
         };
-        NearVmImports { memory, vmlogic: logic, metadata: Arc::new(metadata), engine }
+        let custom = custom_imports(logic);
+        NearVmImports { memory, vmlogic: logic, metadata: Arc::new(metadata), engine, custom }
     }
 }
 
 #[cfg(feature = "wasmtime_vm")]
 pub(crate) mod wasmtime {
     use super::str_eq;
+    use crate::logic::host_functions::for_each_available_import;
     use crate::logic::{VMLogic, VMLogicError};
     use std::cell::UnsafeCell;
     use std::ffi::c_void;
@@ -635,6 +627,10 @@ pub(crate) mod wasmtime {
     #[derive(Debug)]
     pub(crate) struct ErrorContainer(std::sync::Mutex<Option<VMLogicError>>);
     impl ErrorContainer {
+        pub(crate) fn new(err: VMLogicError) -> Self {
+            Self(std::sync::Mutex::new(Some(err)))
+        }
+
         pub(crate) fn take(&self) -> Option<VMLogicError> {
             let mut guard = self.0.lock().unwrap_or_else(|e| e.into_inner());
             guard.take()
@@ -690,7 +686,15 @@ pub(crate) mod wasmtime {
                         crate::wasmtime_runner::CALLER.with(|runner_caller| *runner_caller.borrow_mut() = std::mem::transmute(caller));
                     }
                     let logic: &mut VMLogic<'_> = unsafe { &mut *(data as *mut VMLogic<'_>) };
-                    match logic.$func( $( $arg_name as $arg_type, )* ) {
+                    const IS_HOST_CALL: bool = !IS_GAS && !str_eq(stringify!($mod), "internal");
+                    if IS_HOST_CALL {
+                        logic.enter_host_function(stringify!($name), &[$( $arg_name as u64 ),*]);
+                    }
+                    let mut result = logic.$func( $( $arg_name as $arg_type, )* );
+                    if IS_HOST_CALL {
+                        result = logic.exit_host_function(result);
+                    }
+                    match result {
                         Ok(result) => Ok(result as ($( $returns ),* ) ),
                         Err(err) => {
                             Err(ErrorContainer(std::sync::Mutex::new(Some(err))).into())
@@ -702,6 +706,44 @@ pub(crate) mod wasmtime {
             };
         }
         for_each_available_import!(logic.config, add_import);
+        #[cfg(feature = "coverage")]
+        if logic.has_coverage() {
+            add_import!(unc_coverage / coverage_hit : coverage_hit < [block: u32] -> [] >);
+        }
+
+        let Some(registry) = logic.custom_host_functions() else { return };
+        for (index, function) in registry.functions().enumerate() {
+            let params = vec![wasmtime::ValType::I64; function.params];
+            let results = function.returns.then_some(wasmtime::ValType::I64);
+            let ty = wasmtime::FuncType::new(params, results);
+            let call = move |caller: wasmtime::Caller<'_, ()>,
+                             params: &[wasmtime::Val],
+                             results: &mut [wasmtime::Val]| {
+                let _span = tracing::trace_span!(target: "host-function", "custom").entered();
+                // See `add_import`.
+                let data = CALLER_CONTEXT.with(|caller_context| unsafe { *caller_context.get() });
+                unsafe {
+                    crate::wasmtime_runner::CALLER.with(|runner_caller| {
+                        *runner_caller.borrow_mut() = Some(std::mem::transmute::<
+                            wasmtime::Caller<'_, ()>,
+                            wasmtime::Caller<'static, ()>,
+                        >(caller))
+                    });
+                }
+                let logic: &mut VMLogic<'_> = unsafe { &mut *(data as *mut VMLogic<'_>) };
+                let args: Vec<u64> = params.iter().map(|param| param.unwrap_i64() as u64).collect();
+                match logic.call_custom_host_function(index, &args) {
+                    Ok(value) => {
+                        if let (Some(value), Some(result)) = (value, results.first_mut()) {
+                            *result = wasmtime::Val::I64(value as i64);
+                        }
+                        Ok(())
+                    }
+                    Err(err) => Err(ErrorContainer(std::sync::Mutex::new(Some(err))).into()),
+                }
+            };
+            linker.func_new("env", function.name, ty, call).expect("cannot link external");
+        }
     }
 }
 
@@ -14,6 +14,8 @@
 //! something odd! See <https://github.com/utnet-org/utility/issues/6659> for the
 //! overall instrumentation story.
 
+#[cfg(feature = "coverage")]
+pub(crate) mod coverage;
 pub(crate) mod gas;
 pub(crate) mod rules;
 pub(crate) mod stack_height;
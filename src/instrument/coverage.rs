@@ -0,0 +1,122 @@
+//! Instrumentation of wasm code counting the executions of its blocks, see
+//! [`crate::coverage_map`].
+
+use super::gas::{determine_metered_blocks, update_call_index};
+use super::rules;
+use parity_wasm::{builder, elements, elements::ValueType};
+
+/// A block of a function in which either all instructions are executed or
+/// none, as for the gas metering.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct InstrumentedBlock {
+    /// Index of the function in the function index space of the original
+    /// module.
+    pub function_index: u32,
+    /// Index of the first instruction of the block in the body of the
+    /// function.
+    pub instruction: usize,
+    /// Number of instructions of the block.
+    pub instructions: u32,
+}
+
+/// Imports the function `module.name` of type `[i32] -> []` and calls it
+/// with the index of the block in the returned list at the start of each
+/// block.  The first block of each function starts with the function.
+///
+/// The name section is dropped: it would name the wrong functions once the
+/// import shifts their indices.
+pub(crate) fn inject_block_counters(
+    module: elements::Module,
+    module_name: &str,
+    name: &str,
+) -> Result<(elements::Module, Vec<InstrumentedBlock>), ()> {
+    let mut mbuilder = builder::from_module(module);
+    let import_sig =
+        mbuilder.push_signature(builder::signature().with_param(ValueType::I32).build_sig());
+    mbuilder.push_import(
+        builder::import().module(module_name).field(name).external().func(import_sig).build(),
+    );
+    let mut module = mbuilder.build();
+    let hit_func = module.import_count(elements::ImportCountType::Function) as u32 - 1;
+    let shift = |index: &mut u32| {
+        if *index >= hit_func {
+            *index += 1
+        }
+    };
+
+    let mut blocks = Vec::new();
+    let rules = rules::Set::default();
+    module.sections_mut().retain(|section| !matches!(section, elements::Section::Name(_)));
+    for section in module.sections_mut() {
+        match section {
+            elements::Section::Code(code_section) => {
+                for (body_index, func_body) in code_section.bodies_mut().iter_mut().enumerate() {
+                    let instructions = func_body.code_mut();
+                    let function_index = hit_func + body_index as u32;
+                    // The default rules cost one per instruction.
+                    let mut metered: Vec<(usize, u32)> =
+                        determine_metered_blocks(instructions, &rules)?
+                            .iter()
+                            .map(|block| (block.start_pos, block.cost))
+                            .collect();
+                    // Functions without a metered instruction have no block,
+                    // their calls are counted all the same.
+                    if metered.first().map(|(start, _)| *start) != Some(0) {
+                        metered.insert(0, (0, 0));
+                    }
+                    update_call_index(instructions, hit_func);
+                    let first_block = blocks.len();
+                    blocks.extend(metered.iter().map(|&(instruction, instructions)| {
+                        InstrumentedBlock { function_index, instruction, instructions }
+                    }));
+                    let starts: Vec<usize> = metered.iter().map(|(start, _)| *start).collect();
+                    insert_hits(instructions, &starts, first_block, hit_func)?;
+                }
+            }
+            elements::Section::Export(export_section) => {
+                for export in export_section.entries_mut() {
+                    if let elements::Internal::Function(func_index) = export.internal_mut() {
+                        shift(func_index);
+                    }
+                }
+            }
+            elements::Section::Element(elements_section) => {
+                for segment in elements_section.entries_mut() {
+                    segment.members_mut().iter_mut().for_each(shift);
+                }
+            }
+            elements::Section::Start(start_idx) => shift(start_idx),
+            _ => {}
+        }
+    }
+    Ok((module, blocks))
+}
+
+/// Calls `hit_func` with the index of each block, numbered from
+/// `first_block`, before its first instruction.
+fn insert_hits(
+    instructions: &mut elements::Instructions,
+    starts: &[usize],
+    first_block: usize,
+    hit_func: u32,
+) -> Result<(), ()> {
+    use parity_wasm::elements::Instruction::*;
+
+    let capacity = instructions.elements().len() + 2 * starts.len();
+    let original_instrs =
+        std::mem::replace(instructions.elements_mut(), Vec::with_capacity(capacity));
+    let new_instrs = instructions.elements_mut();
+    let mut starts = starts.iter().enumerate().peekable();
+    for (original_pos, instr) in original_instrs.into_iter().enumerate() {
+        if let Some((block, _)) = starts.next_if(|(_, start)| **start == original_pos) {
+            let block = i32::try_from(first_block + block).map_err(drop)?;
+            new_instrs.push(I32Const(block));
+            new_instrs.push(Call(hit_func));
+        }
+        new_instrs.push(instr);
+    }
+    match starts.next() {
+        Some(_) => Err(()),
+        None => Ok(()),
+    }
+}
@@ -67,9 +67,9 @@ struct ControlBlock {
 #[derive(Debug)]
 pub(crate) struct MeteredBlock {
     /// Index of the first instruction (aka `Opcode`) in the block.
-    start_pos: usize,
+    pub(crate) start_pos: usize,
     /// Sum of costs of all instructions until end of the block.
-    cost: u32,
+    pub(crate) cost: u32,
 }
 
 /// Counter is used to manage state during the gas metering algorithm implemented by
@@ -0,0 +1,242 @@
+//! Invariants every outcome of a call satisfies, whatever the contract did.
+//!
+//! The runtime trusts the gas and balances of a [`VMOutcome`] to update the
+//! state, so a logic bug breaking them corrupts the chain quietly instead of
+//! failing a test.  [`validate_outcome`] checks them after the fact: the gas
+//! counters against each other, the prepaid gas and the limits, the balance
+//! against the tokens sent away by the receipts, the logs against their
+//! limits and the receipts against their costs.  Nothing checks them
+//! when running a call with [`crate::run`]: the [`crate::Simulator`] checks
+//! every call it runs, and the tests of this crate every call they make.
+//!
+//! The runtime ignores the balance and the receipts of an aborted call, and
+//! old protocols report nothing but the error for some of them, see
+//! [`VMOutcome::nop_outcome`], so only the gas limits and the logs of
+//! aborted calls are checked.
+
+use crate::logic::types::ReceiptIndex;
+use crate::logic::{VMContext, VMOutcome};
+use crate::logic::Config;
+use unc_primitives_core::types::{Balance, Gas};
+
+/// An invariant a [`VMOutcome`] breaks, see [`validate_outcome`].
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum OutcomeViolation {
+    #[error("burnt {burnt} gas, more than the {used} used")]
+    BurntOverUsed { burnt: Gas, used: Gas },
+    #[error("used {used} gas, more than the {prepaid} prepaid")]
+    UsedOverPrepaid { used: Gas, prepaid: Gas },
+    #[error("burnt {burnt} gas, more than the limit of {limit}")]
+    BurntOverLimit { burnt: Gas, limit: Gas },
+    #[error("{promises} gas went to the promises instead of the {expected} used and not burnt")]
+    PromisesGas { promises: Gas, expected: Gas },
+    #[error("refunded {refunded} gas instead of the {expected} prepaid and not used")]
+    RefundedGas { refunded: Gas, expected: Gas },
+    #[error("the receipts burnt {send_gas} gas, more than the {burnt} burnt by the call")]
+    ReceiptsOverBurnt { send_gas: Gas, burnt: Gas },
+    #[error("the receipts took {gas} gas, more than the {promises} of the promises")]
+    ReceiptsOverPromises { gas: Gas, promises: Gas },
+    #[error("the balance is {balance} instead of the {expected} left after the receipts")]
+    Balance { balance: Balance, expected: Balance },
+    #[error("{count} logs, more than the limit of {limit}")]
+    TooManyLogs { count: u64, limit: u64 },
+    #[error("{length} bytes of logs, more than the limit of {limit}")]
+    LogsTooLong { length: u64, limit: u64 },
+    #[error("receipt {receipt_index} has no cost or the cost of another receipt")]
+    ReceiptCost { receipt_index: ReceiptIndex },
+    #[error("receipt {receipt_index} depends on {dependency}, which is not created before it")]
+    Dependency { receipt_index: ReceiptIndex, dependency: ReceiptIndex },
+}
+
+/// Checks that `outcome`, of a call made with `context` and `config`,
+/// satisfies the invariants of the module documentation.
+///
+/// The outcome is the one the runner returned: the unused gas the runtime
+/// gives the receipts with gas weights afterwards breaks the checks of their
+/// gas.
+pub fn validate_outcome(
+    outcome: &VMOutcome,
+    context: &VMContext,
+    config: &Config,
+) -> Result<(), OutcomeViolation> {
+    let (burnt, used) = (outcome.burnt_gas, outcome.used_gas);
+    if burnt > used {
+        return Err(OutcomeViolation::BurntOverUsed { burnt, used });
+    }
+    let limit = match &context.view_config {
+        Some(view_config) => view_config.max_gas_burnt,
+        None => {
+            if used > context.prepaid_gas {
+                return Err(OutcomeViolation::UsedOverPrepaid {
+                    used,
+                    prepaid: context.prepaid_gas,
+                });
+            }
+            config.limit_config.max_gas_burnt
+        }
+    };
+    if burnt > limit {
+        return Err(OutcomeViolation::BurntOverLimit { burnt, limit });
+    }
+    if outcome.promises_gas != used - burnt {
+        return Err(OutcomeViolation::PromisesGas {
+            promises: outcome.promises_gas,
+            expected: used - burnt,
+        });
+    }
+
+    let count = outcome.logs.len() as u64;
+    if count > config.limit_config.max_number_logs {
+        return Err(OutcomeViolation::TooManyLogs {
+            count,
+            limit: config.limit_config.max_number_logs,
+        });
+    }
+    let length = outcome.logs.iter().map(|log| log.len() as u64).sum();
+    if length > config.limit_config.max_total_log_length {
+        return Err(OutcomeViolation::LogsTooLong {
+            length,
+            limit: config.limit_config.max_total_log_length,
+        });
+    }
+
+    if outcome.aborted.is_some() {
+        return Ok(());
+    }
+    let expected = if context.is_view() { 0 } else { context.prepaid_gas - used };
+    if outcome.refunded_gas != expected {
+        return Err(OutcomeViolation::RefundedGas { refunded: outcome.refunded_gas, expected });
+    }
+
+    for (position, receipt) in outcome.receipts.iter().enumerate() {
+        let receipt_index = receipt.receipt_index;
+        let cost = outcome.receipt_costs.get(position);
+        if cost.map(|cost| cost.receipt_index) != Some(receipt_index) {
+            return Err(OutcomeViolation::ReceiptCost { receipt_index });
+        }
+        let earlier = &outcome.receipts[..position];
+        for &dependency in &receipt.dependencies {
+            if !earlier.iter().any(|earlier| earlier.receipt_index == dependency) {
+                return Err(OutcomeViolation::Dependency { receipt_index, dependency });
+            }
+        }
+    }
+    // Receipts are only checked against their costs when recorded.
+    let unrecorded = outcome.receipts.is_empty();
+    if let Some(cost) = outcome.receipt_costs.get(outcome.receipts.len()).filter(|_| !unrecorded) {
+        return Err(OutcomeViolation::ReceiptCost { receipt_index: cost.receipt_index });
+    }
+    let costs = &outcome.receipt_costs;
+    let send_gas = costs.iter().fold(0, |sum: Gas, cost| sum.saturating_add(cost.send_gas));
+    if send_gas > burnt {
+        return Err(OutcomeViolation::ReceiptsOverBurnt { send_gas, burnt });
+    }
+    let gas = costs.iter().fold(0, |sum: Gas, cost| {
+        sum.saturating_add(cost.exec_gas).saturating_add(cost.prepaid_gas)
+    });
+    if gas > outcome.promises_gas {
+        return Err(OutcomeViolation::ReceiptsOverPromises { gas, promises: outcome.promises_gas });
+    }
+
+    // The balance starts with the attached deposit and only decreases by the
+    // tokens the receipts send away.
+    let sent = costs.iter().try_fold(0, |sum: Balance, cost| sum.checked_add(cost.deposit));
+    let initial = context.account_balance.checked_add(context.attached_deposit);
+    if let (Some(sent), Some(initial)) = (sent, initial) {
+        if outcome.balance.checked_add(sent) != Some(initial) {
+            return Err(OutcomeViolation::Balance {
+                balance: outcome.balance,
+                expected: initial.saturating_sub(sent),
+            });
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::tests::{create_context, test_vm_config};
+    use crate::ContractCode;
+    use unc_parameters::RuntimeFeesConfig;
+
+    /// Logs "hi" and sends 1 token to bob.
+    const CONTRACT: &str = r#"
+(module
+  (import "env" "log_utf8" (func $log_utf8 (param i64 i64)))
+  (import "env" "promise_batch_create" (func $batch_create (param i64 i64) (result i64)))
+  (import "env" "promise_batch_action_transfer"
+    (func $transfer (param i64 i64)))
+  (memory 1)
+  (data (i32.const 0) "bob")
+  (data (i32.const 8) "hi")
+  (data (i32.const 16) "\01")
+  (func (export "main")
+    (call $log_utf8 (i64.const 2) (i64.const 8))
+    (call $transfer (call $batch_create (i64.const 3) (i64.const 0)) (i64.const 16))))"#;
+
+    /// Checks `outcome` once changed by `change`.
+    fn validate_changed(
+        outcome: &VMOutcome,
+        change: impl FnOnce(&mut VMOutcome),
+    ) -> Result<(), OutcomeViolation> {
+        let mut changed: VMOutcome = borsh::from_slice(&borsh::to_vec(outcome).unwrap()).unwrap();
+        change(&mut changed);
+        validate_outcome(&changed, &create_context(vec![]), &test_vm_config())
+    }
+
+    #[test]
+    fn test_validate_outcome() {
+        let code = ContractCode::new(wat::parse_str(CONTRACT).unwrap(), None);
+        let config = test_vm_config();
+        let fees = RuntimeFeesConfig::test();
+        let context = create_context(vec![]);
+        let mut ext = MockedExternal::new();
+        let options = crate::RunOptions { record_receipts: true, ..crate::RunOptions::default() };
+        let outcome = crate::run_with_options(
+            &code,
+            "main",
+            &mut ext,
+            context.clone(),
+            &config,
+            &fees,
+            &[],
+            None,
+            &options,
+        )
+        .unwrap();
+        assert_eq!(outcome.aborted, None);
+        assert_eq!(outcome.balance, 3);
+        assert_eq!(validate_outcome(&outcome, &context, &config), Ok(()));
+
+        assert_eq!(
+            validate_changed(&outcome, |outcome| outcome.balance = 4),
+            Err(OutcomeViolation::Balance { balance: 4, expected: 3 })
+        );
+        assert!(matches!(
+            validate_changed(&outcome, |outcome| outcome.burnt_gas = outcome.used_gas + 1),
+            Err(OutcomeViolation::BurntOverUsed { .. })
+        ));
+        assert!(matches!(
+            validate_changed(&outcome, |outcome| outcome.refunded_gas = 0),
+            Err(OutcomeViolation::RefundedGas { .. })
+        ));
+        assert!(matches!(
+            validate_changed(&outcome, |outcome| outcome.receipt_costs.clear()),
+            Err(OutcomeViolation::ReceiptCost { .. })
+        ));
+        let small = VMContext { prepaid_gas: outcome.used_gas - 1, ..create_context(vec![]) };
+        assert!(matches!(
+            validate_outcome(&outcome, &small, &config),
+            Err(OutcomeViolation::UsedOverPrepaid { .. })
+        ));
+
+        let mut strict = config.clone();
+        strict.limit_config.max_total_log_length = 1;
+        assert_eq!(
+            validate_outcome(&outcome, &context, &strict),
+            Err(OutcomeViolation::LogsTooLong { length: 2, limit: 1 })
+        );
+    }
+}
@@ -0,0 +1,584 @@
+//! Compiling untrusted contracts in jailed worker processes.
+//!
+//! Preparation and compilation parse and transform attacker controlled wasm
+//! and are a large attack surface.  Validators with strict threat models can
+//! compile them in worker processes first: an [`IsolatedCompiler`] keeps a
+//! pool of worker processes, each running [`run_compile_worker`], and sends
+//! them the contracts to compile.  Contracts crashing or hanging the
+//! compilers take down a worker rather than the node.
+//!
+//! The artifacts of a worker are native code the node would run, and a
+//! worker exploited by its contract could return anything, so the node does
+//! not store them as they are: it compiles the contracts the workers compiled
+//! again, and only stores its own artifact if the worker returned the same.
+//!
+//! Before reading any contract, a worker jails itself: on Linux it limits its
+//! address space to [`IsolationLimits::memory_bytes`] and installs a seccomp
+//! filter only allowing the syscalls of compiling, in memory, with threads.
+//! Workers exceeding [`IsolationLimits::timeout`] are killed.  Workers which
+//! crash or are killed are replaced by new ones.
+//!
+//! `unc-vm-run compile-worker` is such a worker, and `unc-vm-run precompile
+//! --isolated` compiles through a pool of them.
+
+use crate::cache::get_contract_cache_key_with_options;
+use crate::logic::errors::{CacheError, CompilationError};
+use crate::logic::{CompiledContract, CompiledContractCache, Config};
+use crate::runner::{CodegenTarget, CompilationInfo, CompileOptions, OptLevel};
+use crate::{ContractCode, ContractPrecompilatonResult, MockCompiledContractCache};
+use borsh::{BorshDeserialize, BorshSerialize};
+use std::ffi::OsString;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::PathBuf;
+use std::process::{Child, ChildStdin, Command, Stdio};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::time::Duration;
+use unc_parameters::vm::VMKind;
+use unc_parameters::RuntimeConfigStore;
+use unc_primitives_core::hash::CryptoHash;
+use unc_primitives_core::types::ProtocolVersion;
+
+/// Limits applied to the worker processes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IsolationLimits {
+    /// Maximum address space of a worker.
+    pub memory_bytes: u64,
+    /// Maximum duration of a compilation.
+    pub timeout: Duration,
+}
+
+impl Default for IsolationLimits {
+    fn default() -> Self {
+        Self { memory_bytes: 2 << 30, timeout: Duration::from_secs(60) }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IsolatedCompileError {
+    #[error("cannot start the compile worker")]
+    Spawn(#[source] io::Error),
+    #[error("the compile worker failed: {0}")]
+    WorkerFailed(String),
+    #[error("the compilation did not finish within {0:?}")]
+    Timeout(Duration),
+    #[error("the compile worker cannot compile the contract: {0}")]
+    Rejected(String),
+    #[error(
+        "the compile worker uses another config: it produced key {actual} instead of {expected}"
+    )]
+    ConfigMismatch { expected: CryptoHash, actual: CryptoHash },
+    #[error("the compile worker returned another artifact than the node compiled for {0}")]
+    ArtifactMismatch(CryptoHash),
+    #[error("the node cannot compile the contract: {0}")]
+    InProcess(String),
+    #[error(transparent)]
+    Cache(#[from] CacheError),
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct WorkerSetup {
+    memory_bytes: u64,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct CompileRequest {
+    protocol_version: ProtocolVersion,
+    vm_kind: u8,
+    baseline: bool,
+    opt_level: OptLevel,
+    code: Vec<u8>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct CompiledArtifact {
+    key: CryptoHash,
+    contract: CompiledContract,
+    info: Option<CompilationInfo>,
+}
+
+type CompileResponse = Result<CompiledArtifact, String>;
+
+const VM_KINDS: [VMKind; 4] = [VMKind::Wasmer0, VMKind::Wasmtime, VMKind::Wasmer2, VMKind::NearVm];
+
+fn write_frame(writer: &mut impl Write, message: &impl BorshSerialize) -> io::Result<()> {
+    let bytes = borsh::to_vec(message)?;
+    let len = u32::try_from(bytes.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "message too large"))?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    writer.flush()
+}
+
+/// Bytes of the first message of a worker, far more than it takes.
+const MAX_SETUP_FRAME_LEN: u64 = 1024;
+
+/// Reads a message of at most `max_len` bytes.
+///
+/// The buffer grows with the bytes actually read, so a peer announcing a
+/// long message without sending it does not make the reader allocate it.
+fn read_frame<T: BorshDeserialize>(reader: &mut impl Read, max_len: u64) -> io::Result<T> {
+    let mut len = [0; 4];
+    reader.read_exact(&mut len)?;
+    let len = u64::from(u32::from_le_bytes(len));
+    if len > max_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("message of {len} bytes exceeds the limit of {max_len} bytes"),
+        ));
+    }
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if bytes.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    T::try_from_slice(&bytes)
+}
+
+/// Serves compilation requests on the standard input and output until the
+/// input is closed.
+///
+/// This is the body of a worker process of an [`IsolatedCompiler`], which
+/// passes the configuration of the worker in the first message.  The worker
+/// compiles with the config of the protocol version of each request.
+pub fn run_compile_worker() -> io::Result<()> {
+    let mut input = BufReader::new(io::stdin().lock());
+    let mut output = BufWriter::new(io::stdout().lock());
+    let setup: WorkerSetup = read_frame(&mut input, MAX_SETUP_FRAME_LEN)?;
+    // The configs are built before jailing, which may prevent reading them.
+    let store = RuntimeConfigStore::new(None);
+    jail::enter(setup.memory_bytes)?;
+    loop {
+        // Requests larger than the address space of the worker could not be
+        // compiled anyway.
+        let request: CompileRequest = match read_frame(&mut input, setup.memory_bytes) {
+            Ok(request) => request,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let response = compile(&store, request);
+        write_frame(&mut output, &response)?;
+    }
+}
+
+fn compile(store: &RuntimeConfigStore, request: CompileRequest) -> CompileResponse {
+    let vm_kind = *VM_KINDS
+        .get(usize::from(request.vm_kind))
+        .ok_or_else(|| format!("unknown VM kind {}", request.vm_kind))?;
+    let mut config = Config::from(store.get_config(request.protocol_version).wasm_config.clone());
+    config.vm_kind = vm_kind;
+    let codegen = if request.baseline { CodegenTarget::Baseline } else { CodegenTarget::Host };
+    let options = CompileOptions { codegen, opt_level: request.opt_level };
+    crate::runner::check_backend(vm_kind, &config).map_err(|err| err.to_string())?;
+    compile_in_process(&ContractCode::new(request.code, None), &config, options)
+}
+
+/// The artifact of `code`, compiled by this process.
+fn compile_in_process(
+    code: &ContractCode,
+    config: &Config,
+    options: CompileOptions,
+) -> CompileResponse {
+    let cache = MockCompiledContractCache::default();
+    let result = crate::precompile_contract_with_options(code, config, options, Some(&cache))
+        .map_err(|err| err.to_string())?;
+    if let Ok(ContractPrecompilatonResult::CacheNotAvailable) = result {
+        return Err(format!("{:?} does not cache its artifacts", config.vm_kind));
+    }
+    let key = get_contract_cache_key_with_options(code, config, options);
+    let contract = cache
+        .get(&key)
+        .map_err(|err| err.to_string())?
+        .ok_or("the compiled contract is missing from the cache")?;
+    let info = cache.get_compilation_info(&key).map_err(|err| err.to_string())?;
+    Ok(CompiledArtifact { key, contract, info })
+}
+
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    responses: mpsc::Receiver<io::Result<CompileResponse>>,
+}
+
+impl Worker {
+    fn kill(mut self) -> String {
+        let _ = self.child.kill();
+        match self.child.wait() {
+            Ok(status) => status.to_string(),
+            Err(err) => err.to_string(),
+        }
+    }
+}
+
+#[derive(Default)]
+struct Pool {
+    idle: Vec<Worker>,
+    /// Number of workers, idle or busy.
+    live: usize,
+}
+
+/// Compiles contracts in a pool of jailed worker processes, see the module
+/// documentation.
+///
+/// The compiler can be shared between threads, each compilation taking a
+/// worker for itself.
+pub struct IsolatedCompiler {
+    program: PathBuf,
+    args: Vec<OsString>,
+    limits: IsolationLimits,
+    max_workers: usize,
+    pool: Mutex<Pool>,
+    released: Condvar,
+}
+
+impl IsolatedCompiler {
+    /// Makes a compiler running up to `max_workers` instances of `program`
+    /// with `args`, which must call [`run_compile_worker`].
+    ///
+    /// Workers are started on demand.
+    pub fn new(
+        program: impl Into<PathBuf>,
+        args: impl IntoIterator<Item = impl Into<OsString>>,
+        limits: IsolationLimits,
+        max_workers: usize,
+    ) -> Self {
+        Self {
+            program: program.into(),
+            args: args.into_iter().map(Into::into).collect(),
+            limits,
+            max_workers: max_workers.max(1),
+            pool: Mutex::default(),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Same as [`crate::precompile_contract_with_options`], but compiles in a
+    /// worker process first.
+    ///
+    /// The worker compiles with the config of `protocol_version`, which must
+    /// be `config`.  Once it has, the contract is compiled again in this
+    /// process, and the artifact stored if the worker returned the same.
+    pub fn precompile(
+        &self,
+        code: &ContractCode,
+        config: &Config,
+        protocol_version: ProtocolVersion,
+        options: CompileOptions,
+        cache: &dyn CompiledContractCache,
+    ) -> Result<Result<ContractPrecompilatonResult, CompilationError>, IsolatedCompileError> {
+        let _span = tracing::debug_span!(target: "vm", "isolated_precompile").entered();
+        let expected = get_contract_cache_key_with_options(code, config, options);
+        if cache.has(&expected).map_err(CacheError::ReadError)? {
+            return Ok(Ok(ContractPrecompilatonResult::ContractAlreadyInCache));
+        }
+        let request = CompileRequest {
+            protocol_version,
+            vm_kind: VM_KINDS.iter().position(|&vm_kind| vm_kind == config.vm_kind).unwrap() as u8,
+            baseline: options.codegen == CodegenTarget::Baseline,
+            opt_level: options.opt_level,
+            code: code.code().to_vec(),
+        };
+        let worker_artifact = self.compile(&request)?;
+        if worker_artifact.key != expected {
+            return Err(IsolatedCompileError::ConfigMismatch {
+                expected,
+                actual: worker_artifact.key,
+            });
+        }
+        let artifact =
+            compile_in_process(code, config, options).map_err(IsolatedCompileError::InProcess)?;
+        if artifact.contract != worker_artifact.contract {
+            return Err(IsolatedCompileError::ArtifactMismatch(expected));
+        }
+        cache.put(&expected, artifact.contract.clone()).map_err(CacheError::WriteError)?;
+        Ok(match artifact.contract {
+            CompiledContract::CompileModuleError(err) => Err(err),
+            CompiledContract::Code(_) => {
+                let info = match artifact.info {
+                    Some(info) => {
+                        cache
+                            .put_compilation_info(&expected, &info)
+                            .map_err(CacheError::WriteError)?;
+                        info
+                    }
+                    None => CompilationInfo::singlepass(Duration::ZERO),
+                };
+                Ok(ContractPrecompilatonResult::ContractCompiled(info))
+            }
+        })
+    }
+
+    fn compile(&self, request: &CompileRequest) -> Result<CompiledArtifact, IsolatedCompileError> {
+        let mut worker = self.acquire()?;
+        let response = match write_frame(&mut worker.stdin, request) {
+            Ok(()) => worker.responses.recv_timeout(self.limits.timeout),
+            Err(err) => Ok(Err(err)),
+        };
+        match response {
+            Ok(Ok(response)) => {
+                self.release(Some(worker));
+                response.map_err(IsolatedCompileError::Rejected)
+            }
+            Ok(Err(err)) => {
+                let status = worker.kill();
+                self.release(None);
+                Err(IsolatedCompileError::WorkerFailed(format!(
+                    "{err}, worker exited with {status}"
+                )))
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                worker.kill();
+                self.release(None);
+                Err(IsolatedCompileError::Timeout(self.limits.timeout))
+            }
+            Err(mpsc::RecvTimeoutError::Disconnected) => {
+                let status = worker.kill();
+                self.release(None);
+                Err(IsolatedCompileError::WorkerFailed(format!("worker exited with {status}")))
+            }
+        }
+    }
+
+    /// Takes an idle worker, starting one if the pool is not full.
+    fn acquire(&self) -> Result<Worker, IsolatedCompileError> {
+        let mut pool = self.pool.lock().unwrap();
+        loop {
+            if let Some(worker) = pool.idle.pop() {
+                return Ok(worker);
+            }
+            if pool.live < self.max_workers {
+                pool.live += 1;
+                drop(pool);
+                return self.spawn().map_err(|err| {
+                    self.release(None);
+                    IsolatedCompileError::Spawn(err)
+                });
+            }
+            pool = self.released.wait(pool).unwrap();
+        }
+    }
+
+    /// Returns a worker to the pool, or frees its slot if it is gone.
+    fn release(&self, worker: Option<Worker>) {
+        let mut pool = self.pool.lock().unwrap();
+        match worker {
+            Some(worker) => pool.idle.push(worker),
+            None => pool.live -= 1,
+        }
+        self.released.notify_one();
+    }
+
+    fn spawn(&self) -> io::Result<Worker> {
+        let mut child = Command::new(&self.program)
+            .args(&self.args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let mut stdin = child.stdin.take().unwrap();
+        let mut stdout = BufReader::new(child.stdout.take().unwrap());
+        let (sender, responses) = mpsc::channel();
+        // Reading on a thread of its own lets the compiler stop waiting for
+        // a worker which hangs.
+        // Artifacts larger than the address space of the worker are not ones
+        // it compiled.
+        let max_len = self.limits.memory_bytes;
+        std::thread::Builder::new().name("compile-worker-reader".to_string()).spawn(move || {
+            loop {
+                let response = read_frame(&mut stdout, max_len);
+                let failed = response.is_err();
+                if sender.send(response).is_err() || failed {
+                    return;
+                }
+            }
+        })?;
+        write_frame(&mut stdin, &WorkerSetup { memory_bytes: self.limits.memory_bytes })?;
+        Ok(Worker { child, stdin, responses })
+    }
+}
+
+impl Drop for IsolatedCompiler {
+    fn drop(&mut self) {
+        for worker in self.pool.get_mut().unwrap().idle.drain(..) {
+            // Closing the input stops the worker.
+            drop(worker.stdin);
+            let mut child = worker.child;
+            let _ = child.wait();
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod jail {
+    use std::io;
+
+    /// Syscalls of compiling in memory with threads and reporting on the
+    /// open standard streams, the only ones the workers can make.  Others
+    /// fail with `EPERM`, except `clone`, which is only allowed for threads.
+    const ALLOWED_SYSCALLS: &[libc::c_long] = &[
+        libc::SYS_read,
+        libc::SYS_write,
+        libc::SYS_close,
+        libc::SYS_mmap,
+        libc::SYS_munmap,
+        libc::SYS_mremap,
+        libc::SYS_mprotect,
+        libc::SYS_madvise,
+        libc::SYS_brk,
+        libc::SYS_futex,
+        libc::SYS_set_robust_list,
+        libc::SYS_rseq,
+        libc::SYS_sched_yield,
+        libc::SYS_sched_getaffinity,
+        libc::SYS_sigaltstack,
+        libc::SYS_rt_sigaction,
+        libc::SYS_rt_sigprocmask,
+        libc::SYS_rt_sigreturn,
+        libc::SYS_clock_gettime,
+        libc::SYS_clock_nanosleep,
+        libc::SYS_nanosleep,
+        libc::SYS_getrandom,
+        libc::SYS_getpid,
+        libc::SYS_gettid,
+        libc::SYS_tgkill,
+        libc::SYS_exit,
+        libc::SYS_exit_group,
+    ];
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xc000_003e;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+    /// Syscall numbers from this bit on are the x32 ABI, which would bypass
+    /// the filter.
+    #[cfg(target_arch = "x86_64")]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    fn statement(code: u32, k: u32) -> libc::sock_filter {
+        libc::sock_filter { code: code as u16, jt: 0, jf: 0, k }
+    }
+
+    fn jump(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter { code: code as u16, jt, jf, k }
+    }
+
+    fn check(result: libc::c_int) -> io::Result<()> {
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    pub(super) fn enter(memory_bytes: u64) -> io::Result<()> {
+        let limit = |value| libc::rlimit { rlim_cur: value, rlim_max: value };
+        // SAFETY: plain syscalls on valid arguments.
+        unsafe {
+            check(libc::setrlimit(libc::RLIMIT_AS, &limit(memory_bytes)))?;
+            check(libc::setrlimit(libc::RLIMIT_CORE, &limit(0)))?;
+            check(libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0))?;
+        }
+
+        use libc::{BPF_ABS, BPF_JEQ, BPF_JMP, BPF_K, BPF_LD, BPF_RET, BPF_W};
+        let kill = statement(BPF_RET | BPF_K, libc::SECCOMP_RET_KILL_PROCESS);
+        let deny = statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ERRNO | libc::EPERM as u32);
+        let allow = statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ALLOW);
+        // Offsets of the fields of `seccomp_data`, the low half of the first
+        // argument on these little endian architectures.
+        let (nr, arch, arg0) = (0, 4, 16);
+        let mut filter = vec![
+            statement(BPF_LD | BPF_W | BPF_ABS, arch),
+            jump(BPF_JMP | BPF_JEQ | BPF_K, AUDIT_ARCH, 1, 0),
+            kill,
+            statement(BPF_LD | BPF_W | BPF_ABS, nr),
+        ];
+        #[cfg(target_arch = "x86_64")]
+        filter.extend([jump(BPF_JMP | libc::BPF_JGE | BPF_K, X32_SYSCALL_BIT, 0, 1), kill]);
+        for &syscall in ALLOWED_SYSCALLS {
+            filter.extend([jump(BPF_JMP | BPF_JEQ | BPF_K, syscall as u32, 0, 1), allow]);
+        }
+        // The flags of `clone3` are in memory, out of reach of the filter, so
+        // it is reported missing, for the C library to fall back to `clone`.
+        let missing = statement(BPF_RET | BPF_K, libc::SECCOMP_RET_ERRNO | libc::ENOSYS as u32);
+        filter.extend([jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone3 as u32, 0, 1), missing]);
+        filter.extend([
+            jump(BPF_JMP | BPF_JEQ | BPF_K, libc::SYS_clone as u32, 0, 3),
+            statement(BPF_LD | BPF_W | BPF_ABS, arg0),
+            jump(BPF_JMP | libc::BPF_JSET | BPF_K, libc::CLONE_THREAD as u32, 0, 1),
+            allow,
+        ]);
+        filter.push(deny);
+        let program = libc::sock_fprog { len: filter.len() as u16, filter: filter.as_mut_ptr() };
+        // SAFETY: `program` points to `filter`, which the kernel copies.
+        unsafe {
+            check(libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &program as *const libc::sock_fprog,
+            ))
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod jail {
+    pub(super) fn enter(_memory_bytes: u64) -> std::io::Result<()> {
+        tracing::warn!(target: "vm", "compile workers are only jailed on Linux");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::with_vm_variants;
+    use unc_primitives_core::version::PROTOCOL_VERSION;
+
+    /// The worker side of a compilation, without the process and the jail.
+    #[test]
+    fn test_compile_request() {
+        let store = RuntimeConfigStore::new(None);
+        let config = Config::from(store.get_config(PROTOCOL_VERSION).wasm_config.clone());
+        let code = wat::parse_str(r#"(module (func (export "main")))"#).unwrap();
+        with_vm_variants(&config, |vm_kind| {
+            let mut config = config.clone();
+            config.vm_kind = vm_kind;
+            if crate::runner::check_backend(vm_kind, &config).is_err() {
+                return;
+            }
+            let request = CompileRequest {
+                protocol_version: PROTOCOL_VERSION,
+                vm_kind: VM_KINDS.iter().position(|&kind| kind == vm_kind).unwrap() as u8,
+                baseline: false,
+                opt_level: OptLevel::default(),
+                code: code.clone(),
+            };
+            let mut frame = Vec::new();
+            write_frame(&mut frame, &request).unwrap();
+            let request: CompileRequest = read_frame(&mut &frame[..], u64::MAX).unwrap();
+            let artifact = compile(&store, request).unwrap();
+            let code = ContractCode::new(code.clone(), None);
+            let options = CompileOptions::default();
+            let expected = get_contract_cache_key_with_options(&code, &config, options);
+            assert_eq!(artifact.key, expected, "{vm_kind:?}");
+            assert!(matches!(artifact.contract, CompiledContract::Code(_)), "{vm_kind:?}");
+            // What the node compares the artifacts of the workers with.
+            let in_process = compile_in_process(&code, &config, options).unwrap();
+            assert_eq!(in_process.contract, artifact.contract, "{vm_kind:?}");
+        });
+    }
+
+    #[test]
+    fn test_frame_limit() {
+        let mut frame = Vec::new();
+        write_frame(&mut frame, &vec![0u8; 100]).unwrap();
+        let bytes: Vec<u8> = read_frame(&mut &frame[..], 104).unwrap();
+        assert_eq!(bytes.len(), 100);
+        let err = read_frame::<Vec<u8>>(&mut &frame[..], 103).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Announcing more than is sent allocates nothing beyond what is sent.
+        let mut announced = u32::MAX.to_le_bytes().to_vec();
+        announced.extend([0; 8]);
+        let err = read_frame::<Vec<u8>>(&mut &announced[..], u64::MAX).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+}
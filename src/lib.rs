@@ -1,35 +1,234 @@
 #![doc = include_str!("../README.md")]
 
+#[cfg(feature = "abi_fuzz")]
+mod abi;
+mod admission;
+mod analysis;
+pub mod api;
+mod archive;
+mod artifact;
+#[cfg(feature = "backtrace")]
+mod backtrace;
+mod batch;
 mod cache;
+#[cfg(any(test, feature = "test-support"))]
+#[doc(hidden)]
+pub mod chaos;
+mod clock;
 mod code;
+mod concurrency;
+#[cfg(any(test, feature = "costs"))]
+#[doc(hidden)]
+pub mod costs;
+#[cfg(feature = "coverage")]
+mod coverage;
+#[cfg(any(feature = "coverage", feature = "backtrace"))]
+mod debug_info;
+mod deploy_precompile;
+#[doc(hidden)]
+pub mod differential;
+mod dry_run;
 mod errors;
 mod features;
+#[cfg(feature = "ffi")]
+#[doc(hidden)]
+pub mod ffi;
+mod fingerprint;
+mod hardening;
+mod heatmap;
+#[cfg(all(feature = "unc_vm", target_arch = "x86_64", target_os = "linux"))]
+mod huge_pages;
 mod imports;
 mod instrument;
+mod invariants;
+#[cfg(feature = "isolated_compile")]
+mod isolated_compile;
+mod limit_diagnostics;
+mod log_sink;
+#[doc(hidden)]
 pub mod logic;
+#[cfg(any(test, feature = "test-support"))]
+#[doc(hidden)]
+pub mod malformed;
 #[cfg(all(feature = "wasmer0_vm", target_arch = "x86_64"))]
 mod memory;
+mod method_name;
+mod metrics;
 #[cfg(all(feature = "unc_vm", target_arch = "x86_64"))]
 mod unc_vm_runner;
+mod parsed_code;
+#[doc(hidden)]
 pub mod prepare;
+mod prepare_pipeline;
 mod profile;
+mod provenance;
+mod reoptimize;
+mod resources;
+mod return_sink;
 mod runner;
+#[cfg(feature = "sandbox")]
+mod sandbox;
+mod shadow;
+mod simulator;
+#[cfg(any(test, feature = "test-support"))]
+#[doc(hidden)]
+pub mod testing;
 #[cfg(test)]
 mod tests;
+mod throughput;
+mod traps;
 mod utils;
+mod view_cache;
 #[cfg(all(feature = "wasmer2_vm", target_arch = "x86_64"))]
 mod wasmer2_runner;
 #[cfg(all(feature = "wasmer0_vm", target_arch = "x86_64"))]
 mod wasmer_runner;
 #[cfg(feature = "wasmtime_vm")]
 mod wasmtime_runner;
+#[cfg(any(test, feature = "wat"))]
+mod wat_parser;
 
+#[doc(hidden)]
 pub use crate::logic::with_ext_cost_counter;
-pub use cache::{get_contract_cache_key, precompile_contract, MockCompiledContractCache};
-pub use code::ContractCode;
+#[cfg(feature = "abi_fuzz")]
+#[doc(hidden)]
+pub use abi::{
+    decode_return_value, AbiError, AbiFunction, AbiFunctionKind, AbiFuzzOptions, AbiFuzzer,
+    AbiParameter, AbiResult, AbiSerialization, AbiType, BreakingChange, Compatibility, ContractAbi,
+    ContractInterface, DecodedValue, FuzzFailure, InvariantViolation, MethodFuzzReport,
+    ABI_SECTION,
+};
+#[doc(hidden)]
+pub use admission::{
+    Admission, AdmissionController, AdmissionPolicy, CallStats, OverloadAction, Overloaded,
+};
+#[doc(hidden)]
+pub use analysis::{analyze_contract, ContractAnalysis, ImportedFunction, Limits, WasmFeature};
+#[doc(hidden)]
+pub use archive::{
+    decode_archived_outcome, encode_archived_outcome, ArchiveError, ARCHIVE_FORMAT_VERSION,
+};
+#[doc(hidden)]
+pub use artifact::{
+    export_artifact, import_artifact, read_artifact_header, ArtifactError, ArtifactHeader,
+    ARTIFACT_FORMAT_VERSION,
+};
+#[doc(hidden)]
+pub use batch::{BatchRunner, PreparedCall};
+#[doc(hidden)]
+pub use cache::{
+    get_contract_cache_key, get_contract_cache_key_with_options, precompile_contract,
+    precompile_contract_for_codegen, precompile_contract_with_options,
+    FilesystemContractRuntimeCache, MockCompiledContractCache, PrefetchingContractCache,
+};
+#[doc(hidden)]
+pub use clock::{Clock, SystemClock, VirtualClock};
+#[doc(hidden)]
+pub use code::{verify_contract_codes, CodeHashMismatch, ContractCode};
+#[doc(hidden)]
+pub use concurrency::{execution_concurrency, ExecutionConcurrency};
+#[cfg(feature = "coverage")]
+#[doc(hidden)]
+pub use coverage::{coverage_map, CoverageBlock, CoverageCounters, CoverageMap};
+#[doc(hidden)]
+pub use deploy_precompile::{
+    DeployPrecompiler, DeployPrecompilerPolicy, DeployPrecompilerStats, DeployPriority, Enqueued,
+    PrecompilePause, PrecompilerPaused, QueueFullAction,
+};
+#[doc(hidden)]
+pub use dry_run::{DryRunExternal, GasEstimate};
+#[doc(hidden)]
+pub use errors::ContractPrecompilatonResult;
+#[doc(hidden)]
+pub use fingerprint::ConfigFingerprint;
+#[doc(hidden)]
+pub use hardening::{
+    hardening_capabilities, host_capabilities, CodeProtection, HardeningCapabilities,
+    HardeningError, HostCapabilities, MemoryHardening,
+};
+#[doc(hidden)]
+pub use heatmap::{FunctionHeat, Heatmap};
+#[cfg(all(feature = "unc_vm", target_arch = "x86_64", target_os = "linux"))]
+#[doc(hidden)]
+pub use huge_pages::HugePageAllocator;
+#[doc(hidden)]
+pub use invariants::{validate_outcome, OutcomeViolation};
+#[doc(hidden)]
+pub use logic::host_functions::{host_functions, HostFnInfo, HostValType};
+#[cfg(feature = "isolated_compile")]
+#[doc(hidden)]
+pub use isolated_compile::{
+    run_compile_worker, IsolatedCompileError, IsolatedCompiler, IsolationLimits,
+};
+#[doc(hidden)]
+pub use limit_diagnostics::{
+    function_size_diagnostics, limit_diagnostics, precompile_contract_with_diagnostics,
+    ContractLimit, LargeFunction, LimitWarning,
+};
+#[doc(hidden)]
+pub use log_sink::{BoundedLogSink, BufferLogSink, LogCapture, LogSink};
+#[doc(hidden)]
+pub use method_name::{check_method_name, exported_methods, ExportedMethod};
+#[cfg(feature = "metrics")]
+#[doc(hidden)]
+pub use metrics::{
+    precompile_metrics, prometheus_metrics, runner_metrics, HistogramSnapshot, VMMetrics,
+};
+#[doc(hidden)]
+pub use parsed_code::{ParsedContractCode, ParsedExport, ParsedExportKind, ParsedSection};
+#[doc(hidden)]
+pub use prepare_pipeline::{PreparePipeline, PreparedContract};
+#[doc(hidden)]
 pub use profile::ProfileDataV2;
+#[doc(hidden)]
 pub use profile::ProfileDataV3;
-pub use runner::{run, VM};
+#[doc(hidden)]
+pub use profile::VersionedProfileData;
+#[doc(hidden)]
+pub use provenance::{
+    run_with_provenance, AttestationError, Attester, ExecutionClaim, Provenance, ProvenanceError,
+};
+#[cfg(feature = "ed25519")]
+#[doc(hidden)]
+pub use provenance::Ed25519Attester;
+#[doc(hidden)]
+pub use reoptimize::{ContractStats, ContractTier, HotContractThresholds, Reoptimizer};
+#[cfg(feature = "leak_detector")]
+#[doc(hidden)]
+pub use resources::{check_leaks, live_resources, LiveResources, ResourceLeak};
+#[doc(hidden)]
+pub use return_sink::{BufferReturnSink, ReturnSink, RETURN_CHUNK_SIZE};
+#[doc(hidden)]
+pub use runner::{
+    check_backend, host_cpu_features, precompile_contracts, run, run_with_diagnostics,
+    run_with_options, warm_up, BackendRejection, BackendSelection, BackendUnavailable, CodePricing,
+    CodegenTarget, CompilationInfo, CompilationPass, CompileOptions, Compiler, ConsensusHooks,
+    HostSettings, HugePages, MethodCall, OptLevel, PrecompileResult, RunDiagnostics, RunOptions,
+    RunWithDiagnosticsError, WarmUp, WarmUpError, WarmUpStage, WarmUpStep, BASELINE_CPU_FEATURES,
+    VM,
+};
+#[cfg(feature = "sandbox")]
+#[doc(hidden)]
+pub use sandbox::{LimitOverrideError, LimitOverrides};
+#[doc(hidden)]
+pub use shadow::{
+    replay, run_recorded, run_shadowed, CheckpointDivergence, ExternalCall, ExternalTrace,
+    RecordingExternal, Replay, ShadowCall, ShadowDivergence, ShadowReport, ShadowSink,
+};
+#[doc(hidden)]
+pub use simulator::{
+    OracleRequest, SimulatedAccount, SimulatedExecution, Simulation, SimulationError, Simulator,
+};
+#[doc(hidden)]
+pub use throughput::{SharedContract, ThroughputRunner, ThroughputRunnerError};
+#[cfg(all(feature = "unc_vm", target_arch = "x86_64"))]
+#[doc(hidden)]
+pub use unc_vm_runner::{GuestMemoryAllocator, NearVmMemoryPool};
+#[doc(hidden)]
+pub use view_cache::{ViewCachePolicy, ViewCallCache, ViewCallKey};
+#[cfg(any(test, feature = "wat"))]
+#[doc(hidden)]
+pub use wat_parser::{parse_wat, WatError, WatLimits};
 
 /// This is public for internal experimentation use only, and should otherwise be considered an
 /// implementation detail of `unc-vm-runner`.
@@ -0,0 +1,391 @@
+//! Warnings for contracts close to the limits of the config.
+//!
+//! A contract within its limits compiles and runs, but may stop compiling
+//! when a later protocol version tightens them.  [`limit_diagnostics`]
+//! reports the limits a contract is close to, so that deploy pipelines can
+//! tell its authors before that happens, and
+//! [`precompile_contract_with_diagnostics`] reports them while compiling.
+//! The diagnostics never change whether or how a contract compiles.
+//!
+//! A contract rejected with [`PrepareError::FunctionTooLarge`] has functions
+//! above the [`FunctionSizeLimit`], which [`function_size_diagnostics`] lists
+//! by index and name, so that its authors know which ones to split.
+//!
+//! [`PrepareError::FunctionTooLarge`]: crate::logic::errors::PrepareError::FunctionTooLarge
+
+use crate::logic::{CompiledContractCache, Config};
+use crate::prepare::FunctionSizeLimit;
+use crate::runner::PrecompileResult;
+use crate::ContractCode;
+use finite_wasm::wasmparser as wp;
+use std::collections::HashMap;
+use std::fmt;
+
+/// A limit of the config a contract can be close to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ContractLimit {
+    /// `max_contract_size`, against the size of the code in bytes.
+    CodeSize,
+    /// `max_functions_number_per_contract`, imported functions included.
+    Functions,
+    /// `max_locals_per_contract`, over all the functions.
+    Locals,
+    /// `max_stack_height`, against the stack the largest activation of a
+    /// function takes, as the finite-wasm stack limiter accounts it.
+    StackHeight,
+    /// `initial_memory_pages`, against the pages the contract needs when
+    /// instantiated: its declared memory and its active data segments.
+    MemoryPages,
+    /// The [`FunctionSizeLimit`], against the largest function body, when
+    /// the config has one.
+    FunctionBodySize,
+}
+
+/// A contract within the margin of one of its limits.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LimitWarning {
+    pub limit: ContractLimit,
+    /// How much of the limit the contract uses.
+    pub used: u64,
+    /// The limit in the config.
+    pub max: u64,
+}
+
+/// The limits `code` uses more than `100 - margin_percent` percent of.
+///
+/// Limits already exceeded are compilation errors and not reported.  The
+/// limits which cannot be measured because the code fails to parse are left
+/// out, as are the ones the config does not set.
+pub fn limit_diagnostics(code: &[u8], config: &Config, margin_percent: u64) -> Vec<LimitWarning> {
+    let limits = &config.limit_config;
+    let mut usage =
+        vec![(ContractLimit::CodeSize, Some(code.len() as u64), limits.max_contract_size)];
+    if let Ok(module) = measure(code) {
+        usage.extend([
+            (
+                ContractLimit::Functions,
+                Some(module.functions),
+                limits.max_functions_number_per_contract.unwrap_or(u64::MAX),
+            ),
+            (
+                ContractLimit::Locals,
+                Some(module.locals),
+                limits.max_locals_per_contract.unwrap_or(u64::MAX),
+            ),
+            (
+                ContractLimit::MemoryPages,
+                Some(module.memory_pages),
+                u64::from(limits.initial_memory_pages),
+            ),
+        ]);
+        usage.push((
+            ContractLimit::StackHeight,
+            crate::prepare::max_function_stack(code).ok(),
+            u64::from(limits.max_stack_height),
+        ));
+        if let Some(limit) = FunctionSizeLimit::for_config(config) {
+            usage.push((
+                ContractLimit::FunctionBodySize,
+                Some(module.largest_function_body),
+                limit.max_function_body_size,
+            ));
+        }
+    }
+    let margin_percent = u128::from(margin_percent.min(100));
+    usage
+        .into_iter()
+        .filter_map(|(limit, used, max)| {
+            let used = used?;
+            // Checked in u128 to not overflow with the unset limits.
+            let close = u128::from(used) * 100 >= u128::from(max) * (100 - margin_percent);
+            (max != u64::MAX && used != 0 && used <= max && close).then_some(LimitWarning {
+                limit,
+                used,
+                max,
+            })
+        })
+        .collect()
+}
+
+/// Same as [`crate::precompile_contract`], also returning the
+/// [`limit_diagnostics`] of the contract.
+pub fn precompile_contract_with_diagnostics(
+    code: &ContractCode,
+    config: &Config,
+    cache: Option<&dyn CompiledContractCache>,
+    margin_percent: u64,
+) -> (PrecompileResult, Vec<LimitWarning>) {
+    let result = crate::precompile_contract(code, config, cache);
+    (result, limit_diagnostics(code.code(), config, margin_percent))
+}
+
+/// A function larger than the [`FunctionSizeLimit`] of the config.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LargeFunction {
+    /// Index of the function in the function index space of the contract,
+    /// imported functions included.
+    pub index: u32,
+    /// Name of the function from the `name` section of the contract, if any.
+    pub name: Option<String>,
+    /// Bytes of its body.
+    pub size: u64,
+    /// The limit in the config.
+    pub max: u64,
+}
+
+impl fmt::Display for LargeFunction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "function {}", self.index)?;
+        if let Some(name) = &self.name {
+            write!(f, " ({name})")?;
+        }
+        write!(
+            f,
+            " takes {} bytes, more than the {} bytes a function may take: split it into \
+             smaller functions, e.g. by inlining less into it",
+            self.size, self.max
+        )
+    }
+}
+
+/// The functions of `code` larger than the [`FunctionSizeLimit`] of
+/// `config`, by index.
+///
+/// Empty when the config has no limit or when the code fails to parse before
+/// the first function above the limit.
+pub fn function_size_diagnostics(code: &[u8], config: &Config) -> Vec<LargeFunction> {
+    let Some(limit) = FunctionSizeLimit::for_config(config) else { return Vec::new() };
+    let mut functions = Vec::new();
+    let mut names = HashMap::new();
+    let mut index = 0;
+    for payload in wp::Parser::new(0).parse_all(code) {
+        let Ok(payload) = payload else { break };
+        match payload {
+            wp::Payload::ImportSection(reader) => {
+                let imports = reader.into_iter().filter_map(Result::ok);
+                index += imports.filter(|import| matches!(import.ty, wp::TypeRef::Func(_))).count()
+                    as u32;
+            }
+            wp::Payload::CodeSectionEntry(body) => {
+                let size = body.range().len() as u64;
+                if size > limit.max_function_body_size {
+                    functions.push(LargeFunction {
+                        index,
+                        name: None,
+                        size,
+                        max: limit.max_function_body_size,
+                    });
+                }
+                index += 1;
+            }
+            wp::Payload::CustomSection(reader) if reader.name() == "name" => {
+                // The names only make the diagnostics easier to read, so a
+                // malformed section is ignored.
+                let _ = read_function_names(reader.data(), reader.data_offset(), &mut names);
+            }
+            _ => {}
+        }
+    }
+    for function in &mut functions {
+        function.name = names.remove(&function.index);
+    }
+    functions
+}
+
+fn read_function_names(
+    data: &[u8],
+    data_offset: usize,
+    names: &mut HashMap<u32, String>,
+) -> Result<(), wp::BinaryReaderError> {
+    for name in wp::NameSectionReader::new(data, data_offset) {
+        if let wp::Name::Function(map) = name? {
+            for naming in map {
+                let naming = naming?;
+                names.insert(naming.index, naming.name.to_string());
+            }
+        }
+    }
+    Ok(())
+}
+
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
+#[derive(Default)]
+struct ModuleUsage {
+    functions: u64,
+    locals: u64,
+    memory_pages: u64,
+    largest_function_body: u64,
+}
+
+fn measure(code: &[u8]) -> Result<ModuleUsage, wp::BinaryReaderError> {
+    let mut usage = ModuleUsage::default();
+    for payload in wp::Parser::new(0).parse_all(code) {
+        match payload? {
+            wp::Payload::ImportSection(reader) => {
+                for import in reader {
+                    match import?.ty {
+                        wp::TypeRef::Func(_) => usage.functions += 1,
+                        wp::TypeRef::Memory(ty) => {
+                            usage.memory_pages = usage.memory_pages.max(ty.initial)
+                        }
+                        _ => {}
+                    }
+                }
+            }
+            wp::Payload::MemorySection(reader) => {
+                for ty in reader {
+                    usage.memory_pages = usage.memory_pages.max(ty?.initial);
+                }
+            }
+            wp::Payload::DataSection(reader) => {
+                for data in reader {
+                    let data = data?;
+                    let wp::DataKind::Active { offset_expr, .. } = data.kind else { continue };
+                    // Offsets computed from imported globals are only known at
+                    // instantiation, and contracts cannot import globals.
+                    let wp::Operator::I32Const { value } =
+                        offset_expr.get_operators_reader().read()?
+                    else {
+                        continue;
+                    };
+                    let end = u64::from(value as u32) + data.data.len() as u64;
+                    usage.memory_pages = usage.memory_pages.max(end.div_ceil(WASM_PAGE_SIZE));
+                }
+            }
+            wp::Payload::CodeSectionStart { count, .. } => usage.functions += u64::from(count),
+            wp::Payload::CodeSectionEntry(func) => {
+                let size = func.range().len() as u64;
+                usage.largest_function_body = usage.largest_function_body.max(size);
+                for local in func.get_locals_reader()? {
+                    usage.locals += u64::from(local?.0);
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(usage)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_vm_config;
+    use crate::MockCompiledContractCache;
+
+    fn limits(warnings: &[LimitWarning]) -> Vec<ContractLimit> {
+        warnings.iter().map(|warning| warning.limit).collect()
+    }
+
+    #[test]
+    fn test_small_contract() {
+        let code = wat::parse_str(r#"(module (func (export "main")))"#).unwrap();
+        assert_eq!(limit_diagnostics(&code, &test_vm_config(), 10), vec![]);
+    }
+
+    #[test]
+    fn test_limits_close() {
+        let mut config = test_vm_config();
+        let code = wat::parse_str(
+            r#"(module
+  (import "env" "memory" (memory 1))
+  (data (i32.const 0x1fff0) "0123456789abcdef")
+  (func (local i64 i64 i64 i64 i64 i64 i64 i64 i64 i64))
+  (func (export "main")))"#,
+        )
+        .unwrap();
+        config.limit_config.max_contract_size = code.len() as u64 + 1;
+        config.limit_config.max_functions_number_per_contract = Some(2);
+        config.limit_config.max_locals_per_contract = Some(100);
+        config.limit_config.initial_memory_pages = 2;
+        let warnings = limit_diagnostics(&code, &config, 10);
+        assert_eq!(
+            limits(&warnings),
+            [ContractLimit::CodeSize, ContractLimit::Functions, ContractLimit::MemoryPages]
+        );
+        assert_eq!(warnings[1], LimitWarning { limit: ContractLimit::Functions, used: 2, max: 2 });
+        assert_eq!(warnings[2].used, 2);
+        // Locals are at 10% of the limit.
+        assert_eq!(limits(&limit_diagnostics(&code, &config, 90)).len(), 4);
+        assert_eq!(
+            limits(&limit_diagnostics(&code, &config, 0)),
+            [ContractLimit::Functions, ContractLimit::MemoryPages]
+        );
+
+        // The largest frame of the contract holds 10 locals.
+        let stack = crate::prepare::max_function_stack(&code).unwrap();
+        assert!(stack >= 80);
+        config.limit_config.max_stack_height = stack as u32;
+        assert!(limits(&limit_diagnostics(&code, &config, 0)).contains(&ContractLimit::StackHeight));
+        // Exceeded limits are errors, not warnings.
+        config.limit_config.max_stack_height = stack as u32 - 1;
+        config.limit_config.max_functions_number_per_contract = Some(1);
+        let exceeded = limits(&limit_diagnostics(&code, &config, 10));
+        assert!(!exceeded.contains(&ContractLimit::StackHeight));
+        assert!(!exceeded.contains(&ContractLimit::Functions));
+    }
+
+    #[test]
+    fn test_function_size_diagnostics() {
+        let mut config = test_vm_config();
+        config.limit_config.contract_prepare_version = crate::logic::ContractPrepareVersion::V2;
+        let max = 1000;
+        config.extra_limits.max_function_body_size = Some(max);
+        let nops = |size: u64| "nop ".repeat(size as usize - 2);
+        let code = wat::parse_str(format!(
+            r#"(module
+  (import "env" "input" (func (param i64)))
+  (func $small)
+  (func $giant {})
+  (func (export "main") {}))"#,
+            nops(max + 1),
+            nops(max - 10),
+        ))
+        .unwrap();
+        let functions = function_size_diagnostics(&code, &config);
+        assert_eq!(
+            functions,
+            [LargeFunction { index: 2, name: Some("giant".to_string()), size: max + 1, max }]
+        );
+        assert_eq!(
+            functions[0].to_string(),
+            format!(
+                "function 2 (giant) takes {} bytes, more than the {max} bytes a function may \
+                 take: split it into smaller functions, e.g. by inlining less into it",
+                max + 1
+            )
+        );
+        // Exceeded limits are errors, not warnings.
+        let warnings = limit_diagnostics(&code, &config, 10);
+        assert!(!limits(&warnings).contains(&ContractLimit::FunctionBodySize));
+        let close = format!(r#"(module (func (export "main") {}))"#, nops(max - 10));
+        let close = wat::parse_str(close).unwrap();
+        let warnings = limit_diagnostics(&close, &config, 10);
+        assert!(limits(&warnings).contains(&ContractLimit::FunctionBodySize));
+        assert_eq!(function_size_diagnostics(&close, &config), []);
+
+        config.limit_config.contract_prepare_version = crate::logic::ContractPrepareVersion::V1;
+        assert_eq!(function_size_diagnostics(&code, &config), []);
+    }
+
+    #[test]
+    fn test_precompile_with_diagnostics() {
+        let mut config = test_vm_config();
+        let code = ContractCode::new(
+            wat::parse_str(r#"(module (func (export "a")) (func (export "b")))"#).unwrap(),
+            None,
+        );
+        config.limit_config.max_functions_number_per_contract = Some(2);
+        let cache = MockCompiledContractCache::default();
+        let (result, warnings) =
+            precompile_contract_with_diagnostics(&code, &config, Some(&cache), 10);
+        assert!(matches!(result, Ok(Ok(_))));
+        assert_eq!(limits(&warnings), [ContractLimit::Functions]);
+
+        let invalid = ContractCode::new(b"\0asm\x01\0\0\0\x01".to_vec(), None);
+        let (result, warnings) =
+            precompile_contract_with_diagnostics(&invalid, &config, Some(&cache), 10);
+        assert!(matches!(result, Ok(Err(_))));
+        assert_eq!(warnings, vec![]);
+    }
+}
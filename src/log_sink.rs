@@ -0,0 +1,235 @@
+//! Streaming the logs of a call to the embedder as the contract emits them.
+//!
+//! By default the logs of a call are collected in [`VMOutcome::logs`] and
+//! only seen once the call is over.  With [`RunOptions::log_capture`] the
+//! embedder can have them passed to a [`LogSink`] as well, or instead, e.g.
+//! to show the logs of a long local simulation live without buffering them.
+//! [`BoundedLogSink`] hands them over to another thread, and
+//! [`BufferLogSink`] collects them like the outcome does, e.g. to gather the
+//! logs of several calls.
+//!
+//! The limits on the number and length of logs apply whatever the capture,
+//! so the gas and outcome of a call do not depend on it, except for the logs
+//! missing from the outcome with [`LogCapture::Stream`].
+//!
+//! [`VMOutcome::logs`]: crate::logic::VMOutcome::logs
+//! [`RunOptions::log_capture`]: crate::RunOptions::log_capture
+
+use std::collections::VecDeque;
+use std::fmt::Debug;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+/// Receives the logs of a call as the contract emits them.
+pub trait LogSink: Send + Sync + Debug {
+    /// Called with each log of the call, in order, while the contract runs.
+    ///
+    /// The contract waits for this to return, so slow sinks slow down the
+    /// call.
+    fn log(&self, message: &str);
+}
+
+/// Where the logs of a call go.
+#[derive(Clone, Debug, Default)]
+pub enum LogCapture {
+    /// In [`crate::logic::VMOutcome::logs`].
+    #[default]
+    Buffer,
+    /// To the sink and in the outcome.
+    Tee(Arc<dyn LogSink>),
+    /// To the sink only, the logs of the outcome stay empty.
+    Stream(Arc<dyn LogSink>),
+}
+
+impl LogCapture {
+    pub(crate) fn sink(&self) -> Option<&dyn LogSink> {
+        match self {
+            LogCapture::Buffer => None,
+            LogCapture::Tee(sink) | LogCapture::Stream(sink) => Some(&**sink),
+        }
+    }
+
+    pub(crate) fn buffers(&self) -> bool {
+        !matches!(self, LogCapture::Stream(_))
+    }
+}
+
+/// [`LogSink`] collecting the logs, in order.
+#[derive(Debug, Default)]
+pub struct BufferLogSink {
+    logs: Mutex<Vec<String>>,
+}
+
+impl BufferLogSink {
+    /// Takes the logs collected so far.
+    pub fn take(&self) -> Vec<String> {
+        std::mem::take(&mut *self.logs.lock().unwrap())
+    }
+}
+
+impl LogSink for BufferLogSink {
+    fn log(&self, message: &str) {
+        self.logs.lock().unwrap().push(message.to_string());
+    }
+}
+
+/// [`LogSink`] queueing the logs for another thread.
+///
+/// At most `capacity` logs are queued.  When the queue is full the call waits
+/// for the consumer, holding back the contract, but at most `max_wait` per
+/// log: logs which still do not fit are dropped and counted in
+/// [`Self::dropped`].
+#[derive(Debug)]
+pub struct BoundedLogSink {
+    capacity: usize,
+    max_wait: Duration,
+    queue: Mutex<VecDeque<String>>,
+    /// Notified when a log is queued or taken from the queue.
+    changed: Condvar,
+    dropped: AtomicU64,
+}
+
+impl BoundedLogSink {
+    pub fn new(capacity: usize, max_wait: Duration) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            max_wait,
+            queue: Mutex::new(VecDeque::new()),
+            changed: Condvar::new(),
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    /// Takes the next log, waiting for it at most `timeout`.
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<String> {
+        let queue = self.queue.lock().unwrap();
+        let (mut queue, _) =
+            self.changed.wait_timeout_while(queue, timeout, |queue| queue.is_empty()).unwrap();
+        let message = queue.pop_front();
+        if message.is_some() {
+            self.changed.notify_all();
+        }
+        message
+    }
+
+    /// Takes the logs queued.
+    pub fn drain(&self) -> Vec<String> {
+        let messages = self.queue.lock().unwrap().drain(..).collect();
+        self.changed.notify_all();
+        messages
+    }
+
+    /// Number of logs dropped because the queue stayed full.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl LogSink for BoundedLogSink {
+    fn log(&self, message: &str) {
+        let queue = self.queue.lock().unwrap();
+        let (mut queue, _) = self
+            .changed
+            .wait_timeout_while(queue, self.max_wait, |queue| queue.len() >= self.capacity)
+            .unwrap();
+        if queue.len() >= self.capacity {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        queue.push_back(message.to_string());
+        self.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+    use crate::{ContractCode, RunOptions};
+    use crate::logic::Config;
+    use unc_parameters::vm::VMKind;
+    use unc_parameters::RuntimeFeesConfig;
+
+    #[test]
+    fn test_bounded_sink() {
+        let sink = BoundedLogSink::new(2, Duration::ZERO);
+        sink.log("a");
+        sink.log("b");
+        sink.log("c");
+        assert_eq!(sink.dropped(), 1);
+        assert_eq!(sink.recv_timeout(Duration::ZERO).as_deref(), Some("a"));
+        sink.log("d");
+        assert_eq!(sink.drain(), ["b", "d"]);
+        assert_eq!(sink.recv_timeout(Duration::from_millis(1)), None);
+
+        // A full queue holds back the call until the consumer catches up.
+        let sink = Arc::new(BoundedLogSink::new(1, Duration::from_secs(60)));
+        let consumer = std::thread::spawn({
+            let sink = Arc::clone(&sink);
+            move || (0..100).map(|_| sink.recv_timeout(Duration::from_secs(60)).unwrap()).count()
+        });
+        for i in 0..100 {
+            sink.log(&i.to_string());
+        }
+        assert_eq!(consumer.join().unwrap(), 100);
+        assert_eq!(sink.dropped(), 0);
+    }
+
+    #[test]
+    fn test_log_capture() {
+        let code = wat::parse_str(
+            r#"
+(module
+  (import "env" "log_utf8" (func $log_utf8 (param i64 i64)))
+  (memory 1)
+  (data (i32.const 0) "helloworld")
+  (func (export "main")
+    (call $log_utf8 (i64.const 5) (i64.const 0))
+    (call $log_utf8 (i64.const 5) (i64.const 5)))
+)"#,
+        )
+        .unwrap();
+        let code = ContractCode::new(code, None);
+        let fees = RuntimeFeesConfig::test();
+        with_vm_variants(&test_vm_config(), |vm_kind: VMKind| {
+            let mut config = test_vm_config();
+            config.vm_kind = vm_kind;
+            let run = |log_capture| {
+                let options = RunOptions { log_capture, ..RunOptions::default() };
+                crate::run_with_options(
+                    &code,
+                    "main",
+                    &mut MockedExternal::new(),
+                    create_context(vec![]),
+                    &config,
+                    &fees,
+                    &[],
+                    None,
+                    &options,
+                )
+                .unwrap()
+            };
+            let buffered = run(LogCapture::Buffer);
+            assert_eq!(buffered.logs, ["hello", "world"]);
+
+            let sink = Arc::new(BoundedLogSink::new(10, Duration::ZERO));
+            let teed = run(LogCapture::Tee(sink.clone()));
+            assert_eq!(teed.logs, buffered.logs);
+            assert_eq!(sink.drain(), buffered.logs);
+
+            let streamed = run(LogCapture::Stream(sink.clone()));
+            assert!(streamed.logs.is_empty());
+            assert_eq!(sink.drain(), buffered.logs);
+            assert_eq!(streamed.burnt_gas, buffered.burnt_gas);
+
+            // The logs of several calls gathered in one buffer.
+            let sink = Arc::new(BufferLogSink::default());
+            run(LogCapture::Stream(sink.clone()));
+            run(LogCapture::Stream(sink.clone()));
+            assert_eq!(sink.take(), ["hello", "world", "hello", "world"]);
+            assert!(sink.take().is_empty());
+        });
+    }
+}
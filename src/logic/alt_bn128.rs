@@ -5,8 +5,9 @@ const BOOL_SIZE: usize = 1;
 const SCALAR_SIZE: usize = 256 / 8;
 const POINT_SIZE: usize = SCALAR_SIZE * 2;
 
-pub(super) struct InvalidInput {
-    pub(super) msg: String,
+#[derive(Debug)]
+pub(crate) struct InvalidInput {
+    pub(crate) msg: String,
 }
 
 impl InvalidInput {
@@ -22,7 +23,7 @@ impl From<InvalidInput> for VMLogicError {
     }
 }
 
-pub(super) fn split_elements<const ELEMENT_SIZE: usize>(
+pub(crate) fn split_elements<const ELEMENT_SIZE: usize>(
     data: &[u8],
 ) -> Result<&[[u8; ELEMENT_SIZE]], InvalidInput> {
     stdx::as_chunks_exact(data).map_err(|e| InvalidInput { msg: e.to_string() })
@@ -30,27 +31,31 @@ pub(super) fn split_elements<const ELEMENT_SIZE: usize>(
 
 const G1_MULTIEXP_ELEMENT_SIZE: usize = POINT_SIZE + SCALAR_SIZE;
 
-pub(super) fn g1_multiexp(
+pub(crate) fn g1_multiexp(
     elements: &[[u8; G1_MULTIEXP_ELEMENT_SIZE]],
 ) -> Result<[u8; POINT_SIZE], InvalidInput> {
-    let elements: Vec<(bn::G1, bn::Fr)> = elements
+    multiexp(elements, decode_g1).map(encode_g1)
+}
+
+/// \sum_i fr_i * g_i of the elements `(g, fr)`, their points decoded with
+/// `decode_point`.
+fn multiexp<G: Group, const ELEMENT_SIZE: usize, const POINT: usize>(
+    elements: &[[u8; ELEMENT_SIZE]],
+    decode_point: fn(&[u8; POINT]) -> Result<G, InvalidInput>,
+) -> Result<G, InvalidInput> {
+    let elements: Vec<(G, bn::Fr)> = elements
         .iter()
         .map(|chunk| {
-            let (g1, fr) = stdx::split_array(chunk);
-            let g1 = decode_g1(g1)?;
-            let fr = decode_fr(fr)?;
-            Ok((g1, fr))
+            let (point, fr) = stdx::split_array::<ELEMENT_SIZE, POINT, SCALAR_SIZE>(chunk);
+            Ok((decode_point(point)?, decode_fr(fr)?))
         })
         .collect::<Result<Vec<_>, InvalidInput>>()?;
-
-    let res = bn::G1::multiexp(&elements);
-
-    Ok(encode_g1(res))
+    Ok(G::multiexp(&elements))
 }
 
 const G1_SUM_ELEMENT_SIZE: usize = BOOL_SIZE + POINT_SIZE;
 
-pub(super) fn g1_sum(
+pub(crate) fn g1_sum(
     elements: &[[u8; G1_SUM_ELEMENT_SIZE]],
 ) -> Result<[u8; POINT_SIZE], InvalidInput> {
     let elements: Vec<(bool, bn::G1)> = {
@@ -72,9 +77,9 @@ pub(super) fn g1_sum(
     Ok(encode_g1(res))
 }
 
-const PAIRING_CHECK_ELEMENT_SIZE: usize = POINT_SIZE + POINT_SIZE * 2;
+pub(crate) const PAIRING_CHECK_ELEMENT_SIZE: usize = POINT_SIZE + POINT_SIZE * 2;
 
-pub(super) fn pairing_check(
+pub(crate) fn pairing_check(
     elements: &[[u8; PAIRING_CHECK_ELEMENT_SIZE]],
 ) -> Result<bool, InvalidInput> {
     let elements: Vec<(bn::G1, bn::G2)> = elements
@@ -92,7 +97,37 @@ pub(super) fn pairing_check(
     Ok(res)
 }
 
-fn encode_g1(val: bn::G1) -> [u8; POINT_SIZE] {
+/// Splits the input of a batch of pairing checks: each check is the number of
+/// its elements as a little-endian `u32`, followed by the elements.
+pub(super) fn split_pairing_checks(
+    data: &[u8],
+) -> Result<Vec<&[[u8; PAIRING_CHECK_ELEMENT_SIZE]]>, InvalidInput> {
+    let mut checks = Vec::new();
+    let mut rest = data;
+    while !rest.is_empty() {
+        let Some((len, tail)) = rest.split_first_chunk::<4>() else {
+            return Err(InvalidInput::new("truncated pairing check length", rest));
+        };
+        let len = (u32::from_le_bytes(*len) as usize).saturating_mul(PAIRING_CHECK_ELEMENT_SIZE);
+        if tail.len() < len {
+            return Err(InvalidInput { msg: "truncated pairing check".to_string() });
+        }
+        let (check, tail) = tail.split_at(len);
+        checks.push(split_elements(check)?);
+        rest = tail;
+    }
+    Ok(checks)
+}
+
+const G2_MULTIEXP_ELEMENT_SIZE: usize = POINT_SIZE * 2 + SCALAR_SIZE;
+
+pub(crate) fn g2_multiexp(
+    elements: &[[u8; G2_MULTIEXP_ELEMENT_SIZE]],
+) -> Result<[u8; POINT_SIZE * 2], InvalidInput> {
+    multiexp(elements, decode_g2).map(encode_g2)
+}
+
+pub(crate) fn encode_g1(val: bn::G1) -> [u8; POINT_SIZE] {
     let (x, y) = bn::AffineG1::from_jacobian(val)
         .map(|p| (p.x(), p.y()))
         .unwrap_or_else(|| (bn::Fq::zero(), bn::Fq::zero()));
@@ -101,6 +136,19 @@ fn encode_g1(val: bn::G1) -> [u8; POINT_SIZE] {
     stdx::join_array(x, y)
 }
 
+pub(crate) fn encode_g2(val: bn::G2) -> [u8; 2 * POINT_SIZE] {
+    let (x, y) = bn::AffineG2::from_jacobian(val)
+        .map(|p| (p.x(), p.y()))
+        .unwrap_or_else(|| (bn::Fq2::zero(), bn::Fq2::zero()));
+    let x = encode_fq2(x);
+    let y = encode_fq2(y);
+    stdx::join_array(x, y)
+}
+
+fn encode_fq2(val: bn::Fq2) -> [u8; 2 * SCALAR_SIZE] {
+    stdx::join_array(encode_fq(val.real()), encode_fq(val.imaginary()))
+}
+
 fn encode_fq(val: bn::Fq) -> [u8; SCALAR_SIZE] {
     encode_u256(val.into_u256())
 }
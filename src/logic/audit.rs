@@ -0,0 +1,436 @@
+//! Opt-in audit trail of contract executions.
+//!
+//! Operators with compliance requirements may need to keep a record of which
+//! account executed which method, with what deposit and which storage keys it
+//! touched.  [`AuditingExternal`] wraps the [`External`] of an execution and
+//! observes its storage accesses; once the execution is over
+//! [`AuditingExternal::finish`] turns them into an [`AuditRecord`] which is
+//! handed to an [`AuditSink`].
+//!
+//! Keys and values are passed through the configured [`AuditRedaction`]
+//! before being recorded, so that the log only contains as much of the state
+//! as the operator is allowed to keep.  By default keys are recorded as is
+//! and values are left out entirely.
+
+use super::dependencies::{External, Result, ValuePtr};
+use super::types::ReceiptIndex;
+use super::{TrieNodesCount, VMContext, VMLogicError};
+use std::cell::RefCell;
+use unc_crypto::PublicKey;
+use unc_parameters::vm::StorageGetMode;
+use unc_primitives_core::hash::CryptoHash;
+use unc_primitives_core::serialize::{dec_format, to_base64};
+use unc_primitives_core::types::{AccountId, Balance, BlockHeight, Gas, GasWeight, Nonce, Power};
+
+/// How a piece of data is written to the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Redaction {
+    /// Record the data itself, base64 encoded.
+    Plain,
+    /// Record the sha256 hash of the data.
+    Hash,
+    /// Record the length of the data only.
+    Length,
+    /// Do not record anything.
+    Omit,
+}
+
+/// Data after [`Redaction`].
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RedactedBytes {
+    Base64(String),
+    Sha256(CryptoHash),
+    Length(usize),
+}
+
+impl Redaction {
+    pub fn apply(self, data: &[u8]) -> Option<RedactedBytes> {
+        match self {
+            Redaction::Plain => Some(RedactedBytes::Base64(to_base64(data))),
+            Redaction::Hash => Some(RedactedBytes::Sha256(CryptoHash::hash_bytes(data))),
+            Redaction::Length => Some(RedactedBytes::Length(data.len())),
+            Redaction::Omit => None,
+        }
+    }
+}
+
+/// Redaction applied to the storage keys and values of the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AuditRedaction {
+    pub keys: Redaction,
+    pub values: Redaction,
+}
+
+impl Default for AuditRedaction {
+    fn default() -> Self {
+        Self { keys: Redaction::Plain, values: Redaction::Omit }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageOperation {
+    Read,
+    Write,
+    Remove,
+    RemoveSubtree,
+    HasKey,
+}
+
+/// A single storage access of the contract, in execution order.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct StorageAccess {
+    pub operation: StorageOperation,
+    /// The key, or the prefix for [`StorageOperation::RemoveSubtree`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key: Option<RedactedBytes>,
+    /// The value written, or read if the key was present.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<RedactedBytes>,
+}
+
+/// Audit log entry of a single contract execution.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct AuditRecord {
+    pub block_height: BlockHeight,
+    pub account_id: AccountId,
+    pub predecessor_account_id: AccountId,
+    pub signer_account_id: AccountId,
+    pub method_name: String,
+    #[serde(with = "dec_format")]
+    pub attached_deposit: Balance,
+    pub storage: Vec<StorageAccess>,
+}
+
+/// Destination of the audit records, e.g. an append-only file or a database.
+pub trait AuditSink {
+    fn record(&mut self, record: AuditRecord);
+}
+
+impl AuditSink for Vec<AuditRecord> {
+    fn record(&mut self, record: AuditRecord) {
+        self.push(record);
+    }
+}
+
+/// [`External`] which records the storage accesses going through it.
+pub struct AuditingExternal<'a> {
+    inner: &'a mut dyn External,
+    redaction: AuditRedaction,
+    // `External::storage_get` takes `&self`.
+    accesses: RefCell<Vec<StorageAccess>>,
+}
+
+impl<'a> AuditingExternal<'a> {
+    pub fn new(inner: &'a mut dyn External, redaction: AuditRedaction) -> Self {
+        Self { inner, redaction, accesses: RefCell::new(Vec::new()) }
+    }
+
+    fn observe(&self, operation: StorageOperation, key: &[u8], value: Option<&[u8]>) {
+        let key = self.redaction.keys.apply(key);
+        let value = value.and_then(|value| self.redaction.values.apply(value));
+        self.accesses.borrow_mut().push(StorageAccess { operation, key, value });
+    }
+
+    /// Makes the audit record of the execution of `method_name` in `context`
+    /// and passes it to `sink`.
+    pub fn finish(self, context: &VMContext, method_name: &str, sink: &mut dyn AuditSink) {
+        sink.record(AuditRecord {
+            block_height: context.block_height,
+            account_id: context.current_account_id.clone(),
+            predecessor_account_id: context.predecessor_account_id.clone(),
+            signer_account_id: context.signer_account_id.clone(),
+            method_name: method_name.to_string(),
+            attached_deposit: context.attached_deposit,
+            storage: self.accesses.into_inner(),
+        });
+    }
+}
+
+impl External for AuditingExternal<'_> {
+    fn storage_set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.observe(StorageOperation::Write, key, Some(value));
+        self.inner.storage_set(key, value)
+    }
+
+    fn storage_get<'b>(
+        &'b self,
+        key: &[u8],
+        mode: StorageGetMode,
+    ) -> Result<Option<Box<dyn ValuePtr + 'b>>> {
+        let ptr = self.inner.storage_get(key, mode)?;
+        let value = match (&ptr, self.redaction.values) {
+            (None, _) | (Some(_), Redaction::Omit) => None,
+            (Some(ptr), Redaction::Length) => Some(RedactedBytes::Length(ptr.len() as usize)),
+            (Some(ptr), redaction) => redaction.apply(&ptr.deref()?),
+        };
+        let key = self.redaction.keys.apply(key);
+        self.accesses.borrow_mut().push(StorageAccess {
+            operation: StorageOperation::Read,
+            key,
+            value,
+        });
+        Ok(ptr)
+    }
+
+    fn storage_remove(&mut self, key: &[u8]) -> Result<()> {
+        self.observe(StorageOperation::Remove, key, None);
+        self.inner.storage_remove(key)
+    }
+
+    fn storage_remove_subtree(&mut self, prefix: &[u8]) -> Result<()> {
+        self.observe(StorageOperation::RemoveSubtree, prefix, None);
+        self.inner.storage_remove_subtree(prefix)
+    }
+
+    fn storage_has_key(&mut self, key: &[u8], mode: StorageGetMode) -> Result<bool> {
+        self.observe(StorageOperation::HasKey, key, None);
+        self.inner.storage_has_key(key, mode)
+    }
+
+    fn storage_proof(&self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.inner.storage_proof(key)
+    }
+
+    fn state_changed(&self) -> bool {
+        self.inner.state_changed()
+    }
+
+    fn generate_data_id(&mut self) -> CryptoHash {
+        self.inner.generate_data_id()
+    }
+
+    fn get_trie_nodes_count(&self) -> TrieNodesCount {
+        self.inner.get_trie_nodes_count()
+    }
+
+    fn validator_frozen(&self, account_id: &AccountId) -> Result<Option<Balance>> {
+        self.inner.validator_frozen(account_id)
+    }
+
+    fn validator_power(&self, account_id: &AccountId) -> Result<Option<Power>> {
+        self.inner.validator_power(account_id)
+    }
+
+    fn validator_total_frozen(&self) -> Result<Balance> {
+        self.inner.validator_total_frozen()
+    }
+
+    fn validator_total_power(&self) -> Result<Power> {
+        self.inner.validator_total_power()
+    }
+
+    fn account_code_hash(&self, account_id: &AccountId) -> Result<Option<CryptoHash>> {
+        self.inner.account_code_hash(account_id)
+    }
+
+    fn create_receipt(
+        &mut self,
+        receipt_indices: Vec<ReceiptIndex>,
+        receiver_id: AccountId,
+    ) -> Result<ReceiptIndex, VMLogicError> {
+        self.inner.create_receipt(receipt_indices, receiver_id)
+    }
+
+    fn append_action_create_account(
+        &mut self,
+        receipt_index: ReceiptIndex,
+    ) -> Result<(), VMLogicError> {
+        self.inner.append_action_create_account(receipt_index)
+    }
+
+    fn append_action_deploy_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code: Vec<u8>,
+    ) -> Result<(), VMLogicError> {
+        self.inner.append_action_deploy_contract(receipt_index, code)
+    }
+
+    fn append_action_deploy_global_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code_hash: CryptoHash,
+    ) -> Result<(), VMLogicError> {
+        self.inner.append_action_deploy_global_contract(receipt_index, code_hash)
+    }
+
+    fn append_action_function_call_weight(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        method_name: Vec<u8>,
+        args: Vec<u8>,
+        attached_deposit: Balance,
+        prepaid_gas: Gas,
+        gas_weight: GasWeight,
+    ) -> Result<(), VMLogicError> {
+        self.inner.append_action_function_call_weight(
+            receipt_index,
+            method_name,
+            args,
+            attached_deposit,
+            prepaid_gas,
+            gas_weight,
+        )
+    }
+
+    fn append_action_transfer(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        deposit: Balance,
+    ) -> Result<(), VMLogicError> {
+        self.inner.append_action_transfer(receipt_index, deposit)
+    }
+
+    fn append_action_stake(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        stake: Balance,
+        public_key: PublicKey,
+    ) {
+        self.inner.append_action_stake(receipt_index, stake, public_key)
+    }
+
+    fn append_action_add_key_with_full_access(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+    ) {
+        self.inner.append_action_add_key_with_full_access(receipt_index, public_key, nonce)
+    }
+
+    fn append_action_add_key_with_function_call(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+        allowance: Option<Balance>,
+        receiver_id: AccountId,
+        method_names: Vec<Vec<u8>>,
+    ) -> Result<(), VMLogicError> {
+        self.inner.append_action_add_key_with_function_call(
+            receipt_index,
+            public_key,
+            nonce,
+            allowance,
+            receiver_id,
+            method_names,
+        )
+    }
+
+    fn append_action_delete_key(&mut self, receipt_index: ReceiptIndex, public_key: PublicKey) {
+        self.inner.append_action_delete_key(receipt_index, public_key)
+    }
+
+    fn append_action_delete_account(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        beneficiary_id: AccountId,
+    ) -> Result<(), VMLogicError> {
+        self.inner.append_action_delete_account(receipt_index, beneficiary_id)
+    }
+
+    fn scratch_get(&self) -> Result<Option<Vec<u8>>> {
+        self.inner.scratch_get()
+    }
+
+    fn append_scratch(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        data: Vec<u8>,
+    ) -> Result<(), VMLogicError> {
+        self.inner.append_scratch(receipt_index, data)
+    }
+
+    fn get_receipt_receiver(&self, receipt_index: ReceiptIndex) -> &AccountId {
+        self.inner.get_receipt_receiver(receipt_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_context::get_context;
+    use crate::logic::mocks::mock_external::MockedExternal;
+
+    fn audit(redaction: AuditRedaction) -> AuditRecord {
+        let mut ext = MockedExternal::new();
+        let mut audited = AuditingExternal::new(&mut ext, redaction);
+        audited.storage_set(b"key", b"value").unwrap();
+        audited.storage_get(b"key", StorageGetMode::Trie).unwrap();
+        audited.storage_get(b"missing", StorageGetMode::Trie).unwrap();
+        audited.storage_remove(b"key").unwrap();
+        let mut sink = Vec::new();
+        audited.finish(&get_context(), "main", &mut sink);
+        assert_eq!(sink.len(), 1);
+        sink.pop().unwrap()
+    }
+
+    #[test]
+    fn test_external_storage() {
+        let mut ext = MockedExternal::new();
+        let mut audited = AuditingExternal::new(&mut ext, AuditRedaction::default());
+        crate::logic::test_utils::test_external_storage(&mut audited);
+    }
+
+    #[test]
+    fn test_default_redaction() {
+        let record = audit(AuditRedaction::default());
+        expect_test::expect![[r#"
+            {
+              "block_height": 10,
+              "account_id": "alice.near",
+              "predecessor_account_id": "carol.near",
+              "signer_account_id": "bob.near",
+              "method_name": "main",
+              "attached_deposit": "10",
+              "storage": [
+                {
+                  "operation": "write",
+                  "key": {
+                    "base64": "a2V5"
+                  }
+                },
+                {
+                  "operation": "read",
+                  "key": {
+                    "base64": "a2V5"
+                  }
+                },
+                {
+                  "operation": "read",
+                  "key": {
+                    "base64": "bWlzc2luZw=="
+                  }
+                },
+                {
+                  "operation": "remove",
+                  "key": {
+                    "base64": "a2V5"
+                  }
+                }
+              ]
+            }"#]]
+        .assert_eq(&serde_json::to_string_pretty(&record).unwrap());
+    }
+
+    #[test]
+    fn test_values_redaction() {
+        let record = audit(AuditRedaction { keys: Redaction::Omit, values: Redaction::Length });
+        let values: Vec<_> = record.storage.iter().map(|access| access.value.clone()).collect();
+        assert_eq!(
+            values,
+            [Some(RedactedBytes::Length(5)), Some(RedactedBytes::Length(5)), None, None]
+        );
+        assert!(record.storage.iter().all(|access| access.key.is_none()));
+
+        let record = audit(AuditRedaction { keys: Redaction::Hash, values: Redaction::Plain });
+        assert_eq!(
+            record.storage[0].key,
+            Some(RedactedBytes::Sha256(CryptoHash::hash_bytes(b"key")))
+        );
+        assert_eq!(record.storage[1].value, Some(RedactedBytes::Base64("dmFsdWU=".to_string())));
+    }
+}
@@ -0,0 +1,248 @@
+//! BLS12-381 arithmetic of the `bls12381_*` host functions, computed by
+//! `blst`.
+//!
+//! Points are encoded like those of the `alt_bn128_*` functions: coordinates
+//! as little-endian integers of 48 bytes, elements of Fp2 as their real part
+//! followed by their imaginary part, and the point at infinity as zeros.
+//! `blst` reads and writes the big-endian encodings of the ZCash format, so
+//! the points are converted from and to its affine points coordinate by
+//! coordinate.
+//!
+//! The map to G2 is the one of the suite `BLS12381G2_XMD:SHA-256_SSWU_RO_` of
+//! RFC 9380: the sum of the maps of the two field elements hashed from a
+//! message is its `hash_to_curve`.
+
+use super::{HostError, VMLogicError};
+use blst::{
+    blst_final_exp, blst_fp, blst_fp12, blst_fp12_is_one, blst_fp12_mul, blst_fp12_one, blst_fp2,
+    blst_fp_from_lendian, blst_lendian_from_fp, blst_map_to_g2, blst_miller_loop, blst_p1,
+    blst_p1_add_or_double, blst_p1_affine, blst_p1_affine_in_g1, blst_p1_affine_is_inf,
+    blst_p1_affine_on_curve, blst_p1_cneg, blst_p1_from_affine, blst_p1_to_affine, blst_p2,
+    blst_p2_add_or_double, blst_p2_affine, blst_p2_affine_in_g2, blst_p2_affine_is_inf,
+    blst_p2_affine_on_curve, blst_p2_cneg, blst_p2_from_affine, blst_p2_to_affine,
+};
+
+const BOOL_SIZE: usize = 1;
+const FP_SIZE: usize = 384 / 8;
+const FP2_SIZE: usize = FP_SIZE * 2;
+const G1_SIZE: usize = FP_SIZE * 2;
+const G2_SIZE: usize = FP2_SIZE * 2;
+
+#[derive(Debug)]
+pub(crate) struct InvalidInput {
+    pub(crate) msg: String,
+}
+
+impl InvalidInput {
+    fn new(msg: &str, bad_value: &[u8]) -> InvalidInput {
+        let msg = format!("{msg}: {bad_value:X?}");
+        InvalidInput { msg }
+    }
+}
+
+impl From<InvalidInput> for VMLogicError {
+    fn from(err: InvalidInput) -> Self {
+        HostError::Bls12381InvalidInput { msg: err.msg }.into()
+    }
+}
+
+pub(crate) fn split_elements<const ELEMENT_SIZE: usize>(
+    data: &[u8],
+) -> Result<&[[u8; ELEMENT_SIZE]], InvalidInput> {
+    stdx::as_chunks_exact(data).map_err(|e| InvalidInput { msg: e.to_string() })
+}
+
+const G1_SUM_ELEMENT_SIZE: usize = BOOL_SIZE + G1_SIZE;
+
+/// \sum_i (-1)^{sign_i} g_i of the elements `(sign, g)` of G1.
+pub(crate) fn g1_sum(
+    elements: &[[u8; G1_SUM_ELEMENT_SIZE]],
+) -> Result<[u8; G1_SIZE], InvalidInput> {
+    let mut acc = blst_p1::default();
+    let acc_ptr: *mut blst_p1 = &mut acc;
+    for chunk in elements {
+        let (sign, point) = stdx::split_array::<G1_SUM_ELEMENT_SIZE, BOOL_SIZE, G1_SIZE>(chunk);
+        let sign = decode_bool(sign)?;
+        let mut point = g1_from_affine(&decode_g1(point)?);
+        // SAFETY: the pointers are to valid points, `acc_ptr` is both an
+        // input and the output, which `blst` allows.
+        unsafe {
+            blst_p1_cneg(&mut point, sign);
+            blst_p1_add_or_double(acc_ptr, acc_ptr, &point);
+        }
+    }
+    Ok(encode_g1(&acc))
+}
+
+const G2_SUM_ELEMENT_SIZE: usize = BOOL_SIZE + G2_SIZE;
+
+/// \sum_i (-1)^{sign_i} g_i of the elements `(sign, g)` of G2.
+pub(crate) fn g2_sum(
+    elements: &[[u8; G2_SUM_ELEMENT_SIZE]],
+) -> Result<[u8; G2_SIZE], InvalidInput> {
+    let mut acc = blst_p2::default();
+    let acc_ptr: *mut blst_p2 = &mut acc;
+    for chunk in elements {
+        let (sign, point) = stdx::split_array::<G2_SUM_ELEMENT_SIZE, BOOL_SIZE, G2_SIZE>(chunk);
+        let sign = decode_bool(sign)?;
+        let mut point = g2_from_affine(&decode_g2(point)?);
+        // SAFETY: as in `g1_sum`.
+        unsafe {
+            blst_p2_cneg(&mut point, sign);
+            blst_p2_add_or_double(acc_ptr, acc_ptr, &point);
+        }
+    }
+    Ok(encode_g2(&acc))
+}
+
+pub(crate) const PAIRING_CHECK_ELEMENT_SIZE: usize = G1_SIZE + G2_SIZE;
+
+/// Whether \prod_i e(g_{1 i}, g_{2 i}) is one, the points checked to be in
+/// their subgroups.
+pub(crate) fn pairing_check(
+    elements: &[[u8; PAIRING_CHECK_ELEMENT_SIZE]],
+) -> Result<bool, InvalidInput> {
+    // SAFETY: `blst_fp12_one` points to a constant.
+    let mut f: blst_fp12 = unsafe { *blst_fp12_one() };
+    let f_ptr: *mut blst_fp12 = &mut f;
+    for chunk in elements {
+        let (g1, g2) = stdx::split_array(chunk);
+        let g1 = decode_g1(g1)?;
+        // SAFETY: the pointers are to valid points.
+        if !unsafe { blst_p1_affine_in_g1(&g1) } {
+            return Err(InvalidInput::new("g1 not in the subgroup", chunk));
+        }
+        let g2 = decode_g2(g2)?;
+        // SAFETY: as above.
+        if !unsafe { blst_p2_affine_in_g2(&g2) } {
+            return Err(InvalidInput::new("g2 not in the subgroup", chunk));
+        }
+        // Pairs with a point at infinity pair to one.
+        // SAFETY: as above.
+        if unsafe { blst_p1_affine_is_inf(&g1) || blst_p2_affine_is_inf(&g2) } {
+            continue;
+        }
+        let mut pair = blst_fp12::default();
+        // SAFETY: as above, `f_ptr` is both an input and the output of the
+        // product, which `blst` allows.
+        unsafe {
+            blst_miller_loop(&mut pair, &g2, &g1);
+            blst_fp12_mul(f_ptr, f_ptr, &pair);
+        }
+    }
+    let mut res = blst_fp12::default();
+    // SAFETY: the pointers are to valid elements.
+    unsafe {
+        blst_final_exp(&mut res, &f);
+        Ok(blst_fp12_is_one(&res))
+    }
+}
+
+/// The points of G2 the Fp2 elements map to, concatenated.
+pub(crate) fn map_to_curve(elements: &[[u8; FP2_SIZE]]) -> Result<Vec<u8>, InvalidInput> {
+    let mut res = Vec::with_capacity(elements.len() * G2_SIZE);
+    for chunk in elements {
+        let u = decode_fp2(chunk)?;
+        let mut point = blst_p2::default();
+        // SAFETY: the pointers are to valid elements, and a null `v` maps
+        // `u` alone, clearing the cofactor of its map.
+        unsafe { blst_map_to_g2(&mut point, &u, std::ptr::null()) };
+        res.extend(encode_g2(&point));
+    }
+    Ok(res)
+}
+
+fn g1_from_affine(point: &blst_p1_affine) -> blst_p1 {
+    let mut res = blst_p1::default();
+    // SAFETY: the pointers are to valid points.
+    unsafe { blst_p1_from_affine(&mut res, point) };
+    res
+}
+
+fn g2_from_affine(point: &blst_p2_affine) -> blst_p2 {
+    let mut res = blst_p2::default();
+    // SAFETY: the pointers are to valid points.
+    unsafe { blst_p2_from_affine(&mut res, point) };
+    res
+}
+
+/// The affine coordinates of `point`, zeros for the point at infinity.
+fn encode_g1(point: &blst_p1) -> [u8; G1_SIZE] {
+    let mut affine = blst_p1_affine::default();
+    // SAFETY: the pointers are to valid points.
+    unsafe { blst_p1_to_affine(&mut affine, point) };
+    stdx::join_array(encode_fp(&affine.x), encode_fp(&affine.y))
+}
+
+/// The affine coordinates of `point`, zeros for the point at infinity.
+fn encode_g2(point: &blst_p2) -> [u8; G2_SIZE] {
+    let mut affine = blst_p2_affine::default();
+    // SAFETY: the pointers are to valid points.
+    unsafe { blst_p2_to_affine(&mut affine, point) };
+    stdx::join_array(encode_fp2(&affine.x), encode_fp2(&affine.y))
+}
+
+fn encode_fp2(val: &blst_fp2) -> [u8; FP2_SIZE] {
+    stdx::join_array(encode_fp(&val.fp[0]), encode_fp(&val.fp[1]))
+}
+
+fn encode_fp(val: &blst_fp) -> [u8; FP_SIZE] {
+    let mut res = [0; FP_SIZE];
+    // SAFETY: `res` has the 48 bytes `blst` writes.
+    unsafe { blst_lendian_from_fp(res.as_mut_ptr(), val) };
+    res
+}
+
+/// The point of G1 of the coordinates `raw`, not checked to be in the
+/// subgroup.
+fn decode_g1(raw: &[u8; G1_SIZE]) -> Result<blst_p1_affine, InvalidInput> {
+    let (x, y) = stdx::split_array(raw);
+    let point = blst_p1_affine { x: decode_fp(x)?, y: decode_fp(y)? };
+    // SAFETY: the pointers are to valid points.  The point at infinity, all
+    // zeros, is on the curve for `blst`.
+    if unsafe { blst_p1_affine_on_curve(&point) } {
+        Ok(point)
+    } else {
+        Err(InvalidInput::new("invalid g1", raw))
+    }
+}
+
+/// The point of G2 of the coordinates `raw`, not checked to be in the
+/// subgroup.
+fn decode_g2(raw: &[u8; G2_SIZE]) -> Result<blst_p2_affine, InvalidInput> {
+    let (x, y) = stdx::split_array(raw);
+    let point = blst_p2_affine { x: decode_fp2(x)?, y: decode_fp2(y)? };
+    // SAFETY: as in `decode_g1`.
+    if unsafe { blst_p2_affine_on_curve(&point) } {
+        Ok(point)
+    } else {
+        Err(InvalidInput::new("invalid g2", raw))
+    }
+}
+
+fn decode_fp2(raw: &[u8; FP2_SIZE]) -> Result<blst_fp2, InvalidInput> {
+    let (c0, c1) = stdx::split_array(raw);
+    Ok(blst_fp2 { fp: [decode_fp(c0)?, decode_fp(c1)?] })
+}
+
+/// The canonical little-endian `raw`, which must be below the modulus.
+fn decode_fp(raw: &[u8; FP_SIZE]) -> Result<blst_fp, InvalidInput> {
+    let mut res = blst_fp::default();
+    // SAFETY: `raw` has the 48 bytes `blst` reads.
+    unsafe { blst_fp_from_lendian(&mut res, raw.as_ptr()) };
+    // `blst` reduces the integer modulo the modulus, so only the canonical
+    // ones encode back to themselves.
+    if encode_fp(&res) == *raw {
+        Ok(res)
+    } else {
+        Err(InvalidInput::new("invalid fp", raw))
+    }
+}
+
+fn decode_bool(raw: &[u8; BOOL_SIZE]) -> Result<bool, InvalidInput> {
+    match raw {
+        [0] => Ok(false),
+        [1] => Ok(true),
+        _ => Err(InvalidInput::new("invalid bool", raw)),
+    }
+}
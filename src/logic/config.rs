@@ -0,0 +1,379 @@
+//! The config of the VM.
+//!
+//! The parameters of the protocol come from `unc-parameters`, per protocol
+//! version.  Some features of this crate change which contracts are valid or
+//! how much gas calls burn, but have no parameter there yet, so [`Config`]
+//! adds them to the config of `unc-parameters`, which it dereferences to.
+//!
+//! Like those of `unc-parameters`, these parameters are part of the protocol:
+//! every node must run a call with the same values to agree on its outcome.
+//! A config converted from the one of `unc-parameters` with [`From`] has the
+//! defaults, which keep the behaviour of the contracts and calls from before
+//! the features; the protocol version stabilizing a feature is the one to
+//! change its parameter.
+
+use crate::prepare::{OpcodeBlocklist, PreparePasses};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::ops::{Deref, DerefMut};
+use unc_parameters::{ExtCosts, ExtCostsConfig};
+use unc_primitives_core::types::Gas;
+
+/// The config of `unc-parameters` with the parameters of the features of
+/// this crate, see the module documentation.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct Config {
+    /// The config of `unc-parameters` for the protocol version.
+    pub base: unc_parameters::vm::Config,
+
+    /// Accept contracts importing `env.memory` when the standardized memory
+    /// satisfies the import, instead of rejecting them at preparation with
+    /// `PrepareError::Memory`.
+    ///
+    /// Contracts never get to define their own memory: whatever memory a
+    /// module declares is replaced by the `env.memory` import of the
+    /// standardized memory described by the config, which every backend
+    /// provides when instantiating the contract, and so is an accepted import.
+    /// The policy is applied by the preparation rather than left to the
+    /// linker of each backend so that all of them agree on which contracts
+    /// are valid.  Contracts prepared with V0 keep the legacy behaviour of
+    /// that version.
+    pub host_imported_memory: bool,
+
+    /// Charge for decoding odd length UTF-16 logs before rejecting them, like
+    /// any other invalid UTF-16.
+    pub log_decoding_cost: bool,
+
+    /// Accept the fixed-width SIMD proposal in contracts prepared with V2.
+    /// The singlepass compilers of NearVM and Wasmer2 do not generate code
+    /// for it yet and fail to compile such contracts.
+    pub simd: bool,
+
+    /// Accept the bulk memory and reference types proposals, which the
+    /// toolchains emit by default, in contracts prepared with V2.  Their
+    /// copies, fills and growths pay for their length on top of the cost of
+    /// the instruction, see `prepare::aggregate_gas`.
+    pub bulk_memory_reftypes: bool,
+
+    /// Canonicalize the NaNs of contracts prepared with V2 by a pass of the
+    /// preparation rather than by the compilers of the backends.
+    pub nan_canonicalization_pass: bool,
+
+    /// Keep the storage writes of a call until it succeeds and apply them to
+    /// the `External` at once, in the order of the keys.  The writes of a
+    /// failed call never reach the `External`, and reads of keys written by
+    /// the call do not either.  Such reads touch no trie nodes, so the call
+    /// burns less gas than without buffering when the `External` charges for
+    /// reading back its own writes.
+    pub buffer_storage_writes: bool,
+
+    /// Prepare the contracts of V2 with these passes instead, the V3
+    /// preparation, see `prepare_contract_with_passes`.
+    pub prepare_passes: Option<PreparePasses>,
+
+    /// Fail the calls of methods named by more than `max_length_method_name`
+    /// bytes with `MethodResolveError::MethodNameTooLong` before loading the
+    /// contract, rather than looking them up in its exports.
+    pub limit_method_name_length: bool,
+
+    /// Make the finite-wasm instrumentation the only limit on the stack of
+    /// contracts prepared with V2, failing the calls exhausting it with
+    /// `WasmTrap::StackOverflow` on every backend.
+    ///
+    /// The instrumentation accounts the frame and operand stack of each
+    /// activation, sized by the same configuration on every backend, against
+    /// `max_stack_height`.  Without this, exhausting the accounted stack fails
+    /// with `HostError::MemoryAccessViolation` where the host does the
+    /// accounting, while NearVM compiles it into the contract.  With it calls
+    /// trap at the same depth with the same error whatever the backend,
+    /// provided the thread running them has a native stack large enough for
+    /// `max_stack_height`.  Wasmer2 still checks its native stack against
+    /// `wasmer2_stack_limit`, as a guard against overflowing the stack of the
+    /// thread which calls do not reach first.  V0 and V1 keep their pwasm
+    /// stack limiter and the limits of the backends.
+    pub deterministic_stack_limit: bool,
+
+    /// Refunds of less gas are burnt instead, as in `VMOutcome::burnt_gas`,
+    /// sparing the runtime a refund receipt worth less than it costs.  The
+    /// dust is only burnt when the call can still burn it under its limit.
+    pub min_refund_gas: Gas,
+
+    /// Instructions the preparation rejects in every prepare version, see
+    /// [`OpcodeBlocklist`].
+    pub opcode_blocklist: OpcodeBlocklist,
+
+    /// Limits which the `limit_config` of `unc-parameters` does not have.
+    pub extra_limits: ExtraLimitConfig,
+
+    /// Costs which the `ext_costs` of `unc-parameters` does not have.
+    pub extra_ext_costs: ExtraExtCostsConfig,
+
+    /// Link the host functions of the `env_experimental` import module and
+    /// accept the contracts prepared with V2 importing them.  They are
+    /// trialled on test networks and can change or go away, so this is never
+    /// set on mainnet configs.
+    pub experimental_host_fns: bool,
+
+    /// Accept the `RunOptions::consensus` hooks of the embedder, which change
+    /// the gas of calls, instead of failing the calls given them with
+    /// `VMRunnerError::ConsensusHooksDisabled`.  Only set by the networks
+    /// whose every node runs the calls with the same hooks.
+    pub consensus_hooks: bool,
+
+    /// Instrument the contracts prepared with V2 to count the executions of
+    /// their blocks, see `coverage_map`.  No parameter of the protocol:
+    /// `run_with_options` sets it for the calls given `RunOptions::coverage`
+    /// counters, and it is part of the hash as it changes the prepared code.
+    /// The other preparations, and builds without the `coverage` feature,
+    /// fail with `PrepareError::UnsupportedPasses` instead.
+    pub(crate) coverage: bool,
+}
+
+/// Limits of the contracts and calls of a config, in addition to its
+/// `limit_config`.
+///
+/// The default sets none of them, since each rejects contracts which were
+/// accepted before.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct ExtraLimitConfig {
+    /// Bytes the body of a function of a contract prepared with V2 may take
+    /// as encoded, its locals included.
+    pub max_function_body_size: Option<u64>,
+    /// Targets a `br_table` of a contract prepared with V2 may have, its
+    /// default target not included.
+    pub max_br_table_targets: Option<u32>,
+    /// Blocks, loops and ifs which may enclose one another in a function of a
+    /// contract prepared with V2.
+    pub max_nesting_depth: Option<u32>,
+    /// Operations the preparation of a contract with V2 may take per byte of
+    /// `max_contract_size`, see `PrepareBudget`.
+    pub max_prepare_operations_per_contract_byte: Option<u64>,
+    /// Bytes of memory a call may use, failing with
+    /// `FunctionCallError::MemoryCapExceeded` once over.
+    ///
+    /// Counts the linear memory of the contract and the registers, logs and
+    /// receipts the host keeps for it, as reported in `VMOutcome::used_memory`.
+    /// The memory is measured when the contract calls a host function, so a
+    /// contract growing its memory over the cap fails at its next host call.
+    /// Without a cap the memory is not measured; `Some(u64::MAX)` measures it
+    /// without limiting it.
+    pub memory_cap: Option<u64>,
+}
+
+/// Costs of the host functions of this crate which have none in the
+/// `ext_costs` of `unc-parameters`, in gas.
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub struct ExtraExtCostsConfig {
+    /// Charged by `alt_bn128_g2_multiexp` before reading its input.
+    pub alt_bn128_g2_multiexp_base: Gas,
+    /// Charged by `alt_bn128_g2_multiexp` for each element of its input.
+    pub alt_bn128_g2_multiexp_element: Gas,
+    /// Charged by `alt_bn128_pairing_check_batch` before reading its input,
+    /// the checks it computes then costing what `alt_bn128_pairing_check`
+    /// charges for them.
+    pub alt_bn128_pairing_check_batch_base: Gas,
+    /// Charged by `ed25519_verify_batch` before reading its input.
+    pub ed25519_verify_batch_base: Gas,
+    /// Charged by `ed25519_verify_batch` for each signature it verifies, on
+    /// top of the `ed25519_verify_byte` of its message.
+    pub ed25519_verify_batch_element: Gas,
+    /// Charged by `u256_muldiv` for its 512-bit product and division.
+    pub u256_muldiv_base: Gas,
+    /// Charged by `u256_pow` before reading its base.
+    pub u256_pow_base: Gas,
+    /// Charged by `u256_pow` for each bit of its exponent, up to its highest
+    /// one set.
+    pub u256_pow_bit: Gas,
+    /// Charged by the log and panic host functions for each byte of a
+    /// message they decode, valid or not, on top of the `utf8_decoding_byte`
+    /// or `utf16_decoding_byte` of `ext_costs`.
+    pub log_decoding_byte: Gas,
+    /// Charged by the log host functions for each byte of a message they
+    /// emit, on top of the `log_byte` of `ext_costs`.
+    pub log_emit_byte: Gas,
+    /// Charged by `bls12381_g1_sum` before reading its input.
+    pub bls12381_g1_sum_base: Gas,
+    /// Charged by `bls12381_g1_sum` for each element of its input.
+    pub bls12381_g1_sum_element: Gas,
+    /// Charged by `bls12381_g2_sum` before reading its input.
+    pub bls12381_g2_sum_base: Gas,
+    /// Charged by `bls12381_g2_sum` for each element of its input.
+    pub bls12381_g2_sum_element: Gas,
+    /// Charged by `bls12381_pairing_check` before reading its input.
+    pub bls12381_pairing_check_base: Gas,
+    /// Charged by `bls12381_pairing_check` for each element of its input.
+    pub bls12381_pairing_check_element: Gas,
+    /// Charged by `bls12381_map_to_curve` before reading its input.
+    pub bls12381_map_to_curve_base: Gas,
+    /// Charged by `bls12381_map_to_curve` for each element of its input.
+    pub bls12381_map_to_curve_element: Gas,
+}
+
+impl ExtraExtCostsConfig {
+    /// The costs of the host functions before they had their own, from
+    /// `ext_costs`: a G2 multiexp costs a G1 multiexp with three times its
+    /// element cost, a batch of pairing checks or of ed25519 signatures the
+    /// `base` of host functions, and each signature of a batch what
+    /// `ed25519_verify` charges for one.  The `u256_*` functions have no such
+    /// cost to keep: a muldiv and a pow cost that `base`, and each bit of an
+    /// exponent twice that for its squaring and multiplication.  Logs cost
+    /// nothing more to decode and emit than the costs of `ext_costs`.
+    ///
+    /// The `bls12381_*` functions have not been measured yet: until they are,
+    /// their costs are placeholders, multiples of the costs of their
+    /// `alt_bn128_*` counterparts.  The base of a sum is twenty times that of an
+    /// alt_bn128 one, each element of a G2 sum twice one of a G1 sum, and each
+    /// element of a pairing check twice one of an alt_bn128 check.  Mapping
+    /// an element to G2 costs an alt_bn128 pairing element, with the base of
+    /// an alt_bn128 sum.  The protocol feature of the functions is not to be
+    /// stabilized with these costs.
+    pub fn new(ext_costs: &ExtCostsConfig) -> Self {
+        let sum_base = ext_costs.gas_cost(ExtCosts::alt_bn128_g1_sum_base).saturating_mul(20);
+        let sum_element = ext_costs.gas_cost(ExtCosts::alt_bn128_g1_sum_element);
+        let pairing_element = ext_costs.gas_cost(ExtCosts::alt_bn128_pairing_check_element);
+        Self {
+            alt_bn128_g2_multiexp_base: ext_costs.gas_cost(ExtCosts::alt_bn128_g1_multiexp_base),
+            alt_bn128_g2_multiexp_element: ext_costs
+                .gas_cost(ExtCosts::alt_bn128_g1_multiexp_element)
+                .saturating_mul(3),
+            alt_bn128_pairing_check_batch_base: ext_costs.gas_cost(ExtCosts::base),
+            ed25519_verify_batch_base: ext_costs.gas_cost(ExtCosts::base),
+            ed25519_verify_batch_element: ext_costs.gas_cost(ExtCosts::ed25519_verify_base),
+            u256_muldiv_base: ext_costs.gas_cost(ExtCosts::base),
+            u256_pow_base: ext_costs.gas_cost(ExtCosts::base),
+            u256_pow_bit: ext_costs.gas_cost(ExtCosts::base).saturating_mul(2),
+            log_decoding_byte: 0,
+            log_emit_byte: 0,
+            bls12381_g1_sum_base: sum_base,
+            bls12381_g1_sum_element: sum_element,
+            bls12381_g2_sum_base: sum_base,
+            bls12381_g2_sum_element: sum_element.saturating_mul(2),
+            bls12381_pairing_check_base: ext_costs.gas_cost(ExtCosts::alt_bn128_pairing_check_base),
+            bls12381_pairing_check_element: pairing_element.saturating_mul(2),
+            bls12381_map_to_curve_base: ext_costs.gas_cost(ExtCosts::alt_bn128_g1_sum_base),
+            bls12381_map_to_curve_element: pairing_element,
+        }
+    }
+
+    /// Every cost zero.
+    pub fn free() -> Self {
+        Self {
+            alt_bn128_g2_multiexp_base: 0,
+            alt_bn128_g2_multiexp_element: 0,
+            alt_bn128_pairing_check_batch_base: 0,
+            ed25519_verify_batch_base: 0,
+            ed25519_verify_batch_element: 0,
+            u256_muldiv_base: 0,
+            u256_pow_base: 0,
+            u256_pow_bit: 0,
+            log_decoding_byte: 0,
+            log_emit_byte: 0,
+            bls12381_g1_sum_base: 0,
+            bls12381_g1_sum_element: 0,
+            bls12381_g2_sum_base: 0,
+            bls12381_g2_sum_element: 0,
+            bls12381_pairing_check_base: 0,
+            bls12381_pairing_check_element: 0,
+            bls12381_map_to_curve_base: 0,
+            bls12381_map_to_curve_element: 0,
+        }
+    }
+}
+
+impl From<unc_parameters::vm::Config> for Config {
+    fn from(base: unc_parameters::vm::Config) -> Self {
+        let extra_ext_costs = ExtraExtCostsConfig::new(&base.ext_costs);
+        Self {
+            base,
+            host_imported_memory: false,
+            log_decoding_cost: false,
+            simd: false,
+            bulk_memory_reftypes: false,
+            nan_canonicalization_pass: false,
+            buffer_storage_writes: false,
+            prepare_passes: None,
+            limit_method_name_length: false,
+            deterministic_stack_limit: false,
+            min_refund_gas: 0,
+            opcode_blocklist: OpcodeBlocklist::default(),
+            extra_limits: ExtraLimitConfig::default(),
+            extra_ext_costs,
+            experimental_host_fns: false,
+            consensus_hooks: false,
+            coverage: false,
+        }
+    }
+}
+
+impl Deref for Config {
+    type Target = unc_parameters::vm::Config;
+
+    fn deref(&self) -> &Self::Target {
+        &self.base
+    }
+}
+
+impl DerefMut for Config {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.base
+    }
+}
+
+impl Config {
+    /// Computes non-cryptographically-proof hash. The computation is fast but not cryptographically
+    /// secure.
+    ///
+    /// The hash of a config with the default parameters is that of its
+    /// config of `unc-parameters`, so the contracts compiled before a
+    /// parameter was added keep their keys in the compiled contract cache.
+    pub fn non_crypto_hash(&self) -> u64 {
+        if self.compiles_as_base() {
+            return self.base.non_crypto_hash();
+        }
+        let mut s = DefaultHasher::new();
+        self.hash(&mut s);
+        s.finish()
+    }
+
+    /// Whether the contracts compile with this config as with its config of
+    /// `unc-parameters`: the parameters of this crate are those of [`From`].
+    pub(crate) fn compiles_as_base(&self) -> bool {
+        self.has_default_parameters()
+    }
+
+    /// Same as the `make_free` of `unc-parameters`, the costs of
+    /// `extra_ext_costs` included.
+    pub fn make_free(&mut self) {
+        self.base.make_free();
+        self.extra_ext_costs = ExtraExtCostsConfig::free();
+    }
+
+    /// Whether the parameters of this crate are those of [`From`].
+    fn has_default_parameters(&self) -> bool {
+        *self == Self::from(self.base.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::tests::test_vm_config;
+
+    #[test]
+    fn test_non_crypto_hash() {
+        let mut config = test_vm_config();
+        assert_eq!(config.non_crypto_hash(), config.base.non_crypto_hash());
+        config.host_imported_memory = true;
+        assert_ne!(config.non_crypto_hash(), config.base.non_crypto_hash());
+        config.host_imported_memory = false;
+        config.extra_limits.max_function_body_size = Some(1);
+        assert_ne!(config.non_crypto_hash(), config.base.non_crypto_hash());
+        config.extra_limits.max_function_body_size = None;
+        config.extra_ext_costs.alt_bn128_g2_multiexp_element += 1;
+        assert_ne!(config.non_crypto_hash(), config.base.non_crypto_hash());
+        config.extra_ext_costs = super::ExtraExtCostsConfig::new(&config.ext_costs);
+        config.extra_ext_costs.ed25519_verify_batch_base += 1;
+        assert_ne!(config.non_crypto_hash(), config.base.non_crypto_hash());
+        config.extra_ext_costs = super::ExtraExtCostsConfig::new(&config.ext_costs);
+        assert_eq!(config.non_crypto_hash(), config.base.non_crypto_hash());
+    }
+}
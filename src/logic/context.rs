@@ -1,11 +1,23 @@
 use super::types::PublicKey;
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde_with::base64::Base64;
+use serde_with::serde_as;
 use unc_primitives_core::config::ViewConfig;
+use unc_primitives_core::serialize::dec_format;
 use unc_primitives_core::types::{
     AccountId, Balance, BlockHeight, EpochHeight, Gas, StorageUsage,
 };
 
-#[derive(Clone)]
 /// Context for the contract execution.
+///
+/// The serde format is stable: fields are only appended, with a default, so
+/// that recorded contexts can be replayed later.  The borsh format is not:
+/// it changes with the fields, so borsh bytes are only read back by the
+/// version of this crate which wrote them.
+#[serde_as]
+#[derive(
+    Clone, Debug, PartialEq, BorshSerialize, BorshDeserialize, serde::Serialize, serde::Deserialize,
+)]
 pub struct VMContext {
     /// The account id of the current contract that we are executing.
     pub current_account_id: AccountId,
@@ -14,6 +26,7 @@ pub struct VMContext {
     pub signer_account_id: AccountId,
     /// The public key that was used to sign the original transaction that led to
     /// this execution.
+    #[serde_as(as = "Base64")]
     pub signer_account_pk: PublicKey,
     /// If this execution is the result of cross-contract call or a callback then
     /// predecessor is the account that called it.
@@ -22,6 +35,7 @@ pub struct VMContext {
     pub predecessor_account_id: AccountId,
     /// The input to the contract call.
     /// Encoded as base64 string to be able to pass input in borsh binary format.
+    #[serde_as(as = "Base64")]
     pub input: Vec<u8>,
     /// The current block height.
     pub block_height: BlockHeight,
@@ -32,21 +46,29 @@ pub struct VMContext {
 
     /// The balance attached to the given account. Excludes the `attached_deposit` that was
     /// attached to the transaction.
+    #[serde(with = "dec_format")]
     pub account_balance: Balance,
     /// The balance of locked tokens on the given account.
+    #[serde(with = "dec_format")]
     pub account_locked_balance: Balance,
     /// The account's storage usage before the contract execution
     pub storage_usage: StorageUsage,
     /// The balance that was attached to the call that will be immediately deposited before the
     /// contract execution starts.
+    #[serde(with = "dec_format")]
     pub attached_deposit: Balance,
     /// The gas attached to the call that can be used to pay for the gas fees.
     pub prepaid_gas: Gas,
     /// Initial seed for randomness
+    #[serde_as(as = "Base64")]
     pub random_seed: Vec<u8>,
     /// If Some, it means that execution is made in a view mode and defines its configuration.
     /// View mode means that only read-only operations are allowed.
     /// See <https://nomicon.io/Proposals/0018-view-change-method.html> for more details.
+    #[borsh(
+        serialize_with = "borsh_view_config::serialize",
+        deserialize_with = "borsh_view_config::deserialize"
+    )]
     pub view_config: Option<ViewConfig>,
     /// How many `DataReceipt`'s should receive this execution result. This should be empty if
     /// this function call is a part of a batch and it is not the last action.
@@ -58,3 +80,24 @@ impl VMContext {
         self.view_config.is_some()
     }
 }
+
+/// Borsh encoding of [`VMContext::view_config`], which has no borsh impls.
+mod borsh_view_config {
+    use borsh::{BorshDeserialize, BorshSerialize};
+    use unc_primitives_core::config::ViewConfig;
+    use unc_primitives_core::types::Gas;
+
+    pub(super) fn serialize<W: std::io::Write>(
+        view_config: &Option<ViewConfig>,
+        writer: &mut W,
+    ) -> std::io::Result<()> {
+        view_config.as_ref().map(|config| config.max_gas_burnt).serialize(writer)
+    }
+
+    pub(super) fn deserialize<R: std::io::Read>(
+        reader: &mut R,
+    ) -> std::io::Result<Option<ViewConfig>> {
+        let max_gas_burnt = Option::<Gas>::deserialize_reader(reader)?;
+        Ok(max_gas_burnt.map(|max_gas_burnt| ViewConfig { max_gas_burnt }))
+    }
+}
@@ -0,0 +1,348 @@
+//! Host functions added by the embedder, see [`CustomHostFunctions`].
+//!
+//! Sidechains and test frameworks give their contracts imports of their own
+//! without forking the runner: they register them in a
+//! [`CustomHostFunctionRegistry`] passed with
+//! [`crate::ConsensusHooks::custom_host_functions`], to the calls of a config
+//! enabling `consensus_hooks`.  Calls without a registry, which includes all
+//! the calls of the chain, only link the host functions of
+//! [`super::host_functions`].
+//!
+//! Custom functions live in the `env` module next to the built-in ones and
+//! only take and return `i64`s.  Every call pays `base` and the gas of the
+//! function before it runs, then the memory and registers the function
+//! accesses through its [`CustomHostContext`], like a built-in function
+//! would.  The functions are linked when the contract is instantiated, so
+//! the compiled artifacts and their cache keys do not depend on the registry.
+//!
+//! NearVM, Wasmer2 and Wasmtime link custom functions.  Wasmer0, kept for
+//! the old protocol versions, does not: contracts importing them fail to
+//! link on it.
+
+use super::gas_counter::GasCounter;
+use super::host_functions::HOST_FUNCTIONS;
+use super::logic::Result;
+use super::vmstate::{Memory, Registers};
+use super::MemSlice;
+use std::sync::Arc;
+use unc_parameters::vm::LimitConfig;
+use unc_primitives_core::types::Gas;
+
+/// Most parameters a custom host function may take.
+pub const MAX_CUSTOM_PARAMS: usize = 8;
+
+/// Signature and gas of a function of [`CustomHostFunctions`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CustomHostFunction {
+    /// Import name in the `env` module.
+    pub name: &'static str,
+    /// Number of `i64` parameters, at most [`MAX_CUSTOM_PARAMS`].
+    pub params: usize,
+    /// Whether the function returns an `i64`.
+    pub returns: bool,
+    /// Gas burnt by every call on top of `base`, before the function runs.
+    pub gas: Gas,
+}
+
+/// Host functions of an embedder, see the module documentation.
+///
+/// The functions must behave the same on every node running the contracts,
+/// as their results and gas are part of the outcome.
+pub trait CustomHostFunctions: Send + Sync + std::fmt::Debug {
+    /// The functions, in the order of the `index` of [`Self::call`].
+    fn functions(&self) -> Vec<CustomHostFunction>;
+
+    /// Runs the function `index` of [`Self::functions`] with `args`, one per
+    /// parameter.
+    ///
+    /// The result of the functions which do not return one is ignored.  An
+    /// error aborts the call as the errors of the built-in functions do.
+    fn call(
+        &self,
+        index: usize,
+        context: &mut CustomHostContext<'_, '_>,
+        args: &[u64],
+    ) -> Result<u64>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum CustomHostFunctionError {
+    #[error("host function {0} is already defined")]
+    AlreadyDefined(&'static str),
+    #[error("host function {0} takes more than {MAX_CUSTOM_PARAMS} parameters")]
+    TooManyParams(&'static str),
+}
+
+#[derive(Clone, Debug)]
+struct Registered {
+    function: CustomHostFunction,
+    provider: Arc<dyn CustomHostFunctions>,
+    /// Index of the function in the `functions` of its provider.
+    index: usize,
+}
+
+/// The custom host functions linked for a call, see the module
+/// documentation.
+#[derive(Clone, Debug, Default)]
+pub struct CustomHostFunctionRegistry {
+    functions: Vec<Registered>,
+}
+
+impl CustomHostFunctionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds the functions of `provider`.
+    ///
+    /// Fails without adding any if one of them has the name of a built-in
+    /// host function, even one unavailable at the current protocol version,
+    /// or of a function registered before.
+    pub fn register(
+        &mut self,
+        provider: Arc<dyn CustomHostFunctions>,
+    ) -> std::result::Result<(), CustomHostFunctionError> {
+        let functions = provider.functions();
+        for (index, function) in functions.iter().enumerate() {
+            let name = function.name;
+            if matches!(name, "memory" | "gas")
+                || HOST_FUNCTIONS.get("env", name).is_some()
+                || self.get("env", name).is_some()
+                || functions[..index].iter().any(|other| other.name == name)
+            {
+                return Err(CustomHostFunctionError::AlreadyDefined(name));
+            }
+            if function.params > MAX_CUSTOM_PARAMS {
+                return Err(CustomHostFunctionError::TooManyParams(name));
+            }
+        }
+        self.functions.extend(
+            functions.into_iter().enumerate().map(|(index, function)| Registered {
+                function,
+                provider: provider.clone(),
+                index,
+            }),
+        );
+        Ok(())
+    }
+
+    /// The registered functions, in the order they have been registered.
+    pub fn functions(&self) -> impl Iterator<Item = &CustomHostFunction> {
+        self.functions.iter().map(|registered| &registered.function)
+    }
+
+    /// The function `name` of `module` with its index in the registry.
+    pub(crate) fn get(&self, module: &str, name: &str) -> Option<(usize, &CustomHostFunction)> {
+        if module != "env" {
+            return None;
+        }
+        self.functions().enumerate().find(|(_, function)| function.name == name)
+    }
+
+    pub(super) fn function(&self, index: usize) -> &CustomHostFunction {
+        &self.functions[index].function
+    }
+
+    pub(super) fn call(
+        &self,
+        index: usize,
+        context: &mut CustomHostContext<'_, '_>,
+        args: &[u64],
+    ) -> Result<u64> {
+        let registered = &self.functions[index];
+        registered.provider.call(registered.index, context, args)
+    }
+}
+
+/// The parts of the call a custom host function can access, charged like
+/// the built-in functions charge them.
+pub struct CustomHostContext<'l, 'a> {
+    pub(super) gas_counter: &'l mut GasCounter,
+    pub(super) memory: &'l mut Memory<'a>,
+    pub(super) registers: &'l mut Registers,
+    pub(super) limit_config: &'l LimitConfig,
+}
+
+impl CustomHostContext<'_, '_> {
+    /// Burns `gas` on top of the gas of the function, e.g. a price depending
+    /// on the arguments.
+    pub fn burn_gas(&mut self, gas: Gas) -> Result<()> {
+        self.gas_counter.burn_gas(gas)
+    }
+
+    /// Reads `len` bytes of the guest memory at `ptr`.
+    ///
+    /// # Cost
+    ///
+    /// `read_memory_base + read_memory_byte * len`
+    pub fn read_memory(&mut self, ptr: u64, len: u64) -> Result<Vec<u8>> {
+        Ok(self.memory.view(self.gas_counter, MemSlice { ptr, len })?.into_owned())
+    }
+
+    /// Writes `data` to the guest memory at `ptr`.
+    ///
+    /// # Cost
+    ///
+    /// `write_memory_base + write_memory_byte * data.len()`
+    pub fn write_memory(&mut self, ptr: u64, data: &[u8]) -> Result<()> {
+        self.memory.set(self.gas_counter, ptr, data)
+    }
+
+    /// Reads the register `register_id`, failing with
+    /// [`super::HostError::InvalidRegisterId`] if it is not set.
+    ///
+    /// # Cost
+    ///
+    /// `read_register_base + read_register_byte * num_bytes`
+    pub fn read_register(&mut self, register_id: u64) -> Result<Vec<u8>> {
+        Ok(self.registers.get(self.gas_counter, register_id)?.to_vec())
+    }
+
+    /// Sets the register `register_id` to `data`, within the limits of the
+    /// registers of the config.
+    ///
+    /// # Cost
+    ///
+    /// `write_register_base + write_register_byte * data.len()`
+    pub fn write_register(&mut self, register_id: u64, data: &[u8]) -> Result<()> {
+        self.registers.set(self.gas_counter, self.limit_config, register_id, data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::logic::{FunctionCallError, ReturnData, VMLogicError};
+    use crate::runner::{ConsensusHooks, RunOptions};
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+    use crate::ContractCode;
+    use crate::logic::Config;
+    use unc_parameters::vm::VMKind;
+    use unc_parameters::RuntimeFeesConfig;
+
+    const DOUBLE_GAS: Gas = 1_000_000;
+
+    #[derive(Debug)]
+    struct Doubler(Vec<CustomHostFunction>);
+
+    impl Doubler {
+        fn new() -> Self {
+            Self::with_gas(DOUBLE_GAS)
+        }
+
+        fn with_gas(gas: Gas) -> Self {
+            Self(vec![CustomHostFunction { name: "double", params: 1, returns: true, gas }])
+        }
+    }
+
+    impl CustomHostFunctions for Doubler {
+        fn functions(&self) -> Vec<CustomHostFunction> {
+            self.0.clone()
+        }
+
+        fn call(
+            &self,
+            _index: usize,
+            _context: &mut CustomHostContext<'_, '_>,
+            args: &[u64],
+        ) -> Result<u64, VMLogicError> {
+            Ok(args[0] * 2)
+        }
+    }
+
+    const CONTRACT: &str = r#"
+(module
+  (import "env" "double" (func $double (param i64) (result i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (memory 1)
+  (func (export "main")
+    (i64.store (i32.const 0) (call $double (i64.const 21)))
+    (call $value_return (i64.const 8) (i64.const 0))))"#;
+
+    fn function(name: &'static str, params: usize) -> CustomHostFunction {
+        CustomHostFunction { name, params, returns: false, gas: 0 }
+    }
+
+    #[test]
+    fn test_register() {
+        let mut registry = CustomHostFunctionRegistry::new();
+        registry.register(Arc::new(Doubler::new())).unwrap();
+        assert_eq!(
+            registry.register(Arc::new(Doubler::new())),
+            Err(CustomHostFunctionError::AlreadyDefined("double"))
+        );
+        for name in ["sha256", "memory", "gas"] {
+            assert_eq!(
+                registry.register(Arc::new(Doubler(vec![function(name, 0)]))),
+                Err(CustomHostFunctionError::AlreadyDefined(name))
+            );
+        }
+        let duplicated = Doubler(vec![function("twice", 0), function("twice", 1)]);
+        assert_eq!(
+            registry.register(Arc::new(duplicated)),
+            Err(CustomHostFunctionError::AlreadyDefined("twice"))
+        );
+        let wide = Doubler(vec![function("fine", 0), function("wide", MAX_CUSTOM_PARAMS + 1)]);
+        assert_eq!(
+            registry.register(Arc::new(wide)),
+            Err(CustomHostFunctionError::TooManyParams("wide"))
+        );
+        // The failed registrations added nothing.
+        assert_eq!(
+            registry.functions().map(|function| function.name).collect::<Vec<_>>(),
+            ["double"]
+        );
+        assert_eq!(registry.get("env", "double").map(|(index, _)| index), Some(0));
+        assert!(registry.get("env_experimental", "double").is_none());
+    }
+
+    #[test]
+    fn test_custom_host_function() {
+        let code = ContractCode::new(wat::parse_str(CONTRACT).unwrap(), None);
+        let config = test_vm_config();
+        let fees = RuntimeFeesConfig::test();
+        let registry = |gas| {
+            let mut registry = CustomHostFunctionRegistry::new();
+            registry.register(Arc::new(Doubler::with_gas(gas))).unwrap();
+            Some(Arc::new(registry))
+        };
+        with_vm_variants(&config, |vm_kind| {
+            if vm_kind == VMKind::Wasmer0 {
+                return;
+            }
+            let mut config = config.clone();
+            config.vm_kind = vm_kind;
+            config.consensus_hooks = true;
+            let run = |custom_host_functions| {
+                let consensus =
+                    ConsensusHooks { custom_host_functions, ..ConsensusHooks::default() };
+                let options = RunOptions { consensus, ..RunOptions::default() };
+                crate::run_with_options(
+                    &code,
+                    "main",
+                    &mut MockedExternal::new(),
+                    create_context(vec![]),
+                    &config,
+                    &fees,
+                    &[],
+                    None,
+                    &options,
+                )
+                .unwrap()
+            };
+            let outcome = run(registry(DOUBLE_GAS));
+            assert_eq!(outcome.aborted, None, "{vm_kind:?}");
+            assert_eq!(outcome.return_data, ReturnData::Value(42u64.to_le_bytes().to_vec()));
+            let free = run(registry(0));
+            assert_eq!(outcome.burnt_gas - free.burnt_gas, DOUBLE_GAS, "{vm_kind:?}");
+
+            // The default options keep the registry closed.
+            let outcome = run(None);
+            assert!(
+                matches!(outcome.aborted, Some(FunctionCallError::LinkError { .. })),
+                "{vm_kind:?}"
+            );
+        });
+    }
+}
@@ -2,6 +2,7 @@
 
 use super::types::ReceiptIndex;
 use super::TrieNodesCount;
+use super::errors::InconsistentStateError;
 use super::VMLogicError;
 use unc_crypto::PublicKey;
 use unc_parameters::vm::StorageGetMode;
@@ -99,6 +100,17 @@ pub trait MemoryLike {
     /// Returns error if the memory interval isn’t completely inside the smart
     /// contract memory.
     fn write_memory(&mut self, offset: u64, buffer: &[u8]) -> Result<(), ()>;
+
+    /// Current size of the smart contract memory in bytes.
+    ///
+    /// Only called from the host functions, while the contract runs, with a
+    /// [`crate::logic::ExtraLimitConfig::memory_cap`].  The default
+    /// implementation reports no memory, leaving the linear memory out of the
+    /// cap, so that the implementations written before this method keep
+    /// compiling.
+    fn data_size(&self) -> u64 {
+        0
+    }
 }
 
 pub type Result<T, E = VMLogicError> = ::std::result::Result<T, E>;
@@ -118,6 +130,21 @@ pub trait ValuePtr {
 }
 
 /// An external blockchain interface for the Runtime logic
+///
+/// # Consistency
+///
+/// The storage host functions return what the storage methods return, so
+/// implementations must agree on them for calls to have the same outcome:
+///
+/// * reads see the writes and removals made before them, with both
+///   [`StorageGetMode`]s;
+/// * a key set to an empty value is present;
+/// * [`External::storage_remove_subtree`] removes the keys written before it,
+///   not the ones written after;
+/// * the [`ValuePtr::len`] of a value is the length of its
+///   [`ValuePtr::deref`].
+///
+/// [`crate::logic::test_utils::test_external_storage`] checks these rules.
 pub trait External {
     /// Write `value` to the `key` of the storage trie associated with the current account.
     ///
@@ -239,6 +266,26 @@ pub trait External {
     /// ```
     fn storage_has_key(&mut self, key: &[u8], mode: StorageGetMode) -> Result<bool>;
 
+    /// Returns the serialized trie nodes and value proving the value of `key`, or its absence,
+    /// in the state the current call started from.
+    ///
+    /// Only called to record a [`crate::logic::StateWitness`], once per key touched by the call
+    /// and before the call accesses it.  Reading the proof must not count as touching trie nodes
+    /// in [`External::get_trie_nodes_count`], as it is not part of the call.
+    ///
+    /// The default implementation proves nothing, so that the witness only lists the keys.
+    fn storage_proof(&self, _key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        Ok(Vec::new())
+    }
+
+    /// Whether the state this external reads differs from the state of the block named by the
+    /// caller, e.g. because it includes changes not yet in a block.
+    ///
+    /// The outcomes of view calls on such a state are not cached by a
+    /// [`crate::ViewCallCache`].  There is no default implementation, as only the implementation
+    /// knows where its state comes from.
+    fn state_changed(&self) -> bool;
+
     fn generate_data_id(&mut self) -> CryptoHash;
 
     /// Returns amount of touched trie nodes by storage operations
@@ -253,6 +300,16 @@ pub trait External {
     fn validator_total_frozen(&self) -> Result<Balance>;
     fn validator_total_power(&self) -> Result<Power>;
 
+    /// Returns the hash of the contract deployed on `account_id`, as the state of the current
+    /// call sees it.  Returns `None` if the account does not exist or has no contract.
+    ///
+    /// The default implementation fails the call with
+    /// [`InconsistentStateError::ExternalMethodNotImplemented`], so that implementations
+    /// written before this method keep compiling.
+    fn account_code_hash(&self, _account_id: &AccountId) -> Result<Option<CryptoHash>> {
+        Err(not_implemented("account_code_hash"))
+    }
+
     /// Create a receipt which will be executed after all the receipts identified by
     /// `receipt_indices` are complete.
     ///
@@ -300,6 +357,30 @@ pub trait External {
         code: Vec<u8>,
     ) -> Result<(), VMLogicError>;
 
+    /// Attach an action deploying the global contract of hash `code_hash` to an existing
+    /// receipt.  The code itself is not part of the receipt: the receiver account refers to the
+    /// global contract, which the runtime looks up by its hash.
+    ///
+    /// # Arguments
+    ///
+    /// * `receipt_index` - an index of Receipt to append an action
+    /// * `code_hash` - the hash of the global contract to deploy
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `receipt_index` does not refer to a known receipt.
+    ///
+    /// The default implementation fails the call with
+    /// [`InconsistentStateError::ExternalMethodNotImplemented`], as for
+    /// [`External::account_code_hash`].
+    fn append_action_deploy_global_contract(
+        &mut self,
+        _receipt_index: ReceiptIndex,
+        _code_hash: CryptoHash,
+    ) -> Result<(), VMLogicError> {
+        Err(not_implemented("append_action_deploy_global_contract"))
+    }
+
     /// Attach the [`FunctionCallAction`] action to an existing receipt.
     ///
     /// `prepaid_gas` and `gas_weight` can either be specified or both. If a `gas_weight` is
@@ -437,8 +518,45 @@ pub trait External {
         beneficiary_id: AccountId,
     ) -> Result<(), VMLogicError>;
 
+    /// Returns the scratch area carried into the current execution by the incoming receipt, if
+    /// any.
+    ///
+    /// # Example
+    /// ```
+    /// # use unc_vm_runner::logic::mocks::mock_external::MockedExternal;
+    /// # use unc_vm_runner::logic::External;
+    ///
+    /// # let mut external = MockedExternal::new();
+    /// assert_eq!(external.scratch_get(), Ok(None));
+    /// external.scratch = Some(b"page 2".to_vec());
+    /// assert_eq!(external.scratch_get(), Ok(Some(b"page 2".to_vec())));
+    /// ```
+    fn scratch_get(&self) -> Result<Option<Vec<u8>>>;
+
+    /// Attach the scratch area to an existing receipt so that it is carried into the execution of
+    /// that receipt.
+    ///
+    /// # Arguments
+    ///
+    /// * `receipt_index` - an index of Receipt to attach the scratch area to
+    /// * `data` - the content of the scratch area
+    ///
+    /// # Panics
+    ///
+    /// Panics if the `receipt_index` does not refer to a known receipt.
+    fn append_scratch(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        data: Vec<u8>,
+    ) -> Result<(), VMLogicError>;
+
     /// # Panic
     ///
     /// Panics if `ReceiptIndex` is invalid.
     fn get_receipt_receiver(&self, receipt_index: ReceiptIndex) -> &AccountId;
 }
+
+/// The error of the default implementations of the methods added to [`External`].
+fn not_implemented(method: &str) -> VMLogicError {
+    InconsistentStateError::ExternalMethodNotImplemented { method: method.to_string() }.into()
+}
@@ -0,0 +1,256 @@
+//! Stable codes of the errors calls fail with, for embedders to localize.
+//!
+//! The messages of [`FunctionCallError`] are English, truncated and free to
+//! change between versions, so wallets and explorers which show failures in
+//! the language of their users should not parse them.  [`ErrorCode`] gives
+//! each failure a code which never changes once released, such as
+//! `host.gas_exceeded`, along with the fields of the error as typed
+//! parameters, and an [`ErrorLocalizer`] turns them into a message.
+//!
+//! Every variant of every error has its own code: the matches below list
+//! them all, so an added variant does not compile until it gets one.
+
+use super::errors::{
+    CompilationError, FunctionCallError, HostError, MethodResolveError, PrepareError, WasmTrap,
+};
+
+/// A parameter of an [`ErrorCode`].
+///
+/// Unlike in the messages of the errors, strings are not truncated.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(untagged)]
+pub enum ErrorParam<'a> {
+    U64(u64),
+    Str(&'a str),
+}
+
+/// The code of an error and its parameters.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct ErrorCode<'a> {
+    /// Dot separated, from the kind of the error to the variant.
+    pub code: &'static str,
+    /// The fields of the error, named as in the error.
+    pub params: Vec<(&'static str, ErrorParam<'a>)>,
+}
+
+impl<'a> ErrorCode<'a> {
+    fn new(code: &'static str) -> Self {
+        Self { code, params: Vec::new() }
+    }
+
+    fn u64(mut self, name: &'static str, value: u64) -> Self {
+        self.params.push((name, ErrorParam::U64(value)));
+        self
+    }
+
+    fn str(mut self, name: &'static str, value: &'a str) -> Self {
+        self.params.push((name, ErrorParam::Str(value)));
+        self
+    }
+
+    /// The parameter called `name`.
+    pub fn param(&self, name: &str) -> Option<ErrorParam<'a>> {
+        self.params.iter().find(|(param, _)| *param == name).map(|(_, value)| *value)
+    }
+}
+
+/// Messages of the errors in the language of some users.
+pub trait ErrorLocalizer {
+    /// The message of `code`, or `None` if there is no translation of it.
+    fn localize(&self, code: &ErrorCode<'_>) -> Option<String>;
+}
+
+/// The message of `err` from `localizer`, or the English message of the
+/// error if the localizer has none.
+pub fn localized_message(err: &FunctionCallError, localizer: &dyn ErrorLocalizer) -> String {
+    localizer.localize(&err.code()).unwrap_or_else(|| err.to_string())
+}
+
+impl FunctionCallError {
+    /// The stable code of the error, see the [module documentation](self).
+    pub fn code(&self) -> ErrorCode<'_> {
+        match self {
+            FunctionCallError::CompilationError(err) => err.code(),
+            FunctionCallError::LinkError { msg } => ErrorCode::new("link").str("msg", msg),
+            FunctionCallError::MethodResolveError(err) => err.code(),
+            FunctionCallError::WasmTrap(trap) => trap.code(),
+            FunctionCallError::HostError(err) => err.code(),
+            FunctionCallError::Timeout => ErrorCode::new("timeout"),
+            FunctionCallError::MemoryCapExceeded { used, cap } => {
+                ErrorCode::new("memory_cap_exceeded").u64("used", *used).u64("cap", *cap)
+            }
+        }
+    }
+}
+
+impl CompilationError {
+    pub fn code(&self) -> ErrorCode<'_> {
+        match self {
+            CompilationError::CodeDoesNotExist { account_id } => {
+                ErrorCode::new("compilation.code_does_not_exist").str("account_id", account_id)
+            }
+            CompilationError::PrepareError(err) => err.code(),
+            CompilationError::WasmerCompileError { msg } => {
+                ErrorCode::new("compilation.compile").str("msg", msg)
+            }
+        }
+    }
+}
+
+impl PrepareError {
+    pub fn code(&self) -> ErrorCode<'static> {
+        ErrorCode::new(match self {
+            PrepareError::Serialization => "compilation.prepare.serialization",
+            PrepareError::Deserialization => "compilation.prepare.deserialization",
+            PrepareError::InternalMemoryDeclared => "compilation.prepare.internal_memory_declared",
+            PrepareError::GasInstrumentation => "compilation.prepare.gas_instrumentation",
+            PrepareError::StackHeightInstrumentation => {
+                "compilation.prepare.stack_height_instrumentation"
+            }
+            PrepareError::Instantiate => "compilation.prepare.instantiate",
+            PrepareError::Memory => "compilation.prepare.memory",
+            PrepareError::TooManyFunctions => "compilation.prepare.too_many_functions",
+            PrepareError::TooManyLocals => "compilation.prepare.too_many_locals",
+            PrepareError::TooComplex => "compilation.prepare.too_complex",
+            PrepareError::BlockedOpcode => "compilation.prepare.blocked_opcode",
+            PrepareError::BrTableTooLarge => "compilation.prepare.br_table_too_large",
+            PrepareError::TooDeeplyNested => "compilation.prepare.too_deeply_nested",
+            PrepareError::FunctionTooLarge => "compilation.prepare.function_too_large",
+            PrepareError::UnsupportedPasses => "compilation.prepare.unsupported_passes",
+        })
+    }
+}
+
+impl MethodResolveError {
+    pub fn code(&self) -> ErrorCode<'_> {
+        match self {
+            MethodResolveError::MethodEmptyName => ErrorCode::new("method.empty_name"),
+            MethodResolveError::MethodNotFound => ErrorCode::new("method.not_found"),
+            MethodResolveError::MethodInvalidSignature => {
+                ErrorCode::new("method.invalid_signature")
+            }
+            MethodResolveError::MethodNameTooLong { length, limit } => {
+                ErrorCode::new("method.name_too_long").u64("length", *length).u64("limit", *limit)
+            }
+        }
+    }
+}
+
+impl WasmTrap {
+    pub fn code(&self) -> ErrorCode<'static> {
+        ErrorCode::new(match self {
+            WasmTrap::Unreachable => "trap.unreachable",
+            WasmTrap::IncorrectCallIndirectSignature => "trap.incorrect_call_indirect_signature",
+            WasmTrap::MemoryOutOfBounds => "trap.memory_out_of_bounds",
+            WasmTrap::CallIndirectOOB => "trap.call_indirect_out_of_bounds",
+            WasmTrap::IllegalArithmetic => "trap.illegal_arithmetic",
+            WasmTrap::MisalignedAtomicAccess => "trap.misaligned_atomic_access",
+            WasmTrap::IndirectCallToNull => "trap.indirect_call_to_null",
+            WasmTrap::StackOverflow => "trap.stack_overflow",
+            WasmTrap::GenericTrap => "trap.generic",
+        })
+    }
+}
+
+impl HostError {
+    pub fn code(&self) -> ErrorCode<'_> {
+        let length_limit = |code, length: &u64, limit: &u64| {
+            ErrorCode::new(code).u64("length", *length).u64("limit", *limit)
+        };
+        match self {
+            HostError::BadUTF16 => ErrorCode::new("host.bad_utf16"),
+            HostError::BadUTF8 => ErrorCode::new("host.bad_utf8"),
+            HostError::GasExceeded => ErrorCode::new("host.gas_exceeded"),
+            HostError::GasLimitExceeded => ErrorCode::new("host.gas_limit_exceeded"),
+            HostError::BalanceExceeded => ErrorCode::new("host.balance_exceeded"),
+            HostError::EmptyMethodName => ErrorCode::new("host.empty_method_name"),
+            HostError::GuestPanic { panic_msg } => {
+                ErrorCode::new("host.guest_panic").str("panic_msg", panic_msg)
+            }
+            HostError::IntegerOverflow => ErrorCode::new("host.integer_overflow"),
+            HostError::InvalidPromiseIndex { promise_idx } => {
+                ErrorCode::new("host.invalid_promise_index").u64("promise_idx", *promise_idx)
+            }
+            HostError::CannotAppendActionToJointPromise => {
+                ErrorCode::new("host.cannot_append_action_to_joint_promise")
+            }
+            HostError::CannotReturnJointPromise => {
+                ErrorCode::new("host.cannot_return_joint_promise")
+            }
+            HostError::InvalidPromiseResultIndex { result_idx } => {
+                ErrorCode::new("host.invalid_promise_result_index").u64("result_idx", *result_idx)
+            }
+            HostError::InvalidRegisterId { register_id } => {
+                ErrorCode::new("host.invalid_register_id").u64("register_id", *register_id)
+            }
+            HostError::MemoryAccessViolation => ErrorCode::new("host.memory_access_violation"),
+            HostError::InvalidReceiptIndex { receipt_index } => {
+                ErrorCode::new("host.invalid_receipt_index").u64("receipt_index", *receipt_index)
+            }
+            HostError::InvalidIteratorIndex { iterator_index } => {
+                ErrorCode::new("host.invalid_iterator_index").u64("iterator_index", *iterator_index)
+            }
+            HostError::InvalidAccountId => ErrorCode::new("host.invalid_account_id"),
+            HostError::InvalidMethodName => ErrorCode::new("host.invalid_method_name"),
+            HostError::InvalidPublicKey => ErrorCode::new("host.invalid_public_key"),
+            HostError::ProhibitedInView { method_name } => {
+                ErrorCode::new("host.prohibited_in_view").str("method_name", method_name)
+            }
+            HostError::NumberOfLogsExceeded { limit } => {
+                ErrorCode::new("host.number_of_logs_exceeded").u64("limit", *limit)
+            }
+            HostError::KeyLengthExceeded { length, limit } => {
+                length_limit("host.key_length_exceeded", length, limit)
+            }
+            HostError::ValueLengthExceeded { length, limit } => {
+                length_limit("host.value_length_exceeded", length, limit)
+            }
+            HostError::TotalLogLengthExceeded { length, limit } => {
+                length_limit("host.total_log_length_exceeded", length, limit)
+            }
+            HostError::NumberPromisesExceeded { number_of_promises, limit } => {
+                ErrorCode::new("host.number_promises_exceeded")
+                    .u64("number_of_promises", *number_of_promises)
+                    .u64("limit", *limit)
+            }
+            HostError::NumberInputDataDependenciesExceeded {
+                number_of_input_data_dependencies,
+                limit,
+            } => ErrorCode::new("host.number_input_data_dependencies_exceeded")
+                .u64("number_of_input_data_dependencies", *number_of_input_data_dependencies)
+                .u64("limit", *limit),
+            HostError::ReturnedValueLengthExceeded { length, limit } => {
+                length_limit("host.returned_value_length_exceeded", length, limit)
+            }
+            HostError::ContractSizeExceeded { size, limit } => {
+                ErrorCode::new("host.contract_size_exceeded")
+                    .u64("size", *size)
+                    .u64("limit", *limit)
+            }
+            HostError::Deprecated { method_name } => {
+                ErrorCode::new("host.deprecated").str("method_name", method_name)
+            }
+            HostError::ECRecoverError { msg } => ErrorCode::new("host.ecrecover").str("msg", msg),
+            HostError::AltBn128InvalidInput { msg } => {
+                ErrorCode::new("host.alt_bn128_invalid_input").str("msg", msg)
+            }
+            HostError::Ed25519VerifyInvalidInput { msg } => {
+                ErrorCode::new("host.ed25519_verify_invalid_input").str("msg", msg)
+            }
+            HostError::ScratchLengthExceeded { length, limit } => {
+                length_limit("host.scratch_length_exceeded", length, limit)
+            }
+            HostError::FormatDecimalsExceeded { decimals, limit } => {
+                ErrorCode::new("host.format_decimals_exceeded")
+                    .u64("decimals", *decimals)
+                    .u64("limit", *limit)
+            }
+            HostError::WideMathInvalidInput { msg } => {
+                ErrorCode::new("host.wide_math_invalid_input").str("msg", msg)
+            }
+            HostError::Bls12381InvalidInput { msg } => {
+                ErrorCode::new("host.bls12381_invalid_input").str("msg", msg)
+            }
+        }
+    }
+}
@@ -28,6 +28,13 @@ pub enum VMRunnerError {
     Nondeterministic(String),
     #[error("unknown error during contract execution: {debug_message}")]
     WasmUnknownError { debug_message: String },
+    /// The runtime of the VM kind of the config cannot run on this host.
+    #[error("{0}")]
+    BackendUnavailable(#[from] crate::runner::BackendUnavailable),
+    /// The run options have consensus hooks which the config does not
+    /// enable, see [`crate::ConsensusHooks`].
+    #[error("the config does not enable the consensus hooks of the run options")]
+    ConsensusHooksDisabled,
 }
 
 /// Permitted errors that cause a function call to fail gracefully.
@@ -38,7 +45,22 @@ pub enum VMRunnerError {
 /// See the doc comment on `VMResult` for an explanation what the difference
 /// between this and a `VMRunnerError` is. And see `PartialExecutionStatus`
 /// for what gets stored on chain.
-#[derive(Debug, PartialEq, Eq, strum::IntoStaticStr)]
+///
+/// Variants are only ever appended to this enum and the errors it contains,
+/// which the serde format names.  The borsh format is that of the outcome:
+/// borsh bytes are only read back by the version of this crate which wrote
+/// them.
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::IntoStaticStr,
+)]
 pub enum FunctionCallError {
     /// Wasm compilation error
     CompilationError(CompilationError),
@@ -51,6 +73,21 @@ pub enum FunctionCallError {
     /// A trap happened during execution of a binary
     WasmTrap(WasmTrap),
     HostError(HostError),
+    /// The call was interrupted at the deadline of
+    /// [`crate::RunOptions::deadline`].
+    ///
+    /// Whether a call times out depends on the host, so this is never the
+    /// outcome of a call without a deadline.
+    Timeout,
+    /// The call used `used` bytes of memory, over the
+    /// [`crate::logic::ExtraLimitConfig::memory_cap`] of its config, see
+    /// [`super::types::UsedMemory`].
+    ///
+    /// This is never the outcome of a call of a config without a memory cap.
+    MemoryCapExceeded {
+        used: u64,
+        cap: u64,
+    },
 }
 
 #[derive(Debug, thiserror::Error, strum::IntoStaticStr)]
@@ -65,7 +102,17 @@ pub enum CacheError {
     SerializationError { hash: [u8; 32] },
 }
 /// A kind of a trap happened during execution of a binary
-#[derive(Debug, Clone, PartialEq, Eq, strum::IntoStaticStr)]
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::IntoStaticStr,
+)]
 pub enum WasmTrap {
     /// An `unreachable` opcode was executed.
     Unreachable,
@@ -87,14 +134,40 @@ pub enum WasmTrap {
     GenericTrap,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, strum::IntoStaticStr)]
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::IntoStaticStr,
+)]
 pub enum MethodResolveError {
     MethodEmptyName,
     MethodNotFound,
     MethodInvalidSignature,
+    /// The name is longer than `max_length_method_name` under the
+    /// `limit_method_name_length` parameter, see [`crate::check_method_name`].
+    MethodNameTooLong {
+        length: u64,
+        limit: u64,
+    },
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize, strum::IntoStaticStr)]
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::IntoStaticStr,
+)]
 pub enum CompilationError {
     CodeDoesNotExist {
         account_id: Box<str>,
@@ -108,7 +181,16 @@ pub enum CompilationError {
     },
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, BorshDeserialize, BorshSerialize)]
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
 /// Error that can occur while preparing or executing Wasm smart-contract.
 pub enum PrepareError {
     /// Error happened while serializing the module.
@@ -136,9 +218,37 @@ pub enum PrepareError {
     TooManyFunctions,
     /// Contract contains too many locals.
     TooManyLocals,
+    /// Contract exceeds the budget of operations of its preparation.
+    TooComplex,
+    /// Contract uses an instruction of the blocklist of the network, see
+    /// [`crate::prepare::OpcodeBlocklist`].
+    BlockedOpcode,
+    /// Contract has a `br_table` with more targets than
+    /// [`crate::prepare::ControlFlowLimits`] allow.
+    BrTableTooLarge,
+    /// Contract nests blocks deeper than [`crate::prepare::ControlFlowLimits`]
+    /// allow.
+    TooDeeplyNested,
+    /// Contract has a function larger than
+    /// [`crate::prepare::FunctionSizeLimit`] allows.
+    FunctionTooLarge,
+    /// The VM cannot run contracts prepared with the passes of the config,
+    /// see [`crate::prepare::prepare_contract_with_passes`], or the
+    /// preparation cannot run the coverage pass the config asks for.
+    UnsupportedPasses,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq, strum::IntoStaticStr)]
+#[derive(
+    Debug,
+    Clone,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+    strum::IntoStaticStr,
+)]
 pub enum HostError {
     /// String encoding is bad UTF-16 sequence
     BadUTF16,
@@ -206,6 +316,16 @@ pub enum HostError {
     /// Invalid input to ed25519 signature verification function (e.g. signature cannot be
     /// derived from bytes).
     Ed25519VerifyInvalidInput { msg: String },
+    /// The scratch area attached to a receipt exceeded the limit.
+    ScratchLengthExceeded { length: u64, limit: u64 },
+    /// The number of decimals requested from `format_fixed_point` exceeded the limit.
+    FormatDecimalsExceeded { decimals: u64, limit: u64 },
+    /// Invalid input to the 256-bit integer functions, e.g. a zero denominator or a result not
+    /// fitting in 256 bits.
+    WideMathInvalidInput { msg: String },
+    /// Invalid input to the bls12381 family of functions, e.g. a point which isn't on the curve
+    /// or in its subgroup.
+    Bls12381InvalidInput { msg: String },
 }
 
 #[derive(Debug, PartialEq, Eq)]
@@ -222,10 +342,17 @@ impl std::error::Error for VMLogicError {}
 
 /// An error that is caused by an operation on an inconsistent state, such as
 /// integer overflow.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum InconsistentStateError {
     /// Math operation with a value from the state resulted in a integer overflow.
     IntegerOverflow,
+    /// The config links a host function which this build of the runner
+    /// leaves out, see the crypto features of the crate.  Running the call
+    /// anyway would make its outcome depend on the build.
+    HostFunctionNotCompiled { host_function: String, feature: String },
+    /// The call needs a method of the `External` which its implementation
+    /// leaves to the default, written before the method was added.
+    ExternalMethodNotImplemented { method: String },
 }
 
 impl From<HostError> for VMLogicError {
@@ -286,6 +413,12 @@ impl fmt::Display for PrepareError {
             Memory => "Error creating memory.",
             TooManyFunctions => "Too many functions in contract.",
             TooManyLocals => "Too many locals declared in the contract.",
+            TooComplex => "The contract is too complex to prepare.",
+            BlockedOpcode => "The contract uses an instruction blocked on this network.",
+            BrTableTooLarge => "A branch table of the contract has too many targets.",
+            TooDeeplyNested => "The contract nests blocks too deeply.",
+            FunctionTooLarge => "A function of the contract is too large.",
+            UnsupportedPasses => "The VM does not support the preparation passes.",
         })
     }
 }
@@ -298,6 +431,10 @@ impl fmt::Display for FunctionCallError {
             FunctionCallError::HostError(e) => e.fmt(f),
             FunctionCallError::LinkError { msg } => write!(f, "{}", msg),
             FunctionCallError::WasmTrap(trap) => write!(f, "WebAssembly trap: {}", trap),
+            FunctionCallError::Timeout => write!(f, "The call did not finish before its deadline."),
+            FunctionCallError::MemoryCapExceeded { used, cap } => {
+                write!(f, "The call used {used} bytes of memory, over its cap of {cap} bytes.")
+            }
         }
     }
 }
@@ -349,6 +486,13 @@ impl std::fmt::Display for InconsistentStateError {
                 f,
                 "Math operation with a value from the state resulted in a integer overflow.",
             ),
+            InconsistentStateError::HostFunctionNotCompiled { host_function, feature } => write!(
+                f,
+                "host function {host_function} is enabled by the config but the runner was built without the `{feature}` feature",
+            ),
+            InconsistentStateError::ExternalMethodNotImplemented { method } => {
+                write!(f, "the External does not implement {method}, which the call needs")
+            }
         }
     }
 }
@@ -435,6 +579,14 @@ impl std::fmt::Display for HostError {
             Ed25519VerifyInvalidInput { msg } => {
                 write!(f, "ED25519 signature verification error: {}", msg)
             }
+            ScratchLengthExceeded { length, limit } => {
+                write!(f, "The length of a scratch area {} exceeds the limit {}", length, limit)
+            }
+            FormatDecimalsExceeded { decimals, limit } => {
+                write!(f, "The number of decimals {} exceeds the limit {}", decimals, limit)
+            }
+            WideMathInvalidInput { msg } => write!(f, "256-bit math error: {}", msg),
+            Bls12381InvalidInput { msg } => write!(f, "BLS12-381 invalid input: {}", msg),
         }
     }
 }
@@ -4,7 +4,10 @@ use crate::ProfileDataV3;
 use unc_parameters::ExtCosts::{read_cached_trie_node, touching_trie_node};
 use unc_parameters::{ActionCosts, ExtCosts, ExtCostsConfig};
 use unc_primitives_core::types::Gas;
-use std::collections::HashMap;
+use borsh::{BorshDeserialize, BorshSerialize};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicU64, Ordering};
 
 #[inline]
 pub fn with_ext_cost_counter(f: impl FnOnce(&mut HashMap<ExtCosts, u64>)) {
@@ -41,10 +44,33 @@ pub struct FastGasCounter {
     pub opcode_cost: u64,
 }
 
+/// Interrupts a call by dropping its gas limit to zero, see
+/// [`GasCounter::interrupt_handle`].
+///
+/// Both the host functions and the gas checks of the generated code fail
+/// once the limit is lowered, so this stops the call on every VM.  The
+/// generated code reads the limit with plain loads, so the handle is only
+/// used on the thread running the call, e.g. from a signal handler
+/// interrupting it, and is not `Send`.
+#[derive(Clone, Copy)]
+pub(crate) struct GasInterrupt {
+    gas_limit: *mut u64,
+}
+
+impl GasInterrupt {
+    pub(crate) fn interrupt(&self) {
+        // SAFETY: see `GasCounter::interrupt_handle`.
+        unsafe { AtomicU64::from_ptr(self.gas_limit) }.store(0, Ordering::Relaxed);
+    }
+}
+
 /// Gas counter (a part of VMlogic)
 pub struct GasCounter {
     /// Shared gas counter data.
-    fast_counter: FastGasCounter,
+    ///
+    /// Boxed so that the generated code and [`GasInterrupt`] can keep a
+    /// pointer to it while the counter moves.
+    fast_counter: Box<FastGasCounter>,
     /// Gas that was attached to the promises.
     promises_gas: Gas,
     /// Hard gas limit for execution
@@ -57,6 +83,57 @@ pub struct GasCounter {
     ext_costs_config: ExtCostsConfig,
     /// Where to store profile data, if needed.
     profile: ProfileDataV3,
+    #[cfg(any(test, feature = "gas_profile"))]
+    gas_profile: GasProfile,
+    /// Host function the costs are charged for, see
+    /// [`Self::enter_host_function`].
+    #[cfg(any(test, feature = "gas_profile"))]
+    current_host_function: Option<&'static str>,
+}
+
+/// Gas burnt by a call broken down by where it was spent, see
+/// [`super::VMOutcome::gas_profile`].
+///
+/// Only collected with the `gas_profile` feature, so that production builds
+/// do not pay for it.
+#[derive(
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct GasProfile {
+    /// Calls and gas burnt by each host function called, by import name.
+    pub host_functions: BTreeMap<Cow<'static, str>, HostFunctionGas>,
+    /// Gas charged by the metering of the wasm code itself.  The contracts
+    /// are metered by basic blocks, every operator costing the same, so the
+    /// gas of the operators is not broken down further.
+    pub wasm_ops: Gas,
+    /// Gas charged before running the contract, i.e. for loading it.
+    pub loading: Gas,
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct HostFunctionGas {
+    pub calls: u64,
+    /// Gas burnt, not counting the gas attached to the receipts created.
+    pub gas: Gas,
 }
 
 impl GasCounter {
@@ -72,30 +149,30 @@ impl GasCounter {
         let prepaid_gas = if is_view { Gas::MAX } else { prepaid_gas };
         Self {
             ext_costs_config,
-            fast_counter: FastGasCounter {
+            fast_counter: Box::new(FastGasCounter {
                 burnt_gas: 0,
                 gas_limit: min(max_gas_burnt, prepaid_gas),
                 opcode_cost: Gas::from(opcode_cost),
-            },
+            }),
             max_gas_burnt,
             promises_gas: 0,
             prepaid_gas,
             is_view,
             profile: Default::default(),
+            #[cfg(any(test, feature = "gas_profile"))]
+            gas_profile: GasProfile::default(),
+            #[cfg(any(test, feature = "gas_profile"))]
+            current_host_function: None,
         }
     }
 
     /// Deducts burnt and used gas.
     ///
     /// Returns an error if the `max_gax_burnt` or the `prepaid_gas` limits are
-    /// crossed or there are arithmetic overflows.
-    ///
-    /// Panics
-    ///
-    /// This function asserts that `gas_burnt <= gas_used`
+    /// crossed or there are arithmetic overflows, including `gas_burnt`
+    /// exceeding `gas_used`.
     fn deduct_gas(&mut self, gas_burnt: Gas, gas_used: Gas) -> Result<()> {
-        assert!(gas_burnt <= gas_used);
-        let promises_gas = gas_used - gas_burnt;
+        let promises_gas = gas_used.checked_sub(gas_burnt).ok_or(HostError::IntegerOverflow)?;
         let new_promises_gas =
             self.promises_gas.checked_add(promises_gas).ok_or(HostError::IntegerOverflow)?;
         let new_burnt_gas =
@@ -105,8 +182,11 @@ impl GasCounter {
         if new_burnt_gas <= self.max_gas_burnt && new_used_gas <= self.prepaid_gas {
             use std::cmp::min;
             if promises_gas != 0 && !self.is_view {
-                self.fast_counter.gas_limit =
-                    min(self.max_gas_burnt, self.prepaid_gas - new_promises_gas);
+                // The limit only ever decreases, `fetch_min` keeps the limit
+                // lowered by an interruption.
+                let gas_limit =
+                    min(self.max_gas_burnt, self.prepaid_gas.saturating_sub(new_promises_gas));
+                self.gas_limit().fetch_min(gas_limit, Ordering::Relaxed);
             }
             self.fast_counter.burnt_gas = new_burnt_gas;
             self.promises_gas = new_promises_gas;
@@ -122,7 +202,7 @@ impl GasCounter {
     pub fn burn_gas(&mut self, gas_burnt: Gas) -> Result<()> {
         let new_burnt_gas =
             self.fast_counter.burnt_gas.checked_add(gas_burnt).ok_or(HostError::IntegerOverflow)?;
-        if new_burnt_gas <= self.fast_counter.gas_limit {
+        if new_burnt_gas <= self.gas_limit().load(Ordering::Relaxed) {
             self.fast_counter.burnt_gas = new_burnt_gas;
             Ok(())
         } else {
@@ -191,12 +271,33 @@ impl GasCounter {
     ///    ja emit_gas_exceeded
     pub fn gas_counter_raw_ptr(&mut self) -> *mut FastGasCounter {
         use std::ptr;
-        ptr::addr_of_mut!(self.fast_counter)
+        ptr::addr_of_mut!(*self.fast_counter)
+    }
+
+    /// The gas limit, which a signal handler can lower through a
+    /// [`GasInterrupt`] while a host function runs.
+    fn gas_limit(&mut self) -> &AtomicU64 {
+        // SAFETY: the pointer is valid, aligned and only accessed atomically
+        // by the host.
+        unsafe { AtomicU64::from_ptr(std::ptr::addr_of_mut!(self.fast_counter.gas_limit)) }
+    }
+
+    /// Returns a handle which makes the next gas charge of the call fail.
+    ///
+    /// # Safety
+    ///
+    /// The handle must not be used after the counter is dropped, nor on
+    /// another thread than the one running the call.
+    pub(crate) unsafe fn interrupt_handle(&mut self) -> GasInterrupt {
+        GasInterrupt { gas_limit: std::ptr::addr_of_mut!(self.fast_counter.gas_limit) }
     }
 
     #[inline]
     fn inc_ext_costs_counter(&mut self, cost: ExtCosts, value: u64) {
-        with_ext_cost_counter(|cc| *cc.entry(cost).or_default() += value)
+        with_ext_cost_counter(|cc| {
+            let count = cc.entry(cost).or_default();
+            *count = count.saturating_add(value);
+        })
     }
 
     #[inline]
@@ -209,6 +310,34 @@ impl GasCounter {
         self.profile.add_action_cost(action, value)
     }
 
+    /// Attributes the costs charged from now on to the host function `name`,
+    /// in the [`GasProfile`].
+    #[inline]
+    pub fn enter_host_function(&mut self, name: &'static str) {
+        #[cfg(any(test, feature = "gas_profile"))]
+        {
+            let host = self.gas_profile.host_functions.entry(Cow::Borrowed(name)).or_default();
+            host.calls = host.calls.saturating_add(1);
+            self.current_host_function = Some(name);
+        }
+        #[cfg(not(any(test, feature = "gas_profile")))]
+        let _ = name;
+    }
+
+    #[inline]
+    fn update_gas_profile(&mut self, burnt_gas: Gas) {
+        #[cfg(any(test, feature = "gas_profile"))]
+        match self.current_host_function {
+            Some(name) => {
+                let host = self.gas_profile.host_functions.entry(Cow::Borrowed(name)).or_default();
+                host.gas = host.gas.saturating_add(burnt_gas);
+            }
+            None => self.gas_profile.loading = self.gas_profile.loading.saturating_add(burnt_gas),
+        }
+        #[cfg(not(any(test, feature = "gas_profile")))]
+        let _ = burnt_gas;
+    }
+
     /// A helper function to pay a multiple of a cost.
     pub fn pay_per(&mut self, cost: ExtCosts, num: u64) -> Result<()> {
         let use_gas =
@@ -217,7 +346,9 @@ impl GasCounter {
         self.inc_ext_costs_counter(cost, num);
         let old_burnt_gas = self.fast_counter.burnt_gas;
         let burn_gas_result = self.burn_gas(use_gas);
-        self.update_profile_host(cost, self.fast_counter.burnt_gas.saturating_sub(old_burnt_gas));
+        let burnt_gas = self.fast_counter.burnt_gas.saturating_sub(old_burnt_gas);
+        self.update_profile_host(cost, burnt_gas);
+        self.update_gas_profile(burnt_gas);
         burn_gas_result
     }
 
@@ -227,7 +358,20 @@ impl GasCounter {
         self.inc_ext_costs_counter(cost, 1);
         let old_burnt_gas = self.fast_counter.burnt_gas;
         let burn_gas_result = self.burn_gas(base_fee);
-        self.update_profile_host(cost, self.fast_counter.burnt_gas.saturating_sub(old_burnt_gas));
+        let burnt_gas = self.fast_counter.burnt_gas.saturating_sub(old_burnt_gas);
+        self.update_profile_host(cost, burnt_gas);
+        self.update_gas_profile(burnt_gas);
+        burn_gas_result
+    }
+
+    /// Pays `num` times `cost`, one of the costs of [`crate::logic::ExtraExtCostsConfig`],
+    /// which have no [`ExtCosts`] to count them under in the profile.
+    pub fn pay_extra(&mut self, cost: Gas, num: u64) -> Result<()> {
+        let use_gas = num.checked_mul(cost).ok_or(HostError::IntegerOverflow)?;
+        let old_burnt_gas = self.fast_counter.burnt_gas;
+        let burn_gas_result = self.burn_gas(use_gas);
+        let burnt_gas = self.fast_counter.burnt_gas.saturating_sub(old_burnt_gas);
+        self.update_gas_profile(burnt_gas);
         burn_gas_result
     }
 
@@ -244,10 +388,9 @@ impl GasCounter {
     ) -> Result<()> {
         let old_burnt_gas = self.fast_counter.burnt_gas;
         let deduct_gas_result = self.deduct_gas(burn_gas, use_gas);
-        self.update_profile_action(
-            action,
-            self.fast_counter.burnt_gas.saturating_sub(old_burnt_gas),
-        );
+        let burnt_gas = self.fast_counter.burnt_gas.saturating_sub(old_burnt_gas);
+        self.update_profile_action(action, burnt_gas);
+        self.update_gas_profile(burnt_gas);
         deduct_gas_result
     }
 
@@ -261,28 +404,140 @@ impl GasCounter {
         self.deduct_gas(0, use_gas)
     }
 
+    /// Returns a counter for host-side work done on another thread, allowed
+    /// to burn the gas the call has left.
+    ///
+    /// The charges of the forked counters are paid with [`Self::merge`] once
+    /// the work is over.
+    pub fn fork(&mut self) -> LocalGasCounter {
+        let allowance =
+            self.gas_limit().load(Ordering::Relaxed).saturating_sub(self.fast_counter.burnt_gas);
+        LocalGasCounter {
+            ext_costs_config: self.ext_costs_config.clone(),
+            allowance,
+            charges: GasCharges::default(),
+        }
+    }
+
+    /// Pays the charges of forked counters.
+    ///
+    /// The charges are added up and every cost is paid in the order of
+    /// [`ExtCosts`], so the outcome, including the error and the gas burnt
+    /// when the call runs out of gas, does not depend on the order the
+    /// charges come in nor on the threads that made them.  The costs are
+    /// attributed to the current host function.
+    pub fn merge(&mut self, charges: impl IntoIterator<Item = GasCharges>) -> Result<()> {
+        let mut total = GasCharges::default();
+        for charges in charges {
+            total.merge(charges);
+        }
+        for (cost, num) in total.counts {
+            self.pay_per(cost, num)?;
+        }
+        Ok(())
+    }
+
     pub fn burnt_gas(&self) -> Gas {
         self.fast_counter.burnt_gas
     }
 
     /// Amount of gas used through promises and amount burned.
+    ///
+    /// The counter never lets it exceed the prepaid gas.
     pub fn used_gas(&self) -> Gas {
-        self.promises_gas + self.fast_counter.burnt_gas
+        self.promises_gas.saturating_add(self.fast_counter.burnt_gas)
     }
 
     /// Remaining gas based on the amount of prepaid gas not yet used.
     pub fn unused_gas(&self) -> Gas {
-        self.prepaid_gas - self.used_gas()
+        self.prepaid_gas.saturating_sub(self.used_gas())
     }
 
     pub fn profile_data(&self) -> ProfileDataV3 {
         self.profile.clone()
     }
+
+    /// The gas burnt so far broken down, if collected.
+    pub fn gas_profile(&self) -> Option<GasProfile> {
+        #[cfg(any(test, feature = "gas_profile"))]
+        {
+            let mut profile = self.gas_profile.clone();
+            let host_gas =
+                profile.host_functions.values().map(|host| host.gas).fold(0, Gas::saturating_add);
+            profile.wasm_ops = self
+                .fast_counter
+                .burnt_gas
+                .saturating_sub(host_gas)
+                .saturating_sub(profile.loading);
+            Some(profile)
+        }
+        #[cfg(not(any(test, feature = "gas_profile")))]
+        None
+    }
+}
+
+/// Gas counter of host-side work running on another thread than the call,
+/// see [`GasCounter::fork`].
+///
+/// The counter only fails once the work costs more than the gas the call had
+/// left when forking, so that it can stop early: whether the call can pay is
+/// only decided when merging.  The charges and failures only depend on the
+/// work charged to this counter.
+#[derive(Clone, Debug)]
+pub struct LocalGasCounter {
+    ext_costs_config: ExtCostsConfig,
+    allowance: Gas,
+    charges: GasCharges,
+}
+
+impl LocalGasCounter {
+    pub fn pay_base(&mut self, cost: ExtCosts) -> Result<()> {
+        self.pay_per(cost, 1)
+    }
+
+    pub fn pay_per(&mut self, cost: ExtCosts, num: u64) -> Result<()> {
+        let gas = num.saturating_mul(cost.gas(&self.ext_costs_config));
+        let count = self.charges.counts.entry(cost).or_default();
+        *count = count.saturating_add(num);
+        self.charges.gas = self.charges.gas.saturating_add(gas);
+        if self.charges.gas > self.allowance {
+            return Err(HostError::GasExceeded.into());
+        }
+        Ok(())
+    }
+
+    pub fn burnt_gas(&self) -> Gas {
+        self.charges.gas
+    }
+
+    pub fn into_charges(self) -> GasCharges {
+        self.charges
+    }
+}
+
+/// Costs charged to [`LocalGasCounter`]s, to pay with [`GasCounter::merge`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct GasCharges {
+    /// Number of times each cost has been charged, saturating.
+    counts: BTreeMap<ExtCosts, u64>,
+    /// Gas of the costs, saturating.
+    gas: Gas,
+}
+
+impl GasCharges {
+    /// Adds the charges of `other`, in any order.
+    pub fn merge(&mut self, other: GasCharges) {
+        for (cost, num) in other.counts {
+            let count = self.counts.entry(cost).or_default();
+            *count = count.saturating_add(num);
+        }
+        self.gas = self.gas.saturating_add(other.gas);
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{ExtCostsConfig, HostError};
+    use super::{ExtCostsConfig, GasCharges, HostError};
     use unc_parameters::{ActionCosts, ExtCosts};
     use unc_primitives_core::types::Gas;
 
@@ -293,6 +548,29 @@ mod tests {
         super::GasCounter::new(ExtCostsConfig::test(), max_burnt, 1, prepaid, is_view)
     }
 
+    #[test]
+    fn test_gas_profile() {
+        let config = ExtCostsConfig::test();
+        let mut counter = make_test_counter(MAX_GAS, MAX_GAS, false);
+        counter.pay_base(ExtCosts::contract_loading_base).unwrap();
+        counter.enter_host_function("sha256");
+        counter.pay_base(ExtCosts::sha256_base).unwrap();
+        counter.pay_per(ExtCosts::sha256_byte, 10).unwrap();
+        counter.burn_gas(100).unwrap();
+        counter.enter_host_function("sha256");
+        counter.pay_base(ExtCosts::sha256_base).unwrap();
+
+        let profile = counter.gas_profile().unwrap();
+        assert_eq!(profile.loading, ExtCosts::contract_loading_base.gas(&config));
+        let sha256 = profile.host_functions["sha256"];
+        assert_eq!(sha256.calls, 2);
+        assert_eq!(
+            sha256.gas,
+            2 * ExtCosts::sha256_base.gas(&config) + 10 * ExtCosts::sha256_byte.gas(&config)
+        );
+        assert_eq!(profile.wasm_ops, 100);
+    }
+
     #[test]
     fn test_deduct_gas() {
         let mut counter = make_test_counter(10, 10, false);
@@ -302,15 +580,122 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
     fn test_burn_gas_must_be_lt_use_gas() {
-        let _ = make_test_counter(10, 10, false).deduct_gas(5, 2);
+        let mut counter = make_test_counter(10, 10, false);
+        assert_eq!(counter.deduct_gas(5, 2), Err(HostError::IntegerOverflow.into()));
+        assert_eq!(counter.burnt_gas(), 0);
+        assert_eq!(counter.used_gas(), 0);
     }
 
     #[test]
-    #[should_panic]
     fn test_burn_gas_must_be_lt_use_gas_view() {
-        let _ = make_test_counter(10, 10, true).deduct_gas(5, 2);
+        let mut counter = make_test_counter(10, 10, true);
+        assert_eq!(counter.deduct_gas(5, 2), Err(HostError::IntegerOverflow.into()));
+        assert_eq!(counter.burnt_gas(), 0);
+        assert_eq!(counter.used_gas(), 0);
+    }
+
+    /// An action costing more burnt than used gas, which only a broken config
+    /// has, fails every call charging it the same way, leaving the counter and
+    /// its profile as they were.
+    #[test]
+    fn test_burn_gas_gt_use_gas_action() {
+        let charge = || {
+            let mut counter = make_test_counter(MAX_GAS, MAX_GAS, false);
+            counter.pay_base(ExtCosts::base).unwrap();
+            let result = counter.pay_action_accumulated(5, 2, ActionCosts::transfer);
+            (result, counter.burnt_gas(), counter.used_gas(), counter.profile_data())
+        };
+        let (result, burnt_gas, used_gas, profile) = charge();
+        assert_eq!(result, Err(HostError::IntegerOverflow.into()));
+        assert_eq!(burnt_gas, ExtCosts::base.gas(&ExtCostsConfig::test()));
+        assert_eq!(used_gas, burnt_gas);
+        assert_eq!(profile.get_action_cost(ActionCosts::transfer), 0);
+        assert_eq!(charge(), (result, burnt_gas, used_gas, profile));
+    }
+
+    #[test]
+    fn test_huge_costs_overflow() {
+        let mut config = ExtCostsConfig::test();
+        config.costs[ExtCosts::sha256_byte].gas = Gas::MAX / 2 + 1;
+        let mut counter = super::GasCounter::new(config, Gas::MAX, 1, Gas::MAX, false);
+        assert_eq!(
+            counter.pay_per(ExtCosts::sha256_byte, 2),
+            Err(HostError::IntegerOverflow.into())
+        );
+        assert_eq!(counter.pay_per(ExtCosts::sha256_byte, 1), Ok(()));
+        assert_eq!(
+            counter.pay_per(ExtCosts::sha256_byte, 1),
+            Err(HostError::IntegerOverflow.into())
+        );
+        assert_eq!(counter.prepay_gas(Gas::MAX), Err(HostError::IntegerOverflow.into()));
+        assert_eq!(counter.used_gas(), Gas::MAX / 2 + 1);
+        let mut local = counter.fork();
+        assert_eq!(
+            local.pay_per(ExtCosts::sha256_byte, u64::MAX),
+            Err(HostError::GasExceeded.into())
+        );
+        assert_eq!(local.burnt_gas(), Gas::MAX);
+        assert_eq!(counter.merge([local.into_charges()]), Err(HostError::IntegerOverflow.into()));
+    }
+
+    /// Whatever the costs and the charges, up to the `u64` bounds, the counter
+    /// fails with a gas error instead of panicking and stays within its
+    /// limits.
+    #[test]
+    fn gas_counter_fuzzer() {
+        bolero::check!().with_type::<(Gas, Gas, bool, Gas, Vec<(u8, u64, u64)>)>().for_each(
+            |(max_burnt, prepaid, is_view, cost, charges)| {
+                let mut config = ExtCostsConfig::test();
+                for parameter in config.costs.values_mut() {
+                    parameter.gas = *cost;
+                    parameter.compute = *cost;
+                }
+                let mut counter =
+                    super::GasCounter::new(config.clone(), *max_burnt, 1, *prepaid, *is_view);
+                // View calls ignore the prepaid gas.
+                let prepaid = if *is_view { Gas::MAX } else { *prepaid };
+                for &(kind, a, b) in charges {
+                    let ext_cost = <ExtCosts as enum_map::Enum>::from_usize(
+                        a as usize % <ExtCosts as enum_map::Enum>::LENGTH,
+                    );
+                    let result = match kind % 7 {
+                        0 => counter.burn_gas(a),
+                        1 => counter.deduct_gas(a, b),
+                        2 => counter.pay_per(ext_cost, b),
+                        3 => counter.pay_base(ext_cost),
+                        4 => counter.prepay_gas(a),
+                        5 => counter.pay_action_accumulated(a, b, ActionCosts::transfer),
+                        _ => {
+                            let mut local = counter.fork();
+                            let _ = local.pay_per(ext_cost, b);
+                            counter.merge([local.into_charges()])
+                        }
+                    };
+                    if let Err(error) = result {
+                        assert!(
+                            matches!(
+                                error,
+                                super::VMLogicError::HostError(
+                                    HostError::GasExceeded
+                                        | HostError::GasLimitExceeded
+                                        | HostError::IntegerOverflow
+                                )
+                            ),
+                            "{error:?}"
+                        );
+                    }
+                    assert!(counter.burnt_gas() <= (*max_burnt).min(prepaid));
+                    assert!(counter.burnt_gas() <= counter.used_gas());
+                    assert!(counter.used_gas() <= prepaid);
+                    assert_eq!(counter.used_gas() + counter.unused_gas(), prepaid);
+                }
+                let mut profile = counter.profile_data();
+                profile.compute_wasm_instruction_cost(counter.burnt_gas());
+                let _ = profile.total_compute_usage(&config);
+                let _ = counter.gas_profile();
+            },
+        );
     }
 
     #[test]
@@ -404,4 +789,62 @@ mod tests {
         test(1_000_000_000, MAX_GAS, Err(HostError::GasLimitExceeded));
         test(1_000_000_000, 1_000_000_000, Err(HostError::GasLimitExceeded));
     }
+
+    #[test]
+    fn test_merge() {
+        let config = ExtCostsConfig::test();
+        let work = [
+            (ExtCosts::ed25519_verify_base, 3),
+            (ExtCosts::ed25519_verify_byte, 1000),
+            (ExtCosts::sha256_base, 1),
+        ];
+        let mut counter = make_test_counter(MAX_GAS, MAX_GAS, false);
+        let charges: Vec<GasCharges> = std::thread::scope(|s| {
+            let workers: Vec<_> = work
+                .into_iter()
+                .map(|(cost, num)| {
+                    let mut local = counter.fork();
+                    s.spawn(move || {
+                        local.pay_per(cost, num).unwrap();
+                        local.into_charges()
+                    })
+                })
+                .collect();
+            workers.into_iter().map(|worker| worker.join().unwrap()).collect()
+        });
+        let expected: Gas = work.iter().map(|(cost, num)| num * cost.gas(&config)).sum();
+        let mut reversed = make_test_counter(MAX_GAS, MAX_GAS, false);
+        reversed.merge(charges.iter().rev().cloned()).unwrap();
+        counter.merge(charges).unwrap();
+        assert_eq!(counter.burnt_gas(), expected);
+        assert_eq!(reversed.burnt_gas(), expected);
+        assert_eq!(counter.profile_data(), reversed.profile_data());
+    }
+
+    #[test]
+    fn test_merge_too_much() {
+        let config = ExtCostsConfig::test();
+        let base = ExtCosts::sha256_base.gas(&config);
+        let byte = ExtCosts::sha256_byte.gas(&config);
+        let limit = 2 * base + 10 * byte;
+        let mut counter = make_test_counter(limit, limit, false);
+        let mut a = counter.fork();
+        let mut b = counter.fork();
+        // Each fits in the gas left but not both.
+        a.pay_base(ExtCosts::sha256_base).unwrap();
+        a.pay_per(ExtCosts::sha256_byte, 8).unwrap();
+        b.pay_base(ExtCosts::sha256_base).unwrap();
+        b.pay_per(ExtCosts::sha256_byte, 8).unwrap();
+        // A local counter fails once it alone cannot be paid for.
+        let mut c = counter.fork();
+        assert_eq!(c.pay_per(ExtCosts::sha256_byte, 11), Err(HostError::GasExceeded.into()));
+        assert_eq!(c.burnt_gas(), 11 * byte);
+
+        let (a, b) = (a.into_charges(), b.into_charges());
+        for order in [[a.clone(), b.clone()], [b, a]] {
+            let mut counter = make_test_counter(limit, limit, false);
+            assert_eq!(counter.merge(order), Err(HostError::GasLimitExceeded.into()));
+            assert_eq!(counter.burnt_gas(), limit);
+        }
+    }
 }
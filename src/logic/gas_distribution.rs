@@ -0,0 +1,105 @@
+//! Distribution of unused prepaid gas among function calls with a gas weight.
+//!
+//! A contract can schedule function calls with
+//! [`promise_batch_action_function_call_weight`](super::VMLogic::promise_batch_action_function_call_weight)
+//! leaving the amount of gas to be decided once the execution is over.  The
+//! embedder then splits the unused gas of the execution among those calls.
+//! How it is split is decided by a [`GasDistributionPolicy`] which embedders
+//! can replace, e.g. with [`ReserveForLastCall`] to make sure the final
+//! callback of a call chain has some safety margin.
+
+use unc_primitives_core::types::{Gas, GasWeight};
+
+/// Policy deciding how unused gas is split among function calls.
+pub trait GasDistributionPolicy: Send + Sync {
+    /// Splits `unused_gas` among function calls with the given `weights`.
+    ///
+    /// Returns the amount of gas to add to each function call, index by index.
+    /// Calls with zero weight must get no gas.  The sum of the returned
+    /// amounts must not exceed `unused_gas`; whatever is left is refunded.
+    fn distribute(&self, unused_gas: Gas, weights: &[GasWeight]) -> Vec<Gas>;
+}
+
+/// Splits unused gas proportionally to the weights.
+///
+/// Gas per weight unit is the floor of unused gas divided by the sum of all
+/// weights.  The remainder of the division goes to the last function call
+/// with a non-zero weight so that all of the unused gas is distributed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ProportionalDistribution;
+
+impl GasDistributionPolicy for ProportionalDistribution {
+    fn distribute(&self, unused_gas: Gas, weights: &[GasWeight]) -> Vec<Gas> {
+        let mut result = vec![0; weights.len()];
+        let total_weight: u128 = weights.iter().map(|w| u128::from(w.0)).sum();
+        if total_weight == 0 {
+            return result;
+        }
+        let mut distributed: Gas = 0;
+        let mut last = 0;
+        for (i, weight) in weights.iter().enumerate() {
+            if weight.0 == 0 {
+                continue;
+            }
+            // Can’t overflow since weight / total_weight ≤ 1.
+            let gas = (u128::from(unused_gas) * u128::from(weight.0) / total_weight) as Gas;
+            result[i] = gas;
+            distributed += gas;
+            last = i;
+        }
+        result[last] += unused_gas - distributed;
+        result
+    }
+}
+
+/// Sets aside `reserve` gas for the last function call with a non-zero weight
+/// and distributes the rest with [`ProportionalDistribution`].
+///
+/// If there is less unused gas than `reserve`, all of it goes to the last
+/// call.
+#[derive(Clone, Copy, Debug)]
+pub struct ReserveForLastCall {
+    pub reserve: Gas,
+}
+
+impl GasDistributionPolicy for ReserveForLastCall {
+    fn distribute(&self, unused_gas: Gas, weights: &[GasWeight]) -> Vec<Gas> {
+        let Some(last) = weights.iter().rposition(|w| w.0 != 0) else {
+            return vec![0; weights.len()];
+        };
+        let reserve = self.reserve.min(unused_gas);
+        let mut result = ProportionalDistribution.distribute(unused_gas - reserve, weights);
+        result[last] += reserve;
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn weights(weights: &[u64]) -> Vec<GasWeight> {
+        weights.iter().copied().map(GasWeight).collect()
+    }
+
+    #[test]
+    fn test_proportional() {
+        let policy = ProportionalDistribution;
+        assert_eq!(policy.distribute(40, &weights(&[1, 5, 2])), [5, 25, 10]);
+        assert_eq!(policy.distribute(10, &weights(&[1, 0, 2])), [3, 0, 7]);
+        assert_eq!(policy.distribute(10, &weights(&[0, 0])), [0, 0]);
+        assert_eq!(policy.distribute(10, &[]), Vec::<Gas>::new());
+        assert_eq!(
+            policy.distribute(Gas::MAX, &weights(&[u64::MAX, u64::MAX])),
+            [Gas::MAX / 2, Gas::MAX / 2 + 1]
+        );
+    }
+
+    #[test]
+    fn test_reserve_for_last_call() {
+        let policy = ReserveForLastCall { reserve: 10 };
+        assert_eq!(policy.distribute(40, &weights(&[1, 2, 0])), [10, 30, 0]);
+        assert_eq!(policy.distribute(5, &weights(&[1, 2])), [0, 5]);
+        assert_eq!(policy.distribute(40, &weights(&[0, 0])), [0, 0]);
+    }
+}
@@ -0,0 +1,189 @@
+//! Planning the gas of cross-contract calls.
+//!
+//! A contract calling another one must attach enough gas for the callee to
+//! run and to schedule the calls of its own, each of which costs the callee
+//! the fees of a new receipt and of a function call action on top of the gas
+//! it attaches.  [`plan_call_chain`] computes, for a chain of calls where each
+//! callee makes the next call, the gas to attach to each of them.
+//!
+//! The fees are the ones [`super::VMLogic`] charges: it computes them with
+//! the functions of this module, so SDKs, the [`crate::Simulator`] and the
+//! runner agree on every number.  Send fees are burnt by the caller, exec
+//! fees are used, and so is the gas attached to the call, which the caller
+//! does not burn.
+
+use super::HostError;
+use unc_parameters::{ActionCosts, RuntimeFeesConfig};
+use unc_primitives_core::types::{AccountId, Gas};
+
+type Result<T> = std::result::Result<T, HostError>;
+
+/// Gas charged to a contract for scheduling a receipt or an action, without
+/// the gas attached to it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ActionGas {
+    pub burnt: Gas,
+    /// The burnt gas and the exec fees, charged against the prepaid gas.
+    pub used: Gas,
+}
+
+impl ActionGas {
+    pub fn checked_add(self, other: ActionGas) -> Result<ActionGas> {
+        Ok(ActionGas {
+            burnt: self.burnt.checked_add(other.burnt).ok_or(HostError::IntegerOverflow)?,
+            used: self.used.checked_add(other.used).ok_or(HostError::IntegerOverflow)?,
+        })
+    }
+}
+
+/// Fees of a new action receipt depending on `data_dependencies` data
+/// receipts, `sir` telling whether it is sent to the scheduling account and
+/// each dependency whether its result is.
+///
+/// The data receipts are paid for when the action receipt is scheduled, so
+/// both their send and exec fees are burnt.
+pub fn new_receipt_gas(
+    fees: &RuntimeFeesConfig,
+    sir: bool,
+    data_dependencies: &[bool],
+) -> Result<ActionGas> {
+    let mut burnt = fees.fee(ActionCosts::new_action_receipt).send_fee(sir);
+    let exec = fees.fee(ActionCosts::new_action_receipt).exec_fee();
+    for dep in data_dependencies {
+        burnt = burnt
+            .checked_add(fees.fee(ActionCosts::new_data_receipt_base).send_fee(*dep))
+            .ok_or(HostError::IntegerOverflow)?
+            .checked_add(fees.fee(ActionCosts::new_data_receipt_base).exec_fee())
+            .ok_or(HostError::IntegerOverflow)?;
+    }
+    let used = exec.checked_add(burnt).ok_or(HostError::IntegerOverflow)?;
+    Ok(ActionGas { burnt, used })
+}
+
+/// Base fee of `action`.
+pub fn action_base_gas(
+    fees: &RuntimeFeesConfig,
+    action: ActionCosts,
+    sir: bool,
+) -> Result<ActionGas> {
+    let fee = fees.fee(action);
+    let burnt = fee.send_fee(sir);
+    let used = burnt.checked_add(fee.exec_fee()).ok_or(HostError::IntegerOverflow)?;
+    Ok(ActionGas { burnt, used })
+}
+
+/// Per byte fee of `action` for `num_bytes` bytes.
+pub fn action_per_byte_gas(
+    fees: &RuntimeFeesConfig,
+    action: ActionCosts,
+    num_bytes: u64,
+    sir: bool,
+) -> Result<ActionGas> {
+    let fee = fees.fee(action);
+    let burnt = num_bytes.checked_mul(fee.send_fee(sir)).ok_or(HostError::IntegerOverflow)?;
+    let exec = num_bytes.checked_mul(fee.exec_fee()).ok_or(HostError::IntegerOverflow)?;
+    let used = burnt.checked_add(exec).ok_or(HostError::IntegerOverflow)?;
+    Ok(ActionGas { burnt, used })
+}
+
+/// Fees of a function call action of `method_name_len` and `args_len` bytes.
+pub fn function_call_gas(
+    fees: &RuntimeFeesConfig,
+    sir: bool,
+    method_name_len: u64,
+    args_len: u64,
+) -> Result<ActionGas> {
+    let num_bytes = method_name_len.checked_add(args_len).ok_or(HostError::IntegerOverflow)?;
+    let base = action_base_gas(fees, ActionCosts::function_call_base, sir)?;
+    base.checked_add(action_per_byte_gas(fees, ActionCosts::function_call_byte, num_bytes, sir)?)
+}
+
+/// A call of a chain planned by [`plan_call_chain`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PlannedCall {
+    pub receiver_id: AccountId,
+    pub method_name: String,
+    pub args_len: u64,
+    /// Gas the callee uses for itself, without the fees and the gas of the
+    /// next call of the chain.
+    pub execution_gas: Gas,
+}
+
+/// Gas of a call of a chain, see [`plan_call_chain`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlannedHop {
+    /// Gas to attach to the call.
+    pub attached_gas: Gas,
+    /// Fees the caller pays for scheduling the call in a receipt of its own.
+    pub scheduling: ActionGas,
+}
+
+/// Plans a chain of calls, where `caller_id` makes the first of `calls` and
+/// the receiver of each makes the next one, each in a receipt of its own.
+///
+/// Returns the gas of each call: it is attached the execution gas of its
+/// callee, the fees its callee pays for scheduling the next call and the gas
+/// attached to that one.  The caller of the first call needs
+/// `scheduling.used + attached_gas` of its own prepaid gas to make it.
+pub fn plan_call_chain(
+    fees: &RuntimeFeesConfig,
+    caller_id: &AccountId,
+    calls: &[PlannedCall],
+) -> Result<Vec<PlannedHop>> {
+    let mut hops = Vec::with_capacity(calls.len());
+    let mut caller_id = caller_id;
+    for call in calls {
+        let sir = &call.receiver_id == caller_id;
+        let method_name_len = call.method_name.len() as u64;
+        let action = function_call_gas(fees, sir, method_name_len, call.args_len)?;
+        let scheduling = new_receipt_gas(fees, sir, &[])?.checked_add(action)?;
+        hops.push(PlannedHop { attached_gas: call.execution_gas, scheduling });
+        caller_id = &call.receiver_id;
+    }
+    for i in (1..hops.len()).rev() {
+        let next = hops[i].scheduling.used.checked_add(hops[i].attached_gas);
+        hops[i - 1].attached_gas = next
+            .and_then(|next| hops[i - 1].attached_gas.checked_add(next))
+            .ok_or(HostError::IntegerOverflow)?;
+    }
+    Ok(hops)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(receiver_id: &str, execution_gas: Gas) -> PlannedCall {
+        PlannedCall {
+            receiver_id: receiver_id.parse().unwrap(),
+            method_name: "main".to_string(),
+            args_len: 10,
+            execution_gas,
+        }
+    }
+
+    #[test]
+    fn test_plan_call_chain() {
+        let fees = RuntimeFeesConfig::test();
+        let alice: AccountId = "alice.near".parse().unwrap();
+        let calls = [call("bob.near", 5), call("carol.near", 7), call("carol.near", 11)];
+        let hops = plan_call_chain(&fees, &alice, &calls).unwrap();
+
+        let scheduling = |sir| {
+            new_receipt_gas(&fees, sir, &[])
+                .unwrap()
+                .checked_add(function_call_gas(&fees, sir, 4, 10).unwrap())
+                .unwrap()
+        };
+        assert_eq!(hops[2], PlannedHop { attached_gas: 11, scheduling: scheduling(true) });
+        let attached_gas = 7 + scheduling(true).used + 11;
+        assert_eq!(hops[1], PlannedHop { attached_gas, scheduling: scheduling(false) });
+        let attached_gas = 5 + scheduling(false).used + attached_gas;
+        assert_eq!(hops[0], PlannedHop { attached_gas, scheduling: scheduling(false) });
+        assert!(scheduling(false).burnt < scheduling(false).used);
+
+        assert_eq!(plan_call_chain(&fees, &alice, &[]), Ok(vec![]));
+        let calls = [call("bob.near", 1), call("carol.near", Gas::MAX)];
+        assert_eq!(plan_call_chain(&fees, &alice, &calls), Err(HostError::IntegerOverflow));
+    }
+}
@@ -0,0 +1,215 @@
+//! Simulation of gas price changes over the blocks of a call chain.
+//!
+//! Gas for a transaction is purchased once, at the pessimistic gas price: the
+//! gas price of the block the transaction is included in, inflated by
+//! [`pessimistic_gas_price_inflation_ratio`] for every block the call chain
+//! may take to complete.  Receipts created along the chain inherit that
+//! purchase price while each of them is executed, and charged, at the gas
+//! price of its own block.  The runtime settles the difference in the refund
+//! of every receipt, which [`GasPriceSimulator::refund`] reproduces so that
+//! contracts sensitive to refund amounts can be tested against varying gas
+//! prices.
+//!
+//! [`pessimistic_gas_price_inflation_ratio`]: unc_parameters::RuntimeFeesConfig::pessimistic_gas_price_inflation_ratio
+
+use super::VMOutcome;
+use std::collections::BTreeMap;
+use unc_parameters::RuntimeFeesConfig;
+use unc_primitives_core::types::{Balance, BlockHeight, Gas};
+
+/// Gas prices of a sequence of simulated blocks.
+///
+/// The gas price of a block is the one last set at or below its height.
+#[derive(Clone, Debug)]
+pub struct GasPriceSimulator {
+    prices: BTreeMap<BlockHeight, Balance>,
+}
+
+/// Balance returned to the signer after the execution of a receipt.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GasRefund {
+    /// Amount refunded to the signer.
+    pub balance: Balance,
+    /// Amount the refund was short of when the gas price of the block exceeded
+    /// the purchase price of the gas, which the runtime reports as a deficit.
+    pub deficit: Balance,
+}
+
+impl GasPriceSimulator {
+    /// Starts a simulation where all blocks from `height` on have `gas_price`.
+    pub fn new(height: BlockHeight, gas_price: Balance) -> Self {
+        Self { prices: BTreeMap::from([(height, gas_price)]) }
+    }
+
+    /// Changes the gas price of the blocks from `height` on.
+    pub fn set_gas_price(&mut self, height: BlockHeight, gas_price: Balance) {
+        self.prices.insert(height, gas_price);
+    }
+
+    /// Returns the gas price of the block at `height`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `height` is below the start of the simulation.
+    pub fn gas_price(&self, height: BlockHeight) -> Balance {
+        match self.prices.range(..=height).next_back() {
+            Some((_, gas_price)) => *gas_price,
+            None => panic!("no gas price set at or below height {height}"),
+        }
+    }
+
+    /// Returns the price at which `prepaid_gas` is purchased by a transaction
+    /// included in the block at `height`.
+    ///
+    /// The gas price is inflated once for every receipt of the longest call
+    /// chain the prepaid gas can pay for, plus one, rounding up after each
+    /// block.  If the fees charge nothing for a function call receipt the
+    /// chain length is unbounded and the gas price is used as is.
+    pub fn pessimistic_gas_price(
+        &self,
+        fees: &RuntimeFeesConfig,
+        height: BlockHeight,
+        prepaid_gas: Gas,
+    ) -> Balance {
+        let gas_price = self.gas_price(height);
+        let Some(blocks) = prepaid_gas.checked_div(fees.min_receipt_with_function_call_gas())
+        else {
+            return gas_price;
+        };
+        let ratio = fees.pessimistic_gas_price_inflation_ratio;
+        let (numer, denom) = (*ratio.numer() as Balance, *ratio.denom() as Balance);
+        (0..=blocks).fold(gas_price, |price, _| {
+            (price.saturating_mul(numer).saturating_add(denom - 1)) / denom
+        })
+    }
+
+    /// Computes the refund of a receipt executed in the block at `height` with
+    /// gas purchased at `purchased_gas_price`.
+    ///
+    /// The unused gas of the execution is refunded at the purchase price.  The
+    /// burnt gas is charged at the gas price of the block: if that is lower
+    /// than the purchase price the difference is refunded as well, otherwise
+    /// it is taken out of the refund.
+    pub fn refund(
+        &self,
+        purchased_gas_price: Balance,
+        height: BlockHeight,
+        outcome: &VMOutcome,
+    ) -> GasRefund {
+        let gas_price = self.gas_price(height);
+        let burnt_gas = Balance::from(outcome.burnt_gas);
+        // Prices are only bounded by the configuration, so the amounts
+        // saturate.
+        let mut balance = Balance::from(outcome.refunded_gas).saturating_mul(purchased_gas_price);
+        if gas_price > purchased_gas_price {
+            let deficit = (gas_price - purchased_gas_price).saturating_mul(burnt_gas);
+            if balance >= deficit {
+                GasRefund { balance: balance - deficit, deficit: 0 }
+            } else {
+                GasRefund { balance: 0, deficit: deficit - balance }
+            }
+        } else {
+            balance =
+                balance.saturating_add((purchased_gas_price - gas_price).saturating_mul(burnt_gas));
+            GasRefund { balance, deficit: 0 }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::types::ReturnData;
+    use crate::ProfileDataV3;
+
+    fn outcome(burnt_gas: Gas, refunded_gas: Gas) -> VMOutcome {
+        VMOutcome {
+            balance: 0,
+            storage_usage: 0,
+            storage_delta: Default::default(),
+            return_data: ReturnData::None,
+            burnt_gas,
+            used_gas: burnt_gas,
+            promises_gas: 0,
+            refunded_gas,
+            compute_usage: 0,
+            logs: Vec::new(),
+            receipts: Vec::new(),
+            receipt_costs: Vec::new(),
+            profile: ProfileDataV3::default(),
+            gas_profile: None,
+            aborted: None,
+            checkpoints: Vec::new(),
+            gas_exhaustion_trace: Vec::new(),
+            host_calls: Vec::new(),
+            used_memory: Default::default(),
+            backtrace: None,
+            state_witness: None,
+            execution_fingerprint: None,
+        }
+    }
+
+    #[test]
+    fn test_gas_price_per_block() {
+        let mut prices = GasPriceSimulator::new(10, 100);
+        prices.set_gas_price(12, 150);
+        assert_eq!(prices.gas_price(10), 100);
+        assert_eq!(prices.gas_price(11), 100);
+        assert_eq!(prices.gas_price(12), 150);
+        assert_eq!(prices.gas_price(100), 150);
+    }
+
+    #[test]
+    fn test_pessimistic_gas_price() {
+        let fees = RuntimeFeesConfig::test();
+        let prices = GasPriceSimulator::new(0, 100_000_000);
+        let min_gas = fees.min_receipt_with_function_call_gas();
+        // A single block of inflation by 3%.
+        assert_eq!(prices.pessimistic_gas_price(&fees, 0, min_gas - 1), 103_000_000);
+        assert_eq!(prices.pessimistic_gas_price(&fees, 0, min_gas), 106_090_000);
+        assert_eq!(
+            prices.pessimistic_gas_price(&RuntimeFeesConfig::free(), 0, min_gas),
+            100_000_000
+        );
+    }
+
+    #[test]
+    fn test_refund_along_call_chain() {
+        let fees = RuntimeFeesConfig::test();
+        let mut prices = GasPriceSimulator::new(0, 100);
+        let purchased =
+            prices.pessimistic_gas_price(&fees, 0, fees.min_receipt_with_function_call_gas());
+        assert_eq!(purchased, 107);
+        prices.set_gas_price(2, 110);
+
+        // Cheaper block: the price difference of the burnt gas is refunded.
+        assert_eq!(
+            prices.refund(purchased, 1, &outcome(10, 5)),
+            GasRefund { balance: 5 * 107 + 10 * 7, deficit: 0 }
+        );
+        // More expensive block: the difference is taken out of the refund.
+        assert_eq!(
+            prices.refund(purchased, 2, &outcome(10, 5)),
+            GasRefund { balance: 5 * 107 - 10 * 3, deficit: 0 }
+        );
+        // ...and whatever the refund cannot cover becomes a deficit.
+        assert_eq!(
+            prices.refund(purchased, 3, &outcome(1000, 5)),
+            GasRefund { balance: 0, deficit: 1000 * 3 - 5 * 107 }
+        );
+    }
+
+    #[test]
+    fn test_refund_saturates() {
+        let mut prices = GasPriceSimulator::new(0, 1);
+        prices.set_gas_price(1, Balance::MAX);
+        assert_eq!(
+            prices.refund(Balance::MAX / 2, 0, &outcome(Gas::MAX, Gas::MAX)),
+            GasRefund { balance: Balance::MAX, deficit: 0 }
+        );
+        assert_eq!(
+            prices.refund(1, 1, &outcome(Gas::MAX, 0)),
+            GasRefund { balance: 0, deficit: Balance::MAX }
+        );
+    }
+}
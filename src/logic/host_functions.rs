@@ -0,0 +1,582 @@
+//! The host functions contracts can import, in one declarative list.
+//!
+//! Each function of the `host_functions!` invocation below declares its
+//! import module and name, its signature, the config flag and cargo feature
+//! gating it, and the parameters of the protocol it charges.  Everything else
+//! is derived from the list:
+//!
+//! * [`HOST_FUNCTIONS`], the registry of the functions compiled in, which
+//!   tells which ones are available at a protocol version and what they cost;
+//! * `for_each_available_import!`, which each backend in [`crate::imports`]
+//!   calls with a macro of its own to link the functions available at the
+//!   config of a call.
+//!
+//! Adding a host function thus only takes adding it to the list and to
+//! [`super::VMLogic`]: the backends cannot drift from each other.
+//!
+//! The linking is a macro rather than a loop over the registry because host
+//! functions have different signatures, so there isn't a trivial single type
+//! one can use to hold a host function, and because we want to use direct
+//! calls in the compiled WASM, so functions must be handed to the WASM
+//! runtimes as ZSTs rather than dynamically dispatched.  `host_functions! {
+//! foo, bar, baz }` expands to roughly
+//!
+//! ```ignore
+//! macro_rules! for_each_available_import {
+//!    $($config:expr, $M:ident) => {
+//!        $M!(foo);
+//!        $M!(bar);
+//!        $M!(baz);
+//!    }
+//! }
+//! ```
+//!
+//! calling `M!` only for the imports available with the config: we can add
+//! new imports, but we must make sure that they are only available to
+//! contracts at a specific protocol version -- we can't make imports
+//! retroactively available to old transactions.
+//!
+//! Host functions which are still being trialled on test networks live in a
+//! separate `env_experimental` import module rather than in `env`.  They are
+//! declared with `#[experimental_host_fns] @in env_experimental:`, so that
+//! every backend links them only with the `experimental_host_fns` flag of
+//! the config, which is never set on mainnet configs.  Without the flag,
+//! contracts importing anything from that module are rejected during
+//! preparation, so functions can be added, changed and removed there without
+//! affecting the stable `env` namespace.
+//!
+//! The costs of a function are the parameters specific to it: every function
+//! also pays `base` and the costs of the memory and registers it reads and
+//! writes, and the storage functions pay for the trie nodes they touch.
+
+use crate::logic::Config;
+use unc_parameters::{ActionCosts, ExtCosts, RuntimeConfigStore};
+use unc_primitives_core::types::{Gas, ProtocolVersion};
+
+/// Type of a parameter or a result of a host function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HostValType {
+    I32,
+    I64,
+}
+
+trait HostType {
+    const TYPE: HostValType;
+}
+
+impl std::fmt::Display for HostValType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::I32 => "u32",
+            Self::I64 => "u64",
+        })
+    }
+}
+
+impl HostType for u32 {
+    const TYPE: HostValType = HostValType::I32;
+}
+
+impl HostType for u64 {
+    const TYPE: HostValType = HostValType::I64;
+}
+
+/// A host function of the registry.
+#[derive(Clone, Copy, Debug)]
+pub struct HostFunction {
+    /// Import module, `env` but for the finite-wasm internals and the
+    /// experimental functions.
+    pub module: &'static str,
+    /// Import name, which is the name of the method of [`super::VMLogic`]
+    /// but for `gas`.
+    pub name: &'static str,
+    /// Names and types of the parameters.
+    pub params: &'static [(&'static str, HostValType)],
+    pub results: &'static [HostValType],
+    /// Field of [`Config`] enabling the function at a protocol version.
+    pub config_flag: Option<&'static str>,
+    /// Cargo feature the function is compiled in with.
+    pub cargo_feature: Option<&'static str>,
+    /// Parameters of the protocol specific to the function, see the module
+    /// documentation.
+    pub costs: &'static [ExtCosts],
+    /// Fees of the receipts and actions the function schedules.
+    pub fees: &'static [ActionCosts],
+    available: fn(&Config) -> bool,
+}
+
+impl HostFunction {
+    /// Whether contracts running with `config` can import the function.
+    pub fn is_available(&self, config: &Config) -> bool {
+        (self.available)(config)
+    }
+}
+
+/// The host functions compiled in, see [`HOST_FUNCTIONS`].
+#[derive(Clone, Copy, Debug)]
+pub struct HostFunctionRegistry {
+    functions: &'static [HostFunction],
+}
+
+impl HostFunctionRegistry {
+    /// Every host function compiled in, in the order of the list.
+    pub fn all(&self) -> &'static [HostFunction] {
+        self.functions
+    }
+
+    /// The host functions contracts running with `config` can import.
+    pub fn available<'a>(
+        &self,
+        config: &'a Config,
+    ) -> impl Iterator<Item = &'static HostFunction> + 'a {
+        self.functions.iter().filter(move |function| function.is_available(config))
+    }
+
+    /// The function `name` of `module`, if compiled in.
+    pub fn get(&self, module: &str, name: &str) -> Option<&'static HostFunction> {
+        self.functions.iter().find(|function| function.module == module && function.name == name)
+    }
+
+    /// Whether contracts running with `config` can import the function
+    /// `name` of `module`.
+    pub fn is_available(&self, config: &Config, module: &str, name: &str) -> bool {
+        self.get(module, name).is_some_and(|function| function.is_available(config))
+    }
+}
+
+/// A host function available at a protocol version, see [`host_functions`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HostFnInfo {
+    pub module: &'static str,
+    pub name: &'static str,
+    /// Names and types of the parameters.
+    pub params: &'static [(&'static str, HostValType)],
+    pub results: &'static [HostValType],
+    /// Parameters of the protocol specific to the function, with their gas
+    /// at the protocol version.
+    pub costs: Vec<(ExtCosts, Gas)>,
+    /// Fees of the receipts and actions the function schedules.
+    pub fees: &'static [ActionCosts],
+    /// Field of [`Config`] enabling the function, as in
+    /// [`HostFunction::config_flag`].
+    pub config_flag: Option<&'static str>,
+    /// Cargo feature the function is compiled in with.
+    pub cargo_feature: Option<&'static str>,
+}
+
+impl HostFnInfo {
+    /// Signature of the function as contracts import it, for instance
+    /// `register_len(register_id: u64) -> u64`.
+    pub fn signature(&self) -> String {
+        let params: Vec<_> = self.params.iter().map(|(name, ty)| format!("{name}: {ty}")).collect();
+        let mut signature = format!("{}({})", self.name, params.join(", "));
+        match self.results {
+            [] => {}
+            [result] => signature += &format!(" -> {result}"),
+            results => {
+                let results: Vec<_> = results.iter().map(ToString::to_string).collect();
+                signature += &format!(" -> ({})", results.join(", "));
+            }
+        }
+        signature
+    }
+}
+
+/// The host functions contracts can import at `protocol_version`, with the
+/// config of `store`, in the order of [`HOST_FUNCTIONS`].
+///
+/// These are exactly the functions the backends link, so that documentation
+/// and the bindings of the SDKs can be generated from them.
+pub fn host_functions(
+    protocol_version: ProtocolVersion,
+    store: &RuntimeConfigStore,
+) -> Vec<HostFnInfo> {
+    let config = &Config::from(store.get_config(protocol_version).wasm_config.clone());
+    HOST_FUNCTIONS
+        .available(config)
+        .map(|function| HostFnInfo {
+            module: function.module,
+            name: function.name,
+            params: function.params,
+            results: function.results,
+            costs: function.costs.iter().map(|cost| (*cost, cost.gas(&config.ext_costs))).collect(),
+            fees: function.fees,
+            config_flag: function.config_flag,
+            cargo_feature: function.cargo_feature,
+        })
+        .collect()
+}
+
+macro_rules! call_with_name {
+    ( $M:ident => @in $mod:ident : $func:ident < [ $( $arg_name:ident : $arg_type:ident ),* ] -> [ $( $returns:ident ),* ] > ) => {
+        $M!($mod / $func : $func < [ $( $arg_name : $arg_type ),* ] -> [ $( $returns ),* ] >)
+    };
+    ( $M:ident => @as $name:ident : $func:ident < [ $( $arg_name:ident : $arg_type:ident ),* ] -> [ $( $returns:ident ),* ] > ) => {
+        $M!(env / $name : $func < [ $( $arg_name : $arg_type ),* ] -> [ $( $returns ),* ] >)
+    };
+    ( $M:ident => $func:ident < [ $( $arg_name:ident : $arg_type:ident ),* ] -> [ $( $returns:ident ),* ] > ) => {
+        $M!(env / $func : $func < [ $( $arg_name : $arg_type ),* ] -> [ $( $returns ),* ] >)
+    };
+}
+pub(crate) use call_with_name;
+
+macro_rules! or_default {
+    ($default:ident) => {
+        stringify!($default)
+    };
+    ($default:ident $value:ident) => {
+        stringify!($value)
+    };
+}
+
+macro_rules! optional_str {
+    () => {
+        None
+    };
+    ($value:ident) => {
+        Some(stringify!($value))
+    };
+    ($value:literal) => {
+        Some($value)
+    };
+}
+
+macro_rules! host_functions {
+    (
+      $($(#[$config_field:ident])? $(##[$feature_name:literal])?
+        $( @in $mod:ident : )?
+        $( @as $name:ident : )?
+        $func:ident < [ $( $arg_name:ident : $arg_type:ident ),* ] -> [ $( $returns:ident ),* ] >
+        $( @costs [ $( $cost:ident ),* ] )?
+        $( @fees [ $( $fee:ident ),* ] )?,)*
+    ) => {
+        macro_rules! for_each_available_import {
+            ($config:expr, $M:ident) => {$(
+                $(#[cfg(feature = $feature_name)])?
+                if true $(&& ($config).$config_field)? {
+                    $crate::logic::host_functions::call_with_name!($M => $( @in $mod : )? $( @as $name : )? $func < [ $( $arg_name : $arg_type ),* ] -> [ $( $returns ),* ] >);
+                }
+            )*}
+        }
+        // Unused when no backend is compiled in.
+        #[allow(unused_imports)]
+        pub(crate) use for_each_available_import;
+
+        /// The host functions compiled in.
+        pub static HOST_FUNCTIONS: HostFunctionRegistry = HostFunctionRegistry { functions: &[$(
+            $(#[cfg(feature = $feature_name)])?
+            HostFunction {
+                module: or_default!(env $($mod)?),
+                name: or_default!($func $($name)?),
+                params: &[$( (stringify!($arg_name), <$arg_type as HostType>::TYPE) ),*],
+                results: &[$( <$returns as HostType>::TYPE ),*],
+                config_flag: optional_str!($($config_field)?),
+                cargo_feature: optional_str!($($feature_name)?),
+                costs: &[$($( ExtCosts::$cost ),*)?],
+                fees: &[$($( ActionCosts::$fee ),*)?],
+                available: |_config: &Config| true $(&& _config.$config_field)?,
+            },
+        )*]};
+    }
+}
+
+host_functions! {
+    // #########################
+    // # Finite-wasm internals #
+    // #########################
+    @in internal: finite_wasm_gas<[gas: u64] -> []>,
+    @in internal: finite_wasm_stack<[operand_size: u64, frame_size: u64] -> []>,
+    @in internal: finite_wasm_unstack<[operand_size: u64, frame_size: u64] -> []>,
+    // #############
+    // # Registers #
+    // #############
+    read_register<[register_id: u64, ptr: u64] -> []>,
+    register_len<[register_id: u64] -> [u64]>,
+    ##["protocol_feature_register_slice"] read_register_slice<[register_id: u64, offset: u64, len: u64, ptr: u64] -> []>,
+    write_register<[register_id: u64, data_len: u64, data_ptr: u64] -> []>,
+    // ###############
+    // # Context API #
+    // ###############
+    current_account_id<[register_id: u64] -> []>,
+    signer_account_id<[register_id: u64] -> []>,
+    signer_account_pk<[register_id: u64] -> []>,
+    predecessor_account_id<[register_id: u64] -> []>,
+    input<[register_id: u64] -> []>,
+    block_index<[] -> [u64]>,
+    block_timestamp<[] -> [u64]>,
+    epoch_height<[] -> [u64]>,
+    storage_usage<[] -> [u64]>,
+    // #################
+    // # Economics API #
+    // #################
+    account_balance<[balance_ptr: u64] -> []>,
+    account_locked_balance<[balance_ptr: u64] -> []>,
+    attached_deposit<[balance_ptr: u64] -> []>,
+    prepaid_gas<[] -> [u64]>,
+    used_gas<[] -> [u64]>,
+    // ############
+    // # Math API #
+    // ############
+    random_seed<[register_id: u64] -> []>,
+    sha256<[value_len: u64, value_ptr: u64, register_id: u64] -> []> @costs[sha256_base, sha256_byte],
+    keccak256<[value_len: u64, value_ptr: u64, register_id: u64] -> []> @costs[keccak256_base, keccak256_byte],
+    keccak512<[value_len: u64, value_ptr: u64, register_id: u64] -> []> @costs[keccak512_base, keccak512_byte],
+    #[ed25519_verify] ed25519_verify<[sig_len: u64,
+        sig_ptr: u64,
+        msg_len: u64,
+        msg_ptr: u64,
+        pub_key_len: u64,
+        pub_key_ptr: u64
+    ] -> [u64]> @costs[ed25519_verify_base, ed25519_verify_byte],
+    #[ed25519_verify] ##["protocol_feature_ed25519_verify_batch"] ed25519_verify_batch<[batch_len: u64, batch_ptr: u64] -> [u64]> @costs[ed25519_verify_byte],
+    #[math_extension] ripemd160<[value_len: u64, value_ptr: u64, register_id: u64] -> []> @costs[ripemd160_base, ripemd160_block],
+    #[math_extension] ecrecover<[hash_len: u64, hash_ptr: u64, sign_len: u64, sig_ptr: u64, v: u64, malleability_flag: u64, register_id: u64] -> [u64]> @costs[ecrecover_base],
+    #[math_extension] ##["protocol_feature_ecrecover_batch"] ecrecover_compressed<[hash_len: u64, hash_ptr: u64, sign_len: u64, sig_ptr: u64, v: u64, malleability_flag: u64, register_id: u64] -> [u64]> @costs[ecrecover_base],
+    #[math_extension] ##["protocol_feature_ecrecover_batch"] ecrecover_batch<[batch_len: u64, batch_ptr: u64, compressed: u64, register_id: u64] -> [u64]> @costs[ecrecover_base],
+    ##["protocol_feature_wide_math"] u256_muldiv<[a_ptr: u64, b_ptr: u64, denominator_ptr: u64, rounding: u64, result_ptr: u64] -> []>,
+    ##["protocol_feature_wide_math"] u256_pow<[base_ptr: u64, exponent: u64, result_ptr: u64] -> []>,
+    // #####################
+    // # Miscellaneous API #
+    // #####################
+    value_return<[value_len: u64, value_ptr: u64] -> []> @fees[new_data_receipt_byte],
+    panic<[] -> []>,
+    panic_utf8<[len: u64, ptr: u64] -> []>,
+    log_utf8<[len: u64, ptr: u64] -> []> @costs[log_base, log_byte],
+    log_utf16<[len: u64, ptr: u64] -> []> @costs[log_base, log_byte],
+    ##["protocol_feature_validate_utf8"] validate_utf8<[len: u64, ptr: u64] -> [u64]>,
+    abort<[msg_ptr: u32, filename_ptr: u32, line: u32, col: u32] -> []> @costs[log_base, log_byte],
+    // ################
+    // # Promises API #
+    // ################
+    promise_create<[
+        account_id_len: u64,
+        account_id_ptr: u64,
+        method_name_len: u64,
+        method_name_ptr: u64,
+        arguments_len: u64,
+        arguments_ptr: u64,
+        amount_ptr: u64,
+        gas: u64
+    ] -> [u64]> @fees[new_action_receipt, function_call_base, function_call_byte],
+    promise_then<[
+        promise_index: u64,
+        account_id_len: u64,
+        account_id_ptr: u64,
+        method_name_len: u64,
+        method_name_ptr: u64,
+        arguments_len: u64,
+        arguments_ptr: u64,
+        amount_ptr: u64,
+        gas: u64
+    ] -> [u64]> @fees[new_action_receipt, new_data_receipt_base, function_call_base, function_call_byte],
+    promise_and<[promise_idx_ptr: u64, promise_idx_count: u64] -> [u64]> @costs[promise_and_base, promise_and_per_promise],
+    promise_batch_create<[account_id_len: u64, account_id_ptr: u64] -> [u64]> @fees[new_action_receipt],
+    promise_batch_then<[promise_index: u64, account_id_len: u64, account_id_ptr: u64] -> [u64]> @fees[new_action_receipt, new_data_receipt_base],
+    // #######################
+    // # Promise API actions #
+    // #######################
+    promise_batch_action_create_account<[promise_index: u64] -> []> @fees[create_account],
+    promise_batch_action_deploy_contract<[promise_index: u64, code_len: u64, code_ptr: u64] -> []> @fees[deploy_contract_base, deploy_contract_byte],
+    ##["protocol_feature_global_contracts"] promise_batch_action_deploy_global_contract<[promise_index: u64, code_hash_ptr: u64] -> []> @fees[deploy_contract_base],
+    promise_batch_action_function_call<[
+        promise_index: u64,
+        method_name_len: u64,
+        method_name_ptr: u64,
+        arguments_len: u64,
+        arguments_ptr: u64,
+        amount_ptr: u64,
+        gas: u64
+    ] -> []> @fees[function_call_base, function_call_byte],
+    #[function_call_weight] promise_batch_action_function_call_weight<[
+        promise_index: u64,
+        method_name_len: u64,
+        method_name_ptr: u64,
+        arguments_len: u64,
+        arguments_ptr: u64,
+        amount_ptr: u64,
+        gas: u64,
+        gas_weight: u64
+    ] -> []> @fees[function_call_base, function_call_byte],
+    promise_batch_action_transfer<[promise_index: u64, amount_ptr: u64] -> []> @fees[transfer],
+    promise_batch_action_stake<[
+        promise_index: u64,
+        amount_ptr: u64,
+        public_key_len: u64,
+        public_key_ptr: u64
+    ] -> []> @fees[stake],
+    promise_batch_action_add_key_with_full_access<[
+        promise_index: u64,
+        public_key_len: u64,
+        public_key_ptr: u64,
+        nonce: u64
+    ] -> []> @fees[add_full_access_key],
+    promise_batch_action_add_key_with_function_call<[
+        promise_index: u64,
+        public_key_len: u64,
+        public_key_ptr: u64,
+        nonce: u64,
+        allowance_ptr: u64,
+        receiver_id_len: u64,
+        receiver_id_ptr: u64,
+        method_names_len: u64,
+        method_names_ptr: u64
+    ] -> []> @fees[add_function_call_key_base, add_function_call_key_byte],
+    promise_batch_action_delete_key<[
+        promise_index: u64,
+        public_key_len: u64,
+        public_key_ptr: u64
+    ] -> []> @fees[delete_key],
+    promise_batch_action_delete_account<[
+        promise_index: u64,
+        beneficiary_id_len: u64,
+        beneficiary_id_ptr: u64
+    ] -> []> @fees[delete_account],
+    // #######################
+    // # Promise API results #
+    // #######################
+    promise_results_count<[] -> [u64]>,
+    promise_result<[result_idx: u64, register_id: u64] -> [u64]>,
+    promise_return<[promise_idx: u64] -> []> @costs[promise_return],
+    // ####################
+    // # Scratch area API #
+    // ####################
+    ##["protocol_feature_scratch_area"] scratch_read<[register_id: u64] -> [u64]>,
+    ##["protocol_feature_scratch_area"] promise_batch_scratch_write<[
+        promise_index: u64,
+        data_len: u64,
+        data_ptr: u64
+    ] -> []> @fees[new_data_receipt_byte],
+    // ##################
+    // # Formatting API #
+    // ##################
+    ##["protocol_feature_format_host_fns"] format_u128<[value_ptr: u64, register_id: u64] -> []>,
+    ##["protocol_feature_format_host_fns"] format_fixed_point<[value_ptr: u64, decimals: u64, register_id: u64] -> []>,
+    // ###############
+    // # Storage API #
+    // ###############
+    storage_write<[key_len: u64, key_ptr: u64, value_len: u64, value_ptr: u64, register_id: u64] -> [u64]> @costs[storage_write_base, storage_write_key_byte, storage_write_value_byte, storage_write_evicted_byte],
+    storage_read<[key_len: u64, key_ptr: u64, register_id: u64] -> [u64]> @costs[storage_read_base, storage_read_key_byte, storage_read_value_byte],
+    storage_remove<[key_len: u64, key_ptr: u64, register_id: u64] -> [u64]> @costs[storage_remove_base, storage_remove_key_byte, storage_remove_ret_value_byte],
+    storage_has_key<[key_len: u64, key_ptr: u64] -> [u64]> @costs[storage_has_key_base, storage_has_key_byte],
+    storage_iter_prefix<[prefix_len: u64, prefix_ptr: u64] -> [u64]>,
+    storage_iter_range<[start_len: u64, start_ptr: u64, end_len: u64, end_ptr: u64] -> [u64]>,
+    storage_iter_next<[iterator_id: u64, key_register_id: u64, value_register_id: u64] -> [u64]>,
+    // Function for the injected gas counter. Automatically called by the gas meter.
+    @as gas: gas_seen_from_wasm<[gas_amount: u32] -> []>,
+    // ###############
+    // # Validator API #
+    // ###############
+    validator_frozen<[account_id_len: u64, account_id_ptr: u64, frozen_ptr: u64] -> []> @costs[validator_frozen_base],
+    validator_total_frozen<[frozen_ptr: u64] -> []> @costs[validator_total_frozen_base],
+    validator_power<[account_id_len: u64, account_id_ptr: u64, power_ptr: u64] -> []> @costs[validator_power_base],
+    validator_total_power<[power_ptr: u64] -> []> @costs[validator_total_power_base],
+    // ###############
+    // # Account API #
+    // ###############
+    ##["protocol_feature_global_contracts"] account_code_hash<[account_id_len: u64, account_id_ptr: u64, register_id: u64] -> [u64]> @costs[storage_read_base],
+    // #############
+    // # Alt BN128 #
+    // #############
+    #[alt_bn128] alt_bn128_g1_multiexp<[value_len: u64, value_ptr: u64, register_id: u64] -> []> @costs[alt_bn128_g1_multiexp_base, alt_bn128_g1_multiexp_element],
+    #[alt_bn128] alt_bn128_g1_sum<[value_len: u64, value_ptr: u64, register_id: u64] -> []> @costs[alt_bn128_g1_sum_base, alt_bn128_g1_sum_element],
+    #[alt_bn128] alt_bn128_pairing_check<[value_len: u64, value_ptr: u64] -> [u64]> @costs[alt_bn128_pairing_check_base, alt_bn128_pairing_check_element],
+    #[alt_bn128] ##["protocol_feature_alt_bn128_g2"] alt_bn128_g2_multiexp<[value_len: u64, value_ptr: u64, register_id: u64] -> []> @costs[alt_bn128_g1_multiexp_base, alt_bn128_g1_multiexp_element],
+    #[alt_bn128] ##["protocol_feature_alt_bn128_g2"] alt_bn128_pairing_check_batch<[value_len: u64, value_ptr: u64] -> [u64]> @costs[alt_bn128_pairing_check_base, alt_bn128_pairing_check_element],
+    // #############
+    // # BLS12-381 #
+    // #############
+    ##["protocol_feature_bls12381"] bls12381_g1_sum<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
+    ##["protocol_feature_bls12381"] bls12381_g2_sum<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
+    ##["protocol_feature_bls12381"] bls12381_pairing_check<[value_len: u64, value_ptr: u64] -> [u64]>,
+    ##["protocol_feature_bls12381"] bls12381_map_to_curve<[value_len: u64, value_ptr: u64, register_id: u64] -> []>,
+    // #############
+    // #  Sandbox  #
+    // #############
+    ##["sandbox"] sandbox_debug_log<[len: u64, ptr: u64] -> []>,
+    // ##########################
+    // # Experimental namespace #
+    // ##########################
+    // Functions trialled on test networks are added here as
+    // `#[experimental_host_fns] @in env_experimental: name<[...] -> [...]>`.
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_vm_config;
+
+    #[test]
+    fn test_registry() {
+        let sha256 = HOST_FUNCTIONS.get("env", "sha256").unwrap();
+        assert_eq!(sha256.params.len(), 3);
+        assert_eq!(sha256.params[0], ("value_len", HostValType::I64));
+        assert_eq!(sha256.results, &[]);
+        assert_eq!(sha256.costs, &[ExtCosts::sha256_base, ExtCosts::sha256_byte]);
+        assert_eq!(sha256.config_flag, None);
+
+        let gas = HOST_FUNCTIONS.get("env", "gas").unwrap();
+        assert_eq!(gas.params, &[("gas_amount", HostValType::I32)]);
+        assert!(HOST_FUNCTIONS.get("env", "gas_seen_from_wasm").is_none());
+        assert!(HOST_FUNCTIONS.get("internal", "finite_wasm_gas").is_some());
+
+        let transfer = HOST_FUNCTIONS.get("env", "promise_batch_action_transfer").unwrap();
+        assert_eq!(transfer.fees, &[ActionCosts::transfer]);
+
+        let mut config = test_vm_config();
+        config.ed25519_verify = true;
+        assert!(HOST_FUNCTIONS.is_available(&config, "env", "ed25519_verify"));
+        config.ed25519_verify = false;
+        assert!(!HOST_FUNCTIONS.is_available(&config, "env", "ed25519_verify"));
+        assert_eq!(
+            HOST_FUNCTIONS.get("env", "ed25519_verify").unwrap().config_flag,
+            Some("ed25519_verify")
+        );
+        assert!(!HOST_FUNCTIONS.is_available(&config, "env", "memory"));
+    }
+
+    #[test]
+    fn test_host_functions() {
+        let store = RuntimeConfigStore::test();
+        let version = unc_primitives_core::version::PROTOCOL_VERSION;
+        let functions = host_functions(version, &store);
+        let config = &store.get_config(version).wasm_config;
+        assert_eq!(functions.len(), HOST_FUNCTIONS.available(config).count());
+
+        let sha256 = functions.iter().find(|function| function.name == "sha256").unwrap();
+        assert_eq!(sha256.signature(), "sha256(value_len: u64, value_ptr: u64, register_id: u64)");
+        assert_eq!(
+            sha256.costs,
+            [
+                (ExtCosts::sha256_base, config.ext_costs.gas_cost(ExtCosts::sha256_base)),
+                (ExtCosts::sha256_byte, config.ext_costs.gas_cost(ExtCosts::sha256_byte)),
+            ]
+        );
+        let register_len = functions.iter().find(|function| function.name == "register_len");
+        assert_eq!(register_len.unwrap().signature(), "register_len(register_id: u64) -> u64");
+    }
+
+    /// The backends link exactly the functions the registry says are
+    /// available.
+    #[test]
+    fn test_registry_matches_imports() {
+        let mut config = test_vm_config();
+        for enabled in [false, true] {
+            config.ed25519_verify = enabled;
+            config.math_extension = enabled;
+            config.alt_bn128 = enabled;
+            config.function_call_weight = enabled;
+            let mut linked = Vec::new();
+            macro_rules! link {
+                ($mod:ident / $name:ident : $func:ident < [ $( $arg_name:ident : $arg_type:ident ),* ] -> [ $( $returns:ident ),* ] >) => {
+                    linked.push((stringify!($mod), stringify!($name)));
+                };
+            }
+            for_each_available_import!(config, link);
+            let available: Vec<_> = HOST_FUNCTIONS
+                .available(&config)
+                .map(|function| (function.module, function.name))
+                .collect();
+            assert_eq!(linked, available);
+        }
+    }
+}
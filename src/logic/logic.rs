@@ -1,26 +1,51 @@
 use super::context::VMContext;
+use super::custom_host_functions::{CustomHostContext, CustomHostFunctionRegistry};
 use super::dependencies::{External, MemSlice, MemoryLike};
-use super::errors::{FunctionCallError, InconsistentStateError};
-use super::gas_counter::{FastGasCounter, GasCounter};
-use super::types::{PromiseIndex, PromiseResult, ReceiptIndex, ReturnData};
+use super::errors::{FunctionCallError, InconsistentStateError, VMRunnerError, WasmTrap};
+use super::gas_counter::{FastGasCounter, GasCounter, GasProfile};
+use super::gas_planning::{self, ActionGas};
+use super::state_witness::{StateWitness, StateWitnessRecorder};
+use super::storage_buffer::StorageBuffer;
+use super::types::{
+    ActionReceipt, HostCallCheckpoint, HostCallRecord, HostCallValues, PromiseIndex, PromiseResult,
+    ReceiptAction, ReceiptCost, ReceiptIndex, ReturnData, StorageBytes, StorageUsageDelta,
+    UsedMemory,
+};
 use super::utils::split_method_names;
+use super::watchdog::{Interrupt, Watchdog};
+use super::wide_math;
 use super::ValuePtr;
 use super::{HostError, VMLogicError};
+use crate::clock::Clock;
+use crate::log_sink::LogCapture;
+use crate::return_sink::ReturnSink;
+use crate::runner::{CodePricing, RunOptions};
 use crate::ProfileDataV3;
+#[cfg(feature = "secp256k1")]
 use unc_crypto::Secp256K1Signature;
-use unc_parameters::vm::{Config, StorageGetMode};
+use crate::logic::Config;
+use unc_parameters::vm::StorageGetMode;
 use unc_parameters::{
     transfer_exec_fee, transfer_send_fee, ActionCosts, ExtCosts, RuntimeFeesConfig,
 };
 use unc_primitives_core::config::ViewConfig;
+use unc_primitives_core::hash::CryptoHash;
+use unc_primitives_core::serialize::dec_format;
 use unc_primitives_core::types::{
     AccountId, Balance, Compute, EpochHeight, Gas, GasWeight, StorageUsage,
 };
+use borsh::{BorshDeserialize, BorshSerialize};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
 use std::mem::size_of;
+use std::sync::Arc;
+use std::time::Instant;
 use ExtCosts::*;
 
 pub type Result<T, E = VMLogicError> = ::std::result::Result<T, E>;
 
+const WASM_PAGE_SIZE: u64 = 64 * 1024;
+
 #[cfg(feature = "io_trace")]
 fn base64(s: &[u8]) -> String {
     use base64::Engine;
@@ -51,22 +76,97 @@ pub struct VMLogic<'a> {
     current_account_locked_balance: Balance,
     /// Storage usage of the current account at the moment
     current_storage_usage: StorageUsage,
+    /// Storage usage added and removed so far.
+    storage_delta: StorageUsageDelta,
+    /// Storage writes not yet applied to the `ext`, see
+    /// [`Config::buffer_storage_writes`].
+    storage_buffer: Option<StorageBuffer>,
+    /// Keys touched by the call and their proofs, see
+    /// [`RunOptions::record_state_witness`].
+    state_witness: Option<StateWitnessRecorder>,
+    /// Interrupts the call at its deadline, if it has one.  Declared before
+    /// the gas counter it interrupts so that it is dropped first.
+    watchdog: Option<Watchdog>,
     gas_counter: GasCounter,
     /// What method returns.
     return_data: ReturnData,
-    /// Logs written by the runtime.
+    /// Logs written by the runtime, unless they are only streamed.
     logs: Vec<String>,
+    /// Number of logs written, including the ones streamed.
+    log_count: u64,
+    /// Where the logs go, see [`RunOptions::log_capture`].
+    log_capture: LogCapture,
+    /// Where the return value goes, see [`RunOptions::return_sink`].
+    return_sink: Option<Arc<dyn ReturnSink>>,
+    /// Length of the value streamed to the return sink last, unless the
+    /// method returned something else since.
+    streamed_return: Option<u64>,
     /// Registers can be used by the guest to store blobs of data without moving them across
     /// host-guest boundary.
     registers: super::vmstate::Registers,
+    /// Input of the call shared with the registers, set by the first
+    /// [`VMLogic::input`].
+    shared_input: Option<Arc<[u8]>>,
 
     /// The DAG of promises, indexed by promise id.
     promises: Vec<Promise>,
+    /// Receipts created so far, in creation order, only recorded with
+    /// [`RunOptions::record_receipts`].
+    receipts: Option<Vec<ActionReceipt>>,
+    /// Gas and tokens attached to each of `receipts`.
+    receipt_costs: Vec<ReceiptCost>,
     /// Tracks the total log length. The sum of length of all logs.
     total_log_length: u64,
 
     /// Stores the amount of stack space remaining
     remaining_stack: u64,
+    /// Whether the call failed because it ran out of `remaining_stack`.
+    stack_exhausted: bool,
+
+    /// State of the call at each host function called, if requested.
+    checkpoints: Option<Vec<HostCallCheckpoint>>,
+
+    /// Host function calls of the contract, if requested.
+    host_calls: Option<Vec<HostCallRecord>>,
+
+    /// Hash of the host functions called so far with the gas burnt before
+    /// each, if requested.
+    execution_fingerprint: Option<Sha256>,
+
+    /// Extra cost of loading the contract set by the embedder.
+    code_pricing: Option<Arc<dyn CodePricing>>,
+    /// Host functions of the embedder, see
+    /// [`crate::ConsensusHooks::custom_host_functions`].
+    custom_host_functions: Option<Arc<CustomHostFunctionRegistry>>,
+
+    /// Wasm call stack at the point the execution ran out of gas, only
+    /// recorded with [`RunOptions::record_gas_exhaustion_trace`], see
+    /// [`Self::record_abort_trace`].
+    gas_exhaustion_trace: Option<Vec<WasmFrame>>,
+
+    /// Bytes of memory the call may use, see
+    /// [`crate::logic::ExtraLimitConfig::memory_cap`].
+    memory_cap: Option<u64>,
+    /// Memory used when the call went over `memory_cap`, which failed it.
+    memory_cap_exceeded: Option<u64>,
+    /// Peaks of the memory used so far.
+    used_memory: UsedMemory,
+    /// Borsh size of the receipts created so far, see [`UsedMemory`].
+    receipts_memory_usage: u64,
+
+    /// Block counters of code instrumented for coverage, see
+    /// [`RunOptions::coverage`].
+    #[cfg(feature = "coverage")]
+    coverage: Option<Arc<crate::CoverageCounters>>,
+
+    /// Code of the contract, kept to symbolicate the wasm call stack of the
+    /// call if it aborts, see [`RunOptions::backtrace`].
+    #[cfg(feature = "backtrace")]
+    backtrace_code: Option<&'a crate::ContractCode>,
+    /// Wasm call stack at the point the call aborted, collected with
+    /// `backtrace_code`.
+    #[cfg(feature = "backtrace")]
+    abort_trace: Vec<WasmFrame>,
 }
 
 /// Promises API allows to create a DAG-structure that defines dependencies between smart contract
@@ -159,16 +259,149 @@ impl<'a> VMLogic<'a> {
             current_account_balance,
             current_account_locked_balance,
             current_storage_usage,
+            storage_delta: StorageUsageDelta::new(),
+            storage_buffer: config.buffer_storage_writes.then(StorageBuffer::default),
+            state_witness: None,
+            watchdog: None,
             gas_counter,
             return_data: ReturnData::None,
             logs: vec![],
+            log_count: 0,
+            log_capture: LogCapture::Buffer,
+            return_sink: None,
+            streamed_return: None,
             registers: Default::default(),
+            shared_input: None,
             promises: vec![],
+            receipts: None,
+            receipt_costs: vec![],
             total_log_length: 0,
             remaining_stack: u64::from(config.limit_config.max_stack_height),
+            stack_exhausted: false,
+            checkpoints: None,
+            host_calls: None,
+            execution_fingerprint: None,
+            code_pricing: None,
+            custom_host_functions: None,
+            gas_exhaustion_trace: None,
+            memory_cap: config.extra_limits.memory_cap,
+            memory_cap_exceeded: None,
+            used_memory: UsedMemory::default(),
+            receipts_memory_usage: 0,
+            #[cfg(feature = "coverage")]
+            coverage: None,
+            #[cfg(feature = "backtrace")]
+            backtrace_code: None,
+            #[cfg(feature = "backtrace")]
+            abort_trace: Vec::new(),
+        }
+    }
+
+    /// Applies the options of the call, see [`RunOptions`].
+    ///
+    /// Fails with [`VMRunnerError::ConsensusHooksDisabled`] for hooks the
+    /// config does not enable.
+    pub(crate) fn apply_run_options(&mut self, options: &RunOptions) -> Result<(), VMRunnerError> {
+        self.apply_run_options_with(options, Interrupt::Signal)
+    }
+
+    /// Same as [`Self::apply_run_options`], the deadline interrupting the
+    /// call with `interrupt`.
+    pub(crate) fn apply_run_options_with(
+        &mut self,
+        options: &RunOptions,
+        interrupt: Interrupt,
+    ) -> Result<(), VMRunnerError> {
+        if !options.consensus.is_empty() && !self.config.consensus_hooks {
+            return Err(VMRunnerError::ConsensusHooksDisabled);
+        }
+        if let Some(deadline) = options.deadline {
+            self.set_deadline(deadline, options.clock.clone(), interrupt);
+        }
+        if options.record_checkpoints {
+            self.checkpoints = Some(Vec::new());
+        }
+        if options.record_host_calls {
+            self.host_calls = Some(Vec::new());
+        }
+        if options.record_receipts {
+            self.receipts = Some(Vec::new());
+        }
+        if options.record_gas_exhaustion_trace {
+            self.gas_exhaustion_trace = Some(Vec::new());
+        }
+        if options.execution_fingerprint {
+            self.execution_fingerprint = Some(Sha256::new());
+        }
+        self.code_pricing = options.consensus.code_pricing.clone();
+        self.custom_host_functions = options.consensus.custom_host_functions.clone();
+        self.log_capture = options.log_capture.clone();
+        self.return_sink = options.return_sink.clone();
+        if options.record_state_witness {
+            self.state_witness = Some(StateWitnessRecorder::default());
+        }
+        #[cfg(feature = "coverage")]
+        {
+            self.coverage = options.coverage.clone();
+        }
+        Ok(())
+    }
+
+    /// Keeps `code`, the contract being called, to symbolicate the wasm call
+    /// stack of the call if [`RunOptions::backtrace`] is set and the call
+    /// aborts.
+    ///
+    /// Runners call this after [`Self::apply_run_options`].
+    #[cfg(feature = "backtrace")]
+    pub(crate) fn keep_code_for_backtrace(
+        &mut self,
+        code: &'a crate::ContractCode,
+        options: &RunOptions,
+    ) {
+        if options.backtrace {
+            self.backtrace_code = Some(code);
+        }
+    }
+
+    /// Applies the buffered storage writes to the `ext`, once the call has
+    /// succeeded.
+    fn commit_storage(&mut self) -> Result<()> {
+        match self.storage_buffer.take() {
+            Some(buffer) => buffer.commit(self.ext),
+            None => Ok(()),
+        }
+    }
+
+    /// Interrupts the call once `deadline` passes on `clock`, the system clock
+    /// if `None`.
+    ///
+    /// The call then fails with [`FunctionCallError::Timeout`] at its next gas
+    /// charge, or epoch check on Wasmtime.
+    fn set_deadline(
+        &mut self,
+        deadline: Instant,
+        clock: Option<Arc<dyn Clock>>,
+        interrupt: Interrupt,
+    ) {
+        // SAFETY: the watchdog is stopped before the gas counter is dropped,
+        // see the `watchdog` field, and only lowers the limit on this thread.
+        let gas = unsafe { self.gas_counter.interrupt_handle() };
+        self.watchdog = Some(Watchdog::start(deadline, clock, gas, interrupt));
+    }
+
+    /// Tells the return sink, if any, that the call ended, and whether it
+    /// `succeeded` with the value streamed last as its result.
+    fn finish_return_stream(&self, succeeded: bool) {
+        if let Some(sink) = &self.return_sink {
+            sink.finish(self.streamed_return.filter(|_| succeeded));
         }
     }
 
+    /// Stops the watchdog and returns whether it interrupted the call.
+    fn stop_watchdog(&mut self) -> bool {
+        self.watchdog.take().map_or(false, Watchdog::stop)
+    }
+
     /// Returns reference to logs that have been created so far.
     pub fn logs(&self) -> &[String] {
         &self.logs
@@ -205,17 +438,22 @@ impl<'a> VMLogic<'a> {
         self.remaining_stack =
             match self.remaining_stack.checked_sub(operand_size.saturating_add(frame_size)) {
                 Some(s) => s,
-                None => return Err(VMLogicError::HostError(HostError::MemoryAccessViolation)),
+                None => {
+                    self.stack_exhausted = true;
+                    return Err(VMLogicError::HostError(HostError::MemoryAccessViolation));
+                }
             };
-        self.gas(((frame_size + 7) / 8) * u64::from(self.config.regular_op_cost))?;
+        self.gas(frame_size.div_ceil(8).saturating_mul(u64::from(self.config.regular_op_cost)))?;
         Ok(())
     }
 
     pub fn finite_wasm_unstack(&mut self, operand_size: u64, frame_size: u64) -> Result<()> {
+        // The instrumentation releases the stack it reserved, so this only
+        // overflows if it is broken.
         self.remaining_stack = self
             .remaining_stack
             .checked_add(operand_size.saturating_add(frame_size))
-            .expect("remaining stack integer overflow");
+            .ok_or(InconsistentStateError::IntegerOverflow)?;
         Ok(())
     }
 
@@ -271,6 +509,40 @@ impl<'a> VMLogic<'a> {
         Ok(self.registers.get_len(register_id).unwrap_or(u64::MAX))
     }
 
+    /// Writes `len` bytes of the register `register_id` starting at `offset` into the memory of
+    /// the guest starting with `ptr`.
+    ///
+    /// Together with `register_len`, lets a contract process a large value in chunks without
+    /// copying all of it into its memory.
+    ///
+    /// # Arguments
+    ///
+    /// * `register_id` -- a register id from where to read the data;
+    /// * `offset` -- offset in the register of the first byte to read;
+    /// * `len` -- number of bytes to read;
+    /// * `ptr` -- location on guest memory where to copy the data.
+    ///
+    /// # Errors
+    ///
+    /// * If `register_id` is pointing to unused register returns `InvalidRegisterId` error message;
+    /// * If `offset + len` is larger than the register or the content extends outside the memory
+    ///   allocated to the guest returns `MemoryAccessViolation` error message.
+    ///
+    /// # Cost
+    ///
+    /// `base + read_register_base + read_register_byte * len + write_memory_base + write_memory_byte * len`
+    pub fn read_register_slice(
+        &mut self,
+        register_id: u64,
+        offset: u64,
+        len: u64,
+        ptr: u64,
+    ) -> Result<()> {
+        self.gas_counter.pay_base(base)?;
+        let data = self.registers.get_slice(&mut self.gas_counter, register_id, offset, len)?;
+        self.memory.set(&mut self.gas_counter, ptr, data)
+    }
+
     /// Copies `data` from the guest memory into the register. If register is unused will initialize
     /// it. If register has larger capacity than needed for `data` will not re-allocate it. The
     /// register will lose the pre-existing data if any.
@@ -308,10 +580,10 @@ impl<'a> VMLogic<'a> {
     /// # Cost
     ///
     /// For not nul-terminated string:
-    /// `read_memory_base + read_memory_byte * num_bytes + utf8_decoding_base + utf8_decoding_byte * num_bytes`
+    /// `read_memory_base + read_memory_byte * num_bytes + utf8_decoding_base + (utf8_decoding_byte + log_decoding_byte) * num_bytes`
     ///
     /// For nul-terminated string:
-    /// `(read_memory_base + read_memory_byte) * num_bytes + utf8_decoding_base + utf8_decoding_byte * num_bytes`
+    /// `(read_memory_base + read_memory_byte) * num_bytes + utf8_decoding_base + (utf8_decoding_byte + log_decoding_byte) * num_bytes`
     fn get_utf8_string(&mut self, len: u64, ptr: u64) -> Result<String> {
         self.gas_counter.pay_base(utf8_decoding_base)?;
         let mut buf;
@@ -337,6 +609,8 @@ impl<'a> VMLogic<'a> {
             }
         }
         self.gas_counter.pay_per(utf8_decoding_byte, buf.len() as _)?;
+        self.gas_counter
+            .pay_extra(self.config.extra_ext_costs.log_decoding_byte, buf.len() as _)?;
         String::from_utf8(buf).map_err(|_| HostError::BadUTF8.into())
     }
 
@@ -361,10 +635,10 @@ impl<'a> VMLogic<'a> {
     /// # Cost
     ///
     /// For not nul-terminated string:
-    /// `read_memory_base + read_memory_byte * num_bytes + utf16_decoding_base + utf16_decoding_byte * num_bytes`
+    /// `read_memory_base + read_memory_byte * num_bytes + utf16_decoding_base + (utf16_decoding_byte + log_decoding_byte) * num_bytes`
     ///
     /// For nul-terminated string:
-    /// `read_memory_base * num_bytes / 2 + read_memory_byte * num_bytes + utf16_decoding_base + utf16_decoding_byte * num_bytes`
+    /// `read_memory_base * num_bytes / 2 + read_memory_byte * num_bytes + utf16_decoding_base + (utf16_decoding_byte + log_decoding_byte) * num_bytes`
     fn get_utf16_string(&mut self, mut len: u64, ptr: u64) -> Result<String> {
         self.gas_counter.pay_base(utf16_decoding_base)?;
         let max_len =
@@ -377,12 +651,24 @@ impl<'a> VMLogic<'a> {
             self.memory.view(&mut self.gas_counter, MemSlice { ptr, len })
         }?;
 
+        // With `log_decoding_cost`, odd lengths are rejected after charging
+        // for decoding, like any other invalid UTF-16.
+        let charge_before_validation = self.config.log_decoding_cost;
+        if charge_before_validation {
+            if len > max_len {
+                return self.total_log_length_exceeded(len);
+            }
+            self.gas_counter.pay_per(utf16_decoding_byte, len)?;
+            self.gas_counter.pay_extra(self.config.extra_ext_costs.log_decoding_byte, len)?;
+        }
         let input = stdx::as_chunks_exact(&mem_view).map_err(|_| HostError::BadUTF16)?;
-        if len > max_len {
-            return self.total_log_length_exceeded(len);
+        if !charge_before_validation {
+            if len > max_len {
+                return self.total_log_length_exceeded(len);
+            }
+            self.gas_counter.pay_per(utf16_decoding_byte, len)?;
+            self.gas_counter.pay_extra(self.config.extra_ext_costs.log_decoding_byte, len)?;
         }
-
-        self.gas_counter.pay_per(utf16_decoding_byte, len)?;
         char::decode_utf16(input.into_iter().copied().map(u16::from_le_bytes))
             .collect::<Result<String, _>>()
             .map_err(|_| HostError::BadUTF16.into())
@@ -413,7 +699,7 @@ impl<'a> VMLogic<'a> {
 
     /// Checks that the current log number didn't reach the limit yet, so we can add a new message.
     fn check_can_add_a_log_message(&self) -> Result<()> {
-        if self.logs.len() as u64 >= self.config.limit_config.max_number_logs {
+        if self.log_count >= self.config.limit_config.max_number_logs {
             Err(HostError::NumberOfLogsExceeded { limit: self.config.limit_config.max_number_logs }
                 .into())
         } else {
@@ -421,6 +707,109 @@ impl<'a> VMLogic<'a> {
         }
     }
 
+    /// Records a receipt created through [`External::create_receipt`] for
+    /// `gas` of fees, see [`VMOutcome::receipts`].
+    fn record_receipt(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        receiver_id: AccountId,
+        dependencies: Vec<ReceiptIndex>,
+        gas: ActionGas,
+    ) {
+        if self.memory_cap.is_some() {
+            let usage = borsh::object_length(&(&receiver_id, &dependencies)).unwrap_or_default();
+            self.receipts_memory_usage += usage as u64;
+        }
+        if let Some(receipts) = &mut self.receipts {
+            // Receipts can only depend on receipts created before them.
+            debug_assert!(dependencies
+                .iter()
+                .all(|dep| receipts.iter().any(|receipt| receipt.receipt_index == *dep)));
+            receipts.push(ActionReceipt {
+                receipt_index,
+                receiver_id,
+                dependencies,
+                actions: Vec::new(),
+            });
+        }
+        self.receipt_costs.push(ReceiptCost {
+            receipt_index,
+            send_gas: 0,
+            exec_gas: 0,
+            prepaid_gas: 0,
+            gas_weight: 0,
+            deposit: 0,
+        });
+        self.charge_receipt(receipt_index, gas);
+    }
+
+    /// Whether the actions appended to receipts are recorded or measured, so
+    /// that the actions costly to make, with the hash of a contract or a copy
+    /// of arguments, are only made then.
+    fn keeps_actions(&self) -> bool {
+        self.receipts.is_some() || self.memory_cap.is_some()
+    }
+
+    /// Records an action appended to a receipt through the [`External`] for
+    /// `gas` of fees.
+    fn record_action(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        action: ReceiptAction,
+        gas: ActionGas,
+    ) {
+        match &action {
+            ReceiptAction::FunctionCall { attached_deposit, prepaid_gas, gas_weight, .. } => {
+                self.attach_to_receipt(receipt_index, *prepaid_gas, *gas_weight, *attached_deposit)
+            }
+            ReceiptAction::Transfer { deposit } => {
+                self.attach_to_receipt(receipt_index, 0, 0, *deposit)
+            }
+            _ => {}
+        }
+        self.charge_receipt(receipt_index, gas);
+        if self.memory_cap.is_some() {
+            let usage = borsh::object_length(&action).unwrap_or_default();
+            self.receipts_memory_usage += usage as u64;
+        }
+        let Some(receipts) = &mut self.receipts else { return };
+        let receipt =
+            receipts.iter_mut().rev().find(|receipt| receipt.receipt_index == receipt_index);
+        if let Some(receipt) = receipt {
+            receipt.actions.push(action);
+        } else {
+            debug_assert!(false, "action appended to unknown receipt {receipt_index}");
+        }
+    }
+
+    /// Adds the gas and tokens an action attaches to a receipt to its
+    /// [`ReceiptCost`].
+    fn attach_to_receipt(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        prepaid_gas: Gas,
+        gas_weight: u64,
+        deposit: Balance,
+    ) {
+        if let Some(cost) = self.receipt_cost(receipt_index) {
+            cost.prepaid_gas = cost.prepaid_gas.saturating_add(prepaid_gas);
+            cost.gas_weight = cost.gas_weight.saturating_add(gas_weight);
+            cost.deposit = cost.deposit.saturating_add(deposit);
+        }
+    }
+
+    /// Adds `gas` of fees paid for a receipt to its [`ReceiptCost`].
+    fn charge_receipt(&mut self, receipt_index: ReceiptIndex, gas: ActionGas) {
+        if let Some(cost) = self.receipt_cost(receipt_index) {
+            cost.send_gas = cost.send_gas.saturating_add(gas.burnt);
+            cost.exec_gas = cost.exec_gas.saturating_add(gas.used.saturating_sub(gas.burnt));
+        }
+    }
+
+    fn receipt_cost(&mut self, receipt_index: ReceiptIndex) -> Option<&mut ReceiptCost> {
+        self.receipt_costs.iter_mut().rev().find(|cost| cost.receipt_index == receipt_index)
+    }
+
     /// Adds a given promise to the vector of promises and returns a new promise index.
     /// Throws `NumberPromisesExceeded` if the total number of promises exceeded the limit.
     fn checked_push_promise(&mut self, promise: Promise) -> Result<PromiseIndex> {
@@ -439,13 +828,24 @@ impl<'a> VMLogic<'a> {
         }
     }
 
+    /// Pays the `log_emit_byte` of the `len` bytes of a message to log.
+    fn pay_log_emit(&mut self, len: usize) -> Result<()> {
+        self.gas_counter.pay_extra(self.config.extra_ext_costs.log_emit_byte, len as u64)
+    }
+
     fn checked_push_log(&mut self, message: String) -> Result<()> {
         // The size of logged data can't be too large. No overflow.
         self.total_log_length += message.len() as u64;
         if self.total_log_length > self.config.limit_config.max_total_log_length {
             return self.total_log_length_exceeded(0);
         }
-        self.logs.push(message);
+        self.log_count += 1;
+        if let Some(sink) = self.log_capture.sink() {
+            sink.log(&message);
+        }
+        if self.log_capture.buffers() {
+            self.logs.push(message);
+        }
         Ok(())
     }
 
@@ -582,11 +982,12 @@ impl<'a> VMLogic<'a> {
     pub fn input(&mut self, register_id: u64) -> Result<()> {
         self.gas_counter.pay_base(base)?;
 
+        let input = self.shared_input.get_or_insert_with(|| self.context.input.as_slice().into());
         self.registers.set(
             &mut self.gas_counter,
             &self.config.limit_config,
             register_id,
-            self.context.input.as_slice(),
+            Arc::clone(input),
         )
     }
 
@@ -677,6 +1078,48 @@ impl<'a> VMLogic<'a> {
         self.memory.set_u64(&mut self.gas_counter, power_ptr, total_power)
     }
 
+    // ###############
+    // # Account API #
+    // ###############
+
+    /// Writes the 32 bytes of the hash of the contract deployed on the account
+    /// `account_id` into the register `register_id` and returns 1, or returns
+    /// 0 and leaves the register untouched if the account does not exist or
+    /// has no contract.
+    ///
+    /// # Errors
+    ///
+    /// * If `account_id_len + account_id_ptr` points outside the memory of the guest or host
+    /// returns `MemoryAccessViolation`.
+    /// * If the account id is not UTF-8 returns `BadUTF8`.
+    ///
+    /// # Cost
+    ///
+    /// `base + read_memory_base + read_memory_byte * account_id_len + storage_read_base
+    ///  + write_register_base + write_register_byte * 32`
+    pub fn account_code_hash(
+        &mut self,
+        account_id_len: u64,
+        account_id_ptr: u64,
+        register_id: u64,
+    ) -> Result<u64> {
+        self.gas_counter.pay_base(base)?;
+        let account_id = self.read_and_parse_account_id(account_id_ptr, account_id_len)?;
+        self.gas_counter.pay_base(storage_read_base)?;
+        match self.ext.account_code_hash(&account_id)? {
+            Some(code_hash) => {
+                self.registers.set(
+                    &mut self.gas_counter,
+                    &self.config.limit_config,
+                    register_id,
+                    code_hash.0.as_slice(),
+                )?;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
     /// Returns the number of bytes used by the contract if it was saved to the trie as of the
     /// invocation. This includes:
     /// * The data written with storage_* functions during current and previous execution;
@@ -804,6 +1247,7 @@ impl<'a> VMLogic<'a> {
     /// `base + write_register_base + write_register_byte * num_bytes +
     ///  alt_bn128_g1_multiexp_base +
     ///  alt_bn128_g1_multiexp_element * num_elements`
+    #[cfg(feature = "bn128")]
     pub fn alt_bn128_g1_multiexp(
         &mut self,
         value_len: u64,
@@ -847,6 +1291,7 @@ impl<'a> VMLogic<'a> {
     ///
     /// `base + write_register_base + write_register_byte * num_bytes +
     /// alt_bn128_g1_sum_base + alt_bn128_g1_sum_element * num_elements`
+    #[cfg(feature = "bn128")]
     pub fn alt_bn128_g1_sum(
         &mut self,
         value_len: u64,
@@ -891,18 +1336,327 @@ impl<'a> VMLogic<'a> {
     /// # Cost
     ///
     /// `base + write_register_base + write_register_byte * num_bytes + alt_bn128_pairing_base + alt_bn128_pairing_element * num_elements`
+    #[cfg(feature = "bn128")]
     pub fn alt_bn128_pairing_check(&mut self, value_len: u64, value_ptr: u64) -> Result<u64> {
         self.gas_counter.pay_base(alt_bn128_pairing_check_base)?;
         let data = get_memory_or_register!(self, value_ptr, value_len)?;
 
         let elements = super::alt_bn128::split_elements(&data)?;
-        self.gas_counter.pay_per(alt_bn128_pairing_check_element, elements.len() as u64)?;
+        let res = Self::alt_bn128_pairing_check_elements(&mut self.gas_counter, elements)?;
+
+        Ok(res as u64)
+    }
+
+    /// Pays for the elements of a pairing check, its base paid, and computes
+    /// it.
+    #[cfg(feature = "bn128")]
+    fn alt_bn128_pairing_check_elements(
+        gas_counter: &mut GasCounter,
+        elements: &[[u8; super::alt_bn128::PAIRING_CHECK_ELEMENT_SIZE]],
+    ) -> Result<bool> {
+        gas_counter.pay_per(alt_bn128_pairing_check_element, elements.len() as u64)?;
+        Ok(super::alt_bn128::pairing_check(elements)?)
+    }
+
+    /// Computes multiexp on the alt_bn128 twist \sum_i mul_i g_{2 i}, like
+    /// [`Self::alt_bn128_g1_multiexp`] does on G1.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - sequence of (g2:G2, fr:Fr), where
+    ///   G2 is Fr-ordered subgroup point (x:Fq2, y:Fq2) on alt_bn128 twist,
+    ///   encoded as in [`Self::alt_bn128_pairing_check`].
+    ///
+    ///   `value` is encoded as packed, little-endian
+    ///   `[(((u256, u256), (u256, u256)), u256)]` slice.
+    ///
+    /// # Errors
+    ///
+    /// If `value_len + value_ptr` points outside the memory or the registers
+    /// use more memory than the limit, the function returns
+    /// `MemoryAccessViolation`.
+    ///
+    /// If point coordinates are not on curve, point is not in the subgroup,
+    /// scalar is not in the field or  `value.len()%160!=0`, the function
+    /// returns `AltBn128InvalidInput`.
+    ///
+    /// # Cost
+    ///
+    /// The costs are those of [`crate::logic::ExtraExtCostsConfig`]:
+    ///
+    /// `alt_bn128_g2_multiexp_base + write_register_base + write_register_byte * num_bytes +
+    ///  alt_bn128_g2_multiexp_element * num_elements`
+    #[cfg(feature = "bn128")]
+    pub fn alt_bn128_g2_multiexp(
+        &mut self,
+        value_len: u64,
+        value_ptr: u64,
+        register_id: u64,
+    ) -> Result<()> {
+        let costs = &self.config.extra_ext_costs;
+        self.gas_counter.pay_extra(costs.alt_bn128_g2_multiexp_base, 1)?;
+        let data = get_memory_or_register!(self, value_ptr, value_len)?;
+
+        let elements = super::alt_bn128::split_elements(&data)?;
+        self.gas_counter.pay_extra(costs.alt_bn128_g2_multiexp_element, elements.len() as u64)?;
+
+        let res = super::alt_bn128::g2_multiexp(elements)?;
+
+        self.registers.set(&mut self.gas_counter, &self.config.limit_config, register_id, res)
+    }
+
+    /// Computes several pairing checks on alt_bn128 curve at once, each like
+    /// [`Self::alt_bn128_pairing_check`].
+    ///
+    /// What the batch saves is the cost of reading the inputs separately and
+    /// of calling the host function for every check.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - sequence of checks, each made of the number of its elements
+    ///   as a little-endian `u32` followed by the elements, encoded as in
+    ///   [`Self::alt_bn128_pairing_check`].
+    ///
+    /// # Returns
+    ///
+    /// * If all the checks pass returns `u64::MAX`;
+    /// * Otherwise returns the index of the first failing check.  The checks
+    ///   after it are not computed.
+    ///
+    /// # Errors
+    ///
+    /// If `value_len + value_ptr` points outside the memory or the registers
+    /// use more memory than the limit, the function returns
+    /// `MemoryAccessViolation`.
+    ///
+    /// If the checks are not well formed, or a point of a computed check is
+    /// not on curve or not in the subgroup, the function returns
+    /// `AltBn128InvalidInput`.
+    ///
+    /// # Cost
+    ///
+    /// `alt_bn128_pairing_check_batch_base` is a cost of
+    /// [`crate::logic::ExtraExtCostsConfig`]:
+    ///
+    /// `alt_bn128_pairing_check_batch_base + input_cost(num_bytes) +
+    ///  alt_bn128_pairing_check_base * num_computed_checks +
+    ///  alt_bn128_pairing_check_element * num_computed_elements`
+    #[cfg(feature = "bn128")]
+    pub fn alt_bn128_pairing_check_batch(&mut self, value_len: u64, value_ptr: u64) -> Result<u64> {
+        let batch_base = self.config.extra_ext_costs.alt_bn128_pairing_check_batch_base;
+        self.gas_counter.pay_extra(batch_base, 1)?;
+        let data = get_memory_or_register!(self, value_ptr, value_len)?;
+
+        let checks = super::alt_bn128::split_pairing_checks(&data)?;
+        for (index, elements) in checks.into_iter().enumerate() {
+            self.gas_counter.pay_base(alt_bn128_pairing_check_base)?;
+            if !Self::alt_bn128_pairing_check_elements(&mut self.gas_counter, elements)? {
+                return Ok(index as u64);
+            }
+        }
+        Ok(u64::MAX)
+    }
+
+    /// Computes the sum of signed points of G1 on the BLS12-381 curve \sum_i
+    /// (-1)^{sign_i} g_{1 i}, like [`Self::alt_bn128_g1_sum`] does on
+    /// alt_bn128.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - sequence of (sign:bool, g1:G1), where
+    ///    G1 is point (x:Fp, y:Fp) on BLS12-381,
+    ///    BLS12-381 is Y^2 = X^3 + 4 curve over Fp.
+    ///
+    ///   `value` is encoded as packed, little-endian
+    ///   `[(u8, (u384, u384))]` slice.  `0u8` is positive sign, `1u8` --
+    ///   negative, and `(0, 0)` the point at infinity.
+    ///
+    /// # Errors
+    ///
+    /// If `value_len + value_ptr` points outside the memory or the registers
+    /// use more memory than the limit, the function returns
+    /// `MemoryAccessViolation`.
+    ///
+    /// If a coordinate is not in the field, a point is not on curve, a sign
+    /// is not 0 or 1, or `value.len()%97!=0`, the function returns
+    /// `Bls12381InvalidInput`.  Points need not be in the subgroup.
+    ///
+    /// # Cost
+    ///
+    /// The costs are those of [`crate::logic::ExtraExtCostsConfig`]:
+    ///
+    /// `bls12381_g1_sum_base + input_cost(num_bytes) + write_register_base +
+    ///  write_register_byte * num_bytes + bls12381_g1_sum_element * num_elements`
+    #[cfg(feature = "protocol_feature_bls12381")]
+    pub fn bls12381_g1_sum(
+        &mut self,
+        value_len: u64,
+        value_ptr: u64,
+        register_id: u64,
+    ) -> Result<()> {
+        let costs = &self.config.extra_ext_costs;
+        let (sum_base, sum_element) = (costs.bls12381_g1_sum_base, costs.bls12381_g1_sum_element);
+        self.gas_counter.pay_extra(sum_base, 1)?;
+        let data = get_memory_or_register!(self, value_ptr, value_len)?;
+
+        let elements = super::bls12381::split_elements(&data)?;
+        self.gas_counter.pay_extra(sum_element, elements.len() as u64)?;
+
+        let res = super::bls12381::g1_sum(elements)?;
+
+        self.registers.set(&mut self.gas_counter, &self.config.limit_config, register_id, res)
+    }
+
+    /// Computes the sum of signed points of G2 on the BLS12-381 twist, like
+    /// [`Self::bls12381_g1_sum`] does on G1.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - sequence of (sign:bool, g2:G2), where
+    ///   G2 is point (x:Fp2, y:Fp2) on BLS12-381 twist,
+    ///   BLS12-381 twist is Y^2 = X^3 + 4(i+1) curve over Fp2,
+    ///   Fp2 is complex field element (re: Fp, im: Fp).
+    ///
+    ///   `value` is encoded as packed, little-endian
+    ///   `[(u8, ((u384, u384), (u384, u384)))]` slice.
+    ///
+    /// # Errors
+    ///
+    /// If `value_len + value_ptr` points outside the memory or the registers
+    /// use more memory than the limit, the function returns
+    /// `MemoryAccessViolation`.
+    ///
+    /// If a coordinate is not in the field, a point is not on curve, a sign
+    /// is not 0 or 1, or `value.len()%193!=0`, the function returns
+    /// `Bls12381InvalidInput`.  Points need not be in the subgroup.
+    ///
+    /// # Cost
+    ///
+    /// The costs are those of [`crate::logic::ExtraExtCostsConfig`]:
+    ///
+    /// `bls12381_g2_sum_base + input_cost(num_bytes) + write_register_base +
+    ///  write_register_byte * num_bytes + bls12381_g2_sum_element * num_elements`
+    #[cfg(feature = "protocol_feature_bls12381")]
+    pub fn bls12381_g2_sum(
+        &mut self,
+        value_len: u64,
+        value_ptr: u64,
+        register_id: u64,
+    ) -> Result<()> {
+        let costs = &self.config.extra_ext_costs;
+        let (sum_base, sum_element) = (costs.bls12381_g2_sum_base, costs.bls12381_g2_sum_element);
+        self.gas_counter.pay_extra(sum_base, 1)?;
+        let data = get_memory_or_register!(self, value_ptr, value_len)?;
 
-        let res = super::alt_bn128::pairing_check(elements)?;
+        let elements = super::bls12381::split_elements(&data)?;
+        self.gas_counter.pay_extra(sum_element, elements.len() as u64)?;
+
+        let res = super::bls12381::g2_sum(elements)?;
+
+        self.registers.set(&mut self.gas_counter, &self.config.limit_config, register_id, res)
+    }
+
+    /// Computes pairing check on the BLS12-381 curve.
+    /// \sum_i e(g_{1 i}, g_{2 i}) should be equal one (in additive notation),
+    /// e(g1, g2) is the optimal ate pairing.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - sequence of (g1:G1, g2:G2), where G1 and G2 are points
+    ///   encoded as in [`Self::bls12381_g1_sum`] and
+    ///   [`Self::bls12381_g2_sum`], in their subgroup of order r.
+    ///
+    ///   `value` is encoded as packed, little-endian
+    ///   `[((u384, u384), ((u384, u384), (u384, u384)))]` slice.
+    ///
+    /// # Returns
+    ///
+    /// * `1` if the pairing check passes, `0` otherwise.
+    ///
+    /// # Errors
+    ///
+    /// If `value_len + value_ptr` points outside the memory or the registers
+    /// use more memory than the limit, the function returns
+    /// `MemoryAccessViolation`.
+    ///
+    /// If a coordinate is not in the field, a point is not on curve or not in
+    /// the subgroup, or `value.len()%288!=0`, the function returns
+    /// `Bls12381InvalidInput`.
+    ///
+    /// # Cost
+    ///
+    /// The costs are those of [`crate::logic::ExtraExtCostsConfig`]:
+    ///
+    /// `bls12381_pairing_check_base + input_cost(num_bytes) +
+    ///  bls12381_pairing_check_element * num_elements`
+    #[cfg(feature = "protocol_feature_bls12381")]
+    pub fn bls12381_pairing_check(&mut self, value_len: u64, value_ptr: u64) -> Result<u64> {
+        let costs = &self.config.extra_ext_costs;
+        let (check_base, check_element) =
+            (costs.bls12381_pairing_check_base, costs.bls12381_pairing_check_element);
+        self.gas_counter.pay_extra(check_base, 1)?;
+        let data = get_memory_or_register!(self, value_ptr, value_len)?;
+
+        let elements = super::bls12381::split_elements(&data)?;
+        self.gas_counter.pay_extra(check_element, elements.len() as u64)?;
+
+        let res = super::bls12381::pairing_check(elements)?;
 
         Ok(res as u64)
     }
 
+    /// Maps elements of Fp2 to points of G2 on the BLS12-381 twist, by the
+    /// map of the `BLS12381G2_XMD:SHA-256_SSWU_RO_` suite of RFC 9380: the
+    /// simplified SWU map, its 3-isogeny and the clearing of the cofactor.
+    ///
+    /// The `hash_to_curve` of a message is the sum of the points its
+    /// `hash_to_field` maps to, computed by the contract, as the messages
+    /// signed by Ethereum validators are hashed.
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - sequence of Fp2 elements, encoded as packed, little-endian
+    ///   `[(u384, u384)]` slice, real part first.
+    ///
+    /// The register is set to the points of G2, encoded as in
+    /// [`Self::bls12381_g2_sum`], in the order of the elements.
+    ///
+    /// # Errors
+    ///
+    /// If `value_len + value_ptr` points outside the memory or the registers
+    /// use more memory than the limit, the function returns
+    /// `MemoryAccessViolation`.
+    ///
+    /// If a coordinate is not in the field, or `value.len()%96!=0`, the
+    /// function returns `Bls12381InvalidInput`.
+    ///
+    /// # Cost
+    ///
+    /// The costs are those of [`crate::logic::ExtraExtCostsConfig`]:
+    ///
+    /// `bls12381_map_to_curve_base + input_cost(num_bytes) + write_register_base +
+    ///  write_register_byte * num_bytes + bls12381_map_to_curve_element * num_elements`
+    #[cfg(feature = "protocol_feature_bls12381")]
+    pub fn bls12381_map_to_curve(
+        &mut self,
+        value_len: u64,
+        value_ptr: u64,
+        register_id: u64,
+    ) -> Result<()> {
+        let costs = &self.config.extra_ext_costs;
+        let (map_base, map_element) =
+            (costs.bls12381_map_to_curve_base, costs.bls12381_map_to_curve_element);
+        self.gas_counter.pay_extra(map_base, 1)?;
+        let data = get_memory_or_register!(self, value_ptr, value_len)?;
+
+        let elements = super::bls12381::split_elements(&data)?;
+        self.gas_counter.pay_extra(map_element, elements.len() as u64)?;
+
+        let res = super::bls12381::map_to_curve(elements)?;
+
+        self.registers.set(&mut self.gas_counter, &self.config.limit_config, register_id, res)
+    }
+
     /// Writes random seed into the register.
     ///
     /// # Errors
@@ -937,9 +1691,7 @@ impl<'a> VMLogic<'a> {
         let value = get_memory_or_register!(self, value_ptr, value_len)?;
         self.gas_counter.pay_per(sha256_byte, value.len() as u64)?;
 
-        use sha2::Digest;
-
-        let value_hash = sha2::Sha256::digest(&value);
+        let value_hash = Sha256::digest(&value);
         self.registers.set(
             &mut self.gas_counter,
             &self.config.limit_config,
@@ -1056,6 +1808,7 @@ impl<'a> VMLogic<'a> {
     /// # Cost
     ///
     /// `base + write_register_base + write_register_byte * 64 + ecrecover_base`
+    #[cfg(feature = "secp256k1")]
     pub fn ecrecover(
         &mut self,
         hash_len: u64,
@@ -1079,17 +1832,7 @@ impl<'a> VMLogic<'a> {
                 }));
             }
 
-            let mut bytes = [0u8; 65];
-            bytes[0..64].copy_from_slice(&vec);
-
-            if v < 4 {
-                bytes[64] = v as u8;
-                Secp256K1Signature::from(bytes)
-            } else {
-                return Err(VMLogicError::HostError(HostError::ECRecoverError {
-                    msg: format!("V recovery byte 0 through 3 are valid but was provided {}", v),
-                }));
-            }
+            ecrecover_signature(vec.as_ref().try_into().unwrap(), v)?
         };
 
         let hash = {
@@ -1108,20 +1851,7 @@ impl<'a> VMLogic<'a> {
             bytes
         };
 
-        if malleability_flag != 0 && malleability_flag != 1 {
-            return Err(VMLogicError::HostError(HostError::ECRecoverError {
-                msg: format!(
-                    "Malleability flag needs to be 0 or 1, but is instead {}",
-                    malleability_flag
-                ),
-            }));
-        }
-
-        if !signature.check_signature_values(malleability_flag != 0) {
-            return Ok(false as u64);
-        }
-
-        if let Ok(pk) = signature.recover(hash) {
+        if let Some(pk) = ecrecover_key(&signature, hash, malleability_flag)? {
             self.registers.set(
                 &mut self.gas_counter,
                 &self.config.limit_config,
@@ -1134,6 +1864,139 @@ impl<'a> VMLogic<'a> {
         Ok(false as u64)
     }
 
+    /// Same as [`Self::ecrecover`] but returns the public key compressed, in
+    /// the 33 bytes of its SEC1 encoding: `0x02` or `0x03` for the parity of
+    /// its `y` coordinate followed by its `x` coordinate.
+    ///
+    /// # Cost
+    ///
+    /// `write_register_base + write_register_byte * 33 + ecrecover_base`
+    #[cfg(feature = "secp256k1")]
+    pub fn ecrecover_compressed(
+        &mut self,
+        hash_len: u64,
+        hash_ptr: u64,
+        sig_len: u64,
+        sig_ptr: u64,
+        v: u64,
+        malleability_flag: u64,
+        register_id: u64,
+    ) -> Result<u64> {
+        self.gas_counter.pay_base(ecrecover_base)?;
+        let signature = {
+            let vec = get_memory_or_register!(self, sig_ptr, sig_len)?;
+            let bytes = vec.as_ref().try_into().map_err(|_| HostError::ECRecoverError {
+                msg: format!(
+                    "The length of the signature: {}, exceeds the limit of 64 bytes",
+                    vec.len()
+                ),
+            })?;
+            ecrecover_signature(bytes, v)?
+        };
+        let hash = {
+            let vec = get_memory_or_register!(self, hash_ptr, hash_len)?;
+            vec.as_ref().try_into().map_err(|_| HostError::ECRecoverError {
+                msg: format!(
+                    "The length of the hash: {}, exceeds the limit of 32 bytes",
+                    vec.len()
+                ),
+            })?
+        };
+        let Some(pk) = ecrecover_key(&signature, hash, malleability_flag)? else {
+            return Ok(false as u64);
+        };
+        self.registers.set(
+            &mut self.gas_counter,
+            &self.config.limit_config,
+            register_id,
+            compress_secp256k1_key(pk.as_ref()),
+        )?;
+        Ok(true as u64)
+    }
+
+    /// Recovers the signers of a batch of ECDSA signatures read from a single
+    /// buffer and returns their public keys, concatenated, into
+    /// `register_id`.
+    ///
+    /// The batch is a concatenation of entries of 98 bytes, each made of the
+    /// 32 bytes hash, the 64 bytes signature, the `v` byte and the
+    /// malleability flag byte of a call to [`Self::ecrecover`].  The keys are
+    /// written uncompressed if `compressed` is `0`, and as with
+    /// [`Self::ecrecover_compressed`] if it is `1`.
+    ///
+    /// What the batch saves over [`Self::ecrecover`] is reading the inputs
+    /// separately and calling the host function for every signature: each
+    /// recovery costs the same.
+    ///
+    /// # Returns
+    ///
+    /// * If all the signers are recovered returns `u64::MAX`;
+    /// * Otherwise returns the index of the first signature which fails to
+    ///   recover, the register holding the keys of the signatures before it.
+    ///   The signatures after it are not recovered.
+    ///
+    /// # Errors
+    ///
+    /// * If the batch is not made of whole entries, or an entry or
+    ///   `compressed` is not a valid argument of [`Self::ecrecover`] returns
+    ///   [`HostError::ECRecoverError`] before recovering any signature.
+    /// * If the batch is out of memory bounds or the registers use more memory
+    ///   than the limit returns [`HostError::MemoryAccessViolation`].
+    ///
+    /// # Cost
+    ///
+    /// `input_cost(num_bytes_batch) + ecrecover_base * num_recovered +
+    ///  write_register_base + write_register_byte * num_bytes_keys`
+    #[cfg(feature = "secp256k1")]
+    pub fn ecrecover_batch(
+        &mut self,
+        batch_len: u64,
+        batch_ptr: u64,
+        compressed: u64,
+        register_id: u64,
+    ) -> Result<u64> {
+        const ENTRY_LEN: usize = 32 + 64 + 2;
+
+        let batch = get_memory_or_register!(self, batch_ptr, batch_len)?;
+        if batch.len() % ENTRY_LEN != 0 {
+            return Err(HostError::ECRecoverError {
+                msg: format!("The length of the batch: {}, is not a multiple of 98", batch.len()),
+            }
+            .into());
+        }
+        if compressed > 1 {
+            return Err(HostError::ECRecoverError {
+                msg: format!("Compressed flag needs to be 0 or 1, but is instead {}", compressed),
+            }
+            .into());
+        }
+        let mut entries = Vec::with_capacity(batch.len() / ENTRY_LEN);
+        for entry in batch.chunks_exact(ENTRY_LEN) {
+            let (hash, rest) = entry.split_at(32);
+            let (signature, flags) = rest.split_at(64);
+            let signature = ecrecover_signature(signature.try_into().unwrap(), flags[0].into())?;
+            let malleability_flag = u64::from(flags[1]);
+            check_malleability_flag(malleability_flag)?;
+            entries.push((<[u8; 32]>::try_from(hash).unwrap(), signature, malleability_flag));
+        }
+
+        let mut keys = Vec::new();
+        let mut result = u64::MAX;
+        for (index, (hash, signature, malleability_flag)) in entries.into_iter().enumerate() {
+            self.gas_counter.pay_base(ecrecover_base)?;
+            match ecrecover_key(&signature, hash, malleability_flag)? {
+                Some(pk) if compressed == 1 => keys.extend(compress_secp256k1_key(pk.as_ref())),
+                Some(pk) => keys.extend_from_slice(pk.as_ref()),
+                None => {
+                    result = index as u64;
+                    break;
+                }
+            }
+        }
+        self.registers.set(&mut self.gas_counter, &self.config.limit_config, register_id, keys)?;
+        Ok(result)
+    }
+
     /// Verify an ED25519 signature given a message and a public key.
     ///
     /// Returns a bool indicating success (1) or failure (0) as a `u64`.
@@ -1158,6 +2021,7 @@ impl<'a> VMLogic<'a> {
     /// `input_cost(num_bytes_signature) + input_cost(num_bytes_message) +
     ///  input_cost(num_bytes_public_key) + ed25519_verify_base +
     ///  ed25519_verify_byte * num_bytes_message`
+    #[cfg(feature = "ed25519")]
     pub fn ed25519_verify(
         &mut self,
         signature_len: u64,
@@ -1210,6 +2074,226 @@ impl<'a> VMLogic<'a> {
         }
     }
 
+    /// Verifies a batch of ed25519 signatures read from a single buffer.
+    ///
+    /// The batch is a concatenation of entries, each made of a 64 bytes
+    /// signature, a 32 bytes public key, the length of the message as a
+    /// little-endian `u32` and the message.  The signatures are checked in
+    /// order exactly like with [`Self::ed25519_verify`].
+    ///
+    /// The signatures are verified one by one rather than with the batch
+    /// verification of `ed25519-dalek`, which does not accept exactly the same
+    /// signatures.  What the batch saves is the cost of reading the inputs
+    /// separately and of calling the host function for every signature.
+    ///
+    /// # Returns
+    ///
+    /// * If all the signatures are valid returns `u64::MAX`;
+    /// * Otherwise returns the index of the first invalid signature.  The
+    ///   signatures after it are not verified.
+    ///
+    /// # Errors
+    ///
+    /// * If the batch does not consist of well formed entries returns
+    ///   [`HostError::Ed25519VerifyInvalidInput`].
+    /// * If the batch is out of memory bounds returns
+    ///   [`HostError::MemoryAccessViolation`].
+    ///
+    /// # Cost
+    ///
+    /// `ed25519_verify_batch_base` and `ed25519_verify_batch_element` are
+    /// costs of [`crate::logic::ExtraExtCostsConfig`]:
+    ///
+    /// `ed25519_verify_batch_base + input_cost(num_bytes_batch) +
+    ///  ed25519_verify_batch_element * num_verified +
+    ///  ed25519_verify_byte * num_bytes_verified_messages`
+    #[cfg(feature = "ed25519")]
+    pub fn ed25519_verify_batch(&mut self, batch_len: u64, batch_ptr: u64) -> Result<u64> {
+        use ed25519_dalek::{Verifier, PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH};
+
+        let costs = &self.config.extra_ext_costs;
+        let (batch_base, batch_element) =
+            (costs.ed25519_verify_batch_base, costs.ed25519_verify_batch_element);
+        self.gas_counter.pay_extra(batch_base, 1)?;
+        let batch = get_memory_or_register!(self, batch_ptr, batch_len)?;
+        let invalid_input = |msg: &str| {
+            VMLogicError::HostError(HostError::Ed25519VerifyInvalidInput { msg: msg.to_string() })
+        };
+        let mut entries = Vec::new();
+        let mut rest = &batch[..];
+        while !rest.is_empty() {
+            let header_len = SIGNATURE_LENGTH + PUBLIC_KEY_LENGTH + size_of::<u32>();
+            if rest.len() < header_len {
+                return Err(invalid_input("truncated batch entry"));
+            }
+            let (signature, tail) = rest.split_at(SIGNATURE_LENGTH);
+            let (public_key, tail) = tail.split_at(PUBLIC_KEY_LENGTH);
+            let (message_len, tail) = tail.split_at(size_of::<u32>());
+            let message_len = u32::from_le_bytes(message_len.try_into().unwrap()) as usize;
+            if tail.len() < message_len {
+                return Err(invalid_input("truncated batch message"));
+            }
+            let (message, tail) = tail.split_at(message_len);
+            entries.push((
+                <&[u8; SIGNATURE_LENGTH]>::try_from(signature).unwrap(),
+                <&[u8; PUBLIC_KEY_LENGTH]>::try_from(public_key).unwrap(),
+                message,
+            ));
+            rest = tail;
+        }
+
+        for (index, (signature, public_key, message)) in entries.into_iter().enumerate() {
+            self.gas_counter.pay_extra(batch_element, 1)?;
+            self.gas_counter.pay_per(ed25519_verify_byte, message.len() as u64)?;
+            // Same sanity-check as in `ed25519_verify`.
+            let valid = signature[SIGNATURE_LENGTH - 1] & 0b1110_0000 == 0
+                && ed25519_dalek::VerifyingKey::from_bytes(public_key).map_or(false, |key| {
+                    key.verify(message, &ed25519_dalek::Signature::from_bytes(signature)).is_ok()
+                });
+            if !valid {
+                return Ok(index as u64);
+            }
+        }
+        Ok(u64::MAX)
+    }
+
+    /// Computes `a * b / denominator` on the 256-bit little-endian integers at `a_ptr`, `b_ptr`
+    /// and `denominator_ptr` and writes the quotient at `result_ptr`.
+    ///
+    /// The product is computed on 512 bits, so the quotient is exact whenever it fits in 256
+    /// bits.  Inexact quotients are rounded down if `rounding` is `0` and up if it is `1`.
+    ///
+    /// # Errors
+    ///
+    /// * If any of the pointers plus 32 points outside the memory of the guest returns
+    ///   `MemoryAccessViolation`.
+    /// * If `denominator` is zero, the quotient does not fit in 256 bits or `rounding` is neither
+    ///   `0` nor `1` returns `WideMathInvalidInput`.
+    ///
+    /// # Cost
+    ///
+    /// `u256_muldiv_base` is a cost of [`crate::logic::ExtraExtCostsConfig`]:
+    ///
+    /// `u256_muldiv_base + 3 * (read_memory_base + 32 * read_memory_byte) + write_memory_base +
+    /// 32 * write_memory_byte`
+    pub fn u256_muldiv(
+        &mut self,
+        a_ptr: u64,
+        b_ptr: u64,
+        denominator_ptr: u64,
+        rounding: u64,
+        result_ptr: u64,
+    ) -> Result<()> {
+        self.gas_counter.pay_extra(self.config.extra_ext_costs.u256_muldiv_base, 1)?;
+        let a = self.memory.get_u256(&mut self.gas_counter, a_ptr)?;
+        let b = self.memory.get_u256(&mut self.gas_counter, b_ptr)?;
+        let denominator = self.memory.get_u256(&mut self.gas_counter, denominator_ptr)?;
+        let rounding = wide_math::Rounding::from_u64(rounding)?;
+        let result = wide_math::muldiv(&a, &b, &denominator, rounding)?;
+        self.memory.set(&mut self.gas_counter, result_ptr, &result)
+    }
+
+    /// Raises the 256-bit little-endian integer at `base_ptr` to the power of `exponent` and
+    /// writes the result at `result_ptr`.
+    ///
+    /// # Errors
+    ///
+    /// * If `base_ptr + 32` or `result_ptr + 32` points outside the memory of the guest returns
+    ///   `MemoryAccessViolation`.
+    /// * If the result does not fit in 256 bits returns `WideMathInvalidInput`.
+    ///
+    /// # Cost
+    ///
+    /// `u256_pow_base` and `u256_pow_bit` are costs of [`crate::logic::ExtraExtCostsConfig`]:
+    ///
+    /// `u256_pow_base + u256_pow_bit * num_exponent_bits + read_memory_base + 32 *
+    /// read_memory_byte + write_memory_base + 32 * write_memory_byte`
+    ///
+    /// where `num_exponent_bits` is the number of bits of `exponent` up to its highest one set.
+    pub fn u256_pow(&mut self, base_ptr: u64, exponent: u64, result_ptr: u64) -> Result<()> {
+        let costs = &self.config.extra_ext_costs;
+        let (pow_base, pow_bit) = (costs.u256_pow_base, costs.u256_pow_bit);
+        self.gas_counter.pay_extra(pow_base, 1)?;
+        self.gas_counter.pay_extra(pow_bit, u64::from(u64::BITS - exponent.leading_zeros()))?;
+        let value = self.memory.get_u256(&mut self.gas_counter, base_ptr)?;
+        let result = wide_math::pow(&value, exponent)?;
+        self.memory.set(&mut self.gas_counter, result_ptr, &result)
+    }
+
+    /// Called by the backends before each host function called by the
+    /// contract, to break the gas down per host function.
+    #[inline]
+    pub fn enter_host_function(&mut self, name: &'static str, args: &[u64]) {
+        if let Some(host_calls) = &mut self.host_calls {
+            host_calls.push(HostCallRecord {
+                host_function: Cow::Borrowed(name),
+                args: args.to_vec(),
+                result: None,
+                burnt_gas_before: self.gas_counter.burnt_gas(),
+                used_gas_before: self.gas_counter.used_gas(),
+                burnt_gas_after: self.gas_counter.burnt_gas(),
+                used_gas_after: self.gas_counter.used_gas(),
+            });
+        }
+        if let Some(checkpoints) = &mut self.checkpoints {
+            checkpoints.push(HostCallCheckpoint {
+                host_function: Cow::Borrowed(name),
+                burnt_gas: self.gas_counter.burnt_gas(),
+                used_gas: self.gas_counter.used_gas(),
+                registers: self.registers.digest(),
+            });
+        }
+        if let Some(fingerprint) = &mut self.execution_fingerprint {
+            fingerprint.update((name.len() as u64).to_le_bytes());
+            fingerprint.update(name.as_bytes());
+            fingerprint.update(self.gas_counter.burnt_gas().to_le_bytes());
+        }
+        self.gas_counter.enter_host_function(name)
+    }
+
+    /// Called by the backends after each host function called by the
+    /// contract returns, with the result to give back to the contract.
+    ///
+    /// This is where the memory used by the call is measured, so a host
+    /// function which succeeded still fails the call if the memory is over
+    /// [`crate::logic::ExtraLimitConfig::memory_cap`].
+    #[inline]
+    pub fn exit_host_function<T: HostCallValues>(&mut self, result: Result<T>) -> Result<T> {
+        let result = result.and_then(|values| self.account_memory().map(|()| values));
+        let Some(record) = self.host_calls.as_mut().and_then(|calls| calls.last_mut()) else {
+            return result;
+        };
+        record.result = Some(match &result {
+            Ok(values) => Ok(values.to_values()),
+            Err(err) => Err(err.to_string()),
+        });
+        record.burnt_gas_after = self.gas_counter.burnt_gas();
+        record.used_gas_after = self.gas_counter.used_gas();
+        result
+    }
+
+    /// Updates the peaks of [`VMOutcome::used_memory`] with the memory used
+    /// now, failing if it is over the cap.  Calls without a cap measure
+    /// nothing.
+    fn account_memory(&mut self) -> Result<()> {
+        let Some(cap) = self.memory_cap else {
+            return Ok(());
+        };
+        let memory_bytes = self.memory.data_size();
+        let host_bytes =
+            self.registers.memory_usage() + self.total_log_length + self.receipts_memory_usage;
+        let total_bytes = memory_bytes.saturating_add(host_bytes);
+        let used = &mut self.used_memory;
+        used.peak_memory_pages = used.peak_memory_pages.max(memory_bytes / WASM_PAGE_SIZE);
+        used.peak_host_bytes = used.peak_host_bytes.max(host_bytes);
+        used.peak_total_bytes = used.peak_total_bytes.max(total_bytes);
+        if total_bytes > cap {
+            self.memory_cap_exceeded = Some(total_bytes);
+            return Err(HostError::MemoryAccessViolation.into());
+        }
+        Ok(())
+    }
+
     /// Consume gas. Counts both towards `burnt_gas` and `used_gas`.
     ///
     /// # Errors
@@ -1253,22 +2337,20 @@ impl<'a> VMLogic<'a> {
     /// Notice that we prepay all base cost upon the creation of the data dependency, we are going to
     /// pay for the content transmitted through the dependency upon the actual creation of the
     /// DataReceipt.
-    fn pay_gas_for_new_receipt(&mut self, sir: bool, data_dependencies: &[bool]) -> Result<()> {
-        let fees_config_cfg = &self.fees_config;
-        let mut burn_gas = fees_config_cfg.fee(ActionCosts::new_action_receipt).send_fee(sir);
-        let mut use_gas = fees_config_cfg.fee(ActionCosts::new_action_receipt).exec_fee();
-        for dep in data_dependencies {
-            // Both creation and execution for data receipts are considered burnt gas.
-            burn_gas = burn_gas
-                .checked_add(fees_config_cfg.fee(ActionCosts::new_data_receipt_base).send_fee(*dep))
-                .ok_or(HostError::IntegerOverflow)?
-                .checked_add(fees_config_cfg.fee(ActionCosts::new_data_receipt_base).exec_fee())
-                .ok_or(HostError::IntegerOverflow)?;
-        }
-        use_gas = use_gas.checked_add(burn_gas).ok_or(HostError::IntegerOverflow)?;
+    fn pay_gas_for_new_receipt(
+        &mut self,
+        sir: bool,
+        data_dependencies: &[bool],
+    ) -> Result<ActionGas> {
+        let gas = gas_planning::new_receipt_gas(self.fees_config, sir, data_dependencies)?;
         // This should go to `new_data_receipt_base` and `new_action_receipt` in parts.
         // But we have to keep charing these two together unless we make a protocol change.
-        self.gas_counter.pay_action_accumulated(burn_gas, use_gas, ActionCosts::new_action_receipt)
+        self.gas_counter.pay_action_accumulated(
+            gas.burnt,
+            gas.used,
+            ActionCosts::new_action_receipt,
+        )?;
+        Ok(gas)
     }
 
     /// A helper function to subtract balance on transfer or attached deposit for promises.
@@ -1480,8 +2562,9 @@ impl<'a> VMLogic<'a> {
         }
         let account_id = self.read_and_parse_account_id(account_id_ptr, account_id_len)?;
         let sir = account_id == self.context.current_account_id;
-        self.pay_gas_for_new_receipt(sir, &[])?;
-        let new_receipt_idx = self.ext.create_receipt(vec![], account_id)?;
+        let gas = self.pay_gas_for_new_receipt(sir, &[])?;
+        let new_receipt_idx = self.ext.create_receipt(vec![], account_id.clone())?;
+        self.record_receipt(new_receipt_idx, account_id, vec![], gas);
 
         self.checked_push_promise(Promise::Receipt(new_receipt_idx))
     }
@@ -1536,9 +2619,11 @@ impl<'a> VMLogic<'a> {
             .iter()
             .map(|&receipt_idx| self.ext.get_receipt_receiver(receipt_idx) == &account_id)
             .collect();
-        self.pay_gas_for_new_receipt(sir, &deps)?;
+        let gas = self.pay_gas_for_new_receipt(sir, &deps)?;
 
-        let new_receipt_idx = self.ext.create_receipt(receipt_dependencies, account_id)?;
+        let new_receipt_idx =
+            self.ext.create_receipt(receipt_dependencies.clone(), account_id.clone())?;
+        self.record_receipt(new_receipt_idx, account_id, receipt_dependencies, gas);
 
         self.checked_push_promise(Promise::Receipt(new_receipt_idx))
     }
@@ -1588,56 +2673,108 @@ impl<'a> VMLogic<'a> {
         }
         let (receipt_idx, sir) = self.promise_idx_to_receipt_idx_with_sir(promise_idx)?;
 
-        self.pay_action_base(ActionCosts::create_account, sir)?;
+        let gas = self.pay_action_base(ActionCosts::create_account, sir)?;
+
+        self.ext.append_action_create_account(receipt_idx)?;
+        self.record_action(receipt_idx, ReceiptAction::CreateAccount, gas);
+        Ok(())
+    }
+
+    /// Appends `DeployContract` action to the batch of actions for the given promise pointed by
+    /// `promise_idx`.
+    ///
+    /// # Errors
+    ///
+    /// * If `promise_idx` does not correspond to an existing promise returns `InvalidPromiseIndex`.
+    /// * If the promise pointed by the `promise_idx` is an ephemeral promise created by
+    /// `promise_and` returns `CannotAppendActionToJointPromise`.
+    /// * If `code_len + code_ptr` points outside the memory of the guest or host returns
+    /// `MemoryAccessViolation`.
+    /// * If called as view function returns `ProhibitedInView`.
+    /// * If the contract code length exceeds `max_contract_size` returns `ContractSizeExceeded`.
+    ///
+    /// # Cost
+    ///
+    /// `burnt_gas := base + dispatch action base fee + dispatch action per byte fee * num bytes + cost of reading vector from memory `
+    /// `used_gas := burnt_gas + exec action base fee + exec action per byte fee * num bytes`
+    pub fn promise_batch_action_deploy_contract(
+        &mut self,
+        promise_idx: u64,
+        code_len: u64,
+        code_ptr: u64,
+    ) -> Result<()> {
+        self.gas_counter.pay_base(base)?;
+        if self.context.is_view() {
+            return Err(HostError::ProhibitedInView {
+                method_name: "promise_batch_action_deploy_contract".to_string(),
+            }
+            .into());
+        }
+        let code = get_memory_or_register!(self, code_ptr, code_len)?;
+        let code_len = code.len() as u64;
+        let limit = self.config.limit_config.max_contract_size;
+        if code_len > limit {
+            return Err(HostError::ContractSizeExceeded { size: code_len, limit }.into());
+        }
+        let code = code.into_owned();
+
+        let (receipt_idx, sir) = self.promise_idx_to_receipt_idx_with_sir(promise_idx)?;
+
+        let gas = self.pay_action_base(ActionCosts::deploy_contract_base, sir)?.checked_add(
+            self.pay_action_per_byte(ActionCosts::deploy_contract_byte, code_len, sir)?,
+        )?;
+        if let Some(pricing) = &self.code_pricing {
+            self.gas_counter.burn_gas(pricing.deploy_gas(code_len, self.config))?;
+        }
 
-        self.ext.append_action_create_account(receipt_idx)?;
+        let code_hash = self.keeps_actions().then(|| CryptoHash::hash_bytes(&code));
+        self.ext.append_action_deploy_contract(receipt_idx, code)?;
+        match code_hash {
+            Some(code_hash) => {
+                self.record_action(receipt_idx, ReceiptAction::DeployContract { code_hash }, gas)
+            }
+            None => self.charge_receipt(receipt_idx, gas),
+        }
         Ok(())
     }
 
-    /// Appends `DeployContract` action to the batch of actions for the given promise pointed by
-    /// `promise_idx`.
+    /// Appends `DeployGlobalContract` action to the batch of actions for the given promise
+    /// pointed by `promise_idx`.  The receiver runs the global contract whose code hashes to
+    /// the 32 bytes at `code_hash_ptr`, without the code being sent with the receipt.
     ///
     /// # Errors
     ///
     /// * If `promise_idx` does not correspond to an existing promise returns `InvalidPromiseIndex`.
     /// * If the promise pointed by the `promise_idx` is an ephemeral promise created by
     /// `promise_and` returns `CannotAppendActionToJointPromise`.
-    /// * If `code_len + code_ptr` points outside the memory of the guest or host returns
+    /// * If `code_hash_ptr + 32` points outside the memory of the guest or host returns
     /// `MemoryAccessViolation`.
     /// * If called as view function returns `ProhibitedInView`.
-    /// * If the contract code length exceeds `max_contract_size` returns `ContractSizeExceeded`.
     ///
     /// # Cost
     ///
-    /// `burnt_gas := base + dispatch action base fee + dispatch action per byte fee * num bytes + cost of reading vector from memory `
-    /// `used_gas := burnt_gas + exec action base fee + exec action per byte fee * num bytes`
-    pub fn promise_batch_action_deploy_contract(
+    /// `burnt_gas := base + dispatch action base fee + cost of reading 32 bytes from memory`
+    /// `used_gas := burnt_gas + exec action base fee`
+    pub fn promise_batch_action_deploy_global_contract(
         &mut self,
         promise_idx: u64,
-        code_len: u64,
-        code_ptr: u64,
+        code_hash_ptr: u64,
     ) -> Result<()> {
         self.gas_counter.pay_base(base)?;
         if self.context.is_view() {
             return Err(HostError::ProhibitedInView {
-                method_name: "promise_batch_action_deploy_contract".to_string(),
+                method_name: "promise_batch_action_deploy_global_contract".to_string(),
             }
             .into());
         }
-        let code = get_memory_or_register!(self, code_ptr, code_len)?;
-        let code_len = code.len() as u64;
-        let limit = self.config.limit_config.max_contract_size;
-        if code_len > limit {
-            return Err(HostError::ContractSizeExceeded { size: code_len, limit }.into());
-        }
-        let code = code.into_owned();
+        let code_hash = CryptoHash(self.memory.get_u256(&mut self.gas_counter, code_hash_ptr)?);
 
         let (receipt_idx, sir) = self.promise_idx_to_receipt_idx_with_sir(promise_idx)?;
 
-        self.pay_action_base(ActionCosts::deploy_contract_base, sir)?;
-        self.pay_action_per_byte(ActionCosts::deploy_contract_byte, code_len, sir)?;
+        let gas = self.pay_action_base(ActionCosts::deploy_contract_base, sir)?;
 
-        self.ext.append_action_deploy_contract(receipt_idx, code)?;
+        self.ext.append_action_deploy_global_contract(receipt_idx, code_hash)?;
+        self.record_action(receipt_idx, ReceiptAction::DeployGlobalContract { code_hash }, gas);
         Ok(())
     }
 
@@ -1748,13 +2885,21 @@ impl<'a> VMLogic<'a> {
         let arguments = arguments.into_owned();
         // Input can't be large enough to overflow
         let num_bytes = method_name.len() as u64 + arguments.len() as u64;
-        self.pay_action_base(ActionCosts::function_call_base, sir)?;
-        self.pay_action_per_byte(ActionCosts::function_call_byte, num_bytes, sir)?;
+        let fees = self.pay_action_base(ActionCosts::function_call_base, sir)?.checked_add(
+            self.pay_action_per_byte(ActionCosts::function_call_byte, num_bytes, sir)?,
+        )?;
         // Prepaid gas
         self.gas_counter.prepay_gas(gas)?;
 
         self.deduct_balance(amount)?;
 
+        let action = self.keeps_actions().then(|| ReceiptAction::FunctionCall {
+            method_name: method_name.clone(),
+            args: arguments.clone(),
+            attached_deposit: amount,
+            prepaid_gas: gas,
+            gas_weight,
+        });
         self.ext.append_action_function_call_weight(
             receipt_idx,
             method_name,
@@ -1762,7 +2907,15 @@ impl<'a> VMLogic<'a> {
             amount,
             gas,
             GasWeight(gas_weight),
-        )
+        )?;
+        match action {
+            Some(action) => self.record_action(receipt_idx, action, fees),
+            None => {
+                self.attach_to_receipt(receipt_idx, gas, gas_weight, amount);
+                self.charge_receipt(receipt_idx, fees);
+            }
+        }
+        Ok(())
     }
 
     /// Appends `Transfer` action to the batch of actions for the given promise pointed by
@@ -1817,6 +2970,8 @@ impl<'a> VMLogic<'a> {
         self.deduct_balance(amount)?;
 
         self.ext.append_action_transfer(receipt_idx, amount)?;
+        let gas = ActionGas { burnt: burn_gas, used: use_gas };
+        self.record_action(receipt_idx, ReceiptAction::Transfer { deposit: amount }, gas);
         Ok(())
     }
 
@@ -1854,8 +3009,10 @@ impl<'a> VMLogic<'a> {
         let amount = self.memory.get_u128(&mut self.gas_counter, amount_ptr)?;
         let public_key = self.get_public_key(public_key_ptr, public_key_len)?;
         let (receipt_idx, sir) = self.promise_idx_to_receipt_idx_with_sir(promise_idx)?;
-        self.pay_action_base(ActionCosts::stake, sir)?;
-        self.ext.append_action_stake(receipt_idx, amount, public_key.decode()?);
+        let gas = self.pay_action_base(ActionCosts::stake, sir)?;
+        let public_key = public_key.decode()?;
+        self.ext.append_action_stake(receipt_idx, amount, public_key.clone());
+        self.record_action(receipt_idx, ReceiptAction::Stake { stake: amount, public_key }, gas);
         Ok(())
     }
 
@@ -1892,8 +3049,10 @@ impl<'a> VMLogic<'a> {
         }
         let public_key = self.get_public_key(public_key_ptr, public_key_len)?;
         let (receipt_idx, sir) = self.promise_idx_to_receipt_idx_with_sir(promise_idx)?;
-        self.pay_action_base(ActionCosts::add_full_access_key, sir)?;
-        self.ext.append_action_add_key_with_full_access(receipt_idx, public_key.decode()?, nonce);
+        let gas = self.pay_action_base(ActionCosts::add_full_access_key, sir)?;
+        let public_key = public_key.decode()?;
+        self.ext.append_action_add_key_with_full_access(receipt_idx, public_key.clone(), nonce);
+        self.record_action(receipt_idx, ReceiptAction::AddFullAccessKey { public_key, nonce }, gas);
         Ok(())
     }
 
@@ -1946,17 +3105,30 @@ impl<'a> VMLogic<'a> {
 
         // +1 is to account for null-terminating characters.
         let num_bytes = method_names.iter().map(|v| v.len() as u64 + 1).sum::<u64>();
-        self.pay_action_base(ActionCosts::add_function_call_key_base, sir)?;
-        self.pay_action_per_byte(ActionCosts::add_function_call_key_byte, num_bytes, sir)?;
+        let gas = self.pay_action_base(ActionCosts::add_function_call_key_base, sir)?.checked_add(
+            self.pay_action_per_byte(ActionCosts::add_function_call_key_byte, num_bytes, sir)?,
+        )?;
 
+        let public_key = public_key.decode()?;
         self.ext.append_action_add_key_with_function_call(
             receipt_idx,
-            public_key.decode()?,
+            public_key.clone(),
             nonce,
             allowance,
-            receiver_id,
-            method_names,
+            receiver_id.clone(),
+            method_names.clone(),
         )?;
+        self.record_action(
+            receipt_idx,
+            ReceiptAction::AddFunctionCallKey {
+                public_key,
+                nonce,
+                allowance,
+                receiver_id,
+                method_names,
+            },
+            gas,
+        );
         Ok(())
     }
 
@@ -1992,8 +3164,10 @@ impl<'a> VMLogic<'a> {
         }
         let public_key = self.get_public_key(public_key_ptr, public_key_len)?;
         let (receipt_idx, sir) = self.promise_idx_to_receipt_idx_with_sir(promise_idx)?;
-        self.pay_action_base(ActionCosts::delete_key, sir)?;
-        self.ext.append_action_delete_key(receipt_idx, public_key.decode()?);
+        let gas = self.pay_action_base(ActionCosts::delete_key, sir)?;
+        let public_key = public_key.decode()?;
+        self.ext.append_action_delete_key(receipt_idx, public_key.clone());
+        self.record_action(receipt_idx, ReceiptAction::DeleteKey { public_key }, gas);
         Ok(())
     }
 
@@ -2030,9 +3204,10 @@ impl<'a> VMLogic<'a> {
             self.read_and_parse_account_id(beneficiary_id_ptr, beneficiary_id_len)?;
 
         let (receipt_idx, sir) = self.promise_idx_to_receipt_idx_with_sir(promise_idx)?;
-        self.pay_action_base(ActionCosts::delete_account, sir)?;
+        let gas = self.pay_action_base(ActionCosts::delete_account, sir)?;
 
-        self.ext.append_action_delete_account(receipt_idx, beneficiary_id)?;
+        self.ext.append_action_delete_account(receipt_idx, beneficiary_id.clone())?;
+        self.record_action(receipt_idx, ReceiptAction::DeleteAccount { beneficiary_id }, gas);
         Ok(())
     }
 
@@ -2135,12 +3310,108 @@ impl<'a> VMLogic<'a> {
         {
             Promise::Receipt(receipt_idx) => {
                 self.return_data = ReturnData::ReceiptIndex(*receipt_idx);
+                self.streamed_return = None;
                 Ok(())
             }
             Promise::NotReceipt(_) => Err(HostError::CannotReturnJointPromise.into()),
         }
     }
 
+    // ####################
+    // # Scratch area API #
+    // ####################
+
+    /// Reads the scratch area carried into the current execution by the incoming receipt and
+    /// places it into the register.
+    ///
+    /// The scratch area lets a chain of callbacks pass intermediate state along with the receipts
+    /// instead of going through the storage.  It is set for the next step of the chain with
+    /// `promise_batch_scratch_write`.
+    ///
+    /// # Returns
+    ///
+    /// * If the incoming receipt carries a scratch area copies it into the register and returns
+    ///   `1`;
+    /// * Otherwise keeps the register unused and returns `0`.
+    ///
+    /// # Errors
+    ///
+    /// * If copying the scratch area exhausts the memory limit it returns `MemoryAccessViolation`.
+    /// * If called as view function returns `ProhibitedInView`.
+    ///
+    /// # Cost
+    ///
+    /// `base + cost of writing data into a register`
+    pub fn scratch_read(&mut self, register_id: u64) -> Result<u64> {
+        self.gas_counter.pay_base(base)?;
+        if self.context.is_view() {
+            return Err(
+                HostError::ProhibitedInView { method_name: "scratch_read".to_string() }.into()
+            );
+        }
+        match self.ext.scratch_get()? {
+            Some(data) => {
+                self.registers.set(
+                    &mut self.gas_counter,
+                    &self.config.limit_config,
+                    register_id,
+                    data,
+                )?;
+                Ok(1)
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// Attaches the scratch area to the receipt of the promise pointed by `promise_idx`.  The
+    /// receiver of the receipt can read it back with `scratch_read`.  Writing the scratch area of
+    /// the same promise twice overrides the previous value.
+    ///
+    /// # Errors
+    ///
+    /// * If `promise_idx` does not correspond to an existing promise returns `InvalidPromiseIndex`.
+    /// * If the promise pointed by the `promise_idx` is an ephemeral promise created by
+    /// `promise_and` returns `CannotAppendActionToJointPromise`.
+    /// * If `data_len + data_ptr` points outside the memory of the guest or host returns
+    /// `MemoryAccessViolation`.
+    /// * If the length of the data exceeds `max_arguments_length` returns
+    /// `ScratchLengthExceeded`.
+    /// * If called as view function returns `ProhibitedInView`.
+    ///
+    /// # Cost
+    ///
+    /// `burnt_gas := base + cost of reading data from memory + dispatch data receipt per byte fee * num bytes`
+    /// `used_gas := burnt_gas + exec data receipt per byte fee * num bytes`
+    pub fn promise_batch_scratch_write(
+        &mut self,
+        promise_idx: u64,
+        data_len: u64,
+        data_ptr: u64,
+    ) -> Result<()> {
+        self.gas_counter.pay_base(base)?;
+        if self.context.is_view() {
+            return Err(HostError::ProhibitedInView {
+                method_name: "promise_batch_scratch_write".to_string(),
+            }
+            .into());
+        }
+        let data = get_memory_or_register!(self, data_ptr, data_len)?;
+        let data_len = data.len() as u64;
+        let limit = self.config.limit_config.max_arguments_length;
+        if data_len > limit {
+            return Err(HostError::ScratchLengthExceeded { length: data_len, limit }.into());
+        }
+        let data = data.into_owned();
+
+        let (receipt_idx, sir) = self.promise_idx_to_receipt_idx_with_sir(promise_idx)?;
+
+        let gas = self.pay_action_per_byte(ActionCosts::new_data_receipt_byte, data_len, sir)?;
+
+        self.ext.append_scratch(receipt_idx, data)?;
+        self.charge_receipt(receipt_idx, gas);
+        Ok(())
+    }
+
     // #####################
     // # Miscellaneous API #
     // #####################
@@ -2196,7 +3467,13 @@ impl<'a> VMLogic<'a> {
             burn_gas,
             ActionCosts::new_data_receipt_byte,
         )?;
-        self.return_data = ReturnData::Value(return_val.into_owned());
+        (self.return_data, self.streamed_return) = match &self.return_sink {
+            Some(sink) if self.context.output_data_receivers.is_empty() => {
+                crate::return_sink::stream(&**sink, &return_val);
+                (ReturnData::None, Some(num_bytes))
+            }
+            _ => (ReturnData::Value(return_val.into_owned()), None),
+        };
         Ok(())
     }
 
@@ -2240,13 +3517,21 @@ impl<'a> VMLogic<'a> {
     ///
     /// # Cost
     ///
-    /// `base + log_base + log_byte + num_bytes + utf8 decoding cost`
+    /// `base + log_base + (log_byte + log_emit_byte) * num_bytes + utf8 decoding cost`
+    ///
+    /// The decoding cost, see `get_utf8_string`, is charged for all the bytes
+    /// read even if they are not valid UTF-8, while the `log_base`, `log_byte`
+    /// and `log_emit_byte` emitting cost is only charged for messages actually
+    /// logged.  `log_emit_byte` is a cost of
+    /// [`crate::logic::ExtraExtCostsConfig`], like the `log_decoding_byte`
+    /// of the decoding cost.
     pub fn log_utf8(&mut self, len: u64, ptr: u64) -> Result<()> {
         self.gas_counter.pay_base(base)?;
         self.check_can_add_a_log_message()?;
         let message = self.get_utf8_string(len, ptr)?;
         self.gas_counter.pay_base(log_base)?;
         self.gas_counter.pay_per(log_byte, message.len() as u64)?;
+        self.pay_log_emit(message.len())?;
         self.checked_push_log(message)
     }
 
@@ -2264,7 +3549,11 @@ impl<'a> VMLogic<'a> {
     ///
     /// # Cost
     ///
-    /// `base + log_base + log_byte * num_bytes + utf16 decoding cost`
+    /// `base + log_base + (log_byte + log_emit_byte) * num_bytes + utf16 decoding cost`
+    ///
+    /// As for [`Self::log_utf8`], invalid UTF-16 is charged for decoding but
+    /// not for emitting.  Odd lengths are only charged for decoding with
+    /// [`Config::log_decoding_cost`].
     pub fn log_utf16(&mut self, len: u64, ptr: u64) -> Result<()> {
         self.gas_counter.pay_base(base)?;
         self.check_can_add_a_log_message()?;
@@ -2272,9 +3561,37 @@ impl<'a> VMLogic<'a> {
         self.gas_counter.pay_base(log_base)?;
         // Let's not use `encode_utf16` for gas per byte here, since it's a lot of compute.
         self.gas_counter.pay_per(log_byte, message.len() as u64)?;
+        self.pay_log_emit(message.len())?;
         self.checked_push_log(message)
     }
 
+    /// Checks whether the data is a valid UTF-8 sequence without copying it into the contract.
+    ///
+    /// # Returns
+    ///
+    /// * If the data is valid UTF-8 returns `u64::MAX`;
+    /// * Otherwise returns the offset of the first byte which is not part of a valid UTF-8
+    ///   sequence.  All bytes before that offset form a valid UTF-8 string.
+    ///
+    /// # Errors
+    ///
+    /// * If `len + ptr` exceeds the memory container or points to an unused register it returns
+    ///   `MemoryAccessViolation`.
+    ///
+    /// # Cost
+    ///
+    /// `base + cost of reading data from memory + utf8_decoding_base + utf8_decoding_byte * num_bytes`
+    pub fn validate_utf8(&mut self, len: u64, ptr: u64) -> Result<u64> {
+        self.gas_counter.pay_base(base)?;
+        let data = get_memory_or_register!(self, ptr, len)?;
+        self.gas_counter.pay_base(utf8_decoding_base)?;
+        self.gas_counter.pay_per(utf8_decoding_byte, data.len() as u64)?;
+        match std::str::from_utf8(&data) {
+            Ok(_) => Ok(u64::MAX),
+            Err(err) => Ok(err.valid_up_to() as u64),
+        }
+    }
+
     /// Special import kept for compatibility with AssemblyScript contracts. Not called by smart
     /// contracts directly, but instead called by the code generated by AssemblyScript.
     ///
@@ -2289,7 +3606,7 @@ impl<'a> VMLogic<'a> {
     ///
     /// # Cost
     ///
-    /// `base +  log_base + log_byte * num_bytes + utf16 decoding cost`
+    /// `base +  log_base + (log_byte + log_emit_byte) * num_bytes + utf16 decoding cost`
     pub fn abort(&mut self, msg_ptr: u32, filename_ptr: u32, line: u32, col: u32) -> Result<()> {
         self.gas_counter.pay_base(base)?;
         if msg_ptr < 4 || filename_ptr < 4 {
@@ -2307,11 +3624,78 @@ impl<'a> VMLogic<'a> {
         let message = format!("{}, filename: \"{}\" line: {} col: {}", msg, filename, line, col);
         self.gas_counter.pay_base(log_base)?;
         self.gas_counter.pay_per(log_byte, message.as_bytes().len() as u64)?;
+        self.pay_log_emit(message.len())?;
         self.checked_push_log(format!("ABORT: {}", message))?;
 
         Err(HostError::GuestPanic { panic_msg: message }.into())
     }
 
+    // ##################
+    // # Formatting API #
+    // ##################
+
+    /// Writes the decimal representation of the `u128` value stored at `value_ptr` into the
+    /// register, e.g. `1234567` becomes `"1234567"`.
+    ///
+    /// # Errors
+    ///
+    /// * If `value_ptr + 16` points outside the memory of the guest returns
+    ///   `MemoryAccessViolation`.
+    ///
+    /// # Cost
+    ///
+    /// `base + read_memory_base + 16 * read_memory_byte + cost of writing data into a register`
+    pub fn format_u128(&mut self, value_ptr: u64, register_id: u64) -> Result<()> {
+        self.gas_counter.pay_base(base)?;
+        let value = self.memory.get_u128(&mut self.gas_counter, value_ptr)?;
+        self.registers.set(
+            &mut self.gas_counter,
+            &self.config.limit_config,
+            register_id,
+            value.to_string().into_bytes(),
+        )
+    }
+
+    /// Writes the `u128` value stored at `value_ptr` into the register as a fixed point decimal
+    /// number with `decimals` digits after the point, e.g. `1234567` with `3` decimals becomes
+    /// `"1234.567"` and `5` with `3` decimals becomes `"0.005"`.  No point is written if
+    /// `decimals` is zero.
+    ///
+    /// # Errors
+    ///
+    /// * If `value_ptr + 16` points outside the memory of the guest returns
+    ///   `MemoryAccessViolation`.
+    /// * If `decimals` is greater than `38` returns `FormatDecimalsExceeded`.
+    ///
+    /// # Cost
+    ///
+    /// `base + read_memory_base + 16 * read_memory_byte + cost of writing data into a register`
+    pub fn format_fixed_point(
+        &mut self,
+        value_ptr: u64,
+        decimals: u64,
+        register_id: u64,
+    ) -> Result<()> {
+        self.gas_counter.pay_base(base)?;
+        const MAX_DECIMALS: u64 = 38;
+        if decimals > MAX_DECIMALS {
+            return Err(HostError::FormatDecimalsExceeded { decimals, limit: MAX_DECIMALS }.into());
+        }
+        let value = self.memory.get_u128(&mut self.gas_counter, value_ptr)?;
+        let divisor = 10u128.pow(decimals as u32);
+        let formatted = if decimals == 0 {
+            value.to_string()
+        } else {
+            format!("{}.{:0width$}", value / divisor, value % divisor, width = decimals as usize)
+        };
+        self.registers.set(
+            &mut self.gas_counter,
+            &self.config.limit_config,
+            register_id,
+            formatted.into_bytes(),
+        )
+    }
+
     // ###############
     // # Storage API #
     // ###############
@@ -2350,6 +3734,10 @@ impl<'a> VMLogic<'a> {
     /// * If key is not in use it inserts the key-value pair and does not modify the register. Returns `0`;
     /// * If key is in use it inserts the key-value and copies the old value into the `register_id`. Returns `1`.
     ///
+    /// The old value is the one the call sees: the value of its last write of the key, if any,
+    /// and the value in the storage otherwise.  As the register keeps its content when no value
+    /// is evicted, only the return value tells whether the register holds the old value.
+    ///
     /// # Errors
     ///
     /// * If `key_len + key_ptr` or `value_len + value_ptr` exceeds the memory container or points
@@ -2400,10 +3788,12 @@ impl<'a> VMLogic<'a> {
         }
         self.gas_counter.pay_per(storage_write_key_byte, key.len() as u64)?;
         self.gas_counter.pay_per(storage_write_value_byte, value.len() as u64)?;
+        Self::touch_key(&mut self.state_witness, self.ext, &key, false, true)?;
         let nodes_before = self.ext.get_trie_nodes_count();
         // For storage write, we need to first perform a read on the key to calculate the TTN cost.
         // This storage_get must be performed through trie instead of through FlatStorage
-        let evicted_ptr = self.ext.storage_get(&key, StorageGetMode::Trie)?;
+        let evicted_ptr =
+            StorageBuffer::get(self.storage_buffer.as_ref(), self.ext, &key, StorageGetMode::Trie)?;
         let evicted =
             Self::deref_value(&mut self.gas_counter, storage_write_evicted_byte, evicted_ptr)?;
         let nodes_delta = self
@@ -2424,7 +3814,10 @@ impl<'a> VMLogic<'a> {
         );
 
         self.gas_counter.add_trie_fees(&nodes_delta)?;
-        self.ext.storage_set(&key, &value)?;
+        match &mut self.storage_buffer {
+            Some(buffer) => buffer.set(&key, &value),
+            None => self.ext.storage_set(&key, &value)?,
+        }
         let storage_config = &self.fees_config.storage_usage_config;
         match evicted {
             Some(old_value) => {
@@ -2438,6 +3831,10 @@ impl<'a> VMLogic<'a> {
                     .current_storage_usage
                     .checked_add(value.len() as u64)
                     .ok_or(InconsistentStateError::IntegerOverflow)?;
+                self.storage_delta.record(
+                    &key,
+                    StorageBytes { added: value.len() as u64, removed: old_value.len() as u64 },
+                );
                 self.registers.set(
                     &mut self.gas_counter,
                     &self.config.limit_config,
@@ -2448,19 +3845,32 @@ impl<'a> VMLogic<'a> {
             }
             None => {
                 // Inner value can't overflow, because the key/value length is limited.
+                let added =
+                    value.len() as u64 + key.len() as u64 + storage_config.num_extra_bytes_record;
                 self.current_storage_usage = self
                     .current_storage_usage
-                    .checked_add(
-                        value.len() as u64
-                            + key.len() as u64
-                            + storage_config.num_extra_bytes_record,
-                    )
+                    .checked_add(added)
                     .ok_or(InconsistentStateError::IntegerOverflow)?;
+                self.storage_delta.record(&key, StorageBytes { added, removed: 0 });
                 Ok(0)
             }
         }
     }
 
+    /// Records the access to `key` in the state witness, if requested.
+    fn touch_key(
+        state_witness: &mut Option<StateWitnessRecorder>,
+        ext: &dyn External,
+        key: &[u8],
+        read: bool,
+        written: bool,
+    ) -> Result<()> {
+        match state_witness {
+            Some(witness) => witness.touch(ext, key, read, written),
+            None => Ok(()),
+        }
+    }
+
     fn deref_value<'s>(
         gas_counter: &mut GasCounter,
         cost_per_byte: ExtCosts,
@@ -2480,6 +3890,8 @@ impl<'a> VMLogic<'a> {
     ///   is zero bytes. Returns `1`;
     /// * If key is not present then does not modify the register. Returns `0`;
     ///
+    /// The read sees the writes and removals of the key made by the call before it.
+    ///
     /// # Errors
     ///
     /// * If `key_len + key_ptr` exceeds the memory container or points to an unused register it
@@ -2504,8 +3916,14 @@ impl<'a> VMLogic<'a> {
             .into());
         }
         self.gas_counter.pay_per(storage_read_key_byte, key.len() as u64)?;
+        Self::touch_key(&mut self.state_witness, self.ext, &key, true, false)?;
         let nodes_before = self.ext.get_trie_nodes_count();
-        let read = self.ext.storage_get(&key, self.config.storage_get_mode);
+        let read = StorageBuffer::get(
+            self.storage_buffer.as_ref(),
+            self.ext,
+            &key,
+            self.config.storage_get_mode,
+        );
         let nodes_delta = self
             .ext
             .get_trie_nodes_count()
@@ -2543,6 +3961,9 @@ impl<'a> VMLogic<'a> {
     ///   into the `register_id`, even if the content is zero bytes. Returns `1`;
     /// * If key is not present then does not modify the register. Returns `0`.
     ///
+    /// The removed value is the one the call sees, as for [`Self::storage_write`], and the reads
+    /// after the removal do not find the key.
+    ///
     /// # Errors
     ///
     /// * If `key_len + key_ptr` exceeds the memory container or points to an unused register it
@@ -2574,14 +3995,19 @@ impl<'a> VMLogic<'a> {
             .into());
         }
         self.gas_counter.pay_per(storage_remove_key_byte, key.len() as u64)?;
+        Self::touch_key(&mut self.state_witness, self.ext, &key, false, true)?;
         let nodes_before = self.ext.get_trie_nodes_count();
         // To delete a key, we need to first perform a read on the key to calculate the TTN cost.
         // This storage_get must be performed through trie instead of through FlatStorage
-        let removed_ptr = self.ext.storage_get(&key, StorageGetMode::Trie)?;
+        let removed_ptr =
+            StorageBuffer::get(self.storage_buffer.as_ref(), self.ext, &key, StorageGetMode::Trie)?;
         let removed =
             Self::deref_value(&mut self.gas_counter, storage_remove_ret_value_byte, removed_ptr)?;
 
-        self.ext.storage_remove(&key)?;
+        match &mut self.storage_buffer {
+            Some(buffer) => buffer.remove(&key),
+            None => self.ext.storage_remove(&key)?,
+        }
         let nodes_delta = self
             .ext
             .get_trie_nodes_count()
@@ -2603,14 +4029,13 @@ impl<'a> VMLogic<'a> {
         match removed {
             Some(value) => {
                 // Inner value can't overflow, because the key/value length is limited.
+                let removed =
+                    value.len() as u64 + key.len() as u64 + storage_config.num_extra_bytes_record;
                 self.current_storage_usage = self
                     .current_storage_usage
-                    .checked_sub(
-                        value.len() as u64
-                            + key.len() as u64
-                            + storage_config.num_extra_bytes_record,
-                    )
+                    .checked_sub(removed)
                     .ok_or(InconsistentStateError::IntegerOverflow)?;
+                self.storage_delta.record(&key, StorageBytes { added: 0, removed });
                 self.registers.set(
                     &mut self.gas_counter,
                     &self.config.limit_config,
@@ -2647,8 +4072,14 @@ impl<'a> VMLogic<'a> {
             .into());
         }
         self.gas_counter.pay_per(storage_has_key_byte, key.len() as u64)?;
+        Self::touch_key(&mut self.state_witness, self.ext, &key, true, false)?;
         let nodes_before = self.ext.get_trie_nodes_count();
-        let res = self.ext.storage_has_key(&key, self.config.storage_get_mode);
+        let res = StorageBuffer::has_key(
+            self.storage_buffer.as_ref(),
+            self.ext,
+            &key,
+            self.config.storage_get_mode,
+        );
         let nodes_delta = self
             .ext
             .get_trie_nodes_count()
@@ -2685,6 +4116,22 @@ impl<'a> VMLogic<'a> {
         Ok(())
     }
 
+    /// Counts an execution of the block `block` of a contract prepared with
+    /// coverage, see [`crate::coverage_map`].  The VMs only link it as
+    /// `unc_coverage.coverage_hit` for the calls with
+    /// [`RunOptions::coverage`].
+    ///
+    /// # Cost
+    ///
+    /// 0
+    #[cfg(feature = "coverage")]
+    pub fn coverage_hit(&mut self, block: u32) -> Result<()> {
+        if let Some(coverage) = &self.coverage {
+            coverage.hit(block);
+        }
+        Ok(())
+    }
+
     /// DEPRECATED
     /// Creates an iterator object inside the host. Returns the identifier that uniquely
     /// differentiates the given iterator from other iterators that can be simultaneously created.
@@ -2781,24 +4228,57 @@ impl<'a> VMLogic<'a> {
     /// If `FunctionCallWeight` protocol feature (127) is enabled, unused gas will be
     /// distributed to functions that specify a gas weight. If there are no functions with
     /// a gas weight, the outcome will contain unused gas as usual.
-    pub fn compute_outcome(self) -> VMOutcome {
-        let burnt_gas = self.gas_counter.burnt_gas();
-        let used_gas = self.gas_counter.used_gas();
+    pub fn compute_outcome(mut self) -> VMOutcome {
+        self.stop_watchdog();
+        let mut burnt_gas = self.gas_counter.burnt_gas();
+        let mut used_gas = self.gas_counter.used_gas();
+        let promises_gas = used_gas.saturating_sub(burnt_gas);
+        // View calls ignore the prepaid gas, so there is nothing to refund.
+        let mut refunded_gas = if self.context.is_view() {
+            0
+        } else {
+            self.context.prepaid_gas.saturating_sub(used_gas)
+        };
 
         let mut profile = self.gas_counter.profile_data();
         profile.compute_wasm_instruction_cost(burnt_gas);
         let compute_usage = profile.total_compute_usage(&self.config.ext_costs);
 
+        // The dust burnt is neither in the profile nor in the compute usage,
+        // as the call did not spend it.
+        if refunded_gas < self.config.min_refund_gas
+            && burnt_gas.saturating_add(refunded_gas) <= self.config.limit_config.max_gas_burnt
+        {
+            burnt_gas += refunded_gas;
+            used_gas += refunded_gas;
+            refunded_gas = 0;
+        }
+
         VMOutcome {
             balance: self.current_account_balance,
             storage_usage: self.current_storage_usage,
+            storage_delta: self.storage_delta,
             return_data: self.return_data,
             burnt_gas,
             used_gas,
+            promises_gas,
+            refunded_gas,
             compute_usage,
             logs: self.logs,
+            receipts: self.receipts.unwrap_or_default(),
+            receipt_costs: self.receipt_costs,
             profile,
+            gas_profile: self.gas_counter.gas_profile(),
             aborted: None,
+            checkpoints: self.checkpoints.unwrap_or_default(),
+            gas_exhaustion_trace: self.gas_exhaustion_trace.unwrap_or_default(),
+            host_calls: self.host_calls.unwrap_or_default(),
+            execution_fingerprint: self
+                .execution_fingerprint
+                .map(|fingerprint| CryptoHash(fingerprint.finalize().into())),
+            used_memory: self.used_memory,
+            backtrace: None,
+            state_witness: self.state_witness.map(StateWitnessRecorder::finish),
         }
     }
 
@@ -2814,11 +4294,96 @@ impl<'a> VMLogic<'a> {
         self.gas_counter.pay_base(contract_loading_base)
     }
 
+    /// Adds the cost the [`CodePricing`] of the call, if any, sets for loading
+    /// a contract of `code_len` bytes.
+    pub(crate) fn add_code_pricing_fee(
+        &mut self,
+        code_len: usize,
+    ) -> std::result::Result<std::result::Result<(), FunctionCallError>, VMRunnerError> {
+        let Some(pricing) = &self.code_pricing else { return Ok(Ok(())) };
+        let gas = pricing.call_gas(code_len as u64, self.config);
+        match self.gas_counter.burn_gas(gas) {
+            Ok(()) => Ok(Ok(())),
+            Err(err) => Ok(Err(FunctionCallError::try_from(err)?)),
+        }
+    }
+
+    /// Whether the backends link `coverage_hit` for the call.
+    #[cfg(feature = "coverage")]
+    pub(crate) fn has_coverage(&self) -> bool {
+        self.coverage.is_some()
+    }
+
+    /// The host functions of the embedder the backends link, if any.
+    pub(crate) fn custom_host_functions(&self) -> Option<&Arc<CustomHostFunctionRegistry>> {
+        self.custom_host_functions.as_ref()
+    }
+
+    /// Called by the backends for each call of the custom host function
+    /// `index` of [`Self::custom_host_functions`], with one argument per
+    /// parameter.
+    ///
+    /// Returns the result of the function, if it has one.
+    ///
+    /// # Cost
+    ///
+    /// `base + gas` of the function, and what the function charges itself.
+    pub fn call_custom_host_function(&mut self, index: usize, args: &[u64]) -> Result<Option<u64>> {
+        let registry = self.custom_host_functions.clone().expect("linked without a registry");
+        let function = registry.function(index);
+        self.enter_host_function(function.name, args);
+        let result = self.gas_counter.pay_base(base).and_then(|()| {
+            self.gas_counter.burn_gas(function.gas)?;
+            let mut context = CustomHostContext {
+                gas_counter: &mut self.gas_counter,
+                memory: &mut self.memory,
+                registers: &mut self.registers,
+                limit_config: &self.config.limit_config,
+            };
+            let value = registry.call(index, &mut context, args)?;
+            Ok(function.returns.then_some(value))
+        });
+        self.exit_host_function(result)
+    }
+
     /// Gets pointer to the fast gas counter.
     pub fn gas_counter_pointer(&mut self) -> *mut FastGasCounter {
         self.gas_counter.gas_counter_raw_ptr()
     }
 
+    /// Keeps the wasm call stack at the point the execution was aborted with
+    /// `error` if the execution ran out of gas.
+    ///
+    /// Runners call this with the frames reported by their backend, innermost
+    /// first.  The trace is kept with
+    /// [`RunOptions::record_gas_exhaustion_trace`] and ends up in
+    /// [`VMOutcome::gas_exhaustion_trace`], and is symbolicated into
+    /// [`VMOutcome::backtrace`] for any error when the call asked for it.
+    /// Runners need not bother collecting it unless
+    /// [`Self::wants_abort_trace`].
+    pub fn record_abort_trace(&mut self, error: &FunctionCallError, trace: Vec<WasmFrame>) {
+        let out_of_gas = matches!(
+            error,
+            FunctionCallError::HostError(HostError::GasExceeded | HostError::GasLimitExceeded)
+        );
+        #[cfg(feature = "backtrace")]
+        if self.backtrace_code.is_some() {
+            self.abort_trace.clone_from(&trace);
+        }
+        if let Some(kept) = self.gas_exhaustion_trace.as_mut().filter(|_| out_of_gas) {
+            *kept = trace;
+        }
+    }
+
+    /// Whether [`Self::record_abort_trace`] keeps the trace of some errors.
+    pub fn wants_abort_trace(&self) -> bool {
+        #[cfg(feature = "backtrace")]
+        if self.backtrace_code.is_some() {
+            return true;
+        }
+        self.gas_exhaustion_trace.is_some()
+    }
+
     /// Properly handles gas limit exceeded error.
     pub fn process_gas_limit(&mut self) -> HostError {
         let new_burn_gas = self.gas_counter.burnt_gas();
@@ -2827,12 +4392,10 @@ impl<'a> VMLogic<'a> {
     }
 
     /// A helper function to pay base cost gas fee for batching an action.
-    pub fn pay_action_base(&mut self, action: ActionCosts, sir: bool) -> Result<()> {
-        let base_fee = self.fees_config.fee(action);
-        let burn_gas = base_fee.send_fee(sir);
-        let use_gas =
-            burn_gas.checked_add(base_fee.exec_fee()).ok_or(HostError::IntegerOverflow)?;
-        self.gas_counter.pay_action_accumulated(burn_gas, use_gas, action)
+    pub fn pay_action_base(&mut self, action: ActionCosts, sir: bool) -> Result<ActionGas> {
+        let gas = gas_planning::action_base_gas(self.fees_config, action, sir)?;
+        self.gas_counter.pay_action_accumulated(gas.burnt, gas.used, action)?;
+        Ok(gas)
     }
 
     /// A helper function to pay per byte gas fee for batching an action.
@@ -2841,16 +4404,10 @@ impl<'a> VMLogic<'a> {
         action: ActionCosts,
         num_bytes: u64,
         sir: bool,
-    ) -> Result<()> {
-        let per_byte_fee = self.fees_config.fee(action);
-        let burn_gas =
-            num_bytes.checked_mul(per_byte_fee.send_fee(sir)).ok_or(HostError::IntegerOverflow)?;
-        let use_gas = burn_gas
-            .checked_add(
-                num_bytes.checked_mul(per_byte_fee.exec_fee()).ok_or(HostError::IntegerOverflow)?,
-            )
-            .ok_or(HostError::IntegerOverflow)?;
-        self.gas_counter.pay_action_accumulated(burn_gas, use_gas, action)
+    ) -> Result<ActionGas> {
+        let gas = gas_planning::action_per_byte_gas(self.fees_config, action, num_bytes, sir)?;
+        self.gas_counter.pay_action_accumulated(gas.burnt, gas.used, action)?;
+        Ok(gas)
     }
 
     /// VM independent setup before loading the executable.
@@ -2863,11 +4420,8 @@ impl<'a> VMLogic<'a> {
         method_name: &str,
         wasm_code_bytes: usize,
     ) -> std::result::Result<(), super::errors::FunctionCallError> {
-        if method_name.is_empty() {
-            let error = super::errors::FunctionCallError::MethodResolveError(
-                super::errors::MethodResolveError::MethodEmptyName,
-            );
-            return Err(error);
+        if let Err(err) = crate::method_name::check_method_name(method_name, self.config) {
+            return Err(super::errors::FunctionCallError::MethodResolveError(err));
         }
         if self.config.fix_contract_loading_cost {
             if self.add_contract_loading_fee(wasm_code_bytes as u64).is_err() {
@@ -2895,50 +4449,302 @@ impl<'a> VMLogic<'a> {
     }
 }
 
-#[derive(PartialEq)]
+/// A wasm function on the call stack of the contract.
+#[derive(
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct WasmFrame {
+    /// Index of the function in the function index space of the module.
+    pub func_index: u32,
+    /// Name of the function from the name section of the module, if any.
+    pub function_name: Option<String>,
+    /// Offset of the executed instruction in the module, if known.
+    pub module_offset: Option<usize>,
+}
+
+impl std::fmt::Display for WasmFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}[{}]", self.function_name.as_deref().unwrap_or("<unnamed>"), self.func_index)?;
+        if let Some(offset) = self.module_offset {
+            write!(f, ":0x{offset:x}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Wasm call stack of an aborted call resolved against the code of the
+/// contract, see `RunOptions::backtrace` of the `backtrace` feature.
+///
+/// Bounded in size: only the [`Self::MAX_FRAMES`] innermost frames are kept
+/// and names are cut at [`Self::MAX_NAME_LEN`] bytes.
+#[derive(
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct ContractBacktrace {
+    /// Innermost frame first.
+    pub frames: Vec<BacktraceFrame>,
+    /// Number of outer frames left out.
+    pub omitted_frames: u32,
+}
+
+impl ContractBacktrace {
+    pub const MAX_FRAMES: usize = 32;
+    pub const MAX_NAME_LEN: usize = 256;
+}
+
+impl std::fmt::Display for ContractBacktrace {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (index, frame) in self.frames.iter().enumerate() {
+            writeln!(f, "{index:>4}: {frame}")?;
+        }
+        if self.omitted_frames > 0 {
+            writeln!(f, "      ... {} more frames", self.omitted_frames)?;
+        }
+        Ok(())
+    }
+}
+
+/// A frame of a [`ContractBacktrace`].
+#[derive(
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct BacktraceFrame {
+    /// Index of the function in the function index space of the contract as
+    /// deployed, before its preparation.
+    pub func_index: u32,
+    /// Name of the function from the name section of the contract, if any.
+    pub function_name: Option<String>,
+    /// Source file of the executed instruction from the DWARF line
+    /// information of the contract, if any.
+    pub file: Option<String>,
+    pub line: Option<u64>,
+}
+
+impl std::fmt::Display for BacktraceFrame {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}[{}]", self.function_name.as_deref().unwrap_or("<unnamed>"), self.func_index)?;
+        if let (Some(file), Some(line)) = (&self.file, self.line) {
+            write!(f, " at {file}:{line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Outcome of a contract call.
+///
+/// The serde format is stable so that outcomes can be sent over RPC, stored
+/// and compared across versions: fields are only appended, and the fields
+/// added after the first version default to empty when missing from JSON.
+/// The borsh format is not: it changes with the fields, so borsh bytes are
+/// only read back by the version of this crate which wrote them, and
+/// outcomes stored for later versions go through the serde format.
+#[derive(PartialEq, BorshSerialize, BorshDeserialize, serde::Serialize, serde::Deserialize)]
 pub struct VMOutcome {
+    #[serde(with = "dec_format")]
     pub balance: Balance,
     pub storage_usage: StorageUsage,
+    /// Storage usage added and removed by the call, optionally per key.
+    #[serde(default)]
+    pub storage_delta: StorageUsageDelta,
     pub return_data: ReturnData,
+    /// Gas burnt by the execution of the call itself, and the refund of the
+    /// call if it is less than the `min_refund_gas` of the config.
     pub burnt_gas: Gas,
+    /// Gas burnt plus gas attached to the receipts created by the call.
     pub used_gas: Gas,
+    /// Gas attached to and prepaid for the execution of the receipts created by
+    /// the call, i.e. `used_gas - burnt_gas`.
+    ///
+    /// This does not include the unused gas which the runtime distributes
+    /// afterwards to the function calls which specify a gas weight.
+    pub promises_gas: Gas,
+    /// Prepaid gas which was neither burnt nor attached to receipts, i.e.
+    /// `prepaid_gas - used_gas`.  Always zero for view calls.
+    pub refunded_gas: Gas,
     pub compute_usage: Compute,
     pub logs: Vec<String>,
+    /// Action receipts created by the call.
+    ///
+    /// The order is deterministic and the same on every backend: receipts come
+    /// in the order the contract created them, their actions in the order the
+    /// contract appended them and their dependencies in the order of the
+    /// promise results the callback reads.  A receipt only depends on receipts
+    /// before it in the list.  These are the receipts the call created through
+    /// the [`External`], which the runtime discards if the call aborts.
+    ///
+    /// Only collected with [`crate::RunOptions::record_receipts`]; the costs
+    /// of the receipts are in [`Self::receipt_costs`] either way.
+    #[serde(default)]
+    pub receipts: Vec<ActionReceipt>,
     /// Data collected from making a contract call
     pub profile: ProfileDataV3,
+    /// Gas burnt per host function and by the wasm code, only collected with
+    /// the `gas_profile` feature.
+    #[serde(default)]
+    pub gas_profile: Option<GasProfile>,
     pub aborted: Option<FunctionCallError>,
+    /// Wasm call stack, innermost frame first, at the point the execution ran
+    /// out of gas.
+    ///
+    /// Only collected with [`crate::RunOptions::record_gas_exhaustion_trace`]
+    /// and by the backends which can walk the wasm stack (all but Wasmer0),
+    /// so that developers can tell which loop exhausted the gas.  Empty
+    /// otherwise.
+    #[serde(default)]
+    pub gas_exhaustion_trace: Vec<WasmFrame>,
+    /// State of the call at each host function it called, in order.
+    ///
+    /// Only collected with [`crate::RunOptions::record_checkpoints`], to find
+    /// where the executions of a call on two VMs diverge.
+    #[serde(default)]
+    pub checkpoints: Vec<HostCallCheckpoint>,
+    /// Host function calls of the contract with their arguments, results and
+    /// gas, in order.
+    ///
+    /// Only collected with [`crate::RunOptions::record_host_calls`].
+    #[serde(default)]
+    pub host_calls: Vec<HostCallRecord>,
+    /// Peaks of the memory used by the call, only measured for calls of a
+    /// config with a [`crate::logic::ExtraLimitConfig::memory_cap`].  Zero
+    /// otherwise.
+    #[serde(default)]
+    pub used_memory: UsedMemory,
+    /// Wasm call stack at the point the call aborted, only collected with
+    /// `RunOptions::backtrace` of the `backtrace` feature and by the backends
+    /// which can walk the wasm stack (all but Wasmer0).
+    #[serde(default)]
+    pub backtrace: Option<ContractBacktrace>,
+    /// Gas and tokens the call attached to each of [`Self::receipts`], in
+    /// the same order, so that they can be shown per receipt.
+    #[serde(default)]
+    pub receipt_costs: Vec<ReceiptCost>,
+    /// Keys of the storage touched by the call with the proofs of their
+    /// values before it, only collected with
+    /// [`crate::RunOptions::record_state_witness`].
+    #[serde(default)]
+    pub state_witness: Option<StateWitness>,
+    /// Hash of the names of the host functions the contract called, in
+    /// order, with the gas burnt before each, only computed with
+    /// [`crate::RunOptions::execution_fingerprint`].
+    #[serde(default)]
+    pub execution_fingerprint: Option<CryptoHash>,
 }
 
 impl VMOutcome {
     /// Consumes the `VMLogic` object and computes the final outcome with the
     /// given error that stopped execution from finishing successfully.
-    pub fn abort(logic: VMLogic, error: FunctionCallError) -> VMOutcome {
+    ///
+    /// Running out of gas because the deadline interrupted the call is
+    /// reported as [`FunctionCallError::Timeout`].  Running out of the stack
+    /// accounted by the host is reported as [`WasmTrap::StackOverflow`] when
+    /// the instrumentation is the only stack limit, as NearVM reports it.
+    pub fn abort(mut logic: VMLogic, error: FunctionCallError) -> VMOutcome {
+        let out_of_gas = matches!(
+            error,
+            FunctionCallError::HostError(HostError::GasExceeded | HostError::GasLimitExceeded)
+        );
+        let error =
+            if logic.stop_watchdog() && out_of_gas { FunctionCallError::Timeout } else { error };
+        let error = match error {
+            FunctionCallError::HostError(HostError::MemoryAccessViolation)
+                if logic.stack_exhausted
+                    && crate::prepare::instrumented_stack_limit_only(logic.config) =>
+            {
+                FunctionCallError::WasmTrap(WasmTrap::StackOverflow)
+            }
+            error => error,
+        };
+        let error = match (logic.memory_cap, logic.memory_cap_exceeded) {
+            (Some(cap), Some(used)) => FunctionCallError::MemoryCapExceeded { used, cap },
+            _ => error,
+        };
+        #[cfg(feature = "backtrace")]
+        let backtrace = match logic.backtrace_code {
+            Some(code) if !logic.abort_trace.is_empty() => {
+                Some(crate::backtrace::symbolicate(code, logic.config, &logic.abort_trace))
+            }
+            _ => None,
+        };
+        logic.finish_return_stream(false);
         let mut outcome = logic.compute_outcome();
         outcome.aborted = Some(error);
+        #[cfg(feature = "backtrace")]
+        {
+            outcome.backtrace = backtrace;
+        }
         outcome
     }
 
     /// Consumes the `VMLogic` object and computes the final outcome for a
     /// successful execution.
+    ///
+    /// The storage writes buffered under [`Config::buffer_storage_writes`]
+    /// are left unapplied, see [`VMOutcome::try_ok`].
     pub fn ok(logic: VMLogic) -> VMOutcome {
+        logic.finish_return_stream(true);
         logic.compute_outcome()
     }
 
+    /// Same as [`VMOutcome::ok`], with the buffered storage writes applied to
+    /// the [`External`] first, the call failing like the host function
+    /// writing them would have if the `External` fails to.
+    pub fn try_ok(mut logic: VMLogic) -> Result<VMOutcome, VMRunnerError> {
+        match logic.commit_storage() {
+            Ok(()) => Ok(VMOutcome::ok(logic)),
+            Err(err) => Ok(VMOutcome::abort(logic, err.try_into()?)),
+        }
+    }
+
     /// Creates an outcome with a no-op outcome.
     pub fn nop_outcome(error: FunctionCallError) -> VMOutcome {
         VMOutcome {
             // Note: Balance and storage fields are ignored on a failed outcome.
             balance: 0,
             storage_usage: 0,
+            storage_delta: StorageUsageDelta::default(),
             // Note: Fields below are added or merged when processing the
             // outcome. With 0 or the empty set, those are no-ops.
             return_data: ReturnData::None,
             burnt_gas: 0,
             used_gas: 0,
+            promises_gas: 0,
+            refunded_gas: 0,
             compute_usage: 0,
             logs: Vec::new(),
+            receipts: Vec::new(),
+            receipt_costs: Vec::new(),
             profile: ProfileDataV3::default(),
+            gas_profile: None,
             aborted: Some(error),
+            checkpoints: Vec::new(),
+            gas_exhaustion_trace: Vec::new(),
+            host_calls: Vec::new(),
+            used_memory: UsedMemory::default(),
+            backtrace: None,
+            state_witness: None,
+            execution_fingerprint: None,
         }
     }
 
@@ -2951,6 +4757,7 @@ impl VMOutcome {
         if logic.config.fix_contract_loading_cost {
             Self::abort(logic, error)
         } else {
+            logic.finish_return_stream(false);
             Self::nop_outcome(error)
         }
     }
@@ -2974,3 +4781,55 @@ impl std::fmt::Debug for VMOutcome {
         Ok(())
     }
 }
+
+/// The recoverable signature made of the 64 bytes of `signature` and of the
+/// recovery byte `v` of [`VMLogic::ecrecover`].
+#[cfg(feature = "secp256k1")]
+fn ecrecover_signature(signature: &[u8; 64], v: u64) -> Result<Secp256K1Signature> {
+    if v >= 4 {
+        return Err(VMLogicError::HostError(HostError::ECRecoverError {
+            msg: format!("V recovery byte 0 through 3 are valid but was provided {}", v),
+        }));
+    }
+    let mut bytes = [0u8; 65];
+    bytes[0..64].copy_from_slice(signature);
+    bytes[64] = v as u8;
+    Ok(Secp256K1Signature::from(bytes))
+}
+
+/// Recovers the signer of `hash`, or returns `None` if the signature is not
+/// valid with the checks of `malleability_flag`.
+#[cfg(feature = "secp256k1")]
+fn ecrecover_key(
+    signature: &Secp256K1Signature,
+    hash: [u8; 32],
+    malleability_flag: u64,
+) -> Result<Option<unc_crypto::Secp256K1PublicKey>> {
+    check_malleability_flag(malleability_flag)?;
+    if !signature.check_signature_values(malleability_flag != 0) {
+        return Ok(None);
+    }
+    Ok(signature.recover(hash).ok())
+}
+
+#[cfg(feature = "secp256k1")]
+fn check_malleability_flag(malleability_flag: u64) -> Result<()> {
+    if malleability_flag != 0 && malleability_flag != 1 {
+        return Err(VMLogicError::HostError(HostError::ECRecoverError {
+            msg: format!(
+                "Malleability flag needs to be 0 or 1, but is instead {}",
+                malleability_flag
+            ),
+        }));
+    }
+    Ok(())
+}
+
+/// SEC1 compressed encoding of the uncompressed key `x || y`.
+#[cfg(feature = "secp256k1")]
+fn compress_secp256k1_key(key: &[u8]) -> [u8; 33] {
+    let mut compressed = [0u8; 33];
+    compressed[0] = 0x02 | (key[63] & 1);
+    compressed[1..].copy_from_slice(&key[..32]);
+    compressed
+}
@@ -0,0 +1,35 @@
+use crate::logic::VMContext;
+
+/// Returns a context of a regular (non-view) function call suitable for testing.
+///
+/// The context is the one used by the crate's own logic tests: `alice.near` executes its own
+/// contract called by `carol.near` on behalf of `bob.near` with some balance, deposit and prepaid
+/// gas attached.  Fields can be adjusted after construction as needed.
+pub fn get_context() -> VMContext {
+    VMContext {
+        current_account_id: "alice.near".parse().unwrap(),
+        signer_account_id: "bob.near".parse().unwrap(),
+        signer_account_pk: vec![0, 1, 2, 3, 4],
+        predecessor_account_id: "carol.near".parse().unwrap(),
+        input: vec![0, 1, 2, 3, 4],
+        block_height: 10,
+        block_timestamp: 42,
+        epoch_height: 1,
+        account_balance: 100,
+        storage_usage: 0,
+        account_locked_balance: 50,
+        attached_deposit: 10,
+        prepaid_gas: 10u64.pow(14),
+        random_seed: vec![0, 1, 2],
+        view_config: None,
+        output_data_receivers: vec![],
+    }
+}
+
+/// Returns the same context as [`get_context`] but configured as a view call.
+pub fn get_view_context(max_gas_burnt: unc_primitives_core::types::Gas) -> VMContext {
+    VMContext {
+        view_config: Some(unc_primitives_core::config::ViewConfig { max_gas_burnt }),
+        ..get_context()
+    }
+}
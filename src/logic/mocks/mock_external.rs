@@ -1,3 +1,4 @@
+use crate::logic::gas_distribution::GasDistributionPolicy;
 use crate::logic::types::ReceiptIndex;
 use crate::logic::TrieNodesCount;
 use crate::logic::{External, StorageGetMode, ValuePtr};
@@ -61,6 +62,14 @@ pub enum MockAction {
         public_key: unc_crypto::PublicKey,
         nonce: u64,
     },
+    AttachScratch {
+        receipt_index: ReceiptIndex,
+        data: Vec<u8>,
+    },
+    DeployGlobalContract {
+        receipt_index: ReceiptIndex,
+        code_hash: CryptoHash,
+    },
 }
 
 #[derive(Default, Clone)]
@@ -69,6 +78,12 @@ pub struct MockedExternal {
     pub fake_trie: HashMap<Vec<u8>, Vec<u8>>,
     pub validators: HashMap<AccountId, (Power, Balance)>,
     pub action_log: Vec<MockAction>,
+    /// Scratch area carried into the execution by the incoming receipt.
+    pub scratch: Option<Vec<u8>>,
+    /// Hashes of the contracts deployed on other accounts.
+    pub code_hashes: HashMap<AccountId, CryptoHash>,
+    /// Reported by [`External::state_changed`].
+    pub state_changed: bool,
     data_count: u64,
 }
 
@@ -99,6 +114,36 @@ impl MockedExternal {
     pub fn new() -> Self {
         Self::default()
     }
+
+    /// Splits `unused_gas` among the logged function calls with a gas weight
+    /// according to `policy`, the way the runtime does once the execution is
+    /// over.
+    ///
+    /// Returns the amount of gas that was distributed.
+    pub fn distribute_unused_gas(
+        &mut self,
+        unused_gas: Gas,
+        policy: &dyn GasDistributionPolicy,
+    ) -> Gas {
+        let weights: Vec<GasWeight> = self
+            .action_log
+            .iter()
+            .filter_map(|action| match action {
+                MockAction::FunctionCallWeight { gas_weight, .. } => Some(GasWeight(gas_weight.0)),
+                _ => None,
+            })
+            .collect();
+        let mut distribution = policy.distribute(unused_gas, &weights).into_iter();
+        let mut distributed: Gas = 0;
+        for action in &mut self.action_log {
+            if let MockAction::FunctionCallWeight { prepaid_gas, .. } = action {
+                let gas = distribution.next().unwrap_or(0);
+                *prepaid_gas += gas;
+                distributed += gas;
+            }
+        }
+        distributed
+    }
 }
 
 use crate::logic::dependencies::Result;
@@ -130,6 +175,10 @@ impl External for MockedExternal {
         Ok(self.fake_trie.contains_key(key))
     }
 
+    fn state_changed(&self) -> bool {
+        self.state_changed
+    }
+
     fn generate_data_id(&mut self) -> CryptoHash {
         // Generates some hash for the data ID to receive data. This hash should not be functionally
         // used in any mocked contexts.
@@ -168,6 +217,10 @@ impl External for MockedExternal {
         Ok(total_power)
     }
 
+    fn account_code_hash(&self, account_id: &AccountId) -> Result<Option<CryptoHash>> {
+        Ok(self.code_hashes.get(account_id).copied())
+    }
+
     fn create_receipt(
         &mut self,
         receipt_indices: Vec<ReceiptIndex>,
@@ -195,6 +248,15 @@ impl External for MockedExternal {
         Ok(())
     }
 
+    fn append_action_deploy_global_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code_hash: CryptoHash,
+    ) -> Result<(), crate::logic::VMLogicError> {
+        self.action_log.push(MockAction::DeployGlobalContract { receipt_index, code_hash });
+        Ok(())
+    }
+
     fn append_action_function_call_weight(
         &mut self,
         receipt_index: ReceiptIndex,
@@ -279,6 +341,19 @@ impl External for MockedExternal {
         Ok(())
     }
 
+    fn scratch_get(&self) -> Result<Option<Vec<u8>>> {
+        Ok(self.scratch.clone())
+    }
+
+    fn append_scratch(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        data: Vec<u8>,
+    ) -> Result<(), crate::logic::VMLogicError> {
+        self.action_log.push(MockAction::AttachScratch { receipt_index, data });
+        Ok(())
+    }
+
     fn get_receipt_receiver(&self, receipt_index: ReceiptIndex) -> &AccountId {
         match &self.action_log[receipt_index as usize] {
             MockAction::CreateReceipt { receiver_id, .. } => receiver_id,
@@ -286,3 +361,8 @@ impl External for MockedExternal {
         }
     }
 }
+
+#[test]
+fn test_external_storage() {
+    crate::logic::test_utils::test_external_storage(&mut MockedExternal::new());
+}
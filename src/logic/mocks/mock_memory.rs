@@ -43,6 +43,10 @@ impl MemoryLike for MockedMemory {
         slice.copy_from_slice(buffer);
         Ok(())
     }
+
+    fn data_size(&self) -> u64 {
+        self.0.len() as u64
+    }
 }
 
 #[test]
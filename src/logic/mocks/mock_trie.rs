@@ -0,0 +1,762 @@
+//! A mocked [`External`] keeping the state in a merkleized trie.
+//!
+//! [`MockedExternal`] keeps the state in a hash map and touches no trie
+//! nodes, so storage operations cost nothing beyond their per-byte fees.
+//! [`MockedTrieExternal`] keeps it in a trie of the same shape as the one of
+//! the runtime, with nibble-keyed branch, extension and leaf nodes, and
+//! counts the nodes storage operations touch the way the runtime does:
+//!
+//! * reading a key through the trie touches every node on its path and the
+//!   value, a node touched before in the same chunk being a memory read
+//!   instead of a database read, see [`MockedTrieExternal::start_chunk`];
+//! * reading a key through flat storage touches no node;
+//! * writes and removals are kept aside until [`MockedTrieExternal::commit`]
+//!   merges them into the trie, and keys changed since touch no node.
+//!
+//! The gas charged for the touched nodes then matches the gas the runtime
+//! charges for a state of the same keys.  The hashes of the nodes are not
+//! those of the runtime, but the trie is merkleized all the same:
+//! [`MockedTrieExternal::prove`] returns proofs of the values of keys which
+//! [`StorageProof::verify`] checks against [`MockedTrieExternal::root`].
+
+use super::mock_external::{MockedExternal, MockedValuePtr};
+use crate::logic::dependencies::Result;
+use crate::logic::types::ReceiptIndex;
+use crate::logic::{External, StorageGetMode, TrieNodesCount, VMLogicError, ValuePtr};
+use borsh::BorshSerialize;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+use unc_primitives_core::hash::CryptoHash;
+use unc_primitives_core::types::{AccountId, Balance, Gas, GasWeight, Nonce, Power};
+
+/// A node of the trie as it appears in a [`StorageProof`], with the hashes of
+/// its children and value.
+///
+/// Keys are sequences of nibbles, the hash of a node is the hash of its borsh
+/// encoding.
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize)]
+pub enum ProofNode {
+    Leaf { key: Vec<u8>, value: CryptoHash },
+    Extension { key: Vec<u8>, child: CryptoHash },
+    Branch { children: Box<[Option<CryptoHash>; 16]>, value: Option<CryptoHash> },
+}
+
+impl ProofNode {
+    pub fn hash(&self) -> CryptoHash {
+        CryptoHash::hash_borsh(self)
+    }
+}
+
+/// The nodes on the path of a key, from the root, and its value.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct StorageProof {
+    pub nodes: Vec<ProofNode>,
+    pub value: Option<Vec<u8>>,
+}
+
+impl StorageProof {
+    /// Whether this proves that `key` has [`Self::value`] in the trie of
+    /// `root`.
+    pub fn verify(&self, root: &CryptoHash, key: &[u8]) -> bool {
+        let value_is = |hash: Option<&CryptoHash>| match (hash, &self.value) {
+            (Some(hash), Some(value)) => CryptoHash::hash_bytes(value) == *hash,
+            (None, None) => true,
+            _ => false,
+        };
+        if *root == CryptoHash::default() {
+            return self.nodes.is_empty() && self.value.is_none();
+        }
+        let key = nibbles(key);
+        let mut rest = &key[..];
+        let mut expected = *root;
+        for (i, node) in self.nodes.iter().enumerate() {
+            let last = i + 1 == self.nodes.len();
+            if node.hash() != expected {
+                return false;
+            }
+            match node {
+                ProofNode::Leaf { key, value } => {
+                    return last && value_is((rest == &key[..]).then_some(value));
+                }
+                ProofNode::Extension { key, child } => match rest.strip_prefix(&key[..]) {
+                    Some(tail) => {
+                        rest = tail;
+                        expected = *child;
+                    }
+                    None => return last && value_is(None),
+                },
+                ProofNode::Branch { children, value } => match rest.split_first() {
+                    None => return last && value_is(value.as_ref()),
+                    Some((nibble, tail)) => match children[*nibble as usize] {
+                        Some(child) => {
+                            rest = tail;
+                            expected = child;
+                        }
+                        None => return last && value_is(None),
+                    },
+                },
+            }
+        }
+        false
+    }
+}
+
+fn nibbles(key: &[u8]) -> Vec<u8> {
+    key.iter().flat_map(|byte| [byte >> 4, byte & 0xf]).collect()
+}
+
+struct TrieNode {
+    hash: CryptoHash,
+    kind: NodeKind,
+}
+
+enum NodeKind {
+    Leaf { key: Vec<u8>, value: Vec<u8> },
+    Extension { key: Vec<u8>, child: Box<TrieNode> },
+    Branch { children: [Option<Box<TrieNode>>; 16], value: Option<Vec<u8>> },
+}
+
+impl TrieNode {
+    fn new(kind: NodeKind) -> Box<Self> {
+        let mut node = TrieNode { hash: CryptoHash::default(), kind };
+        node.hash = node.proof_node().hash();
+        Box::new(node)
+    }
+
+    /// Builds the trie of `entries`, sorted by key, whose keys share their
+    /// first `depth` nibbles.
+    fn build(entries: &[(Vec<u8>, &[u8])], depth: usize) -> Box<Self> {
+        let (first_key, first_value) = &entries[0];
+        if entries.len() == 1 {
+            return Self::new(NodeKind::Leaf {
+                key: first_key[depth..].to_vec(),
+                value: first_value.to_vec(),
+            });
+        }
+        // The keys are sorted, so the prefix of the first and last ones is
+        // shared by all of them.
+        let last_key = &entries[entries.len() - 1].0;
+        let common = first_key[depth..]
+            .iter()
+            .zip(&last_key[depth..])
+            .take_while(|(first, last)| first == last)
+            .count();
+        if common > 0 {
+            let key = first_key[depth..depth + common].to_vec();
+            let child = Self::build(entries, depth + common);
+            return Self::new(NodeKind::Extension { key, child });
+        }
+        let (value, mut rest) = if first_key.len() == depth {
+            (Some(first_value.to_vec()), &entries[1..])
+        } else {
+            (None, entries)
+        };
+        let mut children: [Option<Box<TrieNode>>; 16] = Default::default();
+        while let Some((key, _)) = rest.first() {
+            let nibble = key[depth];
+            let len = rest.iter().take_while(|(key, _)| key[depth] == nibble).count();
+            children[nibble as usize] = Some(Self::build(&rest[..len], depth + 1));
+            rest = &rest[len..];
+        }
+        Self::new(NodeKind::Branch { children, value })
+    }
+
+    fn proof_node(&self) -> ProofNode {
+        let value_hash = |value: &[u8]| CryptoHash::hash_bytes(value);
+        match &self.kind {
+            NodeKind::Leaf { key, value } => {
+                ProofNode::Leaf { key: key.clone(), value: value_hash(value) }
+            }
+            NodeKind::Extension { key, child } => {
+                ProofNode::Extension { key: key.clone(), child: child.hash }
+            }
+            NodeKind::Branch { children, value } => ProofNode::Branch {
+                children: Box::new(std::array::from_fn(|i| {
+                    children[i].as_ref().map(|child| child.hash)
+                })),
+                value: value.as_deref().map(value_hash),
+            },
+        }
+    }
+
+    /// The nodes on the path of `key` and its value, if any.
+    fn path(&self, key: &[u8]) -> (Vec<&TrieNode>, Option<&[u8]>) {
+        let mut path = vec![self];
+        let mut rest = key;
+        let mut node = self;
+        loop {
+            let next = match &node.kind {
+                NodeKind::Leaf { key, value } => {
+                    return (path, (rest == &key[..]).then_some(&value[..]));
+                }
+                NodeKind::Extension { key, child } => match rest.strip_prefix(&key[..]) {
+                    Some(tail) => {
+                        rest = tail;
+                        child
+                    }
+                    None => return (path, None),
+                },
+                NodeKind::Branch { children, value } => match rest.split_first() {
+                    None => return (path, value.as_deref()),
+                    Some((nibble, tail)) => match &children[*nibble as usize] {
+                        Some(child) => {
+                            rest = tail;
+                            child
+                        }
+                        None => return (path, None),
+                    },
+                },
+            };
+            path.push(next);
+            node = next;
+        }
+    }
+}
+
+/// [`External`] keeping the state in a merkleized trie, see the module docs.
+///
+/// Everything but the state is handled by [`Self::ext`].
+#[derive(Default)]
+pub struct MockedTrieExternal {
+    /// Receipts, validators and scratch area.  Its own state is unused.
+    pub ext: MockedExternal,
+    /// The state merged into the trie.
+    state: BTreeMap<Vec<u8>, Vec<u8>>,
+    root: Option<Box<TrieNode>>,
+    /// Changes not merged into the trie yet, `None` for removed keys.
+    changes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Hashes of the nodes and values touched in the current chunk.
+    touched: RefCell<HashSet<CryptoHash>>,
+    db_reads: Cell<u64>,
+    mem_reads: Cell<u64>,
+    db_read_latency: Option<Duration>,
+}
+
+impl MockedTrieExternal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds `entries` to the state, merged into the trie.
+    pub fn with_state(mut self, entries: impl IntoIterator<Item = (Vec<u8>, Vec<u8>)>) -> Self {
+        self.changes.extend(entries.into_iter().map(|(key, value)| (key, Some(value))));
+        self.commit();
+        self
+    }
+
+    /// Makes each database read of a node sleep for `latency`, as a node
+    /// would wait for its disk.
+    pub fn with_db_read_latency(mut self, latency: Duration) -> Self {
+        self.db_read_latency = Some(latency);
+        self
+    }
+
+    /// Value of `key`, including the changes not merged yet.
+    pub fn get(&self, key: &[u8]) -> Option<&[u8]> {
+        match self.changes.get(key) {
+            Some(change) => change.as_deref(),
+            None => self.state.get(key).map(Vec::as_slice),
+        }
+    }
+
+    /// Merges the changes into the trie, as the runtime does once a chunk
+    /// is applied.
+    pub fn commit(&mut self) {
+        for (key, change) in std::mem::take(&mut self.changes) {
+            match change {
+                Some(value) => self.state.insert(key, value),
+                None => self.state.remove(&key),
+            };
+        }
+        let entries: Vec<_> =
+            self.state.iter().map(|(key, value)| (nibbles(key), value.as_slice())).collect();
+        self.root = (!entries.is_empty()).then(|| TrieNode::build(&entries, 0));
+    }
+
+    /// Hash of the root of the trie, the default hash for an empty trie.
+    ///
+    /// The changes not merged yet are not part of the trie.
+    pub fn root(&self) -> CryptoHash {
+        self.root.as_ref().map_or_else(CryptoHash::default, |root| root.hash)
+    }
+
+    /// Proof of the value of `key` in the trie of [`Self::root`].
+    pub fn prove(&self, key: &[u8]) -> StorageProof {
+        let Some(root) = &self.root else {
+            return StorageProof { nodes: Vec::new(), value: None };
+        };
+        let (path, value) = root.path(&nibbles(key));
+        StorageProof {
+            nodes: path.iter().map(|node| node.proof_node()).collect(),
+            value: value.map(<[u8]>::to_vec),
+        }
+    }
+
+    /// Starts a new chunk: every node touched from now on is a database read
+    /// the first time again.
+    pub fn start_chunk(&mut self) {
+        self.touched.get_mut().clear();
+    }
+
+    fn touch(&self, hash: CryptoHash) {
+        if self.touched.borrow_mut().insert(hash) {
+            self.db_reads.set(self.db_reads.get() + 1);
+            if let Some(latency) = self.db_read_latency {
+                std::thread::sleep(latency);
+            }
+        } else {
+            self.mem_reads.set(self.mem_reads.get() + 1);
+        }
+    }
+
+    /// Value of `key`, touching the nodes like the runtime does.
+    fn read(&self, key: &[u8], mode: StorageGetMode, with_value: bool) -> Option<&[u8]> {
+        if let Some(change) = self.changes.get(key) {
+            return change.as_deref();
+        }
+        if mode == StorageGetMode::FlatStorage {
+            return self.state.get(key).map(Vec::as_slice);
+        }
+        let (path, value) = self.root.as_ref()?.path(&nibbles(key));
+        for node in path {
+            self.touch(node.hash);
+        }
+        if let (Some(value), true) = (value, with_value) {
+            self.touch(CryptoHash::hash_bytes(value));
+        }
+        value
+    }
+}
+
+impl External for MockedTrieExternal {
+    fn storage_set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.changes.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn storage_get(&self, key: &[u8], mode: StorageGetMode) -> Result<Option<Box<dyn ValuePtr>>> {
+        Ok(self.read(key, mode, true).map(|value| Box::new(MockedValuePtr::new(value)) as Box<_>))
+    }
+
+    fn storage_remove(&mut self, key: &[u8]) -> Result<()> {
+        self.changes.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn storage_remove_subtree(&mut self, prefix: &[u8]) -> Result<()> {
+        let keys: Vec<_> = self
+            .state
+            .keys()
+            .chain(self.changes.keys())
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        for key in keys {
+            self.changes.insert(key, None);
+        }
+        Ok(())
+    }
+
+    fn storage_has_key(&mut self, key: &[u8], mode: StorageGetMode) -> Result<bool> {
+        Ok(self.read(key, mode, false).is_some())
+    }
+
+    /// The borsh encoding of the nodes of [`Self::prove`], then the value.
+    fn storage_proof(&self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        let proof = self.prove(key);
+        let mut nodes: Vec<_> =
+            proof.nodes.iter().map(|node| borsh::to_vec(node).expect("nodes serialize")).collect();
+        nodes.extend(proof.value);
+        Ok(nodes)
+    }
+
+    /// Whether there are changes not merged into the trie yet.
+    fn state_changed(&self) -> bool {
+        !self.changes.is_empty()
+    }
+
+    fn generate_data_id(&mut self) -> CryptoHash {
+        self.ext.generate_data_id()
+    }
+
+    fn get_trie_nodes_count(&self) -> TrieNodesCount {
+        TrieNodesCount { db_reads: self.db_reads.get(), mem_reads: self.mem_reads.get() }
+    }
+
+    fn validator_frozen(&self, account_id: &AccountId) -> Result<Option<Balance>> {
+        self.ext.validator_frozen(account_id)
+    }
+
+    fn validator_power(&self, account_id: &AccountId) -> Result<Option<Power>> {
+        self.ext.validator_power(account_id)
+    }
+
+    fn validator_total_frozen(&self) -> Result<Balance> {
+        self.ext.validator_total_frozen()
+    }
+
+    fn validator_total_power(&self) -> Result<Power> {
+        self.ext.validator_total_power()
+    }
+
+    fn account_code_hash(&self, account_id: &AccountId) -> Result<Option<CryptoHash>> {
+        self.ext.account_code_hash(account_id)
+    }
+
+    fn create_receipt(
+        &mut self,
+        receipt_indices: Vec<ReceiptIndex>,
+        receiver_id: AccountId,
+    ) -> Result<ReceiptIndex, VMLogicError> {
+        self.ext.create_receipt(receipt_indices, receiver_id)
+    }
+
+    fn append_action_create_account(
+        &mut self,
+        receipt_index: ReceiptIndex,
+    ) -> Result<(), VMLogicError> {
+        self.ext.append_action_create_account(receipt_index)
+    }
+
+    fn append_action_deploy_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code: Vec<u8>,
+    ) -> Result<(), VMLogicError> {
+        self.ext.append_action_deploy_contract(receipt_index, code)
+    }
+
+    fn append_action_deploy_global_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code_hash: CryptoHash,
+    ) -> Result<(), VMLogicError> {
+        self.ext.append_action_deploy_global_contract(receipt_index, code_hash)
+    }
+
+    fn append_action_function_call_weight(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        method_name: Vec<u8>,
+        args: Vec<u8>,
+        attached_deposit: Balance,
+        prepaid_gas: Gas,
+        gas_weight: GasWeight,
+    ) -> Result<(), VMLogicError> {
+        self.ext.append_action_function_call_weight(
+            receipt_index,
+            method_name,
+            args,
+            attached_deposit,
+            prepaid_gas,
+            gas_weight,
+        )
+    }
+
+    fn append_action_transfer(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        deposit: Balance,
+    ) -> Result<(), VMLogicError> {
+        self.ext.append_action_transfer(receipt_index, deposit)
+    }
+
+    fn append_action_stake(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        stake: Balance,
+        public_key: unc_crypto::PublicKey,
+    ) {
+        self.ext.append_action_stake(receipt_index, stake, public_key)
+    }
+
+    fn append_action_add_key_with_full_access(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: unc_crypto::PublicKey,
+        nonce: Nonce,
+    ) {
+        self.ext.append_action_add_key_with_full_access(receipt_index, public_key, nonce)
+    }
+
+    fn append_action_add_key_with_function_call(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: unc_crypto::PublicKey,
+        nonce: Nonce,
+        allowance: Option<Balance>,
+        receiver_id: AccountId,
+        method_names: Vec<Vec<u8>>,
+    ) -> Result<(), VMLogicError> {
+        self.ext.append_action_add_key_with_function_call(
+            receipt_index,
+            public_key,
+            nonce,
+            allowance,
+            receiver_id,
+            method_names,
+        )
+    }
+
+    fn append_action_delete_key(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: unc_crypto::PublicKey,
+    ) {
+        self.ext.append_action_delete_key(receipt_index, public_key)
+    }
+
+    fn append_action_delete_account(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        beneficiary_id: AccountId,
+    ) -> Result<(), VMLogicError> {
+        self.ext.append_action_delete_account(receipt_index, beneficiary_id)
+    }
+
+    fn scratch_get(&self) -> Result<Option<Vec<u8>>> {
+        self.ext.scratch_get()
+    }
+
+    fn append_scratch(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        data: Vec<u8>,
+    ) -> Result<(), VMLogicError> {
+        self.ext.append_scratch(receipt_index, data)
+    }
+
+    fn get_receipt_receiver(&self, receipt_index: ReceiptIndex) -> &AccountId {
+        self.ext.get_receipt_receiver(receipt_index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+    use crate::logic::TouchedKey;
+    use crate::runner::RunOptions;
+    use crate::ContractCode;
+    use crate::logic::Config;
+    use unc_parameters::vm::VMKind;
+    use unc_parameters::{ExtCosts, RuntimeFeesConfig};
+
+    fn state() -> Vec<(Vec<u8>, Vec<u8>)> {
+        ["a", "ab", "abc", "b", "ba", "xyz"]
+            .into_iter()
+            .map(|key| (key.as_bytes().to_vec(), key.repeat(2).into_bytes()))
+            .collect()
+    }
+
+    fn trie_read(ext: &MockedTrieExternal, key: &[u8]) -> (Option<Vec<u8>>, TrieNodesCount) {
+        let before = ext.get_trie_nodes_count();
+        let value = ext.storage_get(key, StorageGetMode::Trie).unwrap();
+        let value = value.map(|value| value.deref().unwrap());
+        (value, ext.get_trie_nodes_count().checked_sub(&before).unwrap())
+    }
+
+    #[test]
+    fn test_external_storage() {
+        crate::logic::test_utils::test_external_storage(&mut MockedTrieExternal::new());
+    }
+
+    #[test]
+    fn test_touched_nodes() {
+        let mut ext = MockedTrieExternal::new().with_state(state());
+        // The root branch on the first nibble of `a` and `b` or `x`, the branch
+        // on the second nibble of `a` and `b`, then the branch with the value
+        // of `a` and the value.
+        let (value, count) = trie_read(&ext, b"a");
+        assert_eq!(value.as_deref(), Some(&b"aa"[..]));
+        assert_eq!(count, TrieNodesCount { db_reads: 4, mem_reads: 0 });
+        // The same nodes again, now cached.
+        assert_eq!(trie_read(&ext, b"a").1, TrieNodesCount { db_reads: 0, mem_reads: 4 });
+        // A missing key touches the nodes up to where its path ends.
+        let (value, count) = trie_read(&ext, b"ac");
+        assert_eq!(value, None);
+        assert_eq!(count.db_reads + count.mem_reads, 4);
+
+        ext.start_chunk();
+        assert_eq!(trie_read(&ext, b"a").1, TrieNodesCount { db_reads: 4, mem_reads: 0 });
+        let before = ext.get_trie_nodes_count();
+        assert!(ext.storage_has_key(b"xyz", StorageGetMode::Trie).unwrap());
+        assert!(ext.storage_has_key(b"xyz", StorageGetMode::FlatStorage).unwrap());
+        // The root, then the leaf of `xyz`, without its value.
+        let count = ext.get_trie_nodes_count().checked_sub(&before).unwrap();
+        assert_eq!(count, TrieNodesCount { db_reads: 1, mem_reads: 1 });
+
+        // Changes touch no nodes until they are committed.
+        ext.storage_set(b"xyz", b"new").unwrap();
+        let (value, count) = trie_read(&ext, b"xyz");
+        assert_eq!(value.as_deref(), Some(&b"new"[..]));
+        assert_eq!(count, TrieNodesCount { db_reads: 0, mem_reads: 0 });
+    }
+
+    #[test]
+    fn test_root_and_proofs() {
+        let mut ext = MockedTrieExternal::new();
+        assert_eq!(ext.root(), CryptoHash::default());
+        assert!(ext.prove(b"a").verify(&ext.root(), b"a"));
+
+        ext.storage_set(b"a", b"aa").unwrap();
+        assert_eq!(ext.root(), CryptoHash::default());
+        ext.commit();
+        let single = ext.root();
+        assert_ne!(single, CryptoHash::default());
+
+        let mut ext = MockedTrieExternal::new().with_state(state());
+        let root = ext.root();
+        // The root only depends on the state.
+        let mut shuffled = state();
+        shuffled.reverse();
+        assert_eq!(MockedTrieExternal::new().with_state(shuffled).root(), root);
+
+        for key in ["a", "ab", "abc", "b", "ba", "xyz", "", "ac", "abcd", "c", "xy", "xyzz"] {
+            let key = key.as_bytes();
+            let proof = ext.prove(key);
+            assert_eq!(proof.value.as_deref(), ext.get(key), "{key:?}");
+            assert!(proof.verify(&root, key), "{key:?}");
+            let mut forged = proof.clone();
+            forged.value = match forged.value {
+                Some(_) => None,
+                None => Some(b"forged".to_vec()),
+            };
+            assert!(!forged.verify(&root, key), "{key:?}");
+        }
+        assert!(!ext.prove(b"a").verify(&root, b"b"));
+
+        ext.storage_remove_subtree(b"a").unwrap();
+        ext.storage_remove(b"ba").unwrap();
+        ext.storage_remove(b"xyz").unwrap();
+        ext.commit();
+        assert_eq!(ext.get(b"abc"), None);
+        assert_eq!(ext.root(), MockedTrieExternal::new().with_state([lookup(b"b")]).root());
+        assert_ne!(ext.root(), single);
+    }
+
+    fn lookup(key: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        state().into_iter().find(|(k, _)| k == key).unwrap()
+    }
+
+    #[test]
+    fn test_trie_fees_charged() {
+        // Overwrites the key `k`.
+        let code = wat::parse_str(
+            r#"
+(module
+  (import "env" "storage_write"
+    (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (memory 1)
+  (data (i32.const 0) "k")
+  (func (export "main")
+    (drop (call $storage_write (i64.const 1) (i64.const 0) (i64.const 1) (i64.const 0)
+      (i64.const 0))))
+)"#,
+        )
+        .unwrap();
+        let code = ContractCode::new(code, None);
+        let fees = RuntimeFeesConfig::test();
+        with_vm_variants(&test_vm_config(), |vm_kind: VMKind| {
+            let mut config = test_vm_config();
+            config.vm_kind = vm_kind;
+            let run = |ext: &mut MockedTrieExternal| {
+                crate::run(&code, "main", ext, create_context(vec![]), &config, &fees, &[], None)
+                    .unwrap()
+            };
+            let state = (0..100u8).map(|i| (vec![b'k', i], vec![i]));
+            let mut ext = MockedTrieExternal::new().with_state(state).with_state([lookup(b"a")]);
+            ext.storage_set(b"k", b"v").unwrap();
+            ext.commit();
+
+            let costs = |ext: &mut MockedTrieExternal| {
+                let profile = run(ext).profile;
+                (
+                    profile.get_ext_cost(ExtCosts::touching_trie_node),
+                    profile.get_ext_cost(ExtCosts::read_cached_trie_node),
+                )
+            };
+            let (touching, cached) = costs(&mut ext);
+            assert!(touching > 0, "{vm_kind:?}");
+            assert_eq!(cached, 0);
+            // The key changed during the chunk touches no nodes.
+            assert_eq!(costs(&mut ext), (0, 0));
+            ext.commit();
+            ext.start_chunk();
+            assert_eq!(costs(&mut ext), (touching, 0));
+            // The value is the same, so are the nodes, already touched.
+            ext.commit();
+            let (touching, cached) = costs(&mut ext);
+            assert_eq!(touching, 0);
+            assert!(cached > 0);
+        });
+    }
+
+    #[test]
+    fn test_state_witness() {
+        // Reads `k`, checks for `x` and overwrites `k`.
+        let code = wat::parse_str(
+            r#"
+(module
+  (import "env" "storage_read" (func $storage_read (param i64 i64 i64) (result i64)))
+  (import "env" "storage_has_key" (func $storage_has_key (param i64 i64) (result i64)))
+  (import "env" "storage_write"
+    (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (memory 1)
+  (data (i32.const 0) "kx")
+  (func (export "main")
+    (drop (call $storage_read (i64.const 1) (i64.const 0) (i64.const 0)))
+    (drop (call $storage_has_key (i64.const 1) (i64.const 1)))
+    (drop (call $storage_write (i64.const 1) (i64.const 0) (i64.const 1) (i64.const 1)
+      (i64.const 0))))
+)"#,
+        )
+        .unwrap();
+        let code = ContractCode::new(code, None);
+        let fees = RuntimeFeesConfig::test();
+        with_vm_variants(&test_vm_config(), |vm_kind: VMKind| {
+            let mut config = test_vm_config();
+            config.vm_kind = vm_kind;
+            let new_ext = || {
+                let state = (0..20u8).map(|i| (vec![b'k', i], vec![i]));
+                MockedTrieExternal::new()
+                    .with_state(state)
+                    .with_state([(b"k".to_vec(), b"old".to_vec())])
+            };
+            let run = |record_state_witness| {
+                let mut ext = new_ext();
+                let options = RunOptions { record_state_witness, ..RunOptions::default() };
+                crate::run_with_options(
+                    &code,
+                    "main",
+                    &mut ext,
+                    create_context(vec![]),
+                    &config,
+                    &fees,
+                    &[],
+                    None,
+                    &options,
+                )
+                .unwrap()
+            };
+            let outcome = run(true);
+            assert_eq!(outcome.aborted, None, "{vm_kind:?}");
+            let witness = outcome.state_witness.unwrap();
+            let touched =
+                |key: &[u8], read, written| TouchedKey { key: key.to_vec(), read, written };
+            assert_eq!(witness.keys, [touched(b"k", true, true), touched(b"x", true, false)]);
+            let ext = new_ext();
+            let mut nodes = ext.storage_proof(b"k").unwrap();
+            nodes.extend(ext.storage_proof(b"x").unwrap());
+            nodes.sort();
+            nodes.dedup();
+            assert_eq!(witness.nodes, nodes);
+            assert!(witness.nodes.contains(&b"old".to_vec()));
+            // Proving touches no nodes, so costs nothing.
+            let without = run(false);
+            assert_eq!(without.state_witness, None);
+            assert_eq!(without.burnt_gas, outcome.burnt_gas);
+        });
+    }
+}
@@ -1,2 +1,10 @@
+//! Mocked implementations of the logic dependencies.
+//!
+//! Embedders and SDKs can enable the `test-support` feature to additionally get
+//! the context fixtures from [`mock_context`].
+
+#[cfg(any(test, feature = "test-support"))]
+pub mod mock_context;
 pub mod mock_external;
 pub mod mock_memory;
+pub mod mock_trie;
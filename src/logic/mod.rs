@@ -3,28 +3,53 @@ use unc_primitives_core::hash::CryptoHash;
 use std::fmt;
 use types::AccountId;
 
-mod alt_bn128;
+#[cfg(feature = "bn128")]
+pub(crate) mod alt_bn128;
+pub mod audit;
+#[cfg(feature = "protocol_feature_bls12381")]
+mod bls12381;
+mod config;
 mod context;
+pub mod custom_host_functions;
 mod dependencies;
+pub mod error_codes;
 pub mod errors;
 pub mod gas_counter;
+pub mod gas_distribution;
+pub mod gas_planning;
+pub mod gas_price;
+pub mod host_functions;
 mod logic;
 pub mod mocks;
+#[cfg(not(all(feature = "bn128", feature = "ed25519", feature = "secp256k1")))]
+mod not_compiled;
+pub mod shuffle;
+mod state_witness;
+mod storage_buffer;
+pub mod strict;
 pub mod test_utils;
 #[cfg(test)]
 mod tests;
 pub mod types;
 mod utils;
 mod vmstate;
+mod watchdog;
+mod wide_math;
 
+pub use config::{Config, ExtraExtCostsConfig, ExtraLimitConfig};
 pub use context::VMContext;
 pub use dependencies::{External, MemSlice, MemoryLike, ValuePtr};
 pub use errors::{HostError, VMLogicError};
-pub use gas_counter::with_ext_cost_counter;
-pub use logic::{VMLogic, VMOutcome};
-pub use unc_parameters::vm::{Config, ContractPrepareVersion, LimitConfig, StorageGetMode};
+pub use gas_counter::{with_ext_cost_counter, GasCharges, GasProfile, HostFunctionGas, LocalGasCounter};
+pub use logic::{BacktraceFrame, ContractBacktrace, VMLogic, VMOutcome, WasmFrame};
+pub use state_witness::{StateWitness, TouchedKey};
+pub(crate) use watchdog::Interrupt;
+pub use unc_parameters::vm::{ContractPrepareVersion, LimitConfig, StorageGetMode};
 pub use unc_primitives_core::types::ProtocolVersion;
-pub use types::ReturnData;
+pub use types::{
+    ActionReceipt, HostCallCheckpoint, HostCallRecord, ReceiptAction, ReceiptCost, ReturnData,
+    StorageBytes, StorageUsageDelta, UsedMemory,
+};
 
 #[derive(Debug, Clone, PartialEq, BorshDeserialize, BorshSerialize)]
 pub enum CompiledContract {
@@ -39,6 +64,25 @@ pub trait CompiledContractCache: Send + Sync {
     fn has(&self, key: &CryptoHash) -> std::io::Result<bool> {
         self.get(key).map(|entry| entry.is_some())
     }
+
+    /// Records how the artifact stored under `key` has been compiled.
+    ///
+    /// Caches not keeping this metadata ignore it.
+    fn put_compilation_info(
+        &self,
+        _key: &CryptoHash,
+        _info: &crate::CompilationInfo,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// How the artifact stored under `key` has been compiled, if known.
+    fn get_compilation_info(
+        &self,
+        _key: &CryptoHash,
+    ) -> std::io::Result<Option<crate::CompilationInfo>> {
+        Ok(None)
+    }
 }
 
 impl fmt::Debug for dyn CompiledContractCache {
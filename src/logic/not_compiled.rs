@@ -0,0 +1,147 @@
+//! Host functions of the crypto primitives left out of the build.
+//!
+//! The config decides which host functions are linked, the build which ones
+//! are implemented.  A function the config links but the build leaves out
+//! fails the call with [`InconsistentStateError::HostFunctionNotCompiled`],
+//! which the runner reports as a [`VMRunnerError`] instead of an outcome:
+//! builds with and without the function never disagree on the outcome of a
+//! call, the minimal ones just cannot run it.
+//!
+//! [`VMRunnerError`]: super::errors::VMRunnerError
+
+#![allow(unused_variables)]
+
+use super::errors::InconsistentStateError;
+use super::logic::{Result, VMLogic};
+
+fn not_compiled<T>(host_function: &str, feature: &str) -> Result<T> {
+    Err(InconsistentStateError::HostFunctionNotCompiled {
+        host_function: host_function.to_string(),
+        feature: feature.to_string(),
+    }
+    .into())
+}
+
+#[cfg(not(feature = "bn128"))]
+impl VMLogic<'_> {
+    pub fn alt_bn128_g1_multiexp(
+        &mut self,
+        value_len: u64,
+        value_ptr: u64,
+        register_id: u64,
+    ) -> Result<()> {
+        not_compiled("alt_bn128_g1_multiexp", "bn128")
+    }
+
+    pub fn alt_bn128_g1_sum(
+        &mut self,
+        value_len: u64,
+        value_ptr: u64,
+        register_id: u64,
+    ) -> Result<()> {
+        not_compiled("alt_bn128_g1_sum", "bn128")
+    }
+
+    pub fn alt_bn128_pairing_check(&mut self, value_len: u64, value_ptr: u64) -> Result<u64> {
+        not_compiled("alt_bn128_pairing_check", "bn128")
+    }
+
+    pub fn alt_bn128_g2_multiexp(
+        &mut self,
+        value_len: u64,
+        value_ptr: u64,
+        register_id: u64,
+    ) -> Result<()> {
+        not_compiled("alt_bn128_g2_multiexp", "bn128")
+    }
+
+    pub fn alt_bn128_pairing_check_batch(&mut self, value_len: u64, value_ptr: u64) -> Result<u64> {
+        not_compiled("alt_bn128_pairing_check_batch", "bn128")
+    }
+}
+
+#[cfg(not(feature = "secp256k1"))]
+impl VMLogic<'_> {
+    pub fn ecrecover(
+        &mut self,
+        hash_len: u64,
+        hash_ptr: u64,
+        sig_len: u64,
+        sig_ptr: u64,
+        v: u64,
+        malleability_flag: u64,
+        register_id: u64,
+    ) -> Result<u64> {
+        not_compiled("ecrecover", "secp256k1")
+    }
+
+    pub fn ecrecover_compressed(
+        &mut self,
+        hash_len: u64,
+        hash_ptr: u64,
+        sig_len: u64,
+        sig_ptr: u64,
+        v: u64,
+        malleability_flag: u64,
+        register_id: u64,
+    ) -> Result<u64> {
+        not_compiled("ecrecover_compressed", "secp256k1")
+    }
+
+    pub fn ecrecover_batch(
+        &mut self,
+        batch_len: u64,
+        batch_ptr: u64,
+        compressed: u64,
+        register_id: u64,
+    ) -> Result<u64> {
+        not_compiled("ecrecover_batch", "secp256k1")
+    }
+}
+
+#[cfg(not(feature = "ed25519"))]
+impl VMLogic<'_> {
+    pub fn ed25519_verify(
+        &mut self,
+        signature_len: u64,
+        signature_ptr: u64,
+        message_len: u64,
+        message_ptr: u64,
+        public_key_len: u64,
+        public_key_ptr: u64,
+    ) -> Result<u64> {
+        not_compiled("ed25519_verify", "ed25519")
+    }
+
+    pub fn ed25519_verify_batch(&mut self, batch_len: u64, batch_ptr: u64) -> Result<u64> {
+        not_compiled("ed25519_verify_batch", "ed25519")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logic::errors::{InconsistentStateError, VMLogicError};
+    use crate::logic::tests::vm_logic_builder::VMLogicBuilder;
+
+    #[test]
+    fn test_not_compiled() {
+        let mut logic_builder = VMLogicBuilder::default();
+        let mut logic = logic_builder.build();
+        let results = [
+            ("alt_bn128_g1_sum", "bn128", logic.alt_bn128_g1_sum(0, 0, 0).map(|()| 0)),
+            ("ecrecover", "secp256k1", logic.ecrecover(0, 0, 0, 0, 0, 0, 0)),
+            ("ed25519_verify", "ed25519", logic.ed25519_verify(0, 0, 0, 0, 0, 0)),
+        ];
+        let compiled =
+            [cfg!(feature = "bn128"), cfg!(feature = "secp256k1"), cfg!(feature = "ed25519")];
+        for ((host_function, feature, result), compiled) in results.into_iter().zip(compiled) {
+            let not_compiled = VMLogicError::InconsistentStateError(
+                InconsistentStateError::HostFunctionNotCompiled {
+                    host_function: host_function.to_string(),
+                    feature: feature.to_string(),
+                },
+            );
+            assert_eq!(result.err() == Some(not_compiled), !compiled, "{host_function}");
+        }
+    }
+}
@@ -0,0 +1,133 @@
+//! Deterministic pseudo-random ordering.
+//!
+//! Anything that needs a reproducible pseudo-random order (simulated receipt
+//! processing, validator ordering in tests, etc.) should go through this
+//! module rather than pull in an ad-hoc generator, so that every user agrees
+//! on the exact same sequence for the same seed.
+//!
+//! The generator is not meant to be cryptographically strong against an
+//! adversary who controls the seed.  Its only guarantee is stability: the
+//! output depends on nothing but the seed and is fixed across platforms and
+//! releases.
+//!
+//! The stream is produced by hashing the SHA-256 of the seed together with a
+//! little-endian block counter; each 32-byte block yields four little-endian
+//! `u64` words.  Bounded values are drawn with rejection sampling so they are
+//! free of modulo bias, and [`shuffle`] is the Fisher–Yates shuffle walking
+//! from the last element down.
+
+use unc_primitives_core::hash::{hash, CryptoHash};
+
+/// Seeded deterministic generator of `u64` words.
+pub struct DeterministicRng {
+    seed: CryptoHash,
+    counter: u64,
+    block: [u64; 4],
+    pos: usize,
+}
+
+impl DeterministicRng {
+    pub fn new(seed: &[u8]) -> Self {
+        Self { seed: hash(seed), counter: 0, block: [0; 4], pos: 4 }
+    }
+
+    /// Returns the next word of the stream.
+    pub fn next_u64(&mut self) -> u64 {
+        if self.pos == self.block.len() {
+            let mut input = [0u8; 40];
+            input[..32].copy_from_slice(&self.seed.0);
+            input[32..].copy_from_slice(&self.counter.to_le_bytes());
+            let block = hash(&input);
+            for (word, chunk) in self.block.iter_mut().zip(block.0.chunks_exact(8)) {
+                *word = u64::from_le_bytes(chunk.try_into().unwrap());
+            }
+            self.counter += 1;
+            self.pos = 0;
+        }
+        let word = self.block[self.pos];
+        self.pos += 1;
+        word
+    }
+
+    /// Returns a value uniformly distributed in `0..bound`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `bound` is zero.
+    pub fn next_below(&mut self, bound: u64) -> u64 {
+        assert!(bound > 0, "bound must be positive");
+        let limit = u64::MAX - u64::MAX % bound;
+        loop {
+            let value = self.next_u64();
+            if value < limit {
+                return value % bound;
+            }
+        }
+    }
+}
+
+/// Derives an independent seed for the given `domain` from the `seed`, so
+/// unrelated users of the same seed do not observe correlated sequences.
+pub fn derive_seed(seed: &[u8], domain: &[u8]) -> CryptoHash {
+    let mut input = Vec::with_capacity(8 + domain.len() + seed.len());
+    input.extend_from_slice(&(domain.len() as u64).to_le_bytes());
+    input.extend_from_slice(domain);
+    input.extend_from_slice(seed);
+    hash(&input)
+}
+
+/// Shuffles `items` in place in an order determined by `seed` only.
+pub fn shuffle<T>(seed: &[u8], items: &mut [T]) {
+    let mut rng = DeterministicRng::new(seed);
+    for i in (1..items.len()).rev() {
+        let j = rng.next_below(i as u64 + 1) as usize;
+        items.swap(i, j);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stream_is_stable() {
+        let mut rng = DeterministicRng::new(b"seed");
+        let words: Vec<u64> = (0..5).map(|_| rng.next_u64()).collect();
+        assert_eq!(
+            words,
+            [
+                9885667256050267852,
+                12227912125408643751,
+                1944513753714367730,
+                1070502864246908624,
+                9658116283504686303
+            ]
+        );
+    }
+
+    #[test]
+    fn test_shuffle_is_stable() {
+        let mut items: Vec<u32> = (0..10).collect();
+        shuffle(b"seed", &mut items);
+        assert_eq!(items, [6, 5, 4, 7, 1, 3, 8, 9, 0, 2]);
+
+        let mut items: Vec<u32> = (0..10).collect();
+        shuffle(b"other", &mut items);
+        assert_eq!(items, [7, 9, 2, 1, 6, 8, 3, 0, 5, 4]);
+    }
+
+    #[test]
+    fn test_shuffle_trivial() {
+        let mut empty: [u8; 0] = [];
+        shuffle(b"seed", &mut empty);
+        let mut one = [1];
+        shuffle(b"seed", &mut one);
+        assert_eq!(one, [1]);
+    }
+
+    #[test]
+    fn test_derive_seed_separates_domains() {
+        assert_ne!(derive_seed(b"seed", b"a"), derive_seed(b"seed", b"b"));
+        assert_ne!(derive_seed(b"ab", b"c"), derive_seed(b"b", b"ac"));
+    }
+}
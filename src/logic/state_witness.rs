@@ -0,0 +1,100 @@
+//! The state a call touched with the proofs of its values, see
+//! [`crate::RunOptions::record_state_witness`].
+//!
+//! A validator without the state of the account can run the call again from
+//! the witness: the proofs give the values of the touched keys before the
+//! call, checked against the state root of its chunk.
+
+use super::dependencies::External;
+use super::logic::Result;
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde_with::base64::Base64;
+use serde_with::serde_as;
+use std::collections::{BTreeSet, HashMap};
+
+/// A key of the storage the call read or wrote.
+#[serde_as]
+#[derive(
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct TouchedKey {
+    #[serde_as(as = "Base64")]
+    pub key: Vec<u8>,
+    /// Whether the call read the key with `storage_read` or
+    /// `storage_has_key`.
+    pub read: bool,
+    /// Whether the call wrote or removed the key, even if the call failed
+    /// afterwards.  Writes and removals need the proof of the old value all
+    /// the same, as they return it.
+    pub written: bool,
+}
+
+/// Keys touched by a call and the trie nodes proving their values before
+/// the call, see [`External::storage_proof`].
+#[serde_as]
+#[derive(
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct StateWitness {
+    /// The touched keys, in the order the call first touched them.
+    pub keys: Vec<TouchedKey>,
+    /// Nodes of the proofs of all keys, sorted and without duplicates: keys
+    /// sharing a path share its nodes.
+    #[serde_as(as = "Vec<Base64>")]
+    pub nodes: Vec<Vec<u8>>,
+}
+
+/// Collects the [`StateWitness`] of a call as it touches keys.
+#[derive(Default)]
+pub(super) struct StateWitnessRecorder {
+    keys: Vec<TouchedKey>,
+    index: HashMap<Vec<u8>, usize>,
+    nodes: BTreeSet<Vec<u8>>,
+}
+
+impl StateWitnessRecorder {
+    /// Records an access to `key`, asking `ext` for its proof the first time.
+    ///
+    /// Called before the access, so that the proof is of the value from
+    /// before the call.
+    pub(super) fn touch(
+        &mut self,
+        ext: &dyn External,
+        key: &[u8],
+        read: bool,
+        written: bool,
+    ) -> Result<()> {
+        let index = match self.index.get(key) {
+            Some(&index) => index,
+            None => {
+                self.nodes.extend(ext.storage_proof(key)?);
+                self.index.insert(key.to_vec(), self.keys.len());
+                self.keys.push(TouchedKey { key: key.to_vec(), read: false, written: false });
+                self.keys.len() - 1
+            }
+        };
+        let touched = &mut self.keys[index];
+        touched.read |= read;
+        touched.written |= written;
+        Ok(())
+    }
+
+    pub(super) fn finish(self) -> StateWitness {
+        StateWitness { keys: self.keys, nodes: self.nodes.into_iter().collect() }
+    }
+}
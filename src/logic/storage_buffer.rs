@@ -0,0 +1,76 @@
+//! Storage writes kept in the [`super::VMLogic`] until the call succeeds, see
+//! [`crate::logic::Config::buffer_storage_writes`].
+
+use super::dependencies::{External, ValuePtr};
+use super::logic::Result;
+use super::StorageGetMode;
+use std::collections::BTreeMap;
+
+/// The writes of a call, the values of the removed keys being `None`.
+#[derive(Default)]
+pub(super) struct StorageBuffer {
+    changes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+struct BufferedValuePtr<'a>(&'a [u8]);
+
+impl ValuePtr for BufferedValuePtr<'_> {
+    fn len(&self) -> u32 {
+        self.0.len() as u32
+    }
+
+    fn deref(&self) -> Result<Vec<u8>> {
+        Ok(self.0.to_vec())
+    }
+}
+
+impl StorageBuffer {
+    pub(super) fn set(&mut self, key: &[u8], value: &[u8]) {
+        self.changes.insert(key.to_vec(), Some(value.to_vec()));
+    }
+
+    pub(super) fn remove(&mut self, key: &[u8]) {
+        self.changes.insert(key.to_vec(), None);
+    }
+
+    /// Reads `key` from the buffer, or from `ext` if the call did not write
+    /// it.
+    pub(super) fn get<'a>(
+        buffer: Option<&'a Self>,
+        ext: &'a dyn External,
+        key: &[u8],
+        mode: StorageGetMode,
+    ) -> Result<Option<Box<dyn ValuePtr + 'a>>> {
+        match buffer.and_then(|buffer| buffer.changes.get(key)) {
+            Some(value) => {
+                Ok(value.as_deref().map(|value| Box::new(BufferedValuePtr(value)) as Box<_>))
+            }
+            None => ext.storage_get(key, mode),
+        }
+    }
+
+    /// Whether `key` is in the buffer, or in `ext` if the call did not write
+    /// it.
+    pub(super) fn has_key(
+        buffer: Option<&Self>,
+        ext: &mut dyn External,
+        key: &[u8],
+        mode: StorageGetMode,
+    ) -> Result<bool> {
+        match buffer.and_then(|buffer| buffer.changes.get(key)) {
+            Some(value) => Ok(value.is_some()),
+            None => ext.storage_has_key(key, mode),
+        }
+    }
+
+    /// Applies the writes to `ext`, in the order of the keys.
+    pub(super) fn commit(self, ext: &mut dyn External) -> Result<()> {
+        for (key, value) in self.changes {
+            match value {
+                Some(value) => ext.storage_set(&key, &value)?,
+                None => ext.storage_remove(&key)?,
+            }
+        }
+        Ok(())
+    }
+}
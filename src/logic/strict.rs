@@ -0,0 +1,542 @@
+//! Runtime checks of the determinism of an [`External`].
+//!
+//! Every node must see the same state through its [`External`], or the
+//! outcomes of a call differ and the nodes disagree on the block.  An
+//! embedder's implementation answering a read from a cache gone stale, or
+//! counting trie nodes per thread, only shows up as such a consensus failure.
+//! [`StrictExternal`] wraps the [`External`] of a call and checks that what it
+//! answers is consistent within the call:
+//!
+//! * a key reads the value written or removed last by the call, or else the
+//!   value it read before, in both lookup modes, and has it if and only if
+//!   it reads a value;
+//! * the length of a value pointer is the length of its value;
+//! * the trie node counts never decrease;
+//! * data ids and receipt indices are never given twice, and receipts keep
+//!   their receivers;
+//! * the validators and the code hashes of the accounts do not change.
+//!
+//! The storage usage is not checked: the [`External`] does not report it,
+//! the runner counts it itself from the lengths of the keys and the values
+//! written, and removals lower it.
+//!
+//! Violations are kept in [`StrictExternal::violations`] and fail the call
+//! with an [`ExternalViolation`] as [`VMLogicError::ExternalError`], so that
+//! it fails as a node error instead of producing an outcome.  The checked
+//! methods returning a result fail with their violations.  The other ones,
+//! [`External::generate_data_id`], [`External::get_trie_nodes_count`] and
+//! [`External::get_receipt_receiver`], cannot: the next method returning a
+//! result fails with their violation instead, and [`StrictExternal::check`]
+//! returns it once the call is over if no method did.
+
+use super::dependencies::{External, Result, ValuePtr};
+use super::errors::AnyError;
+use super::mocks::mock_external::MockedValuePtr;
+use super::types::ReceiptIndex;
+use super::{TrieNodesCount, VMLogicError};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use unc_crypto::PublicKey;
+use unc_parameters::vm::StorageGetMode;
+use unc_primitives_core::hash::CryptoHash;
+use unc_primitives_core::types::{AccountId, Balance, Gas, GasWeight, Nonce, Power};
+
+/// An inconsistency of the [`External`] of a [`StrictExternal`].
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ExternalViolation {
+    #[error("key {} reads {}, but {} before", .key.escape_ascii(), show(.value), show(.expected))]
+    StorageValue { key: Vec<u8>, value: Option<Vec<u8>>, expected: Option<Vec<u8>> },
+    #[error(
+        "key {} is {}present, but reads {}",
+        .key.escape_ascii(),
+        if *.has_key { "" } else { "not " },
+        show(.value)
+    )]
+    HasKey { key: Vec<u8>, has_key: bool, value: Option<Vec<u8>> },
+    #[error("the value of key {} has {value_len} bytes, its pointer {len}", .key.escape_ascii())]
+    ValueLength { key: Vec<u8>, len: u32, value_len: usize },
+    #[error("the trie node counts went from {before:?} down to {after:?}")]
+    TrieNodesDecreased { before: (u64, u64), after: (u64, u64) },
+    #[error("data id {data_id} was generated twice")]
+    DuplicateDataId { data_id: CryptoHash },
+    #[error("receipt index {receipt_index} was given twice")]
+    DuplicateReceiptIndex { receipt_index: ReceiptIndex },
+    #[error("receipt {receipt_index} is sent to {receiver_id} instead of {expected}")]
+    ReceiptReceiver { receipt_index: ReceiptIndex, receiver_id: AccountId, expected: AccountId },
+    #[error("{function} now answers {value} instead of {expected}")]
+    ChainState { function: String, value: String, expected: String },
+}
+
+fn show(value: &Option<Vec<u8>>) -> String {
+    match value {
+        Some(value) => format!("\"{}\"", value.escape_ascii()),
+        None => "nothing".to_string(),
+    }
+}
+
+/// What the call saw through the [`External`].
+#[derive(Default)]
+struct Seen {
+    /// Values of the keys read, written or removed, `None` for the absent
+    /// ones.
+    values: HashMap<Vec<u8>, Option<Vec<u8>>>,
+    /// Keys looked up with [`External::storage_has_key`] alone.
+    presence: HashMap<Vec<u8>, bool>,
+    trie_nodes: Option<(u64, u64)>,
+    data_ids: HashSet<CryptoHash>,
+    receipts: HashMap<ReceiptIndex, AccountId>,
+    /// Answers of the chain state methods, by method and argument.
+    chain_state: HashMap<String, String>,
+    violations: Vec<ExternalViolation>,
+    /// The first violation of a method which cannot fail, until a method
+    /// fails with it.
+    deferred: Option<ExternalViolation>,
+}
+
+impl Seen {
+    fn violate(&mut self, violation: ExternalViolation) -> VMLogicError {
+        tracing::error!(target: "vm", %violation, "nondeterministic External");
+        self.violations.push(violation.clone());
+        VMLogicError::ExternalError(AnyError::new(violation))
+    }
+
+    /// Same as [`Self::violate`], for the methods which cannot fail.
+    fn violate_later(&mut self, violation: ExternalViolation) {
+        self.violate(violation.clone());
+        self.deferred.get_or_insert(violation);
+    }
+
+    fn check_value(&mut self, key: &[u8], value: &Option<Vec<u8>>) -> Result<()> {
+        if let Some(expected) = self.values.get(key) {
+            if expected != value {
+                let expected = expected.clone();
+                let key = key.to_vec();
+                let value = value.clone();
+                return Err(self.violate(ExternalViolation::StorageValue { key, value, expected }));
+            }
+        } else if let Some(&has_key) = self.presence.get(key) {
+            if has_key != value.is_some() {
+                let (key, value) = (key.to_vec(), value.clone());
+                return Err(self.violate(ExternalViolation::HasKey { key, has_key, value }));
+            }
+        }
+        self.values.insert(key.to_vec(), value.clone());
+        Ok(())
+    }
+
+    fn check_has_key(&mut self, key: &[u8], has_key: bool) -> Result<()> {
+        if let Some(value) = self.values.get(key) {
+            if value.is_some() != has_key {
+                let (key, value) = (key.to_vec(), value.clone());
+                return Err(self.violate(ExternalViolation::HasKey { key, has_key, value }));
+            }
+        } else if let Some(&expected) = self.presence.get(key) {
+            if expected != has_key {
+                let key = key.to_vec();
+                return Err(self.violate(ExternalViolation::HasKey { key, has_key, value: None }));
+            }
+        }
+        self.presence.insert(key.to_vec(), has_key);
+        Ok(())
+    }
+
+    fn check_chain_state<T: std::fmt::Debug>(
+        &mut self,
+        function: String,
+        value: Result<T>,
+    ) -> Result<T> {
+        let value = value?;
+        let shown = format!("{value:?}");
+        match self.chain_state.get(&function) {
+            Some(expected) if *expected != shown => {
+                let expected = expected.clone();
+                Err(self.violate(ExternalViolation::ChainState {
+                    function,
+                    value: shown,
+                    expected,
+                }))
+            }
+            Some(_) => Ok(value),
+            None => {
+                self.chain_state.insert(function, shown);
+                Ok(value)
+            }
+        }
+    }
+}
+
+/// [`External`] checking the consistency of the one it wraps, see the module
+/// documentation.
+pub struct StrictExternal<'a> {
+    inner: &'a mut dyn External,
+    // `External::storage_get` takes `&self`.
+    seen: RefCell<Seen>,
+}
+
+impl<'a> StrictExternal<'a> {
+    pub fn new(inner: &'a mut dyn External) -> Self {
+        Self { inner, seen: RefCell::default() }
+    }
+
+    /// The inconsistencies found so far, in order.
+    pub fn violations(&self) -> Vec<ExternalViolation> {
+        self.seen.borrow().violations.clone()
+    }
+
+    /// The violation of a method which cannot fail that no method failed
+    /// with yet, failing the call once it is over.
+    pub fn check(&self) -> Result<(), ExternalViolation> {
+        match self.seen.borrow_mut().deferred.take() {
+            Some(violation) => Err(violation),
+            None => Ok(()),
+        }
+    }
+
+    /// Fails with the violation of a method which cannot fail, once.
+    fn deferred(&self) -> Result<()> {
+        self.check().map_err(|violation| VMLogicError::ExternalError(AnyError::new(violation)))
+    }
+}
+
+impl External for StrictExternal<'_> {
+    fn storage_set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.deferred()?;
+        self.inner.storage_set(key, value)?;
+        let seen = self.seen.get_mut();
+        seen.presence.remove(key);
+        seen.values.insert(key.to_vec(), Some(value.to_vec()));
+        Ok(())
+    }
+
+    fn storage_get<'b>(
+        &'b self,
+        key: &[u8],
+        mode: StorageGetMode,
+    ) -> Result<Option<Box<dyn ValuePtr + 'b>>> {
+        self.deferred()?;
+        let value = match self.inner.storage_get(key, mode)? {
+            Some(ptr) => {
+                let value = ptr.deref()?;
+                if ptr.len() as usize != value.len() {
+                    let violation = ExternalViolation::ValueLength {
+                        key: key.to_vec(),
+                        len: ptr.len(),
+                        value_len: value.len(),
+                    };
+                    return Err(self.seen.borrow_mut().violate(violation));
+                }
+                Some(value)
+            }
+            None => None,
+        };
+        self.seen.borrow_mut().check_value(key, &value)?;
+        Ok(value.map(|value| Box::new(MockedValuePtr::new(value)) as Box<_>))
+    }
+
+    fn storage_remove(&mut self, key: &[u8]) -> Result<()> {
+        self.deferred()?;
+        self.inner.storage_remove(key)?;
+        let seen = self.seen.get_mut();
+        seen.presence.remove(key);
+        seen.values.insert(key.to_vec(), None);
+        Ok(())
+    }
+
+    fn storage_remove_subtree(&mut self, prefix: &[u8]) -> Result<()> {
+        self.deferred()?;
+        self.inner.storage_remove_subtree(prefix)?;
+        let seen = self.seen.get_mut();
+        seen.presence.retain(|key, _| !key.starts_with(prefix));
+        for (_, value) in seen.values.iter_mut().filter(|(key, _)| key.starts_with(prefix)) {
+            *value = None;
+        }
+        Ok(())
+    }
+
+    fn storage_has_key(&mut self, key: &[u8], mode: StorageGetMode) -> Result<bool> {
+        self.deferred()?;
+        let has_key = self.inner.storage_has_key(key, mode)?;
+        self.seen.get_mut().check_has_key(key, has_key)?;
+        Ok(has_key)
+    }
+
+    fn storage_proof(&self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.deferred()?;
+        self.inner.storage_proof(key)
+    }
+
+    fn state_changed(&self) -> bool {
+        self.inner.state_changed()
+    }
+
+    fn generate_data_id(&mut self) -> CryptoHash {
+        let data_id = self.inner.generate_data_id();
+        let seen = self.seen.get_mut();
+        if !seen.data_ids.insert(data_id) {
+            seen.violate_later(ExternalViolation::DuplicateDataId { data_id });
+        }
+        data_id
+    }
+
+    fn get_trie_nodes_count(&self) -> TrieNodesCount {
+        let count = self.inner.get_trie_nodes_count();
+        let after = (count.db_reads, count.mem_reads);
+        let mut seen = self.seen.borrow_mut();
+        if let Some(before) = seen.trie_nodes {
+            if after.0 < before.0 || after.1 < before.1 {
+                seen.violate_later(ExternalViolation::TrieNodesDecreased { before, after });
+            }
+        }
+        seen.trie_nodes = Some(after);
+        count
+    }
+
+    fn validator_frozen(&self, account_id: &AccountId) -> Result<Option<Balance>> {
+        self.deferred()?;
+        let value = self.inner.validator_frozen(account_id);
+        let function = format!("validator_frozen({account_id})");
+        self.seen.borrow_mut().check_chain_state(function, value)
+    }
+
+    fn validator_power(&self, account_id: &AccountId) -> Result<Option<Power>> {
+        self.deferred()?;
+        let value = self.inner.validator_power(account_id);
+        let function = format!("validator_power({account_id})");
+        self.seen.borrow_mut().check_chain_state(function, value)
+    }
+
+    fn validator_total_frozen(&self) -> Result<Balance> {
+        self.deferred()?;
+        let value = self.inner.validator_total_frozen();
+        self.seen.borrow_mut().check_chain_state("validator_total_frozen()".to_string(), value)
+    }
+
+    fn validator_total_power(&self) -> Result<Power> {
+        self.deferred()?;
+        let value = self.inner.validator_total_power();
+        self.seen.borrow_mut().check_chain_state("validator_total_power()".to_string(), value)
+    }
+
+    fn account_code_hash(&self, account_id: &AccountId) -> Result<Option<CryptoHash>> {
+        self.deferred()?;
+        let value = self.inner.account_code_hash(account_id);
+        let function = format!("account_code_hash({account_id})");
+        self.seen.borrow_mut().check_chain_state(function, value)
+    }
+
+    fn create_receipt(
+        &mut self,
+        receipt_indices: Vec<ReceiptIndex>,
+        receiver_id: AccountId,
+    ) -> Result<ReceiptIndex, VMLogicError> {
+        self.deferred()?;
+        let receipt_index = self.inner.create_receipt(receipt_indices, receiver_id.clone())?;
+        let seen = self.seen.get_mut();
+        if seen.receipts.insert(receipt_index, receiver_id).is_some() {
+            return Err(seen.violate(ExternalViolation::DuplicateReceiptIndex { receipt_index }));
+        }
+        Ok(receipt_index)
+    }
+
+    fn append_action_create_account(
+        &mut self,
+        receipt_index: ReceiptIndex,
+    ) -> Result<(), VMLogicError> {
+        self.deferred()?;
+        self.inner.append_action_create_account(receipt_index)
+    }
+
+    fn append_action_deploy_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code: Vec<u8>,
+    ) -> Result<(), VMLogicError> {
+        self.deferred()?;
+        self.inner.append_action_deploy_contract(receipt_index, code)
+    }
+
+    fn append_action_deploy_global_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code_hash: CryptoHash,
+    ) -> Result<(), VMLogicError> {
+        self.deferred()?;
+        self.inner.append_action_deploy_global_contract(receipt_index, code_hash)
+    }
+
+    fn append_action_function_call_weight(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        method_name: Vec<u8>,
+        args: Vec<u8>,
+        attached_deposit: Balance,
+        prepaid_gas: Gas,
+        gas_weight: GasWeight,
+    ) -> Result<(), VMLogicError> {
+        self.deferred()?;
+        self.inner.append_action_function_call_weight(
+            receipt_index,
+            method_name,
+            args,
+            attached_deposit,
+            prepaid_gas,
+            gas_weight,
+        )
+    }
+
+    fn append_action_transfer(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        deposit: Balance,
+    ) -> Result<(), VMLogicError> {
+        self.deferred()?;
+        self.inner.append_action_transfer(receipt_index, deposit)
+    }
+
+    fn append_action_stake(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        stake: Balance,
+        public_key: PublicKey,
+    ) {
+        self.inner.append_action_stake(receipt_index, stake, public_key)
+    }
+
+    fn append_action_add_key_with_full_access(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+    ) {
+        self.inner.append_action_add_key_with_full_access(receipt_index, public_key, nonce)
+    }
+
+    fn append_action_add_key_with_function_call(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+        allowance: Option<Balance>,
+        receiver_id: AccountId,
+        method_names: Vec<Vec<u8>>,
+    ) -> Result<(), VMLogicError> {
+        self.deferred()?;
+        self.inner.append_action_add_key_with_function_call(
+            receipt_index,
+            public_key,
+            nonce,
+            allowance,
+            receiver_id,
+            method_names,
+        )
+    }
+
+    fn append_action_delete_key(&mut self, receipt_index: ReceiptIndex, public_key: PublicKey) {
+        self.inner.append_action_delete_key(receipt_index, public_key)
+    }
+
+    fn append_action_delete_account(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        beneficiary_id: AccountId,
+    ) -> Result<(), VMLogicError> {
+        self.deferred()?;
+        self.inner.append_action_delete_account(receipt_index, beneficiary_id)
+    }
+
+    fn scratch_get(&self) -> Result<Option<Vec<u8>>> {
+        self.deferred()?;
+        self.inner.scratch_get()
+    }
+
+    fn append_scratch(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        data: Vec<u8>,
+    ) -> Result<(), VMLogicError> {
+        self.deferred()?;
+        self.inner.append_scratch(receipt_index, data)
+    }
+
+    fn get_receipt_receiver(&self, receipt_index: ReceiptIndex) -> &AccountId {
+        let receiver_id = self.inner.get_receipt_receiver(receipt_index);
+        let mut seen = self.seen.borrow_mut();
+        if let Some(expected) = seen.receipts.get(&receipt_index) {
+            if expected != receiver_id {
+                let violation = ExternalViolation::ReceiptReceiver {
+                    receipt_index,
+                    receiver_id: receiver_id.clone(),
+                    expected: expected.clone(),
+                };
+                seen.violate_later(violation);
+            }
+        }
+        receiver_id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+
+    #[test]
+    fn test_external_storage() {
+        let mut ext = MockedExternal::new();
+        let mut strict = StrictExternal::new(&mut ext);
+        crate::logic::test_utils::test_external_storage(&mut strict);
+        assert_eq!(strict.violations(), []);
+    }
+
+    #[test]
+    fn test_violations() {
+        let mut ext = MockedExternal::new();
+        ext.fake_trie.insert(b"key".to_vec(), b"old".to_vec());
+        let read = |ext: &StrictExternal| {
+            ext.storage_get(b"key", StorageGetMode::Trie).map(|ptr| ptr.unwrap().deref().unwrap())
+        };
+        let mut strict = StrictExternal::new(&mut ext);
+        assert_eq!(read(&strict).unwrap(), b"old");
+        strict.storage_set(b"key", b"new").unwrap();
+        assert_eq!(read(&strict).unwrap(), b"new");
+        assert!(strict.storage_has_key(b"key", StorageGetMode::FlatStorage).unwrap());
+        assert_eq!(strict.violations(), []);
+
+        // The state changes under the call.
+        strict.inner.storage_set(b"key", b"other").unwrap();
+        assert!(read(&strict).is_err());
+        strict.inner.storage_remove(b"key").unwrap();
+        assert!(strict.storage_has_key(b"key", StorageGetMode::Trie).is_err());
+        strict.get_trie_nodes_count();
+        let violations = strict.violations();
+        assert_eq!(violations.len(), 2);
+        assert_eq!(violations[0].to_string(), r#"key key reads "other", but "new" before"#);
+        assert_eq!(violations[1].to_string(), r#"key key is not present, but reads "new""#);
+    }
+
+    #[test]
+    fn test_deferred_violations() {
+        let mut ext = MockedExternal::new();
+        let mut strict = StrictExternal::new(&mut ext);
+        // The trie node counts go down from a count read before.
+        strict.seen.get_mut().trie_nodes = Some((1, 1));
+        strict.get_trie_nodes_count();
+        let Err(VMLogicError::ExternalError(error)) = strict.storage_set(b"key", b"value") else {
+            panic!("the violation does not fail the next call");
+        };
+        assert_eq!(
+            error.downcast::<ExternalViolation>().unwrap(),
+            ExternalViolation::TrieNodesDecreased { before: (1, 1), after: (0, 0) }
+        );
+        strict.storage_set(b"key", b"value").unwrap();
+        assert_eq!(strict.check(), Ok(()));
+
+        strict.seen.get_mut().trie_nodes = Some((1, 0));
+        strict.get_trie_nodes_count();
+        assert_eq!(
+            strict.check(),
+            Err(ExternalViolation::TrieNodesDecreased { before: (1, 0), after: (0, 0) })
+        );
+        assert_eq!(strict.violations().len(), 2);
+    }
+}
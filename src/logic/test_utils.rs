@@ -1,4 +1,4 @@
-use super::{MemSlice, MemoryLike};
+use super::{External, MemSlice, MemoryLike, StorageGetMode};
 
 /// Tests for implementation of MemoryLike interface.
 ///
@@ -69,3 +69,57 @@ pub fn test_memory_like(factory: impl FnOnce() -> Box<dyn MemoryLike>) {
     // None of the writes in OOB should have any effect.
     ctx.test_read(0, PAGE, 0);
 }
+
+/// Tests the storage of an implementation of the External interface.
+///
+/// `ext` must have no storage yet.  The tests check the consistency rules of
+/// [`External`] on which the storage host functions rely, with both lookup
+/// modes: a call reads back its own writes and removals, empty values are
+/// present, and the length of a value pointer is the length of the value.
+///
+/// Panics if any of the tests fails.
+pub fn test_external_storage(ext: &mut dyn External) {
+    fn get(ext: &mut dyn External, key: &[u8]) -> Option<Vec<u8>> {
+        let [trie, flat] = [StorageGetMode::Trie, StorageGetMode::FlatStorage].map(|mode| {
+            let value = ext.storage_get(key, mode).unwrap().map(|ptr| {
+                let value = ptr.deref().unwrap();
+                assert_eq!(ptr.len() as usize, value.len(), "{key:?}");
+                value
+            });
+            assert_eq!(ext.storage_has_key(key, mode).unwrap(), value.is_some(), "{key:?}");
+            value
+        });
+        assert_eq!(trie, flat, "{key:?}");
+        trie
+    }
+
+    assert_eq!(get(ext, b"key"), None);
+    ext.storage_remove(b"key").unwrap();
+    assert_eq!(get(ext, b"key"), None);
+
+    // Writes are visible at once, and overwrite the previous value.
+    ext.storage_set(b"key", b"value1").unwrap();
+    assert_eq!(get(ext, b"key"), Some(b"value1".to_vec()));
+    ext.storage_set(b"key", b"value2").unwrap();
+    assert_eq!(get(ext, b"key"), Some(b"value2".to_vec()));
+    // An empty value is not a missing one.
+    ext.storage_set(b"key", b"").unwrap();
+    assert_eq!(get(ext, b"key"), Some(vec![]));
+
+    // Keys sharing a prefix are independent.
+    ext.storage_set(b"key2", b"value3").unwrap();
+    ext.storage_remove(b"key").unwrap();
+    assert_eq!(get(ext, b"key"), None);
+    assert_eq!(get(ext, b"key2"), Some(b"value3".to_vec()));
+    ext.storage_set(b"key", b"value4").unwrap();
+    assert_eq!(get(ext, b"key"), Some(b"value4".to_vec()));
+
+    // Removing a subtree only hides the keys written before.
+    ext.storage_set(b"other", b"value5").unwrap();
+    ext.storage_remove_subtree(b"key").unwrap();
+    assert_eq!(get(ext, b"key"), None);
+    assert_eq!(get(ext, b"key2"), None);
+    assert_eq!(get(ext, b"other"), Some(b"value5".to_vec()));
+    ext.storage_set(b"key2", b"value6").unwrap();
+    assert_eq!(get(ext, b"key2"), Some(b"value6".to_vec()));
+}
@@ -1,6 +1,9 @@
+use crate::logic::tests::helpers::*;
 use crate::logic::tests::vm_logic_builder::VMLogicBuilder;
 use crate::logic::{HostError, VMLogicError};
+use crate::map;
 use std::fmt::Write;
+use unc_parameters::ExtCosts;
 
 /// Converts a sequence of integers to a single little-endian encoded byte
 /// vector. `u8` literals like `0u8` are treated as u8, hex literals like
@@ -268,3 +271,149 @@ fn test_alt_bn128_pairing_check() {
     check_err(b"XXXX", "slice of size 4 cannot be precisely split into chunks of size 192");
     check_err(&le_bytes![0x0 0x0  0x0 0x0 0x0 0x0, 0x0 0x0  0x0 0x0 0x0 0x111], "invalid g2");
 }
+
+fn fr(k: &str) -> bn::Fr {
+    bn::Fr::from_str(k).unwrap()
+}
+
+fn g1(k: &str) -> [u8; 64] {
+    use bn::Group;
+    crate::logic::alt_bn128::encode_g1(bn::G1::one() * fr(k))
+}
+
+fn g2(k: &str) -> [u8; 128] {
+    use bn::Group;
+    crate::logic::alt_bn128::encode_g2(bn::G2::one() * fr(k))
+}
+
+#[test]
+fn test_alt_bn128_g2_multiexp() {
+    #[track_caller]
+    fn check(input: &[u8], expected: Result<&[u8], &str>) {
+        let mut logic_builder = VMLogicBuilder::default();
+        let mut logic = logic_builder.build();
+        let input = logic.internal_mem_write(input);
+
+        let res = logic.alt_bn128_g2_multiexp(input.len, input.ptr, 0);
+        if let Some(((), expected)) = check_result(res, expected) {
+            let got = logic.registers().get_for_free(0).unwrap();
+            assert_eq!(expected, got);
+        }
+    }
+
+    check(&[], Ok(&[0; 128]));
+    check(&[g2("7").as_slice(), &le_bytes![0x1]].concat(), Ok(&g2("7")));
+    // 2 * 3G + 5 * 7G = 41G
+    check(
+        &[g2("3").as_slice(), &le_bytes![0x2], &g2("7"), &le_bytes![0x5]].concat(),
+        Ok(&g2("41")),
+    );
+
+    check(b"XXXX", Err("slice of size 4 cannot be precisely split into chunks of size 160"));
+    check(&[[0; 96].as_slice(), &le_bytes![0x111 0x1]].concat(), Err("invalid g2"));
+    check(
+        &[
+            g2("3").as_slice(),
+            &le_bytes![0xffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffffff],
+        ]
+        .concat(),
+        Err("invalid fr"),
+    );
+}
+
+#[test]
+fn test_alt_bn128_g2_multiexp_cost() {
+    let mut logic_builder = VMLogicBuilder::free();
+    logic_builder.config.extra_ext_costs.alt_bn128_g2_multiexp_base = 1000;
+    logic_builder.config.extra_ext_costs.alt_bn128_g2_multiexp_element = 10;
+    let mut logic = logic_builder.build();
+    let input = [g2("3").as_slice(), &le_bytes![0x2], &g2("7"), &le_bytes![0x5]].concat();
+    let input = logic.internal_mem_write(&input);
+
+    logic.alt_bn128_g2_multiexp(input.len, input.ptr, 0).unwrap();
+    assert_costs(map! {
+        ExtCosts::read_memory_base: 1,
+        ExtCosts::read_memory_byte: 320,
+        ExtCosts::write_register_base: 1,
+        ExtCosts::write_register_byte: 128,
+    });
+    assert_eq!(logic.gas_counter().burnt_gas(), 1000 + 2 * 10);
+
+    // The base is paid before the input is read.
+    let mut logic = logic_builder.build();
+    assert_eq!(
+        logic.alt_bn128_g2_multiexp(160, u64::MAX, 0),
+        Err(HostError::MemoryAccessViolation.into())
+    );
+    assert_eq!(logic.gas_counter().burnt_gas(), 1000);
+}
+
+#[test]
+fn test_alt_bn128_pairing_check_batch() {
+    #[track_caller]
+    fn check(input: &[u8], expected: Result<u64, &str>) {
+        let mut logic_builder = VMLogicBuilder::default();
+        let mut logic = logic_builder.build();
+        let input = logic.internal_mem_write(input);
+
+        let res = logic.alt_bn128_pairing_check_batch(input.len, input.ptr);
+        if let Some((res, expected)) = check_result(res, expected) {
+            assert_eq!(res, expected)
+        }
+    }
+    fn batch(checks: &[&[u8]]) -> Vec<u8> {
+        let mut batch = Vec::new();
+        for check in checks {
+            batch.extend((check.len() as u32 / 192).to_le_bytes());
+            batch.extend(*check);
+        }
+        batch
+    }
+
+    // e(5G1, 3G2) * e(-15G1, G2) == 1
+    let neg_g1 = {
+        use bn::Group;
+        crate::logic::alt_bn128::encode_g1(-(bn::G1::one() * fr("15")))
+    };
+    let passing = [g1("5").as_slice(), &g2("3"), &neg_g1, &g2("1")].concat();
+    let failing = [g1("5").as_slice(), &g2("3")].concat();
+
+    check(&[], Ok(u64::MAX));
+    check(&batch(&[&[]]), Ok(u64::MAX));
+    check(&batch(&[&passing, &passing]), Ok(u64::MAX));
+    check(&batch(&[&passing, &failing, &passing]), Ok(1));
+    check(&batch(&[&failing]), Ok(0));
+    // Checks after the failing one are not computed.
+    check(&batch(&[&failing, &[0xff; 192]]), Ok(0));
+
+    check(&[1, 0], Err("truncated pairing check length"));
+    check(&batch(&[&passing])[..200], Err("truncated pairing check"));
+    check(&batch(&[&passing, &[0xff; 192]]), Err("invalid"));
+
+    // Only the computed checks are paid for.
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+    let input = logic.internal_mem_write(&batch(&[&passing, &failing, &passing]));
+    reset_costs_counter();
+    assert_eq!(logic.alt_bn128_pairing_check_batch(input.len, input.ptr), Ok(1));
+    assert_costs(map! {
+        ExtCosts::read_memory_base: 1,
+        ExtCosts::read_memory_byte: input.len,
+        ExtCosts::alt_bn128_pairing_check_base: 2,
+        ExtCosts::alt_bn128_pairing_check_element: 3,
+    });
+
+    // The batch base is paid once, before the input is read.
+    let mut logic_builder = VMLogicBuilder::free();
+    logic_builder.config.extra_ext_costs.alt_bn128_pairing_check_batch_base = 7;
+    let mut logic = logic_builder.build();
+    let input = logic.internal_mem_write(&batch(&[&failing, &passing]));
+    assert_eq!(logic.alt_bn128_pairing_check_batch(input.len, input.ptr), Ok(0));
+    assert_eq!(logic.gas_counter().burnt_gas(), 7);
+    let mut logic = logic_builder.build();
+    assert_eq!(
+        logic.alt_bn128_pairing_check_batch(192, u64::MAX),
+        Err(HostError::MemoryAccessViolation.into())
+    );
+    assert_eq!(logic.gas_counter().burnt_gas(), 7);
+}
@@ -0,0 +1,191 @@
+use crate::logic::tests::vm_logic_builder::{TestVMLogic, VMLogicBuilder};
+use crate::logic::{HostError, VMLogicError};
+
+/// The generator of G1, encoded as the host functions take it.
+const G1: [&str; 3] = [
+    "bbc622db0af03afbef1a7af93fe8556c58ac1b173f3a4ea105b974974f8c68c30faca94f8c63952694d79731",
+    "a7d3f117e1e7c5462923aa0ce48a88a244c73cd0edb3042ccb18db00f60ad0d595e0f5fce48a1d74ed309ea0",
+    "f1a0aae381f4b308",
+];
+/// The generator of G2.
+const G2: [&str; 5] = [
+    "b8bd21c1c85680d4efbb05a82603ac0b77d1e37a640b51b4023b40fad47ae4c65110c52d27050826910a8ff0",
+    "b2a24a027e2b045d057dace5575d941312f14c3349507fdcbb61dab51ab62099d0d06b59654f2788a0d3ac7d",
+    "609f7152602be0130128b808865493e189a2ac3bccc93a922cd16051699a426da7d3bd8caa9bfdad1a352eda",
+    "c6cdc98c116e7d7227d5e50cbe795ff05f07a9aaa11dec5c270d373fab992e57ab927426af63a7857e283ecb",
+    "998bc22bb0d2ac32cc34a72ea0c40606",
+];
+/// The two elements `hash_to_field` gives for the empty message in the test
+/// vectors of RFC 9380, appendix J.10.1.
+const U: [&str; 5] = [
+    "f81cdcdf07b88ea6d013a694b579bd809d68219fccb9b275ce1c8da02e4a19987f916bf208bb3ca91be974e1",
+    "ccc2db039ab3b3116507f794eb6179d6ca39b78619bd9805f16d872ca9703b2538ba25d1ab39a39e19541a71",
+    "45481164ecaca20594ee3e4734a3267723bc0d8c1441765b1b19de25c86b8462469890c07ef4a11b5220918e",
+    "e1d760eddeaca5e89897f90235a430de985ae90746fae636b415914cb9c4b06e2f7b21872fa2e70ee6894e07",
+    "301b39148fa627c00c01d418e4815a14",
+];
+/// The `hash_to_curve` of the empty message, the sum of their maps.
+const P: [&str; 5] = [
+    "8ab71fc4f5fa9353dddce938104cc44a8d9b0df3665233698de9a3f160cf73c689b60a132e14875bb80ea4dc",
+    "fbeb41013da07df3b0f54770711824b771ddf55bff3da19b37abefae8b455284c339410398dfad2b75f7aeff",
+    "ec205e533784cb0592dd4f06980c0c194b8c35bcd4f2da76c05ee72e06115d19ca5049522a7b1c47f30c3c96",
+    "0b94725e80126a7f1d920305d6b8badccf9e3ebf8d1e9fd1cd11c8c395631f7ddc9d254e979a0900bee720c6",
+    "b7128a7060c2e33f3f496125c34a4212",
+];
+
+fn decode(hex: &[&str]) -> Vec<u8> {
+    hex::decode(hex.concat()).unwrap()
+}
+
+/// `(sign, point)` elements of a sum.
+fn signed(points: &[(u8, &[u8])]) -> Vec<u8> {
+    points.iter().flat_map(|(sign, point)| [&[*sign], *point].concat()).collect()
+}
+
+fn g1_sum(logic: &mut TestVMLogic<'_>, input: &[u8]) -> Result<Vec<u8>, VMLogicError> {
+    let input = logic.internal_mem_write(input);
+    logic.bls12381_g1_sum(input.len, input.ptr, 0)?;
+    Ok(logic.registers().get_for_free(0).unwrap().to_vec())
+}
+
+fn g2_sum(logic: &mut TestVMLogic<'_>, input: &[u8]) -> Result<Vec<u8>, VMLogicError> {
+    let input = logic.internal_mem_write(input);
+    logic.bls12381_g2_sum(input.len, input.ptr, 0)?;
+    Ok(logic.registers().get_for_free(0).unwrap().to_vec())
+}
+
+fn pairing_check(logic: &mut TestVMLogic<'_>, input: &[u8]) -> Result<u64, VMLogicError> {
+    let input = logic.internal_mem_write(input);
+    logic.bls12381_pairing_check(input.len, input.ptr)
+}
+
+#[track_caller]
+fn assert_invalid_input<T: std::fmt::Debug>(res: Result<T, VMLogicError>, msg: &str) {
+    match res {
+        Err(VMLogicError::HostError(HostError::Bls12381InvalidInput { msg: err })) => {
+            assert!(err.contains(msg), "expected `{msg}` error, got {err}")
+        }
+        res => panic!("expected `{msg}` error, got {res:?}"),
+    }
+}
+
+#[test]
+fn test_bls12381_g1_sum() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+    let g1 = decode(&G1);
+
+    assert_eq!(g1_sum(&mut logic, &[]).unwrap(), [0; 96]);
+    let sum = g1_sum(&mut logic, &signed(&[(0, &g1), (0, &g1), (1, &g1)])).unwrap();
+    assert_eq!(sum, g1);
+    assert_eq!(g1_sum(&mut logic, &signed(&[(0, &g1), (1, &g1)])).unwrap(), [0; 96]);
+    // The point at infinity is the zero of the sum.
+    assert_eq!(g1_sum(&mut logic, &signed(&[(0, &[0; 96]), (0, &g1)])).unwrap(), g1);
+
+    assert_invalid_input(g1_sum(&mut logic, &signed(&[(2, &g1)])), "invalid bool");
+    let mut off_curve = g1.clone();
+    off_curve[0] ^= 1;
+    assert_invalid_input(g1_sum(&mut logic, &signed(&[(0, &off_curve)])), "invalid g1");
+    let mut not_in_field = g1.clone();
+    not_in_field[..48].fill(0xff);
+    assert_invalid_input(g1_sum(&mut logic, &signed(&[(0, &not_in_field)])), "invalid fp");
+    assert_invalid_input(
+        g1_sum(&mut logic, b"XXXX"),
+        "slice of size 4 cannot be precisely split into chunks of size 97",
+    );
+}
+
+#[test]
+fn test_bls12381_g2_sum() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+    let g2 = decode(&G2);
+
+    assert_eq!(g2_sum(&mut logic, &[]).unwrap(), [0; 192]);
+    let sum = g2_sum(&mut logic, &signed(&[(1, &g2), (0, &g2), (0, &g2)])).unwrap();
+    assert_eq!(sum, g2);
+    assert_eq!(g2_sum(&mut logic, &signed(&[(0, &g2), (1, &g2)])).unwrap(), [0; 192]);
+
+    let mut off_curve = g2.clone();
+    off_curve[96] ^= 1;
+    assert_invalid_input(g2_sum(&mut logic, &signed(&[(0, &off_curve)])), "invalid g2");
+    assert_invalid_input(
+        g2_sum(&mut logic, &[0; 96]),
+        "slice of size 96 cannot be precisely split into chunks of size 193",
+    );
+}
+
+#[test]
+fn test_bls12381_pairing_check() {
+    // Free, as the checks cost more than the gas of a call.
+    let mut logic_builder = VMLogicBuilder::free();
+    let mut logic = logic_builder.build();
+    let (g1, g2) = (decode(&G1), decode(&G2));
+    let neg_g1 = g1_sum(&mut logic, &signed(&[(1, &g1)])).unwrap();
+    let double_g1 = g1_sum(&mut logic, &signed(&[(0, &g1), (0, &g1)])).unwrap();
+    let double_g2 = g2_sum(&mut logic, &signed(&[(0, &g2), (0, &g2)])).unwrap();
+
+    assert_eq!(pairing_check(&mut logic, &[]).unwrap(), 1);
+    assert_eq!(pairing_check(&mut logic, &[g1.as_slice(), &g2].concat()).unwrap(), 0);
+    let input = [g1.as_slice(), &g2, &neg_g1, &g2].concat();
+    assert_eq!(pairing_check(&mut logic, &input).unwrap(), 1);
+    // e(2 g1, g2) e(-g1, 2 g2) is one by bilinearity, e(2 g1, g2) e(-g1, g2) is not.
+    let input = [double_g1.as_slice(), &g2, &neg_g1, &double_g2].concat();
+    assert_eq!(pairing_check(&mut logic, &input).unwrap(), 1);
+    let input = [double_g1.as_slice(), &g2, &neg_g1, &g2].concat();
+    assert_eq!(pairing_check(&mut logic, &input).unwrap(), 0);
+    // Points at infinity pair to one.
+    assert_eq!(pairing_check(&mut logic, &[[0; 96].as_slice(), &g2].concat()).unwrap(), 1);
+
+    // (0, 2) is on the curve, of order 3.
+    let mut order_3 = [0; 96];
+    order_3[48] = 2;
+    let input = [order_3.as_slice(), &g2].concat();
+    assert_invalid_input(pairing_check(&mut logic, &input), "g1 not in the subgroup");
+    assert_invalid_input(
+        pairing_check(&mut logic, &g1),
+        "slice of size 96 cannot be precisely split into chunks of size 288",
+    );
+}
+
+#[test]
+fn test_bls12381_map_to_curve() {
+    let mut logic_builder = VMLogicBuilder::free();
+    let mut logic = logic_builder.build();
+
+    let input = logic.internal_mem_write(&decode(&U));
+    logic.bls12381_map_to_curve(input.len, input.ptr, 0).unwrap();
+    let points = logic.registers().get_for_free(0).unwrap().to_vec();
+    assert_eq!(points.len(), 2 * 192);
+    let (q0, q1) = points.split_at(192);
+    assert_eq!(g2_sum(&mut logic, &signed(&[(0, q0), (0, q1)])).unwrap(), decode(&P));
+    // The points are in G2.
+    let g1 = decode(&G1);
+    assert_eq!(pairing_check(&mut logic, &[g1.as_slice(), q0].concat()).unwrap(), 0);
+
+    let input = logic.internal_mem_write(&[0xff; 96]);
+    assert_invalid_input(logic.bls12381_map_to_curve(input.len, input.ptr, 0), "invalid fp");
+}
+
+#[test]
+fn test_bls12381_costs() {
+    let mut logic_builder = VMLogicBuilder::free();
+    let costs = &mut logic_builder.config.extra_ext_costs;
+    costs.bls12381_g1_sum_base = 1;
+    costs.bls12381_g1_sum_element = 10;
+    costs.bls12381_g2_sum_base = 100;
+    costs.bls12381_g2_sum_element = 1000;
+    costs.bls12381_pairing_check_base = 10_000;
+    costs.bls12381_pairing_check_element = 100_000;
+    costs.bls12381_map_to_curve_base = 1_000_000;
+    costs.bls12381_map_to_curve_element = 10_000_000;
+    let mut logic = logic_builder.build();
+    let (g1, g2) = (decode(&G1), decode(&G2));
+
+    g1_sum(&mut logic, &signed(&[(0, &g1), (0, &g1)])).unwrap();
+    g2_sum(&mut logic, &signed(&[(0, &g2)])).unwrap();
+    pairing_check(&mut logic, &[g1.as_slice(), &g2].concat()).unwrap();
+    let input = logic.internal_mem_write(&decode(&U));
+    logic.bls12381_map_to_curve(input.len, input.ptr, 0).unwrap();
+    assert_eq!(logic.gas_counter().burnt_gas(), 21_111_121);
+}
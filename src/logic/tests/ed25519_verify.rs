@@ -350,3 +350,95 @@ fn test_ed25519_verify_check_registers() {
         },
     );
 }
+
+fn batch_entry(signature: &[u8; 64], public_key: &[u8; 32], message: &[u8]) -> Vec<u8> {
+    let mut entry = [&signature[..], &public_key[..]].concat();
+    entry.extend_from_slice(&(message.len() as u32).to_le_bytes());
+    entry.extend_from_slice(message);
+    entry
+}
+
+#[track_caller]
+fn check_ed25519_verify_batch(
+    batch: &[u8],
+    want: Result<u64, HostError>,
+    want_costs: HashMap<ExtCosts, u64>,
+) {
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+    let batch = logic.internal_mem_write(batch);
+    let result = logic.ed25519_verify_batch(batch.len, batch.ptr);
+    assert_eq!(want.map_err(VMLogicError::HostError), result);
+    assert_costs(want_costs);
+}
+
+#[test]
+fn test_ed25519_verify_batch() {
+    let valid = batch_entry(&SIGNATURE, &PUBLIC_KEY, &MESSAGE);
+    check_ed25519_verify_batch(
+        &[valid.clone(), valid.clone()].concat(),
+        Ok(u64::MAX),
+        map! {
+            ExtCosts::read_memory_base: 1,
+            ExtCosts::read_memory_byte: 264,
+            ExtCosts::ed25519_verify_byte: 64,
+        },
+    );
+    // Verification stops at the first invalid signature.
+    for invalid in [
+        batch_entry(&BAD_SIGNATURE, &PUBLIC_KEY, &MESSAGE),
+        batch_entry(&FORGED_SIGNATURE, &PUBLIC_KEY, &MESSAGE),
+        batch_entry(&SIGNATURE, &FORGED_PUBLIC_KEY, &MESSAGE),
+        batch_entry(&SIGNATURE, &PUBLIC_KEY, &MESSAGE[1..]),
+    ] {
+        let len = valid.len() + invalid.len() + valid.len();
+        check_ed25519_verify_batch(
+            &[valid.clone(), invalid.clone(), valid.clone()].concat(),
+            Ok(1),
+            map! {
+                ExtCosts::read_memory_base: 1,
+                ExtCosts::read_memory_byte: len as u64,
+                ExtCosts::ed25519_verify_byte: 32 + invalid.len() as u64 - 100,
+            },
+        );
+    }
+    // Malformed batches are rejected before verifying anything.
+    check_ed25519_verify_batch(
+        &[valid.clone(), valid[..99].to_vec()].concat(),
+        Err(HostError::Ed25519VerifyInvalidInput { msg: "truncated batch entry".to_string() }),
+        map! {
+            ExtCosts::read_memory_base: 1,
+            ExtCosts::read_memory_byte: 231,
+        },
+    );
+    check_ed25519_verify_batch(
+        &valid[..131],
+        Err(HostError::Ed25519VerifyInvalidInput { msg: "truncated batch message".to_string() }),
+        map! {
+            ExtCosts::read_memory_base: 1,
+            ExtCosts::read_memory_byte: 131,
+        },
+    );
+}
+
+#[test]
+fn test_ed25519_verify_batch_costs() {
+    let valid = batch_entry(&SIGNATURE, &PUBLIC_KEY, &MESSAGE);
+    let invalid = batch_entry(&BAD_SIGNATURE, &PUBLIC_KEY, &MESSAGE);
+    let mut logic_builder = VMLogicBuilder::free();
+    logic_builder.config.extra_ext_costs.ed25519_verify_batch_base = 1000;
+    logic_builder.config.extra_ext_costs.ed25519_verify_batch_element = 10;
+    // Each signature verified pays the element cost, up to the first invalid one.
+    let mut logic = logic_builder.build();
+    let batch = logic.internal_mem_write(&[valid.clone(), invalid, valid.clone()].concat());
+    assert_eq!(logic.ed25519_verify_batch(batch.len, batch.ptr), Ok(1));
+    assert_eq!(logic.gas_counter().burnt_gas(), 1020);
+
+    // The batch base is paid once, before the batch is read.
+    let mut logic = logic_builder.build();
+    assert_eq!(
+        logic.ed25519_verify_batch(valid.len() as u64, u64::MAX),
+        Err(HostError::MemoryAccessViolation.into())
+    );
+    assert_eq!(logic.gas_counter().burnt_gas(), 1000);
+}
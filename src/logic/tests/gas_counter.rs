@@ -1,10 +1,12 @@
 use crate::logic::tests::helpers::*;
 use crate::logic::tests::vm_logic_builder::{TestVMLogic, VMLogicBuilder};
 use crate::logic::types::Gas;
-use crate::logic::MemSlice;
+use crate::logic::{Config, MemSlice};
 use crate::logic::{HostError, VMLogicError};
 use crate::tests::test_vm_config;
+use crate::{ConsensusHooks, RunOptions};
 use expect_test::expect;
+use std::sync::Arc;
 use unc_parameters::{ActionCosts, ExtCosts, Fee};
 
 #[test]
@@ -136,6 +138,38 @@ fn function_call_no_weight_refund() {
     assert!(outcome.used_gas < gas_limit);
 }
 
+#[test]
+fn test_gas_breakdown() {
+    let gas_limit = 10u64.pow(14);
+
+    let mut logic_builder = VMLogicBuilder::default();
+    logic_builder.config.limit_config.max_gas_burnt = gas_limit;
+    logic_builder.context.prepaid_gas = gas_limit;
+    let mut logic = logic_builder.build();
+
+    let index = promise_create(&mut logic, b"rick.test", 0, gas_limit / 4)
+        .expect("should create a promise");
+    promise_batch_action_function_call(&mut logic, index, 0, gas_limit / 4)
+        .expect("should add action to receipt");
+    let outcome = logic.compute_outcome();
+
+    assert!(outcome.promises_gas > gas_limit / 2);
+    assert_eq!(outcome.burnt_gas + outcome.promises_gas, outcome.used_gas);
+    assert_eq!(outcome.used_gas + outcome.refunded_gas, gas_limit);
+}
+
+#[test]
+fn test_gas_breakdown_view() {
+    let mut logic_builder = VMLogicBuilder::view();
+    let mut logic = logic_builder.build();
+
+    logic.gas_opcodes(1).expect("should burn some gas");
+    let outcome = logic.compute_outcome();
+
+    assert_eq!(outcome.promises_gas, 0);
+    assert_eq!(outcome.refunded_gas, 0);
+}
+
 #[test]
 fn test_overflowing_burn_gas_with_promises_gas() {
     let gas_limit = 3 * 10u64.pow(14);
@@ -481,6 +515,58 @@ fn deploy_contract(logic: &mut TestVMLogic) -> Result<(), VMLogicError> {
     Ok(())
 }
 
+#[test]
+fn test_code_pricing_deploy_gas() {
+    #[derive(Debug)]
+    struct Pricing;
+
+    impl crate::CodePricing for Pricing {
+        fn call_gas(&self, _code_len: u64, _config: &Config) -> Gas {
+            0
+        }
+
+        fn deploy_gas(&self, code_len: u64, _config: &Config) -> Gas {
+            code_len * 1000
+        }
+    }
+
+    let burnt_gas = |options: &RunOptions| {
+        let mut logic_builder = VMLogicBuilder::default();
+        logic_builder.config.consensus_hooks = true;
+        let mut logic = logic_builder.build();
+        logic.apply_run_options(options).unwrap();
+        deploy_contract(&mut logic).unwrap();
+        logic.compute_outcome().burnt_gas
+    };
+    let consensus = ConsensusHooks { code_pricing: Some(Arc::new(Pricing)), ..Default::default() };
+    let options = RunOptions { consensus, ..RunOptions::default() };
+    assert_eq!(burnt_gas(&options), burnt_gas(&RunOptions::default()) + 26 * 1000);
+}
+
+#[test]
+fn test_execution_fingerprint() {
+    let fingerprint = |value: &[u8], options: &RunOptions| {
+        let mut logic_builder = VMLogicBuilder::default();
+        let mut logic = logic_builder.build();
+        logic.apply_run_options(options).unwrap();
+        let key = logic.internal_mem_write(b"key");
+        let value = logic.internal_mem_write(value);
+        logic.enter_host_function("storage_write", &[]);
+        logic.storage_write(key.len, key.ptr, value.len, value.ptr, 0).unwrap();
+        logic.enter_host_function("storage_read", &[]);
+        logic.storage_read(key.len, key.ptr, 0).unwrap();
+        logic.compute_outcome().execution_fingerprint
+    };
+    assert_eq!(fingerprint(b"value", &RunOptions::default()), None);
+    let options = RunOptions { execution_fingerprint: true, ..RunOptions::default() };
+    let value_fingerprint = fingerprint(b"value", &options);
+    assert!(value_fingerprint.is_some());
+    assert_eq!(fingerprint(b"value", &options), value_fingerprint);
+    // A longer value costs more to write, so the gas burnt before the host
+    // calls after the write differs.
+    assert_ne!(fingerprint(b"longer value", &options), value_fingerprint);
+}
+
 /// see longer comment above for how this test works
 #[test]
 fn out_of_gas_function_call_base() {
@@ -732,3 +818,49 @@ fn test_pk() -> Vec<u8> {
 fn write_test_pk(logic: &mut TestVMLogic) -> MemSlice {
     logic.internal_mem_write(&test_pk())
 }
+
+/// Burns `burnt` gas of `prepaid` with refunds of less than `min_refund_gas`
+/// burnt.
+fn outcome_with_min_refund(
+    prepaid: Gas,
+    burnt: Gas,
+    max_gas_burnt: Gas,
+    min_refund_gas: Gas,
+) -> crate::logic::VMOutcome {
+    let mut logic_builder = VMLogicBuilder::default();
+    logic_builder.config.limit_config.max_gas_burnt = max_gas_burnt;
+    logic_builder.config.min_refund_gas = min_refund_gas;
+    logic_builder.context.prepaid_gas = prepaid;
+    let mut logic = logic_builder.build();
+    logic.gas(burnt).unwrap();
+    logic.compute_outcome()
+}
+
+#[test]
+fn test_refund_dust_burnt() {
+    let outcome = outcome_with_min_refund(1_000_000, 999_000, 10u64.pow(15), 1_001);
+    assert_eq!(outcome.burnt_gas, 1_000_000);
+    assert_eq!(outcome.used_gas, 1_000_000);
+
+    // Refunds of the threshold are kept.
+    let outcome = outcome_with_min_refund(1_000_000, 999_000, 10u64.pow(15), 1_000);
+    assert_eq!(outcome.burnt_gas, 999_000);
+    assert_eq!(outcome.used_gas, 999_000);
+
+    // Without a threshold, nothing is burnt.
+    let outcome = outcome_with_min_refund(1_000_000, 999_999, 10u64.pow(15), 0);
+    assert_eq!(outcome.burnt_gas, 999_999);
+    assert_eq!(outcome.used_gas, 999_999);
+}
+
+#[test]
+fn test_refund_dust_under_max_gas_burnt() {
+    // Burning the dust would go over the limit, so it is refunded.
+    let outcome = outcome_with_min_refund(1_000_000, 999_000, 999_999, 1_001);
+    assert_eq!(outcome.burnt_gas, 999_000);
+    assert_eq!(outcome.used_gas, 999_000);
+
+    let outcome = outcome_with_min_refund(1_000_000, 999_000, 1_000_000, 1_001);
+    assert_eq!(outcome.burnt_gas, 1_000_000);
+    assert_eq!(outcome.used_gas, 1_000_000);
+}
@@ -0,0 +1,64 @@
+use crate::logic::tests::helpers::*;
+use crate::logic::tests::vm_logic_builder::VMLogicBuilder;
+use crate::logic::HostError;
+use unc_primitives_core::hash::CryptoHash;
+
+#[test]
+fn test_account_code_hash() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let code_hash = CryptoHash([7; 32]);
+    logic_builder.ext.code_hashes.insert("rick.test".parse().unwrap(), code_hash);
+    let mut logic = logic_builder.build();
+
+    let account_id = logic.internal_mem_write(b"morty.test");
+    assert_eq!(logic.account_code_hash(account_id.len, account_id.ptr, 0), Ok(0));
+    assert_eq!(logic.register_len(0), Ok(u64::MAX), "register must stay unused");
+
+    let account_id = logic.internal_mem_write(b"rick.test");
+    assert_eq!(logic.account_code_hash(account_id.len, account_id.ptr, 0), Ok(1));
+    logic.assert_read_register(&code_hash.0, 0);
+
+    let account_id = logic.internal_mem_write(&[0xff, 0xfe]);
+    assert_eq!(
+        logic.account_code_hash(account_id.len, account_id.ptr, 0),
+        Err(HostError::BadUTF8.into())
+    );
+}
+
+#[test]
+fn test_promise_batch_action_deploy_global_contract() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+    let index = promise_batch_create(&mut logic, "rick.test").expect("should create a promise");
+    let index_ptr = logic.internal_mem_write(&index.to_le_bytes()).ptr;
+    let code_hash = logic.internal_mem_write(&[7; 32]);
+
+    logic
+        .promise_batch_action_deploy_global_contract(123, code_hash.ptr)
+        .expect_err("shouldn't accept not existent promise index");
+    let non_receipt =
+        logic.promise_and(index_ptr, 1u64).expect("should create a non-receipt promise");
+    logic
+        .promise_batch_action_deploy_global_contract(non_receipt, code_hash.ptr)
+        .expect_err("shouldn't accept non-receipt promise index");
+
+    logic
+        .promise_batch_action_deploy_global_contract(index, code_hash.ptr)
+        .expect("should add an action to deploy the global contract");
+    expect_test::expect![[r#"
+        [
+          {
+            "CreateReceipt": {
+              "receipt_indices": [],
+              "receiver_id": "rick.test"
+            }
+          },
+          {
+            "DeployGlobalContract": {
+              "receipt_index": 0,
+              "code_hash": "US517G5965aydkZ46HS38QLi7UQiSojurfbQfKCELFx"
+            }
+          }
+        ]"#]]
+    .assert_eq(&serde_json::to_string_pretty(&logic_builder.ext.action_log).unwrap());
+}
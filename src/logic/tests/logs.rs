@@ -42,6 +42,30 @@ fn test_invalid_utf8() {
     });
 }
 
+#[test]
+fn test_log_decoding_and_emit_costs() {
+    let mut logic_builder = VMLogicBuilder::free();
+    logic_builder.config.extra_ext_costs.log_decoding_byte = 3;
+    logic_builder.config.extra_ext_costs.log_emit_byte = 10;
+    let mut logic = logic_builder.build();
+    let bytes = logic.internal_mem_write(b"hello");
+    logic.log_utf8(bytes.len, bytes.ptr).unwrap();
+    assert_eq!(logic.gas_counter().burnt_gas(), 5 * 3 + 5 * 10);
+
+    // Invalid messages only pay for their decoding.
+    let mut logic = logic_builder.build();
+    let bytes = logic.internal_mem_write(b"hell\x80");
+    assert_eq!(logic.log_utf8(bytes.len, bytes.ptr), Err(HostError::BadUTF8.into()));
+    assert_eq!(logic.gas_counter().burnt_gas(), 5 * 3);
+
+    let mut logic = logic_builder.build();
+    let mut utf16 = Vec::new();
+    append_utf16(&mut utf16, "abc");
+    let bytes = logic.internal_mem_write(&utf16);
+    logic.log_utf16(bytes.len, bytes.ptr).unwrap();
+    assert_eq!(logic.gas_counter().burnt_gas(), 6 * 3 + 3 * 10);
+}
+
 #[test]
 fn test_valid_null_terminated_utf8() {
     let mut logic_builder = VMLogicBuilder::default();
@@ -373,6 +397,30 @@ fn test_invalid_log_utf16() {
     });
 }
 
+#[test]
+fn test_odd_length_log_utf16() {
+    for log_decoding_cost in [false, true] {
+        let mut logic_builder = VMLogicBuilder::default();
+        logic_builder.config.log_decoding_cost = log_decoding_cost;
+        let mut logic = logic_builder.build();
+        let mut bytes = Vec::new();
+        append_utf16(&mut bytes, "abc");
+        let bytes = logic.internal_mem_write(&bytes[..5]);
+        let res = logic.log_utf16(bytes.len, bytes.ptr);
+        assert_eq!(res, Err(HostError::BadUTF16.into()));
+        let mut costs = map! {
+            ExtCosts::base: 1,
+            ExtCosts::read_memory_base: 1,
+            ExtCosts::read_memory_byte: bytes.len,
+            ExtCosts::utf16_decoding_base: 1,
+        };
+        if log_decoding_cost {
+            costs.insert(ExtCosts::utf16_decoding_byte, bytes.len);
+        }
+        assert_costs(costs);
+    }
+}
+
 #[test]
 fn test_valid_log_utf16_null_terminated_fail() {
     let mut logic_builder = VMLogicBuilder::default();
@@ -147,6 +147,7 @@ where
 }
 
 #[test]
+#[cfg(feature = "secp256k1")]
 fn test_ecrecover() {
     for EcrecoverTest { m, v, sig, mc, res } in from_slice::<'_, Vec<_>>(
         fs::read("src/logic/tests/ecrecover-tests.json").unwrap().as_slice(),
@@ -182,6 +183,106 @@ fn test_ecrecover() {
     }
 }
 
+#[cfg(feature = "secp256k1")]
+fn ecrecover_tests() -> Vec<EcrecoverTest> {
+    from_slice(fs::read("src/logic/tests/ecrecover-tests.json").unwrap().as_slice()).unwrap()
+}
+
+#[cfg(feature = "secp256k1")]
+fn compressed_key(key: &[u8; 64]) -> Vec<u8> {
+    [&[0x02 | (key[63] & 1)], &key[..32]].concat()
+}
+
+#[test]
+#[cfg(feature = "secp256k1")]
+fn test_ecrecover_compressed() {
+    for EcrecoverTest { m, v, sig, mc, res } in ecrecover_tests() {
+        let mut logic_builder = VMLogicBuilder::default();
+        let mut logic = logic_builder.build();
+        let m = logic.internal_mem_write(&m);
+        let sig = logic.internal_mem_write(&sig);
+
+        let b =
+            logic.ecrecover_compressed(m.len, m.ptr, sig.len, sig.ptr, v as _, mc as _, 1).unwrap();
+        assert_eq!(b, res.is_some() as u64);
+        if let Some(res) = res {
+            logic.assert_read_register(&compressed_key(&res), 1);
+        }
+        reset_costs_counter();
+    }
+}
+
+#[test]
+#[cfg(feature = "secp256k1")]
+fn test_ecrecover_batch() {
+    let tests = ecrecover_tests();
+    let entry = |test: &EcrecoverTest| [&test.m[..], &test.sig, &[test.v, test.mc as u8]].concat();
+    let valid: Vec<_> = tests.iter().filter(|test| test.res.is_some()).collect();
+    let invalid = tests.iter().find(|test| test.res.is_none()).unwrap();
+
+    for compressed in [0, 1] {
+        let mut logic_builder = VMLogicBuilder::default();
+        let mut logic = logic_builder.build();
+        let batch: Vec<u8> = valid.iter().flat_map(|test| entry(test)).collect();
+        let batch = logic.internal_mem_write(&batch);
+        assert_eq!(logic.ecrecover_batch(batch.len, batch.ptr, compressed, 1), Ok(u64::MAX));
+        let keys: Vec<u8> = valid
+            .iter()
+            .flat_map(|test| {
+                let key = test.res.unwrap();
+                if compressed == 1 {
+                    compressed_key(&key)
+                } else {
+                    key.to_vec()
+                }
+            })
+            .collect();
+        let num_keys = valid.len() as u64;
+        assert_costs(map! {
+            ExtCosts::read_memory_base: 1,
+            ExtCosts::read_memory_byte: batch.len,
+            ExtCosts::write_register_base: 1,
+            ExtCosts::write_register_byte: keys.len() as u64,
+            ExtCosts::ecrecover_base: num_keys,
+        });
+        logic.assert_read_register(&keys, 1);
+        reset_costs_counter();
+    }
+
+    // Recovery stops at the first signature which fails.
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+    let batch = [entry(valid[0]), entry(invalid), entry(valid[0])].concat();
+    let batch = logic.internal_mem_write(&batch);
+    assert_eq!(logic.ecrecover_batch(batch.len, batch.ptr, 0, 1), Ok(1));
+    logic.assert_read_register(&valid[0].res.unwrap(), 1);
+    reset_costs_counter();
+
+    // Malformed batches are rejected before recovering anything.
+    let mut bad_v = entry(valid[0]);
+    bad_v[96] = 4;
+    for (batch, compressed, msg) in [
+        (entry(valid[0])[..97].to_vec(), 0, "The length of the batch: 97, is not a multiple of 98"),
+        (entry(valid[0]), 2, "Compressed flag needs to be 0 or 1, but is instead 2"),
+        (bad_v, 0, "V recovery byte 0 through 3 are valid but was provided 4"),
+    ] {
+        let mut logic_builder = VMLogicBuilder::default();
+        let mut logic = logic_builder.build();
+        let batch = logic.internal_mem_write(&batch);
+        assert_eq!(
+            logic.ecrecover_batch(batch.len, batch.ptr, compressed, 1),
+            Err(crate::logic::VMLogicError::HostError(HostError::ECRecoverError {
+                msg: msg.to_string()
+            }))
+        );
+        assert_costs(map! {
+            ExtCosts::read_memory_base: 1,
+            ExtCosts::read_memory_byte: batch.len,
+        });
+        reset_costs_counter();
+    }
+}
+
 #[test]
 fn test_hash256_register() {
     let mut logic_builder = VMLogicBuilder::default();
@@ -395,3 +496,153 @@ fn test_contract_size_limit() {
         Err(HostError::ContractSizeExceeded { size: limit + 1, limit }.into())
     );
 }
+
+#[test]
+fn test_format_u128() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+
+    let value = logic.internal_mem_write(&u128::MAX.to_le_bytes());
+    logic.format_u128(value.ptr, 0).unwrap();
+    logic.assert_read_register(u128::MAX.to_string().as_bytes(), 0);
+
+    let value = logic.internal_mem_write(&0u128.to_le_bytes());
+    logic.format_u128(value.ptr, 0).unwrap();
+    logic.assert_read_register(b"0", 0);
+}
+
+#[test]
+fn test_format_fixed_point() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+
+    for (value, decimals, want) in [
+        (1234567u128, 3, "1234.567"),
+        (5, 3, "0.005"),
+        (1000, 3, "1.000"),
+        (42, 0, "42"),
+        (0, 2, "0.00"),
+    ] {
+        let value = logic.internal_mem_write(&value.to_le_bytes());
+        logic.format_fixed_point(value.ptr, decimals, 0).unwrap();
+        logic.assert_read_register(want.as_bytes(), 0);
+    }
+
+    let value = logic.internal_mem_write(&1u128.to_le_bytes());
+    assert_eq!(
+        logic.format_fixed_point(value.ptr, 39, 0),
+        Err(HostError::FormatDecimalsExceeded { decimals: 39, limit: 38 }.into())
+    );
+}
+
+#[test]
+fn test_validate_utf8() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+
+    let bytes = logic.internal_mem_write(b"ok \xc3\xb1 \xff tail");
+    assert_eq!(logic.validate_utf8(bytes.len, bytes.ptr), Ok(6));
+    assert_costs(map! {
+        ExtCosts::base: 1,
+        ExtCosts::read_memory_base: 1,
+        ExtCosts::read_memory_byte: bytes.len,
+        ExtCosts::utf8_decoding_base: 1,
+        ExtCosts::utf8_decoding_byte: bytes.len,
+    });
+
+    let bytes = logic.internal_mem_write("j ñ r'ø".as_bytes());
+    assert_eq!(logic.validate_utf8(bytes.len, bytes.ptr), Ok(u64::MAX));
+
+    // A sequence truncated at the end is reported at its start.
+    let bytes = logic.internal_mem_write(b"abc\xe2\x82");
+    assert_eq!(logic.validate_utf8(bytes.len, bytes.ptr), Ok(3));
+}
+
+#[test]
+fn test_u256_muldiv() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+
+    let u256 = |value: u128| [value.to_le_bytes(), [0; 16]].concat();
+    let a = logic.internal_mem_write(&[0xff; 32]);
+    let b = logic.internal_mem_write(&u256(3));
+    let denominator = logic.internal_mem_write(&u256(6));
+    let result = logic.internal_mem_write(&[0; 32]);
+    reset_costs_counter();
+    logic.u256_muldiv(a.ptr, b.ptr, denominator.ptr, 1, result.ptr).unwrap();
+    assert_costs(map! {
+        ExtCosts::read_memory_base: 3,
+        ExtCosts::read_memory_byte: 96,
+        ExtCosts::write_memory_base: 1,
+        ExtCosts::write_memory_byte: 32,
+    });
+    // (2^256 - 1) * 3 / 6 rounded up is 2^255.
+    let mut want = [0; 32];
+    want[31] = 0x80;
+    assert_eq!(logic.internal_mem_read(result.ptr, 32), want);
+
+    let zero = logic.internal_mem_write(&u256(0));
+    assert_eq!(
+        logic.u256_muldiv(a.ptr, b.ptr, zero.ptr, 0, result.ptr),
+        Err(HostError::WideMathInvalidInput { msg: "division by zero".to_string() }.into())
+    );
+    assert_eq!(
+        logic.u256_muldiv(a.ptr, b.ptr, denominator.ptr, 2, result.ptr),
+        Err(HostError::WideMathInvalidInput { msg: "unknown rounding mode 2".to_string() }.into())
+    );
+}
+
+#[test]
+fn test_u256_pow() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+
+    let mut two = [0; 32];
+    two[0] = 2;
+    let base = logic.internal_mem_write(&two);
+    let result = logic.internal_mem_write(&[0; 32]);
+    reset_costs_counter();
+    logic.u256_pow(base.ptr, 130, result.ptr).unwrap();
+    assert_costs(map! {
+        ExtCosts::read_memory_base: 1,
+        ExtCosts::read_memory_byte: 32,
+        ExtCosts::write_memory_base: 1,
+        ExtCosts::write_memory_byte: 32,
+    });
+    let mut want = [0; 32];
+    want[16] = 4;
+    assert_eq!(logic.internal_mem_read(result.ptr, 32), want);
+
+    assert_eq!(
+        logic.u256_pow(base.ptr, 256, result.ptr),
+        Err(HostError::WideMathInvalidInput {
+            msg: "the power does not fit in 256 bits".to_string()
+        }
+        .into())
+    );
+}
+
+#[test]
+fn test_u256_costs() {
+    let mut logic_builder = VMLogicBuilder::free();
+    logic_builder.config.extra_ext_costs.u256_muldiv_base = 7;
+    logic_builder.config.extra_ext_costs.u256_pow_base = 1000;
+    logic_builder.config.extra_ext_costs.u256_pow_bit = 10;
+    let mut logic = logic_builder.build();
+    let one = logic.internal_mem_write(&[[1].as_slice(), &[0; 31]].concat());
+    logic.u256_muldiv(one.ptr, one.ptr, one.ptr, 0, one.ptr).unwrap();
+    assert_eq!(logic.gas_counter().burnt_gas(), 7);
+
+    // An exponent of 130 has 8 bits, paid before the base is read.
+    let mut logic = logic_builder.build();
+    let one = logic.internal_mem_write(&[[1].as_slice(), &[0; 31]].concat());
+    logic.u256_pow(one.ptr, 130, one.ptr).unwrap();
+    assert_eq!(logic.gas_counter().burnt_gas(), 1080);
+    let mut logic = logic_builder.build();
+    assert_eq!(logic.u256_pow(u64::MAX, u64::MAX, 0), Err(HostError::MemoryAccessViolation.into()));
+    assert_eq!(logic.gas_counter().burnt_gas(), 1640);
+    let mut logic = logic_builder.build();
+    let one = logic.internal_mem_write(&[[1].as_slice(), &[0; 31]].concat());
+    logic.u256_pow(one.ptr, 0, one.ptr).unwrap();
+    assert_eq!(logic.gas_counter().burnt_gas(), 1000);
+}
@@ -1,13 +1,20 @@
+#[cfg(feature = "bn128")]
 mod alt_bn128;
+#[cfg(feature = "protocol_feature_bls12381")]
+mod bls12381;
 mod context;
+#[cfg(feature = "ed25519")]
 mod ed25519_verify;
 mod gas_counter;
+mod global_contracts;
 pub(crate) mod helpers;
 mod iterators;
 mod logs;
 mod miscs;
 mod promises;
 mod registers;
+mod scratch;
+mod storage_buffer;
 mod storage_read_write;
 mod storage_usage;
 mod view_method;
@@ -1,9 +1,12 @@
 use crate::logic::mocks::mock_external::MockedExternal;
 use crate::logic::tests::helpers::*;
 use crate::logic::tests::vm_logic_builder::VMLogicBuilder;
-use crate::logic::types::PromiseResult;
+use crate::logic::gas_planning::{self, ActionGas};
+use crate::logic::types::{ActionReceipt, Gas, PromiseResult, ReceiptAction, ReceiptCost};
 
+use crate::RunOptions;
 use unc_crypto::PublicKey;
+use unc_parameters::ActionCosts;
 use serde_json;
 
 fn vm_receipts<'a>(ext: &'a MockedExternal) -> Vec<impl serde::Serialize + 'a> {
@@ -146,6 +149,27 @@ fn test_promise_batch_action_function_call() {
     .assert_eq(&serde_json::to_string_pretty(&vm_receipts(&logic_builder.ext)).unwrap());
 }
 
+#[test]
+fn test_promise_create_charges_planned_gas() {
+    use crate::logic::gas_planning::{plan_call_chain, PlannedCall};
+
+    let mut logic_builder = VMLogicBuilder::default();
+    logic_builder.config.make_free();
+    let fees_config = logic_builder.fees_config.clone();
+    let current_account_id = logic_builder.context.current_account_id.clone();
+    let mut logic = logic_builder.build();
+    let call = PlannedCall {
+        receiver_id: "rick.test".parse().unwrap(),
+        method_name: "promise_create".to_string(),
+        args_len: 4,
+        execution_gas: 1_000,
+    };
+    let hops = plan_call_chain(&fees_config, &current_account_id, &[call]).unwrap();
+    promise_create(&mut logic, b"rick.test", 0, hops[0].attached_gas).unwrap();
+    assert_eq!(logic.gas_counter().burnt_gas(), hops[0].scheduling.burnt);
+    assert_eq!(logic.used_gas().unwrap(), hops[0].scheduling.used + hops[0].attached_gas);
+}
+
 #[test]
 fn test_promise_batch_action_create_account() {
     let mut logic_builder = VMLogicBuilder::default();
@@ -630,3 +654,103 @@ fn test_promise_batch_then() {
         ]"#]]
     .assert_eq(&serde_json::to_string_pretty(&vm_receipts(&logic_builder.ext)).unwrap());
 }
+
+#[test]
+fn test_outcome_receipts() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+    logic
+        .apply_run_options(&RunOptions { record_receipts: true, ..RunOptions::default() })
+        .unwrap();
+
+    let first = promise_create(&mut logic, b"rick.test", 0, 0).expect("should create a promise");
+    let second = promise_batch_create(&mut logic, "morty.test").expect("should create a promise");
+    let first_ptr = logic.internal_mem_write(&first.to_le_bytes()).ptr;
+    let joined = logic.promise_and(first_ptr, 1).expect("should join the promises");
+    let account_id = logic.internal_mem_write(b"bob.test");
+    let callback = logic
+        .promise_batch_then(joined, account_id.len, account_id.ptr)
+        .expect("should create a callback");
+    let amount = logic.internal_mem_write(&10u128.to_le_bytes());
+    logic.promise_batch_action_transfer(callback, amount.ptr).expect("should add a transfer");
+    // Actions are recorded on their receipt whatever the receipts created
+    // since.
+    logic.promise_batch_action_create_account(second).expect("should add an action");
+
+    let receipts = logic.compute_outcome().receipts;
+    assert_eq!(
+        receipts,
+        [
+            ActionReceipt {
+                receipt_index: 0,
+                receiver_id: "rick.test".parse().unwrap(),
+                dependencies: vec![],
+                actions: vec![ReceiptAction::FunctionCall {
+                    method_name: b"promise_create".to_vec(),
+                    args: b"args".to_vec(),
+                    attached_deposit: 0,
+                    prepaid_gas: 0,
+                    gas_weight: 0,
+                }],
+            },
+            ActionReceipt {
+                receipt_index: 1,
+                receiver_id: "morty.test".parse().unwrap(),
+                dependencies: vec![],
+                actions: vec![ReceiptAction::CreateAccount],
+            },
+            ActionReceipt {
+                receipt_index: 2,
+                receiver_id: "bob.test".parse().unwrap(),
+                dependencies: vec![0],
+                actions: vec![ReceiptAction::Transfer { deposit: 10 }],
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_outcome_receipt_costs() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let fees = logic_builder.fees_config.clone();
+    let mut logic = logic_builder.build();
+
+    let call = promise_create(&mut logic, b"rick.test", 5, 1000).expect("should create a promise");
+    let amount = logic.internal_mem_write(&10u128.to_le_bytes());
+    logic.promise_batch_action_transfer(call, amount.ptr).expect("should add a transfer");
+    let batch = promise_batch_create(&mut logic, "morty.test").expect("should create a promise");
+    logic.promise_batch_action_create_account(batch).expect("should add an action");
+
+    let cost = |receipt_index, gases: &[ActionGas], prepaid_gas, deposit| {
+        let gas =
+            gases.iter().fold(ActionGas::default(), |sum, gas| sum.checked_add(*gas).unwrap());
+        ReceiptCost {
+            receipt_index,
+            send_gas: gas.burnt,
+            exec_gas: gas.used - gas.burnt,
+            prepaid_gas,
+            gas_weight: 0,
+            deposit,
+        }
+    };
+    let receipt = gas_planning::new_receipt_gas(&fees, false, &[]).unwrap();
+    let function_call = gas_planning::function_call_gas(&fees, false, 14, 4).unwrap();
+    let transfer = gas_planning::action_base_gas(&fees, ActionCosts::transfer, false).unwrap();
+    let create_account =
+        gas_planning::action_base_gas(&fees, ActionCosts::create_account, false).unwrap();
+    let outcome = logic.compute_outcome();
+    assert_eq!(
+        outcome.receipt_costs,
+        [
+            cost(0, &[receipt, function_call, transfer], 1000, 15),
+            cost(1, &[receipt, create_account], 0, 0),
+        ]
+    );
+    let total_gas: Gas = outcome.receipt_costs.iter().map(ReceiptCost::total_gas).sum();
+    let action_gas = receipt.checked_add(receipt).unwrap();
+    let action_gas = [function_call, transfer, create_account]
+        .into_iter()
+        .fold(action_gas, |sum, gas| sum.checked_add(gas).unwrap());
+    assert_eq!(total_gas, action_gas.used + 1000);
+    assert_eq!(outcome.promises_gas, action_gas.used - action_gas.burnt + 1000);
+}
@@ -86,3 +86,36 @@ fn test_register_is_not_used() {
     let mut logic = logic_builder.build();
     assert_eq!(logic.register_len(0), Ok(u64::MAX));
 }
+
+#[test]
+fn test_read_register_slice() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+
+    logic.wrapped_internal_write_register(0, b"0123456789").unwrap();
+    logic.read_register_slice(0, 3, 4, 0).unwrap();
+    assert_eq!(logic.internal_mem_read(0, 4), b"3456");
+    logic.read_register_slice(0, 10, 0, 0).unwrap();
+
+    assert_eq!(logic.read_register_slice(0, 8, 3, 0), Err(HostError::MemoryAccessViolation.into()));
+    assert_eq!(
+        logic.read_register_slice(0, u64::MAX, 2, 0),
+        Err(HostError::MemoryAccessViolation.into())
+    );
+    assert_eq!(
+        logic.read_register_slice(1, 0, 0, 0),
+        Err(HostError::InvalidRegisterId { register_id: 1 }.into())
+    );
+}
+
+#[test]
+fn test_input_shared_by_registers() {
+    let mut logic_builder = VMLogicBuilder::default();
+    logic_builder.context.input = b"input".to_vec();
+    let mut logic = logic_builder.build();
+
+    logic.input(0).unwrap();
+    logic.input(1).unwrap();
+    logic.assert_read_register(b"input", 0);
+    logic.assert_read_register(b"input", 1);
+}
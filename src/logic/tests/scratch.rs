@@ -0,0 +1,80 @@
+use crate::logic::tests::helpers::*;
+use crate::logic::tests::vm_logic_builder::VMLogicBuilder;
+use crate::logic::HostError;
+
+#[test]
+fn test_scratch_read() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+    assert_eq!(logic.scratch_read(0), Ok(0), "no scratch area must return 0");
+    assert_eq!(logic.register_len(0), Ok(u64::MAX), "register must stay unused");
+
+    let mut logic_builder = VMLogicBuilder::default();
+    logic_builder.ext.scratch = Some(b"cursor:42".to_vec());
+    let mut logic = logic_builder.build();
+    assert_eq!(logic.scratch_read(0), Ok(1));
+    logic.assert_read_register(b"cursor:42", 0);
+}
+
+#[test]
+fn test_promise_batch_scratch_write() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let mut logic = logic_builder.build();
+    let index = promise_batch_create(&mut logic, "rick.test").expect("should create a promise");
+    let index_ptr = logic.internal_mem_write(&index.to_le_bytes()).ptr;
+    let data = logic.internal_mem_write(b"cursor:42");
+
+    logic
+        .promise_batch_scratch_write(123, data.len, data.ptr)
+        .expect_err("shouldn't accept not existent promise index");
+    let non_receipt =
+        logic.promise_and(index_ptr, 1u64).expect("should create a non-receipt promise");
+    logic
+        .promise_batch_scratch_write(non_receipt, data.len, data.ptr)
+        .expect_err("shouldn't accept non-receipt promise index");
+
+    logic
+        .promise_batch_scratch_write(index, data.len, data.ptr)
+        .expect("should attach the scratch area to receipt");
+    expect_test::expect![[r#"
+        [
+          {
+            "CreateReceipt": {
+              "receipt_indices": [],
+              "receiver_id": "rick.test"
+            }
+          },
+          {
+            "AttachScratch": {
+              "receipt_index": 0,
+              "data": [
+                99,
+                117,
+                114,
+                115,
+                111,
+                114,
+                58,
+                52,
+                50
+              ]
+            }
+          }
+        ]"#]]
+    .assert_eq(&serde_json::to_string_pretty(&logic_builder.ext.action_log).unwrap());
+}
+
+#[test]
+fn test_promise_batch_scratch_write_limit() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let limit = 10;
+    logic_builder.config.limit_config.max_arguments_length = limit;
+    let mut logic = logic_builder.build();
+    let index = promise_batch_create(&mut logic, "rick.test").expect("should create a promise");
+    let data = logic.internal_mem_write(&[0; 11]);
+
+    assert_eq!(
+        logic.promise_batch_scratch_write(index, data.len, data.ptr),
+        Err(HostError::ScratchLengthExceeded { length: 11, limit }.into())
+    );
+}
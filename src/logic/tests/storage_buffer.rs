@@ -0,0 +1,67 @@
+use crate::logic::errors::FunctionCallError;
+use super::TestVMLogic;
+use crate::logic::tests::vm_logic_builder::VMLogicBuilder;
+use crate::logic::{HostError, VMOutcome};
+
+/// Writes, overwrites and removes a few keys and reads them back, returning
+/// the results of the host functions.
+fn exercise(logic: &mut TestVMLogic) -> Vec<u64> {
+    let a = logic.internal_mem_write(b"a");
+    let b = logic.internal_mem_write(b"b");
+    let c = logic.internal_mem_write(b"c");
+    let one = logic.internal_mem_write(b"one");
+    let two = logic.internal_mem_write(b"two");
+    vec![
+        logic.storage_write(a.len, a.ptr, one.len, one.ptr, 0).unwrap(),
+        logic.storage_write(a.len, a.ptr, two.len, two.ptr, 0).unwrap(),
+        logic.storage_read(a.len, a.ptr, 1).unwrap(),
+        logic.storage_write(b.len, b.ptr, one.len, one.ptr, 0).unwrap(),
+        logic.storage_remove(b.len, b.ptr, 2).unwrap(),
+        logic.storage_has_key(b.len, b.ptr).unwrap(),
+        logic.storage_remove(c.len, c.ptr, 0).unwrap(),
+        logic.storage_has_key(c.len, c.ptr).unwrap(),
+    ]
+}
+
+#[test]
+fn test_buffered_writes_match_direct_ones() {
+    let mut direct_builder = VMLogicBuilder::default();
+    direct_builder.ext.fake_trie.insert(b"c".to_vec(), b"old".to_vec());
+    let mut direct = direct_builder.build();
+    let direct_results = exercise(&mut direct);
+    let direct = VMOutcome::try_ok(direct.into_logic()).unwrap();
+
+    let mut logic_builder = VMLogicBuilder::default();
+    logic_builder.ext.fake_trie.insert(b"c".to_vec(), b"old".to_vec());
+    logic_builder.config.buffer_storage_writes = true;
+    let mut logic = logic_builder.build();
+    let results = exercise(&mut logic);
+    logic.assert_read_register(b"two", 1);
+    logic.assert_read_register(b"one", 2);
+    let outcome = VMOutcome::try_ok(logic.into_logic()).unwrap();
+
+    assert_eq!(results, direct_results);
+    assert_eq!(results, [0, 1, 1, 0, 1, 0, 1, 0]);
+    assert_eq!(outcome.burnt_gas, direct.burnt_gas);
+    assert_eq!(outcome.storage_usage, direct.storage_usage);
+    assert_eq!(outcome.storage_delta, direct.storage_delta);
+    assert_eq!(logic_builder.ext.fake_trie, direct_builder.ext.fake_trie);
+    assert_eq!(logic_builder.ext.fake_trie.len(), 1);
+    assert_eq!(logic_builder.ext.fake_trie[&b"a".to_vec()], b"two");
+}
+
+#[test]
+fn test_buffered_writes_are_discarded_on_abort() {
+    let mut logic_builder = VMLogicBuilder::default();
+    logic_builder.ext.fake_trie.insert(b"c".to_vec(), b"old".to_vec());
+    let original = logic_builder.ext.fake_trie.clone();
+    logic_builder.config.buffer_storage_writes = true;
+    let mut logic = logic_builder.build();
+    exercise(&mut logic);
+    let error = FunctionCallError::HostError(HostError::GuestPanic {
+        panic_msg: "explicit guest panic".to_string(),
+    });
+    let outcome = VMOutcome::abort(logic.into_logic(), error);
+    assert!(outcome.aborted.is_some());
+    assert_eq!(logic_builder.ext.fake_trie, original);
+}
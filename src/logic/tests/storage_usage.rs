@@ -1,4 +1,5 @@
 use crate::logic::tests::vm_logic_builder::VMLogicBuilder;
+use crate::logic::StorageBytes;
 
 #[test]
 fn test_storage_write_counter() {
@@ -32,3 +33,42 @@ fn test_storage_remove() {
 
     assert_eq!(logic.storage_usage().unwrap(), 0u64);
 }
+
+#[test]
+fn test_storage_delta() {
+    let mut logic_builder = VMLogicBuilder::default();
+    let record = logic_builder.fees_config.storage_usage_config.num_extra_bytes_record;
+    let mut logic = logic_builder.build();
+    let key_a = logic.internal_mem_write(b"a:1");
+    let key_b = logic.internal_mem_write(b"b:1");
+    let val = logic.internal_mem_write(b"value");
+    let short = logic.internal_mem_write(b"v");
+
+    logic.storage_write(key_a.len, key_a.ptr, val.len, val.ptr, 0).expect("storage write ok");
+    logic.storage_write(key_a.len, key_a.ptr, short.len, short.ptr, 0).expect("storage write ok");
+    logic.storage_write(key_b.len, key_b.ptr, val.len, val.ptr, 0).expect("storage write ok");
+    logic.storage_remove(key_b.len, key_b.ptr, 0).expect("storage remove ok");
+
+    let delta = logic.compute_outcome().storage_delta;
+    let new_record = 3 + 5 + record;
+    let key_a = StorageBytes { added: new_record + 1, removed: 5 };
+    let key_b = StorageBytes { added: new_record, removed: new_record };
+    assert_eq!(delta.total, StorageBytes { added: 2 * new_record + 1, removed: new_record + 5 });
+    assert_eq!(delta.total.delta(), i128::from(3 + 1 + record));
+    assert_eq!(
+        delta.by_key,
+        Some([(b"a:1".to_vec(), key_a), (b"b:1".to_vec(), key_b)].into_iter().collect())
+    );
+    assert_eq!(
+        delta.by_prefix(1),
+        Some([(b"a".to_vec(), key_a), (b"b".to_vec(), key_b)].into_iter().collect())
+    );
+    assert_eq!(
+        delta.by_prefix(0),
+        Some(
+            [(Vec::new(), StorageBytes { added: 2 * new_record + 1, removed: new_record + 5 })]
+                .into_iter()
+                .collect()
+        )
+    );
+}
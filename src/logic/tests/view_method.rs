@@ -24,6 +24,7 @@ fn test_prohibited_view_methods() {
     test_prohibited!(promise_batch_then, 0, 0, 0);
     test_prohibited!(promise_batch_action_create_account, 0);
     test_prohibited!(promise_batch_action_deploy_contract, 0, 0, 0);
+    test_prohibited!(promise_batch_action_deploy_global_contract, 0, 0);
     test_prohibited!(promise_batch_action_function_call, 0, 0, 0, 0, 0, 0, 0);
     test_prohibited!(promise_batch_action_transfer, 0, 0);
     test_prohibited!(promise_batch_action_stake, 0, 0, 0, 0);
@@ -34,6 +35,8 @@ fn test_prohibited_view_methods() {
     test_prohibited!(promise_results_count);
     test_prohibited!(promise_result, 0, 0);
     test_prohibited!(promise_return, 0);
+    test_prohibited!(scratch_read, 0);
+    test_prohibited!(promise_batch_scratch_write, 0, 0, 0);
     test_prohibited!(storage_write, 0, 0, 0, 0, 0);
     test_prohibited!(storage_remove, 0, 0, 0);
 }
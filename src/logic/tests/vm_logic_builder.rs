@@ -1,3 +1,4 @@
+use crate::logic::mocks::mock_context::{get_context, get_view_context};
 use crate::logic::mocks::mock_external::MockedExternal;
 use crate::logic::mocks::mock_memory::MockedMemory;
 use crate::logic::types::PromiseResult;
@@ -30,9 +31,7 @@ impl Default for VMLogicBuilder {
 impl VMLogicBuilder {
     pub fn view() -> Self {
         let mut builder = Self::default();
-        let max_gas_burnt = builder.config.limit_config.max_gas_burnt;
-        builder.context.view_config =
-            Some(unc_primitives_core::config::ViewConfig { max_gas_burnt });
+        builder.context = get_view_context(builder.config.limit_config.max_gas_burnt);
         builder
     }
 
@@ -64,27 +63,6 @@ impl VMLogicBuilder {
     }
 }
 
-fn get_context() -> VMContext {
-    VMContext {
-        current_account_id: "alice.near".parse().unwrap(),
-        signer_account_id: "bob.near".parse().unwrap(),
-        signer_account_pk: vec![0, 1, 2, 3, 4],
-        predecessor_account_id: "carol.near".parse().unwrap(),
-        input: vec![0, 1, 2, 3, 4],
-        block_height: 10,
-        block_timestamp: 42,
-        epoch_height: 1,
-        account_balance: 100,
-        storage_usage: 0,
-        account_locked_balance: 50,
-        attached_deposit: 10,
-        prepaid_gas: 10u64.pow(14),
-        random_seed: vec![0, 1, 2],
-        view_config: None,
-        output_data_receivers: vec![],
-    }
-}
-
 /// Wrapper around `VMLogic` which adds helper test methods.
 pub(super) struct TestVMLogic<'a> {
     logic: VMLogic<'a>,
@@ -111,7 +89,7 @@ impl std::ops::DerefMut for TestVMLogic<'_> {
     }
 }
 
-impl TestVMLogic<'_> {
+impl<'a> TestVMLogic<'a> {
     /// Writes data into guest memory and returns pointer at its location.
     ///
     /// Subsequent calls to the method write buffers one after the other.  It
@@ -156,4 +134,8 @@ impl TestVMLogic<'_> {
     pub fn compute_outcome(self) -> crate::logic::VMOutcome {
         self.logic.compute_outcome()
     }
+
+    pub fn into_logic(self) -> VMLogic<'a> {
+        self.logic
+    }
 }
@@ -1,14 +1,24 @@
 pub use unc_primitives_core::types::*;
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde_with::base64::Base64;
+use serde_with::serde_as;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use unc_primitives_core::hash::CryptoHash;
+use unc_primitives_core::serialize::dec_format;
 
 pub type PublicKey = Vec<u8>;
 pub type PromiseIndex = u64;
 pub type ReceiptIndex = u64;
 pub type IteratorIndex = u64;
 
-#[derive(Debug, PartialEq, Clone)]
+#[serde_as]
+#[derive(
+    Debug, PartialEq, Clone, BorshSerialize, BorshDeserialize, serde::Serialize, serde::Deserialize,
+)]
 pub enum ReturnData {
     /// Method returned some value or data.
-    Value(Vec<u8>),
+    Value(#[serde_as(as = "Base64")] Vec<u8>),
 
     /// The return value of the method should be taken from the return value of another method
     /// identified through receipt index.
@@ -30,10 +40,318 @@ impl ReturnData {
 
 /// When there is a callback attached to one or more contract calls the execution results of these
 /// calls are available to the contract invoked through the callback.
-#[derive(Debug, PartialEq)]
+#[serde_as]
+#[derive(
+    Debug, PartialEq, BorshSerialize, BorshDeserialize, serde::Serialize, serde::Deserialize,
+)]
 pub enum PromiseResult {
     /// Current version of the protocol never returns `PromiseResult::NotReady`.
     NotReady,
-    Successful(Vec<u8>),
+    Successful(#[serde_as(as = "Base64")] Vec<u8>),
     Failed,
 }
+
+/// An action receipt created by a call, see [`super::VMOutcome::receipts`].
+#[derive(
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct ActionReceipt {
+    /// Index the [`super::External`] gave to the receipt.
+    pub receipt_index: ReceiptIndex,
+    pub receiver_id: AccountId,
+    /// Receipts whose results are the promise results of this one, in the
+    /// order of the results.
+    pub dependencies: Vec<ReceiptIndex>,
+    /// Actions in the order they are executed.
+    pub actions: Vec<ReceiptAction>,
+}
+
+/// An action of an [`ActionReceipt`].
+#[serde_as]
+#[derive(
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub enum ReceiptAction {
+    CreateAccount,
+    DeployContract {
+        code_hash: CryptoHash,
+    },
+    FunctionCall {
+        #[serde_as(as = "Base64")]
+        method_name: Vec<u8>,
+        #[serde_as(as = "Base64")]
+        args: Vec<u8>,
+        #[serde(with = "dec_format")]
+        attached_deposit: Balance,
+        prepaid_gas: Gas,
+        gas_weight: u64,
+    },
+    Transfer {
+        #[serde(with = "dec_format")]
+        deposit: Balance,
+    },
+    Stake {
+        #[serde(with = "dec_format")]
+        stake: Balance,
+        public_key: unc_crypto::PublicKey,
+    },
+    AddFullAccessKey {
+        public_key: unc_crypto::PublicKey,
+        nonce: Nonce,
+    },
+    AddFunctionCallKey {
+        public_key: unc_crypto::PublicKey,
+        nonce: Nonce,
+        #[serde(with = "dec_format")]
+        allowance: Option<Balance>,
+        receiver_id: AccountId,
+        #[serde_as(as = "Vec<Base64>")]
+        method_names: Vec<Vec<u8>>,
+    },
+    DeleteKey {
+        public_key: unc_crypto::PublicKey,
+    },
+    DeleteAccount {
+        beneficiary_id: AccountId,
+    },
+    /// Deploys the global contract of hash `code_hash` on the receiver.
+    DeployGlobalContract {
+        code_hash: CryptoHash,
+    },
+}
+
+/// Gas and tokens a call attached to a receipt it created, see
+/// [`super::VMOutcome::receipt_costs`].
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct ReceiptCost {
+    pub receipt_index: ReceiptIndex,
+    /// Send fees of the receipt, of its data dependencies and of its actions,
+    /// burnt by the call.
+    pub send_gas: Gas,
+    /// Exec fees of the receipt and of its actions, prepaid by the call for
+    /// their execution.
+    pub exec_gas: Gas,
+    /// Gas attached to the function calls of the receipt, without the unused
+    /// gas the runtime distributes to them afterwards.
+    pub prepaid_gas: Gas,
+    /// Sum of the gas weights of the function calls of the receipt.
+    pub gas_weight: u64,
+    /// Tokens attached to the function calls of the receipt and transferred
+    /// by it.
+    #[serde(with = "dec_format")]
+    pub deposit: Balance,
+}
+
+impl ReceiptCost {
+    /// Gas of the call spent on the receipt: its fees and the gas attached to
+    /// it.
+    pub fn total_gas(&self) -> Gas {
+        self.send_gas.saturating_add(self.exec_gas).saturating_add(self.prepaid_gas)
+    }
+}
+
+/// Bytes of storage usage added and removed, see [`StorageUsageDelta`].
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct StorageBytes {
+    pub added: StorageUsage,
+    pub removed: StorageUsage,
+}
+
+impl StorageBytes {
+    /// Net change of the storage usage, negative if the storage shrank.
+    pub fn delta(&self) -> i128 {
+        i128::from(self.added) - i128::from(self.removed)
+    }
+
+    fn add(&mut self, other: StorageBytes) {
+        self.added += other.added;
+        self.removed += other.removed;
+    }
+}
+
+/// How a call changed the storage usage of the account, see
+/// [`super::VMOutcome::storage_delta`].
+///
+/// Overwriting a value counts the old value as removed and the new one as
+/// added.  Writing a new key or removing one also counts the key and the
+/// extra bytes of the record.
+#[serde_as]
+#[derive(
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct StorageUsageDelta {
+    pub total: StorageBytes,
+    /// The bytes added and removed by the writes and removals of each key,
+    /// only collected with the `storage_attribution` feature.
+    #[serde_as(as = "Option<BTreeMap<Base64, _>>")]
+    pub by_key: Option<BTreeMap<Vec<u8>, StorageBytes>>,
+}
+
+impl StorageUsageDelta {
+    pub(crate) fn new() -> Self {
+        let by_key = cfg!(any(test, feature = "storage_attribution")).then(BTreeMap::new);
+        Self { total: StorageBytes::default(), by_key }
+    }
+
+    pub(crate) fn record(&mut self, key: &[u8], bytes: StorageBytes) {
+        self.total.add(bytes);
+        if let Some(by_key) = &mut self.by_key {
+            by_key.entry(key.to_vec()).or_default().add(bytes);
+        }
+    }
+
+    /// Sums [`Self::by_key`] over the first `prefix_len` bytes of the keys,
+    /// e.g. to attribute the storage to the collections of a contract.
+    pub fn by_prefix(&self, prefix_len: usize) -> Option<BTreeMap<Vec<u8>, StorageBytes>> {
+        let mut by_prefix = BTreeMap::<Vec<u8>, StorageBytes>::new();
+        for (key, bytes) in self.by_key.as_ref()? {
+            let prefix = &key[..key.len().min(prefix_len)];
+            by_prefix.entry(prefix.to_vec()).or_default().add(*bytes);
+        }
+        Some(by_prefix)
+    }
+}
+
+/// State of a call when the contract called a host function, see
+/// [`crate::RunOptions::record_checkpoints`].
+#[derive(
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct HostCallCheckpoint {
+    pub host_function: Cow<'static, str>,
+    pub burnt_gas: Gas,
+    pub used_gas: Gas,
+    /// Hash of the ids and values of the registers.
+    pub registers: CryptoHash,
+}
+
+/// A host function call of the contract, see
+/// [`crate::RunOptions::record_host_calls`].
+#[derive(
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct HostCallRecord {
+    pub host_function: Cow<'static, str>,
+    /// The arguments, widened to `u64`.
+    pub args: Vec<u64>,
+    /// The values returned, or the error the call failed with.  `None` if
+    /// the host function did not return, e.g. because it panicked.
+    pub result: Option<Result<Vec<u64>, String>>,
+    pub burnt_gas_before: Gas,
+    pub used_gas_before: Gas,
+    pub burnt_gas_after: Gas,
+    pub used_gas_after: Gas,
+}
+
+/// Memory used by a call with a
+/// [`crate::logic::ExtraLimitConfig::memory_cap`], see
+/// [`crate::logic::VMOutcome::used_memory`].
+///
+/// The linear memory of the contract is measured each time it calls a host
+/// function, so the peaks miss the memory it grows after its last host call.
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+pub struct UsedMemory {
+    /// Largest size of the linear memory, in wasm pages of 64KiB.
+    pub peak_memory_pages: u64,
+    /// Largest number of bytes held by the host for the contract in
+    /// registers, logs and receipts.
+    ///
+    /// Registers count their value plus eight bytes, as for the register
+    /// memory limit of the protocol, and receipts the borsh size of their
+    /// receiver, dependencies and actions.
+    pub peak_host_bytes: u64,
+    /// Largest sum of the linear memory and the host bytes, which
+    /// [`crate::logic::ExtraLimitConfig::memory_cap`] limits.
+    pub peak_total_bytes: u64,
+}
+
+/// Values returned by a host function, as recorded in a [`HostCallRecord`].
+pub trait HostCallValues {
+    fn to_values(&self) -> Vec<u64>;
+}
+
+impl HostCallValues for () {
+    fn to_values(&self) -> Vec<u64> {
+        Vec::new()
+    }
+}
+
+impl HostCallValues for u64 {
+    fn to_values(&self) -> Vec<u64> {
+        vec![*self]
+    }
+}
+
+impl HostCallValues for Option<u64> {
+    fn to_values(&self) -> Vec<u64> {
+        self.iter().copied().collect()
+    }
+}
@@ -3,9 +3,11 @@ use super::errors::{HostError, VMLogicError};
 use super::gas_counter::GasCounter;
 use core::mem::size_of;
 use unc_parameters::vm::LimitConfig;
+use unc_primitives_core::hash::CryptoHash;
 use unc_parameters::ExtCosts::*;
 use std::borrow::Cow;
 use std::collections::hash_map::Entry;
+use std::sync::Arc;
 
 type Result<T> = ::std::result::Result<T, VMLogicError>;
 
@@ -67,6 +69,11 @@ impl<'a> Memory<'a> {
         self.0.view_memory(slice).map_err(|_| HostError::MemoryAccessViolation.into())
     }
 
+    /// Current size of the guest memory in bytes.
+    pub(super) fn data_size(&self) -> u64 {
+        self.0.data_size()
+    }
+
     /// Like [`Self::view`] but does not pay gas fees.
     pub(super) fn view_for_free(&self, slice: MemSlice) -> Result<Cow<[u8]>> {
         self.0.view_memory(slice).map_err(|_| HostError::MemoryAccessViolation.into())
@@ -97,6 +104,17 @@ impl<'a> Memory<'a> {
         self.0.write_memory(offset, buf).map_err(|_| HostError::MemoryAccessViolation.into())
     }
 
+    /// Reads a 256-bit little-endian integer from guest memory.
+    pub(super) fn get_u256(
+        &mut self,
+        gas_counter: &mut GasCounter,
+        offset: u64,
+    ) -> Result<[u8; 32]> {
+        let mut array = [0u8; 32];
+        self.get_into(gas_counter, offset, &mut array)?;
+        Ok(array)
+    }
+
     memory_get!(u128, get_u128);
     memory_get!(u32, get_u32);
     memory_get!(u16, get_u16);
@@ -111,10 +129,13 @@ impl<'a> Memory<'a> {
 /// usage.
 ///
 /// See documentation of [`Memory`] for more motivation for this struct.
+///
+/// Values are reference counted, so that a value the host already holds can be
+/// put into a register, and registers cloned, without copying the data.
 #[derive(Default, Clone)]
 pub(super) struct Registers {
     /// Values of each existing register.
-    registers: std::collections::HashMap<u64, Box<[u8]>>,
+    registers: std::collections::HashMap<u64, Arc<[u8]>>,
 
     /// Total memory usage as counted for the purposes of the contract
     /// execution.
@@ -145,6 +166,43 @@ impl Registers {
         }
     }
 
+    /// Returns `len` bytes of register with given index starting at `offset`.
+    ///
+    /// Only the bytes returned are paid for.  Returns an error if (i) there’s
+    /// not enough gas to perform the register read, (ii) register with given
+    /// index doesn’t exist or (iii) the range is outside of the register.
+    pub(super) fn get_slice<'s>(
+        &'s self,
+        gas_counter: &mut GasCounter,
+        register_id: u64,
+        offset: u64,
+        len: u64,
+    ) -> Result<&'s [u8]> {
+        let data =
+            self.registers.get(&register_id).ok_or(HostError::InvalidRegisterId { register_id })?;
+        let end = offset.checked_add(len).ok_or(HostError::MemoryAccessViolation)?;
+        if end > data.len() as u64 {
+            return Err(HostError::MemoryAccessViolation.into());
+        }
+        gas_counter.pay_base(read_register_base)?;
+        gas_counter.pay_per(read_register_byte, len)?;
+        Ok(&data[offset as usize..end as usize])
+    }
+
+    /// Returns a hash of the ids and values of the registers.
+    pub(super) fn digest(&self) -> CryptoHash {
+        let mut ids: Vec<_> = self.registers.keys().copied().collect();
+        ids.sort_unstable();
+        let mut bytes = Vec::new();
+        for id in ids {
+            let data = &self.registers[&id];
+            bytes.extend_from_slice(&id.to_le_bytes());
+            bytes.extend_from_slice(&(data.len() as u64).to_le_bytes());
+            bytes.extend_from_slice(data);
+        }
+        CryptoHash::hash_bytes(&bytes)
+    }
+
     #[cfg(test)]
     pub(super) fn get_for_free<'s>(&'s self, register_id: u64) -> Option<&'s [u8]> {
         self.registers.get(&register_id).map(|data| &data[..])
@@ -155,10 +213,17 @@ impl Registers {
         self.registers.get(&register_id).map(|data| data.len() as u64)
     }
 
+    /// Total memory usage of the registers, see [`Self::total_memory_usage`].
+    pub(super) fn memory_usage(&self) -> u64 {
+        self.total_memory_usage
+    }
+
     /// Sets register with given index.
     ///
-    /// Returns an error if (i) there’s not enough gas to perform the register
-    /// write or (ii) if setting the register would violate configured limits.
+    /// Passing an `Arc<[u8]>` shares the value instead of copying it, but is
+    /// charged the same.  Returns an error if (i) there’s not enough gas to
+    /// perform the register write or (ii) if setting the register would
+    /// violate configured limits.
     pub(super) fn set<T>(
         &mut self,
         gas_counter: &mut GasCounter,
@@ -167,7 +232,7 @@ impl Registers {
         data: T,
     ) -> Result<()>
     where
-        T: Into<Box<[u8]>> + AsRef<[u8]>,
+        T: Into<Arc<[u8]>> + AsRef<[u8]>,
     {
         let data_len =
             u64::try_from(data.as_ref().len()).map_err(|_| HostError::MemoryAccessViolation)?;
@@ -196,7 +261,7 @@ impl Registers {
         config: &LimitConfig,
         register_id: u64,
         data_len: u64,
-    ) -> Result<Entry<'a, u64, Box<[u8]>>> {
+    ) -> Result<Entry<'a, u64, Arc<[u8]>>> {
         if data_len > config.max_register_size {
             return Err(HostError::MemoryAccessViolation.into());
         }
@@ -319,6 +384,26 @@ mod tests {
         ctx.assert_used_gas(5394388050);
     }
 
+    /// Tests partial reads of a register.
+    #[test]
+    fn registers_get_slice() {
+        let mut ctx = RegistersTestContext::new();
+        ctx.assert_set_success(42, "foobar");
+        let gas = ctx.gas.used_gas();
+        assert_eq!(Ok(&b"oba"[..]), ctx.regs.get_slice(&mut ctx.gas, 42, 2, 3));
+        assert_eq!(Ok(&b""[..]), ctx.regs.get_slice(&mut ctx.gas, 42, 6, 0));
+        let err = Err(HostError::MemoryAccessViolation.into());
+        assert_eq!(err, ctx.regs.get_slice(&mut ctx.gas, 42, 4, 3));
+        assert_eq!(err, ctx.regs.get_slice(&mut ctx.gas, 42, 1, u64::MAX));
+        let err = Err(HostError::InvalidRegisterId { register_id: 24 }.into());
+        assert_eq!(err, ctx.regs.get_slice(&mut ctx.gas, 24, 0, 0));
+        // Failed reads are free and the others pay for the bytes returned only.
+        let mut full = GasCounter::new(ExtCostsConfig::test(), u64::MAX, 0, u64::MAX, false);
+        ctx.regs.get(&mut full, 42).unwrap();
+        ctx.regs.get(&mut full, 42).unwrap();
+        assert!(ctx.gas.used_gas() - gas < full.used_gas());
+    }
+
     /// Tests limit on number of registers.
     #[test]
     fn registers_max_number_limit() {
@@ -0,0 +1,289 @@
+use super::gas_counter::GasInterrupt;
+use crate::clock::Clock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// Longest the thread waits before reading an injected clock again, as it
+/// does not move with the system clock the thread waits on.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How a [`Watchdog`] stops the call it watches.
+pub(crate) enum Interrupt {
+    /// Signals the thread running the call, whose handler lowers the gas
+    /// limit of the call, see [`signal`].
+    ///
+    /// For the VMs compiling the gas checks into the code of the contracts:
+    /// the code reads the limit with plain loads, which a store from the
+    /// thread of the watchdog would race with.
+    Signal,
+    /// Moves the epoch of the Wasmtime `engine` running the call after
+    /// setting `interrupted`, which the epoch callback of the store of the
+    /// call checks to fail it.
+    ///
+    /// The engine is shared by the calls of the VM, whose callbacks let the
+    /// calls which were not interrupted continue.
+    #[cfg(feature = "wasmtime_vm")]
+    Epoch { engine: wasmtime::Engine, interrupted: Arc<AtomicBool> },
+}
+
+impl Interrupt {
+    /// Returns the function interrupting the call of this thread from the
+    /// thread of the watchdog, and the registration of its gas limit `gas`
+    /// the function needs, or `None` if this host cannot interrupt the call.
+    fn prepare(
+        self,
+        gas: GasInterrupt,
+    ) -> Option<(Box<dyn FnOnce() + Send>, Option<signal::Registration>)> {
+        match self {
+            Interrupt::Signal => {
+                let target = signal::Target::current()?;
+                Some((Box::new(move || target.raise()), Some(signal::Registration::new(gas))))
+            }
+            #[cfg(feature = "wasmtime_vm")]
+            Interrupt::Epoch { engine, interrupted } => {
+                let interrupt = move || {
+                    interrupted.store(true, Ordering::Relaxed);
+                    engine.increment_epoch();
+                };
+                Some((Box::new(interrupt), None))
+            }
+        }
+    }
+}
+
+/// Interrupts a call once its deadline passes.
+///
+/// A thread waits for the deadline and then interrupts the call the way of
+/// its VM, see [`Interrupt`], so that it fails at its next gas charge or
+/// epoch check.  The deadline is a time of the system clock, which the
+/// thread sleeps until, or of an injected `clock`, which the thread reads
+/// every [`POLL_INTERVAL`].  A deadline which already passed lowers the gas
+/// limit before the call starts, without a thread.  Stopping the watchdog, or
+/// dropping it, waits for the thread, so the call is never interrupted after
+/// it ends.
+pub(crate) struct Watchdog {
+    shared: Arc<Shared>,
+    thread: Option<JoinHandle<()>>,
+    /// Points the signal handler of this thread at the gas limit of the call
+    /// until the watchdog is dropped, after its thread.
+    _handler: Option<signal::Registration>,
+}
+
+struct Shared {
+    stopped: Mutex<bool>,
+    stop_requested: Condvar,
+    interrupted: AtomicBool,
+}
+
+impl Watchdog {
+    /// Starts watching the call of the current thread, whose gas limit
+    /// `gas` lowers.
+    pub(crate) fn start(
+        deadline: Instant,
+        clock: Option<Arc<dyn Clock>>,
+        gas: GasInterrupt,
+        interrupt: Interrupt,
+    ) -> Self {
+        let shared = Arc::new(Shared {
+            stopped: Mutex::new(false),
+            stop_requested: Condvar::new(),
+            interrupted: AtomicBool::new(false),
+        });
+        let poll_interval = if clock.is_some() { POLL_INTERVAL } else { Duration::MAX };
+        let now = move || clock.as_ref().map_or_else(Instant::now, |clock| clock.now());
+        if now() >= deadline {
+            // The call has not started yet, so this thread lowers the limit
+            // itself.
+            shared.interrupted.store(true, Ordering::Relaxed);
+            gas.interrupt();
+            return Self { shared, thread: None, _handler: None };
+        }
+        let Some((interrupt, handler)) = interrupt.prepare(gas) else {
+            tracing::warn!(target: "vm", "calls cannot be interrupted, the deadline is ignored");
+            return Self { shared, thread: None, _handler: None };
+        };
+        let thread = std::thread::Builder::new().name("vm-watchdog".to_string()).spawn({
+            let shared = Arc::clone(&shared);
+            move || {
+                let mut stopped = shared.stopped.lock().unwrap();
+                while !*stopped {
+                    let now = now();
+                    if now >= deadline {
+                        shared.interrupted.store(true, Ordering::Relaxed);
+                        interrupt();
+                        return;
+                    }
+                    let wait = (deadline - now).min(poll_interval);
+                    stopped = shared.stop_requested.wait_timeout(stopped, wait).unwrap().0;
+                }
+            }
+        });
+        let thread = match thread {
+            Ok(thread) => Some(thread),
+            Err(err) => {
+                tracing::warn!(target: "vm", %err, "no watchdog, the deadline is ignored");
+                None
+            }
+        };
+        Self { shared, thread, _handler: handler }
+    }
+
+    /// Stops the watchdog and returns whether it interrupted the call.
+    pub(crate) fn stop(mut self) -> bool {
+        self.stop_thread();
+        self.shared.interrupted.load(Ordering::Relaxed)
+    }
+
+    fn stop_thread(&mut self) {
+        if let Some(thread) = self.thread.take() {
+            *self.shared.stopped.lock().unwrap() = true;
+            self.shared.stop_requested.notify_one();
+            thread.join().unwrap();
+        }
+    }
+}
+
+impl Drop for Watchdog {
+    fn drop(&mut self) {
+        self.stop_thread();
+    }
+}
+
+/// Interrupts the calls of the singlepass VMs with `SIGURG`.
+///
+/// The handler runs on the thread of the call, between two of its
+/// instructions, and lowers the gas limit the thread registered, so the
+/// generated code reads the lowered limit at its next gas check without a
+/// data race.  `SIGURG` is ignored by default and only sent for sockets
+/// which asked for it: a handler of the embedder is called for the signals
+/// arriving while no limit is registered.
+#[cfg(all(unix, any(feature = "unc_vm", feature = "wasmer2_vm", feature = "wasmer0_vm")))]
+mod signal {
+    use super::GasInterrupt;
+    use std::cell::Cell;
+    use std::sync::OnceLock;
+
+    const SIGNAL: libc::c_int = libc::SIGURG;
+
+    thread_local! {
+        /// Gas limit of the call running on this thread, if it has a
+        /// deadline.
+        static GAS: Cell<Option<GasInterrupt>> = const { Cell::new(None) };
+    }
+
+    /// Handler of [`SIGNAL`] before ours, or `None` if it could not be
+    /// installed.
+    static PREVIOUS: OnceLock<Option<libc::sigaction>> = OnceLock::new();
+
+    /// Thread running a call, which [`Target::raise`] interrupts.
+    pub(super) struct Target {
+        thread: libc::pthread_t,
+    }
+
+    impl Target {
+        pub(super) fn current() -> Option<Self> {
+            // SAFETY: always safe to call.
+            Some(Self { thread: unsafe { libc::pthread_self() } })
+        }
+
+        pub(super) fn raise(&self) {
+            // The thread is still running the call: the watchdog is joined
+            // before the thread unregisters the limit.
+            // SAFETY: the thread has not been joined, see above.
+            unsafe { libc::pthread_kill(self.thread, SIGNAL) };
+        }
+    }
+
+    /// Registers the gas limit of the call of this thread for the handler,
+    /// restoring the one of the enclosing call, if any, when dropped.
+    pub(super) struct Registration {
+        previous: Option<GasInterrupt>,
+    }
+
+    impl Registration {
+        pub(super) fn new(gas: GasInterrupt) -> Self {
+            PREVIOUS.get_or_init(install);
+            Self { previous: GAS.with(|cell| cell.replace(Some(gas))) }
+        }
+    }
+
+    impl Drop for Registration {
+        fn drop(&mut self) {
+            GAS.with(|cell| cell.set(self.previous));
+        }
+    }
+
+    fn install() -> Option<libc::sigaction> {
+        // SAFETY: the handler only uses async-signal-safe operations, and
+        // both structures are initialized before being read.
+        unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            let handle: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                handle;
+            action.sa_sigaction = handle as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_RESTART | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(SIGNAL, &action, &mut previous) != 0 {
+                tracing::warn!(target: "vm", "cannot handle SIGURG, deadlines are ignored");
+                return None;
+            }
+            Some(previous)
+        }
+    }
+
+    extern "C" fn handle(
+        signum: libc::c_int,
+        info: *mut libc::siginfo_t,
+        context: *mut libc::c_void,
+    ) {
+        if let Ok(Some(gas)) = GAS.try_with(Cell::get) {
+            gas.interrupt();
+            return;
+        }
+        let Some(Some(previous)) = PREVIOUS.get() else { return };
+        let handler = previous.sa_sigaction;
+        if handler == libc::SIG_DFL || handler == libc::SIG_IGN {
+            return;
+        }
+        // SAFETY: the embedder installed the handler for this signal, with
+        // the signature its flags tell.
+        unsafe {
+            if previous.sa_flags & libc::SA_SIGINFO != 0 {
+                let handler: extern "C" fn(libc::c_int, *mut libc::siginfo_t, *mut libc::c_void) =
+                    std::mem::transmute(handler);
+                handler(signum, info, context);
+            } else {
+                let handler: extern "C" fn(libc::c_int) = std::mem::transmute(handler);
+                handler(signum);
+            }
+        }
+    }
+}
+
+/// The singlepass VMs are not compiled in, or the host has no signals: the
+/// calls are not interrupted.
+#[cfg(not(all(unix, any(feature = "unc_vm", feature = "wasmer2_vm", feature = "wasmer0_vm"))))]
+mod signal {
+    use super::GasInterrupt;
+
+    pub(super) struct Target;
+
+    impl Target {
+        pub(super) fn current() -> Option<Self> {
+            None
+        }
+
+        pub(super) fn raise(&self) {}
+    }
+
+    pub(super) struct Registration;
+
+    impl Registration {
+        pub(super) fn new(_gas: GasInterrupt) -> Self {
+            Self
+        }
+    }
+}
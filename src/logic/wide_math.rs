@@ -0,0 +1,117 @@
+//! 256-bit integer arithmetic of the `u256_*` host functions.
+//!
+//! Integers are passed as 32 bytes in little-endian order.
+
+use super::HostError;
+
+uint::construct_uint! {
+    struct U256(4);
+}
+
+uint::construct_uint! {
+    struct U512(8);
+}
+
+/// How [`muldiv`] rounds inexact quotients.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Rounding {
+    /// Towards zero, i.e. the floor of the quotient.
+    Down,
+    /// Away from zero, i.e. the ceiling of the quotient.
+    Up,
+}
+
+impl Rounding {
+    pub(super) fn from_u64(rounding: u64) -> Result<Self, HostError> {
+        match rounding {
+            0 => Ok(Self::Down),
+            1 => Ok(Self::Up),
+            _ => Err(invalid_input(format!("unknown rounding mode {rounding}"))),
+        }
+    }
+}
+
+fn invalid_input(msg: String) -> HostError {
+    HostError::WideMathInvalidInput { msg }
+}
+
+fn to_bytes(value: U256) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    value.to_little_endian(&mut bytes);
+    bytes
+}
+
+/// Computes `a * b / denominator` with a 512-bit intermediate product, so
+/// that the result is exact whenever it fits in 256 bits.
+pub(super) fn muldiv(
+    a: &[u8; 32],
+    b: &[u8; 32],
+    denominator: &[u8; 32],
+    rounding: Rounding,
+) -> Result<[u8; 32], HostError> {
+    let denominator = U512::from_little_endian(denominator);
+    if denominator.is_zero() {
+        return Err(invalid_input("division by zero".to_string()));
+    }
+    let product = U512::from_little_endian(a) * U512::from_little_endian(b);
+    let (mut quotient, remainder) = product.div_mod(denominator);
+    if rounding == Rounding::Up && !remainder.is_zero() {
+        quotient += U512::one();
+    }
+    if quotient.bits() > 256 {
+        return Err(invalid_input("the quotient does not fit in 256 bits".to_string()));
+    }
+    let mut bytes = [0; 64];
+    quotient.to_little_endian(&mut bytes);
+    Ok(bytes[..32].try_into().unwrap())
+}
+
+/// Computes `base` to the power of `exponent`.
+pub(super) fn pow(base: &[u8; 32], exponent: u64) -> Result<[u8; 32], HostError> {
+    U256::from_little_endian(base)
+        .checked_pow(U256::from(exponent))
+        .map(to_bytes)
+        .ok_or_else(|| invalid_input("the power does not fit in 256 bits".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u256(value: u128) -> [u8; 32] {
+        to_bytes(U256::from(value))
+    }
+
+    const MAX: [u8; 32] = [0xff; 32];
+
+    #[test]
+    fn test_muldiv() {
+        assert_eq!(muldiv(&u256(6), &u256(7), &u256(4), Rounding::Down), Ok(u256(10)));
+        assert_eq!(muldiv(&u256(6), &u256(7), &u256(4), Rounding::Up), Ok(u256(11)));
+        assert_eq!(muldiv(&u256(6), &u256(8), &u256(4), Rounding::Up), Ok(u256(12)));
+        // The product does not fit in 256 bits but the quotient does.
+        assert_eq!(muldiv(&MAX, &MAX, &MAX, Rounding::Down), Ok(MAX));
+        assert_eq!(muldiv(&MAX, &u256(3), &u256(4), Rounding::Up).map(|q| q < MAX), Ok(true));
+        assert!(muldiv(&MAX, &u256(2), &u256(1), Rounding::Down).is_err());
+        assert!(muldiv(&MAX, &MAX, &u256(0), Rounding::Down).is_err());
+        let max_minus_one = to_bytes(U256::MAX - 1);
+        assert_eq!(muldiv(&MAX, &max_minus_one, &MAX, Rounding::Up), Ok(max_minus_one));
+        assert!(muldiv(&MAX, &MAX, &max_minus_one, Rounding::Down).is_err());
+    }
+
+    #[test]
+    fn test_pow() {
+        assert_eq!(pow(&u256(3), 4), Ok(u256(81)));
+        assert_eq!(pow(&u256(0), 0), Ok(u256(1)));
+        assert_eq!(pow(&u256(2), 255), Ok(to_bytes(U256::one() << 255)));
+        assert!(pow(&u256(2), 256).is_err());
+        assert_eq!(pow(&u256(1), u64::MAX), Ok(u256(1)));
+    }
+
+    #[test]
+    fn test_rounding() {
+        assert_eq!(Rounding::from_u64(0), Ok(Rounding::Down));
+        assert_eq!(Rounding::from_u64(1), Ok(Rounding::Up));
+        assert!(Rounding::from_u64(2).is_err());
+    }
+}
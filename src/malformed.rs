@@ -0,0 +1,360 @@
+//! Generators of malformed wasm modules.
+//!
+//! Deserialization is the first thing the untrusted code of a contract goes
+//! through.  The generators build modules broken in the ways parsers tend to
+//! mishandle: truncated sections, bad LEB128 integers, counts much larger
+//! than the module, deeply nested blocks.  Each module comes with the
+//! [`PrepareError`] which [`crate::prepare::prepare_contract`] must reject it
+//! with.  The modules are small, so the preparation has to reject them
+//! quickly and without allocating for the counts they claim.
+//!
+//! With the `test-support` feature the generators are available to other
+//! crates, e.g. to seed fuzzers.
+
+use crate::logic::errors::PrepareError;
+use std::ops::Range;
+use crate::logic::Config;
+use unc_parameters::vm::ContractPrepareVersion;
+use wasm_encoder::Encode;
+
+/// A module which the preparation must reject.
+#[derive(Clone, Debug)]
+pub struct MalformedModule {
+    /// What is wrong with the module, for the messages of failing tests.
+    pub description: String,
+    pub code: Vec<u8>,
+    /// The error the preparation must fail with.
+    pub expected: PrepareError,
+}
+
+impl MalformedModule {
+    fn deserialization(description: String, code: Vec<u8>) -> Self {
+        Self { description, code, expected: PrepareError::Deserialization }
+    }
+}
+
+const HEADER: &[u8] = b"\0asm\x01\0\0\0";
+const CUSTOM_SECTION: u8 = 0;
+const TYPE_SECTION: u8 = 1;
+const IMPORT_SECTION: u8 = 2;
+const FUNCTION_SECTION: u8 = 3;
+const CODE_SECTION: u8 = 10;
+
+/// A valid module with one entry in most sections, every section of which
+/// stops being valid if cut short:
+///
+/// ```wat
+/// (module
+///   (import "env" "f" (func))
+///   (memory 1)
+///   (func (export "main") (call 0))
+///   (data (i32.const 0) "a"))
+/// ```
+const BASE_MODULE: &[u8] = &[
+    0x00, 0x61, 0x73, 0x6d, 0x01, 0x00, 0x00, 0x00, // header
+    0x01, 0x04, 0x01, 0x60, 0x00, 0x00, // type
+    0x02, 0x09, 0x01, 0x03, 0x65, 0x6e, 0x76, 0x01, 0x66, 0x00, 0x00, // import
+    0x03, 0x02, 0x01, 0x00, // function
+    0x05, 0x03, 0x01, 0x00, 0x01, // memory
+    0x07, 0x08, 0x01, 0x04, 0x6d, 0x61, 0x69, 0x6e, 0x00, 0x01, // export
+    0x0a, 0x06, 0x01, 0x04, 0x00, 0x10, 0x00, 0x0b, // code
+    0x0b, 0x07, 0x01, 0x00, 0x41, 0x00, 0x0b, 0x01, 0x61, // data
+];
+
+/// Nesting of the blocks of [`deep_nesting`] in the [`corpus`].
+const CORPUS_NESTING: usize = 100_000;
+
+/// All the generators, on [`BASE_MODULE`] for those which alter a module.
+pub fn corpus(config: &Config) -> Vec<MalformedModule> {
+    let mut modules = truncated_sections(BASE_MODULE);
+    modules.extend(bad_leb128(BASE_MODULE));
+    modules.extend(oversized_counts(config));
+    modules.push(deep_nesting(CORPUS_NESTING));
+    modules
+}
+
+/// A section of a module.
+struct Section {
+    id: u8,
+    /// The LEB128 size of the payload, following the id.
+    size: Range<usize>,
+    payload: Range<usize>,
+}
+
+/// The sections of `code`, up to the first one which is malformed.
+fn sections(code: &[u8]) -> Vec<Section> {
+    let mut sections = Vec::new();
+    let mut start = HEADER.len();
+    while let Some(&id) = code.get(start) {
+        let Some((len, size_end)) = read_leb128(code, start + 1) else { break };
+        let payload = size_end..size_end + len as usize;
+        if payload.end > code.len() {
+            break;
+        }
+        sections.push(Section { id, size: start + 1..size_end, payload: payload.clone() });
+        start = payload.end;
+    }
+    sections
+}
+
+/// Decodes the LEB128 `u32` at `pos`, returning it and the offset after it.
+fn read_leb128(code: &[u8], mut pos: usize) -> Option<(u32, usize)> {
+    let mut value = 0u32;
+    for shift in (0..35).step_by(7) {
+        let byte = *code.get(pos)?;
+        pos += 1;
+        // The last byte only holds the 4 highest bits.
+        if shift == 28 && byte & 0x70 != 0 {
+            return None;
+        }
+        value |= u32::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Some((value, pos));
+        }
+    }
+    None
+}
+
+fn leb128(value: u32) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    value.encode(&mut bytes);
+    bytes
+}
+
+/// A module with a single section of `id` holding `payload`.
+fn module_with_section(id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut code = HEADER.to_vec();
+    code.push(id);
+    code.extend(leb128(payload.len() as u32));
+    code.extend(payload);
+    code
+}
+
+fn section_name(id: u8) -> String {
+    let name = match id {
+        CUSTOM_SECTION => "custom",
+        TYPE_SECTION => "type",
+        IMPORT_SECTION => "import",
+        FUNCTION_SECTION => "function",
+        4 => "table",
+        5 => "memory",
+        6 => "global",
+        7 => "export",
+        8 => "start",
+        9 => "element",
+        CODE_SECTION => "code",
+        11 => "data",
+        12 => "data count",
+        _ => return format!("section {id}"),
+    };
+    format!("{name} section")
+}
+
+/// Cuts `code` inside each of its sections, and shrinks the payload of each
+/// of its sections, keeping the rest of the module.
+///
+/// The payloads of custom sections are opaque, so they are left alone.  The
+/// other sections of `code` must stop being valid when cut short, which is
+/// the case when their last entry ends their payload.
+pub fn truncated_sections(code: &[u8]) -> Vec<MalformedModule> {
+    let mut modules = Vec::new();
+    for section in sections(code).iter().filter(|section| section.id != CUSTOM_SECTION) {
+        let name = section_name(section.id);
+        let payload = &section.payload;
+        let mut cuts = vec![
+            section.size.start,
+            payload.start,
+            payload.start + payload.len() / 2,
+            payload.end.saturating_sub(1),
+        ];
+        cuts.dedup();
+        for cut in cuts.into_iter().filter(|cut| (section.size.start..payload.end).contains(cut)) {
+            let description = format!("module cut at byte {cut}, in its {name}");
+            modules.push(MalformedModule::deserialization(description, code[..cut].to_vec()));
+        }
+        let mut lens = vec![0, payload.len() / 2, payload.len().saturating_sub(1)];
+        lens.dedup();
+        for len in lens.into_iter().filter(|len| *len < payload.len()) {
+            let mut shrunk = code[..section.size.start].to_vec();
+            shrunk.extend(leb128(len as u32));
+            shrunk.extend(&code[payload.start..payload.start + len]);
+            shrunk.extend(&code[payload.end..]);
+            let description = format!("{name} shrunk to {len} of its {} bytes", payload.len());
+            modules.push(MalformedModule::deserialization(description, shrunk));
+        }
+    }
+    modules
+}
+
+/// Replaces the size of each section of `code`, and the first integer of
+/// its payload, with malformed LEB128 integers.
+pub fn bad_leb128(code: &[u8]) -> Vec<MalformedModule> {
+    const BAD: [(&str, &[u8]); 2] = [
+        // Five bytes with the continuation bit, the most a `u32` may take.
+        ("unterminated", &[0x80, 0x80, 0x80, 0x80, 0x80]),
+        // Bits set above the 32 bits of a `u32`.
+        ("out of range", &[0xff, 0xff, 0xff, 0xff, 0x7f]),
+    ];
+    let mut modules = Vec::new();
+    for section in sections(code).iter().filter(|section| section.id != CUSTOM_SECTION) {
+        let name = section_name(section.id);
+        let payload = &section.payload;
+        for (kind, bad) in BAD {
+            let mut module = code[..section.size.start].to_vec();
+            module.extend(bad);
+            module.extend(&code[section.size.end..]);
+            let description = format!("{kind} LEB128 size of the {name}");
+            modules.push(MalformedModule::deserialization(description, module));
+
+            let Some((_, first_end)) = read_leb128(code, payload.start) else { continue };
+            if first_end > payload.end {
+                continue;
+            }
+            let mut new_payload = bad.to_vec();
+            new_payload.extend(&code[first_end..payload.end]);
+            let mut module = code[..section.size.start].to_vec();
+            module.extend(leb128(new_payload.len() as u32));
+            module.extend(new_payload);
+            module.extend(&code[payload.end..]);
+            let description = format!("{kind} LEB128 first integer of the {name}");
+            modules.push(MalformedModule::deserialization(description, module));
+        }
+        // A valid integer, but the section would end past the module.
+        let mut module = code[..section.size.start].to_vec();
+        module.extend(leb128(u32::MAX));
+        module.extend(&code[section.size.end..]);
+        let description = format!("size of the {name} larger than the module");
+        modules.push(MalformedModule::deserialization(description, module));
+    }
+    modules
+}
+
+/// Modules claiming `u32::MAX` entries in a vector while holding none.
+///
+/// The expected errors depend on `config`: V2 of the preparation checks the
+/// function and local limits before validating, V0 and V1 only check them on
+/// valid modules.
+pub fn oversized_counts(config: &Config) -> Vec<MalformedModule> {
+    let max = leb128(u32::MAX);
+    let mut modules: Vec<_> = [TYPE_SECTION, IMPORT_SECTION, FUNCTION_SECTION, 4, 5, 6, 7, 9, 11]
+        .into_iter()
+        .map(|id| {
+            let description = format!("{} of u32::MAX entries", section_name(id));
+            MalformedModule::deserialization(description, module_with_section(id, &max))
+        })
+        .collect();
+
+    let mut params = vec![0x01, 0x60];
+    params.extend(&max);
+    modules.push(MalformedModule::deserialization(
+        "function type of u32::MAX parameters".to_string(),
+        module_with_section(TYPE_SECTION, &params),
+    ));
+    let mut name = vec![0x01];
+    name.extend(&max);
+    modules.push(MalformedModule::deserialization(
+        "import module name of u32::MAX bytes".to_string(),
+        module_with_section(IMPORT_SECTION, &name),
+    ));
+
+    let limits = &config.limit_config;
+    let v2 = limits.contract_prepare_version == ContractPrepareVersion::V2;
+    let exceeds =
+        |limit: Option<u64>| v2 && limit.map_or(false, |limit| limit < u64::from(u32::MAX));
+    modules.push(MalformedModule {
+        description: "code section of u32::MAX function bodies".to_string(),
+        code: module_with_section(CODE_SECTION, &max),
+        expected: if exceeds(limits.max_functions_number_per_contract) {
+            PrepareError::TooManyFunctions
+        } else {
+            PrepareError::Deserialization
+        },
+    });
+    // One body declaring u32::MAX locals of type i32.
+    let mut body = vec![0x01];
+    body.extend(&max);
+    body.extend([0x7f, 0x0b]);
+    modules.push(MalformedModule {
+        description: "function of u32::MAX locals".to_string(),
+        code: single_function(&body),
+        expected: if exceeds(limits.max_locals_per_contract) {
+            PrepareError::TooManyLocals
+        } else {
+            PrepareError::Deserialization
+        },
+    });
+    modules
+}
+
+/// A module with a function of `depth` nested blocks which are never closed.
+pub fn deep_nesting(depth: usize) -> MalformedModule {
+    let mut body = vec![0x00];
+    for _ in 0..depth {
+        // `block` without a result.
+        body.extend([0x02, 0x40]);
+    }
+    body.push(0x0b);
+    MalformedModule::deserialization(
+        format!("{depth} nested blocks without their ends"),
+        single_function(&body),
+    )
+}
+
+/// A module with a single `(func)` of `body`.
+fn single_function(body: &[u8]) -> Vec<u8> {
+    let mut code = module_with_section(TYPE_SECTION, &[0x01, 0x60, 0x00, 0x00]);
+    code.extend([FUNCTION_SECTION, 0x02, 0x01, 0x00]);
+    let mut payload = vec![0x01];
+    payload.extend(leb128(body.len() as u32));
+    payload.extend(body);
+    code.push(CODE_SECTION);
+    code.extend(leb128(payload.len() as u32));
+    code.extend(payload);
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prepare::prepare_contract;
+    use crate::tests::test_vm_config;
+    use std::time::{Duration, Instant};
+    use unc_parameters::vm::VMKind;
+
+    /// Generous bound on the time to reject a module, even in debug builds.
+    const PREPARE_TIME_LIMIT: Duration = Duration::from_secs(2);
+
+    #[test]
+    fn test_sections() {
+        let ids: Vec<_> = sections(BASE_MODULE).iter().map(|section| section.id).collect();
+        assert_eq!(ids, [1, 2, 3, 5, 7, 10, 11]);
+        assert_eq!(read_leb128(&[0xe5, 0x8e, 0x26], 0), Some((624485, 3)));
+        assert_eq!(read_leb128(&[0x80, 0x80, 0x80, 0x80, 0x80, 0x00], 0), None);
+        assert_eq!(read_leb128(&[0xff, 0xff, 0xff, 0xff, 0x7f], 0), None);
+    }
+
+    #[test]
+    fn test_prepare_rejects_malformed_modules() {
+        for version in
+            [ContractPrepareVersion::V0, ContractPrepareVersion::V1, ContractPrepareVersion::V2]
+        {
+            let mut config = test_vm_config();
+            config.limit_config.contract_prepare_version = version;
+            let mut kinds = vec![VMKind::Wasmtime];
+            if version == ContractPrepareVersion::V2 {
+                kinds.push(VMKind::NearVm);
+            }
+            for kind in kinds {
+                prepare_contract(BASE_MODULE, &config, kind).unwrap();
+                for module in corpus(&config) {
+                    let start = Instant::now();
+                    let result = prepare_contract(&module.code, &config, kind);
+                    let elapsed = start.elapsed();
+                    let context = format!("{} ({version:?}, {kind:?})", module.description);
+                    assert_eq!(result, Err(module.expected), "{context}");
+                    assert!(elapsed < PREPARE_TIME_LIMIT, "{context} took {elapsed:?}");
+                }
+            }
+        }
+    }
+}
@@ -1,15 +1,17 @@
 use crate::logic::{MemSlice, MemoryLike};
+use crate::resources::{ResourceKind, ResourceToken};
 
 use std::borrow::Cow;
 
-use wasmer_runtime::units::Pages;
+use wasmer_runtime::units::{Bytes, Pages};
 use wasmer_runtime::wasm::MemoryDescriptor;
 use wasmer_runtime::Memory;
 
-pub struct WasmerMemory(Memory);
+pub struct WasmerMemory(Memory, #[allow(dead_code)] ResourceToken);
 
 impl WasmerMemory {
     pub fn new(initial_memory_pages: u32, max_memory_pages: u32) -> Self {
+        let bytes = Bytes::from(Pages(initial_memory_pages)).0 as u64;
         WasmerMemory(
             Memory::new(
                 MemoryDescriptor::new(
@@ -20,6 +22,7 @@ impl WasmerMemory {
                 .unwrap(),
             )
             .expect("TODO creating memory cannot fail"),
+            ResourceToken::new(ResourceKind::Memory, bytes),
         )
     }
 
@@ -61,6 +64,10 @@ impl MemoryLike for WasmerMemory {
             mem.zip(buffer.iter()).for_each(|(dst, src)| dst.set(*src));
         })
     }
+
+    fn data_size(&self) -> u64 {
+        self.0.view::<u8>().len() as u64
+    }
 }
 
 #[test]
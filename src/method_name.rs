@@ -0,0 +1,102 @@
+//! Constraints on the names of the methods a call can run.
+//!
+//! A method name is any non-empty UTF-8 string, of at most
+//! `max_length_method_name` bytes with the `limit_method_name_length`
+//! parameter of the config.  It is compared byte for byte with the
+//! names of the exports of the contract, without any Unicode normalization,
+//! so `"é"` and `"e\u{301}"` are different methods.  The names are checked
+//! before the contract is loaded, the same way on every VM, and
+//! [`exported_methods`] applies the same checks to the exports of a contract.
+
+use crate::logic::errors::{MethodResolveError, PrepareError};
+use crate::logic::Config;
+use finite_wasm::wasmparser as wp;
+
+/// Checks that a call can run a method named `name`.
+pub fn check_method_name(name: &str, config: &Config) -> Result<(), MethodResolveError> {
+    if name.is_empty() {
+        return Err(MethodResolveError::MethodEmptyName);
+    }
+    if !config.limit_method_name_length {
+        return Ok(());
+    }
+    let limit = config.limit_config.max_length_method_name;
+    let length = name.len() as u64;
+    if length > limit {
+        return Err(MethodResolveError::MethodNameTooLong { length, limit });
+    }
+    Ok(())
+}
+
+/// A function exported by a contract, see [`exported_methods`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExportedMethod {
+    pub name: String,
+    /// Whether calls can run the function, or why they fail to.
+    pub callable: Result<(), MethodResolveError>,
+}
+
+/// The functions exported by `code`, in the order of its export section.
+///
+/// Exports other than functions cannot be called and are left out.  The code
+/// is parsed but not validated, invalid modules only fail calls later on.
+pub fn exported_methods(code: &[u8], config: &Config) -> Result<Vec<ExportedMethod>, PrepareError> {
+    let mut types = Vec::new();
+    let mut function_types = Vec::new();
+    let mut methods = Vec::new();
+    for payload in wp::Parser::new(0).parse_all(code) {
+        match payload.map_err(|_| PrepareError::Deserialization)? {
+            wp::Payload::TypeSection(reader) => {
+                for ty in reader {
+                    let wp::Type::Func(ty) = ty.map_err(|_| PrepareError::Deserialization)?;
+                    types.push(ty.params().is_empty() && ty.results().is_empty());
+                }
+            }
+            wp::Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import.map_err(|_| PrepareError::Deserialization)?;
+                    if let wp::TypeRef::Func(ty) = import.ty {
+                        function_types.push(ty);
+                    }
+                }
+            }
+            wp::Payload::FunctionSection(reader) => {
+                for ty in reader {
+                    function_types.push(ty.map_err(|_| PrepareError::Deserialization)?);
+                }
+            }
+            wp::Payload::ExportSection(reader) => {
+                for export in reader {
+                    let export = export.map_err(|_| PrepareError::Deserialization)?;
+                    if export.kind != wp::ExternalKind::Func {
+                        continue;
+                    }
+                    let no_params_nor_results = function_types
+                        .get(export.index as usize)
+                        .and_then(|ty| types.get(*ty as usize))
+                        .ok_or(PrepareError::Deserialization)?;
+                    methods.push(exported_method(export.name, *no_params_nor_results, config));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(methods)
+}
+
+/// The [`ExportedMethod`] of a function exported as `name`, taking and
+/// returning nothing if `no_params_nor_results`.
+pub(crate) fn exported_method(
+    name: &str,
+    no_params_nor_results: bool,
+    config: &Config,
+) -> ExportedMethod {
+    let callable = check_method_name(name, config).and_then(|()| {
+        if no_params_nor_results {
+            Ok(())
+        } else {
+            Err(MethodResolveError::MethodInvalidSignature)
+        }
+    });
+    ExportedMethod { name: name.to_string(), callable }
+}
@@ -0,0 +1,377 @@
+//! Metrics of where the time of the calls goes, for operators.
+//!
+//! With the `metrics` feature, the runners count the lookups of compiled
+//! contracts in the cache and time the compilations and executions, per VM,
+//! and the [`crate::DeployPrecompiler`]s count the deploys they queue and
+//! what becomes of them.
+//! [`runner_metrics`] returns the totals of the process, and
+//! [`prometheus_metrics`] renders them in the Prometheus text format, for the
+//! embedder to serve with its own metrics.
+//!
+//! Without the feature, the accounting compiles to nothing.
+
+#[cfg(feature = "metrics")]
+pub use enabled::{
+    precompile_metrics, prometheus_metrics, runner_metrics, HistogramSnapshot, VMMetrics,
+};
+use crate::deploy_precompile::PrecompileResult;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use unc_parameters::vm::VMKind;
+
+/// Upper bounds of the buckets of the histograms, in microseconds: calls
+/// take from tens of microseconds to a few milliseconds, compilations up to
+/// seconds.
+const BUCKETS_US: [u64; 15] = [
+    100, 250, 500, 1_000, 2_500, 5_000, 10_000, 25_000, 50_000, 100_000, 250_000, 500_000,
+    1_000_000, 2_500_000, 5_000_000,
+];
+
+/// Durations counted in buckets, for the metrics and the
+/// [`crate::AdmissionController`].
+pub(crate) struct Histogram {
+    /// Observations in each bucket, the last one for those above all the
+    /// bounds.
+    buckets: [AtomicU64; BUCKETS_US.len() + 1],
+    #[cfg_attr(not(feature = "metrics"), allow(dead_code))]
+    sum_ns: AtomicU64,
+    max_ns: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS_US.len() + 1],
+            sum_ns: AtomicU64::new(0),
+            max_ns: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn record(&self, elapsed: Duration) {
+        let us = elapsed.as_micros();
+        let bucket = BUCKETS_US.partition_point(|&bound| u128::from(bound) < us);
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        let ns = u64::try_from(elapsed.as_nanos()).unwrap_or(u64::MAX);
+        self.sum_ns.fetch_add(ns, Ordering::Relaxed);
+        self.max_ns.fetch_max(ns, Ordering::Relaxed);
+    }
+
+    /// Number of durations recorded.
+    pub(crate) fn count(&self) -> u64 {
+        self.buckets.iter().map(|bucket| bucket.load(Ordering::Relaxed)).sum()
+    }
+
+    /// Bound of the `percent`th percentile of the durations of `histograms`,
+    /// using the nearest-rank method: the upper bound of its bucket, or the
+    /// longest duration if that is shorter.
+    pub(crate) fn percentile<'a>(
+        histograms: impl IntoIterator<Item = &'a Histogram>,
+        percent: u64,
+    ) -> Option<Duration> {
+        let mut buckets = [0; BUCKETS_US.len() + 1];
+        let mut max_ns = 0;
+        for histogram in histograms {
+            for (sum, bucket) in buckets.iter_mut().zip(&histogram.buckets) {
+                *sum += bucket.load(Ordering::Relaxed);
+            }
+            max_ns = max_ns.max(histogram.max_ns.load(Ordering::Relaxed));
+        }
+        let rank = (buckets.iter().sum::<u64>() * percent).div_ceil(100).max(1);
+        let mut count = 0;
+        let bucket = buckets.iter().position(|observations| {
+            count += observations;
+            count >= rank
+        })?;
+        let max = Duration::from_nanos(max_ns);
+        Some(BUCKETS_US.get(bucket).map_or(max, |bound| Duration::from_micros(*bound).min(max)))
+    }
+}
+
+impl Clone for Histogram {
+    fn clone(&self) -> Self {
+        let load = |counter: &AtomicU64| AtomicU64::new(counter.load(Ordering::Relaxed));
+        Self {
+            buckets: std::array::from_fn(|bucket| load(&self.buckets[bucket])),
+            sum_ns: load(&self.sum_ns),
+            max_ns: load(&self.max_ns),
+        }
+    }
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl std::fmt::Debug for Histogram {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Histogram").field("count", &self.count()).finish()
+    }
+}
+
+/// Counts a lookup of a contract in the cache of a call.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn cache_lookup(vm_kind: VMKind, hit: bool) {
+    #[cfg(feature = "metrics")]
+    enabled::cache_lookup(vm_kind, hit);
+}
+
+/// Records the time spent preparing and compiling a contract.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn compiled(vm_kind: VMKind, elapsed: Duration) {
+    #[cfg(feature = "metrics")]
+    enabled::COMPILATIONS[crate::concurrency::index(vm_kind)].record(elapsed);
+}
+
+/// Accounts the deploys entering or leaving the queues and the compilations
+/// of the [`crate::DeployPrecompiler`]s.
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn precompile_queue(queued: i64, compiling: i64) {
+    #[cfg(feature = "metrics")]
+    enabled::precompile_queue(queued, compiling);
+}
+
+/// Counts a deploy done by a [`crate::DeployPrecompiler`].
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub(crate) fn precompile_done(result: PrecompileResult) {
+    #[cfg(feature = "metrics")]
+    enabled::PRECOMPILED_DEPLOYS[result as usize]
+        .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Times the execution of a contract until dropped.
+#[must_use]
+pub(crate) struct ExecutionTimer {
+    #[cfg(feature = "metrics")]
+    vm_kind: VMKind,
+    #[cfg(feature = "metrics")]
+    start: std::time::Instant,
+}
+
+impl ExecutionTimer {
+    #[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+    pub(crate) fn start(vm_kind: VMKind) -> Self {
+        Self {
+            #[cfg(feature = "metrics")]
+            vm_kind,
+            #[cfg(feature = "metrics")]
+            start: std::time::Instant::now(),
+        }
+    }
+}
+
+#[cfg(feature = "metrics")]
+impl Drop for ExecutionTimer {
+    fn drop(&mut self) {
+        enabled::EXECUTIONS[crate::concurrency::index(self.vm_kind)].record(self.start.elapsed());
+    }
+}
+
+#[cfg(feature = "metrics")]
+mod enabled {
+    use super::{Histogram, BUCKETS_US};
+    use crate::deploy_precompile::{DeployPrecompilerStats, PrecompileResult};
+    use std::fmt::Write;
+    use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+    use std::time::Duration;
+    use unc_parameters::vm::VMKind;
+
+    const VM_KINDS: [VMKind; 4] =
+        [VMKind::Wasmer0, VMKind::Wasmtime, VMKind::Wasmer2, VMKind::NearVm];
+
+    impl Histogram {
+        fn snapshot(&self) -> HistogramSnapshot {
+            let mut count = 0;
+            let mut buckets = Vec::with_capacity(BUCKETS_US.len());
+            for (bound, observations) in BUCKETS_US.iter().zip(&self.buckets) {
+                count += observations.load(Ordering::Relaxed);
+                buckets.push((Duration::from_micros(*bound), count));
+            }
+            count += self.buckets[BUCKETS_US.len()].load(Ordering::Relaxed);
+            let sum = Duration::from_nanos(self.sum_ns.load(Ordering::Relaxed));
+            HistogramSnapshot { buckets, count, sum }
+        }
+    }
+
+    static CACHE_HITS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+    static CACHE_MISSES: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+    pub(super) static COMPILATIONS: [Histogram; 4] = [const { Histogram::new() }; 4];
+    pub(super) static EXECUTIONS: [Histogram; 4] = [const { Histogram::new() }; 4];
+
+    static PRECOMPILE_QUEUED: AtomicI64 = AtomicI64::new(0);
+    static PRECOMPILE_COMPILING: AtomicI64 = AtomicI64::new(0);
+    /// Deploys by [`PrecompileResult`].
+    pub(super) static PRECOMPILED_DEPLOYS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+
+    pub(super) fn precompile_queue(queued: i64, compiling: i64) {
+        PRECOMPILE_QUEUED.fetch_add(queued, Ordering::Relaxed);
+        PRECOMPILE_COMPILING.fetch_add(compiling, Ordering::Relaxed);
+    }
+
+    pub(super) fn cache_lookup(vm_kind: VMKind, hit: bool) {
+        let counters = if hit { &CACHE_HITS } else { &CACHE_MISSES };
+        counters[crate::concurrency::index(vm_kind)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Durations observed by a histogram.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct HistogramSnapshot {
+        /// Upper bounds of the buckets, with the number of durations up to
+        /// each bound.
+        pub buckets: Vec<(Duration, u64)>,
+        pub count: u64,
+        pub sum: Duration,
+    }
+
+    /// Totals of the calls of a VM in the process.
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct VMMetrics {
+        /// Lookups finding the contract, compiled or failing to compile, in
+        /// the cache.
+        pub cache_hits: u64,
+        /// Lookups compiling the contract.  Calls without a cache do not
+        /// look up contracts.
+        pub cache_misses: u64,
+        /// Time spent preparing and compiling contracts.
+        pub compilations: HistogramSnapshot,
+        /// Time spent running contracts, without loading them.
+        pub executions: HistogramSnapshot,
+    }
+
+    pub fn runner_metrics(vm_kind: VMKind) -> VMMetrics {
+        let index = crate::concurrency::index(vm_kind);
+        VMMetrics {
+            cache_hits: CACHE_HITS[index].load(Ordering::Relaxed),
+            cache_misses: CACHE_MISSES[index].load(Ordering::Relaxed),
+            compilations: COMPILATIONS[index].snapshot(),
+            executions: EXECUTIONS[index].snapshot(),
+        }
+    }
+
+    /// Totals of the [`crate::DeployPrecompiler`]s of the process.
+    pub fn precompile_metrics() -> DeployPrecompilerStats {
+        let deploys =
+            |result: PrecompileResult| PRECOMPILED_DEPLOYS[result as usize].load(Ordering::Relaxed);
+        let gauge = |gauge: &AtomicI64| gauge.load(Ordering::Relaxed).max(0) as usize;
+        DeployPrecompilerStats {
+            queued: gauge(&PRECOMPILE_QUEUED),
+            compiling: gauge(&PRECOMPILE_COMPILING),
+            compiled: deploys(PrecompileResult::Compiled),
+            already_cached: deploys(PrecompileResult::AlreadyCached),
+            failed: deploys(PrecompileResult::Failed),
+            dropped: deploys(PrecompileResult::Dropped),
+        }
+    }
+
+    /// The metrics of all the VMs, in the Prometheus text exposition format.
+    pub fn prometheus_metrics() -> String {
+        let metrics = VM_KINDS.map(|vm_kind| (vm_kind, runner_metrics(vm_kind)));
+        let mut out = String::new();
+        out +=
+            "# HELP unc_vm_runner_cache_lookups_total Lookups of compiled contracts in the cache\n";
+        out += "# TYPE unc_vm_runner_cache_lookups_total counter\n";
+        for (vm_kind, metrics) in &metrics {
+            for (result, count) in [("hit", metrics.cache_hits), ("miss", metrics.cache_misses)] {
+                let _ = writeln!(
+                    out,
+                    "unc_vm_runner_cache_lookups_total{{vm_kind=\"{vm_kind:?}\",result=\"{result}\"}} {count}"
+                );
+            }
+        }
+        let histograms: [(&str, &str, fn(&VMMetrics) -> &HistogramSnapshot); 2] = [
+            ("compile", "Time spent preparing and compiling contracts", |m| &m.compilations),
+            ("execution", "Time spent running contracts", |m| &m.executions),
+        ];
+        for (name, help, histogram) in histograms {
+            let name = format!("unc_vm_runner_{name}_seconds");
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} histogram");
+            for (vm_kind, metrics) in &metrics {
+                let histogram = histogram(metrics);
+                let labels = format!("vm_kind=\"{vm_kind:?}\"");
+                for (bound, count) in &histogram.buckets {
+                    let le = bound.as_secs_f64();
+                    let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{le}\"}} {count}");
+                }
+                let count = histogram.count;
+                let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+                let _ = writeln!(out, "{name}_sum{{{labels}}} {}", histogram.sum.as_secs_f64());
+                let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+            }
+        }
+
+        let precompile = precompile_metrics();
+        for (name, help, value) in [
+            ("queue_depth", "Deploys waiting in the precompile queues", precompile.queued),
+            ("compiling", "Deploys being precompiled", precompile.compiling),
+        ] {
+            let name = format!("unc_vm_runner_precompile_{name}");
+            let _ = writeln!(out, "# HELP {name} {help}");
+            let _ = writeln!(out, "# TYPE {name} gauge");
+            let _ = writeln!(out, "{name} {value}");
+        }
+        let name = "unc_vm_runner_precompile_deploys_total";
+        let _ = writeln!(out, "# HELP {name} Deploys given to the precompile queues by outcome");
+        let _ = writeln!(out, "# TYPE {name} counter");
+        for (result, count) in [
+            ("compiled", precompile.compiled),
+            ("already_cached", precompile.already_cached),
+            ("failed", precompile.failed),
+            ("dropped", precompile.dropped),
+        ] {
+            let _ = writeln!(out, "{name}{{result=\"{result}\"}} {count}");
+        }
+        out
+    }
+}
+
+#[cfg(all(test, feature = "metrics"))]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+    use crate::{ContractCode, MockCompiledContractCache};
+    use crate::logic::Config;
+    use unc_parameters::RuntimeFeesConfig;
+
+    #[test]
+    fn test_calls_are_measured() {
+        let code = wat::parse_str(r#"(module (func (export "main")))"#).unwrap();
+        let code = ContractCode::new(code, None);
+        let config = test_vm_config();
+        let fees = RuntimeFeesConfig::test();
+        with_vm_variants(&config, |vm_kind| {
+            let mut config = config.clone();
+            config.vm_kind = vm_kind;
+            let cache = MockCompiledContractCache::default();
+            let before = runner_metrics(vm_kind);
+            for _ in 0..2 {
+                let mut ext = MockedExternal::new();
+                let context = create_context(vec![]);
+                crate::run(&code, "main", &mut ext, context, &config, &fees, &[], Some(&cache))
+                    .unwrap();
+            }
+            // Tests run in parallel, so other calls may be counted too.
+            let after = runner_metrics(vm_kind);
+            assert!(after.executions.count >= before.executions.count + 2, "{vm_kind:?}");
+            assert!(after.cache_misses > before.cache_misses, "{vm_kind:?}");
+            assert!(after.cache_hits > before.cache_hits, "{vm_kind:?}");
+            assert!(after.compilations.count > before.compilations.count, "{vm_kind:?}");
+            let buckets = &after.executions.buckets;
+            assert!(buckets.windows(2).all(|pair| pair[0].1 <= pair[1].1));
+            assert!(buckets.last().unwrap().1 <= after.executions.count);
+        });
+
+        let text = prometheus_metrics();
+        assert!(text.contains("# TYPE unc_vm_runner_execution_seconds histogram\n"));
+        assert!(
+            text.contains("unc_vm_runner_cache_lookups_total{vm_kind=\"NearVm\",result=\"hit\"}")
+        );
+        assert!(
+            text.contains("unc_vm_runner_compile_seconds_bucket{vm_kind=\"NearVm\",le=\"+Inf\"}")
+        );
+        assert!(text.contains("# TYPE unc_vm_runner_precompile_queue_depth gauge\n"));
+        assert!(text.contains("unc_vm_runner_precompile_deploys_total{result=\"dropped\"}"));
+    }
+}
@@ -0,0 +1,259 @@
+//! Contract code parsed once for the inspections repeated on it.
+//!
+//! Listing the methods of a contract, reading one of its custom sections or
+//! analyzing it each parse the module from the start, which adds up for
+//! large contracts inspected over and over, as tests and tooling do.  A
+//! [`ParsedContractCode`] keeps the offsets of the sections and the export
+//! table next to the code, so these inspections read what they need directly,
+//! and keeps the [`ContractAnalysis`] of each config it is analyzed with.
+//!
+//! Parsing reads the sections and the headers of the function bodies but
+//! does not validate the module: invalid contracts still parse, and only
+//! fail their analysis or their calls.
+//!
+//! Only these inspections use the parsed structure.  Preparation,
+//! compilation and calls take [`ParsedContractCode::code`] and parse it
+//! again, as they validate and rewrite the whole module: the compiled
+//! contract cache is what saves them from running twice.
+
+use crate::analysis::{analyze_contract, ContractAnalysis};
+use crate::fingerprint::ConfigFingerprint;
+use crate::logic::errors::PrepareError;
+use crate::logic::Config;
+use crate::method_name::{exported_method, ExportedMethod};
+use crate::ContractCode;
+use finite_wasm::wasmparser as wp;
+use std::collections::HashMap;
+use std::ops::Range;
+use std::sync::Mutex;
+use unc_primitives_core::hash::CryptoHash;
+
+/// A section of a [`ParsedContractCode`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParsedSection {
+    /// Id of the section, 0 for the custom sections.
+    pub id: u8,
+    /// Name of the custom sections.
+    pub name: Option<String>,
+    /// Range of the contents of the section in the code, after the name for
+    /// the custom sections.
+    pub range: Range<usize>,
+}
+
+/// What a [`ParsedExport`] exports.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParsedExportKind {
+    Function,
+    Table,
+    Memory,
+    Global,
+    Tag,
+}
+
+/// An entry of the export section of a [`ParsedContractCode`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParsedExport {
+    pub name: String,
+    pub kind: ParsedExportKind,
+    /// Index of the exported item among those of its kind, imports first.
+    pub index: u32,
+}
+
+/// A [`ContractCode`] with the structure of its module, to list its
+/// methods, read its sections and analyze it without parsing it again, see
+/// the module documentation.
+pub struct ParsedContractCode {
+    code: ContractCode,
+    sections: Vec<ParsedSection>,
+    exports: Vec<ParsedExport>,
+    /// Whether each type takes and returns nothing.
+    nullary_types: Vec<bool>,
+    /// Type of each function, imports first.
+    function_types: Vec<u32>,
+    /// Analyses by fingerprint of the config.
+    analyses: Mutex<HashMap<CryptoHash, ContractAnalysis>>,
+}
+
+impl ParsedContractCode {
+    /// Parses `code`, failing with [`PrepareError::Deserialization`] if it
+    /// is not a wasm module.
+    pub fn new(code: ContractCode) -> Result<Self, PrepareError> {
+        let map_err = |_| PrepareError::Deserialization;
+        let mut sections = Vec::new();
+        let mut exports = Vec::new();
+        let mut nullary_types = Vec::new();
+        let mut function_types = Vec::new();
+        for payload in wp::Parser::new(0).parse_all(code.code()) {
+            let payload = payload.map_err(map_err)?;
+            match &payload {
+                wp::Payload::TypeSection(reader) => {
+                    for ty in reader.clone() {
+                        let wp::Type::Func(ty) = ty.map_err(map_err)?;
+                        nullary_types.push(ty.params().is_empty() && ty.results().is_empty());
+                    }
+                }
+                wp::Payload::ImportSection(reader) => {
+                    for import in reader.clone() {
+                        if let wp::TypeRef::Func(ty) = import.map_err(map_err)?.ty {
+                            function_types.push(ty);
+                        }
+                    }
+                }
+                wp::Payload::FunctionSection(reader) => {
+                    for ty in reader.clone() {
+                        function_types.push(ty.map_err(map_err)?);
+                    }
+                }
+                wp::Payload::ExportSection(reader) => {
+                    for export in reader.clone() {
+                        let export = export.map_err(map_err)?;
+                        let kind = match export.kind {
+                            wp::ExternalKind::Func => ParsedExportKind::Function,
+                            wp::ExternalKind::Table => ParsedExportKind::Table,
+                            wp::ExternalKind::Memory => ParsedExportKind::Memory,
+                            wp::ExternalKind::Global => ParsedExportKind::Global,
+                            wp::ExternalKind::Tag => ParsedExportKind::Tag,
+                        };
+                        let name = export.name.to_string();
+                        exports.push(ParsedExport { name, kind, index: export.index });
+                    }
+                }
+                wp::Payload::CustomSection(reader) => {
+                    let start = reader.data_offset();
+                    sections.push(ParsedSection {
+                        id: 0,
+                        name: Some(reader.name().to_string()),
+                        range: start..start + reader.data().len(),
+                    });
+                    continue;
+                }
+                _ => {}
+            }
+            if let Some((id, range)) = payload.as_section() {
+                sections.push(ParsedSection { id, name: None, range });
+            }
+        }
+        let analyses = Mutex::new(HashMap::new());
+        Ok(Self { code, sections, exports, nullary_types, function_types, analyses })
+    }
+
+    pub fn code(&self) -> &ContractCode {
+        &self.code
+    }
+
+    pub fn into_code(self) -> ContractCode {
+        self.code
+    }
+
+    /// The sections of the module, in the order of the code.
+    pub fn sections(&self) -> &[ParsedSection] {
+        &self.sections
+    }
+
+    /// The contents of the first section with the given `id`.
+    pub fn section(&self, id: u8) -> Option<&[u8]> {
+        let section = self.sections.iter().find(|section| section.id == id)?;
+        Some(&self.code.code()[section.range.clone()])
+    }
+
+    /// The contents of the first custom section named `name`, after its
+    /// name.
+    pub fn custom_section(&self, name: &str) -> Option<&[u8]> {
+        let section = self.sections.iter().find(|section| section.name.as_deref() == Some(name))?;
+        Some(&self.code.code()[section.range.clone()])
+    }
+
+    /// The export section of the module, in order.
+    pub fn exports(&self) -> &[ParsedExport] {
+        &self.exports
+    }
+
+    /// The export named `name`.
+    pub fn export(&self, name: &str) -> Option<&ParsedExport> {
+        self.exports.iter().find(|export| export.name == name)
+    }
+
+    /// Same as [`crate::exported_methods`] without parsing the code again.
+    pub fn exported_methods(&self, config: &Config) -> Result<Vec<ExportedMethod>, PrepareError> {
+        self.exports
+            .iter()
+            .filter(|export| export.kind == ParsedExportKind::Function)
+            .map(|export| {
+                let nullary = self
+                    .function_types
+                    .get(export.index as usize)
+                    .and_then(|ty| self.nullary_types.get(*ty as usize))
+                    .ok_or(PrepareError::Deserialization)?;
+                Ok(exported_method(&export.name, *nullary, config))
+            })
+            .collect()
+    }
+
+    /// Same as [`crate::analyze_contract`], only analyzing the code once for
+    /// each config.
+    pub fn analyze(&self, config: &Config) -> ContractAnalysis {
+        let fingerprint = config.fingerprint();
+        if let Some(analysis) = self.analyses.lock().unwrap().get(&fingerprint) {
+            return analysis.clone();
+        }
+        let analysis = analyze_contract(self.code.code(), config);
+        self.analyses.lock().unwrap().insert(fingerprint, analysis.clone());
+        analysis
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_vm_config;
+
+    const CONTRACT: &str = r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (memory (export "memory") 1)
+  (func (export "main"))
+  (func (export "add") (param i32) (result i32) (local.get 0)))"#;
+
+    #[test]
+    fn test_parsed_contract_code() {
+        let mut code = wat::parse_str(CONTRACT).unwrap();
+        // A custom section `unc_abi` holding `{}`.
+        code.extend_from_slice(b"\0\x0a\x07unc_abi{}");
+        let config = test_vm_config();
+        let parsed = ParsedContractCode::new(ContractCode::new(code.clone(), None)).unwrap();
+        assert_eq!(
+            parsed.exports(),
+            [
+                ParsedExport {
+                    name: "memory".to_string(),
+                    kind: ParsedExportKind::Memory,
+                    index: 0
+                },
+                ParsedExport {
+                    name: "main".to_string(),
+                    kind: ParsedExportKind::Function,
+                    index: 1
+                },
+                ParsedExport {
+                    name: "add".to_string(),
+                    kind: ParsedExportKind::Function,
+                    index: 2
+                },
+            ]
+        );
+        assert_eq!(parsed.export("add").map(|export| export.index), Some(2));
+        assert_eq!(parsed.custom_section("unc_abi"), Some(&b"{}"[..]));
+        assert_eq!(parsed.custom_section("name"), None);
+        assert!(parsed.section(2).is_some());
+        assert_eq!(
+            parsed.exported_methods(&config).unwrap(),
+            crate::exported_methods(&code, &config).unwrap()
+        );
+        assert_eq!(parsed.analyze(&config), analyze_contract(&code, &config));
+        assert_eq!(parsed.analyze(&config), analyze_contract(&code, &config));
+        assert_eq!(parsed.analyses.lock().unwrap().len(), 1);
+
+        let garbage = ContractCode::new(b"\0asm\x01\0\0\0\x01".to_vec(), None);
+        assert_eq!(ParsedContractCode::new(garbage).err(), Some(PrepareError::Deserialization));
+    }
+}
@@ -2,11 +2,132 @@
 //! wasm module before execution.
 
 use crate::logic::errors::PrepareError;
-use unc_parameters::vm::{Config, VMKind};
+use crate::logic::Config;
+use unc_parameters::vm::VMKind;
 
+mod aggregate_gas;
+mod blocklist;
+mod nan_canonicalization;
 mod prepare_v0;
 mod prepare_v1;
 mod prepare_v2;
+mod prepare_v3;
+
+pub use blocklist::{BlockedOpcode, OpcodeBlocklist, UnknownOpcode};
+pub(crate) use prepare_v2::SimpleMaxStackCfg as StackSizeCfg;
+pub use prepare_v3::{GasInstrumentation, PreparePasses, StackLimiter};
+
+/// Bound on the work of validating and instrumenting a contract prepared
+/// with V2, failing with [`PrepareError::TooComplex`] beyond it.
+///
+/// The work is counted in operations from the structure of the module rather
+/// than timed, so that every node rejects the same contracts.  Most operators
+/// take one operation; calls, blocks and branch tables take as many more as
+/// the values or targets they handle, since those make the validation and the
+/// instrumentation of a single operator as costly, and each group of locals
+/// takes one.  V0 and V1 are not budgeted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PrepareBudget {
+    /// Operations the validation and instrumentation of the whole contract
+    /// may take.
+    pub max_operations: u64,
+}
+
+impl PrepareBudget {
+    /// The budget of the contracts of `config`, used by [`prepare_contract`]:
+    /// unbounded unless the config sets
+    /// `extra_limits.max_prepare_operations_per_contract_byte`.  The budget is
+    /// then per byte of `max_contract_size`, so contracts exceed it only by
+    /// making each byte of code do many operations.
+    pub fn for_config(config: &Config) -> Self {
+        let Some(per_byte) = config.extra_limits.max_prepare_operations_per_contract_byte else {
+            return Self { max_operations: u64::MAX };
+        };
+        let max_contract_size = config.limit_config.max_contract_size;
+        Self { max_operations: max_contract_size.saturating_mul(per_byte) }
+    }
+}
+
+/// Bounds on the control flow of the functions of a contract prepared with
+/// V2.
+///
+/// The backends each had limits of their own on these, failing with messages
+/// of their own deep inside their compilers.  Contracts beyond these are
+/// rejected by every backend with [`PrepareError::BrTableTooLarge`] and
+/// [`PrepareError::TooDeeplyNested`] before any of them compiles the code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ControlFlowLimits {
+    /// Targets a `br_table` may have, its default target not included.
+    pub max_br_table_targets: Option<u32>,
+    /// Blocks, loops and ifs which may enclose one another in a function, the
+    /// body of the function not included.
+    pub max_nesting_depth: Option<u32>,
+}
+
+impl ControlFlowLimits {
+    /// The limits of the contracts of `config`, those of its `extra_limits`.
+    pub fn for_config(config: &Config) -> Self {
+        Self {
+            max_br_table_targets: config.extra_limits.max_br_table_targets,
+            max_nesting_depth: config.extra_limits.max_nesting_depth,
+        }
+    }
+}
+
+/// Bound on the size of each function of a contract, beyond which it fails
+/// with [`PrepareError::FunctionTooLarge`].
+///
+/// Some toolchains emit a whole contract as a single giant function, which
+/// the compilers of the backends take disproportionately long to compile and
+/// compile into slow code.  The error cannot name the function without
+/// changing its serialization, so [`crate::function_size_diagnostics`] tells
+/// the authors of the contract which functions to split.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FunctionSizeLimit {
+    /// Bytes the body of a function may take as encoded, its locals
+    /// included.
+    pub max_function_body_size: u64,
+}
+
+impl FunctionSizeLimit {
+    /// The limit of the contracts of `config`, if they have one: only the
+    /// contracts prepared with V2 do, when the config sets
+    /// `extra_limits.max_function_body_size`.
+    pub fn for_config(config: &Config) -> Option<Self> {
+        let prepare = config.limit_config.contract_prepare_version;
+        if prepare != crate::logic::ContractPrepareVersion::V2 {
+            return None;
+        }
+        let max_function_body_size = config.extra_limits.max_function_body_size?;
+        Some(Self { max_function_body_size })
+    }
+}
+
+/// Where the NaNs produced by the floating point instructions of the
+/// contracts of a config are made canonical.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NanCanonicalization {
+    /// By the compilers of the backends, each with a setting of its own.
+    Compiler,
+    /// By a pass of the preparation, see `nan_canonicalization`, the
+    /// compilers leaving them as the CPU produces them.  All backends then
+    /// run the same instructions, which are metered like the others.
+    Prepare,
+}
+
+impl NanCanonicalization {
+    /// Where the NaNs of the contracts of `config` are made canonical, by
+    /// the pass for the contracts prepared with V2 when
+    /// [`Config::nan_canonicalization_pass`] is set.
+    pub fn for_config(config: &Config) -> Self {
+        let prepare = config.limit_config.contract_prepare_version;
+        if config.nan_canonicalization_pass && prepare == crate::logic::ContractPrepareVersion::V2 {
+            Self::Prepare
+        } else {
+            Self::Compiler
+        }
+    }
+}
 
 /// Loads the given module given in `original_code`, performs some checks on it and
 /// does some preprocessing.
@@ -17,12 +138,25 @@ mod prepare_v2;
 /// - imported memory (if any) doesn't reserve more memory than permitted by the `config`,
 /// - all imported functions from the external environment matches defined by `env` module,
 /// - functions number does not exceed limit specified in Config,
+/// - branch tables and nested blocks do not exceed the [`ControlFlowLimits`],
+/// - function bodies do not exceed the [`FunctionSizeLimit`], if any,
 ///
 /// The preprocessing includes injecting code for gas metering and metering the height of stack.
 pub fn prepare_contract(
     original_code: &[u8],
     config: &Config,
     kind: VMKind,
+) -> Result<Vec<u8>, PrepareError> {
+    prepare_contract_with_budget(original_code, config, kind, PrepareBudget::for_config(config))
+}
+
+/// Same as [`prepare_contract`] with the given `budget` instead of the one of
+/// the config.
+pub fn prepare_contract_with_budget(
+    original_code: &[u8],
+    config: &Config,
+    kind: VMKind,
+    budget: PrepareBudget,
 ) -> Result<Vec<u8>, PrepareError> {
     let prepare = config.limit_config.contract_prepare_version;
     // NearVM => ContractPrepareVersion::V2
@@ -30,7 +164,15 @@ pub fn prepare_contract(
         (kind != VMKind::NearVm) || (prepare == crate::logic::ContractPrepareVersion::V2),
         "NearVM only works with contract prepare version V2",
     );
-    let features = crate::features::WasmFeatures::from(prepare);
+    config.opcode_blocklist.check(original_code, kind)?;
+    if config.coverage
+        && (!cfg!(feature = "coverage")
+            || prepare != crate::logic::ContractPrepareVersion::V2
+            || config.prepare_passes.is_some())
+    {
+        return Err(PrepareError::UnsupportedPasses);
+    }
+    let features = crate::features::WasmFeatures::from(config);
     match prepare {
         crate::logic::ContractPrepareVersion::V0 => {
             // NB: v1 here is not a bug, we are reusing the code.
@@ -41,12 +183,108 @@ pub fn prepare_contract(
             prepare_v1::validate_contract(original_code, features, config)?;
             prepare_v1::prepare_contract(original_code, config)
         }
+        crate::logic::ContractPrepareVersion::V2 => match &config.prepare_passes {
+            Some(passes) => prepare_with_passes(original_code, config, kind, passes),
+            None => prepare_v2::prepare_contract(original_code, features, config, kind, budget),
+        },
+    }
+}
+
+/// Same as [`prepare_contract`] with the given `passes` instead of the ones
+/// of the prepare version of the config.
+///
+/// This is the V3 preparation, of which the passes are chosen by the embedder
+/// rather than by the protocol version, to experiment with other metering.
+/// [`ContractPrepareVersion`](crate::logic::ContractPrepareVersion) is
+/// defined in `unc-parameters` and has no `V3`: the VMs prepare contracts
+/// with V3 when the prepare version of their config is V2 and its
+/// `prepare_passes` are set, and compile them with the features of those
+/// passes.
+///
+/// Validation, the standardized memory and the limits are those of V2.
+/// NearVM instruments contracts itself while compiling them, so it only
+/// takes the features of `passes`, and fails with
+/// [`PrepareError::UnsupportedPasses`] unless both instrumentations are those
+/// of finite-wasm.
+pub fn prepare_contract_with_passes(
+    original_code: &[u8],
+    config: &Config,
+    kind: VMKind,
+    passes: &PreparePasses,
+) -> Result<Vec<u8>, PrepareError> {
+    config.opcode_blocklist.check(original_code, kind)?;
+    if config.coverage {
+        return Err(PrepareError::UnsupportedPasses);
+    }
+    prepare_with_passes(original_code, config, kind, passes)
+}
+
+fn prepare_with_passes(
+    original_code: &[u8],
+    config: &Config,
+    kind: VMKind,
+    passes: &PreparePasses,
+) -> Result<Vec<u8>, PrepareError> {
+    if kind == VMKind::NearVm
+        && (passes.gas != GasInstrumentation::FiniteWasm
+            || passes.stack != StackLimiter::FiniteWasm)
+    {
+        return Err(PrepareError::UnsupportedPasses);
+    }
+    prepare_v3::prepare_contract(original_code, config, kind, passes)
+}
+
+/// Runs the checks of [`prepare_contract`] on `original_code`, without
+/// instrumenting it when the prepare version allows.
+pub(crate) fn validate_contract(original_code: &[u8], config: &Config) -> Result<(), PrepareError> {
+    let prepare = config.limit_config.contract_prepare_version;
+    match prepare {
+        crate::logic::ContractPrepareVersion::V0 | crate::logic::ContractPrepareVersion::V1 => {
+            prepare_contract(original_code, config, VMKind::Wasmer0).map(drop)
+        }
         crate::logic::ContractPrepareVersion::V2 => {
-            prepare_v2::prepare_contract(original_code, features, config, kind)
+            config.opcode_blocklist.check(original_code, config.vm_kind)?;
+            let features = crate::features::WasmFeatures::from(config);
+            prepare_v2::PrepareContext::new(original_code, features, config).run().map(drop)
         }
     }
 }
 
+/// Whether the finite-wasm instrumentation is the only limit on the stack of
+/// the contracts of `config`, see [`Config::deterministic_stack_limit`].
+pub(crate) fn instrumented_stack_limit_only(config: &Config) -> bool {
+    config.deterministic_stack_limit
+        && config.limit_config.contract_prepare_version == crate::logic::ContractPrepareVersion::V2
+}
+
+/// Largest stack an activation of a function of `original_code` takes, as
+/// the stack limiter of V2 accounts it against `max_stack_height`.
+pub(crate) fn max_function_stack(original_code: &[u8]) -> Result<u64, PrepareError> {
+    prepare_v2::max_function_stack(original_code)
+}
+
+/// Checks a memory import of the contract against the import policy.
+///
+/// The standardized memory satisfies an import declaring `initial` and
+/// `maximum` pages under the usual wasm linking rules: it must have at least
+/// as many initial pages and at most as many maximum pages as declared.
+fn check_memory_import(
+    config: &Config,
+    name: &str,
+    initial: u64,
+    maximum: Option<u64>,
+) -> Result<(), PrepareError> {
+    let limits = &config.limit_config;
+    let satisfied = name == "memory"
+        && u64::from(limits.initial_memory_pages) >= initial
+        && maximum.map_or(true, |maximum| u64::from(limits.max_memory_pages) <= maximum);
+    if config.host_imported_memory && satisfied {
+        Ok(())
+    } else {
+        Err(PrepareError::Memory)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -116,6 +354,176 @@ mod tests {
         })
     }
 
+    #[test]
+    fn memory_imports_satisfied_by_host_memory() {
+        let mut config = test_vm_config();
+        config.host_imported_memory = true;
+        let limits = &config.limit_config;
+        let (initial, max) = (limits.initial_memory_pages, limits.max_memory_pages);
+        with_vm_variants(&config, |kind| {
+            let wat = format!(r#"(module (import "env" "memory" (memory {initial} {max})))"#);
+            let r = parse_and_prepare_wat(&config, kind, &wat);
+            assert_matches!(r, Ok(_));
+            let r = parse_and_prepare_wat(&test_vm_config(), kind, &wat);
+            assert_matches!(r, Err(PrepareError::Memory));
+
+            // no maximum
+            let r = parse_and_prepare_wat(
+                &config,
+                kind,
+                r#"(module (import "env" "memory" (memory 1)))"#,
+            );
+            assert_matches!(r, Ok(_));
+
+            // more initial pages than the host memory has
+            let wat = format!(r#"(module (import "env" "memory" (memory {})))"#, initial + 1);
+            let r = parse_and_prepare_wat(&config, kind, &wat);
+            assert_matches!(r, Err(PrepareError::Memory));
+
+            // only `env.memory` can be provided
+            let wat = format!(r#"(module (import "env" "heap" (memory {initial} {max})))"#);
+            let r = parse_and_prepare_wat(&config, kind, &wat);
+            assert_matches!(r, Err(PrepareError::Memory));
+        })
+    }
+
+    #[test]
+    fn prepare_budget() {
+        let mut config = test_vm_config();
+        config.limit_config.contract_prepare_version = crate::logic::ContractPrepareVersion::V2;
+        let prepare = |wat: &str, max_operations| {
+            let code = wat::parse_str(wat).unwrap();
+            let budget = PrepareBudget { max_operations };
+            prepare_contract_with_budget(&code, &config, VMKind::Wasmtime, budget)
+        };
+        // Two operators, the implicit `end` and a group of locals.
+        let wat = r#"(module (func (export "main") (local i32 i64) nop nop))"#;
+        assert_matches!(prepare(wat, 5), Ok(_));
+        assert_matches!(prepare(wat, 4), Err(PrepareError::TooComplex));
+
+        // Unreachable code calls functions without pushing their arguments.
+        let params = "i32 ".repeat(1000);
+        let calls = "(call $many) ".repeat(100);
+        let wat = format!(
+            r#"(module (func $many (param {params})) (func (export "main") unreachable {calls}))"#
+        );
+        assert_matches!(prepare(&wat, 100_000), Err(PrepareError::TooComplex));
+        assert_matches!(prepare(&wat, 101_000), Ok(_));
+        let code = wat::parse_str(&wat).unwrap();
+        assert_matches!(prepare_contract(&code, &config, VMKind::Wasmtime), Ok(_));
+
+        assert_eq!(PrepareBudget::for_config(&config).max_operations, u64::MAX);
+        config.extra_limits.max_prepare_operations_per_contract_byte = Some(8);
+        let max_operations = config.limit_config.max_contract_size * 8;
+        assert_eq!(PrepareBudget::for_config(&config), PrepareBudget { max_operations });
+        assert_matches!(prepare_contract(&code, &config, VMKind::Wasmtime), Ok(_));
+        config.limit_config.max_contract_size = 10_000;
+        assert_matches!(
+            prepare_contract(&code, &config, VMKind::Wasmtime),
+            Err(PrepareError::TooComplex)
+        );
+    }
+
+    #[test]
+    fn coverage_pass() {
+        let mut config = test_vm_config();
+        config.limit_config.contract_prepare_version = crate::logic::ContractPrepareVersion::V2;
+        config.coverage = true;
+        let code = wat::parse_str(r#"(module (func (export "main")))"#).unwrap();
+        let prepared = prepare_contract(&code, &config, VMKind::Wasmtime);
+        if cfg!(feature = "coverage") {
+            let prepared = prepared.unwrap();
+            assert!(prepared.windows(12).any(|bytes| bytes == b"unc_coverage"));
+        } else {
+            assert_matches!(prepared, Err(PrepareError::UnsupportedPasses));
+        }
+        // Contracts cannot import the module themselves.
+        let wat = r#"(module (import "unc_coverage" "coverage_hit" (func (param i32))))"#;
+        let code = wat::parse_str(wat).unwrap();
+        let plain = Config { coverage: false, ..config.clone() };
+        assert_matches!(
+            prepare_contract(&code, &plain, VMKind::Wasmtime),
+            Err(PrepareError::Instantiate)
+        );
+
+        config.limit_config.contract_prepare_version = crate::logic::ContractPrepareVersion::V1;
+        assert_matches!(
+            prepare_contract(&code, &config, VMKind::Wasmer0),
+            Err(PrepareError::UnsupportedPasses)
+        );
+    }
+
+    #[test]
+    fn control_flow_limits() {
+        let mut config = test_vm_config();
+        config.limit_config.contract_prepare_version = crate::logic::ContractPrepareVersion::V2;
+        let (max_br_table_targets, max_nesting_depth) = (1000, 100);
+        let unlimited = config.clone();
+        config.extra_limits.max_br_table_targets = Some(max_br_table_targets);
+        config.extra_limits.max_nesting_depth = Some(max_nesting_depth);
+        let prepare_with = |wat: &str, config: &Config| {
+            let code = wat::parse_str(wat).unwrap();
+            let budget = PrepareBudget { max_operations: u64::MAX };
+            prepare_contract_with_budget(&code, config, VMKind::Wasmtime, budget)
+        };
+        let prepare = |wat: &str| prepare_with(wat, &config);
+
+        // All the targets and the default exit the same block.
+        let br_table = |targets: u32| {
+            let labels = " 0".repeat(targets as usize + 1);
+            format!(r#"(module (func (export "main") block i32.const 0 br_table{labels} end))"#)
+        };
+        assert_matches!(prepare(&br_table(max_br_table_targets)), Ok(_));
+        assert_matches!(
+            prepare(&br_table(max_br_table_targets + 1)),
+            Err(PrepareError::BrTableTooLarge)
+        );
+        assert_matches!(prepare_with(&br_table(max_br_table_targets + 1), &unlimited), Ok(_));
+
+        let nested = |depth: u32, block: &str| {
+            let blocks = block.repeat(depth as usize);
+            let ends = "end ".repeat(depth as usize);
+            format!(r#"(module (func (export "main") {blocks} {ends}))"#)
+        };
+        for block in ["block ", "loop ", "i32.const 0 if "] {
+            assert_matches!(prepare(&nested(max_nesting_depth, block)), Ok(_));
+            assert_matches!(
+                prepare(&nested(max_nesting_depth + 1, block)),
+                Err(PrepareError::TooDeeplyNested)
+            );
+            assert_matches!(prepare_with(&nested(max_nesting_depth + 1, block), &unlimited), Ok(_));
+        }
+        // The depth is of the blocks enclosing one another, not of all the
+        // blocks of the function.
+        let siblings = "block end ".repeat(max_nesting_depth as usize + 1);
+        let wat = format!(r#"(module (func (export "main") {siblings}) (func {siblings}))"#);
+        assert_matches!(prepare(&wat), Ok(_));
+    }
+
+    #[test]
+    fn function_size_limit() {
+        let mut config = test_vm_config();
+        config.limit_config.contract_prepare_version = crate::logic::ContractPrepareVersion::V2;
+        assert_eq!(FunctionSizeLimit::for_config(&config), None);
+        let limit = 1000;
+        config.extra_limits.max_function_body_size = Some(limit);
+        // The body holds the number of groups of locals, the `nop`s and the
+        // final `end`, a byte each.
+        let function = |size: u64| {
+            let nops = "nop ".repeat(size as usize - 2);
+            wat::parse_str(format!(r#"(module (func (export "main") {nops}))"#)).unwrap()
+        };
+        let prepare = |code: &[u8]| {
+            let budget = PrepareBudget { max_operations: u64::MAX };
+            prepare_contract_with_budget(code, &config, VMKind::Wasmtime, budget)
+        };
+        assert_matches!(prepare(&function(limit)), Ok(_));
+        assert_matches!(prepare(&function(limit + 1)), Err(PrepareError::FunctionTooLarge));
+
+        config.limit_config.contract_prepare_version = crate::logic::ContractPrepareVersion::V1;
+        assert_eq!(FunctionSizeLimit::for_config(&config), None);
+    }
+
     #[test]
     fn multiple_valid_memory_are_disabled() {
         let config = test_vm_config();
@@ -162,6 +570,14 @@ mod tests {
             );
             assert_matches!(r, Ok(_));
 
+            // host functions under trial can only be imported with the flag set.
+            let trial = r#"(module (import "env_experimental" "trial" (func)))"#;
+            let r = parse_and_prepare_wat(&config, kind, trial);
+            assert_matches!(r, Err(PrepareError::Instantiate));
+            let experimental = Config { experimental_host_fns: true, ..config.clone() };
+            let r = parse_and_prepare_wat(&experimental, kind, trial);
+            assert_matches!(r, Ok(_));
+
             // TODO: Address tests once we check proper function signatures.
             /*
             // wrong signature
@@ -0,0 +1,414 @@
+//! Gas of the instructions doing work in proportion to an operand.
+//!
+//! The finite-wasm analysis charges each instruction a fixed cost before it
+//! runs, which does not bound the instructions of the bulk memory and the
+//! reference types proposals copying, filling or growing a whole range at
+//! once.  These instructions take the length of the range as their last
+//! operand, and this pass charges for it: before each of them, it calls
+//! `internal.finite_wasm_gas` with the length times the gas of a unit, a
+//! byte of a memory or an element of a table.  The length is charged before
+//! the instruction runs, so that a call with too little gas for it stops
+//! with the gas exceeded rather than with the trap of the instruction.
+//!
+//! The import is appended to the function imports of the module, which moves
+//! the functions of the module one index up: the pass renumbers the calls,
+//! `ref.func`s, exports, start function and element segments to match.  The
+//! names of the name section are left as they are.  Modules without such
+//! instructions are returned unchanged.
+
+use crate::logic::errors::PrepareError;
+use finite_wasm::wasmparser as wp;
+use crate::logic::Config;
+use wasm_encoder::{Encode, Instruction, RawSection, Section, SectionId};
+
+/// Bytes a bulk memory instruction copies or fills for the gas of a regular
+/// instruction.
+const BYTES_PER_REGULAR_OP: u64 = 8;
+
+/// The gas of a unit of the length operand of `op`, for the instructions
+/// which have one.
+fn gas_per_unit(op: &wp::Operator, regular_op_cost: u64) -> Option<u64> {
+    match op {
+        wp::Operator::MemoryCopy { .. }
+        | wp::Operator::MemoryFill { .. }
+        | wp::Operator::MemoryInit { .. } => Some(regular_op_cost.div_ceil(BYTES_PER_REGULAR_OP)),
+        wp::Operator::TableCopy { .. }
+        | wp::Operator::TableFill { .. }
+        | wp::Operator::TableInit { .. }
+        | wp::Operator::TableGrow { .. } => Some(regular_op_cost),
+        _ => None,
+    }
+}
+
+/// Whether `body` has an instruction charged by the pass.
+fn has_aggregates(body: &wp::FunctionBody, regular_op_cost: u64) -> Result<bool, PrepareError> {
+    let mut reader = body.get_operators_reader().map_err(|_| PrepareError::Deserialization)?;
+    while !reader.eof() {
+        let op = reader.read().map_err(|_| PrepareError::Deserialization)?;
+        if gas_per_unit(&op, regular_op_cost).is_some() {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Charges the instructions of `code`, a module validated by the early
+/// preparation, for their length operand, see the module documentation.
+pub(super) fn instrument(code: &[u8], config: &Config) -> Result<Vec<u8>, PrepareError> {
+    let regular_op_cost = u64::from(config.regular_op_cost);
+    let mut types = 0;
+    let mut imported_functions = 0;
+    let mut charged = false;
+    for payload in wp::Parser::new(0).parse_all(code) {
+        match payload.map_err(|_| PrepareError::Deserialization)? {
+            wp::Payload::TypeSection(reader) => types = reader.count(),
+            wp::Payload::ImportSection(reader) => {
+                for import in reader {
+                    let import = import.map_err(|_| PrepareError::Deserialization)?;
+                    if let wp::TypeRef::Func(_) = import.ty {
+                        imported_functions += 1;
+                    }
+                }
+            }
+            wp::Payload::CodeSectionEntry(body) => {
+                charged = charged || has_aggregates(&body, regular_op_cost)?;
+            }
+            _ => {}
+        }
+    }
+    if !charged {
+        return Ok(code.to_vec());
+    }
+    Instrumentation {
+        code,
+        regular_op_cost,
+        gas_type: types,
+        gas_function: imported_functions,
+        function_types: Vec::new(),
+        param_counts: Vec::new(),
+        output: Vec::with_capacity(code.len()),
+    }
+    .run()
+}
+
+struct Instrumentation<'a> {
+    code: &'a [u8],
+    regular_op_cost: u64,
+    /// Index of the type of the gas import, appended to the types.
+    gas_type: u32,
+    /// Index of the gas import, appended to the function imports.
+    gas_function: u32,
+    /// Type of each function of the module, imports excluded.
+    function_types: Vec<u32>,
+    /// Parameters of each type of the module.
+    param_counts: Vec<u32>,
+    output: Vec<u8>,
+}
+
+impl Instrumentation<'_> {
+    fn run(mut self) -> Result<Vec<u8>, PrepareError> {
+        let mut code_section = wasm_encoder::CodeSection::new();
+        let mut function = 0;
+        for payload in wp::Parser::new(0).parse_all(self.code) {
+            match payload.map_err(|_| PrepareError::Deserialization)? {
+                wp::Payload::Version { range, .. } => {
+                    self.output.extend_from_slice(&self.code[range]);
+                }
+                wp::Payload::TypeSection(reader) => {
+                    for ty in reader.clone() {
+                        let wp::Type::Func(ty) = ty.map_err(|_| PrepareError::Deserialization)?;
+                        self.param_counts.push(ty.params().len() as u32);
+                    }
+                    // The type `(func (param i64))` of the gas import.
+                    self.append_entry(SectionId::Type, &reader, &[0x60, 1, 0x7e, 0])?;
+                }
+                wp::Payload::ImportSection(reader) => {
+                    let mut import = Vec::new();
+                    "internal".encode(&mut import);
+                    "finite_wasm_gas".encode(&mut import);
+                    import.push(0x00);
+                    self.gas_type.encode(&mut import);
+                    self.append_entry(SectionId::Import, &reader, &import)?;
+                }
+                wp::Payload::FunctionSection(reader) => {
+                    for ty in reader.clone() {
+                        self.function_types.push(ty.map_err(|_| PrepareError::Deserialization)?);
+                    }
+                    self.copy_section(SectionId::Function, reader.range());
+                }
+                wp::Payload::GlobalSection(reader) => {
+                    let mut globals = Vec::new();
+                    reader.count().encode(&mut globals);
+                    for global in reader.into_iter_with_offsets() {
+                        let (offset, global) = global.map_err(|_| PrepareError::Deserialization)?;
+                        let init_expr = global.init_expr.get_binary_reader();
+                        globals
+                            .extend_from_slice(&self.code[offset..init_expr.original_position()]);
+                        self.rewrite(init_expr, None, &mut globals)?;
+                    }
+                    RawSection { id: SectionId::Global as u8, data: &globals }
+                        .append_to(&mut self.output);
+                }
+                wp::Payload::ExportSection(reader) => {
+                    let mut exports = wasm_encoder::ExportSection::new();
+                    for export in reader {
+                        let export = export.map_err(|_| PrepareError::Deserialization)?;
+                        let (kind, index) = match export.kind {
+                            wp::ExternalKind::Func => {
+                                (wasm_encoder::ExportKind::Func, self.remap(export.index))
+                            }
+                            wp::ExternalKind::Table => {
+                                (wasm_encoder::ExportKind::Table, export.index)
+                            }
+                            wp::ExternalKind::Memory => {
+                                (wasm_encoder::ExportKind::Memory, export.index)
+                            }
+                            wp::ExternalKind::Global => {
+                                (wasm_encoder::ExportKind::Global, export.index)
+                            }
+                            wp::ExternalKind::Tag => (wasm_encoder::ExportKind::Tag, export.index),
+                        };
+                        exports.export(export.name, kind, index);
+                    }
+                    exports.append_to(&mut self.output);
+                }
+                wp::Payload::StartSection { func, .. } => {
+                    wasm_encoder::StartSection { function_index: self.remap(func) }
+                        .append_to(&mut self.output);
+                }
+                wp::Payload::ElementSection(reader) => {
+                    let mut elements = Vec::new();
+                    reader.count().encode(&mut elements);
+                    for element in reader {
+                        let element = element.map_err(|_| PrepareError::Deserialization)?;
+                        self.rewrite_element(&element, &mut elements)?;
+                    }
+                    RawSection { id: SectionId::Element as u8, data: &elements }
+                        .append_to(&mut self.output);
+                }
+                wp::Payload::CodeSectionStart { count, .. } => {
+                    if count == 0 {
+                        code_section.append_to(&mut self.output);
+                    }
+                }
+                wp::Payload::CodeSectionEntry(body) => {
+                    code_section.raw(&self.rewrite_body(&body, function)?);
+                    function += 1;
+                    if function == self.function_types.len() {
+                        code_section.append_to(&mut self.output);
+                    }
+                }
+                wp::Payload::TableSection(reader) => {
+                    self.copy_section(SectionId::Table, reader.range());
+                }
+                wp::Payload::DataCountSection { range, .. } => {
+                    self.copy_section(SectionId::DataCount, range);
+                }
+                wp::Payload::DataSection(reader) => {
+                    self.copy_section(SectionId::Data, reader.range());
+                }
+                wp::Payload::CustomSection(reader) => {
+                    self.copy_section(SectionId::Custom, reader.range());
+                }
+                wp::Payload::End(_) => {}
+                // The early preparation rejects the other sections, and
+                // replaces the memories with an import.
+                _ => return Err(PrepareError::Deserialization),
+            }
+        }
+        Ok(self.output)
+    }
+
+    /// Index of the function `index` of the original module.
+    fn remap(&self, index: u32) -> u32 {
+        if index < self.gas_function {
+            index
+        } else {
+            index + 1
+        }
+    }
+
+    fn copy_section(&mut self, id: SectionId, range: std::ops::Range<usize>) {
+        RawSection { id: id as u8, data: &self.code[range] }.append_to(&mut self.output);
+    }
+
+    /// Copies the section of `reader` with `entry` appended to its entries.
+    fn append_entry<T>(
+        &mut self,
+        id: SectionId,
+        reader: &wp::SectionLimited<'_, T>,
+        entry: &[u8],
+    ) -> Result<(), PrepareError> {
+        let range = reader.range();
+        let mut count_reader =
+            wp::BinaryReader::new_with_offset(&self.code[range.clone()], range.start);
+        count_reader.read_var_u32().map_err(|_| PrepareError::Deserialization)?;
+        let mut section = Vec::new();
+        (reader.count() + 1).encode(&mut section);
+        section.extend_from_slice(&self.code[count_reader.original_position()..range.end]);
+        section.extend_from_slice(entry);
+        RawSection { id: id as u8, data: &section }.append_to(&mut self.output);
+        Ok(())
+    }
+
+    fn rewrite_element(
+        &self,
+        element: &wp::Element<'_>,
+        output: &mut Vec<u8>,
+    ) -> Result<(), PrepareError> {
+        let items = match &element.items {
+            wp::ElementItems::Functions(functions) => functions.range(),
+            wp::ElementItems::Expressions(expressions) => expressions.range(),
+        };
+        // The flags, table, offset and type of the segment do not refer to
+        // functions.
+        output.extend_from_slice(&self.code[element.range.start..items.start]);
+        match &element.items {
+            wp::ElementItems::Functions(functions) => {
+                functions.count().encode(output);
+                for function in functions.clone() {
+                    let function = function.map_err(|_| PrepareError::Deserialization)?;
+                    self.remap(function).encode(output);
+                }
+            }
+            wp::ElementItems::Expressions(expressions) => {
+                expressions.count().encode(output);
+                for expression in expressions.clone() {
+                    let expression = expression.map_err(|_| PrepareError::Deserialization)?;
+                    self.rewrite(expression.get_binary_reader(), None, output)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The body of the function `function` of the module, excluding the
+    /// imports, charged and renumbered.
+    fn rewrite_body(
+        &self,
+        body: &wp::FunctionBody<'_>,
+        function: usize,
+    ) -> Result<Vec<u8>, PrepareError> {
+        let map_err = |_| PrepareError::Deserialization;
+        let mut reader = body.get_binary_reader();
+        let groups = reader.read_var_u32().map_err(map_err)?;
+        let groups_start = reader.original_position();
+        let mut locals = self
+            .function_types
+            .get(function)
+            .map_or(0, |ty| self.param_counts.get(*ty as usize).copied().unwrap_or_default());
+        for _ in 0..groups {
+            locals += reader.read_var_u32().map_err(map_err)?;
+            reader.read::<wp::ValType>().map_err(map_err)?;
+        }
+        let groups_end = reader.original_position();
+
+        let mut output = Vec::new();
+        let scratch = if has_aggregates(body, self.regular_op_cost)? {
+            // An `i32` local keeping the length while it is charged.
+            (groups + 1).encode(&mut output);
+            output.extend_from_slice(&self.code[groups_start..groups_end]);
+            output.extend_from_slice(&[1, 0x7f]);
+            Some(locals)
+        } else {
+            output.extend_from_slice(&self.code[body.range().start..groups_end]);
+            None
+        };
+        self.rewrite(reader, scratch, &mut output)?;
+        Ok(output)
+    }
+
+    /// Copies the instructions of `reader`, renumbering the functions and,
+    /// given a `scratch` local, charging the length operands.
+    fn rewrite(
+        &self,
+        mut reader: wp::BinaryReader<'_>,
+        scratch: Option<u32>,
+        output: &mut Vec<u8>,
+    ) -> Result<(), PrepareError> {
+        while !reader.eof() {
+            let start = reader.original_position();
+            let op = reader.read_operator().map_err(|_| PrepareError::Deserialization)?;
+            let end = reader.original_position();
+            let instruction = match op {
+                wp::Operator::Call { function_index } => {
+                    Instruction::Call(self.remap(function_index))
+                }
+                wp::Operator::ReturnCall { function_index } => {
+                    Instruction::ReturnCall(self.remap(function_index))
+                }
+                wp::Operator::RefFunc { function_index } => {
+                    Instruction::RefFunc(self.remap(function_index))
+                }
+                _ => {
+                    let gas = gas_per_unit(&op, self.regular_op_cost);
+                    if let (Some(gas), Some(scratch)) = (gas, scratch) {
+                        // `length` is on top of the stack, and the product
+                        // of two `u32`s does not overflow a `u64`.
+                        for instruction in [
+                            Instruction::LocalTee(scratch),
+                            Instruction::LocalGet(scratch),
+                            Instruction::I64ExtendI32U,
+                            Instruction::I64Const(gas as i64),
+                            Instruction::I64Mul,
+                            Instruction::Call(self.gas_function),
+                        ] {
+                            instruction.encode(output);
+                        }
+                    }
+                    output.extend_from_slice(&self.code[start..end]);
+                    continue;
+                }
+            };
+            instruction.encode(output);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_vm_config;
+
+    #[test]
+    fn test_instrument() {
+        let config = test_vm_config();
+        let plain = wat::parse_str(r#"(module (func (export "main") nop))"#).unwrap();
+        assert_eq!(instrument(&plain, &config).unwrap(), plain);
+
+        let code = wat::parse_str(
+            r#"
+(module
+  (import "env" "memory" (memory 1))
+  (import "env" "abort" (func))
+  (func $fill (param i32)
+    (memory.fill (i32.const 0) (i32.const 1) (local.get 0)))
+  (func $start (call $fill (i32.const 8)))
+  (start $start)
+  (export "fill" (func $fill)))"#,
+        )
+        .unwrap();
+        let instrumented = instrument(&code, &config).unwrap();
+        wp::Validator::new().validate_all(&instrumented).unwrap();
+        let mut imports = Vec::new();
+        for payload in wp::Parser::new(0).parse_all(&instrumented) {
+            match payload.unwrap() {
+                wp::Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import.unwrap();
+                        imports.push(format!("{}.{}", import.module, import.name));
+                    }
+                }
+                // The functions of the module come after the two imported.
+                wp::Payload::StartSection { func, .. } => assert_eq!(func, 3),
+                wp::Payload::ExportSection(reader) => {
+                    let export = reader.into_iter().next().unwrap().unwrap();
+                    assert_eq!((export.name, export.index), ("fill", 2));
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(imports, ["env.memory", "env.abort", "internal.finite_wasm_gas"]);
+    }
+}
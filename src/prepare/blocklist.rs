@@ -0,0 +1,223 @@
+//! Instructions a network rejects in the contracts it prepares.
+//!
+//! Proposal gating decides which instructions a prepare version accepts.  A
+//! network finding that a backend miscompiles one of them can ban it with an
+//! [`OpcodeBlocklist`] until the fix ships, rather than waiting for a new
+//! protocol version: the preparations with a config of which
+//! `opcode_blocklist` has the instruction reject the contracts using it with
+//! [`PrepareError::BlockedOpcode`].
+//!
+//! The check runs on the original contract before any prepare version looks
+//! at it, so that a contract is rejected the same way whatever the version.
+//! Contracts which do not parse are left to the preparation, which rejects
+//! them with its own error.  The blocklist decides which contracts are valid,
+//! so every node of a network must use the same.  It is part of the config,
+//! and so of the cache keys of the artifacts compiled under it, so that the
+//! artifacts compiled before a ban cannot be loaded without the check.
+
+use crate::logic::errors::PrepareError;
+use finite_wasm::wasmparser as wp;
+use std::collections::BTreeSet;
+use unc_parameters::vm::VMKind;
+
+/// An instruction of an [`OpcodeBlocklist`].
+#[derive(Clone, Debug, Hash, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BlockedOpcode {
+    /// Name of the instruction in the text format, e.g. `i64.div_s`.  The
+    /// dots may also be written as underscores.
+    pub opcode: String,
+    /// The VMs on which the instruction is rejected, all of them if empty.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vm_kinds: Vec<VMKind>,
+}
+
+/// Instructions rejected by the preparation, see the module documentation.
+///
+/// Deserializes from a list of [`BlockedOpcode`], e.g.
+/// `[{ "opcode": "f64.nearest", "vm_kinds": ["NearVm"] }]`.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(try_from = "Vec<BlockedOpcode>", into = "Vec<BlockedOpcode>")]
+pub struct OpcodeBlocklist {
+    opcodes: Vec<BlockedOpcode>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("unknown wasm instruction: {0}")]
+pub struct UnknownOpcode(pub String);
+
+impl OpcodeBlocklist {
+    /// Fails if the name of one of `opcodes` is not an instruction, so that a
+    /// typo does not leave the instruction allowed.
+    pub fn new(opcodes: Vec<BlockedOpcode>) -> Result<Self, UnknownOpcode> {
+        for blocked in &opcodes {
+            if operator(&blocked.opcode).is_none() {
+                return Err(UnknownOpcode(blocked.opcode.clone()));
+            }
+        }
+        Ok(Self { opcodes })
+    }
+
+    pub fn opcodes(&self) -> &[BlockedOpcode] {
+        &self.opcodes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.opcodes.is_empty()
+    }
+
+    /// Names, as [`OperatorName`] gives them, of the instructions rejected on
+    /// `vm_kind`.
+    fn blocked(&self, vm_kind: VMKind) -> BTreeSet<&'static str> {
+        self.opcodes
+            .iter()
+            .filter(|blocked| blocked.vm_kinds.is_empty() || blocked.vm_kinds.contains(&vm_kind))
+            .filter_map(|blocked| operator(&blocked.opcode))
+            .collect()
+    }
+
+    /// Rejects `code` if it uses an instruction blocked on `vm_kind`.
+    pub(crate) fn check(&self, code: &[u8], vm_kind: VMKind) -> Result<(), PrepareError> {
+        let blocked = self.blocked(vm_kind);
+        if blocked.is_empty() {
+            return Ok(());
+        }
+        for payload in wp::Parser::new(0).parse_all(code) {
+            let body = match payload {
+                Ok(wp::Payload::CodeSectionEntry(body)) => body,
+                Ok(_) => continue,
+                Err(_) => return Ok(()),
+            };
+            let Ok(mut reader) = body.get_operators_reader() else { return Ok(()) };
+            while !reader.eof() {
+                match reader.visit_operator(&mut OperatorName) {
+                    Ok(name) if blocked.contains(name) => return Err(PrepareError::BlockedOpcode),
+                    Ok(_) => {}
+                    Err(_) => return Ok(()),
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TryFrom<Vec<BlockedOpcode>> for OpcodeBlocklist {
+    type Error = UnknownOpcode;
+
+    fn try_from(opcodes: Vec<BlockedOpcode>) -> Result<Self, UnknownOpcode> {
+        Self::new(opcodes)
+    }
+}
+
+impl From<OpcodeBlocklist> for Vec<BlockedOpcode> {
+    fn from(blocklist: OpcodeBlocklist) -> Self {
+        blocklist.opcodes
+    }
+}
+
+/// The name of the instruction in [`OperatorName`] form, if `name` is one.
+fn operator(name: &str) -> Option<&'static str> {
+    let name = name.replace('.', "_");
+    OPERATORS.iter().map(|visit| &visit["visit_".len()..]).find(|operator| *operator == name)
+}
+
+macro_rules! visit_names {
+    ($(@$proposal:ident $op:ident $({ $($arg:ident: $argty:ty),* })? => $visit:ident)*) => {
+        &[$(stringify!($visit)),*]
+    };
+}
+
+/// The visit methods of every instruction wasmparser decodes.
+const OPERATORS: &[&str] = wp::for_each_operator!(visit_names);
+
+/// Visitor giving the name of the instruction, as its visit method without
+/// the `visit_` prefix, e.g. `i64_div_s`.
+struct OperatorName;
+
+macro_rules! operator_name {
+    ($(@$proposal:ident $op:ident $({ $($arg:ident: $argty:ty),* })? => $visit:ident)*) => {
+        $(
+            fn $visit(&mut self $($(, _: $argty)*)?) -> &'static str {
+                &stringify!($visit)["visit_".len()..]
+            }
+        )*
+    };
+}
+
+impl<'a> wp::VisitOperator<'a> for OperatorName {
+    type Output = &'static str;
+    wp::for_each_operator!(operator_name);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::ContractPrepareVersion;
+    use crate::prepare::prepare_contract;
+    use crate::tests::test_vm_config;
+    use assert_matches::assert_matches;
+
+    const DIVIDING: &str = r#"
+(module
+  (func (export "main") (param i64 i64) (result i64)
+    (i64.div_s (local.get 0) (local.get 1))))"#;
+
+    const ADDING: &str = r#"
+(module
+  (func (export "main") (param i64 i64) (result i64)
+    (i64.add (local.get 0) (local.get 1))))"#;
+
+    fn blocked(opcode: &str, vm_kinds: Vec<VMKind>) -> BlockedOpcode {
+        BlockedOpcode { opcode: opcode.to_string(), vm_kinds }
+    }
+
+    #[test]
+    fn test_blocked_across_prepare_versions() {
+        let blocklist = OpcodeBlocklist::new(vec![blocked("i64.div_s", vec![])]).unwrap();
+        let dividing = wat::parse_str(DIVIDING).unwrap();
+        let adding = wat::parse_str(ADDING).unwrap();
+        for version in
+            [ContractPrepareVersion::V0, ContractPrepareVersion::V1, ContractPrepareVersion::V2]
+        {
+            let mut config = test_vm_config();
+            config.limit_config.contract_prepare_version = version;
+            let mut blocking = config.clone();
+            blocking.opcode_blocklist = blocklist.clone();
+            let mut vm_kinds = vec![VMKind::Wasmer0, VMKind::Wasmer2, VMKind::Wasmtime];
+            if version == ContractPrepareVersion::V2 {
+                vm_kinds.push(VMKind::NearVm);
+            }
+            for vm_kind in vm_kinds {
+                let prepare = |code: &[u8], config| prepare_contract(code, config, vm_kind);
+                assert_matches!(
+                    prepare(&dividing, &blocking),
+                    Err(PrepareError::BlockedOpcode),
+                    "{version:?} {vm_kind:?}"
+                );
+                assert_matches!(prepare(&dividing, &config), Ok(_), "{version:?} {vm_kind:?}");
+                assert_matches!(prepare(&adding, &blocking), Ok(_), "{version:?} {vm_kind:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_blocked_on_some_vms() {
+        let blocklist =
+            OpcodeBlocklist::new(vec![blocked("i64_div_s", vec![VMKind::NearVm])]).unwrap();
+        let dividing = wat::parse_str(DIVIDING).unwrap();
+        assert_eq!(blocklist.check(&dividing, VMKind::NearVm), Err(PrepareError::BlockedOpcode));
+        assert_eq!(blocklist.check(&dividing, VMKind::Wasmtime), Ok(()));
+        // Malformed contracts are left to the preparation.
+        assert_eq!(blocklist.check(&dividing[..dividing.len() - 2], VMKind::NearVm), Ok(()));
+    }
+
+    #[test]
+    fn test_unknown_opcode() {
+        let typo = OpcodeBlocklist::new(vec![blocked("i64.dvi_s", vec![])]);
+        assert_eq!(typo, Err(UnknownOpcode("i64.dvi_s".to_string())));
+        let json = r#"[{"opcode":"f64.nearest","vm_kinds":["NearVm"]},{"opcode":"memory.grow"}]"#;
+        let blocklist: OpcodeBlocklist = serde_json::from_str(json).unwrap();
+        assert_eq!(blocklist.opcodes().len(), 2);
+        assert_eq!(serde_json::to_string(&blocklist).unwrap(), json);
+        assert!(serde_json::from_str::<OpcodeBlocklist>(r#"[{"opcode":"nope"}]"#).is_err());
+    }
+}
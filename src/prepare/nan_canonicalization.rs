@@ -0,0 +1,278 @@
+//! Canonicalization of the NaNs produced by floating point instructions.
+//!
+//! The bits of the NaN an arithmetic instruction produces depend on the CPU,
+//! and contracts can observe them by storing the result or reinterpreting it
+//! as an integer.  Each backend used to make them canonical with a setting of
+//! its compiler, so whether two backends agreed on them depended on how each
+//! compiler implements the setting.  This pass makes the contract do it: after
+//! each instruction which may produce a new NaN, the result is replaced by the
+//! canonical NaN, positive with only the top bit of the payload set, if it is
+//! a NaN.  These are the instructions the compilers canonicalized: the
+//! arithmetic, rounding, minimum, maximum and square root instructions and
+//! the conversions between `f32` and `f64`, on scalars and vector lanes.
+//! Loads, stores, constants, `abs`, `neg`, `copysign` and reinterpretations
+//! keep the bits they are given, signaling NaNs included, as the
+//! specification requires.
+//!
+//! The pass runs before the gas and stack instrumentation, so the inserted
+//! instructions are metered like those of the contract, and before NearVM
+//! instruments the code, so that all backends charge the same for them.
+//! Functions with such instructions get a scratch local of each type they
+//! canonicalize.
+
+use crate::logic::errors::PrepareError;
+use finite_wasm::wasmparser as wp;
+use wasm_encoder::{Encode, Instruction, RawSection, Section};
+
+const CANONICAL_F32: u32 = 0x7fc0_0000;
+const CANONICAL_F64: u64 = 0x7ff8_0000_0000_0000;
+
+/// The type of the result of an instruction canonicalized by the pass.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Canonicalized {
+    F32,
+    F64,
+    F32x4,
+    F64x2,
+}
+
+impl Canonicalized {
+    fn of(op: &wp::Operator) -> Option<Self> {
+        use wp::Operator::*;
+        Some(match op {
+            F32Add | F32Sub | F32Mul | F32Div | F32Min | F32Max | F32Sqrt | F32Ceil | F32Floor
+            | F32Trunc | F32Nearest | F32DemoteF64 => Self::F32,
+            F64Add | F64Sub | F64Mul | F64Div | F64Min | F64Max | F64Sqrt | F64Ceil | F64Floor
+            | F64Trunc | F64Nearest | F64PromoteF32 => Self::F64,
+            F32x4Add | F32x4Sub | F32x4Mul | F32x4Div | F32x4Min | F32x4Max | F32x4Sqrt
+            | F32x4Ceil | F32x4Floor | F32x4Trunc | F32x4Nearest | F32x4DemoteF64x2Zero => {
+                Self::F32x4
+            }
+            F64x2Add | F64x2Sub | F64x2Mul | F64x2Div | F64x2Min | F64x2Max | F64x2Sqrt
+            | F64x2Ceil | F64x2Floor | F64x2Trunc | F64x2Nearest | F64x2PromoteLowF32x4 => {
+                Self::F64x2
+            }
+            _ => return None,
+        })
+    }
+
+    /// Index of the scratch local of the type among [`SCRATCH_TYPES`].
+    fn scratch(self) -> usize {
+        match self {
+            Self::F32 => 0,
+            Self::F64 => 1,
+            Self::F32x4 | Self::F64x2 => 2,
+        }
+    }
+
+    /// The instructions replacing a NaN on top of the stack, kept in `local`,
+    /// with the canonical NaN.
+    fn instructions(self, local: u32) -> [Instruction<'static>; 6] {
+        // `select` and `v128.bitselect` keep the value where it equals
+        // itself, i.e. where it is not a NaN, and take the canonical NaN
+        // elsewhere.
+        let f32x4 = splat(&CANONICAL_F32.to_le_bytes());
+        let f64x2 = splat(&CANONICAL_F64.to_le_bytes());
+        let (canonical, eq, select) = match self {
+            Self::F32 => (
+                Instruction::F32Const(f32::from_bits(CANONICAL_F32)),
+                Instruction::F32Eq,
+                Instruction::Select,
+            ),
+            Self::F64 => (
+                Instruction::F64Const(f64::from_bits(CANONICAL_F64)),
+                Instruction::F64Eq,
+                Instruction::Select,
+            ),
+            Self::F32x4 => {
+                (Instruction::V128Const(f32x4), Instruction::F32x4Eq, Instruction::V128Bitselect)
+            }
+            Self::F64x2 => {
+                (Instruction::V128Const(f64x2), Instruction::F64x2Eq, Instruction::V128Bitselect)
+            }
+        };
+        [
+            Instruction::LocalTee(local),
+            canonical,
+            Instruction::LocalGet(local),
+            Instruction::LocalGet(local),
+            eq,
+            select,
+        ]
+    }
+}
+
+/// The vector of which every lane is `lane`.
+fn splat(lane: &[u8]) -> i128 {
+    let mut bytes = [0; 16];
+    for (index, byte) in bytes.iter_mut().enumerate() {
+        *byte = lane[index % lane.len()];
+    }
+    i128::from_le_bytes(bytes)
+}
+
+/// The types of the scratch locals, `f32`, `f64` and `v128`, by
+/// [`Canonicalized::scratch`].
+const SCRATCH_TYPES: [u8; 3] = [0x7d, 0x7c, 0x7b];
+
+/// The scratch locals `body` needs, by [`Canonicalized::scratch`].
+fn needed_scratch(body: &wp::FunctionBody) -> Result<[bool; 3], PrepareError> {
+    let mut needed = [false; 3];
+    let mut reader = body.get_operators_reader().map_err(|_| PrepareError::Deserialization)?;
+    while !reader.eof() {
+        let op = reader.read().map_err(|_| PrepareError::Deserialization)?;
+        if let Some(canonicalized) = Canonicalized::of(&op) {
+            needed[canonicalized.scratch()] = true;
+        }
+    }
+    Ok(needed)
+}
+
+/// Canonicalizes the NaNs produced by the functions of `code`, a module
+/// validated by the early preparation, see the module documentation.
+pub(super) fn instrument(code: &[u8]) -> Result<Vec<u8>, PrepareError> {
+    let map_err = |_| PrepareError::Deserialization;
+    let mut param_counts = Vec::new();
+    let mut function_types = Vec::new();
+    let mut output = Vec::with_capacity(code.len());
+    let mut code_section = wasm_encoder::CodeSection::new();
+    let mut function = 0;
+    let mut canonicalized = false;
+    for payload in wp::Parser::new(0).parse_all(code) {
+        let payload = payload.map_err(map_err)?;
+        match &payload {
+            wp::Payload::Version { range, .. } => output.extend_from_slice(&code[range.clone()]),
+            wp::Payload::TypeSection(reader) => {
+                for ty in reader.clone() {
+                    let wp::Type::Func(ty) = ty.map_err(map_err)?;
+                    param_counts.push(ty.params().len() as u32);
+                }
+            }
+            wp::Payload::FunctionSection(reader) => {
+                for ty in reader.clone() {
+                    function_types.push(ty.map_err(map_err)?);
+                }
+            }
+            wp::Payload::CodeSectionStart { count: 0, .. } => {
+                code_section.append_to(&mut output);
+                continue;
+            }
+            wp::Payload::CodeSectionStart { .. } => continue,
+            wp::Payload::CodeSectionEntry(body) => {
+                let params = function_types
+                    .get(function)
+                    .and_then(|ty| param_counts.get(*ty as usize))
+                    .copied()
+                    .unwrap_or_default();
+                let (body, changed) = rewrite_body(code, body, params)?;
+                canonicalized |= changed;
+                code_section.raw(&body);
+                function += 1;
+                if function == function_types.len() {
+                    code_section.append_to(&mut output);
+                }
+                continue;
+            }
+            _ => {}
+        }
+        if let Some((id, range)) = payload.as_section() {
+            RawSection { id, data: &code[range] }.append_to(&mut output);
+        }
+    }
+    if !canonicalized {
+        return Ok(code.to_vec());
+    }
+    Ok(output)
+}
+
+/// The body of a function with `params` parameters, with its NaNs
+/// canonicalized, and whether it has anything to canonicalize.
+fn rewrite_body(
+    code: &[u8],
+    body: &wp::FunctionBody<'_>,
+    params: u32,
+) -> Result<(Vec<u8>, bool), PrepareError> {
+    let map_err = |_| PrepareError::Deserialization;
+    let mut reader = body.get_binary_reader();
+    let groups = reader.read_var_u32().map_err(map_err)?;
+    let groups_start = reader.original_position();
+    let mut locals = params;
+    for _ in 0..groups {
+        locals += reader.read_var_u32().map_err(map_err)?;
+        reader.read::<wp::ValType>().map_err(map_err)?;
+    }
+    let groups_end = reader.original_position();
+
+    let needed = needed_scratch(body)?;
+    if !needed.contains(&true) {
+        return Ok((code[body.range()].to_vec(), false));
+    }
+    let mut output = Vec::new();
+    let added = needed.iter().filter(|needed| **needed).count() as u32;
+    (groups + added).encode(&mut output);
+    output.extend_from_slice(&code[groups_start..groups_end]);
+    let mut scratch = [0; 3];
+    for (index, ty) in SCRATCH_TYPES.iter().enumerate() {
+        if needed[index] {
+            output.extend_from_slice(&[1, *ty]);
+            scratch[index] = locals;
+            locals += 1;
+        }
+    }
+    while !reader.eof() {
+        let start = reader.original_position();
+        let op = reader.read_operator().map_err(map_err)?;
+        output.extend_from_slice(&code[start..reader.original_position()]);
+        if let Some(canonicalized) = Canonicalized::of(&op) {
+            for instruction in canonicalized.instructions(scratch[canonicalized.scratch()]) {
+                instruction.encode(&mut output);
+            }
+        }
+    }
+    Ok((output, true))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_instrument() {
+        let plain = wat::parse_str(
+            r#"(module (func (export "main") (result f32) (f32.neg (f32.const nan:0x1))))"#,
+        )
+        .unwrap();
+        assert_eq!(instrument(&plain).unwrap(), plain);
+
+        let code = wat::parse_str(
+            r#"
+(module
+  (func (param f64) (result f32)
+    (local i32)
+    (f32.add (f32.demote_f64 (local.get 0)) (f32.const 1)))
+  (func (result f64) (f64.sqrt (f64.const -1)))
+  (func (export "main")))"#,
+        )
+        .unwrap();
+        let instrumented = instrument(&code).unwrap();
+        wp::Validator::new().validate_all(&instrumented).unwrap();
+        let mut bodies = Vec::new();
+        for payload in wp::Parser::new(0).parse_all(&instrumented) {
+            if let wp::Payload::CodeSectionEntry(body) = payload.unwrap() {
+                let locals: Vec<_> = body
+                    .get_locals_reader()
+                    .unwrap()
+                    .into_iter()
+                    .map(|group| group.unwrap())
+                    .collect();
+                let ops = body.get_operators_reader().unwrap().into_iter().count();
+                bodies.push((locals, ops));
+            }
+        }
+        use wp::ValType::*;
+        assert_eq!(
+            bodies,
+            [(vec![(1, I32), (1, F32)], 5 + 2 * 6), (vec![(1, F64)], 3 + 6), (vec![], 1),]
+        );
+    }
+}
@@ -49,6 +49,12 @@ impl<'a> ContractModule<'a> {
         let mut tmp = MemorySection::default();
 
         module.memory_section_mut().unwrap_or(&mut tmp).entries_mut().pop();
+        // An imported memory has passed `scan_imports` and is replaced as well.
+        if let Some(imports) = module.import_section_mut() {
+            imports
+                .entries_mut()
+                .retain(|import| !matches!(import.external(), External::Memory(_)));
+        }
 
         let entry = elements::MemoryType::new(
             config.limit_config.initial_memory_pages,
@@ -78,7 +84,7 @@ impl<'a> ContractModule<'a> {
         }
     }
 
-    fn inject_gas_metering(self) -> Result<Self, PrepareError> {
+    pub(crate) fn inject_gas_metering(self) -> Result<Self, PrepareError> {
         let Self { module, config } = self;
         // Free config, no need for gas metering.
         if config.regular_op_cost == 0 {
@@ -91,7 +97,7 @@ impl<'a> ContractModule<'a> {
         Ok(Self { module, config })
     }
 
-    fn inject_stack_height_metering(self) -> Result<Self, PrepareError> {
+    pub(crate) fn inject_stack_height_metering(self) -> Result<Self, PrepareError> {
         let Self { module, config } = self;
         let module = crate::instrument::stack_height::inject_limiter(
             module,
@@ -124,7 +130,16 @@ impl<'a> ContractModule<'a> {
 
             let type_idx = match *import.external() {
                 External::Function(ref type_idx) => type_idx,
-                External::Memory(_) => return Err(PrepareError::Memory),
+                External::Memory(ref memory_type) => {
+                    let limits = memory_type.limits();
+                    super::check_memory_import(
+                        config,
+                        import.field(),
+                        limits.initial().into(),
+                        limits.maximum().map(Into::into),
+                    )?;
+                    continue;
+                }
                 _ => continue,
             };
 
@@ -242,7 +257,7 @@ mod test {
         let mut config = test_vm_config();
         let prepare_version = ContractPrepareVersion::V1;
         config.limit_config.contract_prepare_version = prepare_version;
-        let features = crate::features::WasmFeatures::from(prepare_version);
+        let features = crate::features::WasmFeatures::from(&config);
         bolero::check!().for_each(|input: &[u8]| {
             // DO NOT use ArbitraryModule. We do want modules that may be invalid here, if they pass our validation step!
             if let Ok(_) = super::validate_contract(input, features, &config) {
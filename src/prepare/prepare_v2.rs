@@ -1,21 +1,32 @@
 use crate::logic::errors::PrepareError;
+use crate::prepare::{ControlFlowLimits, FunctionSizeLimit, NanCanonicalization, PrepareBudget};
 use finite_wasm::wasmparser as wp;
-use unc_parameters::vm::{Config, VMKind};
+use crate::logic::Config;
+use unc_parameters::vm::VMKind;
 use wasm_encoder::{Encode, Section, SectionId};
 
-struct PrepareContext<'a> {
+pub(super) struct PrepareContext<'a> {
     code: &'a [u8],
     config: &'a Config,
     output_code: Vec<u8>,
     function_limit: u64,
     local_limit: u64,
+    /// Operations of the [`PrepareBudget`] left.
+    operations_left: u64,
+    control_flow_limits: ControlFlowLimits,
+    /// Bytes of a function body, see [`FunctionSizeLimit`].
+    max_function_body_size: u64,
     validator: wp::Validator,
     func_validator_allocations: wp::FuncValidatorAllocations,
     before_import_section: bool,
 }
 
 impl<'a> PrepareContext<'a> {
-    fn new(code: &'a [u8], features: crate::features::WasmFeatures, config: &'a Config) -> Self {
+    pub(super) fn new(
+        code: &'a [u8],
+        features: crate::features::WasmFeatures,
+        config: &'a Config,
+    ) -> Self {
         let limits = &config.limit_config;
         Self {
             code,
@@ -25,19 +36,28 @@ impl<'a> PrepareContext<'a> {
             // specified, use that as a limit.
             function_limit: limits.max_functions_number_per_contract.unwrap_or(u64::MAX),
             local_limit: limits.max_locals_per_contract.unwrap_or(u64::MAX),
+            operations_left: PrepareBudget::for_config(config).max_operations,
+            control_flow_limits: ControlFlowLimits::for_config(config),
+            max_function_body_size: FunctionSizeLimit::for_config(config)
+                .map_or(u64::MAX, |limit| limit.max_function_body_size),
             validator: wp::Validator::new_with_features(features.into()),
             func_validator_allocations: wp::FuncValidatorAllocations::default(),
             before_import_section: true,
         }
     }
 
+    pub(super) fn with_budget(mut self, budget: PrepareBudget) -> Self {
+        self.operations_left = budget.max_operations;
+        self
+    }
+
     /// “Early” preparation.
     ///
     /// Must happen before the finite-wasm analysis and is applicable to NearVm just as much as it is
     /// applicable to other runtimes.
     ///
     /// This will validate the module, normalize the memories within, apply limits.
-    fn run(&mut self) -> Result<Vec<u8>, PrepareError> {
+    pub(super) fn run(&mut self) -> Result<Vec<u8>, PrepareError> {
         self.before_import_section = true;
         let parser = wp::Parser::new(0);
         for payload in parser.parse_all(self.code) {
@@ -147,6 +167,9 @@ impl<'a> PrepareContext<'a> {
                     self.copy_section(SectionId::Code, range.clone())?;
                 }
                 wp::Payload::CodeSectionEntry(func) => {
+                    if func.range().len() as u64 > self.max_function_body_size {
+                        return Err(PrepareError::FunctionTooLarge);
+                    }
                     let local_reader =
                         func.get_locals_reader().map_err(|_| PrepareError::Deserialization)?;
                     for local in local_reader {
@@ -155,6 +178,7 @@ impl<'a> PrepareContext<'a> {
                             .local_limit
                             .checked_sub(u64::from(count))
                             .ok_or(PrepareError::TooManyLocals)?;
+                        self.charge(1)?;
                     }
 
                     let func_validator = self
@@ -169,7 +193,24 @@ impl<'a> PrepareContext<'a> {
                         wp::FuncValidatorAllocations::default(),
                     );
                     let mut func_validator = func_validator.into_validator(allocs);
-                    func_validator.validate(&func).map_err(|_| PrepareError::Deserialization)?;
+                    let mut reader = func.get_binary_reader();
+                    func_validator
+                        .read_locals(&mut reader)
+                        .map_err(|_| PrepareError::Deserialization)?;
+                    let mut depth = 0;
+                    while !reader.eof() {
+                        let offset = reader.original_position();
+                        let op =
+                            reader.read_operator().map_err(|_| PrepareError::Deserialization)?;
+                        self.charge(operations(&op, func_validator.resources()))?;
+                        depth = self.check_control_flow(&op, depth)?;
+                        func_validator
+                            .op(offset, &op)
+                            .map_err(|_| PrepareError::Deserialization)?;
+                    }
+                    func_validator
+                        .finish(reader.original_position())
+                        .map_err(|_| PrepareError::Deserialization)?;
                     self.func_validator_allocations = func_validator.into_allocations();
                 }
                 wp::Payload::CustomSection(reader) => {
@@ -206,7 +247,8 @@ impl<'a> PrepareContext<'a> {
         let mut new_section = wasm_encoder::ImportSection::new();
         for import in reader.clone() {
             let import = import.map_err(|_| PrepareError::Deserialization)?;
-            if import.module != "env" {
+            let experimental = import.module == crate::imports::EXPERIMENTAL_MODULE;
+            if import.module != "env" && !(experimental && self.config.experimental_host_fns) {
                 return Err(PrepareError::Instantiate);
             }
             let new_type = match import.ty {
@@ -216,9 +258,14 @@ impl<'a> PrepareContext<'a> {
                         self.function_limit.checked_sub(1).ok_or(PrepareError::TooManyFunctions)?;
                     wasm_encoder::EntityType::Function(id)
                 }
+                _ if experimental => return Err(PrepareError::Instantiate),
                 wp::TypeRef::Table(_) => return Err(PrepareError::Instantiate),
                 wp::TypeRef::Global(_) => return Err(PrepareError::Instantiate),
-                wp::TypeRef::Memory(_) => return Err(PrepareError::Memory),
+                wp::TypeRef::Memory(ty) => {
+                    super::check_memory_import(self.config, import.name, ty.initial, ty.maximum)?;
+                    // Replaced by the standardized memory imported below.
+                    continue;
+                }
                 wp::TypeRef::Tag(_) => return Err(PrepareError::Deserialization),
             };
             new_section.import(import.module, import.name, new_type);
@@ -248,6 +295,37 @@ impl<'a> PrepareContext<'a> {
         })
     }
 
+    fn charge(&mut self, operations: u64) -> Result<(), PrepareError> {
+        self.operations_left =
+            self.operations_left.checked_sub(operations).ok_or(PrepareError::TooComplex)?;
+        Ok(())
+    }
+
+    /// Checks `op` against the [`ControlFlowLimits`], returning the nesting
+    /// depth after it given the `depth` before it.
+    fn check_control_flow(&self, op: &wp::Operator, depth: u32) -> Result<u32, PrepareError> {
+        let limits = &self.control_flow_limits;
+        match op {
+            wp::Operator::Block { .. }
+            | wp::Operator::Loop { .. }
+            | wp::Operator::If { .. }
+            | wp::Operator::Try { .. } => {
+                if limits.max_nesting_depth.is_some_and(|max| depth >= max) {
+                    return Err(PrepareError::TooDeeplyNested);
+                }
+                Ok(depth + 1)
+            }
+            // The last `end` of the function closes its body.
+            wp::Operator::End | wp::Operator::Delegate { .. } => Ok(depth.saturating_sub(1)),
+            wp::Operator::BrTable { targets }
+                if limits.max_br_table_targets.is_some_and(|max| targets.len() > max) =>
+            {
+                Err(PrepareError::BrTableTooLarge)
+            }
+            _ => Ok(depth),
+        }
+    }
+
     fn copy_section(
         &mut self,
         id: SectionId,
@@ -264,13 +342,51 @@ impl<'a> PrepareContext<'a> {
     }
 }
 
+/// Operations of the [`PrepareBudget`] `op` takes, see the documentation of
+/// the prepare module.
+fn operations<R: wp::WasmModuleResources>(op: &wp::Operator, resources: &R) -> u64 {
+    use wp::WasmFuncType;
+    let arity =
+        |ty: Option<&R::FuncType>| ty.map_or(0, |ty| (ty.len_inputs() + ty.len_outputs()) as u64);
+    1 + match op {
+        wp::Operator::Call { function_index } | wp::Operator::ReturnCall { function_index } => {
+            arity(resources.type_of_function(*function_index))
+        }
+        wp::Operator::CallIndirect { type_index, .. }
+        | wp::Operator::ReturnCallIndirect { type_index, .. } => {
+            arity(resources.func_type_at(*type_index))
+        }
+        wp::Operator::Block { blockty: wp::BlockType::FuncType(type_index) }
+        | wp::Operator::Loop { blockty: wp::BlockType::FuncType(type_index) }
+        | wp::Operator::If { blockty: wp::BlockType::FuncType(type_index) } => {
+            arity(resources.func_type_at(*type_index))
+        }
+        wp::Operator::BrTable { targets } => u64::from(targets.len()),
+        _ => 0,
+    }
+}
+
 pub(crate) fn prepare_contract(
     original_code: &[u8],
     features: crate::features::WasmFeatures,
     config: &Config,
     kind: VMKind,
+    budget: PrepareBudget,
 ) -> Result<Vec<u8>, PrepareError> {
-    let lightly_steamed = PrepareContext::new(original_code, features, config).run()?;
+    let mut lightly_steamed =
+        PrepareContext::new(original_code, features, config).with_budget(budget).run()?;
+    // Before the passes adding instructions, so that the blocks are those of
+    // `coverage_map` of the original code.
+    #[cfg(feature = "coverage")]
+    if config.coverage {
+        lightly_steamed = crate::coverage::instrument(&lightly_steamed)?;
+    }
+    if features.has_aggregates() {
+        lightly_steamed = super::aggregate_gas::instrument(&lightly_steamed, config)?;
+    }
+    if NanCanonicalization::for_config(config) == NanCanonicalization::Prepare {
+        lightly_steamed = super::nan_canonicalization::instrument(&lightly_steamed)?;
+    }
 
     if kind == VMKind::NearVm {
         // Built-in unc-vm code instruments code for itself.
@@ -294,8 +410,25 @@ pub(crate) fn prepare_contract(
     Ok(res)
 }
 
-// TODO: refactor to avoid copy-paste with the ones currently defined in unc_vm_runner
-struct SimpleMaxStackCfg;
+/// Largest stack the finite-wasm stack limiter charges for an activation of
+/// a function of `code`, its frame and operand stack included.
+pub(super) fn max_function_stack(code: &[u8]) -> Result<u64, PrepareError> {
+    let analysis = finite_wasm::Analysis::new()
+        .with_stack(SimpleMaxStackCfg)
+        .analyze(code)
+        .map_err(|_| PrepareError::Deserialization)?;
+    let frames = analysis.function_frame_sizes.iter();
+    let operands = analysis.function_operand_stack_sizes.iter();
+    Ok(frames
+        .zip(operands)
+        .map(|(frame, operands)| frame.saturating_add(*operands))
+        .max()
+        .unwrap_or(0))
+}
+
+/// Sizes of the values and activations accounted against `max_stack_height`,
+/// shared by the instrumentation of the preparation and of NearVM.
+pub(crate) struct SimpleMaxStackCfg;
 
 impl finite_wasm::max_stack::SizeConfig for SimpleMaxStackCfg {
     fn size_of_value(&self, ty: wp::ValType) -> u8 {
@@ -329,7 +462,15 @@ impl finite_wasm::max_stack::SizeConfig for SimpleMaxStackCfg {
     }
 }
 
-struct SimpleGasCostCfg(u64);
+// TODO: refactor to avoid copy-paste with the one currently defined in unc_vm_runner
+pub(super) struct SimpleGasCostCfg(pub(super) u64);
+
+/// SIMD instructions work on up to 16 lanes at once: they cost as many
+/// regular instructions as they roughly take the time of.
+const SIMD_OP_COST_FACTOR: u64 = 2;
+/// Divisions and square roots of floats, much slower than the other SIMD
+/// instructions.
+const SIMD_SLOW_OP_COST_FACTOR: u64 = 16;
 
 macro_rules! gas_cost {
     ($( @$proposal:ident $op:ident $({ $($arg:ident: $argty:ty),* })? => $visit:ident)*) => {
@@ -349,6 +490,21 @@ macro_rules! gas_cost {
     (@@mvp $_op:ident $_self:ident $({ $($_arg:ident: $_argty:ty),* })? => visit_else) => {
         0
     };
+    (@@simd $_op:ident $self:ident $({ $($_arg:ident: $_argty:ty),* })? => visit_f32x4_div) => {
+        $self.0.saturating_mul(SIMD_SLOW_OP_COST_FACTOR)
+    };
+    (@@simd $_op:ident $self:ident $({ $($_arg:ident: $_argty:ty),* })? => visit_f64x2_div) => {
+        $self.0.saturating_mul(SIMD_SLOW_OP_COST_FACTOR)
+    };
+    (@@simd $_op:ident $self:ident $({ $($_arg:ident: $_argty:ty),* })? => visit_f32x4_sqrt) => {
+        $self.0.saturating_mul(SIMD_SLOW_OP_COST_FACTOR)
+    };
+    (@@simd $_op:ident $self:ident $({ $($_arg:ident: $_argty:ty),* })? => visit_f64x2_sqrt) => {
+        $self.0.saturating_mul(SIMD_SLOW_OP_COST_FACTOR)
+    };
+    (@@simd $_op:ident $self:ident $({ $($_arg:ident: $_argty:ty),* })? => $_visit:ident) => {
+        $self.0.saturating_mul(SIMD_OP_COST_FACTOR)
+    };
     (@@$_proposal:ident $_op:ident $self:ident $({ $($arg:ident: $argty:ty),* })? => $visit:ident) => {
         $self.0
     };
@@ -361,20 +517,33 @@ impl<'a> wp::VisitOperator<'a> for SimpleGasCostCfg {
 
 #[cfg(test)]
 mod test {
-    use super::VMKind;
+    use super::{wp, PrepareBudget, SimpleGasCostCfg, VMKind};
     use crate::logic::ContractPrepareVersion;
     use crate::tests::test_vm_config;
 
+    #[test]
+    fn test_simd_gas_costs() {
+        use wp::VisitOperator;
+        let mut cfg = SimpleGasCostCfg(100);
+        assert_eq!(cfg.visit_i32_add(), 100);
+        assert_eq!(cfg.visit_i32x4_add(), 200);
+        assert_eq!(cfg.visit_i8x16_swizzle(), 200);
+        assert_eq!(cfg.visit_f32x4_div(), 1600);
+        assert_eq!(cfg.visit_f64x2_sqrt(), 1600);
+        assert_eq!(SimpleGasCostCfg(u64::MAX).visit_f64x2_div(), u64::MAX);
+    }
+
     #[test]
     fn v2_preparation_wasmtime_generates_valid_contract_fuzzer() {
         let mut config = test_vm_config();
         let prepare_version = ContractPrepareVersion::V2;
         config.limit_config.contract_prepare_version = prepare_version;
-        let features = crate::features::WasmFeatures::from(prepare_version);
+        let features = crate::features::WasmFeatures::from(&config);
         bolero::check!().for_each(|input: &[u8]| {
             // DO NOT use ArbitraryModule. We do want modules that may be invalid here, if they pass our validation step!
             if let Ok(_) = crate::prepare::prepare_v1::validate_contract(input, features, &config) {
-                match super::prepare_contract(input, features, &config, VMKind::Wasmtime) {
+                let budget = PrepareBudget::for_config(&config);
+                match super::prepare_contract(input, features, &config, VMKind::Wasmtime, budget) {
                     Err(_e) => (), // TODO: this should be a panic, but for now it’d actually trigger
                     Ok(code) => {
                         let mut validator = wasmparser::Validator::new();
@@ -397,12 +566,13 @@ mod test {
         let mut config = test_vm_config();
         let prepare_version = ContractPrepareVersion::V2;
         config.limit_config.contract_prepare_version = prepare_version;
-        let features = crate::features::WasmFeatures::from(prepare_version);
+        let features = crate::features::WasmFeatures::from(&config);
 
         bolero::check!().for_each(|input: &[u8]| {
             // DO NOT use ArbitraryModule. We do want modules that may be invalid here, if they pass our validation step!
             if let Ok(_) = crate::prepare::prepare_v1::validate_contract(input, features, &config) {
-                match super::prepare_contract(input, features, &config, VMKind::NearVm) {
+                let budget = PrepareBudget::for_config(&config);
+                match super::prepare_contract(input, features, &config, VMKind::NearVm, budget) {
                     Err(_e) => (), // TODO: this should be a panic, but for now it’d actually trigger
                     Ok(code) => {
                         let mut validator = wasmparser::Validator::new();
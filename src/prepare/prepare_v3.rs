@@ -0,0 +1,253 @@
+//! Preparation with passes chosen by the embedder, to experiment with other
+//! instrumentation than the one of the protocol.
+//!
+//! The module is validated, its memory standardized and the limits of the
+//! config applied as in V2, then the gas and stack instrumentations given in
+//! [`PreparePasses`] are applied.  The pwasm passes run first and the
+//! finite-wasm ones last, so that the finite-wasm gas instrumentation also
+//! meters the code inserted by the pwasm stack limiter.
+
+use super::{aggregate_gas, nan_canonicalization, prepare_v1, prepare_v2, NanCanonicalization};
+use crate::logic::errors::PrepareError;
+use crate::logic::Config;
+use unc_parameters::vm::VMKind;
+
+/// How the contract is charged for the instructions it executes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GasInstrumentation {
+    /// Not at all, only host functions charge gas.
+    None,
+    /// Calls to `env.gas` at the start of each block, as in V0 and V1.
+    Pwasm,
+    /// Calls to `internal.finite_wasm_gas` where the finite-wasm analysis
+    /// places them, as in V2.
+    FiniteWasm,
+}
+
+/// How the height of the stack of the contract is limited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum StackLimiter {
+    /// A global counting the height, updated around calls, as in V0 and V1.
+    Pwasm,
+    /// Calls to `internal.finite_wasm_stack` with the frame sizes computed
+    /// by the finite-wasm analysis, as in V2.
+    FiniteWasm,
+}
+
+/// The passes a contract goes through, see
+/// [`crate::prepare::prepare_contract_with_passes`].
+///
+/// The default are the passes of V2.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PreparePasses {
+    pub gas: GasInstrumentation,
+    pub stack: StackLimiter,
+    /// Accepts the sign extension instructions.  The pwasm passes cannot
+    /// parse them, so modules using them are rejected with
+    /// [`PrepareError::Deserialization`] by those.  The same goes for the
+    /// SIMD instructions, accepted with [`Config::simd`] when no pwasm pass
+    /// is used, and for the bulk memory and reference
+    /// types instructions of [`Config::bulk_memory_reftypes`].
+    pub sign_extension: bool,
+}
+
+impl Default for PreparePasses {
+    fn default() -> Self {
+        Self {
+            gas: GasInstrumentation::FiniteWasm,
+            stack: StackLimiter::FiniteWasm,
+            sign_extension: true,
+        }
+    }
+}
+
+pub(crate) fn prepare_contract(
+    original_code: &[u8],
+    config: &Config,
+    kind: VMKind,
+    passes: &PreparePasses,
+) -> Result<Vec<u8>, PrepareError> {
+    let features = crate::features::WasmFeatures::for_passes(passes, config);
+    let mut code = prepare_v2::PrepareContext::new(original_code, features, config).run()?;
+    if features.has_aggregates() && passes.gas == GasInstrumentation::FiniteWasm {
+        code = aggregate_gas::instrument(&code, config)?;
+    }
+    // The backends canonicalize the NaNs as the prepare version of the config
+    // says, whatever the passes.
+    if NanCanonicalization::for_config(config) == NanCanonicalization::Prepare {
+        code = nan_canonicalization::instrument(&code)?;
+    }
+
+    if passes.gas == GasInstrumentation::Pwasm || passes.stack == StackLimiter::Pwasm {
+        let mut module = prepare_v1::ContractModule::init(&code, config)?;
+        if passes.gas == GasInstrumentation::Pwasm {
+            module = module.inject_gas_metering()?;
+        }
+        if passes.stack == StackLimiter::Pwasm {
+            module = module.inject_stack_height_metering()?;
+        }
+        code = module.into_wasm_code()?;
+    }
+
+    if passes.gas != GasInstrumentation::FiniteWasm && passes.stack != StackLimiter::FiniteWasm {
+        return Ok(code);
+    }
+    let mut analysis = finite_wasm::Analysis::new()
+        .with_stack(Box::new(prepare_v2::SimpleMaxStackCfg))
+        .with_gas(Box::new(prepare_v2::SimpleGasCostCfg(u64::from(config.regular_op_cost))))
+        .analyze(&code)
+        .map_err(|err| {
+            tracing::error!(?err, ?kind, "Analysis failed");
+            PrepareError::Deserialization
+        })?;
+    // The instrumentation needs both analyses, empty ones drop a pass.
+    if passes.gas != GasInstrumentation::FiniteWasm {
+        for function in 0..analysis.gas_offsets.len() {
+            analysis.gas_offsets[function] = Box::new([]);
+            analysis.gas_costs[function] = Box::new([]);
+            analysis.gas_kinds[function] = Box::new([]);
+        }
+    }
+    if passes.stack != StackLimiter::FiniteWasm {
+        analysis.function_frame_sizes.iter_mut().for_each(|size| *size = 0);
+        analysis.function_operand_stack_sizes.iter_mut().for_each(|size| *size = 0);
+    }
+    // Make sure contracts can’t call the instrumentation functions via `env`.
+    analysis.instrument("internal", &code).map_err(|err| {
+        tracing::error!(?err, ?kind, "Instrumentation failed");
+        PrepareError::Serialization
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::logic::ContractPrepareVersion;
+    use crate::tests::test_vm_config;
+    use finite_wasm::wasmparser as wp;
+
+    /// Whether the prepared `code` calls the import `module.name`.
+    fn calls_import(code: &[u8], module: &str, name: &str) -> bool {
+        let mut index = None;
+        let mut imports = 0;
+        for payload in wp::Parser::new(0).parse_all(code) {
+            match payload.unwrap() {
+                wp::Payload::ImportSection(reader) => {
+                    for import in reader {
+                        let import = import.unwrap();
+                        if let wp::TypeRef::Func(_) = import.ty {
+                            if import.module == module && import.name == name {
+                                index = Some(imports);
+                            }
+                            imports += 1;
+                        }
+                    }
+                }
+                wp::Payload::CodeSectionEntry(body) => {
+                    let mut operators = body.get_operators_reader().unwrap();
+                    while !operators.eof() {
+                        if let wp::Operator::Call { function_index } = operators.read().unwrap() {
+                            if Some(function_index) == index {
+                                return true;
+                            }
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    const LOOP: &str = r#"
+(module
+  (func (export "main") (local i32)
+    (loop
+      (local.set 0 (i32.add (local.get 0) (i32.const 1)))
+      (br_if 0 (i32.lt_u (local.get 0) (i32.const 10)))))
+)"#;
+
+    #[test]
+    fn test_default_passes_are_v2() {
+        let mut config = test_vm_config();
+        config.limit_config.contract_prepare_version = ContractPrepareVersion::V2;
+        let code = wat::parse_str(LOOP).unwrap();
+        let features = crate::features::WasmFeatures::from(&config);
+        assert_eq!(
+            prepare_contract(&code, &config, VMKind::Wasmtime, &PreparePasses::default()),
+            prepare_v2::prepare_contract(
+                &code,
+                features,
+                &config,
+                VMKind::Wasmtime,
+                crate::prepare::PrepareBudget::for_config(&config),
+            ),
+        );
+    }
+
+    #[test]
+    fn test_passes() {
+        let config = test_vm_config();
+        let code = wat::parse_str(LOOP).unwrap();
+        let gas =
+            [GasInstrumentation::None, GasInstrumentation::Pwasm, GasInstrumentation::FiniteWasm];
+        for gas in gas {
+            for stack in [StackLimiter::Pwasm, StackLimiter::FiniteWasm] {
+                let passes = PreparePasses { gas, stack, sign_extension: false };
+                let prepared = prepare_contract(&code, &config, VMKind::Wasmtime, &passes).unwrap();
+                let features = crate::features::WasmFeatures::for_passes(&passes, &config);
+                wp::Validator::new_with_features(features.into())
+                    .validate_all(&prepared)
+                    .unwrap_or_else(|err| panic!("{passes:?}: {err}"));
+                let pwasm_gas = calls_import(&prepared, "env", "gas");
+                let finite_wasm_gas = calls_import(&prepared, "internal", "finite_wasm_gas");
+                let finite_wasm_stack = calls_import(&prepared, "internal", "finite_wasm_stack");
+                assert_eq!(pwasm_gas, gas == GasInstrumentation::Pwasm, "{passes:?}");
+                assert_eq!(finite_wasm_gas, gas == GasInstrumentation::FiniteWasm, "{passes:?}");
+                assert_eq!(finite_wasm_stack, stack == StackLimiter::FiniteWasm, "{passes:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_sign_extension() {
+        let config = test_vm_config();
+        let code = wat::parse_str(
+            r#"(module (func (export "main") (drop (i64.extend8_s (i64.const 1)))))"#,
+        )
+        .unwrap();
+        let prepare = |passes| prepare_contract(&code, &config, VMKind::Wasmtime, &passes);
+        assert!(prepare(PreparePasses::default()).is_ok());
+        let passes = PreparePasses { sign_extension: false, ..PreparePasses::default() };
+        assert_eq!(prepare(passes), Err(PrepareError::Deserialization));
+    }
+
+    #[test]
+    fn test_passes_of_the_config() {
+        let mut config = test_vm_config();
+        config.limit_config.contract_prepare_version = ContractPrepareVersion::V2;
+        let passes = PreparePasses {
+            gas: GasInstrumentation::Pwasm,
+            stack: StackLimiter::Pwasm,
+            sign_extension: false,
+        };
+        config.prepare_passes = Some(passes);
+        let code = wat::parse_str(LOOP).unwrap();
+        assert_eq!(
+            crate::prepare::prepare_contract(&code, &config, VMKind::Wasmtime),
+            prepare_contract(&code, &config, VMKind::Wasmtime, &passes),
+        );
+        assert!(
+            crate::features::WasmFeatures::from(&config)
+                == crate::features::WasmFeatures::for_passes(&passes, &config)
+        );
+        assert_eq!(
+            crate::prepare::prepare_contract(&code, &config, VMKind::NearVm),
+            Err(PrepareError::UnsupportedPasses)
+        );
+        assert_eq!(
+            crate::prepare::prepare_contract_with_passes(&code, &config, VMKind::NearVm, &passes),
+            Err(PrepareError::UnsupportedPasses)
+        );
+    }
+}
@@ -0,0 +1,319 @@
+//! Compiling the contracts of upcoming receipts while the current one runs.
+//!
+//! Applying a chunk runs its receipts one after the other, and a receipt
+//! calling a contract missing from the cache waits for it to compile.  The
+//! embedder knows which contracts the next receipts call: submitting them to
+//! a [`PreparePipeline`] compiles them on background threads into the cache,
+//! and [`PreparePipeline::take`] hands back the [`PreparedContract`] to run
+//! once its receipt comes up.
+//!
+//! The artifacts are the ones [`crate::precompile_contract`] stores in the
+//! cache, so running a prepared contract behaves exactly like running it
+//! after compiling it on the spot.  A compilation panicking on a worker
+//! panics the [`PreparePipeline::take`] of its contract instead, as it would
+//! have compiling on the spot.
+
+use crate::cache::contract_cache_key_for_hash;
+use crate::logic::{CompiledContractCache, Config};
+use crate::runner::{CodegenTarget, PrecompileResult};
+use crate::ContractCode;
+use std::any::Any;
+use std::collections::HashMap;
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread::JoinHandle;
+use unc_primitives_core::hash::CryptoHash;
+
+enum State {
+    /// Waiting for a worker.
+    Queued(ContractCode, Config),
+    /// Being compiled by a worker, or by [`PreparePipeline::take`].
+    Compiling,
+    Done(ContractCode, Config, PrecompileResult),
+    /// The compilation panicked with this payload.
+    Panicked(Box<dyn Any + Send>),
+}
+
+struct Slot {
+    state: Mutex<State>,
+    done: Condvar,
+}
+
+/// Compiles submitted contracts on background threads, see the module
+/// documentation.
+///
+/// Dropping the pipeline waits for the compilations in progress and drops
+/// the queued ones.
+pub struct PreparePipeline {
+    cache: Arc<dyn CompiledContractCache>,
+    /// Contracts submitted and not taken yet, by cache key.
+    slots: Mutex<HashMap<CryptoHash, Arc<Slot>>>,
+    jobs: Option<mpsc::Sender<Arc<Slot>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl PreparePipeline {
+    /// Makes a pipeline compiling into `cache` with `threads` threads.
+    pub fn new(cache: Arc<dyn CompiledContractCache>, threads: usize) -> Self {
+        let (jobs, receiver) = mpsc::channel::<Arc<Slot>>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..threads.max(1))
+            .map(|_| {
+                let receiver = Arc::clone(&receiver);
+                let cache = Arc::clone(&cache);
+                std::thread::spawn(move || loop {
+                    let Ok(slot) = receiver.lock().unwrap().recv() else { break };
+                    compile(&slot, &*cache);
+                })
+            })
+            .collect();
+        Self { cache, slots: Default::default(), jobs: Some(jobs), workers }
+    }
+
+    /// Queues `code` for compilation with `config`.
+    ///
+    /// Submitting a contract already submitted with the same config and not
+    /// taken yet does nothing.
+    pub fn submit(&self, code: ContractCode, config: Config) {
+        let key = contract_cache_key_for_hash(code.hash(), &config, CodegenTarget::Host);
+        let mut slots = self.slots.lock().unwrap();
+        if slots.contains_key(&key) {
+            return;
+        }
+        let slot =
+            Arc::new(Slot { state: Mutex::new(State::Queued(code, config)), done: Condvar::new() });
+        slots.insert(key, Arc::clone(&slot));
+        if let Some(jobs) = &self.jobs {
+            let _ = jobs.send(slot);
+        }
+    }
+
+    /// The contract with `code_hash` submitted with `config`, once compiled.
+    ///
+    /// Waits for its compilation if it is in progress, and compiles it on
+    /// the calling thread if no worker has started yet.  Returns `None` if
+    /// the contract has not been submitted, or already been taken, and
+    /// panics if its compilation did.
+    pub fn take(&self, code_hash: &CryptoHash, config: &Config) -> Option<PreparedContract> {
+        let key = contract_cache_key_for_hash(code_hash, config, CodegenTarget::Host);
+        let slot = self.slots.lock().unwrap().remove(&key)?;
+        compile(&slot, &*self.cache);
+        let state = slot.state.lock().unwrap();
+        let mut state =
+            slot.done.wait_while(state, |state| matches!(state, State::Compiling)).unwrap();
+        match std::mem::replace(&mut *state, State::Compiling) {
+            State::Done(code, config, result) => {
+                Some(PreparedContract { code, config, result, cache: Arc::clone(&self.cache) })
+            }
+            State::Panicked(panic) => std::panic::resume_unwind(panic),
+            State::Queued(..) | State::Compiling => unreachable!("the contract has been compiled"),
+        }
+    }
+
+    /// Number of contracts submitted and not taken yet.
+    pub fn pending(&self) -> usize {
+        self.slots.lock().unwrap().len()
+    }
+}
+
+impl Drop for PreparePipeline {
+    fn drop(&mut self) {
+        // Workers skip the slots already compiled, so drop the queued ones.
+        for slot in self.slots.lock().unwrap().drain().map(|(_, slot)| slot) {
+            let mut state = slot.state.lock().unwrap();
+            if matches!(*state, State::Queued(..)) {
+                *state = State::Compiling;
+            }
+        }
+        drop(self.jobs.take());
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+/// Compiles the contract of `slot` unless someone else already does.
+fn compile(slot: &Slot, cache: &dyn CompiledContractCache) {
+    let (code, config) = {
+        let mut state = slot.state.lock().unwrap();
+        match std::mem::replace(&mut *state, State::Compiling) {
+            State::Queued(code, config) => (code, config),
+            other => {
+                *state = other;
+                return;
+            }
+        }
+    };
+    let _span =
+        tracing::debug_span!(target: "vm", "prepare_pipeline", code.hash = %code.hash()).entered();
+    // Caught so that the worker goes on, and `take` panics instead of
+    // waiting for the compilation forever.
+    let result = std::panic::catch_unwind(AssertUnwindSafe(|| {
+        crate::precompile_contract(&code, &config, Some(cache))
+    }));
+    *slot.state.lock().unwrap() = match result {
+        Ok(result) => State::Done(code, config, result),
+        Err(panic) => State::Panicked(panic),
+    };
+    slot.done.notify_all();
+}
+
+/// A contract compiled by a [`PreparePipeline`], ready to run.
+///
+/// Running [`Self::code`] with the [`Self::config`] and [`Self::cache`] of
+/// the pipeline loads the compiled artifact instead of compiling it.
+pub struct PreparedContract {
+    code: ContractCode,
+    config: Config,
+    result: PrecompileResult,
+    cache: Arc<dyn CompiledContractCache>,
+}
+
+impl PreparedContract {
+    pub fn code(&self) -> &ContractCode {
+        &self.code
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// The cache holding the artifact, to pass to [`crate::VM::run`].
+    pub fn cache(&self) -> &dyn CompiledContractCache {
+        &*self.cache
+    }
+
+    /// Outcome of the compilation, as returned by
+    /// [`crate::precompile_contract`].
+    ///
+    /// Contracts failing to compile can still be run: the error is cached
+    /// and the call fails with it.
+    pub fn result(&self) -> &PrecompileResult {
+        &self.result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::logic::{CompiledContract, VMOutcome};
+    use crate::runner::VMKindExt;
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+    use crate::{ContractPrecompilatonResult, MockCompiledContractCache};
+    use unc_parameters::vm::VMKind;
+    use unc_parameters::RuntimeFeesConfig;
+
+    fn contract(value: u8) -> ContractCode {
+        let wat = format!(
+            r#"(module
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (memory 1)
+  (data (i32.const 0) "\{value:02x}")
+  (func (export "main") (call $value_return (i64.const 1) (i64.const 0))))"#
+        );
+        ContractCode::new(wat::parse_str(wat).unwrap(), None)
+    }
+
+    fn run(prepared: &PreparedContract) -> VMOutcome {
+        let runtime = prepared.config().vm_kind.runtime(prepared.config().clone()).unwrap();
+        runtime
+            .run(
+                prepared.code(),
+                "main",
+                &mut MockedExternal::new(),
+                create_context(vec![]),
+                &RuntimeFeesConfig::test(),
+                &[],
+                Some(prepared.cache()),
+            )
+            .unwrap()
+    }
+
+    #[test]
+    fn test_prepare_pipeline() {
+        with_vm_variants(&test_vm_config(), |vm_kind: VMKind| {
+            let mut config = test_vm_config();
+            config.vm_kind = vm_kind;
+            let cache = Arc::new(MockCompiledContractCache::default());
+            let pipeline = PreparePipeline::new(cache.clone(), 2);
+            let contracts: Vec<ContractCode> = (0..8).map(contract).collect();
+            let hashes: Vec<CryptoHash> = contracts.iter().map(|code| *code.hash()).collect();
+            for code in contracts {
+                pipeline.submit(code, config.clone());
+            }
+            pipeline.submit(contract(0), config.clone());
+            assert!(pipeline.take(&CryptoHash::default(), &config).is_none());
+
+            for (i, hash) in hashes.iter().enumerate() {
+                let prepared = pipeline.take(hash, &config).unwrap();
+                assert!(matches!(
+                    prepared.result(),
+                    Ok(Ok(ContractPrecompilatonResult::ContractCompiled
+                        | ContractPrecompilatonResult::ContractAlreadyInCache
+                        | ContractPrecompilatonResult::CacheNotAvailable))
+                ));
+                let outcome = run(&prepared);
+                assert_eq!(outcome.aborted, None);
+                assert_eq!(
+                    outcome.return_data,
+                    crate::logic::ReturnData::Value(vec![i as u8]),
+                    "{vm_kind:?}"
+                );
+                assert!(pipeline.take(hash, &config).is_none());
+            }
+            assert_eq!(pipeline.pending(), 0);
+        });
+    }
+
+    #[test]
+    fn test_prepare_pipeline_compilation_error() {
+        let config = test_vm_config();
+        let cache = Arc::new(MockCompiledContractCache::default());
+        let pipeline = PreparePipeline::new(cache, 1);
+        let code = ContractCode::new(b"\0asm\x01\0\0\0\x01".to_vec(), None);
+        let hash = *code.hash();
+        pipeline.submit(code, config.clone());
+        let prepared = pipeline.take(&hash, &config).unwrap();
+        assert!(matches!(prepared.result(), Ok(Err(_))));
+        assert!(run(&prepared).aborted.is_some());
+    }
+
+    #[test]
+    fn test_prepare_pipeline_panic() {
+        struct PanickingCache;
+
+        impl CompiledContractCache for PanickingCache {
+            fn put(&self, _: &CryptoHash, _: CompiledContract) -> std::io::Result<()> {
+                panic!("the cache is gone")
+            }
+
+            fn get(&self, _: &CryptoHash) -> std::io::Result<Option<CompiledContract>> {
+                panic!("the cache is gone")
+            }
+        }
+
+        let config = test_vm_config();
+        let pipeline = PreparePipeline::new(Arc::new(PanickingCache), 1);
+        for i in 0..2 {
+            pipeline.submit(contract(i), config.clone());
+            let hash = *contract(i).hash();
+            let panic =
+                std::panic::catch_unwind(AssertUnwindSafe(|| pipeline.take(&hash, &config)))
+                    .err()
+                    .expect("the compilation panicked");
+            assert_eq!(panic.downcast_ref::<&str>(), Some(&"the cache is gone"));
+        }
+    }
+
+    #[test]
+    fn test_drop_with_queued_contracts() {
+        let config = test_vm_config();
+        let pipeline = PreparePipeline::new(Arc::new(MockCompiledContractCache::default()), 1);
+        for i in 0..16 {
+            pipeline.submit(contract(i), config.clone());
+        }
+        drop(pipeline);
+    }
+}
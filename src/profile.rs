@@ -1,13 +1,16 @@
 pub use profile_v2::ProfileDataV2;
+pub use versioned::VersionedProfileData;
 
 use borsh::{BorshDeserialize, BorshSerialize};
-use enum_map::{enum_map, Enum, EnumMap};
+use enum_map::{enum_map, Enum, EnumArray, EnumMap};
 use unc_parameters::{ActionCosts, ExtCosts, ExtCostsConfig};
 use unc_primitives_core::types::{Compute, Gas};
+use std::collections::BTreeMap;
 use std::fmt;
 use strum::IntoEnumIterator;
 
 mod profile_v2;
+mod versioned;
 
 /// Profile of gas consumption.
 #[derive(Clone, PartialEq, Eq)]
@@ -123,8 +126,11 @@ impl ProfileDataV3 {
                 }
                 // If the `value` is non-zero, the gas cost also must be non-zero.
                 debug_assert!(key.gas(ext_costs_config) != 0);
-                ((*value as u128).saturating_mul(key.compute(ext_costs_config) as u128)
-                    / (key.gas(ext_costs_config) as u128)) as u64
+                let compute = (*value as u128)
+                    .saturating_mul(key.compute(ext_costs_config) as u128)
+                    .checked_div(key.gas(ext_costs_config) as u128)
+                    .unwrap_or_default();
+                Compute::try_from(compute).unwrap_or(Compute::MAX)
             })
             .fold(0, Compute::saturating_add);
 
@@ -174,6 +180,60 @@ impl BorshSerialize for ProfileDataV3 {
     }
 }
 
+/// Serde representation of [`ProfileDataV3`].
+///
+/// Costs are keyed by name rather than by index so that the format survives
+/// adding, removing and reordering costs.  Only costs with gas are written,
+/// costs missing from the input are zero and unknown costs are ignored.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SerdeProfileData {
+    #[serde(default)]
+    actions: BTreeMap<String, Gas>,
+    #[serde(default)]
+    ext_costs: BTreeMap<String, Gas>,
+    #[serde(default)]
+    wasm_gas: Gas,
+}
+
+impl serde::Serialize for ProfileDataV3 {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        fn named<K: EnumArray<Gas> + fmt::Display>(
+            costs: &EnumMap<K, Gas>,
+        ) -> BTreeMap<String, Gas> {
+            costs
+                .iter()
+                .filter(|(_, gas)| **gas != 0)
+                .map(|(cost, gas)| (cost.to_string(), *gas))
+                .collect()
+        }
+        SerdeProfileData {
+            actions: named(&self.actions_profile),
+            ext_costs: named(&self.wasm_ext_profile),
+            wasm_gas: self.wasm_gas,
+        }
+        .serialize(serializer)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for ProfileDataV3 {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let data = SerdeProfileData::deserialize(deserializer)?;
+        let mut profile = ProfileDataV3::new();
+        for cost in ActionCosts::iter() {
+            if let Some(gas) = data.actions.get(&cost.to_string()) {
+                profile.add_action_cost(cost, *gas);
+            }
+        }
+        for cost in ExtCosts::iter() {
+            if let Some(gas) = data.ext_costs.get(&cost.to_string()) {
+                profile.add_ext_cost(cost, *gas);
+            }
+        }
+        profile.wasm_gas = data.wasm_gas;
+        Ok(profile)
+    }
+}
+
 /// Fixed index of an action cost for borsh (de)serialization.
 ///
 /// We use borsh to store profiles on the DB and borsh is quite fragile with
@@ -401,6 +461,25 @@ mod test {
         assert_eq!(profile_data, restored);
     }
 
+    #[test]
+    fn test_serde_ser_deser() {
+        let profile_data = ProfileDataV3::test();
+        let json = serde_json::to_value(&profile_data).unwrap();
+        assert_eq!(
+            json["actions"]["function_call_base"],
+            profile_data.get_action_cost(ActionCosts::function_call_base)
+        );
+        assert_eq!(json["ext_costs"].get("base"), None, "zero costs are not written");
+        let restored: ProfileDataV3 = serde_json::from_value(json).unwrap();
+        assert_eq!(profile_data, restored);
+
+        let json = serde_json::json!({ "ext_costs": { "base": 5, "no_such_cost": 7 } });
+        let restored: ProfileDataV3 = serde_json::from_value(json).unwrap();
+        let mut expected = ProfileDataV3::new();
+        expected.add_ext_cost(ExtCosts::base, 5);
+        assert_eq!(restored, expected);
+    }
+
     #[test]
     fn test_borsh_incomplete_profile() {
         let action_profile = vec![50u64, 60];
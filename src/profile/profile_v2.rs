@@ -1,3 +1,4 @@
+use super::ProfileDataV3;
 use borsh::{BorshDeserialize, BorshSerialize};
 use unc_parameters::{ActionCosts, ExtCosts};
 use unc_primitives_core::types::Gas;
@@ -34,8 +35,7 @@ impl ProfileDataV2 {
     }
 
     pub fn get_wasm_cost(&self) -> u64 {
-        // ProfileV2Cost::WasmInstruction => 62,
-        self.data[62]
+        self.data[WASM_INSTRUCTION_INDEX]
     }
 
     fn host_gas(&self) -> u64 {
@@ -122,6 +122,27 @@ impl Index<ActionCosts> for ProfileDataV2 {
     type Output = u64;
 
     fn index(&self, cost: ActionCosts) -> &Self::Output {
+        match Self::action_cost_index(cost) {
+            Some(index) => &self.data[index],
+            None => &0,
+        }
+    }
+}
+
+impl Index<ExtCosts> for ProfileDataV2 {
+    type Output = u64;
+
+    fn index(&self, cost: ExtCosts) -> &Self::Output {
+        match Self::ext_cost_index(cost) {
+            Some(index) => &self.data[index],
+            None => &0,
+        }
+    }
+}
+
+impl ProfileDataV2 {
+    /// Entry of `cost` in the data of V2, which some costs share.
+    fn action_cost_index(cost: ActionCosts) -> Option<usize> {
         let index = match cost {
             ActionCosts::create_account => 0,
             ActionCosts::delete_account => 1,
@@ -140,16 +161,13 @@ impl Index<ActionCosts> for ProfileDataV2 {
             ActionCosts::new_data_receipt_base => 9,
             // new costs added after profile v1 was deprecated don't have this entry
             #[allow(unreachable_patterns)]
-            _ => return &0,
+            _ => return None,
         };
-        &self.data[index]
+        Some(index)
     }
-}
-
-impl Index<ExtCosts> for ProfileDataV2 {
-    type Output = u64;
 
-    fn index(&self, cost: ExtCosts) -> &Self::Output {
+    /// Entry of `cost` in the data of V2, which some costs share.
+    fn ext_cost_index(cost: ExtCosts) -> Option<usize> {
         let index = match cost {
             ExtCosts::base => 10,
             ExtCosts::contract_loading_base => 11,
@@ -214,9 +232,62 @@ impl Index<ExtCosts> for ProfileDataV2 {
             ExtCosts::validator_total_power_base => 61,
             // new costs added after profile v1 was deprecated don't have this entry
             #[allow(unreachable_patterns)]
-            _ => return &0,
+            _ => return None,
         };
-        &self.data[index]
+        Some(index)
+    }
+}
+
+const WASM_INSTRUCTION_INDEX: usize = 62;
+
+/// Upgrades a V2 profile to the current schema.
+///
+/// V2 stores a single entry for some costs, e.g. both costs of deploying a
+/// contract.  The gas of such an entry goes to the first of its costs, in
+/// the order of the cost enums, rather than being counted twice.
+impl From<&ProfileDataV2> for ProfileDataV3 {
+    fn from(v2: &ProfileDataV2) -> Self {
+        let mut v3 = ProfileDataV3::new();
+        let mut converted = [false; DataArray::LEN];
+        for cost in ActionCosts::iter() {
+            if let Some(index) = ProfileDataV2::action_cost_index(cost) {
+                if !std::mem::replace(&mut converted[index], true) {
+                    v3.add_action_cost(cost, v2.data[index]);
+                }
+            }
+        }
+        for cost in ExtCosts::iter() {
+            if let Some(index) = ProfileDataV2::ext_cost_index(cost) {
+                if !std::mem::replace(&mut converted[index], true) {
+                    v3.add_ext_cost(cost, v2.data[index]);
+                }
+            }
+        }
+        v3.wasm_gas = v2.get_wasm_cost();
+        v3
+    }
+}
+
+/// Downgrades a profile to V2, for consumers still reading it.
+///
+/// The costs sharing an entry in V2 add up in it, and the costs added after
+/// V2 are left out.
+impl From<&ProfileDataV3> for ProfileDataV2 {
+    fn from(v3: &ProfileDataV3) -> Self {
+        let mut v2 = ProfileDataV2::default();
+        let data = &mut v2.data.0;
+        for cost in ActionCosts::iter() {
+            if let Some(index) = ProfileDataV2::action_cost_index(cost) {
+                data[index] = data[index].saturating_add(v3.get_action_cost(cost));
+            }
+        }
+        for cost in ExtCosts::iter() {
+            if let Some(index) = ProfileDataV2::ext_cost_index(cost) {
+                data[index] = data[index].saturating_add(v3.get_ext_cost(cost));
+            }
+        }
+        data[WASM_INSTRUCTION_INDEX] = v3.get_wasm_cost();
+        v2
     }
 }
 
@@ -332,6 +403,41 @@ mod test {
         .assert_eq(&pretty_debug_str)
     }
 
+    #[test]
+    fn test_v2_to_v3() {
+        let mut v2 = ProfileDataV2::test();
+        let v3 = ProfileDataV3::from(&v2);
+        assert_eq!(v3.get_action_cost(ActionCosts::deploy_contract_base), 1002);
+        assert_eq!(v3.get_action_cost(ActionCosts::deploy_contract_byte), 0);
+        assert_eq!(v3.get_action_cost(ActionCosts::new_data_receipt_byte), 1008);
+        assert_eq!(v3.get_ext_cost(ExtCosts::read_cached_trie_node), 53);
+        assert_eq!(v3.get_wasm_cost(), v2.get_wasm_cost());
+        assert_eq!(v3.action_gas(), v2.action_gas());
+        // Only the entries which no cost maps to are lost on the way back.
+        v2.data.0[70..].fill(0);
+        assert_eq!(ProfileDataV2::from(&v3), v2);
+    }
+
+    #[test]
+    fn test_v3_to_v2() {
+        let mut v3 = ProfileDataV3::new();
+        v3.add_action_cost(ActionCosts::deploy_contract_base, 10);
+        v3.add_action_cost(ActionCosts::deploy_contract_byte, 5);
+        v3.add_action_cost(ActionCosts::delegate, 100);
+        v3.add_ext_cost(ExtCosts::sha256_base, 7);
+        v3.wasm_gas = 42;
+        let v2 = ProfileDataV2::from(&v3);
+        assert_eq!(v2[ActionCosts::deploy_contract_base], 15);
+        assert_eq!(v2[ActionCosts::delegate], 0);
+        assert_eq!(v2.action_gas(), 15);
+        assert_eq!(v2.get_ext_cost(ExtCosts::sha256_base), 7);
+        assert_eq!(v2.get_wasm_cost(), 42);
+        let back = ProfileDataV3::from(&v2);
+        assert_eq!(back.get_action_cost(ActionCosts::deploy_contract_base), 15);
+        assert_eq!(back.get_ext_cost(ExtCosts::sha256_base), 7);
+        assert_eq!(back.get_wasm_cost(), 42);
+    }
+
     #[test]
     fn test_profile_data_debug_no_data() {
         let profile_data = ProfileDataV2::default();
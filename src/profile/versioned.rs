@@ -0,0 +1,200 @@
+use super::{ProfileDataV2, ProfileDataV3};
+use borsh::{BorshDeserialize, BorshSerialize};
+
+/// Gas profile tagged with the version of its encoding.
+///
+/// This is the encoding to use when storing profiles, e.g. in block outcomes:
+/// a one byte version tag followed by the Borsh encoding of the profile of
+/// that version.  Version numbers match the meta data versions, so start with
+/// 2.  New versions only ever get appended, and [`ProfileDataV3`] tolerates
+/// profiles with more or fewer costs than the decoder knows about, so
+/// indexers built against older versions of this crate keep decoding the
+/// profiles of the versions they know.
+///
+/// With the `borsh_schema` feature, [`borsh::BorshSchema`] is implemented for
+/// the profile types and [`VersionedProfileData::schema`] exports the schema.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum VersionedProfileData {
+    V2(ProfileDataV2),
+    V3(Box<ProfileDataV3>),
+}
+
+impl VersionedProfileData {
+    const V2_TAG: u8 = 2;
+    const V3_TAG: u8 = 3;
+
+    pub fn version(&self) -> u8 {
+        match self {
+            Self::V2(_) => Self::V2_TAG,
+            Self::V3(_) => Self::V3_TAG,
+        }
+    }
+
+    /// The profile in the latest schema, converting older versions as
+    /// described in their conversions to [`ProfileDataV3`].
+    pub fn into_latest(self) -> ProfileDataV3 {
+        match self {
+            Self::V2(profile) => ProfileDataV3::from(&profile),
+            Self::V3(profile) => *profile,
+        }
+    }
+
+    /// Schema of the encoding of the profiles, for decoders not written in
+    /// Rust.
+    #[cfg(feature = "borsh_schema")]
+    pub fn schema() -> borsh::schema::BorshSchemaContainer {
+        borsh::schema_container_of::<Self>()
+    }
+}
+
+impl From<ProfileDataV2> for VersionedProfileData {
+    fn from(profile: ProfileDataV2) -> Self {
+        Self::V2(profile)
+    }
+}
+
+impl From<ProfileDataV3> for VersionedProfileData {
+    fn from(profile: ProfileDataV3) -> Self {
+        Self::V3(Box::new(profile))
+    }
+}
+
+impl BorshSerialize for VersionedProfileData {
+    fn serialize<W: std::io::Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        self.version().serialize(writer)?;
+        match self {
+            Self::V2(profile) => profile.serialize(writer),
+            Self::V3(profile) => profile.serialize(writer),
+        }
+    }
+}
+
+impl BorshDeserialize for VersionedProfileData {
+    fn deserialize_reader<R: std::io::Read>(rd: &mut R) -> std::io::Result<Self> {
+        match u8::deserialize_reader(rd)? {
+            Self::V2_TAG => Ok(Self::V2(ProfileDataV2::deserialize_reader(rd)?)),
+            Self::V3_TAG => Ok(Self::V3(Box::new(ProfileDataV3::deserialize_reader(rd)?))),
+            version => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("unknown gas profile version {version}"),
+            )),
+        }
+    }
+}
+
+#[cfg(feature = "borsh_schema")]
+mod schema {
+    use super::*;
+    use borsh::schema::{add_definition, Declaration, Definition, Fields};
+    use borsh::BorshSchema;
+    use std::collections::BTreeMap;
+
+    /// Both versions store their costs as `Vec<u64>`, indexed as described in
+    /// `borsh_action_index` and `borsh_ext_index` for V3.
+    impl BorshSchema for ProfileDataV3 {
+        fn add_definitions_recursively(definitions: &mut BTreeMap<Declaration, Definition>) {
+            let fields = Fields::NamedFields(vec![
+                ("actions_profile".to_string(), <Vec<u64>>::declaration()),
+                ("wasm_ext_profile".to_string(), <Vec<u64>>::declaration()),
+                ("wasm_gas".to_string(), u64::declaration()),
+            ]);
+            add_definition(Self::declaration(), Definition::Struct { fields }, definitions);
+            <Vec<u64>>::add_definitions_recursively(definitions);
+        }
+
+        fn declaration() -> Declaration {
+            "ProfileDataV3".to_string()
+        }
+    }
+
+    impl BorshSchema for ProfileDataV2 {
+        fn add_definitions_recursively(definitions: &mut BTreeMap<Declaration, Definition>) {
+            let fields = Fields::NamedFields(vec![("data".to_string(), <Vec<u64>>::declaration())]);
+            add_definition(Self::declaration(), Definition::Struct { fields }, definitions);
+            <Vec<u64>>::add_definitions_recursively(definitions);
+        }
+
+        fn declaration() -> Declaration {
+            "ProfileDataV2".to_string()
+        }
+    }
+
+    impl BorshSchema for VersionedProfileData {
+        fn add_definitions_recursively(definitions: &mut BTreeMap<Declaration, Definition>) {
+            let variants = vec![
+                (Self::V2_TAG.into(), "V2".to_string(), ProfileDataV2::declaration()),
+                (Self::V3_TAG.into(), "V3".to_string(), ProfileDataV3::declaration()),
+            ];
+            add_definition(
+                Self::declaration(),
+                Definition::Enum { tag_width: 1, variants },
+                definitions,
+            );
+            ProfileDataV2::add_definitions_recursively(definitions);
+            ProfileDataV3::add_definitions_recursively(definitions);
+        }
+
+        fn declaration() -> Declaration {
+            "VersionedProfileData".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use enum_map::Enum;
+    use unc_parameters::{ActionCosts, ExtCosts};
+
+    #[test]
+    fn test_versioned_roundtrip() {
+        let mut v3 = ProfileDataV3::default();
+        v3.add_action_cost(ActionCosts::transfer, 10);
+        v3.add_ext_cost(ExtCosts::base, 20);
+        for profile in [VersionedProfileData::V2(ProfileDataV2::test()), v3.into()] {
+            let bytes = borsh::to_vec(&profile).unwrap();
+            assert_eq!(bytes[0], profile.version());
+            assert_eq!(VersionedProfileData::try_from_slice(&bytes).unwrap(), profile);
+        }
+    }
+
+    #[test]
+    fn test_versioned_v3_encoding_is_stable() {
+        let mut profile = ProfileDataV3::default();
+        profile.add_action_cost(ActionCosts::create_account, 1);
+        profile.add_ext_cost(ExtCosts::base, 2);
+        let bytes = borsh::to_vec(&VersionedProfileData::from(profile.clone())).unwrap();
+        // The tag followed by the plain V3 encoding.
+        assert_eq!(bytes[1..], borsh::to_vec(&profile).unwrap());
+        assert_eq!(bytes[0], 3);
+        assert_eq!(bytes[1..5], (ActionCosts::LENGTH as u32).to_le_bytes());
+        assert_eq!(bytes[5..13], 1u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_into_latest() {
+        let v3 = ProfileDataV3::test();
+        assert_eq!(VersionedProfileData::from(v3.clone()).into_latest(), v3);
+        let v2 = ProfileDataV2::test();
+        let latest = VersionedProfileData::from(v2.clone()).into_latest();
+        assert_eq!(latest, ProfileDataV3::from(&v2));
+        assert_eq!(latest.get_ext_cost(ExtCosts::base), v2.get_ext_cost(ExtCosts::base));
+    }
+
+    #[test]
+    fn test_unknown_version() {
+        let err = VersionedProfileData::try_from_slice(&[4, 0, 0, 0, 0]).unwrap_err();
+        assert_eq!(err.to_string(), "unknown gas profile version 4");
+    }
+
+    #[cfg(feature = "borsh_schema")]
+    #[test]
+    fn test_schema() {
+        let schema = VersionedProfileData::schema();
+        assert_eq!(schema.declaration(), "VersionedProfileData");
+        let names: Vec<_> = schema.definitions().map(|(name, _)| name.as_str()).collect();
+        for name in ["ProfileDataV2", "ProfileDataV3", "VersionedProfileData"] {
+            assert!(names.contains(&name), "{name} missing from {names:?}");
+        }
+    }
+}
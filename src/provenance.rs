@@ -0,0 +1,307 @@
+//! Attestations that an outcome came from this runner, for services
+//! proving the results of the calls they run off chain.
+//!
+//! [`run_with_provenance`] runs a call, then has an [`Attester`] attest the
+//! [`ExecutionClaim`] of the call: the hashes of its code, method, context,
+//! promise results and outcome, and the fingerprints of its config and fees.
+//! Attesters sign its [`ExecutionClaim::digest`] with a key of the operator,
+//! as [`Ed25519Attester`], or have a TEE produce a quote over it.
+//!
+//! Anyone knowing these inputs of a call checks a [`Provenance`] by
+//! computing the claim of the call with [`ExecutionClaim::new`] and verifying
+//! the attestation of its digest, with [`Provenance::verify_ed25519`] for
+//! those of an [`Ed25519Attester`].  The attestation only proves that the
+//! holder of the key, or the enclave, ran the call: whether the runner it ran
+//! is canonical is up to what the verifier knows of them.  The state the call
+//! read through its `External` is not part of the claim either: the verifier
+//! has to know that the runner read the state it expects, as that of the
+//! block of the context.
+
+use crate::fingerprint::ConfigFingerprint;
+use crate::logic::errors::VMRunnerError;
+use crate::logic::types::PromiseResult;
+use crate::logic::{CompiledContractCache, Config, External, VMContext, VMOutcome};
+use crate::{ContractCode, RunOptions};
+use borsh::{BorshDeserialize, BorshSerialize};
+use unc_parameters::RuntimeFeesConfig;
+use unc_primitives_core::hash::{hash as sha256, CryptoHash};
+
+/// Prefix of the digests, keeping them from being valid signatures of
+/// anything else signed with the key.
+const DIGEST_DOMAIN: &[u8] = b"unc-vm-runner provenance v1\0";
+
+/// What a [`Provenance`] attests, see the module documentation.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct ExecutionClaim {
+    /// Hash of the contract, as [`ContractCode::hash`].
+    pub code_hash: CryptoHash,
+    /// sha256 of the name of the method called.
+    pub method_name_hash: CryptoHash,
+    /// sha256 of the borsh serialization of the [`VMContext`].
+    pub context_hash: CryptoHash,
+    /// sha256 of the borsh serialization of the [`PromiseResult`]s, as a
+    /// `Vec`.
+    pub promise_results_hash: CryptoHash,
+    /// sha256 of the borsh serialization of the [`VMOutcome`].
+    pub outcome_hash: CryptoHash,
+    /// Fingerprint of the config, see [`ConfigFingerprint`].
+    pub config_fingerprint: CryptoHash,
+    /// Fingerprint of the fees config, see [`ConfigFingerprint`].
+    pub fees_fingerprint: CryptoHash,
+}
+
+impl ExecutionClaim {
+    /// The claim that calling `method_name` of `code` with `context` and
+    /// `promise_results`, under `config` and `fees_config`, gave `outcome`.
+    pub fn new(
+        code: &ContractCode,
+        method_name: &str,
+        context: &VMContext,
+        promise_results: &[PromiseResult],
+        outcome: &VMOutcome,
+        config: &Config,
+        fees_config: &RuntimeFeesConfig,
+    ) -> Self {
+        Self::with_context_hash(
+            code,
+            method_name,
+            hash_context(context),
+            promise_results,
+            outcome,
+            config,
+            fees_config,
+        )
+    }
+
+    /// Same as [`Self::new`], for a context already hashed, consumed by the
+    /// call.
+    fn with_context_hash(
+        code: &ContractCode,
+        method_name: &str,
+        context_hash: CryptoHash,
+        promise_results: &[PromiseResult],
+        outcome: &VMOutcome,
+        config: &Config,
+        fees_config: &RuntimeFeesConfig,
+    ) -> Self {
+        let promise_results =
+            borsh::to_vec(promise_results).expect("failed serializing promise results");
+        Self {
+            code_hash: *code.hash(),
+            method_name_hash: sha256(method_name.as_bytes()),
+            context_hash,
+            promise_results_hash: sha256(&promise_results),
+            outcome_hash: sha256(&borsh::to_vec(outcome).expect("failed serializing an outcome")),
+            config_fingerprint: config.fingerprint(),
+            fees_fingerprint: fees_config.fingerprint(),
+        }
+    }
+
+    /// What attesters attest: the sha256 of a prefix of this format followed
+    /// by the borsh serialization of the claim.
+    pub fn digest(&self) -> CryptoHash {
+        let mut bytes = DIGEST_DOMAIN.to_vec();
+        self.serialize(&mut bytes).unwrap();
+        sha256(&bytes)
+    }
+}
+
+fn hash_context(context: &VMContext) -> CryptoHash {
+    sha256(&borsh::to_vec(context).expect("failed serializing a context"))
+}
+
+/// Attests the digests of [`ExecutionClaim`]s, see the module documentation.
+pub trait Attester: Send + Sync + std::fmt::Debug {
+    /// The attestation of `digest`, as a signature or the quote of a TEE.
+    fn attest(&self, digest: &CryptoHash) -> Result<Vec<u8>, AttestationError>;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("cannot attest the execution: {0}")]
+pub struct AttestationError(pub String);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProvenanceError {
+    #[error(transparent)]
+    Runner(#[from] VMRunnerError),
+    #[error(transparent)]
+    Attestation(#[from] AttestationError),
+}
+
+/// A claim with its attestation.
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct Provenance {
+    pub claim: ExecutionClaim,
+    pub attestation: Vec<u8>,
+}
+
+impl Provenance {
+    /// Whether the attestation is the signature of the digest of the claim
+    /// with the key of `verifying_key`, as an [`Ed25519Attester`] signs it.
+    #[cfg(feature = "ed25519")]
+    pub fn verify_ed25519(&self, verifying_key: &ed25519_dalek::VerifyingKey) -> bool {
+        let Ok(signature) = ed25519_dalek::Signature::from_slice(&self.attestation) else {
+            return false;
+        };
+        verifying_key.verify_strict(&self.claim.digest().0, &signature).is_ok()
+    }
+}
+
+/// Signs the digests with an ed25519 key of the operator.
+#[cfg(feature = "ed25519")]
+pub struct Ed25519Attester {
+    key: ed25519_dalek::SigningKey,
+}
+
+#[cfg(feature = "ed25519")]
+impl Ed25519Attester {
+    pub fn new(key: ed25519_dalek::SigningKey) -> Self {
+        Self { key }
+    }
+
+    pub fn verifying_key(&self) -> ed25519_dalek::VerifyingKey {
+        self.key.verifying_key()
+    }
+}
+
+#[cfg(feature = "ed25519")]
+impl std::fmt::Debug for Ed25519Attester {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Keeps the secret key out of the logs.
+        let verifying_key = self.verifying_key();
+        f.debug_struct("Ed25519Attester").field("verifying_key", verifying_key.as_bytes()).finish()
+    }
+}
+
+#[cfg(feature = "ed25519")]
+impl Attester for Ed25519Attester {
+    fn attest(&self, digest: &CryptoHash) -> Result<Vec<u8>, AttestationError> {
+        use ed25519_dalek::Signer;
+        Ok(self.key.sign(&digest.0).to_bytes().to_vec())
+    }
+}
+
+/// Same as [`crate::run_with_options`], with the [`Provenance`] of the
+/// outcome attested by `attester`.
+///
+/// Fails without an outcome if the attester fails: the call is meant to be
+/// run again once the attester can attest it.
+pub fn run_with_provenance(
+    code: &ContractCode,
+    method_name: &str,
+    ext: &mut dyn External,
+    context: VMContext,
+    wasm_config: &Config,
+    fees_config: &RuntimeFeesConfig,
+    promise_results: &[PromiseResult],
+    cache: Option<&dyn CompiledContractCache>,
+    options: &RunOptions,
+    attester: &dyn Attester,
+) -> Result<(VMOutcome, Provenance), ProvenanceError> {
+    let context_hash = hash_context(&context);
+    let outcome = crate::run_with_options(
+        code,
+        method_name,
+        ext,
+        context,
+        wasm_config,
+        fees_config,
+        promise_results,
+        cache,
+        options,
+    )?;
+    let claim = ExecutionClaim::with_context_hash(
+        code,
+        method_name,
+        context_hash,
+        promise_results,
+        &outcome,
+        wasm_config,
+        fees_config,
+    );
+    let attestation = attester.attest(&claim.digest())?;
+    Ok((outcome, Provenance { claim, attestation }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::errors::FunctionCallError;
+    use crate::tests::{create_context, test_vm_config};
+
+    #[derive(Debug)]
+    struct Unavailable;
+
+    impl Attester for Unavailable {
+        fn attest(&self, _: &CryptoHash) -> Result<Vec<u8>, AttestationError> {
+            Err(AttestationError("no enclave".to_string()))
+        }
+    }
+
+    fn claim(
+        method_name: &str,
+        promise_results: &[PromiseResult],
+        outcome: &VMOutcome,
+        config: &Config,
+        fees_config: &RuntimeFeesConfig,
+    ) -> ExecutionClaim {
+        let code = ContractCode::new(b"code".to_vec(), None);
+        let context = create_context(vec![]);
+        ExecutionClaim::new(
+            &code,
+            method_name,
+            &context,
+            promise_results,
+            outcome,
+            config,
+            fees_config,
+        )
+    }
+
+    fn claim_of(outcome: &VMOutcome) -> ExecutionClaim {
+        claim("main", &[], outcome, &test_vm_config(), &RuntimeFeesConfig::test())
+    }
+
+    #[test]
+    fn test_digest() {
+        let outcome = VMOutcome::nop_outcome(FunctionCallError::Timeout);
+        let claim = claim_of(&outcome);
+        assert_eq!(claim.digest(), claim_of(&outcome).digest());
+
+        let mut other = VMOutcome::nop_outcome(FunctionCallError::Timeout);
+        other.burnt_gas = 1;
+        assert_ne!(claim_of(&other).digest(), claim.digest());
+        let (config, fees) = (test_vm_config(), RuntimeFeesConfig::test());
+        let mut other_config = config.clone();
+        other_config.limit_config.max_gas_burnt += 1;
+        assert_ne!(claim("main", &[], &outcome, &other_config, &fees).digest(), claim.digest());
+        // An attestation of a call is not one of another method, other
+        // promise results or other fees.
+        assert_ne!(claim("other", &[], &outcome, &config, &fees).digest(), claim.digest());
+        let results = [PromiseResult::Failed];
+        assert_ne!(claim("main", &results, &outcome, &config, &fees).digest(), claim.digest());
+        let free = RuntimeFeesConfig::free();
+        assert_ne!(claim("main", &[], &outcome, &config, &free).digest(), claim.digest());
+        assert_eq!(
+            Unavailable.attest(&claim.digest()).unwrap_err().to_string(),
+            "cannot attest the execution: no enclave"
+        );
+    }
+
+    #[cfg(feature = "ed25519")]
+    #[test]
+    fn test_ed25519() {
+        let attester = Ed25519Attester::new(ed25519_dalek::SigningKey::from_bytes(&[7; 32]));
+        let claim = claim_of(&VMOutcome::nop_outcome(FunctionCallError::Timeout));
+        let mut provenance =
+            Provenance { claim, attestation: attester.attest(&claim.digest()).unwrap() };
+        assert!(provenance.verify_ed25519(&attester.verifying_key()));
+        let other = ed25519_dalek::SigningKey::from_bytes(&[8; 32]).verifying_key();
+        assert!(!provenance.verify_ed25519(&other));
+
+        provenance.claim.outcome_hash = CryptoHash::default();
+        assert!(!provenance.verify_ed25519(&attester.verifying_key()));
+        provenance.attestation.pop();
+        assert!(!provenance.verify_ed25519(&attester.verifying_key()));
+    }
+}
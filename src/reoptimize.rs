@@ -0,0 +1,256 @@
+//! Background re-optimization of hot contracts.
+//!
+//! Contracts are compiled with [`OptLevel::Fast`] when deployed so that they
+//! are ready quickly.  A [`Reoptimizer`] keeps statistics of the calls made to
+//! each contract and, once a contract crosses the [`HotContractThresholds`],
+//! recompiles it with [`OptLevel::Optimized`] on a background thread and
+//! caches the upgraded artifact.  Caches replace entries atomically (see
+//! [`crate::FilesystemContractRuntimeCache`]), so concurrent calls load either
+//! the old or the new artifact, never a mix of them.
+//!
+//! This does not affect determinism: gas is charged by the instrumentation
+//! inserted when the contract is prepared, which is the same at every
+//! optimization level, so both artifacts burn exactly the same gas.  Wasmtime
+//! stores the upgraded artifact under the key of its level, which its VMs
+//! then load instead of the artifact compiled at deploy time.
+//!
+//! Only the contracts of VMs whose compilers have optimization levels are
+//! recompiled, which is only Wasmtime at the moment: the singlepass compilers
+//! of the other VMs generate the same code at every level, so their
+//! contracts stay [`ContractTier::Baseline`] however hot they get.
+
+use crate::logic::{CompiledContractCache, Config, VMOutcome};
+use crate::runner::{CompileOptions, OptLevel, VMKindExt};
+use crate::{ContractCode, ContractPrecompilatonResult};
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use unc_primitives_core::hash::CryptoHash;
+use unc_parameters::vm::VMKind;
+use unc_primitives_core::types::Gas;
+
+/// When a contract is considered hot: either limit being reached makes it
+/// eligible for re-optimization.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HotContractThresholds {
+    /// Number of calls of the contract.
+    pub calls: u64,
+    /// Total gas burnt by the calls of the contract.
+    pub gas: Gas,
+}
+
+impl Default for HotContractThresholds {
+    fn default() -> Self {
+        Self { calls: 1_000, gas: 1_000 * 10u64.pow(12) }
+    }
+}
+
+/// Compilation tier of a contract tracked by a [`Reoptimizer`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ContractTier {
+    /// Still running the artifact compiled at deploy time.
+    Baseline,
+    /// Waiting for or undergoing recompilation.
+    Scheduled,
+    /// The cache holds the optimized artifact.
+    Optimized,
+    /// The recompilation failed or the VM does not cache its artifacts, the
+    /// cache holds the previous artifact if any.
+    Failed,
+}
+
+/// Statistics of the calls of a contract.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ContractStats {
+    pub calls: u64,
+    pub gas: Gas,
+    pub tier: ContractTier,
+}
+
+enum Job {
+    Recompile(ContractCode),
+    Flush(mpsc::Sender<()>),
+}
+
+/// Recompiles the contracts reaching the [`HotContractThresholds`] in the
+/// background, see the module documentation.
+///
+/// Dropping the reoptimizer waits for the pending recompilations.
+pub struct Reoptimizer {
+    /// Whether the compiler of the VM of the config has optimization levels,
+    /// see the module documentation.
+    optimizes: bool,
+    thresholds: HotContractThresholds,
+    stats: Arc<Mutex<HashMap<CryptoHash, ContractStats>>>,
+    jobs: Option<mpsc::Sender<Job>>,
+    worker: Option<JoinHandle<()>>,
+}
+
+impl Reoptimizer {
+    /// Makes a reoptimizer upgrading the artifacts compiled with `config`
+    /// stored in `cache`.
+    pub fn new(
+        config: Config,
+        cache: Arc<dyn CompiledContractCache>,
+        thresholds: HotContractThresholds,
+    ) -> Self {
+        let optimizes = config.vm_kind == VMKind::Wasmtime;
+        let stats: Arc<Mutex<HashMap<CryptoHash, ContractStats>>> = Default::default();
+        let (jobs, receiver) = mpsc::channel();
+        let worker = {
+            let stats = Arc::clone(&stats);
+            std::thread::Builder::new()
+                .name("contract-reoptimizer".to_string())
+                .spawn(move || {
+                    for job in receiver {
+                        match job {
+                            Job::Recompile(code) => {
+                                let tier = recompile(&config, &code, &*cache);
+                                if let Some(stats) = stats.lock().unwrap().get_mut(code.hash()) {
+                                    stats.tier = tier;
+                                }
+                            }
+                            Job::Flush(done) => {
+                                let _ = done.send(());
+                            }
+                        }
+                    }
+                })
+                .expect("failed to spawn the reoptimizer thread")
+        };
+        Self { optimizes, thresholds, stats, jobs: Some(jobs), worker: Some(worker) }
+    }
+
+    /// Accounts the call of `code` which resulted in `outcome`, scheduling its
+    /// recompilation if this makes the contract hot.
+    ///
+    /// Returns whether the recompilation has been scheduled by this call,
+    /// never the case for the VMs without optimization levels.
+    pub fn record_call(&self, code: &ContractCode, outcome: &VMOutcome) -> bool {
+        let mut stats = self.stats.lock().unwrap();
+        let stats = stats.entry(*code.hash()).or_insert(ContractStats {
+            calls: 0,
+            gas: 0,
+            tier: ContractTier::Baseline,
+        });
+        stats.calls += 1;
+        stats.gas = stats.gas.saturating_add(outcome.burnt_gas);
+        let hot = stats.calls >= self.thresholds.calls || stats.gas >= self.thresholds.gas;
+        if !self.optimizes || stats.tier != ContractTier::Baseline || !hot {
+            return false;
+        }
+        stats.tier = ContractTier::Scheduled;
+        let code = ContractCode::new(code.code().to_vec(), Some(*code.hash()));
+        self.jobs.as_ref().unwrap().send(Job::Recompile(code)).is_ok()
+    }
+
+    /// Statistics of the contract with the given code hash, if it has been
+    /// called.
+    pub fn stats(&self, code_hash: &CryptoHash) -> Option<ContractStats> {
+        self.stats.lock().unwrap().get(code_hash).copied()
+    }
+
+    /// Waits until the recompilations scheduled so far are done.
+    pub fn flush(&self) {
+        let (done, wait) = mpsc::channel();
+        if self.jobs.as_ref().unwrap().send(Job::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+impl Drop for Reoptimizer {
+    fn drop(&mut self) {
+        drop(self.jobs.take());
+        if let Some(worker) = self.worker.take() {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn recompile(
+    config: &Config,
+    code: &ContractCode,
+    cache: &dyn CompiledContractCache,
+) -> ContractTier {
+    let _span =
+        tracing::debug_span!(target: "vm", "reoptimize", code_hash = %code.hash()).entered();
+    let options = CompileOptions { opt_level: OptLevel::Optimized, ..CompileOptions::default() };
+    let runtime = match config.vm_kind.runtime_with_options(config.clone(), options) {
+        Ok(runtime) => runtime,
+        Err(err) => {
+            tracing::warn!(target: "vm", %err, "cannot reoptimize contract");
+            return ContractTier::Failed;
+        }
+    };
+    // Unlike `precompile_contract`, `VM::precompile` compiles even if the
+    // cache already has an entry, which it then replaces.
+    match runtime.precompile(code, cache) {
+        Ok(Ok(ContractPrecompilatonResult::ContractCompiled(info)))
+            if info.opt_level == OptLevel::Optimized =>
+        {
+            ContractTier::Optimized
+        }
+        Ok(Ok(_)) => ContractTier::Failed,
+        Ok(Err(err)) => {
+            tracing::warn!(target: "vm", %err, "reoptimized contract does not compile");
+            ContractTier::Failed
+        }
+        Err(err) => {
+            tracing::warn!(target: "vm", %err, "cannot store reoptimized contract");
+            ContractTier::Failed
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::errors::{FunctionCallError, MethodResolveError};
+    use crate::tests::{test_vm_config, with_vm_variants};
+    use crate::{get_contract_cache_key_with_options, MockCompiledContractCache};
+
+    #[test]
+    fn test_hot_contract_is_reoptimized() {
+        let code = ContractCode::new(unc_test_contracts::trivial_contract().to_vec(), None);
+        let outcome = VMOutcome::nop_outcome(FunctionCallError::MethodResolveError(
+            MethodResolveError::MethodNotFound,
+        ));
+        let config = test_vm_config();
+        with_vm_variants(&config, |vm_kind| {
+            let mut config = config.clone();
+            config.vm_kind = vm_kind;
+            let cache = Arc::new(MockCompiledContractCache::default());
+            // Compiled at deploy time.
+            let fast = CompileOptions { opt_level: OptLevel::Fast, ..CompileOptions::default() };
+            crate::precompile_contract_with_options(&code, &config, fast, Some(&*cache))
+                .unwrap()
+                .unwrap();
+            let thresholds = HotContractThresholds { calls: 3, gas: Gas::MAX };
+            let reoptimizer = Reoptimizer::new(config.clone(), cache.clone(), thresholds);
+            assert!(!reoptimizer.record_call(&code, &outcome));
+            assert!(!reoptimizer.record_call(&code, &outcome));
+            let optimizes = vm_kind == VMKind::Wasmtime;
+            assert_eq!(reoptimizer.record_call(&code, &outcome), optimizes);
+            // The contract is only recompiled once.
+            assert!(!reoptimizer.record_call(&code, &outcome));
+            reoptimizer.flush();
+
+            let stats = reoptimizer.stats(code.hash()).unwrap();
+            assert_eq!(stats.calls, 4);
+            if !optimizes {
+                assert_eq!(stats.tier, ContractTier::Baseline);
+                assert_eq!(cache.len(), 1);
+                return;
+            }
+            assert_eq!(stats.tier, ContractTier::Optimized);
+            // The optimized artifact is cached next to the one of deploy time,
+            // under the key the VMs load by default.
+            assert_eq!(cache.len(), 2);
+            let key = get_contract_cache_key_with_options(&code, &config, Default::default());
+            let info = cache.get_compilation_info(&key).unwrap().unwrap();
+            assert_eq!(info.opt_level, OptLevel::Optimized);
+        });
+    }
+}
@@ -0,0 +1,275 @@
+//! Accounting of the resources held by the runner, to find leaks.
+//!
+//! With the `leak_detector` feature, the runners count the instances,
+//! memories and buffers read from the cache they hold at any time.
+//! [`live_resources`] reports the totals of the process, so that the memory
+//! growth of long running nodes can be attributed, and [`check_leaks`]
+//! verifies that a call frees everything it allocated.  [`crate::run`] checks
+//! every call: leaks are logged and fail a debug assertion.
+//!
+//! Without the feature, the accounting compiles to nothing.
+
+#[cfg(feature = "leak_detector")]
+pub use enabled::{check_leaks, live_resources, LiveResources, ResourceLeak};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum ResourceKind {
+    /// Instantiated module.
+    Instance,
+    /// Linear memory of a call, with its initial size.
+    Memory,
+    /// Serialized artifact read from the cache, with its size.
+    CacheBuffer,
+}
+
+/// Accounts a resource for as long as it is alive.
+///
+/// Store it inside the struct owning the resource, or wrap the resource in a
+/// [`Tracked`], so that both are dropped together.
+#[derive(Debug)]
+#[must_use]
+pub(crate) struct ResourceToken {
+    #[cfg(feature = "leak_detector")]
+    kind: ResourceKind,
+    #[cfg(feature = "leak_detector")]
+    bytes: u64,
+}
+
+impl ResourceToken {
+    #[cfg_attr(not(feature = "leak_detector"), allow(unused_variables))]
+    pub(crate) fn new(kind: ResourceKind, bytes: u64) -> Self {
+        #[cfg(feature = "leak_detector")]
+        enabled::acquire(kind, bytes);
+        Self {
+            #[cfg(feature = "leak_detector")]
+            kind,
+            #[cfg(feature = "leak_detector")]
+            bytes,
+        }
+    }
+}
+
+/// The clone accounts for another resource of the same kind and size.
+impl Clone for ResourceToken {
+    fn clone(&self) -> Self {
+        #[cfg(feature = "leak_detector")]
+        {
+            Self::new(self.kind, self.bytes)
+        }
+        #[cfg(not(feature = "leak_detector"))]
+        {
+            Self {}
+        }
+    }
+}
+
+#[cfg(feature = "leak_detector")]
+impl Drop for ResourceToken {
+    fn drop(&mut self) {
+        enabled::release(self.kind, self.bytes);
+    }
+}
+
+/// A resource with the tokens of what it holds, released once it is dropped.
+#[derive(Debug)]
+pub(crate) struct Tracked<T> {
+    // Dropped before the tokens.
+    resource: T,
+    tokens: Vec<ResourceToken>,
+}
+
+impl<T> Tracked<T> {
+    pub(crate) fn new(resource: T, kind: ResourceKind, bytes: u64) -> Self {
+        Self { resource, tokens: vec![ResourceToken::new(kind, bytes)] }
+    }
+
+    /// Accounts another resource living in this one, as the instances of a
+    /// Wasmtime store.
+    pub(crate) fn track(&mut self, kind: ResourceKind, bytes: u64) {
+        self.tokens.push(ResourceToken::new(kind, bytes));
+    }
+}
+
+impl<T> std::ops::Deref for Tracked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.resource
+    }
+}
+
+impl<T> std::ops::DerefMut for Tracked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.resource
+    }
+}
+
+#[cfg(feature = "leak_detector")]
+mod enabled {
+    use super::ResourceKind;
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    const KINDS: usize = 3;
+
+    static LIVE: [AtomicU64; KINDS] = [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+    static LIVE_BYTES: [AtomicU64; KINDS] =
+        [AtomicU64::new(0), AtomicU64::new(0), AtomicU64::new(0)];
+
+    thread_local! {
+        /// Resources allocated minus resources freed by this thread.  Calls
+        /// free their resources on the thread they run on, so this does not
+        /// change over a call which does not leak.
+        static THREAD_BALANCE: Cell<[i64; KINDS]> = const { Cell::new([0; KINDS]) };
+    }
+
+    fn index(kind: ResourceKind) -> usize {
+        match kind {
+            ResourceKind::Instance => 0,
+            ResourceKind::Memory => 1,
+            ResourceKind::CacheBuffer => 2,
+        }
+    }
+
+    fn update_thread_balance(kind: ResourceKind, delta: i64) {
+        THREAD_BALANCE.with(|balance| {
+            let mut counts = balance.get();
+            counts[index(kind)] += delta;
+            balance.set(counts);
+        });
+    }
+
+    pub(super) fn acquire(kind: ResourceKind, bytes: u64) {
+        LIVE[index(kind)].fetch_add(1, Ordering::Relaxed);
+        LIVE_BYTES[index(kind)].fetch_add(bytes, Ordering::Relaxed);
+        update_thread_balance(kind, 1);
+    }
+
+    pub(super) fn release(kind: ResourceKind, bytes: u64) {
+        LIVE[index(kind)].fetch_sub(1, Ordering::Relaxed);
+        LIVE_BYTES[index(kind)].fetch_sub(bytes, Ordering::Relaxed);
+        update_thread_balance(kind, -1);
+    }
+
+    /// Resources held by the runners of the whole process.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+    pub struct LiveResources {
+        pub instances: u64,
+        pub memories: u64,
+        /// Initial size of the live memories.
+        pub memory_bytes: u64,
+        pub cache_buffers: u64,
+        pub cache_buffer_bytes: u64,
+    }
+
+    pub fn live_resources() -> LiveResources {
+        let live = |kind| LIVE[index(kind)].load(Ordering::Relaxed);
+        let bytes = |kind| LIVE_BYTES[index(kind)].load(Ordering::Relaxed);
+        LiveResources {
+            instances: live(ResourceKind::Instance),
+            memories: live(ResourceKind::Memory),
+            memory_bytes: bytes(ResourceKind::Memory),
+            cache_buffers: live(ResourceKind::CacheBuffer),
+            cache_buffer_bytes: bytes(ResourceKind::CacheBuffer),
+        }
+    }
+
+    /// Resources allocated and not freed by a call.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+    #[error(
+        "the call leaked {instances} instances, {memories} memories and {cache_buffers} cache \
+         buffers"
+    )]
+    pub struct ResourceLeak {
+        pub instances: i64,
+        pub memories: i64,
+        pub cache_buffers: i64,
+    }
+
+    /// Runs `call` and checks that it freed all the resources it allocated
+    /// on this thread.
+    ///
+    /// Resources moved to and dropped by another thread while the call runs
+    /// show up as leaks.
+    pub fn check_leaks<R>(call: impl FnOnce() -> R) -> (R, Result<(), ResourceLeak>) {
+        let before = THREAD_BALANCE.with(Cell::get);
+        let result = call();
+        let after = THREAD_BALANCE.with(Cell::get);
+        let leaked = |kind| after[index(kind)] - before[index(kind)];
+        let leak = ResourceLeak {
+            instances: leaked(ResourceKind::Instance),
+            memories: leaked(ResourceKind::Memory),
+            cache_buffers: leaked(ResourceKind::CacheBuffer),
+        };
+        let check = if leak == (ResourceLeak { instances: 0, memories: 0, cache_buffers: 0 }) {
+            Ok(())
+        } else {
+            Err(leak)
+        };
+        (result, check)
+    }
+}
+
+#[cfg(all(test, feature = "leak_detector"))]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+    use crate::{ContractCode, MockCompiledContractCache};
+    use crate::logic::Config;
+    use unc_parameters::RuntimeFeesConfig;
+
+    #[test]
+    fn test_calls_free_their_resources() {
+        let code = wat::parse_str(r#"(module (memory 1) (func (export "main")))"#).unwrap();
+        let code = ContractCode::new(code, None);
+        let config = test_vm_config();
+        let fees = RuntimeFeesConfig::test();
+        with_vm_variants(&config, |vm_kind| {
+            let mut config = config.clone();
+            config.vm_kind = vm_kind;
+            let cache = MockCompiledContractCache::default();
+            // The first call compiles the contract, the second one reads it
+            // from the cache.
+            for _ in 0..2 {
+                let mut ext = MockedExternal::new();
+                let (outcome, check) = check_leaks(|| {
+                    crate::run(
+                        &code,
+                        "main",
+                        &mut ext,
+                        create_context(Vec::new()),
+                        &config,
+                        &fees,
+                        &[],
+                        Some(&cache),
+                    )
+                });
+                assert_eq!(outcome.unwrap().aborted, None);
+                assert_eq!(check, Ok(()), "{vm_kind:?}");
+            }
+        });
+    }
+
+    #[test]
+    fn test_tracked() {
+        let ((), check) = check_leaks(|| {
+            let mut tracked = Tracked::new(vec![0u8; 4], ResourceKind::CacheBuffer, 4);
+            tracked.track(ResourceKind::Instance, 0);
+            assert_eq!(tracked.len(), 4);
+        });
+        assert_eq!(check, Ok(()));
+        let (tracked, check) = check_leaks(|| Tracked::new((), ResourceKind::Instance, 0));
+        assert_eq!(check, Err(ResourceLeak { instances: 1, memories: 0, cache_buffers: 0 }));
+        drop(tracked);
+    }
+
+    #[test]
+    fn test_leak_is_detected() {
+        let (token, check) = check_leaks(|| ResourceToken::new(ResourceKind::Memory, 42));
+        assert_eq!(check, Err(ResourceLeak { instances: 0, memories: 1, cache_buffers: 0 }));
+        assert!(live_resources().memory_bytes >= 42);
+        let ((), check) = check_leaks(|| drop(token));
+        assert_eq!(check, Err(ResourceLeak { instances: 0, memories: -1, cache_buffers: 0 }));
+    }
+}
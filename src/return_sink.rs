@@ -0,0 +1,191 @@
+//! Streaming the return value of a call to the embedder.
+//!
+//! By default `value_return` copies the value into
+//! [`ReturnData::Value`], so a view call returning a multi-megabyte value
+//! holds it once in the guest memory and once in the outcome.  With
+//! [`RunOptions::return_sink`] the value is handed to a [`ReturnSink`] in
+//! chunks of [`RETURN_CHUNK_SIZE`] bytes straight from the guest memory, and
+//! the `return_data` of the outcome is [`ReturnData::None`].
+//!
+//! The value is streamed as `value_return` is called, once its gas is paid
+//! and its length checked against `max_length_returned_data`, so the gas of
+//! a call does not depend on the sink.  A contract calling `value_return`
+//! again replaces the value, and the sink sees a new [`ReturnSink::start`].
+//! Once the call ends, [`ReturnSink::finish`] tells whether the value
+//! streamed last is its result: a call failing after returning, or returning
+//! a promise afterwards, leaves the value of the sink unused.
+//!
+//! Values are only streamed when the call has no output data receivers, as
+//! those need the value for their data receipts.
+//!
+//! [`ReturnData::Value`]: crate::logic::ReturnData::Value
+//! [`ReturnData::None`]: crate::logic::ReturnData::None
+//! [`RunOptions::return_sink`]: crate::RunOptions::return_sink
+
+use std::fmt::Debug;
+use std::sync::Mutex;
+
+/// Largest chunk passed to [`ReturnSink::chunk`].
+pub const RETURN_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Receives the return value of a call in chunks.
+pub trait ReturnSink: Send + Sync + Debug {
+    /// Called when the contract returns a value of `len` bytes, replacing
+    /// the value of the previous calls if any.
+    fn start(&self, len: u64);
+
+    /// Called with the bytes of the value, in order, after [`Self::start`].
+    ///
+    /// The contract waits for this to return, so slow sinks slow down the
+    /// call.
+    fn chunk(&self, data: &[u8]);
+
+    /// Called when the call ends, with the length of the value streamed
+    /// last if it is the result of the call, or `None` if the call failed or
+    /// returned no value streamed to the sink.
+    ///
+    /// Calls failing before they run the contract end without it.
+    fn finish(&self, _result: Option<u64>) {}
+}
+
+/// Streams `value` to `sink`.
+pub(crate) fn stream(sink: &dyn ReturnSink, value: &[u8]) {
+    sink.start(value.len() as u64);
+    for chunk in value.chunks(RETURN_CHUNK_SIZE) {
+        sink.chunk(chunk);
+    }
+}
+
+/// [`ReturnSink`] collecting the value, mostly for tests.
+#[derive(Debug, Default)]
+pub struct BufferReturnSink {
+    value: Mutex<Vec<u8>>,
+}
+
+impl BufferReturnSink {
+    /// Takes the value returned last, empty once a call ended without
+    /// returning it.
+    pub fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut *self.value.lock().unwrap())
+    }
+}
+
+impl ReturnSink for BufferReturnSink {
+    fn start(&self, len: u64) {
+        let mut value = self.value.lock().unwrap();
+        value.clear();
+        value.reserve(usize::try_from(len).unwrap_or(0));
+    }
+
+    fn chunk(&self, data: &[u8]) {
+        self.value.lock().unwrap().extend_from_slice(data);
+    }
+
+    fn finish(&self, result: Option<u64>) {
+        if result.is_none() {
+            self.value.lock().unwrap().clear();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::logic::ReturnData;
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+    use crate::{ContractCode, RunOptions};
+    use std::sync::Arc;
+    use crate::logic::Config;
+    use unc_parameters::vm::VMKind;
+    use unc_parameters::RuntimeFeesConfig;
+
+    /// Records the calls of the sink.
+    #[derive(Debug, Default)]
+    struct RecordingSink {
+        starts: Mutex<Vec<u64>>,
+        results: Mutex<Vec<Option<u64>>>,
+        buffer: BufferReturnSink,
+        largest_chunk: Mutex<usize>,
+    }
+
+    impl ReturnSink for RecordingSink {
+        fn start(&self, len: u64) {
+            self.starts.lock().unwrap().push(len);
+            self.buffer.start(len);
+        }
+
+        fn chunk(&self, data: &[u8]) {
+            let mut largest = self.largest_chunk.lock().unwrap();
+            *largest = (*largest).max(data.len());
+            self.buffer.chunk(data);
+        }
+
+        fn finish(&self, result: Option<u64>) {
+            self.results.lock().unwrap().push(result);
+            self.buffer.finish(result);
+        }
+    }
+
+    #[test]
+    fn test_return_sink() {
+        // Returns one byte, then 200000 bytes starting with "abc".
+        let code = wat::parse_str(
+            r#"
+(module
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (memory 4)
+  (data (i32.const 0) "abc")
+  (func (export "main")
+    (call $value_return (i64.const 1) (i64.const 0))
+    (call $value_return (i64.const 200000) (i64.const 0)))
+)"#,
+        )
+        .unwrap();
+        let code = ContractCode::new(code, None);
+        let fees = RuntimeFeesConfig::test();
+        let mut expected = vec![0; 200000];
+        expected[..3].copy_from_slice(b"abc");
+        with_vm_variants(&test_vm_config(), |vm_kind: VMKind| {
+            let mut config = test_vm_config();
+            config.vm_kind = vm_kind;
+            let run = |return_sink: Option<Arc<dyn ReturnSink>>, receivers| {
+                let options = RunOptions { return_sink, ..RunOptions::default() };
+                let mut context = create_context(vec![]);
+                context.output_data_receivers = receivers;
+                crate::run_with_options(
+                    &code,
+                    "main",
+                    &mut MockedExternal::new(),
+                    context,
+                    &config,
+                    &fees,
+                    &[],
+                    None,
+                    &options,
+                )
+                .unwrap()
+            };
+            let buffered = run(None, vec![]);
+            assert_eq!(buffered.return_data, ReturnData::Value(expected.clone()));
+
+            let sink = Arc::new(RecordingSink::default());
+            let streamed = run(Some(sink.clone()), vec![]);
+            assert_eq!(streamed.aborted, None);
+            assert_eq!(streamed.return_data, ReturnData::None, "{vm_kind:?}");
+            assert_eq!(streamed.burnt_gas, buffered.burnt_gas);
+            assert_eq!(*sink.starts.lock().unwrap(), [1, 200000]);
+            assert_eq!(*sink.results.lock().unwrap(), [Some(200000)]);
+            assert_eq!(*sink.largest_chunk.lock().unwrap(), RETURN_CHUNK_SIZE);
+            assert_eq!(sink.buffer.take(), expected);
+
+            // Data receipts need the value.
+            let sink = Arc::new(RecordingSink::default());
+            let receiver = "alice.unc".parse().unwrap();
+            let outcome = run(Some(sink.clone()), vec![receiver]);
+            assert_eq!(outcome.return_data, ReturnData::Value(expected.clone()));
+            assert!(sink.starts.lock().unwrap().is_empty());
+            assert_eq!(*sink.results.lock().unwrap(), [None]);
+        });
+    }
+}
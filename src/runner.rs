@@ -1,10 +1,22 @@
+use crate::clock::Clock;
+use crate::dry_run::{DryRunExternal, GasEstimate};
 use crate::errors::ContractPrecompilatonResult;
-use crate::logic::errors::{CacheError, CompilationError, VMRunnerError};
+use crate::log_sink::LogCapture;
+use crate::return_sink::ReturnSink;
+use crate::logic::custom_host_functions::CustomHostFunctionRegistry;
+use crate::logic::errors::{CacheError, CompilationError, FunctionCallError, VMRunnerError};
+use crate::logic::mocks::mock_external::MockedExternal;
 use crate::logic::types::PromiseResult;
 use crate::logic::{CompiledContractCache, External, VMContext, VMOutcome};
 use crate::ContractCode;
-use unc_parameters::vm::{Config, VMKind};
+use borsh::{BorshDeserialize, BorshSerialize};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::logic::Config;
+use unc_parameters::vm::{ContractPrepareVersion, VMKind};
 use unc_parameters::RuntimeFeesConfig;
+use unc_primitives_core::types::Gas;
 
 /// Returned by VM::run method.
 ///
@@ -21,7 +33,7 @@ use unc_parameters::RuntimeFeesConfig;
 /// (See also `PartialExecutionStatus`.)
 /// Similarly, the gas values on `VMOutcome` must be the exact same on all
 /// validators, even when a guest error occurs, or else their state will diverge.
-pub(crate) type VMResult<T = VMOutcome> = Result<T, VMRunnerError>;
+pub type VMResult<T = VMOutcome> = Result<T, VMRunnerError>;
 
 /// Validate and run the specified contract.
 ///
@@ -39,6 +51,9 @@ pub(crate) type VMResult<T = VMOutcome> = Result<T, VMRunnerError>;
 ///
 /// The gas cost for contract preparation will be subtracted by the VM
 /// implementation.
+///
+/// Fails with [`VMRunnerError::BackendUnavailable`] if the runtime of the
+/// VM kind of the config cannot run on this host, see [`VMKindExt::runtime`].
 pub fn run(
     code: &ContractCode,
     method_name: &str,
@@ -48,6 +63,31 @@ pub fn run(
     fees_config: &RuntimeFeesConfig,
     promise_results: &[PromiseResult],
     cache: Option<&dyn CompiledContractCache>,
+) -> VMResult {
+    run_with_options(
+        code,
+        method_name,
+        ext,
+        context,
+        wasm_config,
+        fees_config,
+        promise_results,
+        cache,
+        &RunOptions::default(),
+    )
+}
+
+/// Same as [`run`] with the given `options`.
+pub fn run_with_options(
+    code: &ContractCode,
+    method_name: &str,
+    ext: &mut dyn External,
+    context: VMContext,
+    wasm_config: &Config,
+    fees_config: &RuntimeFeesConfig,
+    promise_results: &[PromiseResult],
+    cache: Option<&dyn CompiledContractCache>,
+    options: &RunOptions,
 ) -> VMResult {
     let vm_kind = wasm_config.vm_kind;
     let span = tracing::debug_span!(
@@ -60,17 +100,509 @@ pub fn run(
     )
     .entered();
 
-    let runtime = vm_kind
-        .runtime(wasm_config.clone())
-        .unwrap_or_else(|| panic!("the {vm_kind:?} runtime has not been enabled at compile time"));
+    #[allow(unused_mut)]
+    let mut config = wasm_config.clone();
+    #[cfg(feature = "coverage")]
+    if options.coverage.is_some() {
+        config.coverage = true;
+    }
+    let compile = CompileOptions { codegen: options.codegen, ..CompileOptions::default() };
+    let runtime = vm_kind.runtime_on_host(config, compile, options.host.clone())?;
 
-    let outcome =
-        runtime.run(code, method_name, ext, context, fees_config, promise_results, cache)?;
+    #[cfg(not(feature = "leak_detector"))]
+    let outcome = runtime.run_with_options(
+        code,
+        method_name,
+        ext,
+        context,
+        fees_config,
+        promise_results,
+        cache,
+        options,
+    )?;
+    #[cfg(feature = "leak_detector")]
+    let outcome = {
+        let (outcome, check) = crate::resources::check_leaks(|| {
+            runtime.run_with_options(
+                code,
+                method_name,
+                ext,
+                context,
+                fees_config,
+                promise_results,
+                cache,
+                options,
+            )
+        });
+        if let Err(leak) = check {
+            tracing::error!(target: "vm", %leak, "contract call leaked resources");
+            debug_assert!(false, "{leak}");
+        }
+        outcome?
+    };
 
     span.record("burnt_gas", &outcome.burnt_gas);
     Ok(outcome)
 }
 
+/// Options of a contract call which are not part of the protocol, but for
+/// [`Self::consensus`].
+#[derive(Clone, Debug, Default)]
+pub struct RunOptions {
+    /// Time at which the call is interrupted, failing with
+    /// [`crate::logic::errors::FunctionCallError::Timeout`].
+    ///
+    /// Meant for view calls, to keep a contract from burning all its gas
+    /// slowly.  The singlepass VMs are interrupted with a signal to the
+    /// thread of the call, whose handler lowers the gas limit of the call, so
+    /// the call fails at its next gas charge.  Wasmtime is interrupted at the
+    /// next epoch check of the compiled code.  Without a deadline, the
+    /// outcome of a call only depends on its inputs.
+    ///
+    /// The deadline is a time of [`Self::clock`].
+    pub deadline: Option<Instant>,
+    /// Clock the deadline is measured on, by default the system clock.
+    pub clock: Option<Arc<dyn Clock>>,
+    /// Records the state of the call each time the contract calls a host
+    /// function, in [`VMOutcome::checkpoints`].
+    pub record_checkpoints: bool,
+    /// Records every host function the contract calls with its arguments,
+    /// result and gas, in [`VMOutcome::host_calls`].
+    ///
+    /// Together with the [`crate::ExternalTrace`] of the call this explains
+    /// an execution step by step, see [`crate::replay`].
+    pub record_host_calls: bool,
+    /// Records the receipts the call creates, with the hashes of the
+    /// contracts they deploy and the arguments of their function calls, in
+    /// [`VMOutcome::receipts`].
+    pub record_receipts: bool,
+    /// Records the wasm call stack at the point the call runs out of gas, in
+    /// [`VMOutcome::gas_exhaustion_trace`].
+    pub record_gas_exhaustion_trace: bool,
+    /// Hashes the names of the host functions the contract calls, with the
+    /// gas burnt before each, into [`VMOutcome::execution_fingerprint`].
+    ///
+    /// Much cheaper than [`Self::record_host_calls`]: nodes running the same
+    /// call compare their fingerprints to tell whether the executions
+    /// diverged, and only then record the host calls to find where.
+    pub execution_fingerprint: bool,
+    /// Hooks of the embedder changing the gas of the call, see
+    /// [`ConsensusHooks`].
+    pub consensus: ConsensusHooks,
+    /// Where the logs of the call go, by default only in
+    /// [`VMOutcome::logs`].
+    pub log_capture: LogCapture,
+    /// Streams the return value of the call to the sink instead of keeping
+    /// it in [`VMOutcome::return_data`], see [`crate::ReturnSink`].
+    pub return_sink: Option<Arc<dyn ReturnSink>>,
+    /// Records the storage keys the call touches and the proofs of their
+    /// values before the call, from [`crate::logic::External::storage_proof`],
+    /// into [`VMOutcome::state_witness`].
+    pub record_state_witness: bool,
+    /// Counts the blocks of the contract executed by the call, in the order
+    /// of [`crate::coverage_map`], each call adding to the counts.
+    ///
+    /// Only [`run_with_options`] instruments the contract, by giving its
+    /// config `coverage`.
+    #[cfg(feature = "coverage")]
+    pub coverage: Option<Arc<crate::CoverageCounters>>,
+    /// Resolves the wasm call stack of the call into
+    /// [`VMOutcome::backtrace`] when it aborts, with the names of the
+    /// functions and, when the contract has DWARF line information, the
+    /// source lines of the instructions.
+    ///
+    /// Lines are only resolved for the contracts prepared with
+    /// [`crate::logic::ContractPrepareVersion::V2`].  Symbolicating prepares
+    /// the contract once more, so it is meant for testing and debugging.
+    #[cfg(feature = "backtrace")]
+    pub backtrace: bool,
+    /// Memories NearVM reuses instead of mapping a new one for the call, see
+    /// [`crate::NearVmMemoryPool`], instead of the pool of the
+    /// `memory_pool_size` of the host settings.  The other VMs ignore it.
+    #[cfg(all(feature = "unc_vm", target_arch = "x86_64"))]
+    pub memory_pool: Option<Arc<crate::NearVmMemoryPool>>,
+    /// Allocator of the memories of NearVM calls, which takes precedence over
+    /// [`Self::memory_pool`], see [`crate::GuestMemoryAllocator`].  The other
+    /// VMs ignore it.
+    #[cfg(all(feature = "unc_vm", target_arch = "x86_64"))]
+    pub memory_allocator: Option<Arc<dyn crate::GuestMemoryAllocator>>,
+    /// CPU features [`run_with_options`] generates the code of the contract
+    /// for, and so which of its artifacts it loads from the cache:
+    /// [`CodegenTarget::Baseline`] runs those precompiled with it, see
+    /// [`crate::precompile_contract_for_codegen`].  Panics like the other
+    /// runs if the VM does not support it.
+    pub codegen: CodegenTarget,
+    /// Settings of the host [`run_with_options`] makes the VM of the call
+    /// with, see [`VMKindExt::runtime_on_host`].
+    pub host: HostSettings,
+}
+
+/// Hooks of the embedder changing the gas and outcome of a call.
+///
+/// Unlike the rest of the [`RunOptions`], they are part of the protocol:
+/// every node must run a call with the same hooks to agree on its outcome.
+/// The calls with hooks fail with [`VMRunnerError::ConsensusHooksDisabled`]
+/// unless their config enables them with `consensus_hooks`, which the configs
+/// of `unc-parameters` never do, so that a node does not diverge by passing
+/// them on the path of the chain by mistake.
+#[derive(Clone, Debug, Default)]
+pub struct ConsensusHooks {
+    /// Extra gas charged for loading the contract, see [`CodePricing`].
+    pub code_pricing: Option<Arc<dyn CodePricing>>,
+    /// Host functions of the embedder the contract can import on top of the
+    /// built-in ones, see [`crate::logic::custom_host_functions`].
+    pub custom_host_functions: Option<Arc<CustomHostFunctionRegistry>>,
+}
+
+impl ConsensusHooks {
+    /// Whether no hook is set.
+    pub fn is_empty(&self) -> bool {
+        self.code_pricing.is_none() && self.custom_host_functions.is_none()
+    }
+}
+
+/// Prices of contract code set by the embedder, on top of the costs of the
+/// config.
+///
+/// This lets the embedder change how the size of contracts is priced without
+/// a release of the runner.  The prices must only depend on their arguments,
+/// and the embedder must use the same pricing on every node, as they are part
+/// of the outcome.  Nothing local to a node, such as whether its cache holds
+/// the compiled contract, is given to them for that reason.
+pub trait CodePricing: Send + Sync + std::fmt::Debug {
+    /// Gas burnt for loading a contract of `code_len` bytes at the start of a
+    /// call with `config`.
+    ///
+    /// The runner charges this gas before loading the contract, as part of
+    /// the burnt gas of the call, and the call fails with the error of the
+    /// gas counter if it cannot pay.
+    fn call_gas(&self, code_len: u64, config: &Config) -> Gas;
+
+    /// Gas burnt by a call with `config` for each contract of `code_len`
+    /// bytes it deploys with `promise_batch_action_deploy_contract`, on top
+    /// of the fees of the deploy action.
+    fn deploy_gas(&self, _code_len: u64, _config: &Config) -> Gas {
+        0
+    }
+}
+
+/// Same as [`run`] but also explains how the VM running the contract has
+/// been selected.
+///
+/// The contract only runs on the `vm_kind` of the config, see
+/// [`check_backend`]: running it on another VM could change its outcome.
+/// When that VM cannot run it, the call fails without running the contract,
+/// with a [`BackendSelection`] telling why and which other VMs could.
+pub fn run_with_diagnostics(
+    code: &ContractCode,
+    method_name: &str,
+    ext: &mut dyn External,
+    context: VMContext,
+    wasm_config: &Config,
+    fees_config: &RuntimeFeesConfig,
+    promise_results: &[PromiseResult],
+    cache: Option<&dyn CompiledContractCache>,
+) -> Result<(VMOutcome, RunDiagnostics), RunWithDiagnosticsError> {
+    let mut candidates = vec![wasm_config.vm_kind];
+    candidates.extend(ALL_VM_KINDS.iter().filter(|&&vm_kind| vm_kind != wasm_config.vm_kind));
+    let backend = BackendSelection::new(wasm_config, &candidates);
+    if backend.chosen != Some(wasm_config.vm_kind) {
+        return Err(RunWithDiagnosticsError::Rejected(backend));
+    }
+    let outcome =
+        run(code, method_name, ext, context, wasm_config, fees_config, promise_results, cache)?;
+    Ok((outcome, RunDiagnostics { backend }))
+}
+
+/// Diagnostics of a [`run_with_diagnostics`] call.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RunDiagnostics {
+    pub backend: BackendSelection,
+}
+
+/// Why [`run_with_diagnostics`] failed.
+#[derive(Debug, thiserror::Error)]
+pub enum RunWithDiagnosticsError {
+    /// The VM of the config cannot run its contracts, rejected for the
+    /// reason of the selection, which also has the other VMs.
+    #[error("the VM of the config cannot run its contracts")]
+    Rejected(BackendSelection),
+    #[error(transparent)]
+    Runner(#[from] VMRunnerError),
+}
+
+/// Result of precompiling one contract, see [`crate::precompile_contract`].
+pub type PrecompileResult =
+    Result<Result<ContractPrecompilatonResult, CompilationError>, CacheError>;
+
+/// Compiles `codes` in parallel for the VM of `config` and stores the
+/// artifacts in `cache`.
+///
+/// This warms the cache after an upgrade changing the config, so that the
+/// first call of each contract does not have to compile it.  Contracts are
+/// compiled by one thread per available CPU and the results come in the
+/// order of `codes`.  Like [`crate::precompile_contract`], this panics if
+/// the VM is unavailable on this host.
+pub fn precompile_contracts(
+    codes: &[ContractCode],
+    config: &Config,
+    cache: &dyn CompiledContractCache,
+) -> Vec<PrecompileResult> {
+    let _span =
+        tracing::debug_span!(target: "vm", "precompile_contracts", count = codes.len()).entered();
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get()).min(codes.len());
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(Vec::with_capacity(codes.len()));
+    std::thread::scope(|s| {
+        for _ in 0..threads {
+            s.spawn(|| loop {
+                let index = next.fetch_add(1, Ordering::Relaxed);
+                let Some(code) = codes.get(index) else { break };
+                let result = crate::precompile_contract(code, config, Some(cache));
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// A stage of [`warm_up`], with what it initializes the first time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WarmUpStage {
+    /// Making the runtime: the engine, the compiler and, for NearVM, the pool
+    /// of code memories allocated once per process.
+    Runtime,
+    /// Preparing and compiling the module: the instrumentation and the code
+    /// generator.
+    Compile,
+    /// Loading the artifact and calling it: the trap handlers, which the VMs
+    /// install on their first call, the imports of the host functions and
+    /// the thread locals of the calling thread.
+    Call,
+}
+
+/// A stage of [`warm_up`] and the time it took.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WarmUpStep {
+    pub stage: WarmUpStage,
+    pub time: Duration,
+}
+
+/// Outcome of [`warm_up`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct WarmUp {
+    pub vm_kind: VMKind,
+    /// Whether no warm up of `vm_kind` succeeded before in this process.  The
+    /// later ones initialize nothing more, their times are the ones of a warm
+    /// VM compiling and calling a contract.
+    pub first: bool,
+    /// The stages, in the order they ran.
+    pub steps: Vec<WarmUpStep>,
+}
+
+impl WarmUp {
+    pub fn total_time(&self) -> Duration {
+        self.steps.iter().map(|step| step.time).sum()
+    }
+}
+
+/// Why [`warm_up`] failed.
+#[derive(Debug, thiserror::Error)]
+pub enum WarmUpError {
+    #[error(transparent)]
+    Rejected(#[from] BackendRejection),
+    #[error(transparent)]
+    Runner(#[from] VMRunnerError),
+    #[error("the warm up module failed to compile: {0}")]
+    Compilation(CompilationError),
+    #[error("the warm up call aborted: {0}")]
+    Aborted(FunctionCallError),
+}
+
+/// Whether a warm up of each VM succeeded, by [`crate::concurrency::index`].
+static WARMED_UP: [AtomicBool; 4] = [const { AtomicBool::new(false) }; 4];
+
+/// Compiles and calls a built-in module on `vm_kind` with the limits of
+/// `config`, so that the one-time initialization of the VM, see
+/// [`WarmUpStage`], happens before the first contract call rather than
+/// during it.
+///
+/// Embedders call this at start, on each VM they may run contracts with.
+/// The module calls a host function, so that the path of the imports is
+/// taken as well.  The gas of the call is not charged to anyone and nothing
+/// is cached: the artifact of the module is dropped afterwards.
+pub fn warm_up(vm_kind: VMKind, config: &Config) -> Result<WarmUp, WarmUpError> {
+    let _span = tracing::debug_span!(target: "vm", "warm_up", ?vm_kind).entered();
+    check_prepare_version(vm_kind, config)?;
+    let mut steps = Vec::new();
+    let mut step = |stage, start: Instant| steps.push(WarmUpStep { stage, time: start.elapsed() });
+
+    let start = Instant::now();
+    let mut config = config.clone();
+    config.vm_kind = vm_kind;
+    let runtime = vm_kind.runtime(config).map_err(BackendRejection::Unavailable)?;
+    step(WarmUpStage::Runtime, start);
+
+    let code = ContractCode::new(warm_up_module(), None);
+    let cache = crate::MockCompiledContractCache::default();
+    let start = Instant::now();
+    runtime
+        .precompile(&code, &cache)
+        .map_err(VMRunnerError::from)?
+        .map_err(WarmUpError::Compilation)?;
+    step(WarmUpStage::Compile, start);
+
+    let start = Instant::now();
+    let outcome = runtime.run(
+        &code,
+        "main",
+        &mut MockedExternal::new(),
+        standalone_context(Vec::new()),
+        &RuntimeFeesConfig::free(),
+        &[],
+        Some(&cache),
+    )?;
+    step(WarmUpStage::Call, start);
+    if let Some(err) = outcome.aborted {
+        return Err(WarmUpError::Aborted(err));
+    }
+
+    let first = !WARMED_UP[crate::concurrency::index(vm_kind)].swap(true, Ordering::Relaxed);
+    Ok(WarmUp { vm_kind, first, steps })
+}
+
+/// The module of [`warm_up`]: `main` reads the block index and keeps it in
+/// memory.
+fn warm_up_module() -> Vec<u8> {
+    use wasm_encoder::{
+        CodeSection, EntityType, ExportKind, ExportSection, Function, FunctionSection,
+        ImportSection, Instruction, MemArg, MemorySection, MemoryType, Module, TypeSection,
+        ValType,
+    };
+    let mut types = TypeSection::new();
+    types.function([], [ValType::I64]);
+    types.function([], []);
+    let mut imports = ImportSection::new();
+    imports.import("env", "block_index", EntityType::Function(0));
+    let mut functions = FunctionSection::new();
+    functions.function(1);
+    let mut memories = MemorySection::new();
+    memories.memory(MemoryType { minimum: 1, maximum: None, memory64: false, shared: false });
+    let mut exports = ExportSection::new();
+    exports.export("main", ExportKind::Func, 1);
+    let mut main = Function::new([]);
+    main.instruction(&Instruction::I32Const(0))
+        .instruction(&Instruction::Call(0))
+        .instruction(&Instruction::I64Store(MemArg { offset: 0, align: 3, memory_index: 0 }))
+        .instruction(&Instruction::End);
+    let mut code = CodeSection::new();
+    code.function(&main);
+    let mut module = Module::new();
+    module
+        .section(&types)
+        .section(&imports)
+        .section(&functions)
+        .section(&memories)
+        .section(&exports)
+        .section(&code);
+    module.finish()
+}
+
+/// A context of `alice.near` calling its own contract with `input`, for the
+/// calls the runner makes on its own.
+pub(crate) fn standalone_context(input: Vec<u8>) -> VMContext {
+    VMContext {
+        current_account_id: "alice.near".parse().unwrap(),
+        signer_account_id: "alice.near".parse().unwrap(),
+        signer_account_pk: Vec::new(),
+        predecessor_account_id: "alice.near".parse().unwrap(),
+        input,
+        block_height: 1,
+        block_timestamp: 1_000_000_000,
+        epoch_height: 1,
+        account_balance: 10u128.pow(24),
+        account_locked_balance: 0,
+        storage_usage: 0,
+        attached_deposit: 0,
+        prepaid_gas: 10u64.pow(14),
+        random_seed: vec![0; 32],
+        view_config: None,
+        output_data_receivers: Vec::new(),
+    }
+}
+
+const ALL_VM_KINDS: [VMKind; 4] =
+    [VMKind::NearVm, VMKind::Wasmer2, VMKind::Wasmer0, VMKind::Wasmtime];
+
+/// Why a VM cannot run the contracts of a [`Config`], or has not been chosen
+/// to, see [`BackendSelection`].
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum BackendRejection {
+    /// NearVM only supports [`ContractPrepareVersion::V2`].
+    #[error("NearVM does not support contract preparation {0:?}")]
+    UnsupportedPrepareVersion(ContractPrepareVersion),
+    #[error(transparent)]
+    Unavailable(#[from] BackendUnavailable),
+    /// The VM could run the contracts, but a preferred one has been chosen.
+    #[error("{0:?} has been chosen instead")]
+    NotChosen(VMKind),
+}
+
+/// Checks whether `vm_kind` can run the contracts of `config`, without
+/// making its runtime.
+pub fn check_backend(vm_kind: VMKind, config: &Config) -> Result<(), BackendRejection> {
+    check_prepare_version(vm_kind, config)?;
+    check_available(vm_kind, CodegenTarget::Host)?;
+    Ok(())
+}
+
+fn check_prepare_version(vm_kind: VMKind, config: &Config) -> Result<(), BackendRejection> {
+    let prepare_version = config.limit_config.contract_prepare_version;
+    if vm_kind == VMKind::NearVm && prepare_version != ContractPrepareVersion::V2 {
+        return Err(BackendRejection::UnsupportedPrepareVersion(prepare_version));
+    }
+    Ok(())
+}
+
+/// The VM chosen among candidates to run the contracts of a [`Config`], and
+/// why each of the others has not been.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BackendSelection {
+    /// The first candidate able to run the contracts, if any.
+    pub chosen: Option<VMKind>,
+    /// The other candidates, in order, with the reason they were not chosen.
+    pub rejected: Vec<(VMKind, BackendRejection)>,
+}
+
+impl BackendSelection {
+    /// Chooses the first of the `candidates` able to run the contracts of
+    /// `config`, see [`check_backend`].
+    pub fn new(config: &Config, candidates: &[VMKind]) -> Self {
+        let mut chosen = None;
+        let mut rejected = Vec::new();
+        for &vm_kind in candidates {
+            match (chosen, check_backend(vm_kind, config)) {
+                (None, Ok(())) => chosen = Some(vm_kind),
+                (Some(chosen), Ok(())) => {
+                    rejected.push((vm_kind, BackendRejection::NotChosen(chosen)))
+                }
+                (_, Err(rejection)) => rejected.push((vm_kind, rejection)),
+            }
+        }
+        Self { chosen, rejected }
+    }
+}
+
+/// A call of [`VM::run_many`]: the method to run and its context, holding
+/// its input.
+#[derive(Clone, Debug)]
+pub struct MethodCall {
+    pub method_name: String,
+    pub context: VMContext,
+}
+
 pub trait VM {
     /// Validate and run the specified contract.
     ///
@@ -95,8 +627,112 @@ pub trait VM {
         fees_config: &RuntimeFeesConfig,
         promise_results: &[PromiseResult],
         cache: Option<&dyn CompiledContractCache>,
+    ) -> VMResult {
+        self.run_with_options(
+            code,
+            method_name,
+            ext,
+            context,
+            fees_config,
+            promise_results,
+            cache,
+            &RunOptions::default(),
+        )
+    }
+
+    /// Same as [`Self::run`] with the given `options`.
+    fn run_with_options(
+        &self,
+        code: &ContractCode,
+        method_name: &str,
+        ext: &mut dyn External,
+        context: VMContext,
+        fees_config: &RuntimeFeesConfig,
+        promise_results: &[PromiseResult],
+        cache: Option<&dyn CompiledContractCache>,
+        options: &RunOptions,
     ) -> VMResult;
 
+    /// Runs the call with all the gas it can use, without side effects, and
+    /// reports the gas it needs and the receipts it creates.
+    ///
+    /// The prepaid gas of `context` is ignored and the call is only limited
+    /// by the gas a call can burn, so a single run tells how much gas to
+    /// attach instead of retrying with more until the call succeeds.  The
+    /// call runs on a [`crate::DryRunExternal`] over `ext`, which is only
+    /// read from.  Contracts reading their prepaid gas see [`Gas::MAX`] and
+    /// may attach more gas to their receipts than with the actual one.
+    fn estimate_gas(
+        &self,
+        code: &ContractCode,
+        method_name: &str,
+        ext: &mut dyn External,
+        mut context: VMContext,
+        fees_config: &RuntimeFeesConfig,
+        promise_results: &[PromiseResult],
+        cache: Option<&dyn CompiledContractCache>,
+    ) -> VMResult<GasEstimate> {
+        context.prepaid_gas = Gas::MAX;
+        let mut ext = DryRunExternal::new(ext);
+        let options = RunOptions { record_receipts: true, ..RunOptions::default() };
+        let outcome = self.run_with_options(
+            code,
+            method_name,
+            &mut ext,
+            context,
+            fees_config,
+            promise_results,
+            cache,
+            &options,
+        )?;
+        Ok(GasEstimate {
+            burnt_gas: outcome.burnt_gas,
+            used_gas: outcome.used_gas,
+            receipts: outcome.receipts,
+            receipt_costs: outcome.receipt_costs,
+            aborted: outcome.aborted,
+        })
+    }
+
+    /// Runs the `calls` of the contract one after the other, returning the
+    /// outcome of each.
+    ///
+    /// Meant for view-call servers running many calls of the same contract:
+    /// the contract is loaded once for all the calls, and NearVM runs view
+    /// calls on one instance, restoring its memory and globals after each,
+    /// when the contract lets it.  Other calls start from a freshly
+    /// instantiated contract, reusing the memory of the call before.  Every
+    /// call runs on a [`crate::DryRunExternal`] over `ext`, so calls do not
+    /// see the memory or the writes of the ones before, and the outcome of
+    /// each is the one of running it alone on `ext`.  Stops at the first
+    /// [`VMRunnerError`].
+    fn run_many(
+        &self,
+        code: &ContractCode,
+        calls: &[MethodCall],
+        ext: &mut dyn External,
+        fees_config: &RuntimeFeesConfig,
+        promise_results: &[PromiseResult],
+        cache: Option<&dyn CompiledContractCache>,
+        options: &RunOptions,
+    ) -> VMResult<Vec<VMOutcome>> {
+        calls
+            .iter()
+            .map(|call| {
+                self.run_with_options(
+                    code,
+                    &call.method_name,
+                    &mut DryRunExternal::new(&mut *ext),
+                    call.context.clone(),
+                    fees_config,
+                    promise_results,
+                    cache,
+                    options,
+                )
+            })
+            .collect()
+    }
+
     /// Precompile a WASM contract to a VM specific format and store the result
     /// into the `cache`.
     ///
@@ -110,27 +746,467 @@ pub trait VM {
     ) -> Result<Result<ContractPrecompilatonResult, CompilationError>, CacheError>;
 }
 
+/// A [`VM`] could not be created on this host.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+#[error("the {vm_kind:?} runtime is unavailable: {reason}")]
+pub struct BackendUnavailable {
+    pub vm_kind: VMKind,
+    pub reason: String,
+    /// CPU features required by the runtime which the host does not support.
+    pub required_cpu_features: Vec<&'static str>,
+}
+
+/// CPU features used by the code the singlepass compilers generate for
+/// [`CodegenTarget::Baseline`], which is x86-64-v2 with AVX.
+///
+/// Only the hosts loading such code need them all, the compilers themselves
+/// only require AVX, see [`VMKindExt::required_cpu_features`].
+pub const BASELINE_CPU_FEATURES: &[&str] =
+    &["sse2", "sse3", "ssse3", "sse4.1", "sse4.2", "popcnt", "avx"];
+
+/// CPU features the VMs generate code for.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum CodegenTarget {
+    /// Use every feature of the host CPU.
+    ///
+    /// Builds with the `no_cpu_compatibility_checks` feature use the
+    /// [`BASELINE_CPU_FEATURES`] instead, but still share the cache keys
+    /// with the other hosts.
+    #[default]
+    Host,
+    /// Only use the [`BASELINE_CPU_FEATURES`] regardless of the host CPU.
+    ///
+    /// The resulting artifacts load on every host able to run the VM, so
+    /// caches can be shared between heterogeneous machines.  They are
+    /// cached under different keys than the artifacts of [`Self::Host`].
+    Baseline,
+}
+
+/// How much effort the compiler spends on optimizing the generated code.
+///
+/// Deploy-time compilation wants the contract to be ready quickly, while
+/// background recompilation can afford to produce faster code, see
+/// [`crate::Reoptimizer`].  Only Wasmtime's Cranelift compiler has
+/// optimization levels; the singlepass compilers of the other VMs always
+/// generate the same code.
+///
+/// Gas is charged by the instrumentation, so artifacts of any level behave
+/// the same.  The artifacts of Wasmtime still get a cache key per level,
+/// and a VM at [`OptLevel::Optimized`] loads the artifact compiled at
+/// [`OptLevel::Fast`] until it has its own.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize)]
+pub enum OptLevel {
+    /// Generate code as quickly as possible.
+    Fast,
+    /// Spend more time compiling to generate faster code.
+    #[default]
+    Optimized,
+}
+
+/// How a [`VM`] compiles contracts.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct CompileOptions {
+    pub codegen: CodegenTarget,
+    pub opt_level: OptLevel,
+}
+
+/// Settings of the host a [`VM`] runs the calls on, see
+/// [`VMKindExt::runtime_on_host`].
+///
+/// Unlike the [`Config`], these are no parameters of the protocol: they only
+/// change how fast and how safely the calls run, never their outcomes, so
+/// nodes choose them on their own and they are part of neither the cache
+/// keys nor the fingerprints of the configs.
+#[derive(Clone, Debug, Default, Hash, PartialEq, Eq)]
+pub struct HostSettings {
+    /// Put the linear memories and the compiled code of NearVM calls in huge
+    /// pages, on x86_64 Linux.
+    pub huge_pages: Option<HugePages>,
+    /// Hardening of the memory of the VM, see [`crate::MemoryHardening`].
+    pub hardening: crate::MemoryHardening,
+    /// Linear memories NearVM keeps between calls to reuse them, see
+    /// [`crate::NearVmMemoryPool`], none with 0.  The NearVM runtimes with the
+    /// same size share their pool.
+    pub memory_pool_size: usize,
+}
+
+/// How to get huge pages from the kernel, see [`HostSettings::huge_pages`].
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+pub enum HugePages {
+    /// Transparent huge pages, which the kernel assembles when it can.
+    ///
+    /// Needs `/sys/kernel/mm/transparent_hugepage/enabled` set to `madvise`
+    /// or `always`, and `shmem_enabled` set to `advise` or `always` for the
+    /// code.
+    Transparent,
+    /// Huge pages reserved by the operator in `/proc/sys/vm/nr_hugepages`.
+    ///
+    /// Memories are put in normal pages when no reserved page is left, and
+    /// the code always gets transparent huge pages.
+    Explicit,
+}
+
+/// A compiler generating the code of contracts.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize, strum::Display,
+)]
+#[strum(serialize_all = "snake_case")]
+pub enum Compiler {
+    /// The singlepass compiler of NearVM and the Wasmer VMs.
+    Singlepass,
+    /// The Cranelift compiler of Wasmtime.
+    Cranelift,
+}
+
+/// A pass a contract goes through to be compiled, see
+/// [`CompilationInfo::passes`].
+///
+/// Displayed as the snake case name of the pass, `{compiler}_codegen` for
+/// the generation of the code.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize)]
+pub enum CompilationPass {
+    /// Validation of the contract against the limits of the config.
+    Validate,
+    /// Instrumentation with the gas metering.
+    GasMetering,
+    /// Instrumentation with the stack limiter.
+    StackLimit,
+    /// Generation of the machine code by the compiler.
+    Codegen(Compiler),
+}
+
+impl std::fmt::Display for CompilationPass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Validate => f.write_str("validate"),
+            Self::GasMetering => f.write_str("gas_metering"),
+            Self::StackLimit => f.write_str("stack_limit"),
+            Self::Codegen(compiler) => write!(f, "{compiler}_codegen"),
+        }
+    }
+}
+
+/// How a contract has been compiled, as reported by [`VM::precompile`] and
+/// stored alongside the artifact in caches supporting it, see
+/// [`CompiledContractCache::put_compilation_info`].
+#[derive(Clone, Debug, PartialEq, Eq, BorshSerialize, BorshDeserialize)]
+pub struct CompilationInfo {
+    /// The compiler generating the code.
+    pub compiler: Compiler,
+    /// The optimization level the code has been generated with.  Singlepass
+    /// compilers do not optimize and always report [`OptLevel::Fast`].
+    pub opt_level: OptLevel,
+    /// The passes the contract went through, in order.
+    pub passes: Vec<CompilationPass>,
+    /// Time spent preparing and compiling the contract, in nanoseconds.
+    pub compile_time_ns: u64,
+}
+
+impl CompilationInfo {
+    /// Contract preparation passes shared by all the VMs, see
+    /// [`crate::prepare::prepare_contract`].
+    const PREPARE_PASSES: [CompilationPass; 3] =
+        [CompilationPass::Validate, CompilationPass::GasMetering, CompilationPass::StackLimit];
+
+    pub(crate) fn singlepass(compile_time: Duration) -> Self {
+        Self::new(Compiler::Singlepass, OptLevel::Fast, compile_time)
+    }
+
+    pub(crate) fn new(compiler: Compiler, opt_level: OptLevel, compile_time: Duration) -> Self {
+        let passes = Self::PREPARE_PASSES
+            .into_iter()
+            .chain(std::iter::once(CompilationPass::Codegen(compiler)))
+            .collect();
+        Self {
+            compiler,
+            opt_level,
+            passes,
+            compile_time_ns: compile_time.as_nanos().try_into().unwrap_or(u64::MAX),
+        }
+    }
+
+    pub fn compile_time(&self) -> Duration {
+        Duration::from_nanos(self.compile_time_ns)
+    }
+}
+
+/// Returns the CPU features relevant to the runtimes which the host supports.
+pub fn host_cpu_features() -> Vec<&'static str> {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    let detected = [
+        ("sse2", is_x86_feature_detected!("sse2")),
+        ("sse3", is_x86_feature_detected!("sse3")),
+        ("ssse3", is_x86_feature_detected!("ssse3")),
+        ("sse4.1", is_x86_feature_detected!("sse4.1")),
+        ("sse4.2", is_x86_feature_detected!("sse4.2")),
+        ("popcnt", is_x86_feature_detected!("popcnt")),
+        ("avx", is_x86_feature_detected!("avx")),
+    ];
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+    let detected: [(&str, bool); 0] = [];
+    detected.into_iter().filter_map(|(feature, supported)| supported.then_some(feature)).collect()
+}
+
+/// Fails if a host with the CPU features `host` cannot run the code `vm_kind`
+/// generates for `codegen`.
+pub(crate) fn check_cpu_features(
+    vm_kind: VMKind,
+    codegen: CodegenTarget,
+    host: &[&str],
+) -> Result<(), BackendUnavailable> {
+    let required = match codegen {
+        CodegenTarget::Host => vm_kind.required_cpu_features(),
+        CodegenTarget::Baseline => BASELINE_CPU_FEATURES,
+    };
+    let missing: Vec<_> =
+        required.iter().copied().filter(|feature| !host.contains(feature)).collect();
+    if missing.is_empty() {
+        return Ok(());
+    }
+    let reason = format!("host CPU does not support {}", missing.join(", "));
+    Err(BackendUnavailable { vm_kind, reason, required_cpu_features: missing })
+}
+
+/// Fails if a runtime of `vm_kind` generating code for `codegen` cannot be
+/// made, see [`VMKindExt::runtime`].
+fn check_available(vm_kind: VMKind, codegen: CodegenTarget) -> Result<(), BackendUnavailable> {
+    let unavailable = |reason: &str| BackendUnavailable {
+        vm_kind,
+        reason: reason.to_string(),
+        required_cpu_features: Vec::new(),
+    };
+    let enabled = match vm_kind {
+        VMKind::Wasmer0 => cfg!(all(feature = "wasmer0_vm", target_arch = "x86_64")),
+        VMKind::Wasmtime => cfg!(feature = "wasmtime_vm"),
+        VMKind::Wasmer2 => cfg!(all(feature = "wasmer2_vm", target_arch = "x86_64")),
+        VMKind::NearVm => cfg!(all(feature = "unc_vm", target_arch = "x86_64")),
+    };
+    if !cfg!(feature = "no_cpu_compatibility_checks") {
+        check_cpu_features(vm_kind, codegen, &host_cpu_features())?;
+    }
+    if codegen == CodegenTarget::Baseline && matches!(vm_kind, VMKind::Wasmer0 | VMKind::Wasmtime) {
+        return Err(unavailable("the runtime cannot generate code for the baseline CPU"));
+    }
+    if !enabled {
+        return Err(unavailable("the runtime has not been enabled at compile time"));
+    }
+    Ok(())
+}
+
 pub trait VMKindExt {
     /// Make a [`VM`] for this [`VMKind`].
     ///
+    /// Fails if the runtime has not been enabled at compile time or if the
+    /// host lacks some of the [`VMKindExt::required_cpu_features`].
+    ///
     /// This is not intended to be used by code other than internal tools like
     /// the estimator.
-    fn runtime(&self, config: Config) -> Option<Box<dyn VM>>;
+    fn runtime(&self, config: Config) -> Result<Box<dyn VM>, BackendUnavailable> {
+        self.runtime_for_codegen(config, CodegenTarget::Host)
+    }
+
+    /// Same as [`VMKindExt::runtime`] but generates code for `codegen`.
+    ///
+    /// Only the singlepass runtimes of Wasmer2 and NearVM support
+    /// [`CodegenTarget::Baseline`].
+    fn runtime_for_codegen(
+        &self,
+        config: Config,
+        codegen: CodegenTarget,
+    ) -> Result<Box<dyn VM>, BackendUnavailable> {
+        self.runtime_with_options(config, CompileOptions { codegen, ..CompileOptions::default() })
+    }
+
+    /// Same as [`VMKindExt::runtime`] but compiles contracts with `options`.
+    fn runtime_with_options(
+        &self,
+        config: Config,
+        options: CompileOptions,
+    ) -> Result<Box<dyn VM>, BackendUnavailable> {
+        self.runtime_on_host(config, options, HostSettings::default())
+    }
+
+    /// Same as [`VMKindExt::runtime_with_options`] but runs the calls with
+    /// the `host` settings.
+    ///
+    /// Also fails if the runtime does not support the hardening of `host` on
+    /// this host.
+    fn runtime_on_host(
+        &self,
+        config: Config,
+        options: CompileOptions,
+        host: HostSettings,
+    ) -> Result<Box<dyn VM>, BackendUnavailable>;
+
+    /// CPU features the compiler of this runtime requires, generating code
+    /// for the features of the host.
+    fn required_cpu_features(&self) -> &'static [&'static str];
 }
 
 impl VMKindExt for VMKind {
-    fn runtime(&self, config: Config) -> Option<Box<dyn VM>> {
-        match self {
+    fn runtime_on_host(
+        &self,
+        config: Config,
+        options: CompileOptions,
+        host: HostSettings,
+    ) -> Result<Box<dyn VM>, BackendUnavailable> {
+        let CompileOptions { codegen, opt_level } = options;
+        check_available(*self, codegen)?;
+        crate::hardening::check_memory_hardening(*self, &host.hardening).map_err(|err| {
+            BackendUnavailable {
+                vm_kind: *self,
+                reason: err.to_string(),
+                required_cpu_features: Vec::new(),
+            }
+        })?;
+        let runtime: Box<dyn VM> = match self {
             #[cfg(all(feature = "wasmer0_vm", target_arch = "x86_64"))]
-            Self::Wasmer0 => Some(Box::new(crate::wasmer_runner::Wasmer0VM::new(config))),
+            Self::Wasmer0 => Box::new(crate::wasmer_runner::Wasmer0VM::new(config)),
             #[cfg(feature = "wasmtime_vm")]
-            Self::Wasmtime => Some(Box::new(crate::wasmtime_runner::WasmtimeVM::new(config))),
+            Self::Wasmtime => {
+                Box::new(crate::wasmtime_runner::WasmtimeVM::new(config, opt_level, host))
+            }
             #[cfg(all(feature = "wasmer2_vm", target_arch = "x86_64"))]
-            Self::Wasmer2 => Some(Box::new(crate::wasmer2_runner::Wasmer2VM::new(config))),
+            Self::Wasmer2 => {
+                Box::new(crate::wasmer2_runner::Wasmer2VM::new_with_codegen(config, codegen, host))
+            }
             #[cfg(all(feature = "unc_vm", target_arch = "x86_64"))]
-            Self::NearVm => Some(Box::new(crate::unc_vm_runner::NearVM::new(config))),
+            Self::NearVm => {
+                Box::new(crate::unc_vm_runner::NearVM::new_with_codegen(config, codegen, host))
+            }
             #[allow(unreachable_patterns)] // reachable when some of the VMs are disabled.
-            _ => None,
+            _ => {
+                let _ = (config, codegen, opt_level, host);
+                return Err(BackendUnavailable {
+                    vm_kind: *self,
+                    reason: "the runtime has not been enabled at compile time".to_string(),
+                    required_cpu_features: Vec::new(),
+                });
+            }
+        };
+        Ok(runtime)
+    }
+
+    fn required_cpu_features(&self) -> &'static [&'static str] {
+        match self {
+            // The singlepass compilers emit AVX for floating point operations
+            // and refuse targets without it.
+            Self::Wasmer0 | Self::Wasmer2 | Self::NearVm => &["avx"],
+            Self::Wasmtime => &[],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backend_selection() {
+        let mut config = crate::tests::test_vm_config();
+        config.limit_config.contract_prepare_version = ContractPrepareVersion::V1;
+        let selection = BackendSelection::new(&config, &[VMKind::NearVm, VMKind::Wasmtime]);
+        assert_eq!(
+            selection.rejected[0],
+            (
+                VMKind::NearVm,
+                BackendRejection::UnsupportedPrepareVersion(ContractPrepareVersion::V1)
+            )
+        );
+        if cfg!(feature = "wasmtime_vm") {
+            assert_eq!(selection.chosen, Some(VMKind::Wasmtime));
+            assert_eq!(selection.rejected.len(), 1);
+        }
+
+        config.limit_config.contract_prepare_version = ContractPrepareVersion::V2;
+        let selection = BackendSelection::new(&config, &[VMKind::Wasmtime, VMKind::Wasmtime]);
+        if cfg!(feature = "wasmtime_vm") {
+            assert_eq!(
+                selection.rejected,
+                [(VMKind::Wasmtime, BackendRejection::NotChosen(VMKind::Wasmtime))]
+            );
+        }
+    }
+
+    #[test]
+    fn test_run_with_diagnostics() {
+        let mut config = crate::tests::test_vm_config();
+        config.vm_kind = VMKind::NearVm;
+        config.limit_config.contract_prepare_version = ContractPrepareVersion::V1;
+        let code = ContractCode::new(warm_up_module(), None);
+        let result = run_with_diagnostics(
+            &code,
+            "main",
+            &mut MockedExternal::new(),
+            standalone_context(Vec::new()),
+            &config,
+            &RuntimeFeesConfig::free(),
+            &[],
+            None,
+        );
+        match result {
+            Err(RunWithDiagnosticsError::Rejected(selection)) => {
+                assert_ne!(selection.chosen, Some(VMKind::NearVm));
+                assert_eq!(
+                    selection.rejected[0],
+                    (
+                        VMKind::NearVm,
+                        BackendRejection::UnsupportedPrepareVersion(ContractPrepareVersion::V1)
+                    )
+                );
+            }
+            Ok(_) => panic!("ran on another VM than NearVM"),
+            Err(err) => panic!("{err}"),
+        }
+
+        if cfg!(feature = "wasmtime_vm") {
+            config.vm_kind = VMKind::Wasmtime;
+            let (outcome, diagnostics) = run_with_diagnostics(
+                &code,
+                "main",
+                &mut MockedExternal::new(),
+                standalone_context(Vec::new()),
+                &config,
+                &RuntimeFeesConfig::free(),
+                &[],
+                None,
+            )
+            .unwrap();
+            assert_eq!(outcome.aborted, None);
+            assert_eq!(diagnostics.backend.chosen, Some(VMKind::Wasmtime));
+        }
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let config = crate::tests::test_vm_config();
+        crate::tests::with_vm_variants(&config, |vm_kind| {
+            let warm_up = warm_up(vm_kind, &config).unwrap();
+            let stages: Vec<_> = warm_up.steps.iter().map(|step| step.stage).collect();
+            assert_eq!(stages, [WarmUpStage::Runtime, WarmUpStage::Compile, WarmUpStage::Call]);
+            assert_eq!(warm_up.vm_kind, vm_kind);
+            assert!(!super::warm_up(vm_kind, &config).unwrap().first);
+        });
+        let mut config = config;
+        config.limit_config.contract_prepare_version = ContractPrepareVersion::V1;
+        assert!(matches!(
+            warm_up(VMKind::NearVm, &config),
+            Err(WarmUpError::Rejected(BackendRejection::UnsupportedPrepareVersion(_)))
+        ));
+    }
+
+    #[test]
+    fn test_backend_unavailable() {
+        let host = host_cpu_features();
+        for vm_kind in [VMKind::Wasmer0, VMKind::Wasmtime, VMKind::Wasmer2, VMKind::NearVm] {
+            let supported = vm_kind.required_cpu_features().iter().all(|f| host.contains(f));
+            match vm_kind.runtime(crate::tests::test_vm_config()) {
+                Ok(_) => assert!(supported || cfg!(feature = "no_cpu_compatibility_checks")),
+                Err(err) => {
+                    assert_eq!(err.vm_kind, vm_kind);
+                    assert!(err.required_cpu_features.iter().all(|f| !host.contains(f)));
+                }
+            }
         }
     }
 }
@@ -0,0 +1,134 @@
+//! Limits relaxed far beyond those of the protocol, for sandbox networks.
+//!
+//! Developers running a local chain want to try the worst-case contracts
+//! they can think of, which the limits of mainnet reject.  [`LimitOverrides`]
+//! raises specific limits of a [`Config`], and [`LimitOverrides::sandbox`] is
+//! a preset raising all of them, so that sandboxes need not patch
+//! `unc-parameters`.
+//!
+//! The overrides are not part of the protocol: two nodes only agree on the
+//! outcomes of calls if they run the same overrides, and nodes of the other
+//! networks never do.  This module is only compiled in with the `sandbox`
+//! feature, which must never be enabled for nodes of mainnet or testnet.
+
+use crate::logic::Config;
+use unc_primitives_core::types::Gas;
+
+/// Pages of the largest wasm memory, 4 GiB.
+const MAX_WASM_MEMORY_PAGES: u32 = 1 << 16;
+
+/// Limits of a config to raise, each left as it is when `None`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct LimitOverrides {
+    /// `max_contract_size`, and `max_transaction_size` if lower, so that
+    /// the contracts can be deployed.
+    pub max_contract_size: Option<u64>,
+    pub max_functions_number_per_contract: Option<u64>,
+    pub max_locals_per_contract: Option<u64>,
+    /// `max_gas_burnt`, and `max_total_prepaid_gas` if lower, so that calls
+    /// can be given the gas.
+    pub max_gas_burnt: Option<Gas>,
+    /// `max_memory_pages`, at most the 65536 pages of a wasm memory.
+    pub max_memory_pages: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum LimitOverrideError {
+    #[error("{0} memory pages are more than the {MAX_WASM_MEMORY_PAGES} of a wasm memory")]
+    TooManyMemoryPages(u32),
+}
+
+impl LimitOverrides {
+    /// The preset of sandboxes: contracts of 64 MiB with ten times the
+    /// functions and locals of mainnet, calls burning a thousand times its
+    /// gas and memories of 1 GiB.
+    pub fn sandbox() -> Self {
+        Self {
+            max_contract_size: Some(64 * 1024 * 1024),
+            max_functions_number_per_contract: Some(100_000),
+            max_locals_per_contract: Some(10_000_000),
+            max_gas_burnt: Some(300 * 10u64.pow(15)),
+            max_memory_pages: Some(16 * 1024),
+        }
+    }
+
+    /// Raises the limits of `config` to the overrides.
+    ///
+    /// Limits already above their override are kept: these are for relaxing
+    /// limits, not for testing tighter ones, so that the preset applies to
+    /// the configs of every protocol version.
+    pub fn apply(&self, config: &mut Config) -> Result<(), LimitOverrideError> {
+        if let Some(pages) = self.max_memory_pages.filter(|pages| *pages > MAX_WASM_MEMORY_PAGES) {
+            return Err(LimitOverrideError::TooManyMemoryPages(pages));
+        }
+        let limits = &mut config.limit_config;
+        if let Some(size) = self.max_contract_size {
+            limits.max_contract_size = limits.max_contract_size.max(size);
+            limits.max_transaction_size = limits.max_transaction_size.max(size);
+        }
+        // A missing limit is no limit at all.
+        if let (Some(max), Some(functions)) =
+            (&mut limits.max_functions_number_per_contract, self.max_functions_number_per_contract)
+        {
+            *max = (*max).max(functions);
+        }
+        if let (Some(max), Some(locals)) =
+            (&mut limits.max_locals_per_contract, self.max_locals_per_contract)
+        {
+            *max = (*max).max(locals);
+        }
+        if let Some(gas) = self.max_gas_burnt {
+            limits.max_gas_burnt = limits.max_gas_burnt.max(gas);
+            limits.max_total_prepaid_gas = limits.max_total_prepaid_gas.max(gas);
+        }
+        if let Some(pages) = self.max_memory_pages {
+            limits.max_memory_pages = limits.max_memory_pages.max(pages);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_vm_config;
+
+    #[test]
+    fn test_sandbox_preset() {
+        let mut config = test_vm_config();
+        LimitOverrides::sandbox().apply(&mut config).unwrap();
+        let limits = &config.limit_config;
+        assert_eq!(limits.max_contract_size, 64 * 1024 * 1024);
+        assert!(limits.max_transaction_size >= limits.max_contract_size);
+        assert_eq!(limits.max_functions_number_per_contract, Some(100_000));
+        assert_eq!(limits.max_gas_burnt, 300 * 10u64.pow(15));
+        assert!(limits.max_total_prepaid_gas >= limits.max_gas_burnt);
+        assert_eq!(limits.max_memory_pages, 16 * 1024);
+    }
+
+    #[test]
+    fn test_overrides() {
+        let original = test_vm_config();
+        let mut config = original.clone();
+        let overrides = LimitOverrides {
+            max_memory_pages: Some(4096),
+            max_gas_burnt: Some(1),
+            ..LimitOverrides::default()
+        };
+        overrides.apply(&mut config).unwrap();
+        assert_eq!(config.limit_config.max_memory_pages, 4096);
+        // Limits are never tightened.
+        assert_eq!(config.limit_config.max_gas_burnt, original.limit_config.max_gas_burnt);
+        assert_eq!(config.limit_config.max_contract_size, original.limit_config.max_contract_size);
+
+        let mut unbounded = original.clone();
+        unbounded.limit_config.max_functions_number_per_contract = None;
+        LimitOverrides::sandbox().apply(&mut unbounded).unwrap();
+        assert_eq!(unbounded.limit_config.max_functions_number_per_contract, None);
+
+        let too_many =
+            LimitOverrides { max_memory_pages: Some(65537), ..LimitOverrides::default() };
+        assert_eq!(too_many.apply(&mut config), Err(LimitOverrideError::TooManyMemoryPages(65537)));
+        assert_eq!(config.limit_config.max_memory_pages, 4096);
+    }
+}
@@ -0,0 +1,1402 @@
+//! Shadow execution: running calls on a second VM as a canary.
+//!
+//! Before the protocol switches to a new VM, nodes can run every call on the
+//! current VM as usual and additionally on the new one, reporting the calls
+//! where the two disagree.  The outcome of the primary VM is the only one
+//! that matters; the secondary VM never touches the real state.
+//!
+//! [`run_recorded`] runs the primary VM on a [`RecordingExternal`] which
+//! writes down every call made to the [`External`] and its result.  The
+//! returned [`ShadowCall`] owns everything needed to run the secondary VM, so
+//! it can be sent to a background thread.  [`ShadowCall::run`] then replays
+//! the recorded results to the secondary VM: as long as it makes the same
+//! calls as the primary one in the same order it sees the same state, and the
+//! first call that differs is reported as a divergence, as are differences
+//! in the outcomes.
+//!
+//! A divergence often shows up long after the VMs started to disagree.
+//! [`ShadowCall::bisect`] replays the call on both VMs recording their gas
+//! and registers at every host call, and reports the first host call at
+//! which they differ.
+//!
+//! The trace can also be saved, e.g. when a node sees a call diverge on
+//! mainnet, and [`replay`] then re-executes the call offline against the
+//! trace instead of a live [`External`].  With
+//! [`RunOptions::record_host_calls`] the outcome lists every host function
+//! the contract called with its arguments, result and gas, so executions can
+//! be compared step by step.
+
+use crate::fingerprint::ConfigFingerprint;
+use crate::logic::errors::{AnyError, HostError, InconsistentStateError, VMLogicError};
+use crate::logic::types::{PromiseResult, ReceiptIndex, ReturnData};
+use crate::logic::{External, HostCallCheckpoint, TrieNodesCount, VMContext, ValuePtr};
+use crate::logic::{CompiledContractCache, Config};
+use crate::runner::{check_backend, BackendRejection, RunOptions, VMResult};
+use crate::ContractCode;
+use serde_with::base64::Base64;
+use serde_with::serde_as;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
+use unc_crypto::PublicKey;
+use unc_parameters::vm::{StorageGetMode, VMKind};
+use unc_parameters::RuntimeFeesConfig;
+use unc_primitives_core::hash::CryptoHash;
+use unc_primitives_core::serialize::dec_format;
+use unc_primitives_core::types::{AccountId, Balance, Gas, GasWeight, Nonce, Power};
+
+type Result<T, E = VMLogicError> = std::result::Result<T, E>;
+
+/// A call made to the [`External`].
+#[serde_as]
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum ExternalCall {
+    StorageSet {
+        #[serde_as(as = "Base64")]
+        key: Vec<u8>,
+        #[serde_as(as = "Base64")]
+        value: Vec<u8>,
+    },
+    StorageGet {
+        #[serde_as(as = "Base64")]
+        key: Vec<u8>,
+        mode: StorageGetMode,
+    },
+    StorageRemove {
+        #[serde_as(as = "Base64")]
+        key: Vec<u8>,
+    },
+    StorageRemoveSubtree {
+        #[serde_as(as = "Base64")]
+        prefix: Vec<u8>,
+    },
+    StorageHasKey {
+        #[serde_as(as = "Base64")]
+        key: Vec<u8>,
+        mode: StorageGetMode,
+    },
+    GenerateDataId,
+    GetTrieNodesCount,
+    ValidatorFrozen {
+        account_id: AccountId,
+    },
+    ValidatorPower {
+        account_id: AccountId,
+    },
+    ValidatorTotalFrozen,
+    ValidatorTotalPower,
+    AccountCodeHash {
+        account_id: AccountId,
+    },
+    CreateReceipt {
+        receipt_indices: Vec<ReceiptIndex>,
+        receiver_id: AccountId,
+    },
+    CreateAccount {
+        receipt_index: ReceiptIndex,
+    },
+    DeployContract {
+        receipt_index: ReceiptIndex,
+        code_hash: CryptoHash,
+    },
+    FunctionCall {
+        receipt_index: ReceiptIndex,
+        #[serde_as(as = "Base64")]
+        method_name: Vec<u8>,
+        #[serde_as(as = "Base64")]
+        args: Vec<u8>,
+        #[serde(with = "dec_format")]
+        attached_deposit: Balance,
+        prepaid_gas: Gas,
+        gas_weight: u64,
+    },
+    Transfer {
+        receipt_index: ReceiptIndex,
+        #[serde(with = "dec_format")]
+        deposit: Balance,
+    },
+    Stake {
+        receipt_index: ReceiptIndex,
+        #[serde(with = "dec_format")]
+        stake: Balance,
+        public_key: PublicKey,
+    },
+    AddFullAccessKey {
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+    },
+    AddFunctionCallKey {
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+        #[serde(with = "dec_format")]
+        allowance: Option<Balance>,
+        receiver_id: AccountId,
+        #[serde_as(as = "Vec<Base64>")]
+        method_names: Vec<Vec<u8>>,
+    },
+    DeleteKey {
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+    },
+    DeleteAccount {
+        receipt_index: ReceiptIndex,
+        beneficiary_id: AccountId,
+    },
+    ScratchGet,
+    AppendScratch {
+        receipt_index: ReceiptIndex,
+        #[serde_as(as = "Base64")]
+        data: Vec<u8>,
+    },
+    DeployGlobalContract {
+        receipt_index: ReceiptIndex,
+        code_hash: CryptoHash,
+    },
+}
+
+/// The result of an [`ExternalCall`] as seen by the primary VM.
+#[serde_as]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+enum Response {
+    Unit,
+    /// A `storage_get` value: its length and, if the primary VM read it, its
+    /// bytes.
+    Value(#[serde_as(as = "Option<(_, Option<Base64>)>")] Option<(u32, Option<Vec<u8>>)>),
+    Bool(bool),
+    Hash(CryptoHash),
+    TrieNodesCount {
+        db_reads: u64,
+        mem_reads: u64,
+    },
+    Amount(#[serde(with = "dec_format")] Option<Balance>),
+    ReceiptIndex(ReceiptIndex),
+    Scratch(#[serde_as(as = "Option<Base64>")] Option<Vec<u8>>),
+    CodeHash(Option<CryptoHash>),
+    Error(RecordedError),
+}
+
+/// Errors of the [`External`], which are not `Clone`.
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+enum RecordedError {
+    Host(HostError),
+    InconsistentState(InconsistentStateError),
+    External(String),
+}
+
+impl From<&VMLogicError> for RecordedError {
+    fn from(err: &VMLogicError) -> Self {
+        match err {
+            VMLogicError::HostError(err) => Self::Host(err.clone()),
+            VMLogicError::InconsistentStateError(err) => Self::InconsistentState(err.clone()),
+            VMLogicError::ExternalError(err) => Self::External(format!("{err:?}")),
+        }
+    }
+}
+
+impl From<RecordedError> for VMLogicError {
+    fn from(err: RecordedError) -> Self {
+        match err {
+            RecordedError::Host(err) => Self::HostError(err),
+            RecordedError::InconsistentState(err) => Self::InconsistentStateError(err),
+            RecordedError::External(err) => Self::ExternalError(AnyError::new(ShadowError(err))),
+        }
+    }
+}
+
+/// The error returned to the secondary VM by the replaying [`External`]: the
+/// error of the primary VM's external, or a divergence.
+#[derive(Debug, PartialEq, Eq)]
+struct ShadowError(String);
+
+fn diverged() -> VMLogicError {
+    VMLogicError::ExternalError(AnyError::new(ShadowError("diverged from the primary".into())))
+}
+
+/// [`External`] calls and results of a call, in order.
+///
+/// The serde format is meant for saving traces to replay them later with
+/// [`replay`], by the same version of the crate.
+#[derive(Clone, Debug, Default, serde::Serialize, serde::Deserialize)]
+pub struct ExternalTrace {
+    entries: Vec<(ExternalCall, Response)>,
+}
+
+impl ExternalTrace {
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn calls(&self) -> impl Iterator<Item = &ExternalCall> {
+        self.entries.iter().map(|(call, _)| call)
+    }
+}
+
+/// [`External`] which records the calls going through it in an
+/// [`ExternalTrace`].
+pub struct RecordingExternal<'a> {
+    inner: &'a mut dyn External,
+    // Some methods of `External` take `&self`.
+    trace: RefCell<ExternalTrace>,
+}
+
+impl<'a> RecordingExternal<'a> {
+    pub fn new(inner: &'a mut dyn External) -> Self {
+        Self { inner, trace: RefCell::default() }
+    }
+
+    pub fn into_trace(self) -> ExternalTrace {
+        self.trace.into_inner()
+    }
+
+    fn record<T>(
+        &self,
+        call: ExternalCall,
+        result: Result<T>,
+        response: impl FnOnce(&T) -> Response,
+    ) -> Result<T> {
+        let response = match &result {
+            Ok(value) => response(value),
+            Err(err) => Response::Error(err.into()),
+        };
+        self.trace.borrow_mut().entries.push((call, response));
+        result
+    }
+
+    fn record_unit(&self, call: ExternalCall, result: Result<()>) -> Result<()> {
+        self.record(call, result, |()| Response::Unit)
+    }
+}
+
+/// Records the bytes of a `storage_get` value once the VM reads them.
+struct RecordingValuePtr<'a> {
+    inner: Box<dyn ValuePtr + 'a>,
+    trace: &'a RefCell<ExternalTrace>,
+    entry: usize,
+}
+
+impl ValuePtr for RecordingValuePtr<'_> {
+    fn len(&self) -> u32 {
+        self.inner.len()
+    }
+
+    fn deref(&self) -> Result<Vec<u8>> {
+        let value = self.inner.deref()?;
+        if let Response::Value(Some((_, bytes))) =
+            &mut self.trace.borrow_mut().entries[self.entry].1
+        {
+            *bytes = Some(value.clone());
+        }
+        Ok(value)
+    }
+}
+
+impl External for RecordingExternal<'_> {
+    fn storage_set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let call = ExternalCall::StorageSet { key: key.to_vec(), value: value.to_vec() };
+        let result = self.inner.storage_set(key, value);
+        self.record_unit(call, result)
+    }
+
+    fn storage_get<'b>(
+        &'b self,
+        key: &[u8],
+        mode: StorageGetMode,
+    ) -> Result<Option<Box<dyn ValuePtr + 'b>>> {
+        let call = ExternalCall::StorageGet { key: key.to_vec(), mode };
+        let result = self.inner.storage_get(key, mode);
+        let result = self.record(call, result, |ptr| {
+            Response::Value(ptr.as_ref().map(|ptr| (ptr.len(), None)))
+        })?;
+        let entry = self.trace.borrow().entries.len() - 1;
+        Ok(result.map(|inner| {
+            Box::new(RecordingValuePtr { inner, trace: &self.trace, entry }) as Box<dyn ValuePtr>
+        }))
+    }
+
+    fn storage_remove(&mut self, key: &[u8]) -> Result<()> {
+        let result = self.inner.storage_remove(key);
+        self.record_unit(ExternalCall::StorageRemove { key: key.to_vec() }, result)
+    }
+
+    fn storage_remove_subtree(&mut self, prefix: &[u8]) -> Result<()> {
+        let result = self.inner.storage_remove_subtree(prefix);
+        self.record_unit(ExternalCall::StorageRemoveSubtree { prefix: prefix.to_vec() }, result)
+    }
+
+    fn storage_has_key(&mut self, key: &[u8], mode: StorageGetMode) -> Result<bool> {
+        let result = self.inner.storage_has_key(key, mode);
+        let call = ExternalCall::StorageHasKey { key: key.to_vec(), mode };
+        self.record(call, result, |&has| Response::Bool(has))
+    }
+
+    fn storage_proof(&self, key: &[u8]) -> Result<Vec<Vec<u8>>> {
+        self.inner.storage_proof(key)
+    }
+
+    fn state_changed(&self) -> bool {
+        self.inner.state_changed()
+    }
+
+    fn generate_data_id(&mut self) -> CryptoHash {
+        let data_id = self.inner.generate_data_id();
+        let result =
+            self.record(ExternalCall::GenerateDataId, Ok(data_id), |&id| Response::Hash(id));
+        result.unwrap()
+    }
+
+    fn get_trie_nodes_count(&self) -> TrieNodesCount {
+        let count = self.inner.get_trie_nodes_count();
+        let response =
+            Response::TrieNodesCount { db_reads: count.db_reads, mem_reads: count.mem_reads };
+        self.trace.borrow_mut().entries.push((ExternalCall::GetTrieNodesCount, response));
+        count
+    }
+
+    fn validator_frozen(&self, account_id: &AccountId) -> Result<Option<Balance>> {
+        let result = self.inner.validator_frozen(account_id);
+        let call = ExternalCall::ValidatorFrozen { account_id: account_id.clone() };
+        self.record(call, result, |&amount| Response::Amount(amount))
+    }
+
+    fn validator_power(&self, account_id: &AccountId) -> Result<Option<Power>> {
+        let result = self.inner.validator_power(account_id);
+        let call = ExternalCall::ValidatorPower { account_id: account_id.clone() };
+        self.record(call, result, |&power| Response::Amount(power.map(Balance::from)))
+    }
+
+    fn validator_total_frozen(&self) -> Result<Balance> {
+        let result = self.inner.validator_total_frozen();
+        self.record(ExternalCall::ValidatorTotalFrozen, result, |&amount| {
+            Response::Amount(Some(amount))
+        })
+    }
+
+    fn validator_total_power(&self) -> Result<Power> {
+        let result = self.inner.validator_total_power();
+        self.record(ExternalCall::ValidatorTotalPower, result, |&power| {
+            Response::Amount(Some(power.into()))
+        })
+    }
+
+    fn account_code_hash(&self, account_id: &AccountId) -> Result<Option<CryptoHash>> {
+        let result = self.inner.account_code_hash(account_id);
+        let call = ExternalCall::AccountCodeHash { account_id: account_id.clone() };
+        self.record(call, result, |&code_hash| Response::CodeHash(code_hash))
+    }
+
+    fn create_receipt(
+        &mut self,
+        receipt_indices: Vec<ReceiptIndex>,
+        receiver_id: AccountId,
+    ) -> Result<ReceiptIndex, VMLogicError> {
+        let call = ExternalCall::CreateReceipt {
+            receipt_indices: receipt_indices.clone(),
+            receiver_id: receiver_id.clone(),
+        };
+        let result = self.inner.create_receipt(receipt_indices, receiver_id);
+        self.record(call, result, |&index| Response::ReceiptIndex(index))
+    }
+
+    fn append_action_create_account(
+        &mut self,
+        receipt_index: ReceiptIndex,
+    ) -> Result<(), VMLogicError> {
+        let result = self.inner.append_action_create_account(receipt_index);
+        self.record_unit(ExternalCall::CreateAccount { receipt_index }, result)
+    }
+
+    fn append_action_deploy_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code: Vec<u8>,
+    ) -> Result<(), VMLogicError> {
+        let code_hash = CryptoHash::hash_bytes(&code);
+        let result = self.inner.append_action_deploy_contract(receipt_index, code);
+        self.record_unit(ExternalCall::DeployContract { receipt_index, code_hash }, result)
+    }
+
+    fn append_action_deploy_global_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code_hash: CryptoHash,
+    ) -> Result<(), VMLogicError> {
+        let result = self.inner.append_action_deploy_global_contract(receipt_index, code_hash);
+        self.record_unit(ExternalCall::DeployGlobalContract { receipt_index, code_hash }, result)
+    }
+
+    fn append_action_function_call_weight(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        method_name: Vec<u8>,
+        args: Vec<u8>,
+        attached_deposit: Balance,
+        prepaid_gas: Gas,
+        gas_weight: GasWeight,
+    ) -> Result<(), VMLogicError> {
+        let call = ExternalCall::FunctionCall {
+            receipt_index,
+            method_name: method_name.clone(),
+            args: args.clone(),
+            attached_deposit,
+            prepaid_gas,
+            gas_weight: gas_weight.0,
+        };
+        let result = self.inner.append_action_function_call_weight(
+            receipt_index,
+            method_name,
+            args,
+            attached_deposit,
+            prepaid_gas,
+            gas_weight,
+        );
+        self.record_unit(call, result)
+    }
+
+    fn append_action_transfer(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        deposit: Balance,
+    ) -> Result<(), VMLogicError> {
+        let result = self.inner.append_action_transfer(receipt_index, deposit);
+        self.record_unit(ExternalCall::Transfer { receipt_index, deposit }, result)
+    }
+
+    fn append_action_stake(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        stake: Balance,
+        public_key: PublicKey,
+    ) {
+        let call = ExternalCall::Stake { receipt_index, stake, public_key: public_key.clone() };
+        self.inner.append_action_stake(receipt_index, stake, public_key);
+        self.record_unit(call, Ok(())).unwrap()
+    }
+
+    fn append_action_add_key_with_full_access(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+    ) {
+        let call =
+            ExternalCall::AddFullAccessKey { receipt_index, public_key: public_key.clone(), nonce };
+        self.inner.append_action_add_key_with_full_access(receipt_index, public_key, nonce);
+        self.record_unit(call, Ok(())).unwrap()
+    }
+
+    fn append_action_add_key_with_function_call(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+        allowance: Option<Balance>,
+        receiver_id: AccountId,
+        method_names: Vec<Vec<u8>>,
+    ) -> Result<(), VMLogicError> {
+        let call = ExternalCall::AddFunctionCallKey {
+            receipt_index,
+            public_key: public_key.clone(),
+            nonce,
+            allowance,
+            receiver_id: receiver_id.clone(),
+            method_names: method_names.clone(),
+        };
+        let result = self.inner.append_action_add_key_with_function_call(
+            receipt_index,
+            public_key,
+            nonce,
+            allowance,
+            receiver_id,
+            method_names,
+        );
+        self.record_unit(call, result)
+    }
+
+    fn append_action_delete_key(&mut self, receipt_index: ReceiptIndex, public_key: PublicKey) {
+        let call = ExternalCall::DeleteKey { receipt_index, public_key: public_key.clone() };
+        self.inner.append_action_delete_key(receipt_index, public_key);
+        self.record_unit(call, Ok(())).unwrap()
+    }
+
+    fn append_action_delete_account(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        beneficiary_id: AccountId,
+    ) -> Result<(), VMLogicError> {
+        let call =
+            ExternalCall::DeleteAccount { receipt_index, beneficiary_id: beneficiary_id.clone() };
+        let result = self.inner.append_action_delete_account(receipt_index, beneficiary_id);
+        self.record_unit(call, result)
+    }
+
+    fn scratch_get(&self) -> Result<Option<Vec<u8>>> {
+        let result = self.inner.scratch_get();
+        self.record(ExternalCall::ScratchGet, result, |data| Response::Scratch(data.clone()))
+    }
+
+    fn append_scratch(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        data: Vec<u8>,
+    ) -> Result<(), VMLogicError> {
+        let call = ExternalCall::AppendScratch { receipt_index, data: data.clone() };
+        let result = self.inner.append_scratch(receipt_index, data);
+        self.record_unit(call, result)
+    }
+
+    fn get_receipt_receiver(&self, receipt_index: ReceiptIndex) -> &AccountId {
+        self.inner.get_receipt_receiver(receipt_index)
+    }
+}
+
+/// [`External`] answering the calls of the secondary VM from the trace of the
+/// primary one.
+struct ReplayExternal<'a> {
+    trace: &'a ExternalTrace,
+    next: Cell<usize>,
+    /// The first call differing from the trace.
+    mismatch: RefCell<Option<(usize, ExternalCall)>>,
+    receivers: HashMap<ReceiptIndex, AccountId>,
+    /// Receiver of the receipts the trace does not know about.
+    unknown_receiver: AccountId,
+}
+
+impl<'a> ReplayExternal<'a> {
+    fn new(trace: &'a ExternalTrace, unknown_receiver: AccountId) -> Self {
+        Self {
+            trace,
+            next: Cell::new(0),
+            mismatch: RefCell::new(None),
+            receivers: HashMap::new(),
+            unknown_receiver,
+        }
+    }
+
+    /// The primary's response to `call`, if it made the same call at this
+    /// point.
+    fn replay(&self, call: ExternalCall) -> Option<Response> {
+        if self.mismatch.borrow().is_some() {
+            return None;
+        }
+        let index = self.next.get();
+        match self.trace.entries.get(index) {
+            Some((expected, response)) if *expected == call => {
+                self.next.set(index + 1);
+                Some(response.clone())
+            }
+            _ => {
+                *self.mismatch.borrow_mut() = Some((index, call));
+                None
+            }
+        }
+    }
+
+    fn replay_result<T>(
+        &self,
+        call: ExternalCall,
+        value: impl FnOnce(Response) -> Option<T>,
+    ) -> Result<T> {
+        match self.replay(call) {
+            Some(Response::Error(err)) => Err(err.into()),
+            Some(response) => value(response).ok_or_else(diverged),
+            None => Err(diverged()),
+        }
+    }
+
+    fn replay_unit(&self, call: ExternalCall) -> Result<()> {
+        self.replay_result(call, |_| Some(()))
+    }
+
+    /// The first call which differs from the trace, or the first call of the
+    /// trace which was not made.
+    fn into_divergence(self) -> Option<ShadowDivergence> {
+        let calls = self.next.get();
+        match self.mismatch.into_inner() {
+            Some((index, actual)) => Some(ShadowDivergence::ExternalCall {
+                index,
+                expected: self.trace.entries.get(index).map(|(call, _)| Box::new(call.clone())),
+                actual: Some(Box::new(actual)),
+            }),
+            None if calls < self.trace.len() => Some(ShadowDivergence::ExternalCall {
+                index: calls,
+                expected: Some(Box::new(self.trace.entries[calls].0.clone())),
+                actual: None,
+            }),
+            None => None,
+        }
+    }
+}
+
+struct ReplayValuePtr {
+    len: u32,
+    bytes: Option<Vec<u8>>,
+}
+
+impl ValuePtr for ReplayValuePtr {
+    fn len(&self) -> u32 {
+        self.len
+    }
+
+    fn deref(&self) -> Result<Vec<u8>> {
+        // The primary VM did not read the value.
+        self.bytes.clone().ok_or_else(diverged)
+    }
+}
+
+impl External for ReplayExternal<'_> {
+    fn storage_set(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.replay_unit(ExternalCall::StorageSet { key: key.to_vec(), value: value.to_vec() })
+    }
+
+    fn storage_get<'b>(
+        &'b self,
+        key: &[u8],
+        mode: StorageGetMode,
+    ) -> Result<Option<Box<dyn ValuePtr + 'b>>> {
+        self.replay_result(ExternalCall::StorageGet { key: key.to_vec(), mode }, |response| {
+            match response {
+                Response::Value(value) => Some(value.map(|(len, bytes)| {
+                    Box::new(ReplayValuePtr { len, bytes }) as Box<dyn ValuePtr>
+                })),
+                _ => None,
+            }
+        })
+    }
+
+    fn storage_remove(&mut self, key: &[u8]) -> Result<()> {
+        self.replay_unit(ExternalCall::StorageRemove { key: key.to_vec() })
+    }
+
+    fn storage_remove_subtree(&mut self, prefix: &[u8]) -> Result<()> {
+        self.replay_unit(ExternalCall::StorageRemoveSubtree { prefix: prefix.to_vec() })
+    }
+
+    fn storage_has_key(&mut self, key: &[u8], mode: StorageGetMode) -> Result<bool> {
+        let call = ExternalCall::StorageHasKey { key: key.to_vec(), mode };
+        self.replay_result(call, |response| match response {
+            Response::Bool(has) => Some(has),
+            _ => None,
+        })
+    }
+
+    fn generate_data_id(&mut self) -> CryptoHash {
+        match self.replay(ExternalCall::GenerateDataId) {
+            Some(Response::Hash(data_id)) => data_id,
+            _ => CryptoHash::default(),
+        }
+    }
+
+    fn get_trie_nodes_count(&self) -> TrieNodesCount {
+        match self.replay(ExternalCall::GetTrieNodesCount) {
+            Some(Response::TrieNodesCount { db_reads, mem_reads }) => {
+                TrieNodesCount { db_reads, mem_reads }
+            }
+            _ => TrieNodesCount { db_reads: 0, mem_reads: 0 },
+        }
+    }
+
+    fn validator_frozen(&self, account_id: &AccountId) -> Result<Option<Balance>> {
+        let call = ExternalCall::ValidatorFrozen { account_id: account_id.clone() };
+        self.replay_result(call, |response| match response {
+            Response::Amount(amount) => Some(amount),
+            _ => None,
+        })
+    }
+
+    fn validator_power(&self, account_id: &AccountId) -> Result<Option<Power>> {
+        let call = ExternalCall::ValidatorPower { account_id: account_id.clone() };
+        self.replay_result(call, |response| match response {
+            Response::Amount(power) => power.map(Power::try_from).transpose().ok(),
+            _ => None,
+        })
+    }
+
+    fn validator_total_frozen(&self) -> Result<Balance> {
+        self.replay_result(ExternalCall::ValidatorTotalFrozen, |response| match response {
+            Response::Amount(amount) => amount,
+            _ => None,
+        })
+    }
+
+    fn validator_total_power(&self) -> Result<Power> {
+        self.replay_result(ExternalCall::ValidatorTotalPower, |response| match response {
+            Response::Amount(Some(power)) => power.try_into().ok(),
+            _ => None,
+        })
+    }
+
+    fn account_code_hash(&self, account_id: &AccountId) -> Result<Option<CryptoHash>> {
+        let call = ExternalCall::AccountCodeHash { account_id: account_id.clone() };
+        self.replay_result(call, |response| match response {
+            Response::CodeHash(code_hash) => Some(code_hash),
+            _ => None,
+        })
+    }
+
+    /// The responses replayed are those of a recording, not of a block.
+    fn state_changed(&self) -> bool {
+        true
+    }
+
+    fn create_receipt(
+        &mut self,
+        receipt_indices: Vec<ReceiptIndex>,
+        receiver_id: AccountId,
+    ) -> Result<ReceiptIndex, VMLogicError> {
+        let call =
+            ExternalCall::CreateReceipt { receipt_indices, receiver_id: receiver_id.clone() };
+        let index = self.replay_result(call, |response| match response {
+            Response::ReceiptIndex(index) => Some(index),
+            _ => None,
+        })?;
+        self.receivers.insert(index, receiver_id);
+        Ok(index)
+    }
+
+    fn append_action_create_account(
+        &mut self,
+        receipt_index: ReceiptIndex,
+    ) -> Result<(), VMLogicError> {
+        self.replay_unit(ExternalCall::CreateAccount { receipt_index })
+    }
+
+    fn append_action_deploy_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code: Vec<u8>,
+    ) -> Result<(), VMLogicError> {
+        let code_hash = CryptoHash::hash_bytes(&code);
+        self.replay_unit(ExternalCall::DeployContract { receipt_index, code_hash })
+    }
+
+    fn append_action_deploy_global_contract(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        code_hash: CryptoHash,
+    ) -> Result<(), VMLogicError> {
+        self.replay_unit(ExternalCall::DeployGlobalContract { receipt_index, code_hash })
+    }
+
+    fn append_action_function_call_weight(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        method_name: Vec<u8>,
+        args: Vec<u8>,
+        attached_deposit: Balance,
+        prepaid_gas: Gas,
+        gas_weight: GasWeight,
+    ) -> Result<(), VMLogicError> {
+        self.replay_unit(ExternalCall::FunctionCall {
+            receipt_index,
+            method_name,
+            args,
+            attached_deposit,
+            prepaid_gas,
+            gas_weight: gas_weight.0,
+        })
+    }
+
+    fn append_action_transfer(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        deposit: Balance,
+    ) -> Result<(), VMLogicError> {
+        self.replay_unit(ExternalCall::Transfer { receipt_index, deposit })
+    }
+
+    fn append_action_stake(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        stake: Balance,
+        public_key: PublicKey,
+    ) {
+        let _ = self.replay_unit(ExternalCall::Stake { receipt_index, stake, public_key });
+    }
+
+    fn append_action_add_key_with_full_access(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+    ) {
+        let call = ExternalCall::AddFullAccessKey { receipt_index, public_key, nonce };
+        let _ = self.replay_unit(call);
+    }
+
+    fn append_action_add_key_with_function_call(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        public_key: PublicKey,
+        nonce: Nonce,
+        allowance: Option<Balance>,
+        receiver_id: AccountId,
+        method_names: Vec<Vec<u8>>,
+    ) -> Result<(), VMLogicError> {
+        self.replay_unit(ExternalCall::AddFunctionCallKey {
+            receipt_index,
+            public_key,
+            nonce,
+            allowance,
+            receiver_id,
+            method_names,
+        })
+    }
+
+    fn append_action_delete_key(&mut self, receipt_index: ReceiptIndex, public_key: PublicKey) {
+        let _ = self.replay_unit(ExternalCall::DeleteKey { receipt_index, public_key });
+    }
+
+    fn append_action_delete_account(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        beneficiary_id: AccountId,
+    ) -> Result<(), VMLogicError> {
+        self.replay_unit(ExternalCall::DeleteAccount { receipt_index, beneficiary_id })
+    }
+
+    fn scratch_get(&self) -> Result<Option<Vec<u8>>> {
+        self.replay_result(ExternalCall::ScratchGet, |response| match response {
+            Response::Scratch(data) => Some(data),
+            _ => None,
+        })
+    }
+
+    fn append_scratch(
+        &mut self,
+        receipt_index: ReceiptIndex,
+        data: Vec<u8>,
+    ) -> Result<(), VMLogicError> {
+        self.replay_unit(ExternalCall::AppendScratch { receipt_index, data })
+    }
+
+    fn get_receipt_receiver(&self, receipt_index: ReceiptIndex) -> &AccountId {
+        self.receivers.get(&receipt_index).unwrap_or(&self.unknown_receiver)
+    }
+}
+
+/// How the secondary VM diverged from the primary one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ShadowDivergence {
+    /// The secondary VM made the `index`-th [`External`] call differently
+    /// than the primary one, or made fewer calls if `actual` is `None`.
+    ExternalCall {
+        index: usize,
+        expected: Option<Box<ExternalCall>>,
+        actual: Option<Box<ExternalCall>>,
+    },
+    /// Both VMs made the same calls but their outcomes differ.
+    Outcome { primary: String, secondary: String },
+}
+
+/// A call on which the secondary VM diverged from the primary one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ShadowReport {
+    pub primary: VMKind,
+    pub secondary: VMKind,
+    /// The [`ConfigFingerprint`] of the config of the primary VM, to tell
+    /// the reports of nodes running different configs apart.
+    pub config_fingerprint: CryptoHash,
+    pub code_hash: CryptoHash,
+    pub method_name: String,
+    pub divergence: ShadowDivergence,
+}
+
+/// Destination of the divergences found by shadow execution, e.g. metrics
+/// and logs.
+pub trait ShadowSink {
+    fn report(&mut self, report: ShadowReport);
+}
+
+impl ShadowSink for Vec<ShadowReport> {
+    fn report(&mut self, report: ShadowReport) {
+        self.push(report);
+    }
+}
+
+/// Everything needed to run a call on the secondary VM, see [`run_recorded`].
+pub struct ShadowCall {
+    code: ContractCode,
+    method_name: String,
+    context: VMContext,
+    config: Config,
+    fees_config: RuntimeFeesConfig,
+    promise_results: Vec<PromiseResult>,
+    trace: ExternalTrace,
+    primary: String,
+}
+
+impl ShadowCall {
+    /// The [`External`] calls of the primary VM.
+    pub fn trace(&self) -> &ExternalTrace {
+        &self.trace
+    }
+
+    /// Runs the call on `secondary` and reports the divergences to `sink`.
+    ///
+    /// Fails if `secondary` cannot run the contract.  To find where a
+    /// reported divergence starts, see [`Self::bisect`].
+    pub fn run(
+        &self,
+        secondary: VMKind,
+        sink: &mut dyn ShadowSink,
+    ) -> Result<(), BackendRejection> {
+        let mut ext = ReplayExternal::new(&self.trace, self.context.current_account_id.clone());
+        let result = self.replay(secondary, &mut ext, &RunOptions::default())?;
+        let divergence = ext.into_divergence().or_else(|| {
+            let secondary = summarize(&result);
+            (secondary != self.primary)
+                .then(|| ShadowDivergence::Outcome { primary: self.primary.clone(), secondary })
+        });
+        if let Some(divergence) = divergence {
+            sink.report(ShadowReport {
+                primary: self.config.vm_kind,
+                secondary,
+                config_fingerprint: self.config.fingerprint(),
+                code_hash: *self.code.hash(),
+                method_name: self.method_name.clone(),
+                divergence,
+            });
+        }
+        Ok(())
+    }
+
+    /// Replays the call on the primary VM and on `secondary` and returns the
+    /// first host call at which their gas or registers differ.
+    ///
+    /// Both VMs run on the recorded [`External`] results, so this does not
+    /// need the state and can run long after the divergence was reported.
+    /// Returns `None` if the VMs agree at every host call, the divergence is
+    /// then after the last one.
+    pub fn bisect(
+        &self,
+        secondary: VMKind,
+    ) -> Result<Option<CheckpointDivergence>, BackendRejection> {
+        let options = RunOptions { record_checkpoints: true, ..RunOptions::default() };
+        let checkpoints = |vm_kind| -> Result<Vec<HostCallCheckpoint>, BackendRejection> {
+            let mut ext = ReplayExternal::new(&self.trace, self.context.current_account_id.clone());
+            let result = self.replay(vm_kind, &mut ext, &options)?;
+            Ok(result.map(|outcome| outcome.checkpoints).unwrap_or_default())
+        };
+        let primary = checkpoints(self.config.vm_kind)?;
+        let secondary = checkpoints(secondary)?;
+        Ok(first_difference(&primary, &secondary))
+    }
+
+    fn replay(
+        &self,
+        vm_kind: VMKind,
+        ext: &mut ReplayExternal<'_>,
+        options: &RunOptions,
+    ) -> Result<VMResult, BackendRejection> {
+        let mut config = self.config.clone();
+        config.vm_kind = vm_kind;
+        run_replaying(
+            &self.code,
+            &self.method_name,
+            ext,
+            self.context.clone(),
+            &config,
+            &self.fees_config,
+            &self.promise_results,
+            options,
+        )
+    }
+}
+
+fn run_replaying(
+    code: &ContractCode,
+    method_name: &str,
+    ext: &mut ReplayExternal<'_>,
+    context: VMContext,
+    wasm_config: &Config,
+    fees_config: &RuntimeFeesConfig,
+    promise_results: &[PromiseResult],
+    options: &RunOptions,
+) -> Result<VMResult, BackendRejection> {
+    let vm_kind = wasm_config.vm_kind;
+    check_backend(vm_kind, wasm_config)?;
+    let runtime = crate::runner::VMKindExt::runtime(&vm_kind, wasm_config.clone())?;
+    Ok(runtime.run_with_options(
+        code,
+        method_name,
+        ext,
+        context,
+        fees_config,
+        promise_results,
+        None,
+        options,
+    ))
+}
+
+/// A call re-executed against a recorded trace, see [`replay`].
+#[derive(Debug)]
+pub struct Replay {
+    pub result: VMResult,
+    /// The first [`External`] call which differs from the trace, if any.
+    ///
+    /// The state the contract sees after it is made up, so the rest of the
+    /// execution tells nothing about the recorded one.
+    pub divergence: Option<ShadowDivergence>,
+}
+
+/// Re-executes a call on the VM of `wasm_config`, answering its [`External`]
+/// calls from `trace` instead of the state.
+///
+/// The other arguments must be those of the recorded call, which then
+/// replays exactly as it was recorded on the same VM.  Use
+/// [`RunOptions::record_host_calls`] to see what the contract did.
+pub fn replay(
+    code: &ContractCode,
+    method_name: &str,
+    trace: &ExternalTrace,
+    context: VMContext,
+    wasm_config: &Config,
+    fees_config: &RuntimeFeesConfig,
+    promise_results: &[PromiseResult],
+    options: &RunOptions,
+) -> Result<Replay, BackendRejection> {
+    let mut ext = ReplayExternal::new(trace, context.current_account_id.clone());
+    let result = run_replaying(
+        code,
+        method_name,
+        &mut ext,
+        context,
+        wasm_config,
+        fees_config,
+        promise_results,
+        options,
+    )?;
+    Ok(Replay { result, divergence: ext.into_divergence() })
+}
+
+/// First host call at which the executions of a call on two VMs differ, see
+/// [`ShadowCall::bisect`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CheckpointDivergence {
+    /// Number of host calls before, on which the VMs agree.
+    pub index: usize,
+    /// State of the primary VM at the host call, `None` if it made fewer
+    /// host calls.
+    pub primary: Option<HostCallCheckpoint>,
+    /// State of the secondary VM at the host call, `None` if it made fewer
+    /// host calls.
+    pub secondary: Option<HostCallCheckpoint>,
+}
+
+fn first_difference(
+    primary: &[HostCallCheckpoint],
+    secondary: &[HostCallCheckpoint],
+) -> Option<CheckpointDivergence> {
+    let index = primary.iter().zip(secondary).take_while(|(p, s)| p == s).count();
+    if index == primary.len() && index == secondary.len() {
+        return None;
+    }
+    Some(CheckpointDivergence {
+        index,
+        primary: primary.get(index).cloned(),
+        secondary: secondary.get(index).cloned(),
+    })
+}
+
+fn summarize(result: &VMResult) -> String {
+    match result {
+        Ok(outcome) => {
+            let return_data = match &outcome.return_data {
+                ReturnData::Value(value) => format!("value {value:?}"),
+                ReturnData::ReceiptIndex(index) => format!("receipt {index}"),
+                ReturnData::None => "none".to_string(),
+            };
+            let aborted = outcome.aborted.as_ref().map(|err| err.to_string());
+            format!(
+                "balance {} storage_usage {} return data {} burnt gas {} used gas {} logs {:?} \
+                 aborted {:?}",
+                outcome.balance,
+                outcome.storage_usage,
+                return_data,
+                outcome.burnt_gas,
+                outcome.used_gas,
+                outcome.logs,
+                aborted,
+            )
+        }
+        Err(err) => format!("runner error: {err:?}"),
+    }
+}
+
+fn clone_promise_result(result: &PromiseResult) -> PromiseResult {
+    match result {
+        PromiseResult::NotReady => PromiseResult::NotReady,
+        PromiseResult::Successful(data) => PromiseResult::Successful(data.clone()),
+        PromiseResult::Failed => PromiseResult::Failed,
+    }
+}
+
+/// Same as [`crate::run`], but also records the [`External`] calls of the
+/// call so that it can be replayed on another VM with [`ShadowCall::run`].
+pub fn run_recorded(
+    code: &ContractCode,
+    method_name: &str,
+    ext: &mut dyn External,
+    context: VMContext,
+    wasm_config: &Config,
+    fees_config: &RuntimeFeesConfig,
+    promise_results: &[PromiseResult],
+    cache: Option<&dyn CompiledContractCache>,
+) -> (VMResult, ShadowCall) {
+    let mut recording = RecordingExternal::new(ext);
+    let result = crate::run(
+        code,
+        method_name,
+        &mut recording,
+        context.clone(),
+        wasm_config,
+        fees_config,
+        promise_results,
+        cache,
+    );
+    let call = ShadowCall {
+        code: ContractCode::new(code.code().to_vec(), Some(*code.hash())),
+        method_name: method_name.to_string(),
+        context,
+        config: wasm_config.clone(),
+        fees_config: fees_config.clone(),
+        promise_results: promise_results.iter().map(clone_promise_result).collect(),
+        trace: recording.into_trace(),
+        primary: summarize(&result),
+    };
+    (result, call)
+}
+
+/// Runs the call on the VM of `wasm_config` and then on `secondary`,
+/// reporting the divergences to `sink`.
+///
+/// The outcome is the one of the primary VM.  To run the secondary VM in the
+/// background, use [`run_recorded`] instead.
+pub fn run_shadowed(
+    code: &ContractCode,
+    method_name: &str,
+    ext: &mut dyn External,
+    context: VMContext,
+    wasm_config: &Config,
+    fees_config: &RuntimeFeesConfig,
+    promise_results: &[PromiseResult],
+    cache: Option<&dyn CompiledContractCache>,
+    secondary: VMKind,
+    sink: &mut dyn ShadowSink,
+) -> VMResult {
+    let (result, call) = run_recorded(
+        code,
+        method_name,
+        ext,
+        context,
+        wasm_config,
+        fees_config,
+        promise_results,
+        cache,
+    );
+    if let Err(err) = call.run(secondary, sink) {
+        tracing::debug!(target: "vm", %err, "cannot run the shadow call");
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+
+    /// Writes its input under the key `k` and returns it.
+    const CONTRACT: &str = r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "storage_write"
+    (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (memory 1)
+  (data (i32.const 0) "k")
+  (func (export "main")
+    (call $input (i64.const 0))
+    (call $read_register (i64.const 0) (i64.const 8))
+    (drop (call $storage_write
+      (i64.const 1) (i64.const 0) (call $register_len (i64.const 0)) (i64.const 8) (i64.const 1)))
+    (call $value_return (call $register_len (i64.const 0)) (i64.const 8)))
+)"#;
+
+    #[test]
+    fn test_external_storage() {
+        let mut ext = MockedExternal::new();
+        crate::logic::test_utils::test_external_storage(&mut RecordingExternal::new(&mut ext));
+    }
+
+    #[test]
+    fn test_shadow_execution() {
+        let code = ContractCode::new(wat::parse_str(CONTRACT).unwrap(), None);
+        let config = test_vm_config();
+        let fees = RuntimeFeesConfig::test();
+        with_vm_variants(&config, |secondary| {
+            let mut ext = MockedExternal::new();
+            let mut reports = Vec::new();
+            let context = create_context(b"value".to_vec());
+            let outcome = run_shadowed(
+                &code,
+                "main",
+                &mut ext,
+                context,
+                &config,
+                &fees,
+                &[],
+                None,
+                secondary,
+                &mut reports,
+            )
+            .unwrap();
+            assert_eq!(outcome.aborted, None);
+            assert_eq!(reports, [], "{secondary:?}");
+
+            // Replaying with another input makes the secondary VM write
+            // another value than the primary one did.
+            let mut ext = MockedExternal::new();
+            let context = create_context(b"value".to_vec());
+            let (_, mut call) =
+                run_recorded(&code, "main", &mut ext, context, &config, &fees, &[], None);
+            assert!(call.trace().calls().any(|call| matches!(
+                call,
+                ExternalCall::StorageSet { key, value } if key == b"k" && value == b"value"
+            )));
+            assert_eq!(call.bisect(secondary), Ok(None), "{secondary:?}");
+            call.context.input = b"other".to_vec();
+            call.run(secondary, &mut reports).unwrap();
+            assert_matches::assert_matches!(
+                &reports[..],
+                [ShadowReport {
+                    divergence: ShadowDivergence::ExternalCall {
+                        actual: Some(actual),
+                        ..
+                    },
+                    ..
+                }] if matches!(
+                    &**actual,
+                    ExternalCall::StorageSet { value, .. } if value == b"other"
+                )
+            );
+        });
+    }
+
+    #[test]
+    fn test_replay() {
+        let code = ContractCode::new(wat::parse_str(CONTRACT).unwrap(), None);
+        let fees = RuntimeFeesConfig::test();
+        let options = RunOptions { record_host_calls: true, ..RunOptions::default() };
+        with_vm_variants(&test_vm_config(), |vm_kind| {
+            let mut config = test_vm_config();
+            config.vm_kind = vm_kind;
+            let context = create_context(b"value".to_vec());
+            let mut ext = MockedExternal::new();
+            let mut recording = RecordingExternal::new(&mut ext);
+            let recorded = crate::run_with_options(
+                &code,
+                "main",
+                &mut recording,
+                context.clone(),
+                &config,
+                &fees,
+                &[],
+                None,
+                &options,
+            )
+            .unwrap();
+            let trace = serde_json::to_string(&recording.into_trace()).unwrap();
+            let trace: ExternalTrace = serde_json::from_str(&trace).unwrap();
+
+            let storage_write = recorded
+                .host_calls
+                .iter()
+                .find(|call| call.host_function == "storage_write")
+                .unwrap();
+            assert_eq!(storage_write.args, [1, 0, 5, 8, 1]);
+            assert_eq!(storage_write.result, Some(Ok(vec![0])));
+            assert!(storage_write.burnt_gas_after > storage_write.burnt_gas_before);
+
+            let replayed =
+                replay(&code, "main", &trace, context.clone(), &config, &fees, &[], &options)
+                    .unwrap();
+            assert_eq!(replayed.divergence, None, "{vm_kind:?}");
+            let outcome = replayed.result.unwrap();
+            assert_eq!(outcome.host_calls, recorded.host_calls);
+            assert_eq!(outcome.return_data, recorded.return_data);
+            assert_eq!(outcome.burnt_gas, recorded.burnt_gas);
+
+            let context = VMContext { input: b"other".to_vec(), ..context };
+            let replayed =
+                replay(&code, "main", &trace, context, &config, &fees, &[], &options).unwrap();
+            assert_matches::assert_matches!(
+                replayed.divergence,
+                Some(ShadowDivergence::ExternalCall { actual: Some(_), .. })
+            );
+        });
+    }
+
+    #[test]
+    fn test_checkpoints() {
+        let code = ContractCode::new(wat::parse_str(CONTRACT).unwrap(), None);
+        let config = test_vm_config();
+        let fees = RuntimeFeesConfig::test();
+        let options = RunOptions { record_checkpoints: true, ..RunOptions::default() };
+        let outcome = crate::run_with_options(
+            &code,
+            "main",
+            &mut MockedExternal::new(),
+            create_context(b"value".to_vec()),
+            &config,
+            &fees,
+            &[],
+            None,
+            &options,
+        )
+        .unwrap();
+        let host_functions: Vec<_> =
+            outcome.checkpoints.iter().map(|checkpoint| &*checkpoint.host_function).collect();
+        assert_eq!(
+            host_functions,
+            [
+                "input",
+                "read_register",
+                "register_len",
+                "storage_write",
+                "register_len",
+                "value_return"
+            ]
+        );
+        let checkpoints = outcome.checkpoints;
+        assert!(checkpoints.windows(2).all(|w| w[0].burnt_gas < w[1].burnt_gas));
+        assert_ne!(checkpoints[0].registers, checkpoints[1].registers);
+
+        assert_eq!(first_difference(&checkpoints, &checkpoints), None);
+        let mut diverged = checkpoints.clone();
+        diverged[3].burnt_gas += 1;
+        assert_eq!(
+            first_difference(&checkpoints, &diverged),
+            Some(CheckpointDivergence {
+                index: 3,
+                primary: Some(checkpoints[3].clone()),
+                secondary: Some(diverged[3].clone()),
+            })
+        );
+        assert_eq!(
+            first_difference(&checkpoints, &checkpoints[..4]),
+            Some(CheckpointDivergence {
+                index: 4,
+                primary: Some(checkpoints[4].clone()),
+                secondary: None,
+            })
+        );
+    }
+}
@@ -0,0 +1,664 @@
+//! Running cross-contract calls end to end in unit tests.
+//!
+//! [`MockedExternal`] records the receipts a call creates, but nothing runs
+//! them, so a test cannot check what the callbacks of a call do.  A
+//! [`Simulator`] holds a set of accounts, each with a contract and its own
+//! [`MockedExternal`], and runs the receipts a call creates on their
+//! receivers once the receipts they depend on are done, feeding the results
+//! of those to the callbacks as promise results.
+//!
+//! Only the function calls of the receipts are run: deploys, transfers and
+//! the other actions are left to the tests to check in the receipts of the
+//! outcomes, and the balances of the accounts never change.  Writes of a
+//! failing call are dropped, as in the runtime.  Each receipt runs in a block
+//! of its own, in the order in which they become ready, and the outcome of
+//! each call is checked with [`crate::validate_outcome`].  Receipts running
+//! longer than [`Simulator::time_limit`] on [`Simulator::clock`] time out,
+//! which a test makes happen, or never happen, with a [`crate::VirtualClock`].
+//!
+//! The accounts registered with [`Simulator::add_oracle`] stand for the
+//! services outside the chain which contracts wait on, such as price feeds.
+//! Nothing runs the receipts sent to them: they are left in
+//! [`Simulation::awaiting`] until the test answers them with
+//! [`Simulator::resume`], the data it gives becoming the promise result of
+//! the callbacks waiting on them, as a data receipt would.
+
+use crate::clock::Clock;
+use crate::logic::errors::VMRunnerError;
+use crate::logic::gas_distribution::{GasDistributionPolicy, ProportionalDistribution};
+use crate::logic::mocks::mock_external::MockedExternal;
+use crate::logic::types::PromiseResult;
+use crate::logic::{ReceiptAction, ReturnData, VMContext, VMOutcome};
+use crate::runner::RunOptions;
+use crate::{ContractCode, MockCompiledContractCache};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Arc;
+use std::time::Duration;
+use crate::logic::Config;
+use unc_parameters::RuntimeFeesConfig;
+use unc_primitives_core::types::{AccountId, Balance, Gas, GasWeight};
+
+#[derive(Debug, thiserror::Error)]
+pub enum SimulationError {
+    #[error(transparent)]
+    Runner(#[from] VMRunnerError),
+    /// The calls keep creating receipts, see [`Simulator::max_receipts`].
+    #[error("the call ran more than {limit} receipts")]
+    TooManyReceipts { limit: usize },
+    /// [`Simulator::resume`] was given a receipt no oracle is answering.
+    #[error("receipt {receipt_id} is not waiting for data")]
+    NotAwaiting { receipt_id: usize },
+    /// The outcome of a call breaks an invariant, see
+    /// [`crate::validate_outcome`].
+    #[error("the outcome of receipt {receipt_id} breaks an invariant: {violation}")]
+    InvalidOutcome { receipt_id: usize, violation: crate::OutcomeViolation },
+    /// A receipt created by the call of a receipt has no
+    /// [`crate::logic::ReceiptCost`] in its outcome.
+    #[error("receipt {receipt_index} created by receipt {receipt_id} has no cost")]
+    MissingReceiptCost { receipt_id: usize, receipt_index: u64 },
+}
+
+/// An account of a [`Simulator`].
+#[derive(Default)]
+pub struct SimulatedAccount {
+    pub code: Option<ContractCode>,
+    pub ext: MockedExternal,
+    pub balance: Balance,
+}
+
+/// A function call run by [`Simulator::call`].
+#[derive(Debug)]
+pub struct SimulatedExecution {
+    /// Index of the receipt in the simulation, the call itself being 0.
+    pub receipt_id: usize,
+    pub receiver_id: AccountId,
+    pub method_name: String,
+    pub outcome: VMOutcome,
+}
+
+/// A receipt sent to an oracle, see [`Simulator::add_oracle`].
+#[derive(Debug)]
+pub struct OracleRequest {
+    /// Index of the receipt in the simulation, to pass to
+    /// [`Simulator::resume`].
+    pub receipt_id: usize,
+    pub predecessor_id: AccountId,
+    pub receiver_id: AccountId,
+    pub actions: Vec<ReceiptAction>,
+    /// The results of the receipts the request depends on.
+    pub promise_results: Vec<PromiseResult>,
+}
+
+/// Outcome of [`Simulator::call`].
+#[derive(Debug)]
+pub struct Simulation {
+    /// The function calls run, in order.
+    pub executions: Vec<SimulatedExecution>,
+    /// Result of the call, once the receipts it returned are done, and
+    /// [`PromiseResult::NotReady`] while some of them wait for an oracle.
+    pub result: PromiseResult,
+    /// The requests to the oracles not answered yet, in the order they were
+    /// made.
+    pub awaiting: Vec<OracleRequest>,
+    signer_id: AccountId,
+    resolutions: Vec<Resolution>,
+    queue: VecDeque<PendingReceipt>,
+    ran: usize,
+}
+
+#[derive(Debug)]
+enum Resolution {
+    Pending,
+    Done(PromiseResult),
+    /// The receipt returned the result of another one.
+    Forwarded(usize),
+}
+
+#[derive(Debug)]
+struct PendingReceipt {
+    id: usize,
+    predecessor_id: AccountId,
+    receiver_id: AccountId,
+    dependencies: Vec<usize>,
+    actions: Vec<ReceiptAction>,
+}
+
+/// Accounts running the receipts of their calls, see the module
+/// documentation.
+pub struct Simulator {
+    config: Config,
+    fees: RuntimeFeesConfig,
+    cache: MockCompiledContractCache,
+    accounts: HashMap<AccountId, SimulatedAccount>,
+    oracles: HashSet<AccountId>,
+    /// Most receipts a call runs, itself included, before failing with
+    /// [`SimulationError::TooManyReceipts`].
+    pub max_receipts: usize,
+    /// Time each receipt may run before failing with
+    /// [`crate::logic::errors::FunctionCallError::Timeout`], measured on
+    /// [`Self::clock`].
+    pub time_limit: Option<Duration>,
+    /// Clock of [`Self::time_limit`], such as a [`crate::VirtualClock`] the
+    /// test advances to time the receipts out.
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Simulator {
+    pub fn new(mut config: Config, fees: RuntimeFeesConfig) -> Self {
+        // Writes of a failing call are dropped.
+        config.buffer_storage_writes = true;
+        Self {
+            config,
+            fees,
+            cache: MockCompiledContractCache::default(),
+            accounts: HashMap::new(),
+            oracles: HashSet::new(),
+            max_receipts: 1000,
+            time_limit: None,
+            clock: Arc::new(crate::SystemClock),
+        }
+    }
+
+    /// Deploys `code` on `account_id`, creating the account if needed.
+    pub fn deploy(&mut self, account_id: AccountId, code: ContractCode) {
+        self.accounts.entry(account_id).or_default().code = Some(code);
+    }
+
+    pub fn account(&self, account_id: &AccountId) -> Option<&SimulatedAccount> {
+        self.accounts.get(account_id)
+    }
+
+    pub fn account_mut(&mut self, account_id: &AccountId) -> Option<&mut SimulatedAccount> {
+        self.accounts.get_mut(account_id)
+    }
+
+    /// Leaves the receipts sent to `account_id` for the test to answer with
+    /// [`Self::resume`], instead of running them.
+    pub fn add_oracle(&mut self, account_id: AccountId) {
+        self.oracles.insert(account_id);
+    }
+
+    /// Calls `method_name` of `receiver_id` as a transaction of `signer_id`
+    /// would, and runs all the receipts it leads to.
+    pub fn call(
+        &mut self,
+        signer_id: AccountId,
+        receiver_id: AccountId,
+        method_name: &str,
+        args: Vec<u8>,
+        prepaid_gas: Gas,
+    ) -> Result<Simulation, SimulationError> {
+        let queue = VecDeque::from([PendingReceipt {
+            id: 0,
+            predecessor_id: signer_id.clone(),
+            receiver_id,
+            dependencies: Vec::new(),
+            actions: vec![ReceiptAction::FunctionCall {
+                method_name: method_name.as_bytes().to_vec(),
+                args,
+                attached_deposit: 0,
+                prepaid_gas,
+                gas_weight: 0,
+            }],
+        }]);
+        let mut simulation = Simulation {
+            executions: Vec::new(),
+            result: PromiseResult::NotReady,
+            awaiting: Vec::new(),
+            signer_id,
+            resolutions: vec![Resolution::Pending],
+            queue,
+            ran: 0,
+        };
+        self.run(&mut simulation)?;
+        Ok(simulation)
+    }
+
+    /// Answers the request of [`Simulation::awaiting`] for receipt
+    /// `receipt_id` with `result`, and runs the receipts waiting on it.
+    pub fn resume(
+        &mut self,
+        simulation: &mut Simulation,
+        receipt_id: usize,
+        result: PromiseResult,
+    ) -> Result<(), SimulationError> {
+        let index = simulation
+            .awaiting
+            .iter()
+            .position(|request| request.receipt_id == receipt_id)
+            .ok_or(SimulationError::NotAwaiting { receipt_id })?;
+        simulation.awaiting.remove(index);
+        simulation.resolutions[receipt_id] = Resolution::Done(result);
+        self.run(simulation)
+    }
+
+    /// Runs the receipts of `simulation` until none is ready.
+    fn run(&mut self, simulation: &mut Simulation) -> Result<(), SimulationError> {
+        let Simulation { executions, awaiting, signer_id, resolutions, queue, ran, .. } =
+            simulation;
+        loop {
+            let ready = queue.iter().position(|receipt| {
+                receipt.dependencies.iter().all(|id| resolve(resolutions, *id).is_some())
+            });
+            let Some(receipt) = ready.and_then(|index| queue.remove(index)) else { break };
+            let promise_results: Vec<PromiseResult> = receipt
+                .dependencies
+                .iter()
+                .map(|id| clone_result(resolve(resolutions, *id).unwrap()))
+                .collect();
+            if self.oracles.contains(&receipt.receiver_id) {
+                awaiting.push(OracleRequest {
+                    receipt_id: receipt.id,
+                    predecessor_id: receipt.predecessor_id,
+                    receiver_id: receipt.receiver_id,
+                    actions: receipt.actions,
+                    promise_results,
+                });
+                continue;
+            }
+            *ran += 1;
+            if *ran > self.max_receipts {
+                return Err(SimulationError::TooManyReceipts { limit: self.max_receipts });
+            }
+            // Only the last action of a receipt passes its result on.
+            let output_data_receivers: Vec<AccountId> = queue
+                .iter()
+                .filter(|other| other.dependencies.contains(&receipt.id))
+                .map(|other| other.receiver_id.clone())
+                .collect();
+            let last_call = receipt
+                .actions
+                .iter()
+                .rposition(|action| matches!(action, ReceiptAction::FunctionCall { .. }));
+
+            let mut resolution = Resolution::Done(PromiseResult::Successful(Vec::new()));
+            for (index, action) in receipt.actions.iter().enumerate() {
+                let ReceiptAction::FunctionCall {
+                    method_name,
+                    args,
+                    attached_deposit,
+                    prepaid_gas,
+                    ..
+                } = action
+                else {
+                    continue;
+                };
+                let account = self.accounts.get_mut(&receipt.receiver_id);
+                let (Some(account), Ok(method_name)) = (account, std::str::from_utf8(method_name))
+                else {
+                    resolution = Resolution::Done(PromiseResult::Failed);
+                    break;
+                };
+                let Some(code) = &account.code else {
+                    resolution = Resolution::Done(PromiseResult::Failed);
+                    break;
+                };
+                let context = VMContext {
+                    current_account_id: receipt.receiver_id.clone(),
+                    signer_account_id: signer_id.clone(),
+                    signer_account_pk: Vec::new(),
+                    predecessor_account_id: receipt.predecessor_id.clone(),
+                    input: args.clone(),
+                    block_height: *ran as u64,
+                    block_timestamp: *ran as u64 * 1_000_000_000,
+                    epoch_height: 1,
+                    account_balance: account.balance,
+                    account_locked_balance: 0,
+                    storage_usage: 0,
+                    attached_deposit: *attached_deposit,
+                    prepaid_gas: *prepaid_gas,
+                    random_seed: vec![0; 32],
+                    view_config: None,
+                    output_data_receivers: if Some(index) == last_call {
+                        output_data_receivers.clone()
+                    } else {
+                        Vec::new()
+                    },
+                };
+                let options = RunOptions {
+                    deadline: self.time_limit.map(|limit| self.clock.now() + limit),
+                    clock: Some(self.clock.clone()),
+                    record_receipts: true,
+                    ..RunOptions::default()
+                };
+                let mut outcome = crate::run_with_options(
+                    code,
+                    method_name,
+                    &mut account.ext,
+                    context.clone(),
+                    &self.config,
+                    &self.fees,
+                    &promise_results,
+                    Some(&self.cache),
+                    &options,
+                )?;
+                crate::validate_outcome(&outcome, &context, &self.config).map_err(|violation| {
+                    SimulationError::InvalidOutcome { receipt_id: receipt.id, violation }
+                })?;
+                let failed = outcome.aborted.is_some();
+                if !failed {
+                    let unused_gas = prepaid_gas.saturating_sub(outcome.used_gas);
+                    distribute_unused_gas(&mut outcome, unused_gas).map_err(|receipt_index| {
+                        SimulationError::MissingReceiptCost {
+                            receipt_id: receipt.id,
+                            receipt_index,
+                        }
+                    })?;
+                    // The receipts of the call get the next ids, in order.
+                    let ids: HashMap<u64, usize> = outcome
+                        .receipts
+                        .iter()
+                        .enumerate()
+                        .map(|(i, created)| (created.receipt_index, resolutions.len() + i))
+                        .collect();
+                    for created in &outcome.receipts {
+                        resolutions.push(Resolution::Pending);
+                        queue.push_back(PendingReceipt {
+                            id: ids[&created.receipt_index],
+                            predecessor_id: receipt.receiver_id.clone(),
+                            receiver_id: created.receiver_id.clone(),
+                            dependencies: created.dependencies.iter().map(|d| ids[d]).collect(),
+                            actions: created.actions.clone(),
+                        });
+                    }
+                    resolution = match &outcome.return_data {
+                        ReturnData::Value(value) => {
+                            Resolution::Done(PromiseResult::Successful(value.clone()))
+                        }
+                        ReturnData::ReceiptIndex(index) => Resolution::Forwarded(ids[index]),
+                        ReturnData::None => Resolution::Done(PromiseResult::Successful(Vec::new())),
+                    };
+                }
+                executions.push(SimulatedExecution {
+                    receipt_id: receipt.id,
+                    receiver_id: receipt.receiver_id.clone(),
+                    method_name: method_name.to_string(),
+                    outcome,
+                });
+                if failed {
+                    resolution = Resolution::Done(PromiseResult::Failed);
+                    break;
+                }
+            }
+            resolutions[receipt.id] = resolution;
+        }
+        // Receipts always become ready, the dependencies being created first,
+        // unless they wait for an oracle.
+        simulation.result =
+            clone_result(resolve(&simulation.resolutions, 0).unwrap_or(&PromiseResult::NotReady));
+        Ok(())
+    }
+}
+
+/// The result of receipt `id`, following the receipts it forwarded to.
+fn resolve(resolutions: &[Resolution], mut id: usize) -> Option<&PromiseResult> {
+    loop {
+        match &resolutions[id] {
+            Resolution::Pending => return None,
+            Resolution::Done(result) => return Some(result),
+            Resolution::Forwarded(to) => id = *to,
+        }
+    }
+}
+
+fn clone_result(result: &PromiseResult) -> PromiseResult {
+    match result {
+        PromiseResult::NotReady => PromiseResult::NotReady,
+        PromiseResult::Successful(value) => PromiseResult::Successful(value.clone()),
+        PromiseResult::Failed => PromiseResult::Failed,
+    }
+}
+
+/// Adds its share of `unused_gas` to each function call of the receipts of
+/// `outcome` with a gas weight, as the runtime does, and to the
+/// [`crate::logic::ReceiptCost`] of its receipt.
+///
+/// Fails with the index of the first receipt without a cost, before giving
+/// any share.
+fn distribute_unused_gas(outcome: &mut VMOutcome, unused_gas: Gas) -> Result<(), u64> {
+    let calls = || {
+        outcome.receipts.iter().flat_map(|receipt| &receipt.actions).filter_map(|action| {
+            match action {
+                ReceiptAction::FunctionCall { gas_weight, .. } => Some(GasWeight(*gas_weight)),
+                _ => None,
+            }
+        })
+    };
+    let weights: Vec<GasWeight> = calls().collect();
+    for (position, receipt) in outcome.receipts.iter().enumerate() {
+        let cost = outcome.receipt_costs.get(position);
+        if cost.map(|cost| cost.receipt_index) != Some(receipt.receipt_index) {
+            return Err(receipt.receipt_index);
+        }
+    }
+    let mut distribution = ProportionalDistribution.distribute(unused_gas, &weights).into_iter();
+    for (receipt, cost) in outcome.receipts.iter_mut().zip(&mut outcome.receipt_costs) {
+        for action in &mut receipt.actions {
+            if let ReceiptAction::FunctionCall { prepaid_gas, .. } = action {
+                let share = distribution.next().unwrap_or(0);
+                *prepaid_gas += share;
+                cost.prepaid_gas += share;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::{test_vm_config, with_vm_variants};
+    use unc_parameters::vm::VMKind;
+
+    /// `main` calls the method of bob named by its input, with `cb` as the
+    /// callback returning the result of the call or "failed".
+    const CALLER: &str = r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (import "env" "promise_create"
+    (func $promise_create (param i64 i64 i64 i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "promise_then"
+    (func $promise_then (param i64 i64 i64 i64 i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "promise_return" (func $promise_return (param i64)))
+  (import "env" "promise_result" (func $promise_result (param i64 i64) (result i64)))
+  (memory 1)
+  (data (i32.const 0) "bob")
+  (data (i32.const 8) "alice")
+  (data (i32.const 16) "cb")
+  (data (i32.const 32) "failed")
+  (func (export "main")
+    (call $input (i64.const 0))
+    (call $read_register (i64.const 0) (i64.const 100))
+    (call $promise_return
+      (call $promise_then
+        (call $promise_create
+          (i64.const 3) (i64.const 0)
+          (call $register_len (i64.const 0)) (i64.const 100)
+          (i64.const 0) (i64.const 0) (i64.const 64) (i64.const 10000000000000))
+        (i64.const 5) (i64.const 8) (i64.const 2) (i64.const 16)
+        (i64.const 0) (i64.const 0) (i64.const 64) (i64.const 10000000000000))))
+  (func (export "cb")
+    (if (i64.eq (call $promise_result (i64.const 0) (i64.const 0)) (i64.const 1))
+      (then
+        (call $read_register (i64.const 0) (i64.const 200))
+        (call $value_return (call $register_len (i64.const 0)) (i64.const 200)))
+      (else (call $value_return (i64.const 6) (i64.const 32))))))
+"#;
+
+    /// `get` writes and returns "hello", `fail` writes it and traps.
+    const CALLEE: &str = r#"
+(module
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (import "env" "storage_write"
+    (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (memory 1)
+  (data (i32.const 0) "hello")
+  (func $write
+    (drop (call $storage_write
+      (i64.const 1) (i64.const 0) (i64.const 5) (i64.const 0) (i64.const 0))))
+  (func (export "get") (call $write) (call $value_return (i64.const 5) (i64.const 0)))
+  (func (export "fail") (call $write) unreachable))
+"#;
+
+    fn simulator(vm_kind: VMKind) -> Simulator {
+        let mut config = test_vm_config();
+        config.vm_kind = vm_kind;
+        let mut simulator = Simulator::new(config, RuntimeFeesConfig::test());
+        let code = |wat| ContractCode::new(wat::parse_str(wat).unwrap(), None);
+        simulator.deploy("alice".parse().unwrap(), code(CALLER));
+        simulator.deploy("bob".parse().unwrap(), code(CALLEE));
+        simulator
+    }
+
+    #[test]
+    fn test_callbacks_run() {
+        with_vm_variants(&test_vm_config(), |vm_kind: VMKind| {
+            let mut simulator = simulator(vm_kind);
+            let alice: AccountId = "alice".parse().unwrap();
+            let bob: AccountId = "bob".parse().unwrap();
+            let gas = 300_000_000_000_000;
+            let simulation =
+                simulator.call(bob.clone(), alice.clone(), "main", b"get".to_vec(), gas).unwrap();
+            assert_eq!(simulation.result, PromiseResult::Successful(b"hello".to_vec()));
+            let calls: Vec<_> = simulation
+                .executions
+                .iter()
+                .map(|e| (e.receipt_id, e.method_name.as_str()))
+                .collect();
+            assert_eq!(calls, [(0, "main"), (1, "get"), (2, "cb")], "{vm_kind:?}");
+            assert_eq!(simulation.executions[2].outcome.aborted, None);
+            let storage = &simulator.account(&bob).unwrap().ext.fake_trie;
+            assert_eq!(storage.get(b"h".as_slice()).map(Vec::as_slice), Some(b"hello".as_slice()));
+
+            // The callback sees the failure, and the writes of the failed call
+            // are dropped.
+            let mut simulator = self::simulator(vm_kind);
+            let simulation =
+                simulator.call(bob.clone(), alice, "main", b"fail".to_vec(), gas).unwrap();
+            assert_eq!(simulation.result, PromiseResult::Successful(b"failed".to_vec()));
+            assert!(simulation.executions[1].outcome.aborted.is_some());
+            assert!(simulator.account(&bob).unwrap().ext.fake_trie.is_empty());
+        });
+    }
+
+    #[test]
+    fn test_oracle_answers() {
+        with_vm_variants(&test_vm_config(), |vm_kind: VMKind| {
+            let alice: AccountId = "alice".parse().unwrap();
+            let bob: AccountId = "bob".parse().unwrap();
+            let gas = 300_000_000_000_000;
+            for (answer, want) in [
+                (PromiseResult::Successful(b"42".to_vec()), b"42".as_slice()),
+                (PromiseResult::Failed, b"failed".as_slice()),
+            ] {
+                let mut simulator = simulator(vm_kind);
+                simulator.add_oracle(bob.clone());
+                let mut simulation = simulator
+                    .call(bob.clone(), alice.clone(), "main", b"get".to_vec(), gas)
+                    .unwrap();
+                assert_eq!(simulation.result, PromiseResult::NotReady, "{vm_kind:?}");
+                assert_eq!(simulation.executions.len(), 1);
+                let [request] = simulation.awaiting.as_slice() else {
+                    panic!("{:?}", simulation.awaiting)
+                };
+                assert_eq!((request.receipt_id, &request.receiver_id), (1, &bob));
+                assert!(matches!(
+                    &request.actions[..],
+                    [ReceiptAction::FunctionCall { method_name, .. }] if method_name == b"get"
+                ));
+                assert!(matches!(
+                    simulator.resume(&mut simulation, 2, PromiseResult::Failed),
+                    Err(SimulationError::NotAwaiting { receipt_id: 2 })
+                ));
+
+                simulator.resume(&mut simulation, 1, answer).unwrap();
+                assert_eq!(simulation.result, PromiseResult::Successful(want.to_vec()));
+                assert!(simulation.awaiting.is_empty());
+                let calls: Vec<_> = simulation
+                    .executions
+                    .iter()
+                    .map(|e| (e.receipt_id, e.method_name.as_str()))
+                    .collect();
+                assert_eq!(calls, [(0, "main"), (2, "cb")], "{vm_kind:?}");
+                // The oracle's contract never ran.
+                assert!(simulator.account(&bob).unwrap().ext.fake_trie.is_empty());
+            }
+        });
+    }
+
+    #[test]
+    fn test_missing_contract_fails() {
+        let mut simulator = simulator(test_vm_config().vm_kind);
+        let carol: AccountId = "carol".parse().unwrap();
+        let simulation =
+            simulator.call(carol.clone(), carol, "main", Vec::new(), 300_000_000_000_000).unwrap();
+        assert_eq!(simulation.result, PromiseResult::Failed);
+        assert!(simulation.executions.is_empty());
+    }
+
+    #[test]
+    fn test_time_limit() {
+        let alice: AccountId = "alice".parse().unwrap();
+        let bob: AccountId = "bob".parse().unwrap();
+        let gas = 300_000_000_000_000;
+        let clock = Arc::new(crate::VirtualClock::new());
+        let mut simulator = simulator(test_vm_config().vm_kind);
+        simulator.clock = clock.clone();
+        // The virtual clock stands still, so no receipt times out.
+        simulator.time_limit = Some(Duration::from_nanos(1));
+        let simulation =
+            simulator.call(bob.clone(), alice.clone(), "main", b"get".to_vec(), gas).unwrap();
+        assert_eq!(simulation.result, PromiseResult::Successful(b"hello".to_vec()));
+        assert_eq!(clock.elapsed(), Duration::ZERO);
+
+        simulator.time_limit = Some(Duration::ZERO);
+        let simulation = simulator.call(bob, alice, "main", b"get".to_vec(), gas).unwrap();
+        assert_eq!(simulation.result, PromiseResult::Failed);
+        assert_eq!(
+            simulation.executions[0].outcome.aborted,
+            Some(crate::logic::errors::FunctionCallError::Timeout)
+        );
+    }
+
+    #[test]
+    fn test_distribute_unused_gas() {
+        use crate::logic::{ActionReceipt, ReceiptCost};
+
+        let mut outcome = VMOutcome::nop_outcome(crate::logic::errors::FunctionCallError::Timeout);
+        let call = ReceiptAction::FunctionCall {
+            method_name: b"main".to_vec(),
+            args: vec![],
+            attached_deposit: 0,
+            prepaid_gas: 10,
+            gas_weight: 1,
+        };
+        outcome.receipts = (0..2)
+            .map(|receipt_index| ActionReceipt {
+                receipt_index,
+                receiver_id: "bob".parse().unwrap(),
+                dependencies: vec![],
+                actions: vec![call.clone()],
+            })
+            .collect();
+        let cost = |receipt_index| ReceiptCost {
+            receipt_index,
+            send_gas: 0,
+            exec_gas: 0,
+            prepaid_gas: 10,
+            gas_weight: 1,
+            deposit: 0,
+        };
+        outcome.receipt_costs = vec![cost(0)];
+        // Receipts without a cost get no share, and neither do the others.
+        assert_eq!(distribute_unused_gas(&mut outcome, 100), Err(1));
+        assert_eq!(outcome.receipt_costs, [cost(0)]);
+
+        outcome.receipt_costs.push(cost(1));
+        distribute_unused_gas(&mut outcome, 100).unwrap();
+        let prepaid: Vec<Gas> = outcome.receipt_costs.iter().map(|cost| cost.prepaid_gas).collect();
+        assert_eq!(prepaid, [60, 60]);
+    }
+}
@@ -0,0 +1,6 @@
+//! Helpers for testing contracts against the runner.
+//!
+//! With the `test-support` feature these are available to other crates, e.g.
+//! to the test suites of contracts.
+
+pub mod snapshot;
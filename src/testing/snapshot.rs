@@ -0,0 +1,358 @@
+//! Golden snapshots of everything a call did.
+//!
+//! Checking the result of a call against a one-line summary of its outcome
+//! misses the regressions in what else the call did: the receipts it
+//! created, what it logged, where its gas went and what it wrote.  A
+//! [`Snapshot`] holds all of it, with the storage as the changes the call
+//! made.  [`Snapshot::to_text`] formats it with one fact per line, in a
+//! canonical order, so that snapshots can be checked into test suites and
+//! compared with [`diff`], which lists the lines which differ.  The JSON
+//! form of [`Snapshot::to_json`] carries the same data for other tools.
+
+use crate::logic::error_codes::ErrorParam;
+use crate::logic::errors::FunctionCallError;
+use crate::logic::types::{ActionReceipt, Balance, Gas, ReceiptAction, ReturnData, StorageUsage};
+use crate::logic::{GasProfile, VMOutcome};
+use serde_with::base64::Base64;
+use serde_with::serde_as;
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use unc_primitives_core::serialize::dec_format;
+
+/// The effects of a call, see the [module documentation](self).
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Snapshot {
+    #[serde(with = "dec_format")]
+    pub balance: Balance,
+    pub storage_usage: StorageUsage,
+    pub return_data: ReturnData,
+    pub burnt_gas: Gas,
+    pub used_gas: Gas,
+    pub aborted: Option<FunctionCallError>,
+    pub logs: Vec<String>,
+    pub receipts: Vec<ActionReceipt>,
+    pub gas_profile: Option<GasProfile>,
+    /// The keys the call changed, sorted.
+    pub storage: Vec<StorageChange>,
+}
+
+/// The value of a key before and after a call, `None` when there was none.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct StorageChange {
+    #[serde_as(as = "Base64")]
+    pub key: Vec<u8>,
+    #[serde_as(as = "Option<Base64>")]
+    pub before: Option<Vec<u8>>,
+    #[serde_as(as = "Option<Base64>")]
+    pub after: Option<Vec<u8>>,
+}
+
+impl Snapshot {
+    /// The snapshot of the call which returned `outcome`, with the storage
+    /// `before` and `after` it, e.g. the `fake_trie` of a
+    /// [`crate::logic::mocks::mock_external::MockedExternal`].
+    pub fn new(
+        outcome: &VMOutcome,
+        before: &HashMap<Vec<u8>, Vec<u8>>,
+        after: &HashMap<Vec<u8>, Vec<u8>>,
+    ) -> Self {
+        let mut storage: Vec<StorageChange> = before
+            .keys()
+            .chain(after.keys().filter(|key| !before.contains_key(*key)))
+            .filter(|key| before.get(*key) != after.get(*key))
+            .map(|key| StorageChange {
+                key: key.clone(),
+                before: before.get(key).cloned(),
+                after: after.get(key).cloned(),
+            })
+            .collect();
+        storage.sort_unstable_by(|a, b| a.key.cmp(&b.key));
+        Self {
+            balance: outcome.balance,
+            storage_usage: outcome.storage_usage,
+            return_data: outcome.return_data.clone(),
+            burnt_gas: outcome.burnt_gas,
+            used_gas: outcome.used_gas,
+            aborted: outcome.aborted.clone(),
+            logs: outcome.logs.clone(),
+            receipts: outcome.receipts.clone(),
+            gas_profile: outcome.gas_profile.clone(),
+            storage,
+        }
+    }
+
+    /// The canonical text form of the snapshot, one fact per line.
+    ///
+    /// Byte strings are hex-encoded, strings are JSON strings, errors are
+    /// their stable codes with their parameters, see
+    /// [`FunctionCallError::code`], and actions are named after their variant
+    /// in snake case, followed by their fields.  The gas profile is left out
+    /// when the outcome has none.
+    pub fn to_text(&self) -> String {
+        self.to_string()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("snapshots serialize to JSON")
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "balance {} storage_usage {} return ", self.balance, self.storage_usage)?;
+        match &self.return_data {
+            ReturnData::None => write!(f, "none")?,
+            ReturnData::ReceiptIndex(index) => write!(f, "receipt {index}")?,
+            ReturnData::Value(value) => write!(f, "value {}", Hex(value))?,
+        }
+        writeln!(f, " burnt_gas {} used_gas {}", self.burnt_gas, self.used_gas)?;
+        if let Some(err) = &self.aborted {
+            let code = err.code();
+            write!(f, "aborted {}", code.code)?;
+            for (name, param) in &code.params {
+                match param {
+                    ErrorParam::U64(value) => write!(f, " {name}={value}")?,
+                    ErrorParam::Str(value) => write!(f, " {name}={}", Json(value))?,
+                }
+            }
+            writeln!(f)?;
+        }
+        for log in &self.logs {
+            writeln!(f, "log {}", Json(log))?;
+        }
+        for receipt in &self.receipts {
+            write!(f, "receipt {}: {} after", receipt.receipt_index, receipt.receiver_id)?;
+            for dependency in &receipt.dependencies {
+                write!(f, " {dependency}")?;
+            }
+            writeln!(f)?;
+            for action in &receipt.actions {
+                write!(f, "  ")?;
+                write_action(f, action)?;
+                writeln!(f)?;
+            }
+        }
+        if let Some(profile) = &self.gas_profile {
+            writeln!(f, "gas loading {} wasm_ops {}", profile.loading, profile.wasm_ops)?;
+            for (name, host) in &profile.host_functions {
+                writeln!(f, "gas {name}: {} calls {} gas", host.calls, host.gas)?;
+            }
+        }
+        for change in &self.storage {
+            let key = Hex(&change.key);
+            match (&change.before, &change.after) {
+                (None, Some(after)) => writeln!(f, "storage + {key}: {}", Hex(after))?,
+                (Some(before), None) => writeln!(f, "storage - {key}: {}", Hex(before))?,
+                (Some(before), Some(after)) => {
+                    writeln!(f, "storage ~ {key}: {} -> {}", Hex(before), Hex(after))?
+                }
+                (None, None) => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes `action` as its variant in snake case followed by its fields, in
+/// the order of their declaration.
+fn write_action(f: &mut fmt::Formatter<'_>, action: &ReceiptAction) -> fmt::Result {
+    match action {
+        ReceiptAction::CreateAccount => write!(f, "create_account"),
+        ReceiptAction::DeployContract { code_hash } => write!(f, "deploy_contract {code_hash}"),
+        ReceiptAction::FunctionCall {
+            method_name,
+            args,
+            attached_deposit,
+            prepaid_gas,
+            gas_weight,
+        } => write!(
+            f,
+            "function_call {} args {} deposit {attached_deposit} gas {prepaid_gas} weight \
+             {gas_weight}",
+            Hex(method_name),
+            Hex(args)
+        ),
+        ReceiptAction::Transfer { deposit } => write!(f, "transfer {deposit}"),
+        ReceiptAction::Stake { stake, public_key } => write!(f, "stake {stake} {public_key}"),
+        ReceiptAction::AddFullAccessKey { public_key, nonce } => {
+            write!(f, "add_full_access_key {public_key} nonce {nonce}")
+        }
+        ReceiptAction::AddFunctionCallKey {
+            public_key,
+            nonce,
+            allowance,
+            receiver_id,
+            method_names,
+        } => {
+            write!(f, "add_function_call_key {public_key} nonce {nonce} allowance ")?;
+            match allowance {
+                Some(allowance) => write!(f, "{allowance}")?,
+                None => write!(f, "none")?,
+            }
+            write!(f, " receiver {receiver_id} methods")?;
+            method_names.iter().try_for_each(|name| write!(f, " {}", Hex(name)))
+        }
+        ReceiptAction::DeleteKey { public_key } => write!(f, "delete_key {public_key}"),
+        ReceiptAction::DeleteAccount { beneficiary_id } => {
+            write!(f, "delete_account {beneficiary_id}")
+        }
+        ReceiptAction::DeployGlobalContract { code_hash } => {
+            write!(f, "deploy_global_contract {code_hash}")
+        }
+    }
+}
+
+/// A string as a JSON string, escaped the same way whatever the version of
+/// Rust.
+struct Json<'a>(&'a str);
+
+impl fmt::Display for Json<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&serde_json::to_string(self.0).expect("strings serialize to JSON"))
+    }
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.iter().try_for_each(|byte| write!(f, "{byte:02x}"))
+    }
+}
+
+/// The lines of the text forms of `expected` and `actual` which differ, as
+/// `-` lines only in the first and `+` lines only in the second, or `None`
+/// if the snapshots are the same.
+pub fn diff(expected: &Snapshot, actual: &Snapshot) -> Option<String> {
+    if expected == actual {
+        return None;
+    }
+    let (expected, actual) = (expected.to_text(), actual.to_text());
+    let expected: Vec<&str> = expected.lines().collect();
+    let actual: Vec<&str> = actual.lines().collect();
+    // `common[i][j]` is the length of the longest common subsequence of
+    // `expected[i..]` and `actual[j..]`.
+    let mut common = vec![vec![0usize; actual.len() + 1]; expected.len() + 1];
+    for i in (0..expected.len()).rev() {
+        for j in (0..actual.len()).rev() {
+            common[i][j] = if expected[i] == actual[j] {
+                common[i + 1][j + 1] + 1
+            } else {
+                common[i + 1][j].max(common[i][j + 1])
+            };
+        }
+    }
+    let mut out = String::new();
+    let (mut i, mut j) = (0, 0);
+    while i < expected.len() || j < actual.len() {
+        if i < expected.len() && j < actual.len() && expected[i] == actual[j] {
+            i += 1;
+            j += 1;
+        } else if j == actual.len() || (i < expected.len() && common[i + 1][j] >= common[i][j + 1])
+        {
+            writeln!(out, "- {}", expected[i]).unwrap();
+            i += 1;
+        } else {
+            writeln!(out, "+ {}", actual[j]).unwrap();
+            j += 1;
+        }
+    }
+    // Snapshots which only differ in what the text leaves out, e.g. a missing
+    // gas profile against an empty one, still differ.
+    if out.is_empty() {
+        out.push_str("the snapshots only differ in their JSON form\n");
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::errors::HostError;
+    use crate::logic::types::ReceiptAction;
+    use crate::logic::HostFunctionGas;
+
+    fn outcome() -> VMOutcome {
+        let mut outcome =
+            VMOutcome::nop_outcome(FunctionCallError::HostError(HostError::GuestPanic {
+                panic_msg: "oops".to_string(),
+            }));
+        outcome.balance = 10;
+        outcome.burnt_gas = 300;
+        outcome.used_gas = 500;
+        outcome.logs = vec!["hello".to_string()];
+        outcome.receipts = vec![ActionReceipt {
+            receipt_index: 0,
+            receiver_id: "bob".parse().unwrap(),
+            dependencies: vec![],
+            actions: vec![ReceiptAction::Transfer { deposit: 1 }],
+        }];
+        let mut profile = GasProfile { loading: 100, wasm_ops: 150, ..GasProfile::default() };
+        profile.host_functions.insert("log_utf8".into(), HostFunctionGas { calls: 1, gas: 50 });
+        outcome.gas_profile = Some(profile);
+        outcome
+    }
+
+    fn storage(entries: &[(&[u8], &[u8])]) -> HashMap<Vec<u8>, Vec<u8>> {
+        entries.iter().map(|(key, value)| (key.to_vec(), value.to_vec())).collect()
+    }
+
+    #[test]
+    fn test_snapshot_text() {
+        let before = storage(&[(b"a", b"1"), (b"b", b"2"), (b"c", b"3")]);
+        let after = storage(&[(b"a", b"1"), (b"b", b"4"), (b"d", b"5")]);
+        let snapshot = Snapshot::new(&outcome(), &before, &after);
+        assert_eq!(
+            snapshot.to_text(),
+            "\
+balance 10 storage_usage 0 return none burnt_gas 300 used_gas 500
+aborted host.guest_panic panic_msg=\"oops\"
+log \"hello\"
+receipt 0: bob after
+  transfer 1
+gas loading 100 wasm_ops 150
+gas log_utf8: 1 calls 50 gas
+storage ~ 62: 32 -> 34
+storage - 63: 33
+storage + 64: 35
+"
+        );
+        let json = snapshot.to_json();
+        assert_eq!(Snapshot::from_json(&json).unwrap(), snapshot);
+    }
+
+    #[test]
+    fn test_snapshot_diff() {
+        let empty = HashMap::new();
+        let expected = Snapshot::new(&outcome(), &empty, &storage(&[(b"a", b"1")]));
+        assert_eq!(
+            diff(&expected, &Snapshot::new(&outcome(), &empty, &storage(&[(b"a", b"1")]))),
+            None
+        );
+
+        let mut changed = outcome();
+        changed.logs.push("again".to_string());
+        changed.receipts.clear();
+        let actual = Snapshot::new(&changed, &empty, &storage(&[(b"a", b"2")]));
+        assert_eq!(
+            diff(&expected, &actual).unwrap(),
+            "\
+- receipt 0: bob after
+-   transfer 1
++ log \"again\"
+- storage + 61: 31
++ storage + 61: 32
+"
+        );
+
+        let mut unprofiled = outcome();
+        unprofiled.gas_profile = None;
+        let unprofiled = Snapshot::new(&unprofiled, &empty, &storage(&[(b"a", b"1")]));
+        assert_eq!(diff(&expected, &unprofiled).unwrap().lines().count(), 2);
+    }
+}
@@ -1,9 +1,17 @@
 mod cache;
+mod codegen;
 mod compile_errors;
+mod cpu_features;
+mod error_messages;
+mod floats;
 mod fuzzers;
+mod method_names;
 mod regression_tests;
 mod rs_contract;
+mod run_many;
 mod runtime_errors;
+mod serialization;
+mod storage;
 pub(crate) mod test_builder;
 mod ts_contract;
 mod wasm_validation;
@@ -18,17 +26,15 @@ const SIGNER_ACCOUNT_ID: &str = "bob";
 const SIGNER_ACCOUNT_PK: [u8; 3] = [0, 1, 2];
 const PREDECESSOR_ACCOUNT_ID: &str = "carol";
 
-pub(crate) fn test_vm_config() -> unc_parameters::vm::Config {
+pub(crate) fn test_vm_config() -> Config {
     let store = RuntimeConfigStore::test();
-    let config = store.get_config(PROTOCOL_VERSION).wasm_config.clone();
-    unc_parameters::vm::Config {
-        vm_kind: config.vm_kind.replace_with_wasmtime_if_unsupported(),
-        ..config
-    }
+    let mut config = Config::from(store.get_config(PROTOCOL_VERSION).wasm_config.clone());
+    config.vm_kind = config.vm_kind.replace_with_wasmtime_if_unsupported();
+    config
 }
 
 pub(crate) fn with_vm_variants(
-    #[allow(unused)] cfg: &unc_parameters::vm::Config,
+    #[allow(unused)] cfg: &crate::logic::Config,
     runner: impl Fn(VMKind) -> (),
 ) {
     #[cfg(all(feature = "wasmer0_vm", target_arch = "x86_64"))]
@@ -47,7 +53,7 @@ pub(crate) fn with_vm_variants(
     }
 }
 
-fn create_context(input: Vec<u8>) -> VMContext {
+pub(crate) fn create_context(input: Vec<u8>) -> VMContext {
     VMContext {
         current_account_id: CURRENT_ACCOUNT_ID.parse().unwrap(),
         signer_account_id: SIGNER_ACCOUNT_ID.parse().unwrap(),
@@ -9,8 +9,8 @@ use crate::logic::{CompiledContract, CompiledContractCache};
 use crate::runner::VMKindExt;
 use crate::runner::VMResult;
 use crate::wasmer2_runner::Wasmer2VM;
-use crate::ContractCode;
-use crate::{prepare, MockCompiledContractCache};
+use crate::{ContractCode, HostSettings};
+use crate::{prepare, FilesystemContractRuntimeCache, MockCompiledContractCache};
 use assert_matches::assert_matches;
 use unc_parameters::vm::VMKind;
 use unc_parameters::RuntimeFeesConfig;
@@ -24,10 +24,6 @@ use wasmer_engine::Executable;
 fn test_caches_compilation_error() {
     let config = test_vm_config();
     with_vm_variants(&config, |vm_kind: VMKind| {
-        match vm_kind {
-            VMKind::Wasmer0 | VMKind::Wasmer2 | VMKind::NearVm => {}
-            VMKind::Wasmtime => return,
-        }
         let cache = MockCompiledContractCache::default();
         let code = [42; 1000];
         let terragas = 1000000000000u64;
@@ -48,11 +44,6 @@ fn test_caches_compilation_error() {
 fn test_does_not_cache_io_error() {
     let config = test_vm_config();
     with_vm_variants(&config, |vm_kind: VMKind| {
-        match vm_kind {
-            VMKind::Wasmer0 | VMKind::Wasmer2 | VMKind::NearVm => {}
-            VMKind::Wasmtime => return,
-        }
-
         let code = unc_test_contracts::trivial_contract();
         let prepaid_gas = 10u64.pow(12);
         let mut cache = FaultingCompiledContractCache::default();
@@ -75,6 +66,48 @@ fn test_does_not_cache_io_error() {
     })
 }
 
+#[test]
+#[cfg(feature = "wasmtime_vm")]
+fn test_wasmtime_loads_cached_artifact() {
+    let mut config = test_vm_config();
+    config.vm_kind = VMKind::Wasmtime;
+    let code = unc_test_contracts::trivial_contract();
+    let prepaid_gas = 10u64.pow(12);
+    let cache = MockCompiledContractCache::default();
+    let call = || {
+        make_cached_contract_call_vm(&config, &cache, code, "main", prepaid_gas, VMKind::Wasmtime)
+    };
+    let compiled = call().unwrap();
+    assert_eq!(cache.len(), 1);
+    let loaded = call().unwrap();
+    assert_eq!(loaded.aborted, None);
+    assert_eq!(loaded.burnt_gas, compiled.burnt_gas);
+
+    // Artifacts not serialized by wasmtime are compiled again rather than
+    // loaded.
+    let contract = ContractCode::new(code.to_vec(), None);
+    let key = crate::get_contract_cache_key(&contract, &config);
+    cache.put(&key, CompiledContract::Code(vec![42; 100])).unwrap();
+    assert_eq!(call().unwrap().burnt_gas, compiled.burnt_gas);
+    assert_matches!(cache.get(&key), Ok(Some(CompiledContract::Code(code))) if code.len() != 100);
+
+    // Each optimization level gets its own key, and optimizing VMs load the
+    // artifacts compiled at deploy time until they have their own.
+    use crate::{CompileOptions, OptLevel};
+    let cache = MockCompiledContractCache::default();
+    let fast = CompileOptions { opt_level: OptLevel::Fast, ..CompileOptions::default() };
+    crate::precompile_contract_with_options(&contract, &config, fast, Some(&cache))
+        .unwrap()
+        .unwrap();
+    let fast_key = crate::cache::get_contract_cache_key_with_options(&contract, &config, fast);
+    assert_ne!(fast_key, key);
+    assert!(!cache.has(&key).unwrap());
+    let loaded =
+        make_cached_contract_call_vm(&config, &cache, code, "main", prepaid_gas, VMKind::Wasmtime);
+    assert_eq!(loaded.unwrap().burnt_gas, compiled.burnt_gas);
+    assert_eq!(cache.len(), 1);
+}
+
 fn make_cached_contract_call_vm(
     config: &Config,
     cache: &dyn CompiledContractCache,
@@ -141,7 +174,7 @@ fn test_wasmer2_artifact_output_stability() {
         features.insert(CpuFeature::AVX);
         let triple = "x86_64-unknown-linux-gnu".parse().unwrap();
         let target = Target::new(triple, features);
-        let vm = Wasmer2VM::new_for_target(config, target);
+        let vm = Wasmer2VM::new_for_target(config, target, HostSettings::default());
         let artifact = vm.compile_uncached(&contract).unwrap();
         let serialized = artifact.serialize().unwrap();
         let this_hash = crate::utils::stable_hash(&serialized);
@@ -212,7 +245,7 @@ fn test_unc_vm_artifact_output_stability() {
         features.insert(CpuFeature::AVX);
         let triple = "x86_64-unknown-linux-gnu".parse().unwrap();
         let target = Target::new(triple, features);
-        let vm = NearVM::new_for_target(config, target);
+        let vm = NearVM::new_for_target(config, target, HostSettings::default());
         let artifact = vm.compile_uncached(&contract).unwrap();
         let serialized = artifact.serialize().unwrap();
         let this_hash = crate::utils::stable_hash(&serialized);
@@ -241,6 +274,273 @@ fn test_unc_vm_artifact_output_stability() {
     // can be adjusted.
 }
 
+#[test]
+fn test_baseline_codegen_is_portable() {
+    use crate::unc_vm_runner::NearVM;
+    use crate::CodegenTarget;
+
+    // VMs restricted to the baseline features simulate the least capable validator. Artifacts
+    // compiled with the baseline codegen on this host must be identical to those compiled there
+    // and load there.
+    let config = test_vm_config();
+    for seed in [2, 3, 5] {
+        let contract = ContractCode::new(unc_test_contracts::arbitrary_contract(seed), None);
+        {
+            let mut features = CpuFeature::set();
+            features.insert(CpuFeature::SSE2);
+            features.insert(CpuFeature::SSE3);
+            features.insert(CpuFeature::SSSE3);
+            features.insert(CpuFeature::SSE41);
+            features.insert(CpuFeature::SSE42);
+            features.insert(CpuFeature::POPCNT);
+            features.insert(CpuFeature::AVX);
+            let triple = "x86_64-unknown-linux-gnu".parse().unwrap();
+            let target = Target::new(triple, features);
+            let simulator =
+                Wasmer2VM::new_for_target(config.clone(), target, HostSettings::default());
+            let vm = Wasmer2VM::new_with_codegen(
+                config.clone(),
+                CodegenTarget::Baseline,
+                HostSettings::default(),
+            );
+            let artifact = vm.compile_uncached(&contract).unwrap();
+            let expected = simulator.compile_uncached(&contract).unwrap();
+            assert_eq!(artifact.serialize().unwrap(), expected.serialize().unwrap());
+            simulator.engine.load_universal_executable(&artifact).unwrap();
+        }
+        {
+            use unc_vm_compiler::{CpuFeature, Target};
+            let mut features = CpuFeature::set();
+            features.insert(CpuFeature::SSE2);
+            features.insert(CpuFeature::SSE3);
+            features.insert(CpuFeature::SSSE3);
+            features.insert(CpuFeature::SSE41);
+            features.insert(CpuFeature::SSE42);
+            features.insert(CpuFeature::POPCNT);
+            features.insert(CpuFeature::AVX);
+            let triple = "x86_64-unknown-linux-gnu".parse().unwrap();
+            let target = Target::new(triple, features);
+            let simulator = NearVM::new_for_target(config.clone(), target, HostSettings::default());
+            let vm = NearVM::new_with_codegen(
+                config.clone(),
+                CodegenTarget::Baseline,
+                HostSettings::default(),
+            );
+            let artifact = vm.compile_uncached(&contract).unwrap();
+            let expected = simulator.compile_uncached(&contract).unwrap();
+            assert_eq!(artifact.serialize().unwrap(), expected.serialize().unwrap());
+            simulator.engine.load_universal_executable(&artifact).unwrap();
+        }
+    }
+
+    // Baseline artifacts are never confused with the artifacts generated for the host.
+    let contract = ContractCode::new(unc_test_contracts::arbitrary_contract(2), None);
+    assert_ne!(
+        crate::get_contract_cache_key(&contract, &config),
+        crate::cache::contract_cache_key(&contract, &config, CodegenTarget::Baseline),
+    );
+}
+
+#[test]
+fn test_run_baseline_artifacts() {
+    use crate::{CodegenTarget, RunOptions};
+
+    let mut config = test_vm_config();
+    let code = ContractCode::new(unc_test_contracts::trivial_contract().to_vec(), None);
+    for vm_kind in [VMKind::Wasmer2, VMKind::NearVm] {
+        config.vm_kind = vm_kind;
+        let cache = MockCompiledContractCache::default();
+        crate::precompile_contract_for_codegen(
+            &code,
+            &config,
+            CodegenTarget::Baseline,
+            Some(&cache),
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(cache.len(), 1);
+        // Runs with the baseline codegen load the precompiled artifact instead
+        // of compiling the contract again for the host.
+        let options = RunOptions { codegen: CodegenTarget::Baseline, ..RunOptions::default() };
+        let outcome = crate::run_with_options(
+            &code,
+            "main",
+            &mut MockedExternal::new(),
+            create_context(vec![]),
+            &config,
+            &RuntimeFeesConfig::test(),
+            &[],
+            Some(&cache),
+            &options,
+        )
+        .unwrap();
+        assert_eq!(outcome.aborted, None, "{vm_kind:?}");
+        assert_eq!(cache.len(), 1, "{vm_kind:?}");
+    }
+}
+
+#[test]
+fn test_filesystem_cache() {
+    let dir = std::env::temp_dir().join(format!("unc-vm-runner-cache-{}", std::process::id()));
+    let key = CryptoHash::hash_bytes(b"contract");
+    let cache = FilesystemContractRuntimeCache::new(&dir).unwrap();
+    assert_matches!(cache.get(&key), Ok(None));
+    assert!(!cache.has(&key).unwrap());
+
+    cache.put(&key, CompiledContract::Code(vec![1, 2, 3])).unwrap();
+    assert_matches!(cache.get(&key), Ok(Some(CompiledContract::Code(code))) if code == [1, 2, 3]);
+    assert!(cache.has(&key).unwrap());
+
+    // Artifacts survive reopening the cache.
+    let cache = FilesystemContractRuntimeCache::new(&dir).unwrap();
+    assert_matches!(cache.get(&key), Ok(Some(CompiledContract::Code(code))) if code == [1, 2, 3]);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_filesystem_cache_evicts_least_recently_used() {
+    let dir = std::env::temp_dir().join(format!("unc-vm-runner-lru-{}", std::process::id()));
+    let keys: Vec<_> = (0u8..3).map(|i| CryptoHash::hash_bytes(&[i])).collect();
+    let artifact = || CompiledContract::Code(vec![0; 100]);
+    let entry_size = borsh::to_vec(&artifact()).unwrap().len() as u64;
+    let cache = FilesystemContractRuntimeCache::with_max_size(&dir, 2 * entry_size).unwrap();
+    cache.put(&keys[0], artifact()).unwrap();
+    cache.put(&keys[1], artifact()).unwrap();
+    // Overwriting an entry does not count it twice.
+    cache.put(&keys[1], artifact()).unwrap();
+    assert_eq!(cache.size(), Some(2 * entry_size));
+
+    // Reading the first entry makes the second one the least recently used.
+    assert!(cache.get(&keys[0]).unwrap().is_some());
+    cache.put(&keys[2], artifact()).unwrap();
+    assert!(cache.has(&keys[0]).unwrap());
+    assert!(!cache.has(&keys[1]).unwrap());
+    assert!(cache.has(&keys[2]).unwrap());
+    assert_eq!(cache.size(), Some(2 * entry_size));
+
+    // Reopening with a lower limit evicts down to it.
+    let cache = FilesystemContractRuntimeCache::with_max_size(&dir, entry_size).unwrap();
+    assert_eq!(cache.size(), Some(entry_size));
+    assert_eq!(keys.iter().filter(|key| cache.has(key).unwrap()).count(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_precompile_reports_compilation_info() {
+    use crate::errors::ContractPrecompilatonResult;
+    use crate::{CompilationPass, CompileOptions, Compiler, OptLevel};
+
+    let code = ContractCode::new(unc_test_contracts::trivial_contract().to_vec(), None);
+    let config = test_vm_config();
+    with_vm_variants(&config, |vm_kind| {
+        let mut config = config.clone();
+        config.vm_kind = vm_kind;
+        let cache = MockCompiledContractCache::default();
+        let options = CompileOptions { opt_level: OptLevel::Fast, ..CompileOptions::default() };
+        let result = crate::precompile_contract_with_options(&code, &config, options, Some(&cache))
+            .unwrap()
+            .unwrap();
+        let info = match result {
+            ContractPrecompilatonResult::ContractCompiled(info) => info,
+            other => panic!("{vm_kind:?}: unexpected {other:?}"),
+        };
+        let compiler =
+            if vm_kind == VMKind::Wasmtime { Compiler::Cranelift } else { Compiler::Singlepass };
+        assert_eq!(info.compiler, compiler);
+        assert_eq!(info.opt_level, OptLevel::Fast);
+        assert_eq!(info.passes.last(), Some(&CompilationPass::Codegen(compiler)));
+        let names: Vec<String> = info.passes.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            names,
+            ["validate", "gas_metering", "stack_limit", &format!("{compiler}_codegen")]
+        );
+        let key = crate::cache::get_contract_cache_key_with_options(&code, &config, options);
+        assert_eq!(cache.get_compilation_info(&key).unwrap(), Some(info));
+    });
+
+    // The filesystem cache keeps the info next to the artifact.
+    let dir = std::env::temp_dir().join(format!("unc-vm-runner-info-{}", std::process::id()));
+    let cache = FilesystemContractRuntimeCache::new(&dir).unwrap();
+    let key = CryptoHash::hash_bytes(b"contract");
+    let info = crate::CompilationInfo::singlepass(std::time::Duration::from_millis(3));
+    assert_matches!(cache.get_compilation_info(&key), Ok(None));
+    cache.put_compilation_info(&key, &info).unwrap();
+    assert_eq!(cache.get_compilation_info(&key).unwrap(), Some(info));
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_prefetching_cache() {
+    use crate::PrefetchingContractCache;
+    use std::sync::Arc;
+
+    let config = test_vm_config();
+    let codes: Vec<_> = (0..3u8).map(|i| ContractCode::new(vec![i; 10], None)).collect();
+    let inner = Arc::new(MockCompiledContractCache::default());
+    for code in &codes[..2] {
+        let key = crate::get_contract_cache_key(code, &config);
+        inner.put(&key, CompiledContract::Code(code.code().to_vec())).unwrap();
+    }
+    // Room for one of the two cached artifacts, the third is not cached.
+    let cache = PrefetchingContractCache::new(inner, config.clone(), 15);
+    let prefetched = cache.prefetch(codes.iter().map(|code| *code.hash())).join().unwrap();
+    assert_eq!(prefetched, 1);
+    assert_eq!(cache.prefetched_bytes(), 10);
+    for code in &codes[..2] {
+        let key = crate::get_contract_cache_key(code, &config);
+        assert_matches!(cache.get(&key), Ok(Some(CompiledContract::Code(c))) if c == code.code());
+    }
+
+    // The next prefetch drops the artifacts of the previous one.
+    let prefetched = cache.prefetch([*codes[1].hash()]).join().unwrap();
+    assert_eq!(prefetched, 1);
+    assert_eq!(cache.prefetched_bytes(), 10);
+    let prefetched = cache.prefetch([]).join().unwrap();
+    assert_eq!(prefetched, 0);
+    assert_eq!(cache.prefetched_bytes(), 0);
+}
+
+#[test]
+fn test_prefetching_cache_threads() {
+    use crate::PrefetchingContractCache;
+    use std::sync::Arc;
+
+    let config = test_vm_config();
+    let codes: Vec<_> = (0..32u8).map(|i| ContractCode::new(vec![i; 10], None)).collect();
+    let inner = Arc::new(MockCompiledContractCache::default());
+    for code in &codes {
+        let key = crate::get_contract_cache_key(code, &config);
+        inner.put(&key, CompiledContract::Code(code.code().to_vec())).unwrap();
+    }
+    // The budget holds exactly 20 of the artifacts, however the reads race.
+    let cache = PrefetchingContractCache::new(inner, config, 200).with_threads(4);
+    let prefetched = cache.prefetch(codes.iter().map(|code| *code.hash())).join().unwrap();
+    assert_eq!(prefetched, 20);
+    assert_eq!(cache.prefetched_bytes(), 200);
+}
+
+#[test]
+fn test_prefetching_cache_put() {
+    use crate::PrefetchingContractCache;
+    use std::sync::Arc;
+
+    let config = test_vm_config();
+    let code = ContractCode::new(vec![1; 10], None);
+    let key = crate::get_contract_cache_key(&code, &config);
+    let inner = Arc::new(MockCompiledContractCache::default());
+    inner.put(&key, CompiledContract::Code(code.code().to_vec())).unwrap();
+    // A contract called twice in the chunk takes its room in the budget once.
+    let cache = PrefetchingContractCache::new(inner, config, 15).with_threads(2);
+    let prefetched = cache.prefetch([*code.hash(), *code.hash()]).join().unwrap();
+    assert_eq!(prefetched, 1);
+    assert_eq!(cache.prefetched_bytes(), 10);
+
+    // Writes replace the prefetched artifact.
+    cache.put(&key, CompiledContract::Code(vec![2; 5])).unwrap();
+    assert_eq!(cache.prefetched_bytes(), 0);
+    assert_matches!(cache.get(&key), Ok(Some(CompiledContract::Code(c))) if c == [2; 5]);
+}
+
 /// [`CompiledContractCache`] which simulates failures in the underlying
 /// database.
 #[derive(Default)]
@@ -275,3 +575,53 @@ impl CompiledContractCache for FaultingCompiledContractCache {
         self.inner.get(key)
     }
 }
+
+#[test]
+fn test_precompile_contracts() {
+    use crate::errors::ContractPrecompilatonResult;
+    use crate::logic::errors::CompilationError;
+
+    let codes = [
+        ContractCode::new(unc_test_contracts::trivial_contract().to_vec(), None),
+        ContractCode::new(b"not wasm".to_vec(), None),
+        ContractCode::new(
+            wat::parse_str(r#"(module (memory 1) (func (export "main")))"#).unwrap(),
+            None,
+        ),
+    ];
+    let config = test_vm_config();
+    with_vm_variants(&config, |vm_kind| {
+        let mut config = config.clone();
+        config.vm_kind = vm_kind;
+        let cache = MockCompiledContractCache::default();
+        let results = crate::precompile_contracts(&codes, &config, &cache);
+        assert_eq!(results.len(), codes.len());
+        assert_matches!(results[0], Ok(Ok(ContractPrecompilatonResult::ContractCompiled(_))));
+        assert_matches!(results[1], Ok(Err(CompilationError::PrepareError(_))));
+        assert_matches!(results[2], Ok(Ok(ContractPrecompilatonResult::ContractCompiled(_))));
+        for code in &codes {
+            assert!(cache.has(&crate::get_contract_cache_key(code, &config)).unwrap());
+        }
+
+        let results = crate::precompile_contracts(&codes, &config, &cache);
+        assert_matches!(results[0], Ok(Ok(ContractPrecompilatonResult::ContractAlreadyInCache)));
+        assert_matches!(results[1], Ok(Ok(ContractPrecompilatonResult::ContractAlreadyInCache)));
+        assert_matches!(results[2], Ok(Ok(ContractPrecompilatonResult::ContractAlreadyInCache)));
+    });
+}
+
+#[test]
+fn test_cache_key_of_base_config() {
+    let code = ContractCode::new(b"code".to_vec(), None);
+    let config = test_vm_config();
+    let key = crate::get_contract_cache_key(&code, &config);
+    // The artifacts of the configs of `unc-parameters` keep their keys.
+    let base = Config::from(config.base.clone());
+    assert_eq!(crate::get_contract_cache_key(&code, &base), key);
+    let mut changed = base.clone();
+    changed.host_imported_memory = !changed.host_imported_memory;
+    assert_ne!(crate::get_contract_cache_key(&code, &changed), key);
+    let mut changed = base;
+    changed.limit_config.max_memory_pages += 1;
+    assert_ne!(crate::get_contract_cache_key(&code, &changed), key);
+}
@@ -0,0 +1,204 @@
+//! Codegen corner cases of NearVM, cross-checked against Wasmtime.
+//!
+//! NearVM compiles contracts in a single pass, and its bugs hide where the
+//! encoding or the bookkeeping of the compiler changes with the size of what
+//! it compiles: immediates which do not fit a short encoding, frames with more
+//! locals than registers, control stacks thousands of blocks deep, jump
+//! tables with thousands of entries.  Generic corpora rarely get there, so
+//! these tests generate contracts which do, run them on NearVM and Wasmtime,
+//! and check that both VMs agree with each other, gas included, and with the
+//! result computed here.
+
+use crate::differential::{run_all_kinds_with_config, VMRun};
+use crate::logic::errors::{FunctionCallError, WasmTrap};
+use crate::logic::ReturnData;
+use crate::tests::{create_context, test_vm_config};
+use crate::ContractCode;
+use std::fmt::Write;
+use unc_parameters::vm::VMKind;
+use unc_parameters::RuntimeFeesConfig;
+
+/// Functions shared by the contracts: `$arg` reads the first 4 bytes of
+/// the input and `$return` returns an `i64`.
+const PRELUDE: &str = r#"
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (memory 1)
+  (func $arg (result i32)
+    (call $input (i64.const 0))
+    (call $read_register (i64.const 0) (i64.const 0))
+    (i32.load (i32.const 0)))
+  (func $return (param i64)
+    (i64.store (i32.const 0) (local.get 0))
+    (call $value_return (i64.const 8) (i64.const 0)))"#;
+
+/// Runs `method` of the contract made of the prelude and `body` on NearVM and
+/// Wasmtime, checks that they agree and returns the run of the first one.
+#[track_caller]
+fn run(body: &str, method: &str, input: u32) -> VMRun {
+    let wat = format!("(module {PRELUDE}\n{body})");
+    let code = ContractCode::new(wat::parse_str(&wat).unwrap(), None);
+    let context = create_context(input.to_le_bytes().to_vec());
+    let config = test_vm_config();
+    let fees = RuntimeFeesConfig::test();
+    let mut report = run_all_kinds_with_config(&code, method, &context, &config, &fees);
+    report.runs.retain(|run| matches!(run.vm_kind, VMKind::NearVm | VMKind::Wasmtime));
+    report.assert_agree();
+    report.runs.into_iter().next().expect("tests run with NearVM or Wasmtime")
+}
+
+/// The value `method` returns with `input`.
+#[track_caller]
+fn returned(body: &str, method: &str, input: u32) -> Vec<u8> {
+    let run = run(body, method, input);
+    let outcome = run.result.unwrap();
+    assert_eq!(outcome.aborted, None, "{:?}", run.vm_kind);
+    match outcome.return_data {
+        ReturnData::Value(value) => value,
+        other => panic!("{:?} returned {other:?}", run.vm_kind),
+    }
+}
+
+#[track_caller]
+fn returned_i64(body: &str, method: &str, input: u32) -> i64 {
+    i64::from_le_bytes(returned(body, method, input).try_into().unwrap())
+}
+
+#[test]
+fn test_huge_immediates() {
+    let cases: &[(&str, i64)] = &[
+        ("(i64.add (i64.const 0x7fffffffffffffff) (i64.const 1))", i64::MAX.wrapping_add(1)),
+        (
+            "(i64.mul (i64.const 0x0123456789abcdef) (i64.const -0x2000000000000001))",
+            0x0123456789abcdef_i64.wrapping_mul(-0x2000000000000001),
+        ),
+        ("(i64.sub (i64.const 0) (i64.const 0x8000000000000000))", i64::MIN),
+        ("(i64.xor (i64.const -0x8000000000000000) (i64.const 0x7fffffffffffffff))", -1),
+        ("(i64.and (i64.const -1) (i64.const 0x7fffffff80000000))", 0x7fffffff80000000),
+        // Shift counts are taken modulo the width of the operand.
+        ("(i64.shl (i64.const 1) (i64.const 0xffffffffffffff3f))", i64::MIN),
+        ("(i64.rotr (i64.const 0x0123456789abcdef) (i64.const -4))", 0x123456789abcdef0),
+        ("(i64.extend_i32_u (i32.shr_u (i32.const 0x80000000) (i32.const 33)))", 0x40000000),
+        ("(i64.extend_i32_s (i32.add (i32.const 0x7fffffff) (i32.const 1)))", i32::MIN as i64),
+        (
+            "(i64.extend_i32_u (i32.mul (i32.const 0xdeadbeef) (i32.const 0x9e3779b9)))",
+            0xdeadbeef_u32.wrapping_mul(0x9e3779b9) as i64,
+        ),
+        ("(i64.extend_i32_u (i32.lt_u (i32.const 0x80000000) (i32.const 0x7fffffff)))", 0),
+        ("(i64.div_s (i64.const 0x8000000000000000) (i64.const -0x7fffffffffffffff))", 1),
+        ("(i64.rem_u (i64.const -1) (i64.const 0x100000003))", (u64::MAX % 0x100000003) as i64),
+        ("(i64.reinterpret_f64 (f64.const 0x1.fffffffffffffp+1023))", f64::MAX.to_bits() as i64),
+        // An offset too large for a short encoding, at the end of the memory.
+        (
+            "(block (result i64)
+               (i64.store offset=65528 (i32.const 0) (i64.const 0x1122334455667788))
+               (i64.load offset=65528 (i32.const 0)))",
+            0x1122334455667788,
+        ),
+    ];
+    let mut body = String::from("(func (export \"main\")\n");
+    for (index, (expr, _)) in cases.iter().enumerate() {
+        writeln!(body, "(i64.store offset={} (i32.const 0) {expr})", index * 8).unwrap();
+    }
+    writeln!(body, "(call $value_return (i64.const {}) (i64.const 0)))", cases.len() * 8).unwrap();
+    // The effective address of an access is computed on 33 bits: wrapping it
+    // to 32 would read the start of the memory.
+    body.push_str(
+        r#"(func (export "offset_overflow") (drop (i64.load offset=0xfffffff8 (i32.const 16))))
+           (func (export "offset_max") (drop (i64.load8_u offset=0xffffffff (i32.const 0))))"#,
+    );
+
+    let expected: Vec<u8> = cases.iter().flat_map(|(_, value)| value.to_le_bytes()).collect();
+    assert_eq!(returned(&body, "main", 0), expected);
+    for method in ["offset_overflow", "offset_max"] {
+        let vm_run = run(&body, method, 0);
+        assert_eq!(
+            vm_run.result.unwrap().aborted,
+            Some(FunctionCallError::WasmTrap(WasmTrap::MemoryOutOfBounds)),
+            "{method} on {:?}",
+            vm_run.vm_kind
+        );
+    }
+}
+
+#[test]
+fn test_max_locals() {
+    let limits = test_vm_config().limit_config;
+    // As many `i64` locals as the stack limit lets `main` have, leaving room
+    // for its operands and the locals of the prelude.
+    let locals = (u64::from(limits.max_stack_height) - 1024) / 8;
+    let locals = locals.min(limits.max_locals_per_contract.unwrap_or(u64::MAX) - 16);
+    let value = |local: u64| (local as i64 + 1).wrapping_mul(0x9e3779b97f4a7c15_u64 as i64);
+    let set: Vec<u64> =
+        (0..16).chain((0..locals).step_by(1000)).chain(locals - 16..locals).collect();
+    // Locals start at zero: reading ones never set must give zero.
+    let unset = [16, locals / 2 + 1, locals - 17];
+
+    let mut body = String::from("(func (export \"main\") (local");
+    body.push_str(&" i64".repeat(locals as usize));
+    body.push_str(")\n");
+    for local in &set {
+        writeln!(body, "i64.const {} local.set {local}", value(*local)).unwrap();
+    }
+    body.push_str("i64.const 0\n");
+    for local in set.iter().chain(&unset) {
+        writeln!(body, "local.get {local} i64.add").unwrap();
+    }
+    body.push_str("call $return)");
+
+    let expected = set.iter().fold(0i64, |sum, local| sum.wrapping_add(value(*local)));
+    assert_eq!(returned_i64(&body, "main", 0), expected);
+}
+
+#[test]
+fn test_deeply_nested_blocks() {
+    const DEPTH: u32 = 4096;
+    // Each block returns the value of the block it encloses plus one.  With
+    // a non-zero input the innermost block branches out of the outermost
+    // one, carrying 1000.
+    let mut body = String::from("(func (export \"main\") (local $branch i32)\n");
+    body.push_str("call $arg local.set $branch\n");
+    body.push_str(&"block (result i64)\n".repeat(DEPTH as usize));
+    writeln!(body, "i64.const 1000 local.get $branch br_if {} drop i64.const 0", DEPTH - 1)
+        .unwrap();
+    body.push_str(&"end i64.const 1 i64.add\n".repeat(DEPTH as usize));
+    body.push_str("call $return)");
+
+    assert_eq!(returned_i64(&body, "main", 0), i64::from(DEPTH));
+    assert_eq!(returned_i64(&body, "main", 1), 1001);
+}
+
+#[test]
+fn test_br_table_with_thousands_of_targets() {
+    const TARGETS: u32 = 4096;
+    let value = |target: u32| i64::from(target) * 3 + 7;
+    // Target `k` exits the `k`th innermost block, after which the result is
+    // set to its value.  The default target skips them all.
+    let mut body = String::from("(func (export \"main\") (local $result i64)\n");
+    body.push_str("i64.const -1 local.set $result\nblock\n");
+    body.push_str(&"block\n".repeat(TARGETS as usize));
+    body.push_str("call $arg br_table");
+    for target in 0..=TARGETS {
+        write!(body, " {target}").unwrap();
+    }
+    body.push('\n');
+    for target in 0..TARGETS {
+        writeln!(
+            body,
+            "end i64.const {} local.set $result br {}",
+            value(target),
+            TARGETS - 1 - target
+        )
+        .unwrap();
+    }
+    body.push_str("end local.get $result call $return)");
+
+    for target in [0, 1, 127, 128, 255, 256, TARGETS / 2, TARGETS - 1] {
+        assert_eq!(returned_i64(&body, "main", target), value(target), "target {target}");
+    }
+    // The index is unsigned, so the largest ones take the default as well.
+    for index in [TARGETS, TARGETS + 1, i32::MAX as u32, u32::MAX] {
+        assert_eq!(returned_i64(&body, "main", index), -1, "index {index}");
+    }
+}
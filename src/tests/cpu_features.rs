@@ -0,0 +1,214 @@
+//! Loading artifacts on emulated hosts with fewer CPU features than this one.
+//!
+//! The singlepass compilers only generate code for the features of their
+//! target, so a VM built for a restricted target behaves like one running on
+//! a host with just those features, as far as the generated code goes.
+#![cfg(target_arch = "x86_64")]
+
+use super::{create_context, test_vm_config};
+use crate::logic::errors::VMRunnerError;
+use crate::logic::mocks::mock_external::MockedExternal;
+use crate::logic::types::ReturnData;
+use crate::logic::{CompiledContract, CompiledContractCache, Config};
+use crate::runner::{check_cpu_features, VM};
+use crate::{
+    CodegenTarget, ContractCode, HostSettings, MockCompiledContractCache, RunOptions,
+    BASELINE_CPU_FEATURES,
+};
+use unc_parameters::vm::VMKind;
+use unc_parameters::RuntimeFeesConfig;
+
+/// The CPU of an emulated host.
+struct EmulatedCpu {
+    name: &'static str,
+    features: &'static [&'static str],
+}
+
+const CPUS: [EmulatedCpu; 4] = [
+    EmulatedCpu {
+        name: "x86-64-v3",
+        features: &[
+            "sse2", "sse3", "ssse3", "sse4.1", "sse4.2", "popcnt", "avx", "avx2", "bmi", "bmi2",
+            "lzcnt",
+        ],
+    },
+    EmulatedCpu { name: "no-avx2", features: BASELINE_CPU_FEATURES },
+    EmulatedCpu {
+        name: "no-sse4.2",
+        features: &["sse2", "sse3", "ssse3", "sse4.1", "popcnt", "avx"],
+    },
+    EmulatedCpu {
+        name: "no-avx",
+        features: &["sse2", "sse3", "ssse3", "sse4.1", "sse4.2", "popcnt"],
+    },
+];
+
+const CONTRACT: &str = r#"
+(module
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (memory 1)
+  (func (export "main")
+    (i32.store (i32.const 0) (i32.popcnt (i32.const 0xff)))
+    (call $value_return (i64.const 4) (i64.const 0))))
+"#;
+
+fn contract() -> ContractCode {
+    ContractCode::new(wat::parse_str(CONTRACT).unwrap(), None)
+}
+
+fn run(vm: &dyn VM, cache: &dyn CompiledContractCache) -> ReturnData {
+    let outcome = vm
+        .run(
+            &contract(),
+            "main",
+            &mut MockedExternal::new(),
+            create_context(vec![]),
+            &RuntimeFeesConfig::test(),
+            &[],
+            Some(cache),
+        )
+        .unwrap();
+    assert_eq!(outcome.aborted, None);
+    outcome.return_data
+}
+
+#[test]
+fn test_backend_unavailable_on_emulated_cpus() {
+    for cpu in &CPUS {
+        for vm_kind in [VMKind::Wasmer0, VMKind::Wasmtime, VMKind::Wasmer2, VMKind::NearVm] {
+            let singlepass = vm_kind != VMKind::Wasmtime;
+            let result = check_cpu_features(vm_kind, CodegenTarget::Host, cpu.features);
+            if singlepass && cpu.name == "no-avx" {
+                let err = result.unwrap_err();
+                assert_eq!(err.vm_kind, vm_kind);
+                assert_eq!(err.required_cpu_features, ["avx"]);
+                assert!(err.reason.contains("avx"), "{}", err.reason);
+            } else {
+                assert_eq!(result, Ok(()), "{vm_kind:?} on {}", cpu.name);
+            }
+            // Code for the baseline CPU needs every one of its features.
+            let result = check_cpu_features(vm_kind, CodegenTarget::Baseline, cpu.features);
+            match cpu.name {
+                "no-sse4.2" => assert_eq!(result.unwrap_err().required_cpu_features, ["sse4.2"]),
+                "no-avx" => assert_eq!(result.unwrap_err().required_cpu_features, ["avx"]),
+                _ => assert_eq!(result, Ok(()), "{vm_kind:?} on {}", cpu.name),
+            }
+        }
+    }
+}
+
+#[test]
+fn test_run_without_backend() {
+    let mut config = test_vm_config();
+    config.vm_kind = VMKind::Wasmtime;
+    // Wasmtime cannot generate code for the baseline CPU.
+    let options = RunOptions { codegen: CodegenTarget::Baseline, ..RunOptions::default() };
+    let result = crate::run_with_options(
+        &contract(),
+        "main",
+        &mut MockedExternal::new(),
+        create_context(vec![]),
+        &config,
+        &RuntimeFeesConfig::test(),
+        &[],
+        None,
+        &options,
+    );
+    let Err(VMRunnerError::BackendUnavailable(err)) = result else {
+        panic!("expected the backend to be unavailable, got {result:?}");
+    };
+    assert_eq!(err.vm_kind, VMKind::Wasmtime);
+}
+
+/// Checks that `baseline`, generating code for the baseline CPU, produces the
+/// artifacts of the VMs `emulated` makes for each CPU, and that those load
+/// them from the cache and run them.
+fn check_baseline_artifacts<T: VM>(
+    config: &Config,
+    baseline: &dyn VM,
+    emulated: impl Fn(&EmulatedCpu) -> T,
+    serialize: impl Fn(&T) -> Vec<u8>,
+) {
+    let vm_kind = config.vm_kind;
+    let cache = MockCompiledContractCache::default();
+    baseline.precompile(&contract(), &cache).unwrap().unwrap();
+    let key = crate::cache::contract_cache_key(&contract(), config, CodegenTarget::Baseline);
+    let Some(CompiledContract::Code(artifact)) = cache.get(&key).unwrap() else {
+        panic!("{vm_kind:?} did not cache the baseline artifact");
+    };
+    let expected = ReturnData::Value(8u32.to_le_bytes().to_vec());
+    for cpu in &CPUS {
+        if check_cpu_features(vm_kind, CodegenTarget::Baseline, cpu.features).is_err() {
+            continue;
+        }
+        let vm = emulated(cpu);
+        assert_eq!(run(&vm, &cache), expected, "{vm_kind:?} on {}", cpu.name);
+        if cpu.features == BASELINE_CPU_FEATURES {
+            assert_eq!(serialize(&vm), artifact, "{vm_kind:?}");
+        }
+    }
+}
+
+fn config() -> Config {
+    let mut config = test_vm_config();
+    config.vm_kind = VMKind::NearVm;
+    config
+}
+
+#[cfg(feature = "unc_vm")]
+#[test]
+fn test_unc_vm_baseline_artifacts_on_emulated_cpus() {
+    use crate::unc_vm_runner::NearVM;
+    use unc_vm_compiler::{CpuFeature, Target};
+
+    let baseline =
+        NearVM::new_with_codegen(config(), CodegenTarget::Baseline, HostSettings::default());
+    check_baseline_artifacts(
+        &config(),
+        &baseline,
+        |cpu| {
+            let features = cpu.features.iter().map(|f| f.parse::<CpuFeature>().unwrap());
+            let target =
+                Target::new("x86_64-unknown-linux-gnu".parse().unwrap(), features.collect());
+            // Looks up the artifacts of the baseline codegen.
+            NearVM {
+                codegen: CodegenTarget::Baseline,
+                ..NearVM::new_for_target(config(), target, HostSettings::default())
+            }
+        },
+        |vm| vm.compile_uncached(&contract()).unwrap().serialize().unwrap(),
+    );
+}
+
+#[cfg(feature = "wasmer2_vm")]
+#[test]
+fn test_wasmer2_baseline_artifacts_on_emulated_cpus() {
+    use crate::wasmer2_runner::Wasmer2VM;
+    use wasmer_compiler::{CpuFeature, Target};
+    use wasmer_engine::Executable;
+
+    let wasmer2_config = || {
+        let mut config = config();
+        config.vm_kind = VMKind::Wasmer2;
+        config
+    };
+    let baseline = Wasmer2VM::new_with_codegen(
+        wasmer2_config(),
+        CodegenTarget::Baseline,
+        HostSettings::default(),
+    );
+    check_baseline_artifacts(
+        &wasmer2_config(),
+        &baseline,
+        |cpu| {
+            let features = cpu.features.iter().map(|f| f.parse::<CpuFeature>().unwrap());
+            let target =
+                Target::new("x86_64-unknown-linux-gnu".parse().unwrap(), features.collect());
+            Wasmer2VM {
+                codegen: CodegenTarget::Baseline,
+                ..Wasmer2VM::new_for_target(wasmer2_config(), target, HostSettings::default())
+            }
+        },
+        |vm| vm.compile_uncached(&contract()).unwrap().serialize().unwrap(),
+    );
+}
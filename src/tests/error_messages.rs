@@ -0,0 +1,292 @@
+//! The messages of every error a call can fail with are their string fields
+//! and at most [`MAX_MESSAGE_OVERHEAD`] bytes more, so that contracts cannot
+//! make them longer than the limits on those fields allow, and their codes
+//! are unique.
+
+use crate::logic::error_codes::{localized_message, ErrorCode, ErrorLocalizer, ErrorParam};
+use crate::logic::errors::{
+    CompilationError, FunctionCallError, HostError, MethodResolveError, PrepareError, WasmTrap,
+};
+use crate::logic::mocks::mock_external::MockedExternal;
+use crate::runner::VMKindExt;
+use crate::tests::{create_context, test_vm_config, with_vm_variants};
+use crate::ContractCode;
+use unc_parameters::vm::VMKind;
+use unc_parameters::RuntimeFeesConfig;
+
+/// Bytes the message of an error may have besides its string fields.
+const MAX_MESSAGE_OVERHEAD: usize = 1000;
+
+/// Bytes of the string fields of `err`.
+fn fields_len(err: &FunctionCallError) -> usize {
+    let params = err.code().params;
+    params.iter().map(|(_, param)| if let ErrorParam::Str(s) = param { s.len() } else { 0 }).sum()
+}
+
+/// Every error, with `s` in each of its string fields.
+fn all_errors(s: &str) -> Vec<FunctionCallError> {
+    let mut errors = vec![
+        FunctionCallError::LinkError { msg: s.to_string() },
+        FunctionCallError::Timeout,
+        FunctionCallError::MemoryCapExceeded { used: u64::MAX, cap: u64::MAX },
+        FunctionCallError::CompilationError(CompilationError::CodeDoesNotExist {
+            account_id: s.into(),
+        }),
+        FunctionCallError::CompilationError(CompilationError::WasmerCompileError {
+            msg: s.to_string(),
+        }),
+    ];
+    let prepare = [
+        PrepareError::Serialization,
+        PrepareError::Deserialization,
+        PrepareError::InternalMemoryDeclared,
+        PrepareError::GasInstrumentation,
+        PrepareError::StackHeightInstrumentation,
+        PrepareError::Instantiate,
+        PrepareError::Memory,
+        PrepareError::TooManyFunctions,
+        PrepareError::TooManyLocals,
+        PrepareError::TooComplex,
+        PrepareError::BlockedOpcode,
+        PrepareError::BrTableTooLarge,
+        PrepareError::TooDeeplyNested,
+        PrepareError::FunctionTooLarge,
+        PrepareError::UnsupportedPasses,
+    ];
+    errors.extend(prepare.map(FunctionCallError::from));
+    let method_resolve = [
+        MethodResolveError::MethodEmptyName,
+        MethodResolveError::MethodNotFound,
+        MethodResolveError::MethodInvalidSignature,
+        MethodResolveError::MethodNameTooLong { length: u64::MAX, limit: u64::MAX },
+    ];
+    errors.extend(method_resolve.map(FunctionCallError::MethodResolveError));
+    let traps = [
+        WasmTrap::Unreachable,
+        WasmTrap::IncorrectCallIndirectSignature,
+        WasmTrap::MemoryOutOfBounds,
+        WasmTrap::CallIndirectOOB,
+        WasmTrap::IllegalArithmetic,
+        WasmTrap::MisalignedAtomicAccess,
+        WasmTrap::IndirectCallToNull,
+        WasmTrap::StackOverflow,
+        WasmTrap::GenericTrap,
+    ];
+    errors.extend(traps.map(FunctionCallError::WasmTrap));
+    let n = u64::MAX;
+    let host = [
+        HostError::BadUTF16,
+        HostError::BadUTF8,
+        HostError::GasExceeded,
+        HostError::GasLimitExceeded,
+        HostError::BalanceExceeded,
+        HostError::EmptyMethodName,
+        HostError::GuestPanic { panic_msg: s.to_string() },
+        HostError::IntegerOverflow,
+        HostError::InvalidPromiseIndex { promise_idx: n },
+        HostError::CannotAppendActionToJointPromise,
+        HostError::CannotReturnJointPromise,
+        HostError::InvalidPromiseResultIndex { result_idx: n },
+        HostError::InvalidRegisterId { register_id: n },
+        HostError::MemoryAccessViolation,
+        HostError::InvalidReceiptIndex { receipt_index: n },
+        HostError::InvalidIteratorIndex { iterator_index: n },
+        HostError::InvalidAccountId,
+        HostError::InvalidMethodName,
+        HostError::InvalidPublicKey,
+        HostError::ProhibitedInView { method_name: s.to_string() },
+        HostError::NumberOfLogsExceeded { limit: n },
+        HostError::KeyLengthExceeded { length: n, limit: n },
+        HostError::ValueLengthExceeded { length: n, limit: n },
+        HostError::TotalLogLengthExceeded { length: n, limit: n },
+        HostError::NumberPromisesExceeded { number_of_promises: n, limit: n },
+        HostError::NumberInputDataDependenciesExceeded {
+            number_of_input_data_dependencies: n,
+            limit: n,
+        },
+        HostError::ReturnedValueLengthExceeded { length: n, limit: n },
+        HostError::ContractSizeExceeded { size: n, limit: n },
+        HostError::Deprecated { method_name: s.to_string() },
+        HostError::ECRecoverError { msg: s.to_string() },
+        HostError::AltBn128InvalidInput { msg: s.to_string() },
+        HostError::Ed25519VerifyInvalidInput { msg: s.to_string() },
+        HostError::ScratchLengthExceeded { length: n, limit: n },
+        HostError::FormatDecimalsExceeded { decimals: n, limit: n },
+        HostError::WideMathInvalidInput { msg: s.to_string() },
+        HostError::Bls12381InvalidInput { msg: s.to_string() },
+    ];
+    errors.extend(host.map(FunctionCallError::HostError));
+    errors
+}
+
+/// Does not compile once a variant is added, so that it gets added to
+/// [`all_errors`] too.
+#[allow(dead_code)]
+fn is_listed(err: &FunctionCallError) {
+    match err {
+        FunctionCallError::LinkError { .. }
+        | FunctionCallError::Timeout
+        | FunctionCallError::MemoryCapExceeded { .. }
+        | FunctionCallError::WasmTrap(_) => {}
+        FunctionCallError::MethodResolveError(err) => match err {
+            MethodResolveError::MethodEmptyName
+            | MethodResolveError::MethodNotFound
+            | MethodResolveError::MethodInvalidSignature
+            | MethodResolveError::MethodNameTooLong { .. } => {}
+        },
+        FunctionCallError::CompilationError(err) => match err {
+            CompilationError::CodeDoesNotExist { .. }
+            | CompilationError::PrepareError(_)
+            | CompilationError::WasmerCompileError { .. } => {}
+        },
+        FunctionCallError::HostError(err) => match err {
+            HostError::BadUTF16
+            | HostError::BadUTF8
+            | HostError::GasExceeded
+            | HostError::GasLimitExceeded
+            | HostError::BalanceExceeded
+            | HostError::EmptyMethodName
+            | HostError::GuestPanic { .. }
+            | HostError::IntegerOverflow
+            | HostError::InvalidPromiseIndex { .. }
+            | HostError::CannotAppendActionToJointPromise
+            | HostError::CannotReturnJointPromise
+            | HostError::InvalidPromiseResultIndex { .. }
+            | HostError::InvalidRegisterId { .. }
+            | HostError::MemoryAccessViolation
+            | HostError::InvalidReceiptIndex { .. }
+            | HostError::InvalidIteratorIndex { .. }
+            | HostError::InvalidAccountId
+            | HostError::InvalidMethodName
+            | HostError::InvalidPublicKey
+            | HostError::ProhibitedInView { .. }
+            | HostError::NumberOfLogsExceeded { .. }
+            | HostError::KeyLengthExceeded { .. }
+            | HostError::ValueLengthExceeded { .. }
+            | HostError::TotalLogLengthExceeded { .. }
+            | HostError::NumberPromisesExceeded { .. }
+            | HostError::NumberInputDataDependenciesExceeded { .. }
+            | HostError::ReturnedValueLengthExceeded { .. }
+            | HostError::ContractSizeExceeded { .. }
+            | HostError::Deprecated { .. }
+            | HostError::ECRecoverError { .. }
+            | HostError::AltBn128InvalidInput { .. }
+            | HostError::Ed25519VerifyInvalidInput { .. }
+            | HostError::ScratchLengthExceeded { .. }
+            | HostError::FormatDecimalsExceeded { .. }
+            | HostError::WideMathInvalidInput { .. }
+            | HostError::Bls12381InvalidInput { .. } => {}
+        },
+    }
+}
+
+#[test]
+fn test_error_messages_are_bounded() {
+    // Contracts can make messages as long as the contract itself, through
+    // the names of their imports.
+    let max_contract_size = test_vm_config().limit_config.max_contract_size as usize;
+    let long = ["x".repeat(max_contract_size), "é".repeat(max_contract_size / 2)];
+    for s in ["", "x", long[0].as_str(), long[1].as_str()] {
+        for err in all_errors(s) {
+            let message = err.to_string();
+            assert!(!message.is_empty(), "{err:?}");
+            assert!(
+                message.len() <= fields_len(&err) + MAX_MESSAGE_OVERHEAD,
+                "the message of {} is {} bytes long",
+                <&str>::from(&err),
+                message.len()
+            );
+        }
+    }
+}
+
+#[test]
+fn test_error_codes() {
+    let long = "x".repeat(2000);
+    let errors = all_errors(&long);
+    let codes: std::collections::BTreeSet<_> = errors.iter().map(|err| err.code().code).collect();
+    assert_eq!(codes.len(), errors.len(), "codes are not unique");
+    for err in &errors {
+        let code = err.code();
+        let mut names: Vec<_> = code.params.iter().map(|(name, _)| *name).collect();
+        names.dedup();
+        assert_eq!(names.len(), code.params.len(), "{err:?}");
+        // Strings are passed whole.
+        for (_, param) in &code.params {
+            if let ErrorParam::Str(s) = param {
+                assert_eq!(*s, long, "{err:?}");
+            }
+        }
+    }
+    let err = FunctionCallError::HostError(HostError::KeyLengthExceeded { length: 7, limit: 5 });
+    assert_eq!(
+        err.code(),
+        ErrorCode {
+            code: "host.key_length_exceeded",
+            params: vec![("length", ErrorParam::U64(7)), ("limit", ErrorParam::U64(5))],
+        }
+    );
+    assert_eq!(
+        serde_json::to_string(&err.code()).unwrap(),
+        r#"{"code":"host.key_length_exceeded","params":[["length",7],["limit",5]]}"#
+    );
+}
+
+#[test]
+fn test_localized_message() {
+    struct French;
+    impl ErrorLocalizer for French {
+        fn localize(&self, code: &ErrorCode<'_>) -> Option<String> {
+            match (code.code, code.param("panic_msg")) {
+                ("host.guest_panic", Some(ErrorParam::Str(msg))) => {
+                    Some(format!("Le contrat a paniqué : {msg}"))
+                }
+                _ => None,
+            }
+        }
+    }
+    let panic = FunctionCallError::HostError(HostError::GuestPanic { panic_msg: "oups".into() });
+    assert_eq!(localized_message(&panic, &French), "Le contrat a paniqué : oups");
+    let timeout = FunctionCallError::Timeout;
+    assert_eq!(localized_message(&timeout, &French), timeout.to_string());
+}
+
+/// Panics with its input.
+const PANIC_WITH_INPUT: &str = r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "panic_utf8" (func $panic_utf8 (param i64 i64)))
+  (memory 1)
+  (func (export "main")
+    (call $input (i64.const 0))
+    (call $read_register (i64.const 0) (i64.const 0))
+    (call $panic_utf8 (call $register_len (i64.const 0)) (i64.const 0)))
+)"#;
+
+#[test]
+fn test_longest_panic_message_is_bounded() {
+    let config = test_vm_config();
+    let max_len = config.limit_config.max_total_log_length as usize;
+    let code = ContractCode::new(wat::parse_str(PANIC_WITH_INPUT).unwrap(), None);
+    let fees = RuntimeFeesConfig::test();
+    with_vm_variants(&config, |vm_kind: VMKind| {
+        let runtime = vm_kind.runtime(config.clone()).unwrap();
+        let panic_msg = "x".repeat(max_len);
+        let outcome = runtime
+            .run(
+                &code,
+                "main",
+                &mut MockedExternal::new(),
+                create_context(panic_msg.clone().into_bytes()),
+                &fees,
+                &[],
+                None,
+            )
+            .unwrap();
+        let err = outcome.aborted.unwrap();
+        assert_eq!(err, FunctionCallError::HostError(HostError::GuestPanic { panic_msg }));
+        assert!(err.to_string().len() <= max_len + MAX_MESSAGE_OVERHEAD);
+    });
+}
@@ -0,0 +1,130 @@
+//! The bits of the NaNs contracts see, cross-checked between the VMs.
+//!
+//! Contracts can store a float or reinterpret it as an integer, so the bits
+//! of every NaN they compute are part of the consensus.  These tests compute
+//! NaNs from signaling NaNs and invalid operations, return their bits, and
+//! check that all VMs agree with each other and with the canonical NaN, see
+//! [`crate::prepare::NanCanonicalization`].
+
+use crate::differential::run_all_kinds_with_config;
+use crate::logic::{Config, ReturnData};
+use crate::tests::{create_context, test_vm_config};
+use crate::ContractCode;
+use unc_parameters::vm::VMKind;
+use unc_parameters::RuntimeFeesConfig;
+
+const CANONICAL_F32: u64 = 0x7fc0_0000;
+const CANONICAL_F64: u64 = 0x7ff8_0000_0000_0000;
+
+/// The bits `expr`, an `i64` expression, evaluates to, checking that the VMs
+/// agree on them.
+#[track_caller]
+fn bits(expr: &str, config: &Config) -> u64 {
+    let wat = format!(
+        r#"
+(module
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (memory 1)
+  (func (export "main")
+    (i64.store (i32.const 0) {expr})
+    (call $value_return (i64.const 8) (i64.const 0))))"#
+    );
+    let code = ContractCode::new(wat::parse_str(&wat).unwrap(), None);
+    let fees = RuntimeFeesConfig::test();
+    let mut report =
+        run_all_kinds_with_config(&code, "main", &create_context(vec![]), config, &fees);
+    // Wasmer0 leaves the NaNs to the CPU.
+    report.runs.retain(|run| run.vm_kind != VMKind::Wasmer0);
+    report.assert_agree();
+    let run = report.runs.into_iter().next().expect("tests run with some VM");
+    let outcome = run.result.unwrap();
+    assert_eq!(outcome.aborted, None, "{:?} {expr}", run.vm_kind);
+    match outcome.return_data {
+        ReturnData::Value(value) => u64::from_le_bytes(value.try_into().unwrap()),
+        other => panic!("{:?} returned {other:?}", run.vm_kind),
+    }
+}
+
+/// The config of V2 canonicalizing the NaNs by the pass of the preparation.
+fn nan_canonicalization_pass() -> Config {
+    let mut config = test_vm_config();
+    config.nan_canonicalization_pass = true;
+    config
+}
+
+#[track_caller]
+fn f32_bits(expr: &str, config: &Config) -> u64 {
+    bits(&format!("(i64.extend_i32_u (i32.reinterpret_f32 {expr}))"), config)
+}
+
+#[track_caller]
+fn f64_bits(expr: &str, config: &Config) -> u64 {
+    bits(&format!("(i64.reinterpret_f64 {expr})"), config)
+}
+
+#[test]
+fn test_computed_nans_are_canonical() {
+    let f32_cases = [
+        "(f32.add (f32.const nan:0x1) (f32.const 1))",
+        "(f32.mul (f32.const -nan:0x1) (f32.const 1))",
+        "(f32.sub (f32.const 1) (f32.const nan:0x200001))",
+        "(f32.div (f32.const 0) (f32.const 0))",
+        "(f32.sqrt (f32.const -1))",
+        "(f32.min (f32.const nan:0x1) (f32.const 1))",
+        "(f32.max (f32.const 1) (f32.const -nan))",
+        "(f32.ceil (f32.const nan:0x1))",
+        "(f32.nearest (f32.const -nan:0x1))",
+        "(f32.demote_f64 (f64.const nan:0x1))",
+        "(f32.demote_f64 (f64.const -nan:0x8000000000000))",
+    ];
+    for expr in f32_cases {
+        for config in [test_vm_config(), nan_canonicalization_pass()] {
+            assert_eq!(f32_bits(expr, &config), CANONICAL_F32, "{expr}");
+        }
+    }
+    let f64_cases = [
+        "(f64.add (f64.const nan:0x1) (f64.const 1))",
+        "(f64.div (f64.const -0) (f64.const 0))",
+        "(f64.sqrt (f64.const -inf))",
+        "(f64.trunc (f64.const -nan:0x1))",
+        "(f64.promote_f32 (f32.const nan:0x1))",
+        "(f64.promote_f32 (f32.const -nan))",
+    ];
+    for expr in f64_cases {
+        for config in [test_vm_config(), nan_canonicalization_pass()] {
+            assert_eq!(f64_bits(expr, &config), CANONICAL_F64, "{expr}");
+        }
+    }
+}
+
+/// The instructions which only move bits keep those of signaling NaNs, as
+/// the specification requires, once the compilers no longer canonicalize
+/// the NaNs themselves.
+#[test]
+fn test_moved_nans_keep_their_bits() {
+    let config = nan_canonicalization_pass();
+    let f32_cases = [
+        ("(f32.const nan:0x1)", 0x7f80_0001),
+        ("(f32.neg (f32.const nan:0x1))", 0xff80_0001),
+        ("(f32.abs (f32.const -nan:0x1))", 0x7f80_0001),
+        ("(f32.copysign (f32.const nan:0x1) (f32.const -1))", 0xff80_0001),
+        ("(f32.reinterpret_i32 (i32.const 0x7fa00000))", 0x7fa0_0000),
+        ("(f32.store (i32.const 16) (f32.const -nan:0x1)) (f32.load (i32.const 16))", 0xff80_0001),
+    ];
+    for (expr, expected) in f32_cases {
+        let expr = format!("(block (result f32) {expr})");
+        assert_eq!(f32_bits(&expr, &config), expected, "{expr}");
+    }
+    let f64_cases = [
+        ("(f64.neg (f64.const nan:0x1))", 0xfff0_0000_0000_0001),
+        ("(f64.reinterpret_i64 (i64.const 0x7ff4000000000000))", 0x7ff4_0000_0000_0000),
+        (
+            "(f64.store (i32.const 16) (f64.const nan:0x1)) (f64.load (i32.const 16))",
+            0x7ff0_0000_0000_0001,
+        ),
+    ];
+    for (expr, expected) in f64_cases {
+        let expr = format!("(block (result f64) {expr})");
+        assert_eq!(f64_bits(&expr, &config), expected, "{expr}");
+    }
+}
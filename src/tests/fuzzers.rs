@@ -165,6 +165,38 @@ fn unc_vm_and_wasmtime_agree_fuzzer() {
     });
 }
 
+#[test]
+fn all_vms_agree_fuzzer() {
+    bolero::check!().with_arbitrary::<ArbitraryModule>().for_each(|module: &ArbitraryModule| {
+        let code = ContractCode::new(module.0.module.to_bytes(), None);
+        let method_name = find_entry_point(&code).unwrap_or_else(|| "main".to_string());
+        let mut config = test_vm_config();
+        config.limit_config.contract_prepare_version = ContractPrepareVersion::V2;
+        let report = crate::differential::run_all_kinds_with_config(
+            &code,
+            &method_name,
+            &create_context(vec![]),
+            &config,
+            &RuntimeFeesConfig::test(),
+        );
+        report.assert_agree();
+    });
+}
+
+/// Mutates a byte of the malformed modules, which must still be rejected or
+/// accepted without panicking.
+#[test]
+fn prepare_handles_malformed_fuzzer() {
+    let config = test_vm_config();
+    let corpus = crate::malformed::corpus(&config);
+    bolero::check!().with_type::<(usize, usize, u8)>().for_each(|&(index, offset, byte)| {
+        let mut code = corpus[index % corpus.len()].code.clone();
+        let offset = offset % code.len();
+        code[offset] = byte;
+        let _result = crate::prepare::prepare_contract(&code, &config, config.vm_kind);
+    });
+}
+
 #[test]
 #[cfg(all(feature = "unc_vm", target_arch = "x86_64"))]
 fn unc_vm_is_reproducible_fuzzer() {
@@ -176,7 +208,8 @@ fn unc_vm_is_reproducible_fuzzer() {
         let config = test_vm_config();
         let mut first_hash = None;
         for _ in 0..3 {
-            let vm = NearVM::new(config.clone());
+            let host = crate::HostSettings::default();
+            let vm = NearVM::new_with_codegen(config.clone(), crate::CodegenTarget::Host, host);
             let exec = match vm.compile_uncached(&code) {
                 Ok(e) => e,
                 Err(_) => return,
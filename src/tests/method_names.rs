@@ -0,0 +1,112 @@
+use crate::logic::errors::{FunctionCallError, MethodResolveError, PrepareError};
+use crate::logic::mocks::mock_external::MockedExternal;
+use crate::logic::types::ReturnData;
+use crate::runner::VMKindExt;
+use crate::tests::{create_context, test_vm_config, with_vm_variants};
+use crate::{exported_methods, ContractCode, ExportedMethod};
+use unc_parameters::vm::VMKind;
+use unc_parameters::RuntimeFeesConfig;
+
+/// Names which backends could disagree on, the `i`-th one returning `[i]`.
+fn exotic_names(max_length: usize) -> Vec<String> {
+    vec![
+        // The same character, normalized differently.
+        "\u{e9}".to_string(),
+        "e\u{301}".to_string(),
+        "🦀".to_string(),
+        " ".to_string(),
+        "a b".to_string(),
+        "\0".to_string(),
+        "main\0".to_string(),
+        "\u{feff}main".to_string(),
+        "x".repeat(max_length),
+    ]
+}
+
+/// A contract exporting a method returning `[i]` under the `i`-th name, a
+/// method taking a parameter and a global, all named byte by byte.
+fn exporting(names: &[String]) -> ContractCode {
+    let wat_name = |name: &str| name.bytes().map(|b| format!("\\{b:02x}")).collect::<String>();
+    let data: String = (0..names.len()).map(|i| format!("\\{i:02x}")).collect();
+    let mut wat = format!(
+        r#"(module
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (memory 1)
+  (data (i32.const 0) "{data}")
+  (global (export "global") i32 (i32.const 0))
+  (func (export "with_param") (param i32))
+"#
+    );
+    for (i, name) in names.iter().enumerate() {
+        wat += &format!(
+            "  (func (export \"{}\") (call $value_return (i64.const 1) (i64.const {i})))\n",
+            wat_name(name)
+        );
+    }
+    wat += ")";
+    ContractCode::new(wat::parse_str(wat).unwrap(), None)
+}
+
+#[test]
+fn test_exotic_method_names() {
+    let mut config = test_vm_config();
+    config.limit_method_name_length = true;
+    let max_length = config.limit_config.max_length_method_name as usize;
+    let names = exotic_names(max_length);
+    let code = exporting(&names);
+    let fees = RuntimeFeesConfig::test();
+    with_vm_variants(&config, |vm_kind: VMKind| {
+        let runtime = vm_kind.runtime(config.clone()).unwrap();
+        let run = |method: &str| {
+            let context = create_context(vec![]);
+            let outcome = runtime
+                .run(&code, method, &mut MockedExternal::new(), context, &fees, &[], None)
+                .unwrap();
+            outcome.aborted.map_or(Ok(outcome.return_data), Err)
+        };
+        for (i, name) in names.iter().enumerate() {
+            assert_eq!(run(name), Ok(ReturnData::Value(vec![i as u8])), "{vm_kind:?} {name:?}");
+        }
+        let resolve_error = |err| Err(FunctionCallError::MethodResolveError(err));
+        assert_eq!(run("e"), resolve_error(MethodResolveError::MethodNotFound));
+        assert_eq!(run("main"), resolve_error(MethodResolveError::MethodNotFound));
+        assert_eq!(run("global"), resolve_error(MethodResolveError::MethodNotFound));
+        assert_eq!(run("with_param"), resolve_error(MethodResolveError::MethodInvalidSignature));
+        assert_eq!(run(""), resolve_error(MethodResolveError::MethodEmptyName));
+        // The limit is in bytes, not characters.
+        let limit = max_length as u64;
+        for (name, length) in
+            [("x".repeat(max_length + 1), limit + 1), ("🦀".repeat(max_length), limit * 4)]
+        {
+            let too_long = MethodResolveError::MethodNameTooLong { length, limit };
+            assert_eq!(run(&name), resolve_error(too_long));
+        }
+    });
+}
+
+#[test]
+fn test_exported_methods() {
+    let mut config = test_vm_config();
+    let max_length = config.limit_config.max_length_method_name as usize;
+    let mut names = exotic_names(max_length);
+    names.push("y".repeat(max_length + 1));
+    let code = exporting(&names);
+    let methods = exported_methods(code.code(), &config).unwrap();
+    assert_eq!(methods.last().unwrap().callable, Ok(()));
+    config.limit_method_name_length = true;
+    let methods = exported_methods(code.code(), &config).unwrap();
+
+    let mut expected = vec![ExportedMethod {
+        name: "with_param".to_string(),
+        callable: Err(MethodResolveError::MethodInvalidSignature),
+    }];
+    expected
+        .extend(names.iter().map(|name| ExportedMethod { name: name.clone(), callable: Ok(()) }));
+    expected.last_mut().unwrap().callable = Err(MethodResolveError::MethodNameTooLong {
+        length: max_length as u64 + 1,
+        limit: max_length as u64,
+    });
+    assert_eq!(methods, expected);
+
+    assert_eq!(exported_methods(b"\0asm", &config), Err(PrepareError::Deserialization));
+}
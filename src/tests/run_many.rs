@@ -0,0 +1,161 @@
+use crate::logic::mocks::mock_external::MockedExternal;
+use crate::logic::types::ReturnData;
+use crate::runner::{MethodCall, RunOptions, VMKindExt};
+use crate::tests::{create_context, test_vm_config, with_vm_variants};
+use crate::{ContractCode, MockCompiledContractCache};
+use unc_parameters::vm::VMKind;
+use unc_parameters::RuntimeFeesConfig;
+use unc_primitives_core::config::ViewConfig;
+
+/// `bump` increments a counter in memory and in storage and returns both
+/// along with whether the key was there, `echo` returns its input and `trap`
+/// traps.
+const CONTRACT: &str = r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (import "env" "storage_write"
+    (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "storage_has_key" (func $storage_has_key (param i64 i64) (result i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (memory 1)
+  (data (i32.const 0) "c")
+  (func (export "bump")
+    (i64.store (i32.const 8) (i64.add (i64.load (i32.const 8)) (i64.const 1)))
+    (i64.store (i32.const 16) (call $storage_has_key (i64.const 1) (i64.const 0)))
+    (drop (call $storage_write
+      (i64.const 1) (i64.const 0) (i64.const 8) (i64.const 8) (i64.const 0)))
+    (call $value_return (i64.const 16) (i64.const 8)))
+  (func (export "echo")
+    (call $input (i64.const 0))
+    (call $read_register (i64.const 0) (i64.const 100))
+    (call $value_return (call $register_len (i64.const 0)) (i64.const 100)))
+  (func (export "trap") unreachable))
+"#;
+
+#[test]
+fn test_run_many() {
+    let code = ContractCode::new(wat::parse_str(CONTRACT).unwrap(), None);
+    let fees = RuntimeFeesConfig::test();
+    let call = |method_name: &str, input: &[u8]| MethodCall {
+        method_name: method_name.to_string(),
+        context: create_context(input.to_vec()),
+    };
+    let calls = [
+        call("bump", b""),
+        call("echo", b"a"),
+        call("bump", b""),
+        call("trap", b""),
+        call("missing", b""),
+        call("echo", b"bc"),
+        call("bump", b""),
+    ];
+    with_vm_variants(&test_vm_config(), |vm_kind: VMKind| {
+        let runtime = vm_kind.runtime(test_vm_config()).unwrap();
+        let cache = MockCompiledContractCache::default();
+        let options = RunOptions::default();
+        let mut ext = MockedExternal::new();
+        let outcomes =
+            runtime.run_many(&code, &calls, &mut ext, &fees, &[], Some(&cache), &options).unwrap();
+        assert_eq!(outcomes.len(), calls.len());
+        // The calls do not write to the state they run on.
+        assert!(ext.fake_trie.is_empty(), "{vm_kind:?}");
+
+        for (call, outcome) in calls.iter().zip(&outcomes) {
+            let alone = runtime
+                .run_with_options(
+                    &code,
+                    &call.method_name,
+                    &mut MockedExternal::new(),
+                    call.context.clone(),
+                    &fees,
+                    &[],
+                    Some(&cache),
+                    &options,
+                )
+                .unwrap();
+            assert_eq!(outcome, &alone, "{vm_kind:?} {}", call.method_name);
+        }
+        // Every call sees the memory and the state as if it ran first.
+        let bumped = ReturnData::Value([1u64.to_le_bytes(), 0u64.to_le_bytes()].concat());
+        for index in [0, 2, 6] {
+            assert_eq!(outcomes[index].return_data, bumped, "{vm_kind:?}");
+        }
+        assert_eq!(outcomes[1].return_data, ReturnData::Value(b"a".to_vec()));
+        assert_eq!(outcomes[5].return_data, ReturnData::Value(b"bc".to_vec()));
+        assert!(outcomes[3].aborted.is_some(), "{vm_kind:?}");
+        assert!(outcomes[4].aborted.is_some(), "{vm_kind:?}");
+    });
+}
+
+/// `bump` increments a counter in memory and a global and returns both,
+/// `clobber` overwrites the data segment, returning it first, and `grow`
+/// grows the memory.
+const VIEWS: &str = r#"
+(module
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (memory 1)
+  (global $counter (mut i64) (i64.const 7))
+  (data (i32.const 32) "data")
+  (func (export "bump")
+    (i64.store (i32.const 8) (i64.add (i64.load (i32.const 8)) (i64.const 1)))
+    (global.set $counter (i64.add (global.get $counter) (i64.const 1)))
+    (i64.store (i32.const 16) (global.get $counter))
+    (call $value_return (i64.const 16) (i64.const 8)))
+  (func (export "clobber")
+    (call $value_return (i64.const 4) (i64.const 32))
+    (i32.store (i32.const 32) (i32.const 0)))
+  (func (export "grow")
+    (drop (memory.grow (i32.const 1)))
+    (call $value_return (i64.const 4) (i64.const 32))
+    (i32.store (i32.const 32) (i32.const 0))))
+"#;
+
+#[test]
+fn test_run_many_views() {
+    let code = ContractCode::new(wat::parse_str(VIEWS).unwrap(), None);
+    let fees = RuntimeFeesConfig::test();
+    let config = test_vm_config();
+    let call = |method_name: &str| {
+        let mut context = create_context(vec![]);
+        context.view_config = Some(ViewConfig { max_gas_burnt: config.limit_config.max_gas_burnt });
+        MethodCall { method_name: method_name.to_string(), context }
+    };
+    let calls =
+        ["bump", "clobber", "bump", "missing", "clobber", "grow", "bump", "clobber"].map(call);
+    with_vm_variants(&config, |vm_kind: VMKind| {
+        let runtime = vm_kind.runtime(config.clone()).unwrap();
+        let cache = MockCompiledContractCache::default();
+        let options = RunOptions::default();
+        let mut ext = MockedExternal::new();
+        let outcomes =
+            runtime.run_many(&code, &calls, &mut ext, &fees, &[], Some(&cache), &options).unwrap();
+        assert_eq!(outcomes.len(), calls.len());
+        for (call, outcome) in calls.iter().zip(&outcomes) {
+            let alone = runtime
+                .run_with_options(
+                    &code,
+                    &call.method_name,
+                    &mut MockedExternal::new(),
+                    call.context.clone(),
+                    &fees,
+                    &[],
+                    Some(&cache),
+                    &options,
+                )
+                .unwrap();
+            assert_eq!(outcome, &alone, "{vm_kind:?} {}", call.method_name);
+        }
+        // Calls sharing an instance see its memory and globals as
+        // instantiated, before the memory grows and after.
+        let bumped = ReturnData::Value([1u64.to_le_bytes(), 8u64.to_le_bytes()].concat());
+        for index in [0, 2, 6] {
+            assert_eq!(outcomes[index].return_data, bumped, "{vm_kind:?}");
+        }
+        for index in [1, 4, 5, 7] {
+            assert_eq!(outcomes[index].return_data, ReturnData::Value(b"data".to_vec()));
+        }
+        assert!(outcomes[3].aborted.is_some(), "{vm_kind:?}");
+    });
+}
@@ -1,7 +1,20 @@
 use super::test_builder::test_builder;
+use crate::logic::errors::{FunctionCallError, VMRunnerError};
+use crate::logic::mocks::mock_external::MockedExternal;
+use crate::logic::types::PromiseResult;
+use crate::logic::{Config, HostError, UsedMemory, VMContext};
+use crate::runner::VMKindExt;
+use crate::tests::{create_context, test_vm_config, with_vm_variants};
+use crate::{Clock, ConsensusHooks, ContractCode, MockCompiledContractCache, RunOptions};
 use expect_test::expect;
+use unc_parameters::vm::VMKind;
+use unc_parameters::RuntimeFeesConfig;
+use unc_primitives_core::config::ViewConfig;
+use unc_primitives_core::types::Gas;
 use unc_primitives_core::version::ProtocolFeature;
 use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 const FIX_CONTRACT_LOADING_COST: u32 = 129;
 
@@ -401,6 +414,47 @@ fn test_stack_overflow() {
         ]);
 }
 
+#[test]
+fn test_stack_overflow_is_deterministic() {
+    use crate::logic::errors::WasmTrap;
+    use crate::logic::ContractPrepareVersion;
+
+    let mut config = test_vm_config();
+    config.limit_config.contract_prepare_version = ContractPrepareVersion::V2;
+    config.deterministic_stack_limit = true;
+    let code = wat::parse_str(r#"(module (func $f (export "main") (call $f)))"#).unwrap();
+    let code = ContractCode::new(code, None);
+    let burnt_gas = std::cell::Cell::new(None);
+    with_vm_variants(&config, |vm_kind| {
+        if vm_kind == VMKind::Wasmer0 {
+            return;
+        }
+        let mut config = config.clone();
+        config.vm_kind = vm_kind;
+        let runtime = vm_kind.runtime(config).expect("runtime has not been compiled");
+        let outcome = runtime
+            .run(
+                &code,
+                "main",
+                &mut MockedExternal::new(),
+                create_context(Vec::new()),
+                &RuntimeFeesConfig::test(),
+                &[],
+                None,
+            )
+            .unwrap();
+        assert_eq!(
+            outcome.aborted,
+            Some(FunctionCallError::WasmTrap(WasmTrap::StackOverflow)),
+            "{vm_kind:?}"
+        );
+        // The same frames fit on the stack of every backend.
+        let expected = burnt_gas.get().unwrap_or(outcome.burnt_gas);
+        burnt_gas.set(Some(expected));
+        assert_eq!(outcome.burnt_gas, expected, "{vm_kind:?}");
+    });
+}
+
 #[test]
 fn test_stack_instrumentation_protocol_upgrade() {
     test_builder()
@@ -1056,3 +1110,425 @@ fn test_regression_9393() {
         "#]],
     ]);
 }
+
+#[test]
+fn test_gas_exhaustion_trace() {
+    let config = test_vm_config();
+    let code = wat::parse_str(
+        r#"
+(module
+  (func $spin (loop (br 0)))
+  (func (export "main") (call $spin))
+)"#,
+    )
+    .unwrap();
+    let code = ContractCode::new(code, None);
+    with_vm_variants(&config, |vm_kind| {
+        let runtime = vm_kind.runtime(config.clone()).expect("runtime has not been compiled");
+        let mut context = create_context(Vec::new());
+        context.prepaid_gas = 10u64.pow(10);
+        let options = RunOptions { record_gas_exhaustion_trace: true, ..RunOptions::default() };
+        let outcome = runtime
+            .run_with_options(
+                &code,
+                "main",
+                &mut MockedExternal::new(),
+                context,
+                &RuntimeFeesConfig::test(),
+                &[],
+                None,
+                &options,
+            )
+            .unwrap();
+        assert!(
+            matches!(outcome.aborted, Some(FunctionCallError::HostError(HostError::GasExceeded))),
+            "{vm_kind:?}: {outcome:?}"
+        );
+        // The loop runs in `$spin`, called from `main`.
+        let expect_trace = vm_kind != VMKind::Wasmer0;
+        assert_eq!(
+            outcome.gas_exhaustion_trace.len() >= 2,
+            expect_trace,
+            "{vm_kind:?}: {:?}",
+            outcome.gas_exhaustion_trace
+        );
+    });
+}
+
+#[test]
+fn test_storage_write_end_state() {
+    test_builder()
+        .wat(
+            r#"
+(module
+  (import "env" "storage_write"
+    (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (memory 1)
+  (data (i32.const 0) "keyvalue")
+  (data (i32.const 8) "long0123456789012345678901234567890123456789")
+  (func (export "main")
+    (drop (call $storage_write (i64.const 3) (i64.const 0) (i64.const 5) (i64.const 3) (i64.const 0)))
+    (drop (call $storage_write (i64.const 4) (i64.const 8) (i64.const 40) (i64.const 12) (i64.const 0))))
+)"#,
+        )
+        .opaque_outcome()
+        .expect_storage(expect![[r#"
+            6b6579: 76616c7565
+            6c6f6e67: 3031323334353637383930313233343536373839303132333435363738393031... (40 bytes)
+        "#]])
+        .expect(&expect![[""]]);
+}
+
+#[test]
+fn test_chained_calls() {
+    test_builder()
+        .wat(
+            r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "promise_result" (func $promise_result (param i64 i64) (result i64)))
+  (import "env" "storage_write"
+    (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (memory 1)
+  (data (i32.const 0) "inputresult")
+  (func (export "save")
+    (call $input (i64.const 0))
+    (drop (call $storage_write (i64.const 5) (i64.const 0) (i64.const -1) (i64.const 0) (i64.const 1))))
+  (func (export "callback")
+    (drop (call $promise_result (i64.const 0) (i64.const 0)))
+    (drop (call $storage_write (i64.const 6) (i64.const 5) (i64.const -1) (i64.const 0) (i64.const 1))))
+)"#,
+        )
+        .method("save")
+        .input(b"hello")
+        .opaque_outcome()
+        .then_call("callback", expect![[""]])
+        .promise_results(vec![PromiseResult::Successful(b"ok".to_vec())])
+        .then_call(
+            "save",
+            expect![[r#"
+                Err: storage_write is not allowed in view calls
+            "#]],
+        )
+        .view()
+        .expect_storage(expect![[r#"
+            696e707574: 68656c6c6f
+            726573756c74: 6f6b
+        "#]])
+        .expect(&expect![[""]]);
+}
+
+#[test]
+fn test_gas_profile_host_functions() {
+    test_builder()
+        .wat(
+            r#"
+(module
+  (import "env" "storage_write"
+    (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "input" (func $input (param i64)))
+  (memory 1)
+  (data (i32.const 0) "keyvalue")
+  (func (export "main")
+    (call $input (i64.const 0))
+    (drop (call $storage_write (i64.const 3) (i64.const 0) (i64.const 5) (i64.const 3) (i64.const 0)))
+    (drop (call $storage_write (i64.const 3) (i64.const 0) (i64.const 5) (i64.const 3) (i64.const 0))))
+)"#,
+        )
+        .opaque_outcome()
+        .expect_gas_profile(expect![[r#"
+            input: 1 calls
+            storage_write: 2 calls
+        "#]])
+        .expect(&expect![[""]]);
+}
+
+#[test]
+fn test_receipts_order() {
+    test_builder()
+        .wat(
+            r#"
+(module
+  (import "env" "promise_batch_create"
+    (func $promise_batch_create (param i64 i64) (result i64)))
+  (import "env" "promise_batch_then"
+    (func $promise_batch_then (param i64 i64 i64) (result i64)))
+  (import "env" "promise_batch_action_transfer"
+    (func $promise_batch_action_transfer (param i64 i64)))
+  (memory 1)
+  (data (i32.const 0) "bobcarol")
+  (data (i32.const 16) "\01")
+  (func (export "main") (local $first i64) (local $then i64)
+    (local.set $first (call $promise_batch_create (i64.const 3) (i64.const 0)))
+    (local.set $then (call $promise_batch_then (local.get $first) (i64.const 5) (i64.const 3)))
+    (call $promise_batch_action_transfer (local.get $then) (i64.const 16))
+    (call $promise_batch_action_transfer (local.get $first) (i64.const 16)))
+)"#,
+        )
+        .opaque_outcome()
+        .expect_receipts(expect![[r#"
+            0: bob after []
+              Transfer { deposit: 1 }
+            1: carol after [0]
+              Transfer { deposit: 1 }
+        "#]])
+        .expect(&expect![[""]]);
+}
+
+#[test]
+fn test_snapshot() {
+    test_builder()
+        .wat(
+            r#"
+(module
+  (import "env" "storage_write" (func (param i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "log_utf8" (func (param i64 i64)))
+  (import "env" "promise_batch_create" (func (param i64 i64) (result i64)))
+  (import "env" "promise_batch_action_transfer" (func (param i64 i64)))
+  (import "env" "value_return" (func (param i64 i64)))
+  (memory 1)
+  (data (i32.const 0) "keyvaluebob")
+  (data (i32.const 16) "\01")
+  (func (export "main")
+    i64.const 3 i64.const 0 i64.const 5 i64.const 3 i64.const 0
+    call 0
+    drop
+    i64.const 3 i64.const 0
+    call 1
+    i64.const 3 i64.const 8
+    call 2
+    i64.const 16
+    call 3
+    i64.const 5 i64.const 3
+    call 4)
+)"#,
+        )
+        .opaque_outcome()
+        .expect_snapshot(expect![[r#"
+            balance 3 storage_usage 60 return value 76616c7565 burnt_gas 316526497494 used_gas 539709059994
+            log "key"
+            receipt 0: bob after
+              transfer 1
+            gas loading 87249213 wasm_ops 21391656
+            gas log_utf8: 1 calls 10455465231 gas
+            gas promise_batch_action_transfer: 1 calls 118058515139 gas
+            gas promise_batch_create: 1 calls 114932055808 gas
+            gas storage_write: 1 calls 70078182471 gas
+            gas value_return: 1 calls 2893637976 gas
+            storage + 6b6579: 76616c7565
+        "#]])
+        .expect(&expect![[""]]);
+}
+
+#[test]
+fn test_deadline_interrupts_call() {
+    let code =
+        wat::parse_str(r#"(module (func (export "main") (loop (br 0))) (func (export "fast")))"#)
+            .unwrap();
+    let code = ContractCode::new(code, None);
+    let config = test_vm_config();
+    let fees = RuntimeFeesConfig::test();
+    with_vm_variants(&config, |vm_kind| {
+        let mut config = config.clone();
+        config.vm_kind = vm_kind;
+        let calls = [
+            ("main", Duration::from_millis(100), Some(FunctionCallError::Timeout)),
+            ("fast", Duration::from_secs(60), None),
+        ];
+        for (method, timeout, want) in calls {
+            let mut context = create_context(Vec::new());
+            // A view call which could loop for a very long time.
+            context.view_config = Some(ViewConfig { max_gas_burnt: u64::MAX });
+            let options =
+                RunOptions { deadline: Some(Instant::now() + timeout), ..RunOptions::default() };
+            let outcome = crate::run_with_options(
+                &code,
+                method,
+                &mut MockedExternal::new(),
+                context,
+                &config,
+                &fees,
+                &[],
+                None,
+                &options,
+            )
+            .unwrap();
+            assert_eq!(outcome.aborted, want, "{vm_kind:?} {method}");
+        }
+    });
+}
+
+#[test]
+fn test_deadline_virtual_clock() {
+    let code =
+        wat::parse_str(r#"(module (func (export "main") (loop (br 0))) (func (export "fast")))"#)
+            .unwrap();
+    let code = ContractCode::new(code, None);
+    let config = test_vm_config();
+    let fees = RuntimeFeesConfig::test();
+    with_vm_variants(&config, |vm_kind| {
+        let mut config = config.clone();
+        config.vm_kind = vm_kind;
+        let run = |method, clock: &Arc<crate::VirtualClock>, timeout| {
+            let mut context = create_context(Vec::new());
+            context.view_config = Some(ViewConfig { max_gas_burnt: u64::MAX });
+            let options = RunOptions {
+                deadline: Some(clock.now() + timeout),
+                clock: Some(clock.clone()),
+                ..RunOptions::default()
+            };
+            crate::run_with_options(
+                &code,
+                method,
+                &mut MockedExternal::new(),
+                context,
+                &config,
+                &fees,
+                &[],
+                None,
+                &options,
+            )
+            .unwrap()
+            .aborted
+        };
+        let clock = Arc::new(crate::VirtualClock::new());
+        // A deadline which already passed interrupts the call at once, and
+        // one which never passes never does, however slow the call.
+        assert_eq!(run("main", &clock, Duration::ZERO), Some(FunctionCallError::Timeout));
+        assert_eq!(run("fast", &clock, Duration::from_nanos(1)), None);
+
+        let advance = std::thread::spawn({
+            let clock = clock.clone();
+            move || {
+                std::thread::sleep(Duration::from_millis(50));
+                clock.advance(Duration::from_secs(2));
+            }
+        });
+        let aborted = run("main", &clock, Duration::from_secs(1));
+        advance.join().unwrap();
+        assert_eq!(aborted, Some(FunctionCallError::Timeout), "{vm_kind:?}");
+    });
+}
+
+#[test]
+fn test_memory_cap() {
+    let code = wat::parse_str(
+        r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (memory 1)
+  (func (export "main")
+    (drop (memory.grow (i32.const 2)))
+    (call $input (i64.const 0))))"#,
+    )
+    .unwrap();
+    let code = ContractCode::new(code, None);
+    let config = test_vm_config();
+    let fees = RuntimeFeesConfig::test();
+    with_vm_variants(&config, |vm_kind| {
+        let mut config = config.clone();
+        config.vm_kind = vm_kind;
+        let run = |memory_cap| {
+            let mut config = config.clone();
+            config.extra_limits.memory_cap = memory_cap;
+            crate::run(
+                &code,
+                "main",
+                &mut MockedExternal::new(),
+                create_context(vec![1, 2, 3]),
+                &config,
+                &fees,
+                &[],
+                None,
+            )
+            .unwrap()
+        };
+        let unmeasured = run(None);
+        assert_eq!(unmeasured.aborted, None, "{vm_kind:?}");
+        assert_eq!(unmeasured.used_memory, UsedMemory::default(), "{vm_kind:?}");
+        let outcome = run(Some(u64::MAX));
+        assert_eq!(outcome.aborted, None, "{vm_kind:?}");
+        let used = outcome.used_memory;
+        let pages = u64::from(config.limit_config.initial_memory_pages) + 2;
+        assert_eq!(used.peak_memory_pages, pages, "{vm_kind:?}");
+        // The input register.
+        assert_eq!(used.peak_host_bytes, 3 + 8, "{vm_kind:?}");
+        assert_eq!(used.peak_total_bytes, pages * 65536 + 3 + 8, "{vm_kind:?}");
+
+        let total = used.peak_total_bytes;
+        assert_eq!(run(Some(total)).aborted, None, "{vm_kind:?}");
+        let capped = run(Some(total - 1));
+        assert_eq!(
+            capped.aborted,
+            Some(FunctionCallError::MemoryCapExceeded { used: total, cap: total - 1 }),
+            "{vm_kind:?}"
+        );
+        assert_eq!(capped.used_memory, used, "{vm_kind:?}");
+    });
+}
+
+#[test]
+fn test_code_pricing() {
+    #[derive(Debug)]
+    struct Pricing;
+
+    impl crate::CodePricing for Pricing {
+        fn call_gas(&self, code_len: u64, _config: &Config) -> Gas {
+            code_len * 10
+        }
+    }
+
+    let code =
+        ContractCode::new(wat::parse_str(r#"(module (func (export "main")))"#).unwrap(), None);
+    let code_len = code.code().len() as Gas;
+    let config = test_vm_config();
+    let fees = RuntimeFeesConfig::test();
+    with_vm_variants(&config, |vm_kind| {
+        let mut config = config.clone();
+        config.vm_kind = vm_kind;
+        let consensus =
+            ConsensusHooks { code_pricing: Some(Arc::new(Pricing)), ..Default::default() };
+        let options = RunOptions { consensus, ..RunOptions::default() };
+        // Configs without consensus hooks reject them.
+        let result = crate::run_with_options(
+            &code,
+            "main",
+            &mut MockedExternal::new(),
+            create_context(Vec::new()),
+            &config,
+            &fees,
+            &[],
+            None,
+            &options,
+        );
+        assert!(matches!(result, Err(VMRunnerError::ConsensusHooksDisabled)), "{vm_kind:?}");
+
+        config.consensus_hooks = true;
+        let cache = MockCompiledContractCache::default();
+        let call = |options: &RunOptions, context: VMContext| {
+            crate::run_with_options(
+                &code,
+                "main",
+                &mut MockedExternal::new(),
+                context,
+                &config,
+                &fees,
+                &[],
+                Some(&cache),
+                options,
+            )
+            .unwrap()
+        };
+        // The first call compiles the contract into the cache.
+        let uncached = call(&options, create_context(Vec::new()));
+        let unpriced = call(&RunOptions::default(), create_context(Vec::new()));
+        let priced = call(&options, create_context(Vec::new()));
+        assert_eq!(priced.aborted, None);
+        assert_eq!(priced.burnt_gas, unpriced.burnt_gas + code_len * 10, "{vm_kind:?}");
+        assert_eq!(uncached.burnt_gas, priced.burnt_gas, "{vm_kind:?}");
+
+        let context = VMContext { prepaid_gas: code_len * 5, ..create_context(Vec::new()) };
+        let outcome = call(&options, context);
+        assert_eq!(outcome.aborted, Some(FunctionCallError::HostError(HostError::GasExceeded)));
+    });
+}
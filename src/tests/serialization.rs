@@ -0,0 +1,130 @@
+use crate::logic::errors::{
+    CompilationError, FunctionCallError, HostError, MethodResolveError, PrepareError, WasmTrap,
+};
+use crate::logic::mocks::mock_external::MockedExternal;
+use crate::logic::{VMContext, VMOutcome};
+use crate::runner::VMKindExt;
+use crate::tests::{create_context, test_vm_config, with_vm_variants};
+use crate::ContractCode;
+use unc_parameters::vm::VMKind;
+use unc_parameters::RuntimeFeesConfig;
+use unc_primitives_core::config::ViewConfig;
+
+#[track_caller]
+fn assert_round_trips<T>(value: &T)
+where
+    T: borsh::BorshSerialize
+        + borsh::BorshDeserialize
+        + serde::Serialize
+        + serde::de::DeserializeOwned
+        + PartialEq
+        + std::fmt::Debug,
+{
+    let json = serde_json::to_string(value).unwrap();
+    assert_eq!(&serde_json::from_str::<T>(&json).unwrap(), value, "{json}");
+    let bytes = borsh::to_vec(value).unwrap();
+    assert_eq!(&borsh::from_slice::<T>(&bytes).unwrap(), value);
+}
+
+fn run(vm_kind: VMKind, method_name: &str) -> VMOutcome {
+    let code = match vm_kind {
+        VMKind::Wasmer0 | VMKind::Wasmer2 => unc_test_contracts::backwards_compatible_rs_contract(),
+        VMKind::Wasmtime | VMKind::NearVm => unc_test_contracts::rs_contract(),
+    };
+    let code = ContractCode::new(code.to_vec(), None);
+    let mut ext = MockedExternal::new();
+    let context = create_context([10u64, 20u64].iter().flat_map(|x| x.to_le_bytes()).collect());
+    let runtime = vm_kind.runtime(test_vm_config()).expect("runtime has not been compiled");
+    runtime
+        .run(&code, method_name, &mut ext, context, &RuntimeFeesConfig::test(), &[], None)
+        .unwrap()
+}
+
+#[test]
+fn test_outcome_round_trips() {
+    with_vm_variants(&test_vm_config(), |vm_kind: VMKind| {
+        let outcome = run(vm_kind, "write_key_value");
+        assert_eq!(outcome.aborted, None);
+        assert_round_trips(&outcome);
+
+        let json = serde_json::to_value(&outcome).unwrap();
+        assert_eq!(json["balance"], "4", "balances are decimal strings");
+        assert!(json["return_data"]["Value"].is_string(), "bytes are base64");
+        assert!(json["profile"]["ext_costs"].is_object());
+
+        let aborted = run(vm_kind, "no_such_method");
+        assert_eq!(
+            aborted.aborted,
+            Some(FunctionCallError::MethodResolveError(MethodResolveError::MethodNotFound))
+        );
+        assert_round_trips(&aborted);
+    });
+}
+
+#[test]
+fn test_outcome_later_fields_default() {
+    with_vm_variants(&test_vm_config(), |vm_kind: VMKind| {
+        let outcome = run(vm_kind, "write_key_value");
+        let mut json = serde_json::to_value(&outcome).unwrap();
+        let fields = json.as_object_mut().unwrap();
+        for field in [
+            "storage_delta",
+            "receipts",
+            "gas_profile",
+            "gas_exhaustion_trace",
+            "checkpoints",
+            "host_calls",
+            "used_memory",
+            "backtrace",
+            "receipt_costs",
+            "state_witness",
+            "execution_fingerprint",
+        ] {
+            fields.remove(field).unwrap();
+        }
+        let restored: VMOutcome = serde_json::from_value(json).unwrap();
+        assert_eq!(restored.return_data, outcome.return_data);
+        assert_eq!(restored.burnt_gas, outcome.burnt_gas);
+        assert_eq!(restored.storage_delta, Default::default());
+        assert_eq!(restored.receipts, []);
+    });
+}
+
+#[test]
+fn test_context_round_trips() {
+    let context = create_context(vec![1, 2, 3]);
+    assert_round_trips(&context);
+    let json = serde_json::to_value(&context).unwrap();
+    assert_eq!(json["input"], "AQID");
+    assert_eq!(json["account_balance"], "2");
+
+    let view = VMContext {
+        view_config: Some(ViewConfig { max_gas_burnt: 42 }),
+        account_balance: u128::MAX,
+        ..context
+    };
+    assert_round_trips(&view);
+}
+
+#[test]
+fn test_errors_round_trip() {
+    let errors = [
+        FunctionCallError::Timeout,
+        FunctionCallError::WasmTrap(WasmTrap::Unreachable),
+        FunctionCallError::MethodResolveError(MethodResolveError::MethodEmptyName),
+        FunctionCallError::CompilationError(CompilationError::PrepareError(
+            PrepareError::Deserialization,
+        )),
+        FunctionCallError::HostError(HostError::GasExceeded),
+        FunctionCallError::HostError(HostError::InvalidAccountId),
+        FunctionCallError::LinkError { msg: "no such import".to_string() },
+        FunctionCallError::MemoryCapExceeded { used: 2, cap: 1 },
+    ];
+    for error in &errors {
+        assert_round_trips(error);
+    }
+    assert_eq!(
+        serde_json::to_value(&errors[4]).unwrap(),
+        serde_json::json!({ "HostError": "GasExceeded" })
+    );
+}
@@ -0,0 +1,80 @@
+use crate::logic::mocks::mock_external::MockedExternal;
+use crate::logic::types::ReturnData;
+use crate::runner::VMKindExt;
+use crate::tests::{create_context, test_vm_config, with_vm_variants};
+use crate::ContractCode;
+use unc_parameters::vm::VMKind;
+use unc_parameters::RuntimeFeesConfig;
+
+/// Writes, reads and removes the key `k`, and returns the results of the
+/// host functions followed by the registers they set.
+const CONTRACT: &str = r#"
+(module
+  (import "env" "write_register" (func $write_register (param i64 i64 i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (import "env" "storage_write"
+    (func $storage_write (param i64 i64 i64 i64 i64) (result i64)))
+  (import "env" "storage_read" (func $storage_read (param i64 i64 i64) (result i64)))
+  (import "env" "storage_remove" (func $storage_remove (param i64 i64 i64) (result i64)))
+  (import "env" "storage_has_key" (func $storage_has_key (param i64 i64) (result i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (memory 1)
+  (data (i32.const 0) "k")
+  (data (i32.const 8) "v1")
+  (data (i32.const 16) "v2")
+  (data (i32.const 24) "r0")
+  (func (export "main")
+    (call $write_register (i64.const 0) (i64.const 2) (i64.const 24))
+    ;; Nothing is evicted, the register keeps its content.
+    (i64.store8 (i32.const 100) (call $storage_write
+      (i64.const 1) (i64.const 0) (i64.const 2) (i64.const 8) (i64.const 0)))
+    (call $read_register (i64.const 0) (i64.const 109))
+    (i64.store8 (i32.const 101)
+      (call $storage_read (i64.const 1) (i64.const 0) (i64.const 1)))
+    (i64.store8 (i32.const 102) (call $storage_write
+      (i64.const 1) (i64.const 0) (i64.const 2) (i64.const 16) (i64.const 0)))
+    (i64.store8 (i32.const 103)
+      (call $storage_remove (i64.const 1) (i64.const 0) (i64.const 2)))
+    (i64.store8 (i32.const 104)
+      (call $storage_read (i64.const 1) (i64.const 0) (i64.const 3)))
+    (i64.store8 (i32.const 105) (call $storage_has_key (i64.const 1) (i64.const 0)))
+    (i64.store8 (i32.const 106)
+      (call $storage_remove (i64.const 1) (i64.const 0) (i64.const 0)))
+    ;; An empty value is present.
+    (i64.store8 (i32.const 107) (call $storage_write
+      (i64.const 1) (i64.const 0) (i64.const 0) (i64.const 8) (i64.const 0)))
+    (i64.store8 (i32.const 108)
+      (call $storage_read (i64.const 1) (i64.const 0) (i64.const 4)))
+    (call $read_register (i64.const 0) (i64.const 111))
+    (call $read_register (i64.const 1) (i64.const 113))
+    (call $read_register (i64.const 2) (i64.const 115))
+    (i64.store (i32.const 117) (call $register_len (i64.const 3)))
+    (i64.store (i32.const 125) (call $register_len (i64.const 4)))
+    (call $value_return (i64.const 33) (i64.const 100)))
+)"#;
+
+#[test]
+fn test_read_your_writes() {
+    let code = ContractCode::new(wat::parse_str(CONTRACT).unwrap(), None);
+    let mut expected = vec![0, 1, 1, 1, 0, 0, 0, 0, 1];
+    expected.extend(b"r0v1v1v2");
+    expected.extend(u64::MAX.to_le_bytes());
+    expected.extend(0u64.to_le_bytes());
+    let fees = RuntimeFeesConfig::test();
+    with_vm_variants(&test_vm_config(), |vm_kind: VMKind| {
+        for buffer_storage_writes in [false, true] {
+            let mut config = test_vm_config();
+            config.buffer_storage_writes = buffer_storage_writes;
+            let runtime = vm_kind.runtime(config).unwrap();
+            let mut ext = MockedExternal::new();
+            let outcome = runtime
+                .run(&code, "main", &mut ext, create_context(vec![]), &fees, &[], None)
+                .unwrap();
+            assert_eq!(outcome.aborted, None, "{vm_kind:?}");
+            let context = format!("{vm_kind:?} buffer_storage_writes: {buffer_storage_writes}");
+            assert_eq!(outcome.return_data, ReturnData::Value(expected.clone()), "{context}");
+            assert_eq!(ext.fake_trie.get(&b"k"[..]), Some(&vec![]), "{context}");
+        }
+    });
+}
@@ -1,10 +1,14 @@
+use crate::logic::types::PromiseResult;
 use crate::logic::{
-    mocks::mock_external::MockedExternal, ProtocolVersion, ReturnData, VMContext, VMOutcome,
+    mocks::mock_external::MockedExternal, ActionReceipt, GasProfile, ProtocolVersion, ReturnData,
+    VMContext, VMOutcome,
 };
 use crate::runner::VMKindExt;
-use crate::ContractCode;
-use unc_parameters::vm::{ContractPrepareVersion, VMKind};
+use crate::testing::snapshot::{self, Snapshot};
+use crate::{check_backend, parse_wat, BackendRejection, ContractCode, RunOptions, WatError, WatLimits};
+use unc_parameters::vm::VMKind;
 use unc_parameters::{RuntimeConfig, RuntimeConfigStore, RuntimeFeesConfig};
+use unc_primitives_core::config::ViewConfig;
 use unc_primitives_core::types::Gas;
 use unc_primitives_core::version::ProtocolFeature;
 use std::{collections::HashSet, fmt::Write, sync::Arc};
@@ -36,31 +40,57 @@ pub(crate) fn test_builder() -> TestBuilder {
     }
     TestBuilder {
         code: ContractCode::new(Vec::new(), None),
-        context,
-        method: "main".to_string(),
+        calls: vec![Call {
+            method: "main".to_string(),
+            context,
+            promise_results: Vec::new(),
+            want: None,
+        }],
         protocol_versions: vec![u32::MAX],
         skip,
         opaque_error: false,
         opaque_outcome: false,
+        expect_storage: None,
+        expect_gas_profile: None,
+        expect_receipts: None,
+        expect_snapshot: None,
     }
 }
 
+/// A method invocation of a test, see [`TestBuilder::then_call`].
+struct Call {
+    method: String,
+    context: VMContext,
+    promise_results: Vec<PromiseResult>,
+    /// Expected outcome of the calls after the first one.
+    want: Option<expect_test::Expect>,
+}
+
 pub(crate) struct TestBuilder {
     code: ContractCode,
-    context: VMContext,
+    /// The calls to run one after the other, never empty.
+    calls: Vec<Call>,
     protocol_versions: Vec<ProtocolVersion>,
-    method: String,
     skip: HashSet<VMKind>,
     opaque_error: bool,
     opaque_outcome: bool,
+    expect_storage: Option<expect_test::Expect>,
+    expect_gas_profile: Option<expect_test::Expect>,
+    expect_receipts: Option<expect_test::Expect>,
+    expect_snapshot: Option<expect_test::Expect>,
 }
 
 impl TestBuilder {
-    pub(crate) fn wat(mut self, wat: &str) -> Self {
-        let wasm = wat::parse_str(wat)
-            .unwrap_or_else(|err| panic!("failed to parse input wasm: {err}\n{wat}"));
+    pub(crate) fn wat(self, wat: &str) -> Self {
+        self.try_wat(wat).unwrap_or_else(|err| panic!("failed to parse input wasm: {err}\n{wat}"))
+    }
+
+    /// Same as [`Self::wat`] but returns the parse errors, which include
+    /// exceeding the default [`WatLimits`].
+    pub(crate) fn try_wat(mut self, wat: &str) -> Result<Self, WatError> {
+        let wasm = parse_wat(wat, &WatLimits::default())?;
         self.code = ContractCode::new(wasm, None);
-        self
+        Ok(self)
     }
 
     pub(crate) fn wasm(mut self, wasm: &[u8]) -> Self {
@@ -73,13 +103,67 @@ impl TestBuilder {
         self.code.code()
     }
 
+    /// The call the setters of the method, the context and the promise
+    /// results apply to: the last one added by [`Self::then_call`], or the
+    /// first call.
+    fn call_mut(&mut self) -> &mut Call {
+        self.calls.last_mut().expect("a test has at least one call")
+    }
+
     pub(crate) fn method(mut self, method: &str) -> Self {
-        self.method = method.to_string();
+        self.call_mut().method = method.to_string();
+        self
+    }
+
+    pub(crate) fn gas(self, gas: Gas) -> Self {
+        self.context_mut(|context| context.prepaid_gas = gas)
+    }
+
+    pub(crate) fn input(self, input: &[u8]) -> Self {
+        self.context_mut(|context| context.input = input.to_vec())
+    }
+
+    pub(crate) fn promise_results(mut self, promise_results: Vec<PromiseResult>) -> Self {
+        self.call_mut().promise_results = promise_results;
+        self
+    }
+
+    /// Runs the call as a view burning at most its prepaid gas.
+    pub(crate) fn view(self) -> Self {
+        self.context_mut(|context| {
+            context.view_config = Some(ViewConfig { max_gas_burnt: context.prepaid_gas })
+        })
+    }
+
+    #[allow(dead_code)]
+    pub(crate) fn view_config(self, view_config: ViewConfig) -> Self {
+        self.context_mut(|context| context.view_config = Some(view_config))
+    }
+
+    pub(crate) fn context_mut(mut self, f: impl FnOnce(&mut VMContext)) -> Self {
+        f(&mut self.call_mut().context);
         self
     }
 
-    pub(crate) fn gas(mut self, gas: Gas) -> Self {
-        self.context.prepaid_gas = gas;
+    /// Also call `method` after the previous calls, against the storage they
+    /// left, and check its outcome against `want`, formatted like the one of
+    /// the first call.
+    ///
+    /// The call starts with the context of the previous one, without its
+    /// promise results, and the setters called after this one apply to it.
+    /// The account balance and storage usage are the ones the previous call
+    /// left, and so is the storage, even if the previous call failed: the
+    /// mocked external does not roll back the writes of failed calls.  The
+    /// outcome must be the same for all tested protocol versions.
+    pub(crate) fn then_call(mut self, method: &str, want: expect_test::Expect) -> Self {
+        let context = self.call_mut().context.clone();
+        let call = Call {
+            method: method.to_string(),
+            context,
+            promise_results: Vec::new(),
+            want: Some(want),
+        };
+        self.calls.push(call);
         self
     }
 
@@ -93,6 +177,45 @@ impl TestBuilder {
         self
     }
 
+    /// Also check the storage of the mocked external after the last call
+    /// against `want`, see [`fmt_storage`].
+    ///
+    /// The storage must be the same for all tested protocol versions.
+    pub(crate) fn expect_storage(mut self, want: expect_test::Expect) -> Self {
+        self.expect_storage = Some(want);
+        self
+    }
+
+    /// Also check the host functions called, as listed by the gas profile of
+    /// the outcome of the first call, against `want`, see [`fmt_gas_profile`].
+    ///
+    /// The gas amounts are left out of the snapshot, so that it does not
+    /// change with the costs, but they must be the same on all the VMs and
+    /// add up to the burnt gas.
+    pub(crate) fn expect_gas_profile(mut self, want: expect_test::Expect) -> Self {
+        self.expect_gas_profile = Some(want);
+        self
+    }
+
+    /// Also check the receipts of the outcome of the first call against
+    /// `want`, see [`fmt_receipts`].
+    ///
+    /// The receipts are compared across the VMs whether or not this is set.
+    pub(crate) fn expect_receipts(mut self, want: expect_test::Expect) -> Self {
+        self.expect_receipts = Some(want);
+        self
+    }
+
+    /// Also check the [`Snapshot`] of the first call, with the storage it
+    /// left, against `want`.
+    ///
+    /// Unlike the other expectations, this covers everything the call did,
+    /// gas included.  The snapshot must be the same on all the VMs.
+    pub(crate) fn expect_snapshot(mut self, want: expect_test::Expect) -> Self {
+        self.expect_snapshot = Some(want);
+        self
+    }
+
     // We only test trapping tests on Wasmer, as of version 0.17, when tests executed in parallel,
     // Wasmer signal handlers may catch signals thrown from the Wasmtime, and produce fake failing tests.
     pub(crate) fn skip_wasmtime(mut self) -> Self {
@@ -205,67 +328,150 @@ impl TestBuilder {
                 let runtime_config = runtime_config_store.get_config(protocol_version);
 
                 // NearVM includes a different contract preparation algorithm, that is not supported on old protocol versions
-                if vm_kind == VMKind::NearVm
-                    && runtime_config.wasm_config.limit_config.contract_prepare_version
-                        != ContractPrepareVersion::V2
+                if let Err(BackendRejection::UnsupportedPrepareVersion(_)) =
+                    check_backend(vm_kind, &runtime_config.wasm_config)
                 {
                     continue;
                 }
 
                 let mut fake_external = MockedExternal::new();
-                let config = runtime_config.wasm_config.clone();
+                let config = crate::logic::Config::from(runtime_config.wasm_config.clone());
                 let fees = RuntimeFeesConfig::test();
-                let context = self.context.clone();
-
-                let promise_results = vec![];
-
-                let runtime = vm_kind.runtime(config).expect("runtime has not been compiled");
+                let runtime =
+                    vm_kind.runtime(config.clone()).expect("runtime has not been compiled");
                 println!("Running {:?} for protocol version {}", vm_kind, protocol_version);
-                let outcome = runtime
-                    .run(
-                        &self.code,
-                        &self.method,
-                        &mut fake_external,
-                        context,
-                        &fees,
-                        &promise_results,
-                        None,
-                    )
-                    .expect("execution failed");
-
-                let mut got = String::new();
-
-                if !self.opaque_outcome {
-                    fmt_outcome_without_abort(&outcome, &mut got).unwrap();
-                    writeln!(&mut got).unwrap();
-                }
 
-                if let Some(err) = outcome.aborted {
-                    let err_str = err.to_string();
-                    assert!(err_str.len() < 1000, "errors should be bounded in size to prevent abuse via exhausting the storage space");
-                    if self.opaque_error {
-                        writeln!(&mut got, "Err: ...").unwrap();
-                    } else {
-                        writeln!(&mut got, "Err: {err_str}").unwrap();
+                let mut outcomes: Vec<VMOutcome> = Vec::new();
+                let mut first_storage = None;
+                for call in &self.calls {
+                    let mut context = call.context.clone();
+                    if let Some(previous) = outcomes.last() {
+                        context.account_balance = previous.balance;
+                        context.storage_usage = previous.storage_usage;
                     }
-                };
-
-                results.push((vm_kind, got));
+                    let checked = context.clone();
+                    let outcome = runtime
+                        .run_with_options(
+                            &self.code,
+                            &call.method,
+                            &mut fake_external,
+                            context,
+                            &fees,
+                            &call.promise_results,
+                            None,
+                            &RunOptions { record_receipts: true, ..RunOptions::default() },
+                        )
+                        .expect("execution failed");
+                    if let Err(violation) = crate::validate_outcome(&outcome, &checked, &config) {
+                        let method = &call.method;
+                        panic!("{vm_kind:?}: {method} breaks an invariant: {violation}");
+                    }
+                    first_storage.get_or_insert_with(|| fake_external.fake_trie.clone());
+                    outcomes.push(outcome);
+                }
+                let got: Vec<String> = outcomes.iter().map(|outcome| self.fmt(outcome)).collect();
+
+                let storage = fmt_storage(&fake_external);
+                let outcome = outcomes.swap_remove(0);
+                let snapshot = Snapshot::new(
+                    &outcome,
+                    &Default::default(),
+                    &first_storage.expect("a test has at least one call"),
+                );
+                // Contracts failing to load on old protocol versions get no
+                // profile.
+                let gas_profile = outcome.gas_profile.unwrap_or_default();
+                if self.expect_gas_profile.is_some() {
+                    let profiled_gas = gas_profile.wasm_ops
+                        + gas_profile.loading
+                        + gas_profile.host_functions.values().map(|host| host.gas).sum::<Gas>();
+                    assert_eq!(profiled_gas, outcome.burnt_gas, "{vm_kind:?}: {gas_profile:?}");
+                }
+                results.push((vm_kind, got, storage, gas_profile, outcome.receipts, snapshot));
             }
 
             if !results.is_empty() {
-                want.assert_eq(&results[0].1);
+                want.assert_eq(&results[0].1[0]);
+                for (call, got) in self.calls.iter().zip(&results[0].1).skip(1) {
+                    call.want.as_ref().expect("chained calls have expectations").assert_eq(got);
+                }
+                if let Some(want_storage) = &self.expect_storage {
+                    want_storage.assert_eq(&results[0].2);
+                }
+                if let Some(want_gas_profile) = &self.expect_gas_profile {
+                    want_gas_profile.assert_eq(&fmt_gas_profile(&results[0].3));
+                }
+                if let Some(want_receipts) = &self.expect_receipts {
+                    want_receipts.assert_eq(&fmt_receipts(&results[0].4));
+                }
+                if let Some(want_snapshot) = &self.expect_snapshot {
+                    want_snapshot.assert_eq(&results[0].5.to_text());
+                }
                 for i in 1..results.len() {
                     if results[i].1 != results[0].1 {
                         panic!(
                             "Inconsistent VM Output:\n{:?}:\n{}\n\n{:?}:\n{}",
-                            results[0].0, results[0].1, results[i].0, results[i].1
+                            results[0].0,
+                            results[0].1.join("\n"),
+                            results[i].0,
+                            results[i].1.join("\n")
+                        )
+                    }
+                    if self.expect_storage.is_some() && results[i].2 != results[0].2 {
+                        panic!(
+                            "Inconsistent VM Storage:\n{:?}:\n{}\n\n{:?}:\n{}",
+                            results[0].0, results[0].2, results[i].0, results[i].2
                         )
                     }
+                    if self.expect_gas_profile.is_some() && results[i].3 != results[0].3 {
+                        panic!(
+                            "Inconsistent VM Gas Profile:\n{:?}:\n{:?}\n\n{:?}:\n{:?}",
+                            results[0].0, results[0].3, results[i].0, results[i].3
+                        )
+                    }
+                    if results[i].4 != results[0].4 {
+                        panic!(
+                            "Inconsistent VM Receipts:\n{:?}:\n{}\n\n{:?}:\n{}",
+                            results[0].0,
+                            fmt_receipts(&results[0].4),
+                            results[i].0,
+                            fmt_receipts(&results[i].4)
+                        )
+                    }
+                    if self.expect_snapshot.is_some() {
+                        if let Some(diff) = snapshot::diff(&results[0].5, &results[i].5) {
+                            panic!(
+                                "Inconsistent VM Snapshot between {:?} and {:?}:\n{diff}",
+                                results[0].0, results[i].0
+                            )
+                        }
+                    }
                 }
             }
         }
     }
+
+    /// Formats `outcome` for the expectations, as set by
+    /// [`Self::opaque_outcome`] and [`Self::opaque_error`].
+    fn fmt(&self, outcome: &VMOutcome) -> String {
+        let mut got = String::new();
+
+        if !self.opaque_outcome {
+            fmt_outcome_without_abort(outcome, &mut got).unwrap();
+            writeln!(&mut got).unwrap();
+        }
+
+        if let Some(err) = &outcome.aborted {
+            let err_str = err.to_string();
+            assert!(err_str.len() < 1000, "errors should be bounded in size to prevent abuse via exhausting the storage space");
+            if self.opaque_error {
+                writeln!(&mut got, "Err: ...").unwrap();
+            } else {
+                writeln!(&mut got, "Err: {err_str}").unwrap();
+            }
+        };
+        got
+    }
 }
 
 fn fmt_outcome_without_abort(
@@ -288,3 +494,51 @@ fn fmt_outcome_without_abort(
     )?;
     Ok(())
 }
+
+/// Formats the host functions of `profile` as one `name: calls` line per
+/// function, sorted by name.
+fn fmt_gas_profile(profile: &GasProfile) -> String {
+    let mut out = String::new();
+    for (name, host) in &profile.host_functions {
+        writeln!(out, "{name}: {} calls", host.calls).unwrap();
+    }
+    out
+}
+
+/// Formats `receipts` in order as one `receiver after dependencies` line per
+/// receipt, followed by one indented line per action.
+fn fmt_receipts(receipts: &[ActionReceipt]) -> String {
+    let mut out = String::new();
+    for receipt in receipts {
+        writeln!(
+            out,
+            "{}: {} after {:?}",
+            receipt.receipt_index, receipt.receiver_id, receipt.dependencies
+        )
+        .unwrap();
+        for action in &receipt.actions {
+            writeln!(out, "  {action:?}").unwrap();
+        }
+    }
+    out
+}
+
+/// Values longer than this many bytes are cut short in storage snapshots.
+const STORAGE_VALUE_LIMIT: usize = 32;
+
+/// Formats the storage of `ext` as one `key: value` line per entry, sorted by
+/// key, with keys and values hex-encoded.
+fn fmt_storage(ext: &MockedExternal) -> String {
+    let mut entries: Vec<_> = ext.fake_trie.iter().collect();
+    entries.sort();
+    let mut out = String::new();
+    for (key, value) in entries {
+        let shown = &value[..value.len().min(STORAGE_VALUE_LIMIT)];
+        write!(out, "{}: {}", hex::encode(key), hex::encode(shown)).unwrap();
+        if shown.len() < value.len() {
+            write!(out, "... ({} bytes)", value.len()).unwrap();
+        }
+        writeln!(out).unwrap();
+    }
+    out
+}
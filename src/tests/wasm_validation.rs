@@ -120,3 +120,158 @@ fn ensure_fails_execution() {
         "#]]);
     }
 }
+
+#[test]
+fn test_simd() {
+    use crate::logic::errors::FunctionCallError;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::logic::types::ReturnData;
+    use crate::logic::ContractPrepareVersion;
+    use crate::runner::VMKindExt;
+    use crate::tests::create_context;
+    use crate::ContractCode;
+    use unc_parameters::vm::VMKind;
+    use unc_parameters::RuntimeFeesConfig;
+
+    let code = wat::parse_str(
+        r#"
+(module
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (memory 1)
+  (func (export "main")
+    (v128.store (i32.const 0)
+      (i32x4.add (v128.const i32x4 1 2 3 4) (v128.const i32x4 10 20 30 40)))
+    (call $value_return (i64.const 16) (i64.const 0)))
+)"#,
+    )
+    .unwrap();
+    let code = ContractCode::new(code, None);
+    let mut config = test_vm_config();
+    config.limit_config.contract_prepare_version = ContractPrepareVersion::V2;
+    assert!(prepare_contract(code.code(), &config, VMKind::Wasmtime).is_err());
+    config.simd = true;
+    let fees = RuntimeFeesConfig::test();
+    with_vm_variants(&config, |vm_kind: VMKind| {
+        let runtime = vm_kind.runtime(config.clone()).unwrap();
+        let outcome = runtime
+            .run(
+                &code,
+                "main",
+                &mut MockedExternal::new(),
+                create_context(vec![]),
+                &fees,
+                &[],
+                None,
+            )
+            .unwrap();
+        match vm_kind {
+            VMKind::Wasmtime => {
+                let sums: Vec<u8> =
+                    [11u32, 22, 33, 44].iter().flat_map(|x| x.to_le_bytes()).collect();
+                assert_eq!(outcome.aborted, None);
+                assert_eq!(outcome.return_data, ReturnData::Value(sums));
+            }
+            // The singlepass compilers do not generate code for SIMD yet.
+            _ => assert!(
+                matches!(outcome.aborted, Some(FunctionCallError::CompilationError(_))),
+                "{vm_kind:?}: {:?}",
+                outcome.aborted
+            ),
+        }
+    });
+
+    // The pwasm passes cannot parse SIMD instructions.
+    config.limit_config.contract_prepare_version = ContractPrepareVersion::V1;
+    assert!(prepare_contract(code.code(), &config, VMKind::Wasmtime).is_err());
+}
+
+#[test]
+fn test_bulk_memory_reftypes() {
+    use crate::logic::errors::FunctionCallError;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::logic::types::ReturnData;
+    use crate::logic::ContractPrepareVersion;
+    use crate::runner::VMKindExt;
+    use crate::tests::create_context;
+    use crate::ContractCode;
+    use unc_parameters::vm::VMKind;
+    use unc_parameters::RuntimeFeesConfig;
+
+    let code = wat::parse_str(
+        r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (type $const (func (result i64)))
+  (memory 1)
+  (table 1 funcref)
+  (global $eleven funcref (ref.func $eleven))
+  (elem (i32.const 0) $seven)
+  (func $seven (result i64) (i64.const 7))
+  (func $eleven (result i64) (i64.const 11))
+  ;; Fills as many bytes as the input says and copies them, returning the
+  ;; last byte copied.
+  (func (export "copy") (local $len i32)
+    (call $input (i64.const 0))
+    (call $read_register (i64.const 0) (i64.const 0))
+    (local.set $len (i32.load (i32.const 0)))
+    (memory.fill (i32.const 100) (i32.const 42) (local.get $len))
+    (memory.copy (i32.const 10000) (i32.const 100) (local.get $len))
+    (call $value_return
+      (i64.const 1) (i64.extend_i32_u (i32.add (i32.const 9999) (local.get $len)))))
+  ;; Grows the table with the function of the global and calls both entries.
+  (func (export "table")
+    (drop (table.grow (global.get $eleven) (i32.const 3)))
+    (i64.store (i32.const 0)
+      (i64.add
+        (call_indirect (type $const) (i32.const 0))
+        (call_indirect (type $const) (i32.const 3))))
+    (call $value_return (i64.const 8) (i64.const 0)))
+)"#,
+    )
+    .unwrap();
+    let code = ContractCode::new(code, None);
+    let mut config = test_vm_config();
+    config.limit_config.contract_prepare_version = ContractPrepareVersion::V2;
+    assert!(prepare_contract(code.code(), &config, VMKind::Wasmtime).is_err());
+    config.bulk_memory_reftypes = true;
+    let gas_per_byte = u64::from(config.regular_op_cost).div_ceil(8);
+    let fees = RuntimeFeesConfig::test();
+    with_vm_variants(&config, |vm_kind: VMKind| {
+        let runtime = vm_kind.runtime(config.clone()).unwrap();
+        let run = |method: &str, len: u32| {
+            let context = create_context(len.to_le_bytes().to_vec());
+            runtime
+                .run(&code, method, &mut MockedExternal::new(), context, &fees, &[], None)
+                .unwrap()
+        };
+        if vm_kind == VMKind::Wasmer0 {
+            let outcome = run("copy", 0);
+            assert!(
+                matches!(outcome.aborted, Some(FunctionCallError::CompilationError(_))),
+                "{:?}",
+                outcome.aborted
+            );
+            return;
+        }
+        let empty = run("copy", 0);
+        assert_eq!(empty.aborted, None, "{vm_kind:?}");
+        assert_eq!(empty.return_data, ReturnData::Value(vec![0]));
+        for len in [1, 8, 1000] {
+            let outcome = run("copy", len);
+            assert_eq!(outcome.aborted, None, "{vm_kind:?}");
+            assert_eq!(outcome.return_data, ReturnData::Value(vec![42]));
+            // The fill and the copy each pay for every byte.
+            let gas = 2 * u64::from(len) * gas_per_byte;
+            assert_eq!(outcome.burnt_gas - empty.burnt_gas, gas, "{vm_kind:?} {len}");
+        }
+        let outcome = run("table", 0);
+        assert_eq!(outcome.aborted, None, "{vm_kind:?}");
+        assert_eq!(outcome.return_data, ReturnData::Value(18u64.to_le_bytes().to_vec()));
+    });
+
+    // Only V2 accepts the proposals.
+    config.limit_config.contract_prepare_version = ContractPrepareVersion::V1;
+    assert!(prepare_contract(code.code(), &config, VMKind::Wasmtime).is_err());
+}
@@ -0,0 +1,347 @@
+//! Serving many view calls of the same contract.
+//!
+//! RPC nodes answer a large number of view calls of a handful of popular
+//! contracts.  Going through [`crate::run`] for each of them pays for setting
+//! up the runtime and fetching the compiled contract from the cache every
+//! time.  This module keeps all of that around between calls instead: a
+//! [`SharedContract`] is compiled once into an in-memory cache, each serving
+//! thread keeps a [`ThroughputRunner`] with its own runtime alive, and only
+//! the per-call part of the context is swapped in for every call.
+//!
+//! Runtimes cannot be shared between threads, so a server keeps a pool of
+//! runners, one per worker thread, all made from the same [`SharedContract`].
+//! The instances of the calls are pooled as far as the VM lets them be:
+//! NearVM calls of all the runners reuse the memories of a
+//! [`crate::NearVmMemoryPool`] of the contract, and the calls of a
+//! [`ThroughputRunner::view_many`] batch share one instance.
+//!
+//! An [`AdmissionController`] set with [`SharedContract::with_admission`]
+//! keeps the server from spending its threads on methods which became slow:
+//! [`ThroughputRunner::view_admitted`] fails the calls it rejects with
+//! [`Overloaded`], and servers with a queue of calls ask
+//! [`ThroughputRunner::admit`] where to put each call.
+//!
+//! `unc-vm-run throughput` measures the calls per second each backend
+//! achieves with this API on a given contract, and `cargo bench --bench
+//! throughput` on a small one, with and without batches.
+
+use crate::admission::{Admission, AdmissionController, Overloaded};
+use crate::logic::errors::{CacheError, CompilationError};
+use crate::logic::errors::VMRunnerError;
+use crate::logic::types::PromiseResult;
+use crate::logic::{External, VMContext, VMOutcome};
+use crate::runner::{
+    BackendUnavailable, CompileOptions, HostSettings, MethodCall, RunOptions, VMKindExt, VMResult,
+    VM,
+};
+use crate::{ContractCode, MockCompiledContractCache};
+use std::sync::Arc;
+use std::time::Instant;
+use crate::logic::Config;
+use unc_parameters::RuntimeFeesConfig;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ThroughputRunnerError {
+    #[error(transparent)]
+    BackendUnavailable(#[from] BackendUnavailable),
+    #[error("the contract does not compile: {0}")]
+    CompilationError(CompilationError),
+    #[error(transparent)]
+    CacheError(#[from] CacheError),
+}
+
+/// A contract compiled once for serving calls from any number of threads.
+///
+/// Cloning is cheap and shares the compiled contract.
+#[derive(Clone)]
+pub struct SharedContract {
+    config: Config,
+    code: Arc<ContractCode>,
+    cache: Arc<MockCompiledContractCache>,
+    fees: Arc<RuntimeFeesConfig>,
+    admission: Option<Arc<AdmissionController>>,
+    /// Settings of the host the runners run the calls on.
+    host: HostSettings,
+    /// Memories of the NearVM calls of the runners, as many as there are
+    /// CPUs.
+    #[cfg(all(feature = "unc_vm", target_arch = "x86_64"))]
+    memory_pool: Arc<crate::NearVmMemoryPool>,
+}
+
+impl SharedContract {
+    /// Compiles `code` with the VM of `config`.
+    pub fn new(
+        config: Config,
+        fees: Arc<RuntimeFeesConfig>,
+        code: ContractCode,
+    ) -> Result<Self, ThroughputRunnerError> {
+        let runtime = config.vm_kind.runtime(config.clone())?;
+        let cache = Arc::new(MockCompiledContractCache::default());
+        runtime.precompile(&code, &*cache)?.map_err(ThroughputRunnerError::CompilationError)?;
+        Ok(Self {
+            config,
+            code: Arc::new(code),
+            cache,
+            fees,
+            admission: None,
+            host: HostSettings::default(),
+            #[cfg(all(feature = "unc_vm", target_arch = "x86_64"))]
+            memory_pool: Arc::new(crate::NearVmMemoryPool::new(
+                std::thread::available_parallelism().map_or(1, |n| n.get()),
+            )),
+        })
+    }
+
+    /// Accounts the wall time of the calls of the runners made afterwards in
+    /// `admission`, which decides which calls to admit.
+    ///
+    /// The controller can be shared with other contracts, its stats are per
+    /// contract and method.
+    pub fn with_admission(mut self, admission: Arc<AdmissionController>) -> Self {
+        self.admission = Some(admission);
+        self
+    }
+
+    /// Runs the calls of the runners made afterwards with the `host`
+    /// settings.
+    pub fn with_host_settings(mut self, host: HostSettings) -> Self {
+        self.host = host;
+        self
+    }
+
+    /// Makes a runner calling the contract with `context`, to be kept by the
+    /// thread serving the calls.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `context` is not a view context.
+    pub fn runner(&self, context: VMContext) -> Result<ThroughputRunner, ThroughputRunnerError> {
+        assert!(context.is_view(), "throughput runners only serve view calls");
+        let runtime = self.config.vm_kind.runtime_on_host(
+            self.config.clone(),
+            CompileOptions::default(),
+            self.host.clone(),
+        )?;
+        let options = RunOptions {
+            #[cfg(all(feature = "unc_vm", target_arch = "x86_64"))]
+            memory_pool: Some(Arc::clone(&self.memory_pool)),
+            ..RunOptions::default()
+        };
+        Ok(ThroughputRunner { contract: self.clone(), runtime, context, options })
+    }
+}
+
+/// Runs view calls of a [`SharedContract`] with the setup amortized over calls.
+pub struct ThroughputRunner {
+    contract: SharedContract,
+    runtime: Box<dyn VM>,
+    context: VMContext,
+    options: RunOptions,
+}
+
+impl ThroughputRunner {
+    /// Calls `method_name` with `input` against the state of `ext`.
+    ///
+    /// Everything but the input is taken from the context the runner was made
+    /// with; use [`Self::context_mut`] to change it between calls, e.g. when a
+    /// new block arrives.
+    pub fn view(&self, method_name: &str, input: Vec<u8>, ext: &mut dyn External) -> VMResult {
+        let context = VMContext { input, ..self.context.clone() };
+        let promise_results: &[PromiseResult] = &[];
+        let start = Instant::now();
+        let result = self.runtime.run_with_options(
+            &self.contract.code,
+            method_name,
+            ext,
+            context,
+            &self.contract.fees,
+            promise_results,
+            Some(&*self.contract.cache),
+            &self.options,
+        );
+        if let Some(admission) = &self.contract.admission {
+            admission.record(self.contract.code.hash(), method_name, start.elapsed());
+        }
+        result
+    }
+
+    /// Calls the methods of `calls` with their input one after the other
+    /// against the state of `ext`, as [`VM::run_many`]: NearVM runs them on
+    /// one instance of the contract.
+    ///
+    /// The calls are not accounted in the admission controller, which
+    /// accounts the time of single calls.
+    pub fn view_many(
+        &self,
+        calls: &[(&str, Vec<u8>)],
+        ext: &mut dyn External,
+    ) -> Result<Vec<VMOutcome>, VMRunnerError> {
+        let calls: Vec<MethodCall> = calls
+            .iter()
+            .map(|(method_name, input)| MethodCall {
+                method_name: method_name.to_string(),
+                context: VMContext { input: input.clone(), ..self.context.clone() },
+            })
+            .collect();
+        self.runtime.run_many(
+            &self.contract.code,
+            &calls,
+            ext,
+            &self.contract.fees,
+            &[],
+            Some(&*self.contract.cache),
+            &self.options,
+        )
+    }
+
+    /// Decides whether to call `method_name` now, always admitting calls
+    /// without an [`AdmissionController`].
+    pub fn admit(&self, method_name: &str) -> Admission {
+        match &self.contract.admission {
+            Some(admission) => admission.admit(self.contract.code.hash(), method_name),
+            None => Admission::Admit,
+        }
+    }
+
+    /// Calls `method_name` as [`Self::view`] unless the admission controller
+    /// rejects the call.
+    ///
+    /// Deprioritized calls are made: the runner has no queue to put them at
+    /// the end of.
+    pub fn view_admitted(
+        &self,
+        method_name: &str,
+        input: Vec<u8>,
+        ext: &mut dyn External,
+    ) -> Result<VMResult, Overloaded> {
+        if let Admission::Reject(overloaded) = self.admit(method_name) {
+            return Err(overloaded);
+        }
+        Ok(self.view(method_name, input, ext))
+    }
+
+    /// The context used for the following calls.
+    pub fn context_mut(&mut self) -> &mut VMContext {
+        &mut self.context
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::logic::types::ReturnData;
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+    use unc_primitives_core::config::ViewConfig;
+
+    const ECHO_CONTRACT: &str = r#"
+(module
+  (import "env" "input" (func $input (param i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (import "env" "register_len" (func $register_len (param i64) (result i64)))
+  (import "env" "read_register" (func $read_register (param i64 i64)))
+  (memory 1)
+  (func (export "echo")
+    (call $input (i64.const 0))
+    (call $read_register (i64.const 0) (i64.const 0))
+    (call $value_return (call $register_len (i64.const 0)) (i64.const 0)))
+)"#;
+
+    #[test]
+    fn test_shared_contract_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<SharedContract>();
+    }
+
+    #[test]
+    fn test_view_calls() {
+        let code = ContractCode::new(wat::parse_str(ECHO_CONTRACT).unwrap(), None);
+        let config = test_vm_config();
+        with_vm_variants(&config, |vm_kind| {
+            let mut config = config.clone();
+            config.vm_kind = vm_kind;
+            let fees = Arc::new(RuntimeFeesConfig::test());
+            let contract =
+                SharedContract::new(config, fees, ContractCode::new(code.code().to_vec(), None))
+                    .unwrap();
+            let mut context = create_context(Vec::new());
+            context.view_config = Some(ViewConfig { max_gas_burnt: 10u64.pow(14) });
+            std::thread::scope(|s| {
+                for thread in 0..2u8 {
+                    let contract = &contract;
+                    let context = context.clone();
+                    s.spawn(move || {
+                        let runner = contract.runner(context).unwrap();
+                        for call in 0..3u8 {
+                            let mut ext = MockedExternal::new();
+                            let outcome =
+                                runner.view("echo", vec![thread, call], &mut ext).unwrap();
+                            assert_eq!(outcome.aborted, None, "{vm_kind:?}");
+                            assert_eq!(outcome.return_data, ReturnData::Value(vec![thread, call]));
+                        }
+                    });
+                }
+            });
+        });
+    }
+
+    #[test]
+    fn test_view_many() {
+        let code = ContractCode::new(wat::parse_str(ECHO_CONTRACT).unwrap(), None);
+        let config = test_vm_config();
+        with_vm_variants(&config, |vm_kind| {
+            let mut config = config.clone();
+            config.vm_kind = vm_kind;
+            let fees = Arc::new(RuntimeFeesConfig::test());
+            let code = ContractCode::new(code.code().to_vec(), None);
+            let contract = SharedContract::new(config, fees, code).unwrap();
+            let mut context = create_context(Vec::new());
+            context.view_config = Some(ViewConfig { max_gas_burnt: 10u64.pow(14) });
+            let runner = contract.runner(context).unwrap();
+            let calls: Vec<(&str, Vec<u8>)> = (0..4u8).map(|call| ("echo", vec![call])).collect();
+            let outcomes = runner.view_many(&calls, &mut MockedExternal::new()).unwrap();
+            for ((_, input), outcome) in calls.iter().zip(&outcomes) {
+                assert_eq!(
+                    outcome,
+                    &runner.view("echo", input.clone(), &mut MockedExternal::new()).unwrap()
+                );
+                assert_eq!(outcome.return_data, ReturnData::Value(input.clone()), "{vm_kind:?}");
+            }
+            assert_eq!(outcomes.len(), calls.len());
+        });
+    }
+
+    #[test]
+    fn test_admission() {
+        use crate::admission::{AdmissionPolicy, OverloadAction};
+        use std::time::Duration;
+
+        let code = ContractCode::new(wat::parse_str(ECHO_CONTRACT).unwrap(), None);
+        let code_hash = *code.hash();
+        let config = test_vm_config();
+        let fees = Arc::new(RuntimeFeesConfig::test());
+        // Every call is slower than the threshold.
+        let policy = AdmissionPolicy {
+            p95_threshold: Duration::ZERO,
+            min_samples: 3,
+            action: OverloadAction::Reject,
+            ..AdmissionPolicy::default()
+        };
+        let admission = Arc::new(AdmissionController::new(policy));
+        let contract =
+            SharedContract::new(config, fees, code).unwrap().with_admission(admission.clone());
+        let mut context = create_context(Vec::new());
+        context.view_config = Some(ViewConfig { max_gas_burnt: 10u64.pow(14) });
+        let runner = contract.runner(context).unwrap();
+        let mut ext = MockedExternal::new();
+        for call in 0..3u8 {
+            let outcome = runner.view_admitted("echo", vec![call], &mut ext).unwrap().unwrap();
+            assert_eq!(outcome.return_data, ReturnData::Value(vec![call]));
+        }
+        assert_eq!(admission.stats(&code_hash, "echo").unwrap().len(), 3);
+        let overloaded = runner.view_admitted("echo", vec![], &mut ext).unwrap_err();
+        assert_eq!(overloaded.code_hash, code_hash);
+        assert_eq!(overloaded.method_name, "echo");
+        assert!(matches!(runner.admit("other"), Admission::Admit));
+    }
+}
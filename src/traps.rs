@@ -0,0 +1,311 @@
+//! The traps of the backends and the [`WasmTrap`] each of them aborts the
+//! call with.
+//!
+//! The traps of a contract are part of the outcome, so a contract must trap
+//! with the same [`WasmTrap`] whatever backend runs it.  Each backend turns
+//! its trap codes into a [`TrapKind`], which alone decides the [`WasmTrap`].
+//! The conversions match the trap codes exhaustively, so that a backend
+//! gaining a trap code fails to build until the code is mapped here.  The
+//! only exception is Wasmtime, whose traps are non-exhaustive: its new traps
+//! abort the call with [`crate::logic::errors::VMRunnerError::WasmUnknownError`].
+
+use crate::logic::errors::WasmTrap;
+
+/// What made the code of a contract trap, whatever backend ran it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, strum::EnumIter)]
+pub(crate) enum TrapKind {
+    /// An `unreachable` instruction was executed.
+    Unreachable,
+    /// A load or store outside of the memory.
+    MemoryOutOfBounds,
+    /// A `call_indirect` past the end of the table.
+    TableOutOfBounds,
+    /// Another access out of bounds, e.g. of a bulk memory instruction.
+    OutOfBounds,
+    /// A `call_indirect` to an uninitialized element of the table.
+    IndirectCallToNull,
+    /// A `call_indirect` to a function of another type.
+    BadSignature,
+    /// A division of the smallest integer by `-1`.
+    IntegerOverflow,
+    /// An integer division or remainder by zero.
+    IntegerDivisionByZero,
+    /// A float to integer conversion of NaN or of a value out of range.
+    BadConversionToInteger,
+    /// An arithmetic trap of Wasmer0, which does not tell the three above
+    /// apart.
+    IllegalArithmetic,
+    /// An atomic access to a misaligned address.
+    MisalignedAtomicAccess,
+    /// The call stack of the contract was exhausted.
+    StackOverflow,
+    /// A `call_indirect` past the end of the table on Wasmer0, which kept
+    /// its own trap for it.
+    CallIndirectOutOfBounds,
+}
+
+impl TrapKind {
+    pub(crate) fn wasm_trap(self) -> WasmTrap {
+        match self {
+            Self::Unreachable => WasmTrap::Unreachable,
+            Self::MemoryOutOfBounds | Self::TableOutOfBounds | Self::OutOfBounds => {
+                WasmTrap::MemoryOutOfBounds
+            }
+            Self::IndirectCallToNull => WasmTrap::IndirectCallToNull,
+            Self::BadSignature => WasmTrap::IncorrectCallIndirectSignature,
+            Self::IntegerOverflow
+            | Self::IntegerDivisionByZero
+            | Self::BadConversionToInteger
+            | Self::IllegalArithmetic => WasmTrap::IllegalArithmetic,
+            Self::MisalignedAtomicAccess => WasmTrap::MisalignedAtomicAccess,
+            Self::StackOverflow => WasmTrap::StackOverflow,
+            Self::CallIndirectOutOfBounds => WasmTrap::CallIndirectOOB,
+        }
+    }
+}
+
+/// The kind of a trap of NearVM, or `None` for running out of gas, which
+/// the instrumentation reports as a trap.
+#[cfg(all(feature = "unc_vm", target_arch = "x86_64"))]
+pub(crate) fn unc_vm_trap(code: unc_vm_vm::TrapCode) -> Option<TrapKind> {
+    use unc_vm_vm::TrapCode;
+    Some(match code {
+        TrapCode::GasExceeded => return None,
+        TrapCode::StackOverflow => TrapKind::StackOverflow,
+        TrapCode::HeapAccessOutOfBounds => TrapKind::MemoryOutOfBounds,
+        TrapCode::HeapMisaligned => TrapKind::MisalignedAtomicAccess,
+        TrapCode::TableAccessOutOfBounds => TrapKind::TableOutOfBounds,
+        TrapCode::OutOfBounds => TrapKind::OutOfBounds,
+        TrapCode::IndirectCallToNull => TrapKind::IndirectCallToNull,
+        TrapCode::BadSignature => TrapKind::BadSignature,
+        TrapCode::IntegerOverflow => TrapKind::IntegerOverflow,
+        TrapCode::IntegerDivisionByZero => TrapKind::IntegerDivisionByZero,
+        TrapCode::BadConversionToInteger => TrapKind::BadConversionToInteger,
+        TrapCode::UnreachableCodeReached => TrapKind::Unreachable,
+        TrapCode::UnalignedAtomic => TrapKind::MisalignedAtomicAccess,
+    })
+}
+
+/// The kind of a trap of Wasmer2, see [`unc_vm_trap`].
+#[cfg(all(feature = "wasmer2_vm", target_arch = "x86_64"))]
+pub(crate) fn wasmer2_trap(code: wasmer_vm::TrapCode) -> Option<TrapKind> {
+    use wasmer_vm::TrapCode;
+    Some(match code {
+        TrapCode::GasExceeded => return None,
+        TrapCode::StackOverflow => TrapKind::StackOverflow,
+        TrapCode::HeapAccessOutOfBounds => TrapKind::MemoryOutOfBounds,
+        TrapCode::HeapMisaligned => TrapKind::MisalignedAtomicAccess,
+        TrapCode::TableAccessOutOfBounds => TrapKind::TableOutOfBounds,
+        TrapCode::OutOfBounds => TrapKind::OutOfBounds,
+        TrapCode::IndirectCallToNull => TrapKind::IndirectCallToNull,
+        TrapCode::BadSignature => TrapKind::BadSignature,
+        TrapCode::IntegerOverflow => TrapKind::IntegerOverflow,
+        TrapCode::IntegerDivisionByZero => TrapKind::IntegerDivisionByZero,
+        TrapCode::BadConversionToInteger => TrapKind::BadConversionToInteger,
+        TrapCode::UnreachableCodeReached => TrapKind::Unreachable,
+        TrapCode::UnalignedAtomic => TrapKind::MisalignedAtomicAccess,
+    })
+}
+
+/// The kind of a trap of Wasmer0.
+#[cfg(all(feature = "wasmer0_vm", target_arch = "x86_64"))]
+pub(crate) fn wasmer0_trap(code: wasmer_runtime::ExceptionCode) -> TrapKind {
+    use wasmer_runtime::ExceptionCode;
+    match code {
+        ExceptionCode::Unreachable => TrapKind::Unreachable,
+        ExceptionCode::IncorrectCallIndirectSignature => TrapKind::BadSignature,
+        ExceptionCode::MemoryOutOfBounds => TrapKind::MemoryOutOfBounds,
+        ExceptionCode::CallIndirectOOB => TrapKind::CallIndirectOutOfBounds,
+        ExceptionCode::IllegalArithmetic => TrapKind::IllegalArithmetic,
+        ExceptionCode::MisalignedAtomicAccess => TrapKind::MisalignedAtomicAccess,
+    }
+}
+
+/// The kind of a trap of Wasmtime, or `None` for the traps contracts cannot
+/// raise, e.g. those of the features the runner does not enable.
+#[cfg(feature = "wasmtime_vm")]
+pub(crate) fn wasmtime_trap(trap: wasmtime::Trap) -> Option<TrapKind> {
+    use wasmtime::Trap;
+    Some(match trap {
+        Trap::StackOverflow => TrapKind::StackOverflow,
+        Trap::MemoryOutOfBounds => TrapKind::MemoryOutOfBounds,
+        Trap::HeapMisaligned => TrapKind::MisalignedAtomicAccess,
+        Trap::TableOutOfBounds => TrapKind::TableOutOfBounds,
+        Trap::IndirectCallToNull => TrapKind::IndirectCallToNull,
+        Trap::BadSignature => TrapKind::BadSignature,
+        Trap::IntegerOverflow => TrapKind::IntegerOverflow,
+        Trap::IntegerDivisionByZero => TrapKind::IntegerDivisionByZero,
+        Trap::BadConversionToInteger => TrapKind::BadConversionToInteger,
+        Trap::UnreachableCodeReached => TrapKind::Unreachable,
+        _ => return None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::errors::FunctionCallError;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::logic::ContractPrepareVersion;
+    use crate::runner::RunOptions;
+    use crate::tests::{create_context, test_vm_config, with_vm_variants};
+    use crate::ContractCode;
+    use strum::IntoEnumIterator;
+    use crate::logic::Config;
+    use unc_parameters::vm::VMKind;
+    use unc_parameters::RuntimeFeesConfig;
+
+    /// A contract of which `main` raises `kind`.
+    struct Case {
+        kind: TrapKind,
+        expected: WasmTrap,
+        wat: &'static str,
+        /// Whether Wasmer0 raises the same trap, the old backend diverging on
+        /// some of them.
+        on_wasmer0: bool,
+    }
+
+    fn cases() -> Vec<Case> {
+        vec![
+            Case {
+                kind: TrapKind::Unreachable,
+                expected: WasmTrap::Unreachable,
+                wat: r#"(module (func (export "main") unreachable))"#,
+                on_wasmer0: true,
+            },
+            Case {
+                kind: TrapKind::MemoryOutOfBounds,
+                expected: WasmTrap::MemoryOutOfBounds,
+                wat: r#"
+(module
+  (memory 1)
+  (func (export "main") (drop (i32.load (i32.const -8)))))"#,
+                on_wasmer0: true,
+            },
+            Case {
+                kind: TrapKind::IntegerDivisionByZero,
+                expected: WasmTrap::IllegalArithmetic,
+                wat: r#"
+(module
+  (func (export "main") (drop (i32.div_s (i32.const 1) (i32.const 0)))))"#,
+                on_wasmer0: true,
+            },
+            Case {
+                kind: TrapKind::IntegerOverflow,
+                expected: WasmTrap::IllegalArithmetic,
+                wat: r#"
+(module
+  (func (export "main") (drop (i32.div_s (i32.const 0x80000000) (i32.const -1)))))"#,
+                on_wasmer0: true,
+            },
+            Case {
+                kind: TrapKind::BadConversionToInteger,
+                expected: WasmTrap::IllegalArithmetic,
+                wat: r#"
+(module
+  (func (export "main") (drop (i32.trunc_f64_s (f64.const nan)))))"#,
+                on_wasmer0: false,
+            },
+            Case {
+                kind: TrapKind::BadSignature,
+                expected: WasmTrap::IncorrectCallIndirectSignature,
+                wat: r#"
+(module
+  (type $ty (func (result i32)))
+  (func $f)
+  (table 1 funcref)
+  (elem (i32.const 0) $f)
+  (func (export "main") (drop (call_indirect (type $ty) (i32.const 0)))))"#,
+                on_wasmer0: false,
+            },
+            Case {
+                kind: TrapKind::IndirectCallToNull,
+                expected: WasmTrap::IndirectCallToNull,
+                wat: r#"
+(module
+  (func $f)
+  (table 2 funcref)
+  (elem (i32.const 0) $f)
+  (func (export "main") (call_indirect (i32.const 1))))"#,
+                on_wasmer0: false,
+            },
+            Case {
+                kind: TrapKind::TableOutOfBounds,
+                expected: WasmTrap::MemoryOutOfBounds,
+                wat: r#"
+(module
+  (func $f)
+  (table 1 funcref)
+  (elem (i32.const 0) $f)
+  (func (export "main") (call_indirect (i32.const 5))))"#,
+                on_wasmer0: false,
+            },
+            // Run with `deterministic_stack_limit`, without which the stack is
+            // limited by each backend, see the module documentation of
+            // `crate::prepare`.
+            Case {
+                kind: TrapKind::StackOverflow,
+                expected: WasmTrap::StackOverflow,
+                wat: r#"(module (func $f (export "main") (call $f)))"#,
+                on_wasmer0: false,
+            },
+        ]
+    }
+
+    /// Kinds no contract can raise: the last two only exist on Wasmer0 and
+    /// the others need proposals the preparation rejects.
+    const NOT_RAISED: &[TrapKind] = &[
+        TrapKind::OutOfBounds,
+        TrapKind::MisalignedAtomicAccess,
+        TrapKind::IllegalArithmetic,
+        TrapKind::CallIndirectOutOfBounds,
+    ];
+
+    #[test]
+    fn test_every_kind_is_tested() {
+        let cases = cases();
+        for kind in TrapKind::iter() {
+            assert!(
+                cases.iter().any(|case| case.kind == kind) || NOT_RAISED.contains(&kind),
+                "{kind:?} has no test contract"
+            );
+        }
+    }
+
+    #[test]
+    fn test_traps_are_the_same_on_every_backend() {
+        let mut config = test_vm_config();
+        config.limit_config.contract_prepare_version = ContractPrepareVersion::V2;
+        config.deterministic_stack_limit = true;
+        let fees = RuntimeFeesConfig::test();
+        for case in cases() {
+            assert_eq!(case.kind.wasm_trap(), case.expected, "{:?}", case.kind);
+            let code = ContractCode::new(wat::parse_str(case.wat).unwrap(), None);
+            with_vm_variants(&config, |vm_kind| {
+                if vm_kind == VMKind::Wasmer0 && !case.on_wasmer0 {
+                    return;
+                }
+                let mut config = config.clone();
+                config.vm_kind = vm_kind;
+                let outcome = crate::run_with_options(
+                    &code,
+                    "main",
+                    &mut MockedExternal::new(),
+                    create_context(vec![]),
+                    &config,
+                    &fees,
+                    &[],
+                    None,
+                    &RunOptions::default(),
+                )
+                .unwrap();
+                assert_eq!(
+                    outcome.aborted,
+                    Some(FunctionCallError::WasmTrap(case.expected.clone())),
+                    "{:?} {vm_kind:?}",
+                    case.kind
+                );
+            });
+        }
+    }
+}
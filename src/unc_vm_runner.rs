@@ -1,17 +1,21 @@
+use crate::cache::contract_cache_key;
+use crate::dry_run::DryRunExternal;
 use crate::errors::ContractPrecompilatonResult;
-use crate::imports::unc_vm::NearVmImports;
+use crate::imports::unc_vm::{HostEnv, NearVmImports};
 use crate::logic::errors::{
-    CacheError, CompilationError, FunctionCallError, MethodResolveError, VMRunnerError, WasmTrap,
+    CacheError, CompilationError, FunctionCallError, MethodResolveError, VMRunnerError,
 };
 use crate::logic::gas_counter::FastGasCounter;
 use crate::logic::types::PromiseResult;
 use crate::logic::{
     CompiledContract, CompiledContractCache, Config, External, MemSlice, MemoryLike, VMContext,
-    VMLogic, VMOutcome,
+    VMLogic, VMOutcome, WasmFrame,
 };
-use crate::prepare;
-use crate::runner::VMResult;
-use crate::{get_contract_cache_key, imports, ContractCode};
+use crate::prepare::{self, NanCanonicalization};
+use crate::metrics::ExecutionTimer;
+use crate::resources::{ResourceKind, ResourceToken, Tracked};
+use crate::runner::{CodegenTarget, CompilationInfo, HostSettings, VMResult};
+use crate::{imports, ContractCode};
 use memoffset::offset_of;
 use unc_parameters::vm::VMKind;
 use unc_parameters::RuntimeFeesConfig;
@@ -19,31 +23,84 @@ use unc_vm_compiler_singlepass::Singlepass;
 use unc_vm_engine::universal::{
     LimitedMemoryPool, Universal, UniversalEngine, UniversalExecutable, UniversalExecutableRef,
 };
-use unc_vm_types::{FunctionIndex, InstanceConfig, MemoryType, Pages, WASM_PAGE_SIZE};
+use unc_vm_types::{
+    FunctionIndex, InstanceConfig, LocalFunctionIndex, LocalGlobalIndex, MemoryType, Pages,
+    WASM_PAGE_SIZE,
+};
 use unc_vm_vm::{
-    Artifact, Instantiatable, LinearMemory, LinearTable, Memory, MemoryStyle, TrapCode, VMMemory,
+    Artifact, InstanceHandle, Instantiatable, LinearMemory, LinearTable, Memory, MemoryError,
+    MemoryStyle, VMFunctionEnvironment, VMGlobalDefinition, VMMemory, VMMemoryDefinition,
 };
 use std::borrow::Cow;
+use std::cell::UnsafeCell;
+use std::collections::BTreeMap;
 use std::hash::Hash;
 use std::mem::size_of;
-use std::sync::{Arc, OnceLock};
+use std::ptr::NonNull;
+use std::sync::{Arc, Mutex, OnceLock};
+
+#[derive(Clone)]
+pub struct NearVmMemory(Backing, #[allow(dead_code)] ResourceToken);
 
+/// Where the pages of a [`NearVmMemory`] come from.
 #[derive(Clone)]
-pub struct NearVmMemory(Arc<LinearMemory>);
+enum Backing {
+    /// Mapped by NearVM, possibly reused from a [`NearVmMemoryPool`].
+    Mapped(Arc<LinearMemory>),
+    /// Allocated by a [`GuestMemoryAllocator`] of the embedder.
+    #[cfg(unix)]
+    Embedder(Arc<EmbedderMemory>),
+}
+
+impl Backing {
+    fn memory(&self) -> &dyn Memory {
+        match self {
+            Backing::Mapped(memory) => &**memory,
+            #[cfg(unix)]
+            Backing::Embedder(memory) => &**memory,
+        }
+    }
+}
 
 impl NearVmMemory {
-    fn new(
-        initial_memory_pages: u32,
-        max_memory_pages: u32,
-    ) -> Result<Self, unc_vm_vm::MemoryError> {
-        let max_pages = Pages(max_memory_pages);
-        Ok(NearVmMemory(Arc::new(LinearMemory::new(
-            &MemoryType::new(Pages(initial_memory_pages), Some(max_pages), false),
-            &MemoryStyle::Static {
-                bound: max_pages,
-                offset_guard_size: unc_vm_types::WASM_PAGE_SIZE as u64,
-            },
-        )?)))
+    fn new(shape: MemoryShape) -> Result<Self, MemoryError> {
+        let (ty, style) = shape.types();
+        let memory = Arc::new(LinearMemory::new(&ty, &style)?);
+        Ok(Self::account(Backing::Mapped(memory), shape.initial_pages))
+    }
+
+    fn account(backing: Backing, initial_memory_pages: u32) -> Self {
+        let bytes = u64::from(initial_memory_pages) * unc_vm_types::WASM_PAGE_SIZE as u64;
+        NearVmMemory(backing, ResourceToken::new(ResourceKind::Memory, bytes))
+    }
+
+    /// Allocates a memory of the given size with `allocator`, or takes one
+    /// from `pool`, or maps a new one.
+    fn take(
+        allocator: Option<&Arc<dyn GuestMemoryAllocator>>,
+        pool: Option<&NearVmMemoryPool>,
+        shape: MemoryShape,
+    ) -> Result<Self, MemoryError> {
+        #[cfg(unix)]
+        if let Some(allocator) = allocator {
+            let memory = EmbedderMemory::new(allocator, shape)?;
+            if let Some(memory) = memory {
+                return Ok(Self::account(Backing::Embedder(Arc::new(memory)), shape.initial_pages));
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = allocator;
+        match pool.and_then(|pool| pool.take(shape)) {
+            Some(memory) => Ok(Self::account(Backing::Mapped(memory), shape.initial_pages)),
+            None => Self::new(shape),
+        }
+    }
+
+    /// Gives the memory back to `pool` if NearVM mapped it.
+    fn give_back(self, pool: &NearVmMemoryPool, shape: MemoryShape) {
+        if let Backing::Mapped(memory) = self.0 {
+            pool.give_back(shape, memory);
+        }
     }
 
     /// Returns pointer to memory at the specified offset provided that there’s
@@ -54,7 +111,7 @@ impl NearVmMemory {
     unsafe fn get_ptr(&self, offset: u64, len: usize) -> Result<*mut u8, ()> {
         let offset = usize::try_from(offset).map_err(|_| ())?;
         // SAFETY: Caller promisses memory mapping won’t change.
-        let vmmem = unsafe { self.0.vmmemory().as_ref() };
+        let vmmem = unsafe { self.0.memory().vmmemory().as_ref() };
         // `checked_sub` here verifies that offsetting the buffer by offset
         // still lands us in-bounds of the allocated object.
         let remaining = vmmem.current_length.checked_sub(offset).ok_or(())?;
@@ -90,7 +147,30 @@ impl NearVmMemory {
     }
 
     pub(crate) fn vm(&self) -> VMMemory {
-        VMMemory { from: self.0.clone(), instance_ref: None }
+        let from: Arc<dyn Memory> = match &self.0 {
+            Backing::Mapped(memory) => memory.clone(),
+            #[cfg(unix)]
+            Backing::Embedder(memory) => memory.clone(),
+        };
+        VMMemory { from, instance_ref: None }
+    }
+}
+
+/// Pages of the memories of calls, and bytes of guard region after them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct MemoryShape {
+    initial_pages: u32,
+    max_pages: u32,
+    guard_size: u64,
+}
+
+impl MemoryShape {
+    /// Type and style of the memories, which the compiled contracts expect.
+    fn types(self) -> (MemoryType, MemoryStyle) {
+        let max_pages = Pages(self.max_pages);
+        let ty = MemoryType::new(Pages(self.initial_pages), Some(max_pages), false);
+        let style = MemoryStyle::Static { bound: max_pages, offset_guard_size: self.guard_size };
+        (ty, style)
     }
 }
 
@@ -120,6 +200,343 @@ impl MemoryLike for NearVmMemory {
         // will change guest memory mapping under us.
         Ok(unsafe { self.get_mut(offset, buffer.len())? }.copy_from_slice(buffer))
     }
+
+    fn data_size(&self) -> u64 {
+        // SAFETY: the length is read, no reference to the memory is kept.
+        unsafe { self.0.memory().vmmemory().as_ref() }.current_length as u64
+    }
+}
+
+/// Linear memories kept between the calls of [`NearVM`], to reset them
+/// instead of mapping new ones.
+///
+/// Mapping the memory of a call, and unmapping it afterwards, takes a good
+/// share of the time of short calls.  Calls given a pool in
+/// [`crate::RunOptions::memory_pool`], or else run on a host with a
+/// `memory_pool_size`, take their memory from it, and give it back once done
+/// unless the contract grew it: the memory is then reset by dropping its
+/// pages, which the kernel maps back zeroed on the next access.  A call
+/// cannot tell a reused memory from a new one.
+///
+/// Only memories are pooled: NearVM builds the instance of a call from the
+/// artifact and the imports of the call, which only the view calls of a
+/// [`crate::runner::VM::run_many`] batch share.
+/// Memories are only reused on Linux, which guarantees that the dropped pages
+/// read as zero.
+#[derive(Debug)]
+pub struct NearVmMemoryPool {
+    capacity: usize,
+    /// Free memories, with their shape.
+    memories: std::sync::Mutex<Vec<(MemoryShape, Arc<LinearMemory>)>>,
+}
+
+impl NearVmMemoryPool {
+    /// Makes a pool keeping up to `capacity` free memories.
+    ///
+    /// Each memory reserves the address space of the maximum memory of a
+    /// call, but only uses the pages touched by the calls since its reset.
+    pub fn new(capacity: usize) -> Self {
+        Self { capacity, memories: Default::default() }
+    }
+
+    /// Number of free memories in the pool.
+    pub fn len(&self) -> usize {
+        self.memories.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The pool of the runtimes whose host settings have `memory_pool_size`
+    /// set to `capacity`, none for 0.
+    fn shared(capacity: usize) -> Option<Arc<Self>> {
+        static POOLS: Mutex<BTreeMap<usize, Arc<NearVmMemoryPool>>> = Mutex::new(BTreeMap::new());
+        if capacity == 0 {
+            return None;
+        }
+        let mut pools = POOLS.lock().unwrap();
+        Some(Arc::clone(pools.entry(capacity).or_insert_with(|| Arc::new(Self::new(capacity)))))
+    }
+
+    fn take(&self, shape: MemoryShape) -> Option<Arc<LinearMemory>> {
+        let mut memories = self.memories.lock().unwrap();
+        let index = memories.iter().rposition(|(memory_shape, _)| *memory_shape == shape)?;
+        Some(memories.swap_remove(index).1)
+    }
+
+    /// Resets `memory` and keeps it, unless the pool is full or the memory
+    /// cannot be reset.
+    fn give_back(&self, shape: MemoryShape, mut memory: Arc<LinearMemory>) {
+        if Arc::get_mut(&mut memory).is_none() || memory.size() != Pages(shape.initial_pages) {
+            return;
+        }
+        if self.len() >= self.capacity || !Self::reset(&*memory) {
+            return;
+        }
+        let mut memories = self.memories.lock().unwrap();
+        if memories.len() < self.capacity {
+            memories.push((shape, memory));
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn reset(memory: &dyn Memory) -> bool {
+        // SAFETY: the memory is not shared, so nothing accesses it while the
+        // accessible pages are dropped.
+        unsafe {
+            let definition = memory.vmmemory().as_ref();
+            let base = definition.base.cast::<libc::c_void>();
+            libc::madvise(base, definition.current_length, libc::MADV_DONTNEED) == 0
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn reset(_memory: &dyn Memory) -> bool {
+        false
+    }
+}
+
+/// Allocator of the linear memories of [`NearVM`] calls, set by the embedder
+/// in [`crate::RunOptions::memory_allocator`].
+///
+/// This lets the embedder place the memories of contracts, for example in
+/// huge pages or on the NUMA node of the thread running them, which makes
+/// the calls using a lot of memory faster on large hosts.  The region of a
+/// memory covers its maximum size and a guard page after it: the runner
+/// makes the pages past the current size of the memory inaccessible, so
+/// that the contract traps on them as on a memory NearVM maps, makes them
+/// accessible as the contract grows the memory, and makes the whole region
+/// readable and writable again before giving it back.
+///
+/// Allocated memories are not pooled, the embedder reuses them as it likes.
+/// They are only used on Unix hosts, and the runner maps the memories itself
+/// elsewhere.
+///
+/// # Safety
+///
+/// The regions returned by [`Self::allocate`] must be aligned to the pages of
+/// the host, readable, writable and zeroed, and must not be used by anything
+/// else until they are given back.
+pub unsafe trait GuestMemoryAllocator: Send + Sync + std::fmt::Debug {
+    /// Allocates a region of `len` bytes, a multiple of the size of wasm pages,
+    /// or returns `None` to let the runner map the memory itself.
+    fn allocate(&self, len: usize) -> Option<NonNull<u8>>;
+
+    /// Same as [`Self::allocate`], knowing that the first `accessible` bytes
+    /// of the region stay accessible as long as the memory lives: the runner
+    /// only changes the protection of the pages after them.
+    fn allocate_accessible(&self, len: usize, accessible: usize) -> Option<NonNull<u8>> {
+        let _ = accessible;
+        self.allocate(len)
+    }
+
+    /// Takes back a region returned by [`Self::allocate`], holding whatever
+    /// the contract wrote in it.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` and `len` are those of a region allocated by this allocator,
+    /// which is given back once.
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, len: usize);
+}
+
+/// Linear memory in a region of a [`GuestMemoryAllocator`].
+#[cfg(unix)]
+#[derive(Debug)]
+struct EmbedderMemory {
+    allocator: Arc<dyn GuestMemoryAllocator>,
+    region: NonNull<u8>,
+    region_len: usize,
+    ty: MemoryType,
+    style: MemoryStyle,
+    /// Read by the compiled code, and changed by `grow` with the lock held.
+    definition: Box<UnsafeCell<VMMemoryDefinition>>,
+    grow_lock: Mutex<()>,
+}
+
+// SAFETY: the region is owned by the memory, and the definition only changes
+// with `grow_lock` held, as in `LinearMemory`.
+#[cfg(unix)]
+unsafe impl Send for EmbedderMemory {}
+#[cfg(unix)]
+unsafe impl Sync for EmbedderMemory {}
+
+#[cfg(unix)]
+impl EmbedderMemory {
+    /// Allocates a memory with `allocator`, or returns `None` if it does not
+    /// provide one.
+    fn new(
+        allocator: &Arc<dyn GuestMemoryAllocator>,
+        shape: MemoryShape,
+    ) -> Result<Option<Self>, MemoryError> {
+        let (initial_memory_pages, max_memory_pages) = (shape.initial_pages, shape.max_pages);
+        let (ty, style) = shape.types();
+        if max_memory_pages < initial_memory_pages {
+            return Err(MemoryError::InvalidMemory {
+                reason: format!(
+                    "the maximum ({max_memory_pages} pages) is less than the minimum \
+                     ({initial_memory_pages} pages)"
+                ),
+            });
+        }
+        let region_len = Pages(max_memory_pages)
+            .bytes()
+            .0
+            .checked_add(style.offset_guard_size() as usize)
+            .ok_or(MemoryError::MaximumMemoryTooLarge {
+                max_requested: Pages(max_memory_pages),
+                max_allowed: Pages::max_value(),
+            })?;
+        let accessible = Pages(initial_memory_pages).bytes().0;
+        let Some(region) = allocator.allocate_accessible(region_len, accessible) else {
+            return Ok(None);
+        };
+        // SAFETY: `_SC_PAGESIZE` is a valid name.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        if region.as_ptr() as usize % page_size != 0 {
+            // SAFETY: the region was just allocated, and is not used.
+            unsafe { allocator.deallocate(region, region_len) };
+            return Err(MemoryError::Region(format!(
+                "the allocator returned a region not aligned to pages of {page_size} bytes"
+            )));
+        }
+        let memory = EmbedderMemory {
+            allocator: Arc::clone(allocator),
+            region,
+            region_len,
+            ty,
+            style,
+            definition: Box::new(UnsafeCell::new(VMMemoryDefinition {
+                base: region.as_ptr(),
+                current_length: Pages(initial_memory_pages).bytes().0,
+            })),
+            grow_lock: Mutex::new(()),
+        };
+        // SAFETY: nothing references the region yet.  On errors the memory
+        // is dropped and gives the region back.
+        unsafe { memory.protect(accessible, region_len - accessible, libc::PROT_NONE)? };
+        Ok(Some(memory))
+    }
+
+    /// Changes the protection of `len` bytes of the region at `offset`.
+    ///
+    /// Safety: the bytes must not be referenced while they become
+    /// inaccessible.
+    unsafe fn protect(
+        &self,
+        offset: usize,
+        len: usize,
+        prot: libc::c_int,
+    ) -> Result<(), MemoryError> {
+        if len == 0 {
+            return Ok(());
+        }
+        let start = unsafe { self.region.as_ptr().add(offset) }.cast::<libc::c_void>();
+        if unsafe { libc::mprotect(start, len, prot) } == 0 {
+            Ok(())
+        } else {
+            Err(MemoryError::Region(std::io::Error::last_os_error().to_string()))
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Memory for EmbedderMemory {
+    fn ty(&self) -> MemoryType {
+        MemoryType { minimum: self.size(), ..self.ty }
+    }
+
+    fn style(&self) -> &MemoryStyle {
+        &self.style
+    }
+
+    fn size(&self) -> Pages {
+        // SAFETY: the definition lives as long as the memory.
+        let current_length = unsafe { (*self.definition.get()).current_length };
+        Pages((current_length / WASM_PAGE_SIZE) as u32)
+    }
+
+    fn grow(&self, delta: Pages) -> Result<Pages, MemoryError> {
+        let _guard = self.grow_lock.lock().unwrap();
+        let prev_pages = self.size();
+        if delta.0 == 0 {
+            return Ok(prev_pages);
+        }
+        let could_not_grow =
+            MemoryError::CouldNotGrow { current: prev_pages, attempted_delta: delta };
+        let new_pages = prev_pages.checked_add(delta).ok_or(could_not_grow.clone())?;
+        if Some(new_pages) > self.ty.maximum {
+            return Err(could_not_grow);
+        }
+        let prev_bytes = prev_pages.bytes().0;
+        let delta_bytes = delta.bytes().0;
+        // SAFETY: the new pages are within the maximum size of the memory,
+        // and were not accessible until now.
+        unsafe {
+            self.protect(prev_bytes, delta_bytes, libc::PROT_READ | libc::PROT_WRITE)?;
+            (*self.definition.get()).current_length = new_pages.bytes().0;
+        }
+        Ok(prev_pages)
+    }
+
+    fn vmmemory(&self) -> NonNull<VMMemoryDefinition> {
+        let _guard = self.grow_lock.lock().unwrap();
+        // SAFETY: the box is never null.
+        unsafe { NonNull::new_unchecked(self.definition.get()) }
+    }
+}
+
+#[cfg(unix)]
+impl Drop for EmbedderMemory {
+    fn drop(&mut self) {
+        // SAFETY: nothing references the memory anymore.  A region whose
+        // protection cannot be restored is leaked rather than given back.
+        unsafe {
+            if self.protect(0, self.region_len, libc::PROT_READ | libc::PROT_WRITE).is_ok() {
+                self.allocator.deallocate(self.region, self.region_len);
+            }
+        }
+    }
+}
+
+/// Number and size of the memories for the code of the loaded contracts.
+const CODE_MEMORIES: usize = 8;
+const CODE_MEMORY_SIZE: usize = 64 * 1024 * 1024;
+
+/// Advises the kernel to back the free memories of `pool` with huge pages.
+#[cfg(target_os = "linux")]
+fn advise_huge_pages(pool: &LimitedMemoryPool) {
+    let memories: Vec<_> = (0..CODE_MEMORIES).map_while(|_| pool.get(0).ok()).collect();
+    for memory in &memories {
+        // SAFETY: the memories of the pool are mappings of `CODE_MEMORY_SIZE`
+        // bytes, which nothing references while they are taken out.
+        unsafe {
+            crate::huge_pages::advise_huge_pages(memory.writable_address(0), CODE_MEMORY_SIZE)
+        };
+    }
+}
+
+/// Tags the memories of `pool`, all free, with the protection `key` of the
+/// code memories.
+#[cfg(target_os = "linux")]
+fn tag_code_memories(pool: &LimitedMemoryPool, key: u32) {
+    let memories: Vec<_> = (0..CODE_MEMORIES).map_while(|_| pool.get(0).ok()).collect();
+    for memory in &memories {
+        // SAFETY: the memories of the pool are readable and writable mappings
+        // of `CODE_MEMORY_SIZE` bytes, which nothing references while they
+        // are taken out.
+        let tagged = unsafe {
+            crate::hardening::protection_keys::tag(
+                memory.writable_address(0),
+                CODE_MEMORY_SIZE,
+                key,
+            )
+        };
+        if !tagged {
+            tracing::error!(target: "vm", "could not tag the code memories with a protection key");
+        }
+    }
 }
 
 fn get_entrypoint_index(
@@ -150,9 +567,12 @@ fn translate_runtime_error(
 ) -> Result<FunctionCallError, VMRunnerError> {
     // Errors produced by host function calls also become `RuntimeError`s that wrap a dynamic
     // instance of `VMLogicError` internally. See the implementation of `NearVmImports`.
+    let trace = if logic.wants_abort_trace() { wasm_trace(&error) } else { Vec::new() };
     let error = match error.downcast::<crate::logic::VMLogicError>() {
         Ok(vm_logic) => {
-            return vm_logic.try_into();
+            let abort = vm_logic.try_into()?;
+            logic.record_abort_trace(&abort, trace);
+            return Ok(abort);
         }
         Err(original) => original,
     };
@@ -160,27 +580,27 @@ fn translate_runtime_error(
     let trap_code = error.to_trap().unwrap_or_else(|| {
         panic!("runtime error is not a trap: {}", msg);
     });
-    Ok(match trap_code {
-        TrapCode::GasExceeded => FunctionCallError::HostError(logic.process_gas_limit()),
-        TrapCode::StackOverflow => FunctionCallError::WasmTrap(WasmTrap::StackOverflow),
-        TrapCode::HeapAccessOutOfBounds => FunctionCallError::WasmTrap(WasmTrap::MemoryOutOfBounds),
-        TrapCode::HeapMisaligned => FunctionCallError::WasmTrap(WasmTrap::MisalignedAtomicAccess),
-        TrapCode::TableAccessOutOfBounds => {
-            FunctionCallError::WasmTrap(WasmTrap::MemoryOutOfBounds)
-        }
-        TrapCode::OutOfBounds => FunctionCallError::WasmTrap(WasmTrap::MemoryOutOfBounds),
-        TrapCode::IndirectCallToNull => FunctionCallError::WasmTrap(WasmTrap::IndirectCallToNull),
-        TrapCode::BadSignature => {
-            FunctionCallError::WasmTrap(WasmTrap::IncorrectCallIndirectSignature)
-        }
-        TrapCode::IntegerOverflow => FunctionCallError::WasmTrap(WasmTrap::IllegalArithmetic),
-        TrapCode::IntegerDivisionByZero => FunctionCallError::WasmTrap(WasmTrap::IllegalArithmetic),
-        TrapCode::BadConversionToInteger => {
-            FunctionCallError::WasmTrap(WasmTrap::IllegalArithmetic)
-        }
-        TrapCode::UnreachableCodeReached => FunctionCallError::WasmTrap(WasmTrap::Unreachable),
-        TrapCode::UnalignedAtomic => FunctionCallError::WasmTrap(WasmTrap::MisalignedAtomicAccess),
-    })
+    let abort = match crate::traps::unc_vm_trap(trap_code) {
+        Some(kind) => FunctionCallError::WasmTrap(kind.wasm_trap()),
+        None => FunctionCallError::HostError(logic.process_gas_limit()),
+    };
+    logic.record_abort_trace(&abort, trace);
+    Ok(abort)
+}
+
+/// Wasm frames of the call stack at the point of `error`, innermost first.
+///
+/// Only collected when [`VMLogic::wants_abort_trace`].
+fn wasm_trace(error: &unc_vm_engine::RuntimeError) -> Vec<WasmFrame> {
+    error
+        .trace()
+        .iter()
+        .map(|frame| WasmFrame {
+            func_index: frame.func_index(),
+            function_name: frame.function_name().map(String::from),
+            module_offset: Some(frame.module_offset()),
+        })
+        .collect()
 }
 
 #[derive(Hash, PartialEq, Debug)]
@@ -228,17 +648,43 @@ pub(crate) fn unc_vm_vm_hash() -> u64 {
 
 pub(crate) type VMArtifact = Arc<unc_vm_engine::universal::UniversalArtifact>;
 
+/// Addresses of the code of the functions of `artifact`.
+fn code_ranges(artifact: &VMArtifact) -> impl Iterator<Item = std::ops::Range<usize>> + '_ {
+    (0..).map_while(|index| artifact.function_extent(LocalFunctionIndex::from_u32(index))).map(
+        |extent| {
+            let start = extent.address.0 as usize;
+            start..start + extent.length
+        },
+    )
+}
+
 pub(crate) struct NearVM {
     pub(crate) config: Config,
+    /// Settings of the host the calls run on.
+    host: HostSettings,
+    /// Allocator of the memories of the calls without one in their options,
+    /// the one of `host.huge_pages`.
+    memory_allocator: Option<Arc<dyn GuestMemoryAllocator>>,
+    /// Pool of the calls without one in their options, the one of
+    /// `host.memory_pool_size`.
+    memory_pool: Option<Arc<NearVmMemoryPool>>,
     pub(crate) engine: UniversalEngine,
+    pub(crate) codegen: CodegenTarget,
 }
 
 impl NearVM {
-    pub(crate) fn new_for_target(config: Config, target: unc_vm_compiler::Target) -> Self {
+    pub(crate) fn new_for_target(
+        config: Config,
+        target: unc_vm_compiler::Target,
+        host: HostSettings,
+    ) -> Self {
         // We only support singlepass compiler at the moment.
         assert_eq!(VM_CONFIG.compiler, NearVmCompiler::Singlepass);
         let mut compiler = Singlepass::new();
         compiler.set_9393_fix(!config.disable_9393_fix);
+        compiler.canonicalize_nans(
+            NanCanonicalization::for_config(&config) == NanCanonicalization::Compiler,
+        );
         // We only support universal engine at the moment.
         assert_eq!(VM_CONFIG.engine, NearVmEngine::Universal);
 
@@ -258,16 +704,46 @@ impl NearVM {
                 // that particular function call will be slower. Not to mention there isn't a
                 // strong guarantee on the upper bound of the memory that the contract runtime may
                 // require.
-                LimitedMemoryPool::new(8, 64 * 1024 * 1024).unwrap_or_else(|e| {
-                    panic!("could not pre-allocate resources for the runtime: {e}");
-                })
+                let pool =
+                    LimitedMemoryPool::new(CODE_MEMORIES, CODE_MEMORY_SIZE).unwrap_or_else(|e| {
+                        panic!("could not pre-allocate resources for the runtime: {e}");
+                    });
+                // The pool is shared by all the runtimes, so only the
+                // hardening of the first one tags it.
+                #[cfg(target_os = "linux")]
+                if host.hardening.code_protection == crate::CodeProtection::ProtectionKeys {
+                    match crate::hardening::protection_keys::allocate_code_key() {
+                        Some(key) => tag_code_memories(&pool, key),
+                        None => tracing::error!(
+                            target: "vm",
+                            "could not allocate a protection key for the code memories"
+                        ),
+                    }
+                }
+                pool
             })
             .clone();
+        // Code memories which have to grow for a large contract are mapped
+        // again, in normal pages.
+        #[cfg(target_os = "linux")]
+        if host.huge_pages.is_some() {
+            advise_huge_pages(&code_memory_pool);
+        }
+        #[cfg(target_os = "linux")]
+        let memory_allocator = host.huge_pages.map(|huge_pages| {
+            Arc::new(crate::HugePageAllocator::new(huge_pages)) as Arc<dyn GuestMemoryAllocator>
+        });
+        #[cfg(not(target_os = "linux"))]
+        let memory_allocator = None;
 
-        let features =
-            crate::features::WasmFeatures::from(config.limit_config.contract_prepare_version);
+        let memory_pool = NearVmMemoryPool::shared(host.memory_pool_size);
+        let features = crate::features::WasmFeatures::from(&config);
         Self {
             config,
+            host,
+            memory_allocator,
+            memory_pool,
+            codegen: CodegenTarget::Host,
             engine: Universal::new(compiler)
                 .target(target)
                 .features(features.into())
@@ -276,9 +752,15 @@ impl NearVM {
         }
     }
 
-    pub(crate) fn new(config: Config) -> Self {
+    pub(crate) fn new_with_codegen(
+        config: Config,
+        codegen: CodegenTarget,
+        host: HostSettings,
+    ) -> Self {
         use unc_vm_compiler::{CpuFeature, Target, Triple};
-        let target_features = if cfg!(feature = "no_cpu_compatibility_checks") {
+        let target_features = if codegen == CodegenTarget::Baseline
+            || cfg!(feature = "no_cpu_compatibility_checks")
+        {
             let mut fs = CpuFeature::set();
             // These features should be sufficient to run the single pass compiler.
             fs.insert(CpuFeature::SSE2);
@@ -292,7 +774,19 @@ impl NearVM {
         } else {
             CpuFeature::for_host()
         };
-        Self::new_for_target(config, Target::new(Triple::host(), target_features))
+        Self {
+            codegen,
+            ..Self::new_for_target(config, Target::new(Triple::host(), target_features), host)
+        }
+    }
+
+    /// Shape of the memories of the calls of the config.
+    fn memory_shape(&self) -> MemoryShape {
+        MemoryShape {
+            initial_pages: self.config.limit_config.initial_memory_pages,
+            max_pages: self.config.limit_config.max_memory_pages,
+            guard_size: crate::hardening::guard_size(&self.host.hardening, WASM_PAGE_SIZE as u64),
+        }
     }
 
     pub(crate) fn compile_uncached(
@@ -321,9 +815,13 @@ impl NearVM {
         &self,
         code: &ContractCode,
         cache: Option<&dyn CompiledContractCache>,
-    ) -> Result<Result<UniversalExecutable, CompilationError>, CacheError> {
+    ) -> Result<Result<(UniversalExecutable, CompilationInfo), CompilationError>, CacheError> {
+        let start = std::time::Instant::now();
         let executable_or_error = self.compile_uncached(code);
-        let key = get_contract_cache_key(code, &self.config);
+        let elapsed = start.elapsed();
+        crate::metrics::compiled(VMKind::NearVm, elapsed);
+        let info = CompilationInfo::singlepass(elapsed);
+        let key = contract_cache_key(code, &self.config, self.codegen);
 
         if let Some(cache) = cache {
             let record = match &executable_or_error {
@@ -336,9 +834,12 @@ impl NearVM {
                 Err(err) => CompiledContract::CompileModuleError(err.clone()),
             };
             cache.put(&key, record).map_err(CacheError::WriteError)?;
+            if executable_or_error.is_ok() {
+                cache.put_compilation_info(&key, &info).map_err(CacheError::WriteError)?;
+            }
         }
 
-        Ok(executable_or_error)
+        Ok(executable_or_error.map(|executable| (executable, info)))
     }
 
     fn compile_and_load(
@@ -352,18 +853,17 @@ impl NearVM {
         // re-parse invalid code (invalid code, in a sense, is a normal
         // outcome). And `cache`, being a database, can fail with an `io::Error`.
         let _span = tracing::debug_span!(target: "vm", "NearVM::compile_and_load").entered();
-        let key = get_contract_cache_key(code, &self.config);
-        let cache_record = cache
-            .map(|cache| cache.get(&key))
-            .transpose()
-            .map_err(CacheError::ReadError)?
-            .flatten();
+        let key = contract_cache_key(code, &self.config, self.codegen);
+        let cache_record = crate::cache::lookup(cache, &key, VMKind::NearVm)?;
 
         let stored_artifact: Option<VMArtifact> = match cache_record {
             None => None,
             Some(CompiledContract::CompileModuleError(err)) => return Ok(Err(err)),
             Some(CompiledContract::Code(serialized_module)) => {
                 let _span = tracing::debug_span!(target: "vm", "NearVM::read_from_cache").entered();
+                let bytes = serialized_module.len() as u64;
+                let serialized_module =
+                    Tracked::new(serialized_module, ResourceKind::CacheBuffer, bytes);
                 unsafe {
                     // (UN-)SAFETY: the `serialized_module` must have been produced by a prior call to
                     // `serialize`.
@@ -376,6 +876,7 @@ impl NearVM {
                     // we load.
                     let executable = UniversalExecutableRef::deserialize(&serialized_module)
                         .map_err(|_| CacheError::DeserializationError)?;
+                    let _writes = crate::hardening::protection_keys::allow_code_writes();
                     let artifact = self
                         .engine
                         .load_universal_executable_ref(&executable)
@@ -386,18 +887,81 @@ impl NearVM {
             }
         };
 
-        Ok(if let Some(it) = stored_artifact {
+        let artifact = if let Some(it) = stored_artifact {
             Ok(it)
         } else {
             match self.compile_and_cache(code, cache)? {
-                Ok(executable) => Ok(self
-                    .engine
-                    .load_universal_executable(&executable)
-                    .map(Arc::new)
-                    .map_err(|err| VMRunnerError::LoadingError(err.to_string()))?),
+                Ok((executable, _)) => {
+                    let _writes = crate::hardening::protection_keys::allow_code_writes();
+                    Ok(self
+                        .engine
+                        .load_universal_executable(&executable)
+                        .map(Arc::new)
+                        .map_err(|err| VMRunnerError::LoadingError(err.to_string()))?)
+                }
                 Err(err) => Err(err),
             }
-        })
+        };
+        if let Ok(artifact) = &artifact {
+            crate::hardening::check_loaded(&self.host.hardening, code_ranges(artifact))?;
+        }
+        Ok(artifact)
+    }
+
+    fn run_in_memory(
+        &self,
+        memory: &mut NearVmMemory,
+        code: &ContractCode,
+        method_name: &str,
+        ext: &mut dyn External,
+        context: VMContext,
+        fees_config: &RuntimeFeesConfig,
+        promise_results: &[PromiseResult],
+        cache: Option<&dyn CompiledContractCache>,
+        options: &crate::runner::RunOptions,
+        loaded: &mut Option<Result<VMArtifact, CompilationError>>,
+    ) -> Result<VMOutcome, VMRunnerError> {
+        // FIXME: this mostly duplicates the `run_module` method.
+        // Note that we don't clone the actual backing memory, just increase the RC.
+        let vmmemory = memory.vm();
+        let mut logic =
+            VMLogic::new(ext, context, &self.config, fees_config, promise_results, memory);
+        logic.apply_run_options(options)?;
+        #[cfg(feature = "backtrace")]
+        logic.keep_code_for_backtrace(code, options);
+
+        let result = logic.before_loading_executable(method_name, code.code().len());
+        if let Err(e) = result {
+            return Ok(VMOutcome::abort(logic, e));
+        }
+        if let Err(e) = logic.add_code_pricing_fee(code.code().len())? {
+            return Ok(VMOutcome::abort(logic, e));
+        }
+
+        // Calls of a batch load the artifact once, see `VM::run_many`.
+        let artifact = match loaded {
+            Some(artifact) => artifact.clone(),
+            None => loaded.insert(self.compile_and_load(code, cache)?).clone(),
+        };
+        let artifact = match artifact {
+            Ok(it) => it,
+            Err(err) => {
+                return Ok(VMOutcome::abort(logic, FunctionCallError::CompilationError(err)));
+            }
+        };
+
+        let result = logic.after_loading_executable(code.code().len());
+        if let Err(e) = result {
+            return Ok(VMOutcome::abort(logic, e));
+        }
+        let import = imports::unc_vm::build(vmmemory, &mut logic, artifact.engine());
+        if let Err(e) = get_entrypoint_index(&*artifact, method_name) {
+            return Ok(VMOutcome::abort_but_nop_outcome_in_old_protocol(logic, e));
+        }
+        match self.run_method(&artifact, import, method_name)? {
+            Ok(()) => VMOutcome::try_ok(logic),
+            Err(err) => Ok(VMOutcome::abort(logic, err)),
+        }
     }
 
     fn run_method(
@@ -407,7 +971,30 @@ impl NearVM {
         method_name: &str,
     ) -> Result<Result<(), FunctionCallError>, VMRunnerError> {
         let _span = tracing::debug_span!(target: "vm", "run_method").entered();
+        let entrypoint = match get_entrypoint_index(&*artifact, method_name) {
+            Ok(index) => index,
+            Err(abort) => return Ok(Err(abort)),
+        };
+        let instance = match self.instantiate(artifact, &mut import)? {
+            Ok(instance) => instance,
+            Err(abort) => return Ok(Err(abort)),
+        };
+        let result = self.invoke(artifact, &instance, entrypoint, import.vmlogic)?;
+        {
+            let _span = tracing::debug_span!(target: "vm", "run_method/drop_instance").entered();
+            drop(instance)
+        }
+        Ok(result)
+    }
 
+    /// Instantiates `artifact` with the imports of a call, counting the gas
+    /// of the instance with the gas counter of their logic.
+    fn instantiate(
+        &self,
+        artifact: &VMArtifact,
+        import: &mut NearVmImports<'_, '_, '_>,
+    ) -> Result<Result<Tracked<InstanceHandle>, FunctionCallError>, VMRunnerError> {
+        let _span = tracing::debug_span!(target: "vm", "run_method/instantiate").entered();
         // FastGasCounter in Nearcore must be reinterpret_cast-able to the one in NearVm.
         assert_eq!(
             size_of::<FastGasCounter>(),
@@ -422,103 +1009,354 @@ impl NearVM {
             offset_of!(unc_vm_types::FastGasCounter, gas_limit)
         );
         let gas = import.vmlogic.gas_counter_pointer() as *mut unc_vm_types::FastGasCounter;
-        let entrypoint = match get_entrypoint_index(&*artifact, method_name) {
-            Ok(index) => index,
-            Err(abort) => return Ok(Err(abort)),
-        };
         unsafe {
-            let instance = {
-                let _span = tracing::debug_span!(target: "vm", "run_method/instantiate").entered();
-                // An important caveat is that the `'static` lifetime here refers to the lifetime
-                // of `VMLogic` reference to which is retained by the `InstanceHandle` we create.
-                // However this `InstanceHandle` only lives during the execution of this body, so
-                // we can be sure that `VMLogic` remains live and valid at any time.
-                // SAFETY: we ensure that the tables are valid during the lifetime of this instance
-                // by retaining an instance to `UniversalEngine` which holds the allocations.
-                let maybe_handle = Arc::clone(artifact).instantiate(
-                    &self,
-                    &mut import,
-                    Box::new(()),
-                    // SAFETY: We have verified that the `FastGasCounter` layout matches the
-                    // expected layout. `gas` remains dereferenceable throughout this function
-                    // by the virtue of it being contained within `import` which lives for the
-                    // entirety of this function.
-                    InstanceConfig::with_stack_limit(self.config.limit_config.max_stack_height)
-                        .with_counter(gas),
-                );
-                let handle = match maybe_handle {
-                    Ok(handle) => handle,
-                    Err(err) => {
-                        use unc_vm_engine::InstantiationError::*;
-                        let abort = match err {
-                            Start(err) => translate_runtime_error(err, import.vmlogic)?,
-                            Link(e) => FunctionCallError::LinkError { msg: e.to_string() },
-                            CpuFeature(e) => panic!(
+            // An important caveat is that the `'static` lifetime here refers to the lifetime
+            // of `VMLogic` reference to which is retained by the `InstanceHandle` we create.
+            // However this `InstanceHandle` is dropped before the `VMLogic`, so we can be sure
+            // that `VMLogic` remains live and valid at any time.
+            // SAFETY: we ensure that the tables are valid during the lifetime of this instance
+            // by retaining an instance to `UniversalEngine` which holds the allocations.
+            let maybe_handle = Arc::clone(artifact).instantiate(
+                &self,
+                &mut *import,
+                Box::new(()),
+                // SAFETY: We have verified that the `FastGasCounter` layout matches the
+                // expected layout. `gas` remains dereferenceable as long as the instance
+                // by the virtue of it being contained within the logic of `import`.
+                InstanceConfig::with_stack_limit(self.config.limit_config.max_stack_height)
+                    .with_counter(gas),
+            );
+            let handle = match maybe_handle {
+                Ok(handle) => handle,
+                Err(err) => {
+                    use unc_vm_engine::InstantiationError::*;
+                    let abort = match err {
+                        Start(err) => translate_runtime_error(err, import.vmlogic)?,
+                        Link(e) => FunctionCallError::LinkError { msg: e.to_string() },
+                        CpuFeature(e) => {
+                            return Err(VMRunnerError::LoadingError(format!(
                                 "host doesn't support the CPU features needed to run contracts: {}",
                                 e
-                            ),
-                        };
-                        return Ok(Err(abort));
-                    }
-                };
-                // SAFETY: being called immediately after instantiation.
-                match handle.finish_instantiation() {
-                    Ok(handle) => handle,
-                    Err(trap) => {
-                        let abort = translate_runtime_error(
-                            unc_vm_engine::RuntimeError::from_trap(trap),
-                            import.vmlogic,
-                        )?;
-                        return Ok(Err(abort));
-                    }
-                };
-                handle
+                            )))
+                        }
+                    };
+                    return Ok(Err(abort));
+                }
             };
-            if let Some(function) = instance.function_by_index(entrypoint) {
-                let _span = tracing::debug_span!(target: "vm", "run_method/call").entered();
-                // Signature for the entry point should be `() -> ()`. This is only a sanity check
-                // – this should've been already checked by `get_entrypoint_index`.
-                let signature = artifact
-                    .engine()
-                    .lookup_signature(function.signature)
-                    .expect("extern type should refer to valid signature");
-                if signature.params().is_empty() && signature.results().is_empty() {
-                    let trampoline =
-                        function.call_trampoline.expect("externs always have a trampoline");
-                    // SAFETY: we double-checked the signature, and all of the remaining arguments
-                    // come from an exported function definition which must be valid since it comes
-                    // from unc_vm itself.
-                    let res = instance.invoke_function(
-                        function.vmctx,
-                        trampoline,
-                        function.address,
-                        [].as_mut_ptr() as *mut _,
-                    );
-                    if let Err(trap) = res {
-                        let abort = translate_runtime_error(
-                            unc_vm_engine::RuntimeError::from_trap(trap),
-                            import.vmlogic,
-                        )?;
-                        return Ok(Err(abort));
+            // SAFETY: being called immediately after instantiation.
+            if let Err(trap) = handle.finish_instantiation() {
+                let abort = translate_runtime_error(
+                    unc_vm_engine::RuntimeError::from_trap(trap),
+                    import.vmlogic,
+                )?;
+                return Ok(Err(abort));
+            }
+            Ok(Ok(Tracked::new(handle, ResourceKind::Instance, 0)))
+        }
+    }
+
+    /// Calls the function `entrypoint` of `instance`, checked by
+    /// [`get_entrypoint_index`].
+    fn invoke(
+        &self,
+        artifact: &VMArtifact,
+        instance: &InstanceHandle,
+        entrypoint: FunctionIndex,
+        logic: &mut VMLogic,
+    ) -> Result<Result<(), FunctionCallError>, VMRunnerError> {
+        let Some(function) = instance.function_by_index(entrypoint) else {
+            panic!("signature should've already been checked by `get_entrypoint_index`")
+        };
+        let _span = tracing::debug_span!(target: "vm", "run_method/call").entered();
+        let _timer = ExecutionTimer::start(VMKind::NearVm);
+        // Signature for the entry point should be `() -> ()`. This is only a sanity check
+        // – this should've been already checked by `get_entrypoint_index`.
+        let signature = artifact
+            .engine()
+            .lookup_signature(function.signature)
+            .expect("extern type should refer to valid signature");
+        if !signature.params().is_empty() || !signature.results().is_empty() {
+            panic!("signature should've already been checked by `get_entrypoint_index`")
+        }
+        let trampoline = function.call_trampoline.expect("externs always have a trampoline");
+        // SAFETY: we double-checked the signature, and all of the remaining arguments
+        // come from an exported function definition which must be valid since it comes
+        // from unc_vm itself.
+        let res = unsafe {
+            instance.invoke_function(
+                function.vmctx,
+                trampoline,
+                function.address,
+                [].as_mut_ptr() as *mut _,
+            )
+        };
+        match res {
+            Ok(()) => Ok(Ok(())),
+            Err(trap) => Ok(Err(translate_runtime_error(
+                unc_vm_engine::RuntimeError::from_trap(trap),
+                logic,
+            )?)),
+        }
+    }
+
+    /// Runs the view `calls` one after the other on one instance of the
+    /// contract, in `memory`, see [`crate::runner::VM::run_many`].
+    ///
+    /// Between two calls, the memory gets back its pages at instantiation,
+    /// and the globals their initial values: this keeps the outcomes those of
+    /// the calls run alone when the contract has no start function, and no
+    /// data segment at an offset read from a global.  Without bulk memory
+    /// and reference types, nothing else of the instance changes during a
+    /// call.  Returns the outcomes of the calls which could share the
+    /// instance, none if the contract cannot, and stops after a call grows
+    /// the memory or when the contract fails to instantiate: the other calls
+    /// run on instances of their own.
+    fn run_views(
+        &self,
+        memory: &mut NearVmMemory,
+        code: &ContractCode,
+        calls: &[crate::runner::MethodCall],
+        ext: &mut dyn External,
+        fees_config: &RuntimeFeesConfig,
+        promise_results: &[PromiseResult],
+        cache: Option<&dyn CompiledContractCache>,
+        options: &crate::runner::RunOptions,
+        loaded: &mut Option<Result<VMArtifact, CompilationError>>,
+    ) -> Result<Vec<VMOutcome>, VMRunnerError> {
+        let shareable = cfg!(target_os = "linux")
+            && !self.config.bulk_memory_reftypes
+            && matches!(memory.0, Backing::Mapped(_))
+            && !calls.is_empty()
+            && calls.iter().all(|call| call.context.is_view());
+        if !shareable {
+            return Ok(Vec::new());
+        }
+        let artifact = match loaded {
+            Some(artifact) => artifact.clone(),
+            None => loaded.insert(self.compile_and_load(code, cache)?).clone(),
+        };
+        let Ok(artifact) = artifact else { return Ok(Vec::new()) };
+        let global_offsets =
+            artifact.data_segments().iter().any(|segment| segment.location.base.is_some());
+        if artifact.start_function().is_some() || global_offsets {
+            return Ok(Vec::new());
+        }
+
+        let vmmemory = memory.vm();
+        let pages = vmmemory.from.size();
+        // View calls cannot write, so they share one dry run.
+        let mut ext = DryRunExternal::new(ext);
+        let mut instance = None;
+        let mut outcomes = Vec::with_capacity(calls.len());
+        for call in calls {
+            // Each call gets a logic of its own, which the host functions of
+            // the instance are pointed to.
+            let mut logic = VMLogic::new(
+                &mut ext,
+                call.context.clone(),
+                &self.config,
+                fees_config,
+                promise_results,
+                memory,
+            );
+            start_call(&mut logic, code, options)?;
+            let mut import =
+                imports::unc_vm::build(vmmemory.clone(), &mut logic, artifact.engine());
+            let ending = self.run_shared(
+                code,
+                &call.method_name,
+                &artifact,
+                &mut import,
+                &mut instance,
+                &*vmmemory.from,
+            )?;
+            drop(import);
+            let Some(ending) = ending else { break };
+            outcomes.push(ending.outcome(logic)?);
+            if vmmemory.from.size() != pages {
+                break;
+            }
+        }
+        Ok(outcomes)
+    }
+
+    /// Runs the call of the logic of `import` on the shared `instance` of
+    /// [`Self::run_views`], instantiating the contract first if needed.
+    ///
+    /// Returns no ending if the contract fails to instantiate or the memory
+    /// cannot be restored, the call being left to an instance of its own.
+    fn run_shared(
+        &self,
+        code: &ContractCode,
+        method_name: &str,
+        artifact: &VMArtifact,
+        import: &mut NearVmImports<'_, '_, '_>,
+        instance: &mut Option<SharedInstance>,
+        memory: &dyn Memory,
+    ) -> Result<Option<Ending>, VMRunnerError> {
+        let logic = &mut *import.vmlogic;
+        let len = code.code().len();
+        if let Err(e) = logic.before_loading_executable(method_name, len) {
+            return Ok(Some(Ending::Abort(e)));
+        }
+        if let Err(e) = logic.add_code_pricing_fee(len)? {
+            return Ok(Some(Ending::Abort(e)));
+        }
+        if let Err(e) = logic.after_loading_executable(len) {
+            return Ok(Some(Ending::Abort(e)));
+        }
+        let entrypoint = match get_entrypoint_index(&*artifact, method_name) {
+            Ok(index) => index,
+            Err(e) => return Ok(Some(Ending::AbortNop(e))),
+        };
+        let shared = match instance {
+            Some(shared) => {
+                // SAFETY: no call of the instance is running, and the logic
+                // of `import` outlives the call.
+                unsafe {
+                    if !shared.restore(artifact, memory) {
+                        return Ok(None);
                     }
-                } else {
-                    panic!("signature should've already been checked by `get_entrypoint_index`")
+                    shared.point_imports_to(artifact, import);
                 }
-            } else {
-                panic!("signature should've already been checked by `get_entrypoint_index`")
+                shared
             }
+            None => match self.instantiate(artifact, import)? {
+                Ok(handle) => instance.insert(SharedInstance::new(handle, artifact, import)),
+                Err(_) => return Ok(None),
+            },
+        };
+        Ok(Some(match self.invoke(artifact, &shared.handle, entrypoint, import.vmlogic)? {
+            Ok(()) => Ending::Ok,
+            Err(e) => Ending::Abort(e),
+        }))
+    }
+}
 
-            {
-                let _span =
-                    tracing::debug_span!(target: "vm", "run_method/drop_instance").entered();
-                drop(instance)
-            }
+/// Applies the run options to the logic of a call.
+fn start_call<'a>(
+    logic: &mut VMLogic<'a>,
+    code: &'a ContractCode,
+    options: &crate::runner::RunOptions,
+) -> Result<(), VMRunnerError> {
+    logic.apply_run_options(options)?;
+    #[cfg(feature = "backtrace")]
+    logic.keep_code_for_backtrace(code, options);
+    #[cfg(not(feature = "backtrace"))]
+    let _ = code;
+    Ok(())
+}
+
+/// How a call of [`NearVM::run_views`] ended, its outcome computed once the
+/// logic is done with it.
+enum Ending {
+    Ok,
+    Abort(FunctionCallError),
+    AbortNop(FunctionCallError),
+}
+
+impl Ending {
+    fn outcome(self, logic: VMLogic) -> Result<VMOutcome, VMRunnerError> {
+        match self {
+            Ending::Ok => VMOutcome::try_ok(logic),
+            Ending::Abort(e) => Ok(VMOutcome::abort(logic, e)),
+            Ending::AbortNop(e) => Ok(VMOutcome::abort_but_nop_outcome_in_old_protocol(logic, e)),
         }
+    }
+}
 
-        Ok(Ok(()))
+/// The instance shared by the calls of [`NearVM::run_views`], with the
+/// values of its globals once instantiated, and the environments of its
+/// imported functions.
+struct SharedInstance {
+    handle: Tracked<InstanceHandle>,
+    globals: Vec<VMGlobalDefinition>,
+    host_envs: Vec<(FunctionIndex, HostEnv)>,
+}
+
+impl SharedInstance {
+    /// Keeps `handle`, just instantiated with `import`.
+    fn new(handle: Tracked<InstanceHandle>, artifact: &VMArtifact, import: &NearVmImports) -> Self {
+        // SAFETY: the instance was just instantiated, and runs no call.
+        let globals = local_globals(&handle, artifact)
+            .map(|global| unsafe { global.as_ref().clone() })
+            .collect();
+        let host_envs = (0..artifact.import_counts().functions)
+            .map(FunctionIndex::from_u32)
+            .filter_map(|index| {
+                // SAFETY: the index is one of an imported function.
+                let host_env = unsafe { (*import_env(&handle, artifact, index)).host_env };
+                Some((index, import.find_host_env(host_env)?))
+            })
+            .collect();
+        Self { handle, globals, host_envs }
+    }
+
+    /// Points the imported functions of the instance to the environments of
+    /// `import`, and the instance to the gas counter of its logic, for the
+    /// next call to use that logic.
+    ///
+    /// Safety: no call of the instance may be running, and the logic of
+    /// `import` must outlive the next call.
+    unsafe fn point_imports_to(&self, artifact: &VMArtifact, import: &mut NearVmImports) {
+        for &(index, env) in &self.host_envs {
+            let host_env = import.host_env(env);
+            *import_env(&self.handle, artifact, index) = VMFunctionEnvironment { host_env };
+        }
+        let gas = import.vmlogic.gas_counter_pointer() as *const unc_vm_types::FastGasCounter;
+        *self.handle.instance().as_ref().gas_counter_ptr() = gas;
+    }
+
+    /// Gives the memory and the globals of the instance back their values
+    /// once instantiated, unless the memory cannot be reset.
+    ///
+    /// Safety: no call of the instance may be running.
+    unsafe fn restore(&self, artifact: &VMArtifact, memory: &dyn Memory) -> bool {
+        if !NearVmMemoryPool::reset(memory) {
+            return false;
+        }
+        let definition = memory.vmmemory().as_ref();
+        for segment in artifact.data_segments() {
+            let end = segment.location.offset.checked_add(segment.data.len());
+            if end.map_or(true, |end| end > definition.current_length) {
+                return false;
+            }
+            let start = definition.base.add(segment.location.offset);
+            std::ptr::copy_nonoverlapping(segment.data.as_ptr(), start, segment.data.len());
+        }
+        for (mut global, value) in local_globals(&self.handle, artifact).zip(&self.globals) {
+            *global.as_mut() = value.clone();
+        }
+        true
     }
 }
 
+/// The environment which the imported function `index` of `handle` is
+/// called with.
+///
+/// Safety: `index` must be the index of an imported function.
+unsafe fn import_env(
+    handle: &InstanceHandle,
+    artifact: &VMArtifact,
+    index: FunctionIndex,
+) -> *mut VMFunctionEnvironment {
+    let offset = artifact.offsets().vmctx_vmfunction_import_vmctx(index);
+    handle.vmctx_ptr().cast::<u8>().add(offset as usize).cast()
+}
+
+/// The globals defined by the contract of `handle`.
+fn local_globals<'h>(
+    handle: &'h InstanceHandle,
+    artifact: &'h VMArtifact,
+) -> impl Iterator<Item = NonNull<VMGlobalDefinition>> + 'h {
+    let import_counts = artifact.import_counts();
+    (0..artifact.globals().len() as u32).map(move |local| {
+        let index = import_counts.global_index(LocalGlobalIndex::from_u32(local));
+        let global = handle.global_by_index(index).expect("instance should define its globals");
+        global.from.vmglobal()
+    })
+}
+
 impl unc_vm_vm::Tunables for &NearVM {
     fn memory_style(&self, memory: &MemoryType) -> MemoryStyle {
         MemoryStyle::Static {
@@ -577,12 +1415,12 @@ impl unc_vm_vm::Tunables for &NearVM {
     }
 
     fn stack_init_gas_cost(&self, stack_size: u64) -> u64 {
-        u64::from(self.config.regular_op_cost).saturating_mul((stack_size + 7) / 8)
+        u64::from(self.config.regular_op_cost).saturating_mul(stack_size.div_ceil(8))
     }
 
     /// Instrumentation configuration: stack limiter config
     fn stack_limiter_cfg(&self) -> Box<dyn finite_wasm::max_stack::SizeConfig> {
-        Box::new(MaxStackCfg)
+        Box::new(crate::prepare::StackSizeCfg)
     }
 
     /// Instrumentation configuration: gas accounting config
@@ -591,40 +1429,6 @@ impl unc_vm_vm::Tunables for &NearVM {
     }
 }
 
-struct MaxStackCfg;
-
-impl finite_wasm::max_stack::SizeConfig for MaxStackCfg {
-    fn size_of_value(&self, ty: finite_wasm::wasmparser::ValType) -> u8 {
-        use finite_wasm::wasmparser::ValType;
-        match ty {
-            ValType::I32 => 4,
-            ValType::I64 => 8,
-            ValType::F32 => 4,
-            ValType::F64 => 8,
-            ValType::V128 => 16,
-            ValType::Ref(_) => 8,
-        }
-    }
-    fn size_of_function_activation(
-        &self,
-        locals: &prefix_sum_vec::PrefixSumVec<finite_wasm::wasmparser::ValType, u32>,
-    ) -> u64 {
-        let mut res = 64_u64; // Rough accounting for rip, rbp and some registers spilled. Not exact.
-        let mut last_idx_plus_one = 0_u64;
-        for (idx, local) in locals {
-            let idx = u64::from(*idx);
-            res = res.saturating_add(
-                idx.checked_sub(last_idx_plus_one)
-                    .expect("prefix-sum-vec indices went backwards")
-                    .saturating_add(1)
-                    .saturating_mul(u64::from(self.size_of_value(*local))),
-            );
-            last_idx_plus_one = idx.saturating_add(1);
-        }
-        res
-    }
-}
-
 struct GasCostCfg(u64);
 
 macro_rules! gas_cost {
@@ -656,7 +1460,7 @@ impl<'a> finite_wasm::wasmparser::VisitOperator<'a> for GasCostCfg {
 }
 
 impl crate::runner::VM for NearVM {
-    fn run(
+    fn run_with_options(
         &self,
         code: &ContractCode,
         method_name: &str,
@@ -665,44 +1469,84 @@ impl crate::runner::VM for NearVM {
         fees_config: &RuntimeFeesConfig,
         promise_results: &[PromiseResult],
         cache: Option<&dyn CompiledContractCache>,
+        options: &crate::runner::RunOptions,
     ) -> Result<VMOutcome, VMRunnerError> {
-        let mut memory = NearVmMemory::new(
-            self.config.limit_config.initial_memory_pages,
-            self.config.limit_config.max_memory_pages,
-        )
-        .expect("Cannot create memory for a contract call");
-
-        // FIXME: this mostly duplicates the `run_module` method.
-        // Note that we don't clone the actual backing memory, just increase the RC.
-        let vmmemory = memory.vm();
-        let mut logic =
-            VMLogic::new(ext, context, &self.config, fees_config, promise_results, &mut memory);
-
-        let result = logic.before_loading_executable(method_name, code.code().len());
-        if let Err(e) = result {
-            return Ok(VMOutcome::abort(logic, e));
+        crate::hardening::protection_keys::forbid_code_writes();
+        let allocator = options.memory_allocator.as_ref().or(self.memory_allocator.as_ref());
+        let pool = options.memory_pool.as_deref().or(self.memory_pool.as_deref());
+        let shape = self.memory_shape();
+        let mut memory = NearVmMemory::take(allocator, pool, shape)
+            .expect("Cannot create memory for a contract call");
+        let outcome = self.run_in_memory(
+            &mut memory,
+            code,
+            method_name,
+            ext,
+            context,
+            fees_config,
+            promise_results,
+            cache,
+            options,
+            &mut None,
+        );
+        if let Some(pool) = pool {
+            memory.give_back(pool, shape);
         }
+        outcome
+    }
 
-        let artifact = self.compile_and_load(code, cache)?;
-        let artifact = match artifact {
-            Ok(it) => it,
-            Err(err) => {
-                return Ok(VMOutcome::abort(logic, FunctionCallError::CompilationError(err)));
-            }
-        };
-
-        let result = logic.after_loading_executable(code.code().len());
-        if let Err(e) = result {
-            return Ok(VMOutcome::abort(logic, e));
-        }
-        let import = imports::unc_vm::build(vmmemory, &mut logic, artifact.engine());
-        if let Err(e) = get_entrypoint_index(&*artifact, method_name) {
-            return Ok(VMOutcome::abort_but_nop_outcome_in_old_protocol(logic, e));
-        }
-        match self.run_method(&artifact, import, method_name)? {
-            Ok(()) => Ok(VMOutcome::ok(logic)),
-            Err(err) => Ok(VMOutcome::abort(logic, err)),
+    fn run_many(
+        &self,
+        code: &ContractCode,
+        calls: &[crate::runner::MethodCall],
+        ext: &mut dyn External,
+        fees_config: &RuntimeFeesConfig,
+        promise_results: &[PromiseResult],
+        cache: Option<&dyn CompiledContractCache>,
+        options: &crate::runner::RunOptions,
+    ) -> Result<Vec<VMOutcome>, VMRunnerError> {
+        crate::hardening::protection_keys::forbid_code_writes();
+        // Without a pool of the embedder or of the config, the calls pass
+        // their memory to each other through one of their own.
+        let batch_pool = NearVmMemoryPool::new(1);
+        let pool =
+            options.memory_pool.as_deref().or(self.memory_pool.as_deref()).unwrap_or(&batch_pool);
+        let shape = self.memory_shape();
+        let allocator = options.memory_allocator.as_ref().or(self.memory_allocator.as_ref());
+        let mut loaded = None;
+        let mut memory = NearVmMemory::take(allocator, Some(pool), shape)
+            .expect("Cannot create memory for a contract call");
+        let mut outcomes = self.run_views(
+            &mut memory,
+            code,
+            calls,
+            ext,
+            fees_config,
+            promise_results,
+            cache,
+            options,
+            &mut loaded,
+        )?;
+        memory.give_back(pool, shape);
+        for call in &calls[outcomes.len()..] {
+            let mut memory = NearVmMemory::take(allocator, Some(pool), shape)
+                .expect("Cannot create memory for a contract call");
+            let outcome = self.run_in_memory(
+                &mut memory,
+                code,
+                &call.method_name,
+                &mut DryRunExternal::new(&mut *ext),
+                call.context.clone(),
+                fees_config,
+                promise_results,
+                cache,
+                options,
+                &mut loaded,
+            );
+            memory.give_back(pool, shape);
+            outcomes.push(outcome?);
         }
+        Ok(outcomes)
     }
 
     fn precompile(
@@ -715,11 +1559,198 @@ impl crate::runner::VM for NearVM {
     > {
         Ok(self
             .compile_and_cache(code, Some(cache))?
-            .map(|_| ContractPrecompilatonResult::ContractCompiled))
+            .map(|(_, info)| ContractPrecompilatonResult::ContractCompiled(info)))
     }
 }
 
 #[test]
 fn test_memory_like() {
-    crate::logic::test_utils::test_memory_like(|| Box::new(NearVmMemory::new(1, 1).unwrap()));
+    crate::logic::test_utils::test_memory_like(|| {
+        Box::new(
+            NearVmMemory::new(MemoryShape {
+                initial_pages: 1,
+                max_pages: 1,
+                guard_size: WASM_PAGE_SIZE as u64,
+            })
+            .unwrap(),
+        )
+    });
+}
+
+#[test]
+fn test_memory_pool() {
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::logic::ReturnData;
+    use crate::runner::{RunOptions, VM};
+
+    let code = ContractCode::new(
+        wat::parse_str(
+            r#"(module
+  (import "env" "memory" (memory 0))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (func (export "write") (i32.store (i32.const 0) (i32.const 42)))
+  (func (export "grow") (drop (memory.grow (i32.const 1))))
+  (func (export "read") (call $value_return (i64.const 4) (i64.const 0))))"#,
+        )
+        .unwrap(),
+        None,
+    );
+    let mut config = crate::tests::test_vm_config();
+    config.vm_kind = VMKind::NearVm;
+    let vm = NearVM::new_with_codegen(config, CodegenTarget::Host, HostSettings::default());
+    let pool = Arc::new(NearVmMemoryPool::new(1));
+    let options = RunOptions { memory_pool: Some(Arc::clone(&pool)), ..RunOptions::default() };
+    let run = |method: &str| {
+        let outcome = vm
+            .run_with_options(
+                &code,
+                method,
+                &mut MockedExternal::new(),
+                crate::tests::create_context(vec![]),
+                &RuntimeFeesConfig::test(),
+                &[],
+                None,
+                &options,
+            )
+            .unwrap();
+        assert_eq!(outcome.aborted, None, "{method}");
+        outcome.return_data
+    };
+    let reused = cfg!(target_os = "linux");
+
+    run("write");
+    assert_eq!(pool.len(), usize::from(reused));
+    assert_eq!(run("read"), ReturnData::Value(vec![0; 4]));
+    assert_eq!(pool.len(), usize::from(reused));
+    // Memories which grew are not reused.
+    run("grow");
+    assert_eq!(pool.len(), 0);
+}
+
+#[test]
+fn test_config_memory_pool() {
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::logic::ReturnData;
+    use crate::runner::VM;
+
+    let code = ContractCode::new(
+        wat::parse_str(
+            r#"(module
+  (import "env" "memory" (memory 0))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (func (export "write") (i32.store (i32.const 0) (i32.const 42)))
+  (func (export "read") (call $value_return (i64.const 4) (i64.const 0))))"#,
+        )
+        .unwrap(),
+        None,
+    );
+    let mut config = crate::tests::test_vm_config();
+    config.vm_kind = VMKind::NearVm;
+    // A size no other test uses, for a pool of its own.
+    let host = HostSettings { memory_pool_size: 3, ..HostSettings::default() };
+    let run = |method: &str| {
+        let vm = NearVM::new_with_codegen(config.clone(), CodegenTarget::Host, host.clone());
+        let outcome = vm
+            .run(
+                &code,
+                method,
+                &mut MockedExternal::new(),
+                crate::tests::create_context(vec![]),
+                &RuntimeFeesConfig::test(),
+                &[],
+                None,
+            )
+            .unwrap();
+        assert_eq!(outcome.aborted, None, "{method}");
+        (outcome.return_data, vm.memory_pool.unwrap())
+    };
+    let reused = cfg!(target_os = "linux");
+
+    let (_, pool) = run("write");
+    assert_eq!(pool.len(), usize::from(reused));
+    // The runtimes with the same size share the pool.
+    let (return_data, other_pool) = run("read");
+    assert!(Arc::ptr_eq(&pool, &other_pool));
+    assert_eq!(return_data, ReturnData::Value(vec![0; 4]));
+    assert_eq!(pool.len(), usize::from(reused));
+    let config = crate::tests::test_vm_config();
+    let vm = NearVM::new_with_codegen(config, CodegenTarget::Host, HostSettings::default());
+    assert!(vm.memory_pool.is_none());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_memory_allocator() {
+    use crate::logic::errors::WasmTrap;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::logic::ReturnData;
+    use crate::runner::{RunOptions, VM};
+    use std::alloc::Layout;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[derive(Debug, Default)]
+    struct CountingAllocator {
+        allocated: AtomicUsize,
+        live: AtomicUsize,
+    }
+
+    unsafe impl GuestMemoryAllocator for CountingAllocator {
+        fn allocate(&self, len: usize) -> Option<NonNull<u8>> {
+            let layout = Layout::from_size_align(len, 4096).unwrap();
+            self.allocated.fetch_add(1, Ordering::SeqCst);
+            self.live.fetch_add(1, Ordering::SeqCst);
+            NonNull::new(unsafe { std::alloc::alloc_zeroed(layout) })
+        }
+
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, len: usize) {
+            self.live.fetch_sub(1, Ordering::SeqCst);
+            let layout = Layout::from_size_align(len, 4096).unwrap();
+            unsafe { std::alloc::dealloc(ptr.as_ptr(), layout) };
+        }
+    }
+
+    let code = ContractCode::new(
+        wat::parse_str(
+            r#"(module
+  (import "env" "memory" (memory 0))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (func $last_page (result i32) (i32.mul (i32.sub (memory.size) (i32.const 1)) (i32.const 65536)))
+  (func (export "grow")
+    (drop (memory.grow (i32.const 1)))
+    (i32.store (call $last_page) (i32.const 42))
+    (call $value_return (i64.const 4) (i64.extend_i32_u (call $last_page))))
+  (func (export "past_end") (drop (i32.load (i32.mul (memory.size) (i32.const 65536))))))"#,
+        )
+        .unwrap(),
+        None,
+    );
+    let mut config = crate::tests::test_vm_config();
+    config.vm_kind = VMKind::NearVm;
+    let vm = NearVM::new_with_codegen(config, CodegenTarget::Host, HostSettings::default());
+    let allocator = Arc::new(CountingAllocator::default());
+    let options = RunOptions {
+        memory_allocator: Some(Arc::clone(&allocator) as Arc<dyn GuestMemoryAllocator>),
+        ..RunOptions::default()
+    };
+    let run = |method: &str| {
+        vm.run_with_options(
+            &code,
+            method,
+            &mut MockedExternal::new(),
+            crate::tests::create_context(vec![]),
+            &RuntimeFeesConfig::test(),
+            &[],
+            None,
+            &options,
+        )
+        .unwrap()
+    };
+
+    let outcome = run("grow");
+    assert_eq!(outcome.aborted, None);
+    assert_eq!(outcome.return_data, ReturnData::Value(42u32.to_le_bytes().to_vec()));
+    let outcome = run("past_end");
+    assert_eq!(outcome.aborted, Some(FunctionCallError::WasmTrap(WasmTrap::MemoryOutOfBounds)));
+    assert_eq!(allocator.allocated.load(Ordering::SeqCst), 2);
+    assert_eq!(allocator.live.load(Ordering::SeqCst), 0);
 }
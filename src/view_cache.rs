@@ -0,0 +1,407 @@
+//! Memoization of the outcomes of view calls.
+//!
+//! RPC nodes answer the same view queries over and over: wallets polling a
+//! balance, explorers reading the metadata of a token.  A view call cannot
+//! change the state, so its outcome only depends on the contract, the method,
+//! the context of the call, the VM config and the state it reads.  A
+//! [`ViewCallCache`] keeps the outcomes of recent calls under a
+//! [`ViewCallKey`] made of these, the state being named by the block hash or
+//! the state root the caller gives, and answers the same calls from it
+//! without running them.  The whole context is part of the key: views can
+//! read any of its fields, and their gas limits decide whether they abort.
+//!
+//! The cache is bypassed, neither read nor written, when the
+//! [`External`] reports with [`External::state_changed`] that the state it
+//! reads is not the one named by the caller, and for the calls whose
+//! [`RunOptions`] have effects outside of the outcome or change it in ways
+//! the key does not capture.  Outcomes of calls interrupted by their
+//! deadline or memory cap depend on the node and are not cached either.
+//!
+//! Entries expire after the TTL of the [`ViewCachePolicy`], measured on the
+//! [`Clock`] of the cache, and the least recently used ones are evicted to
+//! keep the cache within its bounds.
+
+use crate::clock::Clock;
+use crate::fingerprint::ConfigFingerprint;
+use crate::logic::errors::FunctionCallError;
+use crate::logic::{CompiledContractCache, External, VMContext, VMOutcome};
+use crate::runner::{RunOptions, VMResult};
+use crate::ContractCode;
+use borsh::BorshDeserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use crate::logic::Config;
+use unc_parameters::RuntimeFeesConfig;
+use unc_primitives_core::hash::CryptoHash;
+
+/// How long and how many outcomes a [`ViewCallCache`] keeps.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ViewCachePolicy {
+    /// How long an outcome is served after the call which produced it.
+    pub ttl: Duration,
+    /// Number of outcomes kept.
+    pub max_entries: usize,
+    /// Bytes of outcomes kept, counted as the size of their borsh encoding
+    /// plus the size of the method names of their keys.
+    pub max_bytes: u64,
+}
+
+impl Default for ViewCachePolicy {
+    fn default() -> Self {
+        Self { ttl: Duration::from_secs(10), max_entries: 10_000, max_bytes: 64 << 20 }
+    }
+}
+
+/// What the outcome of a view call depends on, see the module
+/// documentation.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct ViewCallKey {
+    pub code_hash: CryptoHash,
+    pub method_name: String,
+    /// sha256 of the borsh serialization of the [`VMContext`].
+    pub context_hash: CryptoHash,
+    /// [`ConfigFingerprint::fingerprint`] of the VM config.
+    pub config: CryptoHash,
+    /// Hash of the block, or state root, of the state the call reads.
+    pub state_root: CryptoHash,
+}
+
+impl ViewCallKey {
+    pub fn new(
+        code: &ContractCode,
+        method_name: &str,
+        context: &VMContext,
+        config: &Config,
+        state_root: CryptoHash,
+    ) -> Self {
+        let context = borsh::to_vec(context).expect("failed serializing a context");
+        Self {
+            code_hash: *code.hash(),
+            method_name: method_name.to_string(),
+            context_hash: CryptoHash::hash_bytes(&context),
+            config: config.fingerprint(),
+            state_root,
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.method_name.len() as u64
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    /// Borsh encoding of the outcome, [`VMOutcome`] not being `Clone`.
+    outcome: Vec<u8>,
+    inserted: Instant,
+    last_use: u64,
+}
+
+#[derive(Debug, Default)]
+struct Entries {
+    entries: HashMap<ViewCallKey, Entry>,
+    by_use: BTreeMap<u64, ViewCallKey>,
+    size: u64,
+    clock: u64,
+}
+
+impl Entries {
+    fn remove(&mut self, key: &ViewCallKey) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.by_use.remove(&entry.last_use);
+            self.size -= entry.outcome.len() as u64 + key.size();
+        }
+    }
+}
+
+/// Outcomes of recent view calls, see the module documentation.
+///
+/// Shared by all the threads of a server.
+#[derive(Debug)]
+pub struct ViewCallCache {
+    policy: ViewCachePolicy,
+    entries: Mutex<Entries>,
+    /// The system clock if `None`.
+    clock: Option<Arc<dyn Clock>>,
+}
+
+impl ViewCallCache {
+    pub fn new(policy: ViewCachePolicy) -> Self {
+        Self { policy, entries: Default::default(), clock: None }
+    }
+
+    /// Makes the entries expire on `clock` instead of the system clock.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+
+    pub fn policy(&self) -> &ViewCachePolicy {
+        &self.policy
+    }
+
+    /// Number of outcomes kept, including the expired ones not evicted yet.
+    pub fn len(&self) -> usize {
+        self.entries.lock().unwrap().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Bytes of the outcomes kept, as counted for
+    /// [`ViewCachePolicy::max_bytes`].
+    pub fn size(&self) -> u64 {
+        self.entries.lock().unwrap().size
+    }
+
+    pub fn clear(&self) {
+        *self.entries.lock().unwrap() = Entries::default();
+    }
+
+    /// Runs `method_name` of `code` like [`crate::run_with_options`], or
+    /// returns the outcome of the same view call made before.
+    ///
+    /// `state_root` names the state `ext` reads, by the hash of its block or
+    /// by its state root: calls on blocks of different forks at the same
+    /// height must not share outcomes.  Calls which are not view calls
+    /// always run.
+    pub fn run(
+        &self,
+        code: &ContractCode,
+        method_name: &str,
+        ext: &mut dyn External,
+        state_root: CryptoHash,
+        context: VMContext,
+        config: &Config,
+        fees_config: &RuntimeFeesConfig,
+        cache: Option<&dyn CompiledContractCache>,
+        options: &RunOptions,
+    ) -> VMResult {
+        if !context.is_view() || ext.state_changed() || !is_cacheable(options) {
+            return crate::run_with_options(
+                code,
+                method_name,
+                ext,
+                context,
+                config,
+                fees_config,
+                &[],
+                cache,
+                options,
+            );
+        }
+        let key = ViewCallKey::new(code, method_name, &context, config, state_root);
+        if let Some(outcome) = self.get_at(self.now(), &key) {
+            return Ok(outcome);
+        }
+        let outcome = crate::run_with_options(
+            code,
+            method_name,
+            ext,
+            context,
+            config,
+            fees_config,
+            &[],
+            cache,
+            options,
+        )?;
+        let node_dependent = matches!(
+            outcome.aborted,
+            Some(FunctionCallError::Timeout | FunctionCallError::MemoryCapExceeded { .. })
+        );
+        if !node_dependent && !ext.state_changed() {
+            self.insert_at(self.now(), key, &outcome);
+        }
+        Ok(outcome)
+    }
+
+    fn now(&self) -> Instant {
+        self.clock.as_ref().map_or_else(Instant::now, |clock| clock.now())
+    }
+
+    fn get_at(&self, now: Instant, key: &ViewCallKey) -> Option<VMOutcome> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = entries.entries.get(key)?;
+        if now.saturating_duration_since(entry.inserted) > self.policy.ttl {
+            entries.remove(key);
+            return None;
+        }
+        let outcome = VMOutcome::try_from_slice(&entry.outcome).ok();
+        let last_use = entry.last_use;
+        entries.clock += 1;
+        let clock = entries.clock;
+        entries.by_use.remove(&last_use);
+        entries.by_use.insert(clock, key.clone());
+        entries.entries.get_mut(key).unwrap().last_use = clock;
+        outcome
+    }
+
+    fn insert_at(&self, now: Instant, key: ViewCallKey, outcome: &VMOutcome) {
+        let Ok(outcome) = borsh::to_vec(outcome) else { return };
+        let size = outcome.len() as u64 + key.size();
+        if size > self.policy.max_bytes || self.policy.max_entries == 0 {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap();
+        entries.remove(&key);
+        while entries.entries.len() >= self.policy.max_entries
+            || entries.size + size > self.policy.max_bytes
+        {
+            let Some((_, oldest)) = entries.by_use.pop_first() else { break };
+            entries.remove(&oldest);
+        }
+        entries.clock += 1;
+        let last_use = entries.clock;
+        entries.by_use.insert(last_use, key.clone());
+        entries.entries.insert(key, Entry { outcome, inserted: now, last_use });
+        entries.size += size;
+    }
+}
+
+/// Whether the outcome of a call made with `options` can be served from the
+/// cache: the options must not feed sinks or counters, which a cached
+/// outcome would skip, nor record more than the outcome of a plain call or
+/// change it beyond the key.
+fn is_cacheable(options: &RunOptions) -> bool {
+    #[cfg(feature = "coverage")]
+    if options.coverage.is_some() {
+        return false;
+    }
+    #[cfg(feature = "backtrace")]
+    if options.backtrace {
+        return false;
+    }
+    !options.record_checkpoints
+        && !options.record_host_calls
+        && !options.record_state_witness
+        && !options.execution_fingerprint
+        && options.return_sink.is_none()
+        && options.log_capture.sink().is_none()
+        && options.consensus.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::logic::mocks::mock_external::MockedExternal;
+    use crate::logic::ReturnData;
+    use crate::tests::{create_context, test_vm_config};
+    use unc_primitives_core::config::ViewConfig;
+
+    const CONTRACT: &str = r#"
+(module
+  (import "env" "storage_read" (func $storage_read (param i64 i64 i64) (result i64)))
+  (import "env" "value_return" (func $value_return (param i64 i64)))
+  (memory 1)
+  (data (i32.const 0) "k")
+  (func (export "main")
+    (drop (call $storage_read (i64.const 1) (i64.const 0) (i64.const 0)))
+    (call $value_return (i64.const -1) (i64.const 0))))"#;
+
+    fn key(input: u8) -> ViewCallKey {
+        ViewCallKey {
+            code_hash: CryptoHash::hash_bytes(b"contract"),
+            method_name: "main".to_string(),
+            context_hash: CryptoHash::hash_bytes(&[input]),
+            config: CryptoHash::default(),
+            state_root: CryptoHash::default(),
+        }
+    }
+
+    #[test]
+    fn test_view_calls_are_cached() {
+        let code = ContractCode::new(wat::parse_str(CONTRACT).unwrap(), None);
+        let config = test_vm_config();
+        let fees = RuntimeFeesConfig::test();
+        let cache = ViewCallCache::new(ViewCachePolicy::default());
+        let run = |ext: &mut MockedExternal, block: &[u8], context| {
+            let options = RunOptions::default();
+            let state_root = CryptoHash::hash_bytes(block);
+            let outcome = cache
+                .run(&code, "main", ext, state_root, context, &config, &fees, None, &options)
+                .unwrap();
+            assert_eq!(outcome.aborted, None);
+            outcome.return_data
+        };
+        let max_gas_burnt = config.limit_config.max_gas_burnt;
+        let view = |block_height| VMContext {
+            block_height,
+            view_config: Some(ViewConfig { max_gas_burnt }),
+            ..create_context(vec![])
+        };
+        let value = |value: &[u8]| ReturnData::Value(value.to_vec());
+
+        let mut ext = MockedExternal::new();
+        ext.fake_trie.insert(b"k".to_vec(), b"old".to_vec());
+        assert_eq!(run(&mut ext, b"1", view(1)), value(b"old"));
+        ext.fake_trie.insert(b"k".to_vec(), b"new".to_vec());
+        assert_eq!(run(&mut ext, b"1", view(1)), value(b"old"));
+        assert_eq!(run(&mut ext, b"2", view(2)), value(b"new"));
+        assert_eq!(cache.len(), 2);
+
+        // Blocks of other forks and other contexts do not share outcomes.
+        ext.fake_trie.insert(b"k".to_vec(), b"fork".to_vec());
+        assert_eq!(run(&mut ext, b"2'", view(2)), value(b"fork"));
+        let context = VMContext { view_config: Some(ViewConfig { max_gas_burnt: 1 }), ..view(2) };
+        let state_root = CryptoHash::hash_bytes(b"2");
+        let options = RunOptions::default();
+        let outcome = cache
+            .run(&code, "main", &mut ext, state_root, context, &config, &fees, None, &options)
+            .unwrap();
+        assert!(outcome.aborted.is_some());
+        let context = VMContext { signer_account_id: "bob.test".parse().unwrap(), ..view(2) };
+        assert_eq!(run(&mut ext, b"2", context), value(b"fork"));
+        assert_eq!(cache.len(), 5);
+
+        // The cache is bypassed while the state is not the one of the block.
+        ext.state_changed = true;
+        ext.fake_trie.insert(b"k".to_vec(), b"newer".to_vec());
+        assert_eq!(run(&mut ext, b"2", view(2)), value(b"newer"));
+        assert_eq!(run(&mut ext, b"3", view(3)), value(b"newer"));
+        assert_eq!(cache.len(), 5);
+        ext.state_changed = false;
+        assert_eq!(run(&mut ext, b"2", view(2)), value(b"new"));
+
+        // Calls which are not views always run.
+        assert_eq!(run(&mut ext, b"2", create_context(vec![])), value(b"newer"));
+        assert_eq!(cache.len(), 5);
+        cache.clear();
+        assert!(cache.is_empty());
+        assert_eq!(cache.size(), 0);
+    }
+
+    #[test]
+    fn test_bounds() {
+        let outcome = VMOutcome::nop_outcome(FunctionCallError::LinkError { msg: String::new() });
+        let ttl = Duration::from_secs(10);
+        let cache =
+            ViewCallCache::new(ViewCachePolicy { ttl, max_entries: 2, max_bytes: u64::MAX });
+        let now = Instant::now();
+        cache.insert_at(now, key(1), &outcome);
+        cache.insert_at(now, key(2), &outcome);
+        assert_eq!(cache.get_at(now, &key(1)).as_ref(), Some(&outcome));
+        // Evicts the least recently used entry.
+        cache.insert_at(now, key(3), &outcome);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get_at(now, &key(2)).is_none());
+        assert!(cache.get_at(now, &key(3)).is_some());
+        assert!(cache.get_at(now + ttl, &key(1)).is_some());
+        assert!(cache.get_at(now + ttl + Duration::from_secs(1), &key(1)).is_none());
+        assert_eq!(cache.len(), 1);
+
+        let entry_size = cache.size();
+        let policy = ViewCachePolicy { ttl, max_entries: 10, max_bytes: entry_size };
+        let cache = ViewCallCache::new(policy);
+        cache.insert_at(now, key(1), &outcome);
+        cache.insert_at(now, key(2), &outcome);
+        assert_eq!(cache.len(), 1);
+        assert_eq!(cache.size(), entry_size);
+        assert!(cache.get_at(now, &key(2)).is_some());
+        let policy = ViewCachePolicy { max_bytes: entry_size - 1, ..policy };
+        let cache = ViewCallCache::new(policy);
+        cache.insert_at(now, key(1), &outcome);
+        assert!(cache.is_empty());
+    }
+}
@@ -1,17 +1,20 @@
+use crate::cache::contract_cache_key;
 use crate::errors::ContractPrecompilatonResult;
 use crate::imports::wasmer2::Wasmer2Imports;
 use crate::logic::errors::{
-    CacheError, CompilationError, FunctionCallError, MethodResolveError, VMRunnerError, WasmTrap,
+    CacheError, CompilationError, FunctionCallError, MethodResolveError, VMRunnerError,
 };
 use crate::logic::gas_counter::FastGasCounter;
 use crate::logic::types::PromiseResult;
 use crate::logic::{
     CompiledContract, CompiledContractCache, Config, External, MemSlice, MemoryLike, VMContext,
-    VMLogic, VMOutcome,
+    VMLogic, VMOutcome, WasmFrame,
 };
-use crate::prepare;
-use crate::runner::VMResult;
-use crate::{get_contract_cache_key, imports, ContractCode};
+use crate::prepare::{self, NanCanonicalization};
+use crate::metrics::ExecutionTimer;
+use crate::resources::{ResourceKind, ResourceToken, Tracked};
+use crate::runner::{CodegenTarget, CompilationInfo, HostSettings, VMResult};
+use crate::{imports, ContractCode};
 use memoffset::offset_of;
 use unc_parameters::vm::VMKind;
 use unc_parameters::RuntimeFeesConfig;
@@ -24,27 +27,33 @@ use wasmer_engine::{Engine, Executable};
 use wasmer_engine_universal::{
     Universal, UniversalEngine, UniversalExecutable, UniversalExecutableRef,
 };
-use wasmer_types::{FunctionIndex, InstanceConfig, MemoryType, Pages, WASM_PAGE_SIZE};
-use wasmer_vm::{
-    Artifact, Instantiatable, LinearMemory, LinearTable, Memory, MemoryStyle, TrapCode, VMMemory,
+use wasmer_types::{
+    FunctionIndex, InstanceConfig, LocalFunctionIndex, MemoryType, Pages, WASM_PAGE_SIZE,
 };
+use wasmer_vm::{Artifact, Instantiatable, LinearMemory, LinearTable, Memory, MemoryStyle, VMMemory};
 
 #[derive(Clone)]
-pub struct Wasmer2Memory(Arc<LinearMemory>);
+pub struct Wasmer2Memory(Arc<LinearMemory>, #[allow(dead_code)] ResourceToken);
 
 impl Wasmer2Memory {
     fn new(
         initial_memory_pages: u32,
         max_memory_pages: u32,
+        hardening: &crate::MemoryHardening,
     ) -> Result<Self, wasmer_vm::MemoryError> {
         let max_pages = Pages(max_memory_pages);
-        Ok(Wasmer2Memory(Arc::new(LinearMemory::new(
+        let memory = Arc::new(LinearMemory::new(
             &MemoryType::new(Pages(initial_memory_pages), Some(max_pages), false),
             &MemoryStyle::Static {
                 bound: max_pages,
-                offset_guard_size: wasmer_types::WASM_PAGE_SIZE as u64,
+                offset_guard_size: crate::hardening::guard_size(
+                    hardening,
+                    wasmer_types::WASM_PAGE_SIZE as u64,
+                ),
             },
-        )?)))
+        )?);
+        let bytes = u64::from(initial_memory_pages) * wasmer_types::WASM_PAGE_SIZE as u64;
+        Ok(Wasmer2Memory(memory, ResourceToken::new(ResourceKind::Memory, bytes)))
     }
 
     /// Returns pointer to memory at the specified offset provided that there’s
@@ -121,6 +130,11 @@ impl MemoryLike for Wasmer2Memory {
         // will change guest memory mapping under us.
         Ok(unsafe { self.get_mut(offset, buffer.len())? }.copy_from_slice(buffer))
     }
+
+    fn data_size(&self) -> u64 {
+        // SAFETY: the length is read, no reference to the memory is kept.
+        unsafe { self.0.vmmemory().as_ref() }.current_length as u64
+    }
 }
 
 fn get_entrypoint_index(
@@ -151,9 +165,12 @@ fn translate_runtime_error(
 ) -> Result<FunctionCallError, VMRunnerError> {
     // Errors produced by host function calls also become `RuntimeError`s that wrap a dynamic
     // instance of `VMLogicError` internally. See the implementation of `Wasmer2Imports`.
+    let trace = if logic.wants_abort_trace() { wasm_trace(&error) } else { Vec::new() };
     let error = match error.downcast::<crate::logic::VMLogicError>() {
         Ok(vm_logic) => {
-            return vm_logic.try_into();
+            let abort = vm_logic.try_into()?;
+            logic.record_abort_trace(&abort, trace);
+            return Ok(abort);
         }
         Err(original) => original,
     };
@@ -161,27 +178,27 @@ fn translate_runtime_error(
     let trap_code = error.to_trap().unwrap_or_else(|| {
         panic!("runtime error is not a trap: {}", msg);
     });
-    Ok(match trap_code {
-        TrapCode::GasExceeded => FunctionCallError::HostError(logic.process_gas_limit()),
-        TrapCode::StackOverflow => FunctionCallError::WasmTrap(WasmTrap::StackOverflow),
-        TrapCode::HeapAccessOutOfBounds => FunctionCallError::WasmTrap(WasmTrap::MemoryOutOfBounds),
-        TrapCode::HeapMisaligned => FunctionCallError::WasmTrap(WasmTrap::MisalignedAtomicAccess),
-        TrapCode::TableAccessOutOfBounds => {
-            FunctionCallError::WasmTrap(WasmTrap::MemoryOutOfBounds)
-        }
-        TrapCode::OutOfBounds => FunctionCallError::WasmTrap(WasmTrap::MemoryOutOfBounds),
-        TrapCode::IndirectCallToNull => FunctionCallError::WasmTrap(WasmTrap::IndirectCallToNull),
-        TrapCode::BadSignature => {
-            FunctionCallError::WasmTrap(WasmTrap::IncorrectCallIndirectSignature)
-        }
-        TrapCode::IntegerOverflow => FunctionCallError::WasmTrap(WasmTrap::IllegalArithmetic),
-        TrapCode::IntegerDivisionByZero => FunctionCallError::WasmTrap(WasmTrap::IllegalArithmetic),
-        TrapCode::BadConversionToInteger => {
-            FunctionCallError::WasmTrap(WasmTrap::IllegalArithmetic)
-        }
-        TrapCode::UnreachableCodeReached => FunctionCallError::WasmTrap(WasmTrap::Unreachable),
-        TrapCode::UnalignedAtomic => FunctionCallError::WasmTrap(WasmTrap::MisalignedAtomicAccess),
-    })
+    let abort = match crate::traps::wasmer2_trap(trap_code) {
+        Some(kind) => FunctionCallError::WasmTrap(kind.wasm_trap()),
+        None => FunctionCallError::HostError(logic.process_gas_limit()),
+    };
+    logic.record_abort_trace(&abort, trace);
+    Ok(abort)
+}
+
+/// Wasm frames of the call stack at the point of `error`, innermost first.
+///
+/// Only collected when [`VMLogic::wants_abort_trace`].
+fn wasm_trace(error: &wasmer_engine::RuntimeError) -> Vec<WasmFrame> {
+    error
+        .trace()
+        .iter()
+        .map(|frame| WasmFrame {
+            func_index: frame.func_index(),
+            function_name: frame.function_name().map(String::from),
+            module_offset: Some(frame.module_offset()),
+        })
+        .collect()
 }
 
 #[derive(Hash, PartialEq, Debug)]
@@ -229,29 +246,56 @@ pub(crate) fn wasmer2_vm_hash() -> u64 {
 
 pub(crate) type VMArtifact = Arc<wasmer_engine_universal::UniversalArtifact>;
 
+/// Addresses of the code of the functions of `artifact`.
+fn code_ranges(artifact: &VMArtifact) -> impl Iterator<Item = std::ops::Range<usize>> + '_ {
+    (0..).map_while(|index| artifact.function_extent(LocalFunctionIndex::from_u32(index))).map(
+        |extent| {
+            let start = extent.address.0 as usize;
+            start..start + extent.length
+        },
+    )
+}
+
 pub(crate) struct Wasmer2VM {
     pub(crate) config: Config,
+    /// Settings of the host the calls run on.
+    host: HostSettings,
     pub(crate) engine: UniversalEngine,
+    pub(crate) codegen: CodegenTarget,
 }
 
 impl Wasmer2VM {
-    pub(crate) fn new_for_target(config: Config, target: wasmer_compiler::Target) -> Self {
+    pub(crate) fn new_for_target(
+        config: Config,
+        target: wasmer_compiler::Target,
+        host: HostSettings,
+    ) -> Self {
         // We only support singlepass compiler at the moment.
         assert_eq!(WASMER2_CONFIG.compiler, WasmerCompiler::Singlepass);
-        let compiler = Singlepass::new();
+        let mut compiler = Singlepass::new();
+        compiler.canonicalize_nans(
+            NanCanonicalization::for_config(&config) == NanCanonicalization::Compiler,
+        );
         // We only support universal engine at the moment.
         assert_eq!(WASMER2_CONFIG.engine, WasmerEngine::Universal);
-        let features =
-            crate::features::WasmFeatures::from(config.limit_config.contract_prepare_version);
+        let features = crate::features::WasmFeatures::from(&config);
         Self {
             config,
+            host,
+            codegen: CodegenTarget::Host,
             engine: Universal::new(compiler).target(target).features(features.into()).engine(),
         }
     }
 
-    pub(crate) fn new(config: Config) -> Self {
+    pub(crate) fn new_with_codegen(
+        config: Config,
+        codegen: CodegenTarget,
+        host: HostSettings,
+    ) -> Self {
         use wasmer_compiler::{CpuFeature, Target, Triple};
-        let target_features = if cfg!(feature = "no_cpu_compatibility_checks") {
+        let target_features = if codegen == CodegenTarget::Baseline
+            || cfg!(feature = "no_cpu_compatibility_checks")
+        {
             let mut fs = CpuFeature::set();
             // These features should be sufficient to run the single pass compiler.
             fs.insert(CpuFeature::SSE2);
@@ -265,7 +309,10 @@ impl Wasmer2VM {
         } else {
             CpuFeature::for_host()
         };
-        Self::new_for_target(config, Target::new(Triple::host(), target_features))
+        Self {
+            codegen,
+            ..Self::new_for_target(config, Target::new(Triple::host(), target_features), host)
+        }
     }
 
     pub(crate) fn compile_uncached(
@@ -294,9 +341,13 @@ impl Wasmer2VM {
         &self,
         code: &ContractCode,
         cache: Option<&dyn CompiledContractCache>,
-    ) -> Result<Result<UniversalExecutable, CompilationError>, CacheError> {
+    ) -> Result<Result<(UniversalExecutable, CompilationInfo), CompilationError>, CacheError> {
+        let start = std::time::Instant::now();
         let executable_or_error = self.compile_uncached(code);
-        let key = get_contract_cache_key(code, &self.config);
+        let elapsed = start.elapsed();
+        crate::metrics::compiled(VMKind::Wasmer2, elapsed);
+        let info = CompilationInfo::singlepass(elapsed);
+        let key = contract_cache_key(code, &self.config, self.codegen);
 
         if let Some(cache) = cache {
             let record = match &executable_or_error {
@@ -309,9 +360,12 @@ impl Wasmer2VM {
                 Err(err) => CompiledContract::CompileModuleError(err.clone()),
             };
             cache.put(&key, record).map_err(CacheError::WriteError)?;
+            if executable_or_error.is_ok() {
+                cache.put_compilation_info(&key, &info).map_err(CacheError::WriteError)?;
+            }
         }
 
-        Ok(executable_or_error)
+        Ok(executable_or_error.map(|executable| (executable, info)))
     }
 
     fn compile_and_load(
@@ -329,16 +383,12 @@ impl Wasmer2VM {
         // outcome). And `cache`, being a database, can fail with an `io::Error`.
         let _span = tracing::debug_span!(target: "vm", "Wasmer2VM::compile_and_load").entered();
 
-        let key = get_contract_cache_key(code, &self.config);
+        let key = contract_cache_key(code, &self.config, self.codegen);
 
         let compile_or_read_from_cache = || -> VMResult<Result<VMArtifact, CompilationError>> {
             let _span = tracing::debug_span!(target: "vm", "Wasmer2VM::compile_or_read_from_cache")
                 .entered();
-            let cache_record = cache
-                .map(|cache| cache.get(&key))
-                .transpose()
-                .map_err(CacheError::ReadError)?
-                .flatten();
+            let cache_record = crate::cache::lookup(cache, &key, VMKind::Wasmer2)?;
 
             let stored_artifact: Option<VMArtifact> = match cache_record {
                 None => None,
@@ -346,6 +396,9 @@ impl Wasmer2VM {
                 Some(CompiledContract::Code(serialized_module)) => {
                     let _span =
                         tracing::debug_span!(target: "vm", "Wasmer2VM::read_from_cache").entered();
+                    let bytes = serialized_module.len() as u64;
+                    let serialized_module =
+                        Tracked::new(serialized_module, ResourceKind::CacheBuffer, bytes);
                     unsafe {
                         // (UN-)SAFETY: the `serialized_module` must have been produced by a prior call to
                         // `serialize`.
@@ -372,7 +425,7 @@ impl Wasmer2VM {
                 Ok(it)
             } else {
                 match self.compile_and_cache(code, cache)? {
-                    Ok(executable) => Ok(self
+                    Ok((executable, _)) => Ok(self
                         .engine
                         .load_universal_executable(&executable)
                         .map(Arc::new)
@@ -382,7 +435,11 @@ impl Wasmer2VM {
             })
         };
 
-        return compile_or_read_from_cache();
+        let loaded = compile_or_read_from_cache()?;
+        if let Ok(artifact) = &loaded {
+            crate::hardening::check_loaded(&self.host.hardening, code_ranges(artifact))?;
+        }
+        Ok(loaded)
     }
 
     fn run_method(
@@ -440,10 +497,12 @@ impl Wasmer2VM {
                         let abort = match err {
                             Start(err) => translate_runtime_error(err, import.vmlogic)?,
                             Link(e) => FunctionCallError::LinkError { msg: e.to_string() },
-                            CpuFeature(e) => panic!(
-                                "host doesn't support the CPU features needed to run contracts: {}",
-                                e
-                            ),
+                            CpuFeature(e) => {
+                                return Err(VMRunnerError::LoadingError(format!(
+                                    "host doesn't support the CPU features needed to run \
+                                     contracts: {e}"
+                                )))
+                            }
                         };
                         return Ok(Err(abort));
                     }
@@ -461,8 +520,10 @@ impl Wasmer2VM {
                 };
                 handle
             };
+            let instance = Tracked::new(instance, ResourceKind::Instance, 0);
             if let Some(function) = instance.function_by_index(entrypoint) {
                 let _span = tracing::debug_span!(target: "vm", "run_method/call").entered();
+                let _timer = ExecutionTimer::start(VMKind::Wasmer2);
                 // Signature for the entry point should be `() -> ()`. This is only a sanity check
                 // – this should've been already checked by `get_entrypoint_index`.
                 let signature = artifact
@@ -559,7 +620,7 @@ impl wasmer_vm::Tunables for &Wasmer2VM {
 }
 
 impl crate::runner::VM for Wasmer2VM {
-    fn run(
+    fn run_with_options(
         &self,
         code: &ContractCode,
         method_name: &str,
@@ -568,10 +629,12 @@ impl crate::runner::VM for Wasmer2VM {
         fees_config: &RuntimeFeesConfig,
         promise_results: &[PromiseResult],
         cache: Option<&dyn CompiledContractCache>,
+        options: &crate::runner::RunOptions,
     ) -> Result<VMOutcome, VMRunnerError> {
         let mut memory = Wasmer2Memory::new(
             self.config.limit_config.initial_memory_pages,
             self.config.limit_config.max_memory_pages,
+            &self.host.hardening,
         )
         .expect("Cannot create memory for a contract call");
 
@@ -580,11 +643,17 @@ impl crate::runner::VM for Wasmer2VM {
         let vmmemory = memory.vm();
         let mut logic =
             VMLogic::new(ext, context, &self.config, fees_config, promise_results, &mut memory);
+        logic.apply_run_options(options)?;
+        #[cfg(feature = "backtrace")]
+        logic.keep_code_for_backtrace(code, options);
 
         let result = logic.before_loading_executable(method_name, code.code().len());
         if let Err(e) = result {
             return Ok(VMOutcome::abort(logic, e));
         }
+        if let Err(e) = logic.add_code_pricing_fee(code.code().len())? {
+            return Ok(VMOutcome::abort(logic, e));
+        }
 
         let artifact = self.compile_and_load(code, cache)?;
         let artifact = match artifact {
@@ -603,7 +672,7 @@ impl crate::runner::VM for Wasmer2VM {
             return Ok(VMOutcome::abort_but_nop_outcome_in_old_protocol(logic, e));
         }
         match self.run_method(&artifact, import, method_name)? {
-            Ok(()) => Ok(VMOutcome::ok(logic)),
+            Ok(()) => VMOutcome::try_ok(logic),
             Err(err) => Ok(VMOutcome::abort(logic, err)),
         }
     }
@@ -618,11 +687,13 @@ impl crate::runner::VM for Wasmer2VM {
     > {
         Ok(self
             .compile_and_cache(code, Some(cache))?
-            .map(|_| ContractPrecompilatonResult::ContractCompiled))
+            .map(|(_, info)| ContractPrecompilatonResult::ContractCompiled(info)))
     }
 }
 
 #[test]
 fn test_memory_like() {
-    crate::logic::test_utils::test_memory_like(|| Box::new(Wasmer2Memory::new(1, 1).unwrap()));
+    crate::logic::test_utils::test_memory_like(|| {
+        Box::new(Wasmer2Memory::new(1, 1, &Default::default()).unwrap())
+    });
 }
@@ -8,9 +8,12 @@ use crate::logic::{
 };
 use crate::memory::WasmerMemory;
 use crate::prepare;
-use crate::runner::VMResult;
+use crate::metrics::ExecutionTimer;
+use crate::resources::{ResourceKind, Tracked};
+use crate::runner::{CompilationInfo, VMResult};
 use crate::{get_contract_cache_key, imports, ContractCode};
-use unc_parameters::vm::{Config, VMKind};
+use crate::logic::Config;
+use unc_parameters::vm::VMKind;
 use unc_parameters::RuntimeFeesConfig;
 use wasmer_runtime::{ImportObject, Module};
 
@@ -121,26 +124,9 @@ impl IntoVMError for wasmer_runtime::error::RuntimeError {
                     );
                 }
                 // A trap that Wasmer knows about occurred.
-                InvokeError::TrapCode { code, srcloc: _ } => Ok(match code {
-                    wasmer_runtime::ExceptionCode::Unreachable => {
-                        FunctionCallError::WasmTrap(WasmTrap::Unreachable)
-                    }
-                    wasmer_runtime::ExceptionCode::IncorrectCallIndirectSignature => {
-                        FunctionCallError::WasmTrap(WasmTrap::IncorrectCallIndirectSignature)
-                    }
-                    wasmer_runtime::ExceptionCode::MemoryOutOfBounds => {
-                        FunctionCallError::WasmTrap(WasmTrap::MemoryOutOfBounds)
-                    }
-                    wasmer_runtime::ExceptionCode::CallIndirectOOB => {
-                        FunctionCallError::WasmTrap(WasmTrap::CallIndirectOOB)
-                    }
-                    wasmer_runtime::ExceptionCode::IllegalArithmetic => {
-                        FunctionCallError::WasmTrap(WasmTrap::IllegalArithmetic)
-                    }
-                    wasmer_runtime::ExceptionCode::MisalignedAtomicAccess => {
-                        FunctionCallError::WasmTrap(WasmTrap::MisalignedAtomicAccess)
-                    }
-                }),
+                InvokeError::TrapCode { code, srcloc: _ } => {
+                    Ok(FunctionCallError::WasmTrap(crate::traps::wasmer0_trap(code).wasm_trap()))
+                }
                 // A trap occurred that Wasmer knows about but it had a trap code that
                 // we weren't expecting or that we do not handle.
                 // As of 0.17.0, thrown only from Cranelift BE.
@@ -207,9 +193,11 @@ fn run_method(
             }
         }
     };
+    let instance = Tracked::new(instance, ResourceKind::Instance, 0);
 
     {
         let _span = tracing::debug_span!(target: "vm", "run_method/call").entered();
+        let _timer = ExecutionTimer::start(VMKind::Wasmer0);
         if let Err(err) = instance.call(method_name, &[]) {
             let guest_aborted = err.into_vm_error()?;
             return Ok(Err(guest_aborted));
@@ -261,8 +249,13 @@ impl Wasmer0VM {
         &self,
         code: &ContractCode,
         cache: Option<&dyn CompiledContractCache>,
-    ) -> Result<Result<wasmer_runtime::Module, CompilationError>, CacheError> {
+    ) -> Result<Result<(wasmer_runtime::Module, CompilationInfo), CompilationError>, CacheError>
+    {
+        let start = std::time::Instant::now();
         let module_or_error = self.compile_uncached(code);
+        let elapsed = start.elapsed();
+        crate::metrics::compiled(VMKind::Wasmer0, elapsed);
+        let info = CompilationInfo::singlepass(elapsed);
         let key = get_contract_cache_key(code, &self.config);
 
         if let Some(cache) = cache {
@@ -277,9 +270,12 @@ impl Wasmer0VM {
                 Err(err) => CompiledContract::CompileModuleError(err.clone()),
             };
             cache.put(&key, record).map_err(CacheError::WriteError)?;
+            if module_or_error.is_ok() {
+                cache.put_compilation_info(&key, &info).map_err(CacheError::WriteError)?;
+            }
         }
 
-        Ok(module_or_error)
+        Ok(module_or_error.map(|module| (module, info)))
     }
 
     pub(crate) fn compile_and_load(
@@ -297,11 +293,7 @@ impl Wasmer0VM {
                     tracing::debug_span!(target: "vm", "Wasmer0VM::compile_or_read_from_cache")
                         .entered();
 
-                let cache_record = cache
-                    .map(|cache| cache.get(&key))
-                    .transpose()
-                    .map_err(CacheError::ReadError)?
-                    .flatten();
+                let cache_record = crate::cache::lookup(cache, &key, VMKind::Wasmer0)?;
 
                 let stored_module: Option<wasmer_runtime::Module> = match cache_record {
                     None => None,
@@ -310,6 +302,9 @@ impl Wasmer0VM {
                         let _span =
                             tracing::debug_span!(target: "vm", "Wasmer0VM::read_from_cache")
                                 .entered();
+                        let bytes = serialized_module.len() as u64;
+                        let serialized_module =
+                            Tracked::new(serialized_module, ResourceKind::CacheBuffer, bytes);
                         let artifact = wasmer_runtime_core::cache::Artifact::deserialize(
                             serialized_module.as_slice(),
                         )
@@ -331,7 +326,7 @@ impl Wasmer0VM {
 
                 Ok(match stored_module {
                     Some(it) => Ok(it),
-                    None => self.compile_and_cache(code, cache)?,
+                    None => self.compile_and_cache(code, cache)?.map(|(module, _)| module),
                 })
             };
 
@@ -340,7 +335,7 @@ impl Wasmer0VM {
 }
 
 impl crate::runner::VM for Wasmer0VM {
-    fn run(
+    fn run_with_options(
         &self,
         code: &ContractCode,
         method_name: &str,
@@ -349,7 +344,9 @@ impl crate::runner::VM for Wasmer0VM {
         fees_config: &RuntimeFeesConfig,
         promise_results: &[PromiseResult],
         cache: Option<&dyn CompiledContractCache>,
+        options: &crate::runner::RunOptions,
     ) -> Result<VMOutcome, VMRunnerError> {
+        let _execution = crate::concurrency::enter(VMKind::Wasmer0);
         if !cfg!(target_arch = "x86") && !cfg!(target_arch = "x86_64") {
             // TODO(#1940): Remove once NaN is standardized by the VM.
             panic!(
@@ -371,11 +368,15 @@ impl crate::runner::VM for Wasmer0VM {
 
         let mut logic =
             VMLogic::new(ext, context, &self.config, fees_config, promise_results, &mut memory);
+        logic.apply_run_options(options)?;
 
         let result = logic.before_loading_executable(method_name, code.code().len());
         if let Err(e) = result {
             return Ok(VMOutcome::abort(logic, e));
         }
+        if let Err(e) = logic.add_code_pricing_fee(code.code().len())? {
+            return Ok(VMOutcome::abort(logic, e));
+        }
 
         // TODO: consider using get_module() here, once we'll go via deployment path.
         let module = self.compile_and_load(code, cache)?;
@@ -405,7 +406,7 @@ impl crate::runner::VM for Wasmer0VM {
         }
 
         match run_method(&module, &import_object, method_name)? {
-            Ok(()) => Ok(VMOutcome::ok(logic)),
+            Ok(()) => VMOutcome::try_ok(logic),
             Err(err) => Ok(VMOutcome::abort(logic, err)),
         }
     }
@@ -420,6 +421,6 @@ impl crate::runner::VM for Wasmer0VM {
     > {
         Ok(self
             .compile_and_cache(code, Some(cache))?
-            .map(|_| ContractPrecompilatonResult::ContractCompiled))
+            .map(|(_, info)| ContractPrecompilatonResult::ContractCompiled(info)))
     }
 }
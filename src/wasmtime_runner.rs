@@ -1,20 +1,29 @@
 use crate::errors::{ContractPrecompilatonResult, IntoVMError};
+use crate::cache::contract_cache_key_for_vm_hash;
 use crate::logic::errors::{
-    CompilationError, FunctionCallError, MethodResolveError, PrepareError, VMLogicError,
-    VMRunnerError, WasmTrap,
+    CacheError, CompilationError, FunctionCallError, HostError, MethodResolveError, PrepareError,
+    VMLogicError, VMRunnerError,
 };
 use crate::logic::types::PromiseResult;
 use crate::logic::Config;
 use crate::logic::{
-    CompiledContractCache, External, MemSlice, MemoryLike, VMContext, VMLogic, VMOutcome,
+    CompiledContract, CompiledContractCache, External, Interrupt, MemSlice, MemoryLike, VMContext,
+    VMLogic, VMOutcome, WasmFrame,
 };
+use crate::metrics::ExecutionTimer;
+use crate::prepare::NanCanonicalization;
+use crate::resources::{ResourceKind, Tracked};
+use crate::runner::{CompilationInfo, Compiler, HostSettings, OptLevel, VMResult};
 use crate::{imports, prepare, ContractCode};
 use unc_parameters::vm::VMKind;
 use unc_parameters::RuntimeFeesConfig;
+use unc_primitives_core::hash::CryptoHash;
 use std::borrow::Cow;
 use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 use wasmtime::ExternType::Func;
-use wasmtime::{Engine, Linker, Memory, MemoryType, Module, Store};
+use wasmtime::{Engine, Linker, Memory, MemoryType, Module, Store, UpdateDeadline};
 
 type Caller = wasmtime::Caller<'static, ()>;
 thread_local! {
@@ -75,6 +84,10 @@ impl MemoryLike for WasmtimeMemory {
             Ok(())
         })
     }
+
+    fn data_size(&self) -> u64 {
+        with_caller(|caller| self.0.data_size(caller)) as u64
+    }
 }
 
 impl IntoVMError for anyhow::Error {
@@ -90,33 +103,41 @@ impl IntoVMError for anyhow::Error {
             };
         }
         if let Some(trap) = cause.downcast_ref::<wasmtime::Trap>() {
-            use wasmtime::Trap as T;
-            let nondeterministic_message = 'nondet: {
-                return Ok(FunctionCallError::WasmTrap(match *trap {
-                    T::StackOverflow => WasmTrap::StackOverflow,
-                    T::MemoryOutOfBounds => WasmTrap::MemoryOutOfBounds,
-                    T::TableOutOfBounds => WasmTrap::MemoryOutOfBounds,
-                    T::IndirectCallToNull => WasmTrap::IndirectCallToNull,
-                    T::BadSignature => WasmTrap::IncorrectCallIndirectSignature,
-                    T::IntegerOverflow => WasmTrap::IllegalArithmetic,
-                    T::IntegerDivisionByZero => WasmTrap::IllegalArithmetic,
-                    T::BadConversionToInteger => WasmTrap::IllegalArithmetic,
-                    T::UnreachableCodeReached => WasmTrap::Unreachable,
-                    T::Interrupt => break 'nondet "interrupt",
-                    T::HeapMisaligned => break 'nondet "heap misaligned",
-                    t => {
-                        return Err(VMRunnerError::WasmUnknownError {
-                            debug_message: format!("unhandled trap type: {:?}", t),
-                        })
-                    }
-                }));
+            // Fuel is not enabled, gas is charged by the instrumentation, and
+            // the epoch callback of the store fails the interrupted calls
+            // with a host error instead of this trap.
+            if *trap == wasmtime::Trap::Interrupt {
+                return Err(VMRunnerError::Nondeterministic("interrupt".into()));
+            }
+            return match crate::traps::wasmtime_trap(*trap) {
+                Some(kind) => Ok(FunctionCallError::WasmTrap(kind.wasm_trap())),
+                None => Err(VMRunnerError::WasmUnknownError {
+                    debug_message: format!("unhandled trap type: {:?}", trap),
+                }),
             };
-            return Err(VMRunnerError::Nondeterministic(nondeterministic_message.into()));
         }
         Ok(FunctionCallError::LinkError { msg: format!("{:#?}", cause) })
     }
 }
 
+/// Wasm frames of the call stack at the point of `error`, innermost first.
+///
+/// Only collected when [`VMLogic::wants_abort_trace`].
+fn wasm_trace(error: &anyhow::Error) -> Vec<WasmFrame> {
+    let Some(backtrace) = error.downcast_ref::<wasmtime::WasmBacktrace>() else {
+        return Vec::new();
+    };
+    backtrace
+        .frames()
+        .iter()
+        .map(|frame| WasmFrame {
+            func_index: frame.func_index(),
+            function_name: frame.func_name().map(String::from),
+            module_offset: frame.module_offset(),
+        })
+        .collect()
+}
+
 #[cfg(not(feature = "lightbeam"))]
 #[allow(clippy::needless_pass_by_ref_mut)]
 pub fn get_engine(config: &mut wasmtime::Config) -> Engine {
@@ -128,31 +149,162 @@ pub fn get_engine(config: &mut wasmtime::Config) -> Engine {
     Engine::new(config.strategy(wasmtime::Strategy::Lightbeam).unwrap()).unwrap()
 }
 
-pub(crate) fn wasmtime_vm_hash() -> u64 {
-    // TODO: take into account compiler and engine used to compile the contract.
-    64
+/// Hash of the version and the settings of `engine`: wasmtime only loads
+/// the artifacts serialized by engines of the same hash.  The optimization
+/// level is part of it.
+fn engine_hash(engine: &Engine) -> u64 {
+    crate::utils::stable_hash(engine.precompile_compatibility_hash())
+}
+
+pub(crate) fn wasmtime_vm_hash(config: &Config, opt_level: OptLevel) -> u64 {
+    engine_hash(&get_engine(&mut default_wasmtime_config(config, opt_level)))
 }
 
 pub(crate) struct WasmtimeVM {
     config: Config,
+    opt_level: OptLevel,
+    /// Settings of the host the calls run on.
+    host: HostSettings,
+    engine: Engine,
+    /// [`engine_hash`] of the engine, the `vm_hash` of the cache keys.
+    vm_hash: u64,
 }
 
 impl WasmtimeVM {
-    pub(crate) fn new(config: Config) -> Self {
-        Self { config }
+    pub(crate) fn new(config: Config, opt_level: OptLevel, host: HostSettings) -> Self {
+        let engine = get_engine(&mut default_wasmtime_config(&config, opt_level));
+        let vm_hash = engine_hash(&engine);
+        Self { config, opt_level, host, engine, vm_hash }
+    }
+
+    fn cache_key(&self, code: &ContractCode) -> CryptoHash {
+        contract_cache_key_for_vm_hash(code.hash(), &self.config, self.vm_hash)
+    }
+
+    /// The artifact of the contract compiled at [`OptLevel::Fast`], when this
+    /// VM optimizes and has no artifact of its own yet: contracts compiled at
+    /// deploy time run with it until they are compiled again, as wasmtime
+    /// loads the artifacts of any optimization level.
+    fn lookup_fast(
+        &self,
+        code: &ContractCode,
+        cache: Option<&dyn CompiledContractCache>,
+    ) -> Result<Option<CompiledContract>, CacheError> {
+        if self.opt_level == OptLevel::Fast || cache.is_none() {
+            return Ok(None);
+        }
+        let vm_hash = wasmtime_vm_hash(&self.config, OptLevel::Fast);
+        let key = contract_cache_key_for_vm_hash(code.hash(), &self.config, vm_hash);
+        match crate::cache::lookup(cache, &key, VMKind::Wasmtime)? {
+            Some(CompiledContract::Code(code)) => Ok(Some(CompiledContract::Code(code))),
+            _ => Ok(None),
+        }
+    }
+
+    fn compile_uncached(&self, code: &ContractCode) -> Result<Module, CompilationError> {
+        let _span = tracing::debug_span!(target: "vm", "WasmtimeVM::compile_uncached").entered();
+        let prepared_code = prepare::prepare_contract(code.code(), &self.config, VMKind::Wasmtime)
+            .map_err(CompilationError::PrepareError)?;
+        Module::new(&self.engine, prepared_code).map_err(|err| {
+            tracing::error!(?err, "wasmtime failed to compile the prepared code");
+            CompilationError::WasmerCompileError { msg: err.to_string() }
+        })
+    }
+
+    fn compile_and_cache(
+        &self,
+        code: &ContractCode,
+        cache: Option<&dyn CompiledContractCache>,
+    ) -> Result<Result<(Module, CompilationInfo), CompilationError>, CacheError> {
+        let start = std::time::Instant::now();
+        let module_or_error = self.compile_uncached(code);
+        let elapsed = start.elapsed();
+        crate::metrics::compiled(VMKind::Wasmtime, elapsed);
+        let info = CompilationInfo::new(Compiler::Cranelift, self.opt_level, elapsed);
+        let key = self.cache_key(code);
+
+        if let Some(cache) = cache {
+            let record = match &module_or_error {
+                Ok(module) => {
+                    let code = module
+                        .serialize()
+                        .map_err(|_e| CacheError::SerializationError { hash: key.0 })?;
+                    CompiledContract::Code(code)
+                }
+                Err(err) => CompiledContract::CompileModuleError(err.clone()),
+            };
+            cache.put(&key, record).map_err(CacheError::WriteError)?;
+            if module_or_error.is_ok() {
+                cache.put_compilation_info(&key, &info).map_err(CacheError::WriteError)?;
+            }
+        }
+
+        Ok(module_or_error.map(|module| (module, info)))
     }
 
-    pub(crate) fn default_wasmtime_config(&self) -> wasmtime::Config {
-        let features =
-            crate::features::WasmFeatures::from(self.config.limit_config.contract_prepare_version);
-        let mut config = wasmtime::Config::from(features);
-        config.max_wasm_stack(1024 * 1024 * 1024); // wasm stack metering is implemented by instrumentation, we don't want wasmtime to trap before that
-        config
+    fn compile_and_load(
+        &self,
+        code: &ContractCode,
+        cache: Option<&dyn CompiledContractCache>,
+    ) -> VMResult<Result<Module, CompilationError>> {
+        let _span = tracing::debug_span!(target: "vm", "WasmtimeVM::compile_and_load").entered();
+        let key = self.cache_key(code);
+        let cached = match crate::cache::lookup(cache, &key, VMKind::Wasmtime)? {
+            None => self.lookup_fast(code, cache)?,
+            cached => cached,
+        };
+        let module = match cached {
+            None => self.compile_and_cache(code, cache)?.map(|(module, _)| module),
+            Some(CompiledContract::CompileModuleError(err)) => Err(err),
+            Some(CompiledContract::Code(serialized_module)) => {
+                let _span =
+                    tracing::debug_span!(target: "vm", "WasmtimeVM::read_from_cache").entered();
+                let bytes = serialized_module.len() as u64;
+                let serialized_module =
+                    Tracked::new(serialized_module, ResourceKind::CacheBuffer, bytes);
+                // (UN-)SAFETY: the `serialized_module` must have been produced by
+                // `Module::serialize`.  Wasmtime checks that the artifact comes
+                // from the same version with compatible settings, the cache
+                // key covers the rest, but not corruption of the data at rest.
+                match unsafe { Module::deserialize(&self.engine, serialized_module.as_slice()) } {
+                    Ok(module) => Ok(module),
+                    // Wasmtime rejects the artifact, e.g. one of another
+                    // version left in a cache shared by several nodes:
+                    // compile the contract again, replacing it.
+                    Err(err) => {
+                        tracing::warn!(target: "vm", ?err, "wasmtime rejected a cached artifact");
+                        self.compile_and_cache(code, cache)?.map(|(module, _)| module)
+                    }
+                }
+            }
+        };
+        if let Ok(module) = &module {
+            crate::hardening::check_loaded(&self.host.hardening, [module.image_range()])?;
+        }
+        Ok(module)
     }
 }
 
+pub(crate) fn default_wasmtime_config(config: &Config, opt_level: OptLevel) -> wasmtime::Config {
+    let nan_canonicalization = NanCanonicalization::for_config(config);
+    let features = crate::features::WasmFeatures::from(config);
+    let mut config = wasmtime::Config::from(features);
+    config.max_wasm_stack(1024 * 1024 * 1024); // wasm stack metering is implemented by instrumentation, we don't want wasmtime to trap before that
+    config.cranelift_opt_level(match opt_level {
+        OptLevel::Fast => wasmtime::OptLevel::None,
+        OptLevel::Optimized => wasmtime::OptLevel::Speed,
+    });
+    // The bits of the NaNs produced by floating point operations depend on the
+    // CPU otherwise, and contracts can observe them.
+    config.cranelift_nan_canonicalization(nan_canonicalization == NanCanonicalization::Compiler);
+    // The deadlines of the calls move the epoch of the engine, see
+    // `Interrupt::Epoch`.
+    config.epoch_interruption(true);
+    config
+}
+
 impl crate::runner::VM for WasmtimeVM {
-    fn run(
+    fn run_with_options(
         &self,
         code: &ContractCode,
         method_name: &str,
@@ -160,36 +312,60 @@ impl crate::runner::VM for WasmtimeVM {
         context: VMContext,
         fees_config: &RuntimeFeesConfig,
         promise_results: &[PromiseResult],
-        _cache: Option<&dyn CompiledContractCache>,
+        cache: Option<&dyn CompiledContractCache>,
+        options: &crate::runner::RunOptions,
     ) -> Result<VMOutcome, VMRunnerError> {
-        let mut config = self.default_wasmtime_config();
-        let engine = get_engine(&mut config);
-        let mut store = Store::new(&engine, ());
+        let _execution = crate::concurrency::enter(VMKind::Wasmtime);
+        let engine = &self.engine;
+        // The memory and the instance live in the store.
+        let mut store = Tracked::new(
+            Store::new(engine, ()),
+            ResourceKind::Memory,
+            u64::from(self.config.limit_config.initial_memory_pages) * 65_536,
+        );
         let mut memory = WasmtimeMemory::new(
             &mut store,
             self.config.limit_config.initial_memory_pages,
             self.config.limit_config.max_memory_pages,
         )
         .unwrap();
+        // The engine only moves its epoch to interrupt the calls which
+        // passed their deadline, the others go on.
+        let interrupted = Arc::new(AtomicBool::new(false));
+        store.set_epoch_deadline(1);
+        store.epoch_deadline_callback({
+            let interrupted = Arc::clone(&interrupted);
+            move |_| {
+                if interrupted.load(Ordering::Relaxed) {
+                    let err = VMLogicError::HostError(HostError::GasExceeded);
+                    return Err(imports::wasmtime::ErrorContainer::new(err).into());
+                }
+                Ok(UpdateDeadline::Continue(1))
+            }
+        });
         let memory_copy = memory.0;
         let mut logic =
             VMLogic::new(ext, context, &self.config, fees_config, promise_results, &mut memory);
+        let interrupt = Interrupt::Epoch { engine: engine.clone(), interrupted };
+        logic.apply_run_options_with(options, interrupt)?;
+        #[cfg(feature = "backtrace")]
+        logic.keep_code_for_backtrace(code, options);
 
         let result = logic.before_loading_executable(method_name, code.code().len());
         if let Err(e) = result {
             return Ok(VMOutcome::abort(logic, e));
         }
+        if let Err(e) = logic.add_code_pricing_fee(code.code().len())? {
+            return Ok(VMOutcome::abort(logic, e));
+        }
 
-        let prepared_code =
-            match prepare::prepare_contract(code.code(), &self.config, VMKind::Wasmtime) {
-                Ok(code) => code,
-                Err(err) => return Ok(VMOutcome::abort(logic, FunctionCallError::from(err))),
-            };
-        let module = match Module::new(&engine, prepared_code) {
+        let module = match self.compile_and_load(code, cache)? {
             Ok(module) => module,
-            Err(err) => return Ok(VMOutcome::abort(logic, err.into_vm_error()?)),
+            Err(err) => {
+                return Ok(VMOutcome::abort(logic, FunctionCallError::CompilationError(err)));
+            }
         };
-        let mut linker = Linker::new(&engine);
+        let mut linker = Linker::new(engine);
 
         let result = logic.after_loading_executable(code.code().len());
         if let Err(e) = result {
@@ -221,12 +397,33 @@ impl crate::runner::VM for WasmtimeVM {
                 ));
             }
         }
-        match linker.instantiate(&mut store, &module) {
-            Ok(instance) => match instance.get_func(&mut store, method_name) {
-                Some(func) => match func.typed::<(), ()>(&mut store) {
-                    Ok(run) => match run.call(&mut store, ()) {
-                        Ok(_) => Ok(VMOutcome::ok(logic)),
-                        Err(err) => Ok(VMOutcome::abort(logic, err.into_vm_error()?)),
+        let instance = {
+            let _span = tracing::debug_span!(target: "vm", "run_method/instantiate").entered();
+            linker.instantiate(&mut *store, &module)
+        };
+        if instance.is_ok() {
+            store.track(ResourceKind::Instance, 0);
+        }
+        let call = |run: wasmtime::TypedFunc<(), ()>, store: &mut Store<()>| {
+            let _span = tracing::debug_span!(target: "vm", "run_method/call").entered();
+            let _timer = ExecutionTimer::start(VMKind::Wasmtime);
+            run.call(store, ())
+        };
+        match instance {
+            Ok(instance) => match instance.get_func(&mut *store, method_name) {
+                Some(func) => match func.typed::<(), ()>(&*store) {
+                    Ok(run) => match call(run, &mut store) {
+                        Ok(_) => VMOutcome::try_ok(logic),
+                        Err(err) => {
+                            let trace = if logic.wants_abort_trace() {
+                                wasm_trace(&err)
+                            } else {
+                                Vec::new()
+                            };
+                            let abort = err.into_vm_error()?;
+                            logic.record_abort_trace(&abort, trace);
+                            Ok(VMOutcome::abort(logic, abort))
+                        }
                     },
                     Err(err) => Ok(VMOutcome::abort(logic, err.into_vm_error()?)),
                 },
@@ -243,12 +440,11 @@ impl crate::runner::VM for WasmtimeVM {
 
     fn precompile(
         &self,
-        _code: &ContractCode,
-        _cache: &dyn CompiledContractCache,
-    ) -> Result<
-        Result<ContractPrecompilatonResult, CompilationError>,
-        crate::logic::errors::CacheError,
-    > {
-        Ok(Ok(ContractPrecompilatonResult::CacheNotAvailable))
+        code: &ContractCode,
+        cache: &dyn CompiledContractCache,
+    ) -> Result<Result<ContractPrecompilatonResult, CompilationError>, CacheError> {
+        Ok(self
+            .compile_and_cache(code, Some(cache))?
+            .map(|(_, info)| ContractPrecompilatonResult::ContractCompiled(info)))
     }
 }
@@ -0,0 +1,187 @@
+//! Parsing of the WebAssembly text format within limits.
+//!
+//! The text format is convenient for tests, doc examples and quick
+//! experiments on the command line, but the parser has no limits of its own:
+//! a large or generated input can keep it busy and allocate a lot.
+//! [`parse_wat`] bounds the size of the text and of the resulting module and
+//! the time spent parsing, and reports every failure, including parser
+//! panics, as a [`WatError`].
+//!
+//! The texts are parsed on a fixed pool of threads started on first use.
+//! The parser cannot be interrupted, so a parse running past its timeout
+//! keeps its thread busy until it is done, and parses fail with
+//! [`WatError::Busy`] rather than wait once too many are queued.
+
+use std::panic::AssertUnwindSafe;
+use std::sync::mpsc::{self, RecvTimeoutError, TrySendError};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Threads parsing the texts.
+const PARSER_THREADS: usize = 2;
+/// Texts waiting for a parser thread.
+const QUEUE_LEN: usize = 16;
+
+/// Limits of [`parse_wat`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct WatLimits {
+    /// Maximum size of the text, in bytes.
+    pub max_source_len: usize,
+    /// Maximum size of the resulting module, in bytes.
+    pub max_wasm_len: usize,
+    /// Maximum time spent waiting for a parser thread and parsing.
+    pub timeout: Duration,
+}
+
+impl Default for WatLimits {
+    fn default() -> Self {
+        Self {
+            max_source_len: 64 * 1024 * 1024,
+            max_wasm_len: 16 * 1024 * 1024,
+            timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum WatError {
+    #[error("the text is {len} bytes long, more than {limit}")]
+    SourceTooLarge { len: usize, limit: usize },
+    #[error("the module is {len} bytes long, more than {limit}")]
+    ModuleTooLarge { len: usize, limit: usize },
+    #[error("parsing took longer than {0:?}")]
+    Timeout(Duration),
+    #[error("{0}")]
+    Syntax(String),
+    #[error("the parser panicked: {0}")]
+    Panic(String),
+    #[error("cannot start the parser: {0}")]
+    Spawn(String),
+    #[error("too many texts are waiting to be parsed")]
+    Busy,
+}
+
+type ParseResult = std::thread::Result<wat::Result<Vec<u8>>>;
+
+/// A text to parse, and where its module goes.
+struct Job {
+    source: String,
+    /// Time after which the caller no longer waits for the module.
+    deadline: Instant,
+    reply: mpsc::Sender<ParseResult>,
+}
+
+/// Queues `job` for the parser threads, starting them on first use.
+fn submit(job: Job) -> Result<(), WatError> {
+    static QUEUE: Mutex<Option<mpsc::SyncSender<Job>>> = Mutex::new(None);
+    let mut queue = QUEUE.lock().unwrap();
+    let sender = match &mut *queue {
+        Some(sender) => sender,
+        None => queue.insert(start_parsers()?),
+    };
+    sender.try_send(job).map_err(|err| match err {
+        TrySendError::Full(_) => WatError::Busy,
+        TrySendError::Disconnected(_) => WatError::Spawn("the parser threads exited".to_string()),
+    })
+}
+
+fn start_parsers() -> Result<mpsc::SyncSender<Job>, WatError> {
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_LEN);
+    let receiver = std::sync::Arc::new(Mutex::new(receiver));
+    for _ in 0..PARSER_THREADS {
+        let receiver = receiver.clone();
+        // The threads started before a failure exit with the sender dropped.
+        std::thread::Builder::new()
+            .name("wat-parser".to_string())
+            .spawn(move || parse_jobs(&receiver))
+            .map_err(|err| WatError::Spawn(err.to_string()))?;
+    }
+    Ok(sender)
+}
+
+fn parse_jobs(jobs: &Mutex<mpsc::Receiver<Job>>) {
+    loop {
+        let job = match jobs.lock().unwrap().recv() {
+            Ok(job) => job,
+            Err(mpsc::RecvError) => return,
+        };
+        // Its caller gave up waiting.
+        if Instant::now() >= job.deadline {
+            continue;
+        }
+        let result = std::panic::catch_unwind(AssertUnwindSafe(|| wat::parse_str(&job.source)));
+        let _ = job.reply.send(result);
+    }
+}
+
+/// Parses `source` in the text format into a wasm module within `limits`,
+/// see the module documentation.
+pub fn parse_wat(source: &str, limits: &WatLimits) -> Result<Vec<u8>, WatError> {
+    if source.len() > limits.max_source_len {
+        return Err(WatError::SourceTooLarge { len: source.len(), limit: limits.max_source_len });
+    }
+    let (reply, receiver) = mpsc::channel();
+    let deadline = Instant::now() + limits.timeout;
+    submit(Job { source: source.to_owned(), deadline, reply })?;
+    let wasm = match receiver.recv_timeout(limits.timeout) {
+        Ok(Ok(Ok(wasm))) => wasm,
+        Ok(Ok(Err(err))) => return Err(WatError::Syntax(err.to_string())),
+        Ok(Err(panic)) => {
+            let message = panic
+                .downcast_ref::<&str>()
+                .map(|msg| msg.to_string())
+                .or_else(|| panic.downcast_ref::<String>().cloned())
+                .unwrap_or_else(|| "<non-string panic>".to_string());
+            return Err(WatError::Panic(message));
+        }
+        // The parsers drop the jobs reaching them past their deadline.
+        Err(RecvTimeoutError::Timeout | RecvTimeoutError::Disconnected) => {
+            return Err(WatError::Timeout(limits.timeout))
+        }
+    };
+    if wasm.len() > limits.max_wasm_len {
+        return Err(WatError::ModuleTooLarge { len: wasm.len(), limit: limits.max_wasm_len });
+    }
+    Ok(wasm)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_builder::test_builder;
+
+    #[test]
+    fn test_parse_wat_limits() {
+        let limits = WatLimits::default();
+        let wasm = parse_wat("(module)", &limits).unwrap();
+        assert_eq!(wasm, wat::parse_str("(module)").unwrap());
+
+        let source = r#"(module (memory 1) (data (i32.const 0) "0123456789"))"#;
+        let err = parse_wat(source, &WatLimits { max_source_len: 10, ..limits }).unwrap_err();
+        assert_eq!(err, WatError::SourceTooLarge { len: source.len(), limit: 10 });
+        let err = parse_wat(source, &WatLimits { max_wasm_len: 10, ..limits }).unwrap_err();
+        assert!(matches!(err, WatError::ModuleTooLarge { limit: 10, .. }), "{err:?}");
+
+        let err = parse_wat("(module (func (export \"main\") (i32.add)", &limits).unwrap_err();
+        assert!(matches!(err, WatError::Syntax(_)), "{err:?}");
+    }
+
+    #[test]
+    fn test_parse_wat_concurrently() {
+        let limits = WatLimits::default();
+        std::thread::scope(|s| {
+            let parses: Vec<_> = (0..2 * PARSER_THREADS)
+                .map(|_| s.spawn(|| parse_wat("(module (func))", &limits)))
+                .collect();
+            for parse in parses {
+                assert_eq!(parse.join().unwrap(), Ok(wat::parse_str("(module (func))").unwrap()));
+            }
+        });
+    }
+
+    #[test]
+    fn test_builder_reports_wat_errors() {
+        let err = test_builder().try_wat("(module").err().unwrap();
+        assert!(matches!(err, WatError::Syntax(_)), "{err:?}");
+    }
+}